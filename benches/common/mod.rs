@@ -0,0 +1,170 @@
+//! In-process loopback servers shared by benchmarks
+//!
+//! Each cipher gets its own ssserver, and sslocal tunnels forwarding to a sink and an echo server, all on 127.0.0.1.
+
+#![allow(dead_code)]
+
+use std::net::{SocketAddr, TcpListener as StdTcpListener};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, UdpSocket},
+    runtime::{Builder, Runtime},
+    time::{self, Duration},
+};
+
+use shadowsocks_service::{
+    config::{Config, ConfigType, LocalConfig, ProtocolType},
+    run_local,
+    run_server,
+    shadowsocks::{config::Mode, crypto::CipherKind, relay::socks5::Address, ServerConfig},
+};
+
+/// Ciphers to be compared, `none` is the baseline of the relay without encryption
+pub const CIPHERS: &[CipherKind] = &[
+    CipherKind::NONE,
+    CipherKind::AES_128_GCM,
+    CipherKind::AES_256_GCM,
+    CipherKind::CHACHA20_POLY1305,
+];
+
+/// Sink acknowledges each chunk of this size with 1 byte
+pub const SINK_CHUNK_SIZE: usize = 1024 * 1024;
+
+const PASSWORD: &str = "shadowsocks-bench";
+
+/// Multi-threaded runtime, like sslocal and ssserver
+pub fn runtime() -> Runtime {
+    Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .expect("create tokio runtime")
+}
+
+/// Pick a free port on loopback for both TCP and UDP
+pub fn free_addr() -> SocketAddr {
+    loop {
+        let listener = StdTcpListener::bind("127.0.0.1:0").expect("bind loopback");
+        let addr = listener.local_addr().unwrap();
+        if std::net::UdpSocket::bind(addr).is_ok() {
+            return addr;
+        }
+    }
+}
+
+/// Start a ssserver of `method` on loopback
+pub async fn start_server(method: CipherKind) -> ServerConfig {
+    let mut svr_cfg = ServerConfig::new(free_addr(), PASSWORD.to_owned(), method);
+    svr_cfg.set_mode(Mode::TcpAndUdp);
+
+    let mut config = Config::new(ConfigType::Server);
+    config.server = vec![svr_cfg.clone()];
+    // Small acknowledgements of the sink are otherwise held by Nagle's algorithm, stalling until delayed ACKs
+    config.no_delay = true;
+    tokio::spawn(run_server(config));
+
+    svr_cfg
+}
+
+/// Start a sslocal tunnel forwarding to `forward_addr` through `svr_cfg`, returns the tunnel's address
+pub async fn start_tunnel(svr_cfg: ServerConfig, forward_addr: SocketAddr) -> SocketAddr {
+    let local_addr = free_addr();
+    let mut local_config = LocalConfig::new_with_addr(local_addr.into(), ProtocolType::Tunnel);
+    local_config.mode = Mode::TcpAndUdp;
+    local_config.forward_addr = Some(Address::SocketAddress(forward_addr));
+
+    let mut config = Config::new(ConfigType::Local);
+    config.local = vec![local_config];
+    config.server = vec![svr_cfg];
+    // Same as servers, see `start_server`
+    config.no_delay = true;
+    tokio::spawn(run_local(config));
+
+    local_addr
+}
+
+/// Start a TCP server discarding data, 1 byte is sent back after receiving every `SINK_CHUNK_SIZE` bytes
+pub async fn start_tcp_sink() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            tokio::spawn(async move {
+                let mut buffer = vec![0u8; 64 * 1024];
+                let mut received = 0;
+                loop {
+                    let n = match stream.read(&mut buffer).await {
+                        Ok(0) | Err(..) => break,
+                        Ok(n) => n,
+                    };
+                    received += n;
+                    while received >= SINK_CHUNK_SIZE {
+                        received -= SINK_CHUNK_SIZE;
+                        if stream.write_all(&[0]).await.is_err() {
+                            return;
+                        }
+                    }
+                }
+            });
+        }
+    });
+
+    addr
+}
+
+/// Start TCP and UDP echo servers on the same address
+pub async fn start_echo() -> SocketAddr {
+    let addr = free_addr();
+    let listener = TcpListener::bind(addr).await.unwrap();
+    let socket = UdpSocket::bind(addr).await.unwrap();
+
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            tokio::spawn(async move {
+                let (mut reader, mut writer) = stream.split();
+                let _ = tokio::io::copy(&mut reader, &mut writer).await;
+            });
+        }
+    });
+
+    tokio::spawn(async move {
+        let mut buffer = vec![0u8; 65536];
+        loop {
+            let (n, peer_addr) = socket.recv_from(&mut buffer).await.unwrap();
+            let _ = socket.send_to(&buffer[..n], peer_addr).await;
+        }
+    });
+
+    addr
+}
+
+/// Servers of a cipher
+pub struct Loopback {
+    pub method: CipherKind,
+    pub server: ServerConfig,
+    /// Tunnel to the TCP sink
+    pub sink_tunnel: SocketAddr,
+    /// Tunnel to the TCP and UDP echo servers
+    pub echo_tunnel: SocketAddr,
+}
+
+impl Loopback {
+    pub async fn start(method: CipherKind) -> Loopback {
+        let server = start_server(method).await;
+        let sink_tunnel = start_tunnel(server.clone(), start_tcp_sink().await).await;
+        let echo_tunnel = start_tunnel(server.clone(), start_echo().await).await;
+
+        // Wait for all listeners
+        time::sleep(Duration::from_secs(1)).await;
+
+        Loopback {
+            method,
+            server,
+            sink_tunnel,
+            echo_tunnel,
+        }
+    }
+}
@@ -0,0 +1,122 @@
+//! Throughput and latency of relays between sslocal and ssserver on loopback
+//!
+//! ```bash
+//! cargo bench --bench relay
+//! ```
+
+use std::time::Instant;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpStream, UdpSocket},
+    runtime::Runtime,
+    time::{self, Duration},
+};
+
+mod common;
+
+use common::{Loopback, CIPHERS, SINK_CHUNK_SIZE};
+
+/// Number of UDP packets in flight
+const UDP_BATCH_SIZE: usize = 32;
+/// Payload size of UDP packets
+const UDP_PACKET_SIZE: usize = 512;
+
+/// TCP bytes sent through a tunnel to the sink
+fn tcp_throughput(c: &mut Criterion, rt: &Runtime, loopbacks: &[Loopback]) {
+    let mut group = c.benchmark_group("tcp_throughput");
+    group.throughput(Throughput::Bytes(SINK_CHUNK_SIZE as u64));
+
+    for loopback in loopbacks {
+        let mut stream = rt.block_on(TcpStream::connect(loopback.sink_tunnel)).unwrap();
+        let chunk = vec![0u8; SINK_CHUNK_SIZE];
+        let mut ack = [0u8; 1];
+
+        group.bench_function(BenchmarkId::from_parameter(loopback.method), |b| {
+            b.iter_custom(|iters| {
+                rt.block_on(async {
+                    let start = Instant::now();
+                    for _ in 0..iters {
+                        stream.write_all(&chunk).await.unwrap();
+                        stream.read_exact(&mut ack).await.unwrap();
+                    }
+                    start.elapsed()
+                })
+            })
+        });
+    }
+
+    group.finish();
+}
+
+/// UDP packets echoed through a tunnel, `UDP_BATCH_SIZE` packets in flight
+fn udp_pps(c: &mut Criterion, rt: &Runtime, loopbacks: &[Loopback]) {
+    let mut group = c.benchmark_group("udp_pps");
+    group.throughput(Throughput::Elements(UDP_BATCH_SIZE as u64));
+
+    for loopback in loopbacks {
+        let socket = rt.block_on(async {
+            let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            socket.connect(loopback.echo_tunnel).await.unwrap();
+            socket
+        });
+        let packet = vec![0u8; UDP_PACKET_SIZE];
+        let mut buffer = vec![0u8; 65536];
+
+        group.bench_function(BenchmarkId::from_parameter(loopback.method), |b| {
+            b.iter_custom(|iters| {
+                rt.block_on(async {
+                    let start = Instant::now();
+                    for _ in 0..iters {
+                        for _ in 0..UDP_BATCH_SIZE {
+                            socket.send(&packet).await.unwrap();
+                        }
+                        for _ in 0..UDP_BATCH_SIZE {
+                            time::timeout(Duration::from_secs(5), socket.recv(&mut buffer))
+                                .await
+                                .expect("UDP packets lost, try again with larger socket buffers")
+                                .unwrap();
+                        }
+                    }
+                    start.elapsed()
+                })
+            })
+        });
+    }
+
+    group.finish();
+}
+
+/// Time of connecting to a tunnel and receiving the first echoed byte from the target
+fn tcp_connect_latency(c: &mut Criterion, rt: &Runtime, loopbacks: &[Loopback]) {
+    let mut group = c.benchmark_group("tcp_connect_latency");
+
+    for loopback in loopbacks {
+        group.bench_function(BenchmarkId::from_parameter(loopback.method), |b| {
+            b.to_async(rt).iter(|| async {
+                let mut stream = TcpStream::connect(loopback.echo_tunnel).await.unwrap();
+                let mut buffer = [0u8; 1];
+                stream.write_all(&buffer).await.unwrap();
+                stream.read_exact(&mut buffer).await.unwrap();
+            })
+        });
+    }
+
+    group.finish();
+}
+
+fn relay_benchmarks(c: &mut Criterion) {
+    let rt = common::runtime();
+    let loopbacks = CIPHERS
+        .iter()
+        .map(|method| rt.block_on(Loopback::start(*method)))
+        .collect::<Vec<_>>();
+
+    tcp_throughput(c, &rt, &loopbacks);
+    udp_pps(c, &rt, &loopbacks);
+    tcp_connect_latency(c, &rt, &loopbacks);
+}
+
+criterion_group!(benches, relay_benchmarks);
+criterion_main!(benches);
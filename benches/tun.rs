@@ -0,0 +1,115 @@
+//! Throughput of the network stack of sslocal's tun, relaying to ssserver on loopback
+//!
+//! Creating tun devices requires root (or `CAP_NET_ADMIN`). Connections to the tun are made to a fake IP of
+//! `localhost`, so the server connects back to the sink on loopback instead of routing into the tun again.
+//!
+//! ```bash
+//! sudo -E cargo bench --bench tun --features "local-tun local-dns"
+//! ```
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use std::{net::SocketAddr, sync::Arc, time::Instant};
+
+    use criterion::{BenchmarkId, Criterion, Throughput};
+    use ipnet::{IpNet, Ipv4Net};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+        time::{self, Duration},
+    };
+
+    use shadowsocks_service::{
+        local::{
+            context::ServiceContext,
+            dns::{config::FakeIpConfig, FakeIpPool},
+            loadbalancing::PingBalancerBuilder,
+            tun::TunBuilder,
+        },
+        shadowsocks::{config::Mode, crypto::CipherKind},
+        store::MemoryStateStore,
+    };
+
+    use super::common::{self, CIPHERS, SINK_CHUNK_SIZE};
+
+    /// Start a tun relaying through a ssserver of `method`, returns the address of the sink behind the tun
+    async fn start_tun(idx: usize, method: CipherKind) -> SocketAddr {
+        let server = common::start_server(method).await;
+        let sink_addr = common::start_tcp_sink().await;
+
+        let tun_net = format!("10.254.{}.1/24", idx).parse::<IpNet>().unwrap();
+        let fake_ip_range = format!("10.254.{}.128/25", idx).parse::<Ipv4Net>().unwrap();
+
+        let fake_ip_pool = Arc::new(FakeIpPool::new(
+            &FakeIpConfig::new(fake_ip_range),
+            Arc::new(MemoryStateStore::new()),
+        ));
+        let (fake_ip, ..) = fake_ip_pool.allocate("localhost");
+
+        let mut context = ServiceContext::new();
+        context.set_fake_ip_pool(fake_ip_pool);
+        let context = Arc::new(context);
+
+        let mut balancer_builder = PingBalancerBuilder::new(context.clone(), Mode::TcpOnly);
+        balancer_builder.add_server(server);
+        let balancer = balancer_builder.build().await.unwrap();
+
+        let tun = TunBuilder::new(context, balancer)
+            .address(tun_net)
+            .name(&format!("ssbench{}", idx))
+            .build()
+            .await
+            .expect("create tun device, root is required");
+        tokio::spawn(tun.run());
+
+        // Wait for all listeners
+        time::sleep(Duration::from_secs(1)).await;
+
+        SocketAddr::new(fake_ip.into(), sink_addr.port())
+    }
+
+    /// TCP bytes sent through the tun to the sink
+    pub fn tun_tcp_throughput(c: &mut Criterion) {
+        let rt = common::runtime();
+
+        let mut group = c.benchmark_group("tun_tcp_throughput");
+        group.throughput(Throughput::Bytes(SINK_CHUNK_SIZE as u64));
+        // The userspace network stack is much slower than relays, fewer samples keep the run short
+        group.sample_size(20);
+
+        for (idx, method) in CIPHERS.iter().enumerate() {
+            let sink_addr = rt.block_on(start_tun(idx, *method));
+            let mut stream = rt.block_on(TcpStream::connect(sink_addr)).unwrap();
+            let chunk = vec![0u8; SINK_CHUNK_SIZE];
+            let mut ack = [0u8; 1];
+
+            group.bench_function(BenchmarkId::from_parameter(method), |b| {
+                b.iter_custom(|iters| {
+                    rt.block_on(async {
+                        let start = Instant::now();
+                        for _ in 0..iters {
+                            stream.write_all(&chunk).await.unwrap();
+                            stream.read_exact(&mut ack).await.unwrap();
+                        }
+                        start.elapsed()
+                    })
+                })
+            });
+        }
+
+        group.finish();
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod common;
+
+#[cfg(target_os = "linux")]
+criterion::criterion_group!(benches, linux::tun_tcp_throughput);
+#[cfg(target_os = "linux")]
+criterion::criterion_main!(benches);
+
+#[cfg(not(target_os = "linux"))]
+fn main() {
+    eprintln!("tun benchmarks are only supported on Linux");
+}
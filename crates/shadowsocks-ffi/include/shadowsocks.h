@@ -0,0 +1,68 @@
+/*
+ * C interface of shadowsocks local service
+ *
+ * Local service instances (like `sslocal`) are started from configurations in JSON, the same as configuration files
+ * of `sslocal`, and run in threads of their own. Changes of states are reported by the callback, which is called from
+ * the instance's thread.
+ *
+ * Only one instance could be running in a process.
+ */
+
+#ifndef SHADOWSOCKS_H
+#define SHADOWSOCKS_H
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* Local servers are running, after started or reloaded */
+#define SS_LOCAL_STATE_RUNNING 1
+/* Reloading failed, servers of the previous configuration are kept running */
+#define SS_LOCAL_STATE_RELOAD_FAILED 2
+/* The instance has failed and stopped, by errors of starting or serving */
+#define SS_LOCAL_STATE_ERROR 3
+/* The instance has been stopped by `ss_local_stop` */
+#define SS_LOCAL_STATE_STOPPED 4
+
+/* A running local service instance */
+typedef struct SsLocal ss_local_t;
+
+/* Callback of changes of states, with a message of errors or NULL, which is only valid during the call */
+typedef void (*ss_local_state_callback)(void *user_data, int state, const char *message);
+
+/*
+ * Start a local service instance of the configuration `config` in JSON
+ *
+ * Returns NULL if the configuration is invalid or another instance is running, which is reported to the callback with
+ * SS_LOCAL_STATE_ERROR before returning. Another instance could be started after the running one is stopped by
+ * `ss_local_stop`, even if it has failed. Otherwise servers are started in the background, the callback is called
+ * with SS_LOCAL_STATE_RUNNING after they are listening, or SS_LOCAL_STATE_ERROR if they couldn't be started.
+ *
+ * `user_data` is passed to `callback`, it must be valid until the instance is stopped. `callback` could be NULL.
+ */
+ss_local_t *ss_local_start(const char *config, ss_local_state_callback callback, void *user_data);
+
+/*
+ * Reload the instance with the configuration `config` in JSON
+ *
+ * Returns 0 if the configuration is valid, and the result is reported to the callback, with SS_LOCAL_STATE_RUNNING
+ * or SS_LOCAL_STATE_RELOAD_FAILED. Returns -1 if the configuration is invalid, and nothing is changed.
+ */
+int ss_local_reload(ss_local_t *local, const char *config);
+
+/*
+ * Stop the instance and free it, established connections are closed
+ *
+ * It returns after the instance's thread exits, so it mustn't be called from the callback. The callback is called
+ * with SS_LOCAL_STATE_STOPPED before returning, unless the instance has stopped by errors.
+ */
+void ss_local_stop(ss_local_t *local);
+
+/* Version of shadowsocks, a static string */
+const char *ss_version(void);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* SHADOWSOCKS_H */
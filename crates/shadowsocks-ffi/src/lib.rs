@@ -0,0 +1,296 @@
+//! C interface of shadowsocks local service
+//!
+//! Applications embed a local service instance (like `sslocal`) in their own process, instead of spawning `sslocal`
+//! as a child process. Instances are created from configurations in JSON, the same as configuration files of
+//! `sslocal`, and they are running in threads of their own. Declarations are in `include/shadowsocks.h`.
+//!
+//! Changes of states are reported by a callback, which is called from the instance's thread.
+//!
+//! Only one instance could be running in a process, because listening sockets kept for reloading are recorded
+//! process-wide.
+
+use std::{
+    ffi::{CStr, CString},
+    io::{self, ErrorKind},
+    os::raw::{c_char, c_int, c_void},
+    ptr,
+    sync::atomic::{AtomicBool, Ordering},
+    thread::{self, JoinHandle},
+};
+
+use futures::future::{self, Either};
+use log::{error, info};
+use shadowsocks_service::{
+    config::{Config, ConfigType},
+    create_local,
+};
+use tokio::{runtime::Builder, sync::mpsc};
+
+/// Local servers are running, after started or reloaded
+pub const SS_LOCAL_STATE_RUNNING: c_int = 1;
+/// Reloading failed, servers of the previous configuration are kept running
+pub const SS_LOCAL_STATE_RELOAD_FAILED: c_int = 2;
+/// The instance has failed and stopped, by errors of starting or serving
+pub const SS_LOCAL_STATE_ERROR: c_int = 3;
+/// The instance has been stopped by `ss_local_stop`
+pub const SS_LOCAL_STATE_STOPPED: c_int = 4;
+
+/// An instance has been started and its thread hasn't exited
+static RUNNING: AtomicBool = AtomicBool::new(false);
+
+/// Callback of changes of states, with a message of errors or `NULL`
+///
+/// The message is only valid during the call.
+pub type SsLocalStateCallback =
+    Option<unsafe extern "C" fn(user_data: *mut c_void, state: c_int, message: *const c_char)>;
+
+/// Callback and its data, passed to the instance's thread
+struct StateCallback {
+    callback: SsLocalStateCallback,
+    user_data: *mut c_void,
+}
+
+// Callers are responsible for `user_data` being used from the instance's thread
+unsafe impl Send for StateCallback {}
+
+impl StateCallback {
+    fn report(&self, state: c_int, message: Option<&str>) {
+        let callback = match self.callback {
+            Some(callback) => callback,
+            None => return,
+        };
+
+        // Messages are formatted errors, NULs are replaced for C strings
+        let message = message.map(|m| CString::new(m.replace('\0', " ")).unwrap_or_default());
+        unsafe {
+            callback(
+                self.user_data,
+                state,
+                message.as_ref().map(|m| m.as_ptr()).unwrap_or(ptr::null()),
+            );
+        }
+    }
+}
+
+/// Requests sent to the instance's thread
+enum Request {
+    Reload(Box<Config>),
+    Stop,
+}
+
+/// A running local service instance, the opaque handle of C
+pub struct SsLocal {
+    request_tx: mpsc::UnboundedSender<Request>,
+    thread: JoinHandle<()>,
+}
+
+/// Parse and check a configuration of local service in JSON
+unsafe fn load_config(config: *const c_char) -> Result<Config, String> {
+    if config.is_null() {
+        return Err("configuration is NULL".to_owned());
+    }
+
+    let config = CStr::from_ptr(config)
+        .to_str()
+        .map_err(|err| format!("configuration is not UTF-8, {}", err))?;
+    let config = Config::load_from_str(config, ConfigType::Local).map_err(|err| err.to_string())?;
+
+    if config.server.is_empty() {
+        return Err("missing `servers` for client configuration".to_owned());
+    }
+    config.check_integrity().map_err(|err| err.to_string())?;
+
+    Ok(config)
+}
+
+/// Start a local service instance of the configuration `config` in JSON
+///
+/// Returns `NULL` if the configuration is invalid, another instance is running, or the thread couldn't be started,
+/// which is reported to the callback with `SS_LOCAL_STATE_ERROR` before returning. Another instance could be started
+/// after the running one is stopped by `ss_local_stop`, even if it has failed. Otherwise servers are started in the
+/// background, the callback is called with `SS_LOCAL_STATE_RUNNING` after listeners of their local addresses are bound,
+/// or `SS_LOCAL_STATE_ERROR` if they couldn't be started.
+///
+/// # Safety
+///
+/// `config` must be a NUL-terminated string. `user_data` is passed to `callback` from the instance's thread, it must
+/// be valid until the instance is stopped.
+#[no_mangle]
+pub unsafe extern "C" fn ss_local_start(
+    config: *const c_char,
+    callback: SsLocalStateCallback,
+    user_data: *mut c_void,
+) -> *mut SsLocal {
+    let config = match load_config(config) {
+        Ok(config) => config,
+        Err(err) => {
+            StateCallback { callback, user_data }.report(SS_LOCAL_STATE_ERROR, Some(&err));
+            return ptr::null_mut();
+        }
+    };
+
+    if RUNNING
+        .compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire)
+        .is_err()
+    {
+        let err = "another instance is running, only one instance could be running in a process";
+        StateCallback { callback, user_data }.report(SS_LOCAL_STATE_ERROR, Some(err));
+        return ptr::null_mut();
+    }
+
+    let (request_tx, request_rx) = mpsc::unbounded_channel();
+    let state_callback = StateCallback { callback, user_data };
+    let thread = thread::Builder::new()
+        .name("shadowsocks-local".to_owned())
+        .spawn(move || {
+            run_local(config, request_rx, state_callback);
+            RUNNING.store(false, Ordering::Release);
+        });
+
+    match thread {
+        Ok(thread) => Box::into_raw(Box::new(SsLocal { request_tx, thread })),
+        Err(err) => {
+            RUNNING.store(false, Ordering::Release);
+            StateCallback { callback, user_data }.report(SS_LOCAL_STATE_ERROR, Some(&err.to_string()));
+            ptr::null_mut()
+        }
+    }
+}
+
+/// Reload the instance with the configuration `config` in JSON
+///
+/// Returns 0 if the configuration is valid, and the result of reloading is reported to the callback, with
+/// `SS_LOCAL_STATE_RUNNING` if servers of the new configuration are started, or `SS_LOCAL_STATE_RELOAD_FAILED`.
+/// Returns -1 if the configuration is invalid, and nothing is changed.
+///
+/// # Safety
+///
+/// `local` must be returned by `ss_local_start` and not stopped, `config` must be a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn ss_local_reload(local: *mut SsLocal, config: *const c_char) -> c_int {
+    let local = match local.as_ref() {
+        Some(local) => local,
+        None => return -1,
+    };
+
+    let config = match load_config(config) {
+        Ok(config) => config,
+        Err(err) => {
+            error!("reload failed, {}", err);
+            return -1;
+        }
+    };
+
+    // Fails if the instance has stopped by errors, which has been reported
+    match local.request_tx.send(Request::Reload(Box::new(config))) {
+        Ok(..) => 0,
+        Err(..) => -1,
+    }
+}
+
+/// Stop the instance and free it, established connections are closed
+///
+/// It returns after the instance's thread exits, so it mustn't be called from the callback. The callback is called
+/// with `SS_LOCAL_STATE_STOPPED` before returning, unless the instance has stopped by errors.
+///
+/// # Safety
+///
+/// `local` must be returned by `ss_local_start`, and it mustn't be used after stopped.
+#[no_mangle]
+pub unsafe extern "C" fn ss_local_stop(local: *mut SsLocal) {
+    if local.is_null() {
+        return;
+    }
+
+    let local = Box::from_raw(local);
+    let _ = local.request_tx.send(Request::Stop);
+    let _ = local.thread.join();
+}
+
+/// Version of shadowsocks, a static string
+#[no_mangle]
+pub extern "C" fn ss_version() -> *const c_char {
+    concat!(env!("CARGO_PKG_VERSION"), "\0").as_ptr() as *const c_char
+}
+
+/// Run local servers until stop is requested or they fail
+fn run_local(config: Config, mut request_rx: mpsc::UnboundedReceiver<Request>, callback: StateCallback) {
+    let runtime = match Builder::new_multi_thread().enable_all().build() {
+        Ok(runtime) => runtime,
+        Err(err) => {
+            callback.report(SS_LOCAL_STATE_ERROR, Some(&format!("create runtime failed, {}", err)));
+            return;
+        }
+    };
+
+    runtime.block_on(async move {
+        // Listening sockets are recorded for reusing them while reloading
+        #[cfg(unix)]
+        shadowsocks_service::shadowsocks::net::handoff::enable();
+
+        let mut config = config;
+        let mut instance = match create_local(config.clone()).await {
+            Ok(instance) => instance,
+            Err(err) => {
+                callback.report(SS_LOCAL_STATE_ERROR, Some(&format!("start failed, {}", err)));
+                return;
+            }
+        };
+        // Listeners are bound by `create_local`, clients could connect as soon as it is reported
+        callback.report(SS_LOCAL_STATE_RUNNING, None);
+
+        loop {
+            let mut server = Box::pin(instance.wait_until_exit());
+
+            let request = match future::select(server.as_mut(), Box::pin(request_rx.recv())).await {
+                // Servers don't exit without errors
+                Either::Left((result, ..)) => {
+                    let err = result
+                        .err()
+                        .unwrap_or_else(|| io::Error::new(ErrorKind::Other, "server exited unexpectedly"));
+                    callback.report(SS_LOCAL_STATE_ERROR, Some(&format!("server aborted with {}", err)));
+                    return;
+                }
+                Either::Right((Some(request), ..)) => request,
+                // The handle is gone without stopping, which couldn't happen with `ss_local_stop`
+                Either::Right((None, ..)) => Request::Stop,
+            };
+
+            let new_config = match request {
+                Request::Stop => {
+                    drop(server);
+                    info!("local service stopped");
+                    callback.report(SS_LOCAL_STATE_STOPPED, None);
+                    return;
+                }
+                Request::Reload(new_config) => *new_config,
+            };
+
+            // Listeners of unchanged addresses are kept open for the new servers, like reloading `sslocal`
+            #[cfg(unix)]
+            shadowsocks_service::shadowsocks::net::handoff::recycle_bound();
+            drop(server);
+
+            instance = match create_local(new_config.clone()).await {
+                Ok(instance) => {
+                    config = new_config;
+                    callback.report(SS_LOCAL_STATE_RUNNING, None);
+                    instance
+                }
+                Err(err) => {
+                    callback.report(SS_LOCAL_STATE_RELOAD_FAILED, Some(&format!("reload failed, {}", err)));
+                    match create_local(config.clone()).await {
+                        Ok(instance) => instance,
+                        Err(err) => {
+                            callback.report(SS_LOCAL_STATE_ERROR, Some(&format!("restart failed, {}", err)));
+                            return;
+                        }
+                    }
+                }
+            };
+
+            #[cfg(unix)]
+            shadowsocks_service::shadowsocks::net::handoff::clear_inherited();
+        }
+    });
+}
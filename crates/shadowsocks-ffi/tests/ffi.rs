@@ -0,0 +1,98 @@
+use std::{
+    ffi::{CStr, CString},
+    net::TcpStream,
+    os::raw::{c_char, c_int, c_void},
+    ptr,
+    sync::mpsc::{self, Receiver, Sender},
+    time::Duration,
+};
+
+use shadowsocks_ffi::{
+    ss_local_reload,
+    ss_local_start,
+    ss_local_stop,
+    SS_LOCAL_STATE_ERROR,
+    SS_LOCAL_STATE_RUNNING,
+    SS_LOCAL_STATE_STOPPED,
+};
+
+type State = (c_int, Option<String>);
+
+unsafe extern "C" fn state_callback(user_data: *mut c_void, state: c_int, message: *const c_char) {
+    let tx = &*(user_data as *const Sender<State>);
+    let message = if message.is_null() {
+        None
+    } else {
+        Some(CStr::from_ptr(message).to_string_lossy().into_owned())
+    };
+    let _ = tx.send((state, message));
+}
+
+fn recv_state(rx: &Receiver<State>) -> State {
+    rx.recv_timeout(Duration::from_secs(5)).expect("state")
+}
+
+fn local_config(port: u16) -> CString {
+    CString::new(format!(
+        r#"{{
+            "local_address": "127.0.0.1",
+            "local_port": {},
+            "server": "127.0.0.1",
+            "server_port": 9260,
+            "password": "password",
+            "method": "aes-256-gcm"
+        }}"#,
+        port
+    ))
+    .unwrap()
+}
+
+// Instances share the process, they are started one by one in this test
+#[test]
+fn ffi_start_reload_stop() {
+    let _ = env_logger::try_init();
+
+    let (tx, rx) = mpsc::channel::<State>();
+    let user_data = &tx as *const Sender<State> as *mut c_void;
+
+    unsafe {
+        // Invalid configurations are reported before returning
+        let invalid = CString::new("{").unwrap();
+        let local = ss_local_start(invalid.as_ptr(), Some(state_callback), user_data);
+        assert!(local.is_null());
+        let (state, message) = rx.try_recv().unwrap();
+        assert_eq!(state, SS_LOCAL_STATE_ERROR);
+        assert!(message.is_some());
+
+        let port = 9297;
+        let local = ss_local_start(local_config(port).as_ptr(), Some(state_callback), user_data);
+        assert!(!local.is_null());
+        assert_eq!(recv_state(&rx), (SS_LOCAL_STATE_RUNNING, None));
+        TcpStream::connect(("127.0.0.1", port)).unwrap();
+
+        // Only one instance is running
+        let another = ss_local_start(local_config(9298).as_ptr(), Some(state_callback), user_data);
+        assert!(another.is_null());
+        assert_eq!(rx.try_recv().unwrap().0, SS_LOCAL_STATE_ERROR);
+
+        // Invalid configurations are not reloaded
+        assert_eq!(ss_local_reload(local, invalid.as_ptr()), -1);
+        assert_eq!(ss_local_reload(local, ptr::null()), -1);
+
+        let new_port = 9299;
+        assert_eq!(ss_local_reload(local, local_config(new_port).as_ptr()), 0);
+        assert_eq!(recv_state(&rx), (SS_LOCAL_STATE_RUNNING, None));
+        TcpStream::connect(("127.0.0.1", new_port)).unwrap();
+
+        ss_local_stop(local);
+        assert_eq!(recv_state(&rx), (SS_LOCAL_STATE_STOPPED, None));
+        assert!(TcpStream::connect(("127.0.0.1", new_port)).is_err());
+
+        // Started again after stopped
+        let local = ss_local_start(local_config(port).as_ptr(), Some(state_callback), user_data);
+        assert!(!local.is_null());
+        assert_eq!(recv_state(&rx), (SS_LOCAL_STATE_RUNNING, None));
+        ss_local_stop(local);
+        assert_eq!(recv_state(&rx), (SS_LOCAL_STATE_STOPPED, None));
+    }
+}
@@ -0,0 +1,280 @@
+//! Rules in the format of [Clash](https://github.com/Dreamacro/clash/wiki/configuration#rules)
+//!
+//! Rules are read from `rules` of Clash configurations, `payload` of rule providers, or lists with one rule per line,
+//! like `DOMAIN-SUFFIX,google.com,PROXY`. Policies are mapped to sections of ACL
+//!
+//! - `DIRECT` - `[bypass_list]`
+//! - `PROXY` - `[proxy_list]`
+//! - `REJECT` - `[outbound_block_list]`
+//! - `REJECT-DROP` - `[reject:drop]`
+//! - Names of the other proxy groups - `[route:NAME]`
+//!
+//! `MATCH,DIRECT` (the default) switches to `[bypass_all]`, and `MATCH` with the other policies switches to
+//! `[proxy_all]`. Rules without policies, like payloads of rule providers, are exceptions of `MATCH`: proxied with
+//! `MATCH,DIRECT`, or bypassed otherwise.
+
+use std::{collections::BTreeSet, ffi::OsStr, io, net::IpAddr, path::Path};
+
+use ipnet::IpNet;
+use log::{trace, warn};
+
+use super::{Mode, ParsingAcl, ParsingRules, RejectMode};
+
+enum Policy<'a> {
+    Direct,
+    Proxy,
+    Reject,
+    RejectDrop,
+    Group(&'a str),
+}
+
+impl<'a> Policy<'a> {
+    fn parse(policy: &'a str) -> Policy<'a> {
+        match policy {
+            "DIRECT" => Policy::Direct,
+            "REJECT" => Policy::Reject,
+            "REJECT-DROP" => Policy::RejectDrop,
+            p if p.eq_ignore_ascii_case("proxy") => Policy::Proxy,
+            p => Policy::Group(p),
+        }
+    }
+}
+
+enum Rule {
+    Domain(String),
+    DomainSuffix(String),
+    DomainKeyword(String),
+    DomainRegex(String),
+    Ip(IpNet),
+    ProcessName(String),
+    ProcessPath(String),
+    #[cfg(feature = "acl-geoip")]
+    GeoIp(String),
+}
+
+impl Rule {
+    fn add_to(self, rules: &mut ParsingRules) -> io::Result<()> {
+        match self {
+            Rule::Domain(domain) => rules.add_set_rule(&domain)?,
+            Rule::DomainSuffix(domain) => rules.add_tree_rule(&domain)?,
+            Rule::DomainKeyword(keyword) => rules.add_keyword_rule(&keyword)?,
+            Rule::DomainRegex(regex) => rules.add_regex_rule(regex),
+            Rule::Ip(IpNet::V4(v4)) => rules.add_ipv4_rule(v4),
+            Rule::Ip(IpNet::V6(v6)) => rules.add_ipv6_rule(v6),
+            Rule::ProcessName(name) => rules.add_process_name_rule(&name),
+            Rule::ProcessPath(path) => rules.add_process_path_rule(&path),
+            #[cfg(feature = "acl-geoip")]
+            Rule::GeoIp(country) => rules.add_geoip_rule(&country),
+        }
+        Ok(())
+    }
+}
+
+/// Check if `content` of the file `path` is in the format of Clash
+///
+/// YAML files are always Clash configurations or rule providers, the others are detected by their first rules.
+pub(super) fn is_clash_rules(path: &Path, content: &str) -> bool {
+    if matches!(path.extension().and_then(OsStr::to_str), Some("yaml") | Some("yml")) {
+        return true;
+    }
+
+    for line in content.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if line == "payload:" || line == "rules:" {
+            return true;
+        }
+
+        let mut fields = rule_of_item(line).split(',');
+        return match fields.next() {
+            // `GEOIP,CC` and `GEOIP,CC,ACTION` of ACL
+            Some("GEOIP") => !matches!(
+                fields.nth(1),
+                None | Some("bypass") | Some("proxy") | Some("reject") | Some("accept")
+            ),
+            Some(ty) => {
+                matches!(
+                    ty,
+                    "DOMAIN" | "DOMAIN-SUFFIX" | "DOMAIN-KEYWORD" | "DOMAIN-REGEX" | "IP-CIDR" | "IP-CIDR6" | "MATCH"
+                )
+            }
+            None => false,
+        };
+    }
+
+    false
+}
+
+/// Rule of a YAML list item, without the preceding `-`, quotes and comments
+fn rule_of_item(line: &str) -> &str {
+    let rule = line.trim();
+    let rule = rule.strip_prefix('-').map_or(rule, str::trim_start);
+    let rule = match rule.find(" #") {
+        Some(idx) => rule[..idx].trim_end(),
+        None => rule,
+    };
+
+    for quote in ['"', '\''] {
+        if rule.len() >= 2 && rule.starts_with(quote) && rule.ends_with(quote) {
+            return &rule[1..rule.len() - 1];
+        }
+    }
+    rule
+}
+
+/// Parse Clash rules in `content` of the file `path`
+pub(super) fn parse(path: &Path, content: &str) -> io::Result<ParsingAcl> {
+    // Unmatched targets are connected directly without `MATCH`, like `[bypass_all]`
+    let mut mode = Mode::WhiteList;
+    let mut parsed: Vec<(usize, Option<Policy>, Rule)> = Vec::new();
+    let mut unsupported = BTreeSet::new();
+    #[cfg(feature = "acl-geoip")]
+    let mut geoip_path = None;
+
+    // Rules of YAML files are in `rules` or `payload`, the other keys are skipped
+    let is_yaml = matches!(path.extension().and_then(OsStr::to_str), Some("yaml") | Some("yml"));
+    let mut in_rules = !is_yaml;
+
+    trace!("ACL parsing Clash rules from {:?}", path);
+
+    for (idx, line) in content.lines().enumerate() {
+        let trimmed = line.trim();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            continue;
+        }
+
+        if !line.starts_with(|c: char| c.is_whitespace() || c == '-') && trimmed.contains(':') {
+            in_rules = trimmed == "rules:" || trimmed == "payload:";
+            continue;
+        }
+        if !in_rules || (is_yaml && !trimmed.starts_with('-')) {
+            continue;
+        }
+
+        let rule = rule_of_item(trimmed);
+        if !rule.is_ascii() && !rule.starts_with("PROCESS-") {
+            warn!("ACL rule {} containing non-ASCII characters, skipped", rule);
+            continue;
+        }
+
+        let fields: Vec<&str> = rule.split(',').map(str::trim).collect();
+        let ty = fields[0];
+        let value = fields.get(1).copied().unwrap_or_default();
+        // Options like `no-resolve` follow policies, which could be omitted in rule providers
+        let policy = fields
+            .get(2)
+            .copied()
+            .filter(|p| *p != "no-resolve" && *p != "src")
+            .map(Policy::parse);
+
+        if value.is_empty() && ty != "MATCH" && ty != "FINAL" {
+            return Err(invalid_rule(rule, "missing value"));
+        }
+
+        let parsed_rule = match ty {
+            "MATCH" | "FINAL" => {
+                mode = match Policy::parse(value) {
+                    Policy::Direct => Mode::WhiteList,
+                    Policy::Proxy => Mode::BlackList,
+                    Policy::Group(group) => {
+                        warn!(
+                            "ACL rule {} proxies unmatched targets by servers chosen by the balancer, instead of group {}",
+                            rule, group
+                        );
+                        Mode::BlackList
+                    }
+                    Policy::Reject | Policy::RejectDrop => {
+                        return Err(invalid_rule(rule, "rejecting unmatched targets is not supported"))
+                    }
+                };
+                trace!("switch to mode {:?}", mode);
+                continue;
+            }
+            "GEOIP-DATABASE" => {
+                #[cfg(feature = "acl-geoip")]
+                {
+                    geoip_path = Some(ParsingAcl::geoip_path(path, value));
+                    continue;
+                }
+
+                #[cfg(not(feature = "acl-geoip"))]
+                return Err(invalid_rule(rule, "requires feature \"acl-geoip\""));
+            }
+            "DOMAIN" => Rule::Domain(value.to_owned()),
+            "DOMAIN-SUFFIX" => Rule::DomainSuffix(value.to_owned()),
+            "DOMAIN-KEYWORD" => Rule::DomainKeyword(value.to_owned()),
+            "DOMAIN-REGEX" => Rule::DomainRegex(value.to_owned()),
+            "PROCESS-NAME" => Rule::ProcessName(value.to_owned()),
+            "PROCESS-PATH" => Rule::ProcessPath(value.to_owned()),
+            "IP-CIDR" | "IP-CIDR6" => match value.parse::<IpNet>() {
+                Ok(net) => Rule::Ip(net),
+                Err(..) => match value.parse::<IpAddr>() {
+                    Ok(addr) => Rule::Ip(IpNet::from(addr)),
+                    Err(..) => return Err(invalid_rule(rule, "invalid network")),
+                },
+            },
+            "GEOIP" => {
+                #[cfg(feature = "acl-geoip")]
+                {
+                    if value.len() != 2 || !value.bytes().all(|b| b.is_ascii_alphabetic()) {
+                        return Err(invalid_rule(rule, "invalid country code, expecting ISO 3166-1 alpha-2"));
+                    }
+                    Rule::GeoIp(value.to_owned())
+                }
+
+                #[cfg(not(feature = "acl-geoip"))]
+                return Err(invalid_rule(rule, "requires feature \"acl-geoip\""));
+            }
+            _ => {
+                unsupported.insert(ty.to_owned());
+                continue;
+            }
+        };
+
+        parsed.push((idx + 1, policy, parsed_rule));
+    }
+
+    if !unsupported.is_empty() {
+        warn!(
+            "ACL {} skipped Clash rules of unsupported types: {}",
+            path.display(),
+            unsupported.into_iter().collect::<Vec<_>>().join(", ")
+        );
+    }
+
+    let mut acl = ParsingAcl {
+        mode,
+        outbound_block: ParsingRules::new("REJECT"),
+        rejects: Vec::new(),
+        bypass: ParsingRules::new("DIRECT"),
+        proxy: ParsingRules::new("PROXY"),
+        routes: Vec::new(),
+        dns_upstreams: Vec::new(),
+        ip_strategies: Vec::new(),
+        resolves: Vec::new(),
+        #[cfg(feature = "acl-geoip")]
+        geoip_path,
+    };
+
+    for (line, policy, rule) in parsed {
+        let rules = match policy {
+            None if mode == Mode::WhiteList => &mut acl.proxy,
+            None => &mut acl.bypass,
+            Some(Policy::Direct) => &mut acl.bypass,
+            Some(Policy::Proxy) => &mut acl.proxy,
+            Some(Policy::Reject) => &mut acl.outbound_block,
+            Some(Policy::RejectDrop) => ParsingAcl::reject_rules(&mut acl.rejects, RejectMode::Drop),
+            Some(Policy::Group(group)) => ParsingAcl::route_rules(&mut acl.routes, group),
+        };
+        rules.line = line;
+        rule.add_to(rules)?;
+    }
+
+    Ok(acl)
+}
+
+fn invalid_rule(rule: &str, reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, format!("Clash rule {} {}", rule, reason))
+}
@@ -0,0 +1,390 @@
+//! Explaining decisions of ACL, for tracing connections and checking addresses without sending traffic
+
+use std::{
+    fmt,
+    net::{IpAddr, SocketAddr},
+    path::Path,
+};
+
+use ipnet::IpNet;
+
+use shadowsocks::{context::Context, relay::socks5::Address};
+
+use super::{AccessControl, Mode, ProcessAction, RejectMode, ResolveMode, Rules};
+
+/// Rule matched by a target address or a process
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct MatchedRule {
+    /// Section of the rule, like `[outbound_block_list]`, or policy of Clash rules, like `DIRECT`
+    pub section: String,
+    /// The rule, in the form of ACL files, like `||google.com`
+    pub rule: String,
+    /// Line of the rule in the ACL file, `None` for rules merged with the others
+    pub line: Option<usize>,
+}
+
+impl fmt::Display for MatchedRule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "`{}` in {}", self.rule, self.section)?;
+        if let Some(line) = self.line {
+            write!(f, " (line {})", line)?;
+        }
+        Ok(())
+    }
+}
+
+/// Action decided by ACL
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum AclAction {
+    /// Rejected by `[outbound_block_list]` or `[reject:MODE]`
+    Block(RejectMode),
+    /// Proxied through servers tagged by `[route:TAG]`
+    Route(String),
+    /// Proxied
+    Proxy,
+    /// Connected directly
+    Bypass,
+}
+
+impl<'a> From<ProcessAction<'a>> for AclAction {
+    fn from(action: ProcessAction<'a>) -> AclAction {
+        match action {
+            ProcessAction::Block(mode) => AclAction::Block(mode),
+            ProcessAction::Route(tag) => AclAction::Route(tag.to_owned()),
+            ProcessAction::Proxy => AclAction::Proxy,
+            ProcessAction::Bypass => AclAction::Bypass,
+        }
+    }
+}
+
+impl fmt::Display for AclAction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            AclAction::Block(RejectMode::Refuse) => f.write_str("block"),
+            AclAction::Block(mode) => write!(f, "block:{}", mode),
+            AclAction::Route(ref tag) => write!(f, "route:{}", tag),
+            AclAction::Proxy => f.write_str("proxy"),
+            AclAction::Bypass => f.write_str("bypass"),
+        }
+    }
+}
+
+/// Decision of ACL for a target address, with the rule deciding it
+#[derive(Debug, Clone)]
+pub struct AclDecision {
+    /// Action for the target
+    pub action: AclAction,
+    /// Kind of the deciding rule, `"outbound_block"`, `"process"`, `"reverse_lookup"` or names of `TargetRule`
+    pub rule: &'static str,
+    /// The matched rule, `None` if decided by the mode
+    pub matched: Option<MatchedRule>,
+    /// Resolved address of a domain name, which matched IP rules
+    pub resolved: Option<IpAddr>,
+}
+
+impl AclDecision {
+    fn new(action: AclAction, rule: &'static str, matched: Option<MatchedRule>) -> AclDecision {
+        AclDecision {
+            action,
+            rule,
+            matched,
+            resolved: None,
+        }
+    }
+}
+
+impl fmt::Display for AclDecision {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}; rule={}", self.action, self.rule)?;
+        if let Some(ref matched) = self.matched {
+            write!(f, "; matched {}", matched)?;
+        }
+        if let Some(ref resolved) = self.resolved {
+            write!(f, "; resolved {}", resolved)?;
+        }
+        Ok(())
+    }
+}
+
+impl Rules {
+    fn matched_rule(&self, rule: String) -> MatchedRule {
+        MatchedRule {
+            section: self.name.clone(),
+            line: self.lines.get(&rule).copied(),
+            rule,
+        }
+    }
+
+    /// Rule matched by the ASCII `host`, in the same order of `check_host_matched`
+    fn explain_host(&self, host: &str) -> Option<MatchedRule> {
+        let host = host.trim_end_matches('.');
+        if self.rule_set.contains(host) {
+            return Some(self.matched_rule(format!("|{}", host)));
+        }
+        if let Some(domain) = self.rule_tree.matched(host) {
+            return Some(self.matched_rule(format!("||{}", domain)));
+        }
+        if let Some(m) = self.rule_keyword.find(host) {
+            return Some(self.matched_rule(format!("DOMAIN-KEYWORD,{}", self.rule_keywords[m.pattern()])));
+        }
+        if let Some(idx) = self.rule_regex.matches(host.as_bytes()).iter().next() {
+            return Some(self.matched_rule(self.rule_regex.patterns()[idx].clone()));
+        }
+        None
+    }
+
+    /// Rule matched by `ip`, in the same order of `check_ip_matched`
+    fn explain_ip(&self, ip: &IpAddr) -> Option<MatchedRule> {
+        let net = match ip {
+            IpAddr::V4(v4) => self.ipv4.iter().find(|net| net.contains(v4)).map(IpNet::V4),
+            IpAddr::V6(v6) => self.ipv6.iter().find(|net| net.contains(v6)).map(IpNet::V6),
+        };
+
+        if let Some(net) = net {
+            // Networks are merged, find the first one in the file
+            let original = self
+                .lines
+                .iter()
+                .filter_map(|(rule, line)| rule.parse::<IpNet>().ok().map(|net| (net, *line)))
+                .filter(|(net, _)| net.contains(ip))
+                .min_by_key(|(_, line)| *line);
+            return Some(match original {
+                Some((net, line)) => MatchedRule {
+                    section: self.name.clone(),
+                    rule: net.to_string(),
+                    line: Some(line),
+                },
+                None => self.matched_rule(net.to_string()),
+            });
+        }
+
+        #[cfg(feature = "acl-geoip")]
+        if !self.geoip.is_empty() {
+            if let Some(ref db) = self.geoip_db {
+                if let Some(country) = db.country(ip) {
+                    if self.geoip.contains(country) {
+                        return Some(self.matched_rule(format!("GEOIP,{}", country)));
+                    }
+                }
+            }
+        }
+
+        None
+    }
+
+    /// Rule matched by the process `name` or its executable `path`
+    fn explain_process(&self, name: &str, path: &Path) -> Option<MatchedRule> {
+        if self.process_names.contains(name) {
+            return Some(self.matched_rule(format!("PROCESS-NAME,{}", name)));
+        }
+        if self.process_paths.contains(path) {
+            return Some(self.matched_rule(format!("PROCESS-PATH,{}", path.display())));
+        }
+        None
+    }
+}
+
+/// Resolved addresses of a domain name target, which is only resolved once when IP rules are checked
+///
+/// Domain names of `[resolve:remote]` are never resolved, as if they failed to be resolved
+struct Resolved<'a> {
+    context: &'a Context,
+    addr: &'a Address,
+    ips: Option<Vec<IpAddr>>,
+}
+
+impl<'a> Resolved<'a> {
+    fn remote(context: &'a Context, addr: &'a Address) -> Resolved<'a> {
+        Resolved {
+            context,
+            addr,
+            ips: Some(Vec::new()),
+        }
+    }
+
+    async fn ips(&mut self) -> &[IpAddr] {
+        if self.ips.is_none() {
+            let ips = match *self.addr {
+                Address::SocketAddress(ref addr) => vec![addr.ip()],
+                Address::DomainNameAddress(ref host, port) => match self.context.dns_resolve(host, port).await {
+                    Ok(vaddr) => vaddr.map(|addr: SocketAddr| addr.ip()).collect(),
+                    Err(..) => Vec::new(),
+                },
+            };
+            self.ips = Some(ips);
+        }
+        self.ips.as_deref().unwrap_or_default()
+    }
+}
+
+impl AccessControl {
+    /// Explain the action for connections from the local process `name`, whose executable is `path`
+    ///
+    /// Same as `check_process`, with the matched rule
+    pub fn explain_process(&self, name: &str, path: &Path) -> Option<(AclAction, MatchedRule)> {
+        for (mode, rules) in self.reject_lists() {
+            if let Some(matched) = rules.explain_process(name, path) {
+                return Some((AclAction::Block(mode), matched));
+            }
+        }
+        for (tag, rules) in self.routes.iter() {
+            if let Some(matched) = rules.explain_process(name, path) {
+                return Some((AclAction::Route(tag.clone()), matched));
+            }
+        }
+        if let Some(matched) = self.white_list.explain_process(name, path) {
+            return Some((AclAction::Proxy, matched));
+        }
+        if let Some(matched) = self.black_list.explain_process(name, path) {
+            return Some((AclAction::Bypass, matched));
+        }
+        None
+    }
+
+    /// Explain the action for connecting to target address (for client), with the rule deciding it
+    ///
+    /// Rules are checked in the same order of `check_outbound_rejected`, `route_tag` and `check_target`, without
+    /// process rules. This function may perform a DNS resolution
+    pub async fn explain_target(&self, context: &Context, addr: &Address) -> AclDecision {
+        let ascii_host = match *addr {
+            Address::SocketAddress(..) => None,
+            Address::DomainNameAddress(ref host, ..) => Some(Self::convert_to_ascii(host)),
+        };
+        let remote = ascii_host.as_deref().and_then(|host| {
+            self.resolves
+                .iter()
+                .find(|(_, rules)| rules.check_host_matched(host))
+                .filter(|(mode, _)| *mode == ResolveMode::Remote)
+                .and_then(|(_, rules)| rules.explain_host(host))
+        });
+        let mut resolved = match remote {
+            Some(..) => Resolved::remote(context, addr),
+            None => Resolved {
+                context,
+                addr,
+                ips: None,
+            },
+        };
+
+        // [outbound_block_list] and [reject:MODE]
+        if let Some(ref host) = ascii_host {
+            for (mode, rules) in self.reject_lists() {
+                if let Some(matched) = rules.explain_host(host) {
+                    return AclDecision::new(AclAction::Block(mode), "outbound_block", Some(matched));
+                }
+            }
+        }
+        if ascii_host.is_none() || self.reject_lists().any(|(_, rules)| !rules.is_ip_empty()) {
+            for ip in resolved.ips().await.iter() {
+                for (mode, rules) in self.reject_lists() {
+                    if let Some(matched) = rules.explain_ip(ip) {
+                        let mut decision = AclDecision::new(AclAction::Block(mode), "outbound_block", Some(matched));
+                        decision.resolved = ascii_host.as_ref().map(|_| *ip);
+                        return decision;
+                    }
+                }
+            }
+        }
+
+        // [route:TAG]
+        if let Some(ref host) = ascii_host {
+            for (tag, rules) in self.routes.iter() {
+                if let Some(matched) = rules.explain_host(host) {
+                    return AclDecision::new(AclAction::Route(tag.clone()), "route", Some(matched));
+                }
+            }
+        }
+        if ascii_host.is_none() || self.routes.iter().any(|(_, rules)| !rules.is_ip_empty()) {
+            for ip in resolved.ips().await.iter() {
+                for (tag, rules) in self.routes.iter() {
+                    if let Some(matched) = rules.explain_ip(ip) {
+                        let mut decision = AclDecision::new(AclAction::Route(tag.clone()), "route", Some(matched));
+                        decision.resolved = ascii_host.as_ref().map(|_| *ip);
+                        return decision;
+                    }
+                }
+            }
+        }
+
+        // [proxy_list] and [bypass_list]
+        if let Some(ref host) = ascii_host {
+            if let Some(matched) = self.white_list.explain_host(host) {
+                return AclDecision::new(AclAction::Proxy, "host", Some(matched));
+            }
+            if let Some(matched) = self.black_list.explain_host(host) {
+                return AclDecision::new(AclAction::Bypass, "host", Some(matched));
+            }
+            if let Some(matched) = remote {
+                return AclDecision::new(AclAction::Proxy, "resolve", Some(matched));
+            }
+            if self.is_ip_empty() {
+                return self.explain_default();
+            }
+        }
+
+        match self.mode {
+            // Addresses in bypass_list are bypassed
+            Mode::BlackList => {
+                if let Some(decision) = self
+                    .explain_resolved(&mut resolved, &self.black_list, AclAction::Bypass, "ip")
+                    .await
+                {
+                    return decision;
+                }
+            }
+            // Addresses are proxied only if all of them are in proxy_list
+            Mode::WhiteList => {
+                let mut first_matched = None;
+                for ip in resolved.ips().await.iter() {
+                    match self.white_list.explain_ip(ip) {
+                        Some(matched) => {
+                            first_matched.get_or_insert((matched, *ip));
+                        }
+                        None => return self.explain_default(),
+                    }
+                }
+                if let Some((matched, ip)) = first_matched {
+                    let mut decision = AclDecision::new(AclAction::Proxy, "ip", Some(matched));
+                    decision.resolved = ascii_host.as_ref().map(|_| ip);
+                    return decision;
+                }
+            }
+        }
+
+        // Domain names failed to be resolved are proxied, like `check_target`
+        if ascii_host.is_some() && resolved.ips().await.is_empty() {
+            return AclDecision::new(AclAction::Proxy, "default", None);
+        }
+        self.explain_default()
+    }
+
+    /// Decision of the first resolved address matching `rules`
+    async fn explain_resolved(
+        &self,
+        resolved: &mut Resolved<'_>,
+        rules: &Rules,
+        action: AclAction,
+        rule: &'static str,
+    ) -> Option<AclDecision> {
+        let is_domain = matches!(resolved.addr, Address::DomainNameAddress(..));
+        for ip in resolved.ips().await.iter() {
+            if let Some(matched) = rules.explain_ip(ip) {
+                let mut decision = AclDecision::new(action, rule, Some(matched));
+                if is_domain {
+                    decision.resolved = Some(*ip);
+                }
+                return Some(decision);
+            }
+        }
+        None
+    }
+
+    fn explain_default(&self) -> AclDecision {
+        let action = if self.is_default_in_proxy_list() {
+            AclAction::Proxy
+        } else {
+            AclAction::Bypass
+        };
+        AclDecision::new(action, "default", None)
+    }
+}
@@ -0,0 +1,53 @@
+use std::{
+    collections::HashSet,
+    fmt::{self, Debug},
+    io,
+    net::IpAddr,
+    path::{Path, PathBuf},
+};
+
+use maxminddb::{geoip2, Reader};
+
+/// GeoIP database (MaxMind DB) for `GEOIP` rules, shared by all rules of an ACL
+pub struct GeoIpDatabase {
+    path: PathBuf,
+    reader: Reader<Vec<u8>>,
+}
+
+impl Debug for GeoIpDatabase {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "GeoIpDatabase {{ path: {:?} }}", self.path)
+    }
+}
+
+impl GeoIpDatabase {
+    /// Open the database file
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<GeoIpDatabase> {
+        let path = path.as_ref().to_owned();
+        let reader = Reader::open_readfile(&path).map_err(|err| {
+            io::Error::new(
+                io::ErrorKind::Other,
+                format!("GeoIP database {} error: {}", path.display(), err),
+            )
+        })?;
+        Ok(GeoIpDatabase { path, reader })
+    }
+
+    /// Path of the database file
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Country of `ip` (ISO 3166-1 code in upper case)
+    ///
+    /// Addresses without countries, like anycast ones, are located in their registered countries.
+    pub fn country(&self, ip: &IpAddr) -> Option<&str> {
+        let country = self.reader.lookup::<geoip2::Country>(*ip).ok()?;
+        country.country.or(country.registered_country).and_then(|c| c.iso_code)
+    }
+
+    /// Check if `ip` is located in any of `countries` (ISO 3166-1 codes in upper case)
+    pub fn is_in_countries(&self, ip: &IpAddr, countries: &HashSet<String>) -> bool {
+        self.country(ip).map_or(false, |code| countries.contains(code))
+    }
+}
@@ -2,29 +2,144 @@
 //!
 //! This is for advance controlling server behaviors in both local and proxy servers.
 
+#[cfg(feature = "acl-geoip")]
+use std::sync::Arc;
 use std::{
     borrow::Cow,
-    collections::HashSet,
+    collections::{HashMap, HashSet},
     fmt,
-    fs::File,
-    io::{self, BufRead, BufReader, Error, ErrorKind},
+    fs,
+    io::{self, Error, ErrorKind},
+    iter,
     net::{IpAddr, SocketAddr},
-    path::Path,
+    path::{Path, PathBuf},
     str,
 };
 
+use aho_corasick::{AhoCorasick, AhoCorasickBuilder};
 use ipnet::{IpNet, Ipv4Net, Ipv6Net};
 use iprange::IpRange;
 use log::{trace, warn};
 use once_cell::sync::Lazy;
 use regex::bytes::{Regex, RegexBuilder, RegexSet, RegexSetBuilder};
 
-use shadowsocks::{context::Context, relay::socks5::Address};
+use shadowsocks::{context::Context, net::IpStrategy, relay::socks5::Address};
 
+pub use self::explain::{AclAction, AclDecision, MatchedRule};
+#[cfg(feature = "acl-geoip")]
+pub use self::geoip::GeoIpDatabase;
 use self::sub_domains_tree::SubDomainsTree;
 
+mod clash;
+mod explain;
+#[cfg(feature = "acl-geoip")]
+mod geoip;
 mod sub_domains_tree;
 
+/// Rule deciding whether a target address is bypassed
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum TargetRule {
+    /// `[route:TAG]` sections, always proxied
+    Route,
+    /// `[resolve:remote]` sections, proxied without resolving domain names for IP rules
+    Resolve,
+    /// Domain name rules of `[proxy_list]` or `[bypass_list]`
+    Host,
+    /// IP rules of `[proxy_list]` or `[bypass_list]`
+    Ip,
+    /// No rules matched, decided by the mode
+    Default,
+}
+
+impl TargetRule {
+    /// Name of the rule
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            TargetRule::Route => "route",
+            TargetRule::Resolve => "resolve",
+            TargetRule::Host => "host",
+            TargetRule::Ip => "ip",
+            TargetRule::Default => "default",
+        }
+    }
+}
+
+impl fmt::Display for TargetRule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// How connections rejected by ACL are answered (for client)
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum RejectMode {
+    /// `[outbound_block_list]` or `[reject]`, refused by failure replies of protocols, like SOCKS5 or HTTP 403
+    Refuse,
+    /// `[reject:drop]`, closed without any replies. DNS queries are not answered
+    Drop,
+    /// `[reject:rst]`, reset as if targets reset them. DNS queries are answered with `REFUSED`
+    Reset,
+    /// `[reject:response]`, answered with synthetic responses, an empty HTTP 403 for port 80 (or HTTP proxies).
+    /// DNS queries are answered with `NXDOMAIN`
+    Response,
+}
+
+impl RejectMode {
+    /// Name of the mode
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            RejectMode::Refuse => "refuse",
+            RejectMode::Drop => "drop",
+            RejectMode::Reset => "rst",
+            RejectMode::Response => "response",
+        }
+    }
+}
+
+impl fmt::Display for RejectMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Where domain names of proxied targets are resolved (for client), configured by `[resolve:MODE]` sections
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ResolveMode {
+    /// `[resolve:local]`, resolved by local DNS, and servers are asked to connect to the resolved address
+    Local,
+    /// `[resolve:remote]`, sent to servers as domain names, and never resolved by local DNS for checking IP rules
+    Remote,
+}
+
+impl ResolveMode {
+    /// Name of the mode
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            ResolveMode::Local => "local",
+            ResolveMode::Remote => "remote",
+        }
+    }
+}
+
+impl fmt::Display for ResolveMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Action for connections from a local process, configured by `PROCESS-NAME` and `PROCESS-PATH` rules
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ProcessAction<'a> {
+    /// Rules of `[outbound_block_list]` or `[reject:MODE]`
+    Block(RejectMode),
+    /// Rules of `[route:TAG]`, proxied through servers tagged `TAG`
+    Route(&'a str),
+    /// Rules of `[proxy_list]`
+    Proxy,
+    /// Rules of `[bypass_list]`
+    Bypass,
+}
+
 /// Strategy mode that ACL is running
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub enum Mode {
@@ -36,11 +151,21 @@ pub enum Mode {
 
 #[derive(Clone)]
 struct Rules {
+    name: String,
+    lines: HashMap<String, usize>,
     ipv4: IpRange<Ipv4Net>,
     ipv6: IpRange<Ipv6Net>,
     rule_regex: RegexSet,
+    rule_keyword: AhoCorasick,
+    rule_keywords: Vec<String>,
     rule_set: HashSet<String>,
     rule_tree: SubDomainsTree,
+    process_names: HashSet<String>,
+    process_paths: HashSet<PathBuf>,
+    #[cfg(feature = "acl-geoip")]
+    geoip: HashSet<String>,
+    #[cfg(feature = "acl-geoip")]
+    geoip_db: Option<Arc<GeoIpDatabase>>,
 }
 
 impl fmt::Debug for Rules {
@@ -65,6 +190,20 @@ impl fmt::Debug for Rules {
             f.write_str(", ...")?;
         }
 
+        write!(f, "], rule_keyword: [")?;
+
+        let has_more = self.rule_keywords.len() > max_len;
+        for (idx, r) in self.rule_keywords.iter().take(max_len).enumerate() {
+            if idx > 0 {
+                f.write_str(", ")?;
+            }
+            f.write_str(r)?;
+        }
+
+        if has_more {
+            f.write_str(", ...")?;
+        }
+
         write!(f, "], rule_set: [")?;
 
         let has_more = self.rule_set.len() > max_len;
@@ -79,7 +218,20 @@ impl fmt::Debug for Rules {
             f.write_str(", ...")?;
         }
 
-        write!(f, "], rule_tree: {:?} }}", self.rule_tree)
+        write!(f, "], rule_tree: {:?}", self.rule_tree)?;
+
+        if !self.is_process_empty() {
+            write!(
+                f,
+                ", process_names: {:?}, process_paths: {:?}",
+                self.process_names, self.process_paths
+            )?;
+        }
+
+        #[cfg(feature = "acl-geoip")]
+        write!(f, ", geoip: {:?}", self.geoip)?;
+
+        f.write_str(" }")
     }
 }
 
@@ -89,6 +241,7 @@ impl Rules {
         mut ipv4: IpRange<Ipv4Net>,
         mut ipv6: IpRange<Ipv6Net>,
         rule_regex: RegexSet,
+        rule_keywords: Vec<String>,
         rule_set: HashSet<String>,
         rule_tree: SubDomainsTree,
     ) -> Rules {
@@ -96,12 +249,28 @@ impl Rules {
         ipv4.simplify();
         ipv6.simplify();
 
+        // All keywords are matched in one pass
+        let rule_keyword = AhoCorasickBuilder::new()
+            .ascii_case_insensitive(true)
+            .dfa(true)
+            .build(&rule_keywords);
+
         Rules {
+            name: String::new(),
+            lines: HashMap::new(),
             ipv4,
             ipv6,
             rule_regex,
+            rule_keyword,
+            rule_keywords,
             rule_set,
             rule_tree,
+            process_names: HashSet::new(),
+            process_paths: HashSet::new(),
+            #[cfg(feature = "acl-geoip")]
+            geoip: HashSet::new(),
+            #[cfg(feature = "acl-geoip")]
+            geoip_db: None,
         }
     }
 
@@ -116,59 +285,165 @@ impl Rules {
 
     /// Check if the specified address matches any rules
     fn check_ip_matched(&self, addr: &IpAddr) -> bool {
-        match addr {
+        let matched = match addr {
             IpAddr::V4(v4) => self.ipv4.contains(v4),
             IpAddr::V6(v6) => self.ipv6.contains(v6),
+        };
+
+        #[cfg(feature = "acl-geoip")]
+        if !matched && !self.geoip.is_empty() {
+            if let Some(ref db) = self.geoip_db {
+                return db.is_in_countries(addr, &self.geoip);
+            }
         }
+
+        matched
     }
 
     /// Check if the specified ASCII host matches any rules
     fn check_host_matched(&self, host: &str) -> bool {
         let host = host.trim_end_matches('.'); // FQDN, removes the last `.`
-        self.rule_set.contains(host) || self.rule_tree.contains(host) || self.rule_regex.is_match(host.as_bytes())
+        self.rule_set.contains(host)
+            || self.rule_tree.contains(host)
+            || self.rule_keyword.is_match(host)
+            || self.rule_regex.is_match(host.as_bytes())
+    }
+
+    /// Check if the process `name` or its executable `path` matches any rules
+    fn check_process_matched(&self, name: &str, path: &Path) -> bool {
+        self.process_names.contains(name) || self.process_paths.contains(path)
+    }
+
+    /// Export rules
+    fn export(&self) -> ExportedRules {
+        ExportedRules {
+            ipv4: self.ipv4.iter().collect(),
+            ipv6: self.ipv6.iter().collect(),
+            regex: self
+                .rule_regex
+                .patterns()
+                .iter()
+                .cloned()
+                .chain(self.rule_keywords.iter().map(|k| regex::escape(k)))
+                .collect(),
+            hosts: self.rule_set.iter().cloned().collect(),
+            domains: self.rule_tree.domains(),
+        }
     }
 
     /// Check if there are no rules for IP addresses
     fn is_ip_empty(&self) -> bool {
+        #[cfg(feature = "acl-geoip")]
+        if !self.geoip.is_empty() {
+            return false;
+        }
+
         self.ipv4.is_empty() && self.ipv6.is_empty()
     }
 
     /// Check if there are no rules for domain names
     fn is_host_empty(&self) -> bool {
-        self.rule_set.is_empty() && self.rule_tree.is_empty() && self.rule_regex.is_empty()
+        self.rule_set.is_empty()
+            && self.rule_tree.is_empty()
+            && self.rule_keywords.is_empty()
+            && self.rule_regex.is_empty()
+    }
+
+    /// Check if there are no rules for processes
+    fn is_process_empty(&self) -> bool {
+        self.process_names.is_empty() && self.process_paths.is_empty()
     }
 }
 
+/// Rules of a list, exported for generating the other formats, like PAC
+#[derive(Debug, Clone, Default)]
+pub struct ExportedRules {
+    /// IPv4 networks
+    pub ipv4: Vec<Ipv4Net>,
+    /// IPv6 networks
+    pub ipv6: Vec<Ipv6Net>,
+    /// Regular expressions for matching hosts (in lower case)
+    pub regex: Vec<String>,
+    /// Hosts for exact matching (in lower case)
+    pub hosts: Vec<String>,
+    /// Domains for matching with their sub-domains (in lower case)
+    pub domains: Vec<String>,
+}
+
 struct ParsingRules {
-    name: &'static str,
+    name: Cow<'static, str>,
+    // Line of the rule being added, and lines of all rules
+    line: usize,
+    lines: HashMap<String, usize>,
     ipv4: IpRange<Ipv4Net>,
     ipv6: IpRange<Ipv6Net>,
     rules_regex: Vec<String>,
+    rules_keyword: Vec<String>,
     rules_set: HashSet<String>,
     rules_tree: SubDomainsTree,
+    process_names: HashSet<String>,
+    process_paths: HashSet<PathBuf>,
+    #[cfg(feature = "acl-geoip")]
+    geoip: HashSet<String>,
 }
 
 impl ParsingRules {
-    fn new(name: &'static str) -> Self {
+    fn new<N: Into<Cow<'static, str>>>(name: N) -> Self {
         ParsingRules {
-            name,
+            name: name.into(),
+            line: 0,
+            lines: HashMap::new(),
             ipv4: IpRange::new(),
             ipv6: IpRange::new(),
             rules_regex: Vec::new(),
+            rules_keyword: Vec::new(),
             rules_set: HashSet::new(),
             rules_tree: SubDomainsTree::new(),
+            process_names: HashSet::new(),
+            process_paths: HashSet::new(),
+            #[cfg(feature = "acl-geoip")]
+            geoip: HashSet::new(),
         }
     }
 
+    /// Record line of the rule, in the form of `Rules::matched_rule`
+    fn record_line(&mut self, rule: String) {
+        if self.line > 0 {
+            self.lines.entry(rule).or_insert(self.line);
+        }
+    }
+
+    fn add_process_name_rule(&mut self, name: &str) {
+        trace!("PROCESS-NAME-RULE {}", name);
+        self.record_line(format!("PROCESS-NAME,{}", name));
+        self.process_names.insert(name.to_owned());
+    }
+
+    fn add_process_path_rule(&mut self, path: &str) {
+        trace!("PROCESS-PATH-RULE {}", path);
+        self.record_line(format!("PROCESS-PATH,{}", path));
+        self.process_paths.insert(PathBuf::from(path));
+    }
+
+    #[cfg(feature = "acl-geoip")]
+    fn add_geoip_rule(&mut self, country: &str) {
+        let country = country.to_ascii_uppercase();
+        trace!("GEOIP-RULE {}", country);
+        self.record_line(format!("GEOIP,{}", country));
+        self.geoip.insert(country);
+    }
+
     fn add_ipv4_rule(&mut self, rule: impl Into<Ipv4Net>) {
         let rule = rule.into();
         trace!("IPV4-RULE {}", rule);
+        self.record_line(rule.to_string());
         self.ipv4.add(rule);
     }
 
     fn add_ipv6_rule(&mut self, rule: impl Into<Ipv6Net>) {
         let rule = rule.into();
         trace!("IPV6-RULE {}", rule);
+        self.record_line(rule.to_string());
         self.ipv6.add(rule);
     }
 
@@ -202,15 +477,61 @@ impl ParsingRules {
             }
         }
 
+        // Regular expressions of plain words match like keywords
+        if let Some(keyword) = Self::regex_literal(&rule) {
+            trace!("REGEX-RULE {} => KEYWORD-RULE {}", rule, keyword);
+            let keyword = keyword.to_ascii_lowercase();
+            self.record_line(format!("DOMAIN-KEYWORD,{}", keyword));
+            self.rules_keyword.push(keyword);
+            return;
+        }
+
         trace!("REGEX-RULE {}", rule);
 
         rule.make_ascii_lowercase();
 
         // Handle it as a normal REGEX
         // FIXME: If this line is not a valid regex, how can we know without actually compile it?
+        self.record_line(rule.clone());
         self.rules_regex.push(rule);
     }
 
+    /// Unescaped literal of a regular expression without any meta characters, like `google\.com`
+    fn regex_literal(rule: &str) -> Option<String> {
+        let mut literal = String::with_capacity(rule.len());
+        let mut chars = rule.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '\\' => match chars.next() {
+                    Some(c @ ('.' | '-')) => literal.push(c),
+                    _ => return None,
+                },
+                c if c.is_ascii_alphanumeric() || c == '-' || c == '_' => literal.push(c),
+                _ => return None,
+            }
+        }
+
+        if literal.is_empty() {
+            None
+        } else {
+            Some(literal)
+        }
+    }
+
+    fn add_keyword_rule(&mut self, rule: &str) -> io::Result<()> {
+        trace!("KEYWORD-RULE {}", rule);
+        let keyword = self.check_is_ascii(rule)?.to_ascii_lowercase();
+        if keyword.is_empty() {
+            return Err(Error::new(
+                ErrorKind::Other,
+                format!("{} parsing error: empty keyword", self.name),
+            ));
+        }
+        self.record_line(format!("DOMAIN-KEYWORD,{}", keyword));
+        self.rules_keyword.push(keyword);
+        Ok(())
+    }
+
     #[inline]
     fn add_set_rule(&mut self, rule: &str) -> io::Result<()> {
         trace!("SET-RULE {}", rule);
@@ -218,7 +539,9 @@ impl ParsingRules {
     }
 
     fn add_set_rule_inner(&mut self, rule: &str) -> io::Result<()> {
-        self.rules_set.insert(self.check_is_ascii(rule)?.to_ascii_lowercase());
+        let rule = self.check_is_ascii(rule)?.to_ascii_lowercase();
+        self.record_line(format!("|{}", rule));
+        self.rules_set.insert(rule);
         Ok(())
     }
 
@@ -230,7 +553,9 @@ impl ParsingRules {
 
     fn add_tree_rule_inner(&mut self, rule: &str) -> io::Result<()> {
         // SubDomainsTree do lowercase conversion inside insert
-        self.rules_tree.insert(self.check_is_ascii(rule)?);
+        let rule = self.check_is_ascii(rule)?;
+        self.record_line(format!("||{}", rule.to_ascii_lowercase()));
+        self.rules_tree.insert(rule);
         Ok(())
     }
 
@@ -246,23 +571,173 @@ impl ParsingRules {
         }
     }
 
-    fn compile_regex(name: &'static str, regex_rules: Vec<String>) -> io::Result<RegexSet> {
+    fn compile_regex(&self) -> io::Result<RegexSet> {
         const REGEX_SIZE_LIMIT: usize = usize::MAX;
-        RegexSetBuilder::new(regex_rules)
+        RegexSetBuilder::new(&self.rules_regex)
             .size_limit(REGEX_SIZE_LIMIT)
             .unicode(false)
             .build()
-            .map_err(|err| Error::new(ErrorKind::Other, format!("{} regex error: {}", name, err)))
+            .map_err(|err| {
+                // Find the invalid rule for its line
+                let line = self
+                    .rules_regex
+                    .iter()
+                    .find(|rule| {
+                        RegexBuilder::new(rule)
+                            .size_limit(REGEX_SIZE_LIMIT)
+                            .unicode(false)
+                            .build()
+                            .is_err()
+                    })
+                    .and_then(|rule| self.lines.get(rule));
+                match line {
+                    Some(line) => Error::new(
+                        ErrorKind::Other,
+                        format!("line {}, {} regex error: {}", line, self.name, err),
+                    ),
+                    None => Error::new(ErrorKind::Other, format!("{} regex error: {}", self.name, err)),
+                }
+            })
     }
 
     fn into_rules(self) -> io::Result<Rules> {
-        Ok(Rules::new(
+        let rules_regex = self.compile_regex()?;
+        let mut rules = Rules::new(
             self.ipv4,
             self.ipv6,
-            Self::compile_regex(self.name, self.rules_regex)?,
+            rules_regex,
+            self.rules_keyword,
             self.rules_set,
             self.rules_tree,
-        ))
+        );
+        rules.name = self.name.into_owned();
+        rules.lines = self.lines;
+        rules.process_names = self.process_names;
+        rules.process_paths = self.process_paths;
+
+        #[cfg(feature = "acl-geoip")]
+        {
+            rules.geoip = self.geoip;
+        }
+
+        Ok(rules)
+    }
+}
+
+/// Rules of all sections, parsed from ACL file or the other formats
+struct ParsingAcl {
+    mode: Mode,
+    outbound_block: ParsingRules,
+    rejects: Vec<(RejectMode, ParsingRules)>,
+    bypass: ParsingRules,
+    proxy: ParsingRules,
+    routes: Vec<(String, ParsingRules)>,
+    dns_upstreams: Vec<(String, ParsingRules)>,
+    ip_strategies: Vec<(IpStrategy, ParsingRules)>,
+    resolves: Vec<(ResolveMode, ParsingRules)>,
+    #[cfg(feature = "acl-geoip")]
+    geoip_path: Option<PathBuf>,
+}
+
+impl ParsingAcl {
+    /// Rules of `[route:TAG]`, tags are case-insensitive
+    fn route_rules<'a>(routes: &'a mut Vec<(String, ParsingRules)>, tag: &str) -> &'a mut ParsingRules {
+        let idx = match routes.iter().position(|(t, _)| t.eq_ignore_ascii_case(tag)) {
+            Some(idx) => idx,
+            None => {
+                routes.push((tag.to_owned(), ParsingRules::new(format!("[route:{}]", tag))));
+                routes.len() - 1
+            }
+        };
+        &mut routes[idx].1
+    }
+
+    /// Rules of `[reject:MODE]`, except `[reject]` of `RejectMode::Refuse`
+    fn reject_rules(rejects: &mut Vec<(RejectMode, ParsingRules)>, mode: RejectMode) -> &mut ParsingRules {
+        let idx = match rejects.iter().position(|(m, _)| *m == mode) {
+            Some(idx) => idx,
+            None => {
+                rejects.push((mode, ParsingRules::new(format!("[reject:{}]", mode))));
+                rejects.len() - 1
+            }
+        };
+        &mut rejects[idx].1
+    }
+
+    /// Path of GeoIP database, relative to the directory of ACL file
+    #[cfg(feature = "acl-geoip")]
+    fn geoip_path(acl_path: &Path, path: &str) -> PathBuf {
+        let path = Path::new(path.trim());
+        match acl_path.parent() {
+            Some(dir) => dir.join(path),
+            None => path.to_owned(),
+        }
+    }
+
+    fn into_acl(self, file_path: &Path) -> io::Result<AccessControl> {
+        let mut route_rules = Vec::with_capacity(self.routes.len());
+        for (tag, rules) in self.routes {
+            route_rules.push((tag, rules.into_rules()?));
+        }
+
+        let mut reject_rules = Vec::with_capacity(self.rejects.len());
+        for (mode, rules) in self.rejects {
+            reject_rules.push((mode, rules.into_rules()?));
+        }
+
+        let mut dns_upstream_rules = Vec::with_capacity(self.dns_upstreams.len());
+        for (group, rules) in self.dns_upstreams {
+            dns_upstream_rules.push((group, rules.into_rules()?));
+        }
+
+        let mut ip_strategy_rules = Vec::with_capacity(self.ip_strategies.len());
+        for (strategy, rules) in self.ip_strategies {
+            ip_strategy_rules.push((strategy, rules.into_rules()?));
+        }
+
+        let mut resolve_rules = Vec::with_capacity(self.resolves.len());
+        for (mode, rules) in self.resolves {
+            resolve_rules.push((mode, rules.into_rules()?));
+        }
+
+        #[allow(unused_mut)]
+        let mut acl = AccessControl {
+            outbound_block: self.outbound_block.into_rules()?,
+            rejects: reject_rules,
+            black_list: self.bypass.into_rules()?,
+            white_list: self.proxy.into_rules()?,
+            routes: route_rules,
+            dns_upstreams: dns_upstream_rules,
+            ip_strategies: ip_strategy_rules,
+            resolves: resolve_rules,
+            mode: self.mode,
+            file_path: file_path.to_owned(),
+            #[cfg(feature = "acl-geoip")]
+            geoip_db: None,
+        };
+
+        #[cfg(feature = "acl-geoip")]
+        if acl.rules_mut().any(|rules| !rules.geoip.is_empty()) {
+            let path = match self.geoip_path {
+                Some(p) => p,
+                None => {
+                    return Err(Error::new(
+                        ErrorKind::Other,
+                        "GEOIP rules require a database configured by GEOIP-DATABASE",
+                    ));
+                }
+            };
+
+            let db = Arc::new(GeoIpDatabase::open(path)?);
+            for rules in acl.rules_mut() {
+                if !rules.geoip.is_empty() {
+                    rules.geoip_db = Some(db.clone());
+                }
+            }
+            acl.geoip_db = Some(db);
+        }
+
+        Ok(acl)
     }
 }
 
@@ -292,12 +767,28 @@ impl ParsingRules {
 ///     * `[proxy_all]` - ACL runs in `WhiteList` mode.
 ///     * `[bypass_list]` - Rules for connecting directly
 ///     * `[proxy_list]` - Rules for connecting through proxies
+///     * `[route:TAG]` - Rules for connecting through proxies tagged by `TAG` (matching server's `country`,
+///       `city` or `tags`). Sections are matched in order, and they take precedence over `[bypass_list]`
+///     * `[dns:GROUP]` - Domain names resolved by the DNS upstream group `GROUP` of DNS relay (`dns_upstreams`).
+///       Sections are matched in order, and they take precedence over `[bypass_list]` and `[proxy_list]`
+///     * `[reject:MODE]` - Rules for rejecting connections like `[outbound_block_list]`, answered in `MODE`: `drop`
+///       (closed without replies), `rst` (reset) or `response` (synthetic responses, empty HTTP 403 for port 80 and
+///       `NXDOMAIN` for DNS relay). `[reject]` is the same as `[outbound_block_list]`, which is refused by failure
+///       replies of protocols
+///     * `[resolve:MODE]` - Domain names of proxied targets resolved in `MODE`: `local` (resolved by local DNS, and
+///       servers connect to the resolved address) or `remote` (sent to servers, and never resolved by local DNS, so
+///       IP rules are not checked for them and they are proxied unless bypassed by domain name rules). Sections are
+///       matched in order
 /// - For remote servers (`ssserver`)
 ///     * `[reject_all]` - ACL runs in `BlackList` mode.
 ///     * `[accept_all]` - ACL runs in `WhiteList` mode.
 ///     * `[black_list]` - Rules for rejecting
 ///     * `[white_list]` - Rules for allowing
 ///     * `[outbound_block_list]` - Rules for blocking outbound addresses.
+/// - For both
+///     * `[ip_strategy:STRATEGY]` - Domain names whose resolved addresses are chosen by `STRATEGY` (`prefer_ipv4`,
+///       `ipv6_first`, `ipv4_only` or `ipv6_only`) for outbound connections, instead of `ip_strategy` of the
+///       configuration. Sections are matched in order
 ///
 /// ## Mode
 ///
@@ -312,15 +803,41 @@ impl ParsingRules {
 ///
 /// - CIDR form network addresses, like `10.9.0.32/16`
 /// - IP addresses, like `127.0.0.1` or `::1`
-/// - Regular Expression for matching hosts, like `(^|\.)gmail\.com$`
+/// - Regular Expression for matching hosts, like `(^|\.)gmail\.com$`, or `DOMAIN-REGEX,(^|\.)gmail\.com$`
+/// - `DOMAIN-KEYWORD,google` for hosts containing `google`. Regular expressions of plain words, like `google`, are
+///   matched as keywords
 /// - Domain with preceding `|` for exact matching, like `|google.com`
 /// - Domain with preceding `||` for matching with subdomains, like `||google.com`
+/// - `GEOIP,CC` for IP addresses located in country `CC` (ISO 3166-1 alpha-2, like `CN`), and
+///   `GEOIP,CC,ACTION` for adding it to `[bypass_list]` (`bypass` or `reject`) or `[proxy_list]` (`proxy` or `accept`)
+///   regardless of the current section. Requires feature `acl-geoip`
+/// - `PROCESS-NAME,NAME` or `PROCESS-PATH,PATH` for TCP connections from local processes, by names or paths of their
+///   executables (for `sslocal` on Linux and macOS). They take precedence over rules of target addresses
+///
+/// Addresses of `GEOIP` rules are looked up in the MaxMind DB (like GeoLite2 Country) configured by
+/// `GEOIP-DATABASE,/path/to/GeoLite2-Country.mmdb`, which is relative to the ACL file. `GEOIP` rules are not
+/// exported to PAC.
+///
+/// ## Clash rules
+///
+/// Files with extension `.yaml` or `.yml`, or starting with Clash rules like `DOMAIN-SUFFIX,google.com,PROXY`, are
+/// loaded as Clash rules. `DOMAIN`, `DOMAIN-SUFFIX`, `DOMAIN-KEYWORD`, `DOMAIN-REGEX`, `IP-CIDR`, `IP-CIDR6`,
+/// `PROCESS-NAME`, `PROCESS-PATH`, `GEOIP` and `MATCH` are supported, with policies `DIRECT`, `PROXY`, `REJECT`, `REJECT-DROP` or
+/// names of proxy groups mapped to sections above. Unlike Clash, rules are not matched in order.
 #[derive(Debug, Clone)]
 pub struct AccessControl {
     outbound_block: Rules,
+    rejects: Vec<(RejectMode, Rules)>,
     black_list: Rules,
     white_list: Rules,
+    routes: Vec<(String, Rules)>,
+    dns_upstreams: Vec<(String, Rules)>,
+    ip_strategies: Vec<(IpStrategy, Rules)>,
+    resolves: Vec<(ResolveMode, Rules)>,
     mode: Mode,
+    file_path: PathBuf,
+    #[cfg(feature = "acl-geoip")]
+    geoip_db: Option<Arc<GeoIpDatabase>>,
 }
 
 impl AccessControl {
@@ -328,20 +845,49 @@ impl AccessControl {
     pub fn load_from_file<P: AsRef<Path>>(p: P) -> io::Result<AccessControl> {
         trace!("ACL loading from {:?}", p.as_ref());
 
-        let fp = File::open(p)?;
-        let r = BufReader::new(fp);
+        let content = fs::read_to_string(p.as_ref())?;
+        if clash::is_clash_rules(p.as_ref(), &content) {
+            return clash::parse(p.as_ref(), &content)?.into_acl(p.as_ref());
+        }
+
+        let mut line = 0;
+        match AccessControl::parse_rules(p.as_ref(), &content, &mut line) {
+            Ok(parsing) => parsing.into_acl(p.as_ref()),
+            // Errors of rules are prefixed by their line numbers
+            Err(err) if line > 0 => Err(Error::new(err.kind(), format!("line {}, {}", line, err))),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Parse rules of ACL file `p`, with `lineno` set to the line being parsed, or 0 after all lines are parsed
+    fn parse_rules(p: &Path, content: &str, lineno: &mut usize) -> io::Result<ParsingAcl> {
+        // Only for resolving `GEOIP-DATABASE`
+        #[cfg(not(feature = "acl-geoip"))]
+        let _ = p;
 
         let mut mode = Mode::BlackList;
 
         let mut outbound_block = ParsingRules::new("[outbound_block_list]");
         let mut bypass = ParsingRules::new("[black_list] or [bypass_list]");
         let mut proxy = ParsingRules::new("[white_list] or [proxy_list]");
+        let mut rejects: Vec<(RejectMode, ParsingRules)> = Vec::new();
+        let mut routes: Vec<(String, ParsingRules)> = Vec::new();
+        let mut dns_upstreams: Vec<(String, ParsingRules)> = Vec::new();
+        let mut ip_strategies: Vec<(IpStrategy, ParsingRules)> = Vec::new();
+        let mut resolves: Vec<(ResolveMode, ParsingRules)> = Vec::new();
         let mut curr = &mut bypass;
 
+        // `GEOIP,CC,ACTION` rules, (country, bypassed, line)
+        #[cfg(feature = "acl-geoip")]
+        let mut geoip_actions: Vec<(String, bool, usize)> = Vec::new();
+        #[cfg(feature = "acl-geoip")]
+        let mut geoip_path: Option<PathBuf> = None;
+
         trace!("ACL parsing start from mode {:?} and black_list / bypass_list", mode);
 
-        for line in r.lines() {
-            let line = line?;
+        for (idx, line) in content.lines().enumerate() {
+            *lineno = idx + 1;
+            curr.line = idx + 1;
             if line.is_empty() {
                 continue;
             }
@@ -353,11 +899,49 @@ impl AccessControl {
 
             let line = line.trim();
 
+            if let Some(path) = line.strip_prefix("GEOIP-DATABASE,") {
+                #[cfg(feature = "acl-geoip")]
+                {
+                    geoip_path = Some(ParsingAcl::geoip_path(p, path));
+                    continue;
+                }
+
+                #[cfg(not(feature = "acl-geoip"))]
+                {
+                    let _ = path;
+                    return Err(Error::new(
+                        ErrorKind::Other,
+                        format!("{} requires feature \"acl-geoip\"", line),
+                    ));
+                }
+            }
+
+            // Names and paths of executables may be non-ASCII
+            if let Some(name) = line.strip_prefix("PROCESS-NAME,") {
+                curr.add_process_name_rule(name.trim());
+                continue;
+            }
+
+            if let Some(path) = line.strip_prefix("PROCESS-PATH,") {
+                curr.add_process_path_rule(path.trim());
+                continue;
+            }
+
             if !line.is_ascii() {
                 warn!("ACL rule {} containing non-ASCII characters, skipped", line);
                 continue;
             }
 
+            if let Some(rule) = line.strip_prefix("DOMAIN-KEYWORD,") {
+                curr.add_keyword_rule(rule.trim())?;
+                continue;
+            }
+
+            if let Some(rule) = line.strip_prefix("DOMAIN-REGEX,") {
+                curr.add_regex_rule(rule.trim().to_owned());
+                continue;
+            }
+
             if let Some(rule) = line.strip_prefix("||") {
                 curr.add_tree_rule(rule)?;
                 continue;
@@ -368,6 +952,42 @@ impl AccessControl {
                 continue;
             }
 
+            if let Some(rule) = line.strip_prefix("GEOIP,") {
+                #[cfg(feature = "acl-geoip")]
+                {
+                    let mut parts = rule.split(',').map(str::trim);
+                    let country = parts.next().unwrap_or_default();
+                    if country.len() != 2 || !country.bytes().all(|b| b.is_ascii_alphabetic()) {
+                        return Err(Error::new(
+                            ErrorKind::Other,
+                            format!("{} invalid country code, expecting ISO 3166-1 alpha-2", line),
+                        ));
+                    }
+
+                    match (parts.next(), parts.next()) {
+                        (None, None) => curr.add_geoip_rule(country),
+                        (Some("bypass" | "reject"), None) => geoip_actions.push((country.to_owned(), true, idx + 1)),
+                        (Some("proxy" | "accept"), None) => geoip_actions.push((country.to_owned(), false, idx + 1)),
+                        _ => {
+                            return Err(Error::new(
+                                ErrorKind::Other,
+                                format!("{} invalid action, expecting bypass, proxy, reject or accept", line),
+                            ));
+                        }
+                    }
+                    continue;
+                }
+
+                #[cfg(not(feature = "acl-geoip"))]
+                {
+                    let _ = rule;
+                    return Err(Error::new(
+                        ErrorKind::Other,
+                        format!("{} requires feature \"acl-geoip\"", line),
+                    ));
+                }
+            }
+
             match line {
                 "[reject_all]" | "[bypass_all]" => {
                     mode = Mode::WhiteList;
@@ -377,10 +997,26 @@ impl AccessControl {
                     mode = Mode::BlackList;
                     trace!("switch to mode {:?}", mode);
                 }
-                "[outbound_block_list]" => {
+                "[outbound_block_list]" | "[reject]" => {
                     curr = &mut outbound_block;
                     trace!("loading outbound_block_list");
                 }
+                _ if line.starts_with("[reject:") && line.ends_with(']') => {
+                    let mode = match line["[reject:".len()..line.len() - 1].trim() {
+                        "drop" => RejectMode::Drop,
+                        "rst" => RejectMode::Reset,
+                        "response" => RejectMode::Response,
+                        _ => {
+                            return Err(Error::new(
+                                ErrorKind::Other,
+                                format!("{} invalid mode, expecting drop, rst or response", line),
+                            ));
+                        }
+                    };
+
+                    curr = ParsingAcl::reject_rules(&mut rejects, mode);
+                    trace!("loading {}", line);
+                }
                 "[black_list]" | "[bypass_list]" => {
                     curr = &mut bypass;
                     trace!("loading black_list / bypass_list");
@@ -389,6 +1025,77 @@ impl AccessControl {
                     curr = &mut proxy;
                     trace!("loading white_list / proxy_list");
                 }
+                _ if line.starts_with("[route:") && line.ends_with(']') => {
+                    let tag = line["[route:".len()..line.len() - 1].trim();
+                    if tag.is_empty() {
+                        return Err(Error::new(ErrorKind::Other, format!("{} missing tag", line)));
+                    }
+
+                    curr = ParsingAcl::route_rules(&mut routes, tag);
+                    trace!("loading route:{}", tag);
+                }
+                _ if line.starts_with("[dns:") && line.ends_with(']') => {
+                    let group = line["[dns:".len()..line.len() - 1].trim();
+                    if group.is_empty() {
+                        return Err(Error::new(ErrorKind::Other, format!("{} missing group", line)));
+                    }
+
+                    let idx = match dns_upstreams.iter().position(|(g, _)| g == group) {
+                        Some(idx) => idx,
+                        None => {
+                            dns_upstreams.push((group.to_owned(), ParsingRules::new(format!("[dns:{}]", group))));
+                            dns_upstreams.len() - 1
+                        }
+                    };
+                    curr = &mut dns_upstreams[idx].1;
+                    trace!("loading dns:{}", group);
+                }
+                _ if line.starts_with("[ip_strategy:") && line.ends_with(']') => {
+                    let strategy = match line["[ip_strategy:".len()..line.len() - 1].trim().parse::<IpStrategy>() {
+                        Ok(s) => s,
+                        Err(..) => {
+                            return Err(Error::new(
+                                ErrorKind::Other,
+                                format!(
+                                    "{} invalid strategy, expecting prefer_ipv4, ipv6_first, ipv4_only or ipv6_only",
+                                    line
+                                ),
+                            ));
+                        }
+                    };
+
+                    let idx = match ip_strategies.iter().position(|(s, _)| *s == strategy) {
+                        Some(idx) => idx,
+                        None => {
+                            ip_strategies.push((strategy, ParsingRules::new(format!("[ip_strategy:{}]", strategy))));
+                            ip_strategies.len() - 1
+                        }
+                    };
+                    curr = &mut ip_strategies[idx].1;
+                    trace!("loading ip_strategy:{}", strategy);
+                }
+                _ if line.starts_with("[resolve:") && line.ends_with(']') => {
+                    let mode = match line["[resolve:".len()..line.len() - 1].trim() {
+                        "local" => ResolveMode::Local,
+                        "remote" => ResolveMode::Remote,
+                        _ => {
+                            return Err(Error::new(
+                                ErrorKind::Other,
+                                format!("{} invalid mode, expecting local or remote", line),
+                            ));
+                        }
+                    };
+
+                    let idx = match resolves.iter().position(|(m, _)| *m == mode) {
+                        Some(idx) => idx,
+                        None => {
+                            resolves.push((mode, ParsingRules::new(format!("[resolve:{}]", mode))));
+                            resolves.len() - 1
+                        }
+                    };
+                    curr = &mut resolves[idx].1;
+                    trace!("loading resolve:{}", mode);
+                }
                 _ => {
                     match line.parse::<IpNet>() {
                         Ok(IpNet::V4(v4)) => {
@@ -416,12 +1123,46 @@ impl AccessControl {
             }
         }
 
-        Ok(AccessControl {
-            outbound_block: outbound_block.into_rules()?,
-            black_list: bypass.into_rules()?,
-            white_list: proxy.into_rules()?,
+        *lineno = 0;
+
+        #[cfg(feature = "acl-geoip")]
+        for (country, bypassed, line) in geoip_actions {
+            let rules = if bypassed { &mut bypass } else { &mut proxy };
+            rules.line = line;
+            rules.add_geoip_rule(&country);
+        }
+
+        let parsing = ParsingAcl {
             mode,
-        })
+            outbound_block,
+            rejects,
+            bypass,
+            proxy,
+            routes,
+            dns_upstreams,
+            ip_strategies,
+            resolves,
+            #[cfg(feature = "acl-geoip")]
+            geoip_path,
+        };
+        Ok(parsing)
+    }
+
+    #[cfg(feature = "acl-geoip")]
+    fn rules_mut(&mut self) -> impl Iterator<Item = &mut Rules> {
+        [&mut self.outbound_block, &mut self.black_list, &mut self.white_list]
+            .into_iter()
+            .chain(self.rejects.iter_mut().map(|(_, rules)| rules))
+            .chain(self.routes.iter_mut().map(|(_, rules)| rules))
+            .chain(self.dns_upstreams.iter_mut().map(|(_, rules)| rules))
+            .chain(self.ip_strategies.iter_mut().map(|(_, rules)| rules))
+            .chain(self.resolves.iter_mut().map(|(_, rules)| rules))
+    }
+
+    /// GeoIP database of `GEOIP` rules, `None` if there are no `GEOIP` rules
+    #[cfg(feature = "acl-geoip")]
+    pub fn geoip_database(&self) -> Option<&GeoIpDatabase> {
+        self.geoip_db.as_deref()
     }
 
     /// Check if domain name is in proxy_list.
@@ -488,6 +1229,26 @@ impl AccessControl {
         }
     }
 
+    /// ACL strategy mode
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    /// Path of the file that rules are loaded from
+    pub fn file_path(&self) -> &Path {
+        &self.file_path
+    }
+
+    /// Export rules of `[proxy_list]` or `[white_list]`
+    pub fn export_proxy_list(&self) -> ExportedRules {
+        self.white_list.export()
+    }
+
+    /// Export rules of `[bypass_list]` or `[black_list]`
+    pub fn export_bypass_list(&self) -> ExportedRules {
+        self.black_list.export()
+    }
+
     /// Returns the ASCII representation a domain name,
     /// if conversion fails returns original string
     fn convert_to_ascii(host: &str) -> Cow<str> {
@@ -496,32 +1257,186 @@ impl AccessControl {
             .unwrap_or_else(|_| host.into())
     }
 
+    /// Check if there are any `PROCESS-NAME` or `PROCESS-PATH` rules
+    pub fn has_process_rules(&self) -> bool {
+        [&self.outbound_block, &self.black_list, &self.white_list]
+            .into_iter()
+            .chain(self.rejects.iter().map(|(_, rules)| rules))
+            .chain(self.routes.iter().map(|(_, rules)| rules))
+            .any(|rules| !rules.is_process_empty())
+    }
+
+    /// Action for connections from the local process `name`, whose executable is `path` (for client)
+    ///
+    /// Process rules take precedence over rules of target addresses. Returns `None` if no process rules match
+    pub fn check_process(&self, name: &str, path: &Path) -> Option<ProcessAction<'_>> {
+        if let Some((mode, _)) = self
+            .reject_lists()
+            .find(|(_, rules)| rules.check_process_matched(name, path))
+        {
+            return Some(ProcessAction::Block(mode));
+        }
+        if let Some((tag, _)) = self
+            .routes
+            .iter()
+            .find(|(_, rules)| rules.check_process_matched(name, path))
+        {
+            return Some(ProcessAction::Route(tag));
+        }
+        if self.white_list.check_process_matched(name, path) {
+            return Some(ProcessAction::Proxy);
+        }
+        if self.black_list.check_process_matched(name, path) {
+            return Some(ProcessAction::Bypass);
+        }
+        None
+    }
+
+    /// Check if there are any `[route:TAG]` sections
+    pub fn has_routes(&self) -> bool {
+        !self.routes.is_empty()
+    }
+
+    fn route_ip(&self, ip: &IpAddr) -> Option<&str> {
+        self.routes
+            .iter()
+            .find(|(_, rules)| rules.check_ip_matched(ip))
+            .map(|(tag, _)| tag.as_str())
+    }
+
+    /// Names of DNS upstream groups in `[dns:GROUP]` sections
+    pub fn dns_upstream_groups(&self) -> impl Iterator<Item = &str> {
+        self.dns_upstreams.iter().map(|(group, _)| group.as_str())
+    }
+
+    /// DNS upstream group for resolving an ASCII `host` (for DNS relay), configured by `[dns:GROUP]` sections
+    pub fn dns_upstream_group(&self, host: &str) -> Option<&str> {
+        self.dns_upstreams
+            .iter()
+            .find(|(_, rules)| rules.check_host_matched(host))
+            .map(|(group, _)| group.as_str())
+    }
+
+    /// Strategy of choosing among resolved addresses of `host`, configured by `[ip_strategy:STRATEGY]` sections
+    pub fn ip_strategy(&self, host: &str) -> Option<IpStrategy> {
+        if self.ip_strategies.is_empty() {
+            return None;
+        }
+
+        let host = Self::convert_to_ascii(host);
+        self.ip_strategies
+            .iter()
+            .find(|(_, rules)| rules.check_host_matched(&host))
+            .map(|(strategy, _)| *strategy)
+    }
+
+    /// Where the domain name `host` of a proxied target is resolved, configured by `[resolve:MODE]` sections
+    pub fn resolve_mode(&self, host: &str) -> Option<ResolveMode> {
+        if self.resolves.is_empty() {
+            return None;
+        }
+
+        let host = Self::convert_to_ascii(host);
+        self.resolve_mode_ascii(&host)
+    }
+
+    fn resolve_mode_ascii(&self, host: &str) -> Option<ResolveMode> {
+        self.resolves
+            .iter()
+            .find(|(_, rules)| rules.check_host_matched(host))
+            .map(|(mode, _)| *mode)
+    }
+
+    /// Check if the ASCII `host` shouldn't be resolved by local DNS for checking IP rules
+    fn is_resolved_remotely(&self, host: &str) -> bool {
+        self.resolve_mode_ascii(host) == Some(ResolveMode::Remote)
+    }
+
+    /// Tag of servers for connecting to target address (for client), configured by `[route:TAG]` sections
+    ///
+    /// This function may perform a DNS resolution
+    pub async fn route_tag(&self, context: &Context, addr: &Address) -> Option<&str> {
+        if self.routes.is_empty() {
+            return None;
+        }
+
+        match *addr {
+            Address::SocketAddress(ref addr) => self.route_ip(&addr.ip()),
+            Address::DomainNameAddress(ref host, port) => {
+                let ascii_host = Self::convert_to_ascii(host);
+                for (tag, rules) in self.routes.iter() {
+                    if rules.check_host_matched(&ascii_host) {
+                        return Some(tag);
+                    }
+                }
+                if self.routes.iter().all(|(_, rules)| rules.is_ip_empty()) || self.is_resolved_remotely(&ascii_host) {
+                    return None;
+                }
+                if let Ok(vaddr) = context.dns_resolve(host, port).await {
+                    for addr in vaddr {
+                        if let Some(tag) = self.route_ip(&addr.ip()) {
+                            return Some(tag);
+                        }
+                    }
+                }
+                None
+            }
+        }
+    }
+
     /// Check if target address should be bypassed (for client)
     ///
     /// This function may perform a DNS resolution
     pub async fn check_target_bypassed(&self, context: &Context, addr: &Address) -> bool {
+        self.check_target(context, addr).await.0
+    }
+
+    /// Check if target address should be bypassed (for client), with the rule deciding it
+    ///
+    /// This function may perform a DNS resolution
+    pub async fn check_target(&self, context: &Context, addr: &Address) -> (bool, TargetRule) {
+        // Routed addresses are always proxied
+        if self.route_tag(context, addr).await.is_some() {
+            return (false, TargetRule::Route);
+        }
+
         match *addr {
-            Address::SocketAddress(ref addr) => !self.check_ip_in_proxy_list(&addr.ip()),
+            Address::SocketAddress(ref addr) => self.check_target_ip(&addr.ip()),
             // Resolve hostname and check the list
             Address::DomainNameAddress(ref host, port) => {
-                if let Some(value) = self.check_host_in_proxy_list(host) {
-                    return !value;
+                let ascii_host = Self::convert_to_ascii(host);
+                if let Some(value) = self.check_ascii_host_in_proxy_list(&ascii_host) {
+                    return (!value, TargetRule::Host);
+                }
+                if self.is_resolved_remotely(&ascii_host) {
+                    return (false, TargetRule::Resolve);
                 }
                 if self.is_ip_empty() {
-                    return !self.is_default_in_proxy_list();
+                    return (!self.is_default_in_proxy_list(), TargetRule::Default);
                 }
+                let mut result = (false, TargetRule::Default);
                 if let Ok(vaddr) = context.dns_resolve(host, port).await {
                     for addr in vaddr {
-                        if !self.check_ip_in_proxy_list(&addr.ip()) {
-                            return true;
+                        result = self.check_target_ip(&addr.ip());
+                        if result.0 {
+                            return result;
                         }
                     }
                 }
-                false
+                result
             }
         }
     }
 
+    fn check_target_ip(&self, ip: &IpAddr) -> (bool, TargetRule) {
+        let proxied = self.check_ip_in_proxy_list(ip);
+        if proxied == self.is_default_in_proxy_list() {
+            (!proxied, TargetRule::Default)
+        } else {
+            (!proxied, TargetRule::Ip)
+        }
+    }
+
     /// Check if client address should be blocked (for server)
     pub fn check_client_blocked(&self, addr: &SocketAddr) -> bool {
         match self.mode {
@@ -536,28 +1451,61 @@ impl AccessControl {
         }
     }
 
+    /// Rules of `[outbound_block_list]` and `[reject:MODE]`, with their modes
+    fn reject_lists(&self) -> impl Iterator<Item = (RejectMode, &Rules)> {
+        iter::once((RejectMode::Refuse, &self.outbound_block))
+            .chain(self.rejects.iter().map(|(mode, rules)| (*mode, rules)))
+    }
+
     /// Check if outbound address is blocked (for server)
     ///
     /// NOTE: `Address::DomainName` is only validated by regex rules,
     ///       resolved addresses are checked in the `lookup_outbound_then!` macro
     pub async fn check_outbound_blocked(&self, context: &Context, outbound: &Address) -> bool {
+        self.check_outbound_rejected(context, outbound).await.is_some()
+    }
+
+    /// Check if outbound address is rejected by `[outbound_block_list]` or `[reject:MODE]`, with the mode of answering
+    /// clients (for client)
+    ///
+    /// This function may perform a DNS resolution
+    pub async fn check_outbound_rejected(&self, context: &Context, outbound: &Address) -> Option<RejectMode> {
         match outbound {
-            Address::SocketAddress(saddr) => self.outbound_block.check_ip_matched(&saddr.ip()),
+            Address::SocketAddress(saddr) => self.check_ip_rejected(&saddr.ip()),
             Address::DomainNameAddress(host, port) => {
-                if self.outbound_block.check_host_matched(&Self::convert_to_ascii(host)) {
-                    return true;
+                let ascii_host = Self::convert_to_ascii(host);
+                if let Some(mode) = self.check_host_rejected(&ascii_host) {
+                    return Some(mode);
+                }
+
+                if self.reject_lists().all(|(_, rules)| rules.is_ip_empty()) || self.is_resolved_remotely(&ascii_host) {
+                    return None;
                 }
 
                 if let Ok(vaddr) = context.dns_resolve(host, *port).await {
                     for addr in vaddr {
-                        if self.outbound_block.check_ip_matched(&addr.ip()) {
-                            return true;
+                        if let Some(mode) = self.check_ip_rejected(&addr.ip()) {
+                            return Some(mode);
                         }
                     }
                 }
 
-                false
+                None
             }
         }
     }
+
+    /// Check if ASCII domain name is rejected by `[outbound_block_list]` or `[reject:MODE]`, without resolving it
+    /// (for DNS relay)
+    pub fn check_host_rejected(&self, host: &str) -> Option<RejectMode> {
+        self.reject_lists()
+            .find(|(_, rules)| rules.check_host_matched(host))
+            .map(|(mode, _)| mode)
+    }
+
+    fn check_ip_rejected(&self, ip: &IpAddr) -> Option<RejectMode> {
+        self.reject_lists()
+            .find(|(_, rules)| rules.check_ip_matched(ip))
+            .map(|(mode, _)| mode)
+    }
 }
@@ -68,7 +68,46 @@ impl SubDomainsTree {
         false
     }
 
+    /// The domain in this tree matched by `value`, which is `value` itself or one of its parent domains
+    pub fn matched(&self, value: &str) -> Option<String> {
+        let mut current_map = &self.0;
+        let mut parts = Vec::new();
+        for part in value.rsplit('.') {
+            let el = current_map.get(part)?;
+            parts.push(part);
+            if el.included {
+                parts.reverse();
+                return Some(parts.join("."));
+            }
+            current_map = &el.children;
+        }
+        None
+    }
+
     pub fn is_empty(&self) -> bool {
         self.0.is_empty()
     }
+
+    /// All domains in this tree
+    pub fn domains(&self) -> Vec<String> {
+        fn collect(map: &HashMap<String, DomainPart>, suffix: &str, domains: &mut Vec<String>) {
+            for (part, el) in map {
+                let domain = if suffix.is_empty() {
+                    part.clone()
+                } else {
+                    format!("{}.{}", part, suffix)
+                };
+
+                if el.included {
+                    domains.push(domain);
+                } else {
+                    collect(&el.children, &domain, domains);
+                }
+            }
+        }
+
+        let mut domains = Vec::new();
+        collect(&self.0, "", &mut domains);
+        domains
+    }
 }
@@ -0,0 +1,87 @@
+//! Socket activation, listening on sockets passed by the service manager
+//!
+//! Service managers like systemd bind the sockets of `.socket` units, and pass them to the started process as file
+//! descriptors from 3, with `LISTEN_FDS` of the number of sockets and `LISTEN_PID` of the process' pid. They are
+//! inherited like sockets handed off by an old process, so that binds to the same addresses reuse them instead of
+//! creating new ones. Sockets are kept open by the service manager while the process restarts, connections are queued
+//! instead of being refused.
+
+use std::{
+    env,
+    io::{self, ErrorKind},
+    os::unix::io::{FromRawFd, RawFd},
+    process,
+};
+
+use log::{info, warn};
+use shadowsocks::net::handoff::{self, HandoffListener, ListenerKind};
+use socket2::{Socket, Type};
+
+/// The first file descriptor passed (`SD_LISTEN_FDS_START`)
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Inherit listening sockets passed by the service manager, returns the number of them
+///
+/// Variables of the environment are removed, so they are not passed to child processes like plugins. This should be
+/// called before creating servers.
+pub fn inherit_listeners() -> io::Result<usize> {
+    let pid = match env::var("LISTEN_PID") {
+        Ok(pid) => pid,
+        Err(..) => return Ok(0),
+    };
+    let fds = env::var("LISTEN_FDS").unwrap_or_default();
+
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+
+    // Passed to another process, which has started this one
+    match pid.parse::<u32>() {
+        Ok(pid) if pid == process::id() => {}
+        Ok(..) => return Ok(0),
+        Err(..) => {
+            let err = io::Error::new(ErrorKind::InvalidData, format!("invalid LISTEN_PID {:?}", pid));
+            return Err(err);
+        }
+    }
+
+    let count = match fds.parse::<RawFd>() {
+        Ok(n) if n >= 0 => n,
+        _ => {
+            let err = io::Error::new(ErrorKind::InvalidData, format!("invalid LISTEN_FDS {:?}", fds));
+            return Err(err);
+        }
+    };
+
+    let mut listeners = Vec::with_capacity(count as usize);
+    for fd in LISTEN_FDS_START..LISTEN_FDS_START + count {
+        // Passed without FD_CLOEXEC
+        if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let socket = unsafe { Socket::from_raw_fd(fd) };
+        let kind = match socket.r#type()? {
+            Type::STREAM => ListenerKind::Tcp,
+            Type::DGRAM => ListenerKind::Udp,
+            ty => {
+                warn!("activated socket {} of type {:?} is not supported, closing", fd, ty);
+                continue;
+            }
+        };
+        let addr = match socket.local_addr()?.as_socket() {
+            Some(addr) => addr,
+            None => {
+                warn!("activated socket {} is not an IP socket, closing", fd);
+                continue;
+            }
+        };
+
+        info!("listening on activated {:?} socket {}", kind, addr);
+        listeners.push(HandoffListener { kind, addr, socket });
+    }
+
+    let count = listeners.len();
+    handoff::inherit(listeners);
+    Ok(count)
+}
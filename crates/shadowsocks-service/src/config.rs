@@ -40,43 +40,127 @@
 //! ```
 //!
 //! These defined server will be used with a load balancing algorithm.
+//!
+//! Configuration files could also be written in YAML or TOML, detected by their extensions, with the same keys.
 
+#[cfg(feature = "local-dns")]
+use std::collections::HashMap;
 use std::{
     borrow::Cow,
+    collections::BTreeMap,
     convert::{From, Infallible},
     default::Default,
     env,
     fmt::{self, Debug, Display, Formatter},
-    fs::OpenOptions,
+    fs::{self, OpenOptions},
     io::Read,
     net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6},
     option::Option,
     path::{Path, PathBuf},
     str::FromStr,
     string::ToString,
+    sync::Arc,
     time::Duration,
 };
 
 use cfg_if::cfg_if;
-#[cfg(feature = "local-tun")]
+#[cfg(any(feature = "local-tun", feature = "server"))]
 use ipnet::IpNet;
+#[cfg(feature = "local-dns")]
+use ipnet::{Ipv4Net, Ipv6Net};
 use log::warn;
-use serde::{Deserialize, Serialize};
-#[cfg(any(feature = "local-tunnel", feature = "local-dns"))]
-use shadowsocks::relay::socks5::Address;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+#[cfg(feature = "aead-cipher-2022")]
+use shadowsocks::config::{ServerUser, ServerUserManager};
+#[cfg(feature = "transport-grpc")]
+use shadowsocks::net::grpc::GrpcConfig;
+#[cfg(feature = "transport-quic")]
+use shadowsocks::net::quic::QuicConfig;
+#[cfg(feature = "transport-shadow-tls")]
+use shadowsocks::net::shadow_tls::ShadowTlsConfig;
+#[cfg(unix)]
+use shadowsocks::net::SocketProtector;
+#[cfg(feature = "stream-padding")]
+use shadowsocks::relay::tcprelay::padding::{PaddingConfig, DEFAULT_MAX_PADDING, MAX_FRAME_DATA_SIZE};
 use shadowsocks::{
-    config::{ManagerAddr, Mode, ReplayAttackPolicy, ServerAddr, ServerConfig, ServerWeight},
-    crypto::v1::CipherKind,
+    config::{
+        password_to_key,
+        ManagerAddr,
+        Mode,
+        RateLimitConfig,
+        ReplayAttackPolicy,
+        ServerAddr,
+        ServerConfig,
+        ServerListenAddr,
+        ServerWeight,
+    },
+    crypto::CipherKind,
+    net::{IpStrategy, PortHopping, TcpTuning, UpstreamProxy},
     plugin::PluginConfig,
+    relay::{
+        buffer_pool::{BufferPoolConfig, RelayBufferSizes},
+        mux::{
+            MuxConfig,
+            DEFAULT_IDLE_TIMEOUT as DEFAULT_MUX_IDLE_TIMEOUT,
+            DEFAULT_MAX_STREAMS as DEFAULT_MUX_MAX_STREAMS,
+            MAX_CONNECTION_SESSIONS,
+        },
+        socks5::Address,
+    },
 };
 #[cfg(feature = "trust-dns")]
 use trust_dns_resolver::config::{NameServerConfig, Protocol, ResolverConfig};
 
+#[cfg(feature = "acl")]
 use crate::acl::AccessControl;
+#[cfg(feature = "local-dns-over-https")]
+use crate::local::dns::DnsOverHttpsAddr;
+#[cfg(any(feature = "local-dns-over-tls", feature = "local-dns-over-quic"))]
+use crate::local::dns::SecureNameServerAddr;
 #[cfg(feature = "local-dns")]
-use crate::local::dns::NameServerAddr;
+use crate::local::dns::{
+    DnsCacheConfig,
+    DnsHostsConfig,
+    FakeIpConfig,
+    NameServerAddr,
+    NameServerStrategy,
+    StaticRecordConfig,
+    StaticRecordData,
+};
+#[cfg(feature = "local-dns-dnssec")]
+use crate::local::dns::{DnssecAdPolicy, DnssecConfig};
+#[cfg(feature = "local-fault-injection")]
+use crate::local::fault::FaultInjectionConfig;
+#[cfg(feature = "local")]
+use crate::local::flow_export::FlowExportConfig;
+#[cfg(feature = "local-http")]
+use crate::local::http::config::{HttpAuthConfig, HttpCacheConfig};
+#[cfg(feature = "local")]
+use crate::local::net::{UdpNatConfig, UdpNatFiltering, UdpPortTimeout};
+#[cfg(feature = "local-online-config")]
+use crate::local::online_config::{OnlineConfig, DEFAULT_UPDATE_INTERVAL};
+#[cfg(feature = "local")]
+use crate::local::priority::{PriorityClassConfig, PriorityConfig};
 #[cfg(feature = "local")]
-use crate::local::socks::config::Socks5AuthConfig;
+use crate::local::resolver_cache::ResolverCacheConfig;
+#[cfg(feature = "local")]
+use crate::local::routing_mode::RoutingMode;
+#[cfg(feature = "local")]
+use crate::local::socks::config::{Socks5AuthConfig, SocksHostnamePolicy};
+#[cfg(feature = "local-tun")]
+use crate::local::tun::config::{TunTcpConfig, TunTcpProfile};
+#[cfg(feature = "local-tunnel")]
+use crate::local::tunnel::TunnelMapping;
+#[cfg(feature = "server")]
+use crate::server::{
+    client_limit::{ClientLimitConfig, ClientLimitPolicy},
+    firewall::{FirewallAction, FirewallConfig, FirewallProtocol, FirewallRule},
+    udp_eviction::{UdpEvictionConfig, UdpEvictionPolicy},
+};
+use crate::{
+    net::{P2pPolicy, PortRange},
+    store::StateStore,
+};
 
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(untagged)]
@@ -86,16 +170,85 @@ enum SSDnsConfig {
     TrustDns(ResolverConfig),
 }
 
+impl SSDnsConfig {
+    fn from_config(dns: &DnsConfig) -> SSDnsConfig {
+        match *dns {
+            DnsConfig::System => SSDnsConfig::Simple("system".to_owned()),
+            #[cfg(feature = "trust-dns")]
+            DnsConfig::TrustDns(ref dns) => SSDnsConfig::TrustDns(dns.clone()),
+            #[cfg(feature = "local-dns")]
+            DnsConfig::LocalDns(ref ns) => SSDnsConfig::Simple(ns.to_string()),
+        }
+    }
+}
+
+/// Files included by `include`, merged before the configuration including them
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+enum SSIncludeConfig {
+    Single(String),
+    Multiple(Vec<String>),
+}
+
+impl SSIncludeConfig {
+    fn into_paths(self) -> Vec<String> {
+        match self {
+            SSIncludeConfig::Single(p) => vec![p],
+            SSIncludeConfig::Multiple(p) => p,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct SSIncludingConfig {
+    #[serde(default)]
+    include: Option<SSIncludeConfig>,
+}
+
 #[derive(Serialize, Deserialize, Debug, Default)]
 struct SSSecurityConfig {
     #[serde(skip_serializing_if = "Option::is_none")]
     replay_attack: Option<SSSecurityReplayAttackConfig>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    clock_skew: Option<SSSecurityClockSkewConfig>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct SSSecurityClockSkewConfig {
+    /// In seconds
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tolerance: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ntp_server: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Default)]
 struct SSSecurityReplayAttackConfig {
     #[serde(skip_serializing_if = "Option::is_none")]
     policy: Option<String>,
+    #[cfg(feature = "server")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cluster: Option<SSReplayClusterConfig>,
+    #[cfg(feature = "server")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    persist: Option<SSReplayPersistConfig>,
+}
+
+#[cfg(feature = "server")]
+#[derive(Serialize, Deserialize, Debug)]
+struct SSReplayClusterConfig {
+    listen_address: String,
+    peers: Vec<String>,
+    password: String,
+}
+
+#[cfg(feature = "server")]
+#[derive(Serialize, Deserialize, Debug)]
+struct SSReplayPersistConfig {
+    path: String,
+    /// In seconds
+    #[serde(skip_serializing_if = "Option::is_none")]
+    interval: Option<u64>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Default)]
@@ -106,6 +259,64 @@ struct SSBalancerConfig {
     check_interval: Option<u64>,
     #[serde(skip_serializing_if = "Option::is_none")]
     check_best_interval: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    strategy: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    failback_window: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    groups: Option<BTreeMap<String, SSBalancerGroupConfig>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    default_group: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    probe: Option<SSBalancerProbeConfig>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    scoring: Option<SSBalancerScoringConfig>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    warm_pool: Option<SSBalancerWarmPoolConfig>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct SSBalancerGroupConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    strategy: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct SSBalancerScoringConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    algorithm: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ewma_alpha: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    percentile: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rtt_weight: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    failure_weight: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    variation_weight: Option<f64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct SSBalancerWarmPoolConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    size: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    servers: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    idle_timeout: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct SSBalancerProbeConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    protocol: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    target: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    udp_target: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    samples: Option<usize>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Default)]
@@ -127,10 +338,15 @@ struct SSConfig {
     manager_address: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     manager_port: Option<u16>,
+    /// Pre-shared key of manager requests
+    #[serde(skip_serializing_if = "Option::is_none")]
+    manager_key: Option<String>,
 
     #[serde(skip_serializing_if = "Option::is_none")]
     password: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
+    password_file: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     method: Option<String>,
 
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -139,6 +355,8 @@ struct SSConfig {
     plugin_opts: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     plugin_args: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    plugin_mode: Option<String>,
 
     #[serde(skip_serializing_if = "Option::is_none")]
     timeout: Option<u64>,
@@ -147,1603 +365,6083 @@ struct SSConfig {
     udp_timeout: Option<u64>,
     #[serde(skip_serializing_if = "Option::is_none")]
     udp_max_associations: Option<usize>,
+    #[cfg(feature = "local")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    udp_nat: Option<SSUdpNatConfig>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    udp_migration: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    direct_fallback: Option<bool>,
+    #[cfg(feature = "local")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    routing_mode: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    route_debug: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tcp_splice: Option<bool>,
+    #[cfg(feature = "acl")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    acl_trace: Option<bool>,
+    #[cfg(feature = "metrics")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    metrics_address: Option<String>,
 
     #[serde(skip_serializing_if = "Option::is_none", alias = "shadowsocks")]
     servers: Option<Vec<SSServerExtConfig>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    server_urls_file: Option<String>,
 
     #[serde(skip_serializing_if = "Option::is_none")]
-    locals: Option<Vec<SSLocalExtConfig>>,
+    udp_over_tcp: Option<bool>,
 
     #[serde(skip_serializing_if = "Option::is_none")]
-    dns: Option<SSDnsConfig>,
+    mux: Option<SSMuxConfig>,
 
+    #[cfg(feature = "stream-padding")]
     #[serde(skip_serializing_if = "Option::is_none")]
-    mode: Option<String>,
+    padding: Option<SSPaddingConfig>,
 
     #[serde(skip_serializing_if = "Option::is_none")]
-    no_delay: Option<bool>,
+    port_hopping: Option<SSPortHoppingConfig>,
+
     #[serde(skip_serializing_if = "Option::is_none")]
-    keep_alive: Option<u64>,
+    upstream_proxy: Option<String>,
 
-    #[cfg(all(unix, not(target_os = "android")))]
     #[serde(skip_serializing_if = "Option::is_none")]
-    nofile: Option<u64>,
+    listen_addresses: Option<Vec<String>>,
 
     #[serde(skip_serializing_if = "Option::is_none")]
-    ipv6_first: Option<bool>,
+    listeners: Option<usize>,
+
+    #[cfg(feature = "aead-cipher-2022")]
     #[serde(skip_serializing_if = "Option::is_none")]
-    ipv6_only: Option<bool>,
+    users: Option<Vec<SSServerUserConfig>>,
 
+    /// In bytes
     #[serde(skip_serializing_if = "Option::is_none")]
-    fast_open: Option<bool>,
+    quota: Option<u64>,
 
     #[serde(skip_serializing_if = "Option::is_none")]
-    security: Option<SSSecurityConfig>,
+    rate_limit: Option<SSRateLimitConfig>,
 
+    #[cfg(feature = "server")]
     #[serde(skip_serializing_if = "Option::is_none")]
-    balancer: Option<SSBalancerConfig>,
-}
+    outbound_firewall: Option<SSFirewallConfig>,
 
-#[derive(Serialize, Deserialize, Debug, Default)]
-struct SSLocalExtConfig {
+    #[cfg(feature = "server")]
     #[serde(skip_serializing_if = "Option::is_none")]
-    local_address: Option<String>,
+    client_limit: Option<SSClientLimitConfig>,
+
+    #[cfg(feature = "server")]
     #[serde(skip_serializing_if = "Option::is_none")]
-    local_port: Option<u16>,
+    udp_eviction: Option<SSUdpEvictionConfig>,
 
+    #[cfg(feature = "server")]
     #[serde(skip_serializing_if = "Option::is_none")]
-    disabled: Option<bool>,
+    drain_timeout: Option<u64>,
 
+    #[cfg(feature = "manager-http-api")]
     #[serde(skip_serializing_if = "Option::is_none")]
-    mode: Option<String>,
+    manager_http_api: Option<SSManagerHttpApiConfig>,
 
+    #[cfg(feature = "manager-sip008")]
     #[serde(skip_serializing_if = "Option::is_none")]
-    local_udp_address: Option<String>,
+    manager_sip008: Option<SSManagerSip008Config>,
+
+    #[cfg(feature = "manager-web-ui")]
     #[serde(skip_serializing_if = "Option::is_none")]
-    local_udp_port: Option<u16>,
+    manager_web_ui: Option<SSManagerWebUiConfig>,
 
+    /// "host:port"
     #[serde(skip_serializing_if = "Option::is_none")]
-    protocol: Option<String>,
+    fallback: Option<String>,
 
-    /// TCP Transparent Proxy type
-    #[cfg(feature = "local-redir")]
     #[serde(skip_serializing_if = "Option::is_none")]
-    tcp_redir: Option<String>,
-    /// UDP Transparent Proxy type
-    #[cfg(feature = "local-redir")]
+    outbound_bind_interface: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
-    udp_redir: Option<String>,
+    outbound_bind_addr: Option<String>,
 
-    /// Local DNS's address
-    ///
-    /// Sending DNS query directly to this address
-    #[cfg(feature = "local-dns")]
     #[serde(skip_serializing_if = "Option::is_none")]
-    local_dns_address: Option<String>,
-    #[cfg(feature = "local-dns")]
+    tuning: Option<SSTuningConfig>,
+
     #[serde(skip_serializing_if = "Option::is_none")]
-    local_dns_port: Option<u16>,
-    /// Remote DNS's address
-    ///
-    /// Sending DNS query through proxy to this address
-    #[cfg(feature = "local-dns")]
+    locals: Option<Vec<SSLocalExtConfig>>,
+
     #[serde(skip_serializing_if = "Option::is_none")]
-    remote_dns_address: Option<String>,
-    #[cfg(feature = "local-dns")]
+    dns: Option<SSDnsConfig>,
+
     #[serde(skip_serializing_if = "Option::is_none")]
-    remote_dns_port: Option<u16>,
+    server_dns: Option<SSDnsConfig>,
 
-    /// Tunnel
-    #[cfg(feature = "local-tunnel")]
     #[serde(skip_serializing_if = "Option::is_none")]
-    forward_address: Option<String>,
-    #[cfg(feature = "local-tunnel")]
+    mode: Option<String>,
+
     #[serde(skip_serializing_if = "Option::is_none")]
-    forward_port: Option<u16>,
+    no_delay: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    keep_alive: Option<u64>,
 
-    /// Tun
-    #[cfg(feature = "local-tun")]
+    #[cfg(all(unix, not(target_os = "android")))]
     #[serde(skip_serializing_if = "Option::is_none")]
-    tun_interface_name: Option<String>,
-    #[cfg(feature = "local-tun")]
+    nofile: Option<u64>,
+
+    #[cfg(feature = "io-uring")]
     #[serde(skip_serializing_if = "Option::is_none")]
-    tun_interface_address: Option<String>,
+    io_uring: Option<bool>,
 
-    /// SOCKS5
-    #[cfg(feature = "local")]
     #[serde(skip_serializing_if = "Option::is_none")]
-    socks5_auth_config_path: Option<String>,
-}
+    buffer_pool: Option<SSBufferPoolConfig>,
 
-#[derive(Serialize, Deserialize, Debug)]
-struct SSServerExtConfig {
-    // SIP008 https://github.com/shadowsocks/shadowsocks-org/issues/89
-    //
-    // `address` and `port` are non-standard field name only for shadowsocks-rust
-    #[serde(alias = "address")]
-    server: String,
-    #[serde(alias = "port")]
-    server_port: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    udp_offload: Option<bool>,
 
-    password: String,
-    method: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ipv6_first: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ipv6_only: Option<bool>,
+    /// "prefer_ipv4", "ipv6_first", "ipv4_only" or "ipv6_only"
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ip_strategy: Option<String>,
 
     #[serde(skip_serializing_if = "Option::is_none")]
-    disabled: Option<bool>,
+    fast_open: Option<bool>,
 
     #[serde(skip_serializing_if = "Option::is_none")]
-    plugin: Option<String>,
+    security: Option<SSSecurityConfig>,
+
     #[serde(skip_serializing_if = "Option::is_none")]
-    plugin_opts: Option<String>,
+    balancer: Option<SSBalancerConfig>,
+
     #[serde(skip_serializing_if = "Option::is_none")]
-    plugin_args: Option<Vec<String>>,
+    isolate_locals: Option<bool>,
 
     #[serde(skip_serializing_if = "Option::is_none")]
-    timeout: Option<u64>,
+    p2p_policy: Option<String>,
 
-    #[serde(skip_serializing_if = "Option::is_none", alias = "name")]
-    remarks: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
-    id: Option<String>,
+    state_dir: Option<String>,
+    #[cfg(feature = "local")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    export_connections: Option<bool>,
+    #[cfg(feature = "local")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    system_proxy: Option<bool>,
+    #[cfg(feature = "local")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    control_address: Option<String>,
+    /// Pre-shared key of control commands
+    #[cfg(feature = "local")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    control_key: Option<String>,
+    #[cfg(feature = "local")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    flow_export: Option<SSFlowExportConfig>,
 
+    #[cfg(unix)]
     #[serde(skip_serializing_if = "Option::is_none")]
-    mode: Option<String>,
+    handoff: Option<SSHandoffConfig>,
 
+    #[cfg(feature = "local")]
     #[serde(skip_serializing_if = "Option::is_none")]
-    tcp_weight: Option<f32>,
+    priority: Option<SSPriorityConfig>,
+
+    #[cfg(feature = "local")]
     #[serde(skip_serializing_if = "Option::is_none")]
-    udp_weight: Option<f32>,
-}
+    resolver_cache: Option<SSResolverCacheConfig>,
 
-/// Server config type
-#[derive(Clone, Copy, Debug, Eq, PartialEq)]
-pub enum ConfigType {
-    /// Config for local
-    Local,
+    #[cfg(feature = "local-fault-injection")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    fault_injection: Option<SSFaultInjectionConfig>,
 
-    /// Config for server
-    Server,
+    #[cfg(feature = "local-online-config")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    online_config: Option<SSOnlineConfig>,
 
-    /// Config for Manager server
-    Manager,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    profiles: Option<BTreeMap<String, SSProfileConfig>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    profile: Option<String>,
 }
 
-impl ConfigType {
-    /// Check if it is local server type
-    pub fn is_local(self) -> bool {
-        self == ConfigType::Local
-    }
+impl SSConfig {
+    /// Replace `locals`, `servers` and `balancer` with the ones of profile `name`, returns the profile's ACL path
+    fn apply_profile(&mut self, name: &str) -> Result<Option<String>, Error> {
+        let profile = match self.profiles.as_mut().and_then(|p| p.remove(name)) {
+            Some(p) => p,
+            None => {
+                let err = Error::new(
+                    ErrorKind::Malformed,
+                    "profile not found in `profiles`",
+                    Some(name.to_owned()),
+                );
+                return Err(err);
+            }
+        };
 
-    /// Check if it is remote server type
-    pub fn is_server(self) -> bool {
-        self == ConfigType::Server
-    }
+        if profile.locals.is_some() {
+            self.locals = profile.locals;
+        }
+        if profile.servers.is_some() {
+            self.servers = profile.servers;
+        }
+        if profile.balancer.is_some() {
+            self.balancer = profile.balancer;
+        }
 
-    /// Check if it is manager server type
-    pub fn is_manager(self) -> bool {
-        self == ConfigType::Manager
+        Ok(profile.acl)
     }
 }
 
-cfg_if! {
-    if #[cfg(feature = "local-redir")] {
-        /// Transparent Proxy type
-        #[derive(Clone, Copy, Debug, Eq, PartialEq)]
-        pub enum RedirType {
-            /// For not supported platforms
-            NotSupported,
-
-            /// For Linux-like systems' Netfilter `REDIRECT`. Only for TCP connections.
-            ///
-            /// This is supported from Linux 2.4 Kernel. Document: <https://www.netfilter.org/documentation/index.html#documentation-howto>
-            ///
-            /// NOTE: Filter rule `REDIRECT` can only be applied to TCP connections.
-            #[cfg(any(target_os = "linux", target_os = "android"))]
-            Redirect,
-
-            /// For Linux-like systems' Netfilter TPROXY rule.
-            ///
-            /// NOTE: Filter rule `TPROXY` can be applied to TCP and UDP connections.
-            #[cfg(any(target_os = "linux", target_os = "android"))]
-            TProxy,
-
-            /// Packet Filter (pf)
-            ///
-            /// Supported by OpenBSD 3.0+, FreeBSD 5.3+, NetBSD 3.0+, Solaris 11.3+, macOS 10.7+, iOS, QNX
-            ///
-            /// Document: <https://www.freebsd.org/doc/handbook/firewalls-pf.html>
-            #[cfg(any(
-                target_os = "openbsd",
-                target_os = "freebsd",
-                target_os = "netbsd",
-                target_os = "solaris",
-                target_os = "macos",
-                target_os = "ios"
-            ))]
-            PacketFilter,
-
-            /// IPFW
-            ///
-            /// Supported by FreeBSD, macOS 10.6- (Have been removed completely on macOS 10.10)
-            ///
-            /// Document: https://www.freebsd.org/doc/handbook/firewalls-ipfw.html
-            #[cfg(any(target_os = "freebsd", target_os = "macos", target_os = "ios"))]
-            IpFirewall,
-        }
-
-        impl RedirType {
-            cfg_if! {
-                if #[cfg(any(target_os = "linux", target_os = "android"))] {
-                    /// Default TCP transparent proxy solution on this platform
-                    pub const fn tcp_default() -> RedirType {
-                        RedirType::Redirect
-                    }
-
-                    /// Available TCP transparent proxy types
-                    #[doc(hidden)]
-                    pub fn tcp_available_types() -> &'static [&'static str] {
-                        const AVAILABLE_TYPES: &[&str] = &[RedirType::Redirect.name(), RedirType::TProxy.name()];
-                        AVAILABLE_TYPES
-                    }
-
-                    /// Default UDP transparent proxy solution on this platform
-                    pub const fn udp_default() -> RedirType {
-                        RedirType::TProxy
-                    }
-
-                    /// Available UDP transparent proxy types
-                    #[doc(hidden)]
-                    pub fn udp_available_types() -> &'static [&'static str] {
-                        const AVAILABLE_TYPES: &[&str] = &[RedirType::TProxy.name()];
-                        AVAILABLE_TYPES
-                    }
-                } else if #[cfg(any(target_os = "openbsd", target_os = "freebsd"))] {
-                    /// Default TCP transparent proxy solution on this platform
-                    pub fn tcp_default() -> RedirType {
-                        RedirType::PacketFilter
-                    }
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct SSBufferPoolConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    buffer_size: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_buffers: Option<usize>,
+}
 
-                    /// Available TCP transparent proxy types
-                    #[doc(hidden)]
-                    pub fn tcp_available_types() -> &'static [&'static str] {
-                        const AVAILABLE_TYPES: &[&str] = &[RedirType::PacketFilter.name(), RedirType::IpFirewall.name()];
-                        AVAILABLE_TYPES
-                    }
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct SSProfileConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    locals: Option<Vec<SSLocalExtConfig>>,
+    #[serde(skip_serializing_if = "Option::is_none", alias = "shadowsocks")]
+    servers: Option<Vec<SSServerExtConfig>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    balancer: Option<SSBalancerConfig>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    acl: Option<String>,
+}
 
-                    /// Default UDP transparent proxy solution on this platform
-                    pub fn udp_default() -> RedirType {
-                        RedirType::PacketFilter
-                    }
+#[cfg(unix)]
+#[derive(Serialize, Deserialize, Debug)]
+struct SSHandoffConfig {
+    path: String,
+    /// In seconds
+    #[serde(skip_serializing_if = "Option::is_none")]
+    drain_timeout: Option<u64>,
+}
 
-                    /// Available UDP transparent proxy types
-                    #[doc(hidden)]
-                    pub const fn udp_available_types() -> &'static [&'static str] {
-                        const AVAILABLE_TYPES: &[&str] = &[RedirType::PacketFilter.name(), RedirType::IpFirewall.name()];
-                        AVAILABLE_TYPES
-                    }
-                } else if #[cfg(any(target_os = "netbsd", target_os = "solaris", target_os = "macos", target_os = "ios"))] {
-                    /// Default TCP transparent proxy solution on this platform
-                    pub fn tcp_default() -> RedirType {
-                        RedirType::PacketFilter
-                    }
+#[cfg(feature = "local")]
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct SSPriorityConfig {
+    /// In bytes per second
+    #[serde(skip_serializing_if = "Option::is_none")]
+    upload_bandwidth: Option<u64>,
+    /// In bytes per second
+    #[serde(skip_serializing_if = "Option::is_none")]
+    download_bandwidth: Option<u64>,
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    classes: Vec<SSPriorityClassConfig>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    default_weight: Option<u32>,
+}
 
-                    /// Available TCP transparent proxy types
-                    #[doc(hidden)]
-                    pub const fn tcp_available_types() -> &'static [&'static str] {
-                        const AVAILABLE_TYPES: &[&str] = &[RedirType::PacketFilter.name(), RedirType::IpFirewall.name()];
-                        AVAILABLE_TYPES
-                    }
+#[cfg(feature = "local")]
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct SSResolverCacheConfig {
+    /// In seconds, 0 disables the cache
+    #[serde(skip_serializing_if = "Option::is_none")]
+    negative_min_ttl: Option<u64>,
+    /// In seconds
+    #[serde(skip_serializing_if = "Option::is_none")]
+    negative_max_ttl: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    size: Option<usize>,
+}
 
-                    /// Default UDP transparent proxy solution on this platform
-                    pub fn udp_default() -> RedirType {
-                        RedirType::NotSupported
-                    }
+#[cfg(feature = "local")]
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct SSPriorityClassConfig {
+    weight: u32,
+    /// `PORT` or `START-END`
+    ports: Vec<String>,
+}
 
-                    /// Available UDP transparent proxy types
-                    #[doc(hidden)]
-                    pub const fn udp_available_types() -> &'static [&'static str] {
-                        const AVAILABLE_TYPES: &[&str] = &[];
-                        AVAILABLE_TYPES
-                    }
-                } else {
-                    /// Default TCP transparent proxy solution on this platform
-                    pub fn tcp_default() -> RedirType {
-                        RedirType::NotSupported
-                    }
+#[cfg(feature = "local")]
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct SSUdpNatConfig {
+    /// `full_cone`, `address_restricted` or `port_restricted`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    filtering: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    timeouts: Vec<SSUdpPortTimeoutConfig>,
+}
 
-                    /// Available TCP transparent proxy types
-                    #[doc(hidden)]
-                    pub const fn tcp_available_types() -> &'static [&'static str] {
-                        const AVAILABLE_TYPES: &[&str] = &[];
-                        AVAILABLE_TYPES
-                    }
+#[cfg(feature = "local")]
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct SSUdpPortTimeoutConfig {
+    /// `PORT` or `START-END`
+    ports: Vec<String>,
+    /// In seconds
+    timeout: u64,
+}
 
-                    /// Default UDP transparent proxy solution on this platform
-                    pub fn udp_default() -> RedirType {
-                        RedirType::NotSupported
-                    }
+#[cfg(feature = "local")]
+impl SSUdpNatConfig {
+    fn build(&self) -> Result<UdpNatConfig, Error> {
+        let mut nat = UdpNatConfig::default();
 
-                    /// Available UDP transparent proxy types
-                    #[doc(hidden)]
-                    pub const fn udp_available_types() -> &'static [&'static str] {
-                        const AVAILABLE_TYPES: &[&str] = &[];
-                        AVAILABLE_TYPES
-                    }
+        if let Some(ref filtering) = self.filtering {
+            match filtering.parse::<UdpNatFiltering>() {
+                Ok(f) => nat.filtering = f,
+                Err(..) => {
+                    let err = Error::new(
+                        ErrorKind::Malformed,
+                        "`udp_nat.filtering` invalid, expecting \"full_cone\", \"address_restricted\" or \"port_restricted\"",
+                        Some(format!("filtering: {}", filtering)),
+                    );
+                    return Err(err);
                 }
             }
+        }
 
-            /// Check if transparent proxy is supported on this platform
-            pub fn is_supported(self) -> bool {
-                self != RedirType::NotSupported
+        for timeout in &self.timeouts {
+            if timeout.timeout == 0 {
+                let err = Error::new(
+                    ErrorKind::Malformed,
+                    "`udp_nat.timeouts` timeout must be greater than 0",
+                    None,
+                );
+                return Err(err);
             }
 
-            /// Name of redirect type (transparent proxy type)
-            pub const fn name(self) -> &'static str {
-                match self {
-                    // Dummy, shouldn't be used in any useful situations
-                    RedirType::NotSupported => "not_supported",
-
-                    #[cfg(any(target_os = "linux", target_os = "android"))]
-                    RedirType::Redirect => "redirect",
-
-                    #[cfg(any(target_os = "linux", target_os = "android"))]
-                    RedirType::TProxy => "tproxy",
-
-                    #[cfg(any(
-                        target_os = "openbsd",
-                        target_os = "freebsd",
-                        target_os = "netbsd",
-                        target_os = "solaris",
-                        target_os = "macos",
-                        target_os = "ios"
-                    ))]
-                    RedirType::PacketFilter => "pf",
-
-                    #[cfg(any(target_os = "freebsd", target_os = "macos", target_os = "ios"))]
-                    RedirType::IpFirewall => "ipfw",
+            let mut ports = Vec::with_capacity(timeout.ports.len());
+            for port in &timeout.ports {
+                match port.parse::<PortRange>() {
+                    Ok(p) => ports.push(p),
+                    Err(..) => {
+                        let err = Error::new(
+                            ErrorKind::Malformed,
+                            "`udp_nat.timeouts` invalid ports",
+                            Some(format!("ports: {}", port)),
+                        );
+                        return Err(err);
+                    }
                 }
             }
-        }
 
-        impl Display for RedirType {
-            fn fmt(&self, f: &mut Formatter) -> fmt::Result {
-                f.write_str(self.name())
-            }
+            nat.timeouts.push(UdpPortTimeout {
+                ports,
+                timeout: Duration::from_secs(timeout.timeout),
+            });
         }
 
-        /// Error type for `RedirType`'s `FromStr::Err`
-        #[derive(Debug)]
-        pub struct InvalidRedirType;
+        Ok(nat)
+    }
 
-        impl Display for InvalidRedirType {
-            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-                f.write_str("invalid RedirType")
-            }
+    fn from_config(nat: &UdpNatConfig) -> SSUdpNatConfig {
+        SSUdpNatConfig {
+            filtering: if nat.filtering != UdpNatFiltering::default() {
+                Some(nat.filtering.to_string())
+            } else {
+                None
+            },
+            timeouts: nat
+                .timeouts
+                .iter()
+                .map(|t| SSUdpPortTimeoutConfig {
+                    ports: t.ports.iter().map(ToString::to_string).collect(),
+                    timeout: t.timeout.as_secs(),
+                })
+                .collect(),
         }
+    }
+}
 
-        impl FromStr for RedirType {
-            type Err = InvalidRedirType;
+#[cfg(feature = "local")]
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct SSFlowExportConfig {
+    collector: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    observation_domain_id: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    enterprise_number: Option<u32>,
+}
 
-            fn from_str(s: &str) -> Result<RedirType, InvalidRedirType> {
-                match s {
-                    #[cfg(any(target_os = "linux", target_os = "android"))]
-                    "redirect" => Ok(RedirType::Redirect),
+#[cfg(feature = "local-fault-injection")]
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct SSFaultInjectionConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    udp_drop_rate: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    connect_delay: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tunnel_kill_after_bytes: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    seed: Option<u64>,
+}
 
-                    #[cfg(any(target_os = "linux", target_os = "android"))]
-                    "tproxy" => Ok(RedirType::TProxy),
+#[cfg(feature = "local-online-config")]
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct SSOnlineConfig {
+    config_url: String,
+    /// In seconds
+    #[serde(skip_serializing_if = "Option::is_none")]
+    update_interval: Option<u64>,
+}
 
-                    #[cfg(any(
-                        target_os = "openbsd",
-                        target_os = "freebsd",
-                        target_os = "netbsd",
-                        target_os = "solaris",
-                        target_os = "macos",
-                        target_os = "ios",
-                    ))]
-                    "pf" => Ok(RedirType::PacketFilter),
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct SSLocalExtConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    local_address: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    local_port: Option<u16>,
 
-                    #[cfg(any(
-                        target_os = "freebsd",
-                        target_os = "macos",
-                        target_os = "ios",
-                        target_os = "dragonfly"
-                    ))]
-                    "ipfw" => Ok(RedirType::IpFirewall),
+    #[serde(skip_serializing_if = "Option::is_none")]
+    disabled: Option<bool>,
 
-                    _ => Err(InvalidRedirType),
-                }
+    #[serde(skip_serializing_if = "Option::is_none")]
+    mode: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    local_udp_address: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    local_udp_port: Option<u16>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    protocol: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tenant: Option<String>,
+
+    /// Path of the ACL file, overriding the global ACL
+    #[serde(skip_serializing_if = "Option::is_none")]
+    acl: Option<String>,
+    /// Group of servers for targets that are not routed by ACL, overriding `balancer.default_group`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    server_group: Option<String>,
+    /// Resolver of targets, overriding the global `dns`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dns: Option<SSDnsConfig>,
+    /// Overriding the global `ipv6_first` for resolving targets
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ipv6_first: Option<bool>,
+
+    /// Uses global `p2p_policy` if not specified
+    #[serde(skip_serializing_if = "Option::is_none")]
+    p2p_policy: Option<String>,
+
+    /// Retries of binding `local_port` if it is in use
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bind_retries: Option<u32>,
+    /// Ports to bind if `local_port` is still in use after retries
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bind_fallback_ports: Option<Vec<u16>>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    outbound_bind_interface: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    outbound_bind_addr: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tuning: Option<SSTuningConfig>,
+
+    /// Timeout of UDP associations in seconds, overriding the global `udp_timeout`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    udp_timeout: Option<u64>,
+    /// Maximum number of UDP associations, overriding the global `udp_max_associations`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    udp_max_associations: Option<usize>,
+    /// NAT behavior of UDP associations, overriding the global `udp_nat`
+    #[cfg(feature = "local")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    udp_nat: Option<SSUdpNatConfig>,
+
+    /// Permission bits of the Unix Domain Socket, in octal
+    #[cfg(unix)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    unix_socket_mode: Option<String>,
+
+    /// TCP Transparent Proxy type
+    #[cfg(feature = "local-redir")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tcp_redir: Option<String>,
+    /// UDP Transparent Proxy type
+    #[cfg(feature = "local-redir")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    udp_redir: Option<String>,
+    /// Number of Transparent Proxy listeners binding to the same address with `SO_REUSEPORT`
+    #[cfg(feature = "local-redir")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    redir_listeners: Option<usize>,
+    /// Relay bypassed TCP connections of Transparent Proxy in kernel with eBPF sockmap
+    #[cfg(feature = "local-redir-ebpf")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    redir_ebpf: Option<bool>,
+    /// Install firewall rules of Transparent Proxy with `iptables` or `nftables`
+    #[cfg(feature = "local-redir")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    redir_firewall: Option<String>,
+    /// Copy TTL of clients' TCP connections onto bypassed connections, for Transparent Proxy and tun
+    #[cfg(any(feature = "local-redir", feature = "local-tun"))]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    preserve_ttl: Option<bool>,
+
+    /// Local DNS's address
+    ///
+    /// Sending DNS query directly to this address
+    #[cfg(feature = "local-dns")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    local_dns_address: Option<String>,
+    #[cfg(feature = "local-dns")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    local_dns_port: Option<u16>,
+    /// IP address of the host of encrypted `local_dns_address`
+    #[cfg(any(feature = "local-dns-over-tls", feature = "local-dns-over-quic"))]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    local_dns_bootstrap: Option<String>,
+    /// Timeout of queries to local DNS, in seconds
+    #[cfg(feature = "local-dns")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    local_dns_timeout: Option<u64>,
+    /// More name servers queried directly along with `local_dns_address`
+    #[cfg(feature = "local-dns")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    local_dns_extra_addresses: Option<Vec<String>>,
+    /// Strategy of querying `local_dns_address` and `local_dns_extra_addresses`
+    #[cfg(feature = "local-dns")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    local_dns_strategy: Option<String>,
+    /// Remote DNS's address
+    ///
+    /// Sending DNS query through proxy to this address
+    #[cfg(feature = "local-dns")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    remote_dns_address: Option<String>,
+    #[cfg(feature = "local-dns")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    remote_dns_port: Option<u16>,
+    /// IP address of the host of encrypted `remote_dns_address`
+    #[cfg(any(
+        feature = "local-dns-over-https",
+        feature = "local-dns-over-tls",
+        feature = "local-dns-over-quic"
+    ))]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    remote_dns_bootstrap: Option<String>,
+    /// Timeout of queries to remote DNS, in seconds
+    #[cfg(feature = "local-dns")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    remote_dns_timeout: Option<u64>,
+    /// Cache of DNS responses
+    #[cfg(feature = "local-dns")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dns_cache: Option<SSLocalDnsCacheConfig>,
+    /// Answer proxied names with fake IPs
+    #[cfg(feature = "local-dns")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    fake_ip: Option<SSLocalFakeIpConfig>,
+    /// DNS upstream groups referred by ACL's `[dns:GROUP]` sections
+    #[cfg(feature = "local-dns")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dns_upstreams: Option<BTreeMap<String, Vec<String>>>,
+    /// Static records answered before consulting upstreams
+    #[cfg(feature = "local-dns")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dns_hosts: Option<SSLocalDnsHostsConfig>,
+    /// DNSSEC validation of answers from upstreams
+    #[cfg(feature = "local-dns-dnssec")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dnssec: Option<SSLocalDnssecConfig>,
+    /// DNS-over-TLS listener with `tls`
+    #[cfg(feature = "local-dns-tls")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dns_over_tls_address: Option<String>,
+    /// DNS-over-HTTPS listener with `tls`
+    #[cfg(feature = "local-dns-tls")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dns_over_https_address: Option<String>,
+
+    /// Tunnel
+    #[cfg(feature = "local-tunnel")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    forward_address: Option<String>,
+    #[cfg(feature = "local-tunnel")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    forward_port: Option<u16>,
+    #[cfg(feature = "local-tunnel")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tunnel_mappings: Option<Vec<SSLocalTunnelMappingConfig>>,
+
+    /// Tun
+    #[cfg(feature = "local-tun")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tun_interface_name: Option<String>,
+    #[cfg(feature = "local-tun")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tun_interface_address: Option<String>,
+    #[cfg(feature = "local-tun")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tun_tcp: Option<SSLocalTunTcpConfig>,
+
+    /// PAC
+    #[cfg(feature = "local-pac")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pac_template_path: Option<String>,
+
+    /// SOCKS5
+    #[cfg(feature = "local")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    socks5_auth_config_path: Option<String>,
+    /// Handling of host names in SOCKS requests, "forward", "resolve" or "reject"
+    #[cfg(feature = "local")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    socks_hostname: Option<String>,
+
+    /// HTTP
+    #[cfg(feature = "local-http")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    http_auth_config_path: Option<String>,
+    #[cfg(feature = "local-http")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    http_cache: Option<SSLocalHttpCacheConfig>,
+
+    /// TLS
+    #[cfg(any(feature = "local-socks-tls", feature = "local-http-tls", feature = "local-dns-tls"))]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tls: Option<SSLocalTlsConfig>,
+}
+
+#[cfg(feature = "local-http")]
+#[derive(Serialize, Deserialize, Debug)]
+struct SSLocalHttpCacheConfig {
+    size: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_object_size: Option<usize>,
+}
+
+#[cfg(feature = "local-dns")]
+#[derive(Serialize, Deserialize, Debug)]
+struct SSLocalDnsCacheConfig {
+    size: usize,
+    /// In seconds
+    #[serde(skip_serializing_if = "Option::is_none")]
+    min_ttl: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    prefetch: Option<bool>,
+}
+
+#[cfg(feature = "local-dns")]
+#[derive(Serialize, Deserialize, Debug)]
+struct SSLocalFakeIpConfig {
+    ipv4_range: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ipv6_range: Option<String>,
+    /// In seconds
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ttl: Option<u64>,
+}
+
+#[cfg(feature = "local-dns-dnssec")]
+#[derive(Serialize, Deserialize, Debug)]
+struct SSLocalDnssecConfig {
+    /// `validated` or `clear`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ad_bit: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    builtin_trust_anchors: Option<bool>,
+    /// DNSKEY records
+    #[serde(skip_serializing_if = "Option::is_none")]
+    trust_anchors: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    trust_anchors_file: Option<String>,
+}
+
+#[cfg(feature = "local-dns")]
+#[derive(Serialize, Deserialize, Debug)]
+struct SSLocalDnsHostsConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    records: Option<Vec<SSLocalStaticRecordConfig>>,
+    /// Path of the hosts file, or `system`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    hosts_file: Option<String>,
+    /// In seconds
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ttl: Option<u64>,
+}
+
+#[cfg(feature = "local-dns")]
+#[derive(Serialize, Deserialize, Debug)]
+struct SSLocalStaticRecordConfig {
+    name: String,
+    #[serde(rename = "type")]
+    rtype: String,
+    value: String,
+}
+
+#[cfg(feature = "local-tunnel")]
+#[derive(Serialize, Deserialize, Debug)]
+struct SSLocalTunnelMappingConfig {
+    /// Binds to loopback address if not specified, just like `local_address`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    local_address: Option<String>,
+    local_port: u16,
+    forward_address: String,
+    forward_port: u16,
+    /// Uses `mode` of the tunnel local if not specified
+    #[serde(skip_serializing_if = "Option::is_none")]
+    mode: Option<String>,
+}
+
+#[cfg(feature = "local-tun")]
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct SSLocalTunTcpConfig {
+    /// "lan" or "wan"
+    #[serde(skip_serializing_if = "Option::is_none")]
+    profile: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    send_buffer_size: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    recv_buffer_size: Option<u32>,
+    /// In milliseconds, 0 disables delayed ACK
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ack_delay: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    nagle: Option<bool>,
+    /// In seconds
+    #[serde(skip_serializing_if = "Option::is_none")]
+    timeout: Option<u64>,
+}
+
+#[cfg(any(
+    feature = "local-socks-tls",
+    feature = "local-http-tls",
+    feature = "local-dns-tls",
+    feature = "manager-sip008"
+))]
+#[derive(Serialize, Deserialize, Debug)]
+struct SSLocalTlsConfig {
+    certificate: String,
+    private_key: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    client_ca: Option<String>,
+}
+
+#[cfg(feature = "transport-shadow-tls")]
+#[derive(Serialize, Deserialize, Debug)]
+struct SSShadowTlsConfig {
+    password: String,
+    sni: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    handshake_server: Option<String>,
+}
+
+#[cfg(feature = "transport-quic")]
+#[derive(Serialize, Deserialize, Debug)]
+struct SSQuicConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    server_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    certificate: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    private_key: Option<String>,
+}
+
+#[cfg(feature = "transport-grpc")]
+#[derive(Serialize, Deserialize, Debug)]
+struct SSGrpcConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    service_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    server_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tls: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    certificate: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    private_key: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_streams: Option<usize>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct SSMuxConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_streams: Option<usize>,
+    /// In seconds
+    #[serde(skip_serializing_if = "Option::is_none")]
+    idle_timeout: Option<u64>,
+}
+
+impl SSMuxConfig {
+    fn build(&self) -> Result<MuxConfig, Error> {
+        let max_streams = self.max_streams.unwrap_or(DEFAULT_MUX_MAX_STREAMS);
+        if !(1..=MAX_CONNECTION_SESSIONS).contains(&max_streams) {
+            let err = Error::new(
+                ErrorKind::Invalid,
+                "invalid `max_streams` of `mux`",
+                Some(format!("must be in [1, {}]", MAX_CONNECTION_SESSIONS)),
+            );
+            return Err(err);
+        }
+
+        let idle_timeout = match self.idle_timeout {
+            Some(0) => {
+                let err = Error::new(ErrorKind::Malformed, "`idle_timeout` of `mux` must be > 0", None);
+                return Err(err);
             }
+            Some(t) => Duration::from_secs(t),
+            None => DEFAULT_MUX_IDLE_TIMEOUT,
+        };
+
+        Ok(MuxConfig::new(max_streams, idle_timeout))
+    }
+
+    fn from_config(config: &MuxConfig) -> SSMuxConfig {
+        SSMuxConfig {
+            max_streams: Some(config.max_streams()),
+            idle_timeout: Some(config.idle_timeout().as_secs()),
         }
     }
 }
 
-/// Host for servers to bind
-///
-/// Servers will bind to a port of this host
-#[derive(Clone, Debug)]
-pub enum ManagerServerHost {
-    /// Domain name
-    Domain(String),
-    /// IP address
-    Ip(IpAddr),
+#[derive(Serialize, Deserialize, Debug)]
+struct SSPortHoppingConfig {
+    /// "START-END"
+    ports: String,
+    /// In seconds
+    #[serde(skip_serializing_if = "Option::is_none")]
+    interval: Option<u64>,
 }
 
-impl Default for ManagerServerHost {
-    fn default() -> ManagerServerHost {
-        ManagerServerHost::Ip(Ipv4Addr::UNSPECIFIED.into())
-    }
+impl SSPortHoppingConfig {
+    fn build(&self, port: u16) -> Result<PortHopping, Error> {
+        let ports = match self.ports.parse::<PortRange>() {
+            Ok(PortRange(ports)) if *ports.start() > 0 => ports,
+            _ => {
+                let err = Error::new(
+                    ErrorKind::Malformed,
+                    "invalid `ports` of `port_hopping`",
+                    Some(format!(
+                        "expecting \"START-END\" of ports > 0, but found \"{}\"",
+                        self.ports
+                    )),
+                );
+                return Err(err);
+            }
+        };
+        if !ports.contains(&port) {
+            let err = Error::new(
+                ErrorKind::Invalid,
+                "`server_port` must be in `ports` of `port_hopping`",
+                Some(format!("{} is not in {}", port, self.ports)),
+            );
+            return Err(err);
+        }
+        let mut config = PortHopping::new(ports, port);
+
+        match self.interval {
+            Some(0) => {
+                let err = Error::new(ErrorKind::Malformed, "`interval` of `port_hopping` must be > 0", None);
+                return Err(err);
+            }
+            Some(interval) => config.set_interval(Duration::from_secs(interval)),
+            None => {}
+        }
+
+        Ok(config)
+    }
+
+    fn from_config(config: &PortHopping) -> SSPortHoppingConfig {
+        SSPortHoppingConfig {
+            ports: PortRange(config.ports()).to_string(),
+            interval: config.interval().map(|i| i.as_secs()),
+        }
+    }
+}
+
+#[cfg(feature = "stream-padding")]
+#[derive(Serialize, Deserialize, Debug)]
+struct SSPaddingConfig {
+    /// In bytes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_padding: Option<usize>,
+    /// In bytes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_burst: Option<usize>,
+    /// In milliseconds
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_jitter: Option<u64>,
+}
+
+#[cfg(feature = "stream-padding")]
+impl SSPaddingConfig {
+    fn build(&self) -> Result<PaddingConfig, Error> {
+        let max_padding = self.max_padding.unwrap_or(DEFAULT_MAX_PADDING);
+        if max_padding > u16::MAX as usize {
+            let err = Error::new(
+                ErrorKind::Invalid,
+                "invalid `max_padding` of `padding`",
+                Some(format!("must be in [0, {}]", u16::MAX)),
+            );
+            return Err(err);
+        }
+        let mut config = PaddingConfig::new(max_padding);
+
+        if let Some(max_burst) = self.max_burst {
+            if max_burst > MAX_FRAME_DATA_SIZE {
+                let err = Error::new(
+                    ErrorKind::Invalid,
+                    "invalid `max_burst` of `padding`",
+                    Some(format!("must be in [0, {}]", MAX_FRAME_DATA_SIZE)),
+                );
+                return Err(err);
+            }
+            config.set_max_burst(max_burst);
+        }
+
+        if let Some(max_jitter) = self.max_jitter {
+            if max_jitter > 0 && config.max_burst() == 0 {
+                let err = Error::new(
+                    ErrorKind::Invalid,
+                    "`max_jitter` of `padding` requires `max_burst`",
+                    None,
+                );
+                return Err(err);
+            }
+            config.set_max_jitter(Duration::from_millis(max_jitter));
+        }
+
+        Ok(config)
+    }
+
+    fn from_config(config: &PaddingConfig) -> SSPaddingConfig {
+        SSPaddingConfig {
+            max_padding: Some(config.max_padding()),
+            max_burst: if config.max_burst() > 0 {
+                Some(config.max_burst())
+            } else {
+                None
+            },
+            max_jitter: if config.max_jitter().is_zero() {
+                None
+            } else {
+                Some(config.max_jitter().as_millis() as u64)
+            },
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct SSTuningConfig {
+    /// In bytes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    relay_read_buffer_size: Option<usize>,
+    /// In bytes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    relay_write_buffer_size: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    send_buffer_size: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    recv_buffer_size: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    no_delay: Option<bool>,
+    /// In seconds
+    #[serde(skip_serializing_if = "Option::is_none")]
+    keep_alive: Option<u64>,
+    /// In seconds
+    #[serde(skip_serializing_if = "Option::is_none")]
+    keep_alive_interval: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    keep_alive_count: Option<u32>,
+    /// In seconds
+    #[serde(skip_serializing_if = "Option::is_none")]
+    user_timeout: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    congestion: Option<String>,
+    /// In bytes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    notsent_lowat: Option<u32>,
+}
+
+impl SSTuningConfig {
+    fn build(&self) -> Result<(TcpTuning, RelayBufferSizes), Error> {
+        if self.relay_read_buffer_size == Some(0)
+            || self.relay_write_buffer_size == Some(0)
+            || self.send_buffer_size == Some(0)
+            || self.recv_buffer_size == Some(0)
+        {
+            let err = Error::new(
+                ErrorKind::Malformed,
+                "`tuning` buffer sizes must be greater than 0",
+                None,
+            );
+            return Err(err);
+        }
+
+        if self.keep_alive == Some(0)
+            || self.keep_alive_interval == Some(0)
+            || self.keep_alive_count == Some(0)
+            || self.user_timeout == Some(0)
+        {
+            let err = Error::new(
+                ErrorKind::Malformed,
+                "`tuning` keep_alive, keep_alive_interval, keep_alive_count and user_timeout must be greater than 0",
+                None,
+            );
+            return Err(err);
+        }
+
+        if let Some(ref congestion) = self.congestion {
+            // Names of congestion control algorithms are limited by TCP_CA_NAME_MAX (16) with the NUL terminator
+            if congestion.is_empty() || congestion.len() > 15 {
+                let err = Error::new(
+                    ErrorKind::Malformed,
+                    "`tuning` congestion must be a name of 1 to 15 bytes, like \"bbr\"",
+                    None,
+                );
+                return Err(err);
+            }
+        }
+
+        let tcp_tuning = TcpTuning {
+            send_buffer_size: self.send_buffer_size,
+            recv_buffer_size: self.recv_buffer_size,
+            nodelay: self.no_delay,
+            keepalive: self.keep_alive.map(Duration::from_secs),
+            keepalive_interval: self.keep_alive_interval.map(Duration::from_secs),
+            keepalive_retries: self.keep_alive_count,
+            user_timeout: self.user_timeout.map(Duration::from_secs),
+            congestion: self.congestion.clone(),
+            notsent_lowat: self.notsent_lowat,
+        };
+        let relay_buffer_sizes = RelayBufferSizes {
+            read: self.relay_read_buffer_size,
+            write: self.relay_write_buffer_size,
+        };
+        Ok((tcp_tuning, relay_buffer_sizes))
+    }
+
+    /// `None` if nothing is tuned
+    fn from_config(tcp_tuning: &TcpTuning, relay_buffer_sizes: RelayBufferSizes) -> Option<SSTuningConfig> {
+        if tcp_tuning.is_empty() && relay_buffer_sizes.is_default() {
+            return None;
+        }
+
+        Some(SSTuningConfig {
+            relay_read_buffer_size: relay_buffer_sizes.read,
+            relay_write_buffer_size: relay_buffer_sizes.write,
+            send_buffer_size: tcp_tuning.send_buffer_size,
+            recv_buffer_size: tcp_tuning.recv_buffer_size,
+            no_delay: tcp_tuning.nodelay,
+            keep_alive: tcp_tuning.keepalive.map(|d| d.as_secs()),
+            keep_alive_interval: tcp_tuning.keepalive_interval.map(|d| d.as_secs()),
+            keep_alive_count: tcp_tuning.keepalive_retries,
+            user_timeout: tcp_tuning.user_timeout.map(|d| d.as_secs()),
+            congestion: tcp_tuning.congestion.clone(),
+            notsent_lowat: tcp_tuning.notsent_lowat,
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct SSRateLimitConfig {
+    /// In bytes per second, only in the root object
+    #[serde(skip_serializing_if = "Option::is_none")]
+    global: Option<u64>,
+    /// In bytes per second
+    #[serde(skip_serializing_if = "Option::is_none")]
+    server: Option<u64>,
+    /// In bytes per second
+    #[serde(skip_serializing_if = "Option::is_none")]
+    client: Option<u64>,
+    /// In bytes per second
+    #[serde(skip_serializing_if = "Option::is_none")]
+    user: Option<u64>,
+}
+
+impl SSRateLimitConfig {
+    /// Limits of a server, `None` if there is no limit
+    fn build(&self) -> Result<Option<RateLimitConfig>, Error> {
+        let rates = [
+            (self.global, "`global` of `rate_limit` must be > 0"),
+            (self.server, "`server` of `rate_limit` must be > 0"),
+            (self.client, "`client` of `rate_limit` must be > 0"),
+            (self.user, "`user` of `rate_limit` must be > 0"),
+        ];
+        for (rate, desc) in rates {
+            if rate == Some(0) {
+                return Err(Error::new(ErrorKind::Malformed, desc, None));
+            }
+        }
+
+        let config = RateLimitConfig {
+            server: self.server,
+            client: self.client,
+            user: self.user,
+        };
+        if config == RateLimitConfig::default() {
+            return Ok(None);
+        }
+        Ok(Some(config))
+    }
+
+    fn from_config(config: &RateLimitConfig) -> SSRateLimitConfig {
+        SSRateLimitConfig {
+            global: None,
+            server: config.server,
+            client: config.client,
+            user: config.user,
+        }
+    }
+}
+
+#[cfg(feature = "server")]
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct SSFirewallConfig {
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    rules: Vec<SSFirewallRuleConfig>,
+    /// "allow" (default) or "deny"
+    #[serde(skip_serializing_if = "Option::is_none")]
+    default_action: Option<String>,
 }
 
-impl FromStr for ManagerServerHost {
-    type Err = Infallible;
+#[cfg(feature = "server")]
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct SSFirewallRuleConfig {
+    /// "allow" or "deny"
+    action: String,
+    /// "tcp" or "udp"
+    #[serde(skip_serializing_if = "Option::is_none")]
+    protocol: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    cidrs: Vec<String>,
+    /// `PORT` or `START-END`
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    ports: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    users: Vec<String>,
+}
+
+#[cfg(feature = "server")]
+impl SSFirewallConfig {
+    fn build(self) -> Result<FirewallConfig, Error> {
+        let mut config = FirewallConfig::default();
+
+        if let Some(ref action) = self.default_action {
+            config.default_action = match action.parse::<FirewallAction>() {
+                Ok(a) => a,
+                Err(..) => {
+                    let err = Error::new(
+                        ErrorKind::Malformed,
+                        "`default_action` of `outbound_firewall` must be \"allow\" or \"deny\"",
+                        Some(action.clone()),
+                    );
+                    return Err(err);
+                }
+            };
+        }
+
+        for rule in self.rules {
+            let action = match rule.action.parse::<FirewallAction>() {
+                Ok(a) => a,
+                Err(..) => {
+                    let err = Error::new(
+                        ErrorKind::Malformed,
+                        "`action` of `outbound_firewall.rules` must be \"allow\" or \"deny\"",
+                        Some(rule.action),
+                    );
+                    return Err(err);
+                }
+            };
+
+            let protocol = match rule.protocol {
+                None => None,
+                Some(protocol) => match protocol.parse::<FirewallProtocol>() {
+                    Ok(p) => Some(p),
+                    Err(..) => {
+                        let err = Error::new(
+                            ErrorKind::Malformed,
+                            "`protocol` of `outbound_firewall.rules` must be \"tcp\" or \"udp\"",
+                            Some(protocol),
+                        );
+                        return Err(err);
+                    }
+                },
+            };
+
+            let mut cidrs = Vec::with_capacity(rule.cidrs.len());
+            for cidr in rule.cidrs {
+                // Single addresses are allowed without prefix lengths
+                match cidr
+                    .parse::<IpNet>()
+                    .or_else(|_| cidr.parse::<IpAddr>().map(IpNet::from))
+                {
+                    Ok(n) => cidrs.push(n),
+                    Err(..) => {
+                        let err = Error::new(
+                            ErrorKind::Malformed,
+                            "`outbound_firewall.rules` invalid cidrs",
+                            Some(format!("cidrs: {}", cidr)),
+                        );
+                        return Err(err);
+                    }
+                }
+            }
+
+            let mut ports = Vec::with_capacity(rule.ports.len());
+            for port in rule.ports {
+                match port.parse::<PortRange>() {
+                    Ok(p) => ports.push(p),
+                    Err(..) => {
+                        let err = Error::new(
+                            ErrorKind::Malformed,
+                            "`outbound_firewall.rules` invalid ports",
+                            Some(format!("ports: {}", port)),
+                        );
+                        return Err(err);
+                    }
+                }
+            }
+
+            config.rules.push(FirewallRule {
+                action,
+                protocol,
+                cidrs,
+                ports,
+                users: rule.users,
+            });
+        }
+
+        Ok(config)
+    }
+
+    fn from_config(config: &FirewallConfig) -> SSFirewallConfig {
+        SSFirewallConfig {
+            rules: config
+                .rules
+                .iter()
+                .map(|r| SSFirewallRuleConfig {
+                    action: r.action.to_string(),
+                    protocol: r.protocol.map(|p| p.to_string()),
+                    cidrs: r.cidrs.iter().map(ToString::to_string).collect(),
+                    ports: r.ports.iter().map(ToString::to_string).collect(),
+                    users: r.users.clone(),
+                })
+                .collect(),
+            default_action: if config.default_action != FirewallAction::default() {
+                Some(config.default_action.to_string())
+            } else {
+                None
+            },
+        }
+    }
+}
+
+#[cfg(feature = "server")]
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct SSClientLimitConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_tcp_connections: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_udp_associations: Option<usize>,
+    /// "deny" (default) or "lru"
+    #[serde(skip_serializing_if = "Option::is_none")]
+    policy: Option<String>,
+}
+
+#[cfg(feature = "server")]
+impl SSClientLimitConfig {
+    fn build(self) -> Result<ClientLimitConfig, Error> {
+        let policy = match self.policy {
+            None => ClientLimitPolicy::default(),
+            Some(policy) => match policy.parse::<ClientLimitPolicy>() {
+                Ok(p) => p,
+                Err(..) => {
+                    let err = Error::new(
+                        ErrorKind::Malformed,
+                        "`policy` of `client_limit` must be \"deny\" or \"lru\"",
+                        Some(policy),
+                    );
+                    return Err(err);
+                }
+            },
+        };
+
+        Ok(ClientLimitConfig {
+            max_tcp_connections: self.max_tcp_connections,
+            max_udp_associations: self.max_udp_associations,
+            policy,
+        })
+    }
+
+    fn from_config(config: &ClientLimitConfig) -> SSClientLimitConfig {
+        SSClientLimitConfig {
+            max_tcp_connections: config.max_tcp_connections,
+            max_udp_associations: config.max_udp_associations,
+            policy: if config.policy != ClientLimitPolicy::default() {
+                Some(config.policy.to_string())
+            } else {
+                None
+            },
+        }
+    }
+}
+
+#[cfg(feature = "server")]
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct SSUdpEvictionConfig {
+    /// "lru" (default) or "oldest_idle"
+    #[serde(skip_serializing_if = "Option::is_none")]
+    policy: Option<String>,
+    /// In seconds
+    #[serde(skip_serializing_if = "Option::is_none")]
+    min_idle: Option<u64>,
+}
+
+#[cfg(feature = "server")]
+impl SSUdpEvictionConfig {
+    fn build(self) -> Result<UdpEvictionConfig, Error> {
+        let mut config = UdpEvictionConfig::default();
+
+        if let Some(policy) = self.policy {
+            match policy.parse::<UdpEvictionPolicy>() {
+                Ok(p) => config.policy = p,
+                Err(..) => {
+                    let err = Error::new(
+                        ErrorKind::Malformed,
+                        "`policy` of `udp_eviction` must be \"lru\" or \"oldest_idle\"",
+                        Some(policy),
+                    );
+                    return Err(err);
+                }
+            }
+        }
+        if let Some(min_idle) = self.min_idle {
+            config.min_idle = Duration::from_secs(min_idle);
+        }
+
+        Ok(config)
+    }
+
+    fn from_config(config: &UdpEvictionConfig) -> SSUdpEvictionConfig {
+        SSUdpEvictionConfig {
+            policy: if config.policy != UdpEvictionPolicy::default() {
+                Some(config.policy.to_string())
+            } else {
+                None
+            },
+            min_idle: if config.min_idle != UdpEvictionConfig::default().min_idle {
+                Some(config.min_idle.as_secs())
+            } else {
+                None
+            },
+        }
+    }
+}
+
+#[cfg(feature = "manager-http-api")]
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct SSManagerHttpApiConfig {
+    /// "host:port"
+    address: String,
+    /// Sent by clients in `Authorization: Bearer TOKEN`
+    token: String,
+}
+
+#[cfg(feature = "manager-http-api")]
+impl SSManagerHttpApiConfig {
+    fn build(self) -> Result<ManagerHttpApiConfig, Error> {
+        let addr = match self.address.parse::<ServerAddr>() {
+            Ok(addr) => addr,
+            Err(..) => {
+                let err = Error::new(
+                    ErrorKind::Malformed,
+                    "`address` of `manager_http_api` invalid",
+                    Some(self.address),
+                );
+                return Err(err);
+            }
+        };
+
+        if self.token.is_empty() {
+            let err = Error::new(
+                ErrorKind::Invalid,
+                "`token` of `manager_http_api` must not be empty",
+                None,
+            );
+            return Err(err);
+        }
+
+        Ok(ManagerHttpApiConfig {
+            addr,
+            token: self.token,
+        })
+    }
+
+    fn from_config(config: &ManagerHttpApiConfig) -> SSManagerHttpApiConfig {
+        SSManagerHttpApiConfig {
+            address: config.addr.to_string(),
+            token: config.token.clone(),
+        }
+    }
+}
+
+#[cfg(feature = "manager-web-ui")]
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct SSManagerWebUiConfig {
+    /// "host:port"
+    address: String,
+    /// Password of HTTP Basic authentication
+    password: String,
+}
+
+#[cfg(feature = "manager-web-ui")]
+impl SSManagerWebUiConfig {
+    fn build(self) -> Result<ManagerWebUiConfig, Error> {
+        let addr = match self.address.parse::<ServerAddr>() {
+            Ok(addr) => addr,
+            Err(..) => {
+                let err = Error::new(
+                    ErrorKind::Malformed,
+                    "`address` of `manager_web_ui` invalid",
+                    Some(self.address),
+                );
+                return Err(err);
+            }
+        };
+
+        if self.password.is_empty() {
+            let err = Error::new(
+                ErrorKind::Invalid,
+                "`password` of `manager_web_ui` must not be empty",
+                None,
+            );
+            return Err(err);
+        }
+
+        Ok(ManagerWebUiConfig {
+            addr,
+            password: self.password,
+        })
+    }
+
+    fn from_config(config: &ManagerWebUiConfig) -> SSManagerWebUiConfig {
+        SSManagerWebUiConfig {
+            address: config.addr.to_string(),
+            password: config.password.clone(),
+        }
+    }
+}
+
+#[cfg(feature = "manager-sip008")]
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct SSManagerSip008Config {
+    /// "host:port"
+    address: String,
+    /// Host of servers in documents, `server_host` of manager by default
+    #[serde(skip_serializing_if = "Option::is_none")]
+    server: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tls: Option<SSLocalTlsConfig>,
+    subscriptions: Vec<SSSip008SubscriptionConfig>,
+}
+
+#[cfg(feature = "manager-sip008")]
+#[derive(Serialize, Deserialize, Debug)]
+struct SSSip008SubscriptionConfig {
+    token: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    user: Option<String>,
+}
+
+#[cfg(feature = "manager-sip008")]
+impl SSManagerSip008Config {
+    fn build(self) -> Result<ManagerSip008Config, Error> {
+        let addr = match self.address.parse::<ServerAddr>() {
+            Ok(addr) => addr,
+            Err(..) => {
+                let err = Error::new(
+                    ErrorKind::Malformed,
+                    "`address` of `manager_sip008` invalid",
+                    Some(self.address),
+                );
+                return Err(err);
+            }
+        };
+
+        let mut subscriptions = Vec::with_capacity(self.subscriptions.len());
+        for subscription in self.subscriptions {
+            // Tokens are parts of URLs
+            let is_valid_token = !subscription.token.is_empty()
+                && subscription
+                    .token
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_');
+            if !is_valid_token {
+                let err = Error::new(
+                    ErrorKind::Malformed,
+                    "`token` of `manager_sip008.subscriptions` must be non-empty, with only letters, digits, '-' or '_'",
+                    Some(subscription.token),
+                );
+                return Err(err);
+            }
+            if subscriptions
+                .iter()
+                .any(|s: &Sip008Subscription| s.token == subscription.token)
+            {
+                let err = Error::new(
+                    ErrorKind::Invalid,
+                    "duplicated `token` of `manager_sip008.subscriptions`",
+                    Some(subscription.token),
+                );
+                return Err(err);
+            }
+
+            subscriptions.push(Sip008Subscription {
+                token: subscription.token,
+                user: subscription.user,
+            });
+        }
+
+        Ok(ManagerSip008Config {
+            addr,
+            server: self.server,
+            tls: self.tls.map(|tls| LocalTlsConfig {
+                certificate_path: PathBuf::from(tls.certificate),
+                private_key_path: PathBuf::from(tls.private_key),
+                client_ca_path: tls.client_ca.map(PathBuf::from),
+            }),
+            subscriptions,
+        })
+    }
+
+    fn from_config(config: &ManagerSip008Config) -> SSManagerSip008Config {
+        SSManagerSip008Config {
+            address: config.addr.to_string(),
+            server: config.server.clone(),
+            tls: config.tls.as_ref().map(|tls| SSLocalTlsConfig {
+                certificate: tls.certificate_path.display().to_string(),
+                private_key: tls.private_key_path.display().to_string(),
+                client_ca: tls.client_ca_path.as_ref().map(|p| p.display().to_string()),
+            }),
+            subscriptions: config
+                .subscriptions
+                .iter()
+                .map(|s| SSSip008SubscriptionConfig {
+                    token: s.token.clone(),
+                    user: s.user.clone(),
+                })
+                .collect(),
+        }
+    }
+}
+
+#[cfg(feature = "aead-cipher-2022")]
+#[derive(Serialize, Deserialize, Debug)]
+struct SSServerUserConfig {
+    name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    password: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    password_file: Option<String>,
+    /// In bytes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    quota: Option<u64>,
+}
+
+#[cfg(feature = "aead-cipher-2022")]
+impl SSServerUserConfig {
+    fn build_user_manager(method: CipherKind, users: &[SSServerUserConfig]) -> Result<ServerUserManager, Error> {
+        let user_manager = ServerUserManager::new();
+        for user in users {
+            let password = match read_password(user.password.as_deref(), user.password_file.as_deref())? {
+                Some(p) => p,
+                None => {
+                    let err = Error::new(
+                        ErrorKind::MissingField,
+                        "missing `password` of `users`",
+                        Some(format!("user `{}`", user.name)),
+                    );
+                    return Err(err);
+                }
+            };
+
+            match ServerUser::with_encoded_key(user.name.clone(), method, &password) {
+                Ok(mut u) => {
+                    if let Some(quota) = user.quota {
+                        u.set_quota(parse_quota(quota)?);
+                    }
+                    user_manager.add_user(u);
+                }
+                Err(err) => {
+                    let err = Error::new(
+                        ErrorKind::Invalid,
+                        "invalid `password` of `users`",
+                        Some(format!("user `{}`, {}", user.name, err)),
+                    );
+                    return Err(err);
+                }
+            }
+        }
+        Ok(user_manager)
+    }
+
+    fn from_user_manager(user_manager: &ServerUserManager) -> Vec<SSServerUserConfig> {
+        user_manager
+            .users()
+            .iter()
+            .map(|u| SSServerUserConfig {
+                name: u.name().to_owned(),
+                password: Some(u.encoded_key()),
+                password_file: None,
+                quota: u.quota(),
+            })
+            .collect()
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct SSServerExtConfig {
+    // SIP008 https://github.com/shadowsocks/shadowsocks-org/issues/89
+    //
+    // `address` and `port` are non-standard field name only for shadowsocks-rust
+    #[serde(alias = "address")]
+    server: String,
+    #[serde(alias = "port")]
+    server_port: u16,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    password: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    password_file: Option<String>,
+    method: String,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    disabled: Option<bool>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    plugin: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    plugin_opts: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    plugin_args: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    plugin_mode: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    timeout: Option<u64>,
+
+    #[serde(skip_serializing_if = "Option::is_none", alias = "name")]
+    remarks: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    mode: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tcp_weight: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    udp_weight: Option<f32>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    udp_over_tcp: Option<bool>,
+
+    /// Metadata for selecting servers by ACL `[route:TAG]` rules
+    #[serde(skip_serializing_if = "Option::is_none")]
+    country: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    city: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tags: Option<Vec<String>>,
+
+    /// In bytes per second
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tcp_pacing_rate: Option<u64>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    mptcp: Option<bool>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    mux: Option<SSMuxConfig>,
+
+    #[cfg(feature = "stream-padding")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    padding: Option<SSPaddingConfig>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    port_hopping: Option<SSPortHoppingConfig>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    upstream_proxy: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    listen_addresses: Option<Vec<String>>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    listeners: Option<usize>,
+
+    #[cfg(feature = "aead-cipher-2022")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    users: Option<Vec<SSServerUserConfig>>,
+
+    /// In bytes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    quota: Option<u64>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rate_limit: Option<SSRateLimitConfig>,
+
+    /// "host:port"
+    #[serde(skip_serializing_if = "Option::is_none")]
+    fallback: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    outbound_bind_interface: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    outbound_bind_addr: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tuning: Option<SSTuningConfig>,
+
+    #[cfg(feature = "transport-shadow-tls")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    shadow_tls: Option<SSShadowTlsConfig>,
+
+    #[cfg(feature = "transport-quic")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    quic: Option<SSQuicConfig>,
+
+    #[cfg(feature = "transport-grpc")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    grpc: Option<SSGrpcConfig>,
+}
+
+/// Server config type
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ConfigType {
+    /// Config for local
+    Local,
+
+    /// Config for server
+    Server,
+
+    /// Config for Manager server
+    Manager,
+}
+
+impl ConfigType {
+    /// Check if it is local server type
+    pub fn is_local(self) -> bool {
+        self == ConfigType::Local
+    }
+
+    /// Check if it is remote server type
+    pub fn is_server(self) -> bool {
+        self == ConfigType::Server
+    }
+
+    /// Check if it is manager server type
+    pub fn is_manager(self) -> bool {
+        self == ConfigType::Manager
+    }
+}
+
+cfg_if! {
+    if #[cfg(feature = "local-redir")] {
+        /// Transparent Proxy type
+        #[derive(Clone, Copy, Debug, Eq, PartialEq)]
+        pub enum RedirType {
+            /// For not supported platforms
+            NotSupported,
+
+            /// For Linux-like systems' Netfilter `REDIRECT`. Only for TCP connections.
+            ///
+            /// This is supported from Linux 2.4 Kernel. Document: <https://www.netfilter.org/documentation/index.html#documentation-howto>
+            ///
+            /// NOTE: Filter rule `REDIRECT` can only be applied to TCP connections.
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            Redirect,
+
+            /// For Linux-like systems' Netfilter TPROXY rule.
+            ///
+            /// NOTE: Filter rule `TPROXY` can be applied to TCP and UDP connections.
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            TProxy,
+
+            /// Packet Filter (pf)
+            ///
+            /// Supported by OpenBSD 3.0+, FreeBSD 5.3+, NetBSD 3.0+, Solaris 11.3+, macOS 10.7+, iOS, QNX
+            ///
+            /// Document: <https://www.freebsd.org/doc/handbook/firewalls-pf.html>
+            #[cfg(any(
+                target_os = "openbsd",
+                target_os = "freebsd",
+                target_os = "netbsd",
+                target_os = "solaris",
+                target_os = "macos",
+                target_os = "ios"
+            ))]
+            PacketFilter,
+
+            /// IPFW
+            ///
+            /// Supported by FreeBSD, macOS 10.6- (Have been removed completely on macOS 10.10)
+            ///
+            /// Document: https://www.freebsd.org/doc/handbook/firewalls-ipfw.html
+            #[cfg(any(target_os = "freebsd", target_os = "macos", target_os = "ios"))]
+            IpFirewall,
+        }
+
+        impl RedirType {
+            cfg_if! {
+                if #[cfg(any(target_os = "linux", target_os = "android"))] {
+                    /// Default TCP transparent proxy solution on this platform
+                    pub const fn tcp_default() -> RedirType {
+                        RedirType::Redirect
+                    }
+
+                    /// Available TCP transparent proxy types
+                    #[doc(hidden)]
+                    pub fn tcp_available_types() -> &'static [&'static str] {
+                        const AVAILABLE_TYPES: &[&str] = &[RedirType::Redirect.name(), RedirType::TProxy.name()];
+                        AVAILABLE_TYPES
+                    }
+
+                    /// Default UDP transparent proxy solution on this platform
+                    pub const fn udp_default() -> RedirType {
+                        RedirType::TProxy
+                    }
+
+                    /// Available UDP transparent proxy types
+                    #[doc(hidden)]
+                    pub fn udp_available_types() -> &'static [&'static str] {
+                        const AVAILABLE_TYPES: &[&str] = &[RedirType::TProxy.name()];
+                        AVAILABLE_TYPES
+                    }
+                } else if #[cfg(any(target_os = "openbsd", target_os = "freebsd"))] {
+                    /// Default TCP transparent proxy solution on this platform
+                    pub fn tcp_default() -> RedirType {
+                        RedirType::PacketFilter
+                    }
+
+                    /// Available TCP transparent proxy types
+                    #[doc(hidden)]
+                    pub fn tcp_available_types() -> &'static [&'static str] {
+                        const AVAILABLE_TYPES: &[&str] = &[RedirType::PacketFilter.name(), RedirType::IpFirewall.name()];
+                        AVAILABLE_TYPES
+                    }
+
+                    /// Default UDP transparent proxy solution on this platform
+                    pub fn udp_default() -> RedirType {
+                        RedirType::PacketFilter
+                    }
+
+                    /// Available UDP transparent proxy types
+                    #[doc(hidden)]
+                    pub const fn udp_available_types() -> &'static [&'static str] {
+                        const AVAILABLE_TYPES: &[&str] = &[RedirType::PacketFilter.name(), RedirType::IpFirewall.name()];
+                        AVAILABLE_TYPES
+                    }
+                } else if #[cfg(target_os = "macos")] {
+                    /// Default TCP transparent proxy solution on this platform
+                    pub fn tcp_default() -> RedirType {
+                        RedirType::PacketFilter
+                    }
+
+                    /// Available TCP transparent proxy types
+                    #[doc(hidden)]
+                    pub const fn tcp_available_types() -> &'static [&'static str] {
+                        const AVAILABLE_TYPES: &[&str] = &[RedirType::PacketFilter.name(), RedirType::IpFirewall.name()];
+                        AVAILABLE_TYPES
+                    }
+
+                    /// Default UDP transparent proxy solution on this platform
+                    pub fn udp_default() -> RedirType {
+                        RedirType::PacketFilter
+                    }
+
+                    /// Available UDP transparent proxy types
+                    #[doc(hidden)]
+                    pub const fn udp_available_types() -> &'static [&'static str] {
+                        const AVAILABLE_TYPES: &[&str] = &[RedirType::PacketFilter.name()];
+                        AVAILABLE_TYPES
+                    }
+                } else if #[cfg(any(target_os = "netbsd", target_os = "solaris", target_os = "ios"))] {
+                    /// Default TCP transparent proxy solution on this platform
+                    pub fn tcp_default() -> RedirType {
+                        RedirType::PacketFilter
+                    }
+
+                    /// Available TCP transparent proxy types
+                    #[doc(hidden)]
+                    pub const fn tcp_available_types() -> &'static [&'static str] {
+                        const AVAILABLE_TYPES: &[&str] = &[RedirType::PacketFilter.name(), RedirType::IpFirewall.name()];
+                        AVAILABLE_TYPES
+                    }
+
+                    /// Default UDP transparent proxy solution on this platform
+                    pub fn udp_default() -> RedirType {
+                        RedirType::NotSupported
+                    }
+
+                    /// Available UDP transparent proxy types
+                    #[doc(hidden)]
+                    pub const fn udp_available_types() -> &'static [&'static str] {
+                        const AVAILABLE_TYPES: &[&str] = &[];
+                        AVAILABLE_TYPES
+                    }
+                } else {
+                    /// Default TCP transparent proxy solution on this platform
+                    pub fn tcp_default() -> RedirType {
+                        RedirType::NotSupported
+                    }
+
+                    /// Available TCP transparent proxy types
+                    #[doc(hidden)]
+                    pub const fn tcp_available_types() -> &'static [&'static str] {
+                        const AVAILABLE_TYPES: &[&str] = &[];
+                        AVAILABLE_TYPES
+                    }
+
+                    /// Default UDP transparent proxy solution on this platform
+                    pub fn udp_default() -> RedirType {
+                        RedirType::NotSupported
+                    }
+
+                    /// Available UDP transparent proxy types
+                    #[doc(hidden)]
+                    pub const fn udp_available_types() -> &'static [&'static str] {
+                        const AVAILABLE_TYPES: &[&str] = &[];
+                        AVAILABLE_TYPES
+                    }
+                }
+            }
+
+            /// Check if transparent proxy is supported on this platform
+            pub fn is_supported(self) -> bool {
+                self != RedirType::NotSupported
+            }
+
+            /// Name of redirect type (transparent proxy type)
+            pub const fn name(self) -> &'static str {
+                match self {
+                    // Dummy, shouldn't be used in any useful situations
+                    RedirType::NotSupported => "not_supported",
+
+                    #[cfg(any(target_os = "linux", target_os = "android"))]
+                    RedirType::Redirect => "redirect",
+
+                    #[cfg(any(target_os = "linux", target_os = "android"))]
+                    RedirType::TProxy => "tproxy",
+
+                    #[cfg(any(
+                        target_os = "openbsd",
+                        target_os = "freebsd",
+                        target_os = "netbsd",
+                        target_os = "solaris",
+                        target_os = "macos",
+                        target_os = "ios"
+                    ))]
+                    RedirType::PacketFilter => "pf",
+
+                    #[cfg(any(target_os = "freebsd", target_os = "macos", target_os = "ios"))]
+                    RedirType::IpFirewall => "ipfw",
+                }
+            }
+        }
+
+        impl Display for RedirType {
+            fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+                f.write_str(self.name())
+            }
+        }
+
+        /// Error type for `RedirType`'s `FromStr::Err`
+        #[derive(Debug)]
+        pub struct InvalidRedirType;
+
+        impl Display for InvalidRedirType {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("invalid RedirType")
+            }
+        }
+
+        impl FromStr for RedirType {
+            type Err = InvalidRedirType;
+
+            fn from_str(s: &str) -> Result<RedirType, InvalidRedirType> {
+                match s {
+                    #[cfg(any(target_os = "linux", target_os = "android"))]
+                    "redirect" => Ok(RedirType::Redirect),
+
+                    #[cfg(any(target_os = "linux", target_os = "android"))]
+                    "tproxy" => Ok(RedirType::TProxy),
+
+                    #[cfg(any(
+                        target_os = "openbsd",
+                        target_os = "freebsd",
+                        target_os = "netbsd",
+                        target_os = "solaris",
+                        target_os = "macos",
+                        target_os = "ios",
+                    ))]
+                    "pf" => Ok(RedirType::PacketFilter),
+
+                    #[cfg(any(
+                        target_os = "freebsd",
+                        target_os = "macos",
+                        target_os = "ios",
+                        target_os = "dragonfly"
+                    ))]
+                    "ipfw" => Ok(RedirType::IpFirewall),
+
+                    _ => Err(InvalidRedirType),
+                }
+            }
+        }
+
+        /// Firewall installing rules of Transparent Proxy
+        #[derive(Clone, Copy, Debug, Eq, PartialEq)]
+        pub enum RedirFirewall {
+            /// `iptables` and `ip6tables`, in `nat` (`REDIRECT`) and `mangle` (`TPROXY`) tables
+            Iptables,
+            /// `nft`, in a dedicated `inet` table
+            Nftables,
+        }
+
+        impl RedirFirewall {
+            /// Name of the firewall
+            pub const fn name(self) -> &'static str {
+                match self {
+                    RedirFirewall::Iptables => "iptables",
+                    RedirFirewall::Nftables => "nftables",
+                }
+            }
+        }
+
+        impl Display for RedirFirewall {
+            fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+                f.write_str(self.name())
+            }
+        }
+
+        /// Error type for `RedirFirewall`'s `FromStr::Err`
+        #[derive(Debug)]
+        pub struct InvalidRedirFirewall;
+
+        impl Display for InvalidRedirFirewall {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("invalid RedirFirewall")
+            }
+        }
+
+        impl FromStr for RedirFirewall {
+            type Err = InvalidRedirFirewall;
+
+            fn from_str(s: &str) -> Result<RedirFirewall, InvalidRedirFirewall> {
+                match s {
+                    "iptables" => Ok(RedirFirewall::Iptables),
+                    "nftables" | "nft" => Ok(RedirFirewall::Nftables),
+                    _ => Err(InvalidRedirFirewall),
+                }
+            }
+        }
+    }
+}
+
+/// Host for servers to bind
+///
+/// Servers will bind to a port of this host
+#[derive(Clone, Debug)]
+pub enum ManagerServerHost {
+    /// Domain name
+    Domain(String),
+    /// IP address
+    Ip(IpAddr),
+}
+
+impl Default for ManagerServerHost {
+    fn default() -> ManagerServerHost {
+        ManagerServerHost::Ip(Ipv4Addr::UNSPECIFIED.into())
+    }
+}
+
+impl FromStr for ManagerServerHost {
+    type Err = Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.parse::<IpAddr>() {
+            Ok(s) => Ok(ManagerServerHost::Ip(s)),
+            Err(..) => Ok(ManagerServerHost::Domain(s.to_owned())),
+        }
+    }
+}
+
+/// Mode of Manager's server
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ManagerServerMode {
+    /// Run shadowsocks server in the same process of manager
+    Builtin,
+
+    /// Run shadowsocks server in standalone (process) mode
+    #[cfg(unix)]
+    Standalone,
+}
+
+impl Default for ManagerServerMode {
+    fn default() -> ManagerServerMode {
+        ManagerServerMode::Builtin
+    }
+}
+
+/// Parsing ManagerServerMode error
+#[derive(Debug, Clone, Copy)]
+pub struct ManagerServerModeError;
+
+impl Display for ManagerServerModeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str("invalid ManagerServerMode")
+    }
+}
+
+impl FromStr for ManagerServerMode {
+    type Err = ManagerServerModeError;
+
+    fn from_str(s: &str) -> Result<ManagerServerMode, Self::Err> {
+        match s {
+            "builtin" => Ok(ManagerServerMode::Builtin),
+            #[cfg(unix)]
+            "standalone" => Ok(ManagerServerMode::Standalone),
+            _ => Err(ManagerServerModeError),
+        }
+    }
+}
+
+impl Display for ManagerServerMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ManagerServerMode::Builtin => f.write_str("builtin"),
+            #[cfg(unix)]
+            ManagerServerMode::Standalone => f.write_str("standalone"),
+        }
+    }
+}
+
+/// Configuration for Manager
+#[derive(Clone, Debug)]
+pub struct ManagerConfig {
+    /// Address of `ss-manager`. Send servers' statistic data to the manager server
+    pub addr: ManagerAddr,
+    /// Pre-shared key for authenticating requests, requests are not authenticated if not set
+    pub key: Option<String>,
+    /// Manager's default method
+    pub method: Option<CipherKind>,
+    /// Manager's default plugin
+    pub plugin: Option<PluginConfig>,
+    /// Timeout for TCP connections, setting to manager's created servers
+    pub timeout: Option<Duration>,
+    /// IP/Host for servers to bind (inbound)
+    ///
+    /// Note: Outbound address is defined in Config.local_addr
+    pub server_host: ManagerServerHost,
+    /// Server's mode
+    pub mode: Mode,
+    /// Server's running mode
+    pub server_mode: ManagerServerMode,
+    /// Server's command if running in Standalone mode
+    #[cfg(unix)]
+    pub server_program: String,
+    /// Server's working directory if running in Standalone mode
+    #[cfg(unix)]
+    pub server_working_directory: PathBuf,
+    /// HTTP API, serving along with `addr`
+    #[cfg(feature = "manager-http-api")]
+    pub http_api: Option<ManagerHttpApiConfig>,
+    /// SIP008 online configuration server
+    #[cfg(feature = "manager-sip008")]
+    pub sip008: Option<ManagerSip008Config>,
+    /// Web dashboard
+    #[cfg(feature = "manager-web-ui")]
+    pub web_ui: Option<ManagerWebUiConfig>,
+}
+
+impl ManagerConfig {
+    /// Create a ManagerConfig with default options
+    pub fn new(addr: ManagerAddr) -> ManagerConfig {
+        ManagerConfig {
+            addr,
+            key: None,
+            method: None,
+            plugin: None,
+            timeout: None,
+            server_host: ManagerServerHost::default(),
+            mode: Mode::TcpOnly,
+            server_mode: ManagerServerMode::Builtin,
+            #[cfg(unix)]
+            server_program: "ssserver".to_owned(),
+            #[cfg(unix)]
+            server_working_directory: match std::env::current_dir() {
+                Ok(d) => d,
+                Err(..) => "/tmp/shadowsocks-manager".into(),
+            },
+            #[cfg(feature = "manager-http-api")]
+            http_api: None,
+            #[cfg(feature = "manager-sip008")]
+            sip008: None,
+            #[cfg(feature = "manager-web-ui")]
+            web_ui: None,
+        }
+    }
+}
+
+/// Configuration of Manager's HTTP API
+#[cfg(feature = "manager-http-api")]
+#[derive(Clone, Debug)]
+pub struct ManagerHttpApiConfig {
+    /// Listening address
+    pub addr: ServerAddr,
+    /// Bearer token of requests
+    pub token: String,
+}
+
+/// Configuration of Manager's web dashboard
+#[cfg(feature = "manager-web-ui")]
+#[derive(Clone, Debug)]
+pub struct ManagerWebUiConfig {
+    /// Listening address
+    pub addr: ServerAddr,
+    /// Password of HTTP Basic authentication, with any user name
+    pub password: String,
+}
+
+/// Configuration of Manager's SIP008 online configuration server
+#[cfg(feature = "manager-sip008")]
+#[derive(Clone, Debug)]
+pub struct ManagerSip008Config {
+    /// Listening address
+    pub addr: ServerAddr,
+    /// Host of servers in documents, `ManagerConfig::server_host` if not set
+    pub server: Option<String>,
+    /// Served in HTTPS if set
+    pub tls: Option<LocalTlsConfig>,
+    pub subscriptions: Vec<Sip008Subscription>,
+}
+
+/// Subscription of SIP008 documents, served at `/sip008/TOKEN`
+#[cfg(feature = "manager-sip008")]
+#[derive(Clone, Debug)]
+pub struct Sip008Subscription {
+    pub token: String,
+    /// Servers having this user of multi-user servers, or all single-user servers if not set
+    pub user: Option<String>,
+}
+
+/// Protocol of local server
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ProtocolType {
+    Socks,
+    #[cfg(feature = "local-http")]
+    Http,
+    #[cfg(feature = "local-tunnel")]
+    Tunnel,
+    #[cfg(feature = "local-redir")]
+    Redir,
+    #[cfg(feature = "local-dns")]
+    Dns,
+    #[cfg(feature = "local-tun")]
+    Tun,
+    #[cfg(feature = "local-pac")]
+    Pac,
+    #[cfg(feature = "local-web-ui")]
+    WebUi,
+    #[cfg(feature = "local-http")]
+    Mixed,
+}
+
+impl Default for ProtocolType {
+    fn default() -> ProtocolType {
+        ProtocolType::Socks
+    }
+}
+
+impl ProtocolType {
+    /// As string representation
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            ProtocolType::Socks => "socks",
+            #[cfg(feature = "local-http")]
+            ProtocolType::Http => "http",
+            #[cfg(feature = "local-tunnel")]
+            ProtocolType::Tunnel => "tunnel",
+            #[cfg(feature = "local-redir")]
+            ProtocolType::Redir => "redir",
+            #[cfg(feature = "local-dns")]
+            ProtocolType::Dns => "dns",
+            #[cfg(feature = "local-tun")]
+            ProtocolType::Tun => "tun",
+            #[cfg(feature = "local-pac")]
+            ProtocolType::Pac => "pac",
+            #[cfg(feature = "local-web-ui")]
+            ProtocolType::WebUi => "web-ui",
+            #[cfg(feature = "local-http")]
+            ProtocolType::Mixed => "mixed",
+        }
+    }
+
+    /// Get all available protocols
+    pub fn available_protocols() -> &'static [&'static str] {
+        &[
+            "socks",
+            #[cfg(feature = "local-http")]
+            "http",
+            #[cfg(feature = "local-tunnel")]
+            "tunnel",
+            #[cfg(feature = "local-redir")]
+            "redir",
+            #[cfg(feature = "local-dns")]
+            "dns",
+            #[cfg(feature = "local-tun")]
+            "tun",
+            #[cfg(feature = "local-pac")]
+            "pac",
+            #[cfg(feature = "local-web-ui")]
+            "web-ui",
+            #[cfg(feature = "local-http")]
+            "mixed",
+        ]
+    }
+}
+
+/// Error while parsing `ProtocolType` from string
+#[derive(Debug)]
+pub struct ProtocolTypeError;
+
+impl Display for ProtocolTypeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str("invalid ProtocolType")
+    }
+}
+
+impl FromStr for ProtocolType {
+    type Err = ProtocolTypeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "socks" => Ok(ProtocolType::Socks),
+            #[cfg(feature = "local-http")]
+            "http" => Ok(ProtocolType::Http),
+            #[cfg(feature = "local-tunnel")]
+            "tunnel" => Ok(ProtocolType::Tunnel),
+            #[cfg(feature = "local-redir")]
+            "redir" => Ok(ProtocolType::Redir),
+            #[cfg(feature = "local-dns")]
+            "dns" => Ok(ProtocolType::Dns),
+            #[cfg(feature = "local-tun")]
+            "tun" => Ok(ProtocolType::Tun),
+            #[cfg(feature = "local-pac")]
+            "pac" => Ok(ProtocolType::Pac),
+            #[cfg(feature = "local-web-ui")]
+            "web-ui" => Ok(ProtocolType::WebUi),
+            #[cfg(feature = "local-http")]
+            "mixed" => Ok(ProtocolType::Mixed),
+            _ => Err(ProtocolTypeError),
+        }
+    }
+}
+
+/// Local server configuration
+#[derive(Clone, Debug)]
+pub struct LocalConfig {
+    /// Listen address for local servers
+    pub addr: Option<ServerAddr>,
+
+    /// Listen on this Unix Domain Socket path instead of `addr`
+    ///
+    /// Only supported by `socks` and `http`
+    #[cfg(unix)]
+    pub unix_addr: Option<PathBuf>,
+    /// Permission bits of `unix_addr`, for example, `0o660`
+    #[cfg(unix)]
+    pub unix_mode: Option<u32>,
+
+    pub protocol: ProtocolType,
+
+    /// Mode
+    /// Uses global `mode` if not specified
+    pub mode: Mode,
+
+    /// UDP server bind address. Uses `addr` if not specified
+    ///
+    /// Resolving Android's issue: [shadowsocks/shadowsocks-android#2571](https://github.com/shadowsocks/shadowsocks-android/issues/2571)
+    pub udp_addr: Option<ServerAddr>,
+
+    /// Destination address for tunnel
+    #[cfg(feature = "local-tunnel")]
+    pub forward_addr: Option<Address>,
+    /// Additional port mappings served by the same tunnel
+    ///
+    /// `addr` and `forward_addr` could be omitted if there are any mappings
+    #[cfg(feature = "local-tunnel")]
+    pub tunnel_mappings: Vec<TunnelMapping>,
+
+    /// TCP Transparent Proxy type
+    #[cfg(feature = "local-redir")]
+    pub tcp_redir: RedirType,
+    /// UDP Transparent Proxy type
+    #[cfg(feature = "local-redir")]
+    pub udp_redir: RedirType,
+    /// Number of Transparent Proxy listeners binding to the same address, for both TCP and UDP
+    ///
+    /// Listeners are bound with `SO_REUSEPORT` if there are more than one, which is only supported on Linux
+    #[cfg(feature = "local-redir")]
+    pub redir_listeners: usize,
+    /// Relay bypassed TCP connections of Transparent Proxy in kernel with eBPF sockmap
+    ///
+    /// Data is spliced between sockets of clients and targets without being copied to the userspace.
+    /// Proxied connections are not affected, because their data has to be encrypted. Only supported on Linux.
+    #[cfg(feature = "local-redir-ebpf")]
+    pub redir_ebpf: bool,
+    /// Install firewall rules redirecting forwarded traffic to Transparent Proxy, and remove them on exit
+    ///
+    /// Destinations of servers and private ranges are excluded. Only traffic forwarded through this host is
+    /// redirected (`PREROUTING`), traffic of this host itself (`OUTPUT`) is not. Only supported on Linux.
+    #[cfg(feature = "local-redir")]
+    pub redir_firewall: Option<RedirFirewall>,
+    /// Copy TTL (or IPv6 hop limit) of clients' TCP connections onto bypassed connections, for redir and tun
+    ///
+    /// Some middleboxes tell forwarded traffic by TTL, which is reset by connections made by sslocal.
+    /// redir reads TTL from saved SYN packets, which is only supported on Linux.
+    #[cfg(any(feature = "local-redir", feature = "local-tun"))]
+    pub preserve_ttl: bool,
+
+    /// Local DNS's address
+    ///
+    /// Sending DNS query directly to this address
+    #[cfg(feature = "local-dns")]
+    pub local_dns_addr: Option<NameServerAddr>,
+    /// Remote DNS's address
+    ///
+    /// Sending DNS query through proxy to this address
+    #[cfg(feature = "local-dns")]
+    pub remote_dns_addr: Option<Address>,
+    /// Remote DNS-over-HTTPS server, replaces `remote_dns_addr`
+    ///
+    /// Sending DNS query through proxy to this server in HTTPS
+    #[cfg(feature = "local-dns-over-https")]
+    pub remote_dns_https: Option<DnsOverHttpsAddr>,
+    /// Remote DNS-over-TLS or DNS-over-QUIC server, replaces `remote_dns_addr`
+    ///
+    /// Sending DNS query through proxy to this server in TLS or QUIC
+    #[cfg(any(feature = "local-dns-over-tls", feature = "local-dns-over-quic"))]
+    pub remote_dns_secure: Option<SecureNameServerAddr>,
+    /// More name servers queried directly along with `local_dns_addr`
+    #[cfg(feature = "local-dns")]
+    pub local_dns_extra_addrs: Vec<NameServerAddr>,
+    /// Strategy of querying `local_dns_addr` and `local_dns_extra_addrs`
+    #[cfg(feature = "local-dns")]
+    pub local_dns_strategy: NameServerStrategy,
+    /// Timeout of each query to local DNS
+    #[cfg(feature = "local-dns")]
+    pub local_dns_timeout: Option<Duration>,
+    /// Timeout of each query to remote DNS
+    #[cfg(feature = "local-dns")]
+    pub remote_dns_timeout: Option<Duration>,
+    /// Cache of responses from both local and remote DNS
+    #[cfg(feature = "local-dns")]
+    pub dns_cache: Option<DnsCacheConfig>,
+    /// Answer names that should be proxied with fake IPs, which are mapped back to the names by other locals
+    #[cfg(feature = "local-dns")]
+    pub fake_ip: Option<FakeIpConfig>,
+    /// Name servers of DNS upstream groups, for names matching ACL's `[dns:GROUP]` sections
+    #[cfg(feature = "local-dns")]
+    pub dns_upstreams: HashMap<String, Vec<NameServerAddr>>,
+    /// Static records, and addresses in the hosts file, answered before consulting upstreams
+    #[cfg(feature = "local-dns")]
+    pub dns_hosts: Option<DnsHostsConfig>,
+    /// Validate DNSSEC signatures of answers from upstreams
+    #[cfg(feature = "local-dns-dnssec")]
+    pub dnssec: Option<DnssecConfig>,
+    /// Also accept DNS-over-TLS queries on this address, with certificate of `tls`
+    #[cfg(feature = "local-dns-tls")]
+    pub dns_over_tls_addr: Option<ServerAddr>,
+    /// Also accept DNS-over-HTTPS queries on this address, with certificate of `tls`
+    #[cfg(feature = "local-dns-tls")]
+    pub dns_over_https_addr: Option<ServerAddr>,
+
+    /// Tun interface's name
+    ///
+    /// Linux: eth0, eth1, ...
+    /// macOS: utun0, utun1, ...
+    #[cfg(feature = "local-tun")]
+    pub tun_interface_name: Option<String>,
+    /// Tun interface's address and netmask
+    ///
+    /// It will be checked that it doesn't collide with the host's networks and servers' addresses
+    #[cfg(feature = "local-tun")]
+    pub tun_interface_address: Option<IpNet>,
+    /// Choose a free `tun_interface_address` automatically, configured by `"tun_interface_address": "auto"`
+    #[cfg(feature = "local-tun")]
+    pub tun_interface_address_auto: bool,
+    /// TCP parameters of tun's network stack
+    #[cfg(feature = "local-tun")]
+    pub tun_tcp: TunTcpConfig,
+    /// Tun interface's file descriptor
+    #[cfg(all(feature = "local-tun", unix))]
+    pub tun_device_fd: Option<std::os::unix::io::RawFd>,
+    /// Tun interface's file descriptor read from this Unix Domain Socket
+    #[cfg(all(feature = "local-tun", unix))]
+    pub tun_device_fd_from_path: Option<PathBuf>,
+
+    /// Template of the PAC file, replacing the default one
+    #[cfg(feature = "local-pac")]
+    pub pac_template_path: Option<PathBuf>,
+
+    /// Set `IPV6_V6ONLY` for listener socket
+    pub ipv6_only: bool,
+
+    /// Retries of binding `addr` if it is in use, with exponential backoff
+    pub bind_retries: u32,
+    /// Ports of `addr` to bind in order if it is still in use after `bind_retries`
+    pub bind_fallback_ports: Vec<u16>,
+
+    /// Name of the tenant that this local server belongs to
+    ///
+    /// Locals of the same tenant share one DNS resolver, caches and statistic counters,
+    /// which are isolated from the other tenants' and the default shared ones.
+    pub tenant: Option<String>,
+
+    /// Access Control List of this local server, overriding the global one
+    ///
+    /// Locals with their own ACL don't share DNS resolver, caches and statistic counters, like outbound binds.
+    #[cfg(feature = "acl")]
+    pub acl: Option<AccessControl>,
+    /// Group of servers for targets that are not routed by ACL's `[route:TAG]` rules
+    /// Uses `balancer.default_group` if not specified
+    pub server_group: Option<String>,
+    /// DNS resolver of targets of this local server
+    /// Uses global `dns` if not specified
+    pub dns: Option<DnsConfig>,
+    /// Prefer IPv6 addresses of resolved targets of this local server
+    /// Uses global `ipv6_first` if not specified
+    pub ipv6_first: Option<bool>,
+
+    /// What to do with BitTorrent traffic relayed by this local server
+    /// Uses global `p2p_policy` if not specified
+    pub p2p_policy: P2pPolicy,
+
+    /// Interface which outbound sockets of this local server are bound to, overriding the global one
+    ///
+    /// Locals with their own outbound binds don't share DNS resolver, caches and statistic counters.
+    pub outbound_bind_interface: Option<String>,
+    /// Address which outbound sockets of this local server are bound to, overriding the global one
+    pub outbound_bind_addr: Option<IpAddr>,
+
+    /// Tuning of TCP sockets of this local server, both accepted from clients and connected to targets or servers,
+    /// overriding the global options
+    ///
+    /// Locals with their own tuning don't share DNS resolver, caches and statistic counters, like outbound binds.
+    pub tcp_tuning: TcpTuning,
+    /// Sizes of buffers of TCP tunnels of this local server, instead of `buffer_pool.buffer_size`
+    pub relay_buffer_sizes: RelayBufferSizes,
+
+    /// Timeout of UDP associations of this local server
+    /// Uses global `udp_timeout` if not specified
+    pub udp_timeout: Option<Duration>,
+    /// Maximum number of UDP associations of this local server
+    /// Uses global `udp_max_associations` if not specified
+    pub udp_max_associations: Option<usize>,
+    /// NAT behavior of UDP associations of this local server
+    /// Uses global `udp_nat` if not specified
+    #[cfg(feature = "local")]
+    pub udp_nat: Option<UdpNatConfig>,
+
+    /// SOCKS5 Authentication configuration
+    #[cfg(feature = "local")]
+    pub socks5_auth: Socks5AuthConfig,
+    /// Handling of host names in SOCKS requests (socks5h and SOCKS4a), of both TCP and UDP
+    #[cfg(feature = "local")]
+    pub socks_hostname: SocksHostnamePolicy,
+
+    /// HTTP proxy Authentication configuration
+    #[cfg(feature = "local-http")]
+    pub http_auth: HttpAuthConfig,
+
+    /// Cache responses of HTTP proxy
+    #[cfg(feature = "local-http")]
+    pub http_cache: Option<HttpCacheConfig>,
+
+    /// Accept TLS connections on this local server
+    #[cfg(any(feature = "local-socks-tls", feature = "local-http-tls", feature = "local-dns-tls"))]
+    pub tls: Option<LocalTlsConfig>,
+}
+
+impl LocalConfig {
+    /// Create a new `LocalConfig`
+    pub fn new(protocol: ProtocolType) -> LocalConfig {
+        LocalConfig {
+            addr: None,
+            #[cfg(unix)]
+            unix_addr: None,
+            #[cfg(unix)]
+            unix_mode: None,
+
+            protocol,
+
+            mode: Mode::TcpOnly,
+            udp_addr: None,
+
+            #[cfg(feature = "local-tunnel")]
+            forward_addr: None,
+            #[cfg(feature = "local-tunnel")]
+            tunnel_mappings: Vec::new(),
+
+            #[cfg(feature = "local-redir")]
+            tcp_redir: RedirType::tcp_default(),
+            #[cfg(feature = "local-redir")]
+            udp_redir: RedirType::udp_default(),
+            #[cfg(feature = "local-redir")]
+            redir_listeners: 1,
+            #[cfg(feature = "local-redir-ebpf")]
+            redir_ebpf: false,
+            #[cfg(feature = "local-redir")]
+            redir_firewall: None,
+            #[cfg(any(feature = "local-redir", feature = "local-tun"))]
+            preserve_ttl: false,
+
+            #[cfg(feature = "local-dns")]
+            local_dns_addr: None,
+            #[cfg(feature = "local-dns")]
+            remote_dns_addr: None,
+            #[cfg(feature = "local-dns-over-https")]
+            remote_dns_https: None,
+            #[cfg(any(feature = "local-dns-over-tls", feature = "local-dns-over-quic"))]
+            remote_dns_secure: None,
+            #[cfg(feature = "local-dns")]
+            local_dns_extra_addrs: Vec::new(),
+            #[cfg(feature = "local-dns")]
+            local_dns_strategy: NameServerStrategy::default(),
+            #[cfg(feature = "local-dns")]
+            local_dns_timeout: None,
+            #[cfg(feature = "local-dns")]
+            remote_dns_timeout: None,
+            #[cfg(feature = "local-dns")]
+            dns_cache: None,
+            #[cfg(feature = "local-dns")]
+            fake_ip: None,
+            #[cfg(feature = "local-dns")]
+            dns_upstreams: HashMap::new(),
+            #[cfg(feature = "local-dns")]
+            dns_hosts: None,
+            #[cfg(feature = "local-dns-dnssec")]
+            dnssec: None,
+            #[cfg(feature = "local-dns-tls")]
+            dns_over_tls_addr: None,
+            #[cfg(feature = "local-dns-tls")]
+            dns_over_https_addr: None,
+
+            #[cfg(feature = "local-tun")]
+            tun_interface_name: None,
+            #[cfg(feature = "local-tun")]
+            tun_interface_address: None,
+            #[cfg(feature = "local-tun")]
+            tun_interface_address_auto: false,
+            #[cfg(feature = "local-tun")]
+            tun_tcp: TunTcpConfig::default(),
+            #[cfg(all(feature = "local-tun", unix))]
+            tun_device_fd: None,
+            #[cfg(all(feature = "local-tun", unix))]
+            tun_device_fd_from_path: None,
+
+            #[cfg(feature = "local-pac")]
+            pac_template_path: None,
+
+            ipv6_only: false,
+
+            bind_retries: 0,
+            bind_fallback_ports: Vec::new(),
+
+            tenant: None,
+
+            #[cfg(feature = "acl")]
+            acl: None,
+            server_group: None,
+            dns: None,
+            ipv6_first: None,
+
+            p2p_policy: P2pPolicy::Allow,
+
+            outbound_bind_interface: None,
+            outbound_bind_addr: None,
+            tcp_tuning: TcpTuning::default(),
+            relay_buffer_sizes: RelayBufferSizes::default(),
+
+            udp_timeout: None,
+            udp_max_associations: None,
+            #[cfg(feature = "local")]
+            udp_nat: None,
+
+            #[cfg(feature = "local")]
+            socks5_auth: Socks5AuthConfig::default(),
+            #[cfg(feature = "local")]
+            socks_hostname: SocksHostnamePolicy::default(),
+
+            #[cfg(feature = "local-http")]
+            http_auth: HttpAuthConfig::default(),
+            #[cfg(feature = "local-http")]
+            http_cache: None,
+
+            #[cfg(any(feature = "local-socks-tls", feature = "local-http-tls", feature = "local-dns-tls"))]
+            tls: None,
+        }
+    }
+
+    /// Create a new `LocalConfig` with listen address
+    pub fn new_with_addr(addr: ServerAddr, protocol: ProtocolType) -> LocalConfig {
+        let mut config = LocalConfig::new(protocol);
+        config.addr = Some(addr);
+        config
+    }
+
+    fn check_integrity(&self) -> Result<(), Error> {
+        #[cfg(unix)]
+        if self.unix_addr.is_some() {
+            match self.protocol {
+                ProtocolType::Socks => {}
+                #[cfg(feature = "local-http")]
+                ProtocolType::Http => {}
+                #[allow(unreachable_patterns)]
+                _ => {
+                    let err = Error::new(
+                        ErrorKind::Invalid,
+                        "unix domain socket address is only supported by socks and http",
+                        None,
+                    );
+                    return Err(err);
+                }
+            }
+
+            if self.mode.enable_udp() && self.udp_addr.is_none() {
+                let err = Error::new(
+                    ErrorKind::MissingField,
+                    "missing `local_udp_address` for UDP relay of unix domain socket local",
+                    None,
+                );
+                return Err(err);
+            }
+
+            if !self.bind_fallback_ports.is_empty() {
+                let err = Error::new(
+                    ErrorKind::Invalid,
+                    "`bind_fallback_ports` is not supported by unix domain socket local",
+                    None,
+                );
+                return Err(err);
+            }
+
+            return Ok(());
+        }
+
+        match self.protocol {
+            #[cfg(feature = "local-tun")]
+            ProtocolType::Tun => {}
+            #[cfg(feature = "local-tunnel")]
+            ProtocolType::Tunnel if self.addr.is_none() && self.forward_addr.is_none() => {}
+
+            _ => {
+                if self.addr.is_none() {
+                    let err = Error::new(ErrorKind::MissingField, "missing `addr` in configuration", None);
+                    return Err(err);
+                }
+            }
+        }
+
+        // Listeners bound while retrying are taken by servers, redir binds transparent sockets by itself
+        if self.bind_retries > 0 || !self.bind_fallback_ports.is_empty() {
+            match self.protocol {
+                #[cfg(feature = "local-redir")]
+                ProtocolType::Redir => {
+                    let err = Error::new(
+                        ErrorKind::Invalid,
+                        "`bind_retries` and `bind_fallback_ports` are not supported by redir local",
+                        None,
+                    );
+                    return Err(err);
+                }
+                #[cfg(feature = "local-tun")]
+                ProtocolType::Tun => {
+                    let err = Error::new(
+                        ErrorKind::Invalid,
+                        "`bind_retries` and `bind_fallback_ports` are not supported by tun local",
+                        None,
+                    );
+                    return Err(err);
+                }
+                _ => {}
+            }
+        }
+
+        match self.protocol {
+            #[cfg(feature = "local-dns")]
+            ProtocolType::Dns => {
+                #[allow(unused_mut)]
+                let mut has_remote_dns = self.remote_dns_addr.is_some();
+                #[cfg(feature = "local-dns-over-https")]
+                if self.remote_dns_https.is_some() {
+                    has_remote_dns = true;
+                }
+                #[cfg(any(feature = "local-dns-over-tls", feature = "local-dns-over-quic"))]
+                if self.remote_dns_secure.is_some() {
+                    has_remote_dns = true;
+                }
+
+                if self.local_dns_addr.is_none() || !has_remote_dns {
+                    let err = Error::new(
+                        ErrorKind::MissingField,
+                        "missing `local_dns_addr` or `remote_dns_addr` in configuration",
+                        None,
+                    );
+                    return Err(err);
+                }
+            }
+            #[cfg(feature = "local-tunnel")]
+            ProtocolType::Tunnel => {
+                if self.forward_addr.is_none() {
+                    if self.tunnel_mappings.is_empty() || self.addr.is_some() {
+                        let err = Error::new(ErrorKind::MissingField, "missing `forward_addr` in configuration", None);
+                        return Err(err);
+                    }
+                } else if self.addr.is_none() {
+                    let err = Error::new(ErrorKind::MissingField, "missing `addr` in configuration", None);
+                    return Err(err);
+                }
+            }
+
+            #[cfg(feature = "local-http")]
+            ProtocolType::Http | ProtocolType::Mixed => {
+                if !self.mode.enable_tcp() {
+                    let err = Error::new(
+                        ErrorKind::Invalid,
+                        "TCP mode have to be enabled for http and mixed",
+                        None,
+                    );
+                    return Err(err);
+                }
+            }
+
+            _ => {}
+        }
+
+        #[cfg(any(feature = "local-socks-tls", feature = "local-http-tls", feature = "local-dns-tls"))]
+        if self.tls.is_some() {
+            #[allow(unreachable_patterns)]
+            match self.protocol {
+                #[cfg(feature = "local-socks-tls")]
+                ProtocolType::Socks => {}
+                #[cfg(feature = "local-http-tls")]
+                ProtocolType::Http =>
+                {
+                    #[cfg(unix)]
+                    if self.unix_addr.is_some() {
+                        let err = Error::new(
+                            ErrorKind::Invalid,
+                            "`tls` is not supported by http on unix domain sockets",
+                            None,
+                        );
+                        return Err(err);
+                    }
+                }
+                #[cfg(feature = "local-dns-tls")]
+                ProtocolType::Dns => {
+                    if self.dns_over_tls_addr.is_none() && self.dns_over_https_addr.is_none() {
+                        let err = Error::new(
+                            ErrorKind::MissingField,
+                            "`tls` of dns requires `dns_over_tls_address` or `dns_over_https_address`",
+                            None,
+                        );
+                        return Err(err);
+                    }
+                }
+                _ => {
+                    let err = Error::new(ErrorKind::Invalid, "`tls` is not supported by this protocol", None);
+                    return Err(err);
+                }
+            }
+        }
+
+        // Transparent sockets of redir are bound by the server, the port must be known for redirecting to it
+        #[cfg(feature = "local-redir")]
+        if self.protocol == ProtocolType::Redir && self.addr.as_ref().map(|a| a.port()) == Some(0) {
+            let err = Error::new(ErrorKind::Invalid, "port 0 is not supported by redir", None);
+            return Err(err);
+        }
+
+        #[cfg(feature = "local-redir")]
+        if self.redir_listeners != 1 {
+            if self.protocol != ProtocolType::Redir {
+                let err = Error::new(ErrorKind::Invalid, "`redir_listeners` is only supported by redir", None);
+                return Err(err);
+            }
+            if self.redir_listeners == 0 {
+                let err = Error::new(ErrorKind::Malformed, "`redir_listeners` must be greater than 0", None);
+                return Err(err);
+            }
+            if cfg!(not(any(target_os = "linux", target_os = "android"))) {
+                let err = Error::new(
+                    ErrorKind::Invalid,
+                    "multiple `redir_listeners` requires SO_REUSEPORT load balancing, which is only supported on Linux",
+                    None,
+                );
+                return Err(err);
+            }
+        }
+
+        #[cfg(feature = "local-redir")]
+        if self.redir_firewall.is_some() {
+            if self.protocol != ProtocolType::Redir {
+                let err = Error::new(ErrorKind::Invalid, "`redir_firewall` is only supported by redir", None);
+                return Err(err);
+            }
+            if cfg!(not(any(target_os = "linux", target_os = "android"))) {
+                let err = Error::new(
+                    ErrorKind::Invalid,
+                    "`redir_firewall` requires iptables or nftables, which is only supported on Linux",
+                    None,
+                );
+                return Err(err);
+            }
+        }
+
+        #[cfg(feature = "local-redir-ebpf")]
+        if self.redir_ebpf {
+            if self.protocol != ProtocolType::Redir {
+                let err = Error::new(ErrorKind::Invalid, "`redir_ebpf` is only supported by redir", None);
+                return Err(err);
+            }
+            if cfg!(not(target_os = "linux")) {
+                let err = Error::new(
+                    ErrorKind::Invalid,
+                    "`redir_ebpf` requires eBPF sockmap, which is only supported on Linux",
+                    None,
+                );
+                return Err(err);
+            }
+        }
+
+        #[cfg(feature = "local-dns")]
+        if let Some(ref cache) = self.dns_cache {
+            if self.protocol != ProtocolType::Dns {
+                let err = Error::new(ErrorKind::Invalid, "`dns_cache` is only supported by dns", None);
+                return Err(err);
+            }
+            if cache.size == 0 {
+                let err = Error::new(ErrorKind::Malformed, "`dns_cache` size must be greater than 0", None);
+                return Err(err);
+            }
+        }
+
+        #[cfg(feature = "local-dns")]
+        if let Some(ref fake_ip) = self.fake_ip {
+            if self.protocol != ProtocolType::Dns {
+                let err = Error::new(ErrorKind::Invalid, "`fake_ip` is only supported by dns", None);
+                return Err(err);
+            }
+            let too_small = fake_ip.ipv4_range.prefix_len() > 30
+                || matches!(fake_ip.ipv6_range, Some(ref r) if r.prefix_len() > 126);
+            if too_small {
+                let err = Error::new(ErrorKind::Invalid, "`fake_ip` range is too small", None);
+                return Err(err);
+            }
+        }
+
+        #[cfg(feature = "local-dns")]
+        if !self.local_dns_extra_addrs.is_empty() && self.protocol != ProtocolType::Dns {
+            let err = Error::new(
+                ErrorKind::Invalid,
+                "`local_dns_extra_addresses` is only supported by dns",
+                None,
+            );
+            return Err(err);
+        }
+
+        #[cfg(feature = "local-dns")]
+        if !self.dns_upstreams.is_empty() {
+            if self.protocol != ProtocolType::Dns {
+                let err = Error::new(ErrorKind::Invalid, "`dns_upstreams` is only supported by dns", None);
+                return Err(err);
+            }
+            if let Some((group, ..)) = self.dns_upstreams.iter().find(|(_, upstream)| upstream.is_empty()) {
+                let err = Error::new(
+                    ErrorKind::MissingField,
+                    "`dns_upstreams` group without name servers",
+                    Some(format!("group: {}", group)),
+                );
+                return Err(err);
+            }
+        }
+
+        #[cfg(feature = "local-dns")]
+        if self.dns_hosts.is_some() && self.protocol != ProtocolType::Dns {
+            let err = Error::new(ErrorKind::Invalid, "`dns_hosts` is only supported by dns", None);
+            return Err(err);
+        }
+
+        #[cfg(feature = "local-dns-dnssec")]
+        if self.dnssec.is_some() && self.protocol != ProtocolType::Dns {
+            let err = Error::new(ErrorKind::Invalid, "`dnssec` is only supported by dns", None);
+            return Err(err);
+        }
+
+        #[cfg(feature = "local-dns-tls")]
+        if self.dns_over_tls_addr.is_some() || self.dns_over_https_addr.is_some() {
+            if self.protocol != ProtocolType::Dns {
+                let err = Error::new(
+                    ErrorKind::Invalid,
+                    "`dns_over_tls_address` and `dns_over_https_address` are only supported by dns",
+                    None,
+                );
+                return Err(err);
+            }
+            if self.tls.is_none() {
+                let err = Error::new(
+                    ErrorKind::MissingField,
+                    "`dns_over_tls_address` and `dns_over_https_address` require `tls`",
+                    None,
+                );
+                return Err(err);
+            }
+        }
+
+        #[cfg(any(feature = "local-redir", feature = "local-tun"))]
+        if self.preserve_ttl {
+            match self.protocol {
+                #[cfg(feature = "local-redir")]
+                ProtocolType::Redir => {
+                    if cfg!(not(any(target_os = "linux", target_os = "android"))) {
+                        let err = Error::new(
+                            ErrorKind::Invalid,
+                            "`preserve_ttl` of redir requires TCP_SAVED_SYN, which is only supported on Linux",
+                            None,
+                        );
+                        return Err(err);
+                    }
+                }
+                #[cfg(feature = "local-tun")]
+                ProtocolType::Tun => {}
+                _ => {
+                    let err = Error::new(
+                        ErrorKind::Invalid,
+                        "`preserve_ttl` is only supported by redir and tun",
+                        None,
+                    );
+                    return Err(err);
+                }
+            }
+        }
+
+        #[cfg(feature = "local-tun")]
+        if self.tun_tcp != TunTcpConfig::default() {
+            if self.protocol != ProtocolType::Tun {
+                let err = Error::new(ErrorKind::Invalid, "`tun_tcp` is only supported by tun", None);
+                return Err(err);
+            }
+            if self.tun_tcp.send_buffer_size == Some(0) || self.tun_tcp.recv_buffer_size == Some(0) {
+                let err = Error::new(
+                    ErrorKind::Malformed,
+                    "`tun_tcp` buffer sizes must be greater than 0",
+                    None,
+                );
+                return Err(err);
+            }
+            if self.tun_tcp.timeout == Some(Duration::ZERO) {
+                let err = Error::new(ErrorKind::Malformed, "`tun_tcp` timeout must be greater than 0", None);
+                return Err(err);
+            }
+        }
+
+        #[cfg(feature = "local-http")]
+        if let Some(ref cache) = self.http_cache {
+            if self.protocol != ProtocolType::Http && self.protocol != ProtocolType::Mixed {
+                let err = Error::new(
+                    ErrorKind::Invalid,
+                    "`http_cache` is only supported by http and mixed",
+                    None,
+                );
+                return Err(err);
+            }
+            if cache.capacity == 0 {
+                let err = Error::new(ErrorKind::Malformed, "`http_cache` size must be greater than 0", None);
+                return Err(err);
+            }
+        }
+
+        Ok(())
+    }
+
+    // Check if it is a basic format of local
+    pub fn is_basic(&self) -> bool {
+        if self.protocol != ProtocolType::Socks
+            || self.udp_addr.is_some()
+            || self.tenant.is_some()
+            || self.server_group.is_some()
+            || self.dns.is_some()
+            || self.ipv6_first.is_some()
+            || !self.p2p_policy.is_allow()
+            || self.bind_retries > 0
+            || !self.bind_fallback_ports.is_empty()
+            || self.outbound_bind_interface.is_some()
+            || self.outbound_bind_addr.is_some()
+            || !self.tcp_tuning.is_empty()
+            || !self.relay_buffer_sizes.is_default()
+            || self.udp_timeout.is_some()
+            || self.udp_max_associations.is_some()
+        {
+            return false;
+        }
+
+        #[cfg(feature = "local")]
+        if self.udp_nat.is_some() {
+            return false;
+        }
+
+        #[cfg(feature = "acl")]
+        if self.acl.is_some() {
+            return false;
+        }
+
+        #[cfg(unix)]
+        if self.unix_addr.is_some() {
+            return false;
+        }
+
+        #[cfg(feature = "local")]
+        if self.socks_hostname != SocksHostnamePolicy::default() {
+            return false;
+        }
+
+        #[cfg(feature = "local-tunnel")]
+        if self.forward_addr.is_some() || !self.tunnel_mappings.is_empty() {
+            return false;
+        }
+
+        #[cfg(feature = "local-redir")]
+        if self.tcp_redir != RedirType::tcp_default()
+            || self.udp_redir != RedirType::udp_default()
+            || self.redir_listeners != 1
+            || self.redir_firewall.is_some()
+        {
+            return false;
+        }
+
+        #[cfg(feature = "local-redir-ebpf")]
+        if self.redir_ebpf {
+            return false;
+        }
+
+        #[cfg(any(feature = "local-redir", feature = "local-tun"))]
+        if self.preserve_ttl {
+            return false;
+        }
+
+        #[cfg(feature = "local-dns")]
+        if self.local_dns_addr.is_some() || self.remote_dns_addr.is_some() {
+            return false;
+        }
+
+        #[cfg(feature = "local-dns-over-https")]
+        if self.remote_dns_https.is_some() {
+            return false;
+        }
+
+        #[cfg(any(feature = "local-dns-over-tls", feature = "local-dns-over-quic"))]
+        if self.remote_dns_secure.is_some() {
+            return false;
+        }
+
+        #[cfg(feature = "local-dns")]
+        if self.local_dns_timeout.is_some()
+            || !self.local_dns_extra_addrs.is_empty()
+            || self.local_dns_strategy != NameServerStrategy::default()
+            || self.remote_dns_timeout.is_some()
+            || self.dns_cache.is_some()
+            || self.fake_ip.is_some()
+            || !self.dns_upstreams.is_empty()
+            || self.dns_hosts.is_some()
+        {
+            return false;
+        }
+
+        #[cfg(feature = "local-dns-dnssec")]
+        if self.dnssec.is_some() {
+            return false;
+        }
+
+        #[cfg(any(feature = "local-socks-tls", feature = "local-http-tls", feature = "local-dns-tls"))]
+        if self.tls.is_some() {
+            return false;
+        }
+
+        true
+    }
+}
+
+/// TLS configuration for local servers and manager's SIP008 server
+#[cfg(any(
+    feature = "local-socks-tls",
+    feature = "local-http-tls",
+    feature = "local-dns-tls",
+    feature = "manager-sip008"
+))]
+#[derive(Clone, Debug)]
+pub struct LocalTlsConfig {
+    /// Certificate chain in PEM format
+    pub certificate_path: PathBuf,
+    /// Private key in PEM format
+    pub private_key_path: PathBuf,
+    /// CA certificates in PEM format for verifying clients' certificates
+    ///
+    /// Clients won't be required to send certificates if not set
+    pub client_ca_path: Option<PathBuf>,
+}
+
+#[derive(Clone, Debug)]
+pub enum DnsConfig {
+    System,
+    #[cfg(feature = "trust-dns")]
+    TrustDns(ResolverConfig),
+    #[cfg(feature = "local-dns")]
+    LocalDns(NameServerAddr),
+}
+
+impl Default for DnsConfig {
+    fn default() -> DnsConfig {
+        DnsConfig::System
+    }
+}
+
+/// Security Config
+#[derive(Clone, Debug, Default)]
+pub struct SecurityConfig {
+    pub replay_attack: SecurityReplayAttackConfig,
+    pub clock_skew: SecurityClockSkewConfig,
+}
+
+/// Timestamps of AEAD 2022 peers, which are rejected if they are too far from the local clock
+#[derive(Clone, Debug, Default)]
+pub struct SecurityClockSkewConfig {
+    /// Maximum difference between timestamps of peers and the local clock, 30 seconds if not set
+    pub tolerance: Option<Duration>,
+    /// NTP server that the local clock is checked against on startup, only logged if they differ by more than
+    /// `tolerance`
+    pub ntp_server: Option<ServerAddr>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct SecurityReplayAttackConfig {
+    pub policy: ReplayAttackPolicy,
+    /// Share nonces and traffic counters with the other servers (server only)
+    #[cfg(feature = "server")]
+    pub cluster: Option<ReplayClusterConfig>,
+    /// Save nonces to disk, so they are still rejected after restart (server only)
+    #[cfg(feature = "server")]
+    pub persist: Option<ReplayPersistConfig>,
+}
+
+/// Servers telling each other the nonces (IV/salt) they have accepted, so connections captured by one of them
+/// couldn't be replayed to the others, and their traffic counters, so quotas are used up by the traffic of all of them
+#[cfg(feature = "server")]
+#[derive(Clone, Debug)]
+pub struct ReplayClusterConfig {
+    /// UDP address receiving nonces and traffic counters from peers
+    pub listen_addr: SocketAddr,
+    /// `listen_addr` of the other servers
+    pub peers: Vec<ServerAddr>,
+    /// Password shared by all servers of the cluster, for encrypting and authenticating packets sent between them
+    pub password: String,
+}
+
+/// Nonces (IV/salt) recorded by servers are saved periodically and restored on startup, so connections captured
+/// before restarts couldn't be replayed
+#[cfg(feature = "server")]
+#[derive(Clone, Debug)]
+pub struct ReplayPersistConfig {
+    /// Directory of the saved nonces, one file per server port
+    pub path: PathBuf,
+    /// Interval of saving nonces
+    pub interval: Duration,
+}
+
+#[cfg(feature = "server")]
+impl ReplayPersistConfig {
+    /// Create a configuration saving nonces in `path`
+    pub fn new(path: PathBuf) -> ReplayPersistConfig {
+        ReplayPersistConfig {
+            path,
+            interval: Duration::from_secs(60),
+        }
+    }
+}
+
+/// Listening sockets handoff configuration
+#[cfg(unix)]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HandoffConfig {
+    /// Unix socket path, a new process connects to the running one for taking over its listening sockets
+    pub path: PathBuf,
+    /// After handing off, the old process keeps serving established connections for this duration before exiting
+    pub drain_timeout: Duration,
+}
+
+#[cfg(unix)]
+impl HandoffConfig {
+    /// Create a configuration with the Unix socket `path`
+    pub fn new(path: PathBuf) -> HandoffConfig {
+        HandoffConfig {
+            path,
+            drain_timeout: Duration::from_secs(300),
+        }
+    }
+}
+
+/// Strategy of choosing servers for new connections
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum BalancerStrategy {
+    /// Server with the best score, which is calculated from latencies, failures and `tcp_weight` / `udp_weight`
+    #[default]
+    Best,
+    /// Alive servers in turn, proportional to their `tcp_weight` / `udp_weight`
+    Weighted,
+    /// Alive server chosen by hashing the target host, so connections to the same host exit from the same server
+    TargetHash,
+    /// Alive server chosen by hashing the client's IP, so connections of the same client exit from the same server
+    ClientHash,
+    /// Alive server with the highest priority, which is the order in configuration. Switches only if the server
+    /// failed, and switches back after the higher priority server has been alive for `failback_window`
+    Failover,
+}
+
+/// Parsing BalancerStrategy error
+#[derive(Debug, Clone, Copy)]
+pub struct BalancerStrategyError;
+
+impl Display for BalancerStrategyError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str("invalid BalancerStrategy")
+    }
+}
+
+impl FromStr for BalancerStrategy {
+    type Err = BalancerStrategyError;
+
+    fn from_str(s: &str) -> Result<BalancerStrategy, Self::Err> {
+        match s {
+            "best" => Ok(BalancerStrategy::Best),
+            "weighted" => Ok(BalancerStrategy::Weighted),
+            "target_hash" => Ok(BalancerStrategy::TargetHash),
+            "client_hash" => Ok(BalancerStrategy::ClientHash),
+            "failover" => Ok(BalancerStrategy::Failover),
+            _ => Err(BalancerStrategyError),
+        }
+    }
+}
+
+impl Display for BalancerStrategy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            BalancerStrategy::Best => f.write_str("best"),
+            BalancerStrategy::Weighted => f.write_str("weighted"),
+            BalancerStrategy::TargetHash => f.write_str("target_hash"),
+            BalancerStrategy::ClientHash => f.write_str("client_hash"),
+            BalancerStrategy::Failover => f.write_str("failover"),
+        }
+    }
+}
+
+/// Health-check probe of TCP servers
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum BalancerTcpProbe {
+    /// Succeeded when the TCP connection with the server is established
+    Tcp,
+    /// Succeeded when the target answers a TLS ClientHello with a ServerHello through the server
+    Tls(Address),
+    /// Succeeded when the target answers an HTTP GET with a 2xx status through the server
+    Http {
+        /// Address of the HTTP server
+        addr: Address,
+        /// Value of the `Host` header
+        host: String,
+        /// Path of the request
+        path: String,
+    },
+}
+
+impl BalancerTcpProbe {
+    /// Name of the probe's protocol
+    pub fn protocol(&self) -> &'static str {
+        match *self {
+            BalancerTcpProbe::Tcp => "tcp",
+            BalancerTcpProbe::Tls(..) => "tls",
+            BalancerTcpProbe::Http { .. } => "http",
+        }
+    }
+
+    /// Parse an `http://` URL as a HTTP probe
+    pub fn from_http_url(url: &str) -> Option<BalancerTcpProbe> {
+        let url = match url.get(..7) {
+            Some(scheme) if scheme.eq_ignore_ascii_case("http://") => &url[7..],
+            _ => return None,
+        };
+
+        let (host, path) = match url.find(['/', '?']) {
+            Some(pos) if url.as_bytes()[pos] == b'/' => (&url[..pos], url[pos..].to_owned()),
+            Some(pos) => (&url[..pos], format!("/{}", &url[pos..])),
+            None => (url, "/".to_owned()),
+        };
+        if host.is_empty() {
+            return None;
+        }
+
+        // Port is optional in URLs, but not in `Address`
+        let addr = match host.parse::<Address>() {
+            Ok(addr) => addr,
+            Err(..) => format!("{}:80", host).parse::<Address>().ok()?,
+        };
+
+        Some(BalancerTcpProbe::Http {
+            addr,
+            host: host.to_owned(),
+            path,
+        })
+    }
+}
+
+impl Display for BalancerTcpProbe {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            BalancerTcpProbe::Tcp => f.write_str("tcp"),
+            BalancerTcpProbe::Tls(ref addr) => write!(f, "tls://{}", addr),
+            BalancerTcpProbe::Http { ref host, ref path, .. } => write!(f, "http://{}{}", host, path),
+        }
+    }
+}
+
+/// Health-check probes of balancer
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct BalancerProbeConfig {
+    /// Probe of TCP servers
+    pub tcp: BalancerTcpProbe,
+    /// DNS server queried through UDP servers
+    pub udp_target: Address,
+    /// Number of probes sent to each server in every check
+    pub samples: usize,
+}
+
+impl Default for BalancerProbeConfig {
+    fn default() -> BalancerProbeConfig {
+        BalancerProbeConfig {
+            tcp: BalancerTcpProbe::from_http_url("http://detectportal.firefox.com/success.txt")
+                .expect("default HTTP probe"),
+            udp_target: Address::SocketAddress(SocketAddr::new(Ipv4Addr::new(8, 8, 8, 8).into(), 53)),
+            samples: 1,
+        }
+    }
+}
+
+/// Algorithm of estimating servers' latency and its variation from checks, for scoring servers
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum BalancerScoringAlgorithm {
+    /// Median of latencies, varied by their standard deviation
+    Median,
+    /// Exponentially weighted moving average of latencies, varied by their standard deviation
+    Ewma,
+    /// Percentile of latencies (p95 by default), varied by their standard deviation
+    Percentile,
+    /// Median of latencies, varied by jitter (mean difference between consecutive latencies), which weighs more
+    Jitter,
+    /// Median of latencies, varied by their standard deviation, failures weigh much more
+    Loss,
+}
+
+/// Parsing BalancerScoringAlgorithm error
+#[derive(Debug, Clone, Copy)]
+pub struct BalancerScoringAlgorithmError;
+
+impl Display for BalancerScoringAlgorithmError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str("invalid BalancerScoringAlgorithm")
+    }
+}
+
+impl FromStr for BalancerScoringAlgorithm {
+    type Err = BalancerScoringAlgorithmError;
+
+    fn from_str(s: &str) -> Result<BalancerScoringAlgorithm, Self::Err> {
+        match s {
+            "median" => Ok(BalancerScoringAlgorithm::Median),
+            "ewma" => Ok(BalancerScoringAlgorithm::Ewma),
+            "percentile" => Ok(BalancerScoringAlgorithm::Percentile),
+            "jitter" => Ok(BalancerScoringAlgorithm::Jitter),
+            "loss" => Ok(BalancerScoringAlgorithm::Loss),
+            _ => Err(BalancerScoringAlgorithmError),
+        }
+    }
+}
+
+impl Display for BalancerScoringAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            BalancerScoringAlgorithm::Median => f.write_str("median"),
+            BalancerScoringAlgorithm::Ewma => f.write_str("ewma"),
+            BalancerScoringAlgorithm::Percentile => f.write_str("percentile"),
+            BalancerScoringAlgorithm::Jitter => f.write_str("jitter"),
+            BalancerScoringAlgorithm::Loss => f.write_str("loss"),
+        }
+    }
+}
+
+/// Scoring of servers, lower scores are better
+///
+/// Score = (normalized latency * `rtt_weight` + failure rate * `failure_weight` + normalized variation *
+/// `variation_weight`) / (sum of weights) / user's weight
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BalancerScoringConfig {
+    /// Algorithm of estimating latency and its variation
+    pub algorithm: BalancerScoringAlgorithm,
+    /// Weight of the latest latency in EWMA, in (0, 1]
+    pub ewma_alpha: f64,
+    /// Percentile of latencies, in (0, 100]
+    pub percentile: f64,
+    /// Weight of latency
+    pub rtt_weight: f64,
+    /// Weight of failure rate
+    pub failure_weight: f64,
+    /// Weight of latency's variation
+    pub variation_weight: f64,
+}
+
+impl BalancerScoringConfig {
+    /// Create a scoring configuration with default parameters of `algorithm`
+    pub fn new(algorithm: BalancerScoringAlgorithm) -> BalancerScoringConfig {
+        let (rtt_weight, failure_weight, variation_weight) = match algorithm {
+            BalancerScoringAlgorithm::Median
+            | BalancerScoringAlgorithm::Ewma
+            | BalancerScoringAlgorithm::Percentile => (1.0, 3.0, 1.0),
+            BalancerScoringAlgorithm::Jitter => (1.0, 3.0, 3.0),
+            BalancerScoringAlgorithm::Loss => (1.0, 9.0, 1.0),
+        };
+
+        BalancerScoringConfig {
+            algorithm,
+            ewma_alpha: 0.3,
+            percentile: 95.0,
+            rtt_weight,
+            failure_weight,
+            variation_weight,
+        }
+    }
+}
+
+impl Default for BalancerScoringConfig {
+    fn default() -> BalancerScoringConfig {
+        BalancerScoringConfig::new(BalancerScoringAlgorithm::Median)
+    }
+}
+
+/// Connections established ahead of requests to the top-ranked servers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BalancerWarmPoolConfig {
+    /// Connections kept for each server
+    pub size: usize,
+    /// Number of the top-ranked servers having pools
+    pub servers: usize,
+    /// Connections unused for this long are closed, which should be shorter than servers' and middleboxes' timeouts
+    pub idle_timeout: Duration,
+}
+
+impl Default for BalancerWarmPoolConfig {
+    fn default() -> BalancerWarmPoolConfig {
+        BalancerWarmPoolConfig {
+            size: 2,
+            servers: 1,
+            idle_timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+/// Balancer Config
+#[derive(Clone, Debug, Default)]
+pub struct BalancerConfig {
+    /// MAX rtt of servers, which is the timeout duration of each check requests
+    pub max_server_rtt: Option<Duration>,
+    /// Interval between each checking
+    pub check_interval: Option<Duration>,
+    /// Interval for checking the best server
+    pub check_best_interval: Option<Duration>,
+    /// Strategy of choosing servers
+    pub strategy: BalancerStrategy,
+    /// Duration of a higher priority server being alive before switching back to it, for `BalancerStrategy::Failover`
+    pub failback_window: Option<Duration>,
+    /// Strategies of server groups, which are servers tagged with the group's name
+    ///
+    /// Servers of groups without strategies are chosen by score.
+    pub groups: BTreeMap<String, BalancerStrategy>,
+    /// Group of servers proxying targets that are not routed by ACL's `[route:TAG]` rules, all servers by default
+    pub default_group: Option<String>,
+    /// Health-check probes
+    pub probe: BalancerProbeConfig,
+    /// Scoring of servers
+    pub scoring: BalancerScoringConfig,
+    /// Connections established ahead of requests, disabled by default
+    pub warm_pool: Option<BalancerWarmPoolConfig>,
+}
+
+/// Configuration
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// Remote ShadowSocks server configurations
+    pub server: Vec<ServerConfig>,
+    /// Local server configuration
+    pub local: Vec<LocalConfig>,
+
+    /// DNS configuration, uses system-wide DNS configuration by default
+    ///
+    /// Value could be a `IpAddr`, uses UDP DNS protocol with port `53`. For example: `8.8.8.8`
+    ///
+    /// Also Value could be some pre-defined DNS server names:
+    ///
+    /// - `google`
+    /// - `cloudflare`, `cloudflare_tls`, `cloudflare_https`
+    /// - `quad9`, `quad9_tls`
+    pub dns: DnsConfig,
+    /// DNS configuration for hostnames of servers, resolved by `dns` if not set
+    ///
+    /// Plaintext answers of servers' hostnames are often poisoned, encrypted nameservers with pinned IP addresses
+    /// could be used for them, like `tls://1.1.1.1#cloudflare-dns.com` or `https://8.8.8.8#dns.google`.
+    pub server_dns: Option<DnsConfig>,
+    /// Uses IPv6 addresses first
+    ///
+    /// Set to `true` if you want to query IPv6 addresses before IPv4
+    pub ipv6_first: bool,
+    /// Set `IPV6_V6ONLY` for listener sockets
+    pub ipv6_only: bool,
+    /// Strategy of choosing among resolved IPv4 and IPv6 addresses of outbound connections
+    ///
+    /// Overrides `ipv6_first` for connections, which could be overridden by ACL's `[ip_strategy:STRATEGY]` sections
+    pub ip_strategy: IpStrategy,
+
+    /// Set `TCP_NODELAY` socket option
+    pub no_delay: bool,
+    /// Set `TCP_FASTOPEN` socket option
+    pub fast_open: bool,
+    /// Set TCP Keep-Alive duration, will set both `TCP_KEEPIDLE` and `TCP_KEEPINTVL`
+    ///
+    /// <https://github.com/shadowsocks/shadowsocks-rust/issues/546>
+    ///
+    /// If this is not set, sockets will be set with a default timeout
+    pub keep_alive: Option<Duration>,
+
+    /// `RLIMIT_NOFILE` option for *nix systems
+    #[cfg(all(unix, not(target_os = "android")))]
+    pub nofile: Option<u64>,
+
+    /// Receive and send with io_uring instead of epoll, for TCP streams and batches of UDP datagrams (Linux only)
+    #[cfg(feature = "io-uring")]
+    pub io_uring: bool,
+
+    /// Buffers shared by TCP and UDP relays
+    pub buffer_pool: BufferPoolConfig,
+
+    /// Send batches of UDP datagrams with GSO, and receive datagrams from servers or clients with GRO (Linux and
+    /// Android)
+    pub udp_offload: bool,
+
+    /// Set `SO_MARK` socket option for outbound sockets
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub outbound_fwmark: Option<u32>,
+    /// Set `SO_USER_COOKIE` socket option for outbound sockets
+    #[cfg(target_os = "freebsd")]
+    pub outbound_user_cookie: Option<u32>,
+    /// Set `SO_BINDTODEVICE` (Linux), `IP_BOUND_IF` (BSD), `IP_UNICAST_IF` (Windows) socket option for outbound sockets
+    pub outbound_bind_interface: Option<String>,
+    /// Outbound sockets will `bind` to this address
+    pub outbound_bind_addr: Option<IpAddr>,
+    /// Path to protect callback unix address, only for Android
+    #[cfg(target_os = "android")]
+    pub outbound_vpn_protect_path: Option<PathBuf>,
+    /// Callback protecting outbound sockets before they connect, set by frontends embedding the service
+    #[cfg(unix)]
+    pub outbound_socket_protector: Option<SocketProtector>,
+
+    /// Set `SO_SNDBUF` for inbound sockets
+    pub inbound_send_buffer_size: Option<u32>,
+    /// Set `SO_RCVBUF` for inbound sockets
+    pub inbound_recv_buffer_size: Option<u32>,
+    /// Set `SO_SNDBUF` for outbound sockets
+    pub outbound_send_buffer_size: Option<u32>,
+    /// Set `SO_RCVBUF` for outbound sockets
+    pub outbound_recv_buffer_size: Option<u32>,
+
+    /// Manager's configuration
+    pub manager: Option<ManagerConfig>,
+
+    /// Config is for Client or Server
+    pub config_type: ConfigType,
+
+    /// Timeout for UDP Associations, default is 5 minutes
+    pub udp_timeout: Option<Duration>,
+    /// Maximum number of UDP Associations, default is unconfigured
+    pub udp_max_associations: Option<usize>,
+    /// NAT filtering and timeouts by target ports of UDP Associations of locals, default is full cone
+    #[cfg(feature = "local")]
+    pub udp_nat: Option<UdpNatConfig>,
+    /// Migrate UDP Associations to another server when their server is marked dead by the balancer
+    ///
+    /// Only applies to local servers, associations are re-established transparently with the new best server.
+    pub udp_migration: bool,
+    /// Connect targets directly, except those rejected by ACL, while the balancer marks all servers down
+    ///
+    /// Only applies to local servers, targets are proxied again once any server recovers.
+    pub direct_fallback: bool,
+    /// Initial routing mode of local servers, which could be switched at runtime by the control socket
+    #[cfg(feature = "local")]
+    pub routing_mode: RoutingMode,
+
+    /// Report servers and ACL rules handling connections of SOCKS and HTTP local servers, for debugging routes
+    ///
+    /// Routes are logged with IDs, which are also sent to HTTP clients in the `X-SS-Route` response header.
+    pub route_debug: bool,
+
+    /// Relay bypassed TCP tunnels of local servers with `splice(2)`, without copying data to the userspace
+    ///
+    /// Only applies to tunnels between plain TCP sockets, the others are relayed as usual. Only supported on Linux and
+    /// Android.
+    pub tcp_splice: bool,
+
+    /// ACL configuration
+    #[cfg(feature = "acl")]
+    pub acl: Option<AccessControl>,
+
+    /// Log ACL decisions of TCP connections of local servers, with the rules (and their lines) deciding them
+    #[cfg(feature = "acl")]
+    pub acl_trace: bool,
+
+    /// Address of the HTTP server of Prometheus metrics, served in `/metrics`
+    #[cfg(feature = "metrics")]
+    pub metrics_addr: Option<ServerAddr>,
+
+    /// Flow statistic report Unix socket path (only for Android)
+    #[cfg(feature = "local-flow-stat")]
+    pub stat_path: Option<PathBuf>,
+
+    /// Replay attack policy
+    pub security: SecurityConfig,
+
+    /// Balancer config of local server
+    pub balancer: BalancerConfig,
+
+    /// Isolate every local servers that doesn't belong to a tenant
+    ///
+    /// By default, locals without `tenant` share the same DNS resolver, caches and statistic counters.
+    pub isolate_locals: bool,
+
+    /// What to do with BitTorrent traffic
+    ///
+    /// Default policy of locals. `P2pPolicy::Route` is not supported by servers.
+    pub p2p_policy: P2pPolicy,
+
+    /// Directory for saving persistent states
+    ///
+    /// States are kept in memory if neither `state_dir` nor `state_store` is set.
+    pub state_dir: Option<PathBuf>,
+    /// Storage backend for persistent states, supplied by embedders. Takes precedence over `state_dir`.
+    pub state_store: Option<Arc<dyn StateStore>>,
+    /// Save active connections of locals into the state store on shutdown, and summarize connections cut by the
+    /// shutdown on the next start
+    #[cfg(feature = "local")]
+    pub export_connections: bool,
+    /// Point proxy settings of the operating system to HTTP and SOCKS locals while running, and restore the previous
+    /// settings on exit. Supported on Windows, macOS, and GNOME of Linux and BSDs.
+    ///
+    /// Settings left by a crashed process are restored on the next start, if they are persisted by `state_dir`.
+    #[cfg(feature = "local")]
+    pub system_proxy: bool,
+    /// Control socket of locals, listing active connections and terminating them. A TCP address or the path of a
+    /// Unix socket.
+    #[cfg(feature = "local")]
+    pub control_addr: Option<ManagerAddr>,
+    /// Pre-shared key of control commands, which are authenticated like requests of the manager. Required by TCP
+    /// addresses of `control_addr`
+    #[cfg(feature = "local")]
+    pub control_key: Option<String>,
+    /// Export records of closed connections of locals to an IPFIX collector
+    #[cfg(feature = "local")]
+    pub flow_export: Option<FlowExportConfig>,
+
+    /// Hand off listening sockets to a new process started with the same configuration, for binary upgrades
+    #[cfg(unix)]
+    pub handoff: Option<HandoffConfig>,
+
+    /// Bandwidth priority classes of local TCP tunnels
+    #[cfg(feature = "local")]
+    pub priority: Option<PriorityConfig>,
+
+    /// Negative cache of the resolver for outbound connections of locals, enabled by default
+    #[cfg(feature = "local")]
+    pub resolver_cache: Option<ResolverCacheConfig>,
+
+    /// Bandwidth limit shared by all servers, in bytes per second of each direction
+    pub global_rate_limit: Option<u64>,
+
+    /// Outbound firewall shared by all servers
+    #[cfg(feature = "server")]
+    pub outbound_firewall: Option<FirewallConfig>,
+
+    /// Limits of connections and associations of each client, shared by all servers
+    #[cfg(feature = "server")]
+    pub client_limit: Option<ClientLimitConfig>,
+
+    /// Which UDP association of a server is evicted when there are `udp_max_associations`
+    #[cfg(feature = "server")]
+    pub udp_eviction: Option<UdpEvictionConfig>,
+
+    /// Drain servers on shutdown, keeping established connections and associations for at most this duration
+    #[cfg(feature = "server")]
+    pub drain_timeout: Option<Duration>,
+
+    /// Faults injected into local relays, for testing
+    #[cfg(feature = "local-fault-injection")]
+    pub fault_injection: Option<FaultInjectionConfig>,
+
+    /// SIP008 online configuration subscription, whose servers are merged with `server`
+    #[cfg(feature = "local-online-config")]
+    pub online_config: Option<OnlineConfig>,
+
+    /// Names of profiles in the configuration file, which could be switched at runtime
+    pub profiles: Vec<String>,
+    /// The applied profile, replacing locals, servers, balancer and ACL of the configuration file
+    pub profile: Option<String>,
+
+    /// Configuration file path, the actual path of the configuration.
+    /// This is normally for auto-reloading if implementation supports.
+    pub config_path: Option<PathBuf>,
+}
+
+/// Format of configuration files
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigFormat {
+    /// JSON, with extensions of JSON5 like comments and trailing commas
+    Json,
+    /// YAML, requires feature `config-yaml`
+    Yaml,
+    /// TOML, requires feature `config-toml`
+    Toml,
+}
+
+impl ConfigFormat {
+    /// Detect format by extension of `path`, `.yaml` or `.yml` for YAML, `.toml` for TOML, JSON for the others
+    pub fn from_path<P: AsRef<Path>>(path: P) -> ConfigFormat {
+        let ext = path.as_ref().extension().and_then(|e| e.to_str()).unwrap_or_default();
+        if ext.eq_ignore_ascii_case("yaml") || ext.eq_ignore_ascii_case("yml") {
+            ConfigFormat::Yaml
+        } else if ext.eq_ignore_ascii_case("toml") {
+            ConfigFormat::Toml
+        } else {
+            ConfigFormat::Json
+        }
+    }
+
+    /// Deserialize `s` in this format
+    pub fn deserialize<T: DeserializeOwned>(self, s: &str) -> Result<T, Error> {
+        match self {
+            ConfigFormat::Json => Ok(json5::from_str(s)?),
+            #[cfg(feature = "config-yaml")]
+            ConfigFormat::Yaml => Ok(serde_yaml::from_str(s)?),
+            #[cfg(not(feature = "config-yaml"))]
+            ConfigFormat::Yaml => Err(Error::new(
+                ErrorKind::YamlParsingError,
+                "YAML configuration requires feature \"config-yaml\"",
+                None,
+            )),
+            #[cfg(feature = "config-toml")]
+            ConfigFormat::Toml => Ok(toml::from_str(s)?),
+            #[cfg(not(feature = "config-toml"))]
+            ConfigFormat::Toml => Err(Error::new(
+                ErrorKind::TomlParsingError,
+                "TOML configuration requires feature \"config-toml\"",
+                None,
+            )),
+        }
+    }
+}
+
+/// Configuration parsing error kind
+#[derive(Copy, Clone, Debug)]
+pub enum ErrorKind {
+    /// Missing required fields in JSON configuration
+    MissingField,
+    /// Missing some keys that must be provided together
+    Malformed,
+    /// Invalid value of some configuration keys
+    Invalid,
+    /// Invalid JSON
+    JsonParsingError,
+    /// Invalid YAML
+    YamlParsingError,
+    /// Invalid TOML
+    TomlParsingError,
+    /// `std::io::Error`
+    IoError,
+}
+
+/// Configuration parsing error
+pub struct Error {
+    pub kind: ErrorKind,
+    pub desc: &'static str,
+    pub detail: Option<String>,
+}
+
+impl Error {
+    pub fn new(kind: ErrorKind, desc: &'static str, detail: Option<String>) -> Error {
+        Error { kind, desc, detail }
+    }
+}
+
+macro_rules! impl_from {
+    ($error:ty, $kind:expr, $desc:expr) => {
+        impl From<$error> for Error {
+            fn from(err: $error) -> Self {
+                Error::new($kind, $desc, Some(format!("{:?}", err)))
+            }
+        }
+    };
+}
+
+impl_from!(::std::io::Error, ErrorKind::IoError, "error while reading file");
+impl_from!(json5::Error, ErrorKind::JsonParsingError, "json parse error");
+impl_from!(serde_json::Error, ErrorKind::JsonParsingError, "json parse error");
+#[cfg(feature = "config-yaml")]
+impl_from!(serde_yaml::Error, ErrorKind::YamlParsingError, "yaml parse error");
+#[cfg(feature = "config-toml")]
+impl_from!(toml::de::Error, ErrorKind::TomlParsingError, "toml parse error");
+
+impl Debug for Error {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self.detail {
+            None => write!(f, "{}", self.desc),
+            Some(ref det) => write!(f, "{} {}", self.desc, det),
+        }
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self.detail {
+            None => f.write_str(self.desc),
+            Some(ref d) => write!(f, "{}, {}", self.desc, d),
+        }
+    }
+}
+
+impl Config {
+    /// Creates an empty configuration
+    pub fn new(config_type: ConfigType) -> Config {
+        Config {
+            server: Vec::new(),
+            local: Vec::new(),
+
+            dns: DnsConfig::default(),
+            server_dns: None,
+            ipv6_first: false,
+            ipv6_only: false,
+            ip_strategy: IpStrategy::default(),
+
+            no_delay: false,
+            fast_open: false,
+            keep_alive: None,
+
+            #[cfg(all(unix, not(target_os = "android")))]
+            nofile: None,
+
+            #[cfg(feature = "io-uring")]
+            io_uring: false,
+
+            buffer_pool: BufferPoolConfig::default(),
+
+            udp_offload: false,
+
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            outbound_fwmark: None,
+            #[cfg(target_os = "freebsd")]
+            outbound_user_cookie: None,
+            outbound_bind_interface: None,
+            outbound_bind_addr: None,
+            #[cfg(target_os = "android")]
+            outbound_vpn_protect_path: None,
+            #[cfg(unix)]
+            outbound_socket_protector: None,
+
+            inbound_send_buffer_size: None,
+            inbound_recv_buffer_size: None,
+            outbound_send_buffer_size: None,
+            outbound_recv_buffer_size: None,
+
+            manager: None,
+
+            config_type,
+
+            udp_timeout: None,
+            udp_max_associations: None,
+            #[cfg(feature = "local")]
+            udp_nat: None,
+            udp_migration: false,
+            direct_fallback: false,
+            #[cfg(feature = "local")]
+            routing_mode: RoutingMode::default(),
+            route_debug: false,
+            tcp_splice: false,
+
+            #[cfg(feature = "acl")]
+            acl: None,
+            #[cfg(feature = "acl")]
+            acl_trace: false,
+
+            #[cfg(feature = "metrics")]
+            metrics_addr: None,
+
+            #[cfg(feature = "local-flow-stat")]
+            stat_path: None,
+
+            security: SecurityConfig::default(),
+
+            balancer: BalancerConfig::default(),
+
+            isolate_locals: false,
+            p2p_policy: P2pPolicy::Allow,
+            state_dir: None,
+            state_store: None,
+            #[cfg(feature = "local")]
+            export_connections: false,
+            #[cfg(feature = "local")]
+            system_proxy: false,
+            #[cfg(feature = "local")]
+            control_addr: None,
+            #[cfg(feature = "local")]
+            control_key: None,
+            #[cfg(feature = "local")]
+            flow_export: None,
+            #[cfg(unix)]
+            handoff: None,
+            #[cfg(feature = "local")]
+            priority: None,
+            #[cfg(feature = "local")]
+            resolver_cache: Some(ResolverCacheConfig::default()),
+            global_rate_limit: None,
+            #[cfg(feature = "server")]
+            outbound_firewall: None,
+            #[cfg(feature = "server")]
+            client_limit: None,
+            #[cfg(feature = "server")]
+            udp_eviction: None,
+            #[cfg(feature = "server")]
+            drain_timeout: None,
+            #[cfg(feature = "local-fault-injection")]
+            fault_injection: None,
+            #[cfg(feature = "local-online-config")]
+            online_config: None,
+
+            profiles: Vec::new(),
+            profile: None,
+
+            config_path: None,
+        }
+    }
+
+    fn load_from_ssconfig(config: SSConfig, config_type: ConfigType) -> Result<Config, Error> {
+        let mut nconfig = Config::new(config_type);
+
+        // Client
+        //
+        // local_address is allowed to be NULL, which means to bind to ::1 or 127.0.0.1
+        //
+        // https://shadowsocks.org/en/config/quick-guide.html
+        #[inline]
+        fn get_local_address(local_address: Option<String>, local_port: u16, ipv6_first: bool) -> ServerAddr {
+            match local_address {
+                Some(addr) => {
+                    match addr.parse::<IpAddr>() {
+                        Ok(ip) => ServerAddr::from(SocketAddr::new(ip, local_port)),
+                        Err(..) => {
+                            // treated as domain
+                            ServerAddr::from((addr, local_port))
+                        }
+                    }
+                }
+                None => {
+                    // Implementation note: This is not implemented like libev which will choose IPv6 or IPv6 LoopBack address
+                    // by checking all its remote servers if all of them supports IPv6.
+                    let ip = if ipv6_first {
+                        Ipv6Addr::LOCALHOST.into()
+                    } else {
+                        Ipv4Addr::LOCALHOST.into()
+                    };
+
+                    ServerAddr::from(SocketAddr::new(ip, local_port))
+                }
+            }
+        }
+
+        // Mode
+        let mut global_mode = Mode::TcpOnly;
+        if let Some(m) = config.mode {
+            match m.parse::<Mode>() {
+                Ok(xm) => global_mode = xm,
+                Err(..) => {
+                    let e = Error::new(
+                        ErrorKind::Malformed,
+                        "malformed `mode`, must be one of `tcp_only`, `udp_only` and `tcp_and_udp`",
+                        None,
+                    );
+                    return Err(e);
+                }
+            }
+        }
+
+        // BitTorrent traffic
+        if let Some(p) = config.p2p_policy {
+            match p.parse::<P2pPolicy>() {
+                Ok(p) => nconfig.p2p_policy = p,
+                Err(err) => {
+                    let err = Error::new(ErrorKind::Malformed, "invalid `p2p_policy`", Some(err.to_string()));
+                    return Err(err);
+                }
+            }
+        }
+
+        match config_type {
+            ConfigType::Local => {
+                // Standard config
+                if config.local_address.is_some() && config.local_port.unwrap_or(0) == 0 {
+                    let err = Error::new(ErrorKind::MissingField, "missing `local_port`", None);
+                    return Err(err);
+                }
+
+                if let Some(local_port) = config.local_port {
+                    // local_port won't be 0, it was checked above
+                    assert_ne!(local_port, 0);
+
+                    let local_addr =
+                        get_local_address(config.local_address, local_port, config.ipv6_first.unwrap_or(false));
+
+                    // shadowsocks uses SOCKS5 by default
+                    let mut local_config = LocalConfig::new(ProtocolType::Socks);
+                    local_config.addr = Some(local_addr);
+                    local_config.mode = global_mode;
+                    local_config.p2p_policy = nconfig.p2p_policy.clone();
+                    local_config.protocol = match config.protocol {
+                        None => ProtocolType::Socks,
+                        Some(p) => match p.parse::<ProtocolType>() {
+                            Ok(p) => p,
+                            Err(..) => {
+                                let err = Error::new(
+                                    ErrorKind::Malformed,
+                                    "`protocol` invalid",
+                                    Some(format!("unrecognized protocol {}", p)),
+                                );
+                                return Err(err);
+                            }
+                        },
+                    };
+                    nconfig.local.push(local_config);
+                }
+
+                // Ext locals
+                // `locals` are only effective in local server
+                if let Some(locals) = config.locals {
+                    for local in locals {
+                        if local.disabled.unwrap_or(false) {
+                            continue;
+                        }
+
+                        let protocol = match local.protocol {
+                            None => ProtocolType::Socks,
+                            Some(p) => match p.parse::<ProtocolType>() {
+                                Ok(p) => p,
+                                Err(..) => {
+                                    let err = Error::new(
+                                        ErrorKind::Malformed,
+                                        "`protocol` invalid",
+                                        Some(format!("unrecognized protocol {}", p)),
+                                    );
+                                    return Err(err);
+                                }
+                            },
+                        };
+
+                        let mut local_config = LocalConfig::new(protocol);
+                        local_config.tenant = local.tenant;
+                        local_config.bind_retries = local.bind_retries.unwrap_or(0);
+
+                        if let Some(acl_path) = local.acl {
+                            #[cfg(feature = "acl")]
+                            match AccessControl::load_from_file(&acl_path) {
+                                Ok(acl) => local_config.acl = Some(acl),
+                                Err(err) => {
+                                    let err = Error::new(
+                                        ErrorKind::Invalid,
+                                        "failed to load `acl` of local",
+                                        Some(format!("{}, {}", acl_path, err)),
+                                    );
+                                    return Err(err);
+                                }
+                            }
+
+                            #[cfg(not(feature = "acl"))]
+                            {
+                                let err = Error::new(
+                                    ErrorKind::Invalid,
+                                    "`acl` of local requires feature \"acl\"",
+                                    Some(acl_path),
+                                );
+                                return Err(err);
+                            }
+                        }
+                        local_config.server_group = local.server_group;
+                        local_config.dns = match local.dns {
+                            Some(SSDnsConfig::Simple(ds)) => Some(nconfig.parse_dns_formatted(&ds)?),
+                            #[cfg(feature = "trust-dns")]
+                            Some(SSDnsConfig::TrustDns(c)) => Some(DnsConfig::TrustDns(c)),
+                            None => None,
+                        };
+                        local_config.ipv6_first = local.ipv6_first;
+
+                        if let Some(bind_fallback_ports) = local.bind_fallback_ports {
+                            if bind_fallback_ports.contains(&0) {
+                                let err =
+                                    Error::new(ErrorKind::Malformed, "`bind_fallback_ports` cannot contain 0", None);
+                                return Err(err);
+                            }
+                            local_config.bind_fallback_ports = bind_fallback_ports;
+                        }
+
+                        local_config.outbound_bind_interface = local.outbound_bind_interface;
+                        if let Some(ref addr) = local.outbound_bind_addr {
+                            local_config.outbound_bind_addr = Some(parse_outbound_bind_addr(addr)?);
+                        }
+
+                        if let Some(ref tuning) = local.tuning {
+                            let (tcp_tuning, relay_buffer_sizes) = tuning.build()?;
+                            local_config.tcp_tuning = tcp_tuning;
+                            local_config.relay_buffer_sizes = relay_buffer_sizes;
+                        }
+
+                        local_config.udp_timeout = local.udp_timeout.map(Duration::from_secs);
+                        local_config.udp_max_associations = local.udp_max_associations;
+                        #[cfg(feature = "local")]
+                        if let Some(ref udp_nat) = local.udp_nat {
+                            local_config.udp_nat = Some(udp_nat.build()?);
+                        }
+
+                        if let Some(local_port) = local.local_port {
+                            if local_port == 0 {
+                                let err = Error::new(ErrorKind::Malformed, "`local_port` cannot be 0", None);
+                                return Err(err);
+                            }
+
+                            let local_addr =
+                                get_local_address(local.local_address, local_port, config.ipv6_first.unwrap_or(false));
+                            local_config.addr = Some(local_addr);
+                        } else if let Some(local_address) = local.local_address {
+                            // `local_address` without `local_port` is a path of Unix Domain Socket
+                            #[cfg(unix)]
+                            {
+                                local_config.unix_addr = Some(PathBuf::from(local_address));
+                            }
+
+                            #[cfg(not(unix))]
+                            {
+                                let _ = local_address;
+                                let err = Error::new(ErrorKind::Malformed, "missing `local_port`", None);
+                                return Err(err);
+                            }
+                        }
+
+                        #[cfg(unix)]
+                        if let Some(unix_socket_mode) = local.unix_socket_mode {
+                            match u32::from_str_radix(unix_socket_mode.trim_start_matches("0o"), 8) {
+                                Ok(mode) if mode <= 0o777 => local_config.unix_mode = Some(mode),
+                                _ => {
+                                    let err = Error::new(
+                                        ErrorKind::Malformed,
+                                        "`unix_socket_mode` should be permission bits in octal, like \"660\"",
+                                        None,
+                                    );
+                                    return Err(err);
+                                }
+                            }
+                        }
+
+                        if let Some(local_udp_port) = local.local_udp_port {
+                            if local_udp_port == 0 {
+                                let err = Error::new(ErrorKind::Malformed, "`local_udp_port` cannot be 0", None);
+                                return Err(err);
+                            }
+
+                            let local_udp_addr = get_local_address(
+                                local.local_udp_address,
+                                local_udp_port,
+                                config.ipv6_first.unwrap_or(false),
+                            );
+
+                            local_config.udp_addr = Some(local_udp_addr);
+                        }
+
+                        match local.mode {
+                            Some(mode) => match mode.parse::<Mode>() {
+                                Ok(mode) => local_config.mode = mode,
+                                Err(..) => {
+                                    let err = Error::new(ErrorKind::Malformed, "invalid `mode`", None);
+                                    return Err(err);
+                                }
+                            },
+                            None => {
+                                local_config.mode = global_mode;
+                            }
+                        }
+
+                        match local.p2p_policy {
+                            Some(p) => match p.parse::<P2pPolicy>() {
+                                Ok(p) => local_config.p2p_policy = p,
+                                Err(err) => {
+                                    let err =
+                                        Error::new(ErrorKind::Malformed, "invalid `p2p_policy`", Some(err.to_string()));
+                                    return Err(err);
+                                }
+                            },
+                            None => {
+                                local_config.p2p_policy = nconfig.p2p_policy.clone();
+                            }
+                        }
+
+                        #[cfg(feature = "local-tunnel")]
+                        if let Some(forward_address) = local.forward_address {
+                            let forward_port = match local.forward_port {
+                                None | Some(0) => {
+                                    let err =
+                                        Error::new(ErrorKind::Malformed, "`forward_port` cannot be missing or 0", None);
+                                    return Err(err);
+                                }
+                                Some(p) => p,
+                            };
+
+                            local_config.forward_addr = Some(match forward_address.parse::<IpAddr>() {
+                                Ok(ip) => Address::from(SocketAddr::new(ip, forward_port)),
+                                Err(..) => Address::from((forward_address, forward_port)),
+                            });
+                        }
+
+                        #[cfg(feature = "local-tunnel")]
+                        if let Some(tunnel_mappings) = local.tunnel_mappings {
+                            for mapping in tunnel_mappings {
+                                if mapping.local_port == 0 || mapping.forward_port == 0 {
+                                    let err = Error::new(
+                                        ErrorKind::Malformed,
+                                        "`local_port` and `forward_port` of `tunnel_mappings` cannot be 0",
+                                        None,
+                                    );
+                                    return Err(err);
+                                }
+
+                                let local_addr = get_local_address(
+                                    mapping.local_address,
+                                    mapping.local_port,
+                                    config.ipv6_first.unwrap_or(false),
+                                );
+                                let forward_addr = match mapping.forward_address.parse::<IpAddr>() {
+                                    Ok(ip) => Address::from(SocketAddr::new(ip, mapping.forward_port)),
+                                    Err(..) => Address::from((mapping.forward_address, mapping.forward_port)),
+                                };
+
+                                let mut tunnel_mapping = TunnelMapping::new(local_addr, forward_addr);
+                                tunnel_mapping.mode = match mapping.mode {
+                                    Some(mode) => match mode.parse::<Mode>() {
+                                        Ok(mode) => mode,
+                                        Err(..) => {
+                                            let err = Error::new(
+                                                ErrorKind::Malformed,
+                                                "invalid `mode` of `tunnel_mappings`",
+                                                None,
+                                            );
+                                            return Err(err);
+                                        }
+                                    },
+                                    None => local_config.mode,
+                                };
+                                local_config.tunnel_mappings.push(tunnel_mapping);
+                            }
+                        }
+
+                        #[cfg(feature = "local-redir")]
+                        if let Some(tcp_redir) = local.tcp_redir {
+                            match tcp_redir.parse::<RedirType>() {
+                                Ok(r) => local_config.tcp_redir = r,
+                                Err(..) => {
+                                    let err = Error::new(ErrorKind::Malformed, "`tcp_redir` invalid", None);
+                                    return Err(err);
+                                }
+                            }
+                        }
+
+                        #[cfg(feature = "local-redir")]
+                        if let Some(udp_redir) = local.udp_redir {
+                            match udp_redir.parse::<RedirType>() {
+                                Ok(r) => local_config.udp_redir = r,
+                                Err(..) => {
+                                    let err = Error::new(ErrorKind::Malformed, "`udp_redir` invalid", None);
+                                    return Err(err);
+                                }
+                            }
+                        }
+
+                        #[cfg(feature = "local-redir")]
+                        if let Some(redir_listeners) = local.redir_listeners {
+                            local_config.redir_listeners = redir_listeners;
+                        }
+
+                        #[cfg(feature = "local-redir-ebpf")]
+                        if let Some(redir_ebpf) = local.redir_ebpf {
+                            local_config.redir_ebpf = redir_ebpf;
+                        }
+
+                        #[cfg(feature = "local-redir")]
+                        if let Some(redir_firewall) = local.redir_firewall {
+                            match redir_firewall.parse::<RedirFirewall>() {
+                                Ok(f) => local_config.redir_firewall = Some(f),
+                                Err(..) => {
+                                    let err = Error::new(
+                                        ErrorKind::Malformed,
+                                        "`redir_firewall` invalid, should be \"iptables\" or \"nftables\"",
+                                        None,
+                                    );
+                                    return Err(err);
+                                }
+                            }
+                        }
+
+                        #[cfg(any(feature = "local-redir", feature = "local-tun"))]
+                        if let Some(preserve_ttl) = local.preserve_ttl {
+                            local_config.preserve_ttl = preserve_ttl;
+                        }
+
+                        #[cfg(any(feature = "local-dns-over-tls", feature = "local-dns-over-quic"))]
+                        let mut local_dns_bootstrap = match local.local_dns_bootstrap {
+                            None => None,
+                            Some(b) => match b.parse::<IpAddr>() {
+                                Ok(ip) => Some(ip),
+                                Err(..) => {
+                                    let err = Error::new(
+                                        ErrorKind::Malformed,
+                                        "`local_dns_bootstrap` should be an IP address",
+                                        None,
+                                    );
+                                    return Err(err);
+                                }
+                            },
+                        };
+
+                        #[cfg(feature = "local-dns")]
+                        if let Some(local_dns_address) = local.local_dns_address {
+                            #[allow(unused_mut)]
+                            let mut local_dns_address = Some(local_dns_address);
+
+                            #[cfg(any(feature = "local-dns-over-tls", feature = "local-dns-over-quic"))]
+                            if let Some(secure_address) = local_dns_address.as_deref().filter(|a| a.contains("://")) {
+                                let mut addr = match secure_address.parse::<SecureNameServerAddr>() {
+                                    Ok(a) => a,
+                                    Err(err) => {
+                                        let err = Error::new(
+                                            ErrorKind::Malformed,
+                                            "`local_dns_address` invalid",
+                                            Some(err.to_string()),
+                                        );
+                                        return Err(err);
+                                    }
+                                };
+                                if let Some(ip) = local_dns_bootstrap.take() {
+                                    addr.set_bootstrap(ip);
+                                }
+
+                                local_config.local_dns_addr = Some(NameServerAddr::Secure(addr));
+                                local_dns_address = None;
+                            }
 
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match s.parse::<IpAddr>() {
-            Ok(s) => Ok(ManagerServerHost::Ip(s)),
-            Err(..) => Ok(ManagerServerHost::Domain(s.to_owned())),
-        }
-    }
-}
+                            if let Some(local_dns_address) = local_dns_address {
+                                match local_dns_address.parse::<IpAddr>() {
+                                    Ok(ip) => {
+                                        local_config.local_dns_addr = Some(NameServerAddr::SocketAddr(
+                                            SocketAddr::new(ip, local.local_dns_port.unwrap_or(53)),
+                                        ));
+                                    }
+                                    #[cfg(unix)]
+                                    Err(..) => {
+                                        local_config.local_dns_addr =
+                                            Some(NameServerAddr::UnixSocketAddr(PathBuf::from(local_dns_address)));
+                                    }
+                                    #[cfg(not(unix))]
+                                    Err(..) => {
+                                        let err = Error::new(ErrorKind::Malformed, "`local_dns_address` invalid", None);
+                                        return Err(err);
+                                    }
+                                }
+                            }
+                        }
 
-/// Mode of Manager's server
-#[derive(Debug, Clone, Copy, Eq, PartialEq)]
-pub enum ManagerServerMode {
-    /// Run shadowsocks server in the same process of manager
-    Builtin,
+                        #[cfg(any(feature = "local-dns-over-tls", feature = "local-dns-over-quic"))]
+                        if local_dns_bootstrap.is_some() {
+                            let err = Error::new(
+                                ErrorKind::Invalid,
+                                "`local_dns_bootstrap` is only for encrypted `local_dns_address`",
+                                None,
+                            );
+                            return Err(err);
+                        }
 
-    /// Run shadowsocks server in standalone (process) mode
-    #[cfg(unix)]
-    Standalone,
-}
+                        #[cfg(any(
+                            feature = "local-dns-over-https",
+                            feature = "local-dns-over-tls",
+                            feature = "local-dns-over-quic"
+                        ))]
+                        let mut remote_dns_bootstrap = match local.remote_dns_bootstrap {
+                            None => None,
+                            Some(b) => match b.parse::<IpAddr>() {
+                                Ok(ip) => Some(ip),
+                                Err(..) => {
+                                    let err = Error::new(
+                                        ErrorKind::Malformed,
+                                        "`remote_dns_bootstrap` should be an IP address",
+                                        None,
+                                    );
+                                    return Err(err);
+                                }
+                            },
+                        };
 
-impl Default for ManagerServerMode {
-    fn default() -> ManagerServerMode {
-        ManagerServerMode::Builtin
-    }
-}
+                        #[cfg(feature = "local-dns")]
+                        if let Some(remote_dns_address) = local.remote_dns_address {
+                            #[allow(unused_mut)]
+                            let mut remote_dns_address = Some(remote_dns_address);
+
+                            #[cfg(feature = "local-dns-over-https")]
+                            if let Some(https_address) =
+                                remote_dns_address.as_deref().filter(|a| a.starts_with("https://"))
+                            {
+                                let mut addr = match https_address.parse::<DnsOverHttpsAddr>() {
+                                    Ok(a) => a,
+                                    Err(err) => {
+                                        let err = Error::new(
+                                            ErrorKind::Malformed,
+                                            "`remote_dns_address` invalid",
+                                            Some(err.to_string()),
+                                        );
+                                        return Err(err);
+                                    }
+                                };
+                                if let Some(ip) = remote_dns_bootstrap.take() {
+                                    addr.set_bootstrap(ip);
+                                }
 
-/// Parsing ManagerServerMode error
-#[derive(Debug, Clone, Copy)]
-pub struct ManagerServerModeError;
+                                local_config.remote_dns_https = Some(addr);
+                                remote_dns_address = None;
+                            }
 
-impl Display for ManagerServerModeError {
-    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-        f.write_str("invalid ManagerServerMode")
-    }
-}
+                            #[cfg(any(feature = "local-dns-over-tls", feature = "local-dns-over-quic"))]
+                            if let Some(secure_address) = remote_dns_address.as_deref().filter(|a| a.contains("://")) {
+                                let mut addr = match secure_address.parse::<SecureNameServerAddr>() {
+                                    Ok(a) => a,
+                                    Err(err) => {
+                                        let err = Error::new(
+                                            ErrorKind::Malformed,
+                                            "`remote_dns_address` invalid",
+                                            Some(err.to_string()),
+                                        );
+                                        return Err(err);
+                                    }
+                                };
+                                if let Some(ip) = remote_dns_bootstrap.take() {
+                                    addr.set_bootstrap(ip);
+                                }
 
-impl FromStr for ManagerServerMode {
-    type Err = ManagerServerModeError;
+                                local_config.remote_dns_secure = Some(addr);
+                                remote_dns_address = None;
+                            }
 
-    fn from_str(s: &str) -> Result<ManagerServerMode, Self::Err> {
-        match s {
-            "builtin" => Ok(ManagerServerMode::Builtin),
-            #[cfg(unix)]
-            "standalone" => Ok(ManagerServerMode::Standalone),
-            _ => Err(ManagerServerModeError),
-        }
-    }
-}
+                            if let Some(remote_dns_address) = remote_dns_address {
+                                let remote_dns_port = local.remote_dns_port.unwrap_or(53);
+                                local_config.remote_dns_addr = Some(match remote_dns_address.parse::<IpAddr>() {
+                                    Ok(ip) => Address::from(SocketAddr::new(ip, remote_dns_port)),
+                                    Err(..) => Address::from((remote_dns_address, remote_dns_port)),
+                                });
+                            }
+                        }
 
-impl Display for ManagerServerMode {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match *self {
-            ManagerServerMode::Builtin => f.write_str("builtin"),
-            #[cfg(unix)]
-            ManagerServerMode::Standalone => f.write_str("standalone"),
-        }
-    }
-}
+                        #[cfg(any(
+                            feature = "local-dns-over-https",
+                            feature = "local-dns-over-tls",
+                            feature = "local-dns-over-quic"
+                        ))]
+                        if remote_dns_bootstrap.is_some() {
+                            let err = Error::new(
+                                ErrorKind::Invalid,
+                                "`remote_dns_bootstrap` is only for encrypted `remote_dns_address`",
+                                None,
+                            );
+                            return Err(err);
+                        }
 
-/// Configuration for Manager
-#[derive(Clone, Debug)]
-pub struct ManagerConfig {
-    /// Address of `ss-manager`. Send servers' statistic data to the manager server
-    pub addr: ManagerAddr,
-    /// Manager's default method
-    pub method: Option<CipherKind>,
-    /// Manager's default plugin
-    pub plugin: Option<PluginConfig>,
-    /// Timeout for TCP connections, setting to manager's created servers
-    pub timeout: Option<Duration>,
-    /// IP/Host for servers to bind (inbound)
-    ///
-    /// Note: Outbound address is defined in Config.local_addr
-    pub server_host: ManagerServerHost,
-    /// Server's mode
-    pub mode: Mode,
-    /// Server's running mode
-    pub server_mode: ManagerServerMode,
-    /// Server's command if running in Standalone mode
-    #[cfg(unix)]
-    pub server_program: String,
-    /// Server's working directory if running in Standalone mode
-    #[cfg(unix)]
-    pub server_working_directory: PathBuf,
-}
+                        #[cfg(feature = "local-dns")]
+                        {
+                            local_config.local_dns_timeout = local.local_dns_timeout.map(Duration::from_secs);
+                            local_config.remote_dns_timeout = local.remote_dns_timeout.map(Duration::from_secs);
+
+                            for ns in local.local_dns_extra_addresses.unwrap_or_default() {
+                                match ns.parse::<NameServerAddr>() {
+                                    Ok(ns) => local_config.local_dns_extra_addrs.push(ns),
+                                    #[allow(unreachable_patterns)]
+                                    Err(..) => {
+                                        let err = Error::new(
+                                            ErrorKind::Malformed,
+                                            "`local_dns_extra_addresses` invalid name server",
+                                            Some(format!("name server: {}", ns)),
+                                        );
+                                        return Err(err);
+                                    }
+                                }
+                            }
 
-impl ManagerConfig {
-    /// Create a ManagerConfig with default options
-    pub fn new(addr: ManagerAddr) -> ManagerConfig {
-        ManagerConfig {
-            addr,
-            method: None,
-            plugin: None,
-            timeout: None,
-            server_host: ManagerServerHost::default(),
-            mode: Mode::TcpOnly,
-            server_mode: ManagerServerMode::Builtin,
-            #[cfg(unix)]
-            server_program: "ssserver".to_owned(),
-            #[cfg(unix)]
-            server_working_directory: match std::env::current_dir() {
-                Ok(d) => d,
-                Err(..) => "/tmp/shadowsocks-manager".into(),
-            },
-        }
-    }
-}
+                            if let Some(strategy) = local.local_dns_strategy {
+                                match strategy.parse::<NameServerStrategy>() {
+                                    Ok(s) => local_config.local_dns_strategy = s,
+                                    Err(..) => {
+                                        let err = Error::new(
+                                            ErrorKind::Malformed,
+                                            "`local_dns_strategy` invalid, could be \"failover\", \"round_robin\", \"race\" or \"fastest\"",
+                                            None,
+                                        );
+                                        return Err(err);
+                                    }
+                                }
+                            }
 
-/// Protocol of local server
-#[derive(Debug, Clone, Copy, Eq, PartialEq)]
-pub enum ProtocolType {
-    Socks,
-    #[cfg(feature = "local-http")]
-    Http,
-    #[cfg(feature = "local-tunnel")]
-    Tunnel,
-    #[cfg(feature = "local-redir")]
-    Redir,
-    #[cfg(feature = "local-dns")]
-    Dns,
-    #[cfg(feature = "local-tun")]
-    Tun,
-}
+                            if let Some(dns_cache) = local.dns_cache {
+                                let mut cache_config = DnsCacheConfig::new(dns_cache.size);
+                                cache_config.min_ttl = dns_cache.min_ttl.map(Duration::from_secs);
+                                cache_config.prefetch = dns_cache.prefetch.unwrap_or(false);
+                                local_config.dns_cache = Some(cache_config);
+                            }
 
-impl Default for ProtocolType {
-    fn default() -> ProtocolType {
-        ProtocolType::Socks
-    }
-}
+                            if let Some(fake_ip) = local.fake_ip {
+                                let ipv4_range = match fake_ip.ipv4_range.parse::<Ipv4Net>() {
+                                    Ok(r) => r,
+                                    Err(..) => {
+                                        let err =
+                                            Error::new(ErrorKind::Malformed, "`fake_ip` ipv4_range invalid", None);
+                                        return Err(err);
+                                    }
+                                };
+
+                                let mut fake_ip_config = FakeIpConfig::new(ipv4_range);
+                                if let Some(ipv6_range) = fake_ip.ipv6_range {
+                                    match ipv6_range.parse::<Ipv6Net>() {
+                                        Ok(r) => fake_ip_config.ipv6_range = Some(r),
+                                        Err(..) => {
+                                            let err =
+                                                Error::new(ErrorKind::Malformed, "`fake_ip` ipv6_range invalid", None);
+                                            return Err(err);
+                                        }
+                                    }
+                                }
+                                if let Some(ttl) = fake_ip.ttl {
+                                    fake_ip_config.ttl = Duration::from_secs(ttl);
+                                }
+                                local_config.fake_ip = Some(fake_ip_config);
+                            }
 
-impl ProtocolType {
-    /// As string representation
-    pub fn as_str(&self) -> &'static str {
-        match *self {
-            ProtocolType::Socks => "socks",
-            #[cfg(feature = "local-http")]
-            ProtocolType::Http => "http",
-            #[cfg(feature = "local-tunnel")]
-            ProtocolType::Tunnel => "tunnel",
-            #[cfg(feature = "local-redir")]
-            ProtocolType::Redir => "redir",
-            #[cfg(feature = "local-dns")]
-            ProtocolType::Dns => "dns",
-            #[cfg(feature = "local-tun")]
-            ProtocolType::Tun => "tun",
-        }
-    }
+                            for (group, upstream) in local.dns_upstreams.unwrap_or_default() {
+                                let mut name_servers = Vec::with_capacity(upstream.len());
+                                for ns in upstream {
+                                    match ns.parse::<NameServerAddr>() {
+                                        Ok(ns) => name_servers.push(ns),
+                                        #[allow(unreachable_patterns)]
+                                        Err(..) => {
+                                            let err = Error::new(
+                                                ErrorKind::Malformed,
+                                                "`dns_upstreams` invalid name server",
+                                                Some(format!("group: {}, name server: {}", group, ns)),
+                                            );
+                                            return Err(err);
+                                        }
+                                    }
+                                }
+                                local_config.dns_upstreams.insert(group, name_servers);
+                            }
 
-    /// Get all available protocols
-    pub fn available_protocols() -> &'static [&'static str] {
-        &[
-            "socks",
-            #[cfg(feature = "local-http")]
-            "http",
-            #[cfg(feature = "local-tunnel")]
-            "tunnel",
-            #[cfg(feature = "local-redir")]
-            "redir",
-            #[cfg(feature = "local-dns")]
-            "dns",
-            #[cfg(feature = "local-tun")]
-            "tun",
-        ]
-    }
-}
+                            if let Some(dns_hosts) = local.dns_hosts {
+                                let mut hosts_config = DnsHostsConfig::default();
+                                for record in dns_hosts.records.unwrap_or_default() {
+                                    match StaticRecordData::from_type_value(&record.rtype, &record.value) {
+                                        Some(data) => hosts_config.records.push(StaticRecordConfig {
+                                            name: record.name,
+                                            data,
+                                        }),
+                                        None => {
+                                            let err = Error::new(
+                                                ErrorKind::Malformed,
+                                                "`dns_hosts` invalid record",
+                                                Some(format!(
+                                                    "name: {}, type: {}, value: {}",
+                                                    record.name, record.rtype, record.value
+                                                )),
+                                            );
+                                            return Err(err);
+                                        }
+                                    }
+                                }
+                                hosts_config.hosts_file = dns_hosts.hosts_file.map(|path| {
+                                    if path == "system" {
+                                        DnsHostsConfig::system_hosts_file()
+                                    } else {
+                                        PathBuf::from(path)
+                                    }
+                                });
+                                hosts_config.ttl = dns_hosts.ttl.map(Duration::from_secs);
+                                local_config.dns_hosts = Some(hosts_config);
+                            }
+                        }
 
-/// Error while parsing `ProtocolType` from string
-#[derive(Debug)]
-pub struct ProtocolTypeError;
+                        #[cfg(feature = "local-dns-dnssec")]
+                        if let Some(dnssec) = local.dnssec {
+                            let mut dnssec_config = DnssecConfig::default();
+                            if let Some(ad_bit) = dnssec.ad_bit {
+                                match ad_bit.parse::<DnssecAdPolicy>() {
+                                    Ok(p) => dnssec_config.ad_policy = p,
+                                    Err(..) => {
+                                        let err = Error::new(
+                                            ErrorKind::Malformed,
+                                            "`dnssec.ad_bit` invalid, could be \"validated\" or \"clear\"",
+                                            None,
+                                        );
+                                        return Err(err);
+                                    }
+                                }
+                            }
+                            if let Some(b) = dnssec.builtin_trust_anchors {
+                                dnssec_config.builtin_trust_anchors = b;
+                            }
+                            dnssec_config.trust_anchors = dnssec.trust_anchors.unwrap_or_default();
+                            dnssec_config.trust_anchors_file = dnssec.trust_anchors_file.map(PathBuf::from);
+                            local_config.dnssec = Some(dnssec_config);
+                        }
 
-impl Display for ProtocolTypeError {
-    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-        f.write_str("invalid ProtocolType")
-    }
-}
+                        #[cfg(feature = "local-dns-tls")]
+                        {
+                            if let Some(addr) = local.dns_over_tls_address {
+                                match addr.parse::<ServerAddr>() {
+                                    Ok(addr) => local_config.dns_over_tls_addr = Some(addr),
+                                    Err(..) => {
+                                        let err =
+                                            Error::new(ErrorKind::Malformed, "`dns_over_tls_address` invalid", None);
+                                        return Err(err);
+                                    }
+                                }
+                            }
+                            if let Some(addr) = local.dns_over_https_address {
+                                match addr.parse::<ServerAddr>() {
+                                    Ok(addr) => local_config.dns_over_https_addr = Some(addr),
+                                    Err(..) => {
+                                        let err =
+                                            Error::new(ErrorKind::Malformed, "`dns_over_https_address` invalid", None);
+                                        return Err(err);
+                                    }
+                                }
+                            }
+                        }
 
-impl FromStr for ProtocolType {
-    type Err = ProtocolTypeError;
+                        #[cfg(feature = "local-tun")]
+                        if let Some(tun_interface_address) = local.tun_interface_address {
+                            if tun_interface_address == "auto" {
+                                local_config.tun_interface_address_auto = true;
+                            } else {
+                                match tun_interface_address.parse::<IpNet>() {
+                                    Ok(addr) => local_config.tun_interface_address = Some(addr),
+                                    Err(..) => {
+                                        let err =
+                                            Error::new(ErrorKind::Malformed, "`tun_interface_address` invalid", None);
+                                        return Err(err);
+                                    }
+                                }
+                            }
+                        }
 
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match s {
-            "socks" => Ok(ProtocolType::Socks),
-            #[cfg(feature = "local-http")]
-            "http" => Ok(ProtocolType::Http),
-            #[cfg(feature = "local-tunnel")]
-            "tunnel" => Ok(ProtocolType::Tunnel),
-            #[cfg(feature = "local-redir")]
-            "redir" => Ok(ProtocolType::Redir),
-            #[cfg(feature = "local-dns")]
-            "dns" => Ok(ProtocolType::Dns),
-            #[cfg(feature = "local-tun")]
-            "tun" => Ok(ProtocolType::Tun),
-            _ => Err(ProtocolTypeError),
-        }
-    }
-}
+                        #[cfg(feature = "local-tun")]
+                        if let Some(tun_interface_name) = local.tun_interface_name {
+                            local_config.tun_interface_name = Some(tun_interface_name);
+                        }
 
-/// Local server configuration
-#[derive(Clone, Debug)]
-pub struct LocalConfig {
-    /// Listen address for local servers
-    pub addr: Option<ServerAddr>,
+                        #[cfg(feature = "local-tun")]
+                        if let Some(tun_tcp) = local.tun_tcp {
+                            let profile = match tun_tcp.profile {
+                                None => TunTcpProfile::default(),
+                                Some(p) => match p.parse::<TunTcpProfile>() {
+                                    Ok(p) => p,
+                                    Err(..) => {
+                                        let err = Error::new(
+                                            ErrorKind::Malformed,
+                                            "`tun_tcp.profile` invalid, could be \"lan\" or \"wan\"",
+                                            None,
+                                        );
+                                        return Err(err);
+                                    }
+                                },
+                            };
 
-    pub protocol: ProtocolType,
+                            let mut tcp_config = TunTcpConfig::new(profile);
+                            tcp_config.send_buffer_size = tun_tcp.send_buffer_size;
+                            tcp_config.recv_buffer_size = tun_tcp.recv_buffer_size;
+                            tcp_config.ack_delay = tun_tcp.ack_delay.map(Duration::from_millis);
+                            tcp_config.nagle = tun_tcp.nagle;
+                            tcp_config.timeout = tun_tcp.timeout.map(Duration::from_secs);
+                            local_config.tun_tcp = tcp_config;
+                        }
 
-    /// Mode
-    /// Uses global `mode` if not specified
-    pub mode: Mode,
+                        #[cfg(feature = "local-pac")]
+                        if let Some(pac_template_path) = local.pac_template_path {
+                            local_config.pac_template_path = Some(PathBuf::from(pac_template_path));
+                        }
 
-    /// UDP server bind address. Uses `addr` if not specified
-    ///
-    /// Resolving Android's issue: [shadowsocks/shadowsocks-android#2571](https://github.com/shadowsocks/shadowsocks-android/issues/2571)
-    pub udp_addr: Option<ServerAddr>,
+                        #[cfg(feature = "local")]
+                        if let Some(socks5_auth_config_path) = local.socks5_auth_config_path {
+                            local_config.socks5_auth = Socks5AuthConfig::load_from_file(&socks5_auth_config_path)?;
+                        }
 
-    /// Destination address for tunnel
-    #[cfg(feature = "local-tunnel")]
-    pub forward_addr: Option<Address>,
+                        #[cfg(feature = "local")]
+                        if let Some(socks_hostname) = local.socks_hostname {
+                            match socks_hostname.parse::<SocksHostnamePolicy>() {
+                                Ok(p) => local_config.socks_hostname = p,
+                                Err(err) => {
+                                    let err = Error::new(
+                                        ErrorKind::Malformed,
+                                        "invalid `socks_hostname`",
+                                        Some(err.to_string()),
+                                    );
+                                    return Err(err);
+                                }
+                            }
+                        }
 
-    /// TCP Transparent Proxy type
-    #[cfg(feature = "local-redir")]
-    pub tcp_redir: RedirType,
-    /// UDP Transparent Proxy type
-    #[cfg(feature = "local-redir")]
-    pub udp_redir: RedirType,
+                        #[cfg(feature = "local-http")]
+                        if let Some(http_auth_config_path) = local.http_auth_config_path {
+                            local_config.http_auth = HttpAuthConfig::load_from_file(&http_auth_config_path)?;
+                        }
 
-    /// Local DNS's address
-    ///
-    /// Sending DNS query directly to this address
-    #[cfg(feature = "local-dns")]
-    pub local_dns_addr: Option<NameServerAddr>,
-    /// Remote DNS's address
-    ///
-    /// Sending DNS query through proxy to this address
-    #[cfg(feature = "local-dns")]
-    pub remote_dns_addr: Option<Address>,
+                        #[cfg(feature = "local-http")]
+                        if let Some(http_cache) = local.http_cache {
+                            let mut cache_config = HttpCacheConfig::new(http_cache.size);
+                            if let Some(max_object_size) = http_cache.max_object_size {
+                                cache_config.max_object_size = max_object_size;
+                            }
+                            local_config.http_cache = Some(cache_config);
+                        }
 
-    /// Tun interface's name
-    ///
-    /// Linux: eth0, eth1, ...
-    /// macOS: utun0, utun1, ...
-    #[cfg(feature = "local-tun")]
-    pub tun_interface_name: Option<String>,
-    /// Tun interface's address and netmask
-    #[cfg(feature = "local-tun")]
-    pub tun_interface_address: Option<IpNet>,
-    /// Tun interface's file descriptor
-    #[cfg(all(feature = "local-tun", unix))]
-    pub tun_device_fd: Option<std::os::unix::io::RawFd>,
-    /// Tun interface's file descriptor read from this Unix Domain Socket
-    #[cfg(all(feature = "local-tun", unix))]
-    pub tun_device_fd_from_path: Option<PathBuf>,
+                        #[cfg(any(feature = "local-socks-tls", feature = "local-http-tls", feature = "local-dns-tls"))]
+                        if let Some(tls) = local.tls {
+                            local_config.tls = Some(LocalTlsConfig {
+                                certificate_path: PathBuf::from(tls.certificate),
+                                private_key_path: PathBuf::from(tls.private_key),
+                                client_ca_path: tls.client_ca.map(PathBuf::from),
+                            });
+                        }
 
-    /// Set `IPV6_V6ONLY` for listener socket
-    pub ipv6_only: bool,
+                        nconfig.local.push(local_config);
+                    }
+                }
+            }
+            ConfigType::Server | ConfigType::Manager => {
+                // NOTE: IGNORED.
+                // servers only uses `local_address` for binding outbound interfaces
+                //
+                // This behavior causes lots of confusion. use outbound_bind_addr instead
+            }
+        }
 
-    /// SOCKS5 Authentication configuration
-    #[cfg(feature = "local")]
-    pub socks5_auth: Socks5AuthConfig,
-}
+        // Standard config
+        // Server
+        let password = read_password(config.password.as_deref(), config.password_file.as_deref())?;
+        match (config.server, config.server_port, password, &config.method) {
+            (Some(address), Some(port), Some(pwd), Some(m)) => {
+                let addr = match address.parse::<Ipv4Addr>() {
+                    Ok(v4) => ServerAddr::SocketAddr(SocketAddr::V4(SocketAddrV4::new(v4, port))),
+                    Err(..) => match address.parse::<Ipv6Addr>() {
+                        Ok(v6) => ServerAddr::SocketAddr(SocketAddr::V6(SocketAddrV6::new(v6, port, 0, 0))),
+                        Err(..) => ServerAddr::DomainName(address, port),
+                    },
+                };
 
-impl LocalConfig {
-    /// Create a new `LocalConfig`
-    pub fn new(protocol: ProtocolType) -> LocalConfig {
-        LocalConfig {
-            addr: None,
+                let method = match m.parse::<CipherKind>() {
+                    Ok(m) => m,
+                    Err(..) => {
+                        let err = Error::new(
+                            ErrorKind::Invalid,
+                            "unsupported method",
+                            Some(format!("`{}` is not a supported method", m)),
+                        );
+                        return Err(err);
+                    }
+                };
 
-            protocol,
+                let password = pwd;
 
-            mode: Mode::TcpOnly,
-            udp_addr: None,
+                if let Err(err) = password_to_key(method, &password) {
+                    let err = Error::new(ErrorKind::Invalid, "invalid password", Some(err.to_string()));
+                    return Err(err);
+                }
 
-            #[cfg(feature = "local-tunnel")]
-            forward_addr: None,
+                let mut nsvr = ServerConfig::new(addr, password, method);
+                nsvr.set_mode(global_mode);
 
-            #[cfg(feature = "local-redir")]
-            tcp_redir: RedirType::tcp_default(),
-            #[cfg(feature = "local-redir")]
-            udp_redir: RedirType::udp_default(),
+                if let Some(ref p) = config.plugin {
+                    // SIP008 allows "plugin" to be an empty string
+                    // Empty string implies "no plugin"
+                    if !p.is_empty() {
+                        let plugin = PluginConfig {
+                            plugin: p.clone(),
+                            plugin_opts: config.plugin_opts.clone(),
+                            plugin_args: config.plugin_args.clone().unwrap_or_default(),
+                            plugin_mode: parse_plugin_mode(config.plugin_mode.as_deref())?,
+                        };
+                        nsvr.set_plugin(plugin);
+                    }
+                }
 
-            #[cfg(feature = "local-dns")]
-            local_dns_addr: None,
-            #[cfg(feature = "local-dns")]
-            remote_dns_addr: None,
+                if let Some(timeout) = config.timeout.map(Duration::from_secs) {
+                    nsvr.set_timeout(timeout);
+                }
 
-            #[cfg(feature = "local-tun")]
-            tun_interface_name: None,
-            #[cfg(feature = "local-tun")]
-            tun_interface_address: None,
-            #[cfg(all(feature = "local-tun", unix))]
-            tun_device_fd: None,
-            #[cfg(all(feature = "local-tun", unix))]
-            tun_device_fd_from_path: None,
+                if let Some(udp_over_tcp) = config.udp_over_tcp {
+                    nsvr.set_udp_over_tcp(udp_over_tcp);
+                }
 
-            ipv6_only: false,
+                if let Some(ref mux) = config.mux {
+                    nsvr.set_mux(mux.build()?);
+                }
 
-            #[cfg(feature = "local")]
-            socks5_auth: Socks5AuthConfig::default(),
-        }
-    }
+                #[cfg(feature = "stream-padding")]
+                if let Some(ref padding) = config.padding {
+                    nsvr.set_padding(padding.build()?);
+                }
 
-    /// Create a new `LocalConfig` with listen address
-    pub fn new_with_addr(addr: ServerAddr, protocol: ProtocolType) -> LocalConfig {
-        let mut config = LocalConfig::new(protocol);
-        config.addr = Some(addr);
-        config
-    }
+                if let Some(ref port_hopping) = config.port_hopping {
+                    nsvr.set_port_hopping(port_hopping.build(nsvr.addr().port())?);
+                }
 
-    fn check_integrity(&self) -> Result<(), Error> {
-        match self.protocol {
-            #[cfg(feature = "local-tun")]
-            ProtocolType::Tun => {}
+                if let Some(ref listen_addresses) = config.listen_addresses {
+                    nsvr.set_listen_addrs(parse_listen_addresses(listen_addresses)?);
+                }
 
-            _ => {
-                if self.addr.is_none() {
-                    let err = Error::new(ErrorKind::MissingField, "missing `addr` in configuration", None);
-                    return Err(err);
+                if let Some(listeners) = config.listeners {
+                    nsvr.set_listeners(listeners);
                 }
-            }
-        }
 
-        match self.protocol {
-            #[cfg(feature = "local-dns")]
-            ProtocolType::Dns => {
-                if self.local_dns_addr.is_none() || self.remote_dns_addr.is_none() {
-                    let err = Error::new(
-                        ErrorKind::MissingField,
-                        "missing `local_dns_addr` or `remote_dns_addr` in configuration",
-                        None,
-                    );
-                    return Err(err);
+                if let Some(ref upstream_proxy) = config.upstream_proxy {
+                    match upstream_proxy.parse::<UpstreamProxy>() {
+                        Ok(proxy) => nsvr.set_upstream_proxy(proxy),
+                        Err(err) => {
+                            let err =
+                                Error::new(ErrorKind::Malformed, "invalid `upstream_proxy`", Some(err.to_string()));
+                            return Err(err);
+                        }
+                    }
                 }
-            }
-            #[cfg(feature = "local-tunnel")]
-            ProtocolType::Tunnel => {
-                if self.forward_addr.is_none() {
-                    let err = Error::new(ErrorKind::MissingField, "missing `forward_addr` in configuration", None);
-                    return Err(err);
+
+                #[cfg(feature = "aead-cipher-2022")]
+                if let Some(ref users) = config.users {
+                    let user_manager = SSServerUserConfig::build_user_manager(method, users)?;
+                    nsvr.set_user_manager(Arc::new(user_manager));
                 }
-            }
 
-            #[cfg(feature = "local-http")]
-            ProtocolType::Http => {
-                if !self.mode.enable_tcp() {
-                    let err = Error::new(ErrorKind::Invalid, "TCP mode have to be enabled for http", None);
-                    return Err(err);
+                if let Some(quota) = config.quota {
+                    nsvr.set_quota(parse_quota(quota)?);
                 }
-            }
 
-            _ => {}
-        }
+                if let Some(ref fallback) = config.fallback {
+                    nsvr.set_fallback(parse_fallback(fallback)?);
+                }
 
-        Ok(())
-    }
+                if let Some(iface) = config.outbound_bind_interface {
+                    nsvr.set_outbound_bind_interface(iface);
+                }
 
-    // Check if it is a basic format of local
-    pub fn is_basic(&self) -> bool {
-        if self.protocol != ProtocolType::Socks || self.udp_addr.is_some() {
-            return false;
-        }
+                if let Some(ref addr) = config.outbound_bind_addr {
+                    nsvr.set_outbound_bind_addr(parse_outbound_bind_addr(addr)?);
+                }
 
-        #[cfg(feature = "local-tunnel")]
-        if self.forward_addr.is_some() {
-            return false;
-        }
+                if let Some(ref tuning) = config.tuning {
+                    let (tcp_tuning, relay_buffer_sizes) = tuning.build()?;
+                    nsvr.set_tcp_tuning(tcp_tuning);
+                    nsvr.set_relay_buffer_sizes(relay_buffer_sizes);
+                }
 
-        #[cfg(feature = "local-redir")]
-        if self.tcp_redir != RedirType::tcp_default() || self.udp_redir != RedirType::udp_default() {
-            return false;
+                nconfig.server.push(nsvr);
+            }
+            (None, None, None, Some(_)) if config_type.is_manager() => {
+                // Set the default method for manager
+            }
+            (None, None, None, None) => (),
+            _ => {
+                let err = Error::new(
+                    ErrorKind::Malformed,
+                    "`server`, `server_port`, `method`, `password` must be provided together",
+                    None,
+                );
+                return Err(err);
+            }
         }
 
-        #[cfg(feature = "local-dns")]
-        if self.local_dns_addr.is_some() || self.remote_dns_addr.is_some() {
-            return false;
-        }
+        // Ext servers
+        if let Some(servers) = config.servers {
+            for svr in servers {
+                // Skip if server is disabled
+                if svr.disabled.unwrap_or(false) {
+                    continue;
+                }
 
-        true
-    }
-}
+                let address = svr.server;
+                let port = svr.server_port;
 
-#[derive(Clone, Debug)]
-pub enum DnsConfig {
-    System,
-    #[cfg(feature = "trust-dns")]
-    TrustDns(ResolverConfig),
-    #[cfg(feature = "local-dns")]
-    LocalDns(NameServerAddr),
-}
+                let addr = match address.parse::<Ipv4Addr>() {
+                    Ok(v4) => ServerAddr::SocketAddr(SocketAddr::V4(SocketAddrV4::new(v4, port))),
+                    Err(..) => match address.parse::<Ipv6Addr>() {
+                        Ok(v6) => ServerAddr::SocketAddr(SocketAddr::V6(SocketAddrV6::new(v6, port, 0, 0))),
+                        Err(..) => ServerAddr::DomainName(address, port),
+                    },
+                };
+
+                let method = match svr.method.parse::<CipherKind>() {
+                    Ok(m) => m,
+                    Err(..) => {
+                        let err = Error::new(
+                            ErrorKind::Invalid,
+                            "unsupported method",
+                            Some(format!("`{}` is not a supported method", svr.method)),
+                        );
+                        return Err(err);
+                    }
+                };
 
-impl Default for DnsConfig {
-    fn default() -> DnsConfig {
-        DnsConfig::System
-    }
-}
+                let password = match read_password(svr.password.as_deref(), svr.password_file.as_deref())? {
+                    Some(p) => p,
+                    None => {
+                        let err = Error::new(
+                            ErrorKind::MissingField,
+                            "missing `password` of `servers`",
+                            Some(addr.to_string()),
+                        );
+                        return Err(err);
+                    }
+                };
 
-/// Security Config
-#[derive(Clone, Debug, Default)]
-pub struct SecurityConfig {
-    pub replay_attack: SecurityReplayAttackConfig,
-}
+                if let Err(err) = password_to_key(method, &password) {
+                    let err = Error::new(ErrorKind::Invalid, "invalid password", Some(err.to_string()));
+                    return Err(err);
+                }
 
-#[derive(Clone, Debug, Default)]
-pub struct SecurityReplayAttackConfig {
-    pub policy: ReplayAttackPolicy,
-}
+                let mut nsvr = ServerConfig::new(addr, password, method);
 
-/// Balancer Config
-#[derive(Clone, Debug, Default)]
-pub struct BalancerConfig {
-    /// MAX rtt of servers, which is the timeout duration of each check requests
-    pub max_server_rtt: Option<Duration>,
-    /// Interval between each checking
-    pub check_interval: Option<Duration>,
-    /// Interval for checking the best server
-    pub check_best_interval: Option<Duration>,
-}
+                #[cfg(feature = "transport-quic")]
+                let explicit_mode = svr.mode.is_some();
 
-/// Configuration
-#[derive(Clone, Debug)]
-pub struct Config {
-    /// Remote ShadowSocks server configurations
-    pub server: Vec<ServerConfig>,
-    /// Local server configuration
-    pub local: Vec<LocalConfig>,
+                match svr.mode {
+                    Some(mode) => match mode.parse::<Mode>() {
+                        Ok(mode) => nsvr.set_mode(mode),
+                        Err(..) => {
+                            let err = Error::new(ErrorKind::Invalid, "invalid `mode`", None);
+                            return Err(err);
+                        }
+                    },
+                    None => {
+                        // Server will derive mode from the global scope
+                        if matches!(config_type, ConfigType::Server | ConfigType::Manager) {
+                            nsvr.set_mode(global_mode);
+                        }
+                    }
+                }
 
-    /// DNS configuration, uses system-wide DNS configuration by default
-    ///
-    /// Value could be a `IpAddr`, uses UDP DNS protocol with port `53`. For example: `8.8.8.8`
-    ///
-    /// Also Value could be some pre-defined DNS server names:
-    ///
-    /// - `google`
-    /// - `cloudflare`, `cloudflare_tls`, `cloudflare_https`
-    /// - `quad9`, `quad9_tls`
-    pub dns: DnsConfig,
-    /// Uses IPv6 addresses first
-    ///
-    /// Set to `true` if you want to query IPv6 addresses before IPv4
-    pub ipv6_first: bool,
-    /// Set `IPV6_V6ONLY` for listener sockets
-    pub ipv6_only: bool,
+                if let Some(p) = svr.plugin {
+                    // SIP008 allows "plugin" to be an empty string
+                    // Empty string implies "no plugin"
+                    if !p.is_empty() {
+                        let plugin = PluginConfig {
+                            plugin: p,
+                            plugin_opts: svr.plugin_opts,
+                            plugin_args: svr.plugin_args.unwrap_or_default(),
+                            plugin_mode: parse_plugin_mode(svr.plugin_mode.as_deref())?,
+                        };
+                        nsvr.set_plugin(plugin);
+                    }
+                }
 
-    /// Set `TCP_NODELAY` socket option
-    pub no_delay: bool,
-    /// Set `TCP_FASTOPEN` socket option
-    pub fast_open: bool,
-    /// Set TCP Keep-Alive duration, will set both `TCP_KEEPIDLE` and `TCP_KEEPINTVL`
-    ///
-    /// <https://github.com/shadowsocks/shadowsocks-rust/issues/546>
-    ///
-    /// If this is not set, sockets will be set with a default timeout
-    pub keep_alive: Option<Duration>,
+                if let Some(timeout) = config.timeout.map(Duration::from_secs) {
+                    nsvr.set_timeout(timeout);
+                }
 
-    /// `RLIMIT_NOFILE` option for *nix systems
-    #[cfg(all(unix, not(target_os = "android")))]
-    pub nofile: Option<u64>,
+                if let Some(remarks) = svr.remarks {
+                    nsvr.set_remarks(remarks);
+                }
 
-    /// Set `SO_MARK` socket option for outbound sockets
-    #[cfg(any(target_os = "linux", target_os = "android"))]
-    pub outbound_fwmark: Option<u32>,
-    /// Set `SO_USER_COOKIE` socket option for outbound sockets
-    #[cfg(target_os = "freebsd")]
-    pub outbound_user_cookie: Option<u32>,
-    /// Set `SO_BINDTODEVICE` (Linux), `IP_BOUND_IF` (BSD), `IP_UNICAST_IF` (Windows) socket option for outbound sockets
-    pub outbound_bind_interface: Option<String>,
-    /// Outbound sockets will `bind` to this address
-    pub outbound_bind_addr: Option<IpAddr>,
-    /// Path to protect callback unix address, only for Android
-    #[cfg(target_os = "android")]
-    pub outbound_vpn_protect_path: Option<PathBuf>,
+                if let Some(id) = svr.id {
+                    nsvr.set_id(id);
+                }
 
-    /// Set `SO_SNDBUF` for inbound sockets
-    pub inbound_send_buffer_size: Option<u32>,
-    /// Set `SO_RCVBUF` for inbound sockets
-    pub inbound_recv_buffer_size: Option<u32>,
-    /// Set `SO_SNDBUF` for outbound sockets
-    pub outbound_send_buffer_size: Option<u32>,
-    /// Set `SO_RCVBUF` for outbound sockets
-    pub outbound_recv_buffer_size: Option<u32>,
+                if svr.tcp_weight.is_some() || svr.udp_weight.is_some() {
+                    let tcp_weight = svr.tcp_weight.unwrap_or(1.0);
+                    if !(0.0..=1.0).contains(&tcp_weight) {
+                        let err = Error::new(ErrorKind::Invalid, "invalid `tcp_weight`, must be in [0, 1]", None);
+                        return Err(err);
+                    }
+                    let udp_weight = svr.udp_weight.unwrap_or(1.0);
+                    if !(0.0..=1.0).contains(&udp_weight) {
+                        let err = Error::new(ErrorKind::Invalid, "invalid `udp_weight`, must be in [0, 1]", None);
+                        return Err(err);
+                    }
+                    let mut weight = ServerWeight::new();
+                    weight.set_tcp_weight(tcp_weight);
+                    weight.set_udp_weight(udp_weight);
+                    nsvr.set_weight(weight);
+                }
 
-    /// Manager's configuration
-    pub manager: Option<ManagerConfig>,
+                if let Some(udp_over_tcp) = svr.udp_over_tcp {
+                    nsvr.set_udp_over_tcp(udp_over_tcp);
+                }
 
-    /// Config is for Client or Server
-    pub config_type: ConfigType,
+                if let Some(country) = svr.country {
+                    nsvr.set_country(country);
+                }
 
-    /// Timeout for UDP Associations, default is 5 minutes
-    pub udp_timeout: Option<Duration>,
-    /// Maximum number of UDP Associations, default is unconfigured
-    pub udp_max_associations: Option<usize>,
+                if let Some(city) = svr.city {
+                    nsvr.set_city(city);
+                }
 
-    /// ACL configuration
-    pub acl: Option<AccessControl>,
+                if let Some(tags) = svr.tags {
+                    nsvr.set_tags(tags);
+                }
 
-    /// Flow statistic report Unix socket path (only for Android)
-    #[cfg(feature = "local-flow-stat")]
-    pub stat_path: Option<PathBuf>,
+                if let Some(rate) = svr.tcp_pacing_rate {
+                    if rate == 0 {
+                        let err = Error::new(ErrorKind::Malformed, "`tcp_pacing_rate` must be > 0", None);
+                        return Err(err);
+                    }
+                    nsvr.set_tcp_pacing_rate(rate);
+                }
 
-    /// Replay attack policy
-    pub security: SecurityConfig,
+                if let Some(mptcp) = svr.mptcp {
+                    nsvr.set_mptcp(mptcp);
+                }
 
-    /// Balancer config of local server
-    pub balancer: BalancerConfig,
+                if let Some(ref mux) = svr.mux {
+                    nsvr.set_mux(mux.build()?);
+                }
 
-    /// Configuration file path, the actual path of the configuration.
-    /// This is normally for auto-reloading if implementation supports.
-    pub config_path: Option<PathBuf>,
-}
+                #[cfg(feature = "stream-padding")]
+                if let Some(ref padding) = svr.padding {
+                    nsvr.set_padding(padding.build()?);
+                }
 
-/// Configuration parsing error kind
-#[derive(Copy, Clone, Debug)]
-pub enum ErrorKind {
-    /// Missing required fields in JSON configuration
-    MissingField,
-    /// Missing some keys that must be provided together
-    Malformed,
-    /// Invalid value of some configuration keys
-    Invalid,
-    /// Invalid JSON
-    JsonParsingError,
-    /// `std::io::Error`
-    IoError,
-}
+                if let Some(ref port_hopping) = svr.port_hopping {
+                    nsvr.set_port_hopping(port_hopping.build(nsvr.addr().port())?);
+                }
 
-/// Configuration parsing error
-pub struct Error {
-    pub kind: ErrorKind,
-    pub desc: &'static str,
-    pub detail: Option<String>,
-}
+                if let Some(ref listen_addresses) = svr.listen_addresses {
+                    nsvr.set_listen_addrs(parse_listen_addresses(listen_addresses)?);
+                }
 
-impl Error {
-    pub fn new(kind: ErrorKind, desc: &'static str, detail: Option<String>) -> Error {
-        Error { kind, desc, detail }
-    }
-}
+                if let Some(listeners) = svr.listeners {
+                    nsvr.set_listeners(listeners);
+                }
 
-macro_rules! impl_from {
-    ($error:ty, $kind:expr, $desc:expr) => {
-        impl From<$error> for Error {
-            fn from(err: $error) -> Self {
-                Error::new($kind, $desc, Some(format!("{:?}", err)))
-            }
-        }
-    };
-}
+                if let Some(ref upstream_proxy) = svr.upstream_proxy {
+                    match upstream_proxy.parse::<UpstreamProxy>() {
+                        Ok(proxy) => nsvr.set_upstream_proxy(proxy),
+                        Err(err) => {
+                            let err =
+                                Error::new(ErrorKind::Malformed, "invalid `upstream_proxy`", Some(err.to_string()));
+                            return Err(err);
+                        }
+                    }
+                }
 
-impl_from!(::std::io::Error, ErrorKind::IoError, "error while reading file");
-impl_from!(json5::Error, ErrorKind::JsonParsingError, "json parse error");
+                #[cfg(feature = "aead-cipher-2022")]
+                if let Some(ref users) = svr.users {
+                    let user_manager = SSServerUserConfig::build_user_manager(method, users)?;
+                    nsvr.set_user_manager(Arc::new(user_manager));
+                }
 
-impl Debug for Error {
-    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
-        match self.detail {
-            None => write!(f, "{}", self.desc),
-            Some(ref det) => write!(f, "{} {}", self.desc, det),
-        }
-    }
-}
+                if let Some(quota) = svr.quota {
+                    nsvr.set_quota(parse_quota(quota)?);
+                }
 
-impl Display for Error {
-    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
-        match self.detail {
-            None => f.write_str(self.desc),
-            Some(ref d) => write!(f, "{}, {}", self.desc, d),
-        }
-    }
-}
+                if let Some(ref fallback) = svr.fallback {
+                    nsvr.set_fallback(parse_fallback(fallback)?);
+                }
 
-impl Config {
-    /// Creates an empty configuration
-    pub fn new(config_type: ConfigType) -> Config {
-        Config {
-            server: Vec::new(),
-            local: Vec::new(),
+                if let Some(iface) = svr.outbound_bind_interface {
+                    nsvr.set_outbound_bind_interface(iface);
+                }
 
-            dns: DnsConfig::default(),
-            ipv6_first: false,
-            ipv6_only: false,
+                if let Some(ref addr) = svr.outbound_bind_addr {
+                    nsvr.set_outbound_bind_addr(parse_outbound_bind_addr(addr)?);
+                }
 
-            no_delay: false,
-            fast_open: false,
-            keep_alive: None,
+                if let Some(ref tuning) = svr.tuning {
+                    let (tcp_tuning, relay_buffer_sizes) = tuning.build()?;
+                    nsvr.set_tcp_tuning(tcp_tuning);
+                    nsvr.set_relay_buffer_sizes(relay_buffer_sizes);
+                }
+
+                if let Some(ref rate_limit) = svr.rate_limit {
+                    if rate_limit.global.is_some() {
+                        let err = Error::new(
+                            ErrorKind::Malformed,
+                            "`global` of `rate_limit` is only allowed in the root object",
+                            None,
+                        );
+                        return Err(err);
+                    }
+                    if let Some(rate_limit) = rate_limit.build()? {
+                        nsvr.set_rate_limit(rate_limit);
+                    }
+                }
 
-            #[cfg(all(unix, not(target_os = "android")))]
-            nofile: None,
+                #[cfg(feature = "transport-shadow-tls")]
+                if let Some(st) = svr.shadow_tls {
+                    let mut shadow_tls = ShadowTlsConfig::new(st.password, st.sni);
+                    if let Some(handshake_server) = st.handshake_server {
+                        match handshake_server.parse::<Address>() {
+                            Ok(addr) => shadow_tls.set_handshake_server(addr),
+                            Err(..) => {
+                                let err = Error::new(
+                                    ErrorKind::Invalid,
+                                    "invalid `handshake_server` of `shadow_tls`, must be \"host:port\"",
+                                    None,
+                                );
+                                return Err(err);
+                            }
+                        }
+                    }
+                    nsvr.set_shadow_tls(shadow_tls);
+                }
 
-            #[cfg(any(target_os = "linux", target_os = "android"))]
-            outbound_fwmark: None,
-            #[cfg(target_os = "freebsd")]
-            outbound_user_cookie: None,
-            outbound_bind_interface: None,
-            outbound_bind_addr: None,
-            #[cfg(target_os = "android")]
-            outbound_vpn_protect_path: None,
+                #[cfg(feature = "transport-quic")]
+                if let Some(q) = svr.quic {
+                    // QUIC listens on UDP of the server's port, UDP relay couldn't share it
+                    if explicit_mode && nsvr.mode().enable_udp() {
+                        let err = Error::new(
+                            ErrorKind::Invalid,
+                            "`quic` couldn't be used with UDP relay `mode`",
+                            None,
+                        );
+                        return Err(err);
+                    }
+                    nsvr.set_mode(Mode::TcpOnly);
 
-            inbound_send_buffer_size: None,
-            inbound_recv_buffer_size: None,
-            outbound_send_buffer_size: None,
-            outbound_recv_buffer_size: None,
+                    let mut quic = QuicConfig::new();
+                    if let Some(server_name) = q.server_name {
+                        quic.set_server_name(server_name);
+                    }
+                    if let Some(certificate) = q.certificate {
+                        quic.set_certificate(certificate);
+                    }
+                    if let Some(private_key) = q.private_key {
+                        quic.set_private_key(private_key);
+                    }
+                    nsvr.set_quic(quic);
+                }
 
-            manager: None,
+                #[cfg(feature = "transport-grpc")]
+                if let Some(g) = svr.grpc {
+                    let mut grpc = GrpcConfig::new();
+                    if let Some(service_name) = g.service_name {
+                        if service_name.is_empty() || service_name.contains('/') {
+                            let err = Error::new(
+                                ErrorKind::Malformed,
+                                "`service_name` of `grpc` shouldn't be empty or contain '/'",
+                                None,
+                            );
+                            return Err(err);
+                        }
+                        grpc.set_service_name(service_name);
+                    }
+                    if let Some(server_name) = g.server_name {
+                        grpc.set_server_name(server_name);
+                    }
+                    if let Some(tls) = g.tls {
+                        grpc.set_tls(tls);
+                    }
+                    if let Some(certificate) = g.certificate {
+                        grpc.set_certificate(certificate);
+                    }
+                    if let Some(private_key) = g.private_key {
+                        grpc.set_private_key(private_key);
+                    }
+                    if let Some(max_streams) = g.max_streams {
+                        if max_streams == 0 {
+                            let err = Error::new(ErrorKind::Malformed, "`max_streams` of `grpc` must be > 0", None);
+                            return Err(err);
+                        }
+                        grpc.set_max_streams(max_streams);
+                    }
+                    nsvr.set_grpc(grpc);
+                }
 
-            config_type,
+                nconfig.server.push(nsvr);
+            }
+        }
 
-            udp_timeout: None,
-            udp_max_associations: None,
+        if let Some(ref server_urls_file) = config.server_urls_file {
+            nconfig.server.extend(read_server_urls_file(server_urls_file)?);
+        }
 
-            acl: None,
+        // Set timeout globally
+        if let Some(timeout) = config.timeout {
+            let timeout = Duration::from_secs(timeout);
+            // Set as a default timeout
+            for svr in &mut nconfig.server {
+                if svr.timeout().is_none() {
+                    svr.set_timeout(timeout);
+                }
+            }
+        }
 
-            #[cfg(feature = "local-flow-stat")]
-            stat_path: None,
+        #[cfg(feature = "server")]
+        if let Some(outbound_firewall) = config.outbound_firewall {
+            nconfig.outbound_firewall = Some(outbound_firewall.build()?);
+        }
 
-            security: SecurityConfig::default(),
+        #[cfg(feature = "server")]
+        if let Some(client_limit) = config.client_limit {
+            nconfig.client_limit = Some(client_limit.build()?);
+        }
 
-            balancer: BalancerConfig::default(),
+        #[cfg(feature = "server")]
+        if let Some(udp_eviction) = config.udp_eviction {
+            nconfig.udp_eviction = Some(udp_eviction.build()?);
+        }
 
-            config_path: None,
+        #[cfg(feature = "server")]
+        if let Some(drain_timeout) = config.drain_timeout {
+            nconfig.drain_timeout = Some(Duration::from_secs(drain_timeout));
         }
-    }
 
-    fn load_from_ssconfig(config: SSConfig, config_type: ConfigType) -> Result<Config, Error> {
-        let mut nconfig = Config::new(config_type);
+        // Set rate limits globally, as defaults of servers
+        if let Some(ref rate_limit) = config.rate_limit {
+            nconfig.global_rate_limit = rate_limit.global;
+            if let Some(rate_limit) = rate_limit.build()? {
+                for svr in &mut nconfig.server {
+                    if svr.rate_limit().is_none() {
+                        svr.set_rate_limit(rate_limit.clone());
+                    }
+                }
+            }
+        }
 
-        // Client
-        //
-        // local_address is allowed to be NULL, which means to bind to ::1 or 127.0.0.1
-        //
-        // https://shadowsocks.org/en/config/quick-guide.html
-        #[inline]
-        fn get_local_address(local_address: Option<String>, local_port: u16, ipv6_first: bool) -> ServerAddr {
-            match local_address {
-                Some(addr) => {
-                    match addr.parse::<IpAddr>() {
-                        Ok(ip) => ServerAddr::from(SocketAddr::new(ip, local_port)),
+        // Manager Address
+        if let Some(ma) = config.manager_address {
+            let manager = match config.manager_port {
+                Some(port) => {
+                    match ma.parse::<IpAddr>() {
+                        Ok(ip) => ManagerAddr::from(SocketAddr::new(ip, port)),
                         Err(..) => {
                             // treated as domain
-                            ServerAddr::from((addr, local_port))
+                            ManagerAddr::from((ma, port))
                         }
                     }
                 }
+                #[cfg(unix)]
+                None => ManagerAddr::from(PathBuf::from(ma)),
+                #[cfg(not(unix))]
                 None => {
-                    // Implementation note: This is not implemented like libev which will choose IPv6 or IPv6 LoopBack address
-                    // by checking all its remote servers if all of them supports IPv6.
-                    let ip = if ipv6_first {
-                        Ipv6Addr::LOCALHOST.into()
-                    } else {
-                        Ipv4Addr::LOCALHOST.into()
-                    };
+                    let e = Error::new(ErrorKind::MissingField, "missing `manager_port`", None);
+                    return Err(e);
+                }
+            };
 
-                    ServerAddr::from(SocketAddr::new(ip, local_port))
+            let mut manager_config = ManagerConfig::new(manager);
+            manager_config.mode = global_mode;
+
+            if let Some(key) = config.manager_key {
+                if key.is_empty() {
+                    let err = Error::new(ErrorKind::Invalid, "`manager_key` must not be empty", None);
+                    return Err(err);
                 }
+                manager_config.key = Some(key);
             }
-        }
 
-        // Mode
-        let mut global_mode = Mode::TcpOnly;
-        if let Some(m) = config.mode {
-            match m.parse::<Mode>() {
-                Ok(xm) => global_mode = xm,
-                Err(..) => {
-                    let e = Error::new(
-                        ErrorKind::Malformed,
-                        "malformed `mode`, must be one of `tcp_only`, `udp_only` and `tcp_and_udp`",
-                        None,
-                    );
-                    return Err(e);
+            if let Some(ref m) = config.method {
+                match m.parse::<CipherKind>() {
+                    Ok(method) => manager_config.method = Some(method),
+                    Err(..) => {
+                        let err = Error::new(
+                            ErrorKind::Invalid,
+                            "unsupported method",
+                            Some(format!("`{}` is not a supported method", m)),
+                        );
+                        return Err(err);
+                    }
                 }
             }
-        }
 
-        match config_type {
-            ConfigType::Local => {
-                // Standard config
-                if config.local_address.is_some() && config.local_port.unwrap_or(0) == 0 {
-                    let err = Error::new(ErrorKind::MissingField, "missing `local_port`", None);
-                    return Err(err);
+            if let Some(p) = config.plugin {
+                // SIP008 allows "plugin" to be an empty string
+                // Empty string implies "no plugin"
+                if !p.is_empty() {
+                    manager_config.plugin = Some(PluginConfig {
+                        plugin: p,
+                        plugin_opts: config.plugin_opts,
+                        plugin_args: config.plugin_args.unwrap_or_default(),
+                        plugin_mode: parse_plugin_mode(config.plugin_mode.as_deref())?,
+                    });
                 }
+            }
 
-                if let Some(local_port) = config.local_port {
-                    // local_port won't be 0, it was checked above
-                    assert_ne!(local_port, 0);
+            #[cfg(feature = "manager-http-api")]
+            if let Some(http_api) = config.manager_http_api {
+                manager_config.http_api = Some(http_api.build()?);
+            }
 
-                    let local_addr =
-                        get_local_address(config.local_address, local_port, config.ipv6_first.unwrap_or(false));
+            #[cfg(feature = "manager-sip008")]
+            if let Some(sip008) = config.manager_sip008 {
+                manager_config.sip008 = Some(sip008.build()?);
+            }
 
-                    // shadowsocks uses SOCKS5 by default
-                    let mut local_config = LocalConfig::new(ProtocolType::Socks);
-                    local_config.addr = Some(local_addr);
-                    local_config.mode = global_mode;
-                    local_config.protocol = match config.protocol {
-                        None => ProtocolType::Socks,
-                        Some(p) => match p.parse::<ProtocolType>() {
-                            Ok(p) => p,
-                            Err(..) => {
-                                let err = Error::new(
-                                    ErrorKind::Malformed,
-                                    "`protocol` invalid",
-                                    Some(format!("unrecognized protocol {}", p)),
-                                );
-                                return Err(err);
-                            }
-                        },
-                    };
-                    nconfig.local.push(local_config);
-                }
+            #[cfg(feature = "manager-web-ui")]
+            if let Some(web_ui) = config.manager_web_ui {
+                manager_config.web_ui = Some(web_ui.build()?);
+            }
 
-                // Ext locals
-                // `locals` are only effective in local server
-                if let Some(locals) = config.locals {
-                    for local in locals {
-                        if local.disabled.unwrap_or(false) {
-                            continue;
-                        }
+            nconfig.manager = Some(manager_config);
+        }
 
-                        let protocol = match local.protocol {
-                            None => ProtocolType::Socks,
-                            Some(p) => match p.parse::<ProtocolType>() {
-                                Ok(p) => p,
-                                Err(..) => {
-                                    let err = Error::new(
-                                        ErrorKind::Malformed,
-                                        "`protocol` invalid",
-                                        Some(format!("unrecognized protocol {}", p)),
-                                    );
-                                    return Err(err);
-                                }
-                            },
-                        };
+        // DNS
+        {
+            match config.dns {
+                Some(SSDnsConfig::Simple(ds)) => nconfig.set_dns_formatted(&ds)?,
+                #[cfg(feature = "trust-dns")]
+                Some(SSDnsConfig::TrustDns(c)) => nconfig.dns = DnsConfig::TrustDns(c),
+                None => nconfig.dns = DnsConfig::System,
+            }
 
-                        let mut local_config = LocalConfig::new(protocol);
+            match config.server_dns {
+                Some(SSDnsConfig::Simple(ds)) => nconfig.set_server_dns_formatted(&ds)?,
+                #[cfg(feature = "trust-dns")]
+                Some(SSDnsConfig::TrustDns(c)) => nconfig.server_dns = Some(DnsConfig::TrustDns(c)),
+                None => {}
+            }
+        }
 
-                        if let Some(local_port) = local.local_port {
-                            if local_port == 0 {
-                                let err = Error::new(ErrorKind::Malformed, "`local_port` cannot be 0", None);
-                                return Err(err);
-                            }
+        // TCP nodelay
+        if let Some(b) = config.no_delay {
+            nconfig.no_delay = b;
+        }
 
-                            let local_addr =
-                                get_local_address(local.local_address, local_port, config.ipv6_first.unwrap_or(false));
-                            local_config.addr = Some(local_addr);
-                        } else if local.local_address.is_some() {
-                            let err = Error::new(ErrorKind::Malformed, "missing `local_port`", None);
-                            return Err(err);
-                        }
+        // TCP fast open
+        if let Some(b) = config.fast_open {
+            nconfig.fast_open = b;
+        }
+
+        // TCP Keep-Alive
+        if let Some(d) = config.keep_alive {
+            nconfig.keep_alive = Some(Duration::from_secs(d));
+        }
+
+        // UDP
+        nconfig.udp_timeout = config.udp_timeout.map(Duration::from_secs);
 
-                        if let Some(local_udp_port) = local.local_udp_port {
-                            if local_udp_port == 0 {
-                                let err = Error::new(ErrorKind::Malformed, "`local_udp_port` cannot be 0", None);
-                                return Err(err);
-                            }
+        // Maximum associations to be kept simultaneously
+        nconfig.udp_max_associations = config.udp_max_associations;
+        #[cfg(feature = "local")]
+        if let Some(ref udp_nat) = config.udp_nat {
+            nconfig.udp_nat = Some(udp_nat.build()?);
+        }
+        if let Some(udp_migration) = config.udp_migration {
+            nconfig.udp_migration = udp_migration;
+        }
+        if let Some(direct_fallback) = config.direct_fallback {
+            nconfig.direct_fallback = direct_fallback;
+        }
+        #[cfg(feature = "local")]
+        if let Some(ref routing_mode) = config.routing_mode {
+            match routing_mode.parse::<RoutingMode>() {
+                Ok(m) => nconfig.routing_mode = m,
+                Err(..) => {
+                    let err = Error::new(
+                        ErrorKind::Malformed,
+                        "`routing_mode` invalid, expecting \"global\", \"rule\" or \"direct\"",
+                        Some(format!("routing_mode: {}", routing_mode)),
+                    );
+                    return Err(err);
+                }
+            }
+        }
+        if let Some(route_debug) = config.route_debug {
+            nconfig.route_debug = route_debug;
+        }
+        if let Some(tcp_splice) = config.tcp_splice {
+            nconfig.tcp_splice = tcp_splice;
+        }
+        #[cfg(feature = "acl")]
+        if let Some(acl_trace) = config.acl_trace {
+            nconfig.acl_trace = acl_trace;
+        }
 
-                            let local_udp_addr = get_local_address(
-                                local.local_udp_address,
-                                local_udp_port,
-                                config.ipv6_first.unwrap_or(false),
-                            );
+        #[cfg(feature = "metrics")]
+        if let Some(metrics_address) = config.metrics_address {
+            nconfig.metrics_addr = match metrics_address.parse::<ServerAddr>() {
+                Ok(addr) => Some(addr),
+                Err(..) => {
+                    let err = Error::new(ErrorKind::Malformed, "`metrics_address` invalid", Some(metrics_address));
+                    return Err(err);
+                }
+            };
+        }
 
-                            local_config.udp_addr = Some(local_udp_addr);
-                        }
+        // RLIMIT_NOFILE
+        #[cfg(all(unix, not(target_os = "android")))]
+        {
+            nconfig.nofile = config.nofile;
+        }
 
-                        match local.mode {
-                            Some(mode) => match mode.parse::<Mode>() {
-                                Ok(mode) => local_config.mode = mode,
-                                Err(..) => {
-                                    let err = Error::new(ErrorKind::Malformed, "invalid `mode`", None);
-                                    return Err(err);
-                                }
-                            },
-                            None => {
-                                local_config.mode = global_mode;
-                            }
-                        }
+        #[cfg(feature = "io-uring")]
+        if let Some(io_uring) = config.io_uring {
+            nconfig.io_uring = io_uring;
+        }
 
-                        #[cfg(feature = "local-tunnel")]
-                        if let Some(forward_address) = local.forward_address {
-                            let forward_port = match local.forward_port {
-                                None | Some(0) => {
-                                    let err =
-                                        Error::new(ErrorKind::Malformed, "`forward_port` cannot be missing or 0", None);
-                                    return Err(err);
-                                }
-                                Some(p) => p,
-                            };
+        if let Some(buffer_pool) = config.buffer_pool {
+            if let Some(buffer_size) = buffer_pool.buffer_size {
+                nconfig.buffer_pool.buffer_size = buffer_size;
+            }
+            if let Some(max_buffers) = buffer_pool.max_buffers {
+                nconfig.buffer_pool.max_buffers = max_buffers;
+            }
+        }
 
-                            local_config.forward_addr = Some(match forward_address.parse::<IpAddr>() {
-                                Ok(ip) => Address::from(SocketAddr::new(ip, forward_port)),
-                                Err(..) => Address::from((forward_address, forward_port)),
-                            });
-                        }
+        if let Some(udp_offload) = config.udp_offload {
+            nconfig.udp_offload = udp_offload;
+        }
 
-                        #[cfg(feature = "local-redir")]
-                        if let Some(tcp_redir) = local.tcp_redir {
-                            match tcp_redir.parse::<RedirType>() {
-                                Ok(r) => local_config.tcp_redir = r,
-                                Err(..) => {
-                                    let err = Error::new(ErrorKind::Malformed, "`tcp_redir` invalid", None);
-                                    return Err(err);
-                                }
-                            }
-                        }
+        // Uses IPv6 first
+        if let Some(f) = config.ipv6_first {
+            nconfig.ipv6_first = f;
+        }
+        nconfig.ip_strategy = IpStrategy::from_ipv6_first(nconfig.ipv6_first);
 
-                        #[cfg(feature = "local-redir")]
-                        if let Some(udp_redir) = local.udp_redir {
-                            match udp_redir.parse::<RedirType>() {
-                                Ok(r) => local_config.udp_redir = r,
-                                Err(..) => {
-                                    let err = Error::new(ErrorKind::Malformed, "`udp_redir` invalid", None);
-                                    return Err(err);
-                                }
-                            }
-                        }
+        if let Some(ref strategy) = config.ip_strategy {
+            nconfig.ip_strategy = match strategy.parse::<IpStrategy>() {
+                Ok(s) => s,
+                Err(..) => {
+                    let err = Error::new(
+                        ErrorKind::Malformed,
+                        "`ip_strategy` must be \"prefer_ipv4\", \"ipv6_first\", \"ipv4_only\" or \"ipv6_only\"",
+                        Some(strategy.clone()),
+                    );
+                    return Err(err);
+                }
+            };
+        }
 
-                        #[cfg(feature = "local-dns")]
-                        if let Some(local_dns_address) = local.local_dns_address {
-                            match local_dns_address.parse::<IpAddr>() {
-                                Ok(ip) => {
-                                    local_config.local_dns_addr = Some(NameServerAddr::SocketAddr(SocketAddr::new(
-                                        ip,
-                                        local.local_dns_port.unwrap_or(53),
-                                    )));
-                                }
-                                #[cfg(unix)]
-                                Err(..) => {
-                                    local_config.local_dns_addr =
-                                        Some(NameServerAddr::UnixSocketAddr(PathBuf::from(local_dns_address)));
-                                }
-                                #[cfg(not(unix))]
-                                Err(..) => {
-                                    let err = Error::new(ErrorKind::Malformed, "`local_dns_address` invalid", None);
-                                    return Err(err);
-                                }
-                            }
+        // IPV6_V6ONLY
+        if let Some(o) = config.ipv6_only {
+            nconfig.ipv6_only = o;
+        }
+
+        // Security
+        if let Some(sec) = config.security {
+            if let Some(replay_attack) = sec.replay_attack {
+                if let Some(policy) = replay_attack.policy {
+                    match policy.parse::<ReplayAttackPolicy>() {
+                        Ok(p) => nconfig.security.replay_attack.policy = p,
+                        Err(..) => {
+                            let err = Error::new(ErrorKind::Invalid, "invalid replay attack policy", None);
+                            return Err(err);
                         }
+                    }
+                }
 
-                        #[cfg(feature = "local-dns")]
-                        if let Some(remote_dns_address) = local.remote_dns_address {
-                            let remote_dns_port = local.remote_dns_port.unwrap_or(53);
-                            local_config.remote_dns_addr = Some(match remote_dns_address.parse::<IpAddr>() {
-                                Ok(ip) => Address::from(SocketAddr::new(ip, remote_dns_port)),
-                                Err(..) => Address::from((remote_dns_address, remote_dns_port)),
-                            });
+                #[cfg(feature = "server")]
+                if let Some(cluster) = replay_attack.cluster {
+                    let listen_addr = match cluster.listen_address.parse::<SocketAddr>() {
+                        Ok(a) => a,
+                        Err(err) => {
+                            let err = Error::new(
+                                ErrorKind::Malformed,
+                                "invalid `replay_attack.cluster.listen_address`",
+                                Some(err.to_string()),
+                            );
+                            return Err(err);
                         }
+                    };
 
-                        #[cfg(feature = "local-tun")]
-                        if let Some(tun_interface_address) = local.tun_interface_address {
-                            match tun_interface_address.parse::<IpNet>() {
-                                Ok(addr) => local_config.tun_interface_address = Some(addr),
-                                Err(..) => {
-                                    let err = Error::new(ErrorKind::Malformed, "`tun_interface_address` invalid", None);
-                                    return Err(err);
-                                }
+                    let mut peers = Vec::with_capacity(cluster.peers.len());
+                    for peer in cluster.peers {
+                        match peer.parse::<ServerAddr>() {
+                            Ok(a) => peers.push(a),
+                            Err(..) => {
+                                let err = Error::new(
+                                    ErrorKind::Malformed,
+                                    "invalid `replay_attack.cluster.peers`",
+                                    Some(format!("peer: {}", peer)),
+                                );
+                                return Err(err);
                             }
                         }
+                    }
 
-                        #[cfg(feature = "local-tun")]
-                        if let Some(tun_interface_name) = local.tun_interface_name {
-                            local_config.tun_interface_name = Some(tun_interface_name);
-                        }
+                    nconfig.security.replay_attack.cluster = Some(ReplayClusterConfig {
+                        listen_addr,
+                        peers,
+                        password: cluster.password,
+                    });
+                }
 
-                        #[cfg(feature = "local")]
-                        if let Some(socks5_auth_config_path) = local.socks5_auth_config_path {
-                            local_config.socks5_auth = Socks5AuthConfig::load_from_file(&socks5_auth_config_path)?;
+                #[cfg(feature = "server")]
+                if let Some(persist) = replay_attack.persist {
+                    let mut persist_config = ReplayPersistConfig::new(PathBuf::from(persist.path));
+                    match persist.interval {
+                        Some(0) => {
+                            let err = Error::new(
+                                ErrorKind::Malformed,
+                                "`replay_attack.persist.interval` must be > 0",
+                                None,
+                            );
+                            return Err(err);
                         }
-
-                        nconfig.local.push(local_config);
+                        Some(interval) => persist_config.interval = Duration::from_secs(interval),
+                        None => {}
                     }
+                    nconfig.security.replay_attack.persist = Some(persist_config);
                 }
             }
-            ConfigType::Server | ConfigType::Manager => {
-                // NOTE: IGNORED.
-                // servers only uses `local_address` for binding outbound interfaces
-                //
-                // This behavior causes lots of confusion. use outbound_bind_addr instead
-            }
-        }
-
-        // Standard config
-        // Server
-        match (config.server, config.server_port, config.password, &config.method) {
-            (Some(address), Some(port), Some(pwd), Some(m)) => {
-                let addr = match address.parse::<Ipv4Addr>() {
-                    Ok(v4) => ServerAddr::SocketAddr(SocketAddr::V4(SocketAddrV4::new(v4, port))),
-                    Err(..) => match address.parse::<Ipv6Addr>() {
-                        Ok(v6) => ServerAddr::SocketAddr(SocketAddr::V6(SocketAddrV6::new(v6, port, 0, 0))),
-                        Err(..) => ServerAddr::DomainName(address, port),
-                    },
-                };
 
-                let method = match m.parse::<CipherKind>() {
-                    Ok(m) => m,
-                    Err(..) => {
-                        let err = Error::new(
-                            ErrorKind::Invalid,
-                            "unsupported method",
-                            Some(format!("`{}` is not a supported method", m)),
-                        );
+            if let Some(clock_skew) = sec.clock_skew {
+                match clock_skew.tolerance {
+                    Some(0) => {
+                        let err = Error::new(ErrorKind::Malformed, "`clock_skew.tolerance` must be > 0", None);
                         return Err(err);
                     }
-                };
-
-                // Only "password" support getting from environment variable.
-                let password = read_variable_field_value(&pwd);
-
-                let mut nsvr = ServerConfig::new(addr, password, method);
-                nsvr.set_mode(global_mode);
-
-                if let Some(ref p) = config.plugin {
-                    // SIP008 allows "plugin" to be an empty string
-                    // Empty string implies "no plugin"
-                    if !p.is_empty() {
-                        let plugin = PluginConfig {
-                            plugin: p.clone(),
-                            plugin_opts: config.plugin_opts.clone(),
-                            plugin_args: config.plugin_args.clone().unwrap_or_default(),
-                        };
-                        nsvr.set_plugin(plugin);
-                    }
+                    Some(tolerance) => nconfig.security.clock_skew.tolerance = Some(Duration::from_secs(tolerance)),
+                    None => {}
                 }
 
-                if let Some(timeout) = config.timeout.map(Duration::from_secs) {
-                    nsvr.set_timeout(timeout);
+                if let Some(ntp_server) = clock_skew.ntp_server {
+                    // Port of NTP is 123 if it is omitted
+                    let addr = match ntp_server.parse::<ServerAddr>() {
+                        Ok(a) => a,
+                        Err(..) => match ntp_server.parse::<IpAddr>() {
+                            Ok(ip) => ServerAddr::SocketAddr(SocketAddr::new(ip, 123)),
+                            Err(..) if !ntp_server.is_empty() && !ntp_server.contains(':') => {
+                                ServerAddr::DomainName(ntp_server, 123)
+                            }
+                            Err(..) => {
+                                let err = Error::new(
+                                    ErrorKind::Malformed,
+                                    "invalid `clock_skew.ntp_server`",
+                                    Some(format!("ntp_server: {}", ntp_server)),
+                                );
+                                return Err(err);
+                            }
+                        },
+                    };
+                    nconfig.security.clock_skew.ntp_server = Some(addr);
                 }
-
-                nconfig.server.push(nsvr);
-            }
-            (None, None, None, Some(_)) if config_type.is_manager() => {
-                // Set the default method for manager
-            }
-            (None, None, None, None) => (),
-            _ => {
-                let err = Error::new(
-                    ErrorKind::Malformed,
-                    "`server`, `server_port`, `method`, `password` must be provided together",
-                    None,
-                );
-                return Err(err);
             }
         }
 
-        // Ext servers
-        if let Some(servers) = config.servers {
-            for svr in servers {
-                // Skip if server is disabled
-                if svr.disabled.unwrap_or(false) {
-                    continue;
-                }
-
-                let address = svr.server;
-                let port = svr.server_port;
+        if let Some(balancer) = config.balancer {
+            let strategy = match balancer.strategy {
+                None => BalancerStrategy::default(),
+                Some(strategy) => match strategy.parse::<BalancerStrategy>() {
+                    Ok(s) => s,
+                    Err(..) => {
+                        let err = Error::new(
+                            ErrorKind::Malformed,
+                            "`balancer.strategy` invalid, could be \"best\", \"weighted\", \"target_hash\", \"client_hash\" or \"failover\"",
+                            None,
+                        );
+                        return Err(err);
+                    }
+                },
+            };
 
-                let addr = match address.parse::<Ipv4Addr>() {
-                    Ok(v4) => ServerAddr::SocketAddr(SocketAddr::V4(SocketAddrV4::new(v4, port))),
-                    Err(..) => match address.parse::<Ipv6Addr>() {
-                        Ok(v6) => ServerAddr::SocketAddr(SocketAddr::V6(SocketAddrV6::new(v6, port, 0, 0))),
-                        Err(..) => ServerAddr::DomainName(address, port),
+            let mut groups = BTreeMap::new();
+            for (name, group) in balancer.groups.unwrap_or_default() {
+                let strategy = match group.strategy {
+                    None => BalancerStrategy::default(),
+                    Some(strategy) => match strategy.parse::<BalancerStrategy>() {
+                        Ok(s) => s,
+                        Err(..) => {
+                            let err = Error::new(
+                                ErrorKind::Malformed,
+                                "`balancer.groups` strategy invalid, could be \"best\", \"weighted\", \"target_hash\", \"client_hash\" or \"failover\"",
+                                Some(format!("group: {}", name)),
+                            );
+                            return Err(err);
+                        }
                     },
                 };
+                groups.insert(name, strategy);
+            }
 
-                let method = match svr.method.parse::<CipherKind>() {
-                    Ok(m) => m,
-                    Err(..) => {
+            let mut probe = BalancerProbeConfig::default();
+            if let Some(p) = balancer.probe {
+                probe.tcp = match p.protocol.as_deref() {
+                    None | Some("http") => match p.target {
+                        None => probe.tcp,
+                        Some(target) => match BalancerTcpProbe::from_http_url(&target) {
+                            Some(p) => p,
+                            None => {
+                                let err = Error::new(
+                                    ErrorKind::Malformed,
+                                    "`balancer.probe.target` invalid, should be an \"http://\" URL",
+                                    None,
+                                );
+                                return Err(err);
+                            }
+                        },
+                    },
+                    Some("tls") => match p.target {
+                        // Port is 443 if omitted, instead of 80 as `Address` assumes
+                        Some(target) if !target.is_empty() && !target.contains(':') => {
+                            BalancerTcpProbe::Tls(Address::DomainNameAddress(target, 443))
+                        }
+                        Some(target) => match target.parse::<Address>() {
+                            Ok(addr) => BalancerTcpProbe::Tls(addr),
+                            Err(..) => {
+                                let err = Error::new(
+                                    ErrorKind::Malformed,
+                                    "`balancer.probe.target` invalid, should be \"host:port\"",
+                                    None,
+                                );
+                                return Err(err);
+                            }
+                        },
+                        None => {
+                            let err = Error::new(
+                                ErrorKind::Malformed,
+                                "`balancer.probe.target` is required for \"tls\" probes",
+                                None,
+                            );
+                            return Err(err);
+                        }
+                    },
+                    Some("tcp") => BalancerTcpProbe::Tcp,
+                    Some(..) => {
                         let err = Error::new(
-                            ErrorKind::Invalid,
-                            "unsupported method",
-                            Some(format!("`{}` is not a supported method", svr.method)),
+                            ErrorKind::Malformed,
+                            "`balancer.probe.protocol` invalid, could be \"tcp\", \"tls\" or \"http\"",
+                            None,
                         );
                         return Err(err);
                     }
                 };
 
-                // Only "password" support getting from environment variable.
-                let password = read_variable_field_value(&svr.password);
-
-                let mut nsvr = ServerConfig::new(addr, password, method);
-
-                match svr.mode {
-                    Some(mode) => match mode.parse::<Mode>() {
-                        Ok(mode) => nsvr.set_mode(mode),
+                if let Some(udp_target) = p.udp_target {
+                    probe.udp_target = match udp_target.parse::<Address>() {
+                        Ok(addr) => addr,
                         Err(..) => {
-                            let err = Error::new(ErrorKind::Invalid, "invalid `mode`", None);
+                            let err = Error::new(
+                                ErrorKind::Malformed,
+                                "`balancer.probe.udp_target` invalid, should be \"host:port\"",
+                                None,
+                            );
                             return Err(err);
                         }
-                    },
-                    None => {
-                        // Server will derive mode from the global scope
-                        if matches!(config_type, ConfigType::Server | ConfigType::Manager) {
-                            nsvr.set_mode(global_mode);
-                        }
-                    }
+                    };
                 }
 
-                if let Some(p) = svr.plugin {
-                    // SIP008 allows "plugin" to be an empty string
-                    // Empty string implies "no plugin"
-                    if !p.is_empty() {
-                        let plugin = PluginConfig {
-                            plugin: p,
-                            plugin_opts: svr.plugin_opts,
-                            plugin_args: svr.plugin_args.unwrap_or_default(),
-                        };
-                        nsvr.set_plugin(plugin);
+                match p.samples {
+                    None => {}
+                    Some(0) => {
+                        let err = Error::new(
+                            ErrorKind::Malformed,
+                            "`balancer.probe.samples` should be at least 1",
+                            None,
+                        );
+                        return Err(err);
                     }
+                    Some(samples) => probe.samples = samples,
                 }
+            }
 
-                if let Some(timeout) = config.timeout.map(Duration::from_secs) {
-                    nsvr.set_timeout(timeout);
-                }
-
-                if let Some(remarks) = svr.remarks {
-                    nsvr.set_remarks(remarks);
-                }
-
-                if let Some(id) = svr.id {
-                    nsvr.set_id(id);
+            let mut scoring = BalancerScoringConfig::default();
+            if let Some(s) = balancer.scoring {
+                if let Some(algorithm) = s.algorithm {
+                    match algorithm.parse::<BalancerScoringAlgorithm>() {
+                        Ok(algorithm) => scoring = BalancerScoringConfig::new(algorithm),
+                        Err(..) => {
+                            let err = Error::new(
+                                ErrorKind::Malformed,
+                                "`balancer.scoring.algorithm` invalid, could be \"median\", \"ewma\", \"percentile\", \"jitter\" or \"loss\"",
+                                None,
+                            );
+                            return Err(err);
+                        }
+                    }
                 }
 
-                if svr.tcp_weight.is_some() || svr.udp_weight.is_some() {
-                    let tcp_weight = svr.tcp_weight.unwrap_or(1.0);
-                    if !(0.0..=1.0).contains(&tcp_weight) {
-                        let err = Error::new(ErrorKind::Invalid, "invalid `tcp_weight`, must be in [0, 1]", None);
-                        return Err(err);
-                    }
-                    let udp_weight = svr.udp_weight.unwrap_or(1.0);
-                    if !(0.0..=1.0).contains(&udp_weight) {
-                        let err = Error::new(ErrorKind::Invalid, "invalid `udp_weight`, must be in [0, 1]", None);
+                if let Some(ewma_alpha) = s.ewma_alpha {
+                    if !(ewma_alpha > 0.0 && ewma_alpha <= 1.0) {
+                        let err = Error::new(
+                            ErrorKind::Malformed,
+                            "`balancer.scoring.ewma_alpha` should be in (0, 1]",
+                            None,
+                        );
                         return Err(err);
                     }
-                    let mut weight = ServerWeight::new();
-                    weight.set_tcp_weight(tcp_weight);
-                    weight.set_udp_weight(udp_weight);
-                    nsvr.set_weight(weight);
+                    scoring.ewma_alpha = ewma_alpha;
                 }
 
-                nconfig.server.push(nsvr);
-            }
-        }
-
-        // Set timeout globally
-        if let Some(timeout) = config.timeout {
-            let timeout = Duration::from_secs(timeout);
-            // Set as a default timeout
-            for svr in &mut nconfig.server {
-                if svr.timeout().is_none() {
-                    svr.set_timeout(timeout);
+                if let Some(percentile) = s.percentile {
+                    if !(percentile > 0.0 && percentile <= 100.0) {
+                        let err = Error::new(
+                            ErrorKind::Malformed,
+                            "`balancer.scoring.percentile` should be in (0, 100]",
+                            None,
+                        );
+                        return Err(err);
+                    }
+                    scoring.percentile = percentile;
                 }
-            }
-        }
 
-        // Manager Address
-        if let Some(ma) = config.manager_address {
-            let manager = match config.manager_port {
-                Some(port) => {
-                    match ma.parse::<IpAddr>() {
-                        Ok(ip) => ManagerAddr::from(SocketAddr::new(ip, port)),
-                        Err(..) => {
-                            // treated as domain
-                            ManagerAddr::from((ma, port))
+                for (weight, field) in [
+                    (s.rtt_weight, &mut scoring.rtt_weight),
+                    (s.failure_weight, &mut scoring.failure_weight),
+                    (s.variation_weight, &mut scoring.variation_weight),
+                ] {
+                    if let Some(weight) = weight {
+                        if !(weight >= 0.0 && weight.is_finite()) {
+                            let err =
+                                Error::new(ErrorKind::Malformed, "`balancer.scoring` weights should be >= 0", None);
+                            return Err(err);
                         }
+                        *field = weight;
                     }
                 }
-                #[cfg(unix)]
-                None => ManagerAddr::from(PathBuf::from(ma)),
-                #[cfg(not(unix))]
-                None => {
-                    let e = Error::new(ErrorKind::MissingField, "missing `manager_port`", None);
-                    return Err(e);
+
+                if scoring.rtt_weight + scoring.failure_weight + scoring.variation_weight <= 0.0 {
+                    let err = Error::new(
+                        ErrorKind::Malformed,
+                        "`balancer.scoring` weights couldn't be all 0",
+                        None,
+                    );
+                    return Err(err);
                 }
-            };
+            }
 
-            let mut manager_config = ManagerConfig::new(manager);
-            manager_config.mode = global_mode;
+            let warm_pool = match balancer.warm_pool {
+                None => None,
+                Some(w) => {
+                    let default_warm_pool = BalancerWarmPoolConfig::default();
+                    let warm_pool = BalancerWarmPoolConfig {
+                        size: w.size.unwrap_or(default_warm_pool.size),
+                        servers: w.servers.unwrap_or(default_warm_pool.servers),
+                        idle_timeout: w
+                            .idle_timeout
+                            .map(Duration::from_secs)
+                            .unwrap_or(default_warm_pool.idle_timeout),
+                    };
 
-            if let Some(ref m) = config.method {
-                match m.parse::<CipherKind>() {
-                    Ok(method) => manager_config.method = Some(method),
-                    Err(..) => {
+                    if warm_pool.size == 0 || warm_pool.servers == 0 || warm_pool.idle_timeout.is_zero() {
                         let err = Error::new(
-                            ErrorKind::Invalid,
-                            "unsupported method",
-                            Some(format!("`{}` is not a supported method", m)),
+                            ErrorKind::Malformed,
+                            "`balancer.warm_pool` size, servers and idle_timeout should be at least 1",
+                            None,
                         );
                         return Err(err);
                     }
+                    Some(warm_pool)
                 }
-            }
+            };
 
-            if let Some(p) = config.plugin {
-                // SIP008 allows "plugin" to be an empty string
-                // Empty string implies "no plugin"
-                if !p.is_empty() {
-                    manager_config.plugin = Some(PluginConfig {
-                        plugin: p,
-                        plugin_opts: config.plugin_opts,
-                        plugin_args: config.plugin_args.unwrap_or_default(),
-                    });
-                }
-            }
+            nconfig.balancer = BalancerConfig {
+                max_server_rtt: balancer.max_server_rtt.map(Duration::from_secs),
+                check_interval: balancer.check_interval.map(Duration::from_secs),
+                check_best_interval: balancer.check_best_interval.map(Duration::from_secs),
+                strategy,
+                failback_window: balancer.failback_window.map(Duration::from_secs),
+                groups,
+                default_group: balancer.default_group,
+                probe,
+                scoring,
+                warm_pool,
+            };
+        }
 
-            nconfig.manager = Some(manager_config);
+        if let Some(isolate_locals) = config.isolate_locals {
+            nconfig.isolate_locals = isolate_locals;
         }
 
-        // DNS
-        {
-            match config.dns {
-                Some(SSDnsConfig::Simple(ds)) => nconfig.set_dns_formatted(&ds)?,
-                #[cfg(feature = "trust-dns")]
-                Some(SSDnsConfig::TrustDns(c)) => nconfig.dns = DnsConfig::TrustDns(c),
-                None => nconfig.dns = DnsConfig::System,
+        nconfig.state_dir = config.state_dir.map(PathBuf::from);
+        #[cfg(feature = "local")]
+        if let Some(export_connections) = config.export_connections {
+            nconfig.export_connections = export_connections;
+        }
+        #[cfg(feature = "local")]
+        if let Some(system_proxy) = config.system_proxy {
+            nconfig.system_proxy = system_proxy;
+        }
+        #[cfg(feature = "local")]
+        if let Some(control_address) = config.control_address {
+            nconfig.control_addr = match control_address.parse::<ManagerAddr>() {
+                Ok(addr) => Some(addr),
+                Err(..) => {
+                    let err = Error::new(ErrorKind::Malformed, "`control_address` invalid", Some(control_address));
+                    return Err(err);
+                }
+            };
+        }
+        #[cfg(feature = "local")]
+        if let Some(control_key) = config.control_key {
+            if control_key.is_empty() {
+                let err = Error::new(ErrorKind::Invalid, "`control_key` must not be empty", None);
+                return Err(err);
             }
+            nconfig.control_key = Some(control_key);
         }
+        #[cfg(feature = "local")]
+        if let Some(flow_export) = config.flow_export {
+            let collector = match flow_export.collector.parse::<ServerAddr>() {
+                Ok(addr) => addr,
+                Err(..) => {
+                    let err = Error::new(
+                        ErrorKind::Malformed,
+                        "`flow_export.collector` invalid",
+                        Some(flow_export.collector),
+                    );
+                    return Err(err);
+                }
+            };
 
-        // TCP nodelay
-        if let Some(b) = config.no_delay {
-            nconfig.no_delay = b;
+            let mut flow_export_config = FlowExportConfig::new(collector);
+            if let Some(observation_domain_id) = flow_export.observation_domain_id {
+                flow_export_config.observation_domain_id = observation_domain_id;
+            }
+            if let Some(enterprise_number) = flow_export.enterprise_number {
+                flow_export_config.enterprise_number = enterprise_number;
+            }
+            nconfig.flow_export = Some(flow_export_config);
         }
 
-        // TCP fast open
-        if let Some(b) = config.fast_open {
-            nconfig.fast_open = b;
+        #[cfg(unix)]
+        if let Some(handoff) = config.handoff {
+            let mut handoff_config = HandoffConfig::new(PathBuf::from(handoff.path));
+            if let Some(drain_timeout) = handoff.drain_timeout {
+                handoff_config.drain_timeout = Duration::from_secs(drain_timeout);
+            }
+            nconfig.handoff = Some(handoff_config);
         }
 
-        // TCP Keep-Alive
-        if let Some(d) = config.keep_alive {
-            nconfig.keep_alive = Some(Duration::from_secs(d));
-        }
+        #[cfg(feature = "local")]
+        if let Some(priority) = config.priority {
+            let mut priority_config = PriorityConfig::new();
+            priority_config.upload_bandwidth = priority.upload_bandwidth;
+            priority_config.download_bandwidth = priority.download_bandwidth;
+            if let Some(default_weight) = priority.default_weight {
+                priority_config.default_weight = default_weight;
+            }
 
-        // UDP
-        nconfig.udp_timeout = config.udp_timeout.map(Duration::from_secs);
+            for class in priority.classes {
+                let mut ports = Vec::with_capacity(class.ports.len());
+                for port in class.ports {
+                    match port.parse::<PortRange>() {
+                        Ok(p) => ports.push(p),
+                        Err(..) => {
+                            let err = Error::new(
+                                ErrorKind::Malformed,
+                                "`priority.classes` invalid ports",
+                                Some(format!("ports: {}", port)),
+                            );
+                            return Err(err);
+                        }
+                    }
+                }
 
-        // Maximum associations to be kept simultaneously
-        nconfig.udp_max_associations = config.udp_max_associations;
+                priority_config.classes.push(PriorityClassConfig {
+                    weight: class.weight,
+                    ports,
+                });
+            }
 
-        // RLIMIT_NOFILE
-        #[cfg(all(unix, not(target_os = "android")))]
-        {
-            nconfig.nofile = config.nofile;
+            nconfig.priority = Some(priority_config);
         }
 
-        // Uses IPv6 first
-        if let Some(f) = config.ipv6_first {
-            nconfig.ipv6_first = f;
+        #[cfg(feature = "local")]
+        if let Some(cache) = config.resolver_cache {
+            let mut cache_config = ResolverCacheConfig::default();
+            if let Some(ttl) = cache.negative_min_ttl {
+                cache_config.negative_min_ttl = Duration::from_secs(ttl);
+            }
+            if let Some(ttl) = cache.negative_max_ttl {
+                cache_config.negative_max_ttl = Duration::from_secs(ttl);
+            }
+            if let Some(size) = cache.size {
+                cache_config.size = size;
+            }
+
+            nconfig.resolver_cache = if cache_config.negative_min_ttl.is_zero() {
+                None
+            } else {
+                Some(cache_config)
+            };
         }
 
-        // IPV6_V6ONLY
-        if let Some(o) = config.ipv6_only {
-            nconfig.ipv6_only = o;
+        #[cfg(feature = "local-fault-injection")]
+        if let Some(fault) = config.fault_injection {
+            let udp_drop_rate = fault.udp_drop_rate.unwrap_or(0.0);
+            if !(0.0..=1.0).contains(&udp_drop_rate) {
+                let err = Error::new(
+                    ErrorKind::Malformed,
+                    "`fault_injection.udp_drop_rate` must be in [0, 1]",
+                    None,
+                );
+                return Err(err);
+            }
+
+            nconfig.fault_injection = Some(FaultInjectionConfig {
+                udp_drop_rate,
+                connect_delay: fault.connect_delay.map(Duration::from_millis),
+                tunnel_kill_after_bytes: fault.tunnel_kill_after_bytes,
+                seed: fault.seed,
+            });
         }
 
-        // Security
-        if let Some(sec) = config.security {
-            if let Some(replay_attack) = sec.replay_attack {
-                if let Some(policy) = replay_attack.policy {
-                    match policy.parse::<ReplayAttackPolicy>() {
-                        Ok(p) => nconfig.security.replay_attack.policy = p,
-                        Err(..) => {
-                            let err = Error::new(ErrorKind::Invalid, "invalid replay attack policy", None);
-                            return Err(err);
-                        }
-                    }
+        #[cfg(feature = "local-online-config")]
+        if let Some(online_config) = config.online_config {
+            let config_url = match online_config.config_url.parse::<hyper::Uri>() {
+                Ok(u) if matches!(u.scheme_str(), Some("http" | "https")) && u.host().is_some() => u,
+                _ => {
+                    let err = Error::new(
+                        ErrorKind::Malformed,
+                        "`config_url` of `online_config` must be a http or https URL",
+                        None,
+                    );
+                    return Err(err);
                 }
-            }
-        }
+            };
 
-        if let Some(balancer) = config.balancer {
-            nconfig.balancer = BalancerConfig {
-                max_server_rtt: balancer.max_server_rtt.map(Duration::from_secs),
-                check_interval: balancer.check_interval.map(Duration::from_secs),
-                check_best_interval: balancer.check_best_interval.map(Duration::from_secs),
+            let update_interval = match online_config.update_interval {
+                Some(0) => {
+                    let err = Error::new(
+                        ErrorKind::Malformed,
+                        "`update_interval` of `online_config` must be > 0",
+                        None,
+                    );
+                    return Err(err);
+                }
+                Some(intv) => Duration::from_secs(intv),
+                None => DEFAULT_UPDATE_INTERVAL,
             };
+
+            nconfig.online_config = Some(OnlineConfig {
+                config_url,
+                update_interval,
+            });
         }
 
         Ok(nconfig)
@@ -1752,9 +6450,21 @@ impl Config {
     /// Set DNS configuration in string format
     ///
     /// 1. `[(unix|tcp|udp)://]host[:port][,host[:port]]...`
-    /// 2. Pre-defined. Like `google`, `cloudflare`
+    /// 2. `(tls|https)://host[:port]#name[,host[:port]#name]...`, with the name of TLS certificates
+    /// 3. Pre-defined. Like `google`, `cloudflare`
     pub fn set_dns_formatted(&mut self, dns: &str) -> Result<(), Error> {
-        self.dns = match dns {
+        self.dns = self.parse_dns_formatted(dns)?;
+        Ok(())
+    }
+
+    /// Set DNS configuration for hostnames of servers in string format, the same as `set_dns_formatted`
+    pub fn set_server_dns_formatted(&mut self, dns: &str) -> Result<(), Error> {
+        self.server_dns = Some(self.parse_dns_formatted(dns)?);
+        Ok(())
+    }
+
+    fn parse_dns_formatted(&mut self, dns: &str) -> Result<DnsConfig, Error> {
+        let dns = match dns {
             "system" => DnsConfig::System,
 
             #[cfg(feature = "trust-dns")]
@@ -1777,7 +6487,7 @@ impl Config {
             nameservers => self.parse_dns_nameservers(nameservers)?,
         };
 
-        Ok(())
+        Ok(dns)
     }
 
     #[cfg(any(feature = "trust-dns", feature = "local-dns"))]
@@ -1792,6 +6502,15 @@ impl Config {
             ))));
         }
 
+        #[cfg(feature = "dns-over-tls")]
+        if let Some(nameservers) = nameservers.strip_prefix("tls://") {
+            return parse_encrypted_dns_nameservers(nameservers, Protocol::Tls, 853);
+        }
+        #[cfg(feature = "dns-over-https")]
+        if let Some(nameservers) = nameservers.strip_prefix("https://") {
+            return parse_encrypted_dns_nameservers(nameservers, Protocol::Https, 443);
+        }
+
         enum DnsProtocol {
             Tcp,
             Udp,
@@ -1883,19 +6602,103 @@ impl Config {
 
     /// Load Config from a `str`
     pub fn load_from_str(s: &str, config_type: ConfigType) -> Result<Config, Error> {
-        let c = json5::from_str::<SSConfig>(s)?;
-        Config::load_from_ssconfig(c, config_type)
+        Config::load_profile_from_str(s, config_type, None)
+    }
+
+    /// Load Config from a `str` with profile `profile` applied, or the `profile` in the configuration if `None`
+    pub fn load_profile_from_str(s: &str, config_type: ConfigType, profile: Option<&str>) -> Result<Config, Error> {
+        Config::load_profile_from_str_with_format(s, ConfigFormat::Json, config_type, profile)
+    }
+
+    /// Load Config from a `str` in `format`, with profile `profile` applied, or the `profile` in the configuration if
+    /// `None`
+    pub fn load_profile_from_str_with_format(
+        s: &str,
+        format: ConfigFormat,
+        config_type: ConfigType,
+        profile: Option<&str>,
+    ) -> Result<Config, Error> {
+        let c = format.deserialize::<SSConfig>(s)?;
+        Config::load_profile_from_ssconfig(c, config_type, profile)
+    }
+
+    fn load_profile_from_ssconfig(
+        mut c: SSConfig,
+        config_type: ConfigType,
+        profile: Option<&str>,
+    ) -> Result<Config, Error> {
+        let profiles = match c.profiles {
+            Some(ref p) => p.keys().cloned().collect(),
+            None => Vec::new(),
+        };
+        let profile = profile.map(ToOwned::to_owned).or_else(|| c.profile.take());
+        let acl_path = match profile {
+            Some(ref name) => c.apply_profile(name)?,
+            None => None,
+        };
+
+        let mut config = Config::load_from_ssconfig(c, config_type)?;
+        config.profiles = profiles;
+        config.profile = profile;
+
+        if let Some(acl_path) = acl_path {
+            #[cfg(feature = "acl")]
+            match AccessControl::load_from_file(&acl_path) {
+                Ok(acl) => config.acl = Some(acl),
+                Err(err) => {
+                    let err = Error::new(
+                        ErrorKind::Invalid,
+                        "failed to load `acl` of profile",
+                        Some(format!("{}, {}", acl_path, err)),
+                    );
+                    return Err(err);
+                }
+            }
+
+            #[cfg(not(feature = "acl"))]
+            {
+                let err = Error::new(
+                    ErrorKind::Invalid,
+                    "`acl` of profile requires feature \"acl\"",
+                    Some(acl_path),
+                );
+                return Err(err);
+            }
+        }
+
+        Ok(config)
     }
 
     /// Load Config from a File
     pub fn load_from_file<P: AsRef<Path>>(filename: P, config_type: ConfigType) -> Result<Config, Error> {
+        Config::load_profile_from_file(filename, config_type, None)
+    }
+
+    /// Load Config from a File with profile `profile` applied, or the `profile` in the configuration if `None`
+    ///
+    /// Format of the file is detected by its extension, see `ConfigFormat::from_path`. Environment variables are
+    /// substituted in string values, see `interpolate_config_value`. Files listed in `include` are merged, see `load_config_value`.
+    pub fn load_profile_from_file<P: AsRef<Path>>(
+        filename: P,
+        config_type: ConfigType,
+        profile: Option<&str>,
+    ) -> Result<Config, Error> {
         let filename = filename.as_ref();
 
         let mut reader = OpenOptions::new().read(true).open(filename)?;
         let mut content = String::new();
         reader.read_to_string(&mut content)?;
 
-        let mut config = Config::load_from_str(&content[..], config_type)?;
+        let format = ConfigFormat::from_path(filename);
+        let value = match format.deserialize::<SSIncludingConfig>(&content)?.include {
+            None => {
+                let mut value = format.deserialize::<serde_json::Value>(&content)?;
+                interpolate_config_value(&mut value);
+                value
+            }
+            Some(..) => load_config_value(filename, &mut Vec::new())?,
+        };
+        let mut config = Config::load_profile_from_ssconfig(serde_json::from_value(value)?, config_type, profile)?;
 
         // Record the path of the configuration for auto-reloading
         config.config_path = Some(filename.to_owned());
@@ -1906,7 +6709,7 @@ impl Config {
     /// Check if there are any plugin are enabled with servers
     pub fn has_server_plugins(&self) -> bool {
         for server in &self.server {
-            if server.plugin().is_some() {
+            if server.external_plugin().is_some() {
                 return true;
             }
         }
@@ -1925,44 +6728,362 @@ impl Config {
                 return Err(err);
             }
 
-            for local_config in &self.local {
-                local_config.check_integrity()?;
-            }
+            // Commands of TCP control sockets could be sent by any local users
+            #[cfg(feature = "local")]
+            if let Some(ref control_addr) = self.control_addr {
+                let is_unix = match *control_addr {
+                    #[cfg(unix)]
+                    ManagerAddr::UnixSocketAddr(..) => true,
+                    _ => false,
+                };
+                if !is_unix && self.control_key.is_none() {
+                    let err = Error::new(
+                        ErrorKind::MissingField,
+                        "TCP `control_address` requires `control_key` for authenticating commands",
+                        None,
+                    );
+                    return Err(err);
+                }
+            }
+
+            #[cfg(feature = "local")]
+            if self.export_connections && self.state_dir.is_none() && self.state_store.is_none() {
+                let err = Error::new(
+                    ErrorKind::MissingField,
+                    "`export_connections` requires `state_dir` for saving connections",
+                    None,
+                );
+                return Err(err);
+            }
+
+            #[cfg(feature = "local")]
+            if self.system_proxy {
+                #[cfg(any(
+                    windows,
+                    target_os = "macos",
+                    target_os = "linux",
+                    target_os = "freebsd",
+                    target_os = "openbsd",
+                    target_os = "netbsd"
+                ))]
+                if crate::local::system_proxy::SystemProxyConfig::from_locals(&self.local).is_empty() {
+                    let err = Error::new(
+                        ErrorKind::Invalid,
+                        "`system_proxy` requires a socks, http or mixed local listening on a TCP address",
+                        None,
+                    );
+                    return Err(err);
+                }
+                #[cfg(not(any(
+                    windows,
+                    target_os = "macos",
+                    target_os = "linux",
+                    target_os = "freebsd",
+                    target_os = "openbsd",
+                    target_os = "netbsd"
+                )))]
+                {
+                    let err = Error::new(
+                        ErrorKind::Invalid,
+                        "`system_proxy` is only supported on Windows, macOS and GNOME of Linux and BSDs",
+                        None,
+                    );
+                    return Err(err);
+                }
+            }
+
+            if let Some(ref group) = self.balancer.default_group {
+                if !self.server.iter().any(|svr| svr.has_tag(group)) {
+                    let err = Error::new(
+                        ErrorKind::Invalid,
+                        "`balancer.default_group` is a group that no server is tagged with",
+                        Some(format!("group: {}", group)),
+                    );
+                    return Err(err);
+                }
+            }
+
+            for local_config in &self.local {
+                local_config.check_integrity()?;
+
+                if let Some(ref group) = local_config.server_group {
+                    if !self.server.iter().any(|svr| svr.has_tag(group)) {
+                        let err = Error::new(
+                            ErrorKind::Invalid,
+                            "`server_group` of local is a group that no server is tagged with",
+                            Some(format!("group: {}", group)),
+                        );
+                        return Err(err);
+                    }
+                }
+
+                if let P2pPolicy::Route(ref tag) = local_config.p2p_policy {
+                    if !self.server.iter().any(|svr| svr.has_tag(tag)) {
+                        let err = Error::new(
+                            ErrorKind::Invalid,
+                            "`p2p_policy` routes to a tag that no server has",
+                            Some(format!("tag: {}", tag)),
+                        );
+                        return Err(err);
+                    }
+                }
+            }
+
+            #[cfg(feature = "local")]
+            if let Some(ref priority) = self.priority {
+                if priority.upload_bandwidth.is_none() && priority.download_bandwidth.is_none() {
+                    let err = Error::new(
+                        ErrorKind::MissingField,
+                        "`priority` requires `upload_bandwidth` or `download_bandwidth`",
+                        None,
+                    );
+                    return Err(err);
+                }
+                if priority.upload_bandwidth == Some(0) || priority.download_bandwidth == Some(0) {
+                    let err = Error::new(ErrorKind::Invalid, "`priority` bandwidth must be > 0", None);
+                    return Err(err);
+                }
+                if priority.default_weight == 0 || priority.classes.iter().any(|c| c.weight == 0) {
+                    let err = Error::new(ErrorKind::Invalid, "`priority` weight must be > 0", None);
+                    return Err(err);
+                }
+            }
+
+            #[cfg(feature = "local")]
+            if let Some(ref cache) = self.resolver_cache {
+                if cache.negative_max_ttl < cache.negative_min_ttl {
+                    let err = Error::new(
+                        ErrorKind::Invalid,
+                        "`resolver_cache.negative_max_ttl` must be >= `negative_min_ttl`",
+                        None,
+                    );
+                    return Err(err);
+                }
+                if cache.size == 0 {
+                    let err = Error::new(ErrorKind::Invalid, "`resolver_cache.size` must be > 0", None);
+                    return Err(err);
+                }
+            }
+
+            #[cfg(feature = "local-dns")]
+            for local_config in self.local.iter().filter(|l| l.protocol == ProtocolType::Dns) {
+                if let Some(acl) = local_config.acl.as_ref().or(self.acl.as_ref()) {
+                    if let Some(group) = acl
+                        .dns_upstream_groups()
+                        .find(|group| !local_config.dns_upstreams.contains_key(*group))
+                    {
+                        let err = Error::new(
+                            ErrorKind::Invalid,
+                            "ACL `[dns:GROUP]` section refers to a group that is not in `dns_upstreams`",
+                            Some(format!("group: {}", group)),
+                        );
+                        return Err(err);
+                    }
+                }
+            }
+
+            #[cfg(feature = "local-dns")]
+            if self.local.iter().filter(|l| l.fake_ip.is_some()).count() > 1 {
+                let err = Error::new(
+                    ErrorKind::Invalid,
+                    "`fake_ip` could only be enabled in one dns local",
+                    None,
+                );
+                return Err(err);
+            }
+
+            // Forwarded traffic is redirected by the rules of one local
+            #[cfg(feature = "local-redir")]
+            if self.local.iter().filter(|l| l.redir_firewall.is_some()).count() > 1 {
+                let err = Error::new(
+                    ErrorKind::Invalid,
+                    "`redir_firewall` could only be enabled in one redir local",
+                    None,
+                );
+                return Err(err);
+            }
+
+            // Rules are removed by the exiting process, after they are installed by the new process
+            #[cfg(all(unix, feature = "local-redir"))]
+            if self.handoff.is_some() && self.local.iter().any(|l| l.redir_firewall.is_some()) {
+                let err = Error::new(
+                    ErrorKind::Invalid,
+                    "`redir_firewall` couldn't be used with `handoff`",
+                    None,
+                );
+                return Err(err);
+            }
+
+            #[cfg(feature = "local-online-config")]
+            let has_servers = !self.server.is_empty() || self.online_config.is_some();
+            #[cfg(not(feature = "local-online-config"))]
+            let has_servers = !self.server.is_empty();
+            if !has_servers {
+                let err = Error::new(
+                    ErrorKind::MissingField,
+                    "missing `servers` for client configuration",
+                    None,
+                );
+                return Err(err);
+            }
+
+            // Balancer related checks
+            if let Some(rtt) = self.balancer.max_server_rtt {
+                if rtt.as_secs() == 0 {
+                    let err = Error::new(ErrorKind::Invalid, "balancer.max_server_rtt must be > 0", None);
+                    return Err(err);
+                }
+            }
+
+            if let Some(intv) = self.balancer.check_interval {
+                if intv.as_secs() == 0 {
+                    let err = Error::new(ErrorKind::Invalid, "balancer.check_interval must be > 0", None);
+                    return Err(err);
+                }
+            }
+        }
+
+        if self.config_type.is_server() && self.server.is_empty() {
+            let err = Error::new(
+                ErrorKind::MissingField,
+                "missing any valid servers in configuration",
+                None,
+            );
+            return Err(err);
+        }
+
+        #[cfg(feature = "server")]
+        if let Some(ref cluster) = self.security.replay_attack.cluster {
+            if !self.config_type.is_server() {
+                let err = Error::new(ErrorKind::Invalid, "`replay_attack.cluster` is only for server", None);
+                return Err(err);
+            }
+            if self.security.replay_attack.policy == ReplayAttackPolicy::Ignore {
+                let err = Error::new(
+                    ErrorKind::Invalid,
+                    "`replay_attack.cluster` requires `policy` \"detect\" or \"reject\"",
+                    None,
+                );
+                return Err(err);
+            }
+            if cluster.peers.is_empty() || cluster.password.is_empty() {
+                let err = Error::new(
+                    ErrorKind::MissingField,
+                    "`replay_attack.cluster` requires `peers` and `password`",
+                    None,
+                );
+                return Err(err);
+            }
+            if cfg!(not(feature = "security-replay-attack-detect")) {
+                let err = Error::new(
+                    ErrorKind::Invalid,
+                    "`replay_attack.cluster` requires feature \"security-replay-attack-detect\"",
+                    None,
+                );
+                return Err(err);
+            }
+        }
+
+        #[cfg(feature = "server")]
+        if self.security.replay_attack.persist.is_some() {
+            if !self.config_type.is_server() && !self.config_type.is_manager() {
+                let err = Error::new(
+                    ErrorKind::Invalid,
+                    "`replay_attack.persist` is only for server and manager",
+                    None,
+                );
+                return Err(err);
+            }
+            if self.security.replay_attack.policy == ReplayAttackPolicy::Ignore {
+                let err = Error::new(
+                    ErrorKind::Invalid,
+                    "`replay_attack.persist` requires `policy` \"detect\" or \"reject\"",
+                    None,
+                );
+                return Err(err);
+            }
+            if cfg!(not(feature = "security-replay-attack-detect")) {
+                let err = Error::new(
+                    ErrorKind::Invalid,
+                    "`replay_attack.persist` requires feature \"security-replay-attack-detect\"",
+                    None,
+                );
+                return Err(err);
+            }
+        }
+
+        let clock_skew = &self.security.clock_skew;
+        let has_clock_skew = clock_skew.tolerance.is_some() || clock_skew.ntp_server.is_some();
+        if has_clock_skew && cfg!(not(feature = "aead-cipher-2022")) {
+            let err = Error::new(
+                ErrorKind::Invalid,
+                "`clock_skew` requires feature \"aead-cipher-2022\"",
+                None,
+            );
+            return Err(err);
+        }
+
+        #[cfg(feature = "server")]
+        if self.outbound_firewall.is_some() && !self.config_type.is_server() && !self.config_type.is_manager() {
+            let err = Error::new(
+                ErrorKind::Invalid,
+                "`outbound_firewall` is only for server and manager",
+                None,
+            );
+            return Err(err);
+        }
 
-            if self.server.is_empty() {
+        #[cfg(feature = "server")]
+        if self.client_limit.is_some() && !self.config_type.is_server() && !self.config_type.is_manager() {
+            let err = Error::new(
+                ErrorKind::Invalid,
+                "`client_limit` is only for server and manager",
+                None,
+            );
+            return Err(err);
+        }
+
+        #[cfg(feature = "server")]
+        if self.udp_eviction.is_some() && !self.config_type.is_server() && !self.config_type.is_manager() {
+            let err = Error::new(
+                ErrorKind::Invalid,
+                "`udp_eviction` is only for server and manager",
+                None,
+            );
+            return Err(err);
+        }
+
+        #[cfg(feature = "server")]
+        if let Some(drain_timeout) = self.drain_timeout {
+            if !self.config_type.is_server() && !self.config_type.is_manager() {
                 let err = Error::new(
-                    ErrorKind::MissingField,
-                    "missing `servers` for client configuration",
+                    ErrorKind::Invalid,
+                    "`drain_timeout` is only for server and manager",
                     None,
                 );
                 return Err(err);
             }
-
-            // Balancer related checks
-            if let Some(rtt) = self.balancer.max_server_rtt {
-                if rtt.as_secs() == 0 {
-                    let err = Error::new(ErrorKind::Invalid, "balancer.max_server_rtt must be > 0", None);
-                    return Err(err);
-                }
-            }
-
-            if let Some(intv) = self.balancer.check_interval {
-                if intv.as_secs() == 0 {
-                    let err = Error::new(ErrorKind::Invalid, "balancer.check_interval must be > 0", None);
-                    return Err(err);
-                }
+            if drain_timeout.is_zero() {
+                let err = Error::new(ErrorKind::Invalid, "`drain_timeout` must be > 0", None);
+                return Err(err);
             }
         }
 
-        if self.config_type.is_server() && self.server.is_empty() {
+        if !self.config_type.is_local() && matches!(self.p2p_policy, P2pPolicy::Route(..)) {
             let err = Error::new(
-                ErrorKind::MissingField,
-                "missing any valid servers in configuration",
+                ErrorKind::Invalid,
+                "`p2p_policy` of server could only be \"allow\" or \"block\"",
                 None,
             );
             return Err(err);
         }
 
+        #[cfg(unix)]
+        if self.handoff.is_some() && self.config_type.is_manager() {
+            let err = Error::new(ErrorKind::Invalid, "`handoff` is not supported by manager", None);
+            return Err(err);
+        }
+
         if self.config_type.is_manager() && self.manager.is_none() {
             let err = Error::new(
                 ErrorKind::MissingField,
@@ -1981,6 +7102,172 @@ impl Config {
                 }
             }
 
+            // Plugins listen on one port, clients of them connect to one port
+            if server.port_hopping().is_some() && server.plugin().is_some() {
+                let err = Error::new(
+                    ErrorKind::Invalid,
+                    "`port_hopping` couldn't be used with `plugin`",
+                    None,
+                );
+                return Err(err);
+            }
+
+            // Plugins listen on one address
+            if !server.listen_addrs().is_empty() && server.plugin().is_some() {
+                let err = Error::new(
+                    ErrorKind::Invalid,
+                    "`listen_addresses` couldn't be used with `plugin`",
+                    None,
+                );
+                return Err(err);
+            }
+
+            if server.listeners() == 0 {
+                let err = Error::new(ErrorKind::Malformed, "`listeners` must be greater than 0", None);
+                return Err(err);
+            }
+            if server.listeners() > 1 && cfg!(not(any(target_os = "linux", target_os = "android"))) {
+                let err = Error::new(
+                    ErrorKind::Invalid,
+                    "multiple `listeners` requires SO_REUSEPORT load balancing, which is only supported on Linux",
+                    None,
+                );
+                return Err(err);
+            }
+
+            // Plugins and transports make connections to servers by themselves
+            if server.upstream_proxy().is_some() && server.plugin().is_some() {
+                let err = Error::new(
+                    ErrorKind::Invalid,
+                    "`upstream_proxy` couldn't be used with `plugin`",
+                    None,
+                );
+                return Err(err);
+            }
+
+            #[cfg(feature = "transport-websocket")]
+            if server.upstream_proxy().is_some() && server.websocket().is_some() {
+                let err = Error::new(
+                    ErrorKind::Invalid,
+                    "`upstream_proxy` couldn't be used with `websocket`",
+                    None,
+                );
+                return Err(err);
+            }
+
+            #[cfg(feature = "transport-obfs")]
+            if server.upstream_proxy().is_some() && server.obfs().is_some() {
+                let err = Error::new(
+                    ErrorKind::Invalid,
+                    "`upstream_proxy` couldn't be used with `obfs`",
+                    None,
+                );
+                return Err(err);
+            }
+
+            #[cfg(feature = "transport-shadow-tls")]
+            if server.upstream_proxy().is_some() && server.shadow_tls().is_some() {
+                let err = Error::new(
+                    ErrorKind::Invalid,
+                    "`upstream_proxy` couldn't be used with `shadow_tls`",
+                    None,
+                );
+                return Err(err);
+            }
+
+            #[cfg(feature = "transport-quic")]
+            if server.upstream_proxy().is_some() && server.quic().is_some() {
+                let err = Error::new(
+                    ErrorKind::Invalid,
+                    "`upstream_proxy` couldn't be used with `quic`",
+                    None,
+                );
+                return Err(err);
+            }
+
+            #[cfg(feature = "transport-grpc")]
+            if server.upstream_proxy().is_some() && server.grpc().is_some() {
+                let err = Error::new(
+                    ErrorKind::Invalid,
+                    "`upstream_proxy` couldn't be used with `grpc`",
+                    None,
+                );
+                return Err(err);
+            }
+
+            #[cfg(feature = "transport-quic")]
+            if let Some(quic) = server.quic() {
+                if server.plugin().is_some() {
+                    let err = Error::new(ErrorKind::Invalid, "`quic` couldn't be used with `plugin`", None);
+                    return Err(err);
+                }
+                #[cfg(feature = "transport-shadow-tls")]
+                if server.shadow_tls().is_some() {
+                    let err = Error::new(ErrorKind::Invalid, "`quic` couldn't be used with `shadow_tls`", None);
+                    return Err(err);
+                }
+                if self.config_type.is_server() && (quic.certificate().is_none() || quic.private_key().is_none()) {
+                    let err = Error::new(
+                        ErrorKind::MissingField,
+                        "missing `certificate` or `private_key` of `quic` for server configuration",
+                        None,
+                    );
+                    return Err(err);
+                }
+                if self.config_type.is_local()
+                    && quic.server_name().is_none()
+                    && matches!(server.addr(), ServerAddr::SocketAddr(..))
+                {
+                    let err = Error::new(
+                        ErrorKind::MissingField,
+                        "missing `server_name` of `quic` for server with IP address",
+                        None,
+                    );
+                    return Err(err);
+                }
+            }
+
+            #[cfg(feature = "transport-grpc")]
+            if let Some(grpc) = server.grpc() {
+                if server.plugin().is_some() {
+                    let err = Error::new(ErrorKind::Invalid, "`grpc` couldn't be used with `plugin`", None);
+                    return Err(err);
+                }
+                #[cfg(feature = "transport-shadow-tls")]
+                if server.shadow_tls().is_some() {
+                    let err = Error::new(ErrorKind::Invalid, "`grpc` couldn't be used with `shadow_tls`", None);
+                    return Err(err);
+                }
+                #[cfg(feature = "transport-quic")]
+                if server.quic().is_some() {
+                    let err = Error::new(ErrorKind::Invalid, "`grpc` couldn't be used with `quic`", None);
+                    return Err(err);
+                }
+                if self.config_type.is_server()
+                    && grpc.tls()
+                    && (grpc.certificate().is_none() || grpc.private_key().is_none())
+                {
+                    let err = Error::new(
+                        ErrorKind::MissingField,
+                        "missing `certificate` or `private_key` of `grpc` for server configuration with tls",
+                        None,
+                    );
+                    return Err(err);
+                }
+                if self.config_type.is_local()
+                    && grpc.tls()
+                    && grpc.server_name().is_none()
+                    && matches!(server.addr(), ServerAddr::SocketAddr(..))
+                {
+                    let err = Error::new(
+                        ErrorKind::MissingField,
+                        "missing `server_name` of `grpc` for server with IP address",
+                        None,
+                    );
+                    return Err(err);
+                }
+            }
+
             // Server's domain name shouldn't be an empty string
             match server.addr() {
                 ServerAddr::SocketAddr(sa) => {
@@ -2015,6 +7302,39 @@ impl Config {
             }
         }
 
+        #[cfg(feature = "io-uring")]
+        if self.io_uring && cfg!(not(target_os = "linux")) {
+            let err = Error::new(ErrorKind::Invalid, "`io_uring` is only supported on Linux", None);
+            return Err(err);
+        }
+
+        if self.tcp_splice && cfg!(not(any(target_os = "linux", target_os = "android"))) {
+            let err = Error::new(
+                ErrorKind::Invalid,
+                "`tcp_splice` is only supported on Linux and Android",
+                None,
+            );
+            return Err(err);
+        }
+
+        if self.udp_offload && cfg!(not(any(target_os = "linux", target_os = "android"))) {
+            let err = Error::new(
+                ErrorKind::Invalid,
+                "`udp_offload` is only supported on Linux and Android",
+                None,
+            );
+            return Err(err);
+        }
+
+        if self.buffer_pool.buffer_size == 0 {
+            let err = Error::new(
+                ErrorKind::Malformed,
+                "`buffer_pool.buffer_size` must be greater than 0",
+                None,
+            );
+            return Err(err);
+        }
+
         Ok(())
     }
 }
@@ -2045,11 +7365,19 @@ impl fmt::Display for Config {
             } else {
                 let mut jlocals = Vec::with_capacity(self.local.len());
                 for local in &self.local {
-                    let jlocal = SSLocalExtConfig {
-                        local_address: local.addr.as_ref().map(|a| match a {
-                            ServerAddr::SocketAddr(ref sa) => sa.ip().to_string(),
-                            ServerAddr::DomainName(ref dm, ..) => dm.to_string(),
-                        }),
+                    #[allow(unused_mut)]
+                    let mut local_address = local.addr.as_ref().map(|a| match a {
+                        ServerAddr::SocketAddr(ref sa) => sa.ip().to_string(),
+                        ServerAddr::DomainName(ref dm, ..) => dm.to_string(),
+                    });
+                    #[cfg(unix)]
+                    if let Some(ref unix_addr) = local.unix_addr {
+                        local_address = Some(unix_addr.display().to_string());
+                    }
+
+                    #[allow(unused_mut)]
+                    let mut jlocal = SSLocalExtConfig {
+                        local_address,
                         local_port: local.addr.as_ref().map(|a| match a {
                             ServerAddr::SocketAddr(ref sa) => sa.port(),
                             ServerAddr::DomainName(.., port) => *port,
@@ -2069,6 +7397,38 @@ impl fmt::Display for Config {
                             #[allow(unreachable_patterns)]
                             p => Some(p.as_str().to_owned()),
                         },
+                        tenant: local.tenant.clone(),
+                        #[cfg(feature = "acl")]
+                        acl: local.acl.as_ref().map(|acl| acl.file_path().display().to_string()),
+                        #[cfg(not(feature = "acl"))]
+                        acl: None,
+                        server_group: local.server_group.clone(),
+                        dns: local.dns.as_ref().map(SSDnsConfig::from_config),
+                        ipv6_first: local.ipv6_first,
+                        p2p_policy: if local.p2p_policy != self.p2p_policy {
+                            Some(local.p2p_policy.to_string())
+                        } else {
+                            None
+                        },
+                        bind_retries: if local.bind_retries > 0 {
+                            Some(local.bind_retries)
+                        } else {
+                            None
+                        },
+                        bind_fallback_ports: if !local.bind_fallback_ports.is_empty() {
+                            Some(local.bind_fallback_ports.clone())
+                        } else {
+                            None
+                        },
+                        outbound_bind_interface: local.outbound_bind_interface.clone(),
+                        outbound_bind_addr: local.outbound_bind_addr.map(|a| a.to_string()),
+                        tuning: SSTuningConfig::from_config(&local.tcp_tuning, local.relay_buffer_sizes),
+                        udp_timeout: local.udp_timeout.map(|t| t.as_secs()),
+                        udp_max_associations: local.udp_max_associations,
+                        #[cfg(feature = "local")]
+                        udp_nat: local.udp_nat.as_ref().map(SSUdpNatConfig::from_config),
+                        #[cfg(unix)]
+                        unix_socket_mode: local.unix_mode.map(|m| format!("{:o}", m)),
                         #[cfg(feature = "local-redir")]
                         tcp_redir: if local.tcp_redir != RedirType::tcp_default() {
                             Some(local.tcp_redir.to_string())
@@ -2081,6 +7441,18 @@ impl fmt::Display for Config {
                         } else {
                             None
                         },
+                        #[cfg(feature = "local-redir")]
+                        redir_listeners: if local.redir_listeners != 1 {
+                            Some(local.redir_listeners)
+                        } else {
+                            None
+                        },
+                        #[cfg(feature = "local-redir-ebpf")]
+                        redir_ebpf: if local.redir_ebpf { Some(true) } else { None },
+                        #[cfg(feature = "local-redir")]
+                        redir_firewall: local.redir_firewall.map(|f| f.to_string()),
+                        #[cfg(any(feature = "local-redir", feature = "local-tun"))]
+                        preserve_ttl: if local.preserve_ttl { Some(true) } else { None },
                         #[cfg(feature = "local-tunnel")]
                         forward_address: match local.forward_addr {
                             None => None,
@@ -2097,6 +7469,36 @@ impl fmt::Display for Config {
                                 Address::DomainNameAddress(.., port) => Some(*port),
                             },
                         },
+                        #[cfg(feature = "local-tunnel")]
+                        tunnel_mappings: if local.tunnel_mappings.is_empty() {
+                            None
+                        } else {
+                            Some(
+                                local
+                                    .tunnel_mappings
+                                    .iter()
+                                    .map(|mapping| SSLocalTunnelMappingConfig {
+                                        local_address: Some(match mapping.local_addr {
+                                            ServerAddr::SocketAddr(ref sa) => sa.ip().to_string(),
+                                            ServerAddr::DomainName(ref dm, ..) => dm.to_string(),
+                                        }),
+                                        local_port: match mapping.local_addr {
+                                            ServerAddr::SocketAddr(ref sa) => sa.port(),
+                                            ServerAddr::DomainName(.., port) => port,
+                                        },
+                                        forward_address: match mapping.forward_addr {
+                                            Address::SocketAddress(ref sa) => sa.ip().to_string(),
+                                            Address::DomainNameAddress(ref dm, ..) => dm.to_string(),
+                                        },
+                                        forward_port: match mapping.forward_addr {
+                                            Address::SocketAddress(ref sa) => sa.port(),
+                                            Address::DomainNameAddress(.., port) => port,
+                                        },
+                                        mode: Some(mapping.mode.to_string()),
+                                    })
+                                    .collect(),
+                            )
+                        },
                         #[cfg(feature = "local-dns")]
                         local_dns_address: match local.local_dns_addr {
                             None => None,
@@ -2106,6 +7508,8 @@ impl fmt::Display for Config {
                                 NameServerAddr::UnixSocketAddr(ref path) => {
                                     Some(path.to_str().expect("path is not utf-8").to_owned())
                                 }
+                                #[cfg(any(feature = "local-dns-over-tls", feature = "local-dns-over-quic"))]
+                                NameServerAddr::Secure(ref addr) => Some(addr.to_string()),
                             },
                         },
                         #[cfg(feature = "local-dns")]
@@ -2115,6 +7519,8 @@ impl fmt::Display for Config {
                                 NameServerAddr::SocketAddr(ref sa) => Some(sa.port()),
                                 #[cfg(unix)]
                                 NameServerAddr::UnixSocketAddr(..) => None,
+                                #[cfg(any(feature = "local-dns-over-tls", feature = "local-dns-over-quic"))]
+                                NameServerAddr::Secure(..) => None,
                             },
                         },
                         #[cfg(feature = "local-dns")]
@@ -2133,14 +7539,170 @@ impl fmt::Display for Config {
                                 Address::DomainNameAddress(.., port) => Some(*port),
                             },
                         },
+                        #[cfg(any(feature = "local-dns-over-tls", feature = "local-dns-over-quic"))]
+                        local_dns_bootstrap: match local.local_dns_addr {
+                            Some(NameServerAddr::Secure(ref addr)) => match addr.bootstrap() {
+                                Some(ip) if ip.to_string() != addr.host() => Some(ip.to_string()),
+                                _ => None,
+                            },
+                            _ => None,
+                        },
+                        #[cfg(feature = "local-dns")]
+                        local_dns_timeout: local.local_dns_timeout.map(|d| d.as_secs()),
+                        #[cfg(feature = "local-dns")]
+                        local_dns_extra_addresses: if local.local_dns_extra_addrs.is_empty() {
+                            None
+                        } else {
+                            Some(local.local_dns_extra_addrs.iter().map(ToString::to_string).collect())
+                        },
+                        #[cfg(feature = "local-dns")]
+                        local_dns_strategy: if local.local_dns_strategy == NameServerStrategy::default() {
+                            None
+                        } else {
+                            Some(local.local_dns_strategy.to_string())
+                        },
+                        #[cfg(any(
+                            feature = "local-dns-over-https",
+                            feature = "local-dns-over-tls",
+                            feature = "local-dns-over-quic"
+                        ))]
+                        remote_dns_bootstrap: None,
+                        #[cfg(feature = "local-dns")]
+                        remote_dns_timeout: local.remote_dns_timeout.map(|d| d.as_secs()),
+                        #[cfg(feature = "local-dns")]
+                        dns_cache: local.dns_cache.as_ref().map(|c| SSLocalDnsCacheConfig {
+                            size: c.size,
+                            min_ttl: c.min_ttl.as_ref().map(Duration::as_secs),
+                            prefetch: if c.prefetch { Some(true) } else { None },
+                        }),
+                        #[cfg(feature = "local-dns")]
+                        fake_ip: local.fake_ip.as_ref().map(|c| SSLocalFakeIpConfig {
+                            ipv4_range: c.ipv4_range.to_string(),
+                            ipv6_range: c.ipv6_range.as_ref().map(ToString::to_string),
+                            ttl: Some(c.ttl.as_secs()),
+                        }),
+                        #[cfg(feature = "local-dns")]
+                        dns_upstreams: if local.dns_upstreams.is_empty() {
+                            None
+                        } else {
+                            Some(
+                                local
+                                    .dns_upstreams
+                                    .iter()
+                                    .map(|(group, upstream)| {
+                                        (group.clone(), upstream.iter().map(ToString::to_string).collect())
+                                    })
+                                    .collect(),
+                            )
+                        },
+                        #[cfg(feature = "local-dns-tls")]
+                        dns_over_tls_address: local.dns_over_tls_addr.as_ref().map(ToString::to_string),
+                        #[cfg(feature = "local-dns-tls")]
+                        dns_over_https_address: local.dns_over_https_addr.as_ref().map(ToString::to_string),
+                        #[cfg(feature = "local-dns")]
+                        dns_hosts: local.dns_hosts.as_ref().map(|c| SSLocalDnsHostsConfig {
+                            records: if c.records.is_empty() {
+                                None
+                            } else {
+                                Some(
+                                    c.records
+                                        .iter()
+                                        .map(|r| SSLocalStaticRecordConfig {
+                                            name: r.name.clone(),
+                                            rtype: r.data.record_type().to_owned(),
+                                            value: r.data.value(),
+                                        })
+                                        .collect(),
+                                )
+                            },
+                            hosts_file: c.hosts_file.as_ref().map(|p| p.display().to_string()),
+                            ttl: c.ttl.as_ref().map(Duration::as_secs),
+                        }),
+                        #[cfg(feature = "local-dns-dnssec")]
+                        dnssec: local.dnssec.as_ref().map(|c| SSLocalDnssecConfig {
+                            ad_bit: Some(c.ad_policy.to_string()),
+                            builtin_trust_anchors: Some(c.builtin_trust_anchors),
+                            trust_anchors: if c.trust_anchors.is_empty() {
+                                None
+                            } else {
+                                Some(c.trust_anchors.clone())
+                            },
+                            trust_anchors_file: c.trust_anchors_file.as_ref().map(|p| p.display().to_string()),
+                        }),
                         #[cfg(feature = "local-tun")]
                         tun_interface_name: local.tun_interface_name.clone(),
                         #[cfg(feature = "local-tun")]
-                        tun_interface_address: local.tun_interface_address.as_ref().map(ToString::to_string),
+                        tun_interface_address: if local.tun_interface_address_auto {
+                            Some("auto".to_owned())
+                        } else {
+                            local.tun_interface_address.as_ref().map(ToString::to_string)
+                        },
+                        #[cfg(feature = "local-tun")]
+                        tun_tcp: if local.tun_tcp == TunTcpConfig::default() {
+                            None
+                        } else {
+                            Some(SSLocalTunTcpConfig {
+                                profile: Some(local.tun_tcp.profile.to_string()),
+                                send_buffer_size: local.tun_tcp.send_buffer_size,
+                                recv_buffer_size: local.tun_tcp.recv_buffer_size,
+                                ack_delay: local.tun_tcp.ack_delay.map(|d| d.as_millis() as u64),
+                                nagle: local.tun_tcp.nagle,
+                                timeout: local.tun_tcp.timeout.map(|d| d.as_secs()),
+                            })
+                        },
+
+                        #[cfg(feature = "local-pac")]
+                        pac_template_path: local.pac_template_path.as_ref().map(|p| p.display().to_string()),
+
+                        #[cfg(feature = "local")]
+                        socks5_auth_config_path: None,
+                        #[cfg(feature = "local")]
+                        socks_hostname: if local.socks_hostname != SocksHostnamePolicy::default() {
+                            Some(local.socks_hostname.to_string())
+                        } else {
+                            None
+                        },
+
+                        #[cfg(feature = "local-http")]
+                        http_auth_config_path: None,
+                        #[cfg(feature = "local-http")]
+                        http_cache: local.http_cache.as_ref().map(|c| SSLocalHttpCacheConfig {
+                            size: c.capacity,
+                            max_object_size: Some(c.max_object_size),
+                        }),
+
+                        #[cfg(any(
+                            feature = "local-socks-tls",
+                            feature = "local-http-tls",
+                            feature = "local-dns-tls"
+                        ))]
+                        tls: local.tls.as_ref().map(|tls| SSLocalTlsConfig {
+                            certificate: tls.certificate_path.display().to_string(),
+                            private_key: tls.private_key_path.display().to_string(),
+                            client_ca: tls.client_ca_path.as_ref().map(|p| p.display().to_string()),
+                        }),
+                    };
+
+                    #[cfg(feature = "local-dns-over-https")]
+                    if let Some(ref remote_dns_https) = local.remote_dns_https {
+                        jlocal.remote_dns_address = Some(remote_dns_https.to_string());
+                        jlocal.remote_dns_port = None;
+                        jlocal.remote_dns_bootstrap = match remote_dns_https.bootstrap() {
+                            Some(ip) if ip.to_string() != remote_dns_https.host() => Some(ip.to_string()),
+                            _ => None,
+                        };
+                    }
+
+                    #[cfg(any(feature = "local-dns-over-tls", feature = "local-dns-over-quic"))]
+                    if let Some(ref remote_dns_secure) = local.remote_dns_secure {
+                        jlocal.remote_dns_address = Some(remote_dns_secure.to_string());
+                        jlocal.remote_dns_port = None;
+                        jlocal.remote_dns_bootstrap = match remote_dns_secure.bootstrap() {
+                            Some(ip) if ip.to_string() != remote_dns_secure.host() => Some(ip.to_string()),
+                            _ => None,
+                        };
+                    }
 
-                        #[cfg(feature = "local")]
-                        socks5_auth_config_path: None,
-                    };
                     jlocals.push(jlocal);
                 }
                 jconf.locals = Some(jlocals);
@@ -2173,8 +7735,34 @@ impl fmt::Display for Config {
                         Some(p.plugin_args.clone())
                     }
                 });
+                jconf.plugin_mode = svr.plugin().and_then(plugin_mode_to_string);
                 jconf.timeout = svr.timeout().map(|t| t.as_secs());
                 jconf.mode = Some(svr.mode().to_string());
+                if svr.udp_over_tcp() {
+                    jconf.udp_over_tcp = Some(true);
+                }
+                jconf.mux = svr.mux().map(SSMuxConfig::from_config);
+                #[cfg(feature = "stream-padding")]
+                {
+                    jconf.padding = svr.padding().map(SSPaddingConfig::from_config);
+                }
+                jconf.port_hopping = svr.port_hopping().map(SSPortHoppingConfig::from_config);
+                jconf.upstream_proxy = svr.upstream_proxy().map(ToString::to_string);
+                jconf.listen_addresses = listen_addresses_to_strings(svr);
+                jconf.listeners = if svr.listeners() != 1 {
+                    Some(svr.listeners())
+                } else {
+                    None
+                };
+                jconf.rate_limit = svr.rate_limit().map(SSRateLimitConfig::from_config);
+                jconf.fallback = svr.fallback().map(ToString::to_string);
+                jconf.outbound_bind_interface = svr.outbound_bind_interface().map(ToOwned::to_owned);
+                jconf.outbound_bind_addr = svr.outbound_bind_addr().map(|a| a.to_string());
+                jconf.tuning = SSTuningConfig::from_config(svr.tcp_tuning(), svr.relay_buffer_sizes());
+                #[cfg(feature = "aead-cipher-2022")]
+                {
+                    jconf.users = svr.user_manager().map(|m| SSServerUserConfig::from_user_manager(m));
+                }
             }
             // For >1 servers, uses extended multiple server format
             _ => {
@@ -2190,7 +7778,8 @@ impl fmt::Display for Config {
                             ServerAddr::SocketAddr(ref sa) => sa.port(),
                             ServerAddr::DomainName(.., port) => port,
                         },
-                        password: svr.password().to_string(),
+                        password: Some(svr.password().to_string()),
+                        password_file: None,
                         method: svr.method().to_string(),
                         disabled: None,
                         plugin: svr.plugin().map(|p| p.plugin.to_string()),
@@ -2202,6 +7791,7 @@ impl fmt::Display for Config {
                                 Some(p.plugin_args.clone())
                             }
                         }),
+                        plugin_mode: svr.plugin().and_then(plugin_mode_to_string),
                         timeout: svr.timeout().map(|t| t.as_secs()),
                         remarks: svr.remarks().map(ToOwned::to_owned),
                         id: svr.id().map(ToOwned::to_owned),
@@ -2216,6 +7806,56 @@ impl fmt::Display for Config {
                         } else {
                             None
                         },
+                        udp_over_tcp: if svr.udp_over_tcp() { Some(true) } else { None },
+                        country: svr.country().map(ToOwned::to_owned),
+                        city: svr.city().map(ToOwned::to_owned),
+                        tags: if svr.tags().is_empty() {
+                            None
+                        } else {
+                            Some(svr.tags().to_vec())
+                        },
+                        tcp_pacing_rate: svr.tcp_pacing_rate(),
+                        mptcp: if svr.mptcp() { Some(true) } else { None },
+                        mux: svr.mux().map(SSMuxConfig::from_config),
+                        #[cfg(feature = "stream-padding")]
+                        padding: svr.padding().map(SSPaddingConfig::from_config),
+                        port_hopping: svr.port_hopping().map(SSPortHoppingConfig::from_config),
+                        upstream_proxy: svr.upstream_proxy().map(ToString::to_string),
+                        listen_addresses: listen_addresses_to_strings(svr),
+                        listeners: if svr.listeners() != 1 {
+                            Some(svr.listeners())
+                        } else {
+                            None
+                        },
+                        quota: svr.quota(),
+                        rate_limit: svr.rate_limit().map(SSRateLimitConfig::from_config),
+                        fallback: svr.fallback().map(ToString::to_string),
+                        outbound_bind_interface: svr.outbound_bind_interface().map(ToOwned::to_owned),
+                        outbound_bind_addr: svr.outbound_bind_addr().map(|a| a.to_string()),
+                        tuning: SSTuningConfig::from_config(svr.tcp_tuning(), svr.relay_buffer_sizes()),
+                        #[cfg(feature = "aead-cipher-2022")]
+                        users: svr.user_manager().map(|m| SSServerUserConfig::from_user_manager(m)),
+                        #[cfg(feature = "transport-shadow-tls")]
+                        shadow_tls: svr.shadow_tls().map(|st| SSShadowTlsConfig {
+                            password: st.password().to_owned(),
+                            sni: st.sni().to_owned(),
+                            handshake_server: st.handshake_server().map(ToString::to_string),
+                        }),
+                        #[cfg(feature = "transport-quic")]
+                        quic: svr.quic().map(|q| SSQuicConfig {
+                            server_name: q.server_name().map(ToOwned::to_owned),
+                            certificate: q.certificate().map(|p| p.display().to_string()),
+                            private_key: q.private_key().map(|p| p.display().to_string()),
+                        }),
+                        #[cfg(feature = "transport-grpc")]
+                        grpc: svr.grpc().map(|g| SSGrpcConfig {
+                            service_name: Some(g.service_name().to_owned()),
+                            server_name: g.server_name().map(ToOwned::to_owned),
+                            tls: if g.tls() { None } else { Some(false) },
+                            certificate: g.certificate().map(|p| p.display().to_string()),
+                            private_key: g.private_key().map(|p| p.display().to_string()),
+                            max_streams: Some(g.max_streams()),
+                        }),
                     });
                 }
 
@@ -2238,6 +7878,23 @@ impl fmt::Display for Config {
                 ManagerAddr::UnixSocketAddr(..) => None,
             };
 
+            jconf.manager_key = m.key.clone();
+
+            #[cfg(feature = "manager-http-api")]
+            if let Some(ref http_api) = m.http_api {
+                jconf.manager_http_api = Some(SSManagerHttpApiConfig::from_config(http_api));
+            }
+
+            #[cfg(feature = "manager-sip008")]
+            if let Some(ref sip008) = m.sip008 {
+                jconf.manager_sip008 = Some(SSManagerSip008Config::from_config(sip008));
+            }
+
+            #[cfg(feature = "manager-web-ui")]
+            if let Some(ref web_ui) = m.web_ui {
+                jconf.manager_web_ui = Some(SSManagerWebUiConfig::from_config(web_ui));
+            }
+
             if jconf.mode.is_none() {
                 jconf.mode = Some(m.mode.to_string());
             }
@@ -2257,6 +7914,7 @@ impl fmt::Display for Config {
                     if !p.plugin_args.is_empty() {
                         jconf.plugin_args = Some(p.plugin_args.clone());
                     }
+                    jconf.plugin_mode = plugin_mode_to_string(p);
                 }
             }
         }
@@ -2285,42 +7943,481 @@ impl fmt::Display for Config {
             }
         }
 
+        jconf.server_dns = self.server_dns.as_ref().map(SSDnsConfig::from_config);
+
         jconf.udp_timeout = self.udp_timeout.map(|t| t.as_secs());
 
         jconf.udp_max_associations = self.udp_max_associations;
+        #[cfg(feature = "local")]
+        {
+            jconf.udp_nat = self.udp_nat.as_ref().map(SSUdpNatConfig::from_config);
+        }
+        if self.udp_migration {
+            jconf.udp_migration = Some(self.udp_migration);
+        }
+        if self.direct_fallback {
+            jconf.direct_fallback = Some(self.direct_fallback);
+        }
+        #[cfg(feature = "local")]
+        if self.routing_mode != RoutingMode::default() {
+            jconf.routing_mode = Some(self.routing_mode.to_string());
+        }
+        if self.route_debug {
+            jconf.route_debug = Some(self.route_debug);
+        }
+        if self.tcp_splice {
+            jconf.tcp_splice = Some(self.tcp_splice);
+        }
+        #[cfg(feature = "acl")]
+        if self.acl_trace {
+            jconf.acl_trace = Some(self.acl_trace);
+        }
+
+        #[cfg(feature = "metrics")]
+        if let Some(ref metrics_addr) = self.metrics_addr {
+            jconf.metrics_address = Some(metrics_addr.to_string());
+        }
 
         #[cfg(all(unix, not(target_os = "android")))]
         {
             jconf.nofile = self.nofile;
         }
 
+        #[cfg(feature = "io-uring")]
+        if self.io_uring {
+            jconf.io_uring = Some(self.io_uring);
+        }
+
+        if self.buffer_pool != BufferPoolConfig::default() {
+            jconf.buffer_pool = Some(SSBufferPoolConfig {
+                buffer_size: Some(self.buffer_pool.buffer_size),
+                max_buffers: Some(self.buffer_pool.max_buffers),
+            });
+        }
+
+        if self.udp_offload {
+            jconf.udp_offload = Some(self.udp_offload);
+        }
+
         if self.ipv6_first {
             jconf.ipv6_first = Some(self.ipv6_first);
         }
 
+        if self.ip_strategy != IpStrategy::from_ipv6_first(self.ipv6_first) {
+            jconf.ip_strategy = Some(self.ip_strategy.to_string());
+        }
+
         if self.ipv6_only {
             jconf.ipv6_only = Some(self.ipv6_only);
         }
 
         // Security
-        if self.security.replay_attack.policy != ReplayAttackPolicy::default() {
+        let clock_skew = &self.security.clock_skew;
+        let has_clock_skew = clock_skew.tolerance.is_some() || clock_skew.ntp_server.is_some();
+        if self.security.replay_attack.policy != ReplayAttackPolicy::default() || has_clock_skew {
             jconf.security = Some(SSSecurityConfig {
                 replay_attack: Some(SSSecurityReplayAttackConfig {
                     policy: Some(self.security.replay_attack.policy.to_string()),
+                    #[cfg(feature = "server")]
+                    cluster: self
+                        .security
+                        .replay_attack
+                        .cluster
+                        .as_ref()
+                        .map(|cluster| SSReplayClusterConfig {
+                            listen_address: cluster.listen_addr.to_string(),
+                            peers: cluster.peers.iter().map(ToString::to_string).collect(),
+                            password: cluster.password.clone(),
+                        }),
+                    #[cfg(feature = "server")]
+                    persist: self
+                        .security
+                        .replay_attack
+                        .persist
+                        .as_ref()
+                        .map(|persist| SSReplayPersistConfig {
+                            path: persist.path.display().to_string(),
+                            interval: Some(persist.interval.as_secs()),
+                        }),
                 }),
+                clock_skew: if has_clock_skew {
+                    Some(SSSecurityClockSkewConfig {
+                        tolerance: clock_skew.tolerance.map(|d| d.as_secs()),
+                        ntp_server: clock_skew.ntp_server.as_ref().map(ToString::to_string),
+                    })
+                } else {
+                    None
+                },
             });
         }
 
         // Balancer
-        if self.balancer.max_server_rtt.is_some() || self.balancer.check_interval.is_some() {
+        if self.balancer.max_server_rtt.is_some()
+            || self.balancer.check_interval.is_some()
+            || self.balancer.check_best_interval.is_some()
+            || self.balancer.strategy != BalancerStrategy::default()
+            || self.balancer.failback_window.is_some()
+            || !self.balancer.groups.is_empty()
+            || self.balancer.default_group.is_some()
+            || self.balancer.probe != BalancerProbeConfig::default()
+            || self.balancer.scoring != BalancerScoringConfig::default()
+            || self.balancer.warm_pool.is_some()
+        {
+            let default_probe = BalancerProbeConfig::default();
+            let probe = &self.balancer.probe;
+            let scoring = &self.balancer.scoring;
+
             jconf.balancer = Some(SSBalancerConfig {
                 max_server_rtt: self.balancer.max_server_rtt.as_ref().map(Duration::as_secs),
                 check_interval: self.balancer.check_interval.as_ref().map(Duration::as_secs),
                 check_best_interval: self.balancer.check_best_interval.as_ref().map(Duration::as_secs),
+                strategy: match self.balancer.strategy {
+                    BalancerStrategy::Best => None,
+                    strategy => Some(strategy.to_string()),
+                },
+                failback_window: self.balancer.failback_window.as_ref().map(Duration::as_secs),
+                groups: if self.balancer.groups.is_empty() {
+                    None
+                } else {
+                    Some(
+                        self.balancer
+                            .groups
+                            .iter()
+                            .map(|(name, strategy)| {
+                                let group = SSBalancerGroupConfig {
+                                    strategy: match *strategy {
+                                        BalancerStrategy::Best => None,
+                                        strategy => Some(strategy.to_string()),
+                                    },
+                                };
+                                (name.clone(), group)
+                            })
+                            .collect(),
+                    )
+                },
+                default_group: self.balancer.default_group.clone(),
+                probe: if *probe == default_probe {
+                    None
+                } else {
+                    Some(SSBalancerProbeConfig {
+                        protocol: match probe.tcp {
+                            BalancerTcpProbe::Http { .. } => None,
+                            ref tcp => Some(tcp.protocol().to_owned()),
+                        },
+                        target: match probe.tcp {
+                            BalancerTcpProbe::Tcp => None,
+                            BalancerTcpProbe::Tls(ref addr) => Some(addr.to_string()),
+                            ref tcp if *tcp == default_probe.tcp => None,
+                            ref tcp => Some(tcp.to_string()),
+                        },
+                        udp_target: if probe.udp_target == default_probe.udp_target {
+                            None
+                        } else {
+                            Some(probe.udp_target.to_string())
+                        },
+                        samples: if probe.samples == default_probe.samples {
+                            None
+                        } else {
+                            Some(probe.samples)
+                        },
+                    })
+                },
+                scoring: if *scoring == BalancerScoringConfig::default() {
+                    None
+                } else {
+                    // Parameters are written only if they differ from the algorithm's
+                    let preset = BalancerScoringConfig::new(scoring.algorithm);
+                    let diff = |v: f64, p: f64| if v == p { None } else { Some(v) };
+
+                    Some(SSBalancerScoringConfig {
+                        algorithm: match scoring.algorithm {
+                            BalancerScoringAlgorithm::Median => None,
+                            algorithm => Some(algorithm.to_string()),
+                        },
+                        ewma_alpha: diff(scoring.ewma_alpha, preset.ewma_alpha),
+                        percentile: diff(scoring.percentile, preset.percentile),
+                        rtt_weight: diff(scoring.rtt_weight, preset.rtt_weight),
+                        failure_weight: diff(scoring.failure_weight, preset.failure_weight),
+                        variation_weight: diff(scoring.variation_weight, preset.variation_weight),
+                    })
+                },
+                warm_pool: self.balancer.warm_pool.as_ref().map(|w| SSBalancerWarmPoolConfig {
+                    size: Some(w.size),
+                    servers: Some(w.servers),
+                    idle_timeout: Some(w.idle_timeout.as_secs()),
+                }),
+            });
+        }
+
+        if self.isolate_locals {
+            jconf.isolate_locals = Some(self.isolate_locals);
+        }
+
+        if !self.p2p_policy.is_allow() {
+            jconf.p2p_policy = Some(self.p2p_policy.to_string());
+        }
+
+        if let Some(global) = self.global_rate_limit {
+            jconf.rate_limit.get_or_insert_with(SSRateLimitConfig::default).global = Some(global);
+        }
+
+        #[cfg(feature = "server")]
+        if let Some(ref outbound_firewall) = self.outbound_firewall {
+            jconf.outbound_firewall = Some(SSFirewallConfig::from_config(outbound_firewall));
+        }
+
+        #[cfg(feature = "server")]
+        if let Some(ref client_limit) = self.client_limit {
+            jconf.client_limit = Some(SSClientLimitConfig::from_config(client_limit));
+        }
+
+        #[cfg(feature = "server")]
+        if let Some(ref udp_eviction) = self.udp_eviction {
+            jconf.udp_eviction = Some(SSUdpEvictionConfig::from_config(udp_eviction));
+        }
+
+        #[cfg(feature = "server")]
+        if let Some(drain_timeout) = self.drain_timeout {
+            jconf.drain_timeout = Some(drain_timeout.as_secs());
+        }
+
+        jconf.state_dir = self.state_dir.as_ref().map(|p| p.display().to_string());
+        #[cfg(feature = "local")]
+        if self.export_connections {
+            jconf.export_connections = Some(self.export_connections);
+        }
+        #[cfg(feature = "local")]
+        if self.system_proxy {
+            jconf.system_proxy = Some(self.system_proxy);
+        }
+        #[cfg(feature = "local")]
+        if let Some(ref control_addr) = self.control_addr {
+            jconf.control_address = Some(control_addr.to_string());
+        }
+        #[cfg(feature = "local")]
+        if let Some(ref control_key) = self.control_key {
+            jconf.control_key = Some(control_key.clone());
+        }
+        #[cfg(feature = "local")]
+        if let Some(ref flow_export) = self.flow_export {
+            jconf.flow_export = Some(SSFlowExportConfig {
+                collector: flow_export.collector.to_string(),
+                observation_domain_id: Some(flow_export.observation_domain_id),
+                enterprise_number: Some(flow_export.enterprise_number),
+            });
+        }
+
+        #[cfg(unix)]
+        if let Some(ref handoff) = self.handoff {
+            jconf.handoff = Some(SSHandoffConfig {
+                path: handoff.path.display().to_string(),
+                drain_timeout: Some(handoff.drain_timeout.as_secs()),
+            });
+        }
+
+        #[cfg(feature = "local")]
+        if let Some(ref priority) = self.priority {
+            jconf.priority = Some(SSPriorityConfig {
+                upload_bandwidth: priority.upload_bandwidth,
+                download_bandwidth: priority.download_bandwidth,
+                classes: priority
+                    .classes
+                    .iter()
+                    .map(|c| SSPriorityClassConfig {
+                        weight: c.weight,
+                        ports: c.ports.iter().map(ToString::to_string).collect(),
+                    })
+                    .collect(),
+                default_weight: Some(priority.default_weight),
+            });
+        }
+
+        // Only written if it isn't the default
+        #[cfg(feature = "local")]
+        if self.config_type.is_local() {
+            let default_cache = ResolverCacheConfig::default();
+            jconf.resolver_cache = match self.resolver_cache {
+                Some(ref cache)
+                    if cache.negative_min_ttl == default_cache.negative_min_ttl
+                        && cache.negative_max_ttl == default_cache.negative_max_ttl
+                        && cache.size == default_cache.size =>
+                {
+                    None
+                }
+                Some(ref cache) => Some(SSResolverCacheConfig {
+                    negative_min_ttl: Some(cache.negative_min_ttl.as_secs()),
+                    negative_max_ttl: Some(cache.negative_max_ttl.as_secs()),
+                    size: Some(cache.size),
+                }),
+                None => Some(SSResolverCacheConfig {
+                    negative_min_ttl: Some(0),
+                    ..Default::default()
+                }),
+            };
+        }
+
+        #[cfg(feature = "local-fault-injection")]
+        if let Some(ref fault) = self.fault_injection {
+            jconf.fault_injection = Some(SSFaultInjectionConfig {
+                udp_drop_rate: if fault.udp_drop_rate > 0.0 {
+                    Some(fault.udp_drop_rate)
+                } else {
+                    None
+                },
+                connect_delay: fault.connect_delay.as_ref().map(|d| d.as_millis() as u64),
+                tunnel_kill_after_bytes: fault.tunnel_kill_after_bytes,
+                seed: fault.seed,
+            });
+        }
+
+        #[cfg(feature = "local-online-config")]
+        if let Some(ref online_config) = self.online_config {
+            jconf.online_config = Some(SSOnlineConfig {
+                config_url: online_config.config_url.to_string(),
+                update_interval: if online_config.update_interval != DEFAULT_UPDATE_INTERVAL {
+                    Some(online_config.update_interval.as_secs())
+                } else {
+                    None
+                },
             });
         }
 
-        write!(f, "{}", json5::to_string(&jconf).unwrap())
+        // Written configurations are loaded without substituting variables in values
+        write!(f, "{}", json5::to_string(&jconf).unwrap().replace("${", "$${"))
+    }
+}
+
+/// Parse `quota` of servers and users, in bytes
+/// Parse nameservers of DNS over TLS or HTTPS, formatted like `host[:port]#name[,host[:port]#name]...`
+///
+/// Hosts are IP addresses, so that they are connected without resolving. `name` is verified with certificates of them.
+#[cfg(any(feature = "dns-over-tls", feature = "dns-over-https"))]
+fn parse_encrypted_dns_nameservers(
+    nameservers: &str,
+    protocol: Protocol,
+    default_port: u16,
+) -> Result<DnsConfig, Error> {
+    let mut c = ResolverConfig::new();
+    for part in nameservers.split(',') {
+        let (addr, tls_dns_name) = match part.split_once('#') {
+            Some((addr, name)) if !name.is_empty() => (addr, name),
+            _ => {
+                let err = Error::new(
+                    ErrorKind::Invalid,
+                    "invalid DNS nameserver, DNS over TLS or HTTPS requires the name of certificates, like tls://1.1.1.1#cloudflare-dns.com",
+                    None,
+                );
+                return Err(err);
+            }
+        };
+
+        let socket_addr = if let Ok(socket_addr) = addr.parse::<SocketAddr>() {
+            socket_addr
+        } else if let Ok(ipaddr) = addr.parse::<IpAddr>() {
+            SocketAddr::new(ipaddr, default_port)
+        } else {
+            let err = Error::new(
+                ErrorKind::Invalid,
+                "invalid DNS nameserver, DNS over TLS or HTTPS requires an IP address",
+                Some(format!("{:?}", addr)),
+            );
+            return Err(err);
+        };
+
+        c.add_name_server(NameServerConfig {
+            socket_addr,
+            protocol,
+            tls_dns_name: Some(tls_dns_name.to_owned()),
+            trust_nx_responses: false,
+            tls_config: None,
+            bind_addr: None,
+        });
+    }
+
+    Ok(DnsConfig::TrustDns(c))
+}
+
+fn parse_quota(quota: u64) -> Result<u64, Error> {
+    if quota == 0 {
+        let err = Error::new(ErrorKind::Malformed, "`quota` must be > 0", None);
+        return Err(err);
+    }
+    Ok(quota)
+}
+
+/// Parse `fallback` of servers, "host:port"
+fn parse_fallback(fallback: &str) -> Result<Address, Error> {
+    match fallback.parse::<Address>() {
+        Ok(addr) => Ok(addr),
+        Err(..) => {
+            let err = Error::new(
+                ErrorKind::Invalid,
+                "invalid `fallback`, must be \"host:port\"",
+                Some(fallback.to_owned()),
+            );
+            Err(err)
+        }
+    }
+}
+
+/// Parse `listen_addresses` of servers, "IP:PORT" or "IP:START-END"
+fn parse_listen_addresses(addrs: &[String]) -> Result<Vec<ServerListenAddr>, Error> {
+    let mut listen_addrs = Vec::with_capacity(addrs.len());
+    for addr in addrs {
+        match addr.parse::<ServerListenAddr>() {
+            Ok(addr) => listen_addrs.push(addr),
+            Err(err) => {
+                let err = Error::new(
+                    ErrorKind::Malformed,
+                    "invalid `listen_addresses`",
+                    Some(format!("{addr}: {err}")),
+                );
+                return Err(err);
+            }
+        }
+    }
+    Ok(listen_addrs)
+}
+
+fn listen_addresses_to_strings(svr: &ServerConfig) -> Option<Vec<String>> {
+    if svr.listen_addrs().is_empty() {
+        None
+    } else {
+        Some(svr.listen_addrs().iter().map(ToString::to_string).collect())
+    }
+}
+
+/// Parse `outbound_bind_addr` of servers and locals
+fn parse_outbound_bind_addr(addr: &str) -> Result<IpAddr, Error> {
+    match addr.parse::<IpAddr>() {
+        Ok(addr) => Ok(addr),
+        Err(..) => {
+            let err = Error::new(
+                ErrorKind::Malformed,
+                "invalid `outbound_bind_addr`, must be an IP address",
+                Some(addr.to_owned()),
+            );
+            Err(err)
+        }
+    }
+}
+
+/// Parse `plugin_mode`, plugins relay TCP only by default
+fn parse_plugin_mode(mode: Option<&str>) -> Result<Mode, Error> {
+    match mode {
+        None => Ok(Mode::TcpOnly),
+        Some(mode) => match mode.parse::<Mode>() {
+            Ok(mode) => Ok(mode),
+            Err(..) => Err(Error::new(ErrorKind::Invalid, "invalid `plugin_mode`", None)),
+        },
+    }
+}
+
+/// `plugin_mode` of `plugin`, `None` for the default `tcp_only`
+fn plugin_mode_to_string(plugin: &PluginConfig) -> Option<String> {
+    match plugin.plugin_mode {
+        Mode::TcpOnly => None,
+        mode => Some(mode.to_string()),
     }
 }
 
@@ -2345,3 +8442,269 @@ pub fn read_variable_field_value(value: &str) -> Cow<'_, str> {
 
     value.into()
 }
+
+/// Substitute environment variables in string values of a parsed configuration, see `interpolate_variables`
+///
+/// Keys and values of the other types are kept, so values of variables are never parsed in the format of the file.
+/// This shouldn't be applied on configurations from the others, like SIP008 online configurations, which could read
+/// variables of this process.
+pub fn interpolate_config_value(value: &mut serde_json::Value) {
+    match value {
+        serde_json::Value::String(s) => {
+            if let Cow::Owned(interpolated) = interpolate_variables(s) {
+                *s = interpolated;
+            }
+        }
+        serde_json::Value::Array(a) => a.iter_mut().for_each(interpolate_config_value),
+        serde_json::Value::Object(o) => o.values_mut().for_each(interpolate_config_value),
+        _ => {}
+    }
+}
+
+/// Substitute `${VAR}` in `s` with the value of environment variable `VAR`, or `DEFAULT` of `${VAR:-DEFAULT}` if
+/// `VAR` is not set. `$${` is a literal `${`, which is written by `Display` of `Config`
+///
+/// `${VAR}` of variables not set, and unterminated `${`, are kept as they are with warnings. Values of variables are
+/// not substituted again.
+pub fn interpolate_variables(s: &str) -> Cow<'_, str> {
+    if !s.contains("${") {
+        return s.into();
+    }
+
+    let mut output = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(pos) = rest.find("${") {
+        // Escaped
+        if rest[..pos].ends_with('$') {
+            output.push_str(&rest[..pos - 1]);
+            output.push_str("${");
+            rest = &rest[pos + 2..];
+            continue;
+        }
+
+        output.push_str(&rest[..pos]);
+        let expr = &rest[pos + 2..];
+        let end = match expr.find('}') {
+            Some(end) => end,
+            None => {
+                warn!("unterminated `${{` in configuration is kept");
+                output.push_str("${");
+                rest = expr;
+                continue;
+            }
+        };
+        let (name, default) = match expr[..end].split_once(":-") {
+            Some((name, default)) => (name, Some(default)),
+            None => (&expr[..end], None),
+        };
+
+        // Not a variable, like `${` in regular expressions
+        if name.is_empty() || !name.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'_') {
+            output.push_str("${");
+            rest = expr;
+            continue;
+        }
+
+        match (env::var(name), default) {
+            (Ok(value), ..) => output.push_str(&value),
+            (Err(..), Some(default)) => output.push_str(default),
+            (Err(err), None) => {
+                warn!(
+                    "couldn't read environment variable {} in configuration, error: {}",
+                    name, err
+                );
+                output.push_str(&rest[pos..pos + 2 + end + 1]);
+            }
+        }
+        rest = &expr[end + 1..];
+    }
+    output.push_str(rest);
+
+    output.into()
+}
+
+/// Read servers from a file of SIP002 URLs, one per line, or the base64 encoding of it, like subscriptions of
+/// servers. Empty lines and lines starting with `#` are skipped
+fn read_server_urls_file(path: &str) -> Result<Vec<ServerConfig>, Error> {
+    let content = match fs::read_to_string(path) {
+        Ok(c) => c,
+        Err(err) => {
+            let err = Error::new(
+                ErrorKind::IoError,
+                "failed to read `server_urls_file`",
+                Some(format!("{}, {}", path, err)),
+            );
+            return Err(err);
+        }
+    };
+
+    let content = if content.contains("://") {
+        content
+    } else {
+        let encoded: String = content.split_whitespace().collect();
+        let encoded = encoded.trim_end_matches('=');
+        match base64::decode_config(encoded, base64::STANDARD_NO_PAD)
+            .or_else(|_| base64::decode_config(encoded, base64::URL_SAFE_NO_PAD))
+            .map(String::from_utf8)
+        {
+            Ok(Ok(decoded)) => decoded,
+            _ => {
+                let err = Error::new(
+                    ErrorKind::Invalid,
+                    "`server_urls_file` is neither SIP002 URLs nor base64 encoded",
+                    Some(path.to_owned()),
+                );
+                return Err(err);
+            }
+        }
+    };
+
+    let mut servers = Vec::new();
+    for (lineno, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        match ServerConfig::from_url(line) {
+            Ok(svr) => servers.push(svr),
+            Err(err) => {
+                let err = Error::new(
+                    ErrorKind::Invalid,
+                    "invalid SIP002 URL in `server_urls_file`",
+                    Some(format!("{}:{}, {}", path, lineno + 1, err)),
+                );
+                return Err(err);
+            }
+        }
+    }
+    Ok(servers)
+}
+
+/// Extensions of files included from directories
+const INCLUDE_EXTENSIONS: &[&str] = &["json", "json5", "yaml", "yml", "toml"];
+
+/// Load the configuration file `path`, merged with files of its `include`
+///
+/// Included files are merged in order, files in included directories are sorted by their names, and the including
+/// file is merged at last. Objects are merged by keys, arrays like `servers` and `locals` are concatenated, and the
+/// other values are replaced by the later ones. Relative paths are relative to the directory of the including file.
+fn load_config_value(path: &Path, including: &mut Vec<PathBuf>) -> Result<serde_json::Value, Error> {
+    let canonical_path = match fs::canonicalize(path) {
+        Ok(p) => p,
+        Err(err) => {
+            let err = Error::new(
+                ErrorKind::IoError,
+                "failed to read `include` of configuration",
+                Some(format!("{}, {}", path.display(), err)),
+            );
+            return Err(err);
+        }
+    };
+    if including.contains(&canonical_path) {
+        let err = Error::new(
+            ErrorKind::Malformed,
+            "recursive `include` of configuration",
+            Some(path.display().to_string()),
+        );
+        return Err(err);
+    }
+
+    let content = fs::read_to_string(path)?;
+    let mut value = ConfigFormat::from_path(path).deserialize::<serde_json::Value>(&content)?;
+    interpolate_config_value(&mut value);
+
+    let include = match value.as_object_mut().and_then(|o| o.remove("include")) {
+        Some(include) => serde_json::from_value::<SSIncludeConfig>(include)?,
+        None => return Ok(value),
+    };
+
+    including.push(canonical_path);
+    let base_dir = path.parent().unwrap_or_else(|| Path::new(""));
+    let mut merged = serde_json::Value::Object(serde_json::Map::new());
+    for include_path in include.into_paths() {
+        for file in include_files(&base_dir.join(include_path))? {
+            let included = load_config_value(&file, including)?;
+            if !included.is_object() {
+                let err = Error::new(
+                    ErrorKind::Malformed,
+                    "`include` of configuration is not an object",
+                    Some(file.display().to_string()),
+                );
+                return Err(err);
+            }
+            merge_config_value(&mut merged, included);
+        }
+    }
+    including.pop();
+
+    merge_config_value(&mut merged, value);
+    Ok(merged)
+}
+
+/// Files of `path` if it is a directory, with extensions of `INCLUDE_EXTENSIONS` and sorted by names
+fn include_files(path: &Path) -> Result<Vec<PathBuf>, Error> {
+    if !path.is_dir() {
+        return Ok(vec![path.to_owned()]);
+    }
+
+    let mut files = Vec::new();
+    for entry in fs::read_dir(path)? {
+        let file = entry?.path();
+        let hidden = file
+            .file_name()
+            .and_then(|n| n.to_str())
+            .map(|n| n.starts_with('.'))
+            .unwrap_or(true);
+        let ext = file.extension().and_then(|e| e.to_str()).unwrap_or_default();
+        if !hidden && file.is_file() && INCLUDE_EXTENSIONS.iter().any(|e| ext.eq_ignore_ascii_case(e)) {
+            files.push(file);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+fn merge_config_value(base: &mut serde_json::Value, value: serde_json::Value) {
+    match (base, value) {
+        (serde_json::Value::Object(base), serde_json::Value::Object(value)) => {
+            for (key, value) in value {
+                match base.get_mut(&key) {
+                    Some(base) => merge_config_value(base, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (serde_json::Value::Array(base), serde_json::Value::Array(value)) => base.extend(value),
+        (base, value) => *base = value,
+    }
+}
+
+/// Read password from `password`, or the content of `password_file` without trailing newlines, which are exclusive.
+/// `None` if both are not set
+fn read_password(password: Option<&str>, password_file: Option<&str>) -> Result<Option<String>, Error> {
+    match (password, password_file) {
+        (Some(..), Some(..)) => {
+            let err = Error::new(
+                ErrorKind::Malformed,
+                "`password` and `password_file` are exclusive",
+                None,
+            );
+            Err(err)
+        }
+        (None, Some(path)) => match fs::read_to_string(path) {
+            Ok(content) => Ok(Some(content.trim_end_matches(&['\r', '\n'][..]).to_owned())),
+            Err(err) => {
+                let err = Error::new(
+                    ErrorKind::IoError,
+                    "failed to read `password_file`",
+                    Some(format!("{}, {}", path, err)),
+                );
+                Err(err)
+            }
+        },
+        (password, None) => Ok(password.map(ToOwned::to_owned)),
+    }
+}
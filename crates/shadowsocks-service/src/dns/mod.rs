@@ -1,6 +1,13 @@
 //! DNS resolvers
 
+#[cfg(feature = "local")]
+use std::{collections::HashSet, io, net::SocketAddr};
+
+#[cfg(feature = "local")]
+use async_trait::async_trait;
 use log::trace;
+#[cfg(feature = "local")]
+use shadowsocks::{config::ServerConfig, dns_resolver::DnsResolve, ServerAddr};
 use shadowsocks::{dns_resolver::DnsResolver, net::ConnectOpts};
 
 use crate::config::DnsConfig;
@@ -69,3 +76,66 @@ pub async fn build_dns_resolver(dns: DnsConfig, ipv6_first: bool, connect_opts:
         }
     }
 }
+
+/// Resolver of hostnames of servers by a dedicated resolver, and the other names by the general one
+#[cfg(feature = "local")]
+struct ServerDnsResolver {
+    server_names: HashSet<String>,
+    server_resolver: DnsResolver,
+    resolver: DnsResolver,
+}
+
+#[cfg(feature = "local")]
+impl ServerDnsResolver {
+    fn is_server_name(&self, addr: &str) -> bool {
+        let addr = addr.trim_end_matches('.').to_ascii_lowercase();
+        self.server_names.contains(&addr)
+    }
+}
+
+#[cfg(feature = "local")]
+#[async_trait]
+impl DnsResolve for ServerDnsResolver {
+    async fn resolve(&self, addr: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+        let resolver = if self.is_server_name(addr) {
+            trace!("resolving server hostname {} by server_dns", addr);
+            &self.server_resolver
+        } else {
+            &self.resolver
+        };
+        Ok(resolver.resolve(addr, port).await?.collect())
+    }
+}
+
+/// Wraps `resolver` with `server_dns` for resolving hostnames of `servers`
+///
+/// Returns `resolver` unchanged if none of `servers` are configured with hostnames.
+#[cfg(feature = "local")]
+pub async fn build_server_dns_resolver(
+    server_dns: DnsConfig,
+    servers: &[ServerConfig],
+    resolver: Option<DnsResolver>,
+    ipv6_first: bool,
+    connect_opts: &ConnectOpts,
+) -> Option<DnsResolver> {
+    let server_names = servers
+        .iter()
+        .filter_map(|svr_cfg| match *svr_cfg.addr() {
+            ServerAddr::DomainName(ref dname, ..) => Some(dname.trim_end_matches('.').to_ascii_lowercase()),
+            ServerAddr::SocketAddr(..) => None,
+        })
+        .collect::<HashSet<_>>();
+    if server_names.is_empty() {
+        return resolver;
+    }
+
+    let server_resolver = build_dns_resolver(server_dns, ipv6_first, connect_opts)
+        .await
+        .unwrap_or_else(DnsResolver::system_resolver);
+
+    Some(DnsResolver::custom_resolver(ServerDnsResolver {
+        server_names,
+        server_resolver,
+        resolver: resolver.unwrap_or_else(DnsResolver::system_resolver),
+    }))
+}
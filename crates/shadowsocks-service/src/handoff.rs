@@ -0,0 +1,252 @@
+//! Handing off listening sockets for binary upgrades
+//!
+//! A process with `handoff` configured listens on the Unix socket `path`. A new process started with the same
+//! configuration connects to it before creating servers, and receives all its listening sockets, so that binds to the
+//! same addresses reuse them without a window of refusing connections. Once the new process has listened on them, the
+//! old one stops accepting, keeps serving established connections until `drain_timeout`, and exits.
+//!
+//! Established connections are not transferred, they are finished by the old process.
+//!
+//! ```plain
+//! old -> new: | STATE_LEN (u32, big endian) | STATE (JSON) | with SCM_RIGHTS of the sockets in STATE
+//! new -> old: | 0x01 | after listening on the sockets
+//! ```
+
+use std::{
+    fs,
+    io::{self, ErrorKind},
+    net::SocketAddr,
+    os::unix::io::{FromRawFd, RawFd},
+    process,
+    time::Duration,
+};
+
+use byteorder::{BigEndian, ByteOrder};
+use futures::future;
+use log::{error, info, trace, warn};
+use serde::{Deserialize, Serialize};
+use shadowsocks::net::{
+    handoff::{self, HandoffListener, ListenerKind},
+    UnixListener,
+    UnixStream,
+};
+use socket2::Socket;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    time::{self, Instant},
+};
+
+use crate::config::HandoffConfig;
+
+/// Maximum file descriptors in one `SCM_RIGHTS` message (`SCM_MAX_FD` of Linux)
+const MAX_LISTENERS: usize = 253;
+
+const MAX_STATE_SIZE: usize = 65536;
+
+/// The old process is told to stop accepting after this duration, even if some of the sockets are not listened on
+const TAKE_OVER_TIMEOUT: Duration = Duration::from_secs(10);
+
+const ACK_DONE: u8 = 0x01;
+
+#[derive(Serialize, Deserialize, Debug)]
+struct HandoffState {
+    pid: u32,
+    version: String,
+    listeners: Vec<ListenerState>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct ListenerState {
+    kind: ListenerKind,
+    addr: SocketAddr,
+}
+
+/// Listening sockets handoff between processes
+pub struct Handoff {
+    config: HandoffConfig,
+    /// Connection to the old process
+    old_process: Option<UnixStream>,
+}
+
+impl Handoff {
+    /// Take over listening sockets from the process listening on `config.path`, if there is one
+    ///
+    /// This should be called before creating servers.
+    pub async fn start(config: HandoffConfig) -> io::Result<Handoff> {
+        handoff::enable();
+
+        let mut stream = match UnixStream::connect(&config.path).await {
+            Ok(s) => s,
+            Err(ref err) if matches!(err.kind(), ErrorKind::NotFound | ErrorKind::ConnectionRefused) => {
+                trace!("no process is listening on handoff path {}", config.path.display());
+                return Ok(Handoff {
+                    config,
+                    old_process: None,
+                });
+            }
+            Err(err) => return Err(err),
+        };
+
+        let mut buffer = vec![0u8; 4 + MAX_STATE_SIZE];
+        let mut fds = [0 as RawFd; MAX_LISTENERS];
+        let (mut n, nfds) = stream.recv_with_fd(&mut buffer, &mut fds).await?;
+
+        // Owns the received descriptors before anything could fail
+        let sockets = fds[..nfds]
+            .iter()
+            .map(|fd| unsafe { Socket::from_raw_fd(*fd) })
+            .collect::<Vec<_>>();
+
+        if n < 4 {
+            stream.read_exact(&mut buffer[n..4]).await?;
+            n = 4;
+        }
+        let state_len = BigEndian::read_u32(&buffer[..4]) as usize;
+        if state_len > MAX_STATE_SIZE {
+            return Err(io::Error::new(ErrorKind::InvalidData, "handoff state too large"));
+        }
+        if n < 4 + state_len {
+            stream.read_exact(&mut buffer[n..4 + state_len]).await?;
+        }
+
+        let state = match std::str::from_utf8(&buffer[4..4 + state_len])
+            .ok()
+            .and_then(|s| json5::from_str::<HandoffState>(s).ok())
+        {
+            Some(s) => s,
+            None => return Err(io::Error::new(ErrorKind::InvalidData, "invalid handoff state")),
+        };
+        if state.listeners.len() != sockets.len() {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "handoff state doesn't match the received sockets",
+            ));
+        }
+
+        info!(
+            "taking over {} listeners from process {} (version {})",
+            sockets.len(),
+            state.pid,
+            state.version
+        );
+
+        let listeners = sockets
+            .into_iter()
+            .zip(state.listeners)
+            .map(|(socket, listener)| HandoffListener {
+                kind: listener.kind,
+                addr: listener.addr,
+                socket,
+            })
+            .collect();
+        handoff::inherit(listeners);
+
+        Ok(Handoff {
+            config,
+            old_process: Some(stream),
+        })
+    }
+
+    /// Duration of serving established connections after handing off
+    pub fn drain_timeout(&self) -> Duration {
+        self.config.drain_timeout
+    }
+
+    /// Serve the next process, resolves after the listening sockets are handed off
+    ///
+    /// The old process, if any, is told to stop accepting once the inherited sockets are listened on.
+    pub async fn run(self) {
+        if let Some(stream) = self.old_process {
+            finish_take_over(stream).await;
+        }
+
+        // Replaces the old process's socket, which is unreachable from now on
+        let _ = fs::remove_file(&self.config.path);
+        let listener = match UnixListener::bind(&self.config.path) {
+            Ok(l) => l,
+            Err(err) => {
+                error!(
+                    "handoff listening on {} failed, error: {}",
+                    self.config.path.display(),
+                    err
+                );
+                return future::pending().await;
+            }
+        };
+
+        info!("shadowsocks handoff listening on {}", self.config.path.display());
+
+        loop {
+            let stream = match listener.accept().await {
+                Ok((s, _)) => s,
+                Err(err) => {
+                    error!("handoff accept failed with error: {}", err);
+                    time::sleep(Duration::from_secs(1)).await;
+                    continue;
+                }
+            };
+
+            match hand_off(stream).await {
+                Ok(n) => {
+                    info!("handed off {} listeners to the new process", n);
+                    handoff::clear_bound();
+                    return;
+                }
+                Err(err) => warn!("handoff to the new process failed, keep serving, error: {}", err),
+            }
+        }
+    }
+}
+
+async fn finish_take_over(mut stream: UnixStream) {
+    let start = Instant::now();
+    while handoff::inherited_count() > 0 && start.elapsed() < TAKE_OVER_TIMEOUT {
+        time::sleep(Duration::from_millis(100)).await;
+    }
+    handoff::clear_inherited();
+
+    match stream.write_all(&[ACK_DONE]).await {
+        Ok(..) => trace!("told the old process to stop accepting"),
+        Err(err) => warn!("failed to tell the old process to stop accepting, error: {}", err),
+    }
+}
+
+async fn hand_off(mut stream: UnixStream) -> io::Result<usize> {
+    let listeners = handoff::bound_listeners();
+    if listeners.len() > MAX_LISTENERS {
+        return Err(io::Error::new(ErrorKind::Other, "too many listeners"));
+    }
+
+    let state = HandoffState {
+        pid: process::id(),
+        version: env!("CARGO_PKG_VERSION").to_owned(),
+        listeners: listeners
+            .iter()
+            .map(|l| ListenerState {
+                kind: l.kind,
+                addr: l.addr,
+            })
+            .collect(),
+    };
+    let state = json5::to_string(&state).map_err(|err| io::Error::new(ErrorKind::Other, err))?;
+
+    let mut buffer = vec![0u8; 4];
+    BigEndian::write_u32(&mut buffer, state.len() as u32);
+    buffer.extend_from_slice(state.as_bytes());
+
+    let fds = listeners.iter().map(HandoffListener::as_raw_fd).collect::<Vec<_>>();
+    let n = stream.send_with_fd(&buffer, &fds).await?;
+    stream.write_all(&buffer[n..]).await?;
+
+    // Keeps accepting until the new process has listened on the sockets
+    let mut ack = [0u8; 1];
+    stream.read_exact(&mut ack).await?;
+    if ack[0] != ACK_DONE {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            "invalid handoff acknowledgement",
+        ));
+    }
+
+    Ok(listeners.len())
+}
@@ -56,19 +56,29 @@ pub use self::local::{create as create_local, run as run_local};
 #[cfg(feature = "manager")]
 pub use self::manager::run as run_manager;
 #[cfg(feature = "server")]
-pub use self::server::run as run_server;
+pub use self::server::{create as create_server, run as run_server};
 pub use shadowsocks;
 
+#[cfg(feature = "acl")]
 pub mod acl;
+#[cfg(unix)]
+pub mod activation;
 pub mod config;
 mod dns;
+#[cfg(unix)]
+pub mod handoff;
 #[cfg(feature = "local")]
 pub mod local;
+pub mod log_fields;
 #[cfg(feature = "manager")]
 pub mod manager;
+#[cfg(any(feature = "metrics", feature = "manager-http-api"))]
+pub mod metrics;
 pub mod net;
 #[cfg(feature = "server")]
 pub mod server;
+pub mod shutdown;
+pub mod store;
 mod sys;
 
 /// Default UDP association's expire duration
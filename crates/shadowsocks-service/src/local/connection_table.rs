@@ -0,0 +1,395 @@
+//! Table of active connections, exported on shutdown
+//!
+//! Relaying TCP connections and UDP associations are registered with their clients and targets. The table is saved
+//! into `StateStore` on shutdown, and connections cut by the restart are summarized on the next start, for
+//! quantifying the impact of restarts and tuning `handoff.drain_timeout`.
+//!
+//! Connections are also listed with their servers and traffic, and terminated by the control socket. Records of
+//! closed connections are sent to `FlowExporter`, if flows are exported.
+
+use std::{
+    collections::HashMap,
+    fmt::Write,
+    io::{self, ErrorKind},
+    net::SocketAddr,
+    str,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use futures::future::{AbortHandle, AbortRegistration};
+use log::{debug, info};
+use shadowsocks::{config::ServerAddr, relay::socks5::Address};
+use spin::Mutex as SpinMutex;
+
+use crate::{
+    local::flow_export::{FlowExporter, FlowRecord, FlowVerdict},
+    net::{ConcurrencyKind, FlowStat},
+    store::StateStore,
+};
+
+const CONNECTION_TABLE_STATE_KEY: &str = "connection-table";
+
+/// Target of UDP associations sending to any targets
+const ANY_TARGET: &str = "*";
+
+/// Number of targets listed in the summary
+const SUMMARY_TOP_TARGETS: usize = 5;
+
+fn unix_now() -> u64 {
+    unix_time(SystemTime::now())
+}
+
+fn unix_time(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+struct ConnectionEntry {
+    kind: ConcurrencyKind,
+    peer_addr: SocketAddr,
+    /// `None` for UDP associations sending to any targets
+    target_addr: Option<Address>,
+    since: SystemTime,
+    server_addr: Option<String>,
+    /// Relayed through a server, or directly
+    relayed: bool,
+    rule: Option<&'static str>,
+    flow_stat: Arc<FlowStat>,
+    abort_handle: AbortHandle,
+}
+
+impl ConnectionEntry {
+    fn target(&self) -> String {
+        match self.target_addr {
+            Some(ref addr) => addr.to_string(),
+            None => ANY_TARGET.to_owned(),
+        }
+    }
+}
+
+/// Snapshot of an active connection
+#[derive(Debug, Clone)]
+pub struct ConnectionInfo {
+    /// ID in the table, for terminating the connection
+    pub id: u64,
+    /// `TcpTunnel` or `UdpAssociation`
+    pub kind: ConcurrencyKind,
+    /// Address of the client
+    pub peer_addr: SocketAddr,
+    /// `*` for UDP associations sending to any targets
+    pub target_addr: String,
+    /// `None` if it is bypassed, or the server hasn't been chosen yet
+    pub server_addr: Option<String>,
+    /// Bytes sent from the client to the target
+    pub l2r_bytes: u64,
+    /// Bytes sent from the target back to the client
+    pub r2l_bytes: u64,
+    /// Seconds since the connection was accepted
+    pub age: u64,
+}
+
+/// Active connections of local servers
+#[derive(Default)]
+pub struct ConnectionTable {
+    next_id: AtomicU64,
+    entries: SpinMutex<HashMap<u64, ConnectionEntry>>,
+    export: bool,
+    flow_exporter: Option<FlowExporter>,
+}
+
+impl ConnectionTable {
+    /// Create an empty table
+    pub fn new() -> ConnectionTable {
+        ConnectionTable::default()
+    }
+
+    /// Set whether the table is saved on shutdown, otherwise it is only for the control socket
+    pub fn set_export(&mut self, export: bool) {
+        self.export = export;
+    }
+
+    /// Check if the table is saved on shutdown
+    pub fn is_export(&self) -> bool {
+        self.export
+    }
+
+    /// Set exporter of records of closed connections
+    pub fn set_flow_exporter(&mut self, flow_exporter: FlowExporter) {
+        self.flow_exporter = Some(flow_exporter);
+    }
+
+    /// Number of active connections
+    pub fn len(&self) -> usize {
+        self.entries.lock().len()
+    }
+
+    /// Check if there are no active connections
+    pub fn is_empty(&self) -> bool {
+        self.entries.lock().is_empty()
+    }
+
+    /// Register a connection of `kind` to `target_addr`, `None` for UDP associations sending to any targets
+    ///
+    /// The connection will be removed when the returned guard is dropped
+    pub fn register(
+        self: &Arc<Self>,
+        kind: ConcurrencyKind,
+        peer_addr: SocketAddr,
+        target_addr: Option<&Address>,
+    ) -> ConnectionTableGuard {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let flow_stat = Arc::new(FlowStat::new());
+        let (abort_handle, abort_registration) = AbortHandle::new_pair();
+        let entry = ConnectionEntry {
+            kind,
+            peer_addr,
+            target_addr: target_addr.cloned(),
+            since: SystemTime::now(),
+            server_addr: None,
+            relayed: false,
+            rule: None,
+            flow_stat: flow_stat.clone(),
+            abort_handle,
+        };
+        self.entries.lock().insert(id, entry);
+
+        ConnectionTableGuard {
+            table: self.clone(),
+            id,
+            flow_stat,
+            abort_registration: Some(abort_registration),
+        }
+    }
+
+    /// List active connections, ordered by their IDs
+    pub fn list(&self) -> Vec<ConnectionInfo> {
+        let now = SystemTime::now();
+        let mut connections = self
+            .entries
+            .lock()
+            .iter()
+            .map(|(id, entry)| ConnectionInfo {
+                id: *id,
+                kind: entry.kind,
+                peer_addr: entry.peer_addr,
+                target_addr: entry.target(),
+                server_addr: entry.server_addr.clone(),
+                l2r_bytes: entry.flow_stat.rx(),
+                r2l_bytes: entry.flow_stat.tx(),
+                age: now.duration_since(entry.since).map(|d| d.as_secs()).unwrap_or(0),
+            })
+            .collect::<Vec<_>>();
+        connections.sort_unstable_by_key(|c| c.id);
+        connections
+    }
+
+    /// Terminate the connection `id`, its relay is stopped and the client is disconnected
+    pub fn terminate(&self, id: u64) -> io::Result<()> {
+        match self.entries.lock().get(&id) {
+            Some(entry) => {
+                entry.abort_handle.abort();
+                Ok(())
+            }
+            None => Err(io::Error::new(
+                ErrorKind::NotFound,
+                format!("connection {} not found", id),
+            )),
+        }
+    }
+
+    /// Save active connections to `state_store`, returns the number of connections
+    ///
+    /// Saved as a line of the shutdown time, followed by lines of `KIND SINCE CLIENT TARGET`. Times are UNIX
+    /// timestamps in seconds.
+    pub fn save(&self, state_store: &dyn StateStore) -> io::Result<usize> {
+        let mut value = String::new();
+        let _ = writeln!(value, "{}", unix_now());
+
+        let entries = self.entries.lock();
+        for entry in entries.values() {
+            let _ = writeln!(
+                value,
+                "{} {} {} {}",
+                entry.kind.as_str(),
+                unix_time(entry.since),
+                entry.peer_addr,
+                entry.target()
+            );
+        }
+        let n = entries.len();
+        drop(entries);
+
+        state_store.save(CONNECTION_TABLE_STATE_KEY, value.as_bytes())?;
+        Ok(n)
+    }
+}
+
+/// Guard of a connection registered in `ConnectionTable`
+pub struct ConnectionTableGuard {
+    table: Arc<ConnectionTable>,
+    id: u64,
+    flow_stat: Arc<FlowStat>,
+    abort_registration: Option<AbortRegistration>,
+}
+
+impl ConnectionTableGuard {
+    /// Bytes relayed of the connection, `rx` from the client and `tx` to the client
+    pub fn flow_stat(&self) -> &Arc<FlowStat> {
+        &self.flow_stat
+    }
+
+    /// Set the server relaying the connection
+    pub fn set_server(&self, server_addr: &ServerAddr) {
+        if let Some(entry) = self.table.entries.lock().get_mut(&self.id) {
+            entry.server_addr = Some(server_addr.to_string());
+            entry.relayed = true;
+        }
+    }
+
+    /// Set the connection relayed directly, bypassing servers
+    pub fn set_bypassed(&self) {
+        if let Some(entry) = self.table.entries.lock().get_mut(&self.id) {
+            entry.relayed = true;
+        }
+    }
+
+    /// Check if records of the connection are exported, with its ACL rule set by `set_rule`
+    pub fn is_flow_exported(&self) -> bool {
+        self.table.flow_exporter.is_some()
+    }
+
+    /// Set the ACL rule deciding whether the connection is bypassed
+    pub fn set_rule(&self, rule: &'static str) {
+        if let Some(entry) = self.table.entries.lock().get_mut(&self.id) {
+            entry.rule = Some(rule);
+        }
+    }
+
+    /// Registration of the relay of the connection, for stopping it by `Abortable` when the connection is terminated
+    /// by `ConnectionTable::terminate`
+    ///
+    /// This should be called once, for the future relaying the connection.
+    pub fn abort_registration(&mut self) -> AbortRegistration {
+        match self.abort_registration.take() {
+            Some(r) => r,
+            None => {
+                let (abort_handle, abort_registration) = AbortHandle::new_pair();
+                if let Some(entry) = self.table.entries.lock().get_mut(&self.id) {
+                    entry.abort_handle = abort_handle;
+                }
+                abort_registration
+            }
+        }
+    }
+}
+
+impl Drop for ConnectionTableGuard {
+    fn drop(&mut self) {
+        let entry = match self.table.entries.lock().remove(&self.id) {
+            Some(e) => e,
+            None => return,
+        };
+
+        if let Some(ref flow_exporter) = self.table.flow_exporter {
+            let verdict = match (entry.relayed, entry.server_addr.is_some()) {
+                (false, _) => FlowVerdict::Failed,
+                (true, true) => FlowVerdict::Proxy,
+                (true, false) => FlowVerdict::Bypass,
+            };
+            flow_exporter.export(FlowRecord {
+                protocol: match entry.kind {
+                    ConcurrencyKind::UdpAssociation => 17,
+                    _ => 6,
+                },
+                peer_addr: entry.peer_addr,
+                target_addr: entry.target_addr,
+                l2r_bytes: self.flow_stat.rx(),
+                r2l_bytes: self.flow_stat.tx(),
+                start_time: entry.since,
+                end_time: SystemTime::now(),
+                server_addr: entry.server_addr,
+                verdict,
+                rule: entry.rule,
+            });
+        }
+    }
+}
+
+/// Log a summary of connections cut by the last shutdown, which are saved by `ConnectionTable::save`
+///
+/// The saved table is removed, so it will only be reported once.
+pub fn report_cut_connections(state_store: &dyn StateStore) -> io::Result<()> {
+    let value = match state_store.load(CONNECTION_TABLE_STATE_KEY)? {
+        Some(v) => v,
+        None => return Ok(()),
+    };
+    state_store.remove(CONNECTION_TABLE_STATE_KEY)?;
+
+    let mut lines = value.split(|b| *b == b'\n').filter_map(|l| str::from_utf8(l).ok());
+    let shutdown_time = match lines.next().and_then(|l| l.trim().parse::<u64>().ok()) {
+        Some(t) => t,
+        None => {
+            debug!("ignored invalid connection table of the last shutdown");
+            return Ok(());
+        }
+    };
+
+    let mut kinds: HashMap<&str, usize> = HashMap::new();
+    let mut targets: HashMap<&str, usize> = HashMap::new();
+    let mut total = 0;
+    let mut longest = 0;
+    for line in lines {
+        let mut parts = line.split_whitespace();
+        let (kind, since, target) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+            (Some(k), Some(s), Some(_), Some(t)) => match s.parse::<u64>() {
+                Ok(s) => (k, s, t),
+                Err(..) => continue,
+            },
+            _ => continue,
+        };
+
+        *kinds.entry(kind).or_default() += 1;
+        if target != ANY_TARGET {
+            *targets.entry(target).or_default() += 1;
+        }
+        total += 1;
+        longest = longest.max(shutdown_time.saturating_sub(since));
+    }
+
+    let downtime = unix_now().saturating_sub(shutdown_time);
+    if total == 0 {
+        info!("no connections were cut by the last shutdown, {}s ago", downtime);
+        return Ok(());
+    }
+
+    let mut kinds = kinds.into_iter().collect::<Vec<_>>();
+    kinds.sort_unstable();
+    let kinds = kinds
+        .iter()
+        .map(|(kind, n)| format!("{} {}", n, kind))
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    let mut targets = targets.into_iter().collect::<Vec<_>>();
+    targets.sort_unstable_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+    let targets = targets
+        .iter()
+        .take(SUMMARY_TOP_TARGETS)
+        .map(|(target, n)| format!("{} ({})", target, n))
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    info!(
+        "{} connections were cut by the last shutdown, {}s ago: {}, the longest had been alive for {:?}, top targets: {}",
+        total,
+        downtime,
+        kinds,
+        Duration::from_secs(longest),
+        targets
+    );
+
+    Ok(())
+}
@@ -1,22 +1,60 @@
 //! Shadowsocks Local Server Context
 
-use std::sync::Arc;
 #[cfg(feature = "local-dns")]
-use std::{net::IpAddr, time::Duration};
+use std::net::IpAddr;
+#[cfg(any(feature = "local-dns", feature = "acl"))]
+use std::time::Duration;
+use std::{
+    borrow::Cow,
+    io,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
 
-#[cfg(feature = "local-dns")]
+#[cfg(feature = "acl")]
+use arc_swap::ArcSwap;
+use log::info;
+#[cfg(any(feature = "local-dns", feature = "acl"))]
+use log::trace;
+#[cfg(any(feature = "local-dns", feature = "acl"))]
 use lru_time_cache::LruCache;
 use shadowsocks::{
-    config::ServerType,
+    config::{ServerConfig, ServerType},
     context::{Context, SharedContext},
     dns_resolver::DnsResolver,
-    net::{AcceptOpts, ConnectOpts},
-    relay::Address,
+    net::{AcceptOpts, ConnectOpts, IpStrategy},
+    relay::{buffer_pool::RelayBufferSizes, Address},
 };
-#[cfg(feature = "local-dns")]
+#[cfg(any(feature = "local-dns", feature = "acl"))]
 use tokio::sync::Mutex;
 
-use crate::{acl::AccessControl, config::SecurityConfig, net::FlowStat};
+#[cfg(all(feature = "acl", feature = "local-dns"))]
+use crate::acl::AclAction;
+#[cfg(feature = "local-dns")]
+use crate::local::dns::{DnsCacheStat, FakeIpPool};
+#[cfg(feature = "local-fault-injection")]
+use crate::local::fault::FaultInjector;
+#[cfg(feature = "acl")]
+use crate::{
+    acl::{AccessControl, AclDecision, ProcessAction, RejectMode, ResolveMode},
+    local::net::process::{self, ProcessInfo},
+};
+use crate::{
+    config::SecurityConfig,
+    local::{
+        connection_table::{ConnectionTable, ConnectionTableGuard},
+        direct_fallback::DirectFallback,
+        pause::PauseController,
+        priority::PriorityScheduler,
+        profile::ProfileSwitch,
+        routing_mode::{RoutingMode, RoutingModeSwitch},
+    },
+    net::{ConcurrencyKind, ConcurrencyStat, FlowStat},
+    store::{MemoryStateStore, StateStore},
+};
 
 /// Local Service Context
 pub struct ServiceContext {
@@ -24,15 +62,73 @@ pub struct ServiceContext {
     connect_opts: ConnectOpts,
     accept_opts: AcceptOpts,
 
-    // Access Control
-    acl: Option<AccessControl>,
+    // Access Control, could be shared by all contexts and reloaded
+    #[cfg(feature = "acl")]
+    acl: Option<Arc<ArcSwap<AccessControl>>>,
+
+    // Processes of local TCP clients, for ACL's process rules
+    #[cfg(feature = "acl")]
+    process_cache: Mutex<LruCache<SocketAddr, Option<Arc<ProcessInfo>>>>,
+
+    // Log ACL rules deciding every connection
+    #[cfg(feature = "acl")]
+    acl_trace: bool,
 
     // Flow statistic report
     flow_stat: Arc<FlowStat>,
 
+    // Concurrently alive objects and their high-water marks
+    concurrency_stat: Arc<ConcurrencyStat>,
+
+    // Persistent states
+    state_store: Arc<dyn StateStore>,
+
+    // Active connections, exported on shutdown
+    connection_table: Option<Arc<ConnectionTable>>,
+
+    // Migrate UDP associations from dead servers
+    udp_migration: bool,
+
+    // Report servers and rules handling connections, for debugging routes
+    route_debug: bool,
+    route_id: AtomicU64,
+
+    // Relay bypassed TCP tunnels with splice(2)
+    tcp_splice: bool,
+
+    // Sizes of buffers of TCP tunnels, configured by the local instance
+    relay_buffer_sizes: RelayBufferSizes,
+
+    // Bandwidth priority classes of TCP tunnels, shared by all contexts
+    priority_scheduler: Option<Arc<PriorityScheduler>>,
+
+    // Pausing relays and timers, shared by all contexts
+    pause_controller: Arc<PauseController>,
+
+    // Requests of switching profiles, shared by all contexts
+    profile_switch: Option<Arc<ProfileSwitch>>,
+
+    // Connecting targets directly while all servers are down, shared by all contexts
+    direct_fallback: Option<Arc<DirectFallback>>,
+
+    // Routing mode switched at runtime, shared by all contexts
+    routing_mode: Arc<RoutingModeSwitch>,
+
+    // Faults injected into relays
+    #[cfg(feature = "local-fault-injection")]
+    fault_injector: Option<Arc<FaultInjector>>,
+
     // For DNS relay's ACL domain name reverse lookup -- whether the IP shall be forwarded
     #[cfg(feature = "local-dns")]
     reverse_lookup_cache: Mutex<LruCache<IpAddr, bool>>,
+
+    // Fake IPs allocated by DNS relay, for recovering the domain names
+    #[cfg(feature = "local-dns")]
+    fake_ip_pool: Option<Arc<FakeIpPool>>,
+
+    // Lookups of DNS relay's response caches
+    #[cfg(feature = "local-dns")]
+    dns_cache_stat: DnsCacheStat,
 }
 
 impl Default for ServiceContext {
@@ -48,13 +144,40 @@ impl ServiceContext {
             context: Context::new_shared(ServerType::Local),
             connect_opts: ConnectOpts::default(),
             accept_opts: AcceptOpts::default(),
+            #[cfg(feature = "acl")]
             acl: None,
+            #[cfg(feature = "acl")]
+            process_cache: Mutex::new(LruCache::with_expiry_duration_and_capacity(
+                Duration::from_secs(10),
+                1024,
+            )),
+            #[cfg(feature = "acl")]
+            acl_trace: false,
             flow_stat: Arc::new(FlowStat::new()),
+            concurrency_stat: Arc::new(ConcurrencyStat::new()),
+            state_store: Arc::new(MemoryStateStore::new()),
+            connection_table: None,
+            udp_migration: false,
+            route_debug: false,
+            route_id: AtomicU64::new(0),
+            tcp_splice: false,
+            relay_buffer_sizes: RelayBufferSizes::default(),
+            priority_scheduler: None,
+            pause_controller: Arc::new(PauseController::new()),
+            profile_switch: None,
+            direct_fallback: None,
+            routing_mode: Arc::new(RoutingModeSwitch::default()),
+            #[cfg(feature = "local-fault-injection")]
+            fault_injector: None,
             #[cfg(feature = "local-dns")]
             reverse_lookup_cache: Mutex::new(LruCache::with_expiry_duration_and_capacity(
                 Duration::from_secs(3 * 24 * 60 * 60),
                 10240, // XXX: It should be enough for a normal user.
             )),
+            #[cfg(feature = "local-dns")]
+            fake_ip_pool: None,
+            #[cfg(feature = "local-dns")]
+            dns_cache_stat: DnsCacheStat::new(),
         }
     }
 
@@ -78,6 +201,85 @@ impl ServiceContext {
         &self.connect_opts
     }
 
+    /// Get `ConnectOpts` of connections to the server `svr_cfg`, with outbound binds of the server applied
+    pub fn server_connect_opts(&self, svr_cfg: &ServerConfig) -> Cow<'_, ConnectOpts> {
+        if svr_cfg.outbound_bind_interface().is_none() && svr_cfg.outbound_bind_addr().is_none() {
+            return Cow::Borrowed(&self.connect_opts);
+        }
+
+        let mut connect_opts = self.connect_opts.clone();
+        if let Some(iface) = svr_cfg.outbound_bind_interface() {
+            connect_opts.bind_interface = Some(iface.to_owned());
+        }
+        if let Some(addr) = svr_cfg.outbound_bind_addr() {
+            connect_opts.bind_local_addr = Some(addr);
+        }
+        Cow::Owned(connect_opts)
+    }
+
+    /// Get `connect_opts` of direct connections to target `addr`, with strategy configured by ACL applied
+    pub fn target_connect_opts<'a>(&self, addr: &Address, connect_opts: &'a ConnectOpts) -> Cow<'a, ConnectOpts> {
+        match self.acl_ip_strategy(addr) {
+            Some(ip_strategy) if connect_opts.ip_strategy.is_none() => {
+                let mut connect_opts = connect_opts.clone();
+                connect_opts.ip_strategy = Some(ip_strategy);
+                Cow::Owned(connect_opts)
+            }
+            _ => Cow::Borrowed(connect_opts),
+        }
+    }
+
+    /// Strategy of choosing among resolved addresses of target `addr` for direct connections
+    pub fn target_ip_strategy(&self, addr: &Address) -> IpStrategy {
+        self.acl_ip_strategy(addr).unwrap_or_else(|| self.context.ip_strategy())
+    }
+
+    // Strategy of host names configured by ACL's `[ip_strategy:STRATEGY]` sections
+    #[cfg_attr(not(feature = "acl"), allow(unused_variables))]
+    fn acl_ip_strategy(&self, addr: &Address) -> Option<IpStrategy> {
+        #[cfg(feature = "acl")]
+        if let (Some(ref acl), Address::DomainNameAddress(ref host, ..)) = (&self.acl, addr) {
+            return acl.load().ip_strategy(host);
+        }
+        None
+    }
+
+    /// Get target `addr` sent to servers, with domain names of ACL's `[resolve:local]` sections resolved by local DNS
+    ///
+    /// The address is chosen among the resolved ones by the strategy of direct connections to `addr`
+    pub async fn proxied_target_addr(&self, addr: Address) -> io::Result<Address> {
+        #[cfg(feature = "acl")]
+        if let (Some(acl), Address::DomainNameAddress(ref host, port)) = (self.acl(), &addr) {
+            if acl.resolve_mode(host) == Some(ResolveMode::Local) {
+                let ip_strategy = self.target_ip_strategy(&addr);
+                let resolved = self
+                    .context
+                    .dns_resolve(host, *port)
+                    .await?
+                    .filter(|a| ip_strategy.is_allowed(a))
+                    .collect::<Vec<SocketAddr>>();
+                let chosen = resolved
+                    .iter()
+                    .find(|a| a.is_ipv6() == ip_strategy.is_ipv6_first())
+                    .or_else(|| resolved.first());
+                return match chosen {
+                    Some(resolved_addr) => {
+                        trace!("{} resolved locally to {} for proxying", addr, resolved_addr);
+                        Ok(Address::SocketAddress(*resolved_addr))
+                    }
+                    None => {
+                        let err = io::Error::new(
+                            io::ErrorKind::NotFound,
+                            format!("{} resolved locally to no addresses allowed by {}", host, ip_strategy),
+                        );
+                        Err(err)
+                    }
+                };
+            }
+        }
+        Ok(addr)
+    }
+
     /// Set `AcceptOpts`
     pub fn set_accept_opts(&mut self, accept_opts: AcceptOpts) {
         self.accept_opts = accept_opts;
@@ -89,13 +291,39 @@ impl ServiceContext {
     }
 
     /// Set Access Control List
+    #[cfg(feature = "acl")]
     pub fn set_acl(&mut self, acl: AccessControl) {
+        self.acl = Some(Arc::new(ArcSwap::from_pointee(acl)));
+    }
+
+    /// Set Access Control List shared with the other contexts, which are reloaded together
+    #[cfg(feature = "acl")]
+    pub fn set_shared_acl(&mut self, acl: Arc<ArcSwap<AccessControl>>) {
         self.acl = Some(acl);
     }
 
-    /// Get Access Control List reference
-    pub fn acl(&self) -> Option<&AccessControl> {
-        self.acl.as_ref()
+    /// Get the current Access Control List
+    #[cfg(feature = "acl")]
+    pub fn acl(&self) -> Option<Arc<AccessControl>> {
+        self.acl.as_ref().map(|acl| acl.load_full())
+    }
+
+    /// Reload Access Control List from its file
+    ///
+    /// New rules apply to connections established after reloading, established ones are kept.
+    #[cfg(feature = "acl")]
+    pub fn reload_acl(&self) -> io::Result<()> {
+        let acl = match self.acl {
+            Some(ref acl) => acl,
+            None => return Err(io::Error::new(io::ErrorKind::NotFound, "ACL is not configured")),
+        };
+
+        let path = acl.load().file_path().to_owned();
+        let new_acl = AccessControl::load_from_file(&path)?;
+        acl.store(Arc::new(new_acl));
+
+        info!("ACL {} reloaded", path.display());
+        Ok(())
     }
 
     /// Get cloned flow statistic
@@ -108,6 +336,179 @@ impl ServiceContext {
         self.flow_stat.as_ref()
     }
 
+    /// Get concurrency statistic, including high-water marks since this context was created
+    pub fn concurrency_stat(&self) -> &Arc<ConcurrencyStat> {
+        &self.concurrency_stat
+    }
+
+    /// Set storage backend of persistent states
+    pub fn set_state_store(&mut self, state_store: Arc<dyn StateStore>) {
+        self.state_store = state_store;
+    }
+
+    /// Get storage backend of persistent states
+    pub fn state_store(&self) -> &Arc<dyn StateStore> {
+        &self.state_store
+    }
+
+    /// Set table of active connections, which could be shared between contexts
+    pub fn set_connection_table(&mut self, connection_table: Arc<ConnectionTable>) {
+        self.connection_table = Some(connection_table);
+    }
+
+    /// Get table of active connections
+    pub fn connection_table(&self) -> Option<&Arc<ConnectionTable>> {
+        self.connection_table.as_ref()
+    }
+
+    /// Register a connection of `kind` into the table of active connections, if it is enabled
+    ///
+    /// `target_addr` is `None` for UDP associations sending to any targets
+    pub fn register_connection(
+        &self,
+        kind: ConcurrencyKind,
+        peer_addr: SocketAddr,
+        target_addr: Option<&Address>,
+    ) -> Option<ConnectionTableGuard> {
+        self.connection_table
+            .as_ref()
+            .map(|table| table.register(kind, peer_addr, target_addr))
+    }
+
+    /// Save active connections into `StateStore`, if the table of active connections is enabled and exported
+    ///
+    /// This should be called on shutdown, connections cut by the shutdown will be reported on the next start.
+    pub fn save_connection_table(&self) -> io::Result<()> {
+        if let Some(ref table) = self.connection_table {
+            if !table.is_export() {
+                return Ok(());
+            }
+
+            let n = table.save(self.state_store.as_ref())?;
+            info!("saved {} active connections", n);
+        }
+        Ok(())
+    }
+
+    /// Set whether UDP associations are migrated to another server when their server is marked dead by the balancer
+    pub fn set_udp_migration(&mut self, udp_migration: bool) {
+        self.udp_migration = udp_migration;
+    }
+
+    /// Check if UDP associations are migrated to another server when their server is marked dead by the balancer
+    pub fn udp_migration(&self) -> bool {
+        self.udp_migration
+    }
+
+    /// Set whether servers and rules handling connections are reported, for debugging routes
+    pub fn set_route_debug(&mut self, route_debug: bool) {
+        self.route_debug = route_debug;
+    }
+
+    /// Check if servers and rules handling connections are reported, for debugging routes
+    pub fn route_debug(&self) -> bool {
+        self.route_debug
+    }
+
+    /// Set whether bypassed TCP tunnels between plain sockets are relayed with `splice(2)`
+    pub fn set_tcp_splice(&mut self, tcp_splice: bool) {
+        self.tcp_splice = tcp_splice;
+    }
+
+    /// Check if bypassed TCP tunnels between plain sockets are relayed with `splice(2)`
+    pub fn tcp_splice(&self) -> bool {
+        self.tcp_splice
+    }
+
+    /// Set sizes of buffers of TCP tunnels, instead of the size of the shared pool
+    pub fn set_relay_buffer_sizes(&mut self, sizes: RelayBufferSizes) {
+        self.relay_buffer_sizes = sizes;
+    }
+
+    /// Get sizes of buffers of TCP tunnels
+    pub fn relay_buffer_sizes(&self) -> RelayBufferSizes {
+        self.relay_buffer_sizes
+    }
+
+    /// Set whether ACL rules deciding every connection are logged
+    #[cfg(feature = "acl")]
+    pub fn set_acl_trace(&mut self, acl_trace: bool) {
+        self.acl_trace = acl_trace;
+    }
+
+    /// Check if ACL rules deciding every connection are logged
+    #[cfg(feature = "acl")]
+    pub fn acl_trace(&self) -> bool {
+        self.acl_trace
+    }
+
+    /// Allocate an ID for correlating a connection's route with logs
+    pub fn next_route_id(&self) -> u64 {
+        self.route_id.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    /// Set scheduler of bandwidth priority classes
+    pub fn set_priority_scheduler(&mut self, priority_scheduler: Arc<PriorityScheduler>) {
+        self.priority_scheduler = Some(priority_scheduler);
+    }
+
+    /// Get scheduler of bandwidth priority classes
+    pub fn priority_scheduler(&self) -> Option<&PriorityScheduler> {
+        self.priority_scheduler.as_deref()
+    }
+
+    /// Set controller of pausing relays and timers
+    pub fn set_pause_controller(&mut self, pause_controller: Arc<PauseController>) {
+        self.pause_controller = pause_controller;
+    }
+
+    /// Get controller of pausing relays and timers
+    pub fn pause_controller(&self) -> &Arc<PauseController> {
+        &self.pause_controller
+    }
+
+    /// Set requests of switching profiles, which should be shared by all contexts
+    pub fn set_profile_switch(&mut self, profile_switch: Arc<ProfileSwitch>) {
+        self.profile_switch = Some(profile_switch);
+    }
+
+    /// Get requests of switching profiles, `None` if there are no profiles
+    pub fn profile_switch(&self) -> Option<&Arc<ProfileSwitch>> {
+        self.profile_switch.as_ref()
+    }
+
+    /// Set state of connecting targets directly while all servers are down, which should be shared by all contexts
+    pub fn set_direct_fallback(&mut self, direct_fallback: Arc<DirectFallback>) {
+        self.direct_fallback = Some(direct_fallback);
+    }
+
+    /// Get state of connecting targets directly while all servers are down, `None` if it is not enabled
+    pub fn direct_fallback(&self) -> Option<&Arc<DirectFallback>> {
+        self.direct_fallback.as_ref()
+    }
+
+    /// Set routing mode switched at runtime, which should be shared by all contexts
+    pub fn set_routing_mode(&mut self, routing_mode: Arc<RoutingModeSwitch>) {
+        self.routing_mode = routing_mode;
+    }
+
+    /// Get routing mode switched at runtime
+    pub fn routing_mode(&self) -> &Arc<RoutingModeSwitch> {
+        &self.routing_mode
+    }
+
+    /// Set injector of faults into relays
+    #[cfg(feature = "local-fault-injection")]
+    pub fn set_fault_injector(&mut self, fault_injector: Arc<FaultInjector>) {
+        self.fault_injector = Some(fault_injector);
+    }
+
+    /// Get injector of faults into relays
+    #[cfg(feature = "local-fault-injection")]
+    pub fn fault_injector(&self) -> Option<&FaultInjector> {
+        self.fault_injector.as_deref()
+    }
+
     /// Set customized DNS resolver
     pub fn set_dns_resolver(&mut self, resolver: Arc<DnsResolver>) {
         let context = Arc::get_mut(&mut self.context).expect("cannot set DNS resolver on a shared context");
@@ -120,35 +521,196 @@ impl ServiceContext {
     }
 
     /// Check if target should be bypassed
-    pub async fn check_target_bypassed(&self, addr: &Address) -> bool {
-        match self.acl {
-            None => false,
-            Some(ref acl) => {
-                #[cfg(feature = "local-dns")]
-                {
-                    if let Address::SocketAddress(ref saddr) = addr {
-                        // do the reverse lookup in our local cache
-                        let mut reverse_lookup_cache = self.reverse_lookup_cache.lock().await;
-                        // if a qname is found
-                        if let Some(forward) = reverse_lookup_cache.get(&saddr.ip()) {
-                            return !*forward;
-                        }
+    ///
+    /// `peer_addr` is the address of a TCP client, whose process is matched by ACL's process rules
+    pub async fn check_target_bypassed(&self, peer_addr: Option<SocketAddr>, addr: &Address) -> bool {
+        self.check_target_bypassed_by(peer_addr, addr).await.0
+    }
+
+    /// Check if target should be bypassed, with the name of the rule deciding it
+    ///
+    /// Rules are `"none"` without ACL, `"direct_fallback"` while all servers are down, `"routing_mode"` in global or
+    /// direct routing mode, `"process"` for ACL's process rules of the TCP client `peer_addr`, `"reverse_lookup"` for
+    /// addresses resolved by local DNS, or names of `acl::TargetRule`
+    #[cfg_attr(not(feature = "acl"), allow(unused_variables))]
+    pub async fn check_target_bypassed_by(
+        &self,
+        peer_addr: Option<SocketAddr>,
+        addr: &Address,
+    ) -> (bool, &'static str) {
+        // Rejected targets are checked before, so they are still rejected
+        if let Some(ref direct_fallback) = self.direct_fallback {
+            if direct_fallback.is_active() {
+                return (true, "direct_fallback");
+            }
+        }
+
+        match self.routing_mode.mode() {
+            RoutingMode::Global => return (false, "routing_mode"),
+            RoutingMode::Direct => return (true, "routing_mode"),
+            RoutingMode::Rule => {}
+        }
+
+        #[cfg(feature = "acl")]
+        if let Some(acl) = self.acl() {
+            match self.check_peer_process(&acl, peer_addr).await {
+                Some(ProcessAction::Bypass) => return (true, "process"),
+                Some(ProcessAction::Proxy) | Some(ProcessAction::Route(..)) => return (false, "process"),
+                Some(ProcessAction::Block(..)) | None => {}
+            }
+
+            #[cfg(feature = "local-dns")]
+            {
+                if let Address::SocketAddress(ref saddr) = addr {
+                    // do the reverse lookup in our local cache
+                    let mut reverse_lookup_cache = self.reverse_lookup_cache.lock().await;
+                    // if a qname is found
+                    if let Some(forward) = reverse_lookup_cache.get(&saddr.ip()) {
+                        return (!*forward, "reverse_lookup");
                     }
                 }
+            }
+
+            let (bypassed, rule) = acl.check_target(&self.context, addr).await;
+            return (bypassed, rule.as_str());
+        }
+        (false, "none")
+    }
+
+    /// Check if connecting to `addr` is blocked by ACL's `[outbound_block_list]` or `[reject:MODE]`, including process
+    /// rules of the TCP client `peer_addr`
+    #[cfg_attr(not(feature = "acl"), allow(unused_variables))]
+    pub async fn check_target_blocked(&self, peer_addr: Option<SocketAddr>, addr: &Address) -> bool {
+        #[cfg(feature = "acl")]
+        return self.check_target_rejected(peer_addr, addr).await.is_some();
+
+        #[cfg(not(feature = "acl"))]
+        false
+    }
+
+    /// Check if connecting to `addr` is rejected by ACL's `[outbound_block_list]` or `[reject:MODE]`, with the mode of
+    /// answering the client `peer_addr`
+    #[cfg(feature = "acl")]
+    pub async fn check_target_rejected(&self, peer_addr: Option<SocketAddr>, addr: &Address) -> Option<RejectMode> {
+        let acl = self.acl()?;
+        match self.check_peer_process(&acl, peer_addr).await {
+            Some(ProcessAction::Block(mode)) => return Some(mode),
+            Some(..) => return None,
+            None => {}
+        }
+        acl.check_outbound_rejected(&self.context, addr).await
+    }
 
-                acl.check_target_bypassed(&self.context, addr).await
+    /// Tag of servers for connecting to `addr`, configured by ACL's `[route:TAG]` sections, including process rules
+    /// of the TCP client `peer_addr`. Routes are only followed in rule routing mode
+    #[cfg_attr(not(feature = "acl"), allow(unused_variables))]
+    pub async fn route_tag(&self, peer_addr: Option<SocketAddr>, addr: &Address) -> Option<String> {
+        #[cfg(feature = "acl")]
+        if let Some(acl) = self.acl() {
+            if self.routing_mode.mode() != RoutingMode::Rule {
+                return None;
+            }
+
+            match self.check_peer_process(&acl, peer_addr).await {
+                Some(ProcessAction::Route(tag)) => return Some(tag.to_owned()),
+                Some(..) => return None,
+                None => {}
+            }
+            return acl.route_tag(&self.context, addr).await.map(ToOwned::to_owned);
+        }
+        None
+    }
+
+    /// Log the ACL decision of connecting to `addr` for the TCP client `peer_addr`, if ACL tracing is enabled
+    #[cfg_attr(not(feature = "acl"), allow(unused_variables))]
+    pub async fn trace_acl(&self, peer_addr: Option<SocketAddr>, addr: &Address) {
+        #[cfg(feature = "acl")]
+        if self.acl_trace {
+            if let Some(decision) = self.explain_acl(peer_addr, addr).await {
+                match peer_addr {
+                    Some(peer_addr) => info!("acl {} -> {} {}", peer_addr, addr, decision),
+                    None => info!("acl {} {}", addr, decision),
+                }
             }
         }
     }
 
+    /// Explain the ACL decision of connecting to `addr` for the TCP client `peer_addr`, in the same order of
+    /// `check_target_blocked` and `check_target_bypassed`
+    #[cfg(feature = "acl")]
+    pub async fn explain_acl(&self, peer_addr: Option<SocketAddr>, addr: &Address) -> Option<AclDecision> {
+        let acl = self.acl()?;
+
+        if let Some(peer_addr) = peer_addr {
+            if let Some(process) = self.peer_process(peer_addr).await {
+                if let Some((action, matched)) = acl.explain_process(&process.name, &process.path) {
+                    return Some(AclDecision {
+                        action,
+                        rule: "process",
+                        matched: Some(matched),
+                        resolved: None,
+                    });
+                }
+            }
+        }
+
+        let decision = acl.explain_target(&self.context, addr).await;
+
+        #[cfg(feature = "local-dns")]
+        if let (AclAction::Proxy | AclAction::Bypass, Address::SocketAddress(ref saddr)) = (&decision.action, addr) {
+            if let Some(forward) = self.reverse_lookup_cache.lock().await.get(&saddr.ip()) {
+                return Some(AclDecision {
+                    action: if *forward { AclAction::Proxy } else { AclAction::Bypass },
+                    rule: "reverse_lookup",
+                    matched: None,
+                    resolved: None,
+                });
+            }
+        }
+
+        Some(decision)
+    }
+
+    /// Process of the local TCP client `peer_addr`, which is only looked up if ACL has process rules
+    #[cfg(feature = "acl")]
+    pub async fn peer_process(&self, peer_addr: SocketAddr) -> Option<Arc<ProcessInfo>> {
+        if !self.acl()?.has_process_rules() {
+            return None;
+        }
+
+        if let Some(process) = self.process_cache.lock().await.get(&peer_addr) {
+            return process.clone();
+        }
+
+        let process = tokio::task::spawn_blocking(move || process::find_tcp_process(peer_addr))
+            .await
+            .ok()
+            .flatten()
+            .map(Arc::new);
+        trace!("local client {} process {:?}", peer_addr, process);
+
+        self.process_cache.lock().await.insert(peer_addr, process.clone());
+        process
+    }
+
+    #[cfg(feature = "acl")]
+    async fn check_peer_process<'a>(
+        &self,
+        acl: &'a AccessControl,
+        peer_addr: Option<SocketAddr>,
+    ) -> Option<ProcessAction<'a>> {
+        let process = self.peer_process(peer_addr?).await?;
+        acl.check_process(&process.name, &process.path)
+    }
+
     /// Add a record to the reverse lookup cache
     #[cfg(feature = "local-dns")]
     pub async fn add_to_reverse_lookup_cache(&self, addr: IpAddr, forward: bool) {
         let is_exception = forward
-            != match self.acl {
+            != match self.acl() {
                 // Proxy everything by default
                 None => true,
-                Some(ref a) => a.check_ip_in_proxy_list(&addr),
+                Some(a) => a.check_ip_in_proxy_list(&addr),
             };
         let mut reverse_lookup_cache = self.reverse_lookup_cache.lock().await;
         match reverse_lookup_cache.get_mut(&addr) {
@@ -168,15 +730,60 @@ impl ServiceContext {
         }
     }
 
+    /// Set fake IP pool of DNS relay
+    #[cfg(feature = "local-dns")]
+    pub fn set_fake_ip_pool(&mut self, pool: Arc<FakeIpPool>) {
+        self.fake_ip_pool = Some(pool);
+    }
+
+    /// Get fake IP pool of DNS relay
+    #[cfg(feature = "local-dns")]
+    pub fn fake_ip_pool(&self) -> Option<&Arc<FakeIpPool>> {
+        self.fake_ip_pool.as_ref()
+    }
+
+    /// Get lookups of DNS relay's response caches
+    #[cfg(feature = "local-dns")]
+    pub fn dns_cache_stat(&self) -> &DnsCacheStat {
+        &self.dns_cache_stat
+    }
+
+    /// Recover the domain name of `addr` if it is a fake IP allocated by DNS relay
+    ///
+    /// Domain names will be resolved by servers, and matched by ACL rules.
+    pub fn map_fake_ip(&self, addr: Address) -> Address {
+        #[cfg(feature = "local-dns")]
+        if let Some(ref pool) = self.fake_ip_pool {
+            if let Address::SocketAddress(ref saddr) = addr {
+                if let Some(name) = pool.lookup(&saddr.ip()) {
+                    trace!("fake IP {} mapped to {}", saddr, name);
+                    return Address::DomainNameAddress(name, saddr.port());
+                }
+            }
+        }
+
+        addr
+    }
+
     /// Try to connect IPv6 addresses first if hostname could be resolved to both IPv4 and IPv6
     pub fn set_ipv6_first(&mut self, ipv6_first: bool) {
         let context = Arc::get_mut(&mut self.context).expect("cannot set ipv6_first on a shared context");
         context.set_ipv6_first(ipv6_first);
     }
 
+    /// Set strategy of choosing among resolved IPv4 and IPv6 addresses
+    pub fn set_ip_strategy(&mut self, ip_strategy: IpStrategy) {
+        let context = Arc::get_mut(&mut self.context).expect("cannot set ip_strategy on a shared context");
+        context.set_ip_strategy(ip_strategy);
+    }
+
     /// Set security config
     pub fn set_security_config(&mut self, security: &SecurityConfig) {
         let context = Arc::get_mut(&mut self.context).expect("cannot set security on a shared context");
         context.set_replay_attack_policy(security.replay_attack.policy);
+        #[cfg(feature = "aead-cipher-2022")]
+        if let Some(tolerance) = security.clock_skew.tolerance {
+            context.set_timestamp_tolerance(tolerance);
+        }
     }
 }
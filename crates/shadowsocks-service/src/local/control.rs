@@ -0,0 +1,532 @@
+//! Control socket of live connections
+//!
+//! Listens on `control_address`, a TCP address or the path of a Unix socket. Clients send commands in lines of JSON
+//! objects, and every command is answered by a line of JSON object:
+//!
+//! - `{"command":"list"}`, returns `{"connections":[...]}`, active TCP tunnels and UDP associations with their clients,
+//!   targets, servers, bytes relayed and ages
+//! - `{"command":"kill","id":ID}`, terminates the connection `ID`, returns `{}`
+//! - `{"command":"servers"}`, returns `{"servers":[...]}`, remote servers with latencies of handshakes, failures of
+//!   connecting and errors of relaying
+//! - `{"command":"dns_cache"}`, returns `{"entries":[...]}`, names failed to be resolved in the negative cache of the
+//!   resolver, with their errors and remaining seconds
+//! - `{"command":"dns_flush","name":NAME}`, removes `NAME` from the negative cache, or all names if `name` is omitted,
+//!   returns `{"flushed":N}`
+//! - `{"command":"mode","mode":MODE}`, switches routing of new connections to `MODE`, `global` for proxying all
+//!   targets, `rule` for following ACL, or `direct` for bypassing all targets, returns `{"mode":MODE}`. Returns the
+//!   current mode without switching if `mode` is omitted
+//! - `{"command":"server_add","url":URL,"index":N}`, adds the server of SIP002 `URL` at `N` of the server list, or
+//!   appends it if `index` is omitted, returns `{}`
+//! - `{"command":"server_remove","server":ADDR}`, removes the server listening on `ADDR`, returns `{}`
+//! - `{"command":"server_disable","server":ADDR}` and `{"command":"server_enable","server":ADDR}`, excludes the server
+//!   from being chosen by the balancer and includes it again, returns `{}`
+//! - `{"command":"server_move","server":ADDR,"index":N}`, moves the server to `N` of the server list, which is the
+//!   priority of the `failover` strategy, returns `{}`
+//!
+//! Servers are checked again after they are added, removed or moved. Established connections are kept, and changes are
+//! lost after servers are reloaded from the configuration file.
+//!
+//! Failed commands are answered by `{"error":"..."}`.
+//!
+//! Unix sockets are accessible only by their owners. Commands of TCP addresses are authenticated with `control_key`,
+//! each of them is prefixed by a line `auth TIMESTAMP TAG` as requests of the manager, and commands failed to be
+//! authenticated are answered by `{"error":"authentication failed, ..."}`.
+
+use std::{
+    collections::BTreeMap,
+    io::{self, ErrorKind},
+    sync::Arc,
+};
+
+use log::{debug, error, info, trace};
+use serde::{Deserialize, Serialize};
+use shadowsocks::{
+    config::{ManagerAddr, ServerAddr, ServerConfig},
+    context::SharedContext,
+    lookup_then,
+    manager::ManagerAuthenticator,
+    net::{AcceptOpts, TcpListener},
+};
+use spin::Mutex as SpinMutex;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+
+use crate::{
+    local::{
+        connection_table::{ConnectionInfo, ConnectionTable},
+        loadbalancing::{PingBalancer, ServerIdent},
+        resolver_cache::{ResolverCache, ResolverCacheEntry},
+        routing_mode::{RoutingMode, RoutingModeSwitch},
+    },
+    net::ConcurrencyKind,
+};
+
+/// Maximum length of a command line, which may carry SIP002 URLs of servers
+const MAX_REQUEST_SIZE: u64 = 8192;
+
+#[derive(Deserialize)]
+#[serde(tag = "command", rename_all = "lowercase")]
+enum ControlRequest {
+    List,
+    Kill {
+        id: u64,
+    },
+    Servers,
+    #[serde(rename = "dns_cache")]
+    DnsCache,
+    #[serde(rename = "dns_flush")]
+    DnsFlush {
+        #[serde(default)]
+        name: Option<String>,
+    },
+    Mode {
+        #[serde(default)]
+        mode: Option<String>,
+    },
+    #[serde(rename = "server_add")]
+    ServerAdd {
+        url: String,
+        #[serde(default)]
+        index: Option<usize>,
+    },
+    #[serde(rename = "server_remove")]
+    ServerRemove {
+        server: String,
+    },
+    #[serde(rename = "server_disable")]
+    ServerDisable {
+        server: String,
+    },
+    #[serde(rename = "server_enable")]
+    ServerEnable {
+        server: String,
+    },
+    #[serde(rename = "server_move")]
+    ServerMove {
+        server: String,
+        index: usize,
+    },
+}
+
+#[derive(Serialize)]
+struct ListResponse {
+    connections: Vec<ConnectionStatus>,
+}
+
+#[derive(Serialize)]
+struct ConnectionStatus {
+    id: u64,
+    /// `tcp` or `udp`
+    kind: &'static str,
+    peer: String,
+    target: String,
+    /// `None` if it is bypassed, or the server hasn't been chosen yet
+    server: Option<String>,
+    l2r_bytes: u64,
+    r2l_bytes: u64,
+    /// Seconds since the connection was accepted
+    age: u64,
+}
+
+impl From<ConnectionInfo> for ConnectionStatus {
+    fn from(info: ConnectionInfo) -> ConnectionStatus {
+        ConnectionStatus {
+            id: info.id,
+            kind: match info.kind {
+                ConcurrencyKind::TcpTunnel => "tcp",
+                ConcurrencyKind::UdpAssociation => "udp",
+                kind => kind.as_str(),
+            },
+            peer: info.peer_addr.to_string(),
+            target: info.target_addr,
+            server: info.server_addr,
+            l2r_bytes: info.l2r_bytes,
+            r2l_bytes: info.r2l_bytes,
+            age: info.age,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ServersResponse {
+    servers: Vec<ServerStatus>,
+}
+
+#[derive(Serialize)]
+struct ServerStatus {
+    server: String,
+    /// Excluded from being chosen by the balancer
+    disabled: bool,
+    /// Count of successful handshakes
+    handshakes: u64,
+    /// Total latency of handshakes, in millisec
+    handshake_sum_ms: u64,
+    /// Cumulative counts of handshakes, by upper bounds of latencies in millisec
+    handshake_buckets: Vec<(u64, u64)>,
+    connect_failures: u64,
+    /// Counts of TCP tunnels closed with errors, by classes
+    relay_errors: BTreeMap<&'static str, u64>,
+}
+
+impl From<&ServerIdent> for ServerStatus {
+    fn from(server: &ServerIdent) -> ServerStatus {
+        let relay_stat = server.relay_stat();
+        ServerStatus {
+            server: server.server_config().addr().to_string(),
+            disabled: server.is_excluded(),
+            handshakes: relay_stat.handshake_count(),
+            handshake_sum_ms: relay_stat.handshake_sum(),
+            handshake_buckets: relay_stat.handshake_buckets(),
+            connect_failures: relay_stat.connect_failures(),
+            relay_errors: relay_stat
+                .relay_errors()
+                .into_iter()
+                .map(|(class, n)| (class.as_str(), n))
+                .collect(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct DnsCacheResponse {
+    entries: Vec<DnsCacheStatus>,
+}
+
+#[derive(Serialize)]
+struct DnsCacheStatus {
+    name: String,
+    error: String,
+    /// Consecutive failures
+    failures: u32,
+    /// Seconds before the name is resolved again
+    expires_in: u64,
+}
+
+impl From<ResolverCacheEntry> for DnsCacheStatus {
+    fn from(entry: ResolverCacheEntry) -> DnsCacheStatus {
+        DnsCacheStatus {
+            name: entry.name,
+            error: entry.error,
+            failures: entry.failures,
+            expires_in: entry.expires_in.as_secs(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct DnsFlushResponse {
+    flushed: usize,
+}
+
+#[derive(Serialize)]
+struct ModeResponse {
+    mode: String,
+}
+
+#[derive(Serialize)]
+struct EmptyResponse {}
+
+#[derive(Serialize)]
+struct ErrorResponse {
+    error: String,
+}
+
+/// Control server of active connections in `ConnectionTable`, and remote servers in the balancer
+pub struct ControlServer {
+    context: SharedContext,
+    accept_opts: AcceptOpts,
+    connection_table: Arc<ConnectionTable>,
+    balancer: PingBalancer,
+    resolver_cache: Option<Arc<ResolverCache>>,
+    routing_mode: Arc<RoutingModeSwitch>,
+    // Shared by all clients, so commands authenticated on a connection couldn't be replayed on another one
+    authenticator: Option<Arc<SpinMutex<ManagerAuthenticator>>>,
+}
+
+impl ControlServer {
+    /// Create with the table of active connections and the balancer of remote servers
+    pub fn new(
+        context: SharedContext,
+        accept_opts: AcceptOpts,
+        connection_table: Arc<ConnectionTable>,
+        balancer: PingBalancer,
+    ) -> ControlServer {
+        ControlServer {
+            context,
+            accept_opts,
+            connection_table,
+            balancer,
+            resolver_cache: None,
+            routing_mode: Arc::new(RoutingModeSwitch::default()),
+            authenticator: None,
+        }
+    }
+
+    /// Set the pre-shared key, commands are accepted only if they are authenticated with it
+    pub fn set_key(&mut self, key: &[u8]) {
+        self.authenticator = Some(Arc::new(SpinMutex::new(ManagerAuthenticator::new(key))));
+    }
+
+    /// Set the negative cache of the resolver, which is dumped and flushed by clients
+    pub fn set_resolver_cache(&mut self, resolver_cache: Arc<ResolverCache>) {
+        self.resolver_cache = Some(resolver_cache);
+    }
+
+    /// Set the routing mode of local servers, which is switched by clients
+    pub fn set_routing_mode(&mut self, routing_mode: Arc<RoutingModeSwitch>) {
+        self.routing_mode = routing_mode;
+    }
+
+    /// Run server
+    pub async fn run(self, bind_addr: &ManagerAddr) -> io::Result<()> {
+        let listener = match *bind_addr {
+            ManagerAddr::SocketAddr(sa) => TcpListener::bind_with_opts(&sa, self.accept_opts.clone()).await,
+            ManagerAddr::DomainName(ref dname, port) => lookup_then!(&self.context, dname, port, |addr| {
+                TcpListener::bind_with_opts(&addr, self.accept_opts.clone()).await
+            })
+            .map(|(_, l)| l),
+            #[cfg(unix)]
+            ManagerAddr::UnixSocketAddr(ref path) => return self.run_unix(path).await,
+        };
+
+        let listener = match listener {
+            Ok(l) => l,
+            Err(err) => {
+                error!("control server bind error: {}", err);
+                return Err(err);
+            }
+        };
+
+        info!("shadowsocks control listening on {}", listener.local_addr()?);
+
+        loop {
+            let (stream, peer_addr) = match listener.accept().await {
+                Ok(s) => s,
+                Err(err) => {
+                    error!("control server accept failed with error: {}", err);
+                    continue;
+                }
+            };
+
+            trace!("control client {} connected", peer_addr);
+
+            let connection_table = self.connection_table.clone();
+            let balancer = self.balancer.clone();
+            let resolver_cache = self.resolver_cache.clone();
+            let routing_mode = self.routing_mode.clone();
+            let authenticator = self.authenticator.clone();
+            tokio::spawn(async move {
+                if let Err(err) = handle_client(
+                    &connection_table,
+                    &balancer,
+                    resolver_cache.as_deref(),
+                    &routing_mode,
+                    authenticator.as_deref(),
+                    stream,
+                )
+                .await
+                {
+                    debug!("control client {} closed with error: {}", peer_addr, err);
+                }
+            });
+        }
+    }
+
+    #[cfg(unix)]
+    async fn run_unix(self, path: &std::path::Path) -> io::Result<()> {
+        use crate::local::net::uds::bind_unix_listener;
+
+        // Connections could be terminated by anyone connecting to it, so it is accessible only by the owner
+        let listener = match bind_unix_listener(path, Some(0o600)) {
+            Ok(l) => l,
+            Err(err) => {
+                error!("control server bind {} error: {}", path.display(), err);
+                return Err(err);
+            }
+        };
+
+        info!("shadowsocks control listening on {}", path.display());
+
+        loop {
+            let (stream, _) = match listener.accept().await {
+                Ok(s) => s,
+                Err(err) => {
+                    error!("control server accept failed with error: {}", err);
+                    continue;
+                }
+            };
+
+            let connection_table = self.connection_table.clone();
+            let balancer = self.balancer.clone();
+            let resolver_cache = self.resolver_cache.clone();
+            let routing_mode = self.routing_mode.clone();
+            let authenticator = self.authenticator.clone();
+            tokio::spawn(async move {
+                if let Err(err) = handle_client(
+                    &connection_table,
+                    &balancer,
+                    resolver_cache.as_deref(),
+                    &routing_mode,
+                    authenticator.as_deref(),
+                    stream,
+                )
+                .await
+                {
+                    debug!("control client closed with error: {}", err);
+                }
+            });
+        }
+    }
+}
+
+async fn handle_client<S>(
+    connection_table: &ConnectionTable,
+    balancer: &PingBalancer,
+    resolver_cache: Option<&ResolverCache>,
+    routing_mode: &RoutingModeSwitch,
+    authenticator: Option<&SpinMutex<ManagerAuthenticator>>,
+    stream: S,
+) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut stream = BufReader::new(stream);
+    let mut line = Vec::new();
+
+    loop {
+        line.clear();
+        if !read_line(&mut stream, &mut line).await? {
+            return Ok(());
+        }
+        // The authentication line is verified together with the command after it
+        if authenticator.is_some() && line.starts_with(b"auth ") && !read_line(&mut stream, &mut line).await? {
+            return Ok(());
+        }
+
+        let mut response = match authenticator {
+            Some(authenticator) => match authenticator.lock().verify(&line) {
+                Ok(request) => handle_request(connection_table, balancer, resolver_cache, routing_mode, request).await,
+                Err(err) => error_response(err.to_string()),
+            },
+            None => handle_request(connection_table, balancer, resolver_cache, routing_mode, &line).await,
+        };
+        response.push('\n');
+        stream.write_all(response.as_bytes()).await?;
+    }
+}
+
+/// Append a line of `stream` to `line`, returns `false` if `stream` is closed
+async fn read_line<S>(stream: &mut BufReader<S>, line: &mut Vec<u8>) -> io::Result<bool>
+where
+    S: AsyncRead + Unpin,
+{
+    let n = stream.take(MAX_REQUEST_SIZE).read_until(b'\n', line).await?;
+    if n == 0 {
+        return Ok(false);
+    }
+    if line.last() != Some(&b'\n') && n as u64 == MAX_REQUEST_SIZE {
+        return Err(io::Error::new(ErrorKind::InvalidData, "command too long"));
+    }
+    Ok(true)
+}
+
+async fn handle_request(
+    connection_table: &ConnectionTable,
+    balancer: &PingBalancer,
+    resolver_cache: Option<&ResolverCache>,
+    routing_mode: &RoutingModeSwitch,
+    line: &[u8],
+) -> String {
+    let request = match serde_json::from_slice::<ControlRequest>(line) {
+        Ok(r) => r,
+        Err(err) => return error_response(format!("invalid command, {}", err)),
+    };
+
+    match request {
+        ControlRequest::List => {
+            let response = ListResponse {
+                connections: connection_table.list().into_iter().map(From::from).collect(),
+            };
+            serde_json::to_string(&response).expect("serialize connections")
+        }
+        ControlRequest::Kill { id } => match connection_table.terminate(id) {
+            Ok(..) => {
+                debug!("connection {} terminated by control client", id);
+                serde_json::to_string(&EmptyResponse {}).expect("serialize response")
+            }
+            Err(err) => error_response(err.to_string()),
+        },
+        ControlRequest::Servers => {
+            let response = ServersResponse {
+                servers: balancer.servers().map(ServerStatus::from).collect(),
+            };
+            serde_json::to_string(&response).expect("serialize servers")
+        }
+        ControlRequest::DnsCache => match resolver_cache {
+            Some(resolver_cache) => {
+                let response = DnsCacheResponse {
+                    entries: resolver_cache.entries().into_iter().map(From::from).collect(),
+                };
+                serde_json::to_string(&response).expect("serialize DNS cache")
+            }
+            None => error_response("resolver cache is disabled".to_owned()),
+        },
+        ControlRequest::DnsFlush { name } => match resolver_cache {
+            Some(resolver_cache) => {
+                let flushed = resolver_cache.flush(name.as_deref());
+                debug!("{} names flushed from resolver cache by control client", flushed);
+                serde_json::to_string(&DnsFlushResponse { flushed }).expect("serialize response")
+            }
+            None => error_response("resolver cache is disabled".to_owned()),
+        },
+        ControlRequest::Mode { mode } => {
+            if let Some(mode) = mode {
+                match mode.parse::<RoutingMode>() {
+                    Ok(mode) => routing_mode.set_mode(mode),
+                    Err(err) => return error_response(err.to_string()),
+                }
+            }
+            let response = ModeResponse {
+                mode: routing_mode.mode().to_string(),
+            };
+            serde_json::to_string(&response).expect("serialize response")
+        }
+        ControlRequest::ServerAdd { url, index } => match ServerConfig::from_url(&url) {
+            Ok(server) => empty_or_error_response(balancer.add_server(server, index).await),
+            Err(err) => error_response(format!("invalid server URL, {}", err)),
+        },
+        ControlRequest::ServerRemove { server } => match parse_server_addr(&server) {
+            Ok(addr) => empty_or_error_response(balancer.remove_server(&addr).await),
+            Err(err) => err,
+        },
+        ControlRequest::ServerDisable { server } => match parse_server_addr(&server) {
+            Ok(addr) => empty_or_error_response(balancer.exclude_server(&addr)),
+            Err(err) => err,
+        },
+        ControlRequest::ServerEnable { server } => match parse_server_addr(&server) {
+            Ok(addr) => empty_or_error_response(balancer.include_server(&addr)),
+            Err(err) => err,
+        },
+        ControlRequest::ServerMove { server, index } => match parse_server_addr(&server) {
+            Ok(addr) => empty_or_error_response(balancer.move_server(&addr, index).await),
+            Err(err) => err,
+        },
+    }
+}
+
+/// Parse address of a server, or the error response
+fn parse_server_addr(server: &str) -> Result<ServerAddr, String> {
+    server
+        .parse::<ServerAddr>()
+        .map_err(|_| error_response(format!("invalid server address {}", server)))
+}
+
+fn empty_or_error_response(result: io::Result<()>) -> String {
+    match result {
+        Ok(..) => serde_json::to_string(&EmptyResponse {}).expect("serialize response"),
+        Err(err) => error_response(err.to_string()),
+    }
+}
+
+fn error_response(error: String) -> String {
+    serde_json::to_string(&ErrorResponse { error }).expect("serialize error")
+}
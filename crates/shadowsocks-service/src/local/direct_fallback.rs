@@ -0,0 +1,39 @@
+//! Connecting directly when all servers are unreachable
+//!
+//! Networks like captive portals or flight Wi-Fi block all servers until the user signs in, which couldn't be done
+//! through a proxy. With direct fallback enabled, while the balancer marks every server down, targets are connected
+//! directly as if they were bypassed by ACL, except those rejected by ACL. Targets are proxied again once any server
+//! is marked up by the balancer's next check.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use log::{info, warn};
+
+/// Direct fallback state shared by all contexts of local servers
+#[derive(Debug, Default)]
+pub struct DirectFallback {
+    active: AtomicBool,
+}
+
+impl DirectFallback {
+    /// Create in proxying state
+    pub fn new() -> DirectFallback {
+        DirectFallback::default()
+    }
+
+    /// Update with whether all servers are down, does nothing if it is unchanged
+    pub fn set_servers_down(&self, down: bool) {
+        if self.active.swap(down, Ordering::AcqRel) != down {
+            if down {
+                warn!("all servers are down, connecting targets directly");
+            } else {
+                info!("servers recovered, proxying targets again");
+            }
+        }
+    }
+
+    /// Check if targets are connected directly
+    pub fn is_active(&self) -> bool {
+        self.active.load(Ordering::Acquire)
+    }
+}
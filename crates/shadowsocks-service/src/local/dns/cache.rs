@@ -0,0 +1,255 @@
+//! DNS response cache
+//!
+//! Responses are cached by their queries, and expire after the least TTL of their records. Negative responses
+//! (NXDOMAIN and NODATA) expire after the MINIMUM of their SOA records, RFC2308. TTLs of records in the cached
+//! responses are counting down since they were stored.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::atomic::Ordering,
+    time::{Duration, Instant},
+};
+
+use log::trace;
+use spin::Mutex as SpinMutex;
+use trust_dns_resolver::proto::{
+    op::{response_code::ResponseCode, Message, Query},
+    rr::{RData, Record},
+};
+
+use super::config::DnsCacheConfig;
+
+/// Names that have been hit at least this times are prefetched
+const PREFETCH_MIN_HITS: u32 = 2;
+
+#[cfg(not(any(target_arch = "mips", target_arch = "powerpc")))]
+type LookupCounter = std::sync::atomic::AtomicU64;
+#[cfg(any(target_arch = "mips", target_arch = "powerpc"))]
+type LookupCounter = std::sync::atomic::AtomicU32;
+
+/// Lookups of DNS response caches, hits and misses
+#[derive(Debug, Default)]
+pub struct DnsCacheStat {
+    hits: LookupCounter,
+    misses: LookupCounter,
+}
+
+impl DnsCacheStat {
+    /// Create an empty statistic
+    pub fn new() -> DnsCacheStat {
+        DnsCacheStat::default()
+    }
+
+    /// Lookups that found unexpired responses
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed) as _
+    }
+
+    /// Lookups that found nothing, or expired responses
+    pub fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed) as _
+    }
+
+    pub(crate) fn record(&self, hit: bool) {
+        let counter = if hit { &self.hits } else { &self.misses };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Cached response that has been found
+pub struct CacheHit {
+    /// Response with TTLs of the remaining time
+    pub message: Message,
+    /// Whether the response was from the remote DNS
+    pub forward: bool,
+    /// Caller should look it up again in background and store the fresh response
+    pub prefetch: bool,
+}
+
+struct CachedResponse {
+    message: Message,
+    forward: bool,
+    ttl: Duration,
+    response_time: Instant,
+    hits: u32,
+    prefetching: bool,
+    access_seq: u64,
+}
+
+struct CacheInner {
+    entries: HashMap<Query, CachedResponse>,
+    /// Access order, for evicting the least recently used entries
+    lru: BTreeMap<u64, Query>,
+    access_seq: u64,
+}
+
+impl CacheInner {
+    fn remove(&mut self, query: &Query) {
+        if let Some(entry) = self.entries.remove(query) {
+            self.lru.remove(&entry.access_seq);
+        }
+    }
+
+    fn touch(&mut self, query: &Query) {
+        self.access_seq += 1;
+        let seq = self.access_seq;
+
+        if let Some(entry) = self.entries.get_mut(query) {
+            self.lru.remove(&entry.access_seq);
+            entry.access_seq = seq;
+            self.lru.insert(seq, query.clone());
+        }
+    }
+}
+
+/// Entry count bounded DNS response cache
+pub struct DnsResponseCache {
+    inner: SpinMutex<CacheInner>,
+    size: usize,
+    min_ttl: Option<Duration>,
+    prefetch: bool,
+}
+
+impl DnsResponseCache {
+    /// Create a cache from configuration
+    pub fn new(config: &DnsCacheConfig) -> DnsResponseCache {
+        DnsResponseCache {
+            inner: SpinMutex::new(CacheInner {
+                entries: HashMap::new(),
+                lru: BTreeMap::new(),
+                access_seq: 0,
+            }),
+            size: config.size,
+            min_ttl: config.min_ttl,
+            prefetch: config.prefetch,
+        }
+    }
+
+    /// Lookup an unexpired response of `query`
+    pub fn lookup(&self, query: &Query) -> Option<CacheHit> {
+        let mut inner = self.inner.lock();
+
+        let entry = inner.entries.get_mut(query)?;
+
+        let age = entry.response_time.elapsed();
+        if age >= entry.ttl {
+            trace!("DNS cache {} {} expired", query.query_type(), query.name());
+            inner.remove(query);
+            return None;
+        }
+
+        entry.hits += 1;
+
+        // Refresh hot names when less than 1/10 of TTL left
+        let prefetch = self.prefetch
+            && !entry.prefetching
+            && entry.hits >= PREFETCH_MIN_HITS
+            && (entry.ttl - age) * 10 <= entry.ttl;
+        if prefetch {
+            entry.prefetching = true;
+        }
+
+        let mut message = entry.message.clone();
+        let forward = entry.forward;
+
+        inner.touch(query);
+        drop(inner);
+
+        let age = age.as_secs() as u32;
+        update_ttls(&mut message, |ttl| ttl.saturating_sub(age));
+
+        trace!(
+            "DNS cache {} {} hit, age {}s, prefetch {}",
+            query.query_type(),
+            query.name(),
+            age,
+            prefetch
+        );
+
+        Some(CacheHit {
+            message,
+            forward,
+            prefetch,
+        })
+    }
+
+    /// Store `message` as the response of `query` if it is cacheable
+    pub fn insert(&self, query: Query, message: &Message, forward: bool) {
+        let mut ttl = match response_ttl(message) {
+            Some(ttl) => ttl,
+            None => return,
+        };
+        if let Some(min_ttl) = self.min_ttl {
+            ttl = ttl.max(min_ttl.as_secs() as u32);
+        }
+        if ttl == 0 {
+            return;
+        }
+
+        let mut message = message.clone();
+        if let Some(min_ttl) = self.min_ttl {
+            let min_ttl = min_ttl.as_secs() as u32;
+            update_ttls(&mut message, |ttl| ttl.max(min_ttl));
+        }
+
+        let entry = CachedResponse {
+            message,
+            forward,
+            ttl: Duration::from_secs(ttl as u64),
+            response_time: Instant::now(),
+            hits: 0,
+            prefetching: false,
+            access_seq: 0,
+        };
+
+        let mut inner = self.inner.lock();
+        inner.remove(&query);
+
+        while inner.entries.len() >= self.size {
+            let lru_query = match inner.lru.iter().next() {
+                Some((_, q)) => q.clone(),
+                None => break,
+            };
+            trace!("DNS cache {} {} evicted", lru_query.query_type(), lru_query.name());
+            inner.remove(&lru_query);
+        }
+
+        trace!("DNS cache {} {} stored, ttl {}s", query.query_type(), query.name(), ttl);
+
+        inner.entries.insert(query.clone(), entry);
+        inner.touch(&query);
+    }
+}
+
+/// TTL of `message` in seconds, `None` if it shouldn't be cached
+fn response_ttl(message: &Message) -> Option<u32> {
+    if message.truncated() {
+        return None;
+    }
+
+    match message.response_code() {
+        ResponseCode::NoError if !message.answers().is_empty() => message.answers().iter().map(Record::ttl).min(),
+        ResponseCode::NoError | ResponseCode::NXDomain => {
+            message.name_servers().iter().find_map(|rec| match rec.data() {
+                Some(RData::SOA(soa)) => Some(rec.ttl().min(soa.minimum())),
+                _ => None,
+            })
+        }
+        _ => None,
+    }
+}
+
+fn update_ttls<F>(message: &mut Message, f: F)
+where
+    F: Fn(u32) -> u32,
+{
+    for rec in message.answers_mut() {
+        rec.set_ttl(f(rec.ttl()));
+    }
+    for rec in message.name_servers_mut() {
+        rec.set_ttl(f(rec.ttl()));
+    }
+    for rec in message.additionals_mut() {
+        rec.set_ttl(f(rec.ttl()));
+    }
+}
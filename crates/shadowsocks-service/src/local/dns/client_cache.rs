@@ -18,6 +18,10 @@ use shadowsocks::{config::ServerConfig, net::ConnectOpts, relay::socks5::Address
 
 use crate::local::context::ServiceContext;
 
+#[cfg(feature = "local-dns-over-https")]
+use super::config::DnsOverHttpsAddr;
+#[cfg(any(feature = "local-dns-over-tls", feature = "local-dns-over-quic"))]
+use super::config::SecureNameServerAddr;
 use super::upstream::DnsClient;
 
 #[derive(Clone, Debug, Hash, Eq, PartialEq, PartialOrd, Ord)]
@@ -26,11 +30,16 @@ enum DnsClientKey {
     UdpLocal(SocketAddr),
     TcpRemote(Address),
     UdpRemote(Address),
+    #[cfg(feature = "local-dns-over-https")]
+    HttpsRemote(DnsOverHttpsAddr),
+    #[cfg(any(feature = "local-dns-over-tls", feature = "local-dns-over-quic"))]
+    SecureLocal(SecureNameServerAddr),
+    #[cfg(any(feature = "local-dns-over-tls", feature = "local-dns-over-quic"))]
+    SecureRemote(SecureNameServerAddr),
 }
 
 pub struct DnsClientCache {
     cache: Mutex<HashMap<DnsClientKey, VecDeque<DnsClient>>>,
-    timeout: Duration,
     retry_count: usize,
     max_client_per_addr: usize,
 }
@@ -39,7 +48,6 @@ impl DnsClientCache {
     pub fn new(max_client_per_addr: usize) -> DnsClientCache {
         DnsClientCache {
             cache: Mutex::new(HashMap::new()),
-            timeout: Duration::from_secs(5),
             retry_count: 1,
             max_client_per_addr,
         }
@@ -51,12 +59,29 @@ impl DnsClientCache {
         msg: Message,
         connect_opts: &ConnectOpts,
         is_udp: bool,
+        timeout: Duration,
     ) -> Result<Message, ProtoError> {
         let key = match is_udp {
             true => DnsClientKey::UdpLocal(ns),
             false => DnsClientKey::TcpLocal(ns),
         };
-        self.lookup_dns(&key, msg, Some(connect_opts), None, None).await
+        self.lookup_dns(&key, msg, Some(connect_opts), None, None, timeout)
+            .await
+    }
+
+    #[cfg(any(feature = "local-dns-over-tls", feature = "local-dns-over-quic"))]
+    /// `context` is used for resolving host of `ns` without a bootstrap address
+    pub async fn lookup_local_secure(
+        &self,
+        context: Option<&ServiceContext>,
+        ns: &SecureNameServerAddr,
+        msg: Message,
+        connect_opts: &ConnectOpts,
+        timeout: Duration,
+    ) -> Result<Message, ProtoError> {
+        let key = DnsClientKey::SecureLocal(ns.clone());
+        self.lookup_dns(&key, msg, Some(connect_opts), context, None, timeout)
+            .await
     }
 
     pub async fn lookup_remote(
@@ -66,16 +91,51 @@ impl DnsClientCache {
         ns: &Address,
         msg: Message,
         is_udp: bool,
+        timeout: Duration,
     ) -> Result<Message, ProtoError> {
         let key = match is_udp {
             true => DnsClientKey::UdpRemote(ns.clone()),
             false => DnsClientKey::TcpRemote(ns.clone()),
         };
-        self.lookup_dns(&key, msg, None, Some(context), Some(svr_cfg)).await
+        self.lookup_dns(&key, msg, None, Some(context), Some(svr_cfg), timeout)
+            .await
+    }
+
+    #[cfg(feature = "local-dns-over-https")]
+    pub async fn lookup_remote_https(
+        &self,
+        context: &ServiceContext,
+        svr_cfg: &ServerConfig,
+        ns: &DnsOverHttpsAddr,
+        msg: Message,
+        timeout: Duration,
+    ) -> Result<Message, ProtoError> {
+        let key = DnsClientKey::HttpsRemote(ns.clone());
+        self.lookup_dns(&key, msg, None, Some(context), Some(svr_cfg), timeout)
+            .await
+    }
+
+    #[cfg(any(feature = "local-dns-over-tls", feature = "local-dns-over-quic"))]
+    pub async fn lookup_remote_secure(
+        &self,
+        context: &ServiceContext,
+        svr_cfg: &ServerConfig,
+        ns: &SecureNameServerAddr,
+        msg: Message,
+        timeout: Duration,
+    ) -> Result<Message, ProtoError> {
+        let key = DnsClientKey::SecureRemote(ns.clone());
+        self.lookup_dns(&key, msg, None, Some(context), Some(svr_cfg), timeout)
+            .await
     }
 
     #[cfg(unix)]
-    pub async fn lookup_unix_stream<P: AsRef<Path>>(&self, ns: &P, msg: Message) -> Result<Message, ProtoError> {
+    pub async fn lookup_unix_stream<P: AsRef<Path>>(
+        &self,
+        ns: &P,
+        msg: Message,
+        timeout: Duration,
+    ) -> Result<Message, ProtoError> {
         let mut last_err = None;
 
         for _ in 0..self.retry_count {
@@ -94,7 +154,7 @@ impl DnsClientCache {
                 }
             };
 
-            let res = match client.lookup_timeout(msg.clone(), self.timeout).await {
+            let res = match client.lookup_timeout(msg.clone(), timeout).await {
                 Ok(msg) => msg,
                 Err(error) => {
                     last_err = Some(error);
@@ -113,40 +173,69 @@ impl DnsClientCache {
         connect_opts: Option<&ConnectOpts>,
         context: Option<&ServiceContext>,
         svr_cfg: Option<&ServerConfig>,
+        timeout: Duration,
     ) -> Result<Message, ProtoError> {
         let mut last_err = None;
-        let mut dns_res: io::Result<DnsClient>;
         for _ in 0..self.retry_count {
-            match dck {
-                DnsClientKey::TcpLocal(tcp_l) => {
-                    dns_res = DnsClient::connect_tcp_local(*tcp_l, connect_opts.unwrap()).await;
-                }
-                DnsClientKey::UdpLocal(udp_l) => {
-                    dns_res = DnsClient::connect_udp_local(*udp_l, connect_opts.unwrap()).await;
-                }
-                DnsClientKey::TcpRemote(tcp_l) => {
-                    dns_res = DnsClient::connect_tcp_remote(
-                        context.unwrap().context(),
-                        svr_cfg.unwrap(),
-                        tcp_l,
-                        context.unwrap().connect_opts_ref(),
-                        context.unwrap().flow_stat(),
-                    )
-                    .await;
-                }
-                DnsClientKey::UdpRemote(udp_l) => {
-                    dns_res = DnsClient::connect_udp_remote(
-                        context.unwrap().context(),
-                        svr_cfg.unwrap(),
-                        udp_l.clone(),
-                        context.unwrap().connect_opts_ref(),
-                        context.unwrap().flow_stat(),
-                    )
-                    .await;
+            // Only connect if there is no cached client
+            //
+            // Boxed, because handshake futures of encrypted upstreams are quite large
+            let create_fut = Box::pin(async {
+                match dck {
+                    DnsClientKey::TcpLocal(tcp_l) => DnsClient::connect_tcp_local(*tcp_l, connect_opts.unwrap()).await,
+                    DnsClientKey::UdpLocal(udp_l) => DnsClient::connect_udp_local(*udp_l, connect_opts.unwrap()).await,
+                    DnsClientKey::TcpRemote(tcp_l) => {
+                        DnsClient::connect_tcp_remote(
+                            context.unwrap().context(),
+                            svr_cfg.unwrap(),
+                            tcp_l,
+                            &context.unwrap().server_connect_opts(svr_cfg.unwrap()),
+                            context.unwrap().flow_stat(),
+                        )
+                        .await
+                    }
+                    DnsClientKey::UdpRemote(udp_l) => {
+                        DnsClient::connect_udp_remote(
+                            context.unwrap().context(),
+                            svr_cfg.unwrap(),
+                            udp_l.clone(),
+                            &context.unwrap().server_connect_opts(svr_cfg.unwrap()),
+                            context.unwrap().flow_stat(),
+                        )
+                        .await
+                    }
+                    #[cfg(feature = "local-dns-over-https")]
+                    DnsClientKey::HttpsRemote(https_l) => {
+                        DnsClient::connect_https_remote(
+                            context.unwrap().context(),
+                            svr_cfg.unwrap(),
+                            https_l,
+                            &context.unwrap().server_connect_opts(svr_cfg.unwrap()),
+                            context.unwrap().flow_stat(),
+                        )
+                        .await
+                    }
+                    #[cfg(any(feature = "local-dns-over-tls", feature = "local-dns-over-quic"))]
+                    DnsClientKey::SecureLocal(secure_l) => {
+                        DnsClient::connect_secure_local(context.map(|c| c.context()), secure_l, connect_opts.unwrap())
+                            .await
+                    }
+                    #[cfg(any(feature = "local-dns-over-tls", feature = "local-dns-over-quic"))]
+                    DnsClientKey::SecureRemote(secure_l) => {
+                        DnsClient::connect_secure_remote(
+                            context.unwrap().context(),
+                            svr_cfg.unwrap(),
+                            secure_l,
+                            &context.unwrap().server_connect_opts(svr_cfg.unwrap()),
+                            context.unwrap().flow_stat(),
+                        )
+                        .await
+                    }
                 }
-            }
-            match self.get_client_or_create(dck, async { dns_res }).await {
-                Ok(mut client) => match client.lookup_timeout(msg.clone(), self.timeout).await {
+            });
+
+            match self.get_client_or_create(dck, create_fut).await {
+                Ok(mut client) => match client.lookup_timeout(msg.clone(), timeout).await {
                     Ok(msg) => {
                         self.save_client(dck.clone(), client).await;
                         return Ok(msg);
@@ -1,13 +1,25 @@
 //! DNS configurations
 
 #[cfg(unix)]
-use std::{convert::Infallible, path::PathBuf};
+use std::convert::Infallible;
 use std::{
     fmt::{self, Display},
-    net::{IpAddr, SocketAddr},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::PathBuf,
     str::FromStr,
+    time::Duration,
 };
 
+#[cfg(feature = "local-dns-over-https")]
+use hyper::Uri;
+use ipnet::{Ipv4Net, Ipv6Net};
+#[cfg(any(
+    feature = "local-dns-over-https",
+    feature = "local-dns-over-tls",
+    feature = "local-dns-over-quic"
+))]
+use shadowsocks::relay::socks5::Address;
+
 /// DNS name server address
 #[derive(Debug, Clone, Eq, PartialEq, Hash)]
 pub enum NameServerAddr {
@@ -18,6 +30,9 @@ pub enum NameServerAddr {
     /// Specifically used by Android, which served as a stream protocol based DNS server
     #[cfg(unix)]
     UnixSocketAddr(PathBuf),
+    /// DNS-over-TLS or DNS-over-QUIC server
+    #[cfg(any(feature = "local-dns-over-tls", feature = "local-dns-over-quic"))]
+    Secure(SecureNameServerAddr),
 }
 
 /// Parse `NameServerAddr` error
@@ -35,6 +50,11 @@ impl FromStr for NameServerAddr {
             return Ok(NameServerAddr::SocketAddr(SocketAddr::new(ip, 53)));
         }
 
+        #[cfg(any(feature = "local-dns-over-tls", feature = "local-dns-over-quic"))]
+        if let Ok(addr) = s.parse::<SecureNameServerAddr>() {
+            return Ok(NameServerAddr::Secure(addr));
+        }
+
         match s.parse::<SocketAddr>() {
             Ok(addr) => Ok(NameServerAddr::SocketAddr(addr)),
             #[cfg(unix)]
@@ -51,6 +71,470 @@ impl Display for NameServerAddr {
             NameServerAddr::SocketAddr(ref sa) => Display::fmt(sa, f),
             #[cfg(unix)]
             NameServerAddr::UnixSocketAddr(ref p) => write!(f, "{}", p.display()),
+            #[cfg(any(feature = "local-dns-over-tls", feature = "local-dns-over-quic"))]
+            NameServerAddr::Secure(ref a) => Display::fmt(a, f),
+        }
+    }
+}
+
+/// Strategy of querying multiple local name servers
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum NameServerStrategy {
+    /// The first healthy name server, the next one is tried if it fails
+    #[default]
+    Failover,
+    /// Healthy name servers in turn
+    RoundRobin,
+    /// All healthy name servers in parallel, the first successful response wins
+    Race,
+    /// The healthy name server with the lowest smoothed response time
+    Fastest,
+}
+
+impl NameServerStrategy {
+    /// As string representation
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            NameServerStrategy::Failover => "failover",
+            NameServerStrategy::RoundRobin => "round_robin",
+            NameServerStrategy::Race => "race",
+            NameServerStrategy::Fastest => "fastest",
+        }
+    }
+}
+
+impl Display for NameServerStrategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for NameServerStrategy {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "failover" => Ok(NameServerStrategy::Failover),
+            "round_robin" => Ok(NameServerStrategy::RoundRobin),
+            "race" => Ok(NameServerStrategy::Race),
+            "fastest" => Ok(NameServerStrategy::Fastest),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Protocol of `SecureNameServerAddr`
+#[cfg(any(feature = "local-dns-over-tls", feature = "local-dns-over-quic"))]
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash, PartialOrd, Ord)]
+pub enum SecureDnsProtocol {
+    /// DNS-over-TLS, [RFC 7858](https://datatracker.ietf.org/doc/html/rfc7858)
+    #[cfg(feature = "local-dns-over-tls")]
+    Tls,
+    /// DNS-over-QUIC, [RFC 9250](https://datatracker.ietf.org/doc/html/rfc9250)
+    #[cfg(feature = "local-dns-over-quic")]
+    Quic,
+}
+
+#[cfg(any(feature = "local-dns-over-tls", feature = "local-dns-over-quic"))]
+impl SecureDnsProtocol {
+    /// URL scheme of protocol
+    pub fn scheme(&self) -> &'static str {
+        match *self {
+            #[cfg(feature = "local-dns-over-tls")]
+            SecureDnsProtocol::Tls => "tls",
+            #[cfg(feature = "local-dns-over-quic")]
+            SecureDnsProtocol::Quic => "quic",
+        }
+    }
+}
+
+/// DNS-over-TLS or DNS-over-QUIC name server address, `tls://host[:port]` or `quic://host[:port]`
+#[cfg(any(feature = "local-dns-over-tls", feature = "local-dns-over-quic"))]
+#[derive(Debug, Clone, Eq, PartialEq, Hash, PartialOrd, Ord)]
+pub struct SecureNameServerAddr {
+    protocol: SecureDnsProtocol,
+    host: String,
+    port: u16,
+    bootstrap: Option<IpAddr>,
+}
+
+#[cfg(any(feature = "local-dns-over-tls", feature = "local-dns-over-quic"))]
+impl SecureNameServerAddr {
+    /// Protocol of name server
+    pub fn protocol(&self) -> SecureDnsProtocol {
+        self.protocol
+    }
+
+    /// Host of name server, verified by TLS
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+
+    /// Port of name server
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// IP address of `host`, used for connecting instead of resolving `host`
+    pub fn bootstrap(&self) -> Option<IpAddr> {
+        self.bootstrap
+    }
+
+    /// Set IP address of `host`
+    pub fn set_bootstrap(&mut self, bootstrap: IpAddr) {
+        self.bootstrap = Some(bootstrap);
+    }
+
+    /// Address to be connected through proxy
+    pub fn server_addr(&self) -> Address {
+        match self.bootstrap {
+            Some(ip) => Address::SocketAddress(SocketAddr::new(ip, self.port)),
+            None => Address::DomainNameAddress(self.host.clone(), self.port),
+        }
+    }
+}
+
+/// Parse `SecureNameServerAddr` error
+#[cfg(any(feature = "local-dns-over-tls", feature = "local-dns-over-quic"))]
+#[derive(Debug, Clone, Copy)]
+pub struct SecureNameServerAddrError;
+
+#[cfg(any(feature = "local-dns-over-tls", feature = "local-dns-over-quic"))]
+impl Display for SecureNameServerAddrError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("invalid encrypted DNS address, should be tls://host[:port] or quic://host[:port]")
+    }
+}
+
+#[cfg(any(feature = "local-dns-over-tls", feature = "local-dns-over-quic"))]
+impl FromStr for SecureNameServerAddr {
+    type Err = SecureNameServerAddrError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (protocol, authority) = match s.split_once("://") {
+            #[cfg(feature = "local-dns-over-tls")]
+            Some(("tls", a)) => (SecureDnsProtocol::Tls, a),
+            #[cfg(feature = "local-dns-over-quic")]
+            Some(("quic", a)) => (SecureDnsProtocol::Quic, a),
+            _ => return Err(SecureNameServerAddrError),
+        };
+        let authority = authority.strip_suffix('/').unwrap_or(authority);
+
+        // Both of DoT and DoQ are served on port 853 by default
+        let (host, port) = if let Some(v6) = authority.strip_prefix('[') {
+            match v6.split_once(']') {
+                Some((host, "")) => (host, 853),
+                Some((host, port)) => match port.strip_prefix(':').and_then(|p| p.parse::<u16>().ok()) {
+                    Some(port) => (host, port),
+                    None => return Err(SecureNameServerAddrError),
+                },
+                None => return Err(SecureNameServerAddrError),
+            }
+        } else {
+            match authority.split_once(':') {
+                Some((host, port)) => match port.parse::<u16>() {
+                    Ok(port) => (host, port),
+                    Err(..) => return Err(SecureNameServerAddrError),
+                },
+                None => (authority, 853),
+            }
+        };
+
+        if host.is_empty() || host.contains('/') || port == 0 {
+            return Err(SecureNameServerAddrError);
+        }
+
+        Ok(SecureNameServerAddr {
+            protocol,
+            host: host.to_owned(),
+            port,
+            bootstrap: host.parse::<IpAddr>().ok(),
+        })
+    }
+}
+
+#[cfg(any(feature = "local-dns-over-tls", feature = "local-dns-over-quic"))]
+impl Display for SecureNameServerAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.host.parse::<IpAddr>() {
+            Ok(IpAddr::V6(ip)) => write!(f, "{}://[{}]:{}", self.protocol.scheme(), ip, self.port),
+            _ => write!(f, "{}://{}:{}", self.protocol.scheme(), self.host, self.port),
+        }
+    }
+}
+
+/// DNS-over-HTTPS name server address, [RFC 8484](https://datatracker.ietf.org/doc/html/rfc8484)
+#[cfg(feature = "local-dns-over-https")]
+#[derive(Debug, Clone, Eq, PartialEq, Hash, PartialOrd, Ord)]
+pub struct DnsOverHttpsAddr {
+    host: String,
+    port: u16,
+    path: String,
+    bootstrap: Option<IpAddr>,
+}
+
+#[cfg(feature = "local-dns-over-https")]
+impl DnsOverHttpsAddr {
+    /// Host of the URL, verified by TLS and sent as HTTP host
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+
+    /// Path and query of the URL
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// IP address of `host`, used for connecting instead of resolving `host` on the server
+    pub fn bootstrap(&self) -> Option<IpAddr> {
+        self.bootstrap
+    }
+
+    /// Set IP address of `host`
+    pub fn set_bootstrap(&mut self, bootstrap: IpAddr) {
+        self.bootstrap = Some(bootstrap);
+    }
+
+    /// Address to be connected through proxy
+    pub fn server_addr(&self) -> Address {
+        match self.bootstrap {
+            Some(ip) => Address::SocketAddress(SocketAddr::new(ip, self.port)),
+            None => Address::DomainNameAddress(self.host.clone(), self.port),
+        }
+    }
+}
+
+/// Parse `DnsOverHttpsAddr` error
+#[cfg(feature = "local-dns-over-https")]
+#[derive(Debug, Clone, Copy)]
+pub struct DnsOverHttpsAddrError;
+
+#[cfg(feature = "local-dns-over-https")]
+impl Display for DnsOverHttpsAddrError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("invalid DNS-over-HTTPS URL, should be https://host[:port]/path")
+    }
+}
+
+#[cfg(feature = "local-dns-over-https")]
+impl FromStr for DnsOverHttpsAddr {
+    type Err = DnsOverHttpsAddrError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let uri = s.parse::<Uri>().map_err(|_| DnsOverHttpsAddrError)?;
+        if uri.scheme_str() != Some("https") {
+            return Err(DnsOverHttpsAddrError);
+        }
+
+        let host = match uri.host() {
+            Some(h) if !h.is_empty() => h.trim_start_matches('[').trim_end_matches(']').to_owned(),
+            _ => return Err(DnsOverHttpsAddrError),
+        };
+
+        let path = match uri.path_and_query() {
+            Some(p) if p.as_str() != "/" => p.as_str().to_owned(),
+            _ => "/dns-query".to_owned(),
+        };
+
+        Ok(DnsOverHttpsAddr {
+            bootstrap: host.parse::<IpAddr>().ok(),
+            host,
+            port: uri.port_u16().unwrap_or(443),
+            path,
+        })
+    }
+}
+
+#[cfg(feature = "local-dns-over-https")]
+impl Display for DnsOverHttpsAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("https://")?;
+        match self.host.parse::<IpAddr>() {
+            Ok(IpAddr::V6(ip)) => write!(f, "[{}]", ip)?,
+            _ => f.write_str(&self.host)?,
+        }
+        if self.port != 443 {
+            write!(f, ":{}", self.port)?;
+        }
+        f.write_str(&self.path)
+    }
+}
+
+/// DNS response cache configuration
+#[derive(Debug, Clone)]
+pub struct DnsCacheConfig {
+    /// Maximum number of cached responses
+    pub size: usize,
+    /// Responses with smaller TTLs are cached for `min_ttl`
+    pub min_ttl: Option<Duration>,
+    /// Refresh frequently queried names in background before they expire
+    pub prefetch: bool,
+}
+
+impl DnsCacheConfig {
+    /// Create a configuration holding at most `size` responses
+    pub fn new(size: usize) -> DnsCacheConfig {
+        DnsCacheConfig {
+            size,
+            min_ttl: None,
+            prefetch: false,
+        }
+    }
+}
+
+/// Fake IP configuration
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FakeIpConfig {
+    /// Range of allocated IPv4 addresses
+    pub ipv4_range: Ipv4Net,
+    /// Range of allocated IPv6 addresses, AAAA queries are answered with empty responses if not set
+    pub ipv6_range: Option<Ipv6Net>,
+    /// TTL of answers
+    pub ttl: Duration,
+}
+
+impl FakeIpConfig {
+    /// Create a configuration allocating IPv4 addresses from `ipv4_range`
+    pub fn new(ipv4_range: Ipv4Net) -> FakeIpConfig {
+        FakeIpConfig {
+            ipv4_range,
+            ipv6_range: None,
+            ttl: Duration::from_secs(1),
+        }
+    }
+}
+
+/// Data of a static DNS record
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StaticRecordData {
+    A(Ipv4Addr),
+    Aaaa(Ipv6Addr),
+    /// Canonical name, which could be another static name or resolved by upstreams
+    Cname(String),
+    Txt(String),
+}
+
+impl StaticRecordData {
+    /// Parse from record type (`A`, `AAAA`, `CNAME` or `TXT`) and value
+    pub fn from_type_value(rtype: &str, value: &str) -> Option<StaticRecordData> {
+        match &rtype.to_ascii_uppercase()[..] {
+            "A" => value.parse().ok().map(StaticRecordData::A),
+            "AAAA" => value.parse().ok().map(StaticRecordData::Aaaa),
+            "CNAME" if !value.is_empty() => Some(StaticRecordData::Cname(value.to_owned())),
+            "TXT" => Some(StaticRecordData::Txt(value.to_owned())),
+            _ => None,
+        }
+    }
+
+    /// Record type
+    pub fn record_type(&self) -> &'static str {
+        match *self {
+            StaticRecordData::A(..) => "A",
+            StaticRecordData::Aaaa(..) => "AAAA",
+            StaticRecordData::Cname(..) => "CNAME",
+            StaticRecordData::Txt(..) => "TXT",
+        }
+    }
+
+    /// Record value
+    pub fn value(&self) -> String {
+        match *self {
+            StaticRecordData::A(ref ip) => ip.to_string(),
+            StaticRecordData::Aaaa(ref ip) => ip.to_string(),
+            StaticRecordData::Cname(ref name) => name.clone(),
+            StaticRecordData::Txt(ref text) => text.clone(),
+        }
+    }
+}
+
+/// Static DNS record
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StaticRecordConfig {
+    pub name: String,
+    pub data: StaticRecordData,
+}
+
+/// Static records answered by the DNS local before consulting upstreams
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DnsHostsConfig {
+    /// Configured records
+    pub records: Vec<StaticRecordConfig>,
+    /// Hosts file, whose addresses are also answered, read at start
+    pub hosts_file: Option<PathBuf>,
+    /// TTL of answers, 60 seconds if not set
+    pub ttl: Option<Duration>,
+}
+
+impl DnsHostsConfig {
+    /// Path of the system hosts file
+    pub fn system_hosts_file() -> PathBuf {
+        if cfg!(windows) {
+            let root = std::env::var_os("SystemRoot").unwrap_or_else(|| "C:\\Windows".into());
+            PathBuf::from(root).join("System32\\drivers\\etc\\hosts")
+        } else {
+            PathBuf::from("/etc/hosts")
+        }
+    }
+}
+
+/// AD (Authenticated Data) bit in responses to clients
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DnssecAdPolicy {
+    /// Set on validated answers, for clients asking for it with AD or DO bit (RFC 6840)
+    #[default]
+    Validated,
+    /// Never set, for clients that shouldn't trust the network between them and the DNS local
+    Clear,
+}
+
+impl DnssecAdPolicy {
+    /// As string representation
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            DnssecAdPolicy::Validated => "validated",
+            DnssecAdPolicy::Clear => "clear",
+        }
+    }
+}
+
+impl Display for DnssecAdPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for DnssecAdPolicy {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "validated" => Ok(DnssecAdPolicy::Validated),
+            "clear" => Ok(DnssecAdPolicy::Clear),
+            _ => Err(()),
+        }
+    }
+}
+
+/// DNSSEC validation of responses from upstreams
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DnssecConfig {
+    /// AD bit in responses to clients
+    pub ad_policy: DnssecAdPolicy,
+    /// Trust the root zone KSKs built in
+    pub builtin_trust_anchors: bool,
+    /// Additional trust anchors, DNSKEY records in presentation format, like `. IN DNSKEY 257 3 8 AwEAAa...`
+    pub trust_anchors: Vec<String>,
+    /// File of DNSKEY trust anchors in presentation format, like `root.key` of unbound, read at start
+    pub trust_anchors_file: Option<PathBuf>,
+}
+
+impl Default for DnssecConfig {
+    fn default() -> DnssecConfig {
+        DnssecConfig {
+            ad_policy: DnssecAdPolicy::default(),
+            builtin_trust_anchors: true,
+            trust_anchors: Vec::new(),
+            trust_anchors_file: None,
         }
     }
 }
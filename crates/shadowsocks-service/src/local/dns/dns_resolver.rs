@@ -3,6 +3,7 @@
 use std::{
     io::{self, ErrorKind},
     net::SocketAddr,
+    time::Duration,
 };
 
 use async_trait::async_trait;
@@ -24,6 +25,7 @@ pub struct DnsResolver {
     ipv6_first: bool,
     connect_opts: ConnectOpts,
     attempts: usize,
+    timeout: Duration,
 }
 
 impl DnsResolver {
@@ -35,6 +37,7 @@ impl DnsResolver {
             ipv6_first: false,
             connect_opts: ConnectOpts::default(),
             attempts: 2,
+            timeout: Duration::from_secs(5),
         }
     }
 
@@ -72,7 +75,7 @@ impl DnsResolver {
                 if self.mode.enable_udp() {
                     match self
                         .client_cache
-                        .lookup_local(ns, msg.clone(), &self.connect_opts, true)
+                        .lookup_local(ns, msg.clone(), &self.connect_opts, true, self.timeout)
                         .await
                     {
                         Ok(msg) => return Ok(msg),
@@ -83,7 +86,11 @@ impl DnsResolver {
                 }
 
                 if self.mode.enable_tcp() {
-                    match self.client_cache.lookup_local(ns, msg, &self.connect_opts, false).await {
+                    match self
+                        .client_cache
+                        .lookup_local(ns, msg, &self.connect_opts, false, self.timeout)
+                        .await
+                    {
                         Ok(msg) => return Ok(msg),
                         Err(err) => {
                             last_err = err.into();
@@ -97,7 +104,14 @@ impl DnsResolver {
             #[cfg(unix)]
             NameServerAddr::UnixSocketAddr(ref path) => self
                 .client_cache
-                .lookup_unix_stream(path, msg)
+                .lookup_unix_stream(path, msg, self.timeout)
+                .await
+                .map_err(From::from),
+
+            #[cfg(any(feature = "local-dns-over-tls", feature = "local-dns-over-quic"))]
+            NameServerAddr::Secure(ref ns) => self
+                .client_cache
+                .lookup_local_secure(None, ns, msg, &self.connect_opts, self.timeout)
                 .await
                 .map_err(From::from),
         }
@@ -0,0 +1,411 @@
+//! DNSSEC validation
+//!
+//! Answers from upstreams, especially the remote ones relayed through servers, are validated with the chain of trust
+//! from the trust anchors, and bogus answers are replaced with SERVFAIL. DNSKEY and DS records of the chain are looked
+//! up by the same ACL rules as the other queries.
+//!
+//! Answers without signatures (unsigned zones, or algorithms not supported) and negative answers are passed as
+//! insecure, without the AD bit, because denials of DS (unsigned delegations) and NSEC3 are not checked.
+
+use std::{
+    collections::HashSet,
+    fs,
+    io::{self, ErrorKind},
+    path::Path,
+    pin::Pin,
+    sync::Arc,
+};
+
+use futures::{future, stream, Stream};
+use log::{debug, trace, warn};
+use shadowsocks::relay::Address;
+use spin::Mutex as SpinMutex;
+use trust_dns_resolver::proto::{
+    error::ProtoError,
+    op::{response_code::ResponseCode, Message, Query},
+    rr::{
+        dnssec::{rdata::DNSSECRData, Algorithm, PublicKeyEnum, TrustAnchor},
+        Name,
+        RData,
+        Record,
+        RecordType,
+    },
+    xfer::{DnsHandle, DnsRequest, DnsRequestOptions, DnsResponse, DnssecDnsHandle, FirstAnswer},
+};
+
+use super::{
+    cache::DnsResponseCache,
+    config::{DnsCacheConfig, DnssecAdPolicy, DnssecConfig, NameServerAddr},
+    server::DnsClient,
+};
+
+/// EDNS UDP payload size of queries, which avoids fragmentation on most networks (DNS flag day 2020)
+const EDNS_MAX_PAYLOAD: u16 = 1232;
+
+/// Maximum number of cached DNSKEY and DS responses of the chains
+const KEY_CACHE_SIZE: usize = 256;
+
+/// Flags of DNSKEY, RFC 4034 and RFC 5011
+const DNSKEY_FLAG_ZONE: u16 = 0x0100;
+const DNSKEY_FLAG_REVOKE: u16 = 0x0080;
+
+/// Result of validating an answer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Validation {
+    /// All records are signed and validated
+    Secure,
+    /// Some records are not signed, or the answer is negative
+    Insecure,
+    /// Signatures couldn't be validated
+    Bogus,
+}
+
+/// Validates answers from upstreams
+pub struct DnssecValidator {
+    trust_anchor: TrustAnchor,
+    ad_policy: DnssecAdPolicy,
+    key_cache: Arc<DnsResponseCache>,
+}
+
+impl DnssecValidator {
+    /// Create from `config`, reading the trust anchors file if configured
+    pub fn new(config: &DnssecConfig) -> io::Result<DnssecValidator> {
+        let mut trust_anchor = if config.builtin_trust_anchors {
+            TrustAnchor::default()
+        } else {
+            TrustAnchor::new()
+        };
+
+        for anchor in &config.trust_anchors {
+            if let Err(err) = add_trust_anchor(&mut trust_anchor, anchor) {
+                let err = io::Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("invalid DNSSEC trust anchor {}, {}", anchor, err),
+                );
+                return Err(err);
+            }
+        }
+
+        if let Some(ref path) = config.trust_anchors_file {
+            load_trust_anchors_file(&mut trust_anchor, path)?;
+        }
+
+        if trust_anchor.is_empty() {
+            return Err(io::Error::new(ErrorKind::InvalidInput, "DNSSEC has no trust anchors"));
+        }
+
+        trace!("DNSSEC validation with {} trust anchors", trust_anchor.len());
+
+        Ok(DnssecValidator {
+            trust_anchor,
+            ad_policy: config.ad_policy,
+            key_cache: Arc::new(DnsResponseCache::new(&DnsCacheConfig::new(KEY_CACHE_SIZE))),
+        })
+    }
+
+    /// Validate `response` of `query`, which was looked up with DNSSEC records
+    pub async fn validate(
+        &self,
+        client: &Arc<DnsClient>,
+        query: &Query,
+        response: &Message,
+        local_addr: &Arc<NameServerAddr>,
+        remote_addr: &Arc<Address>,
+    ) -> Validation {
+        if response.response_code() != ResponseCode::NoError || response.answers().is_empty() {
+            return Validation::Insecure;
+        }
+
+        // RRsets covered by signatures that could be validated
+        let signed = response
+            .answers()
+            .iter()
+            .filter_map(|rec| match rec.data() {
+                Some(RData::DNSSEC(DNSSECRData::SIG(sig))) if is_supported_algorithm(sig.algorithm()) => {
+                    Some((rec.name().clone(), sig.type_covered()))
+                }
+                _ => None,
+            })
+            .collect::<HashSet<(Name, RecordType)>>();
+        if signed.is_empty() {
+            return Validation::Insecure;
+        }
+
+        let handle = UpstreamHandle {
+            client: client.clone(),
+            local_addr: local_addr.clone(),
+            remote_addr: remote_addr.clone(),
+            key_cache: self.key_cache.clone(),
+            primed: Arc::new(SpinMutex::new(Some(response.clone()))),
+        };
+        let mut handle = DnssecDnsHandle::with_trust_anchor(handle, self.trust_anchor.clone());
+
+        let mut request = Message::new();
+        request.add_query(query.clone()).set_recursion_desired(true);
+
+        // Unverified RRsets are removed from the validated response
+        let verified = match handle
+            .send(DnsRequest::new(request, DnsRequestOptions::default()))
+            .first_answer()
+            .await
+        {
+            Ok(m) => m,
+            Err(err) => {
+                debug!(
+                    "DNSSEC validation of {} {} failed, error: {}",
+                    query.query_type(),
+                    query.name(),
+                    err
+                );
+                return Validation::Bogus;
+            }
+        };
+        let verified = verified
+            .answers()
+            .iter()
+            .map(|rec| (rec.name().clone(), rec.record_type()))
+            .collect::<HashSet<(Name, RecordType)>>();
+
+        if let Some((name, rtype)) = signed.iter().find(|rrset| !verified.contains(rrset)) {
+            debug!(
+                "DNSSEC validation of {} {} failed, RRset {} {} is bogus",
+                query.query_type(),
+                query.name(),
+                rtype,
+                name
+            );
+            return Validation::Bogus;
+        }
+
+        let all_signed = response
+            .answers()
+            .iter()
+            .filter(|rec| rec.record_type() != RecordType::RRSIG)
+            .all(|rec| signed.contains(&(rec.name().clone(), rec.record_type())));
+        if all_signed {
+            Validation::Secure
+        } else {
+            Validation::Insecure
+        }
+    }
+
+    /// Adjust `response` for the client of `request`
+    ///
+    /// The AD bit is kept for clients asking for it with the AD or DO bit, and DNSSEC records are only returned to
+    /// clients with the DO bit.
+    pub fn respond(&self, request: &Message, mut response: Message) -> Message {
+        let dnssec_ok = request.edns().map(|edns| edns.dnssec_ok()).unwrap_or(false);
+
+        if self.ad_policy == DnssecAdPolicy::Clear || !(dnssec_ok || request.authentic_data()) {
+            response.set_authentic_data(false);
+        }
+
+        if dnssec_ok {
+            return response;
+        }
+
+        let query_type = request.queries().first().map(Query::query_type);
+        let is_requested = |rec: &Record| {
+            let rtype = rec.record_type();
+            !matches!(rtype, RecordType::RRSIG | RecordType::NSEC | RecordType::NSEC3) || Some(rtype) == query_type
+        };
+
+        // Responses without EDNS to clients without EDNS, RFC 6891
+        let mut message = Message::new();
+        message
+            .set_id(response.id())
+            .set_message_type(response.message_type())
+            .set_op_code(response.op_code())
+            .set_authoritative(response.authoritative())
+            .set_truncated(response.truncated())
+            .set_recursion_desired(response.recursion_desired())
+            .set_recursion_available(response.recursion_available())
+            .set_authentic_data(response.authentic_data())
+            .set_checking_disabled(response.checking_disabled())
+            .set_response_code(response.response_code());
+        if let (Some(..), Some(edns)) = (request.edns(), response.edns()) {
+            let mut edns = edns.clone();
+            edns.set_dnssec_ok(false);
+            message.set_edns(edns);
+        }
+
+        message.add_queries(response.take_queries());
+        message.insert_answers(response.take_answers().into_iter().filter(is_requested).collect());
+        message.insert_name_servers(response.take_name_servers().into_iter().filter(is_requested).collect());
+        message.insert_additionals(response.take_additionals().into_iter().filter(is_requested).collect());
+        message
+    }
+}
+
+/// Ask upstreams for DNSSEC records in the response of `message`
+pub fn request_dnssec_records(message: &mut Message) {
+    message
+        .edns_mut()
+        .set_max_payload(EDNS_MAX_PAYLOAD)
+        .set_version(0)
+        .set_dnssec_ok(true);
+}
+
+/// Algorithms that signatures could be validated with
+fn is_supported_algorithm(algorithm: Algorithm) -> bool {
+    matches!(
+        algorithm,
+        Algorithm::RSASHA256
+            | Algorithm::RSASHA512
+            | Algorithm::ECDSAP256SHA256
+            | Algorithm::ECDSAP384SHA384
+            | Algorithm::ED25519
+    )
+}
+
+/// Add a DNSKEY record in presentation format, `[NAME] [TTL] [CLASS] DNSKEY FLAGS PROTOCOL ALGORITHM PUBLIC_KEY`
+///
+/// Owner name, TTL and class could be omitted, the key is trusted for any zone.
+fn add_trust_anchor(trust_anchor: &mut TrustAnchor, record: &str) -> Result<(), String> {
+    let fields = record.split_whitespace().collect::<Vec<_>>();
+    let rdata = match fields.iter().position(|f| f.eq_ignore_ascii_case("DNSKEY")) {
+        Some(pos) => &fields[pos + 1..],
+        None if fields.iter().any(|f| f.eq_ignore_ascii_case("DS")) => {
+            return Err("DS trust anchors are not supported, use DNSKEY instead".to_owned());
+        }
+        None => &fields[..],
+    };
+    if rdata.len() < 4 {
+        return Err("expecting FLAGS PROTOCOL ALGORITHM PUBLIC_KEY".to_owned());
+    }
+
+    let flags = rdata[0].parse::<u16>().map_err(|_| "invalid flags".to_owned())?;
+    if flags & DNSKEY_FLAG_ZONE == 0 {
+        return Err("not a zone key".to_owned());
+    }
+    if flags & DNSKEY_FLAG_REVOKE != 0 {
+        return Err("key is revoked".to_owned());
+    }
+    if rdata[1] != "3" {
+        return Err("protocol should be 3".to_owned());
+    }
+    let algorithm = rdata[2]
+        .parse::<u8>()
+        .map(Algorithm::from_u8)
+        .map_err(|_| "invalid algorithm".to_owned())?;
+
+    let public_key = base64::decode(rdata[3..].concat()).map_err(|err| format!("invalid public key, {}", err))?;
+    let public_key = PublicKeyEnum::from_public_bytes(&public_key, algorithm).map_err(|err| err.to_string())?;
+    trust_anchor.insert_trust_anchor(&public_key);
+
+    Ok(())
+}
+
+/// Load DNSKEY records from a zone file, records of other types are ignored
+fn load_trust_anchors_file(trust_anchor: &mut TrustAnchor, path: &Path) -> io::Result<()> {
+    let content = match fs::read_to_string(path) {
+        Ok(c) => c,
+        Err(err) => {
+            let err = io::Error::new(
+                err.kind(),
+                format!(
+                    "failed to read DNSSEC trust anchors file {}, error: {}",
+                    path.display(),
+                    err
+                ),
+            );
+            return Err(err);
+        }
+    };
+
+    // Records may span lines in parentheses
+    let mut records = Vec::new();
+    let mut record = String::new();
+    let mut depth = 0usize;
+    for line in content.lines() {
+        let line = match line.find(';') {
+            Some(pos) => &line[..pos],
+            None => line,
+        };
+        for c in line.chars() {
+            match c {
+                '(' => depth += 1,
+                ')' => depth = depth.saturating_sub(1),
+                c => record.push(c),
+            }
+        }
+        record.push(' ');
+        if depth == 0 {
+            records.push(std::mem::take(&mut record));
+        }
+    }
+    records.push(record);
+
+    for record in records {
+        let is_dnskey = record.split_whitespace().any(|f| f.eq_ignore_ascii_case("DNSKEY"));
+        if !is_dnskey {
+            if record.split_whitespace().any(|f| f.eq_ignore_ascii_case("DS")) {
+                warn!(
+                    "DNSSEC trust anchors file {} DS record is not supported, ignored",
+                    path.display()
+                );
+            }
+            continue;
+        }
+
+        if let Err(err) = add_trust_anchor(trust_anchor, &record) {
+            warn!(
+                "DNSSEC trust anchors file {} invalid DNSKEY {}, {}, ignored",
+                path.display(),
+                record.trim(),
+                err
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Looks up records of the chains through upstreams of the DNS local
+///
+/// The response being validated is returned for the first query of it instead of asking upstreams again.
+#[derive(Clone)]
+struct UpstreamHandle {
+    client: Arc<DnsClient>,
+    local_addr: Arc<NameServerAddr>,
+    remote_addr: Arc<Address>,
+    key_cache: Arc<DnsResponseCache>,
+    primed: Arc<SpinMutex<Option<Message>>>,
+}
+
+impl DnsHandle for UpstreamHandle {
+    type Error = ProtoError;
+    type Response = Pin<Box<dyn Stream<Item = Result<DnsResponse, ProtoError>> + Send>>;
+
+    fn send<R: Into<DnsRequest> + Unpin + Send + 'static>(&mut self, request: R) -> Self::Response {
+        let request = request.into();
+        let query = match request.queries().first() {
+            Some(q) => q.clone(),
+            None => return Box::pin(stream::once(future::err(ProtoError::from("no query in request")))),
+        };
+
+        {
+            let mut primed = self.primed.lock();
+            if primed.as_ref().map(|m| m.queries().first() == Some(&query)) == Some(true) {
+                let response = primed.take().unwrap();
+                return Box::pin(stream::once(future::ok(DnsResponse::from(response))));
+            }
+        }
+
+        let client = self.client.clone();
+        let local_addr = self.local_addr.clone();
+        let remote_addr = self.remote_addr.clone();
+        let key_cache = self.key_cache.clone();
+        Box::pin(stream::once(async move {
+            if let Some(hit) = key_cache.lookup(&query) {
+                return Ok(DnsResponse::from(hit.message));
+            }
+
+            trace!("DNSSEC lookup {} {}", query.query_type(), query.name());
+            let (r, forward) = client.acl_lookup(&query, &local_addr, &remote_addr).await;
+            let response = r?;
+            key_cache.insert(query, &response, forward);
+            Ok(DnsResponse::from(response))
+        }))
+    }
+}
@@ -0,0 +1,266 @@
+//! Fake IP pool
+//!
+//! Names proxied by the DNS local are answered with addresses allocated from a reserved range, so that locals
+//! receiving connections to these addresses could recover the names, and let the servers resolve them.
+//!
+//! One name owns the same index of the IPv4 and the IPv6 range. Indexes of the least recently queried names are
+//! recycled when the pool is exhausted.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    str,
+    sync::Arc,
+    time::Duration,
+};
+
+use ipnet::{Ipv4Net, Ipv6Net};
+use log::{debug, trace, warn};
+use spin::Mutex as SpinMutex;
+use tokio::time;
+
+use crate::store::StateStore;
+
+use super::config::FakeIpConfig;
+
+/// Key of the allocated names in `StateStore`
+const FAKE_IP_STATE_KEY: &str = "fake-ip";
+
+/// Interval of saving allocated names to `StateStore`
+const FAKE_IP_SAVE_INTERVAL: Duration = Duration::from_secs(10);
+
+struct PoolInner {
+    names: HashMap<String, u32>,
+    indexes: HashMap<u32, (String, u64)>,
+    /// Access order, for recycling the least recently used indexes
+    lru: BTreeMap<u64, u32>,
+    access_seq: u64,
+    next_index: u32,
+    dirty: bool,
+}
+
+impl PoolInner {
+    fn touch(&mut self, index: u32) {
+        self.access_seq += 1;
+        let seq = self.access_seq;
+
+        if let Some((_, ref mut access_seq)) = self.indexes.get_mut(&index) {
+            self.lru.remove(access_seq);
+            *access_seq = seq;
+            self.lru.insert(seq, index);
+        }
+    }
+
+    fn insert(&mut self, index: u32, name: String) {
+        if let Some((old_name, access_seq)) = self.indexes.remove(&index) {
+            self.names.remove(&old_name);
+            self.lru.remove(&access_seq);
+        }
+
+        self.names.insert(name.clone(), index);
+        self.indexes.insert(index, (name, 0));
+        self.touch(index);
+        self.dirty = true;
+    }
+}
+
+/// Bidirectional mappings between names and fake IPs
+pub struct FakeIpPool {
+    ipv4_range: Ipv4Net,
+    ipv6_range: Option<Ipv6Net>,
+    ttl: Duration,
+    capacity: u32,
+    inner: SpinMutex<PoolInner>,
+    state_store: Arc<dyn StateStore>,
+}
+
+impl FakeIpPool {
+    /// Create a pool, and restore names allocated before from `state_store`
+    pub fn new(config: &FakeIpConfig, state_store: Arc<dyn StateStore>) -> FakeIpPool {
+        // Network and broadcast addresses are never allocated
+        let ipv4_hosts = (1u64 << (32 - config.ipv4_range.prefix_len())).saturating_sub(2);
+        let mut capacity = ipv4_hosts.min(u32::MAX as u64) as u32;
+        if let Some(ref ipv6_range) = config.ipv6_range {
+            let ipv6_bits = 128 - ipv6_range.prefix_len() as u32;
+            if ipv6_bits < 32 {
+                let ipv6_hosts = ((1u64 << ipv6_bits) - 1) as u32;
+                capacity = capacity.min(ipv6_hosts);
+            }
+        }
+
+        let pool = FakeIpPool {
+            ipv4_range: config.ipv4_range.trunc(),
+            ipv6_range: config.ipv6_range.map(|r| r.trunc()),
+            ttl: config.ttl,
+            capacity,
+            inner: SpinMutex::new(PoolInner {
+                names: HashMap::new(),
+                indexes: HashMap::new(),
+                lru: BTreeMap::new(),
+                access_seq: 0,
+                next_index: 0,
+                dirty: false,
+            }),
+            state_store,
+        };
+
+        if let Err(err) = pool.load() {
+            warn!("failed to load fake IP mappings, error: {}", err);
+        }
+
+        pool
+    }
+
+    /// TTL of answers with fake IPs
+    pub fn ttl(&self) -> Duration {
+        self.ttl
+    }
+
+    /// Check if IPv6 addresses could be allocated
+    pub fn has_ipv6(&self) -> bool {
+        self.ipv6_range.is_some()
+    }
+
+    /// Allocate fake IPs for `name`, or get the ones allocated before
+    pub fn allocate(&self, name: &str) -> (Ipv4Addr, Option<Ipv6Addr>) {
+        let name = normalize_name(name);
+
+        let mut inner = self.inner.lock();
+
+        let index = match inner.names.get(&name) {
+            Some(index) => {
+                let index = *index;
+                inner.touch(index);
+                index
+            }
+            None => {
+                let index = if inner.next_index < self.capacity {
+                    let index = inner.next_index;
+                    inner.next_index += 1;
+                    index
+                } else {
+                    // Pool exhausted, recycle the least recently used one
+                    let (_, index) = inner.lru.iter().next().expect("fake IP pool is empty");
+                    *index
+                };
+
+                trace!("fake IP #{} allocated for {}", index, name);
+                inner.insert(index, name);
+                index
+            }
+        };
+
+        (self.ipv4_addr(index), self.ipv6_addr(index))
+    }
+
+    /// Get the name which `addr` was allocated for
+    pub fn lookup(&self, addr: &IpAddr) -> Option<String> {
+        let index = match *addr {
+            IpAddr::V4(ref addr) => {
+                if !self.ipv4_range.contains(addr) {
+                    return None;
+                }
+                let offset = u32::from(*addr) - u32::from(self.ipv4_range.network());
+                if offset == 0 {
+                    return None;
+                }
+                offset - 1
+            }
+            IpAddr::V6(ref addr) => match self.ipv6_range {
+                Some(ref range) if range.contains(addr) => {
+                    let offset = u128::from(*addr).wrapping_sub(u128::from(range.network()));
+                    if offset == 0 || offset > u32::MAX as u128 {
+                        return None;
+                    }
+                    (offset - 1) as u32
+                }
+                _ => return None,
+            },
+        };
+
+        let inner = self.inner.lock();
+        inner.indexes.get(&index).map(|(name, _)| name.clone())
+    }
+
+    fn ipv4_addr(&self, index: u32) -> Ipv4Addr {
+        Ipv4Addr::from(u32::from(self.ipv4_range.network()) + index + 1)
+    }
+
+    fn ipv6_addr(&self, index: u32) -> Option<Ipv6Addr> {
+        self.ipv6_range
+            .as_ref()
+            .map(|range| Ipv6Addr::from(u128::from(range.network()) + index as u128 + 1))
+    }
+
+    /// Names are saved as lines of `INDEX NAME`, from the least to the most recently used
+    fn load(&self) -> io::Result<()> {
+        let value = match self.state_store.load(FAKE_IP_STATE_KEY)? {
+            Some(v) => v,
+            None => return Ok(()),
+        };
+
+        let mut inner = self.inner.lock();
+
+        for line in value.split(|b| *b == b'\n') {
+            let line = match str::from_utf8(line) {
+                Ok(l) => l.trim(),
+                Err(..) => continue,
+            };
+            let (index, name) = match line.split_once(' ') {
+                Some((i, n)) => match i.parse::<u32>() {
+                    Ok(i) if i < self.capacity && !n.is_empty() => (i, n),
+                    _ => continue,
+                },
+                None => continue,
+            };
+
+            inner.insert(index, normalize_name(name));
+            if index >= inner.next_index {
+                inner.next_index = index + 1;
+            }
+        }
+        inner.dirty = false;
+
+        debug!("loaded {} fake IP mappings", inner.indexes.len());
+
+        Ok(())
+    }
+
+    fn save(&self) -> io::Result<()> {
+        let mut value = Vec::new();
+        {
+            let mut inner = self.inner.lock();
+            if !inner.dirty {
+                return Ok(());
+            }
+            inner.dirty = false;
+
+            for index in inner.lru.values() {
+                if let Some((ref name, ..)) = inner.indexes.get(index) {
+                    value.extend_from_slice(index.to_string().as_bytes());
+                    value.push(b' ');
+                    value.extend_from_slice(name.as_bytes());
+                    value.push(b'\n');
+                }
+            }
+        }
+
+        self.state_store.save(FAKE_IP_STATE_KEY, &value)
+    }
+
+    /// Save allocated names to `StateStore` periodically
+    pub async fn run_persistence(self: Arc<Self>) -> io::Result<()> {
+        let mut interval = time::interval(FAKE_IP_SAVE_INTERVAL);
+        loop {
+            interval.tick().await;
+            if let Err(err) = self.save() {
+                warn!("failed to save fake IP mappings, error: {}", err);
+            }
+        }
+    }
+}
+
+fn normalize_name(name: &str) -> String {
+    name.trim_end_matches('.').to_ascii_lowercase()
+}
@@ -0,0 +1,181 @@
+//! Static records
+//!
+//! Names configured in `dns_hosts` or listed in the hosts file are answered by the DNS local itself, so lab and
+//! split-horizon names could be resolved without another resolver in front.
+//!
+//! A name with static records is owned by them, queries of types without records are answered with NODATA instead of
+//! being sent to upstreams. CNAMEs are followed in static records, and targets without static records are left to
+//! upstreams.
+
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{self, BufRead, BufReader},
+    net::IpAddr,
+    path::Path,
+    str::FromStr,
+    time::Duration,
+};
+
+use log::{trace, warn};
+use trust_dns_resolver::proto::{
+    op::Query,
+    rr::{rdata::TXT, DNSClass, Name, RData, Record, RecordType},
+};
+
+use super::config::{DnsHostsConfig, StaticRecordData};
+
+/// CNAMEs are followed at most this many times
+const MAX_CNAME_DEPTH: usize = 8;
+
+/// Default TTL of answers
+const DEFAULT_TTL: Duration = Duration::from_secs(60);
+
+/// Answer of static records
+pub struct HostsAnswer {
+    /// Answer records, empty for NODATA
+    pub records: Vec<Record>,
+    /// Target of the last CNAME, which doesn't have static records and should be resolved by upstreams
+    pub alias: Option<Name>,
+}
+
+/// Static records of names
+pub struct DnsHosts {
+    names: HashMap<String, Vec<RData>>,
+    ttl: u32,
+}
+
+impl DnsHosts {
+    /// Create from `config`, reading the hosts file if configured
+    pub fn new(config: &DnsHostsConfig) -> io::Result<DnsHosts> {
+        let mut hosts = DnsHosts {
+            names: HashMap::new(),
+            ttl: config.ttl.unwrap_or(DEFAULT_TTL).as_secs() as u32,
+        };
+
+        if let Some(ref path) = config.hosts_file {
+            hosts.load_hosts_file(path)?;
+        }
+
+        // Configured records take precedence over the hosts file
+        let mut configured: HashMap<String, Vec<RData>> = HashMap::new();
+        for record in &config.records {
+            let rdata = match record.data {
+                StaticRecordData::A(ip) => RData::A(ip),
+                StaticRecordData::Aaaa(ip) => RData::AAAA(ip),
+                StaticRecordData::Cname(ref name) => match Name::from_str(name) {
+                    Ok(mut name) => {
+                        name.set_fqdn(true);
+                        RData::CNAME(name)
+                    }
+                    Err(err) => {
+                        let err = io::Error::new(
+                            io::ErrorKind::InvalidInput,
+                            format!("invalid CNAME {} of {}, error: {}", name, record.name, err),
+                        );
+                        return Err(err);
+                    }
+                },
+                StaticRecordData::Txt(ref text) => RData::TXT(TXT::new(vec![text.clone()])),
+            };
+            configured.entry(normalize_name(&record.name)).or_default().push(rdata);
+        }
+        hosts.names.extend(configured);
+
+        Ok(hosts)
+    }
+
+    fn load_hosts_file(&mut self, path: &Path) -> io::Result<()> {
+        let file = match File::open(path) {
+            Ok(f) => f,
+            Err(err) => {
+                let err = io::Error::new(
+                    err.kind(),
+                    format!("failed to open hosts file {}, error: {}", path.display(), err),
+                );
+                return Err(err);
+            }
+        };
+
+        for line in BufReader::new(file).lines() {
+            let line = line?;
+            let line = match line.find('#') {
+                Some(pos) => &line[..pos],
+                None => &line,
+            };
+
+            let mut fields = line.split_whitespace();
+            let ip = match fields.next() {
+                Some(ip) => ip,
+                None => continue,
+            };
+            let rdata = match ip.parse::<IpAddr>() {
+                Ok(IpAddr::V4(ip)) => RData::A(ip),
+                Ok(IpAddr::V6(ip)) => RData::AAAA(ip),
+                Err(..) => {
+                    warn!("hosts file {} invalid address {}, ignored", path.display(), ip);
+                    continue;
+                }
+            };
+
+            for name in fields {
+                self.names.entry(normalize_name(name)).or_default().push(rdata.clone());
+            }
+        }
+
+        trace!("loaded {} names from hosts file {}", self.names.len(), path.display());
+
+        Ok(())
+    }
+
+    /// Answer `query` if its name has static records
+    pub fn lookup(&self, query: &Query) -> Option<HostsAnswer> {
+        if query.query_class() != DNSClass::IN {
+            return None;
+        }
+
+        let query_type = query.query_type();
+        let mut name = query.name().clone();
+        let mut rdatas = self.names.get(&normalize_name(&name.to_ascii()))?;
+        let mut records = Vec::new();
+
+        for _ in 0..MAX_CNAME_DEPTH {
+            let cname = rdatas.iter().find_map(|rdata| match *rdata {
+                RData::CNAME(ref target) => Some(target),
+                _ => None,
+            });
+
+            match cname {
+                Some(target) if query_type != RecordType::CNAME => {
+                    records.push(Record::from_rdata(name, self.ttl, RData::CNAME(target.clone())));
+                    name = target.clone();
+
+                    rdatas = match self.names.get(&normalize_name(&name.to_ascii())) {
+                        Some(rdatas) => rdatas,
+                        None => {
+                            return Some(HostsAnswer {
+                                records,
+                                alias: Some(name),
+                            });
+                        }
+                    };
+                }
+                _ => {
+                    for rdata in rdatas {
+                        if query_type.is_any() || rdata.to_record_type() == query_type {
+                            records.push(Record::from_rdata(name.clone(), self.ttl, rdata.clone()));
+                        }
+                    }
+                    return Some(HostsAnswer { records, alias: None });
+                }
+            }
+        }
+
+        warn!("DNS static records of {} have too many CNAMEs", query.name());
+        Some(HostsAnswer { records, alias: None })
+    }
+}
+
+fn normalize_name(name: &str) -> String {
+    name.trim_end_matches('.').to_ascii_lowercase()
+}
@@ -1,9 +1,36 @@
 //! Customized DNS resolver
 
-pub use self::{config::NameServerAddr, server::Dns};
+#[cfg(feature = "local-dns-over-https")]
+pub use self::config::DnsOverHttpsAddr;
+#[cfg(any(feature = "local-dns-over-tls", feature = "local-dns-over-quic"))]
+pub use self::config::{SecureDnsProtocol, SecureNameServerAddr};
+pub use self::{
+    cache::DnsCacheStat,
+    config::{
+        DnsCacheConfig,
+        DnsHostsConfig,
+        DnssecAdPolicy,
+        DnssecConfig,
+        FakeIpConfig,
+        NameServerAddr,
+        NameServerStrategy,
+        StaticRecordConfig,
+        StaticRecordData,
+    },
+    fake_ip::FakeIpPool,
+    server::Dns,
+};
 
+mod cache;
 mod client_cache;
 pub mod config;
 pub mod dns_resolver;
+#[cfg(feature = "local-dns-dnssec")]
+mod dnssec;
+pub mod fake_ip;
+mod hosts;
+mod name_server_set;
 pub mod server;
+#[cfg(feature = "local-dns-tls")]
+mod tls_server;
 mod upstream;
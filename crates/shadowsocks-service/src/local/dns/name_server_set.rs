@@ -0,0 +1,158 @@
+//! Multiple local name servers, queried by `NameServerStrategy`
+//!
+//! Health of each name server is tracked, so a slow or broken name server won't degrade all lookups. Name servers
+//! failing continuously are marked down for a while, and are only queried if all the others have failed.
+
+use std::{
+    io::{self, ErrorKind},
+    sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering},
+    time::{Duration, Instant},
+};
+
+use futures::future::{self, FutureExt};
+use log::{debug, warn};
+use spin::Mutex as SpinMutex;
+use trust_dns_resolver::proto::op::{Message, Query};
+
+use super::{
+    config::{NameServerAddr, NameServerStrategy},
+    server::DnsClient,
+};
+
+/// Name server is marked down after this number of continuous failures
+const MAX_CONTINUOUS_FAILURES: u32 = 3;
+
+/// Name server marked down is skipped for this duration, then it is queried again on probation
+const DOWN_DURATION: Duration = Duration::from_secs(30);
+
+struct NameServerStat {
+    /// Smoothed response time in microseconds, 0 before the first response
+    srtt: AtomicU64,
+    failures: AtomicU32,
+    down_until: SpinMutex<Option<Instant>>,
+}
+
+impl NameServerStat {
+    fn new() -> NameServerStat {
+        NameServerStat {
+            srtt: AtomicU64::new(0),
+            failures: AtomicU32::new(0),
+            down_until: SpinMutex::new(None),
+        }
+    }
+
+    fn is_healthy(&self, now: Instant) -> bool {
+        match *self.down_until.lock() {
+            Some(until) => now >= until,
+            None => true,
+        }
+    }
+}
+
+/// Local name servers with health tracking
+pub(super) struct NameServerSet {
+    servers: Vec<(NameServerAddr, NameServerStat)>,
+    strategy: NameServerStrategy,
+    next: AtomicUsize,
+}
+
+impl NameServerSet {
+    pub fn new(servers: Vec<NameServerAddr>, strategy: NameServerStrategy) -> NameServerSet {
+        NameServerSet {
+            servers: servers.into_iter().map(|ns| (ns, NameServerStat::new())).collect(),
+            strategy,
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// Query name servers by strategy, unhealthy name servers are tried in order after all healthy ones failed
+    pub async fn lookup(&self, client: &DnsClient, query: &Query) -> io::Result<Message> {
+        let (healthy, unhealthy) = self.ordered_servers();
+        let mut last_err = io::Error::new(ErrorKind::InvalidData, "resolve empty");
+
+        if self.strategy == NameServerStrategy::Race && healthy.len() > 1 {
+            let queries = healthy
+                .iter()
+                .map(|&idx| self.lookup_server(client, query, idx).boxed());
+            match future::select_ok(queries).await {
+                Ok((m, ..)) => return Ok(m),
+                Err(err) => last_err = err,
+            }
+        } else {
+            for idx in healthy {
+                match self.lookup_server(client, query, idx).await {
+                    Ok(m) => return Ok(m),
+                    Err(err) => last_err = err,
+                }
+            }
+        }
+
+        for idx in unhealthy {
+            match self.lookup_server(client, query, idx).await {
+                Ok(m) => return Ok(m),
+                Err(err) => last_err = err,
+            }
+        }
+
+        Err(last_err)
+    }
+
+    /// Indices of healthy name servers in the order of strategy, and indices of unhealthy name servers
+    fn ordered_servers(&self) -> (Vec<usize>, Vec<usize>) {
+        let now = Instant::now();
+        let (mut healthy, unhealthy): (Vec<usize>, Vec<usize>) =
+            (0..self.servers.len()).partition(|&idx| self.servers[idx].1.is_healthy(now));
+
+        match self.strategy {
+            NameServerStrategy::Failover | NameServerStrategy::Race => {}
+            NameServerStrategy::RoundRobin => {
+                if !healthy.is_empty() {
+                    let n = self.next.fetch_add(1, Ordering::Relaxed) % healthy.len();
+                    healthy.rotate_left(n);
+                }
+            }
+            NameServerStrategy::Fastest => {
+                // Name servers never responded have 0 response time, so they are measured first
+                healthy.sort_by_key(|&idx| self.servers[idx].1.srtt.load(Ordering::Relaxed));
+            }
+        }
+
+        (healthy, unhealthy)
+    }
+
+    async fn lookup_server(&self, client: &DnsClient, query: &Query, idx: usize) -> io::Result<Message> {
+        let (ref ns, ref stat) = self.servers[idx];
+
+        let start = Instant::now();
+        match client.lookup_local(query, ns).await {
+            Ok(m) => {
+                let elapsed = start.elapsed().as_micros() as u64;
+                let srtt = stat.srtt.load(Ordering::Relaxed);
+                let srtt = if srtt == 0 { elapsed } else { (srtt * 7 + elapsed) / 8 };
+                stat.srtt.store(srtt.max(1), Ordering::Relaxed);
+
+                stat.failures.store(0, Ordering::Relaxed);
+                if stat.down_until.lock().take().is_some() {
+                    debug!("DNS name server {} recovered", ns);
+                }
+                Ok(m)
+            }
+            Err(err) => {
+                let failures = stat.failures.fetch_add(1, Ordering::Relaxed) + 1;
+                debug!("DNS name server {} failed {} times, error: {}", ns, failures, err);
+                // Name servers on probation are marked down again by a single failure
+                let now = Instant::now();
+                if failures >= MAX_CONTINUOUS_FAILURES && stat.is_healthy(now) {
+                    warn!(
+                        "DNS name server {} is marked down for {}s after {} continuous failures",
+                        ns,
+                        DOWN_DURATION.as_secs(),
+                        failures
+                    );
+                    *stat.down_until.lock() = Some(now + DOWN_DURATION);
+                }
+                Err(err)
+            }
+        }
+    }
+}
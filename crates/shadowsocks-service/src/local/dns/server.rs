@@ -4,7 +4,7 @@
 
 use std::{
     cmp::Ordering,
-    collections::HashSet,
+    collections::{HashMap, HashSet},
     io::{self, ErrorKind},
     net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
     str::FromStr,
@@ -14,17 +14,19 @@ use std::{
 
 use byteorder::{BigEndian, ByteOrder};
 use bytes::{BufMut, BytesMut};
-use futures::future::{self, Either};
+use futures::future::{self, Either, FutureExt};
 use log::{debug, error, info, trace, warn};
 use rand::{thread_rng, Rng};
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::{TcpStream, UdpSocket},
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::UdpSocket,
     time,
 };
+#[cfg(feature = "local-dns-tls")]
+use tokio_rustls::TlsAcceptor;
 use trust_dns_resolver::proto::{
     op::{header::MessageType, response_code::ResponseCode, Message, OpCode, Query},
-    rr::{DNSClass, Name, RData, RecordType},
+    rr::{DNSClass, Name, RData, Record, RecordType},
 };
 
 use shadowsocks::{
@@ -36,18 +38,56 @@ use shadowsocks::{
 };
 
 use crate::{
-    acl::AccessControl,
+    acl::{AccessControl, RejectMode},
     local::{context::ServiceContext, loadbalancing::PingBalancer},
 };
 
-use super::{client_cache::DnsClientCache, config::NameServerAddr};
+#[cfg(feature = "local-dns-over-https")]
+use super::config::DnsOverHttpsAddr;
+#[cfg(any(feature = "local-dns-over-tls", feature = "local-dns-over-quic"))]
+use super::config::{SecureDnsProtocol, SecureNameServerAddr};
+#[cfg(feature = "local-dns-tls")]
+use super::tls_server;
+use super::{
+    cache::DnsResponseCache,
+    client_cache::DnsClientCache,
+    config::{DnsCacheConfig, DnsHostsConfig, NameServerAddr, NameServerStrategy},
+    fake_ip::FakeIpPool,
+    hosts::{DnsHosts, HostsAnswer},
+    name_server_set::NameServerSet,
+};
+#[cfg(feature = "local-dns-dnssec")]
+use super::{
+    config::DnssecConfig,
+    dnssec::{self, DnssecValidator, Validation},
+};
 
 /// DNS Relay server
 pub struct Dns {
     context: Arc<ServiceContext>,
     mode: Mode,
     local_addr: Arc<NameServerAddr>,
+    local_extra_addrs: Vec<NameServerAddr>,
+    local_strategy: NameServerStrategy,
     remote_addr: Arc<Address>,
+    #[cfg(feature = "local-dns-over-https")]
+    remote_https: Option<DnsOverHttpsAddr>,
+    #[cfg(any(feature = "local-dns-over-tls", feature = "local-dns-over-quic"))]
+    remote_secure: Option<SecureNameServerAddr>,
+    local_timeout: Option<Duration>,
+    remote_timeout: Option<Duration>,
+    cache: Option<DnsCacheConfig>,
+    fake_ip_pool: Option<Arc<FakeIpPool>>,
+    upstreams: HashMap<String, Vec<NameServerAddr>>,
+    hosts: Option<DnsHostsConfig>,
+    #[cfg(feature = "local-dns-dnssec")]
+    dnssec: Option<DnssecConfig>,
+    #[cfg(feature = "local-dns-tls")]
+    tls_listener: Option<(ServerAddr, TlsAcceptor)>,
+    #[cfg(feature = "local-dns-tls")]
+    https_listener: Option<(ServerAddr, TlsAcceptor)>,
+    tcp_listener: Option<TcpListener>,
+    udp_socket: Option<ShadowUdpSocket>,
 }
 
 impl Dns {
@@ -63,35 +103,227 @@ impl Dns {
             context,
             mode: Mode::UdpOnly,
             local_addr: Arc::new(local_addr),
+            local_extra_addrs: Vec::new(),
+            local_strategy: NameServerStrategy::default(),
             remote_addr: Arc::new(remote_addr),
+            #[cfg(feature = "local-dns-over-https")]
+            remote_https: None,
+            #[cfg(any(feature = "local-dns-over-tls", feature = "local-dns-over-quic"))]
+            remote_secure: None,
+            local_timeout: None,
+            remote_timeout: None,
+            cache: None,
+            fake_ip_pool: None,
+            upstreams: HashMap::new(),
+            hosts: None,
+            #[cfg(feature = "local-dns-dnssec")]
+            dnssec: None,
+            #[cfg(feature = "local-dns-tls")]
+            tls_listener: None,
+            #[cfg(feature = "local-dns-tls")]
+            https_listener: None,
+            tcp_listener: None,
+            udp_socket: None,
         }
     }
 
+    /// Send queries through proxy to a DNS-over-HTTPS server, instead of the plain remote DNS
+    ///
+    /// Remote address will be replaced by `addr`'s server address.
+    #[cfg(feature = "local-dns-over-https")]
+    pub fn set_remote_dns_over_https(&mut self, addr: DnsOverHttpsAddr) {
+        self.remote_addr = Arc::new(addr.server_addr());
+        self.remote_https = Some(addr);
+    }
+
+    /// Send queries through proxy to a DNS-over-TLS or DNS-over-QUIC server, instead of the plain remote DNS
+    ///
+    /// Remote address will be replaced by `addr`'s server address.
+    #[cfg(any(feature = "local-dns-over-tls", feature = "local-dns-over-quic"))]
+    pub fn set_remote_dns_secure(&mut self, addr: SecureNameServerAddr) {
+        self.remote_addr = Arc::new(addr.server_addr());
+        self.remote_secure = Some(addr);
+    }
+
+    /// Also send direct queries to `addrs`, along with the local DNS
+    pub fn set_local_dns_extra_addrs(&mut self, addrs: Vec<NameServerAddr>) {
+        self.local_extra_addrs = addrs;
+    }
+
+    /// Set strategy of querying the local DNS and the extra name servers, `NameServerStrategy::Failover` by default
+    pub fn set_local_dns_strategy(&mut self, strategy: NameServerStrategy) {
+        self.local_strategy = strategy;
+    }
+
+    /// Set timeout of each query to local DNS, 5 seconds by default
+    pub fn set_local_dns_timeout(&mut self, t: Duration) {
+        self.local_timeout = Some(t);
+    }
+
+    /// Set timeout of each query to remote DNS, 5 seconds by default
+    pub fn set_remote_dns_timeout(&mut self, t: Duration) {
+        self.remote_timeout = Some(t);
+    }
+
+    /// Cache responses of both local and remote DNS
+    pub fn set_cache(&mut self, cache: DnsCacheConfig) {
+        self.cache = Some(cache);
+    }
+
+    /// Set DNS upstream groups, names matching ACL's `[dns:GROUP]` sections are resolved by name servers of `GROUP`
+    ///
+    /// Name servers of a group are tried in order, and queries are sent directly without proxies.
+    pub fn set_upstreams(&mut self, upstreams: HashMap<String, Vec<NameServerAddr>>) {
+        self.upstreams = upstreams;
+    }
+
+    /// Answer A and AAAA queries of names that should be proxied with fake IPs allocated from `pool`
+    ///
+    /// Set the same `pool` to `ServiceContext` of the other locals for recovering the names.
+    pub fn set_fake_ip_pool(&mut self, pool: Arc<FakeIpPool>) {
+        self.fake_ip_pool = Some(pool);
+    }
+
+    /// Answer names with static records, and addresses in the hosts file, instead of consulting upstreams
+    pub fn set_hosts(&mut self, hosts: DnsHostsConfig) {
+        self.hosts = Some(hosts);
+    }
+
+    /// Validate DNSSEC signatures of answers from upstreams
+    #[cfg(feature = "local-dns-dnssec")]
+    pub fn set_dnssec(&mut self, dnssec: DnssecConfig) {
+        self.dnssec = Some(dnssec);
+    }
+
+    /// Also accept DNS-over-TLS queries on `bind_addr`
+    ///
+    /// `acceptor` should negotiate `dot` with ALPN.
+    #[cfg(feature = "local-dns-tls")]
+    pub fn set_dns_over_tls(&mut self, bind_addr: ServerAddr, acceptor: TlsAcceptor) {
+        self.tls_listener = Some((bind_addr, acceptor));
+    }
+
+    /// Also accept DNS-over-HTTPS queries on `bind_addr`, at path `/dns-query`
+    ///
+    /// `acceptor` should negotiate `h2` and `http/1.1` with ALPN.
+    #[cfg(feature = "local-dns-tls")]
+    pub fn set_dns_over_https(&mut self, bind_addr: ServerAddr, acceptor: TlsAcceptor) {
+        self.https_listener = Some((bind_addr, acceptor));
+    }
+
     /// Set remote server mode
     pub fn set_mode(&mut self, mode: Mode) {
         self.mode = mode;
     }
 
-    /// Run server
-    pub async fn run(self, bind_addr: &ServerAddr, balancer: PingBalancer) -> io::Result<()> {
-        let client = Arc::new(DnsClient::new(self.context.clone(), balancer, self.mode));
-
-        let tcp_fut = self.run_tcp_server(bind_addr, client.clone());
-        let udp_fut = self.run_udp_server(bind_addr, client);
+    /// Serve TCP on a listener bound to the address of `run`, instead of binding it
+    pub fn set_tcp_listener(&mut self, listener: TcpListener) {
+        self.tcp_listener = Some(listener);
+    }
 
-        tokio::pin!(tcp_fut, udp_fut);
+    /// Serve UDP on a socket bound to the address of `run`, instead of binding it
+    pub fn set_udp_socket(&mut self, socket: ShadowUdpSocket) {
+        self.udp_socket = Some(socket);
+    }
 
-        match future::select(tcp_fut, udp_fut).await {
-            Either::Left((res, ..)) => res,
-            Either::Right((res, ..)) => res,
+    /// Run server
+    pub async fn run(mut self, bind_addr: &ServerAddr, balancer: PingBalancer) -> io::Result<()> {
+        let tcp_listener = self.tcp_listener.take();
+        let udp_socket = self.udp_socket.take();
+
+        let mut client = DnsClient::new(self.context.clone(), balancer, self.mode);
+        #[cfg(feature = "local-dns-over-https")]
+        if let Some(ref remote_https) = self.remote_https {
+            info!("shadowsocks dns remote queries are sent to {}", remote_https);
+            client.remote_https = Some(remote_https.clone());
+        }
+        #[cfg(any(feature = "local-dns-over-tls", feature = "local-dns-over-quic"))]
+        if let Some(ref remote_secure) = self.remote_secure {
+            info!("shadowsocks dns remote queries are sent to {}", remote_secure);
+            client.remote_secure = Some(remote_secure.clone());
+        }
+        if !self.local_extra_addrs.is_empty() {
+            let mut local_addrs = vec![self.local_addr.as_ref().clone()];
+            local_addrs.extend(self.local_extra_addrs.iter().cloned());
+            info!(
+                "shadowsocks dns local queries are sent to {} name servers, strategy: {}",
+                local_addrs.len(),
+                self.local_strategy
+            );
+            client.local_servers = Some(NameServerSet::new(local_addrs, self.local_strategy));
+        }
+        if let Some(t) = self.local_timeout {
+            client.local_timeout = t;
+        }
+        if let Some(t) = self.remote_timeout {
+            client.remote_timeout = t;
+        }
+        if let Some(ref cache) = self.cache {
+            client.cache = Some(DnsResponseCache::new(cache));
         }
+        client.fake_ip_pool = self.fake_ip_pool.clone();
+        client.upstreams = self.upstreams.clone();
+        if let Some(ref hosts) = self.hosts {
+            client.hosts = Some(DnsHosts::new(hosts)?);
+        }
+        #[cfg(feature = "local-dns-dnssec")]
+        if let Some(ref dnssec) = self.dnssec {
+            client.dnssec = Some(DnssecValidator::new(dnssec)?);
+        }
+        let client = Arc::new(client);
+
+        #[allow(unused_mut)]
+        let mut vfut = vec![
+            self.run_tcp_server(bind_addr, tcp_listener, client.clone()).boxed(),
+            self.run_udp_server(bind_addr, udp_socket, client.clone()).boxed(),
+        ];
+
+        #[cfg(feature = "local-dns-tls")]
+        if let Some((ref tls_addr, ref acceptor)) = self.tls_listener {
+            vfut.push(
+                tls_server::run_tls_server(
+                    &self.context,
+                    tls_addr,
+                    acceptor.clone(),
+                    client.clone(),
+                    self.local_addr.clone(),
+                    self.remote_addr.clone(),
+                )
+                .boxed(),
+            );
+        }
+        #[cfg(feature = "local-dns-tls")]
+        if let Some((ref https_addr, ref acceptor)) = self.https_listener {
+            vfut.push(
+                tls_server::run_https_server(
+                    &self.context,
+                    https_addr,
+                    acceptor.clone(),
+                    client.clone(),
+                    self.local_addr.clone(),
+                    self.remote_addr.clone(),
+                )
+                .boxed(),
+            );
+        }
+
+        let (res, ..) = future::select_all(vfut).await;
+        res
     }
 
-    async fn run_tcp_server(&self, bind_addr: &ServerAddr, client: Arc<DnsClient>) -> io::Result<()> {
-        let listener = match *bind_addr {
-            ServerAddr::SocketAddr(ref saddr) => TcpListener::bind_with_opts(saddr, self.context.accept_opts()).await?,
-            ServerAddr::DomainName(ref dname, port) => {
-                lookup_then!(self.context.context_ref(), dname, port, |addr| {
+    async fn run_tcp_server(
+        &self,
+        bind_addr: &ServerAddr,
+        listener: Option<TcpListener>,
+        client: Arc<DnsClient>,
+    ) -> io::Result<()> {
+        let listener = match (listener, bind_addr) {
+            (Some(listener), ..) => listener,
+            (None, ServerAddr::SocketAddr(saddr)) => {
+                TcpListener::bind_with_opts(saddr, self.context.accept_opts()).await?
+            }
+            (None, ServerAddr::DomainName(dname, port)) => {
+                lookup_then!(self.context.context_ref(), dname, *port, |addr| {
                     TcpListener::bind_with_opts(&addr, self.context.accept_opts()).await
                 })?
                 .1
@@ -125,13 +357,17 @@ impl Dns {
         }
     }
 
-    async fn handle_tcp_stream(
+    /// Serve queries of a stream, in which messages are prefixed with 2 bytes length (TCP and DNS-over-TLS)
+    pub(super) async fn handle_tcp_stream<S>(
         client: Arc<DnsClient>,
-        mut stream: TcpStream,
+        mut stream: S,
         peer_addr: SocketAddr,
         local_addr: Arc<NameServerAddr>,
         remote_addr: Arc<Address>,
-    ) -> io::Result<()> {
+    ) -> io::Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
         let mut length_buf = [0u8; 2];
         let mut message_buf = BytesMut::new();
         loop {
@@ -172,6 +408,10 @@ impl Dns {
 
             let respond_message = match client.resolve(message, &local_addr, &remote_addr).await {
                 Ok(m) => m,
+                Err(err) if err.kind() == ErrorKind::PermissionDenied => {
+                    debug!("dns tcp {} query not answered, {}", peer_addr, err);
+                    continue;
+                }
                 Err(err) => {
                     error!("dns tcp {} lookup error: {}", peer_addr, err);
                     return Err(err);
@@ -192,11 +432,17 @@ impl Dns {
         Ok(())
     }
 
-    async fn run_udp_server(&self, bind_addr: &ServerAddr, client: Arc<DnsClient>) -> io::Result<()> {
-        let socket = match *bind_addr {
-            ServerAddr::SocketAddr(ref saddr) => ShadowUdpSocket::listen(saddr).await?,
-            ServerAddr::DomainName(ref dname, port) => {
-                lookup_then!(self.context.context_ref(), dname, port, |addr| {
+    async fn run_udp_server(
+        &self,
+        bind_addr: &ServerAddr,
+        socket: Option<ShadowUdpSocket>,
+        client: Arc<DnsClient>,
+    ) -> io::Result<()> {
+        let socket = match (socket, bind_addr) {
+            (Some(socket), ..) => socket,
+            (None, ServerAddr::SocketAddr(saddr)) => ShadowUdpSocket::listen(saddr).await?,
+            (None, ServerAddr::DomainName(dname, port)) => {
+                lookup_then!(self.context.context_ref(), dname, *port, |addr| {
                     ShadowUdpSocket::listen(&addr).await
                 })?
                 .1
@@ -255,6 +501,10 @@ impl Dns {
     ) -> io::Result<()> {
         let respond_message = match client.resolve(message, &local_addr, &remote_addr).await {
             Ok(m) => m,
+            Err(err) if err.kind() == ErrorKind::PermissionDenied => {
+                debug!("dns udp {} query not answered, {}", peer_addr, err);
+                return Ok(());
+            }
             Err(err) => {
                 error!("dns udp {} lookup failed, error: {}", peer_addr, err);
                 return Err(err);
@@ -350,9 +600,9 @@ fn should_forward_by_query(context: &ServiceContext, balancer: &PingBalancer, qu
             // unconditionally use default for all non-IN queries
             Some(acl.is_default_in_proxy_list())
         } else if query.query_type() == RecordType::PTR {
-            Some(should_forward_by_ptr_name(acl, query.name()))
+            Some(should_forward_by_ptr_name(&acl, query.name()))
         } else {
-            let result = check_name_in_proxy_list(acl, query.name());
+            let result = check_name_in_proxy_list(&acl, query.name());
             if result.is_none() && acl.is_ip_empty() && acl.is_host_empty() {
                 Some(acl.is_default_in_proxy_list())
             } else {
@@ -445,12 +695,25 @@ fn should_forward_by_response(
     }
 }
 
-struct DnsClient {
+pub(super) struct DnsClient {
     context: Arc<ServiceContext>,
     client_cache: DnsClientCache,
     mode: Mode,
     balancer: PingBalancer,
     attempts: usize,
+    #[cfg(feature = "local-dns-over-https")]
+    remote_https: Option<DnsOverHttpsAddr>,
+    #[cfg(any(feature = "local-dns-over-tls", feature = "local-dns-over-quic"))]
+    remote_secure: Option<SecureNameServerAddr>,
+    local_servers: Option<NameServerSet>,
+    local_timeout: Duration,
+    remote_timeout: Duration,
+    cache: Option<DnsResponseCache>,
+    fake_ip_pool: Option<Arc<FakeIpPool>>,
+    upstreams: HashMap<String, Vec<NameServerAddr>>,
+    hosts: Option<DnsHosts>,
+    #[cfg(feature = "local-dns-dnssec")]
+    dnssec: Option<DnssecValidator>,
 }
 
 impl DnsClient {
@@ -461,14 +724,27 @@ impl DnsClient {
             mode,
             balancer,
             attempts: 2,
+            #[cfg(feature = "local-dns-over-https")]
+            remote_https: None,
+            #[cfg(any(feature = "local-dns-over-tls", feature = "local-dns-over-quic"))]
+            remote_secure: None,
+            local_servers: None,
+            local_timeout: Duration::from_secs(5),
+            remote_timeout: Duration::from_secs(5),
+            cache: None,
+            fake_ip_pool: None,
+            upstreams: HashMap::new(),
+            hosts: None,
+            #[cfg(feature = "local-dns-dnssec")]
+            dnssec: None,
         }
     }
 
-    async fn resolve(
-        &self,
+    pub(super) async fn resolve(
+        self: &Arc<Self>,
         request: Message,
-        local_addr: &NameServerAddr,
-        remote_addr: &Address,
+        local_addr: &Arc<NameServerAddr>,
+        remote_addr: &Arc<Address>,
     ) -> io::Result<Message> {
         let mut message = Message::new();
         message.set_id(request.id());
@@ -485,10 +761,69 @@ impl DnsClient {
             // Other ops are not supported
 
             message.set_response_code(ResponseCode::NotImp);
+        } else if let Some(mode) = self.rejected_by_query(&request) {
+            // Names rejected by ACL's [reject:MODE] sections
+
+            match mode {
+                RejectMode::Drop => {
+                    return Err(io::Error::new(
+                        ErrorKind::PermissionDenied,
+                        format!("{} is dropped by ACL", request.queries()[0].name()),
+                    ));
+                }
+                RejectMode::Reset => message.set_response_code(ResponseCode::Refused),
+                RejectMode::Refuse | RejectMode::Response => message.set_response_code(ResponseCode::NXDomain),
+            };
+            message.add_query(request.queries()[0].clone());
+        } else if let Some(answer) = self.hosts_lookup(&request) {
+            // Static records, and upstreams only for the CNAME target without static records
+
+            // Addresses of static records are connected directly
+            for rec in &answer.records {
+                match rec.data() {
+                    Some(RData::A(ip)) => self.context.add_to_reverse_lookup_cache((*ip).into(), false).await,
+                    Some(RData::AAAA(ip)) => self.context.add_to_reverse_lookup_cache((*ip).into(), false).await,
+                    _ => (),
+                }
+            }
+
+            message.add_query(request.queries()[0].clone());
+            message.add_answers(answer.records);
+
+            if let Some(alias) = answer.alias {
+                let query = Query::query(alias, request.queries()[0].query_type());
+                let (r, forward) = self.cached_lookup(&query, local_addr, remote_addr).await;
+                match r {
+                    Ok(result) => {
+                        for rec in result.answers() {
+                            trace!("dns answer: {:?}", rec);
+                            match rec.data() {
+                                Some(RData::A(ip)) => {
+                                    self.context.add_to_reverse_lookup_cache((*ip).into(), forward).await
+                                }
+                                Some(RData::AAAA(ip)) => {
+                                    self.context.add_to_reverse_lookup_cache((*ip).into(), forward).await
+                                }
+                                _ => (),
+                            }
+                        }
+                        message.add_answers(result.answers().iter().cloned());
+                        message.set_response_code(result.response_code());
+                    }
+                    Err(..) => {
+                        message.set_response_code(ResponseCode::ServFail);
+                    }
+                }
+            }
+        } else if let Some(answers) = self.fake_ip_lookup(&request) {
+            // Names are resolved by servers when connecting to the fake IPs
+
+            message.add_query(request.queries()[0].clone());
+            message.add_answers(answers);
         } else if request.query_count() > 0 {
             // Make queries according to ACL rules
 
-            let (r, forward) = self.acl_lookup(&request.queries()[0], local_addr, remote_addr).await;
+            let (r, forward) = self.cached_lookup(&request.queries()[0], local_addr, remote_addr).await;
             if let Ok(result) = r {
                 for rec in result.answers() {
                     trace!("dns answer: {:?}", rec);
@@ -504,10 +839,149 @@ impl DnsClient {
                 message.set_response_code(ResponseCode::ServFail);
             }
         }
+
+        #[cfg(feature = "local-dns-dnssec")]
+        if let Some(ref validator) = self.dnssec {
+            message = validator.respond(&request, message);
+        }
+
         Ok(message)
     }
 
-    async fn acl_lookup(
+    /// Mode of rejecting the query's name by ACL's `[reject:MODE]` sections. Names in `[outbound_block_list]` are
+    /// resolved as usual, and rejected while connecting
+    fn rejected_by_query(&self, request: &Message) -> Option<RejectMode> {
+        let query = request.queries().first()?;
+        let acl = self.context.acl()?;
+
+        let mut name = query.name().to_ascii();
+        name.make_ascii_lowercase();
+        match acl.check_host_rejected(&name)? {
+            RejectMode::Refuse => None,
+            mode => Some(mode),
+        }
+    }
+
+    /// Answers with static records if the query's name has
+    fn hosts_lookup(&self, request: &Message) -> Option<HostsAnswer> {
+        let hosts = self.hosts.as_ref()?;
+        let query = request.queries().first()?;
+
+        let answer = hosts.lookup(query)?;
+        trace!("DNS static records {} -> {:?}", query.name(), answer.records);
+        Some(answer)
+    }
+
+    /// Answers with fake IPs if the query should be sent to remote DNS
+    fn fake_ip_lookup(&self, request: &Message) -> Option<Vec<Record>> {
+        let pool = self.fake_ip_pool.as_ref()?;
+        let query = request.queries().first()?;
+
+        if query.query_class() != DNSClass::IN
+            || !matches!(query.query_type(), RecordType::A | RecordType::AAAA)
+            || self.upstream_by_query(query).is_some()
+            || should_forward_by_query(&self.context, &self.balancer, query) != Some(true)
+        {
+            return None;
+        }
+
+        let (ipv4, ipv6) = pool.allocate(&query.name().to_ascii());
+        trace!("DNS fake IP {} -> {} {:?}", query.name(), ipv4, ipv6);
+
+        let ttl = pool.ttl().as_secs() as u32;
+        let rdata = match query.query_type() {
+            RecordType::A => Some(RData::A(ipv4)),
+            // Without IPv6 range, responds NODATA to make clients fallback to IPv4
+            _ => ipv6.map(RData::AAAA),
+        };
+
+        Some(
+            rdata
+                .map(|rdata| Record::from_rdata(query.name().clone(), ttl, rdata))
+                .into_iter()
+                .collect(),
+        )
+    }
+
+    async fn cached_lookup(
+        self: &Arc<Self>,
+        query: &Query,
+        local_addr: &Arc<NameServerAddr>,
+        remote_addr: &Arc<Address>,
+    ) -> (io::Result<Message>, bool) {
+        let cache = match self.cache {
+            Some(ref c) => c,
+            None => return self.validated_lookup(query, local_addr, remote_addr).await,
+        };
+
+        let hit = cache.lookup(query);
+        self.context.dns_cache_stat().record(hit.is_some());
+
+        if let Some(hit) = hit {
+            if hit.prefetch {
+                let client = self.clone();
+                let query = query.clone();
+                let local_addr = local_addr.clone();
+                let remote_addr = remote_addr.clone();
+                tokio::spawn(async move {
+                    debug!("DNS prefetch {:?} {}", query.query_type(), query.name());
+                    if let (Ok(m), forward) = client.validated_lookup(&query, &local_addr, &remote_addr).await {
+                        if let Some(ref cache) = client.cache {
+                            cache.insert(query, &m, forward);
+                        }
+                    }
+                });
+            }
+            return (Ok(hit.message), hit.forward);
+        }
+
+        let (r, forward) = self.validated_lookup(query, local_addr, remote_addr).await;
+        if let Ok(ref m) = r {
+            cache.insert(query.clone(), m, forward);
+        }
+        (r, forward)
+    }
+
+    /// Lookup by ACL rules, and validate DNSSEC signatures of the response if enabled
+    ///
+    /// Bogus responses are errors, and the AD bit is set on validated responses.
+    async fn validated_lookup(
+        self: &Arc<Self>,
+        query: &Query,
+        local_addr: &Arc<NameServerAddr>,
+        remote_addr: &Arc<Address>,
+    ) -> (io::Result<Message>, bool) {
+        let (r, forward) = self.acl_lookup(query, local_addr, remote_addr).await;
+
+        #[cfg(feature = "local-dns-dnssec")]
+        if let Some(ref validator) = self.dnssec {
+            let mut response = match r {
+                Ok(m) => m,
+                Err(err) => return (Err(err), forward),
+            };
+            match validator
+                .validate(self, query, &response, local_addr, remote_addr)
+                .await
+            {
+                Validation::Secure => {
+                    response.set_authentic_data(true);
+                }
+                Validation::Insecure => {
+                    response.set_authentic_data(false);
+                }
+                Validation::Bogus => {
+                    warn!("DNSSEC validation of {} {} failed", query.query_type(), query.name());
+                    let err = io::Error::new(ErrorKind::InvalidData, "DNSSEC validation failed");
+                    return (Err(err), forward);
+                }
+            }
+            return (Ok(response), forward);
+        }
+
+        (r, forward)
+    }
+
+    pub(super) async fn acl_lookup(
         &self,
         query: &Query,
         local_addr: &NameServerAddr,
@@ -516,6 +990,12 @@ impl DnsClient {
         // Start querying name servers
         debug!("DNS lookup {:?} {}", query.query_type(), query.name());
 
+        if let Some((group, upstream)) = self.upstream_by_query(query) {
+            let upstream_response = self.lookup_upstream(query, upstream).await;
+            trace!("pick upstream {} response (query): {:?}", group, upstream_response);
+            return (upstream_response, false);
+        }
+
         match should_forward_by_query(&self.context, &self.balancer, query) {
             Some(true) => {
                 let remote_response = self.lookup_remote(query, remote_addr).await;
@@ -523,7 +1003,7 @@ impl DnsClient {
                 return (remote_response, true);
             }
             Some(false) => {
-                let local_response = self.lookup_local(query, local_addr).await;
+                let local_response = self.lookup_local_servers(query, local_addr).await;
                 trace!("pick local response (query): {:?}", local_response);
                 return (local_response, false);
             }
@@ -531,8 +1011,8 @@ impl DnsClient {
         }
 
         let decider = async {
-            let local_response = self.lookup_local(query, local_addr).await;
-            if should_forward_by_response(self.context.acl(), &local_response, query) {
+            let local_response = self.lookup_local_servers(query, local_addr).await;
+            if should_forward_by_response(self.context.acl().as_deref(), &local_response, query) {
                 None
             } else {
                 Some(local_response)
@@ -570,6 +1050,42 @@ impl DnsClient {
         }
     }
 
+    /// Upstream group configured by ACL's `[dns:GROUP]` sections
+    fn upstream_by_query(&self, query: &Query) -> Option<(&str, &[NameServerAddr])> {
+        if self.upstreams.is_empty() || !query.name().is_fqdn() {
+            return None;
+        }
+        let acl = self.context.acl()?;
+
+        let mut name = query.name().to_ascii();
+        name.make_ascii_lowercase();
+        let group = acl.dns_upstream_group(&name)?;
+
+        match self.upstreams.get_key_value(group) {
+            Some((group, upstream)) => Some((group.as_str(), upstream.as_slice())),
+            None => {
+                warn!("DNS upstream group {} of {} is not configured", group, name);
+                None
+            }
+        }
+    }
+
+    async fn lookup_upstream(&self, query: &Query, upstream: &[NameServerAddr]) -> io::Result<Message> {
+        let mut last_err = io::Error::new(ErrorKind::InvalidData, "resolve empty");
+
+        for ns in upstream {
+            match self.lookup_local(query, ns).await {
+                Ok(m) => return Ok(m),
+                Err(err) => {
+                    debug!("DNS upstream {} failed, error: {}", ns, err);
+                    last_err = err;
+                }
+            }
+        }
+
+        Err(last_err)
+    }
+
     async fn lookup_remote(&self, query: &Query, remote_addr: &Address) -> io::Result<Message> {
         let mut last_err = io::Error::new(ErrorKind::InvalidData, "resolve empty");
 
@@ -590,6 +1106,49 @@ impl DnsClient {
         message.set_id(thread_rng().gen());
         message.set_recursion_desired(true);
         message.add_query(query.clone());
+        #[cfg(feature = "local-dns-dnssec")]
+        if self.dnssec.is_some() {
+            dnssec::request_dnssec_records(&mut message);
+        }
+
+        // DNS-over-HTTPS is always relayed in TCP
+        #[cfg(feature = "local-dns-over-https")]
+        if let Some(ref remote_https) = self.remote_https {
+            let server = self.balancer.best_tcp_server();
+            return self
+                .client_cache
+                .lookup_remote_https(
+                    &self.context,
+                    server.server_config(),
+                    remote_https,
+                    message,
+                    self.remote_timeout,
+                )
+                .await
+                .map_err(From::from);
+        }
+
+        // DNS-over-TLS is relayed in TCP, while DNS-over-QUIC is relayed in UDP
+        #[cfg(any(feature = "local-dns-over-tls", feature = "local-dns-over-quic"))]
+        if let Some(ref remote_secure) = self.remote_secure {
+            let server = match remote_secure.protocol() {
+                #[cfg(feature = "local-dns-over-tls")]
+                SecureDnsProtocol::Tls => self.balancer.best_tcp_server(),
+                #[cfg(feature = "local-dns-over-quic")]
+                SecureDnsProtocol::Quic => self.balancer.best_udp_server(),
+            };
+            return self
+                .client_cache
+                .lookup_remote_secure(
+                    &self.context,
+                    server.server_config(),
+                    remote_secure,
+                    message,
+                    self.remote_timeout,
+                )
+                .await
+                .map_err(From::from);
+        }
 
         // Query UDP and TCP
 
@@ -597,14 +1156,28 @@ impl DnsClient {
             Mode::TcpOnly => {
                 let server = self.balancer.best_tcp_server();
                 self.client_cache
-                    .lookup_remote(&self.context, server.server_config(), remote_addr, message, false)
+                    .lookup_remote(
+                        &self.context,
+                        server.server_config(),
+                        remote_addr,
+                        message,
+                        false,
+                        self.remote_timeout,
+                    )
                     .await
                     .map_err(From::from)
             }
             Mode::UdpOnly => {
                 let server = self.balancer.best_udp_server();
                 self.client_cache
-                    .lookup_remote(&self.context, server.server_config(), remote_addr, message, true)
+                    .lookup_remote(
+                        &self.context,
+                        server.server_config(),
+                        remote_addr,
+                        message,
+                        true,
+                        self.remote_timeout,
+                    )
                     .await
                     .map_err(From::from)
             }
@@ -622,13 +1195,27 @@ impl DnsClient {
 
                     let server = self.balancer.best_tcp_server();
                     self.client_cache
-                        .lookup_remote(&self.context, server.server_config(), remote_addr, message2, false)
+                        .lookup_remote(
+                            &self.context,
+                            server.server_config(),
+                            remote_addr,
+                            message2,
+                            false,
+                            self.remote_timeout,
+                        )
                         .await
                 };
                 let udp_fut = async {
                     let server = self.balancer.best_udp_server();
                     self.client_cache
-                        .lookup_remote(&self.context, server.server_config(), remote_addr, message, true)
+                        .lookup_remote(
+                            &self.context,
+                            server.server_config(),
+                            remote_addr,
+                            message,
+                            true,
+                            self.remote_timeout,
+                        )
                         .await
                 };
 
@@ -649,7 +1236,15 @@ impl DnsClient {
         }
     }
 
-    async fn lookup_local(&self, query: &Query, local_addr: &NameServerAddr) -> io::Result<Message> {
+    /// Query the local DNS, or all local name servers by strategy if extra name servers are configured
+    async fn lookup_local_servers(&self, query: &Query, local_addr: &NameServerAddr) -> io::Result<Message> {
+        match self.local_servers {
+            Some(ref servers) => servers.lookup(self, query).await,
+            None => self.lookup_local(query, local_addr).await,
+        }
+    }
+
+    pub(super) async fn lookup_local(&self, query: &Query, local_addr: &NameServerAddr) -> io::Result<Message> {
         let mut last_err = io::Error::new(ErrorKind::InvalidData, "resolve empty");
 
         for _ in 0..self.attempts {
@@ -669,20 +1264,28 @@ impl DnsClient {
         message.set_id(thread_rng().gen());
         message.set_recursion_desired(true);
         message.add_query(query.clone());
+        #[cfg(feature = "local-dns-dnssec")]
+        if self.dnssec.is_some() {
+            dnssec::request_dnssec_records(&mut message);
+        }
 
         match *local_addr {
             NameServerAddr::SocketAddr(ns) => {
                 // Query UDP then TCP
 
-                let udp_query =
-                    self.client_cache
-                        .lookup_local(ns, message.clone(), self.context.connect_opts_ref(), true);
+                let udp_query = self.client_cache.lookup_local(
+                    ns,
+                    message.clone(),
+                    self.context.connect_opts_ref(),
+                    true,
+                    self.local_timeout,
+                );
                 let tcp_query = async move {
                     // Send TCP query after 500ms, because UDP will always return faster than TCP, there is no need to send queries simutaneously
                     time::sleep(Duration::from_millis(500)).await;
 
                     self.client_cache
-                        .lookup_local(ns, message, self.context.connect_opts_ref(), false)
+                        .lookup_local(ns, message, self.context.connect_opts_ref(), false, self.local_timeout)
                         .await
                 };
 
@@ -699,7 +1302,19 @@ impl DnsClient {
             #[cfg(unix)]
             NameServerAddr::UnixSocketAddr(ref path) => self
                 .client_cache
-                .lookup_unix_stream(path, message)
+                .lookup_unix_stream(path, message, self.local_timeout)
+                .await
+                .map_err(From::from),
+            #[cfg(any(feature = "local-dns-over-tls", feature = "local-dns-over-quic"))]
+            NameServerAddr::Secure(ref ns) => self
+                .client_cache
+                .lookup_local_secure(
+                    Some(&self.context),
+                    ns,
+                    message,
+                    self.context.connect_opts_ref(),
+                    self.local_timeout,
+                )
                 .await
                 .map_err(From::from),
         }
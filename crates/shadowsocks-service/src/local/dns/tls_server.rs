@@ -0,0 +1,248 @@
+//! DNS-over-TLS and DNS-over-HTTPS listeners
+//!
+//! Queries are resolved by the same client as the plain UDP and TCP listeners, so devices speaking only secure DNS,
+//! like Android's Private DNS and browsers with secure DNS enabled, could use the resolver directly.
+//!
+//! - DNS-over-TLS, [RFC 7858](https://datatracker.ietf.org/doc/html/rfc7858)
+//! - DNS-over-HTTPS, [RFC 8484](https://datatracker.ietf.org/doc/html/rfc8484), at path `/dns-query`
+
+use std::{convert::Infallible, io, net::SocketAddr, sync::Arc, time::Duration};
+
+use hyper::{
+    body::HttpBody,
+    header,
+    server::conn::Http,
+    service::service_fn,
+    Body,
+    Method,
+    Request,
+    Response,
+    StatusCode,
+};
+use log::{debug, error, info, trace};
+use shadowsocks::{lookup_then, net::TcpListener, relay::Address, ServerAddr};
+use tokio::time;
+use tokio_rustls::TlsAcceptor;
+use trust_dns_resolver::proto::op::Message;
+
+use crate::local::context::ServiceContext;
+
+use super::{
+    config::NameServerAddr,
+    server::{Dns, DnsClient},
+};
+
+/// Path of DNS-over-HTTPS queries
+const DOH_PATH: &str = "/dns-query";
+
+/// Media type of DNS-over-HTTPS messages
+const DOH_CONTENT_TYPE: &str = "application/dns-message";
+
+const MAX_MESSAGE_SIZE: usize = 65535;
+
+/// Clients that couldn't complete TLS handshakes in time are dropped
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+async fn bind_listener(context: &ServiceContext, bind_addr: &ServerAddr) -> io::Result<TcpListener> {
+    match *bind_addr {
+        ServerAddr::SocketAddr(ref saddr) => TcpListener::bind_with_opts(saddr, context.accept_opts()).await,
+        ServerAddr::DomainName(ref dname, port) => lookup_then!(context.context_ref(), dname, port, |addr| {
+            TcpListener::bind_with_opts(&addr, context.accept_opts()).await
+        })
+        .map(|(_, l)| l),
+    }
+}
+
+/// Run a DNS-over-TLS server on `bind_addr`
+pub(super) async fn run_tls_server(
+    context: &ServiceContext,
+    bind_addr: &ServerAddr,
+    acceptor: TlsAcceptor,
+    client: Arc<DnsClient>,
+    local_addr: Arc<NameServerAddr>,
+    remote_addr: Arc<Address>,
+) -> io::Result<()> {
+    let listener = bind_listener(context, bind_addr).await?;
+
+    info!("shadowsocks dns-over-tls listening on {}", listener.local_addr()?);
+
+    loop {
+        let (stream, peer_addr) = match listener.accept().await {
+            Ok(s) => s,
+            Err(err) => {
+                error!("dns-over-tls accept failed with error: {}", err);
+                time::sleep(Duration::from_secs(1)).await;
+                continue;
+            }
+        };
+
+        let acceptor = acceptor.clone();
+        let client = client.clone();
+        let local_addr = local_addr.clone();
+        let remote_addr = remote_addr.clone();
+        tokio::spawn(async move {
+            let stream = match time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                Ok(Ok(s)) => s,
+                Ok(Err(err)) => {
+                    debug!("dns-over-tls client {} handshake failed, error: {}", peer_addr, err);
+                    return;
+                }
+                Err(..) => {
+                    debug!("dns-over-tls client {} handshake timed out", peer_addr);
+                    return;
+                }
+            };
+
+            let _ = Dns::handle_tcp_stream(client, stream, peer_addr, local_addr, remote_addr).await;
+        });
+    }
+}
+
+/// Run a DNS-over-HTTPS server on `bind_addr`
+pub(super) async fn run_https_server(
+    context: &ServiceContext,
+    bind_addr: &ServerAddr,
+    acceptor: TlsAcceptor,
+    client: Arc<DnsClient>,
+    local_addr: Arc<NameServerAddr>,
+    remote_addr: Arc<Address>,
+) -> io::Result<()> {
+    let listener = bind_listener(context, bind_addr).await?;
+
+    info!("shadowsocks dns-over-https listening on {}", listener.local_addr()?);
+
+    loop {
+        let (stream, peer_addr) = match listener.accept().await {
+            Ok(s) => s,
+            Err(err) => {
+                error!("dns-over-https accept failed with error: {}", err);
+                time::sleep(Duration::from_secs(1)).await;
+                continue;
+            }
+        };
+
+        let acceptor = acceptor.clone();
+        let client = client.clone();
+        let local_addr = local_addr.clone();
+        let remote_addr = remote_addr.clone();
+        tokio::spawn(async move {
+            let stream = match time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                Ok(Ok(s)) => s,
+                Ok(Err(err)) => {
+                    debug!("dns-over-https client {} handshake failed, error: {}", peer_addr, err);
+                    return;
+                }
+                Err(..) => {
+                    debug!("dns-over-https client {} handshake timed out", peer_addr);
+                    return;
+                }
+            };
+
+            // HTTP/1.1 and HTTP/2 (negotiated by ALPN) are both served
+            let service = service_fn(move |req: Request<Body>| {
+                let client = client.clone();
+                let local_addr = local_addr.clone();
+                let remote_addr = remote_addr.clone();
+                async move {
+                    Ok::<_, Infallible>(handle_https_request(client, peer_addr, req, local_addr, remote_addr).await)
+                }
+            });
+
+            if let Err(err) = Http::new().serve_connection(stream, service).await {
+                debug!("dns-over-https client {} connection error: {}", peer_addr, err);
+            }
+        });
+    }
+}
+
+async fn handle_https_request(
+    client: Arc<DnsClient>,
+    peer_addr: SocketAddr,
+    req: Request<Body>,
+    local_addr: Arc<NameServerAddr>,
+    remote_addr: Arc<Address>,
+) -> Response<Body> {
+    trace!("dns-over-https request {} {} {}", peer_addr, req.method(), req.uri());
+
+    if req.uri().path() != DOH_PATH {
+        return error_response(StatusCode::NOT_FOUND);
+    }
+
+    let query = match *req.method() {
+        // dns=BASE64URL(message), without padding
+        Method::GET => {
+            let encoded = req
+                .uri()
+                .query()
+                .unwrap_or("")
+                .split('&')
+                .find_map(|param| param.strip_prefix("dns="));
+            match encoded.and_then(|s| base64::decode_config(s, base64::URL_SAFE_NO_PAD).ok()) {
+                Some(q) => q,
+                None => return error_response(StatusCode::BAD_REQUEST),
+            }
+        }
+        Method::POST => {
+            let content_type = req.headers().get(header::CONTENT_TYPE).map(|v| v.as_bytes());
+            if content_type != Some(DOH_CONTENT_TYPE.as_bytes()) {
+                return error_response(StatusCode::UNSUPPORTED_MEDIA_TYPE);
+            }
+            match read_body(req.into_body()).await {
+                Ok(q) => q,
+                Err(status) => return error_response(status),
+            }
+        }
+        _ => return error_response(StatusCode::METHOD_NOT_ALLOWED),
+    };
+
+    let message = match Message::from_vec(&query) {
+        Ok(m) => m,
+        Err(err) => {
+            debug!("dns-over-https {} query message parse error: {}", peer_addr, err);
+            return error_response(StatusCode::BAD_REQUEST);
+        }
+    };
+
+    let respond_message = match client.resolve(message, &local_addr, &remote_addr).await {
+        Ok(m) => m,
+        Err(err) if err.kind() == io::ErrorKind::PermissionDenied => {
+            debug!("dns-over-https {} query not answered, {}", peer_addr, err);
+            return error_response(StatusCode::BAD_GATEWAY);
+        }
+        Err(err) => {
+            error!("dns-over-https {} lookup failed, error: {}", peer_addr, err);
+            return error_response(StatusCode::BAD_GATEWAY);
+        }
+    };
+
+    let body = match respond_message.to_vec() {
+        Ok(b) => b,
+        Err(err) => {
+            error!("dns-over-https {} response message encode error: {}", peer_addr, err);
+            return error_response(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    };
+
+    let mut builder = Response::builder().header(header::CONTENT_TYPE, DOH_CONTENT_TYPE);
+    // Responses shouldn't be cached by HTTP caches longer than the records
+    if let Some(ttl) = respond_message.answers().iter().map(|r| r.ttl()).min() {
+        builder = builder.header(header::CACHE_CONTROL, format!("max-age={}", ttl));
+    }
+    builder.body(Body::from(body)).unwrap()
+}
+
+async fn read_body(mut body: Body) -> Result<Vec<u8>, StatusCode> {
+    let mut buffer = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|_| StatusCode::BAD_REQUEST)?;
+        if buffer.len() + chunk.len() > MAX_MESSAGE_SIZE {
+            return Err(StatusCode::PAYLOAD_TOO_LARGE);
+        }
+        buffer.extend_from_slice(&chunk);
+    }
+    Ok(buffer)
+}
+
+fn error_response(status: StatusCode) -> Response<Body> {
+    Response::builder().status(status).body(Body::empty()).unwrap()
+}
@@ -1,7 +1,11 @@
 //! DNS Relay Upstream
 
+#[cfg(feature = "local-dns-over-quic")]
+use std::net::Ipv4Addr;
 #[cfg(unix)]
 use std::path::Path;
+#[cfg(feature = "local-dns-over-https")]
+use std::task::{Context, Poll};
 use std::{
     cmp::Ordering,
     io::{self, ErrorKind},
@@ -12,8 +16,36 @@ use std::{
 
 use byteorder::{BigEndian, ByteOrder};
 use bytes::{BufMut, BytesMut};
+#[cfg(feature = "local-dns-over-https")]
+use futures::{future, task::noop_waker_ref};
+#[cfg(feature = "local-dns-over-https")]
+use hyper::{
+    client::conn::{self, SendRequest},
+    header,
+    Body,
+    Request,
+    StatusCode,
+};
 use log::trace;
+#[cfg(any(
+    feature = "local-dns-over-https",
+    feature = "local-dns-over-tls",
+    feature = "local-dns-over-quic"
+))]
+use log::warn;
+#[cfg(any(
+    feature = "local-dns-over-https",
+    feature = "local-dns-over-tls",
+    feature = "local-dns-over-quic"
+))]
+use once_cell::sync::Lazy;
+#[cfg(feature = "local-dns-over-quic")]
+use quinn::{ClientConfig as QuicClientConfig, Connection as QuicConnection, Endpoint, EndpointConfig};
 use rand::{thread_rng, Rng};
+#[cfg(any(feature = "local-dns-over-tls", feature = "local-dns-over-quic"))]
+use shadowsocks::lookup_then;
+#[cfg(feature = "local-dns-over-quic")]
+use shadowsocks::relay::udprelay::MAXIMUM_UDP_PAYLOAD_SIZE;
 use shadowsocks::{
     config::ServerConfig,
     context::SharedContext,
@@ -22,11 +54,23 @@ use shadowsocks::{
 };
 #[cfg(unix)]
 use tokio::net::UnixStream;
+#[cfg(feature = "local-dns-over-quic")]
+use tokio::task::JoinHandle;
 use tokio::{
     io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
     net::UdpSocket,
     time,
 };
+#[cfg(feature = "local-dns-over-tls")]
+use tokio_rustls::client::TlsStream;
+#[cfg(any(
+    feature = "local-dns-over-https",
+    feature = "local-dns-over-tls",
+    feature = "local-dns-over-quic"
+))]
+use tokio_rustls::rustls::{Certificate, ClientConfig, OwnedTrustAnchor, RootCertStore};
+#[cfg(any(feature = "local-dns-over-https", feature = "local-dns-over-tls"))]
+use tokio_rustls::{rustls::ServerName, TlsConnector};
 use trust_dns_resolver::proto::{
     error::{ProtoError, ProtoErrorKind},
     op::Message,
@@ -34,6 +78,14 @@ use trust_dns_resolver::proto::{
 
 use crate::net::{FlowStat, MonProxySocket, MonProxyStream};
 
+#[cfg(feature = "local-dns-over-https")]
+use super::config::DnsOverHttpsAddr;
+#[cfg(any(feature = "local-dns-over-tls", feature = "local-dns-over-quic"))]
+use super::config::{SecureDnsProtocol, SecureNameServerAddr};
+
+/// Receive buffer of UDP responses, large enough for responses of EDNS queries
+const UDP_RESPONSE_BUFFER_SIZE: usize = 4096;
+
 /// Collection of various DNS connections
 #[allow(clippy::large_enum_variant)]
 pub enum DnsClient {
@@ -55,6 +107,28 @@ pub enum DnsClient {
         socket: MonProxySocket,
         ns: Address,
     },
+    #[cfg(feature = "local-dns-over-https")]
+    HttpsRemote {
+        sender: SendRequest<Body>,
+        ns: DnsOverHttpsAddr,
+        http2: bool,
+    },
+    #[cfg(feature = "local-dns-over-tls")]
+    TlsLocal {
+        stream: TlsStream<ShadowTcpStream>,
+    },
+    #[cfg(feature = "local-dns-over-tls")]
+    TlsRemote {
+        stream: TlsStream<ProxyClientStream<MonProxyStream<ShadowTcpStream>>>,
+    },
+    #[cfg(feature = "local-dns-over-quic")]
+    Quic {
+        connection: QuicConnection,
+        // Endpoint have to be kept, otherwise the connection will be closed
+        _endpoint: Endpoint,
+        // UDP relay between endpoint and proxy, only for remote servers
+        _relay: Option<QuicRelay>,
+    },
 }
 
 impl DnsClient {
@@ -105,6 +179,157 @@ impl DnsClient {
         Ok(DnsClient::UdpRemote { socket, ns })
     }
 
+    /// Connect to remote DNS-over-HTTPS server through proxy
+    #[cfg(feature = "local-dns-over-https")]
+    pub async fn connect_https_remote(
+        context: SharedContext,
+        svr_cfg: &ServerConfig,
+        ns: &DnsOverHttpsAddr,
+        connect_opts: &ConnectOpts,
+        flow_stat: Arc<FlowStat>,
+    ) -> io::Result<DnsClient> {
+        let stream = ProxyClientStream::connect_with_opts_map(context, svr_cfg, &ns.server_addr(), connect_opts, |s| {
+            MonProxyStream::from_stream(s, flow_stat)
+        })
+        .await?;
+
+        let server_name = match ServerName::try_from(ns.host()) {
+            Ok(n) => n,
+            Err(..) => {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("invalid dnsname \"{}\"", ns.host()),
+                ));
+            }
+        };
+        let stream = TlsConnector::from(DOH_TLS_CONFIG.clone())
+            .connect(server_name, stream)
+            .await?;
+        let http2 = matches!(stream.get_ref().1.alpn_protocol(), Some(b"h2"));
+
+        let (sender, connection) = match conn::Builder::new().http2_only(http2).handshake(stream).await {
+            Ok(s) => s,
+            Err(err) => return Err(io::Error::new(ErrorKind::Other, err)),
+        };
+
+        tokio::spawn(async move {
+            if let Err(err) = connection.await {
+                trace!("DNS-over-HTTPS connection closed with error: {}", err);
+            }
+        });
+
+        Ok(DnsClient::HttpsRemote {
+            sender,
+            ns: ns.clone(),
+            http2,
+        })
+    }
+
+    /// Connect to local provided DNS-over-TLS or DNS-over-QUIC server
+    #[cfg(any(feature = "local-dns-over-tls", feature = "local-dns-over-quic"))]
+    pub async fn connect_secure_local(
+        context: Option<SharedContext>,
+        ns: &SecureNameServerAddr,
+        connect_opts: &ConnectOpts,
+    ) -> io::Result<DnsClient> {
+        match ns.protocol() {
+            #[cfg(feature = "local-dns-over-tls")]
+            SecureDnsProtocol::Tls => {
+                let stream = match (ns.bootstrap(), context) {
+                    (Some(ip), _) => {
+                        ShadowTcpStream::connect_with_opts(&SocketAddr::new(ip, ns.port()), connect_opts).await?
+                    }
+                    (None, Some(context)) => {
+                        lookup_then!(context, ns.host(), ns.port(), |addr| {
+                            ShadowTcpStream::connect_with_opts(&addr, connect_opts).await
+                        })?
+                        .1
+                    }
+                    (None, None) => return Err(secure_bootstrap_error(ns)),
+                };
+                let stream = tls_connect(ns.host(), stream).await?;
+                Ok(DnsClient::TlsLocal { stream })
+            }
+            #[cfg(feature = "local-dns-over-quic")]
+            SecureDnsProtocol::Quic => match (ns.bootstrap(), context) {
+                (Some(ip), _) => {
+                    DnsClient::connect_quic_local(SocketAddr::new(ip, ns.port()), ns.host(), connect_opts).await
+                }
+                (None, Some(context)) => Ok(lookup_then!(context, ns.host(), ns.port(), |addr| {
+                    DnsClient::connect_quic_local(addr, ns.host(), connect_opts).await
+                })?
+                .1),
+                (None, None) => Err(secure_bootstrap_error(ns)),
+            },
+        }
+    }
+
+    #[cfg(feature = "local-dns-over-quic")]
+    async fn connect_quic_local(addr: SocketAddr, host: &str, connect_opts: &ConnectOpts) -> io::Result<DnsClient> {
+        let socket: UdpSocket = ShadowUdpSocket::connect_any_with_opts(&addr, connect_opts)
+            .await?
+            .into();
+        let (endpoint, _) = Endpoint::new(EndpointConfig::default(), None, socket.into_std()?)?;
+        let connection = quic_connect(&endpoint, addr, host).await?;
+
+        Ok(DnsClient::Quic {
+            connection,
+            _endpoint: endpoint,
+            _relay: None,
+        })
+    }
+
+    /// Connect to remote DNS-over-TLS or DNS-over-QUIC server through proxy
+    #[cfg(any(feature = "local-dns-over-tls", feature = "local-dns-over-quic"))]
+    pub async fn connect_secure_remote(
+        context: SharedContext,
+        svr_cfg: &ServerConfig,
+        ns: &SecureNameServerAddr,
+        connect_opts: &ConnectOpts,
+        flow_stat: Arc<FlowStat>,
+    ) -> io::Result<DnsClient> {
+        match ns.protocol() {
+            #[cfg(feature = "local-dns-over-tls")]
+            SecureDnsProtocol::Tls => {
+                let stream =
+                    ProxyClientStream::connect_with_opts_map(context, svr_cfg, &ns.server_addr(), connect_opts, |s| {
+                        MonProxyStream::from_stream(s, flow_stat)
+                    })
+                    .await?;
+                let stream = tls_connect(ns.host(), stream).await?;
+                Ok(DnsClient::TlsRemote { stream })
+            }
+            #[cfg(feature = "local-dns-over-quic")]
+            SecureDnsProtocol::Quic => {
+                // QUIC endpoint sends packets to a loopback relay, which forwards them through proxy
+                let socket = ProxySocket::connect_with_opts(context, svr_cfg, connect_opts).await?;
+                let socket = MonProxySocket::from_socket(socket, flow_stat);
+
+                let relay_socket = UdpSocket::bind(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0)).await?;
+                let relay_addr = relay_socket.local_addr()?;
+
+                let endpoint_socket = std::net::UdpSocket::bind(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0))?;
+                let endpoint_addr = endpoint_socket.local_addr()?;
+                let (endpoint, _) = Endpoint::new(EndpointConfig::default(), None, endpoint_socket)?;
+
+                let relay = QuicRelay(tokio::spawn(quic_relay(
+                    relay_socket,
+                    endpoint_addr,
+                    socket,
+                    ns.server_addr(),
+                )));
+
+                let connection = quic_connect(&endpoint, relay_addr, ns.host()).await?;
+
+                Ok(DnsClient::Quic {
+                    connection,
+                    _endpoint: endpoint,
+                    _relay: Some(relay),
+                })
+            }
+        }
+    }
+
     /// Make a DNS lookup
     #[allow(dead_code)]
     pub async fn lookup(&mut self, mut msg: Message) -> Result<Message, ProtoError> {
@@ -132,7 +357,7 @@ impl DnsClient {
                 let bytes = msg.to_vec()?;
                 socket.send(&bytes).await?;
 
-                let mut recv_buf = [0u8; 256];
+                let mut recv_buf = [0u8; UDP_RESPONSE_BUFFER_SIZE];
                 let n = socket.recv(&mut recv_buf).await?;
 
                 Message::from_vec(&recv_buf[..n])
@@ -144,11 +369,32 @@ impl DnsClient {
                 let bytes = msg.to_vec()?;
                 socket.send(ns, &bytes).await?;
 
-                let mut recv_buf = [0u8; 256];
+                let mut recv_buf = [0u8; UDP_RESPONSE_BUFFER_SIZE];
                 let (n, _) = socket.recv(&mut recv_buf).await?;
 
                 Message::from_vec(&recv_buf[..n])
             }
+            #[cfg(feature = "local-dns-over-https")]
+            DnsClient::HttpsRemote {
+                ref mut sender,
+                ref ns,
+                http2,
+            } => https_query(sender, ns, http2, msg).await,
+            #[cfg(feature = "local-dns-over-tls")]
+            DnsClient::TlsLocal { ref mut stream } => stream_query(stream, msg).await,
+            #[cfg(feature = "local-dns-over-tls")]
+            DnsClient::TlsRemote { ref mut stream } => stream_query(stream, msg).await,
+            #[cfg(feature = "local-dns-over-quic")]
+            DnsClient::Quic { ref connection, .. } => {
+                // Message ID have to be 0 in DNS-over-QUIC
+                msg.set_id(0);
+
+                // One query in each stream, and the client have to close the stream after sending
+                let (mut send, mut recv) = connection.open_bi().await.map_err(io::Error::from)?;
+                stream_send(&mut send, msg).await?;
+                send.finish().await.map_err(io::Error::from)?;
+                stream_recv(&mut recv).await
+            }
         }
     }
 
@@ -227,13 +473,203 @@ impl DnsClient {
             DnsClient::UnixStream { ref mut stream } => check_peekable(stream),
             DnsClient::TcpRemote { ref mut stream } => check_peekable(stream.get_mut().get_mut()),
             DnsClient::UdpRemote { .. } => true,
+            #[cfg(feature = "local-dns-over-tls")]
+            DnsClient::TlsLocal { ref mut stream } => check_peekable(stream.get_mut().0),
+            #[cfg(feature = "local-dns-over-tls")]
+            DnsClient::TlsRemote { ref mut stream } => check_peekable(stream.get_mut().0.get_mut().get_mut()),
+            #[cfg(feature = "local-dns-over-quic")]
+            DnsClient::Quic { .. } => true,
+            #[cfg(feature = "local-dns-over-https")]
+            DnsClient::HttpsRemote { ref mut sender, .. } => {
+                // Connection is closed if it is not going to be ready
+                let mut cx = Context::from_waker(noop_waker_ref());
+                !matches!(sender.poll_ready(&mut cx), Poll::Ready(Err(..)))
+            }
         }
     }
 }
 
+#[cfg(any(
+    feature = "local-dns-over-https",
+    feature = "local-dns-over-tls",
+    feature = "local-dns-over-quic"
+))]
+fn tls_client_config(alpn_protocols: &[&[u8]]) -> Arc<ClientConfig> {
+    let mut store = RootCertStore::empty();
+    match rustls_native_certs::load_native_certs() {
+        Ok(certs) => {
+            for cert in certs {
+                if let Err(err) = store.add(&Certificate(cert.0)) {
+                    warn!("failed to add cert, error: {}", err);
+                }
+            }
+        }
+        Err(err) => {
+            warn!("failed to load native certs, {}", err);
+
+            store.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|root| {
+                OwnedTrustAnchor::from_subject_spki_name_constraints(root.subject, root.spki, root.name_constraints)
+            }));
+        }
+    }
+
+    let mut config = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(store)
+        .with_no_client_auth();
+    config.alpn_protocols = alpn_protocols.iter().map(|p| p.to_vec()).collect();
+    Arc::new(config)
+}
+
+#[cfg(feature = "local-dns-over-https")]
+static DOH_TLS_CONFIG: Lazy<Arc<ClientConfig>> = Lazy::new(|| tls_client_config(&[b"h2", b"http/1.1"]));
+
+#[cfg(feature = "local-dns-over-tls")]
+static DOT_TLS_CONFIG: Lazy<Arc<ClientConfig>> = Lazy::new(|| tls_client_config(&[]));
+
+#[cfg(feature = "local-dns-over-quic")]
+static DOQ_CLIENT_CONFIG: Lazy<QuicClientConfig> = Lazy::new(|| QuicClientConfig::new(tls_client_config(&[b"doq"])));
+
+#[cfg(any(feature = "local-dns-over-tls", feature = "local-dns-over-quic"))]
+fn secure_bootstrap_error(ns: &SecureNameServerAddr) -> io::Error {
+    io::Error::new(
+        ErrorKind::InvalidInput,
+        format!("{} requires a bootstrap IP address", ns),
+    )
+}
+
+#[cfg(feature = "local-dns-over-tls")]
+async fn tls_connect<S>(host: &str, stream: S) -> io::Result<TlsStream<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let server_name = match ServerName::try_from(host) {
+        Ok(n) => n,
+        Err(..) => {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("invalid dnsname \"{}\"", host),
+            ));
+        }
+    };
+
+    TlsConnector::from(DOT_TLS_CONFIG.clone())
+        .connect(server_name, stream)
+        .await
+}
+
+#[cfg(feature = "local-dns-over-quic")]
+async fn quic_connect(endpoint: &Endpoint, addr: SocketAddr, host: &str) -> io::Result<QuicConnection> {
+    let connecting = match endpoint.connect_with(DOQ_CLIENT_CONFIG.clone(), addr, host) {
+        Ok(c) => c,
+        Err(err) => return Err(io::Error::new(ErrorKind::InvalidInput, err)),
+    };
+    let new_connection = connecting.await?;
+    Ok(new_connection.connection)
+}
+
+/// Relay task of DNS-over-QUIC through proxy, aborted on drop
+#[cfg(feature = "local-dns-over-quic")]
+pub struct QuicRelay(JoinHandle<()>);
+
+#[cfg(feature = "local-dns-over-quic")]
+impl Drop for QuicRelay {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+#[cfg(feature = "local-dns-over-quic")]
+async fn quic_relay(relay_socket: UdpSocket, endpoint_addr: SocketAddr, socket: MonProxySocket, ns: Address) {
+    let mut endpoint_buf = vec![0u8; MAXIMUM_UDP_PAYLOAD_SIZE];
+    let mut proxy_buf = vec![0u8; MAXIMUM_UDP_PAYLOAD_SIZE];
+
+    loop {
+        tokio::select! {
+            r = relay_socket.recv_from(&mut endpoint_buf) => {
+                let (n, peer_addr) = match r {
+                    Ok(r) => r,
+                    Err(err) => {
+                        trace!("DNS-over-QUIC relay recv error: {}", err);
+                        break;
+                    }
+                };
+                if peer_addr != endpoint_addr {
+                    continue;
+                }
+                if let Err(err) = socket.send(&ns, &endpoint_buf[..n]).await {
+                    trace!("DNS-over-QUIC relay send to {} error: {}", ns, err);
+                }
+            }
+            r = socket.recv(&mut proxy_buf) => {
+                let n = match r {
+                    Ok((n, ..)) => n,
+                    Err(err) => {
+                        trace!("DNS-over-QUIC relay recv from {} error: {}", ns, err);
+                        continue;
+                    }
+                };
+                if let Err(err) = relay_socket.send_to(&proxy_buf[..n], endpoint_addr).await {
+                    trace!("DNS-over-QUIC relay send error: {}", err);
+                }
+            }
+        }
+    }
+}
+
+/// DNS query in DNS-over-HTTPS's POST method
+#[cfg(feature = "local-dns-over-https")]
+async fn https_query(
+    sender: &mut SendRequest<Body>,
+    ns: &DnsOverHttpsAddr,
+    http2: bool,
+    r: &Message,
+) -> Result<Message, ProtoError> {
+    fn http_error(err: hyper::Error) -> ProtoError {
+        io::Error::new(ErrorKind::Other, err).into()
+    }
+
+    // HTTP/2 takes authority from the URI, while HTTP/1.1 requires `Host` header
+    let mut builder = Request::post(if http2 {
+        format!("https://{}{}", ns.host(), ns.path())
+    } else {
+        ns.path().to_owned()
+    });
+    if !http2 {
+        builder = builder.header(header::HOST, ns.host());
+    }
+
+    let req = builder
+        .header(header::CONTENT_TYPE, "application/dns-message")
+        .header(header::ACCEPT, "application/dns-message")
+        .body(Body::from(r.to_vec()?))
+        .map_err(|err| ProtoError::from(io::Error::new(ErrorKind::InvalidInput, err)))?;
+
+    future::poll_fn(|cx| sender.poll_ready(cx)).await.map_err(http_error)?;
+    let rsp = sender.send_request(req).await.map_err(http_error)?;
+    if rsp.status() != StatusCode::OK {
+        let err = io::Error::new(
+            ErrorKind::Other,
+            format!("DNS-over-HTTPS server responded {}", rsp.status()),
+        );
+        return Err(err.into());
+    }
+
+    let body = hyper::body::to_bytes(rsp.into_body()).await.map_err(http_error)?;
+    Message::from_vec(&body)
+}
+
 pub async fn stream_query<S>(stream: &mut S, r: &Message) -> Result<Message, ProtoError>
 where
     S: AsyncRead + AsyncWrite + Unpin,
+{
+    stream_send(stream, r).await?;
+    stream_recv(stream).await
+}
+
+async fn stream_send<W>(stream: &mut W, r: &Message) -> Result<(), ProtoError>
+where
+    W: AsyncWrite + Unpin,
 {
     let mut req_bytes = r.to_vec()?;
 
@@ -244,7 +680,13 @@ where
     BigEndian::write_u16(&mut req_bytes[0..2], length as u16);
 
     stream.write_all(&req_bytes).await?;
+    Ok(())
+}
 
+async fn stream_recv<R>(stream: &mut R) -> Result<Message, ProtoError>
+where
+    R: AsyncRead + Unpin,
+{
     // Read response, [LENGTH][Message]
     let mut length_buf = [0u8; 2];
     stream.read_exact(&mut length_buf).await?;
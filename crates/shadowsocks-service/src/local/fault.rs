@@ -0,0 +1,168 @@
+//! Fault injection for reproducing relay failures
+//!
+//! Faults are chosen by a random number generator, which could be seeded for reproducing the same sequence of faults.
+
+use std::{
+    io::{self, ErrorKind, IoSlice},
+    pin::Pin,
+    task::{self, Poll},
+    time::Duration,
+};
+
+use log::trace;
+use pin_project::pin_project;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use spin::Mutex as SpinMutex;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    time,
+};
+
+/// Faults to be injected into relays
+#[derive(Debug, Clone, Default)]
+pub struct FaultInjectionConfig {
+    /// Probability of dropping UDP packets sent by clients, in `[0, 1]`
+    pub udp_drop_rate: f64,
+    /// Delay before connecting to proxy servers
+    pub connect_delay: Option<Duration>,
+    /// TCP tunnels will be reset after relaying this amount of bytes (both directions)
+    pub tunnel_kill_after_bytes: Option<u64>,
+    /// Seed of the random number generator, random if not specified
+    pub seed: Option<u64>,
+}
+
+/// Fault injector shared by all relays of a `ServiceContext`
+pub struct FaultInjector {
+    config: FaultInjectionConfig,
+    rng: SpinMutex<StdRng>,
+}
+
+impl FaultInjector {
+    /// Create an injector from configuration
+    pub fn new(config: FaultInjectionConfig) -> FaultInjector {
+        let rng = match config.seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
+
+        FaultInjector {
+            config,
+            rng: SpinMutex::new(rng),
+        }
+    }
+
+    /// Configuration of this injector
+    pub fn config(&self) -> &FaultInjectionConfig {
+        &self.config
+    }
+
+    /// Check if the next UDP packet should be dropped
+    pub fn drop_udp_packet(&self) -> bool {
+        if self.config.udp_drop_rate <= 0.0 {
+            return false;
+        }
+
+        let dropped = self.rng.lock().gen_bool(self.config.udp_drop_rate.min(1.0));
+        if dropped {
+            trace!("fault injection: dropped UDP packet");
+        }
+        dropped
+    }
+
+    /// Wait before connecting to proxy servers
+    pub async fn delay_connect(&self) {
+        if let Some(delay) = self.config.connect_delay {
+            trace!("fault injection: delay connecting for {:?}", delay);
+            time::sleep(delay).await;
+        }
+    }
+
+    /// Wrap a stream of TCP tunnel, which will be reset after `tunnel_kill_after_bytes`
+    pub fn wrap_tunnel_stream<S>(&self, stream: S) -> FaultStream<S> {
+        FaultStream {
+            stream,
+            remaining: self.config.tunnel_kill_after_bytes,
+        }
+    }
+}
+
+/// Stream that fails after transferring a limited amount of bytes
+#[pin_project]
+pub struct FaultStream<S> {
+    #[pin]
+    stream: S,
+    remaining: Option<u64>,
+}
+
+impl<S> FaultStream<S> {
+    fn consume(remaining: &mut Option<u64>, n: usize) {
+        if let Some(ref mut r) = *remaining {
+            *r = r.saturating_sub(n as u64);
+        }
+    }
+
+    fn check_killed(remaining: &Option<u64>) -> io::Result<()> {
+        if let Some(0) = *remaining {
+            trace!("fault injection: killed tunnel");
+            return Err(io::Error::new(
+                ErrorKind::ConnectionReset,
+                "tunnel killed by fault injection",
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl<S> AsyncRead for FaultStream<S>
+where
+    S: AsyncRead,
+{
+    fn poll_read(self: Pin<&mut Self>, cx: &mut task::Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.project();
+        FaultStream::<S>::check_killed(this.remaining)?;
+
+        let filled = buf.filled().len();
+        futures::ready!(this.stream.poll_read(cx, buf))?;
+        FaultStream::<S>::consume(this.remaining, buf.filled().len() - filled);
+        Ok(()).into()
+    }
+}
+
+impl<S> AsyncWrite for FaultStream<S>
+where
+    S: AsyncWrite,
+{
+    fn poll_write(self: Pin<&mut Self>, cx: &mut task::Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.project();
+        FaultStream::<S>::check_killed(this.remaining)?;
+
+        let n = futures::ready!(this.stream.poll_write(cx, buf))?;
+        FaultStream::<S>::consume(this.remaining, n);
+        Ok(n).into()
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        self.project().stream.poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        self.project().stream.poll_shutdown(cx)
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.project();
+        FaultStream::<S>::check_killed(this.remaining)?;
+
+        let n = futures::ready!(this.stream.poll_write_vectored(cx, bufs))?;
+        FaultStream::<S>::consume(this.remaining, n);
+        Ok(n).into()
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.stream.is_write_vectored()
+    }
+}
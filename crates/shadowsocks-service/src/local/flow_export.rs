@@ -0,0 +1,429 @@
+//! Export of flow records to IPFIX (NetFlow v10) collectors
+//!
+//! A record is exported when a TCP tunnel or UDP association registered in `ConnectionTable` is closed, with its
+//! five-tuple, bytes of both directions, start and end times, and how it was routed. Records are batched and sent
+//! over UDP every second.
+//!
+//! Information elements without IANA assigned ones (the target domain, server, verdict and rule) are
+//! enterprise-specific, under `FlowExportConfig::enterprise_number`.
+
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use bytes::{BufMut, BytesMut};
+use log::{debug, error, info, trace};
+use shadowsocks::{
+    config::ServerAddr,
+    context::SharedContext,
+    net::{ConnectOpts, UdpSocket},
+    relay::socks5::Address,
+};
+use tokio::{
+    sync::mpsc::{self, error::TrySendError},
+    time,
+};
+
+/// Enterprise number reserved for documentation (RFC 5612), used if it is not configured
+pub const DEFAULT_ENTERPRISE_NUMBER: u32 = 32473;
+
+/// Enterprise number of reverse information elements of biflows (RFC 5103)
+const REVERSE_ENTERPRISE_NUMBER: u32 = 29305;
+
+const IPFIX_VERSION: u16 = 10;
+const TEMPLATE_SET_ID: u16 = 2;
+const IPV4_TEMPLATE_ID: u16 = 256;
+const IPV6_TEMPLATE_ID: u16 = 257;
+const VARIABLE_LENGTH: u16 = 65535;
+const ENTERPRISE_BIT: u16 = 0x8000;
+
+const MESSAGE_HEADER_SIZE: usize = 16;
+const SET_HEADER_SIZE: usize = 4;
+/// Messages are kept in a packet without fragmentation in common networks
+const MAX_MESSAGE_SIZE: usize = 1400;
+
+/// Records waiting to be sent, newer records are dropped if the collector couldn't keep up
+const RECORD_CHANNEL_SIZE: usize = 4096;
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+/// Templates are sent again periodically, for collectors (re)started after the first message
+const TEMPLATE_REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+
+// IANA information elements
+const IE_OCTET_DELTA_COUNT: u16 = 1;
+const IE_PROTOCOL_IDENTIFIER: u16 = 4;
+const IE_SOURCE_TRANSPORT_PORT: u16 = 7;
+const IE_SOURCE_IPV4_ADDRESS: u16 = 8;
+const IE_DESTINATION_TRANSPORT_PORT: u16 = 11;
+const IE_DESTINATION_IPV4_ADDRESS: u16 = 12;
+const IE_SOURCE_IPV6_ADDRESS: u16 = 27;
+const IE_DESTINATION_IPV6_ADDRESS: u16 = 28;
+const IE_FLOW_START_MILLISECONDS: u16 = 152;
+const IE_FLOW_END_MILLISECONDS: u16 = 153;
+
+// Enterprise-specific information elements
+const IE_TARGET_DOMAIN: u16 = 1;
+const IE_SERVER: u16 = 2;
+const IE_VERDICT: u16 = 3;
+const IE_RULE: u16 = 4;
+
+/// Configuration of exporting flow records
+#[derive(Debug, Clone)]
+pub struct FlowExportConfig {
+    /// Address of the IPFIX collector, receiving UDP
+    pub collector: ServerAddr,
+    /// Observation domain ID in headers of messages, distinguishing exporters of a collector
+    pub observation_domain_id: u32,
+    /// Private enterprise number of enterprise-specific information elements
+    pub enterprise_number: u32,
+}
+
+impl FlowExportConfig {
+    /// Create with the address of the collector
+    pub fn new(collector: ServerAddr) -> FlowExportConfig {
+        FlowExportConfig {
+            collector,
+            observation_domain_id: 0,
+            enterprise_number: DEFAULT_ENTERPRISE_NUMBER,
+        }
+    }
+}
+
+/// How a flow was routed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlowVerdict {
+    /// Relayed through a server
+    Proxy,
+    /// Relayed directly
+    Bypass,
+    /// Closed before relaying, rejected by ACL or failed to connect
+    Failed,
+}
+
+impl FlowVerdict {
+    /// Name of the verdict in records
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            FlowVerdict::Proxy => "proxy",
+            FlowVerdict::Bypass => "bypass",
+            FlowVerdict::Failed => "failed",
+        }
+    }
+}
+
+/// Record of a closed flow
+#[derive(Debug, Clone)]
+pub struct FlowRecord {
+    /// IP protocol number, 6 for TCP and 17 for UDP
+    pub protocol: u8,
+    /// Address of the client
+    pub peer_addr: SocketAddr,
+    /// Target requested by the client, `None` for UDP associations sending to any targets
+    pub target_addr: Option<Address>,
+    /// Bytes sent from the client to the target
+    pub l2r_bytes: u64,
+    /// Bytes sent from the target back to the client
+    pub r2l_bytes: u64,
+    pub start_time: SystemTime,
+    pub end_time: SystemTime,
+    /// Server relaying the flow, `None` if it is not proxied
+    pub server_addr: Option<String>,
+    pub verdict: FlowVerdict,
+    /// ACL rule deciding the verdict, `None` if it wasn't checked
+    pub rule: Option<&'static str>,
+}
+
+/// Exporter of flow records, sending them to `FlowExportServer`
+#[derive(Clone)]
+pub struct FlowExporter {
+    sender: mpsc::Sender<FlowRecord>,
+}
+
+impl FlowExporter {
+    /// Export a record of a closed flow
+    pub fn export(&self, record: FlowRecord) {
+        match self.sender.try_send(record) {
+            Ok(..) => {}
+            Err(TrySendError::Full(record)) => {
+                debug!(
+                    "flow record of {} dropped, the exporter couldn't keep up",
+                    record.peer_addr
+                );
+            }
+            Err(TrySendError::Closed(..)) => {}
+        }
+    }
+}
+
+/// Server sending flow records to the collector
+pub struct FlowExportServer {
+    config: FlowExportConfig,
+    sender: mpsc::Sender<FlowRecord>,
+    receiver: mpsc::Receiver<FlowRecord>,
+}
+
+impl FlowExportServer {
+    /// Create with the configuration of exporting
+    pub fn new(config: FlowExportConfig) -> FlowExportServer {
+        let (sender, receiver) = mpsc::channel(RECORD_CHANNEL_SIZE);
+        FlowExportServer {
+            config,
+            sender,
+            receiver,
+        }
+    }
+
+    /// Exporter of records sent by this server
+    pub fn exporter(&self) -> FlowExporter {
+        FlowExporter {
+            sender: self.sender.clone(),
+        }
+    }
+
+    /// Run server, resolving the collector with `context`
+    pub async fn run(mut self, context: SharedContext) -> io::Result<()> {
+        let collector = &self.config.collector;
+        let socket = match UdpSocket::connect_server_with_opts(&context, collector, &ConnectOpts::default()).await {
+            Ok(s) => s,
+            Err(err) => {
+                error!("flow export to {} failed to connect, error: {}", collector, err);
+                return Err(err);
+            }
+        };
+
+        info!("shadowsocks flow export to IPFIX collector {}", collector);
+
+        let mut encoder = MessageEncoder::new(&self.config);
+        let mut records = Vec::new();
+        let mut interval = time::interval(FLUSH_INTERVAL);
+
+        loop {
+            tokio::select! {
+                record = self.receiver.recv() => match record {
+                    Some(record) => records.push(record),
+                    None => return Ok(()),
+                },
+                _ = interval.tick() => {
+                    if records.is_empty() {
+                        continue;
+                    }
+
+                    for message in encoder.encode(&records) {
+                        if let Err(err) = socket.send(&message).await {
+                            error!("flow export to {} failed, error: {}", collector, err);
+                        }
+                    }
+                    trace!("exported {} flow records", records.len());
+                    records.clear();
+                }
+            }
+        }
+    }
+}
+
+/// Encoder of IPFIX messages, keeping the sequence number and when templates were sent
+struct MessageEncoder {
+    observation_domain_id: u32,
+    templates: BytesMut,
+    sequence_number: u32,
+    templates_sent: Option<Instant>,
+}
+
+impl MessageEncoder {
+    fn new(config: &FlowExportConfig) -> MessageEncoder {
+        let mut templates = BytesMut::new();
+        templates.put_u16(TEMPLATE_SET_ID);
+        templates.put_u16(0);
+        put_template(&mut templates, IPV4_TEMPLATE_ID, config.enterprise_number);
+        put_template(&mut templates, IPV6_TEMPLATE_ID, config.enterprise_number);
+        let len = templates.len() as u16;
+        templates[2..4].copy_from_slice(&len.to_be_bytes());
+
+        MessageEncoder {
+            observation_domain_id: config.observation_domain_id,
+            templates,
+            sequence_number: 0,
+            templates_sent: None,
+        }
+    }
+
+    /// Encode `records` into messages, each of them is sent in a packet
+    fn encode(&mut self, records: &[FlowRecord]) -> Vec<BytesMut> {
+        let mut messages = Vec::new();
+        let mut message = self.start_message();
+        let mut message_records = 0;
+        // Template ID and offset of the current data set
+        let mut data_set: Option<(u16, usize)> = None;
+
+        for record in records {
+            let mut data = BytesMut::new();
+            let template_id = put_record(&mut data, record);
+
+            let set_size = match data_set {
+                Some((id, _)) if id == template_id => 0,
+                _ => SET_HEADER_SIZE,
+            };
+            if message.len() + set_size + data.len() > MAX_MESSAGE_SIZE && message_records > 0 {
+                finish_set(&mut message, data_set.take());
+                messages.push(self.finish_message(message, message_records));
+                message = self.start_message();
+                message_records = 0;
+            }
+
+            match data_set {
+                Some((id, _)) if id == template_id => {}
+                _ => {
+                    finish_set(&mut message, data_set.take());
+                    data_set = Some((template_id, message.len()));
+                    message.put_u16(template_id);
+                    message.put_u16(0);
+                }
+            }
+            message.extend_from_slice(&data);
+            message_records += 1;
+        }
+
+        if message_records > 0 {
+            finish_set(&mut message, data_set);
+            messages.push(self.finish_message(message, message_records));
+        }
+        messages
+    }
+
+    fn start_message(&mut self) -> BytesMut {
+        let mut message = BytesMut::with_capacity(MAX_MESSAGE_SIZE);
+        message.put_slice(&[0u8; MESSAGE_HEADER_SIZE]);
+
+        let now = Instant::now();
+        let refresh = match self.templates_sent {
+            Some(sent) => now - sent >= TEMPLATE_REFRESH_INTERVAL,
+            None => true,
+        };
+        if refresh {
+            message.extend_from_slice(&self.templates);
+            self.templates_sent = Some(now);
+        }
+        message
+    }
+
+    fn finish_message(&mut self, mut message: BytesMut, records: u32) -> BytesMut {
+        let export_time = unix_millis(SystemTime::now()) / 1000;
+
+        let len = message.len() as u16;
+        let mut header = &mut message[..MESSAGE_HEADER_SIZE];
+        header.put_u16(IPFIX_VERSION);
+        header.put_u16(len);
+        header.put_u32(export_time as u32);
+        // Count of data records sent before this message
+        header.put_u32(self.sequence_number);
+        header.put_u32(self.observation_domain_id);
+
+        self.sequence_number = self.sequence_number.wrapping_add(records);
+        message
+    }
+}
+
+fn finish_set(message: &mut BytesMut, data_set: Option<(u16, usize)>) {
+    if let Some((_, offset)) = data_set {
+        let len = (message.len() - offset) as u16;
+        message[offset + 2..offset + 4].copy_from_slice(&len.to_be_bytes());
+    }
+}
+
+fn put_template(buf: &mut BytesMut, template_id: u16, enterprise_number: u32) {
+    let (source, destination, address_len) = if template_id == IPV4_TEMPLATE_ID {
+        (IE_SOURCE_IPV4_ADDRESS, IE_DESTINATION_IPV4_ADDRESS, 4)
+    } else {
+        (IE_SOURCE_IPV6_ADDRESS, IE_DESTINATION_IPV6_ADDRESS, 16)
+    };
+
+    // In the order of `put_record`
+    let fields = [
+        (source, address_len, None),
+        (IE_SOURCE_TRANSPORT_PORT, 2, None),
+        (destination, address_len, None),
+        (IE_DESTINATION_TRANSPORT_PORT, 2, None),
+        (IE_PROTOCOL_IDENTIFIER, 1, None),
+        (IE_OCTET_DELTA_COUNT, 8, None),
+        (IE_OCTET_DELTA_COUNT, 8, Some(REVERSE_ENTERPRISE_NUMBER)),
+        (IE_FLOW_START_MILLISECONDS, 8, None),
+        (IE_FLOW_END_MILLISECONDS, 8, None),
+        (IE_TARGET_DOMAIN, VARIABLE_LENGTH, Some(enterprise_number)),
+        (IE_SERVER, VARIABLE_LENGTH, Some(enterprise_number)),
+        (IE_VERDICT, VARIABLE_LENGTH, Some(enterprise_number)),
+        (IE_RULE, VARIABLE_LENGTH, Some(enterprise_number)),
+    ];
+
+    buf.put_u16(template_id);
+    buf.put_u16(fields.len() as u16);
+    for (id, len, enterprise_number) in fields {
+        match enterprise_number {
+            Some(n) => {
+                buf.put_u16(id | ENTERPRISE_BIT);
+                buf.put_u16(len);
+                buf.put_u32(n);
+            }
+            None => {
+                buf.put_u16(id);
+                buf.put_u16(len);
+            }
+        }
+    }
+}
+
+/// Put a data record of `record`, returns ID of its template
+fn put_record(buf: &mut BytesMut, record: &FlowRecord) -> u16 {
+    let (target_ip, target_port, target_domain) = match record.target_addr {
+        Some(Address::SocketAddress(ref sa)) => (Some(sa.ip()), sa.port(), None),
+        Some(Address::DomainNameAddress(ref dname, port)) => (None, port, Some(dname.as_str())),
+        None => (None, 0, None),
+    };
+
+    // Addresses of both sides are in the same family, IPv4 addresses are mapped if the other side is IPv6
+    let template_id = match (record.peer_addr.ip(), target_ip) {
+        (IpAddr::V4(..), None | Some(IpAddr::V4(..))) => IPV4_TEMPLATE_ID,
+        _ => IPV6_TEMPLATE_ID,
+    };
+    let put_ip = |buf: &mut BytesMut, ip: Option<IpAddr>| match (template_id, ip) {
+        (IPV4_TEMPLATE_ID, Some(IpAddr::V4(ip))) => buf.put_slice(&ip.octets()),
+        (IPV4_TEMPLATE_ID, _) => buf.put_slice(&Ipv4Addr::UNSPECIFIED.octets()),
+        (_, Some(IpAddr::V4(ip))) => buf.put_slice(&ip.to_ipv6_mapped().octets()),
+        (_, Some(IpAddr::V6(ip))) => buf.put_slice(&ip.octets()),
+        (_, None) => buf.put_slice(&Ipv6Addr::UNSPECIFIED.octets()),
+    };
+
+    put_ip(buf, Some(record.peer_addr.ip()));
+    buf.put_u16(record.peer_addr.port());
+    put_ip(buf, target_ip);
+    buf.put_u16(target_port);
+    buf.put_u8(record.protocol);
+    buf.put_u64(record.l2r_bytes);
+    buf.put_u64(record.r2l_bytes);
+    buf.put_u64(unix_millis(record.start_time));
+    buf.put_u64(unix_millis(record.end_time));
+    put_string(buf, target_domain.unwrap_or_default());
+    put_string(buf, record.server_addr.as_deref().unwrap_or_default());
+    put_string(buf, record.verdict.as_str());
+    put_string(buf, record.rule.unwrap_or_default());
+
+    template_id
+}
+
+/// Put a variable-length string, with its length in 1 byte, or 3 bytes if it is longer than 254 bytes
+fn put_string(buf: &mut BytesMut, s: &str) {
+    let s = &s.as_bytes()[..s.len().min(u16::MAX as usize)];
+    if s.len() < 255 {
+        buf.put_u8(s.len() as u8);
+    } else {
+        buf.put_u8(255);
+        buf.put_u16(s.len() as u16);
+    }
+    buf.put_slice(s);
+}
+
+fn unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
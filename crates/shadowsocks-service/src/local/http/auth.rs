@@ -0,0 +1,565 @@
+//! HTTP proxy authentication
+//!
+//! Basic (RFC7617) and Digest (RFC7616) authentication with `Proxy-Authorization` and `Proxy-Authenticate` headers
+
+use std::{
+    collections::HashMap,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use hyper::{header::HeaderValue, Body, Request, Response, StatusCode};
+use log::{debug, trace};
+use md5::{Digest, Md5};
+use spin::Mutex as SpinMutex;
+
+use super::config::{HttpAuthConfig, HttpAuthScheme};
+
+/// Nonces of Digest challenges are valid in this duration
+const DIGEST_NONCE_EXPIRY_DURATION: Duration = Duration::from_secs(5 * 60);
+
+enum AuthError {
+    /// Credential is missing or invalid
+    Unauthorized,
+    /// Digest nonce is expired or has been used, client should retry with a new nonce
+    StaleNonce,
+}
+
+/// HTTP proxy authenticator
+pub struct HttpAuthenticator {
+    config: HttpAuthConfig,
+    nonce_secret: [u8; 16],
+    // Highest `nc` of nonces accepted, 0 for requests without `qop`
+    used_nonces: SpinMutex<HashMap<String, u32>>,
+}
+
+impl HttpAuthenticator {
+    /// Create an authenticator from configuration
+    pub fn new(config: HttpAuthConfig) -> HttpAuthenticator {
+        HttpAuthenticator {
+            config,
+            nonce_secret: rand::random(),
+            used_nonces: SpinMutex::new(HashMap::new()),
+        }
+    }
+
+    /// Check if authentication is required
+    pub fn auth_required(&self) -> bool {
+        self.config.auth_required()
+    }
+
+    /// Check `Proxy-Authorization` of `req`
+    ///
+    /// Returns a `407 Proxy Authentication Required` response with challenge if failed
+    pub fn check(&self, req: &Request<Body>) -> Option<Response<Body>> {
+        if !self.auth_required() {
+            return None;
+        }
+
+        let credential = match req.headers().get("Proxy-Authorization").map(HeaderValue::to_str) {
+            Some(Ok(c)) => c,
+            Some(Err(..)) => {
+                debug!(
+                    "HTTP {} {} Proxy-Authorization invalid encoding",
+                    req.method(),
+                    req.uri()
+                );
+                return Some(self.make_challenge(false));
+            }
+            None => {
+                trace!("HTTP {} {} missing Proxy-Authorization", req.method(), req.uri());
+                return Some(self.make_challenge(false));
+            }
+        };
+
+        let result = match self.config.scheme() {
+            HttpAuthScheme::Basic => self.check_basic(credential),
+            HttpAuthScheme::Digest => self.check_digest(req, credential),
+        };
+
+        match result {
+            Ok(()) => None,
+            Err(AuthError::Unauthorized) => {
+                debug!("HTTP {} {} authentication failed", req.method(), req.uri());
+                Some(self.make_challenge(false))
+            }
+            Err(AuthError::StaleNonce) => {
+                trace!("HTTP {} {} authentication nonce is stale", req.method(), req.uri());
+                Some(self.make_challenge(true))
+            }
+        }
+    }
+
+    fn check_basic(&self, credential: &str) -> Result<(), AuthError> {
+        let encoded = match split_scheme(credential, "Basic") {
+            Some(e) => e,
+            None => return Err(AuthError::Unauthorized),
+        };
+
+        let decoded = match base64::decode(encoded) {
+            Ok(d) => d,
+            Err(..) => return Err(AuthError::Unauthorized),
+        };
+        let decoded = match String::from_utf8(decoded) {
+            Ok(d) => d,
+            Err(..) => return Err(AuthError::Unauthorized),
+        };
+
+        let (user_name, password) = match decoded.split_once(':') {
+            Some(p) => p,
+            None => return Err(AuthError::Unauthorized),
+        };
+
+        match self.config.password(user_name) {
+            Some(p) if constant_time_eq(p.as_bytes(), password.as_bytes()) => Ok(()),
+            _ => Err(AuthError::Unauthorized),
+        }
+    }
+
+    fn check_digest(&self, req: &Request<Body>, credential: &str) -> Result<(), AuthError> {
+        let params = match split_scheme(credential, "Digest") {
+            Some(p) => parse_auth_params(p),
+            None => return Err(AuthError::Unauthorized),
+        };
+
+        let param = |name: &str| params.get(name).map(String::as_str);
+
+        let (user_name, realm, nonce, uri, response) = match (
+            param("username"),
+            param("realm"),
+            param("nonce"),
+            param("uri"),
+            param("response"),
+        ) {
+            (Some(a), Some(b), Some(c), Some(d), Some(e)) => (a, b, c, d, e),
+            _ => return Err(AuthError::Unauthorized),
+        };
+
+        if realm != self.config.realm() || *req.uri() != *uri {
+            return Err(AuthError::Unauthorized);
+        }
+
+        if let Some(algorithm) = param("algorithm") {
+            if !algorithm.eq_ignore_ascii_case("MD5") {
+                return Err(AuthError::Unauthorized);
+            }
+        }
+
+        let password = match self.config.password(user_name) {
+            Some(p) => p,
+            None => return Err(AuthError::Unauthorized),
+        };
+
+        let qop = match param("qop") {
+            None => None,
+            Some("auth") => match (param("nc"), param("cnonce")) {
+                (Some(nc), Some(cnonce)) => Some((nc, cnonce)),
+                _ => return Err(AuthError::Unauthorized),
+            },
+            Some(..) => return Err(AuthError::Unauthorized),
+        };
+        let nonce_count = match qop {
+            None => 0,
+            Some((nc, ..)) => match u32::from_str_radix(nc, 16) {
+                Ok(n) if n > 0 => n,
+                _ => return Err(AuthError::Unauthorized),
+            },
+        };
+        let expected = digest_response(user_name, realm, password, req.method().as_str(), uri, nonce, qop);
+
+        if !constant_time_eq(expected.as_bytes(), response.as_bytes()) {
+            return Err(AuthError::Unauthorized);
+        }
+
+        // Checks nonce at last, stale nonce is only reported for valid credentials
+        self.check_nonce(nonce)?;
+        self.use_nonce(nonce, nonce_count)
+    }
+
+    /// Nonce is `TIMESTAMP (hex, 16 bytes) + MD5(TIMESTAMP:SECRET)`, which could be verified without keeping states
+    fn make_nonce(&self, timestamp: u64) -> String {
+        let timestamp = format!("{:016x}", timestamp);
+
+        let mut hasher = Md5::new();
+        hasher.update(timestamp.as_bytes());
+        hasher.update(b":");
+        hasher.update(self.nonce_secret);
+
+        format!("{}{:x}", timestamp, hasher.finalize())
+    }
+
+    fn check_nonce(&self, nonce: &str) -> Result<(), AuthError> {
+        let timestamp = match nonce.get(..16).map(|t| u64::from_str_radix(t, 16)) {
+            Some(Ok(t)) => t,
+            _ => return Err(AuthError::Unauthorized),
+        };
+
+        if !constant_time_eq(self.make_nonce(timestamp).as_bytes(), nonce.as_bytes()) {
+            return Err(AuthError::Unauthorized);
+        }
+
+        if nonce_expired(timestamp) {
+            return Err(AuthError::StaleNonce);
+        }
+
+        Ok(())
+    }
+
+    /// Records `nonce_count` of `nonce`, which must be greater than the ones used before, so that requests couldn't be
+    /// replayed. Nonces without `qop` (`nonce_count` is 0) could only be used once
+    fn use_nonce(&self, nonce: &str, nonce_count: u32) -> Result<(), AuthError> {
+        let mut used_nonces = self.used_nonces.lock();
+        match used_nonces.get_mut(nonce) {
+            Some(used) => {
+                if nonce_count == 0 || nonce_count <= *used {
+                    return Err(AuthError::StaleNonce);
+                }
+                *used = nonce_count;
+            }
+            None => {
+                // Expired nonces are rejected by `check_nonce`, forgets them
+                used_nonces.retain(|n, _| match n.get(..16).map(|t| u64::from_str_radix(t, 16)) {
+                    Some(Ok(t)) => !nonce_expired(t),
+                    _ => false,
+                });
+                used_nonces.insert(nonce.to_owned(), nonce_count);
+            }
+        }
+        Ok(())
+    }
+
+    fn make_challenge(&self, stale: bool) -> Response<Body> {
+        let challenge = match self.config.scheme() {
+            HttpAuthScheme::Basic => format!("Basic realm=\"{}\", charset=\"UTF-8\"", self.config.realm()),
+            HttpAuthScheme::Digest => format!(
+                "Digest realm=\"{}\", qop=\"auth\", algorithm=MD5, nonce=\"{}\", stale={}",
+                self.config.realm(),
+                self.make_nonce(unix_timestamp()),
+                if stale { "true" } else { "false" }
+            ),
+        };
+
+        Response::builder()
+            .status(StatusCode::PROXY_AUTHENTICATION_REQUIRED)
+            .header("Proxy-Authenticate", challenge)
+            .body(Body::empty())
+            .unwrap()
+    }
+}
+
+fn nonce_expired(timestamp: u64) -> bool {
+    unix_timestamp().saturating_sub(timestamp) > DIGEST_NONCE_EXPIRY_DURATION.as_secs()
+}
+
+/// Compares `a` and `b` in time depending only on their lengths
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn unix_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+fn md5_hex(parts: &[&str]) -> String {
+    let mut hasher = Md5::new();
+    for (idx, part) in parts.iter().enumerate() {
+        if idx > 0 {
+            hasher.update(b":");
+        }
+        hasher.update(part.as_bytes());
+    }
+    format!("{:x}", hasher.finalize())
+}
+
+/// Digest `response` of MD5 (RFC2617), `qop` is `(nc, cnonce)` of `qop=auth`, or `None` for RFC2069 compatibility
+fn digest_response(
+    user_name: &str,
+    realm: &str,
+    password: &str,
+    method: &str,
+    uri: &str,
+    nonce: &str,
+    qop: Option<(&str, &str)>,
+) -> String {
+    let ha1 = md5_hex(&[user_name, realm, password]);
+    let ha2 = md5_hex(&[method, uri]);
+    match qop {
+        None => md5_hex(&[&ha1, nonce, &ha2]),
+        Some((nc, cnonce)) => md5_hex(&[&ha1, nonce, nc, cnonce, "auth", &ha2]),
+    }
+}
+
+/// Strips `scheme` (case insensitive) from `credential`
+fn split_scheme<'a>(credential: &'a str, scheme: &str) -> Option<&'a str> {
+    let (s, params) = credential.trim().split_once(' ')?;
+    if s.eq_ignore_ascii_case(scheme) {
+        Some(params.trim())
+    } else {
+        None
+    }
+}
+
+/// Parses `key=value, key="quoted, value"` lists, keys are in lower case
+fn parse_auth_params(s: &str) -> HashMap<String, String> {
+    let mut params = HashMap::new();
+    let mut chars = s.chars().peekable();
+
+    loop {
+        // Skips separators
+        while let Some(c) = chars.peek() {
+            if *c == ',' || c.is_whitespace() {
+                chars.next();
+            } else {
+                break;
+            }
+        }
+
+        let mut key = String::new();
+        for c in chars.by_ref() {
+            if c == '=' {
+                break;
+            }
+            key.push(c);
+        }
+
+        if key.is_empty() {
+            break;
+        }
+
+        let mut value = String::new();
+        if chars.peek() == Some(&'"') {
+            chars.next();
+            while let Some(c) = chars.next() {
+                match c {
+                    '"' => break,
+                    '\\' => {
+                        if let Some(c) = chars.next() {
+                            value.push(c);
+                        }
+                    }
+                    c => value.push(c),
+                }
+            }
+        } else {
+            while let Some(c) = chars.peek() {
+                if *c == ',' {
+                    break;
+                }
+                value.push(*c);
+                chars.next();
+            }
+        }
+
+        params.insert(key.trim().to_ascii_lowercase(), value.trim().to_owned());
+    }
+
+    params
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn authenticator(scheme: HttpAuthScheme) -> HttpAuthenticator {
+        let mut config = HttpAuthConfig::new();
+        config.set_scheme(scheme);
+        config.add_user("user", "password");
+        HttpAuthenticator::new(config)
+    }
+
+    fn request(credential: Option<HeaderValue>) -> Request<Body> {
+        let mut req = Request::get("http://example.com/index.html")
+            .body(Body::empty())
+            .unwrap();
+        if let Some(credential) = credential {
+            req.headers_mut().insert("Proxy-Authorization", credential);
+        }
+        req
+    }
+
+    fn check(auth: &HttpAuthenticator, credential: &str) -> Option<Response<Body>> {
+        auth.check(&request(Some(HeaderValue::from_str(credential).unwrap())))
+    }
+
+    fn challenge(resp: Option<Response<Body>>) -> String {
+        let resp = resp.expect("request is accepted");
+        assert_eq!(resp.status(), StatusCode::PROXY_AUTHENTICATION_REQUIRED);
+        resp.headers()["Proxy-Authenticate"].to_str().unwrap().to_owned()
+    }
+
+    fn digest_credential(auth: &HttpAuthenticator, password: &str, nonce: &str) -> String {
+        digest_credential_nc(auth, password, nonce, "00000001")
+    }
+
+    fn digest_credential_nc(auth: &HttpAuthenticator, password: &str, nonce: &str, nc: &str) -> String {
+        let uri = "http://example.com/index.html";
+        let response = digest_response(
+            "user",
+            "shadowsocks",
+            password,
+            "GET",
+            uri,
+            nonce,
+            Some((nc, "0a4f113b")),
+        );
+        format!(
+            "Digest username=\"user\", realm=\"{}\", nonce=\"{}\", uri=\"{}\", qop=auth, nc={}, \
+             cnonce=\"0a4f113b\", response=\"{}\", algorithm=MD5",
+            auth.config.realm(),
+            nonce,
+            uri,
+            nc,
+            response
+        )
+    }
+
+    #[test]
+    fn basic() {
+        let auth = authenticator(HttpAuthScheme::Basic);
+
+        assert!(check(&auth, &format!("Basic {}", base64::encode("user:password"))).is_none());
+        assert!(check(&auth, &format!("basic  {} ", base64::encode("user:password"))).is_none());
+
+        assert_eq!(
+            challenge(auth.check(&request(None))),
+            "Basic realm=\"shadowsocks\", charset=\"UTF-8\""
+        );
+
+        // Wrong credentials
+        challenge(check(&auth, &format!("Basic {}", base64::encode("user:wrong"))));
+        challenge(check(&auth, &format!("Basic {}", base64::encode("nobody:password"))));
+    }
+
+    #[test]
+    fn malformed() {
+        let auth = authenticator(HttpAuthScheme::Basic);
+
+        for credential in [
+            "Basic",
+            "Basic !!!",
+            "Bearer dXNlcjpwYXNzd29yZA==",
+            &format!("Basic {}", base64::encode("user password")),
+            &format!("Basic {}", base64::encode(b"user:\xffpassword")),
+        ] {
+            challenge(check(&auth, credential));
+        }
+
+        // Not UTF-8
+        let credential = HeaderValue::from_bytes(b"Basic \xe7\x94\xa8\xe6\x88\xb7").unwrap();
+        challenge(auth.check(&request(Some(credential))));
+
+        let auth = authenticator(HttpAuthScheme::Digest);
+        for credential in [
+            "Digest",
+            "Digest username=\"user\"",
+            "Digest username=\"user\", realm=\"shadowsocks\", nonce=\"0\", uri=\"/\", response=\"0\", qop=auth-int",
+        ] {
+            assert!(challenge(check(&auth, credential)).ends_with("stale=false"));
+        }
+    }
+
+    #[test]
+    fn digest_rfc2617() {
+        // Example of RFC2617 section 3.5
+        let response = digest_response(
+            "Mufasa",
+            "testrealm@host.com",
+            "Circle Of Life",
+            "GET",
+            "/dir/index.html",
+            "dcd98b7102dd2f0e8b11d0f600bfb0c093",
+            Some(("00000001", "0a4f113b")),
+        );
+        assert_eq!(response, "6629fae49393a05397450978507c4ef1");
+
+        assert_eq!(
+            md5_hex(&["Mufasa", "testrealm@host.com", "Circle Of Life"]),
+            "939e7578ed9e3c518a452acee763bce9"
+        );
+        assert_eq!(md5_hex(&["GET", "/dir/index.html"]), "39aff3a2bab6126f332b942af96d3366");
+    }
+
+    #[test]
+    fn digest() {
+        let auth = authenticator(HttpAuthScheme::Digest);
+
+        let challenge_header = challenge(auth.check(&request(None)));
+        assert!(challenge_header.starts_with("Digest realm=\"shadowsocks\", qop=\"auth\", algorithm=MD5, nonce=\""));
+        let nonce = parse_auth_params(challenge_header.strip_prefix("Digest ").unwrap())["nonce"].clone();
+
+        assert!(check(&auth, &digest_credential(&auth, "password", &nonce)).is_none());
+
+        // Wrong credentials
+        let resp = check(&auth, &digest_credential(&auth, "wrong", &nonce));
+        assert!(challenge(resp).ends_with("stale=false"));
+
+        // Nonces not made by this authenticator
+        let nonce = format!("{:016x}{}", unix_timestamp(), "0".repeat(32));
+        let resp = check(&auth, &digest_credential(&auth, "password", &nonce));
+        assert!(challenge(resp).ends_with("stale=false"));
+    }
+
+    #[test]
+    fn digest_reused_nonce() {
+        let auth = authenticator(HttpAuthScheme::Digest);
+
+        let nonce = auth.make_nonce(unix_timestamp());
+        let credential = digest_credential(&auth, "password", &nonce);
+        assert!(check(&auth, &credential).is_none());
+
+        // Replayed requests are rejected, clients could retry with a new nonce
+        assert!(challenge(check(&auth, &credential)).ends_with("stale=true"));
+
+        // Nonces could be used again with greater `nc`
+        assert!(check(&auth, &digest_credential_nc(&auth, "password", &nonce, "00000002")).is_none());
+        let resp = check(&auth, &digest_credential_nc(&auth, "password", &nonce, "00000002"));
+        assert!(challenge(resp).ends_with("stale=true"));
+        let resp = check(&auth, &digest_credential_nc(&auth, "password", &nonce, "00000000"));
+        assert!(challenge(resp).ends_with("stale=false"));
+
+        // Nonces without `qop` could only be used once
+        let nonce = auth.make_nonce(unix_timestamp() - 1);
+        let response = digest_response(
+            "user",
+            "shadowsocks",
+            "password",
+            "GET",
+            "http://example.com/index.html",
+            &nonce,
+            None,
+        );
+        let credential = format!(
+            "Digest username=\"user\", realm=\"shadowsocks\", nonce=\"{}\", uri=\"http://example.com/index.html\", \
+             response=\"{}\"",
+            nonce, response
+        );
+        assert!(check(&auth, &credential).is_none());
+        assert!(challenge(check(&auth, &credential)).ends_with("stale=true"));
+    }
+
+    #[test]
+    fn compare_constant_time() {
+        assert!(constant_time_eq(b"password", b"password"));
+        assert!(!constant_time_eq(b"password", b"passwore"));
+        assert!(!constant_time_eq(b"password", b"pass"));
+        assert!(constant_time_eq(b"", b""));
+    }
+
+    #[test]
+    fn digest_stale_nonce() {
+        let auth = authenticator(HttpAuthScheme::Digest);
+
+        let nonce = auth.make_nonce(unix_timestamp() - DIGEST_NONCE_EXPIRY_DURATION.as_secs() - 1);
+        let resp = check(&auth, &digest_credential(&auth, "password", &nonce));
+        assert!(challenge(resp).ends_with("stale=true"));
+
+        // Stale nonces are only reported for valid credentials
+        let resp = check(&auth, &digest_credential(&auth, "wrong", &nonce));
+        assert!(challenge(resp).ends_with("stale=false"));
+    }
+}
@@ -0,0 +1,416 @@
+//! HTTP response cache
+//!
+//! A small shared cache (RFC9111) for responses of `GET` requests. Only responses with explicit freshness
+//! (`Cache-Control: s-maxage / max-age` or `Expires`) will be stored, stale responses are evicted instead of
+//! being revalidated.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::{self, ErrorKind},
+    time::{Duration, Instant, SystemTime},
+};
+
+use hyper::{
+    body,
+    header::{self, HeaderValue},
+    Body,
+    HeaderMap,
+    Method,
+    Request,
+    Response,
+    StatusCode,
+};
+use log::trace;
+use spin::Mutex as SpinMutex;
+
+use super::config::HttpCacheConfig;
+
+/// Status codes that are heuristically cacheable, RFC9110 15.1
+const CACHEABLE_STATUS_CODES: [u16; 11] = [200, 203, 204, 300, 301, 308, 404, 405, 410, 414, 501];
+
+/// `Cache-Control` directives
+#[derive(Default, Debug)]
+struct CacheControl {
+    no_store: bool,
+    no_cache: bool,
+    private: bool,
+    public: bool,
+    must_revalidate: bool,
+    only_if_cached: bool,
+    max_age: Option<u64>,
+    s_maxage: Option<u64>,
+    min_fresh: Option<u64>,
+}
+
+impl CacheControl {
+    fn from_headers(headers: &HeaderMap) -> CacheControl {
+        let mut cc = CacheControl::default();
+
+        for value in headers.get_all(header::CACHE_CONTROL) {
+            let value = match value.to_str() {
+                Ok(v) => v,
+                Err(..) => continue,
+            };
+
+            for directive in value.split(',') {
+                let (name, arg) = match directive.split_once('=') {
+                    Some((n, a)) => (n.trim(), Some(a.trim().trim_matches('"'))),
+                    None => (directive.trim(), None),
+                };
+                let seconds = arg.and_then(|a| a.parse::<u64>().ok());
+
+                match name.to_ascii_lowercase().as_str() {
+                    "no-store" => cc.no_store = true,
+                    // no-cache="field-name" is treated as no-cache
+                    "no-cache" => cc.no_cache = true,
+                    "private" => cc.private = true,
+                    "public" => cc.public = true,
+                    "must-revalidate" | "proxy-revalidate" => cc.must_revalidate = true,
+                    "only-if-cached" => cc.only_if_cached = true,
+                    "max-age" => cc.max_age = seconds.or(Some(0)),
+                    "s-maxage" => cc.s_maxage = seconds.or(Some(0)),
+                    "min-fresh" => cc.min_fresh = seconds,
+                    _ => {}
+                }
+            }
+        }
+
+        cc
+    }
+}
+
+/// A request that its response could be stored
+pub struct CacheableRequest {
+    key: String,
+    headers: HeaderMap,
+    request_time: Instant,
+}
+
+struct CachedResponse {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: body::Bytes,
+    /// Request headers selected by `Vary`
+    vary: Vec<(String, Vec<HeaderValue>)>,
+    /// Freshness lifetime
+    lifetime: Duration,
+    /// Corrected initial age, RFC9111 4.2.3
+    initial_age: Duration,
+    response_time: Instant,
+    access_seq: u64,
+}
+
+impl CachedResponse {
+    fn size(&self) -> usize {
+        let headers_size: usize = self
+            .headers
+            .iter()
+            .map(|(name, value)| name.as_str().len() + value.len())
+            .sum();
+        self.body.len() + headers_size
+    }
+
+    fn current_age(&self) -> Duration {
+        self.initial_age + self.response_time.elapsed()
+    }
+
+    fn matches_vary(&self, headers: &HeaderMap) -> bool {
+        self.vary.iter().all(|(name, values)| {
+            let req_values = headers.get_all(name.as_str()).iter().cloned().collect::<Vec<_>>();
+            req_values == *values
+        })
+    }
+}
+
+struct CacheInner {
+    entries: HashMap<String, CachedResponse>,
+    /// Access order, for evicting the least recently used entries
+    lru: BTreeMap<u64, String>,
+    size: usize,
+    access_seq: u64,
+}
+
+impl CacheInner {
+    fn remove(&mut self, key: &str) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.lru.remove(&entry.access_seq);
+            self.size -= entry.size();
+        }
+    }
+
+    fn touch(&mut self, key: &str) {
+        self.access_seq += 1;
+        let seq = self.access_seq;
+
+        if let Some(entry) = self.entries.get_mut(key) {
+            self.lru.remove(&entry.access_seq);
+            entry.access_seq = seq;
+            self.lru.insert(seq, key.to_owned());
+        }
+    }
+}
+
+/// Size bounded HTTP response cache
+pub struct HttpResponseCache {
+    inner: SpinMutex<CacheInner>,
+    capacity: usize,
+    max_object_size: usize,
+}
+
+impl HttpResponseCache {
+    /// Create a cache from configuration
+    pub fn new(config: &HttpCacheConfig) -> HttpResponseCache {
+        HttpResponseCache {
+            inner: SpinMutex::new(CacheInner {
+                entries: HashMap::new(),
+                lru: BTreeMap::new(),
+                size: 0,
+                access_seq: 0,
+            }),
+            capacity: config.capacity,
+            max_object_size: config.max_object_size.min(config.capacity),
+        }
+    }
+
+    /// Lookup a fresh response of `req`
+    ///
+    /// Requests with `only-if-cached` will get `504 Gateway Timeout` if the cache missed
+    pub fn lookup(&self, req: &Request<Body>) -> Option<Response<Body>> {
+        if req.method() != Method::GET || req.headers().contains_key(header::RANGE) {
+            return None;
+        }
+
+        let cc = CacheControl::from_headers(req.headers());
+        if cc.no_store || cc.no_cache || (!req.headers().contains_key(header::CACHE_CONTROL) && is_pragma_no_cache(req))
+        {
+            return None;
+        }
+
+        let key = req.uri().to_string();
+
+        let mut inner = self.inner.lock();
+
+        let (age, remaining) = match inner.entries.get(&key) {
+            Some(entry) if entry.matches_vary(req.headers()) => {
+                let age = entry.current_age();
+                if age >= entry.lifetime {
+                    trace!("HTTP cache {} stale, age {:?}", key, age);
+                    inner.remove(&key);
+                    return make_cache_missed(&cc);
+                }
+                (age, entry.lifetime - age)
+            }
+            _ => return make_cache_missed(&cc),
+        };
+
+        if let Some(max_age) = cc.max_age {
+            if age.as_secs() > max_age {
+                return make_cache_missed(&cc);
+            }
+        }
+        if let Some(min_fresh) = cc.min_fresh {
+            if remaining.as_secs() < min_fresh {
+                return make_cache_missed(&cc);
+            }
+        }
+
+        inner.touch(&key);
+
+        let entry = &inner.entries[&key];
+
+        let mut resp = Response::new(Body::from(entry.body.clone()));
+        *resp.status_mut() = entry.status;
+        *resp.headers_mut() = entry.headers.clone();
+        resp.headers_mut().insert(header::AGE, HeaderValue::from(age.as_secs()));
+
+        trace!("HTTP cache {} hit, age {:?}", key, age);
+
+        Some(resp)
+    }
+
+    /// Check if response of `req` could be stored
+    ///
+    /// Unsafe requests invalidate the stored response of the same URI
+    pub fn cacheable_request(&self, req: &Request<Body>) -> Option<CacheableRequest> {
+        let key = req.uri().to_string();
+
+        if !req.method().is_safe() {
+            self.inner.lock().remove(&key);
+            return None;
+        }
+
+        if req.method() != Method::GET || req.headers().contains_key(header::RANGE) {
+            return None;
+        }
+
+        let cc = CacheControl::from_headers(req.headers());
+        if cc.no_store {
+            return None;
+        }
+
+        Some(CacheableRequest {
+            key,
+            headers: req.headers().clone(),
+            request_time: Instant::now(),
+        })
+    }
+
+    /// Store `res` if it is cacheable
+    ///
+    /// Body of the stored response is buffered, so a new response will be returned
+    pub async fn store(&self, req: CacheableRequest, res: Response<Body>) -> io::Result<Response<Body>> {
+        let lifetime = match self.freshness_lifetime(&req, &res) {
+            Some(l) => l,
+            None => return Ok(res),
+        };
+
+        let content_length = res
+            .headers()
+            .get(header::CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse::<usize>().ok());
+        match content_length {
+            Some(len) if len <= self.max_object_size => {}
+            _ => return Ok(res),
+        }
+
+        let mut vary = Vec::new();
+        for value in res.headers().get_all(header::VARY) {
+            if let Ok(value) = value.to_str() {
+                for name in value.split(',') {
+                    let name = name.trim().to_ascii_lowercase();
+                    if name.is_empty() {
+                        continue;
+                    }
+                    if name == "*" {
+                        return Ok(res);
+                    }
+                    let values = req.headers.get_all(name.as_str()).iter().cloned().collect();
+                    vary.push((name, values));
+                }
+            }
+        }
+
+        let (parts, body) = res.into_parts();
+        let body = match body::to_bytes(body).await {
+            Ok(b) => b,
+            Err(err) => return Err(io::Error::new(ErrorKind::Other, err)),
+        };
+
+        let response_time = Instant::now();
+        let age_value = parts
+            .headers
+            .get(header::AGE)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse::<u64>().ok())
+            .unwrap_or(0);
+        let initial_age = Duration::from_secs(age_value) + (response_time - req.request_time);
+
+        let mut headers = parts.headers.clone();
+        headers.remove(header::AGE);
+
+        let entry = CachedResponse {
+            status: parts.status,
+            headers,
+            body: body.clone(),
+            vary,
+            lifetime,
+            initial_age,
+            response_time,
+            access_seq: 0,
+        };
+
+        if initial_age < lifetime {
+            self.insert(req.key, entry);
+        }
+
+        Ok(Response::from_parts(parts, Body::from(body)))
+    }
+
+    fn insert(&self, key: String, entry: CachedResponse) {
+        let size = entry.size();
+        if size > self.max_object_size {
+            return;
+        }
+
+        let mut inner = self.inner.lock();
+        inner.remove(&key);
+
+        while inner.size + size > self.capacity {
+            let lru_key = match inner.lru.iter().next() {
+                Some((_, k)) => k.clone(),
+                None => break,
+            };
+            trace!("HTTP cache {} evicted", lru_key);
+            inner.remove(&lru_key);
+        }
+
+        trace!(
+            "HTTP cache {} stored, {} bytes, lifetime {:?}",
+            key,
+            size,
+            entry.lifetime
+        );
+
+        inner.size += size;
+        inner.entries.insert(key.clone(), entry);
+        inner.touch(&key);
+    }
+
+    /// Explicit freshness lifetime of `res`, `None` if it shouldn't be stored
+    fn freshness_lifetime(&self, req: &CacheableRequest, res: &Response<Body>) -> Option<Duration> {
+        if !CACHEABLE_STATUS_CODES.contains(&res.status().as_u16()) {
+            return None;
+        }
+
+        let cc = CacheControl::from_headers(res.headers());
+        if cc.no_store || cc.no_cache || cc.private {
+            return None;
+        }
+
+        // Responses with authorization could only be stored if explicitly allowed, RFC9111 3.5
+        if req.headers.contains_key(header::AUTHORIZATION)
+            && !(cc.public || cc.must_revalidate || cc.s_maxage.is_some())
+        {
+            return None;
+        }
+
+        // Shared cache shouldn't store cookies for the other clients
+        if res.headers().contains_key(header::SET_COOKIE) {
+            return None;
+        }
+
+        if let Some(s) = cc.s_maxage.or(cc.max_age) {
+            return if s > 0 { Some(Duration::from_secs(s)) } else { None };
+        }
+
+        let expires = http_date(res.headers(), header::EXPIRES)?;
+        let date = http_date(res.headers(), header::DATE).unwrap_or_else(SystemTime::now);
+        match expires.duration_since(date) {
+            Ok(d) if !d.is_zero() => Some(d),
+            _ => None,
+        }
+    }
+}
+
+fn make_cache_missed(cc: &CacheControl) -> Option<Response<Body>> {
+    if cc.only_if_cached {
+        let mut resp = Response::new(Body::empty());
+        *resp.status_mut() = StatusCode::GATEWAY_TIMEOUT;
+        Some(resp)
+    } else {
+        None
+    }
+}
+
+fn http_date(headers: &HeaderMap, name: header::HeaderName) -> Option<SystemTime> {
+    let value = headers.get(name)?.to_str().ok()?;
+    httpdate::parse_http_date(value).ok()
+}
+
+fn is_pragma_no_cache(req: &Request<Body>) -> bool {
+    req.headers().get_all(header::PRAGMA).iter().any(|v| match v.to_str() {
+        Ok(v) => v.split(',').any(|p| p.trim().eq_ignore_ascii_case("no-cache")),
+        Err(..) => false,
+    })
+}
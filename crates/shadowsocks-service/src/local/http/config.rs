@@ -0,0 +1,191 @@
+//! HTTP protocol configuration
+
+use std::{
+    collections::HashMap,
+    fs::OpenOptions,
+    io::{self, ErrorKind, Read},
+    path::Path,
+    str::FromStr,
+};
+
+use log::trace;
+use serde::Deserialize;
+
+#[derive(Deserialize, Debug)]
+struct SSHttpAuthUserConfig {
+    user_name: String,
+    password: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct SSHttpAuthConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    scheme: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    realm: Option<String>,
+    users: Vec<SSHttpAuthUserConfig>,
+}
+
+/// HTTP proxy authentication scheme
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HttpAuthScheme {
+    /// Basic, RFC7617
+    Basic,
+    /// Digest, RFC7616, with MD5 algorithm and `auth` qop
+    Digest,
+}
+
+impl FromStr for HttpAuthScheme {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<HttpAuthScheme, ()> {
+        if s.eq_ignore_ascii_case("basic") {
+            Ok(HttpAuthScheme::Basic)
+        } else if s.eq_ignore_ascii_case("digest") {
+            Ok(HttpAuthScheme::Digest)
+        } else {
+            Err(())
+        }
+    }
+}
+
+/// HTTP proxy authentication configuration
+#[derive(Debug, Clone)]
+pub struct HttpAuthConfig {
+    scheme: HttpAuthScheme,
+    realm: String,
+    passwd: HashMap<String, String>,
+}
+
+impl HttpAuthConfig {
+    /// Create an empty configuration, which doesn't require authentication
+    pub fn new() -> HttpAuthConfig {
+        HttpAuthConfig {
+            scheme: HttpAuthScheme::Basic,
+            realm: "shadowsocks".to_owned(),
+            passwd: HashMap::new(),
+        }
+    }
+
+    /// Load from configuration file
+    ///
+    /// ```json
+    /// {
+    ///     "scheme": "basic", // or "digest"
+    ///     "realm": "shadowsocks",
+    ///     "users": [
+    ///         {
+    ///             "user_name": "USER_NAME",
+    ///             "password": "PASSWORD"
+    ///         }
+    ///     ]
+    /// }
+    /// ```
+    pub fn load_from_file<P: AsRef<Path> + ?Sized>(filename: &P) -> io::Result<HttpAuthConfig> {
+        let filename = filename.as_ref();
+
+        trace!("loading http authentication configuration from {}", filename.display());
+
+        let mut reader = OpenOptions::new().read(true).open(filename)?;
+        let mut content = String::new();
+        reader.read_to_string(&mut content)?;
+
+        let jconf: SSHttpAuthConfig = match json5::from_str(&content) {
+            Ok(c) => c,
+            Err(err) => return Err(io::Error::new(ErrorKind::Other, err)),
+        };
+
+        let mut config = HttpAuthConfig::new();
+
+        if let Some(scheme) = jconf.scheme {
+            match scheme.parse::<HttpAuthScheme>() {
+                Ok(s) => config.scheme = s,
+                Err(..) => {
+                    let err = io::Error::new(
+                        ErrorKind::InvalidData,
+                        format!("unsupported http authentication scheme \"{}\"", scheme),
+                    );
+                    return Err(err);
+                }
+            }
+        }
+
+        if let Some(realm) = jconf.realm {
+            if realm.contains('"') {
+                let err = io::Error::new(ErrorKind::InvalidData, "http authentication realm contains '\"'");
+                return Err(err);
+            }
+            config.realm = realm;
+        }
+
+        for user in jconf.users {
+            config.add_user(user.user_name, user.password);
+        }
+
+        Ok(config)
+    }
+
+    /// Authentication scheme
+    pub fn scheme(&self) -> HttpAuthScheme {
+        self.scheme
+    }
+
+    /// Set authentication scheme
+    pub fn set_scheme(&mut self, scheme: HttpAuthScheme) {
+        self.scheme = scheme;
+    }
+
+    /// Authentication realm
+    pub fn realm(&self) -> &str {
+        &self.realm
+    }
+
+    /// Add a user with password
+    pub fn add_user<U, P>(&mut self, user_name: U, password: P)
+    where
+        U: Into<String>,
+        P: Into<String>,
+    {
+        self.passwd.insert(user_name.into(), password.into());
+    }
+
+    /// Get password of `user_name`
+    pub fn password<U>(&self, user_name: U) -> Option<&str>
+    where
+        U: AsRef<str>,
+    {
+        self.passwd.get(user_name.as_ref()).map(AsRef::as_ref)
+    }
+
+    /// Check if authentication is required
+    pub fn auth_required(&self) -> bool {
+        !self.passwd.is_empty()
+    }
+}
+
+impl Default for HttpAuthConfig {
+    fn default() -> HttpAuthConfig {
+        HttpAuthConfig::new()
+    }
+}
+
+/// HTTP response cache configuration
+#[derive(Debug, Clone)]
+pub struct HttpCacheConfig {
+    /// Maximum total size of stored responses in bytes
+    pub capacity: usize,
+    /// Responses larger than this size in bytes won't be stored
+    pub max_object_size: usize,
+}
+
+impl HttpCacheConfig {
+    /// Create a configuration with total size `capacity`
+    ///
+    /// `max_object_size` defaults to 1/4 of `capacity`
+    pub fn new(capacity: usize) -> HttpCacheConfig {
+        HttpCacheConfig {
+            capacity,
+            max_object_size: capacity / 4,
+        }
+    }
+}
@@ -16,53 +16,63 @@ use hyper::{
     Version,
 };
 use log::{debug, error, trace};
+use tracing::Instrument;
 
 use shadowsocks::relay::socks5::Address;
 
-use crate::local::{
-    context::ServiceContext,
-    loadbalancing::PingBalancer,
-    net::AutoProxyClientStream,
-    utils::establish_tcp_tunnel,
+#[cfg(feature = "acl")]
+use crate::acl::RejectMode;
+use crate::{
+    local::{
+        context::ServiceContext,
+        loadbalancing::PingBalancer,
+        net::AutoProxyClientStream,
+        utils::{debug_route, establish_tcp_tunnel, report_route},
+    },
+    net::{ConcurrencyKind, P2pPolicy},
 };
 
 use super::{
+    auth::HttpAuthenticator,
+    cache::HttpResponseCache,
     client_cache::ProxyClientCache,
     http_client::{BypassHttpClient, HttpClientEnum},
     utils::{authority_addr, host_addr},
 };
 
+/// Shared states of an HTTP server, for dispatching its requests
+pub struct HttpServiceContext {
+    pub context: Arc<ServiceContext>,
+    pub balancer: PingBalancer,
+    pub bypass_client: BypassHttpClient,
+    pub proxy_client_cache: Arc<ProxyClientCache>,
+    pub authenticator: Arc<HttpAuthenticator>,
+    pub response_cache: Option<Arc<HttpResponseCache>>,
+    pub p2p_policy: P2pPolicy,
+}
+
 pub struct HttpDispatcher {
-    context: Arc<ServiceContext>,
+    http_context: Arc<HttpServiceContext>,
     req: Request<Body>,
-    balancer: PingBalancer,
     client_addr: SocketAddr,
-    bypass_client: BypassHttpClient,
-    proxy_client_cache: Arc<ProxyClientCache>,
 }
 
 impl HttpDispatcher {
-    pub fn new(
-        context: Arc<ServiceContext>,
-        req: Request<Body>,
-        balancer: PingBalancer,
-        client_addr: SocketAddr,
-        bypass_client: BypassHttpClient,
-        proxy_client_cache: Arc<ProxyClientCache>,
-    ) -> HttpDispatcher {
+    pub fn new(http_context: Arc<HttpServiceContext>, req: Request<Body>, client_addr: SocketAddr) -> HttpDispatcher {
         HttpDispatcher {
-            context,
+            http_context,
             req,
-            balancer,
             client_addr,
-            bypass_client,
-            proxy_client_cache,
         }
     }
 
     pub async fn dispatch(mut self) -> io::Result<Response<Body>> {
         trace!("request {} {:?}", self.client_addr, self.req);
 
+        if let Some(resp) = self.http_context.authenticator.check(&self.req) {
+            return Ok(resp);
+        }
+
         // Parse URI
         //
         // Proxy request URI must contains a host
@@ -101,8 +111,43 @@ impl HttpDispatcher {
             // Connect to Shadowsocks' remote
             //
             // FIXME: What STATUS should I return for connection error?
-            let server = self.balancer.best_tcp_server();
-            let mut stream = AutoProxyClientStream::connect(self.context, server.as_ref(), &host).await?;
+            let server = self
+                .http_context
+                .balancer
+                .best_tcp_server_for(self.client_addr, &host)
+                .await?;
+            let tunnel_guard = self
+                .http_context
+                .context
+                .concurrency_stat()
+                .acquire(ConcurrencyKind::TcpTunnel, 1);
+            let connection_guard = self.http_context.context.register_connection(
+                ConcurrencyKind::TcpTunnel,
+                self.client_addr,
+                Some(&host),
+            );
+            let mut stream = match AutoProxyClientStream::connect(
+                self.http_context.context.clone(),
+                server.as_ref(),
+                self.client_addr,
+                &host,
+            )
+            .await
+            {
+                Ok(stream) => stream,
+                Err(err) => {
+                    #[cfg(feature = "acl")]
+                    if let Some(mode) = AutoProxyClientStream::rejected_mode(&err) {
+                        debug!(
+                            "HTTP CONNECT {} -> {} rejected by ACL, mode: {}",
+                            self.client_addr, host, mode
+                        );
+                        return make_rejected_response(&host, mode);
+                    }
+                    return Err(err);
+                }
+            };
+            let route = debug_route(&self.http_context.context, &server, self.client_addr, &host).await;
 
             debug!("CONNECT relay connected {} <-> {}", self.client_addr, host);
 
@@ -113,31 +158,45 @@ impl HttpDispatcher {
             // `on_upgrade` future.
             let req = self.req;
             let client_addr = self.client_addr;
-            tokio::spawn(async move {
-                match upgrade::on(req).await {
-                    Ok(mut upgraded) => {
-                        trace!("CONNECT tunnel upgrade success, {} <-> {}", client_addr, host);
-
-                        let _ = establish_tcp_tunnel(
-                            server.server_config(),
-                            &mut upgraded,
-                            &mut stream,
-                            client_addr,
-                            &host,
-                        )
-                        .await;
-                    }
-                    Err(e) => {
-                        error!(
-                            "failed to upgrade TCP tunnel {} <-> {}, error: {}",
-                            client_addr, host, e
-                        );
+            let context = self.http_context.context.clone();
+            let balancer = self.http_context.balancer.clone();
+            let p2p_policy = self.http_context.p2p_policy.clone();
+            // Tunnels are still in the span of the connection
+            tokio::spawn(
+                async move {
+                    match upgrade::on(req).await {
+                        Ok(mut upgraded) => {
+                            trace!("CONNECT tunnel upgrade success, {} <-> {}", client_addr, host);
+
+                            let _tunnel_guard = tunnel_guard;
+                            let mut connection_guard = connection_guard;
+                            let _ = establish_tcp_tunnel(
+                                &context,
+                                &balancer,
+                                &p2p_policy,
+                                &server,
+                                &mut upgraded,
+                                &mut stream,
+                                client_addr,
+                                &host,
+                                connection_guard.as_mut(),
+                            )
+                            .await;
+                        }
+                        Err(e) => {
+                            error!(
+                                "failed to upgrade TCP tunnel {} <-> {}, error: {}",
+                                client_addr, host, e
+                            );
+                        }
                     }
                 }
-            });
+                .in_current_span(),
+            );
 
             // Connection established
-            let resp = Response::builder().body(Body::empty()).unwrap();
+            let mut resp = Response::builder().body(Body::empty()).unwrap();
+            set_route_header(resp.headers_mut(), route);
 
             Ok(resp)
         } else {
@@ -151,18 +210,69 @@ impl HttpDispatcher {
             // Remove non-forwardable headers
             clear_hop_headers(self.req.headers_mut());
 
+            // Requests from HTTP/2 clients are relayed in HTTP/1.1,
+            // connections to remote servers will negotiate HTTP/2 by themselves
+            if version == Version::HTTP_2 {
+                *self.req.version_mut() = Version::HTTP_11;
+            }
+
+            if let Some(ref cache) = self.http_context.response_cache {
+                if let Some(mut res) = cache.lookup(&self.req) {
+                    debug!("HTTP {} {} served from cache", method, self.req.uri());
+
+                    *res.version_mut() = version;
+                    set_conn_keep_alive(version, res.headers_mut(), conn_keep_alive);
+                    return Ok(res);
+                }
+            }
+            let cacheable_req = match self.http_context.response_cache {
+                Some(ref cache) => cache.cacheable_request(&self.req),
+                None => None,
+            };
+
             // Set keep-alive for connection with remote
             set_conn_keep_alive(version, self.req.headers_mut(), conn_keep_alive);
-            let client = if self.context.check_target_bypassed(&host).await {
+
+            self.http_context.context.trace_acl(Some(self.client_addr), &host).await;
+
+            #[cfg(feature = "acl")]
+            if let Some(mode) = self
+                .http_context
+                .context
+                .check_target_rejected(Some(self.client_addr), &host)
+                .await
+            {
+                debug!(
+                    "HTTP {} {} -> {} rejected by ACL, mode: {}",
+                    method, self.client_addr, host, mode
+                );
+                return make_rejected_response(&host, mode);
+            }
+
+            let (bypassed, rule) = self
+                .http_context
+                .context
+                .check_target_bypassed_by(Some(self.client_addr), &host)
+                .await;
+            let (client, route) = if bypassed {
                 trace!("bypassed {} -> {} {:?}", self.client_addr, host, self.req);
-                HttpClientEnum::Bypass(self.bypass_client)
+                let route = report_route(&self.http_context.context, None, rule, self.client_addr, &host);
+                (HttpClientEnum::Bypass(self.http_context.bypass_client.clone()), route)
             } else {
                 trace!("proxied {} -> {} {:?}", self.client_addr, host, self.req);
 
                 // Keep connections for clients in ServerScore::client
                 // client instance is kept for Keep-Alive connections
-                let server = self.balancer.best_tcp_server();
-                HttpClientEnum::Proxy(self.proxy_client_cache.get_connected(&server).await)
+                let server = self
+                    .http_context
+                    .balancer
+                    .best_tcp_server_for(self.client_addr, &host)
+                    .await?;
+                let route = report_route(&self.http_context.context, Some(&server), rule, self.client_addr, &host);
+                (
+                    HttpClientEnum::Proxy(self.http_context.proxy_client_cache.get_connected(&server).await),
+                    route,
+                )
             };
 
             let mut res = match client.send(self.req).await {
@@ -186,6 +296,22 @@ impl HttpDispatcher {
             // Clear unforwardable headers
             clear_hop_headers(res.headers_mut());
 
+            if let (Some(cache), Some(cacheable_req)) = (self.http_context.response_cache.as_ref(), cacheable_req) {
+                res = match cache.store(cacheable_req, res).await {
+                    Ok(res) => res,
+                    Err(err) => {
+                        error!(
+                            "HTTP {} {} <-> {} relay failed, error: {}",
+                            method, self.client_addr, host, err
+                        );
+
+                        let mut resp = Response::new(Body::from(format!("relay failed to {}", host)));
+                        *resp.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+                        return Ok(resp);
+                    }
+                };
+            }
+
             if res.version() != version {
                 // Reset version to matches req's version
                 trace!("response version {:?} => {:?}", res.version(), version);
@@ -194,6 +320,7 @@ impl HttpDispatcher {
 
             // Set Connection header
             set_conn_keep_alive(res.version(), res.headers_mut(), res_keep_alive);
+            set_route_header(res.headers_mut(), route);
 
             trace!("response {} <- {} {:?}", self.client_addr, host, res);
 
@@ -210,6 +337,27 @@ fn make_bad_request() -> io::Result<Response<Body>> {
     Ok(resp)
 }
 
+/// Response of requests rejected by ACL in `mode`, connections are closed without responses by errors
+#[cfg(feature = "acl")]
+fn make_rejected_response(host: &Address, mode: RejectMode) -> io::Result<Response<Body>> {
+    let (status, body) = match mode {
+        RejectMode::Refuse => (StatusCode::FORBIDDEN, Body::from(format!("{} is blocked", host))),
+        RejectMode::Drop => {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("{} is dropped by ACL", host),
+            ));
+        }
+        // Like targets resetting connections of proxies
+        RejectMode::Reset => (StatusCode::BAD_GATEWAY, Body::empty()),
+        RejectMode::Response => (StatusCode::FORBIDDEN, Body::empty()),
+    };
+
+    let mut resp = Response::new(body);
+    *resp.status_mut() = status;
+    Ok(resp)
+}
+
 fn get_keep_alive_val(values: GetAll<HeaderValue>) -> Option<bool> {
     let mut conn_keep_alive = None;
     for value in values {
@@ -315,6 +463,14 @@ fn set_conn_keep_alive(version: Version, headers: &mut HeaderMap<HeaderValue>, k
     }
 }
 
+fn set_route_header(headers: &mut HeaderMap<HeaderValue>, route: Option<String>) {
+    if let Some(route) = route {
+        if let Ok(value) = HeaderValue::from_str(&route) {
+            headers.insert("X-SS-Route", value);
+        }
+    }
+}
+
 fn get_addr_from_header(req: &mut Request<Body>) -> Result<Address, ()> {
     // Try to be compatible as a transparent HTTP proxy
     match req.headers().get("Host") {
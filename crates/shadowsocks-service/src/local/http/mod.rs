@@ -2,7 +2,10 @@
 
 pub use self::server::Http;
 
+mod auth;
+mod cache;
 mod client_cache;
+pub mod config;
 mod connector;
 mod dispatcher;
 mod http_client;
@@ -1,13 +1,17 @@
 //! Shadowsocks Local HTTP(S) Server
 
+#[cfg(unix)]
+use std::path::Path;
 use std::{
     convert::Infallible,
+    error::Error as StdError,
     io::{self, ErrorKind},
+    net::SocketAddr,
     sync::Arc,
 };
 
 use hyper::{
-    server::conn::AddrStream,
+    server::{accept::Accept, conn::AddrStream, Builder},
     service::{make_service_fn, service_fn},
     Body,
     Client,
@@ -16,20 +20,48 @@ use hyper::{
 };
 use log::{error, info};
 use shadowsocks::{config::ServerAddr, lookup_then, net::TcpListener};
+#[cfg(unix)]
+use tokio::net::UnixStream;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpStream,
+    sync::mpsc,
+};
+#[cfg(feature = "local-http-tls")]
+use tokio_rustls::{server::TlsStream, TlsAcceptor};
+use tracing::Instrument;
 
-use crate::local::{
-    context::ServiceContext,
-    http::connector::Connector,
-    loadbalancing::PingBalancer,
-    LOCAL_DEFAULT_KEEPALIVE_TIMEOUT,
+use crate::{
+    local::{
+        context::ServiceContext,
+        http::{
+            auth::HttpAuthenticator,
+            cache::HttpResponseCache,
+            config::{HttpAuthConfig, HttpCacheConfig},
+            connector::Connector,
+        },
+        loadbalancing::PingBalancer,
+        LOCAL_DEFAULT_KEEPALIVE_TIMEOUT,
+    },
+    log_fields,
+    net::P2pPolicy,
 };
 
-use super::{client_cache::ProxyClientCache, dispatcher::HttpDispatcher};
+use super::{
+    client_cache::ProxyClientCache,
+    dispatcher::{HttpDispatcher, HttpServiceContext},
+};
 
 /// HTTP Local server
 pub struct Http {
     context: Arc<ServiceContext>,
     proxy_client_cache: Arc<ProxyClientCache>,
+    authenticator: Arc<HttpAuthenticator>,
+    response_cache: Option<Arc<HttpResponseCache>>,
+    #[cfg(feature = "local-http-tls")]
+    tls_acceptor: Option<TlsAcceptor>,
+    p2p_policy: P2pPolicy,
+    listener: Option<TcpListener>,
 }
 
 impl Default for Http {
@@ -51,51 +83,70 @@ impl Http {
         Http {
             context,
             proxy_client_cache,
+            authenticator: Arc::new(HttpAuthenticator::new(HttpAuthConfig::new())),
+            response_cache: None,
+            #[cfg(feature = "local-http-tls")]
+            tls_acceptor: None,
+            p2p_policy: P2pPolicy::Allow,
+            listener: None,
         }
     }
 
-    /// Run server
-    pub async fn run(self, client_config: &ServerAddr, balancer: PingBalancer) -> io::Result<()> {
-        let bypass_client = Client::builder()
-            .http1_preserve_header_case(true)
-            .http1_title_case_headers(true)
-            .build::<_, Body>(Connector::new(self.context.clone(), None));
+    /// Set authentication of clients
+    pub fn set_auth(&mut self, auth: HttpAuthConfig) {
+        self.authenticator = Arc::new(HttpAuthenticator::new(auth));
+    }
 
-        let context = self.context.clone();
-        let proxy_client_cache = self.proxy_client_cache.clone();
-        let make_service = make_service_fn(|socket: &AddrStream| {
-            let client_addr = socket.remote_addr();
-            let balancer = balancer.clone();
-            let bypass_client = bypass_client.clone();
-            let context = context.clone();
-            let proxy_client_cache = proxy_client_cache.clone();
+    /// Enable caching responses of `GET` requests
+    pub fn set_response_cache(&mut self, cache: HttpCacheConfig) {
+        self.response_cache = Some(Arc::new(HttpResponseCache::new(&cache)));
+    }
 
-            async move {
-                Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
-                    HttpDispatcher::new(
-                        context.clone(),
-                        req,
-                        balancer.clone(),
-                        client_addr,
-                        bypass_client.clone(),
-                        proxy_client_cache.clone(),
-                    )
-                    .dispatch()
-                }))
-            }
-        });
+    /// Accept TLS connections with this acceptor (HTTPS proxy)
+    #[cfg(feature = "local-http-tls")]
+    pub fn set_tls_acceptor(&mut self, acceptor: TlsAcceptor) {
+        self.tls_acceptor = Some(acceptor);
+    }
 
-        let bind_result = match *client_config {
-            ServerAddr::SocketAddr(sa) => TcpListener::bind_with_opts(&sa, self.context.accept_opts().clone()).await,
-            ServerAddr::DomainName(ref dname, port) => lookup_then!(self.context.context_ref(), dname, port, |addr| {
-                TcpListener::bind_with_opts(&addr, self.context.accept_opts().clone()).await
-            })
-            .map(|(_, b)| b),
+    /// Set policy of BitTorrent traffic in CONNECT tunnels
+    pub fn set_p2p_policy(&mut self, p2p_policy: P2pPolicy) {
+        self.p2p_policy = p2p_policy;
+    }
+
+    /// Serve on a listener bound to the address of `run`, instead of binding it
+    pub fn set_listener(&mut self, listener: TcpListener) {
+        self.listener = Some(listener);
+    }
+
+    /// Run server
+    pub async fn run(mut self, client_config: &ServerAddr, balancer: PingBalancer) -> io::Result<()> {
+        let bind_result = match (self.listener.take(), client_config) {
+            (Some(listener), ..) => Ok(listener),
+            (None, ServerAddr::SocketAddr(sa)) => {
+                TcpListener::bind_with_opts(sa, self.context.accept_opts().clone()).await
+            }
+            (None, ServerAddr::DomainName(dname, port)) => {
+                lookup_then!(self.context.context_ref(), dname, *port, |addr| {
+                    TcpListener::bind_with_opts(&addr, self.context.accept_opts().clone()).await
+                })
+                .map(|(_, b)| b)
+            }
         };
 
-        let server = match bind_result {
+        let builder = match bind_result {
+            #[cfg(feature = "local-http-tls")]
+            Ok(listener) if self.tls_acceptor.is_some() => {
+                info!("shadowsocks HTTP (TLS) listening on {}", listener.local_addr()?);
+
+                let acceptor = self.tls_acceptor.clone().unwrap();
+                return self
+                    .serve(Server::builder(tls_incoming(listener, acceptor)), balancer)
+                    .await;
+            }
             Ok(listener) => {
                 let listener = listener.into_inner().into_std()?;
+                info!("shadowsocks HTTP listening on {}", listener.local_addr()?);
+
                 let builder = match Server::from_tcp(listener) {
                     Ok(builder) => builder,
                     Err(err) => {
@@ -106,9 +157,6 @@ impl Http {
                 };
 
                 builder
-                    .http1_only(true) // HTTP Proxy protocol only defined in HTTP 1.x
-                    .http1_preserve_header_case(true)
-                    .http1_title_case_headers(true)
                     .tcp_sleep_on_accept_errors(true)
                     .tcp_keepalive(
                         self.context
@@ -118,7 +166,6 @@ impl Http {
                             .or(Some(LOCAL_DEFAULT_KEEPALIVE_TIMEOUT)),
                     )
                     .tcp_nodelay(self.context.accept_opts().tcp.nodelay)
-                    .serve(make_service)
             }
             Err(err) => {
                 error!("hyper server bind error: {}", err);
@@ -127,7 +174,83 @@ impl Http {
             }
         };
 
-        info!("shadowsocks HTTP listening on {}", server.local_addr());
+        self.serve(builder, balancer).await
+    }
+
+    /// Run server on a Unix Domain Socket
+    #[cfg(unix)]
+    pub async fn run_unix(self, path: &Path, socket_mode: Option<u32>, balancer: PingBalancer) -> io::Result<()> {
+        use hyper::server::accept;
+
+        use crate::local::net::uds::bind_unix_listener;
+
+        let listener = match bind_unix_listener(path, socket_mode) {
+            Ok(l) => l,
+            Err(err) => {
+                error!("hyper server bind unix:{} error: {}", path.display(), err);
+                return Err(err);
+            }
+        };
+
+        info!("shadowsocks HTTP listening on unix:{}", path.display());
+
+        let incoming = accept::poll_fn(move |cx| listener.poll_accept(cx).map(|r| Some(r.map(|(s, ..)| s))));
+        self.serve(Server::builder(incoming), balancer).await
+    }
+
+    /// Serve connections accepted by the other servers, for example, the `mixed` local
+    pub(crate) async fn run_incoming(
+        self,
+        mut incoming: mpsc::Receiver<TcpStream>,
+        balancer: PingBalancer,
+    ) -> io::Result<()> {
+        use hyper::server::accept;
+
+        let incoming = accept::poll_fn(move |cx| incoming.poll_recv(cx).map(|s| s.map(Ok::<_, io::Error>)));
+        self.serve(Server::builder(incoming), balancer).await
+    }
+
+    async fn serve<I>(self, builder: Builder<I>, balancer: PingBalancer) -> io::Result<()>
+    where
+        I: Accept,
+        I::Conn: HttpClientStream + AsyncRead + AsyncWrite + Unpin + Send + 'static,
+        I::Error: Into<Box<dyn StdError + Send + Sync>>,
+    {
+        let bypass_client = Client::builder()
+            .http1_preserve_header_case(true)
+            .http1_title_case_headers(true)
+            .build::<_, Body>(Connector::new(self.context.clone(), None));
+
+        let http_context = Arc::new(HttpServiceContext {
+            context: self.context.clone(),
+            balancer,
+            bypass_client,
+            proxy_client_cache: self.proxy_client_cache.clone(),
+            authenticator: self.authenticator.clone(),
+            response_cache: self.response_cache.clone(),
+            p2p_policy: self.p2p_policy.clone(),
+        });
+        let make_service = make_service_fn(|socket: &I::Conn| {
+            let client_addr = socket.client_addr();
+            let http_context = http_context.clone();
+            // Connections are served by hyper, requests of the same connection are dispatched in its span
+            let span = log_fields::connection_span(client_addr);
+
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                    HttpDispatcher::new(http_context.clone(), req, client_addr)
+                        .dispatch()
+                        .instrument(span.clone())
+                }))
+            }
+        });
+
+        // HTTP/2 connections are detected by the connection preface, which could be negotiated by ALPN for TLS
+        // connections, or with prior knowledge. CONNECT streams of the same connection are served concurrently.
+        let server = builder
+            .http1_preserve_header_case(true)
+            .http1_title_case_headers(true)
+            .serve(make_service);
 
         if let Err(err) = server.await {
             use std::io::Error;
@@ -139,3 +262,91 @@ impl Http {
         Ok(())
     }
 }
+
+/// Accepts TLS connections from `listener`
+///
+/// Handshakes are done in separated tasks, so slow clients won't block accepting the others
+#[cfg(feature = "local-http-tls")]
+fn tls_incoming(
+    listener: TcpListener,
+    acceptor: TlsAcceptor,
+) -> impl Accept<Conn = TlsStream<TcpStream>, Error = io::Error> {
+    use std::time::Duration;
+
+    use hyper::server::accept;
+    use tokio::time;
+
+    let (tx, mut rx) = mpsc::channel(64);
+
+    tokio::spawn(async move {
+        loop {
+            let (stream, peer_addr) = tokio::select! {
+                _ = tx.closed() => break,
+                r = listener.accept() => match r {
+                    Ok(s) => s,
+                    Err(err) => {
+                        error!("HTTP (TLS) accept failed with error: {}", err);
+                        time::sleep(Duration::from_secs(1)).await;
+                        continue;
+                    }
+                },
+            };
+
+            let acceptor = acceptor.clone();
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                match acceptor.accept(stream).await {
+                    Ok(stream) => {
+                        let _ = tx.send(stream).await;
+                    }
+                    Err(err) => {
+                        error!("HTTP client {} tls handshake failed, error: {}", peer_addr, err);
+                    }
+                }
+            });
+        }
+    });
+
+    accept::poll_fn(move |cx| rx.poll_recv(cx).map(|s| s.map(Ok)))
+}
+
+/// Streams accepted by HTTP local server
+trait HttpClientStream {
+    /// Address of the client, passed to `HttpDispatcher`
+    fn client_addr(&self) -> SocketAddr;
+}
+
+impl HttpClientStream for AddrStream {
+    fn client_addr(&self) -> SocketAddr {
+        self.remote_addr()
+    }
+}
+
+impl HttpClientStream for TcpStream {
+    fn client_addr(&self) -> SocketAddr {
+        self.peer_addr().unwrap_or_else(|_| SocketAddr::from(([0, 0, 0, 0], 0)))
+    }
+}
+
+#[cfg(unix)]
+impl HttpClientStream for UnixStream {
+    fn client_addr(&self) -> SocketAddr {
+        use log::trace;
+
+        use crate::local::net::uds::unix_peer_identity;
+
+        let (peer_identity, peer_addr) = unix_peer_identity(self);
+        trace!("HTTP client {} connected", peer_identity);
+        peer_addr
+    }
+}
+
+#[cfg(feature = "local-http-tls")]
+impl HttpClientStream for TlsStream<TcpStream> {
+    fn client_addr(&self) -> SocketAddr {
+        let (stream, ..) = self.get_ref();
+        stream
+            .peer_addr()
+            .unwrap_or_else(|_| SocketAddr::from(([0, 0, 0, 0], 0)))
+    }
+}
@@ -6,5 +6,7 @@ pub use self::{
 };
 
 pub mod ping_balancer;
+pub mod relay_stat;
 pub mod server_data;
 pub mod server_stat;
+pub mod warm_pool;
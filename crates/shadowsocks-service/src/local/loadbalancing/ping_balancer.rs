@@ -2,10 +2,12 @@
 
 use std::{
     cmp,
+    collections::{hash_map::DefaultHasher, BTreeMap},
     fmt::{self, Debug, Display},
+    hash::{Hash, Hasher},
     io,
     iter::Iterator,
-    net::{Ipv4Addr, SocketAddr},
+    net::SocketAddr,
     sync::{
         atomic::{AtomicUsize, Ordering},
         Arc,
@@ -16,26 +18,30 @@ use std::{
 use arc_swap::ArcSwap;
 use byte_string::ByteStr;
 use futures::future;
-use log::{debug, error, info, trace, warn};
+use log::{debug, info, trace, warn};
 use shadowsocks::{
-    config::Mode,
-    plugin::{Plugin, PluginMode},
+    config::{Mode, ServerAddr},
+    net::TcpStream,
+    plugin::{PluginMode, PluginSupervisor},
     relay::{
         socks5::Address,
-        tcprelay::proxy_stream::ProxyClientStream,
-        udprelay::{proxy_socket::ProxySocket, MAXIMUM_UDP_PAYLOAD_SIZE},
+        tcprelay::proxy_stream::{connect_server_with_opts, ProxyClientStream},
+        udprelay::MAXIMUM_UDP_PAYLOAD_SIZE,
     },
     ServerConfig,
 };
 use spin::Mutex as SpinMutex;
 use tokio::{
-    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
-    sync::Notify,
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    sync::{Mutex as AsyncMutex, Notify},
     task::JoinHandle,
     time,
 };
 
-use crate::local::context::ServiceContext;
+use crate::{
+    config::{BalancerProbeConfig, BalancerScoringConfig, BalancerStrategy, BalancerTcpProbe, BalancerWarmPoolConfig},
+    local::{context::ServiceContext, net::ProxiedUdpSocket},
+};
 
 use super::{
     server_data::ServerIdent,
@@ -44,6 +50,15 @@ use super::{
 
 const EXPECTED_CHECK_POINTS_IN_CHECK_WINDOW: u32 = 67;
 
+/// Value of `pinned_idx` if no server is pinned
+const NOT_PINNED: usize = usize::MAX;
+
+/// Default duration of a higher priority server being alive before switching back to it
+const DEFAULT_FAILBACK_WINDOW_SEC: u64 = 60;
+
+/// Interval of refilling warm pools if no connections are taken
+const WARM_POOL_REFRESH_INTERVAL: Duration = Duration::from_secs(1);
+
 /// Remote Server Type
 #[derive(Debug, Clone, Copy)]
 pub enum ServerType {
@@ -68,6 +83,13 @@ pub struct PingBalancerBuilder {
     max_server_rtt: Duration,
     check_interval: Duration,
     check_best_interval: Option<Duration>,
+    strategy: BalancerStrategy,
+    failback_window: Duration,
+    groups: BTreeMap<String, BalancerStrategy>,
+    default_group: Option<String>,
+    probe: BalancerProbeConfig,
+    scoring: BalancerScoringConfig,
+    warm_pool: Option<BalancerWarmPoolConfig>,
 }
 
 impl PingBalancerBuilder {
@@ -79,14 +101,23 @@ impl PingBalancerBuilder {
             max_server_rtt: Duration::from_secs(DEFAULT_CHECK_TIMEOUT_SEC),
             check_interval: Duration::from_secs(DEFAULT_CHECK_INTERVAL_SEC),
             check_best_interval: None,
+            strategy: BalancerStrategy::default(),
+            failback_window: Duration::from_secs(DEFAULT_FAILBACK_WINDOW_SEC),
+            groups: BTreeMap::new(),
+            default_group: None,
+            probe: BalancerProbeConfig::default(),
+            scoring: BalancerScoringConfig::default(),
+            warm_pool: None,
         }
     }
 
+    /// Add a server, `max_server_rtt`, `check_interval` and `scoring` have to be set before
     pub fn add_server(&mut self, server: ServerConfig) {
         let ident = ServerIdent::new(
             server,
             self.max_server_rtt,
             self.check_interval * EXPECTED_CHECK_POINTS_IN_CHECK_WINDOW,
+            self.scoring,
         );
         self.servers.push(Arc::new(ident));
     }
@@ -103,6 +134,37 @@ impl PingBalancerBuilder {
         self.check_best_interval = Some(intv);
     }
 
+    pub fn strategy(&mut self, strategy: BalancerStrategy) {
+        self.strategy = strategy;
+    }
+
+    pub fn failback_window(&mut self, window: Duration) {
+        self.failback_window = window;
+    }
+
+    /// Strategies of server groups, which are servers tagged by the group's name
+    pub fn groups(&mut self, groups: BTreeMap<String, BalancerStrategy>) {
+        self.groups = groups;
+    }
+
+    /// Group of servers for targets that are not routed by ACL's `[route:TAG]` rules
+    pub fn default_group(&mut self, group: String) {
+        self.default_group = Some(group);
+    }
+
+    pub fn probe(&mut self, probe: BalancerProbeConfig) {
+        self.probe = probe;
+    }
+
+    pub fn scoring(&mut self, scoring: BalancerScoringConfig) {
+        self.scoring = scoring;
+    }
+
+    /// Keep connections established ahead of requests to the top-ranked servers
+    pub fn warm_pool(&mut self, warm_pool: BalancerWarmPoolConfig) {
+        self.warm_pool = Some(warm_pool);
+    }
+
     fn find_best_idx(servers: &[Arc<ServerIdent>], mode: Mode) -> (usize, usize) {
         let mut best_tcp_idx = 0;
         let mut best_udp_idx = 0;
@@ -168,28 +230,55 @@ impl PingBalancerBuilder {
             }
         }
 
-        let (shared_context, task_abortable) = PingBalancerContext::new(
-            self.servers,
-            self.context,
-            self.mode,
-            self.max_server_rtt,
-            self.check_interval,
-            self.check_best_interval,
-        )
-        .await?;
+        let options = PingBalancerOptions {
+            context: self.context,
+            mode: self.mode,
+            max_server_rtt: self.max_server_rtt,
+            check_interval: self.check_interval,
+            check_best_interval: self.check_best_interval,
+            strategy: self.strategy,
+            failback_window: self.failback_window,
+            groups: Arc::new(self.groups),
+            default_group: self.default_group,
+            probe: Arc::new(self.probe),
+            scoring: self.scoring,
+            warm_pool: self.warm_pool,
+        };
+        let (shared_context, task_abortable) = PingBalancerContext::new(self.servers, options).await?;
 
         Ok(PingBalancer {
             inner: Arc::new(PingBalancerInner {
                 context: ArcSwap::new(shared_context),
                 task_abortable: SpinMutex::new(task_abortable),
+                update_lock: AsyncMutex::new(()),
             }),
+            route_context: None,
+            group: None,
         })
     }
 }
 
+/// Options of `PingBalancerContext`, set by `PingBalancerBuilder` and kept while resetting servers
+#[derive(Clone)]
+struct PingBalancerOptions {
+    context: Arc<ServiceContext>,
+    mode: Mode,
+    max_server_rtt: Duration,
+    check_interval: Duration,
+    check_best_interval: Option<Duration>,
+    strategy: BalancerStrategy,
+    failback_window: Duration,
+    groups: Arc<BTreeMap<String, BalancerStrategy>>,
+    default_group: Option<String>,
+    probe: Arc<BalancerProbeConfig>,
+    scoring: BalancerScoringConfig,
+    warm_pool: Option<BalancerWarmPoolConfig>,
+}
+
 struct PingBalancerContextTask {
     checker_abortable: JoinHandle<()>,
     plugin_abortable: Option<JoinHandle<()>>,
+    warm_pool_abortable: Option<JoinHandle<()>>,
 }
 
 impl Drop for PingBalancerContextTask {
@@ -198,6 +287,9 @@ impl Drop for PingBalancerContextTask {
         if let Some(ref p) = self.plugin_abortable {
             p.abort();
         }
+        if let Some(ref w) = self.warm_pool_abortable {
+            w.abort();
+        }
     }
 }
 
@@ -205,108 +297,436 @@ struct PingBalancerContext {
     servers: Vec<Arc<ServerIdent>>,
     best_tcp_idx: AtomicUsize,
     best_udp_idx: AtomicUsize,
+    /// Server chosen by user, overriding the best servers
+    pinned_idx: AtomicUsize,
     context: Arc<ServiceContext>,
     mode: Mode,
     max_server_rtt: Duration,
     check_interval: Duration,
     check_best_interval: Option<Duration>,
     best_task_notify: Notify,
+    strategy: BalancerStrategy,
+    failback_window: Duration,
+    /// Strategies of server groups
+    groups: Arc<BTreeMap<String, BalancerStrategy>>,
+    default_group: Option<String>,
+    probe: Arc<BalancerProbeConfig>,
+    scoring: BalancerScoringConfig,
+    warm_pool: Option<BalancerWarmPoolConfig>,
+    /// Current weights of smooth weighted round-robin, for `BalancerStrategy::Weighted`
+    tcp_turns: SpinMutex<Vec<f64>>,
+    udp_turns: SpinMutex<Vec<f64>>,
 }
 
 impl PingBalancerContext {
     fn best_tcp_server(&self) -> Arc<ServerIdent> {
+        if let Some(server) = self.pinned_server() {
+            if PingBalancerContext::check_server_tcp_enabled(server.server_config()) {
+                return server;
+            }
+        }
+        if self.strategy == BalancerStrategy::Weighted {
+            if let Some(server) = self.weighted_server(ServerType::Tcp, None) {
+                return server;
+            }
+        }
         self.servers[self.best_tcp_idx.load(Ordering::Relaxed)].clone()
     }
 
     fn best_udp_server(&self) -> Arc<ServerIdent> {
+        if let Some(server) = self.pinned_server() {
+            if PingBalancerContext::check_server_udp_enabled(server.server_config()) {
+                return server;
+            }
+        }
+        if self.strategy == BalancerStrategy::Weighted {
+            if let Some(server) = self.weighted_server(ServerType::Udp, None) {
+                return server;
+            }
+        }
         self.servers[self.best_udp_idx.load(Ordering::Relaxed)].clone()
     }
+
+    /// Pick a server in turn, each server is picked proportionally to its weight
+    ///
+    /// Smooth weighted round-robin, servers are interleaved instead of being picked in bursts. Dead servers are
+    /// skipped, unless all servers are dead.
+    fn weighted_server(&self, server_type: ServerType, group: Option<&str>) -> Option<Arc<ServerIdent>> {
+        let candidates = self.weighted_candidates(server_type, group);
+
+        let mut turns = match server_type {
+            ServerType::Tcp => self.tcp_turns.lock(),
+            ServerType::Udp => self.udp_turns.lock(),
+        };
+
+        let mut total_weight = 0.0;
+        let mut chosen: Option<usize> = None;
+        for (idx, weight) in candidates {
+            turns[idx] += weight as f64;
+            total_weight += weight as f64;
+            match chosen {
+                Some(chosen_idx) if turns[chosen_idx] >= turns[idx] => {}
+                _ => chosen = Some(idx),
+            }
+        }
+
+        let idx = chosen?;
+        turns[idx] -= total_weight;
+        Some(self.servers[idx].clone())
+    }
+
+    /// Pick a server by hashing `key`, each server owns a share of keys proportional to its weight
+    ///
+    /// Weighted rendezvous hashing, keys are only moved away from a server when it dies, and moved back after it
+    /// recovers. Dead servers are skipped, unless all servers are dead.
+    fn hashed_server<K: Hash + ?Sized>(
+        &self,
+        key: &K,
+        server_type: ServerType,
+        group: Option<&str>,
+    ) -> Option<Arc<ServerIdent>> {
+        let mut chosen: Option<(usize, f64)> = None;
+        for (idx, weight) in self.weighted_candidates(server_type, group) {
+            let mut hasher = DefaultHasher::new();
+            key.hash(&mut hasher);
+            self.servers[idx].server_config().addr().hash(&mut hasher);
+
+            // Map the hash to (0, 1), and the higher weight, the more likely to win
+            let point = ((hasher.finish() >> 11) as f64 + 0.5) / (1u64 << 53) as f64;
+            let score = -(weight as f64) / point.ln();
+            match chosen {
+                Some((_, chosen_score)) if chosen_score >= score => {}
+                _ => chosen = Some((idx, score)),
+            }
+        }
+
+        chosen.map(|(idx, _)| self.servers[idx].clone())
+    }
+
+    /// Servers of `group` enabled for `server_type` with their weights, dead servers are excluded unless all servers
+    /// are dead
+    fn weighted_candidates(&self, server_type: ServerType, group: Option<&str>) -> Vec<(usize, f32)> {
+        let mut candidates = Vec::with_capacity(self.servers.len());
+        for (idx, server) in self.servers.iter().enumerate() {
+            if server.is_excluded() {
+                continue;
+            }
+            let svr_cfg = server.server_config();
+            if let Some(group) = group {
+                if !svr_cfg.has_tag(group) {
+                    continue;
+                }
+            }
+            match server_type {
+                ServerType::Tcp if PingBalancerContext::check_server_tcp_enabled(svr_cfg) => {
+                    candidates.push((idx, svr_cfg.weight().tcp_weight(), server.tcp_score().is_alive()));
+                }
+                ServerType::Udp if PingBalancerContext::check_server_udp_enabled(svr_cfg) => {
+                    candidates.push((idx, svr_cfg.weight().udp_weight(), server.udp_score().is_alive()));
+                }
+                _ => {}
+            }
+        }
+        if candidates.iter().any(|(_, _, alive)| *alive) {
+            candidates.retain(|(_, _, alive)| *alive);
+        }
+        candidates.into_iter().map(|(idx, weight, _)| (idx, weight)).collect()
+    }
+
+    fn pinned_server(&self) -> Option<Arc<ServerIdent>> {
+        self.servers.get(self.pinned_idx.load(Ordering::Relaxed)).cloned()
+    }
+
+    /// Index of the server listening on `addr`
+    fn find_server(&self, addr: &ServerAddr) -> io::Result<usize> {
+        self.servers
+            .iter()
+            .position(|s| s.server_config().addr() == addr)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("server {} not found", addr)))
+    }
+
+    /// Pick the best server tagged by `tag`, which is not cached, scores of all servers are updated by checker
+    fn best_tagged_server(&self, tag: &str, server_type: ServerType) -> Option<Arc<ServerIdent>> {
+        let mut best: Option<(&Arc<ServerIdent>, u32)> = None;
+        for server in &self.servers {
+            let svr_cfg = server.server_config();
+            if !svr_cfg.has_tag(tag) || server.is_excluded() {
+                continue;
+            }
+
+            let score = match server_type {
+                ServerType::Tcp if PingBalancerContext::check_server_tcp_enabled(svr_cfg) => server.tcp_score().score(),
+                ServerType::Udp if PingBalancerContext::check_server_udp_enabled(svr_cfg) => server.udp_score().score(),
+                _ => continue,
+            };
+
+            match best {
+                Some((_, best_score)) if best_score <= score => {}
+                _ => best = Some((server, score)),
+            }
+        }
+        best.map(|(server, _)| server.clone())
+    }
+
+    /// Pick the best server for `peer_addr` connecting to `addr`, restricted to servers of the group `TAG` of ACL's
+    /// `[route:TAG]` rules in `route_context`, or `default_group`
+    async fn best_server_for(
+        &self,
+        route_context: &ServiceContext,
+        default_group: Option<&str>,
+        peer_addr: SocketAddr,
+        addr: &Address,
+        server_type: ServerType,
+    ) -> io::Result<Arc<ServerIdent>> {
+        // Processes are only matched for TCP clients
+        let tcp_peer_addr = match server_type {
+            ServerType::Tcp => Some(peer_addr),
+            ServerType::Udp => None,
+        };
+        let tag = match route_context.route_tag(tcp_peer_addr, addr).await {
+            Some(tag) => tag,
+            None if default_group.is_some() => default_group.unwrap().to_owned(),
+            None => {
+                if let Some(server) = self.sticky_server(peer_addr, addr, server_type) {
+                    return Ok(server);
+                }
+                return Ok(match server_type {
+                    ServerType::Tcp => self.best_tcp_server(),
+                    ServerType::Udp => self.best_udp_server(),
+                });
+            }
+        };
+
+        self.best_routed_server(peer_addr, addr, &tag, server_type)
+    }
+
+    /// Pick the server by hashing target's host or client's IP, for `BalancerStrategy::TargetHash` and
+    /// `BalancerStrategy::ClientHash`. The pinned server is always preferred.
+    fn sticky_server(
+        &self,
+        peer_addr: SocketAddr,
+        addr: &Address,
+        server_type: ServerType,
+    ) -> Option<Arc<ServerIdent>> {
+        if let Some(server) = self.pinned_server() {
+            let enabled = match server_type {
+                ServerType::Tcp => PingBalancerContext::check_server_tcp_enabled(server.server_config()),
+                ServerType::Udp => PingBalancerContext::check_server_udp_enabled(server.server_config()),
+            };
+            if enabled {
+                return Some(server);
+            }
+        }
+
+        let server = self.hashed_server_for(self.strategy, peer_addr, addr, server_type, None);
+
+        if let Some(ref server) = server {
+            trace!(
+                "{} {} -> {} sticky to server {}",
+                server_type,
+                peer_addr,
+                addr,
+                ServerConfigFormatter::new(server.server_config())
+            );
+        }
+        server
+    }
+
+    /// Pick the server by hashing for `BalancerStrategy::TargetHash` and `BalancerStrategy::ClientHash`, `None` for
+    /// the other strategies
+    fn hashed_server_for(
+        &self,
+        strategy: BalancerStrategy,
+        peer_addr: SocketAddr,
+        addr: &Address,
+        server_type: ServerType,
+        group: Option<&str>,
+    ) -> Option<Arc<ServerIdent>> {
+        match strategy {
+            BalancerStrategy::TargetHash => match *addr {
+                Address::SocketAddress(ref saddr) => self.hashed_server(&saddr.ip(), server_type, group),
+                Address::DomainNameAddress(ref dname, _) => {
+                    self.hashed_server(dname.to_ascii_lowercase().as_str(), server_type, group)
+                }
+            },
+            BalancerStrategy::ClientHash => self.hashed_server(&peer_addr.ip(), server_type, group),
+            BalancerStrategy::Best | BalancerStrategy::Weighted | BalancerStrategy::Failover => None,
+        }
+    }
+
+    /// Strategy of choosing servers of group `tag`, servers are chosen by score if it is not configured
+    fn group_strategy(&self, tag: &str) -> BalancerStrategy {
+        self.groups
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(tag))
+            .map(|(_, strategy)| *strategy)
+            .unwrap_or_default()
+    }
+
+    /// Pick the server of group `tag` by the group's strategy
+    fn group_server(
+        &self,
+        peer_addr: SocketAddr,
+        addr: &Address,
+        tag: &str,
+        server_type: ServerType,
+    ) -> Option<Arc<ServerIdent>> {
+        match self.group_strategy(tag) {
+            BalancerStrategy::Best => self.best_tagged_server(tag, server_type),
+            BalancerStrategy::Weighted => self.weighted_server(server_type, Some(tag)),
+            BalancerStrategy::Failover => self.failover_tagged_server(tag, server_type),
+            strategy => self.hashed_server_for(strategy, peer_addr, addr, server_type, Some(tag)),
+        }
+    }
+
+    /// Pick the alive server tagged by `tag` with the highest priority, which has been alive for `failback_window`
+    ///
+    /// Groups don't keep their current servers, so the alive server with the highest priority is chosen if none
+    /// has been alive for `failback_window`, and servers are dead only if all of them are dead.
+    fn failover_tagged_server(&self, tag: &str, server_type: ServerType) -> Option<Arc<ServerIdent>> {
+        let mut first_alive = None;
+        let mut first = None;
+        for server in &self.servers {
+            let svr_cfg = server.server_config();
+            if !svr_cfg.has_tag(tag) || server.is_excluded() {
+                continue;
+            }
+
+            let score = match server_type {
+                ServerType::Tcp if PingBalancerContext::check_server_tcp_enabled(svr_cfg) => server.tcp_score(),
+                ServerType::Udp if PingBalancerContext::check_server_udp_enabled(svr_cfg) => server.udp_score(),
+                _ => continue,
+            };
+
+            if first.is_none() {
+                first = Some(server);
+            }
+            match score.alive_duration() {
+                Some(d) if d >= self.failback_window => return Some(server.clone()),
+                Some(..) if first_alive.is_none() => first_alive = Some(server),
+                _ => {}
+            }
+        }
+        first_alive.or(first).cloned()
+    }
+
+    /// Pick the server of group `tag` for connecting to `addr`, fails if there is no server tagged
+    fn best_routed_server(
+        &self,
+        peer_addr: SocketAddr,
+        addr: &Address,
+        tag: &str,
+        server_type: ServerType,
+    ) -> io::Result<Arc<ServerIdent>> {
+        match self.group_server(peer_addr, addr, tag, server_type) {
+            Some(server) => {
+                trace!(
+                    "{} {} routed to tag:{} server {}",
+                    server_type,
+                    addr,
+                    tag,
+                    ServerConfigFormatter::new(server.server_config())
+                );
+                Ok(server)
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!(
+                    "{} {} routed to tag:{}, but there is no {} server tagged",
+                    server_type, addr, tag, server_type
+                ),
+            )),
+        }
+    }
 }
 
 impl PingBalancerContext {
     pub(crate) async fn new(
         mut servers: Vec<Arc<ServerIdent>>,
-        context: Arc<ServiceContext>,
-        mode: Mode,
-        max_server_rtt: Duration,
-        check_interval: Duration,
-        check_best_interval: Option<Duration>,
+        options: PingBalancerOptions,
     ) -> io::Result<(Arc<PingBalancerContext>, PingBalancerContextTask)> {
-        let plugin_abortable = if mode.enable_tcp() {
-            // Start plugins for TCP proxies
-
-            let mut plugins = Vec::with_capacity(servers.len());
-
-            for server in &mut servers {
-                let server = Arc::get_mut(server).unwrap();
-                let svr_cfg = server.server_config_mut();
-
-                if let Some(p) = svr_cfg.plugin() {
+        let PingBalancerOptions {
+            context,
+            mode,
+            max_server_rtt,
+            check_interval,
+            check_best_interval,
+            strategy,
+            failback_window,
+            groups,
+            default_group,
+            probe,
+            scoring,
+            warm_pool,
+        } = options;
+
+        // Start plugins for TCP proxies, and UDP proxies if plugins relay UDP (SIP003u)
+        let mut plugins = Vec::with_capacity(servers.len());
+
+        for server in &mut servers {
+            let server = Arc::get_mut(server).unwrap();
+            let svr_cfg = server.server_config_mut();
+
+            if let Some(p) = svr_cfg.external_plugin() {
+                let plugin_mode = p.plugin_mode;
+                if (mode.enable_tcp() && plugin_mode.enable_tcp()) || (mode.enable_udp() && plugin_mode.enable_udp()) {
                     // Start Plugin Process
-                    let plugin = Plugin::start(p, svr_cfg.addr(), PluginMode::Client)?;
+                    let plugin = PluginSupervisor::start(p, svr_cfg.addr(), PluginMode::Client)?;
                     svr_cfg.set_plugin_addr(plugin.local_addr().into());
+                    server.set_plugin_status(plugin.status());
                     plugins.push(plugin);
                 }
             }
+        }
 
-            if plugins.is_empty() {
-                None
-            } else {
-                // Load balancer will check all servers' score before server's actual start.
-                // So we have to ensure all plugins have been started before that.
+        let plugin_abortable = if plugins.is_empty() {
+            None
+        } else {
+            // Load balancer will check all servers' score before server's actual start.
+            // So we have to ensure all plugins have been started before that.
 
-                let mut check_fut = Vec::with_capacity(plugins.len());
+            let mut check_fut = Vec::with_capacity(plugins.len());
 
-                for plugin in &plugins {
-                    // 3 seconds is not a carefully selected value
-                    // I choose that because any values bigger will make me felt too long.
-                    check_fut.push(plugin.wait_started(Duration::from_secs(3)));
-                }
+            for plugin in &plugins {
+                // 3 seconds is not a carefully selected value
+                // I choose that because any values bigger will make me felt too long.
+                check_fut.push(plugin.wait_started(Duration::from_secs(3)));
+            }
 
-                // Run all of them simutaneously
-                let _ = future::join_all(check_fut).await;
-
-                let plugin_abortable = tokio::spawn(async move {
-                    let mut vfut = Vec::with_capacity(plugins.len());
-
-                    for plugin in plugins {
-                        vfut.push(async move {
-                            match plugin.join().await {
-                                Ok(status) => {
-                                    error!("plugin exited with status: {}", status);
-                                    Ok(())
-                                }
-                                Err(err) => {
-                                    error!("plugin exited with error: {}", err);
-                                    Err(err)
-                                }
-                            }
-                        });
-                    }
-
-                    let _ = future::join_all(vfut).await;
-
-                    panic!("all plugins are exited. all connections may fail, check your configuration");
-                });
+            // Run all of them simutaneously
+            let _ = future::join_all(check_fut).await;
 
-                Some(plugin_abortable)
-            }
-        } else {
-            None
+            // Exited plugins are restarted, servers are checked failed until their plugins are running again
+            let plugin_abortable = tokio::spawn(async move {
+                let _ = future::join_all(plugins.into_iter().map(PluginSupervisor::run)).await;
+            });
+
+            Some(plugin_abortable)
         };
 
         let (best_tcp_idx, best_udp_idx) = PingBalancerBuilder::find_best_idx(&servers, mode);
 
+        let turns = vec![0.0; servers.len()];
         let balancer_context = PingBalancerContext {
+            tcp_turns: SpinMutex::new(turns.clone()),
+            udp_turns: SpinMutex::new(turns),
             servers,
             best_tcp_idx: AtomicUsize::new(best_tcp_idx),
             best_udp_idx: AtomicUsize::new(best_udp_idx),
+            pinned_idx: AtomicUsize::new(NOT_PINNED),
             context,
             mode,
             max_server_rtt,
             check_interval,
             check_best_interval,
             best_task_notify: Notify::new(),
+            strategy,
+            failback_window,
+            groups,
+            default_group,
+            probe,
+            scoring,
+            warm_pool,
         };
 
         balancer_context.init_score().await;
@@ -315,7 +735,21 @@ impl PingBalancerContext {
 
         let checker_abortable = {
             let shared_context = shared_context.clone();
-            tokio::spawn(async move { shared_context.checker_task().await })
+            tokio::spawn(async move {
+                let pause_controller = shared_context.context.pause_controller().clone();
+                pause_controller.park(shared_context.checker_task()).await
+            })
+        };
+
+        let warm_pool_abortable = match warm_pool {
+            Some(warm_pool) if mode.enable_tcp() => {
+                let shared_context = shared_context.clone();
+                Some(tokio::spawn(async move {
+                    let pause_controller = shared_context.context.pause_controller().clone();
+                    pause_controller.park(shared_context.warm_pool_task(warm_pool)).await
+                }))
+            }
+            _ => None,
         };
 
         Ok((
@@ -323,10 +757,29 @@ impl PingBalancerContext {
             PingBalancerContextTask {
                 checker_abortable,
                 plugin_abortable,
+                warm_pool_abortable,
             },
         ))
     }
 
+    /// Options of this context, for creating a new one with other servers
+    fn options(&self) -> PingBalancerOptions {
+        PingBalancerOptions {
+            context: self.context.clone(),
+            mode: self.mode,
+            max_server_rtt: self.max_server_rtt,
+            check_interval: self.check_interval,
+            check_best_interval: self.check_best_interval,
+            strategy: self.strategy,
+            failback_window: self.failback_window,
+            groups: self.groups.clone(),
+            default_group: self.default_group.clone(),
+            probe: self.probe.clone(),
+            scoring: self.scoring,
+            warm_pool: self.warm_pool,
+        }
+    }
+
     async fn init_score(&self) {
         assert!(!self.servers.is_empty(), "check PingBalancer without any servers");
 
@@ -338,7 +791,13 @@ impl PingBalancerContext {
     }
 
     fn check_server_udp_enabled(svr_cfg: &ServerConfig) -> bool {
-        svr_cfg.mode().enable_udp() && svr_cfg.weight().udp_weight() > 0.0
+        // UDP-over-TCP relays UDP packets in TCP streams
+        let udp_enabled = if svr_cfg.udp_over_tcp() {
+            svr_cfg.mode().enable_tcp()
+        } else {
+            svr_cfg.mode().enable_udp()
+        };
+        udp_enabled && svr_cfg.weight().udp_weight() > 0.0
     }
 
     fn probing_required(&self) -> bool {
@@ -355,7 +814,45 @@ impl PingBalancerContext {
             }
         }
 
-        tcp_count > 1 || udp_count > 1
+        let min_count = self.min_probed_servers();
+        tcp_count >= min_count || udp_count >= min_count
+    }
+
+    /// Servers are probed for choosing the best one among them, or for knowing whether they are all down if direct
+    /// fallback is enabled
+    fn min_probed_servers(&self) -> usize {
+        if self.context.direct_fallback().is_some() {
+            1
+        } else {
+            2
+        }
+    }
+
+    /// Connect targets directly if all servers are down, or proxy them again if any server is up
+    fn update_direct_fallback(&self) {
+        let direct_fallback = match self.context.direct_fallback() {
+            Some(direct_fallback) => direct_fallback,
+            None => return,
+        };
+
+        let mut checked = false;
+        let mut alive = false;
+        for server in self.servers.iter() {
+            if server.is_excluded() {
+                continue;
+            }
+            let svr_cfg = server.server_config();
+            if self.mode.enable_tcp() && PingBalancerContext::check_server_tcp_enabled(svr_cfg) {
+                checked = true;
+                alive |= server.tcp_score().is_alive();
+            }
+            if self.mode.enable_udp() && PingBalancerContext::check_server_udp_enabled(svr_cfg) {
+                checked = true;
+                alive |= server.udp_score().is_alive();
+            }
+        }
+
+        direct_fallback.set_servers_down(checked && !alive);
     }
 
     async fn checker_task(self: Arc<Self>) {
@@ -373,6 +870,93 @@ impl PingBalancerContext {
         future::pending().await
     }
 
+    /// Choose the best server's index after checking, `old_best_idx` is the current one
+    fn choose_best_idx(&self, server_type: ServerType, old_best_idx: usize) -> usize {
+        if self.strategy == BalancerStrategy::Failover {
+            return self.failover_idx(server_type, old_best_idx);
+        }
+
+        let mut best: Option<(usize, u32)> = None;
+        for (idx, server) in self.servers.iter().enumerate() {
+            if server.is_excluded() {
+                continue;
+            }
+            let score = match server_type {
+                ServerType::Tcp => server.tcp_score().score(),
+                ServerType::Udp => server.udp_score().score(),
+            };
+            match best {
+                Some((_, best_score)) if best_score <= score => {}
+                _ => best = Some((idx, score)),
+            }
+        }
+        best.map(|(idx, _)| idx).unwrap_or(old_best_idx)
+    }
+
+    /// Choose the best servers again immediately, after servers are excluded or included
+    fn update_best_idx(&self) {
+        for (server_type, enabled, best_idx) in [
+            (ServerType::Tcp, self.mode.enable_tcp(), &self.best_tcp_idx),
+            (ServerType::Udp, self.mode.enable_udp(), &self.best_udp_idx),
+        ] {
+            if !enabled {
+                continue;
+            }
+
+            let old_best_idx = best_idx.load(Ordering::Acquire);
+            let new_best_idx = self.choose_best_idx(server_type, old_best_idx);
+            best_idx.store(new_best_idx, Ordering::Release);
+
+            if new_best_idx != old_best_idx {
+                info!(
+                    "switched best {} server from {} to {}",
+                    server_type,
+                    ServerConfigFormatter::new(self.servers[old_best_idx].server_config()),
+                    ServerConfigFormatter::new(self.servers[new_best_idx].server_config())
+                );
+            }
+        }
+
+        self.update_direct_fallback();
+    }
+
+    /// Choose the server for `BalancerStrategy::Failover`
+    ///
+    /// The current server is kept while it is alive, unless a higher priority server has been alive for
+    /// `failback_window`. Otherwise the alive server with the highest priority is chosen.
+    fn failover_idx(&self, server_type: ServerType, old_best_idx: usize) -> usize {
+        let mut first_alive_idx = None;
+        for (idx, server) in self.servers.iter().enumerate() {
+            if server.is_excluded() {
+                continue;
+            }
+            let svr_cfg = server.server_config();
+            let score = match server_type {
+                ServerType::Tcp if PingBalancerContext::check_server_tcp_enabled(svr_cfg) => server.tcp_score(),
+                ServerType::Udp if PingBalancerContext::check_server_udp_enabled(svr_cfg) => server.udp_score(),
+                _ => continue,
+            };
+
+            let alive_duration = match score.alive_duration() {
+                Some(d) => d,
+                None => continue,
+            };
+
+            if idx == old_best_idx {
+                return old_best_idx;
+            }
+            if idx < old_best_idx && alive_duration >= self.failback_window {
+                return idx;
+            }
+            if first_alive_idx.is_none() {
+                first_alive_idx = Some(idx);
+            }
+        }
+
+        // Keep the current server if all servers are dead
+        first_alive_idx.unwrap_or(old_best_idx)
+    }
+
     /// Check each servers' score and update the best server's index
     async fn check_once(&self, first_run: bool) {
         let servers = &self.servers;
@@ -389,6 +973,7 @@ impl PingBalancerContext {
                     server_type: ServerType::Tcp,
                     context: self.context.clone(),
                     max_server_rtt: self.max_server_rtt,
+                    probe: self.probe.clone(),
                 };
                 vfut_tcp.push(checker.check_update_score());
             }
@@ -399,13 +984,15 @@ impl PingBalancerContext {
                     server_type: ServerType::Udp,
                     context: self.context.clone(),
                     max_server_rtt: self.max_server_rtt,
+                    probe: self.probe.clone(),
                 };
                 vfut_udp.push(checker.check_update_score());
             }
         }
 
-        let check_tcp = vfut_tcp.len() > 1;
-        let check_udp = vfut_udp.len() > 1;
+        let min_count = self.min_probed_servers();
+        let check_tcp = vfut_tcp.len() >= min_count;
+        let check_udp = vfut_udp.len() >= min_count;
 
         if !check_tcp && !check_udp {
             return;
@@ -421,19 +1008,11 @@ impl PingBalancerContext {
         };
 
         future::join_all(vfut).await;
+        self.update_direct_fallback();
 
         if self.mode.enable_tcp() && check_tcp {
             let old_best_idx = self.best_tcp_idx.load(Ordering::Acquire);
-
-            let mut best_idx = 0;
-            let mut best_score = u32::MAX;
-            for (idx, server) in servers.iter().enumerate() {
-                let score = server.tcp_score().score();
-                if score < best_score {
-                    best_idx = idx;
-                    best_score = score;
-                }
-            }
+            let best_idx = self.choose_best_idx(ServerType::Tcp, old_best_idx);
             self.best_tcp_idx.store(best_idx, Ordering::Release);
 
             if first_run {
@@ -459,16 +1038,7 @@ impl PingBalancerContext {
 
         if self.mode.enable_udp() && check_udp {
             let old_best_idx = self.best_udp_idx.load(Ordering::Acquire);
-
-            let mut best_idx = 0;
-            let mut best_score = u32::MAX;
-            for (idx, server) in servers.iter().enumerate() {
-                let score = server.udp_score().score();
-                if score < best_score {
-                    best_idx = idx;
-                    best_score = score;
-                }
-            }
+            let best_idx = self.choose_best_idx(ServerType::Udp, old_best_idx);
             self.best_udp_idx.store(best_idx, Ordering::Release);
 
             if first_run {
@@ -516,6 +1086,7 @@ impl PingBalancerContext {
                 server_type: ServerType::Tcp,
                 context: self.context.clone(),
                 max_server_rtt: self.max_server_rtt,
+                probe: self.probe.clone(),
             };
             vfut.push(checker.check_update_score());
             check_tcp = true;
@@ -527,25 +1098,18 @@ impl PingBalancerContext {
                 server_type: ServerType::Udp,
                 context: self.context.clone(),
                 max_server_rtt: self.max_server_rtt,
+                probe: self.probe.clone(),
             };
             vfut.push(checker.check_update_score());
             check_udp = true;
         }
 
         future::join_all(vfut).await;
+        self.update_direct_fallback();
 
         if self.mode.enable_tcp() && check_tcp {
             let old_best_idx = self.best_tcp_idx.load(Ordering::Acquire);
-
-            let mut best_idx = 0;
-            let mut best_score = u32::MAX;
-            for (idx, server) in servers.iter().enumerate() {
-                let score = server.tcp_score().score();
-                if score < best_score {
-                    best_idx = idx;
-                    best_score = score;
-                }
-            }
+            let best_idx = self.choose_best_idx(ServerType::Tcp, old_best_idx);
             self.best_tcp_idx.store(best_idx, Ordering::Release);
 
             if best_idx != old_best_idx {
@@ -566,16 +1130,7 @@ impl PingBalancerContext {
 
         if self.mode.enable_udp() && check_udp {
             let old_best_idx = self.best_udp_idx.load(Ordering::Acquire);
-
-            let mut best_idx = 0;
-            let mut best_score = u32::MAX;
-            for (idx, server) in servers.iter().enumerate() {
-                let score = server.udp_score().score();
-                if score < best_score {
-                    best_idx = idx;
-                    best_score = score;
-                }
-            }
+            let best_idx = self.choose_best_idx(ServerType::Udp, old_best_idx);
             self.best_udp_idx.store(best_idx, Ordering::Release);
 
             if best_idx != old_best_idx {
@@ -636,6 +1191,83 @@ impl PingBalancerContext {
         }
     }
 
+    /// Top-ranked servers for `warm_pool_task`, the pinned server goes first
+    ///
+    /// Servers multiplexing streams in their own pools of connections are skipped. Dead servers are ranked by their
+    /// scores as well, because a single server is never checked again after failures.
+    fn warm_pool_servers(&self, count: usize) -> Vec<Arc<ServerIdent>> {
+        let mut servers = self
+            .servers
+            .iter()
+            .filter(|server| {
+                let svr_cfg = server.server_config();
+                PingBalancerContext::check_server_tcp_enabled(svr_cfg)
+                    && svr_cfg.mux().is_none()
+                    && !server.is_excluded()
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+        servers.sort_by_key(|server| server.tcp_score().score());
+
+        if let Some(pinned) = self.pinned_server() {
+            servers.retain(|server| !Arc::ptr_eq(server, &pinned));
+            servers.insert(0, pinned);
+        }
+
+        servers.truncate(count);
+        servers
+    }
+
+    /// Keep pools of the top-ranked servers filled, and drop connections to the others
+    async fn warm_pool_task(&self, warm_pool: BalancerWarmPoolConfig) {
+        loop {
+            let servers = self.warm_pool_servers(warm_pool.servers);
+            for server in self.servers.iter() {
+                if !servers.iter().any(|s| Arc::ptr_eq(s, server)) {
+                    server.warm_pool().clear();
+                }
+            }
+
+            let vfut = servers.iter().map(|server| self.fill_warm_pool(server, &warm_pool));
+            future::join_all(vfut).await;
+
+            // Refill after connections are taken, or expired connections are evicted, or rankings are changed
+            if servers.is_empty() {
+                time::sleep(WARM_POOL_REFRESH_INTERVAL).await;
+            } else {
+                let taken = future::select_all(servers.iter().map(|server| Box::pin(server.warm_pool().taken())));
+                let _ = time::timeout(WARM_POOL_REFRESH_INTERVAL, taken).await;
+            }
+        }
+    }
+
+    async fn fill_warm_pool(&self, server: &ServerIdent, warm_pool: &BalancerWarmPoolConfig) {
+        let svr_cfg = server.server_config();
+        let len = server.warm_pool().evict_expired();
+        if len >= warm_pool.size {
+            return;
+        }
+
+        let connect_opts = self.context.server_connect_opts(svr_cfg);
+        let vfut = (len..warm_pool.size).map(|_| async {
+            let start = Instant::now();
+            let stream = connect_server_with_opts(self.context.context_ref(), svr_cfg, &connect_opts).await?;
+            server.warm_pool().put(stream, start.elapsed(), warm_pool.idle_timeout);
+            Ok::<_, io::Error>(())
+        });
+
+        for result in future::join_all(vfut).await {
+            if let Err(err) = result {
+                // Failures are scored by checks and requests, connections are tried again in the next round
+                debug!(
+                    "failed to connect warm pool of server {}, error: {}",
+                    ServerConfigFormatter::new(svr_cfg),
+                    err
+                );
+            }
+        }
+    }
+
     async fn checker_task_best_server(&self) {
         // Wait until checker_task_all_servers notify.
         // Because when server starts, the scores are unstable, so we have to run check_all for multiple times
@@ -656,6 +1288,8 @@ impl PingBalancerContext {
 struct PingBalancerInner {
     context: ArcSwap<PingBalancerContext>,
     task_abortable: SpinMutex<PingBalancerContextTask>,
+    // Servers are changed one at a time, changes wouldn't be lost by replacing contexts concurrently
+    update_lock: AsyncMutex<()>,
 }
 
 impl Drop for PingBalancerInner {
@@ -668,9 +1302,25 @@ impl Drop for PingBalancerInner {
 #[derive(Clone)]
 pub struct PingBalancer {
     inner: Arc<PingBalancerInner>,
+    /// Context of the local server choosing servers, whose ACL routes targets instead of the balancer's
+    route_context: Option<Arc<ServiceContext>>,
+    /// Group of servers of the local server, overriding `default_group`
+    group: Option<String>,
 }
 
 impl PingBalancer {
+    /// Get a balancer sharing servers and their scores with this one, for a local server with its own `context`
+    ///
+    /// Targets are routed by ACL's `[route:TAG]` rules of `context`, and chosen from servers of `group` if they are
+    /// not routed, instead of the default group
+    pub fn for_local(&self, context: Arc<ServiceContext>, group: Option<String>) -> PingBalancer {
+        PingBalancer {
+            inner: self.inner.clone(),
+            route_context: Some(context),
+            group: group.or_else(|| self.group.clone()),
+        }
+    }
+
     /// Get service context
     pub fn context(&self) -> Arc<ServiceContext> {
         let context = self.inner.context.load();
@@ -689,6 +1339,159 @@ impl PingBalancer {
         context.best_udp_server()
     }
 
+    /// Pick the best TCP server for client `peer_addr` connecting to `addr`
+    ///
+    /// If `addr` matches ACL's `[route:TAG]` rules, only servers of group `TAG` are chosen, otherwise servers of the
+    /// local server's group or the default group if it is configured
+    pub async fn best_tcp_server_for(&self, peer_addr: SocketAddr, addr: &Address) -> io::Result<Arc<ServerIdent>> {
+        let context = self.inner.context.load_full();
+        let route_context = self.route_context.as_ref().unwrap_or(&context.context);
+        let default_group = self.group.as_deref().or(context.default_group.as_deref());
+        context
+            .best_server_for(route_context, default_group, peer_addr, addr, ServerType::Tcp)
+            .await
+    }
+
+    /// Pick the best UDP server for client `peer_addr` sending packets to `addr`
+    ///
+    /// If `addr` matches ACL's `[route:TAG]` rules, only servers of group `TAG` are chosen, otherwise servers of the
+    /// local server's group or the default group if it is configured
+    pub async fn best_udp_server_for(&self, peer_addr: SocketAddr, addr: &Address) -> io::Result<Arc<ServerIdent>> {
+        let context = self.inner.context.load_full();
+        let route_context = self.route_context.as_ref().unwrap_or(&context.context);
+        let default_group = self.group.as_deref().or(context.default_group.as_deref());
+        context
+            .best_server_for(route_context, default_group, peer_addr, addr, ServerType::Udp)
+            .await
+    }
+
+    /// Pick the best TCP server of group `tag` for client `peer_addr` connecting to `addr`, regardless of ACL
+    pub fn best_tcp_server_tagged(
+        &self,
+        peer_addr: SocketAddr,
+        addr: &Address,
+        tag: &str,
+    ) -> io::Result<Arc<ServerIdent>> {
+        let context = self.inner.context.load();
+        context.best_routed_server(peer_addr, addr, tag, ServerType::Tcp)
+    }
+
+    /// Pick the best UDP server of group `tag` for client `peer_addr` sending packets to `addr`, regardless of ACL
+    pub fn best_udp_server_tagged(
+        &self,
+        peer_addr: SocketAddr,
+        addr: &Address,
+        tag: &str,
+    ) -> io::Result<Arc<ServerIdent>> {
+        let context = self.inner.context.load();
+        context.best_routed_server(peer_addr, addr, tag, ServerType::Udp)
+    }
+
+    /// Pick a new UDP server for sending packets to `addr`, if `server` is marked dead
+    ///
+    /// Returns `None` if `server` is alive, or there is no other alive server to migrate to
+    pub async fn udp_failover_server_for(
+        &self,
+        server: &Arc<ServerIdent>,
+        peer_addr: SocketAddr,
+        addr: &Address,
+    ) -> Option<Arc<ServerIdent>> {
+        if server.udp_score().is_alive() {
+            return None;
+        }
+
+        match self.best_udp_server_for(peer_addr, addr).await {
+            Ok(best) if !Arc::ptr_eq(&best, server) && best.udp_score().is_alive() => Some(best),
+            _ => None,
+        }
+    }
+
+    /// Pin the server listening on `addr`, which will be chosen instead of the best servers
+    ///
+    /// Servers routed by ACL's `[route:TAG]` rules are not affected
+    pub fn pin_server(&self, addr: &ServerAddr) -> io::Result<()> {
+        let context = self.inner.context.load();
+        let idx = context.find_server(addr)?;
+        if context.servers[idx].is_excluded() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("server {} is excluded", addr),
+            ));
+        }
+
+        context.pinned_idx.store(idx, Ordering::Relaxed);
+        info!(
+            "pinned server {}",
+            ServerConfigFormatter::new(context.servers[idx].server_config())
+        );
+        Ok(())
+    }
+
+    /// Unpin the pinned server, choosing the best servers again
+    pub fn unpin_server(&self) {
+        let context = self.inner.context.load();
+        if context.pinned_idx.swap(NOT_PINNED, Ordering::Relaxed) != NOT_PINNED {
+            info!("unpinned server");
+        }
+    }
+
+    /// Get the pinned server
+    pub fn pinned_server(&self) -> Option<Arc<ServerIdent>> {
+        let context = self.inner.context.load();
+        context.pinned_server()
+    }
+
+    /// Exclude the server listening on `addr`, which won't be chosen until it is included again
+    ///
+    /// The server is still checked. It is unpinned if it was pinned, and the last server couldn't be excluded.
+    pub fn exclude_server(&self, addr: &ServerAddr) -> io::Result<()> {
+        let context = self.inner.context.load();
+        let idx = context.find_server(addr)?;
+        let server = &context.servers[idx];
+        if server.is_excluded() {
+            return Ok(());
+        }
+        if context
+            .servers
+            .iter()
+            .all(|s| s.is_excluded() || Arc::ptr_eq(s, server))
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "couldn't exclude the last server",
+            ));
+        }
+
+        server.set_excluded(true);
+        if context
+            .pinned_idx
+            .compare_exchange(idx, NOT_PINNED, Ordering::Relaxed, Ordering::Relaxed)
+            .is_ok()
+        {
+            info!("unpinned server");
+        }
+        info!("excluded server {}", ServerConfigFormatter::new(server.server_config()));
+
+        context.update_best_idx();
+        Ok(())
+    }
+
+    /// Include the server listening on `addr` again, which is excluded by `exclude_server`
+    pub fn include_server(&self, addr: &ServerAddr) -> io::Result<()> {
+        let context = self.inner.context.load();
+        let idx = context.find_server(addr)?;
+        let server = &context.servers[idx];
+        if !server.is_excluded() {
+            return Ok(());
+        }
+
+        server.set_excluded(false);
+        info!("included server {}", ServerConfigFormatter::new(server.server_config()));
+
+        context.update_best_idx();
+        Ok(())
+    }
+
     /// Get the server list
     pub fn servers(&self) -> PingServerIter<'_> {
         let context = self.inner.context.load();
@@ -699,30 +1502,103 @@ impl PingBalancer {
         }
     }
 
+    /// Add `server` at `index` of the server list, or append it if `index` is `None`
+    ///
+    /// Order of the list is the priority of `BalancerStrategy::Failover`. Servers are checked again, like
+    /// `reset_servers`, established connections are kept.
+    pub async fn add_server(&self, server: ServerConfig, index: Option<usize>) -> io::Result<()> {
+        let _guard = self.inner.update_lock.lock().await;
+
+        let mut servers = self.server_configs();
+        if servers.iter().any(|s| s.addr() == server.addr()) {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("server {} already exists", server.addr()),
+            ));
+        }
+
+        let index = index.unwrap_or(servers.len()).min(servers.len());
+        info!("adding server {} at {}", ServerConfigFormatter::new(&server), index);
+        servers.insert(index, server);
+        self.reset_servers_locked(servers).await
+    }
+
+    /// Remove the server listening on `addr`, the last server that isn't excluded couldn't be removed
+    pub async fn remove_server(&self, addr: &ServerAddr) -> io::Result<()> {
+        let _guard = self.inner.update_lock.lock().await;
+
+        let context = self.inner.context.load();
+        let idx = context.find_server(addr)?;
+        if context
+            .servers
+            .iter()
+            .enumerate()
+            .all(|(i, s)| i == idx || s.is_excluded())
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "couldn't remove the last server",
+            ));
+        }
+
+        let mut servers = self.server_configs();
+        let server = servers.remove(idx);
+        info!("removing server {}", ServerConfigFormatter::new(&server));
+        self.reset_servers_locked(servers).await
+    }
+
+    /// Move the server listening on `addr` to `index` of the server list, changing its priority of
+    /// `BalancerStrategy::Failover`
+    pub async fn move_server(&self, addr: &ServerAddr, index: usize) -> io::Result<()> {
+        let _guard = self.inner.update_lock.lock().await;
+
+        let context = self.inner.context.load();
+        let idx = context.find_server(addr)?;
+        let index = index.min(context.servers.len() - 1);
+        if idx == index {
+            return Ok(());
+        }
+
+        let mut servers = self.server_configs();
+        let server = servers.remove(idx);
+        info!("moving server {} to {}", ServerConfigFormatter::new(&server), index);
+        servers.insert(index, server);
+        self.reset_servers_locked(servers).await
+    }
+
+    /// Configurations of servers in the list, plugins are started again by `reset_servers`
+    fn server_configs(&self) -> Vec<ServerConfig> {
+        let context = self.inner.context.load();
+        context.servers.iter().map(|s| s.server_config().clone()).collect()
+    }
+
     /// Reset servers in load balancer. Designed for auto-reloading configuration file.
     pub async fn reset_servers(&self, servers: Vec<ServerConfig>) -> io::Result<()> {
+        let _guard = self.inner.update_lock.lock().await;
+        self.reset_servers_locked(servers).await
+    }
+
+    async fn reset_servers_locked(&self, servers: Vec<ServerConfig>) -> io::Result<()> {
         let old_context = self.inner.context.load();
 
         let servers = servers
             .into_iter()
             .map(|s| {
-                Arc::new(ServerIdent::new(
+                let ident = ServerIdent::new(
                     s,
                     old_context.max_server_rtt,
                     old_context.check_interval * EXPECTED_CHECK_POINTS_IN_CHECK_WINDOW,
-                ))
+                    old_context.scoring,
+                );
+                // Keep the server excluded if it is still there
+                if let Ok(idx) = old_context.find_server(ident.server_config().addr()) {
+                    ident.set_excluded(old_context.servers[idx].is_excluded());
+                }
+                Arc::new(ident)
             })
             .collect::<Vec<Arc<ServerIdent>>>();
 
-        let (shared_context, task_abortable) = PingBalancerContext::new(
-            servers,
-            old_context.context.clone(),
-            old_context.mode,
-            old_context.max_server_rtt,
-            old_context.check_interval,
-            old_context.check_best_interval,
-        )
-        .await?;
+        let (shared_context, task_abortable) = PingBalancerContext::new(servers, old_context.options()).await?;
 
         {
             // Stop the previous task and replace with the new task
@@ -730,6 +1606,18 @@ impl PingBalancer {
             *abortable = task_abortable;
         }
 
+        // Keep the pinned server if it is still there
+        if let Some(pinned) = old_context.pinned_server() {
+            let addr = pinned.server_config().addr();
+            if let Some(idx) = shared_context
+                .servers
+                .iter()
+                .position(|s| s.server_config().addr() == addr)
+            {
+                shared_context.pinned_idx.store(idx, Ordering::Relaxed);
+            }
+        }
+
         // Replace with the new context
         self.inner.context.store(shared_context);
 
@@ -745,6 +1633,7 @@ impl Debug for PingBalancer {
             .field("servers", &context.servers)
             .field("best_tcp_idx", &context.best_tcp_idx.load(Ordering::Relaxed))
             .field("best_udp_idx", &context.best_udp_idx.load(Ordering::Relaxed))
+            .field("pinned_idx", &context.pinned_idx.load(Ordering::Relaxed))
             .finish()
     }
 }
@@ -754,105 +1643,164 @@ struct PingChecker {
     server_type: ServerType,
     context: Arc<ServiceContext>,
     max_server_rtt: Duration,
+    probe: Arc<BalancerProbeConfig>,
 }
 
 impl PingChecker {
-    /// Checks server's score and update into `ServerScore<E>`
+    /// Checks server's score and update into `ServerScore<E>`, with `samples` probes in sequence
     async fn check_update_score(self) {
-        let score = match self.check_delay().await {
-            Ok(d) => match self.server_type {
-                ServerType::Tcp => self.server.tcp_score().push_score(Score::Latency(d)).await,
-                ServerType::Udp => self.server.udp_score().push_score(Score::Latency(d)).await,
-            },
-            // Penalty
-            Err(..) => match self.server_type {
-                ServerType::Tcp => self.server.tcp_score().push_score(Score::Errored).await,
-                ServerType::Udp => self.server.udp_score().push_score(Score::Errored).await,
-            },
+        let server_score = match self.server_type {
+            ServerType::Tcp => self.server.tcp_score(),
+            ServerType::Udp => self.server.udp_score(),
         };
 
-        trace!(
-            "updated remote {} server {} (score: {})",
-            self.server_type,
-            self.server.server_config().addr(),
-            score
-        );
+        for _ in 0..self.probe.samples {
+            let old_score = server_score.score();
+            let old_alive = server_score.is_alive();
+
+            let score = match self.check_delay().await {
+                Ok(d) => server_score.push_score(Score::Latency(d)).await,
+                // Penalty
+                Err(..) => server_score.push_score(Score::Errored).await,
+            };
+
+            trace!(
+                "updated remote {} server {} (score: {})",
+                self.server_type,
+                self.server.server_config().addr(),
+                score
+            );
+
+            // Transitions: server went up or down, or its score changed by more than 10%
+            let alive = server_score.is_alive();
+            if alive != old_alive {
+                info!(
+                    "remote {} server {} is {}, score {} -> {}",
+                    self.server_type,
+                    self.server.server_config().addr(),
+                    if alive { "up" } else { "down" },
+                    old_score,
+                    score
+                );
+            } else if old_score.abs_diff(score) > old_score / 10 {
+                debug!(
+                    "remote {} server {} score {} -> {}",
+                    self.server_type,
+                    self.server.server_config().addr(),
+                    old_score,
+                    score
+                );
+            }
+        }
     }
 
-    /// Detect TCP connectivity with Chromium [Network Portal Detection](https://www.chromium.org/chromium-os/chromiumos-design-docs/network-portal-detection)
-    #[allow(dead_code)]
-    async fn check_request_tcp_chromium(&self) -> io::Result<()> {
-        static GET_BODY: &[u8] =
-            b"GET /generate_204 HTTP/1.1\r\nHost: clients3.google.com\r\nConnection: close\r\nAccept: */*\r\n\r\n";
+    /// Detect TCP connectivity by connecting to the server (or its plugin)
+    async fn check_request_tcp_connect(&self) -> io::Result<()> {
+        // Servers with the QUIC transport are not listening on TCP
+        #[cfg(feature = "transport-quic")]
+        if let Some(quic) = self.server.server_config().quic() {
+            let _stream = quic
+                .connect(
+                    self.context.context_ref(),
+                    self.server.server_config().addr(),
+                    &self.context.server_connect_opts(self.server.server_config()),
+                )
+                .await?;
+            return Ok(());
+        }
+
+        // Servers listening on a range of ports are checked on the current port, through their upstream proxies
+        let svr_cfg = self.server.server_config();
+        let hopping_addr = svr_cfg.hopping_addr();
+        let addr = hopping_addr.as_ref().unwrap_or_else(|| svr_cfg.external_addr());
+        let connect_opts = self.context.server_connect_opts(svr_cfg);
+        let result = match svr_cfg.upstream_proxy() {
+            Some(proxy) => {
+                TcpStream::connect_server_via_proxy_with_opts(self.context.context_ref(), proxy, addr, &connect_opts)
+                    .await
+            }
+            None => TcpStream::connect_server_with_opts(self.context.context_ref(), addr, &connect_opts).await,
+        };
+
+        if let Err(err) = result {
+            if let Some(port_hopping) = svr_cfg.port_hopping() {
+                port_hopping.hop();
+            }
+            return Err(err);
+        }
+
+        Ok(())
+    }
 
-        let addr = Address::DomainNameAddress("clients3.google.com".to_owned(), 80);
+    /// Detect TCP connectivity with a TLS handshake with `addr`, succeeded when `addr` answers with a ServerHello
+    async fn check_request_tcp_tls(&self, addr: &Address) -> io::Result<()> {
+        let client_hello = match *addr {
+            Address::DomainNameAddress(ref domain, ..) => tls_client_hello(Some(domain)),
+            Address::SocketAddress(..) => tls_client_hello(None),
+        };
 
         let mut stream = ProxyClientStream::connect_with_opts(
             self.context.context(),
             self.server.server_config(),
-            &addr,
-            self.context.connect_opts_ref(),
+            addr,
+            &self.context.server_connect_opts(self.server.server_config()),
         )
         .await?;
-        stream.write_all(GET_BODY).await?;
+        stream.write_all(&client_hello).await?;
 
-        let mut reader = BufReader::new(stream);
-
-        let mut buf = Vec::new();
-        reader.read_until(b'\n', &mut buf).await?;
+        // Record header (5 bytes) and the type of the first handshake message
+        let mut header = [0u8; 6];
+        stream.read_exact(&mut header).await?;
 
-        static EXPECTED_HTTP_STATUS_LINE: &[u8] = b"HTTP/1.1 204 No Content\r\n";
-        if buf != EXPECTED_HTTP_STATUS_LINE {
+        // Handshake record with ServerHello (or HelloRetryRequest, which is also a ServerHello)
+        if header[0] != 0x16 || header[5] != 0x02 {
             use std::io::{Error, ErrorKind};
 
-            debug!(
-                "unexpected response from http://clients3.google.com/generate_204, {:?}",
-                ByteStr::new(&buf)
-            );
+            debug!("unexpected TLS response from {}, {:?}", addr, ByteStr::new(&header));
 
-            let err = Error::new(
-                ErrorKind::InvalidData,
-                "unexpected response from http://clients3.google.com/generate_204",
-            );
+            let err = Error::new(ErrorKind::InvalidData, format!("unexpected TLS response from {}", addr));
             return Err(err);
         }
 
         Ok(())
     }
 
-    /// Detect TCP connectivity with Firefox's http://detectportal.firefox.com/success.txt
-    async fn check_request_tcp_firefox(&self) -> io::Result<()> {
-        static GET_BODY: &[u8] =
-            b"GET /success.txt HTTP/1.1\r\nHost: detectportal.firefox.com\r\nConnection: close\r\nAccept: */*\r\n\r\n";
-
-        let addr = Address::DomainNameAddress("detectportal.firefox.com".to_owned(), 80);
+    /// Detect TCP connectivity with HTTP GET, succeeded with 2xx status, like Firefox's http://detectportal.firefox.com/success.txt
+    async fn check_request_tcp_http(&self, addr: &Address, host: &str, path: &str) -> io::Result<()> {
+        let request = format!(
+            "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\nAccept: */*\r\n\r\n",
+            path, host
+        );
 
         let mut stream = ProxyClientStream::connect_with_opts(
             self.context.context(),
             self.server.server_config(),
-            &addr,
-            self.context.connect_opts_ref(),
+            addr,
+            &self.context.server_connect_opts(self.server.server_config()),
         )
         .await?;
-        stream.write_all(GET_BODY).await?;
+        stream.write_all(request.as_bytes()).await?;
 
         let mut reader = BufReader::new(stream);
 
         let mut buf = Vec::new();
         reader.read_until(b'\n', &mut buf).await?;
 
-        static EXPECTED_HTTP_STATUS_LINE: &[u8] = b"HTTP/1.1 200 OK\r\n";
-        if buf != EXPECTED_HTTP_STATUS_LINE {
+        // HTTP/1.x 2xx ...
+        let succeeded = buf.starts_with(b"HTTP/1.") && buf.len() > 12 && buf[8] == b' ' && buf[9] == b'2';
+        if !succeeded {
             use std::io::{Error, ErrorKind};
 
             debug!(
-                "unexpected response from http://detectportal.firefox.com/success.txt, {:?}",
+                "unexpected response from http://{}{}, {:?}",
+                host,
+                path,
                 ByteStr::new(&buf)
             );
 
             let err = Error::new(
                 ErrorKind::InvalidData,
-                "unexpected response from http://detectportal.firefox.com/success.txt",
+                format!("unexpected response from http://{}{}", host, path),
             );
             return Err(err);
         }
@@ -874,15 +1822,10 @@ impl PingChecker {
         static DNS_QUERY: &[u8] =
             b"\x12\x34\x01\x00\x00\x01\x00\x00\x00\x00\x00\x00\x07firefox\x03com\x00\x00\x01\x00\x01";
 
-        let addr = Address::SocketAddress(SocketAddr::new(Ipv4Addr::new(8, 8, 8, 8).into(), 53));
+        let addr = &self.probe.udp_target;
 
-        let client = ProxySocket::connect_with_opts(
-            self.context.context(),
-            self.server.server_config(),
-            self.context.connect_opts_ref(),
-        )
-        .await?;
-        client.send(&addr, DNS_QUERY).await?;
+        let mut client = ProxiedUdpSocket::connect(&self.context, self.server.server_config()).await?;
+        client.send(addr, DNS_QUERY).await?;
 
         let mut buffer = [0u8; MAXIMUM_UDP_PAYLOAD_SIZE];
         let (n, ..) = client.recv(&mut buffer).await?;
@@ -893,9 +1836,9 @@ impl PingChecker {
         if dns_answer.len() < 12 || &dns_answer[0..2] != b"\x12\x34" {
             use std::io::{Error, ErrorKind};
 
-            debug!("unexpected response from 8.8.8.8:53, {:?}", ByteStr::new(dns_answer));
+            debug!("unexpected response from {}, {:?}", addr, ByteStr::new(dns_answer));
 
-            let err = Error::new(ErrorKind::InvalidData, "unexpected response from 8.8.8.8:53");
+            let err = Error::new(ErrorKind::InvalidData, format!("unexpected response from {}", addr));
             return Err(err);
         }
 
@@ -903,8 +1846,31 @@ impl PingChecker {
     }
 
     async fn check_request(&self) -> io::Result<()> {
+        // Servers are down while their plugins are restarting, until the restarted plugins accept connections
+        if let (Some(status), Some(plugin)) = (self.server.plugin_status(), self.server.server_config().plugin()) {
+            // UDP-over-TCP relays UDP packets in TCP streams
+            let relayed = match self.server_type {
+                ServerType::Udp if !self.server.server_config().udp_over_tcp() => plugin.plugin_mode.enable_udp(),
+                _ => plugin.plugin_mode.enable_tcp(),
+            };
+            if relayed && !status.is_running() {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    format!("plugin is {}", status.health()),
+                ));
+            }
+        }
+
         match self.server_type {
-            ServerType::Tcp => self.check_request_tcp_firefox().await,
+            ServerType::Tcp => match self.probe.tcp {
+                BalancerTcpProbe::Tcp => self.check_request_tcp_connect().await,
+                BalancerTcpProbe::Tls(ref addr) => self.check_request_tcp_tls(addr).await,
+                BalancerTcpProbe::Http {
+                    ref addr,
+                    ref host,
+                    ref path,
+                } => self.check_request_tcp_http(addr, host, path).await,
+            },
             ServerType::Udp => self.check_request_udp().await,
         }
     }
@@ -912,7 +1878,7 @@ impl PingChecker {
     async fn check_delay(&self) -> io::Result<u32> {
         let start = Instant::now();
 
-        // Send HTTP GET and read the first byte
+        // Send the probe and read the response
         let res = time::timeout(self.max_server_rtt, self.check_request()).await;
 
         let elapsed = Instant::now() - start;
@@ -957,6 +1923,97 @@ impl PingChecker {
     }
 }
 
+/// TLS ClientHello offering TLS 1.3 and 1.2 with common cipher suites, `server_name` is sent in SNI
+///
+/// Probes never finish handshakes, so the random and the X25519 key share are constants.
+fn tls_client_hello(server_name: Option<&str>) -> Vec<u8> {
+    // X25519 public key of RFC 7748, section 6.1
+    static KEY_SHARE: [u8; 32] = [
+        0x85, 0x20, 0xf0, 0x09, 0x89, 0x30, 0xa7, 0x54, 0x74, 0x8b, 0x7d, 0xdc, 0xb4, 0x3e, 0xf7, 0x5a, 0x0d, 0xbf,
+        0x3a, 0x0d, 0x26, 0x38, 0x1a, 0xf4, 0xeb, 0xa4, 0xa9, 0x8e, 0xaa, 0x9b, 0x4e, 0x6a,
+    ];
+
+    // TLS_AES_128_GCM_SHA256, TLS_AES_256_GCM_SHA384, TLS_CHACHA20_POLY1305_SHA256,
+    // ECDHE-{ECDSA,RSA}-AES128-GCM-SHA256, ECDHE-{ECDSA,RSA}-AES256-GCM-SHA384, ECDHE-{ECDSA,RSA}-CHACHA20-POLY1305
+    static CIPHER_SUITES: &[u16] = &[0x1301, 0x1302, 0x1303, 0xc02b, 0xc02f, 0xc02c, 0xc030, 0xcca9, 0xcca8];
+
+    // ecdsa_secp256r1_sha256, rsa_pss_rsae_sha256, rsa_pkcs1_sha256, ecdsa_secp384r1_sha384, rsa_pss_rsae_sha384,
+    // rsa_pkcs1_sha384, rsa_pss_rsae_sha512, rsa_pkcs1_sha512
+    static SIGNATURE_ALGORITHMS: &[u16] = &[0x0403, 0x0804, 0x0401, 0x0503, 0x0805, 0x0501, 0x0806, 0x0601];
+
+    fn put_u16(buf: &mut Vec<u8>, n: usize) {
+        buf.extend_from_slice(&(n as u16).to_be_bytes());
+    }
+
+    fn put_extension(buf: &mut Vec<u8>, ext_type: u16, data: &[u8]) {
+        put_u16(buf, ext_type as usize);
+        put_u16(buf, data.len());
+        buf.extend_from_slice(data);
+    }
+
+    let mut extensions = Vec::new();
+
+    if let Some(server_name) = server_name {
+        // server_name_list with one host_name
+        let mut data = Vec::new();
+        put_u16(&mut data, server_name.len() + 3);
+        data.push(0x00);
+        put_u16(&mut data, server_name.len());
+        data.extend_from_slice(server_name.as_bytes());
+        put_extension(&mut extensions, 0x0000, &data);
+    }
+
+    // supported_groups: x25519, secp256r1
+    put_extension(&mut extensions, 0x000a, &[0x00, 0x04, 0x00, 0x1d, 0x00, 0x17]);
+    // ec_point_formats: uncompressed
+    put_extension(&mut extensions, 0x000b, &[0x01, 0x00]);
+
+    let mut data = Vec::new();
+    put_u16(&mut data, SIGNATURE_ALGORITHMS.len() * 2);
+    for alg in SIGNATURE_ALGORITHMS {
+        data.extend_from_slice(&alg.to_be_bytes());
+    }
+    put_extension(&mut extensions, 0x000d, &data);
+
+    // supported_versions: TLS 1.3, TLS 1.2
+    put_extension(&mut extensions, 0x002b, &[0x04, 0x03, 0x04, 0x03, 0x03]);
+
+    // key_share: x25519
+    let mut data = Vec::new();
+    put_u16(&mut data, KEY_SHARE.len() + 4);
+    data.extend_from_slice(&[0x00, 0x1d]);
+    put_u16(&mut data, KEY_SHARE.len());
+    data.extend_from_slice(&KEY_SHARE);
+    put_extension(&mut extensions, 0x0033, &data);
+
+    let mut hello = Vec::new();
+    // legacy_version: TLS 1.2
+    hello.extend_from_slice(&[0x03, 0x03]);
+    // random
+    hello.extend_from_slice(&[0x5a; 32]);
+    // legacy_session_id, non-empty for middlebox compatibility
+    hello.push(32);
+    hello.extend_from_slice(&[0xa5; 32]);
+    put_u16(&mut hello, CIPHER_SUITES.len() * 2);
+    for suite in CIPHER_SUITES {
+        hello.extend_from_slice(&suite.to_be_bytes());
+    }
+    // compression_methods: null
+    hello.extend_from_slice(&[0x01, 0x00]);
+    put_u16(&mut hello, extensions.len());
+    hello.extend_from_slice(&extensions);
+
+    let mut record = Vec::with_capacity(hello.len() + 9);
+    // Handshake record of TLS 1.0, for compatibility
+    record.extend_from_slice(&[0x16, 0x03, 0x01]);
+    put_u16(&mut record, hello.len() + 4);
+    // ClientHello
+    record.push(0x01);
+    record.extend_from_slice(&(hello.len() as u32).to_be_bytes()[1..]);
+    record.extend_from_slice(&hello);
+    record
+}
+
 struct ServerConfigFormatter<'a> {
     server_config: &'a ServerConfig,
 }
@@ -0,0 +1,182 @@
+//! Statistic of connections relayed through a remote server
+//!
+//! Unlike `ServerStat`, which scores servers by recent checks, it accumulates latencies of handshakes, failures of
+//! connecting and errors of relaying since the server was added, telling which server is degrading.
+
+use std::{fmt, io, sync::atomic::Ordering, time::Duration};
+
+use shadowsocks::context::ClockSkewError;
+
+#[cfg(not(any(target_arch = "mips", target_arch = "powerpc")))]
+type StatCounter = std::sync::atomic::AtomicU64;
+#[cfg(any(target_arch = "mips", target_arch = "powerpc"))]
+type StatCounter = std::sync::atomic::AtomicU32;
+
+/// Upper bounds (in millisec) of buckets of handshake latencies
+pub const HANDSHAKE_LATENCY_BUCKETS: [u64; 10] = [10, 25, 50, 100, 250, 500, 1000, 2500, 5000, 10000];
+
+/// Class of errors that closed relays
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RelayErrorClass {
+    /// Timed out reading or writing
+    Timeout,
+    /// Connection reset or aborted
+    Reset,
+    /// Connection closed in the middle of a packet
+    UnexpectedEof,
+    /// Data failed to be decrypted or parsed, usually a wrong key or method
+    Protocol,
+    /// AEAD 2022 timestamp of the server is too far from the local clock, the clock of either side is wrong
+    ClockSkew,
+    /// Any other errors
+    Other,
+}
+
+impl RelayErrorClass {
+    /// All classes, in the order of `RelayStat::relay_errors`
+    pub const ALL: [RelayErrorClass; 6] = [
+        RelayErrorClass::Timeout,
+        RelayErrorClass::Reset,
+        RelayErrorClass::UnexpectedEof,
+        RelayErrorClass::Protocol,
+        RelayErrorClass::ClockSkew,
+        RelayErrorClass::Other,
+    ];
+
+    /// Classify an error returned by relaying
+    pub fn of(err: &io::Error) -> RelayErrorClass {
+        if ClockSkewError::is_clock_skew(err) {
+            return RelayErrorClass::ClockSkew;
+        }
+
+        match err.kind() {
+            io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => RelayErrorClass::Timeout,
+            io::ErrorKind::ConnectionReset | io::ErrorKind::ConnectionAborted | io::ErrorKind::BrokenPipe => {
+                RelayErrorClass::Reset
+            }
+            io::ErrorKind::UnexpectedEof => RelayErrorClass::UnexpectedEof,
+            io::ErrorKind::InvalidData => RelayErrorClass::Protocol,
+            // Failures of decryption are reported by AEAD cipher streams without a specific kind
+            io::ErrorKind::Other if err.to_string() == "invalid tag-in" => RelayErrorClass::Protocol,
+            _ => RelayErrorClass::Other,
+        }
+    }
+
+    /// Name of the class, as it is labeled in metrics
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            RelayErrorClass::Timeout => "timeout",
+            RelayErrorClass::Reset => "reset",
+            RelayErrorClass::UnexpectedEof => "unexpected_eof",
+            RelayErrorClass::Protocol => "protocol",
+            RelayErrorClass::ClockSkew => "clock_skew",
+            RelayErrorClass::Other => "other",
+        }
+    }
+}
+
+impl fmt::Display for RelayErrorClass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+fn load(counter: &StatCounter) -> u64 {
+    counter.load(Ordering::Relaxed) as _
+}
+
+/// Accumulated statistic of connections relayed through a remote server
+pub struct RelayStat {
+    handshake_buckets: [StatCounter; HANDSHAKE_LATENCY_BUCKETS.len()],
+    handshake_count: StatCounter,
+    handshake_sum: StatCounter,
+    connect_failures: StatCounter,
+    relay_errors: [StatCounter; RelayErrorClass::ALL.len()],
+}
+
+impl Default for RelayStat {
+    fn default() -> Self {
+        RelayStat {
+            handshake_buckets: Default::default(),
+            handshake_count: StatCounter::new(0),
+            handshake_sum: StatCounter::new(0),
+            connect_failures: StatCounter::new(0),
+            relay_errors: Default::default(),
+        }
+    }
+}
+
+impl RelayStat {
+    /// Create an empty statistic
+    pub fn new() -> RelayStat {
+        RelayStat::default()
+    }
+
+    /// Record a successful handshake with the server, which took `latency`
+    pub fn record_handshake(&self, latency: Duration) {
+        let ms = latency.as_millis() as u64;
+        if let Some(i) = HANDSHAKE_LATENCY_BUCKETS.iter().position(|le| ms <= *le) {
+            self.handshake_buckets[i].fetch_add(1, Ordering::Relaxed);
+        }
+        self.handshake_count.fetch_add(1, Ordering::Relaxed);
+        self.handshake_sum.fetch_add(ms as _, Ordering::Relaxed);
+    }
+
+    /// Record a failure of connecting to the server
+    pub fn record_connect_failure(&self) {
+        self.connect_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record a relay closed by `err`
+    pub fn record_relay_error(&self, err: &io::Error) {
+        self.relay_errors[RelayErrorClass::of(err) as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Cumulative counts of handshakes, by upper bounds (in millisec) of `HANDSHAKE_LATENCY_BUCKETS`
+    ///
+    /// Handshakes slower than the last bound are only in `handshake_count`.
+    pub fn handshake_buckets(&self) -> Vec<(u64, u64)> {
+        let mut count = 0;
+        HANDSHAKE_LATENCY_BUCKETS
+            .iter()
+            .zip(self.handshake_buckets.iter())
+            .map(|(le, n)| {
+                count += load(n);
+                (*le, count)
+            })
+            .collect()
+    }
+
+    /// Count of successful handshakes
+    pub fn handshake_count(&self) -> u64 {
+        load(&self.handshake_count)
+    }
+
+    /// Total latency (in millisec) of successful handshakes
+    pub fn handshake_sum(&self) -> u64 {
+        load(&self.handshake_sum)
+    }
+
+    /// Count of failures of connecting
+    pub fn connect_failures(&self) -> u64 {
+        load(&self.connect_failures)
+    }
+
+    /// Counts of relays closed by errors, by classes
+    pub fn relay_errors(&self) -> Vec<(RelayErrorClass, u64)> {
+        RelayErrorClass::ALL
+            .iter()
+            .zip(self.relay_errors.iter())
+            .map(|(class, n)| (*class, load(n)))
+            .collect()
+    }
+}
+
+impl fmt::Debug for RelayStat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RelayStat")
+            .field("handshake_count", &self.handshake_count())
+            .field("connect_failures", &self.connect_failures())
+            .finish()
+    }
+}
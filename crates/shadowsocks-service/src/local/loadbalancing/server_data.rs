@@ -2,30 +2,53 @@
 
 use std::{
     fmt::{self, Debug},
-    sync::atomic::{AtomicU32, Ordering},
-    time::Duration,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
 };
 
-use shadowsocks::ServerConfig;
+use shadowsocks::{plugin::PluginStatus, ServerConfig};
+use spin::Mutex as SpinMutex;
 use tokio::sync::Mutex;
 
-use super::server_stat::{Score, ServerStat};
+use crate::config::BalancerScoringConfig;
+
+use super::{
+    relay_stat::RelayStat,
+    server_stat::{Score, ServerStat},
+    warm_pool::WarmPool,
+};
 
 /// Server's statistic score
 pub struct ServerScore {
     stat_data: Mutex<ServerStat>,
     score: AtomicU32,
+    alive: AtomicBool,
+    /// Since when the server has been alive continuously, `None` if it is dead
+    alive_since: SpinMutex<Option<Instant>>,
+    /// The latest check or request
+    last_score: SpinMutex<Option<(Instant, Score)>>,
 }
 
 impl ServerScore {
     /// Create a `ServerScore`
-    pub fn new(user_weight: f32, max_server_rtt: Duration, check_window: Duration) -> ServerScore {
+    pub fn new(
+        user_weight: f32,
+        max_server_rtt: Duration,
+        check_window: Duration,
+        scoring: BalancerScoringConfig,
+    ) -> ServerScore {
         let max_server_rtt = max_server_rtt.as_millis() as u32;
         assert!(max_server_rtt > 0);
 
         ServerScore {
-            stat_data: Mutex::new(ServerStat::new(user_weight, max_server_rtt, check_window)),
+            stat_data: Mutex::new(ServerStat::new(user_weight, max_server_rtt, check_window, scoring)),
             score: AtomicU32::new(u32::MAX),
+            alive: AtomicBool::new(true),
+            alive_since: SpinMutex::new(Some(Instant::now())),
+            last_score: SpinMutex::new(None),
         }
     }
 
@@ -41,9 +64,37 @@ impl ServerScore {
             stat.push_score(score)
         };
         self.score.store(updated_score, Ordering::Release);
+
+        let alive = !matches!(score, Score::Errored);
+        {
+            let mut alive_since = self.alive_since.lock();
+            if !alive {
+                *alive_since = None;
+            } else if alive_since.is_none() {
+                *alive_since = Some(Instant::now());
+            }
+        }
+        self.alive.store(alive, Ordering::Release);
+        *self.last_score.lock() = Some((Instant::now(), score));
+
         updated_score
     }
 
+    /// Check if the server is alive, which is false if the latest check or request failed
+    pub fn is_alive(&self) -> bool {
+        self.alive.load(Ordering::Acquire)
+    }
+
+    /// Duration of the server being alive continuously, `None` if it is dead
+    pub fn alive_duration(&self) -> Option<Duration> {
+        self.alive_since.lock().map(|since| since.elapsed())
+    }
+
+    /// The latest check or request, with how long ago it was, `None` if it hasn't been checked
+    pub fn last_score(&self) -> Option<(Duration, Score)> {
+        self.last_score.lock().map(|(time, score)| (time.elapsed(), score))
+    }
+
     /// Report request failure of this server, which will eventually records an `Errored` score
     pub async fn report_failure(&self) -> u32 {
         self.push_score(Score::Errored).await
@@ -52,7 +103,10 @@ impl ServerScore {
 
 impl Debug for ServerScore {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        f.debug_struct("ServerScore").field("score", &self.score()).finish()
+        f.debug_struct("ServerScore")
+            .field("score", &self.score())
+            .field("alive", &self.is_alive())
+            .finish()
     }
 }
 
@@ -62,15 +116,32 @@ pub struct ServerIdent {
     tcp_score: ServerScore,
     udp_score: ServerScore,
     svr_cfg: ServerConfig,
+    /// Handshakes and errors of connections relayed through the server
+    relay_stat: RelayStat,
+    /// Excluded by user, which won't be chosen but is still checked
+    excluded: AtomicBool,
+    /// Status of the supervised plugin
+    plugin_status: Option<Arc<PluginStatus>>,
+    /// Connections established ahead of requests, filled while the server is top-ranked
+    warm_pool: WarmPool,
 }
 
 impl ServerIdent {
     /// Create a `ServerIdent`
-    pub fn new(svr_cfg: ServerConfig, max_server_rtt: Duration, check_window: Duration) -> ServerIdent {
+    pub fn new(
+        svr_cfg: ServerConfig,
+        max_server_rtt: Duration,
+        check_window: Duration,
+        scoring: BalancerScoringConfig,
+    ) -> ServerIdent {
         ServerIdent {
-            tcp_score: ServerScore::new(svr_cfg.weight().tcp_weight(), max_server_rtt, check_window),
-            udp_score: ServerScore::new(svr_cfg.weight().udp_weight(), max_server_rtt, check_window),
+            tcp_score: ServerScore::new(svr_cfg.weight().tcp_weight(), max_server_rtt, check_window, scoring),
+            udp_score: ServerScore::new(svr_cfg.weight().udp_weight(), max_server_rtt, check_window, scoring),
             svr_cfg,
+            relay_stat: RelayStat::new(),
+            excluded: AtomicBool::new(false),
+            plugin_status: None,
+            warm_pool: WarmPool::new(),
         }
     }
 
@@ -89,4 +160,34 @@ impl ServerIdent {
     pub fn udp_score(&self) -> &ServerScore {
         &self.udp_score
     }
+
+    /// Statistic of connections relayed through the server
+    pub fn relay_stat(&self) -> &RelayStat {
+        &self.relay_stat
+    }
+
+    /// Check if the server is excluded from choosing
+    pub fn is_excluded(&self) -> bool {
+        self.excluded.load(Ordering::Relaxed)
+    }
+
+    /// Exclude the server from choosing, or include it again
+    pub fn set_excluded(&self, excluded: bool) {
+        self.excluded.store(excluded, Ordering::Relaxed);
+    }
+
+    /// Status of the server's plugin, `None` if it doesn't use one
+    pub fn plugin_status(&self) -> Option<&PluginStatus> {
+        self.plugin_status.as_deref()
+    }
+
+    /// Connections to the server established ahead of requests
+    pub fn warm_pool(&self) -> &WarmPool {
+        &self.warm_pool
+    }
+
+    /// Set status of the server's supervised plugin
+    pub fn set_plugin_status(&mut self, status: Arc<PluginStatus>) {
+        self.plugin_status = Some(status);
+    }
 }
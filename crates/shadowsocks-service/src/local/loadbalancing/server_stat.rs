@@ -5,6 +5,8 @@ use std::{
     time::{Duration, Instant},
 };
 
+use crate::config::{BalancerScoringAlgorithm, BalancerScoringConfig};
+
 /// Interval between each check
 pub const DEFAULT_CHECK_INTERVAL_SEC: u64 = 10;
 /// Timeout of each check
@@ -22,7 +24,7 @@ pub enum Score {
 /// Statistic of a remote server
 #[derive(Debug)]
 pub struct ServerStat {
-    /// Median of latency time (in millisec)
+    /// Estimated latency time (in millisec), median by default
     ///
     /// Use median instead of average time,
     /// because probing result may have some really bad cases
@@ -39,10 +41,14 @@ pub struct ServerStat {
     max_latency_stdev: f64,
     /// Score's average
     latency_mean: f64,
+    /// Mean difference between consecutive latencies
+    latency_jitter: f64,
     /// User's customized weight
     user_weight: f32,
     /// Checking window size
     check_window: Duration,
+    /// Algorithm and weights of scoring
+    scoring: BalancerScoringConfig,
 }
 
 fn max_latency_stdev(max_server_rtt: u32) -> f64 {
@@ -55,7 +61,12 @@ fn max_latency_stdev(max_server_rtt: u32) -> f64 {
 }
 
 impl ServerStat {
-    pub fn new(user_weight: f32, max_server_rtt: u32, check_window: Duration) -> ServerStat {
+    pub fn new(
+        user_weight: f32,
+        max_server_rtt: u32,
+        check_window: Duration,
+        scoring: BalancerScoringConfig,
+    ) -> ServerStat {
         assert!((0.0..=1.0).contains(&user_weight));
 
         ServerStat {
@@ -66,8 +77,10 @@ impl ServerStat {
             latency_stdev: 0.0,
             max_latency_stdev: max_latency_stdev(max_server_rtt),
             latency_mean: 0.0,
+            latency_jitter: 0.0,
             user_weight,
             check_window,
+            scoring,
         }
     }
 
@@ -75,25 +88,31 @@ impl ServerStat {
         // Normalize rtt
         let nrtt = self.rtt as f64 / self.max_server_rtt as f64;
 
-        // Normalize stdev
-        let nstdev = self.latency_stdev / self.max_latency_stdev;
+        // Normalize variation, jitter is at most the max rtt
+        let nvariation = match self.scoring.algorithm {
+            BalancerScoringAlgorithm::Jitter => (self.latency_jitter / self.max_server_rtt as f64).min(1.0),
+            _ => self.latency_stdev / self.max_latency_stdev,
+        };
 
-        const SCORE_RTT_WEIGHT: f64 = 1.0;
-        const SCORE_FAIL_WEIGHT: f64 = 3.0;
-        const SCORE_STDEV_WEIGHT: f64 = 1.0;
+        let BalancerScoringConfig {
+            rtt_weight,
+            failure_weight,
+            variation_weight,
+            ..
+        } = self.scoring;
 
         // [EPSILON, 1]
         // Just for avoiding divide by 0
         let user_weight = self.user_weight.max(f32::EPSILON);
 
-        // Score = (norm_lat * 1.0 + prop_err * 3.0 + stdev * 1.0) / 5.0 / user_weight
+        // Score = (norm_lat * 1.0 + prop_err * 3.0 + stdev * 1.0) / 5.0 / user_weight, with the default weights
         //
         // 1. The lower latency, the better
         // 2. The lower errored count, the better
-        // 3. The lower latency's stdev, the better
+        // 3. The lower latency's stdev (or jitter), the better
         // 4. The higher user's weight, the better
-        let score = (nrtt * SCORE_RTT_WEIGHT + self.fail_rate * SCORE_FAIL_WEIGHT + nstdev * SCORE_STDEV_WEIGHT)
-            / (SCORE_RTT_WEIGHT + SCORE_FAIL_WEIGHT + SCORE_STDEV_WEIGHT)
+        let score = (nrtt * rtt_weight + self.fail_rate * failure_weight + nvariation * variation_weight)
+            / (rtt_weight + failure_weight + variation_weight)
             / user_weight as f64;
 
         // Times 10000 converts to u32, for 0.0001 precision
@@ -135,15 +154,44 @@ impl ServerStat {
         self.fail_rate = cerr as f64 / self.latency_queue.len() as f64;
 
         if !vlat.is_empty() {
-            vlat.sort_unstable();
-
-            // Find median of latency
-            let mid = vlat.len() / 2;
+            // Latencies in the order of checks
+            if vlat.len() > 1 {
+                let total_diff: u64 = vlat
+                    .windows(2)
+                    .map(|w| (w[0] as i64 - w[1] as i64).unsigned_abs())
+                    .sum();
+                self.latency_jitter = total_diff as f64 / (vlat.len() - 1) as f64;
+            }
 
-            self.rtt = if vlat.len() % 2 == 0 {
-                (vlat[mid] + vlat[mid - 1]) / 2
+            let ewma = if self.scoring.algorithm == BalancerScoringAlgorithm::Ewma {
+                let alpha = self.scoring.ewma_alpha;
+                let ewma = vlat[1..]
+                    .iter()
+                    .fold(vlat[0] as f64, |ewma, lat| alpha * *lat as f64 + (1.0 - alpha) * ewma);
+                Some(ewma as u32)
             } else {
-                vlat[mid]
+                None
+            };
+
+            vlat.sort_unstable();
+
+            self.rtt = match self.scoring.algorithm {
+                BalancerScoringAlgorithm::Ewma => ewma.unwrap_or_default(),
+                BalancerScoringAlgorithm::Percentile => {
+                    // Nearest-rank percentile
+                    let rank = (self.scoring.percentile / 100.0 * vlat.len() as f64).ceil() as usize;
+                    vlat[rank.clamp(1, vlat.len()) - 1]
+                }
+                _ => {
+                    // Find median of latency
+                    let mid = vlat.len() / 2;
+
+                    if vlat.len() % 2 == 0 {
+                        (vlat[mid] + vlat[mid - 1]) / 2
+                    } else {
+                        vlat[mid]
+                    }
+                }
             };
 
             if vlat.len() > 1 {
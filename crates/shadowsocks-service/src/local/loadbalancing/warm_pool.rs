@@ -0,0 +1,121 @@
+//! Connections to a remote server established ahead of requests
+//!
+//! Connecting to a server takes at least a round trip, and more for TLS or other transports. Clients' requests take
+//! connections from the pool, which are refilled in the background, so that the first byte could be sent immediately.
+//! Shadowsocks' requests are sent with the first write, so a connection could be used for any target.
+
+use std::{
+    collections::VecDeque,
+    fmt,
+    pin::Pin,
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use futures::task::noop_waker_ref;
+use shadowsocks::net::TcpStream;
+use spin::Mutex as SpinMutex;
+use tokio::{
+    io::{AsyncRead, ReadBuf},
+    sync::Notify,
+};
+
+/// A connection waiting in the pool
+struct WarmStream {
+    stream: TcpStream,
+    /// Time taken by connecting, recorded as the handshake of the connection when it is taken
+    handshake: Duration,
+    expire_at: Instant,
+}
+
+/// Pool of connections to a server
+pub struct WarmPool {
+    streams: SpinMutex<VecDeque<WarmStream>>,
+    taken: Notify,
+}
+
+impl WarmPool {
+    /// Create an empty pool
+    pub fn new() -> WarmPool {
+        WarmPool {
+            streams: SpinMutex::new(VecDeque::new()),
+            taken: Notify::new(),
+        }
+    }
+
+    /// Take a connection, with the time taken by connecting it
+    ///
+    /// Connections expired or closed by the server are dropped.
+    pub fn take(&self) -> Option<(TcpStream, Duration)> {
+        let now = Instant::now();
+        loop {
+            // Not checked with the lock held, which polls the socket
+            let mut s = self.streams.lock().pop_front()?;
+            self.taken.notify_one();
+            if s.expire_at <= now || is_closed(&mut s.stream) {
+                continue;
+            }
+            return Some((s.stream, s.handshake));
+        }
+    }
+
+    /// Put a connection that could be used until `idle_timeout` elapsed
+    pub fn put(&self, stream: TcpStream, handshake: Duration, idle_timeout: Duration) {
+        self.streams.lock().push_back(WarmStream {
+            stream,
+            handshake,
+            expire_at: Instant::now() + idle_timeout,
+        });
+    }
+
+    /// Drop expired connections, returns the number of remaining ones
+    pub fn evict_expired(&self) -> usize {
+        let now = Instant::now();
+        let mut streams = self.streams.lock();
+        streams.retain(|s| s.expire_at > now);
+        streams.len()
+    }
+
+    /// Drop all connections
+    pub fn clear(&self) {
+        self.streams.lock().clear();
+    }
+
+    /// Number of connections in the pool
+    pub fn len(&self) -> usize {
+        self.streams.lock().len()
+    }
+
+    /// Check if the pool is empty
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Wait until a connection is taken since the last wait
+    pub async fn taken(&self) {
+        self.taken.notified().await
+    }
+}
+
+impl Default for WarmPool {
+    fn default() -> WarmPool {
+        WarmPool::new()
+    }
+}
+
+impl fmt::Debug for WarmPool {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WarmPool").field("len", &self.len()).finish()
+    }
+}
+
+/// Servers don't send anything before requests, so a readable connection has been closed or broken
+fn is_closed(stream: &mut TcpStream) -> bool {
+    let mut cx = Context::from_waker(noop_waker_ref());
+    let mut buf = [0u8; 1];
+    let mut buf = ReadBuf::new(&mut buf);
+    match Pin::new(stream).poll_read(&mut cx, &mut buf) {
+        Poll::Pending => false,
+        Poll::Ready(Ok(..)) | Poll::Ready(Err(..)) => true,
+    }
+}
@@ -0,0 +1,138 @@
+//! Prometheus metrics of local servers, served by `MetricsServer`
+
+use std::sync::Arc;
+
+use crate::{
+    metrics::{HistogramSample, MetricSample, MetricType, MetricsCollector, MetricsEncoder},
+    net::ConcurrencyKind,
+};
+
+use super::{
+    context::ServiceContext,
+    loadbalancing::{server_stat::Score, PingBalancer},
+};
+
+/// Collects traffic and connections of local servers, with states of remote servers in the balancer
+pub struct LocalMetrics {
+    context: Arc<ServiceContext>,
+    balancer: PingBalancer,
+}
+
+impl LocalMetrics {
+    /// Create with the context of local servers and their balancer
+    pub fn new(context: Arc<ServiceContext>, balancer: PingBalancer) -> LocalMetrics {
+        LocalMetrics { context, balancer }
+    }
+}
+
+impl MetricsCollector for LocalMetrics {
+    fn collect(&self, encoder: &mut MetricsEncoder) {
+        let flow_stat = self.context.flow_stat_ref();
+        encoder.family(
+            "shadowsocks_local_bytes_total",
+            "Bytes sent to (tx) and received from (rx) remote servers",
+            MetricType::Counter,
+            &[
+                (vec![("direction", "tx".to_owned())], flow_stat.tx()),
+                (vec![("direction", "rx".to_owned())], flow_stat.rx()),
+            ],
+        );
+
+        let concurrency_stat = self.context.concurrency_stat();
+        let kinds = [
+            (ConcurrencyKind::TcpTunnel, "tcp"),
+            (ConcurrencyKind::UdpAssociation, "udp"),
+        ];
+        let mut connections: Vec<MetricSample> = Vec::new();
+        let mut accepted: Vec<MetricSample> = Vec::new();
+        for (kind, name) in kinds {
+            connections.push((vec![("kind", name.to_owned())], concurrency_stat.current(kind) as u64));
+            accepted.push((vec![("kind", name.to_owned())], concurrency_stat.acquired(kind) as u64));
+        }
+        encoder.family(
+            "shadowsocks_local_connections",
+            "Active TCP connections and UDP associations",
+            MetricType::Gauge,
+            &connections,
+        );
+        encoder.family(
+            "shadowsocks_local_accepted_total",
+            "Accepted TCP connections and UDP associations",
+            MetricType::Counter,
+            &accepted,
+        );
+
+        let mut up: Vec<MetricSample> = Vec::new();
+        let mut latency: Vec<MetricSample> = Vec::new();
+        let mut handshake: Vec<HistogramSample> = Vec::new();
+        let mut connect_failures: Vec<MetricSample> = Vec::new();
+        let mut relay_errors: Vec<MetricSample> = Vec::new();
+        for server in self.balancer.servers() {
+            let addr = server.server_config().addr().to_string();
+
+            let relay_stat = server.relay_stat();
+            handshake.push(HistogramSample {
+                labels: vec![("server", addr.clone())],
+                buckets: relay_stat.handshake_buckets(),
+                sum: relay_stat.handshake_sum(),
+                count: relay_stat.handshake_count(),
+            });
+            connect_failures.push((vec![("server", addr.clone())], relay_stat.connect_failures()));
+            for (class, n) in relay_stat.relay_errors() {
+                relay_errors.push((vec![("server", addr.clone()), ("class", class.to_string())], n));
+            }
+
+            for (score, protocol) in [(server.tcp_score(), "tcp"), (server.udp_score(), "udp")] {
+                let labels = vec![("server", addr.clone()), ("protocol", protocol.to_owned())];
+                up.push((labels.clone(), score.is_alive() as u64));
+                // Servers that haven't been checked, or failed in the last check, don't have latencies
+                if let Some((_, Score::Latency(ms))) = score.last_score() {
+                    latency.push((labels, ms as u64));
+                }
+            }
+        }
+        encoder.family(
+            "shadowsocks_local_server_up",
+            "Whether the remote server is alive, by checks of the balancer",
+            MetricType::Gauge,
+            &up,
+        );
+        encoder.family(
+            "shadowsocks_local_server_latency_milliseconds",
+            "Latency of the last successful check of the remote server",
+            MetricType::Gauge,
+            &latency,
+        );
+        encoder.histogram(
+            "shadowsocks_local_server_handshake_milliseconds",
+            "Latency of connecting to the remote server for relaying",
+            &handshake,
+        );
+        encoder.family(
+            "shadowsocks_local_server_connect_failures_total",
+            "Failures of connecting to the remote server for relaying",
+            MetricType::Counter,
+            &connect_failures,
+        );
+        encoder.family(
+            "shadowsocks_local_server_relay_errors_total",
+            "TCP tunnels through the remote server closed with errors, by class",
+            MetricType::Counter,
+            &relay_errors,
+        );
+
+        #[cfg(feature = "local-dns")]
+        {
+            let dns_cache_stat = self.context.dns_cache_stat();
+            encoder.family(
+                "shadowsocks_local_dns_cache_lookups_total",
+                "Lookups of the DNS cache, by result",
+                MetricType::Counter,
+                &[
+                    (vec![("result", "hit".to_owned())], dns_cache_stat.hits()),
+                    (vec![("result", "miss".to_owned())], dns_cache_stat.misses()),
+                ],
+            );
+        }
+    }
+}
@@ -3,49 +3,106 @@
 #[cfg(feature = "local-flow-stat")]
 use std::path::PathBuf;
 use std::{
+    collections::HashMap,
     future::Future,
     io::{self, ErrorKind},
+    mem,
     pin::Pin,
     sync::Arc,
     task::{Context, Poll},
     time::Duration,
 };
 
+#[cfg(feature = "acl")]
+use arc_swap::ArcSwap;
 use futures::{future, ready};
 use log::trace;
 use shadowsocks::{
     config::Mode,
-    net::{AcceptOpts, ConnectOpts},
+    dns_resolver::DnsResolver,
+    net::{AcceptOpts, ConnectOpts, IpStrategy},
+    ServerAddr,
 };
 use tokio::task::JoinHandle;
 
 #[cfg(feature = "local-flow-stat")]
 use crate::net::FlowStat;
 use crate::{
-    config::{Config, ConfigType, ProtocolType},
-    dns::build_dns_resolver,
+    config::{Config, ConfigType, DnsConfig, ProtocolType},
+    dns::{build_dns_resolver, build_server_dns_resolver},
+    shutdown::ShutdownHandle,
+    store::{create_state_store, StateStore},
 };
 
+#[cfg(all(feature = "local-redir", any(target_os = "linux", target_os = "android")))]
+use self::redir::RedirFirewallGuard;
+#[cfg(any(
+    windows,
+    target_os = "macos",
+    target_os = "linux",
+    target_os = "freebsd",
+    target_os = "openbsd",
+    target_os = "netbsd"
+))]
+use self::system_proxy::{enable_system_proxy, recover_system_proxy, SystemProxyGuard};
 use self::{
+    connection_table::{report_cut_connections, ConnectionTable},
     context::ServiceContext,
+    control::ControlServer,
+    direct_fallback::DirectFallback,
+    flow_export::FlowExportServer,
     loadbalancing::{PingBalancer, PingBalancerBuilder},
+    net::bind::{bind_local_listeners, bind_local_udp_socket},
+    pause::PauseController,
+    priority::PriorityScheduler,
+    profile::ProfileSwitch,
+    resolver_cache::{CachedResolver, ResolverCache},
+    routing_mode::{RoutingMode, RoutingModeSwitch},
 };
 
+pub mod connection_table;
 pub mod context;
+pub mod control;
+pub mod direct_fallback;
 #[cfg(feature = "local-dns")]
 pub mod dns;
+#[cfg(feature = "local-fault-injection")]
+pub mod fault;
+pub mod flow_export;
 #[cfg(feature = "local-http")]
 pub mod http;
 pub mod loadbalancing;
+#[cfg(feature = "metrics")]
+pub mod metrics;
 pub mod net;
+#[cfg(feature = "local-online-config")]
+pub mod online_config;
+#[cfg(feature = "local-pac")]
+pub mod pac;
+pub mod pause;
+pub mod priority;
+pub mod profile;
 #[cfg(feature = "local-redir")]
 pub mod redir;
+pub mod resolver_cache;
+pub mod routing_mode;
 pub mod socks;
+#[cfg(any(
+    windows,
+    target_os = "macos",
+    target_os = "linux",
+    target_os = "freebsd",
+    target_os = "openbsd",
+    target_os = "netbsd"
+))]
+pub mod system_proxy;
 #[cfg(feature = "local-tun")]
 pub mod tun;
 #[cfg(feature = "local-tunnel")]
 pub mod tunnel;
 pub mod utils;
+#[cfg(feature = "local-web-ui")]
+pub mod web_ui;
 
 /// Default TCP Keep Alive timeout
 ///
@@ -61,6 +118,17 @@ impl Drop for ServerHandle {
     }
 }
 
+impl ServerHandle {
+    /// Spawn a server task, which is parked while local servers are paused
+    fn spawn<F>(pause_controller: &Arc<PauseController>, fut: F) -> ServerHandle
+    where
+        F: Future<Output = io::Result<()>> + Send + 'static,
+    {
+        let pause_controller = pause_controller.clone();
+        ServerHandle(tokio::spawn(async move { pause_controller.park(fut).await }))
+    }
+}
+
 impl Future for ServerHandle {
     type Output = io::Result<()>;
 
@@ -73,10 +141,54 @@ impl Future for ServerHandle {
     }
 }
 
+/// A local server that is listening on a fallback address, because the configured one was in use
+#[derive(Debug, Clone)]
+pub struct ListenerFallback {
+    /// Protocol of the local server
+    pub protocol: ProtocolType,
+    /// Configured listen address
+    pub configured_addr: ServerAddr,
+    /// Actual listen address
+    pub addr: ServerAddr,
+}
+
+/// Listen address of a local server
+#[derive(Debug, Clone)]
+pub struct ListenAddr {
+    /// Protocol of the local server
+    pub protocol: ProtocolType,
+    /// Listen address, with the port chosen by the system if it is 0 in the configuration
+    pub addr: ServerAddr,
+    /// Listen address of UDP relay, if it is different from `addr`
+    pub udp_addr: Option<ServerAddr>,
+}
+
 /// Local Server instance
 pub struct Server {
     vfut: Vec<ServerHandle>,
     balancer: PingBalancer,
+    context: Arc<ServiceContext>,
+    tenant_contexts: HashMap<String, Arc<ServiceContext>>,
+    #[cfg(feature = "acl")]
+    local_acl_contexts: Vec<Arc<ServiceContext>>,
+    listen_addrs: Vec<ListenAddr>,
+    listener_fallbacks: Vec<ListenerFallback>,
+    shutdown: ShutdownHandle,
+    pause_controller: Arc<PauseController>,
+    profile_switch: Option<Arc<ProfileSwitch>>,
+    // Previous system proxy settings are restored when servers are dropped
+    #[cfg(any(
+        windows,
+        target_os = "macos",
+        target_os = "linux",
+        target_os = "freebsd",
+        target_os = "openbsd",
+        target_os = "netbsd"
+    ))]
+    _system_proxy_guard: Option<SystemProxyGuard>,
+    // Rules redirecting forwarded traffic, removed after servers exit, or when they are dropped
+    #[cfg(all(feature = "local-redir", any(target_os = "linux", target_os = "android")))]
+    redir_firewall_guards: Vec<RedirFirewallGuard>,
 }
 
 impl Server {
@@ -86,42 +198,160 @@ impl Server {
         self.wait_until_exit().await
     }
 
-    /// Wait until any of the servers were exited
+    /// Wait until any of the servers were exited, or the instance is shut down by its `ShutdownHandle`
+    ///
+    /// Returns `Ok(())` if it is shut down, after listeners and background tasks are closed.
     pub async fn wait_until_exit(self) -> io::Result<()> {
-        let (res, ..) = future::select_all(self.vfut).await;
+        let res = tokio::select! {
+            (res, ..) = future::select_all(self.vfut) => res,
+            _ = self.shutdown.wait() => {
+                trace!("local servers are shut down");
+                Ok(())
+            }
+        };
+
+        #[cfg(all(feature = "local-redir", any(target_os = "linux", target_os = "android")))]
+        for guard in self.redir_firewall_guards {
+            guard.uninstall().await;
+        }
+
         res
     }
 
+    /// Get the handle for shutting down this instance
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
     /// Get the internal server balancer
     pub fn server_balancer(&self) -> &PingBalancer {
         &self.balancer
     }
-}
 
-/// Starts a shadowsocks local server
-pub async fn create(config: Config) -> io::Result<Server> {
-    assert!(config.config_type == ConfigType::Local && !config.local.is_empty());
-    assert!(!config.server.is_empty());
+    /// Get the context shared by locals that are not isolated
+    pub fn context(&self) -> &Arc<ServiceContext> {
+        &self.context
+    }
 
-    trace!("{:?}", config);
+    /// Get the isolated context of a named tenant
+    pub fn tenant_context(&self, tenant: &str) -> Option<&Arc<ServiceContext>> {
+        self.tenant_contexts.get(tenant)
+    }
 
-    // Warning for Stream Ciphers
-    #[cfg(feature = "stream-cipher")]
-    for server in config.server.iter() {
-        if server.method().is_stream() {
-            log::warn!("stream cipher {} for server {} have inherent weaknesses (see discussion in https://github.com/shadowsocks/shadowsocks-org/issues/36). \
-                    DO NOT USE. It will be removed in the future.", server.method(), server.addr());
+    /// Get contexts with ACLs, the shared one if ACL is configured, and those of locals with their own ACLs
+    #[cfg(feature = "acl")]
+    pub fn acl_contexts(&self) -> Vec<Arc<ServiceContext>> {
+        let mut contexts = Vec::with_capacity(1 + self.local_acl_contexts.len());
+        if self.context.acl().is_some() {
+            contexts.push(self.context.clone());
         }
+        contexts.extend(self.local_acl_contexts.iter().cloned());
+        contexts
     }
 
-    #[cfg(all(unix, not(target_os = "android")))]
-    if let Some(nofile) = config.nofile {
-        use crate::sys::set_nofile;
-        if let Err(err) = set_nofile(nofile) {
-            log::warn!("set_nofile {} failed, error: {}", nofile, err);
+    /// Reload ACLs from their files for all locals, established connections are kept
+    #[cfg(feature = "acl")]
+    pub fn reload_acl(&self) -> io::Result<()> {
+        let contexts = self.acl_contexts();
+        if contexts.is_empty() {
+            return self.context.reload_acl();
         }
+        for context in contexts {
+            context.reload_acl()?;
+        }
+        Ok(())
+    }
+
+    /// Get listen addresses of local servers, in the order of the configuration
+    ///
+    /// Locals without listen addresses, like those listening on unix sockets, are not included.
+    pub fn listen_addrs(&self) -> &[ListenAddr] {
+        &self.listen_addrs
+    }
+
+    /// Get local servers that are listening on fallback addresses
+    pub fn listener_fallbacks(&self) -> &[ListenerFallback] {
+        &self.listener_fallbacks
+    }
+
+    /// Pause all local servers, without closing listeners and established connections
+    pub fn pause(&self) {
+        self.pause_controller.pause();
+    }
+
+    /// Resume all local servers
+    pub fn resume(&self) {
+        self.pause_controller.resume();
     }
 
+    /// Check if local servers are paused
+    pub fn is_paused(&self) -> bool {
+        self.pause_controller.is_paused()
+    }
+
+    /// Get the controller of pausing, which could be kept for pausing and resuming after `wait_until_exit` is called
+    pub fn pause_controller(&self) -> Arc<PauseController> {
+        self.pause_controller.clone()
+    }
+
+    /// Get the current routing mode
+    pub fn routing_mode(&self) -> RoutingMode {
+        self.context.routing_mode().mode()
+    }
+
+    /// Switch all local servers to routing `mode`, for new connections
+    pub fn set_routing_mode(&self, mode: RoutingMode) {
+        self.context.routing_mode().set_mode(mode);
+    }
+
+    /// Get requests of switching profiles, `None` if there are no `profiles` in the configuration
+    ///
+    /// Switches are carried out by the caller, by recreating the server with the requested profile applied.
+    pub fn profile_switch(&self) -> Option<&Arc<ProfileSwitch>> {
+        self.profile_switch.as_ref()
+    }
+}
+
+/// Create the resolver of targets with nameservers of `dns`, and servers with `server_dns` of `config`
+///
+/// `None` for the system resolver
+async fn create_dns_resolver(
+    config: &Config,
+    dns: DnsConfig,
+    ipv6_first: bool,
+    connect_opts: &ConnectOpts,
+    resolver_cache: &Option<Arc<ResolverCache>>,
+) -> Option<DnsResolver> {
+    let mut resolver = build_dns_resolver(dns, ipv6_first, connect_opts).await;
+    if let Some(ref server_dns) = config.server_dns {
+        resolver =
+            build_server_dns_resolver(server_dns.clone(), &config.server, resolver, ipv6_first, connect_opts).await;
+    }
+    if let Some(ref resolver_cache) = *resolver_cache {
+        let inner = resolver.take().unwrap_or_else(DnsResolver::system_resolver);
+        resolver = Some(DnsResolver::custom_resolver(CachedResolver::new(
+            resolver_cache.clone(),
+            inner,
+        )));
+    }
+    resolver
+}
+
+/// Create a `ServiceContext` from `config`
+///
+/// Every calls will create a new DNS resolver, caches and statistic counters.
+#[allow(clippy::too_many_arguments)]
+async fn create_service_context(
+    config: &Config,
+    state_store: &Arc<dyn StateStore>,
+    connection_table: &Option<Arc<ConnectionTable>>,
+    priority_scheduler: &Option<Arc<PriorityScheduler>>,
+    pause_controller: &Arc<PauseController>,
+    profile_switch: &Option<Arc<ProfileSwitch>>,
+    resolver_cache: &Option<Arc<ResolverCache>>,
+    direct_fallback: &Option<Arc<DirectFallback>>,
+    routing_mode: &Arc<RoutingModeSwitch>,
+) -> ServiceContext {
     let mut context = ServiceContext::new();
 
     let mut connect_opts = ConnectOpts {
@@ -129,9 +359,11 @@ pub async fn create(config: Config) -> io::Result<Server> {
         fwmark: config.outbound_fwmark,
 
         #[cfg(target_os = "android")]
-        vpn_protect_path: config.outbound_vpn_protect_path,
+        vpn_protect_path: config.outbound_vpn_protect_path.clone(),
+        #[cfg(unix)]
+        socket_protector: config.outbound_socket_protector.clone(),
 
-        bind_interface: config.outbound_bind_interface,
+        bind_interface: config.outbound_bind_interface.clone(),
         bind_local_addr: config.outbound_bind_addr,
 
         ..Default::default()
@@ -154,25 +386,266 @@ pub async fn create(config: Config) -> io::Result<Server> {
     accept_opts.tcp.keepalive = config.keep_alive.or(Some(LOCAL_DEFAULT_KEEPALIVE_TIMEOUT));
     context.set_accept_opts(accept_opts);
 
-    if let Some(resolver) = build_dns_resolver(config.dns, config.ipv6_first, context.connect_opts_ref()).await {
+    let resolver = create_dns_resolver(
+        config,
+        config.dns.clone(),
+        config.ipv6_first,
+        context.connect_opts_ref(),
+        resolver_cache,
+    )
+    .await;
+    if let Some(resolver) = resolver {
         context.set_dns_resolver(Arc::new(resolver));
     }
 
-    if config.ipv6_first {
-        context.set_ipv6_first(config.ipv6_first);
+    context.set_ip_strategy(config.ip_strategy);
+
+    context.set_security_config(&config.security);
+    context.set_state_store(state_store.clone());
+    context.set_udp_migration(config.udp_migration);
+    context.set_route_debug(config.route_debug);
+    context.set_tcp_splice(config.tcp_splice);
+    #[cfg(feature = "acl")]
+    context.set_acl_trace(config.acl_trace);
+
+    if let Some(ref connection_table) = *connection_table {
+        context.set_connection_table(connection_table.clone());
     }
 
-    if let Some(acl) = config.acl {
-        context.set_acl(acl);
+    if let Some(ref priority_scheduler) = *priority_scheduler {
+        context.set_priority_scheduler(priority_scheduler.clone());
     }
 
-    context.set_security_config(&config.security);
+    context.set_pause_controller(pause_controller.clone());
 
-    assert!(!config.local.is_empty(), "no valid local server configuration");
+    if let Some(ref profile_switch) = *profile_switch {
+        context.set_profile_switch(profile_switch.clone());
+    }
+
+    if let Some(ref direct_fallback) = *direct_fallback {
+        context.set_direct_fallback(direct_fallback.clone());
+    }
+
+    context.set_routing_mode(routing_mode.clone());
+
+    #[cfg(feature = "local-fault-injection")]
+    if let Some(ref fault_injection) = config.fault_injection {
+        use self::fault::FaultInjector;
+
+        context.set_fault_injector(Arc::new(FaultInjector::new(fault_injection.clone())));
+    }
+
+    context
+}
+
+/// Builder of local server instances
+///
+/// Servers are spawned on the runtime that `build` is awaited on, which could be the caller's own runtime.
+pub struct LocalBuilder {
+    config: Config,
+    shutdown: ShutdownHandle,
+}
+
+impl LocalBuilder {
+    /// Create a builder of instances of `config`
+    pub fn new(config: Config) -> LocalBuilder {
+        LocalBuilder {
+            config,
+            shutdown: ShutdownHandle::new(),
+        }
+    }
+
+    /// Set the handle for shutting down the instance, a new one is created by default
+    ///
+    /// Instances sharing the same handle are shut down together.
+    pub fn shutdown_handle(&mut self, shutdown: ShutdownHandle) {
+        self.shutdown = shutdown;
+    }
+
+    /// Create the instance and start its servers
+    pub async fn build(self) -> io::Result<Server> {
+        create_with_shutdown(self.config, self.shutdown).await
+    }
+}
+
+/// Starts a shadowsocks local server
+pub async fn create(config: Config) -> io::Result<Server> {
+    LocalBuilder::new(config).build().await
+}
+
+async fn create_with_shutdown(mut config: Config, shutdown: ShutdownHandle) -> io::Result<Server> {
+    assert!(config.config_type == ConfigType::Local && !config.local.is_empty());
+    assert!(!config.server.is_empty());
+
+    trace!("{:?}", config);
+
+    // Warning for Stream Ciphers
+    #[cfg(feature = "stream-cipher")]
+    for server in config.server.iter() {
+        if server.method().is_stream() {
+            log::warn!("stream cipher {} for server {} have inherent weaknesses (see discussion in https://github.com/shadowsocks/shadowsocks-org/issues/36). \
+                    DO NOT USE. It will be removed in the future.", server.method(), server.addr());
+        }
+    }
+
+    #[cfg(feature = "local-fault-injection")]
+    if let Some(ref fault_injection) = config.fault_injection {
+        log::warn!(
+            "fault injection is enabled, relays will fail deliberately, {:?}",
+            fault_injection
+        );
+    }
+
+    #[cfg(all(unix, not(target_os = "android")))]
+    if let Some(nofile) = config.nofile {
+        use crate::sys::set_nofile;
+        if let Err(err) = set_nofile(nofile) {
+            log::warn!("set_nofile {} failed, error: {}", nofile, err);
+        }
+    }
 
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    if config.io_uring {
+        use shadowsocks::net::uring;
+        if let Err(err) = uring::init(uring::DEFAULT_ENTRIES) {
+            log::warn!("io_uring initialization failed, error: {}, fallback to epoll", err);
+        }
+    }
+
+    if let Err(err) = shadowsocks::relay::buffer_pool::init(config.buffer_pool) {
+        log::warn!("buffer pool initialization failed, error: {}", err);
+    }
+
+    if config.udp_offload {
+        if let Err(err) = shadowsocks::net::udp::init_offload() {
+            log::warn!(
+                "UDP offload initialization failed, error: {}, fallback to sending datagrams one by one",
+                err
+            );
+        }
+    }
+
+    // Persistent states are shared by all locals
+    let state_store = create_state_store(&config)?;
+
+    // Records of closed connections of all locals are sent to the same collector
+    let flow_export_server = config.flow_export.take().map(FlowExportServer::new);
+
+    // Connections of all locals are exported together on shutdown, listed by the control socket, and exported as
+    // flow records when they are closed
+    let connection_table = if config.export_connections || config.control_addr.is_some() || flow_export_server.is_some()
+    {
+        if config.export_connections {
+            if let Err(err) = report_cut_connections(state_store.as_ref()) {
+                log::warn!("failed to load connections of the last shutdown, error: {}", err);
+            }
+        }
+        let mut connection_table = ConnectionTable::new();
+        connection_table.set_export(config.export_connections);
+        if let Some(ref server) = flow_export_server {
+            connection_table.set_flow_exporter(server.exporter());
+        }
+        Some(Arc::new(connection_table))
+    } else {
+        None
+    };
+
+    // Bandwidth is shared by all locals
+    let priority_scheduler = config
+        .priority
+        .as_ref()
+        .map(|priority| Arc::new(PriorityScheduler::new(priority.clone())));
+
+    // Failures of resolving are shared by all locals
+    let resolver_cache = config
+        .resolver_cache
+        .as_ref()
+        .map(|cache| Arc::new(ResolverCache::new(cache.clone())));
+
+    // All locals are paused and resumed together
+    let pause_controller = Arc::new(PauseController::new());
+
+    // All locals connect directly together, while servers of the shared balancer are down
+    let direct_fallback = if config.direct_fallback {
+        Some(Arc::new(DirectFallback::new()))
+    } else {
+        None
+    };
+
+    // All locals are switched to another routing mode together
+    let routing_mode = Arc::new(RoutingModeSwitch::new(config.routing_mode));
+
+    // All locals are switched to another profile together
+    let profile_switch = if config.profiles.is_empty() {
+        None
+    } else {
+        Some(Arc::new(ProfileSwitch::new(
+            config.profiles.clone(),
+            config.profile.clone(),
+        )))
+    };
+
+    // ACL is shared by all contexts, and reloaded together
+    #[cfg(feature = "acl")]
+    let shared_acl = config.acl.take().map(|acl| Arc::new(ArcSwap::from_pointee(acl)));
+
+    // Fake IPs answered by the dns local are mapped back by locals sharing its context
+    #[cfg(feature = "local-dns")]
+    let fake_ip_pool = config.local.iter().find_map(|local_config| {
+        local_config.fake_ip.as_ref().map(|fake_ip| {
+            let pool = Arc::new(self::dns::FakeIpPool::new(fake_ip, state_store.clone()));
+            (pool, local_config.tenant.clone())
+        })
+    });
+
+    #[allow(unused_mut)]
+    let mut context = create_service_context(
+        &config,
+        &state_store,
+        &connection_table,
+        &priority_scheduler,
+        &pause_controller,
+        &profile_switch,
+        &resolver_cache,
+        &direct_fallback,
+        &routing_mode,
+    )
+    .await;
+    #[cfg(feature = "acl")]
+    if let Some(ref acl) = shared_acl {
+        context.set_shared_acl(acl.clone());
+    }
+    #[cfg(feature = "local-dns")]
+    if let Some((ref pool, None)) = fake_ip_pool {
+        if !config.isolate_locals {
+            context.set_fake_ip_pool(pool.clone());
+        }
+    }
     let context = Arc::new(context);
 
+    assert!(!config.local.is_empty(), "no valid local server configuration");
+
     let mut vfut = Vec::new();
+    #[cfg(all(feature = "local-redir", any(target_os = "linux", target_os = "android")))]
+    let mut redir_firewall_guards = Vec::new();
+
+    #[cfg(feature = "local-dns")]
+    if let Some((ref pool, ..)) = fake_ip_pool {
+        vfut.push(ServerHandle::spawn(&pause_controller, pool.clone().run_persistence()));
+    }
+
+    // Servers of the online configuration are required for building the balancer
+    #[cfg(feature = "local-online-config")]
+    let online_config_service = match config.online_config.take() {
+        Some(online_config) => {
+            use self::online_config::OnlineConfigService;
+
+            let mut service = OnlineConfigService::new(context.clone(), online_config, config.server.clone());
+            config.server = service.fetch_servers().await?;
+            Some(service)
+        }
+        None => None,
+    };
 
     // Create a service balancer for choosing between multiple servers
     let balancer = {
@@ -197,90 +670,448 @@ pub async fn create(config: Config) -> io::Result<Server> {
             balancer_builder.check_best_interval(intv);
         }
 
-        for server in config.server {
+        balancer_builder.strategy(config.balancer.strategy);
+
+        if let Some(window) = config.balancer.failback_window {
+            balancer_builder.failback_window(window);
+        }
+
+        balancer_builder.groups(config.balancer.groups.clone());
+        if let Some(ref group) = config.balancer.default_group {
+            balancer_builder.default_group(group.clone());
+        }
+
+        balancer_builder.probe(config.balancer.probe.clone());
+        balancer_builder.scoring(config.balancer.scoring);
+        if let Some(warm_pool) = config.balancer.warm_pool {
+            balancer_builder.warm_pool(warm_pool);
+        }
+
+        for server in mem::take(&mut config.server) {
             balancer_builder.add_server(server);
         }
 
         balancer_builder.build().await?
     };
 
+    #[cfg(feature = "local-online-config")]
+    if let Some(service) = online_config_service {
+        vfut.push(ServerHandle::spawn(&pause_controller, service.run(balancer.clone())));
+    }
+
+    // Logged only, the check doesn't stop servers
+    #[cfg(feature = "aead-cipher-2022")]
+    if let Some(ntp_server) = config.security.clock_skew.ntp_server.take() {
+        use shadowsocks::context::DEFAULT_TIMESTAMP_TOLERANCE;
+
+        let tolerance = config
+            .security
+            .clock_skew
+            .tolerance
+            .unwrap_or(DEFAULT_TIMESTAMP_TOLERANCE);
+        tokio::spawn(crate::net::ntp::check_clock(context.context(), ntp_server, tolerance));
+    }
+
     #[cfg(feature = "local-flow-stat")]
-    if let Some(stat_path) = config.stat_path {
+    if let Some(stat_path) = config.stat_path.take() {
         // For Android's flow statistic
 
         let report_fut = flow_report_task(stat_path, context.flow_stat());
-        vfut.push(ServerHandle(tokio::spawn(report_fut)));
+        vfut.push(ServerHandle::spawn(&pause_controller, report_fut));
+    }
+
+    #[cfg(feature = "metrics")]
+    if let Some(metrics_addr) = config.metrics_addr.take() {
+        use self::metrics::LocalMetrics;
+        use crate::metrics::MetricsServer;
+
+        let metrics = LocalMetrics::new(context.clone(), balancer.clone());
+        let server = MetricsServer::new(context.context(), context.accept_opts(), Arc::new(metrics));
+        vfut.push(ServerHandle::spawn(&pause_controller, async move {
+            server.run(&metrics_addr).await
+        }));
     }
 
-    for local_config in config.local {
-        let balancer = balancer.clone();
+    if let Some(server) = flow_export_server {
+        let context = context.context();
+        vfut.push(ServerHandle::spawn(&pause_controller, async move {
+            server.run(context).await
+        }));
+    }
+
+    if let (Some(control_addr), Some(connection_table)) = (config.control_addr.take(), connection_table.clone()) {
+        let mut server = ControlServer::new(
+            context.context(),
+            context.accept_opts(),
+            connection_table,
+            balancer.clone(),
+        );
+        if let Some(ref resolver_cache) = resolver_cache {
+            server.set_resolver_cache(resolver_cache.clone());
+        }
+        server.set_routing_mode(routing_mode.clone());
+        if let Some(ref control_key) = config.control_key {
+            server.set_key(control_key.as_bytes());
+        }
+        vfut.push(ServerHandle::spawn(&pause_controller, async move {
+            server.run(&control_addr).await
+        }));
+    }
+
+    // Proxies referred by PAC files
+    #[cfg(feature = "local-pac")]
+    let pac_proxies = self::pac::PacProxy::from_locals(&config.local);
+
+    // Proxies that the system proxy points to
+    #[cfg(any(
+        windows,
+        target_os = "macos",
+        target_os = "linux",
+        target_os = "freebsd",
+        target_os = "openbsd",
+        target_os = "netbsd"
+    ))]
+    let system_proxy_config = self::system_proxy::SystemProxyConfig::from_locals(&config.local);
+
+    // Contexts of isolated locals, keyed by tenant's name
+    let mut tenant_contexts = HashMap::new();
+    // Contexts of locals with their own ACLs, which are reloaded along with the shared one
+    #[cfg(feature = "acl")]
+    let mut local_acl_contexts = Vec::new();
+
+    let mut listener_fallbacks = Vec::new();
+    let mut listen_addrs = Vec::new();
+
+    for mut local_config in mem::take(&mut config.local) {
+        let has_outbound_bind =
+            local_config.outbound_bind_interface.is_some() || local_config.outbound_bind_addr.is_some();
+        let has_tuning = !local_config.tcp_tuning.is_empty() || !local_config.relay_buffer_sizes.is_default();
+        #[cfg(feature = "acl")]
+        let has_acl = local_config.acl.is_some();
+        #[cfg(not(feature = "acl"))]
+        let has_acl = false;
+        let has_dns = local_config.dns.is_some() || local_config.ipv6_first.is_some();
+
+        let context = match local_config.tenant {
+            // Sockets of the context are bound and tuned, and targets are routed and resolved with options of this
+            // local
+            _ if has_outbound_bind || has_tuning || has_acl || has_dns => {
+                #[allow(unused_mut)]
+                let mut bound_context = create_service_context(
+                    &config,
+                    &state_store,
+                    &connection_table,
+                    &priority_scheduler,
+                    &pause_controller,
+                    &profile_switch,
+                    &resolver_cache,
+                    &direct_fallback,
+                    &routing_mode,
+                )
+                .await;
+                let mut connect_opts = bound_context.connect_opts_ref().clone();
+                if let Some(ref iface) = local_config.outbound_bind_interface {
+                    connect_opts.bind_interface = Some(iface.clone());
+                }
+                if let Some(addr) = local_config.outbound_bind_addr {
+                    connect_opts.bind_local_addr = Some(addr);
+                }
+                local_config.tcp_tuning.apply(&mut connect_opts.tcp);
+                bound_context.set_connect_opts(connect_opts);
+                let mut accept_opts = bound_context.accept_opts();
+                local_config.tcp_tuning.apply(&mut accept_opts.tcp);
+                bound_context.set_accept_opts(accept_opts);
+                bound_context.set_relay_buffer_sizes(local_config.relay_buffer_sizes);
+                if has_dns {
+                    // Resolvers with different nameservers don't share the negative cache
+                    let resolver = create_dns_resolver(
+                        &config,
+                        local_config.dns.clone().unwrap_or_else(|| config.dns.clone()),
+                        local_config.ipv6_first.unwrap_or(config.ipv6_first),
+                        bound_context.connect_opts_ref(),
+                        if local_config.dns.is_some() {
+                            &None
+                        } else {
+                            &resolver_cache
+                        },
+                    )
+                    .await;
+                    bound_context.set_dns_resolver(Arc::new(resolver.unwrap_or_else(DnsResolver::system_resolver)));
+                    if let Some(ipv6_first) = local_config.ipv6_first {
+                        bound_context.set_ip_strategy(IpStrategy::from_ipv6_first(ipv6_first));
+                    }
+                }
+                #[cfg(feature = "acl")]
+                match local_config.acl.take() {
+                    Some(acl) => bound_context.set_acl(acl),
+                    None => {
+                        if let Some(ref acl) = shared_acl {
+                            bound_context.set_shared_acl(acl.clone());
+                        }
+                    }
+                }
+                let bound_context = Arc::new(bound_context);
+                #[cfg(feature = "acl")]
+                if has_acl {
+                    local_acl_contexts.push(bound_context.clone());
+                }
+                bound_context
+            }
+            Some(ref tenant) => match tenant_contexts.get(tenant) {
+                Some(context) => Arc::clone(context),
+                None => {
+                    #[allow(unused_mut)]
+                    let mut tenant_context = create_service_context(
+                        &config,
+                        &state_store,
+                        &connection_table,
+                        &priority_scheduler,
+                        &pause_controller,
+                        &profile_switch,
+                        &resolver_cache,
+                        &direct_fallback,
+                        &routing_mode,
+                    )
+                    .await;
+                    #[cfg(feature = "acl")]
+                    if let Some(ref acl) = shared_acl {
+                        tenant_context.set_shared_acl(acl.clone());
+                    }
+                    #[cfg(feature = "local-dns")]
+                    if let Some((ref pool, Some(ref pool_tenant))) = fake_ip_pool {
+                        if pool_tenant == tenant {
+                            tenant_context.set_fake_ip_pool(pool.clone());
+                        }
+                    }
+                    let tenant_context = Arc::new(tenant_context);
+                    tenant_contexts.insert(tenant.clone(), tenant_context.clone());
+                    tenant_context
+                }
+            },
+            None if config.isolate_locals => {
+                #[allow(unused_mut)]
+                let mut isolated_context = create_service_context(
+                    &config,
+                    &state_store,
+                    &connection_table,
+                    &priority_scheduler,
+                    &pause_controller,
+                    &profile_switch,
+                    &resolver_cache,
+                    &direct_fallback,
+                    &routing_mode,
+                )
+                .await;
+                #[cfg(feature = "acl")]
+                if let Some(ref acl) = shared_acl {
+                    isolated_context.set_shared_acl(acl.clone());
+                }
+                Arc::new(isolated_context)
+            }
+            None => context.clone(),
+        };
+
+        // Servers are chosen by routes of this local's ACL, and from its group of servers
+        let balancer = if has_acl || local_config.server_group.is_some() {
+            balancer.for_local(context.clone(), local_config.server_group.clone())
+        } else {
+            balancer.clone()
+        };
+
+        // Listeners bound before spawning are taken by the server, so that the instance is listening after created
+        let mut listeners = None;
+        if let Some(configured_addr) = local_config.addr.clone() {
+            if let Some(bound) = bind_local_listeners(&context, &local_config).await? {
+                if bound.addr != configured_addr {
+                    listener_fallbacks.push(ListenerFallback {
+                        protocol: local_config.protocol,
+                        configured_addr,
+                        addr: bound.addr.clone(),
+                    });
+                }
+                local_config.addr = Some(bound.local_addr()?);
+                listeners = Some(bound);
+            }
+        }
+
+        // UDP relay on a separate address is also bound before spawning, with the port chosen if it is 0
+        let udp_socket = bind_local_udp_socket(&context, &local_config).await?;
+        if let Some(ref socket) = udp_socket {
+            if let Some(ServerAddr::SocketAddr(..)) = local_config.udp_addr {
+                local_config.udp_addr = Some(ServerAddr::SocketAddr(socket.local_addr()?));
+            }
+        }
+        if let Some(ref addr) = local_config.addr {
+            listen_addrs.push(ListenAddr {
+                protocol: local_config.protocol,
+                addr: addr.clone(),
+                udp_addr: local_config.udp_addr.clone(),
+            });
+        }
+
+        // UDP associations of the local override the global settings
+        let udp_max_associations = local_config.udp_max_associations.or(config.udp_max_associations);
+        let udp_timeout = local_config.udp_timeout.or(config.udp_timeout);
+        let udp_nat = local_config
+            .udp_nat
+            .clone()
+            .or_else(|| config.udp_nat.clone())
+            .unwrap_or_default();
 
         match local_config.protocol {
             ProtocolType::Socks => {
                 use self::socks::Socks;
 
-                let client_addr = match local_config.addr {
-                    Some(a) => a,
-                    None => return Err(io::Error::new(ErrorKind::Other, "socks requires local address")),
-                };
-
                 let mut server = Socks::with_context(context.clone());
                 server.set_mode(local_config.mode);
                 server.set_socks5_auth(local_config.socks5_auth);
+                server.set_p2p_policy(local_config.p2p_policy.clone());
+                server.set_hostname_policy(local_config.socks_hostname);
+                if let Some(listeners) = listeners {
+                    if let Some(listener) = listeners.tcp {
+                        server.set_tcp_listener(listener);
+                    }
+                    if let Some(socket) = listeners.udp {
+                        server.set_udp_socket(socket);
+                    }
+                }
+                if let Some(socket) = udp_socket {
+                    server.set_udp_socket(socket);
+                }
 
-                if let Some(c) = config.udp_max_associations {
+                if let Some(c) = udp_max_associations {
                     server.set_udp_capacity(c);
                 }
-                if let Some(d) = config.udp_timeout {
+                if let Some(d) = udp_timeout {
                     server.set_udp_expiry_duration(d);
                 }
+                server.set_udp_nat(udp_nat);
                 if let Some(b) = local_config.udp_addr {
                     server.set_udp_bind_addr(b.clone());
                 }
+                #[cfg(feature = "local-socks-tls")]
+                if let Some(ref tls) = local_config.tls {
+                    use crate::net::tls::create_tls_acceptor;
 
-                vfut.push(ServerHandle(tokio::spawn(async move {
+                    server.set_tls_acceptor(create_tls_acceptor(tls, &[])?);
+                }
+
+                #[cfg(unix)]
+                if let Some(unix_addr) = local_config.unix_addr {
+                    let unix_mode = local_config.unix_mode;
+                    vfut.push(ServerHandle::spawn(&pause_controller, async move {
+                        server.run_unix(&unix_addr, unix_mode, balancer).await
+                    }));
+                    continue;
+                }
+
+                let client_addr = match local_config.addr {
+                    Some(a) => a,
+                    None => return Err(io::Error::new(ErrorKind::Other, "socks requires local address")),
+                };
+
+                vfut.push(ServerHandle::spawn(&pause_controller, async move {
                     server.run(&client_addr, balancer).await
-                })));
+                }));
             }
             #[cfg(feature = "local-tunnel")]
             ProtocolType::Tunnel => {
                 use self::tunnel::Tunnel;
 
+                let forward_addr = match local_config.forward_addr {
+                    Some(forward_addr) => forward_addr,
+                    None => {
+                        // Tunnel serving `tunnel_mappings` only
+                        let mut server = Tunnel::with_mappings(context.clone(), local_config.tunnel_mappings);
+                        server.set_p2p_policy(local_config.p2p_policy);
+                        if let Some(c) = udp_max_associations {
+                            server.set_udp_capacity(c);
+                        }
+                        if let Some(d) = udp_timeout {
+                            server.set_udp_expiry_duration(d);
+                        }
+                        server.set_udp_nat(udp_nat);
+
+                        vfut.push(ServerHandle::spawn(&pause_controller, async move {
+                            server.run_mappings(balancer).await
+                        }));
+                        continue;
+                    }
+                };
+
                 let client_addr = match local_config.addr {
                     Some(a) => a,
                     None => return Err(io::Error::new(ErrorKind::Other, "tunnel requires local address")),
                 };
 
-                let forward_addr = local_config.forward_addr.expect("tunnel requires forward address");
+                let mut server = Tunnel::with_context(context.clone(), forward_addr);
 
-                let mut server = Tunnel::with_context(context.clone(), forward_addr.clone());
-
-                if let Some(c) = config.udp_max_associations {
+                if let Some(c) = udp_max_associations {
                     server.set_udp_capacity(c);
                 }
-                if let Some(d) = config.udp_timeout {
+                if let Some(d) = udp_timeout {
                     server.set_udp_expiry_duration(d);
                 }
+                server.set_udp_nat(udp_nat);
                 server.set_mode(local_config.mode);
+                server.set_p2p_policy(local_config.p2p_policy);
+                for mapping in local_config.tunnel_mappings {
+                    server.add_mapping(mapping);
+                }
+                if let Some(listeners) = listeners {
+                    if let Some(listener) = listeners.tcp {
+                        server.set_tcp_listener(listener);
+                    }
+                    if let Some(socket) = listeners.udp {
+                        server.set_udp_socket(socket);
+                    }
+                }
+                if let Some(socket) = udp_socket {
+                    server.set_udp_socket(socket);
+                }
 
                 let udp_addr = local_config.udp_addr.unwrap_or_else(|| client_addr.clone());
-                vfut.push(ServerHandle(tokio::spawn(async move {
+                vfut.push(ServerHandle::spawn(&pause_controller, async move {
                     server.run(&client_addr, &udp_addr, balancer).await
-                })));
+                }));
             }
             #[cfg(feature = "local-http")]
             ProtocolType::Http => {
                 use self::http::Http;
 
+                let mut server = Http::with_context(context.clone());
+                server.set_auth(local_config.http_auth);
+                server.set_p2p_policy(local_config.p2p_policy);
+                if let Some(cache) = local_config.http_cache {
+                    server.set_response_cache(cache);
+                }
+                if let Some(listener) = listeners.and_then(|l| l.tcp) {
+                    server.set_listener(listener);
+                }
+                #[cfg(feature = "local-http-tls")]
+                if let Some(ref tls) = local_config.tls {
+                    use crate::net::tls::create_tls_acceptor;
+
+                    server.set_tls_acceptor(create_tls_acceptor(tls, &[b"h2", b"http/1.1"])?);
+                }
+
+                #[cfg(unix)]
+                if let Some(unix_addr) = local_config.unix_addr {
+                    let unix_mode = local_config.unix_mode;
+                    vfut.push(ServerHandle::spawn(&pause_controller, async move {
+                        server.run_unix(&unix_addr, unix_mode, balancer).await
+                    }));
+                    continue;
+                }
+
                 let client_addr = match local_config.addr {
                     Some(a) => a,
                     None => return Err(io::Error::new(ErrorKind::Other, "http requires local address")),
                 };
 
-                let server = Http::with_context(context.clone());
-                vfut.push(ServerHandle(tokio::spawn(async move {
+                vfut.push(ServerHandle::spawn(&pause_controller, async move {
                     server.run(&client_addr, balancer).await
-                })));
+                }));
             }
             #[cfg(feature = "local-redir")]
             ProtocolType::Redir => {
@@ -292,20 +1123,32 @@ pub async fn create(config: Config) -> io::Result<Server> {
                 };
 
                 let mut server = Redir::with_context(context.clone());
-                if let Some(c) = config.udp_max_associations {
+                if let Some(c) = udp_max_associations {
                     server.set_udp_capacity(c);
                 }
-                if let Some(d) = config.udp_timeout {
+                if let Some(d) = udp_timeout {
                     server.set_udp_expiry_duration(d);
                 }
+                server.set_udp_nat(udp_nat);
                 server.set_mode(local_config.mode);
                 server.set_tcp_redir(local_config.tcp_redir);
                 server.set_udp_redir(local_config.udp_redir);
+                server.set_listeners(local_config.redir_listeners);
+                server.set_preserve_ttl(local_config.preserve_ttl);
+                server.set_p2p_policy(local_config.p2p_policy);
+                #[cfg(all(feature = "local-redir-ebpf", target_os = "linux"))]
+                server.set_ebpf(local_config.redir_ebpf);
+                #[cfg(any(target_os = "linux", target_os = "android"))]
+                if let Some(firewall) = local_config.redir_firewall {
+                    server.set_firewall(firewall);
+                }
 
                 let udp_addr = local_config.udp_addr.unwrap_or_else(|| client_addr.clone());
-                vfut.push(ServerHandle(tokio::spawn(async move {
+                #[cfg(any(target_os = "linux", target_os = "android"))]
+                redir_firewall_guards.extend(server.install_firewall(&client_addr, &udp_addr, &balancer).await?);
+                vfut.push(ServerHandle::spawn(&pause_controller, async move {
                     server.run(&client_addr, &udp_addr, balancer).await
-                })));
+                }));
             }
             #[cfg(feature = "local-dns")]
             ProtocolType::Dns => {
@@ -316,17 +1159,84 @@ pub async fn create(config: Config) -> io::Result<Server> {
                     None => return Err(io::Error::new(ErrorKind::Other, "dns requires local address")),
                 };
 
-                let mut server = {
-                    let local_addr = local_config.local_dns_addr.expect("missing local_dns_addr");
-                    let remote_addr = local_config.remote_dns_addr.expect("missing remote_dns_addr");
+                let local_addr = local_config.local_dns_addr.expect("missing local_dns_addr");
 
-                    Dns::with_context(context.clone(), local_addr.clone(), remote_addr.clone())
-                };
+                #[allow(unused_mut)]
+                let mut remote_addr = local_config.remote_dns_addr;
+                #[cfg(feature = "local-dns-over-https")]
+                if let Some(ref remote_https) = local_config.remote_dns_https {
+                    remote_addr = Some(remote_https.server_addr());
+                }
+                #[cfg(any(feature = "local-dns-over-tls", feature = "local-dns-over-quic"))]
+                if let Some(ref remote_secure) = local_config.remote_dns_secure {
+                    remote_addr = Some(remote_secure.server_addr());
+                }
+
+                let mut server = Dns::with_context(
+                    context.clone(),
+                    local_addr,
+                    remote_addr.expect("missing remote_dns_addr"),
+                );
+                #[cfg(feature = "local-dns-over-https")]
+                if let Some(remote_https) = local_config.remote_dns_https {
+                    server.set_remote_dns_over_https(remote_https);
+                }
+                #[cfg(any(feature = "local-dns-over-tls", feature = "local-dns-over-quic"))]
+                if let Some(remote_secure) = local_config.remote_dns_secure {
+                    server.set_remote_dns_secure(remote_secure);
+                }
+                if !local_config.local_dns_extra_addrs.is_empty() {
+                    server.set_local_dns_extra_addrs(local_config.local_dns_extra_addrs);
+                }
+                server.set_local_dns_strategy(local_config.local_dns_strategy);
+                if let Some(t) = local_config.local_dns_timeout {
+                    server.set_local_dns_timeout(t);
+                }
+                if let Some(t) = local_config.remote_dns_timeout {
+                    server.set_remote_dns_timeout(t);
+                }
+                if let Some(cache) = local_config.dns_cache {
+                    server.set_cache(cache);
+                }
+                if !local_config.dns_upstreams.is_empty() {
+                    server.set_upstreams(local_config.dns_upstreams);
+                }
+                if let Some(dns_hosts) = local_config.dns_hosts {
+                    server.set_hosts(dns_hosts);
+                }
+                #[cfg(feature = "local-dns-dnssec")]
+                if let Some(dnssec) = local_config.dnssec {
+                    server.set_dnssec(dnssec);
+                }
+                #[cfg(feature = "local-dns-tls")]
+                if let Some(ref tls) = local_config.tls {
+                    use crate::net::tls::create_tls_acceptor;
+
+                    if let Some(addr) = local_config.dns_over_tls_addr {
+                        server.set_dns_over_tls(addr, create_tls_acceptor(tls, &[b"dot"])?);
+                    }
+                    if let Some(addr) = local_config.dns_over_https_addr {
+                        server.set_dns_over_https(addr, create_tls_acceptor(tls, &[b"h2", b"http/1.1"])?);
+                    }
+                }
+                if local_config.fake_ip.is_some() {
+                    if let Some((ref pool, ..)) = fake_ip_pool {
+                        server.set_fake_ip_pool(pool.clone());
+                    }
+                }
                 server.set_mode(local_config.mode);
+                if let Some(listeners) = listeners {
+                    if let Some(listener) = listeners.tcp {
+                        server.set_tcp_listener(listener);
+                    }
+                    if let Some(socket) = listeners.udp {
+                        server.set_udp_socket(socket);
+                    }
+                }
 
-                vfut.push(ServerHandle(tokio::spawn(async move {
+                vfut.push(ServerHandle::spawn(&pause_controller, async move {
                     server.run(&client_addr, balancer).await
-                })));
+                }));
             }
             #[cfg(feature = "local-tun")]
             ProtocolType::Tun => {
@@ -336,19 +1246,25 @@ pub async fn create(config: Config) -> io::Result<Server> {
                 use self::tun::TunBuilder;
 
                 let mut builder = TunBuilder::new(context.clone(), balancer);
-                if let Some(address) = local_config.tun_interface_address {
+                if local_config.tun_interface_address_auto {
+                    builder = builder.auto_address();
+                } else if let Some(address) = local_config.tun_interface_address {
                     builder = builder.address(address);
                 }
                 if let Some(name) = local_config.tun_interface_name {
                     builder = builder.name(&name);
                 }
-                if let Some(c) = config.udp_max_associations {
+                if let Some(c) = udp_max_associations {
                     builder = builder.udp_capacity(c);
                 }
-                if let Some(d) = config.udp_timeout {
+                if let Some(d) = udp_timeout {
                     builder = builder.udp_expiry_duration(d);
                 }
+                builder = builder.udp_nat(udp_nat);
                 builder = builder.mode(local_config.mode);
+                builder = builder.tcp_config(local_config.tun_tcp);
+                builder = builder.p2p_policy(local_config.p2p_policy);
+                builder = builder.preserve_ttl(local_config.preserve_ttl);
                 #[cfg(unix)]
                 if let Some(fd) = local_config.tun_device_fd {
                     builder = builder.file_descriptor(fd);
@@ -401,12 +1317,147 @@ pub async fn create(config: Config) -> io::Result<Server> {
                     }
                 }
                 let server = builder.build().await?;
+                // Tun device handles pausing by itself, for parking its TCP stack's thread
                 vfut.push(ServerHandle(tokio::spawn(async move { server.run().await })));
             }
+            #[cfg(feature = "local-http")]
+            ProtocolType::Mixed => {
+                use self::{http::Http, socks::Socks};
+
+                let client_addr = match local_config.addr {
+                    Some(a) => a,
+                    None => return Err(io::Error::new(ErrorKind::Other, "mixed requires local address")),
+                };
+
+                let mut http = Http::with_context(context.clone());
+                http.set_auth(local_config.http_auth);
+                http.set_p2p_policy(local_config.p2p_policy.clone());
+                if let Some(cache) = local_config.http_cache {
+                    http.set_response_cache(cache);
+                }
+
+                let mut server = Socks::with_context(context.clone());
+                server.set_mode(local_config.mode);
+                server.set_socks5_auth(local_config.socks5_auth);
+                server.set_p2p_policy(local_config.p2p_policy.clone());
+                server.set_hostname_policy(local_config.socks_hostname);
+                server.set_http(http);
+                if let Some(listeners) = listeners {
+                    if let Some(listener) = listeners.tcp {
+                        server.set_tcp_listener(listener);
+                    }
+                    if let Some(socket) = listeners.udp {
+                        server.set_udp_socket(socket);
+                    }
+                }
+                if let Some(socket) = udp_socket {
+                    server.set_udp_socket(socket);
+                }
+
+                if let Some(c) = udp_max_associations {
+                    server.set_udp_capacity(c);
+                }
+                if let Some(d) = udp_timeout {
+                    server.set_udp_expiry_duration(d);
+                }
+                server.set_udp_nat(udp_nat);
+                if let Some(b) = local_config.udp_addr {
+                    server.set_udp_bind_addr(b.clone());
+                }
+
+                vfut.push(ServerHandle::spawn(&pause_controller, async move {
+                    server.run(&client_addr, balancer).await
+                }));
+            }
+            #[cfg(feature = "local-pac")]
+            ProtocolType::Pac => {
+                use std::fs;
+
+                use self::pac::{Pac, PacGenerator};
+
+                let client_addr = match local_config.addr {
+                    Some(a) => a,
+                    None => return Err(io::Error::new(ErrorKind::Other, "pac requires local address")),
+                };
+
+                let mut generator = PacGenerator::new(context.acl().as_deref(), pac_proxies.clone());
+                if let Some(ref template_path) = local_config.pac_template_path {
+                    generator.set_template(fs::read_to_string(template_path)?);
+                }
+
+                let mut server = Pac::with_context(context.clone(), generator);
+                if let Some(listener) = listeners.and_then(|l| l.tcp) {
+                    server.set_listener(listener);
+                }
+                vfut.push(ServerHandle::spawn(&pause_controller, async move {
+                    server.run(&client_addr).await
+                }));
+            }
+            #[cfg(feature = "local-web-ui")]
+            ProtocolType::WebUi => {
+                use self::web_ui::WebUi;
+
+                let client_addr = match local_config.addr {
+                    Some(a) => a,
+                    None => return Err(io::Error::new(ErrorKind::Other, "web-ui requires local address")),
+                };
+
+                let mut server = WebUi::with_context(context.clone(), balancer);
+                if let Some(listener) = listeners.and_then(|l| l.tcp) {
+                    server.set_listener(listener);
+                }
+                vfut.push(ServerHandle::spawn(&pause_controller, async move {
+                    server.run(&client_addr).await
+                }));
+            }
         }
     }
 
-    Ok(Server { vfut, balancer })
+    // Listeners are spawned, settings left by a crashed process are restored even if it isn't enabled anymore
+    #[cfg(any(
+        windows,
+        target_os = "macos",
+        target_os = "linux",
+        target_os = "freebsd",
+        target_os = "openbsd",
+        target_os = "netbsd"
+    ))]
+    let system_proxy = if config.system_proxy {
+        Some(enable_system_proxy(&system_proxy_config, state_store.clone())?)
+    } else {
+        if let Err(err) = recover_system_proxy(state_store.as_ref()) {
+            log::warn!(
+                "failed to restore system proxy settings of the last run, error: {}",
+                err
+            );
+        }
+        None
+    };
+
+    Ok(Server {
+        vfut,
+        balancer,
+        context,
+        tenant_contexts,
+        #[cfg(feature = "acl")]
+        local_acl_contexts,
+        listen_addrs,
+        listener_fallbacks,
+        shutdown,
+        pause_controller,
+        profile_switch,
+        #[cfg(any(
+            windows,
+            target_os = "macos",
+            target_os = "linux",
+            target_os = "freebsd",
+            target_os = "openbsd",
+            target_os = "netbsd"
+        ))]
+        _system_proxy_guard: system_proxy,
+        #[cfg(all(feature = "local-redir", any(target_os = "linux", target_os = "android")))]
+        redir_firewall_guards,
+    })
 }
 
 #[cfg(feature = "local-flow-stat")]
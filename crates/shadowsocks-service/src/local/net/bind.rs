@@ -0,0 +1,270 @@
+//! Binding listeners of local servers
+//!
+//! Listen address in use will be retried with exponential backoff, and then fallback to the other ports. Listeners are
+//! bound while choosing the address (or the port, if it is 0), and are taken by local servers instead of binding the
+//! address again.
+
+use std::{
+    cmp,
+    io::{self, ErrorKind},
+    net::SocketAddr,
+    time::Duration,
+};
+
+use log::{debug, warn};
+use shadowsocks::{
+    lookup_then,
+    net::{AcceptOpts, TcpListener as ShadowTcpListener, UdpSocket as ShadowUdpSocket},
+    ServerAddr,
+};
+use tokio::time;
+
+use crate::{
+    config::{LocalConfig, ProtocolType},
+    local::context::ServiceContext,
+};
+
+/// Initial interval of retrying an address in use
+const BIND_RETRY_INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+/// Maximum interval of retrying an address in use
+const BIND_RETRY_MAX_BACKOFF: Duration = Duration::from_secs(8);
+/// Times of choosing another port for port 0 if the one chosen by TCP is in use by UDP
+const UNSPECIFIED_PORT_ATTEMPTS: usize = 8;
+
+/// Listeners of a local server bound to its chosen address
+pub struct LocalListeners {
+    /// Address bound, which is one of `bind_fallback_ports` if the configured one is in use
+    pub addr: ServerAddr,
+    /// TCP listener, if TCP is enabled
+    pub tcp: Option<ShadowTcpListener>,
+    /// UDP socket, if UDP is enabled and relayed on `addr`
+    pub udp: Option<ShadowUdpSocket>,
+}
+
+impl LocalListeners {
+    /// Actual address bound, with the port chosen by the system if the configured port is 0
+    pub fn local_addr(&self) -> io::Result<ServerAddr> {
+        match self.addr {
+            ServerAddr::SocketAddr(..) => {
+                let local_addr = match (&self.tcp, &self.udp) {
+                    (Some(tcp), _) => tcp.local_addr()?,
+                    (None, Some(udp)) => udp.local_addr()?,
+                    (None, None) => return Ok(self.addr.clone()),
+                };
+                Ok(ServerAddr::SocketAddr(local_addr))
+            }
+            ServerAddr::DomainName(..) => Ok(self.addr.clone()),
+        }
+    }
+}
+
+/// Check if the server of `local_config` serves on listeners of `addr` bound by `bind_local_listeners`
+///
+/// Others bind `addr` by themselves, like redir, which binds transparent sockets.
+fn takes_listeners(local_config: &LocalConfig) -> bool {
+    #[cfg(unix)]
+    if local_config.unix_addr.is_some() {
+        return false;
+    }
+
+    match local_config.protocol {
+        ProtocolType::Socks => true,
+        #[cfg(feature = "local-tunnel")]
+        ProtocolType::Tunnel => local_config.forward_addr.is_some(),
+        #[cfg(feature = "local-http")]
+        ProtocolType::Http | ProtocolType::Mixed => true,
+        #[cfg(feature = "local-dns")]
+        ProtocolType::Dns => true,
+        #[cfg(feature = "local-pac")]
+        ProtocolType::Pac => true,
+        #[cfg(feature = "local-web-ui")]
+        ProtocolType::WebUi => true,
+        #[allow(unreachable_patterns)]
+        _ => false,
+    }
+}
+
+/// Check if the server of `local_config` relays UDP on its listen address
+fn relays_udp(local_config: &LocalConfig) -> bool {
+    match local_config.protocol {
+        ProtocolType::Socks => true,
+        #[cfg(feature = "local-tunnel")]
+        ProtocolType::Tunnel => true,
+        #[cfg(feature = "local-http")]
+        ProtocolType::Mixed => true,
+        #[cfg(feature = "local-redir")]
+        ProtocolType::Redir => true,
+        #[allow(unreachable_patterns)]
+        _ => false,
+    }
+}
+
+/// Bind listeners of `local_config`
+///
+/// Listeners are bound before servers are spawned, so that they are listening when the local instance is created,
+/// and ports that are 0 are chosen by the system for the listeners taken by the servers.
+///
+/// `addr` will be retried `bind_retries` times if it is in use, then `bind_fallback_ports` will be tried in order.
+/// Returns `None` if the server binds `addr` by itself and neither of them are configured.
+pub async fn bind_local_listeners(
+    context: &ServiceContext,
+    local_config: &LocalConfig,
+) -> io::Result<Option<LocalListeners>> {
+    let addr = match local_config.addr {
+        Some(ref a) => a.clone(),
+        None => return Err(io::Error::new(ErrorKind::Other, "missing local address")),
+    };
+
+    if local_config.bind_retries == 0 && local_config.bind_fallback_ports.is_empty() && !takes_listeners(local_config) {
+        return Ok(None);
+    }
+
+    #[allow(unused_mut)]
+    let mut tcp = local_config.mode.enable_tcp();
+    // UDP relay binds to `addr` only if `udp_addr` is not specified
+    #[allow(unused_mut)]
+    let mut udp = local_config.mode.enable_udp() && local_config.udp_addr.is_none() && relays_udp(local_config);
+
+    // DNS server always listens on both TCP and UDP, `mode` is for the remote queries
+    #[cfg(feature = "local-dns")]
+    if local_config.protocol == crate::config::ProtocolType::Dns {
+        tcp = true;
+        udp = true;
+    }
+
+    let mut backoff = BIND_RETRY_INITIAL_BACKOFF;
+    let mut retries = 0;
+    loop {
+        match bind_addr(context, &addr, tcp, udp).await {
+            Ok(listeners) => return Ok(Some(listeners)),
+            Err(ref err) if err.kind() == ErrorKind::AddrInUse && retries < local_config.bind_retries => {
+                retries += 1;
+                debug!(
+                    "local {} address {} in use, retry {}/{} after {:?}",
+                    local_config.protocol.as_str(),
+                    addr,
+                    retries,
+                    local_config.bind_retries,
+                    backoff
+                );
+                time::sleep(backoff).await;
+                backoff = cmp::min(backoff * 2, BIND_RETRY_MAX_BACKOFF);
+            }
+            Err(err) if err.kind() == ErrorKind::AddrInUse && !local_config.bind_fallback_ports.is_empty() => {
+                debug!(
+                    "local {} address {} in use, trying fallback ports, error: {}",
+                    local_config.protocol.as_str(),
+                    addr,
+                    err
+                );
+                break;
+            }
+            Err(err) => return Err(err),
+        }
+    }
+
+    let mut last_err = None;
+    for port in local_config.bind_fallback_ports.iter() {
+        let fallback_addr = match addr {
+            ServerAddr::SocketAddr(ref sa) => ServerAddr::SocketAddr(SocketAddr::new(sa.ip(), *port)),
+            ServerAddr::DomainName(ref dn, ..) => ServerAddr::DomainName(dn.clone(), *port),
+        };
+
+        match bind_addr(context, &fallback_addr, tcp, udp).await {
+            Ok(listeners) => {
+                warn!(
+                    "local {} address {} in use, fallback to {}",
+                    local_config.protocol.as_str(),
+                    addr,
+                    fallback_addr
+                );
+                return Ok(Some(listeners));
+            }
+            Err(err) if err.kind() == ErrorKind::AddrInUse => {
+                debug!(
+                    "local {} fallback address {} in use",
+                    local_config.protocol.as_str(),
+                    fallback_addr
+                );
+                last_err = Some(err);
+            }
+            Err(err) => return Err(err),
+        }
+    }
+
+    Err(last_err.unwrap_or_else(|| io::Error::new(ErrorKind::AddrInUse, "address in use")))
+}
+
+/// Bind the UDP socket of `udp_addr` of `local_config`, for servers relaying UDP on a separate address
+///
+/// Returns `None` if `udp_addr` is not specified, or the server binds it by itself.
+pub async fn bind_local_udp_socket(
+    context: &ServiceContext,
+    local_config: &LocalConfig,
+) -> io::Result<Option<ShadowUdpSocket>> {
+    let udp_addr = match local_config.udp_addr {
+        Some(ref a) if local_config.mode.enable_udp() && takes_listeners(local_config) && relays_udp(local_config) => a,
+        _ => return Ok(None),
+    };
+
+    let listeners = bind_addr(context, udp_addr, false, true).await?;
+    Ok(listeners.udp)
+}
+
+/// Bind listeners on `addr`, both of them are bound to the same resolved address
+async fn bind_addr(context: &ServiceContext, addr: &ServerAddr, tcp: bool, udp: bool) -> io::Result<LocalListeners> {
+    let (tcp, udp) = match *addr {
+        ServerAddr::SocketAddr(ref sa) => bind_socket_addr(sa, tcp, udp, context.accept_opts()).await?,
+        ServerAddr::DomainName(ref dname, port) => {
+            lookup_then!(context.context_ref(), dname, port, |addr| {
+                bind_socket_addr(&addr, tcp, udp, context.accept_opts()).await
+            })?
+            .1
+        }
+    };
+
+    Ok(LocalListeners {
+        addr: addr.clone(),
+        tcp,
+        udp,
+    })
+}
+
+async fn bind_socket_addr(
+    addr: &SocketAddr,
+    tcp: bool,
+    udp: bool,
+    accept_opts: AcceptOpts,
+) -> io::Result<(Option<ShadowTcpListener>, Option<ShadowUdpSocket>)> {
+    let mut last_err = None;
+    for _ in 0..UNSPECIFIED_PORT_ATTEMPTS {
+        let tcp_listener = if tcp {
+            Some(ShadowTcpListener::bind_with_opts(addr, accept_opts.clone()).await?)
+        } else {
+            None
+        };
+
+        // UDP binds to the same port chosen by TCP if port is 0
+        let udp_socket = if udp {
+            let udp_addr = match tcp_listener {
+                Some(ref l) if addr.port() == 0 => l.local_addr()?,
+                _ => *addr,
+            };
+            match ShadowUdpSocket::listen_with_opts(&udp_addr, accept_opts.clone()).await {
+                Ok(socket) => Some(socket),
+                // The port chosen for TCP is in use by UDP, another one is chosen
+                Err(err) if err.kind() == ErrorKind::AddrInUse && tcp_listener.is_some() && addr.port() == 0 => {
+                    last_err = Some(err);
+                    continue;
+                }
+                Err(err) => return Err(err),
+            }
+        } else {
+            None
+        };
+
+        return Ok((tcp_listener, udp_socket));
+    }
+
+    Err(last_err.unwrap_or_else(|| io::Error::new(ErrorKind::AddrInUse, "address in use")))
+}
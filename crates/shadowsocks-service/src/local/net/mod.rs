@@ -2,8 +2,22 @@
 
 pub use self::{
     tcp::{auto_proxy_io::AutoProxyIo, auto_proxy_stream::AutoProxyClientStream},
-    udp::{UdpAssociationManager, UdpInboundWrite},
+    udp::{
+        ProxiedRecvBatch,
+        ProxiedUdpSocket,
+        ProxyUdpOverTcpSocket,
+        UdpAssociationManager,
+        UdpInboundWrite,
+        UdpNatConfig,
+        UdpNatFiltering,
+        UdpPortTimeout,
+    },
 };
 
+pub mod bind;
+#[cfg(feature = "acl")]
+pub mod process;
 mod tcp;
 mod udp;
+#[cfg(unix)]
+pub mod uds;
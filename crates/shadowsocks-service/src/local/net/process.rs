@@ -0,0 +1,406 @@
+//! Processes of local clients, for ACL's process rules
+//!
+//! Local TCP clients are identified by their sockets, whose local addresses are the peer addresses of connections
+//! accepted by local servers. Processes are found by scanning `/proc` on Linux, or `libproc` on macOS. Sockets of
+//! processes owned by the other users are visible only if the local server runs with sufficient privileges.
+
+use std::{
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+};
+
+/// Process of a local client
+#[derive(Debug, Clone)]
+pub struct ProcessInfo {
+    /// Process ID
+    pub pid: u32,
+    /// Name of the executable
+    pub name: String,
+    /// Path of the executable, empty if it is inaccessible
+    pub path: PathBuf,
+}
+
+/// Find the process owning the TCP socket bound to `addr`, which is the peer address of a connection from local
+pub fn find_tcp_process(addr: SocketAddr) -> Option<ProcessInfo> {
+    let addr = SocketAddr::new(canonical_ip(addr.ip()), addr.port());
+    sys::find_tcp_process(addr)
+}
+
+/// IPv4-mapped IPv6 addresses, from clients of dual-stack servers, are converted to IPv4
+fn canonical_ip(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => match v6.octets() {
+            [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, a, b, c, d] => IpAddr::from([a, b, c, d]),
+            _ => ip,
+        },
+        IpAddr::V4(..) => ip,
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+mod sys {
+    use std::{
+        fs,
+        net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+        path::Path,
+    };
+
+    use super::{canonical_ip, ProcessInfo};
+
+    pub fn find_tcp_process(addr: SocketAddr) -> Option<ProcessInfo> {
+        let inode = ["/proc/net/tcp", "/proc/net/tcp6"]
+            .iter()
+            .find_map(|path| find_socket_inode(path, addr))?;
+        find_inode_owner(inode)
+    }
+
+    /// Find inode of the socket bound to `addr` in `/proc/net/tcp` or `/proc/net/tcp6`
+    fn find_socket_inode(path: &str, addr: SocketAddr) -> Option<u64> {
+        let content = fs::read_to_string(path).ok()?;
+
+        // sl local_address rem_address st tx_queue:rx_queue tr:tm->when retrnsmt uid timeout inode ...
+        for line in content.lines().skip(1) {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() < 10 {
+                continue;
+            }
+
+            if parse_address(fields[1]) == Some(addr) {
+                match fields[9].parse::<u64>() {
+                    // Sockets in TIME_WAIT are orphaned
+                    Ok(inode) if inode != 0 => return Some(inode),
+                    _ => continue,
+                }
+            }
+        }
+
+        None
+    }
+
+    /// Parse addresses like `0100007F:1F90`, IPs are printed as 32-bit words in native byte order
+    fn parse_address(s: &str) -> Option<SocketAddr> {
+        let (ip, port) = s.split_once(':')?;
+        let port = u16::from_str_radix(port, 16).ok()?;
+
+        let mut octets = Vec::with_capacity(16);
+        for idx in (0..ip.len()).step_by(8) {
+            let word = u32::from_str_radix(ip.get(idx..idx + 8)?, 16).ok()?;
+            octets.extend_from_slice(&word.to_ne_bytes());
+        }
+
+        let ip = match octets.len() {
+            4 => Ipv4Addr::new(octets[0], octets[1], octets[2], octets[3]).into(),
+            16 => {
+                let mut v6 = [0u8; 16];
+                v6.copy_from_slice(&octets);
+                canonical_ip(Ipv6Addr::from(v6).into())
+            }
+            _ => return None,
+        };
+        Some(SocketAddr::new(ip, port))
+    }
+
+    /// Find the process holding file descriptor of socket `inode`
+    fn find_inode_owner(inode: u64) -> Option<ProcessInfo> {
+        let target = format!("socket:[{}]", inode);
+
+        for entry in fs::read_dir("/proc").ok()?.flatten() {
+            let pid = match entry.file_name().to_str().and_then(|s| s.parse::<u32>().ok()) {
+                Some(pid) => pid,
+                None => continue,
+            };
+
+            let fds = match fs::read_dir(entry.path().join("fd")) {
+                Ok(fds) => fds,
+                Err(..) => continue,
+            };
+
+            for fd in fds.flatten() {
+                match fs::read_link(fd.path()) {
+                    Ok(link) if link.as_os_str() == target.as_str() => return Some(process_info(pid, &entry.path())),
+                    _ => {}
+                }
+            }
+        }
+
+        None
+    }
+
+    fn process_info(pid: u32, proc_path: &Path) -> ProcessInfo {
+        let path = fs::read_link(proc_path.join("exe")).unwrap_or_default();
+        // comm is truncated to 15 bytes, the name of executable is preferred
+        let name = match path.file_name() {
+            Some(name) => name.to_string_lossy().into_owned(),
+            None => fs::read_to_string(proc_path.join("comm"))
+                .map(|comm| comm.trim_end().to_owned())
+                .unwrap_or_default(),
+        };
+
+        ProcessInfo { pid, name, path }
+    }
+}
+
+// Fields are defined for layouts of C structures
+#[cfg(target_os = "macos")]
+#[allow(dead_code)]
+mod sys {
+    use std::{
+        ffi::OsStr,
+        mem,
+        net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+        os::unix::ffi::OsStrExt,
+        path::PathBuf,
+        ptr,
+    };
+
+    use super::{canonical_ip, ProcessInfo};
+
+    // Definitions in <sys/proc_info.h>
+    const PROC_PIDLISTFDS: libc::c_int = 1;
+    const PROC_PIDFDSOCKETINFO: libc::c_int = 3;
+    const PROX_FDTYPE_SOCKET: u32 = 2;
+    const SOCKINFO_TCP: libc::c_int = 2;
+    const INI_IPV4: u8 = 0x1;
+    const PROC_PIDPATHINFO_MAXSIZE: usize = 4 * libc::PATH_MAX as usize;
+
+    #[repr(C)]
+    #[derive(Copy, Clone)]
+    struct ProcFdInfo {
+        proc_fd: i32,
+        proc_fdtype: u32,
+    }
+
+    #[repr(C)]
+    struct ProcFileInfo {
+        fi_openflags: u32,
+        fi_status: u32,
+        fi_offset: i64,
+        fi_type: i32,
+        fi_guardflags: u32,
+    }
+
+    #[repr(C)]
+    struct VInfoStat {
+        vst_dev: u32,
+        vst_mode: u16,
+        vst_nlink: u16,
+        vst_ino: u64,
+        vst_uid: u32,
+        vst_gid: u32,
+        vst_times: [i64; 8],
+        vst_size: i64,
+        vst_blocks: i64,
+        vst_blksize: i32,
+        vst_flags: u32,
+        vst_gen: u32,
+        vst_rdev: u32,
+        vst_qspare: [i64; 2],
+    }
+
+    #[repr(C)]
+    struct SockbufInfo {
+        sbi_cc: u32,
+        sbi_hiwat: u32,
+        sbi_mbcnt: u32,
+        sbi_mbmax: u32,
+        sbi_lowat: u32,
+        sbi_flags: i16,
+        sbi_timeo: i16,
+    }
+
+    #[repr(C)]
+    #[derive(Copy, Clone)]
+    struct In4In6Addr {
+        i46a_pad32: [u32; 3],
+        i46a_addr4: [u8; 4],
+    }
+
+    #[repr(C)]
+    #[derive(Copy, Clone)]
+    union InSockAddr {
+        ina_46: In4In6Addr,
+        ina_6: [u8; 16],
+    }
+
+    #[repr(C)]
+    #[derive(Copy, Clone)]
+    struct InSockInfo {
+        insi_fport: i32,
+        insi_lport: i32,
+        insi_gencnt: u64,
+        insi_flags: u32,
+        insi_flow: u32,
+        insi_vflag: u8,
+        insi_ip_ttl: u8,
+        rfu_1: u32,
+        insi_faddr: InSockAddr,
+        insi_laddr: InSockAddr,
+        insi_v4: InSockV4,
+        insi_v6: InSockV6,
+    }
+
+    #[repr(C)]
+    #[derive(Copy, Clone)]
+    struct InSockV4 {
+        in4_tos: u8,
+    }
+
+    #[repr(C)]
+    #[derive(Copy, Clone)]
+    struct InSockV6 {
+        in6_hlim: u8,
+        in6_cksum: i32,
+        in6_ifindex: u16,
+        in6_hops: i16,
+    }
+
+    /// `tcp_sockinfo` starts with `in_sockinfo`, the largest member `un_sockinfo` is 528 bytes
+    #[repr(C)]
+    union SocketInfoProto {
+        pri_in: InSockInfo,
+        _size: [u64; 66],
+    }
+
+    #[repr(C)]
+    struct SocketInfo {
+        soi_stat: VInfoStat,
+        soi_so: u64,
+        soi_pcb: u64,
+        soi_type: i32,
+        soi_protocol: i32,
+        soi_family: i32,
+        soi_options: i16,
+        soi_linger: i16,
+        soi_state: i16,
+        soi_qlen: i16,
+        soi_incqlen: i16,
+        soi_qlimit: i16,
+        soi_timeo: i16,
+        soi_error: u16,
+        soi_oobmark: u32,
+        soi_rcv: SockbufInfo,
+        soi_snd: SockbufInfo,
+        soi_kind: i32,
+        rfu_1: u32,
+        soi_proto: SocketInfoProto,
+    }
+
+    #[repr(C)]
+    struct SocketFdInfo {
+        pfi: ProcFileInfo,
+        psi: SocketInfo,
+    }
+
+    pub fn find_tcp_process(addr: SocketAddr) -> Option<ProcessInfo> {
+        list_pids()
+            .into_iter()
+            .find(|pid| list_socket_fds(*pid).into_iter().any(|fd| is_tcp_bound(*pid, fd, addr)))
+            .map(process_info)
+    }
+
+    fn list_pids() -> Vec<libc::c_int> {
+        unsafe {
+            let count = libc::proc_listallpids(ptr::null_mut(), 0);
+            if count <= 0 {
+                return Vec::new();
+            }
+
+            // Processes may be created between calls
+            let mut pids = vec![0 as libc::c_int; count as usize + 64];
+            let count = libc::proc_listallpids(
+                pids.as_mut_ptr() as *mut libc::c_void,
+                (pids.len() * mem::size_of::<libc::c_int>()) as libc::c_int,
+            );
+            pids.truncate(count.max(0) as usize);
+            pids
+        }
+    }
+
+    fn list_socket_fds(pid: libc::c_int) -> Vec<i32> {
+        unsafe {
+            let size = libc::proc_pidinfo(pid, PROC_PIDLISTFDS, 0, ptr::null_mut(), 0);
+            if size <= 0 {
+                return Vec::new();
+            }
+
+            let mut fds = vec![
+                ProcFdInfo {
+                    proc_fd: 0,
+                    proc_fdtype: 0
+                };
+                size as usize / mem::size_of::<ProcFdInfo>() + 16
+            ];
+            let size = libc::proc_pidinfo(
+                pid,
+                PROC_PIDLISTFDS,
+                0,
+                fds.as_mut_ptr() as *mut libc::c_void,
+                (fds.len() * mem::size_of::<ProcFdInfo>()) as libc::c_int,
+            );
+            fds.truncate(size.max(0) as usize / mem::size_of::<ProcFdInfo>());
+
+            fds.into_iter()
+                .filter(|fd| fd.proc_fdtype == PROX_FDTYPE_SOCKET)
+                .map(|fd| fd.proc_fd)
+                .collect()
+        }
+    }
+
+    fn is_tcp_bound(pid: libc::c_int, fd: i32, addr: SocketAddr) -> bool {
+        unsafe {
+            let mut info: SocketFdInfo = mem::zeroed();
+            let size = libc::proc_pidfdinfo(
+                pid,
+                fd,
+                PROC_PIDFDSOCKETINFO,
+                &mut info as *mut SocketFdInfo as *mut libc::c_void,
+                mem::size_of::<SocketFdInfo>() as libc::c_int,
+            );
+            if size <= 0 || info.psi.soi_kind != SOCKINFO_TCP {
+                return false;
+            }
+
+            let ini = info.psi.soi_proto.pri_in;
+            // Ports are in network byte order
+            if u16::from_be(ini.insi_lport as u16) != addr.port() {
+                return false;
+            }
+
+            let ip: IpAddr = if ini.insi_vflag & INI_IPV4 != 0 {
+                Ipv4Addr::from(ini.insi_laddr.ina_46.i46a_addr4).into()
+            } else {
+                canonical_ip(Ipv6Addr::from(ini.insi_laddr.ina_6).into())
+            };
+            ip == addr.ip()
+        }
+    }
+
+    fn process_info(pid: libc::c_int) -> ProcessInfo {
+        let mut buf = vec![0u8; PROC_PIDPATHINFO_MAXSIZE];
+        let len = unsafe { libc::proc_pidpath(pid, buf.as_mut_ptr() as *mut libc::c_void, buf.len() as u32) };
+        buf.truncate(len.max(0) as usize);
+
+        let path = PathBuf::from(OsStr::from_bytes(&buf));
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+
+        ProcessInfo {
+            pid: pid as u32,
+            name,
+            path,
+        }
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "macos")))]
+mod sys {
+    use std::net::SocketAddr;
+
+    use super::ProcessInfo;
+
+    pub fn find_tcp_process(_addr: SocketAddr) -> Option<ProcessInfo> {
+        None
+    }
+}
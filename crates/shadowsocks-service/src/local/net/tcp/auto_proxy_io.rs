@@ -9,4 +9,10 @@ pub trait AutoProxyIo {
     fn is_bypassed(&self) -> bool {
         !self.is_proxied()
     }
+
+    /// Socket of the bypassed connection, if data could be relayed on it directly
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    fn bypassed_socket(&self) -> Option<&tokio::net::TcpStream> {
+        None
+    }
 }
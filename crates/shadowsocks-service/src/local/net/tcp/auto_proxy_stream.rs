@@ -1,23 +1,29 @@
 //! A `ProxyStream` that bypasses or proxies data through proxy server automatically
 
+#[cfg(feature = "acl")]
+use std::fmt;
 use std::{
     io::{self, IoSlice},
     net::SocketAddr,
     pin::Pin,
     sync::Arc,
     task::{self, Poll},
+    time::Instant,
 };
 
 use pin_project::pin_project;
 use shadowsocks::{
-    net::TcpStream,
+    net::{ConnectOpts, TcpStream},
     relay::{
+        mux::{mux_address, BoxedMuxIo, ConnectionAddr, MuxStream},
         socks5::Address,
         tcprelay::proxy_stream::{ProxyClientStream, ProxyClientStreamReadHalf, ProxyClientStreamWriteHalf},
     },
 };
 use tokio::io::{AsyncRead, AsyncWrite, ReadBuf, ReadHalf, WriteHalf};
 
+#[cfg(feature = "acl")]
+use crate::acl::RejectMode;
 use crate::{
     local::{context::ServiceContext, loadbalancing::ServerIdent},
     net::MonProxyStream,
@@ -25,41 +31,133 @@ use crate::{
 
 use super::auto_proxy_io::AutoProxyIo;
 
+/// Error of connecting to targets rejected by ACL
+#[cfg(feature = "acl")]
+#[derive(Debug)]
+struct RejectedError {
+    message: String,
+    mode: RejectMode,
+}
+
+#[cfg(feature = "acl")]
+impl fmt::Display for RejectedError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+#[cfg(feature = "acl")]
+impl std::error::Error for RejectedError {}
+
 /// Unified stream for bypassed and proxied connections
 #[allow(clippy::large_enum_variant)]
 #[pin_project(project = AutoProxyClientStreamProj)]
 pub enum AutoProxyClientStream {
     Proxied(#[pin] ProxyClientStream<MonProxyStream<TcpStream>>),
     Bypassed(#[pin] TcpStream),
+    Multiplexed(#[pin] MuxStream<ConnectionAddr>),
 }
 
 impl AutoProxyClientStream {
-    /// Connect to target `addr` via shadowsocks' server configured by `svr_cfg`
+    /// Connect to target `addr` via shadowsocks' server configured by `svr_cfg`, for the local client `peer_addr`
     pub async fn connect<A>(
         context: Arc<ServiceContext>,
         server: &ServerIdent,
+        peer_addr: SocketAddr,
         addr: A,
     ) -> io::Result<AutoProxyClientStream>
     where
         A: Into<Address>,
     {
         let addr = addr.into();
-        if context.check_target_bypassed(&addr).await {
+        context.trace_acl(Some(peer_addr), &addr).await;
+
+        #[cfg(feature = "acl")]
+        if let Some(mode) = context.check_target_rejected(Some(peer_addr), &addr).await {
+            return Err(AutoProxyClientStream::rejected_error(&addr, mode));
+        }
+
+        if context.check_target_bypassed(Some(peer_addr), &addr).await {
             AutoProxyClientStream::connect_bypassed(context, addr).await
         } else {
             AutoProxyClientStream::connect_proxied(context, server, addr).await
         }
     }
 
+    /// Connect to target `addr` via shadowsocks' server configured by `svr_cfg`, for the local client `peer_addr`
+    ///
+    /// Bypassed connections are sent with IP TTL (or IPv6 hop limit) `ttl`, which transparent proxies copy from clients
+    pub async fn connect_with_ttl<A>(
+        context: Arc<ServiceContext>,
+        server: &ServerIdent,
+        peer_addr: SocketAddr,
+        addr: A,
+        ttl: Option<u8>,
+    ) -> io::Result<AutoProxyClientStream>
+    where
+        A: Into<Address>,
+    {
+        let addr = addr.into();
+        context.trace_acl(Some(peer_addr), &addr).await;
+
+        #[cfg(feature = "acl")]
+        if let Some(mode) = context.check_target_rejected(Some(peer_addr), &addr).await {
+            return Err(AutoProxyClientStream::rejected_error(&addr, mode));
+        }
+
+        if !context.check_target_bypassed(Some(peer_addr), &addr).await {
+            return AutoProxyClientStream::connect_proxied(context, server, addr).await;
+        }
+
+        match ttl {
+            None => AutoProxyClientStream::connect_bypassed(context, addr).await,
+            Some(ttl) => {
+                let mut connect_opts = context.connect_opts_ref().clone();
+                connect_opts.ip_ttl = Some(u32::from(ttl));
+                AutoProxyClientStream::connect_bypassed_with_opts(context, addr, &connect_opts).await
+            }
+        }
+    }
+
+    #[cfg(feature = "acl")]
+    fn rejected_error(addr: &Address, mode: RejectMode) -> io::Error {
+        io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            RejectedError {
+                message: format!("{} is blocked by ACL", addr),
+                mode,
+            },
+        )
+    }
+
+    /// Mode of answering the client, if `err` is returned by `connect` for targets rejected by ACL
+    #[cfg(feature = "acl")]
+    pub fn rejected_mode(err: &io::Error) -> Option<RejectMode> {
+        err.get_ref()?.downcast_ref::<RejectedError>().map(|err| err.mode)
+    }
+
     /// Connect directly to target `addr`
     pub async fn connect_bypassed<A>(context: Arc<ServiceContext>, addr: A) -> io::Result<AutoProxyClientStream>
+    where
+        A: Into<Address>,
+    {
+        let connect_opts = context.connect_opts_ref();
+        AutoProxyClientStream::connect_bypassed_with_opts(context.clone(), addr, connect_opts).await
+    }
+
+    /// Connect directly to target `addr` with `connect_opts`
+    pub async fn connect_bypassed_with_opts<A>(
+        context: Arc<ServiceContext>,
+        addr: A,
+        connect_opts: &ConnectOpts,
+    ) -> io::Result<AutoProxyClientStream>
     where
         A: Into<Address>,
     {
         // Connect directly.
         let addr = addr.into();
-        let stream =
-            TcpStream::connect_remote_with_opts(context.context_ref(), &addr, context.connect_opts_ref()).await?;
+        let connect_opts = context.target_connect_opts(&addr, connect_opts);
+        let stream = TcpStream::connect_remote_with_opts(context.context_ref(), &addr, &connect_opts).await?;
         Ok(AutoProxyClientStream::Bypassed(stream))
     }
 
@@ -72,29 +170,102 @@ impl AutoProxyClientStream {
     where
         A: Into<Address>,
     {
+        #[cfg(feature = "local-fault-injection")]
+        if let Some(fault_injector) = context.fault_injector() {
+            fault_injector.delay_connect().await;
+        }
+
+        let addr = context.proxied_target_addr(addr.into()).await?;
+
+        if server.server_config().mux().is_some() {
+            return AutoProxyClientStream::connect_multiplexed(context, server, addr).await;
+        }
+
         let flow_stat = context.flow_stat();
+
+        // Handshakes of pooled connections are timed when they were connected
+        if let Some((stream, handshake)) = server.warm_pool().take() {
+            let stream = ProxyClientStream::from_stream(
+                context.context(),
+                MonProxyStream::from_stream(stream, flow_stat),
+                server.server_config(),
+                addr,
+            );
+            server.relay_stat().record_handshake(handshake);
+            return Ok(AutoProxyClientStream::Proxied(stream));
+        }
+
+        let start = Instant::now();
         let stream = match ProxyClientStream::connect_with_opts_map(
             context.context(),
             server.server_config(),
             addr,
-            context.connect_opts_ref(),
+            &context.server_connect_opts(server.server_config()),
             |stream| MonProxyStream::from_stream(stream, flow_stat),
         )
         .await
         {
             Ok(s) => s,
             Err(err) => {
+                server.relay_stat().record_connect_failure();
                 server.tcp_score().report_failure().await;
                 return Err(err);
             }
         };
+        server.relay_stat().record_handshake(start.elapsed());
         Ok(AutoProxyClientStream::Proxied(stream))
     }
 
+    /// Open a stream to target `addr` in a connection to the server shared by streams
+    async fn connect_multiplexed<A>(
+        context: Arc<ServiceContext>,
+        server: &ServerIdent,
+        addr: A,
+    ) -> io::Result<AutoProxyClientStream>
+    where
+        A: Into<Address>,
+    {
+        let svr_cfg = server.server_config();
+        let mux = svr_cfg.mux().expect("server without mux");
+
+        // Only handshakes of new connections are recorded, streams opened in pooled connections don't have any
+        let connect = || async {
+            let flow_stat = context.flow_stat();
+            let start = Instant::now();
+            let stream = ProxyClientStream::connect_with_opts_map(
+                context.context(),
+                svr_cfg,
+                mux_address(),
+                &context.server_connect_opts(svr_cfg),
+                |stream| MonProxyStream::from_stream(stream, flow_stat),
+            )
+            .await?;
+            server.relay_stat().record_handshake(start.elapsed());
+
+            let socket = stream.get_ref().get_ref();
+            let info = ConnectionAddr {
+                local_addr: socket.local_addr()?,
+                peer_addr: socket.peer_addr()?,
+            };
+            Ok((Box::new(stream) as BoxedMuxIo, info))
+        };
+
+        let stream = match mux.pool().open(&addr.into(), connect).await {
+            Ok(s) => s,
+            Err(err) => {
+                server.relay_stat().record_connect_failure();
+                server.tcp_score().report_failure().await;
+                return Err(err);
+            }
+        };
+        Ok(AutoProxyClientStream::Multiplexed(stream))
+    }
+
     pub fn local_addr(&self) -> io::Result<SocketAddr> {
         match *self {
             AutoProxyClientStream::Proxied(ref s) => s.get_ref().get_ref().local_addr(),
             AutoProxyClientStream::Bypassed(ref s) => s.local_addr(),
+            AutoProxyClientStream::Multiplexed(ref s) => Ok(s.info().local_addr),
         }
     }
 
@@ -102,13 +273,26 @@ impl AutoProxyClientStream {
         match *self {
             AutoProxyClientStream::Proxied(ref s) => s.get_ref().get_ref().set_nodelay(nodelay),
             AutoProxyClientStream::Bypassed(ref s) => s.set_nodelay(nodelay),
+            // Streams share the connection, which is configured by `ConnectOpts`
+            AutoProxyClientStream::Multiplexed(..) => Ok(()),
         }
     }
 }
 
 impl AutoProxyIo for AutoProxyClientStream {
     fn is_proxied(&self) -> bool {
-        matches!(*self, AutoProxyClientStream::Proxied(..))
+        matches!(
+            *self,
+            AutoProxyClientStream::Proxied(..) | AutoProxyClientStream::Multiplexed(..)
+        )
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    fn bypassed_socket(&self) -> Option<&tokio::net::TcpStream> {
+        match *self {
+            AutoProxyClientStream::Bypassed(ref s) => s.as_plain_socket(),
+            _ => None,
+        }
     }
 }
 
@@ -117,6 +301,7 @@ impl AsyncRead for AutoProxyClientStream {
         match self.project() {
             AutoProxyClientStreamProj::Proxied(s) => s.poll_read(cx, buf),
             AutoProxyClientStreamProj::Bypassed(s) => s.poll_read(cx, buf),
+            AutoProxyClientStreamProj::Multiplexed(s) => s.poll_read(cx, buf),
         }
     }
 }
@@ -126,6 +311,7 @@ impl AsyncWrite for AutoProxyClientStream {
         match self.project() {
             AutoProxyClientStreamProj::Proxied(s) => s.poll_write(cx, buf),
             AutoProxyClientStreamProj::Bypassed(s) => s.poll_write(cx, buf),
+            AutoProxyClientStreamProj::Multiplexed(s) => s.poll_write(cx, buf),
         }
     }
 
@@ -133,6 +319,7 @@ impl AsyncWrite for AutoProxyClientStream {
         match self.project() {
             AutoProxyClientStreamProj::Proxied(s) => s.poll_flush(cx),
             AutoProxyClientStreamProj::Bypassed(s) => s.poll_flush(cx),
+            AutoProxyClientStreamProj::Multiplexed(s) => s.poll_flush(cx),
         }
     }
 
@@ -140,6 +327,7 @@ impl AsyncWrite for AutoProxyClientStream {
         match self.project() {
             AutoProxyClientStreamProj::Proxied(s) => s.poll_shutdown(cx),
             AutoProxyClientStreamProj::Bypassed(s) => s.poll_shutdown(cx),
+            AutoProxyClientStreamProj::Multiplexed(s) => s.poll_shutdown(cx),
         }
     }
 
@@ -151,6 +339,7 @@ impl AsyncWrite for AutoProxyClientStream {
         match self.project() {
             AutoProxyClientStreamProj::Proxied(s) => s.poll_write_vectored(cx, bufs),
             AutoProxyClientStreamProj::Bypassed(s) => s.poll_write_vectored(cx, bufs),
+            AutoProxyClientStreamProj::Multiplexed(s) => s.poll_write_vectored(cx, bufs),
         }
     }
 }
@@ -178,6 +367,13 @@ impl AutoProxyClientStream {
                     AutoProxyClientStreamWriteHalf::Bypassed(w),
                 )
             }
+            AutoProxyClientStream::Multiplexed(s) => {
+                let (r, w) = tokio::io::split(s);
+                (
+                    AutoProxyClientStreamReadHalf::Multiplexed(r),
+                    AutoProxyClientStreamWriteHalf::Multiplexed(w),
+                )
+            }
         }
     }
 }
@@ -187,11 +383,15 @@ impl AutoProxyClientStream {
 pub enum AutoProxyClientStreamReadHalf {
     Proxied(#[pin] ProxyClientStreamReadHalf<MonProxyStream<TcpStream>>),
     Bypassed(#[pin] ReadHalf<TcpStream>),
+    Multiplexed(#[pin] ReadHalf<MuxStream<ConnectionAddr>>),
 }
 
 impl AutoProxyIo for AutoProxyClientStreamReadHalf {
     fn is_proxied(&self) -> bool {
-        matches!(*self, AutoProxyClientStreamReadHalf::Proxied(..))
+        matches!(
+            *self,
+            AutoProxyClientStreamReadHalf::Proxied(..) | AutoProxyClientStreamReadHalf::Multiplexed(..)
+        )
     }
 }
 
@@ -200,6 +400,7 @@ impl AsyncRead for AutoProxyClientStreamReadHalf {
         match self.project() {
             AutoProxyClientStreamReadHalfProj::Proxied(s) => s.poll_read(cx, buf),
             AutoProxyClientStreamReadHalfProj::Bypassed(s) => s.poll_read(cx, buf),
+            AutoProxyClientStreamReadHalfProj::Multiplexed(s) => s.poll_read(cx, buf),
         }
     }
 }
@@ -209,11 +410,15 @@ impl AsyncRead for AutoProxyClientStreamReadHalf {
 pub enum AutoProxyClientStreamWriteHalf {
     Proxied(#[pin] ProxyClientStreamWriteHalf<MonProxyStream<TcpStream>>),
     Bypassed(#[pin] WriteHalf<TcpStream>),
+    Multiplexed(#[pin] WriteHalf<MuxStream<ConnectionAddr>>),
 }
 
 impl AutoProxyIo for AutoProxyClientStreamWriteHalf {
     fn is_proxied(&self) -> bool {
-        matches!(*self, AutoProxyClientStreamWriteHalf::Proxied(..))
+        matches!(
+            *self,
+            AutoProxyClientStreamWriteHalf::Proxied(..) | AutoProxyClientStreamWriteHalf::Multiplexed(..)
+        )
     }
 }
 
@@ -222,6 +427,7 @@ impl AsyncWrite for AutoProxyClientStreamWriteHalf {
         match self.project() {
             AutoProxyClientStreamWriteHalfProj::Proxied(s) => s.poll_write(cx, buf),
             AutoProxyClientStreamWriteHalfProj::Bypassed(s) => s.poll_write(cx, buf),
+            AutoProxyClientStreamWriteHalfProj::Multiplexed(s) => s.poll_write(cx, buf),
         }
     }
 
@@ -229,6 +435,7 @@ impl AsyncWrite for AutoProxyClientStreamWriteHalf {
         match self.project() {
             AutoProxyClientStreamWriteHalfProj::Proxied(s) => s.poll_flush(cx),
             AutoProxyClientStreamWriteHalfProj::Bypassed(s) => s.poll_flush(cx),
+            AutoProxyClientStreamWriteHalfProj::Multiplexed(s) => s.poll_flush(cx),
         }
     }
 
@@ -236,6 +443,7 @@ impl AsyncWrite for AutoProxyClientStreamWriteHalf {
         match self.project() {
             AutoProxyClientStreamWriteHalfProj::Proxied(s) => s.poll_shutdown(cx),
             AutoProxyClientStreamWriteHalfProj::Bypassed(s) => s.poll_shutdown(cx),
+            AutoProxyClientStreamWriteHalfProj::Multiplexed(s) => s.poll_shutdown(cx),
         }
     }
 
@@ -247,6 +455,7 @@ impl AsyncWrite for AutoProxyClientStreamWriteHalf {
         match self.project() {
             AutoProxyClientStreamWriteHalfProj::Proxied(s) => s.poll_write_vectored(cx, bufs),
             AutoProxyClientStreamWriteHalfProj::Bypassed(s) => s.poll_write_vectored(cx, bufs),
+            AutoProxyClientStreamWriteHalfProj::Multiplexed(s) => s.poll_write_vectored(cx, bufs),
         }
     }
 }
@@ -1,32 +1,49 @@
 //! UDP Association Managing
 
 use std::{
+    collections::{HashMap, HashSet},
     io::{self, ErrorKind},
     marker::PhantomData,
-    net::SocketAddr,
+    net::{IpAddr, SocketAddr},
     sync::Arc,
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 use async_trait::async_trait;
 use bytes::Bytes;
-use futures::future;
-use log::{debug, error, trace, warn};
+use futures::future::{self, Abortable, Aborted};
+use log::{debug, error, info, trace, warn};
 use lru_time_cache::LruCache;
 use tokio::{sync::mpsc, task::JoinHandle, time};
 
 use shadowsocks::{
     lookup_then,
-    net::UdpSocket as ShadowUdpSocket,
-    relay::{
-        udprelay::{ProxySocket, MAXIMUM_UDP_PAYLOAD_SIZE},
-        Address,
+    net::{
+        udp::{self as shadow_udp, RecvBatch, UDP_BATCH_SIZE},
+        UdpSocket as ShadowUdpSocket,
     },
+    relay::{buffer_pool::udp_buffer_pool, Address},
 };
 
 use crate::{
-    local::{context::ServiceContext, loadbalancing::PingBalancer},
-    net::{MonProxySocket, UDP_ASSOCIATION_KEEP_ALIVE_CHANNEL_SIZE, UDP_ASSOCIATION_SEND_CHANNEL_SIZE},
+    local::{
+        connection_table::ConnectionTableGuard,
+        context::ServiceContext,
+        loadbalancing::{PingBalancer, ServerIdent},
+    },
+    net::{
+        p2p::is_bittorrent_packet,
+        ConcurrencyGuard,
+        ConcurrencyKind,
+        P2pPolicy,
+        UDP_ASSOCIATION_KEEP_ALIVE_CHANNEL_SIZE,
+        UDP_ASSOCIATION_SEND_CHANNEL_SIZE,
+    },
+};
+
+use super::{
+    nat::{UdpNatConfig, UdpNatFiltering},
+    proxied_socket::{ProxiedRecvBatch, ProxiedUdpSocket},
 };
 
 /// Writer for sending packets back to client
@@ -37,6 +54,24 @@ use crate::{
 pub trait UdpInboundWrite {
     /// Sends packet `data` received from `remote_addr` back to `peer_addr`
     async fn send_to(&self, peer_addr: SocketAddr, remote_addr: &Address, data: &[u8]) -> io::Result<()>;
+
+    /// Sends a batch of packets received from remote addresses back to `peer_addr`
+    ///
+    /// Packets are sent by `send_to` one by one by default. Packets failed to be sent are skipped, and the first error
+    /// is returned after the others are sent.
+    async fn send_batch_to(&self, peer_addr: SocketAddr, packets: &[(Address, &[u8])]) -> io::Result<()> {
+        let mut first_err = None;
+        for (remote_addr, data) in packets {
+            if let Err(err) = self.send_to(peer_addr, remote_addr, data).await {
+                first_err.get_or_insert(err);
+            }
+        }
+
+        match first_err {
+            None => Ok(()),
+            Some(err) => Err(err),
+        }
+    }
 }
 
 type AssociationMap<W> = LruCache<SocketAddr, UdpAssociation<W>>;
@@ -51,6 +86,9 @@ where
     assoc_map: AssociationMap<W>,
     keepalive_tx: mpsc::Sender<SocketAddr>,
     balancer: PingBalancer,
+    p2p_policy: P2pPolicy,
+    time_to_live: Duration,
+    nat: UdpNatConfig,
 }
 
 impl<W> UdpAssociationManager<W>
@@ -60,18 +98,25 @@ where
     /// Create a new `UdpAssociationManager`
     ///
     /// Returns (`UdpAssociationManager`, Cleanup Interval, Keep-alive Receiver<SocketAddr>)
+    ///
+    /// `time_to_live` is the timeout of associations sending to ports that don't match any of `nat.timeouts`.
     pub fn new(
         context: Arc<ServiceContext>,
         respond_writer: W,
         time_to_live: Option<Duration>,
         capacity: Option<usize>,
+        nat: UdpNatConfig,
         balancer: PingBalancer,
     ) -> (UdpAssociationManager<W>, Duration, mpsc::Receiver<SocketAddr>) {
         let time_to_live = time_to_live.unwrap_or(crate::DEFAULT_UDP_EXPIRY_DURATION);
+
+        // Associations with shorter timeouts are removed by `cleanup_expired`
+        let max_time_to_live = nat.max_timeout(time_to_live);
         let assoc_map = match capacity {
-            Some(capacity) => LruCache::with_expiry_duration_and_capacity(time_to_live, capacity),
-            None => LruCache::with_expiry_duration(time_to_live),
+            Some(capacity) => LruCache::with_expiry_duration_and_capacity(max_time_to_live, capacity),
+            None => LruCache::with_expiry_duration(max_time_to_live),
         };
+        let cleanup_interval = nat.min_timeout(time_to_live);
 
         let (keepalive_tx, keepalive_rx) = mpsc::channel(UDP_ASSOCIATION_KEEP_ALIVE_CHANNEL_SIZE);
 
@@ -82,18 +127,39 @@ where
                 assoc_map,
                 keepalive_tx,
                 balancer,
+                p2p_policy: P2pPolicy::Allow,
+                time_to_live,
+                nat,
             },
-            time_to_live,
+            cleanup_interval,
             keepalive_rx,
         )
     }
 
+    /// Set policy of BitTorrent traffic sent through servers
+    pub fn set_p2p_policy(&mut self, p2p_policy: P2pPolicy) {
+        self.p2p_policy = p2p_policy;
+    }
+
     /// Sends `data` from `peer_addr` to `target_addr`
     pub async fn send_to(&mut self, peer_addr: SocketAddr, target_addr: Address, data: &[u8]) -> io::Result<()> {
+        #[cfg(feature = "local-fault-injection")]
+        if let Some(fault_injector) = self.context.fault_injector() {
+            if fault_injector.drop_udp_packet() {
+                return Ok(());
+            }
+        }
+
+        let time_to_live = self.nat.timeout_for(target_addr.port(), self.time_to_live);
+
         // Check or (re)create an association
 
-        if let Some(assoc) = self.assoc_map.get(&peer_addr) {
-            return assoc.try_send((target_addr, Bytes::copy_from_slice(data)));
+        if let Some(assoc) = self.assoc_map.get_mut(&peer_addr) {
+            // Associations terminated by the control socket are recreated
+            if !assoc.is_closed() {
+                assoc.touch(time_to_live);
+                return assoc.try_send((target_addr, Bytes::copy_from_slice(data)));
+            }
         }
 
         let assoc = UdpAssociation::new(
@@ -102,6 +168,9 @@ where
             self.keepalive_tx.clone(),
             self.balancer.clone(),
             self.respond_writer.clone(),
+            self.p2p_policy.clone(),
+            self.nat.filtering,
+            time_to_live,
         );
 
         debug!("created udp association for {}", peer_addr);
@@ -114,12 +183,31 @@ where
 
     /// Cleanup expired associations
     pub async fn cleanup_expired(&mut self) {
+        // iter() will remove associations expired with the longest timeout
         self.assoc_map.iter();
+
+        if self.nat.timeouts.is_empty() {
+            return;
+        }
+
+        let now = Instant::now();
+        let expired = self
+            .assoc_map
+            .peek_iter()
+            .filter(|(_, assoc)| assoc.is_expired(now))
+            .map(|(peer_addr, _)| *peer_addr)
+            .collect::<Vec<_>>();
+        for peer_addr in expired {
+            trace!("udp association for {} expired", peer_addr);
+            self.assoc_map.remove(&peer_addr);
+        }
     }
 
     /// Keep-alive association
     pub async fn keep_alive(&mut self, peer_addr: &SocketAddr) {
-        self.assoc_map.get(peer_addr);
+        if let Some(assoc) = self.assoc_map.get_mut(peer_addr) {
+            assoc.last_active = Instant::now();
+        }
     }
 }
 
@@ -130,6 +218,10 @@ where
     assoc_handle: JoinHandle<()>,
     sender: mpsc::Sender<(Address, Bytes)>,
     writer: PhantomData<W>,
+    _concurrency_guard: ConcurrencyGuard,
+    // The longest timeout of target ports that the client has sent to
+    time_to_live: Duration,
+    last_active: Instant,
 }
 
 impl<W> Drop for UdpAssociation<W>
@@ -145,22 +237,53 @@ impl<W> UdpAssociation<W>
 where
     W: UdpInboundWrite + Send + Sync + Unpin + 'static,
 {
+    #[allow(clippy::too_many_arguments)]
     fn new(
         context: Arc<ServiceContext>,
         peer_addr: SocketAddr,
         keepalive_tx: mpsc::Sender<SocketAddr>,
         balancer: PingBalancer,
         respond_writer: W,
+        p2p_policy: P2pPolicy,
+        filtering: UdpNatFiltering,
+        time_to_live: Duration,
     ) -> UdpAssociation<W> {
-        let (assoc_handle, sender) =
-            UdpAssociationContext::create(context, peer_addr, keepalive_tx, balancer, respond_writer);
+        let concurrency_guard = context.concurrency_stat().acquire(ConcurrencyKind::UdpAssociation, 1);
+        let connection_guard = context.register_connection(ConcurrencyKind::UdpAssociation, peer_addr, None);
+        let (assoc_handle, sender) = UdpAssociationContext::create(
+            context,
+            peer_addr,
+            keepalive_tx,
+            balancer,
+            respond_writer,
+            p2p_policy,
+            filtering,
+            connection_guard,
+        );
         UdpAssociation {
             assoc_handle,
             sender,
             writer: PhantomData,
+            _concurrency_guard: concurrency_guard,
+            time_to_live,
+            last_active: Instant::now(),
         }
     }
 
+    fn is_closed(&self) -> bool {
+        self.sender.is_closed()
+    }
+
+    /// The client sent to a target port with `time_to_live`
+    fn touch(&mut self, time_to_live: Duration) {
+        self.last_active = Instant::now();
+        self.time_to_live = self.time_to_live.max(time_to_live);
+    }
+
+    fn is_expired(&self, now: Instant) -> bool {
+        now.saturating_duration_since(self.last_active) >= self.time_to_live
+    }
+
     fn try_send(&self, data: (Address, Bytes)) -> io::Result<()> {
         if let Err(..) = self.sender.try_send(data) {
             let err = io::Error::new(ErrorKind::Other, "udp relay channel full");
@@ -178,11 +301,26 @@ where
     peer_addr: SocketAddr,
     bypassed_ipv4_socket: Option<ShadowUdpSocket>,
     bypassed_ipv6_socket: Option<ShadowUdpSocket>,
-    proxied_socket: Option<MonProxySocket>,
+    proxied_socket: Option<ProxiedUdpSocket>,
+    proxied_server: Option<Arc<ServerIdent>>,
+    proxied_route: Option<String>,
     keepalive_tx: mpsc::Sender<SocketAddr>,
     keepalive_flag: bool,
     balancer: PingBalancer,
     respond_writer: W,
+    p2p_policy: P2pPolicy,
+    // BitTorrent traffic was detected, all the following packets are treated as BitTorrent traffic
+    p2p_detected: bool,
+    // Fake IP targets by port. Responses are sent from the real addresses of their domain names,
+    // which are mapped back to the fake IPs by port.
+    fake_ip_targets: HashMap<u16, SocketAddr>,
+    filtering: UdpNatFiltering,
+    // Targets that the client has sent to, only recorded for restricted filtering
+    sent_addrs: HashSet<SocketAddr>,
+    sent_ips: HashSet<IpAddr>,
+    // Ports of domain name targets sent through servers, their addresses are resolved by servers
+    sent_name_ports: HashSet<u16>,
+    connection_guard: Option<ConnectionTableGuard>,
 }
 
 impl<W> Drop for UdpAssociationContext<W>
@@ -198,38 +336,64 @@ impl<W> UdpAssociationContext<W>
 where
     W: UdpInboundWrite + Send + Sync + Unpin + 'static,
 {
+    #[allow(clippy::too_many_arguments)]
     fn create(
         context: Arc<ServiceContext>,
         peer_addr: SocketAddr,
         keepalive_tx: mpsc::Sender<SocketAddr>,
         balancer: PingBalancer,
         respond_writer: W,
+        p2p_policy: P2pPolicy,
+        filtering: UdpNatFiltering,
+        mut connection_guard: Option<ConnectionTableGuard>,
     ) -> (JoinHandle<()>, mpsc::Sender<(Address, Bytes)>) {
         // Pending packets UDP_ASSOCIATION_SEND_CHANNEL_SIZE for each association should be good enough for a server.
         // If there are plenty of packets stuck in the channel, dropping excessive packets is a good way to protect the server from
         // being OOM.
         let (sender, receiver) = mpsc::channel(UDP_ASSOCIATION_SEND_CHANNEL_SIZE);
 
+        let abort_registration = connection_guard.as_mut().map(ConnectionTableGuard::abort_registration);
         let mut assoc = UdpAssociationContext {
             context,
             peer_addr,
             bypassed_ipv4_socket: None,
             bypassed_ipv6_socket: None,
             proxied_socket: None,
+            proxied_server: None,
+            proxied_route: None,
             keepalive_tx,
             keepalive_flag: false,
             balancer,
             respond_writer,
+            p2p_policy,
+            p2p_detected: false,
+            fake_ip_targets: HashMap::new(),
+            filtering,
+            sent_addrs: HashSet::new(),
+            sent_ips: HashSet::new(),
+            sent_name_ports: HashSet::new(),
+            connection_guard,
         };
-        let handle = tokio::spawn(async move { assoc.dispatch_packet(receiver).await });
+        let handle = tokio::spawn(async move {
+            let pause_controller = assoc.context.pause_controller().clone();
+            let dispatch = pause_controller.park(assoc.dispatch_packet(receiver));
+            match abort_registration {
+                Some(abort_registration) => {
+                    if let Err(Aborted) = Abortable::new(dispatch, abort_registration).await {
+                        debug!("udp association for {} terminated", peer_addr);
+                    }
+                }
+                None => dispatch.await,
+            }
+        });
 
         (handle, sender)
     }
 
     async fn dispatch_packet(&mut self, mut receiver: mpsc::Receiver<(Address, Bytes)>) {
-        let mut bypassed_ipv4_buffer = Vec::new();
-        let mut bypassed_ipv6_buffer = Vec::new();
-        let mut proxied_buffer = Vec::new();
+        let mut bypassed_ipv4_batch = None;
+        let mut bypassed_ipv6_batch = None;
+        let mut proxied_batch = ProxiedRecvBatch::new();
         let mut keepalive_interval = time::interval(Duration::from_secs(1));
 
         loop {
@@ -246,48 +410,59 @@ where
                     self.dispatch_received_packet(&target_addr, &data).await;
                 }
 
-                received_opt = receive_from_bypassed_opt(&self.bypassed_ipv4_socket, &mut bypassed_ipv4_buffer) => {
-                    let (n, addr) = match received_opt {
-                        Ok(r) => r,
-                        Err(err) => {
-                            error!("udp relay {} <- ... (bypassed) failed, error: {}", self.peer_addr, err);
-                            // Socket failure. Reset for recreation.
-                            self.bypassed_ipv4_socket = None;
-                            continue;
-                        }
-                    };
+                received_opt = receive_from_bypassed_opt(&self.bypassed_ipv4_socket, &mut bypassed_ipv4_batch) => {
+                    if let Err(err) = received_opt {
+                        error!("udp relay {} <- ... (bypassed) failed, error: {}", self.peer_addr, err);
+                        // Socket failure. Reset for recreation.
+                        self.bypassed_ipv4_socket = None;
+                        continue;
+                    }
 
-                    let addr = Address::from(addr);
-                    self.send_received_respond_packet(&addr, &bypassed_ipv4_buffer[..n], true).await;
+                    if let Some(ref batch) = bypassed_ipv4_batch {
+                        let packets = (0..batch.len())
+                            .map(|i| {
+                                let (data, addr) = batch.get(i);
+                                (Address::from(addr), data)
+                            })
+                            .collect::<Vec<_>>();
+                        self.send_received_respond_batch(packets, true).await;
+                    }
                 }
 
-                received_opt = receive_from_bypassed_opt(&self.bypassed_ipv6_socket, &mut bypassed_ipv6_buffer) => {
-                    let (n, addr) = match received_opt {
-                        Ok(r) => r,
-                        Err(err) => {
-                            error!("udp relay {} <- ... (bypassed) failed, error: {}", self.peer_addr, err);
-                            // Socket failure. Reset for recreation.
-                            self.bypassed_ipv6_socket = None;
-                            continue;
-                        }
-                    };
+                received_opt = receive_from_bypassed_opt(&self.bypassed_ipv6_socket, &mut bypassed_ipv6_batch) => {
+                    if let Err(err) = received_opt {
+                        error!("udp relay {} <- ... (bypassed) failed, error: {}", self.peer_addr, err);
+                        // Socket failure. Reset for recreation.
+                        self.bypassed_ipv6_socket = None;
+                        continue;
+                    }
 
-                    let addr = Address::from(addr);
-                    self.send_received_respond_packet(&addr, &bypassed_ipv6_buffer[..n], true).await;
+                    if let Some(ref batch) = bypassed_ipv6_batch {
+                        let packets = (0..batch.len())
+                            .map(|i| {
+                                let (data, addr) = batch.get(i);
+                                (Address::from(addr), data)
+                            })
+                            .collect::<Vec<_>>();
+                        self.send_received_respond_batch(packets, true).await;
+                    }
                 }
 
-                received_opt = receive_from_proxied_opt(&self.proxied_socket, &mut proxied_buffer) => {
-                    let (n, addr) = match received_opt {
-                        Ok(r) => r,
-                        Err(err) => {
-                            error!("udp relay {} <- ... (proxied) failed, error: {}", self.peer_addr, err);
-                            // Socket failure. Reset for recreation.
-                            self.proxied_socket = None;
-                            continue;
-                        }
-                    };
+                received_opt = receive_from_proxied_opt(&mut self.proxied_socket, &mut proxied_batch) => {
+                    if let Err(err) = received_opt {
+                        error!("udp relay {} <- ... (proxied) failed, error: {}", self.peer_addr, err);
+                        // Socket failure. Reset for recreation.
+                        self.proxied_socket = None;
+                        continue;
+                    }
 
-                    self.send_received_respond_packet(&addr, &proxied_buffer[..n], false).await;
+                    let packets = (0..proxied_batch.len())
+                        .map(|i| {
+                            let (data, addr) = proxied_batch.get(i);
+                            (addr.clone(), data)
+                        })
+                        .collect::<Vec<_>>();
+                    self.send_received_respond_batch(packets, false).await;
                 }
 
                 _ = keepalive_interval.tick() => {
@@ -305,39 +480,48 @@ where
         #[inline]
         async fn receive_from_bypassed_opt(
             socket: &Option<ShadowUdpSocket>,
-            buf: &mut Vec<u8>,
-        ) -> io::Result<(usize, SocketAddr)> {
+            batch: &mut Option<RecvBatch>,
+        ) -> io::Result<usize> {
             match *socket {
                 None => future::pending().await,
                 Some(ref s) => {
-                    if buf.is_empty() {
-                        buf.resize(MAXIMUM_UDP_PAYLOAD_SIZE, 0);
-                    }
-                    s.recv_from(buf).await
+                    let batch = batch.get_or_insert_with(|| RecvBatch::with_pool(UDP_BATCH_SIZE, udp_buffer_pool()));
+                    shadow_udp::recv_batch(s, batch).await
                 }
             }
         }
 
         #[inline]
         async fn receive_from_proxied_opt(
-            socket: &Option<MonProxySocket>,
-            buf: &mut Vec<u8>,
-        ) -> io::Result<(usize, Address)> {
+            socket: &mut Option<ProxiedUdpSocket>,
+            batch: &mut ProxiedRecvBatch,
+        ) -> io::Result<usize> {
             match *socket {
                 None => future::pending().await,
-                Some(ref s) => {
-                    if buf.is_empty() {
-                        buf.resize(MAXIMUM_UDP_PAYLOAD_SIZE, 0);
-                    }
-                    s.recv(buf).await
-                }
+                Some(ref mut s) => s.recv_batch(batch).await,
             }
         }
     }
 
     async fn dispatch_received_packet(&mut self, target_addr: &Address, data: &[u8]) {
+        let mapped_addr = self.context.map_fake_ip(target_addr.clone());
+        if let (Address::SocketAddress(fake_addr), Address::DomainNameAddress(..)) = (target_addr, &mapped_addr) {
+            self.fake_ip_targets.insert(fake_addr.port(), *fake_addr);
+        }
+        let target_addr = &mapped_addr;
+
+        if self.context.check_target_blocked(None, target_addr).await {
+            trace!(
+                "udp relay {} -> {} blocked by ACL, dropped {} bytes",
+                self.peer_addr,
+                target_addr,
+                data.len()
+            );
+            return;
+        }
+
         // Check if target should be bypassed. If so, send packets directly.
-        let bypassed = self.context.check_target_bypassed(target_addr).await;
+        let bypassed = self.context.check_target_bypassed(None, target_addr).await;
 
         trace!(
             "udp relay {} -> {} ({}) with {} bytes",
@@ -347,6 +531,17 @@ where
             data.len()
         );
 
+        if let Some(ref connection_guard) = self.connection_guard {
+            connection_guard.flow_stat().incr_rx(data.len() as u64);
+        }
+
+        match *target_addr {
+            Address::SocketAddress(sa) => self.record_sent_addr(sa),
+            // Bypassed names are recorded by their resolved addresses
+            Address::DomainNameAddress(_, port) if !bypassed => self.record_sent_name_port(port),
+            Address::DomainNameAddress(..) => {}
+        }
+
         if bypassed {
             if let Err(err) = self.dispatch_received_bypassed_packet(target_addr, data).await {
                 error!(
@@ -374,7 +569,9 @@ where
         match *target_addr {
             Address::SocketAddress(sa) => self.send_received_bypassed_packet(sa, data).await,
             Address::DomainNameAddress(ref dname, port) => {
-                lookup_then!(self.context.context_ref(), dname, port, |sa| {
+                let ip_strategy = self.context.target_ip_strategy(target_addr);
+                lookup_then!(self.context.context_ref(), dname, port, ip_strategy, |sa| {
+                    self.record_sent_addr(sa);
                     self.send_received_bypassed_packet(sa, data).await
                 })
                 .map(|_| ())
@@ -389,6 +586,9 @@ where
                 None => {
                     let socket =
                         ShadowUdpSocket::connect_any_with_opts(&target_addr, self.context.connect_opts_ref()).await?;
+                    if let Some(ref connection_guard) = self.connection_guard {
+                        connection_guard.set_bypassed();
+                    }
                     self.bypassed_ipv4_socket.insert(socket)
                 }
             },
@@ -397,6 +597,9 @@ where
                 None => {
                     let socket =
                         ShadowUdpSocket::connect_any_with_opts(&target_addr, self.context.connect_opts_ref()).await?;
+                    if let Some(ref connection_guard) = self.connection_guard {
+                        connection_guard.set_bypassed();
+                    }
                     self.bypassed_ipv6_socket.insert(socket)
                 }
             },
@@ -417,24 +620,91 @@ where
     }
 
     async fn dispatch_received_proxied_packet(&mut self, target_addr: &Address, data: &[u8]) -> io::Result<()> {
+        if !self.p2p_policy.is_allow() && !self.p2p_detected && is_bittorrent_packet(data) {
+            debug!(
+                "udp association {} -> {} BitTorrent traffic detected, policy: {}",
+                self.peer_addr, target_addr, self.p2p_policy
+            );
+            self.p2p_detected = true;
+        }
+
+        let p2p_route = match self.p2p_policy {
+            P2pPolicy::Block if self.p2p_detected => {
+                trace!(
+                    "udp relay {} -> {} (proxied) with {} bytes blocked, BitTorrent traffic",
+                    self.peer_addr,
+                    target_addr,
+                    data.len()
+                );
+                return Ok(());
+            }
+            P2pPolicy::Route(ref tag) if self.p2p_detected => Some(tag.clone()),
+            _ => None,
+        };
+
+        let p2p_routed = p2p_route.is_some();
+
+        // Targets routed to different tags have to be sent through different servers.
+        // Responses of the previous server won't be received after switching.
+        let route = match p2p_route {
+            Some(tag) => Some(tag),
+            None => self.context.route_tag(None, target_addr).await,
+        };
+        if self.proxied_socket.is_some() && route != self.proxied_route {
+            debug!(
+                "{} -> {} (proxied) route changed from {:?} to {:?}, reconnecting",
+                self.peer_addr, target_addr, self.proxied_route, route
+            );
+            self.proxied_socket = None;
+        }
+
+        if self.context.udp_migration() && self.proxied_socket.is_some() && !p2p_routed {
+            if let Some(ref server) = self.proxied_server {
+                if let Some(new_server) = self
+                    .balancer
+                    .udp_failover_server_for(server, self.peer_addr, target_addr)
+                    .await
+                {
+                    info!(
+                        "udp association {} migrating from dead server {} to {}",
+                        self.peer_addr,
+                        server.server_config().addr(),
+                        new_server.server_config().addr()
+                    );
+                    self.proxied_socket = None;
+                }
+            }
+        }
+
         let socket = match self.proxied_socket {
             Some(ref mut socket) => socket,
             None => {
                 // Create a new connection to proxy server
 
-                let server = self.balancer.best_udp_server();
+                let server = match route {
+                    Some(ref tag) if p2p_routed => {
+                        self.balancer.best_udp_server_tagged(self.peer_addr, target_addr, tag)?
+                    }
+                    _ => self.balancer.best_udp_server_for(self.peer_addr, target_addr).await?,
+                };
                 let svr_cfg = server.server_config();
 
-                let socket =
-                    ProxySocket::connect_with_opts(self.context.context(), svr_cfg, self.context.connect_opts_ref())
-                        .await?;
-                let socket = MonProxySocket::from_socket(socket, self.context.flow_stat());
+                let socket = ProxiedUdpSocket::connect(&self.context, svr_cfg).await?;
+                if let Err(err) = socket.enable_gro() {
+                    warn!("udp association failed to enable GRO, error: {}", err);
+                }
+                self.proxied_server = Some(server.clone());
+                self.proxied_route = route;
+                if let Some(ref connection_guard) = self.connection_guard {
+                    connection_guard.set_server(svr_cfg.addr());
+                }
 
                 self.proxied_socket.insert(socket)
             }
         };
 
-        match socket.send(target_addr, data).await {
+        let sent_addr = self.context.proxied_target_addr(target_addr.clone()).await?;
+        match socket.send(&sent_addr, data).await {
             Ok(..) => return Ok(()),
             Err(err) => {
                 debug!(
@@ -453,35 +723,98 @@ where
         Ok(())
     }
 
-    async fn send_received_respond_packet(&mut self, addr: &Address, data: &[u8], bypassed: bool) {
-        trace!(
-            "udp relay {} <- {} ({}) received {} bytes",
-            self.peer_addr,
-            addr,
-            if bypassed { "bypassed" } else { "proxied" },
-            data.len(),
-        );
+    fn record_sent_addr(&mut self, addr: SocketAddr) {
+        match self.filtering {
+            UdpNatFiltering::FullCone => {}
+            UdpNatFiltering::AddressRestricted => {
+                self.sent_ips.insert(addr.ip());
+            }
+            UdpNatFiltering::PortRestricted => {
+                self.sent_addrs.insert(addr);
+            }
+        }
+    }
+
+    fn record_sent_name_port(&mut self, port: u16) {
+        if self.filtering != UdpNatFiltering::FullCone {
+            self.sent_name_ports.insert(port);
+        }
+    }
+
+    /// Check if packets from `addr` could be relayed back to the client
+    fn is_respond_allowed(&self, addr: &Address) -> bool {
+        match (self.filtering, addr) {
+            (UdpNatFiltering::FullCone, ..) => true,
+            (UdpNatFiltering::AddressRestricted, Address::SocketAddress(sa)) => {
+                self.sent_ips.contains(&sa.ip()) || self.sent_name_ports.contains(&sa.port())
+            }
+            (UdpNatFiltering::PortRestricted, Address::SocketAddress(sa)) => {
+                self.sent_addrs.contains(sa) || self.sent_name_ports.contains(&sa.port())
+            }
+            (_, Address::DomainNameAddress(_, port)) => self.sent_name_ports.contains(port),
+        }
+    }
+
+    async fn send_received_respond_batch(&mut self, mut packets: Vec<(Address, &[u8])>, bypassed: bool) {
+        if self.filtering != UdpNatFiltering::FullCone {
+            packets.retain(|(addr, data)| {
+                let allowed = self.is_respond_allowed(addr);
+                if !allowed {
+                    trace!(
+                        "udp relay {} <- {} filtered by {} NAT, dropped {} bytes",
+                        self.peer_addr,
+                        addr,
+                        self.filtering,
+                        data.len()
+                    );
+                }
+                allowed
+            });
+        }
+
+        if packets.is_empty() {
+            return;
+        }
 
         // Keep association alive in map
         self.keepalive_flag = true;
 
+        for (addr, data) in packets.iter_mut() {
+            trace!(
+                "udp relay {} <- {} ({}) received {} bytes",
+                self.peer_addr,
+                addr,
+                if bypassed { "bypassed" } else { "proxied" },
+                data.len(),
+            );
+
+            if let Address::SocketAddress(ref sa) = *addr {
+                if let Some(fa) = self.fake_ip_targets.get(&sa.port()) {
+                    *addr = Address::from(*fa);
+                }
+            }
+        }
+
         // Send back to client
-        if let Err(err) = self.respond_writer.send_to(self.peer_addr, addr, data).await {
+        if let Err(err) = self.respond_writer.send_batch_to(self.peer_addr, &packets).await {
             warn!(
-                "udp failed to send back {} bytes to client {}, from target {} ({}), error: {}",
-                data.len(),
+                "udp failed to send back {} packets to client {} ({}), error: {}",
+                packets.len(),
                 self.peer_addr,
-                addr,
                 if bypassed { "bypassed" } else { "proxied" },
                 err
             );
         } else {
+            let n = packets.iter().map(|(_, data)| data.len()).sum::<usize>();
+            if let Some(ref connection_guard) = self.connection_guard {
+                connection_guard.flow_stat().incr_tx(n as u64);
+            }
             trace!(
-                "udp relay {} <- {} ({}) with {} bytes",
+                "udp relay {} <- ... ({}) with {} packets, {} bytes",
                 self.peer_addr,
-                addr,
                 if bypassed { "bypassed" } else { "proxied" },
-                data.len()
+                packets.len(),
+                n
             );
         }
     }
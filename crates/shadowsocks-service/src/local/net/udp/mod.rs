@@ -1,3 +1,11 @@
-pub use self::association::{UdpAssociationManager, UdpInboundWrite};
+pub use self::{
+    association::{UdpAssociationManager, UdpInboundWrite},
+    nat::{UdpNatConfig, UdpNatFiltering, UdpPortTimeout},
+    proxied_socket::{ProxiedRecvBatch, ProxiedUdpSocket},
+    udp_over_tcp::ProxyUdpOverTcpSocket,
+};
 
 pub mod association;
+pub mod nat;
+pub mod proxied_socket;
+pub mod udp_over_tcp;
@@ -0,0 +1,106 @@
+//! NAT behavior of UDP associations
+//!
+//! An association maps a client's address to the sockets sending to its targets, like a NAT mapping. Responses are
+//! relayed back to the client according to its filtering behavior (RFC 4787):
+//!
+//! - Full cone (endpoint-independent), packets from any remote addresses are relayed back
+//! - Address restricted, only packets from IPs that the client has sent to are relayed back
+//! - Port restricted, only packets from addresses (IP and port) that the client has sent to are relayed back
+//!
+//! Associations expire after being idle for the timeout of the target ports they have sent to, or `udp_timeout`.
+
+use std::{
+    fmt::{self, Display},
+    str::FromStr,
+    time::Duration,
+};
+
+use crate::net::PortRange;
+
+/// Filtering of packets received from remote addresses
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum UdpNatFiltering {
+    /// Relay packets from any remote addresses
+    #[default]
+    FullCone,
+    /// Relay packets from IPs that the client has sent to
+    AddressRestricted,
+    /// Relay packets from addresses that the client has sent to
+    PortRestricted,
+}
+
+impl Display for UdpNatFiltering {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            UdpNatFiltering::FullCone => f.write_str("full_cone"),
+            UdpNatFiltering::AddressRestricted => f.write_str("address_restricted"),
+            UdpNatFiltering::PortRestricted => f.write_str("port_restricted"),
+        }
+    }
+}
+
+/// Error while parsing `UdpNatFiltering` from string
+#[derive(Debug, Clone, Copy)]
+pub struct UdpNatFilteringError;
+
+impl Display for UdpNatFilteringError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("invalid UdpNatFiltering, expecting \"full_cone\", \"address_restricted\" or \"port_restricted\"")
+    }
+}
+
+impl FromStr for UdpNatFiltering {
+    type Err = UdpNatFilteringError;
+
+    fn from_str(s: &str) -> Result<UdpNatFiltering, UdpNatFilteringError> {
+        match s {
+            "full_cone" => Ok(UdpNatFiltering::FullCone),
+            "address_restricted" => Ok(UdpNatFiltering::AddressRestricted),
+            "port_restricted" => Ok(UdpNatFiltering::PortRestricted),
+            _ => Err(UdpNatFilteringError),
+        }
+    }
+}
+
+/// Timeout of associations sending to these target ports
+#[derive(Debug, Clone)]
+pub struct UdpPortTimeout {
+    /// Target ports, like `53` for DNS, `443` for QUIC
+    pub ports: Vec<PortRange>,
+    /// Idle time before the association expires, must be > 0
+    pub timeout: Duration,
+}
+
+/// NAT behavior of UDP associations
+#[derive(Debug, Clone, Default)]
+pub struct UdpNatConfig {
+    /// Filtering of packets received from remote addresses
+    pub filtering: UdpNatFiltering,
+    /// Timeouts by target ports, matched in order
+    pub timeouts: Vec<UdpPortTimeout>,
+}
+
+impl UdpNatConfig {
+    /// Check if it is the default full cone NAT without any timeouts
+    pub fn is_default(&self) -> bool {
+        self.filtering == UdpNatFiltering::FullCone && self.timeouts.is_empty()
+    }
+
+    /// Timeout of associations sending to target `port`, `default` if it doesn't match any timeouts
+    pub fn timeout_for(&self, port: u16, default: Duration) -> Duration {
+        self.timeouts
+            .iter()
+            .find(|t| t.ports.iter().any(|p| p.0.contains(&port)))
+            .map_or(default, |t| t.timeout)
+    }
+
+    /// The longest timeout of associations, including `default`
+    pub fn max_timeout(&self, default: Duration) -> Duration {
+        self.timeouts.iter().map(|t| t.timeout).fold(default, Duration::max)
+    }
+
+    /// The shortest timeout of associations, including `default`
+    pub fn min_timeout(&self, default: Duration) -> Duration {
+        self.timeouts.iter().map(|t| t.timeout).fold(default, Duration::min)
+    }
+}
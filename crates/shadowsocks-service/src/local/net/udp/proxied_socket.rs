@@ -0,0 +1,142 @@
+//! UDP socket connected to a shadowsocks server
+
+use std::io;
+
+use log::error;
+use shadowsocks::{
+    net::udp::{RecvBatch, UDP_BATCH_SIZE},
+    relay::{
+        buffer_pool::udp_buffer_pool,
+        socks5::Address,
+        udprelay::{ProxySocket, MAXIMUM_UDP_PAYLOAD_SIZE},
+    },
+    ServerConfig,
+};
+
+use crate::{local::context::ServiceContext, net::MonProxySocket};
+
+use super::udp_over_tcp::ProxyUdpOverTcpSocket;
+
+/// UDP packets received from the server by `ProxiedUdpSocket::recv_batch`
+#[derive(Default)]
+pub struct ProxiedRecvBatch {
+    // Allocated by the first call of `recv_batch`
+    batch: Option<RecvBatch>,
+    // Buffer of the packet received from UDP-over-TCP streams, which are received one by one
+    stream_buf: Vec<u8>,
+    // Indexes in `batch` (`None` for `stream_buf`), lengths of payloads and source addresses of packets
+    packets: Vec<(Option<usize>, usize, Address)>,
+}
+
+impl ProxiedRecvBatch {
+    /// Create an empty batch
+    pub fn new() -> ProxiedRecvBatch {
+        ProxiedRecvBatch::default()
+    }
+
+    /// Number of packets received
+    pub fn len(&self) -> usize {
+        self.packets.len()
+    }
+
+    /// Check if no packet is received
+    pub fn is_empty(&self) -> bool {
+        self.packets.is_empty()
+    }
+
+    /// Payload of the `i`th packet, and its source address
+    pub fn get(&self, i: usize) -> (&[u8], &Address) {
+        let (index, n, ref addr) = self.packets[i];
+        let data = match (index, &self.batch) {
+            (Some(index), Some(batch)) => &batch.get(index).0[..n],
+            _ => &self.stream_buf[..n],
+        };
+        (data, addr)
+    }
+}
+
+/// Unified socket for relaying UDP packets through a shadowsocks server
+///
+/// Packets are carried in a TCP stream if UDP-over-TCP is enabled for the server
+pub enum ProxiedUdpSocket {
+    Udp(Box<MonProxySocket>),
+    UdpOverTcp(Box<ProxyUdpOverTcpSocket>),
+}
+
+impl ProxiedUdpSocket {
+    /// Connect to the server configured by `svr_cfg`
+    pub async fn connect(context: &ServiceContext, svr_cfg: &ServerConfig) -> io::Result<ProxiedUdpSocket> {
+        if svr_cfg.udp_over_tcp() {
+            let socket = ProxyUdpOverTcpSocket::connect(context, svr_cfg).await?;
+            return Ok(ProxiedUdpSocket::UdpOverTcp(Box::new(socket)));
+        }
+
+        let connect_opts = context.server_connect_opts(svr_cfg);
+        let socket = ProxySocket::connect_with_opts(context.context(), svr_cfg, &connect_opts).await?;
+        Ok(ProxiedUdpSocket::Udp(Box::new(MonProxySocket::from_socket(
+            socket,
+            context.flow_stat(),
+        ))))
+    }
+
+    /// Receive packets coalesced by GRO, if UDP offload is enabled
+    ///
+    /// The socket has to be received only by `recv_batch` afterwards, which splits them. UDP-over-TCP isn't affected.
+    pub fn enable_gro(&self) -> io::Result<()> {
+        match *self {
+            ProxiedUdpSocket::Udp(ref s) => s.get_ref().enable_gro(),
+            ProxiedUdpSocket::UdpOverTcp(..) => Ok(()),
+        }
+    }
+
+    /// Send a UDP packet to `addr` through the server
+    pub async fn send(&mut self, addr: &Address, payload: &[u8]) -> io::Result<()> {
+        match *self {
+            ProxiedUdpSocket::Udp(ref s) => s.send(addr, payload).await,
+            ProxiedUdpSocket::UdpOverTcp(ref mut s) => s.send(addr, payload).await,
+        }
+    }
+
+    /// Receive a UDP packet from the server
+    ///
+    /// Returns the length of data in `recv_buf` and the source address of the packet
+    pub async fn recv(&mut self, recv_buf: &mut [u8]) -> io::Result<(usize, Address)> {
+        match *self {
+            ProxiedUdpSocket::Udp(ref s) => s.recv(recv_buf).await,
+            ProxiedUdpSocket::UdpOverTcp(ref mut s) => s.recv(recv_buf).await,
+        }
+    }
+
+    /// Receive a batch of UDP packets from the server
+    ///
+    /// Packets failed to be decrypted are dropped. Returns the number of packets in `batch`, which may be `0`.
+    pub async fn recv_batch(&mut self, batch: &mut ProxiedRecvBatch) -> io::Result<usize> {
+        batch.packets.clear();
+
+        match *self {
+            ProxiedUdpSocket::Udp(ref s) => {
+                let recv_batch = batch
+                    .batch
+                    .get_or_insert_with(|| RecvBatch::with_pool(UDP_BATCH_SIZE, udp_buffer_pool()));
+                s.recv_batch(recv_batch).await?;
+
+                for i in 0..recv_batch.len() {
+                    let (packet, _) = recv_batch.get_mut(i);
+                    match s.decrypt_packet(packet).await {
+                        Ok((n, addr, _)) => batch.packets.push((Some(i), n, addr)),
+                        Err(err) => error!("udp packet from server dropped, error: {}", err),
+                    }
+                }
+            }
+            ProxiedUdpSocket::UdpOverTcp(ref mut s) => {
+                if batch.stream_buf.is_empty() {
+                    batch.stream_buf.resize(MAXIMUM_UDP_PAYLOAD_SIZE, 0);
+                }
+                let (n, addr) = s.recv(&mut batch.stream_buf).await?;
+                batch.packets.push((None, n, addr));
+            }
+        }
+
+        Ok(batch.packets.len())
+    }
+}
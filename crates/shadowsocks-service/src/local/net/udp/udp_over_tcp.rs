@@ -0,0 +1,100 @@
+//! UDP-over-TCP client
+
+use std::io::{self, ErrorKind};
+
+use bytes::Bytes;
+use log::trace;
+use shadowsocks::{
+    net::TcpStream as OutboundTcpStream,
+    relay::{
+        socks5::Address,
+        tcprelay::proxy_stream::{ProxyClientStream, ProxyClientStreamWriteHalf},
+        udp_over_tcp::{read_packet, udp_over_tcp_address, write_packet},
+        udprelay::MAXIMUM_UDP_PAYLOAD_SIZE,
+    },
+    ServerConfig,
+};
+use tokio::{sync::mpsc, task::JoinHandle};
+
+use crate::{
+    local::context::ServiceContext,
+    net::{MonProxyStream, UDP_ASSOCIATION_SEND_CHANNEL_SIZE},
+};
+
+/// Sends and receives UDP packets in a TCP stream connected to a shadowsocks server
+pub struct ProxyUdpOverTcpSocket {
+    writer: ProxyClientStreamWriteHalf<MonProxyStream<OutboundTcpStream>>,
+    receiver: mpsc::Receiver<io::Result<(Address, Bytes)>>,
+    reader_handle: JoinHandle<()>,
+}
+
+impl Drop for ProxyUdpOverTcpSocket {
+    fn drop(&mut self) {
+        self.reader_handle.abort();
+    }
+}
+
+impl ProxyUdpOverTcpSocket {
+    /// Connect to the server configured by `svr_cfg`
+    pub async fn connect(context: &ServiceContext, svr_cfg: &ServerConfig) -> io::Result<ProxyUdpOverTcpSocket> {
+        let flow_stat = context.flow_stat();
+        let stream = ProxyClientStream::connect_with_opts_map(
+            context.context(),
+            svr_cfg,
+            udp_over_tcp_address(),
+            &context.server_connect_opts(svr_cfg),
+            |stream| MonProxyStream::from_stream(stream, flow_stat),
+        )
+        .await?;
+
+        trace!("connected udp-over-tcp stream to {}", svr_cfg.addr());
+
+        let (mut reader, writer) = stream.into_split();
+
+        // Packets are read in a separated task, because reading a frame from stream couldn't be cancelled safely
+        let (sender, receiver) = mpsc::channel(UDP_ASSOCIATION_SEND_CHANNEL_SIZE);
+        let reader_handle = tokio::spawn(async move {
+            let mut buffer = vec![0u8; MAXIMUM_UDP_PAYLOAD_SIZE];
+            loop {
+                let packet = read_packet(&mut reader, &mut buffer)
+                    .await
+                    .map(|(n, addr)| (addr, Bytes::copy_from_slice(&buffer[..n])));
+                let failed = packet.is_err();
+
+                if sender.send(packet).await.is_err() || failed {
+                    break;
+                }
+            }
+        });
+
+        Ok(ProxyUdpOverTcpSocket {
+            writer,
+            receiver,
+            reader_handle,
+        })
+    }
+
+    /// Send a UDP packet to `addr` through the server
+    pub async fn send(&mut self, addr: &Address, payload: &[u8]) -> io::Result<()> {
+        write_packet(&mut self.writer, addr, payload).await
+    }
+
+    /// Receive a UDP packet from the server
+    ///
+    /// Returns the length of data in `recv_buf` and the source address of the packet
+    pub async fn recv(&mut self, recv_buf: &mut [u8]) -> io::Result<(usize, Address)> {
+        match self.receiver.recv().await {
+            Some(Ok((addr, data))) => {
+                if data.len() > recv_buf.len() {
+                    let err = io::Error::new(ErrorKind::InvalidData, "udp-over-tcp packet exceeds receive buffer");
+                    return Err(err);
+                }
+
+                recv_buf[..data.len()].copy_from_slice(&data);
+                Ok((data.len(), addr))
+            }
+            Some(Err(err)) => Err(err),
+            None => Err(io::Error::new(ErrorKind::UnexpectedEof, "udp-over-tcp stream closed")),
+        }
+    }
+}
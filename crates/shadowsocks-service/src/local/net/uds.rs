@@ -0,0 +1,62 @@
+//! Unix Domain Socket listeners for local servers
+
+use std::{
+    fs::{self, Permissions},
+    io::{self, ErrorKind},
+    net::{Ipv4Addr, SocketAddr},
+    os::unix::fs::{FileTypeExt, PermissionsExt},
+    path::Path,
+};
+
+use log::trace;
+use tokio::net::{UnixListener, UnixStream};
+
+/// Bind a `UnixListener` on `path`
+///
+/// A stale socket file left by the previous run will be removed. Permission bits of the socket file will be set to
+/// `mode` if specified.
+pub fn bind_unix_listener(path: &Path, mode: Option<u32>) -> io::Result<UnixListener> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) => {
+            if !metadata.file_type().is_socket() {
+                let err = io::Error::new(
+                    ErrorKind::AlreadyExists,
+                    format!("{} exists and it is not a socket", path.display()),
+                );
+                return Err(err);
+            }
+
+            trace!("removing stale unix socket {}", path.display());
+            fs::remove_file(path)?;
+        }
+        Err(ref err) if err.kind() == ErrorKind::NotFound => {}
+        Err(err) => return Err(err),
+    }
+
+    let listener = UnixListener::bind(path)?;
+
+    if let Some(mode) = mode {
+        fs::set_permissions(path, Permissions::from_mode(mode))?;
+    }
+
+    Ok(listener)
+}
+
+/// Identify the peer of an accepted Unix Domain Socket stream
+///
+/// Returns a printable peer identity (`pid`, `uid`, `gid` of the peer process) and the address that will be passed to
+/// handlers as client's address. Peers of Unix Domain Sockets are always on the local machine, so they are treated as
+/// clients from `127.0.0.1`.
+pub fn unix_peer_identity(stream: &UnixStream) -> (String, SocketAddr) {
+    let peer_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0);
+
+    let identity = match stream.peer_cred() {
+        Ok(cred) => match cred.pid() {
+            Some(pid) => format!("unix:pid={},uid={},gid={}", pid, cred.uid(), cred.gid()),
+            None => format!("unix:uid={},gid={}", cred.uid(), cred.gid()),
+        },
+        Err(..) => "unix:unknown".to_owned(),
+    };
+
+    (identity, peer_addr)
+}
@@ -0,0 +1,268 @@
+//! SIP008 online configuration subscription
+//!
+//! Servers delivered by the subscribed URL are merged with servers of the configuration file, and replaced by the
+//! delivered servers of every update. [SIP008](https://shadowsocks.org/guide/sip008.html)
+
+use std::{
+    io::{self, ErrorKind},
+    net::IpAddr,
+    str,
+    sync::Arc,
+    time::Duration,
+};
+
+use bytes::{Bytes, BytesMut};
+use hyper::{
+    body::HttpBody,
+    client::conn,
+    header::{self, HeaderValue},
+    Body,
+    Request,
+    Response,
+    StatusCode,
+    Uri,
+};
+use log::{debug, error, info, trace, warn};
+use once_cell::sync::Lazy;
+use shadowsocks::{config::ServerConfig, net::TcpStream, relay::socks5::Address};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    time,
+};
+use tokio_rustls::{
+    rustls::{Certificate, ClientConfig, OwnedTrustAnchor, RootCertStore, ServerName},
+    TlsConnector,
+};
+
+use crate::config::{Config, ConfigType};
+
+use super::{context::ServiceContext, loadbalancing::PingBalancer};
+
+/// Default interval of updates
+pub const DEFAULT_UPDATE_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// Timeout of fetching the online configuration
+const FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Maximum length of SIP008 documents
+const MAX_DOCUMENT_SIZE: usize = 4 * 1024 * 1024;
+
+static TLS_CONFIG: Lazy<Arc<ClientConfig>> = Lazy::new(|| {
+    let mut store = RootCertStore::empty();
+    match rustls_native_certs::load_native_certs() {
+        Ok(certs) => {
+            for cert in certs {
+                if let Err(err) = store.add(&Certificate(cert.0)) {
+                    warn!("failed to add cert, error: {}", err);
+                }
+            }
+        }
+        Err(err) => {
+            warn!("failed to load native certs, {}", err);
+
+            store.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|root| {
+                OwnedTrustAnchor::from_subject_spki_name_constraints(root.subject, root.spki, root.name_constraints)
+            }));
+        }
+    }
+
+    let mut config = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(store)
+        .with_no_client_auth();
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    Arc::new(config)
+});
+
+/// Configuration of SIP008 online configuration subscription
+#[derive(Debug, Clone)]
+pub struct OnlineConfig {
+    /// URL of the SIP008 document, `http` or `https`
+    pub config_url: Uri,
+    /// Interval of updates
+    pub update_interval: Duration,
+}
+
+/// Fetches SIP008 documents and replaces servers of the balancer with the delivered servers
+pub struct OnlineConfigService {
+    context: Arc<ServiceContext>,
+    config: OnlineConfig,
+    static_servers: Vec<ServerConfig>,
+    etag: Option<HeaderValue>,
+}
+
+impl OnlineConfigService {
+    /// Create with servers of the configuration file, which are always kept in the balancer
+    pub fn new(context: Arc<ServiceContext>, config: OnlineConfig, static_servers: Vec<ServerConfig>) -> Self {
+        OnlineConfigService {
+            context,
+            config,
+            static_servers,
+            etag: None,
+        }
+    }
+
+    /// Fetch the online configuration for creating the balancer, returning servers of the configuration file with
+    /// the delivered servers
+    ///
+    /// Only servers of the configuration file are returned if the fetch failed, which is an error if there is none.
+    pub async fn fetch_servers(&mut self) -> io::Result<Vec<ServerConfig>> {
+        match self.fetch().await {
+            Ok(Some(servers)) => Ok(servers),
+            Ok(None) => Ok(self.static_servers.clone()),
+            Err(err) if !self.static_servers.is_empty() => {
+                error!(
+                    "online config {} fetch failed, error: {}, starting with servers of the configuration",
+                    self.config.config_url, err
+                );
+                Ok(self.static_servers.clone())
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Run updates periodically, replacing servers of `balancer`
+    pub async fn run(mut self, balancer: PingBalancer) -> io::Result<()> {
+        loop {
+            time::sleep(self.config.update_interval).await;
+
+            match self.fetch().await {
+                Ok(Some(servers)) => {
+                    if let Err(err) = balancer.reset_servers(servers).await {
+                        error!("online config {} update failed, error: {}", self.config.config_url, err);
+                    }
+                }
+                Ok(None) => debug!("online config {} not modified", self.config.config_url),
+                Err(err) => error!("online config {} update failed, error: {}", self.config.config_url, err),
+            }
+        }
+    }
+
+    /// Fetch and parse the document, `None` if it is not modified since the last fetch
+    async fn fetch(&mut self) -> io::Result<Option<Vec<ServerConfig>>> {
+        let (body, etag) = match time::timeout(FETCH_TIMEOUT, self.fetch_document()).await {
+            Ok(Ok(Some(document))) => document,
+            Ok(Ok(None)) => return Ok(None),
+            Ok(Err(err)) => return Err(err),
+            Err(..) => return Err(io::Error::new(ErrorKind::TimedOut, "fetch timed out")),
+        };
+
+        let document = match str::from_utf8(&body) {
+            Ok(s) => s,
+            Err(..) => return Err(io::Error::new(ErrorKind::InvalidData, "document is not UTF-8")),
+        };
+        let online_servers = match Config::load_from_str(document, ConfigType::Local) {
+            Ok(c) => c.server,
+            Err(err) => return Err(io::Error::new(ErrorKind::InvalidData, err.to_string())),
+        };
+
+        let mut servers = self.static_servers.clone();
+        servers.extend(online_servers);
+        if servers.is_empty() {
+            return Err(io::Error::new(ErrorKind::InvalidData, "document without any servers"));
+        }
+
+        info!(
+            "online config {} updated, with {} servers",
+            self.config.config_url,
+            servers.len() - self.static_servers.len()
+        );
+
+        // Applied documents only, a failed document is fetched again in the next update
+        self.etag = etag;
+        Ok(Some(servers))
+    }
+
+    async fn fetch_document(&self) -> io::Result<Option<(Bytes, Option<HeaderValue>)>> {
+        let uri = &self.config.config_url;
+        let https = uri.scheme_str() == Some("https");
+        let host = uri.host().unwrap_or_default();
+        let port = uri.port_u16().unwrap_or(if https { 443 } else { 80 });
+
+        // Hosts of IPv6 addresses are in brackets
+        let target = match host.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>() {
+            Ok(ip) => Address::SocketAddress((ip, port).into()),
+            Err(..) => Address::DomainNameAddress(host.to_owned(), port),
+        };
+
+        let mut builder = Request::get(uri.path_and_query().map(|p| p.as_str()).unwrap_or("/"))
+            .header(header::HOST, uri.authority().map(|a| a.as_str()).unwrap_or(host))
+            .header(header::ACCEPT, "application/json");
+        if let Some(ref etag) = self.etag {
+            builder = builder.header(header::IF_NONE_MATCH, etag.clone());
+        }
+        let req = match builder.body(Body::empty()) {
+            Ok(r) => r,
+            Err(err) => return Err(io::Error::new(ErrorKind::InvalidInput, err)),
+        };
+
+        trace!("online config fetching {}", uri);
+
+        let stream =
+            TcpStream::connect_remote_with_opts(&self.context.context(), &target, self.context.connect_opts_ref())
+                .await?;
+        let rsp = if https {
+            let server_name = match ServerName::try_from(host) {
+                Ok(n) => n,
+                Err(..) => {
+                    return Err(io::Error::new(
+                        ErrorKind::InvalidInput,
+                        format!("invalid dnsname \"{}\"", host),
+                    ));
+                }
+            };
+            let stream = TlsConnector::from(TLS_CONFIG.clone())
+                .connect(server_name, stream)
+                .await?;
+            send_request(stream, req).await?
+        } else {
+            send_request(stream, req).await?
+        };
+
+        match rsp.status() {
+            StatusCode::OK => {}
+            StatusCode::NOT_MODIFIED => return Ok(None),
+            status => {
+                return Err(io::Error::new(
+                    ErrorKind::Other,
+                    format!("online config server responded {}", status),
+                ));
+            }
+        }
+
+        let etag = rsp.headers().get(header::ETAG).cloned();
+
+        let mut body = rsp.into_body();
+        let mut document = BytesMut::new();
+        while let Some(data) = body.data().await {
+            let data = data.map_err(|err| io::Error::new(ErrorKind::Other, err))?;
+            if document.len() + data.len() > MAX_DOCUMENT_SIZE {
+                return Err(io::Error::new(ErrorKind::InvalidData, "document too large"));
+            }
+            document.extend_from_slice(&data);
+        }
+
+        Ok(Some((document.freeze(), etag)))
+    }
+}
+
+async fn send_request<S>(stream: S, req: Request<Body>) -> io::Result<Response<Body>>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (mut sender, connection) = match conn::handshake(stream).await {
+        Ok(s) => s,
+        Err(err) => return Err(io::Error::new(ErrorKind::Other, err)),
+    };
+
+    tokio::spawn(async move {
+        if let Err(err) = connection.await {
+            trace!("online config connection closed with error: {}", err);
+        }
+    });
+
+    match sender.send_request(req).await {
+        Ok(rsp) => Ok(rsp),
+        Err(err) => Err(io::Error::new(ErrorKind::Other, err)),
+    }
+}
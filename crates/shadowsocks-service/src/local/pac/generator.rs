@@ -0,0 +1,232 @@
+//! PAC file generator
+
+use std::{
+    fmt::{self, Write},
+    net::{IpAddr, SocketAddr},
+};
+
+use shadowsocks::ServerAddr;
+
+use crate::{
+    acl::{AccessControl, ExportedRules},
+    config::{LocalConfig, ProtocolType},
+};
+
+/// Default PAC template
+///
+/// `__PROXY__` will be replaced by a JavaScript string of proxies, and `__RULES__` will be replaced by
+/// definitions of `proxyDefault`, `proxyList` and `bypassList`
+const DEFAULT_PAC_TEMPLATE: &str = include_str!("default.pac");
+
+/// Proxy listeners referred by PAC files
+#[derive(Debug, Clone)]
+pub enum PacProxy {
+    /// SOCKS5 proxy
+    Socks(ServerAddr),
+    /// HTTP proxy
+    Http(ServerAddr),
+    /// HTTP proxy over TLS
+    Https(ServerAddr),
+}
+
+impl PacProxy {
+    /// Collect proxy listeners that could be used in PAC files from local servers
+    ///
+    /// Listeners on Unix Domain Sockets and SOCKS5 over TLS are not supported by PAC
+    pub fn from_locals(locals: &[LocalConfig]) -> Vec<PacProxy> {
+        let mut proxies = Vec::new();
+
+        for local in locals {
+            let addr = match local.addr {
+                Some(ref a) => a.clone(),
+                None => continue,
+            };
+
+            #[cfg(unix)]
+            if local.unix_addr.is_some() {
+                continue;
+            }
+
+            #[cfg(any(feature = "local-socks-tls", feature = "local-http-tls", feature = "local-dns-tls"))]
+            let tls = local.tls.is_some();
+            #[cfg(not(any(feature = "local-socks-tls", feature = "local-http-tls", feature = "local-dns-tls")))]
+            let tls = false;
+
+            match local.protocol {
+                ProtocolType::Socks if local.mode.enable_tcp() && !tls => proxies.push(PacProxy::Socks(addr)),
+                #[cfg(feature = "local-http")]
+                ProtocolType::Http if tls => proxies.push(PacProxy::Https(addr)),
+                #[cfg(feature = "local-http")]
+                ProtocolType::Http => proxies.push(PacProxy::Http(addr)),
+                #[cfg(feature = "local-http")]
+                ProtocolType::Mixed if local.mode.enable_tcp() => {
+                    proxies.push(PacProxy::Socks(addr.clone()));
+                    proxies.push(PacProxy::Http(addr));
+                }
+                _ => {}
+            }
+        }
+
+        proxies
+    }
+}
+
+/// PAC file generator
+pub struct PacGenerator {
+    template: String,
+    rules: String,
+    proxies: Vec<PacProxy>,
+}
+
+impl PacGenerator {
+    /// Create a generator with `proxies` and rules from `acl`
+    ///
+    /// All requests will be proxied if `acl` is not set
+    pub fn new(acl: Option<&AccessControl>, proxies: Vec<PacProxy>) -> PacGenerator {
+        let mut rules = String::new();
+        match acl {
+            Some(acl) => {
+                let _ = writeln!(rules, "var proxyDefault = {};", acl.is_default_in_proxy_list());
+                let _ = write_rules(&mut rules, "proxyList", &acl.export_proxy_list());
+                let _ = write_rules(&mut rules, "bypassList", &acl.export_bypass_list());
+            }
+            None => {
+                let _ = writeln!(rules, "var proxyDefault = true;");
+                let _ = write_rules(&mut rules, "proxyList", &ExportedRules::default());
+                let _ = write_rules(&mut rules, "bypassList", &ExportedRules::default());
+            }
+        }
+
+        PacGenerator {
+            template: DEFAULT_PAC_TEMPLATE.to_owned(),
+            rules,
+            proxies,
+        }
+    }
+
+    /// Replace the default template
+    ///
+    /// `__PROXY__` will be replaced by a JavaScript string of proxies, and `__RULES__` will be replaced by
+    /// definitions of `proxyDefault`, `proxyList` and `bypassList`
+    pub fn set_template(&mut self, template: String) {
+        self.template = template;
+    }
+
+    /// Generate the PAC file
+    ///
+    /// Proxies listening on unspecified addresses (`0.0.0.0` or `::`) are referred by `local_ip`,
+    /// which should be the address that clients connected to
+    pub fn generate(&self, local_ip: IpAddr) -> String {
+        let mut proxy = String::new();
+
+        for p in &self.proxies {
+            let (addr, prefix) = match *p {
+                PacProxy::Socks(ref a) => (a, "SOCKS5"),
+                PacProxy::Http(ref a) => (a, "PROXY"),
+                PacProxy::Https(ref a) => (a, "HTTPS"),
+            };
+
+            let addr = match *addr {
+                ServerAddr::SocketAddr(sa) if sa.ip().is_unspecified() => {
+                    ServerAddr::SocketAddr(SocketAddr::new(local_ip, sa.port()))
+                }
+                ref a => a.clone(),
+            };
+
+            if !proxy.is_empty() {
+                proxy.push_str("; ");
+            }
+            let _ = write!(proxy, "{} {}", prefix, addr);
+
+            // For clients that don't understand SOCKS5
+            if let PacProxy::Socks(..) = *p {
+                let _ = write!(proxy, "; SOCKS {}", addr);
+            }
+        }
+
+        if proxy.is_empty() {
+            proxy.push_str("DIRECT");
+        }
+
+        self.template
+            .replace("__PROXY__", &js_string(&proxy))
+            .replace("__RULES__", &self.rules)
+    }
+}
+
+fn write_rules<W: Write>(w: &mut W, name: &str, rules: &ExportedRules) -> fmt::Result {
+    writeln!(w, "var {} = {{", name)?;
+
+    w.write_str("    hosts: {")?;
+    for (idx, host) in rules.hosts.iter().enumerate() {
+        if idx > 0 {
+            w.write_str(", ")?;
+        }
+        write!(w, "{}: 1", js_string(host))?;
+    }
+    w.write_str("},\n")?;
+
+    w.write_str("    domains: {")?;
+    for (idx, domain) in rules.domains.iter().enumerate() {
+        if idx > 0 {
+            w.write_str(", ")?;
+        }
+        write!(w, "{}: 1", js_string(domain))?;
+    }
+    w.write_str("},\n")?;
+
+    // Invalid regular expressions in JavaScript are ignored
+    w.write_str("    regexes: [")?;
+    for (idx, regex) in rules.regex.iter().enumerate() {
+        if idx > 0 {
+            w.write_str(", ")?;
+        }
+        write!(
+            w,
+            "(function () {{ try {{ return new RegExp({}); }} catch (e) {{ return null; }} }})()",
+            js_string(regex)
+        )?;
+    }
+    w.write_str("],\n")?;
+
+    w.write_str("    ipv4: [")?;
+    for (idx, net) in rules.ipv4.iter().enumerate() {
+        if idx > 0 {
+            w.write_str(", ")?;
+        }
+        write!(w, "[\"{}\", \"{}\"]", net.network(), net.netmask())?;
+    }
+    w.write_str("],\n")?;
+
+    w.write_str("    ipv6: [")?;
+    for (idx, net) in rules.ipv6.iter().enumerate() {
+        if idx > 0 {
+            w.write_str(", ")?;
+        }
+        write!(w, "\"{}\"", net)?;
+    }
+    w.write_str("]\n")?;
+
+    w.write_str("};\n")
+}
+
+/// Quoted JavaScript string literal
+fn js_string(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(quoted, "\\u{:04x}", c as u32);
+            }
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
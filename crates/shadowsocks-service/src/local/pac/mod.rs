@@ -0,0 +1,11 @@
+//! PAC (Proxy Auto-Config) file server
+//!
+//! Serves a PAC file generated from the local servers and ACL rules
+
+pub use self::{
+    generator::{PacGenerator, PacProxy},
+    server::Pac,
+};
+
+mod generator;
+mod server;
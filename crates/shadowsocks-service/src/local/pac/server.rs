@@ -0,0 +1,111 @@
+//! PAC file HTTP server
+
+use std::{convert::Infallible, io, net::SocketAddr, sync::Arc, time::Duration};
+
+use hyper::{header, server::conn::Http, service::service_fn, Body, Method, Request, Response, StatusCode};
+use log::{debug, error, info, trace};
+use shadowsocks::{config::ServerAddr, lookup_then, net::TcpListener};
+use tokio::{net::TcpStream, time};
+
+use crate::local::context::ServiceContext;
+
+use super::generator::PacGenerator;
+
+/// PAC file server
+pub struct Pac {
+    context: Arc<ServiceContext>,
+    generator: Arc<PacGenerator>,
+    listener: Option<TcpListener>,
+}
+
+impl Pac {
+    /// Create with an existed context
+    pub fn with_context(context: Arc<ServiceContext>, generator: PacGenerator) -> Pac {
+        Pac {
+            context,
+            generator: Arc::new(generator),
+            listener: None,
+        }
+    }
+
+    /// Serve on a listener bound to the address of `run`, instead of binding it
+    pub fn set_listener(&mut self, listener: TcpListener) {
+        self.listener = Some(listener);
+    }
+
+    /// Run server
+    pub async fn run(mut self, client_config: &ServerAddr) -> io::Result<()> {
+        let bind_result = match (self.listener.take(), client_config) {
+            (Some(listener), ..) => Ok(listener),
+            (None, ServerAddr::SocketAddr(sa)) => {
+                TcpListener::bind_with_opts(sa, self.context.accept_opts().clone()).await
+            }
+            (None, ServerAddr::DomainName(dname, port)) => {
+                lookup_then!(self.context.context_ref(), dname, *port, |addr| {
+                    TcpListener::bind_with_opts(&addr, self.context.accept_opts().clone()).await
+                })
+                .map(|(_, b)| b)
+            }
+        };
+
+        let listener = match bind_result {
+            Ok(l) => l,
+            Err(err) => {
+                error!("PAC server bind error: {}", err);
+                return Err(err);
+            }
+        };
+
+        info!("shadowsocks PAC listening on {}", listener.local_addr()?);
+
+        loop {
+            let (stream, peer_addr) = match listener.accept().await {
+                Ok(s) => s,
+                Err(err) => {
+                    error!("PAC server accept failed with error: {}", err);
+                    time::sleep(Duration::from_secs(1)).await;
+                    continue;
+                }
+            };
+
+            tokio::spawn(serve_connection(stream, peer_addr, self.generator.clone()));
+        }
+    }
+}
+
+async fn serve_connection(stream: TcpStream, peer_addr: SocketAddr, generator: Arc<PacGenerator>) {
+    // Proxies in the PAC file are addressed by the IP clients connected to
+    let local_ip = match stream.local_addr() {
+        Ok(addr) => addr.ip(),
+        Err(err) => {
+            debug!("PAC client {} local address error: {}", peer_addr, err);
+            return;
+        }
+    };
+
+    let service = service_fn(move |req: Request<Body>| {
+        trace!("PAC request {} {} {}", peer_addr, req.method(), req.uri());
+
+        let resp = match *req.method() {
+            Method::GET | Method::HEAD => {
+                let pac = generator.generate(local_ip);
+                Response::builder()
+                    .header(header::CONTENT_TYPE, "application/x-ns-proxy-autoconfig")
+                    .header(header::CACHE_CONTROL, "no-cache")
+                    .body(Body::from(pac))
+                    .unwrap()
+            }
+            _ => Response::builder()
+                .status(StatusCode::METHOD_NOT_ALLOWED)
+                .header(header::ALLOW, "GET, HEAD")
+                .body(Body::empty())
+                .unwrap(),
+        };
+
+        async move { Ok::<_, Infallible>(resp) }
+    });
+
+    if let Err(err) = Http::new().serve_connection(stream, service).await {
+        debug!("PAC client {} connection error: {}", peer_addr, err);
+    }
+}
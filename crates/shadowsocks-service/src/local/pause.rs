@@ -0,0 +1,94 @@
+//! Pausing the data plane of local servers
+//!
+//! Host apps on mobile platforms could pause all local servers when they are moved to background or the device is
+//! dozing, and resume them later without recreating the servers. While paused, listeners stop accepting, relays and
+//! timers are not polled, and the TUN device is not read. Established connections are kept, so they may be reset by
+//! the remote peers if the pause lasts longer than their timeouts.
+
+use std::{
+    future::Future,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use log::info;
+use tokio::sync::Notify;
+
+/// Pause state shared by all contexts of local servers
+#[derive(Debug, Default)]
+pub struct PauseController {
+    paused: AtomicBool,
+    changed: Notify,
+}
+
+impl PauseController {
+    /// Create a controller in running state
+    pub fn new() -> PauseController {
+        PauseController::default()
+    }
+
+    /// Pause the data plane, does nothing if it is already paused
+    pub fn pause(&self) {
+        if !self.paused.swap(true, Ordering::AcqRel) {
+            info!("local servers paused");
+            self.changed.notify_waiters();
+        }
+    }
+
+    /// Resume the data plane, does nothing if it is not paused
+    pub fn resume(&self) {
+        if self.paused.swap(false, Ordering::AcqRel) {
+            info!("local servers resumed");
+            self.changed.notify_waiters();
+        }
+    }
+
+    /// Check if the data plane is paused
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Acquire)
+    }
+
+    /// Wait until the data plane is paused
+    pub async fn wait_paused(&self) {
+        loop {
+            let changed = self.changed.notified();
+            if self.is_paused() {
+                return;
+            }
+            changed.await;
+        }
+    }
+
+    /// Wait until the data plane is resumed, returns immediately if it is not paused
+    pub async fn wait_resumed(&self) {
+        loop {
+            let changed = self.changed.notified();
+            if !self.is_paused() {
+                return;
+            }
+            changed.await;
+        }
+    }
+
+    /// Drive `fut` only while the data plane is not paused
+    pub async fn park<F>(&self, fut: F) -> F::Output
+    where
+        F: Future,
+    {
+        tokio::pin!(fut);
+
+        loop {
+            // Created before checking the state, so changes made in between won't be missed
+            let changed = self.changed.notified();
+            if self.is_paused() {
+                changed.await;
+                continue;
+            }
+
+            tokio::select! {
+                biased;
+                _ = changed => {}
+                r = &mut fut => return r,
+            }
+        }
+    }
+}
@@ -0,0 +1,341 @@
+//! Bandwidth priority classes of TCP tunnels
+//!
+//! Tunnels are classified by their target ports. When the configured bandwidth is saturated, it is shared between
+//! classes that have traffic in proportion to their weights (weighted fair queueing), so bulk transfers couldn't
+//! starve interactive ones. A class could use all the bandwidth if the others are idle.
+//!
+//! Bytes are charged after they were transferred, a tunnel that overdrew its class waits until the debt is repaid.
+
+use std::{
+    future::Future,
+    io::{self, IoSlice},
+    pin::Pin,
+    task::{self, Poll},
+    time::Duration,
+};
+
+use log::trace;
+use pin_project::{pin_project, pinned_drop};
+use shadowsocks::relay::socks5::Address;
+use spin::Mutex as SpinMutex;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    time::{self, Instant, Sleep},
+};
+
+use crate::net::PortRange;
+
+/// Longest wait before checking a class's tokens again
+const MAX_WAIT: Duration = Duration::from_millis(100);
+
+/// A priority class
+#[derive(Debug, Clone)]
+pub struct PriorityClassConfig {
+    /// Share of the bandwidth relative to the other classes, must be > 0
+    pub weight: u32,
+    /// Tunnels to these target ports belong to this class
+    pub ports: Vec<PortRange>,
+}
+
+/// Bandwidth priority classes
+#[derive(Debug, Clone)]
+pub struct PriorityConfig {
+    /// Bandwidth of client -> target, in bytes per second
+    pub upload_bandwidth: Option<u64>,
+    /// Bandwidth of target -> client, in bytes per second
+    pub download_bandwidth: Option<u64>,
+    /// Classes are matched in order
+    pub classes: Vec<PriorityClassConfig>,
+    /// Weight of tunnels that don't match any class
+    pub default_weight: u32,
+}
+
+impl PriorityConfig {
+    /// Create a configuration without any classes
+    pub fn new() -> PriorityConfig {
+        PriorityConfig {
+            upload_bandwidth: None,
+            download_bandwidth: None,
+            classes: Vec::new(),
+            default_weight: 1,
+        }
+    }
+}
+
+impl Default for PriorityConfig {
+    fn default() -> Self {
+        PriorityConfig::new()
+    }
+}
+
+struct BucketInner {
+    last_refill: Instant,
+    /// Negative if overdrawn
+    tokens: Vec<i64>,
+    /// Tunnels waiting for tokens of each class
+    waiting: Vec<usize>,
+}
+
+/// Token bucket of one direction, refilled with the bandwidth shared by weights of the classes that have traffic
+struct Bucket {
+    rate: u64,
+    burst: i64,
+    weights: Vec<u64>,
+    inner: SpinMutex<BucketInner>,
+}
+
+impl Bucket {
+    fn new(rate: u64, weights: Vec<u64>) -> Bucket {
+        // Allows at most 20ms of bursts, so that other classes could take their share quickly
+        let burst = (rate / 50).max(16 * 1024) as i64;
+
+        Bucket {
+            rate,
+            burst,
+            inner: SpinMutex::new(BucketInner {
+                last_refill: Instant::now(),
+                tokens: vec![burst; weights.len()],
+                waiting: vec![0; weights.len()],
+            }),
+            weights,
+        }
+    }
+
+    /// Weight of classes that have traffic now, `class` is the caller
+    fn active_weight(&self, inner: &BucketInner, class: usize) -> u64 {
+        self.weights
+            .iter()
+            .enumerate()
+            .filter(|(idx, _)| *idx == class || inner.waiting[*idx] > 0)
+            .map(|(_, w)| *w)
+            .sum()
+    }
+
+    fn refill(&self, inner: &mut BucketInner, class: usize) {
+        let now = Instant::now();
+        let elapsed = now - inner.last_refill;
+
+        let total = (self.rate as u128 * elapsed.as_nanos() / 1_000_000_000) as u64;
+        if total == 0 {
+            // Keep the fraction for the next refill
+            return;
+        }
+        inner.last_refill = now;
+
+        let active_weight = self.active_weight(inner, class);
+        for (idx, weight) in self.weights.iter().enumerate() {
+            if idx != class && inner.waiting[idx] == 0 {
+                continue;
+            }
+
+            let share = (total as u128 * *weight as u128 / active_weight as u128) as i64;
+            inner.tokens[idx] = inner.tokens[idx].saturating_add(share).min(self.burst);
+        }
+    }
+
+    /// `None` if `class` could transfer now, otherwise time to wait
+    fn check(&self, class: usize) -> Option<Duration> {
+        let mut inner = self.inner.lock();
+        self.refill(&mut inner, class);
+
+        let tokens = inner.tokens[class];
+        if tokens > 0 {
+            return None;
+        }
+
+        let rate = self.rate as u128 * self.weights[class] as u128 / self.active_weight(&inner, class) as u128;
+        let wait_nanos = ((-tokens) as u128 + 1) * 1_000_000_000 / rate.max(1);
+        let wait = Duration::from_nanos(wait_nanos.min(u64::MAX as u128) as u64);
+        Some(wait.clamp(Duration::from_millis(1), MAX_WAIT))
+    }
+
+    fn consume(&self, class: usize, n: usize) {
+        let mut inner = self.inner.lock();
+        inner.tokens[class] = inner.tokens[class].saturating_sub(n as i64);
+    }
+
+    fn set_waiting(&self, class: usize, waiting: bool) {
+        let mut inner = self.inner.lock();
+        if waiting {
+            inner.waiting[class] += 1;
+        } else {
+            inner.waiting[class] -= 1;
+        }
+    }
+}
+
+/// Schedules TCP tunnels of all locals by their priority classes
+pub struct PriorityScheduler {
+    config: PriorityConfig,
+    upload: Option<Bucket>,
+    download: Option<Bucket>,
+}
+
+impl PriorityScheduler {
+    /// Create a scheduler from configuration
+    pub fn new(config: PriorityConfig) -> PriorityScheduler {
+        let mut weights = config.classes.iter().map(|c| c.weight as u64).collect::<Vec<_>>();
+        weights.push(config.default_weight as u64);
+
+        PriorityScheduler {
+            upload: config.upload_bandwidth.map(|rate| Bucket::new(rate, weights.clone())),
+            download: config.download_bandwidth.map(|rate| Bucket::new(rate, weights)),
+            config,
+        }
+    }
+
+    /// Configuration of this scheduler
+    pub fn config(&self) -> &PriorityConfig {
+        &self.config
+    }
+
+    /// Index of the class that tunnels to `target_addr` belong to
+    fn classify(&self, target_addr: &Address) -> usize {
+        let port = target_addr.port();
+        self.config
+            .classes
+            .iter()
+            .position(|c| c.ports.iter().any(|r| r.0.contains(&port)))
+            .unwrap_or(self.config.classes.len())
+    }
+
+    /// Wrap the client side stream of a TCP tunnel to `target_addr`
+    pub fn wrap_tunnel_stream<'a, S>(&'a self, stream: S, target_addr: &Address) -> PriorityStream<'a, S> {
+        let class = self.classify(target_addr);
+        trace!("tcp tunnel -> {} priority class #{}", target_addr, class);
+
+        PriorityStream {
+            stream,
+            scheduler: self,
+            class,
+            upload: Throttle::default(),
+            download: Throttle::default(),
+        }
+    }
+}
+
+/// Waiting state of one direction
+#[derive(Default)]
+struct Throttle {
+    sleep: Option<Pin<Box<Sleep>>>,
+}
+
+impl Throttle {
+    fn poll_ready(&mut self, bucket: Option<&Bucket>, class: usize, cx: &mut task::Context<'_>) -> Poll<()> {
+        let bucket = match bucket {
+            Some(b) => b,
+            None => return Poll::Ready(()),
+        };
+
+        loop {
+            if let Some(ref mut sleep) = self.sleep {
+                futures::ready!(sleep.as_mut().poll(cx));
+            }
+
+            match bucket.check(class) {
+                None => {
+                    if self.sleep.take().is_some() {
+                        bucket.set_waiting(class, false);
+                    }
+                    return Poll::Ready(());
+                }
+                Some(wait) => match self.sleep {
+                    Some(ref mut sleep) => sleep.as_mut().reset(Instant::now() + wait),
+                    None => {
+                        bucket.set_waiting(class, true);
+                        self.sleep = Some(Box::pin(time::sleep(wait)));
+                    }
+                },
+            }
+        }
+    }
+
+    fn release(&mut self, bucket: Option<&Bucket>, class: usize) {
+        if let (Some(..), Some(bucket)) = (self.sleep.take(), bucket) {
+            bucket.set_waiting(class, false);
+        }
+    }
+}
+
+/// Client side stream of a TCP tunnel, reads are charged to upload and writes are charged to download
+#[pin_project(PinnedDrop)]
+pub struct PriorityStream<'a, S> {
+    #[pin]
+    stream: S,
+    scheduler: &'a PriorityScheduler,
+    class: usize,
+    upload: Throttle,
+    download: Throttle,
+}
+
+#[pinned_drop]
+impl<'a, S> PinnedDrop for PriorityStream<'a, S> {
+    fn drop(self: Pin<&mut Self>) {
+        let this = self.project();
+        this.upload.release(this.scheduler.upload.as_ref(), *this.class);
+        this.download.release(this.scheduler.download.as_ref(), *this.class);
+    }
+}
+
+impl<'a, S> AsyncRead for PriorityStream<'a, S>
+where
+    S: AsyncRead,
+{
+    fn poll_read(self: Pin<&mut Self>, cx: &mut task::Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.project();
+        let bucket = this.scheduler.upload.as_ref();
+        futures::ready!(this.upload.poll_ready(bucket, *this.class, cx));
+
+        let filled = buf.filled().len();
+        futures::ready!(this.stream.poll_read(cx, buf))?;
+        if let Some(bucket) = bucket {
+            bucket.consume(*this.class, buf.filled().len() - filled);
+        }
+        Ok(()).into()
+    }
+}
+
+impl<'a, S> AsyncWrite for PriorityStream<'a, S>
+where
+    S: AsyncWrite,
+{
+    fn poll_write(self: Pin<&mut Self>, cx: &mut task::Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.project();
+        let bucket = this.scheduler.download.as_ref();
+        futures::ready!(this.download.poll_ready(bucket, *this.class, cx));
+
+        let n = futures::ready!(this.stream.poll_write(cx, buf))?;
+        if let Some(bucket) = bucket {
+            bucket.consume(*this.class, n);
+        }
+        Ok(n).into()
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        self.project().stream.poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        self.project().stream.poll_shutdown(cx)
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.project();
+        let bucket = this.scheduler.download.as_ref();
+        futures::ready!(this.download.poll_ready(bucket, *this.class, cx));
+
+        let n = futures::ready!(this.stream.poll_write_vectored(cx, bufs))?;
+        if let Some(bucket) = bucket {
+            bucket.consume(*this.class, n);
+        }
+        Ok(n).into()
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.stream.is_write_vectored()
+    }
+}
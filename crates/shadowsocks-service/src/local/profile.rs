@@ -0,0 +1,86 @@
+//! Switching configuration profiles at runtime
+//!
+//! A configuration file could have multiple named `profiles`, each replacing locals, servers, balancer and ACL of the
+//! configuration. Switches are requested by the web UI or signals, and carried out by the launcher of local servers,
+//! which recreates the servers with the requested profile applied. Established connections are kept.
+
+use std::io;
+
+use log::info;
+use spin::Mutex as SpinMutex;
+use tokio::sync::Notify;
+
+/// Requests of switching profiles, shared by all contexts of local servers
+#[derive(Debug)]
+pub struct ProfileSwitch {
+    profiles: Vec<String>,
+    current: Option<String>,
+    requested: SpinMutex<Option<String>>,
+    notify: Notify,
+}
+
+impl ProfileSwitch {
+    /// Create with names of all `profiles`, and the `current` applied one
+    pub fn new(profiles: Vec<String>, current: Option<String>) -> ProfileSwitch {
+        ProfileSwitch {
+            profiles,
+            current,
+            requested: SpinMutex::new(None),
+            notify: Notify::new(),
+        }
+    }
+
+    /// Names of all profiles
+    pub fn profiles(&self) -> &[String] {
+        &self.profiles
+    }
+
+    /// The applied profile, `None` if the configuration is used without profiles
+    pub fn current(&self) -> Option<&str> {
+        self.current.as_deref()
+    }
+
+    /// Request switching to profile `name`, fails if there is no such profile
+    ///
+    /// Switching to the current profile recreates the servers, which reloads the profile from the configuration file.
+    pub fn request(&self, name: &str) -> io::Result<()> {
+        if !self.profiles.iter().any(|p| p == name) {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("profile {} not found", name),
+            ));
+        }
+
+        info!("requested switching to profile {}", name);
+        *self.requested.lock() = Some(name.to_owned());
+        self.notify.notify_one();
+        Ok(())
+    }
+
+    /// Request switching to the next profile of the current one, in the order of names
+    pub fn request_next(&self) -> io::Result<()> {
+        if self.profiles.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "no profiles configured"));
+        }
+
+        let next = match self.current {
+            Some(ref current) => match self.profiles.iter().position(|p| p == current) {
+                Some(idx) => (idx + 1) % self.profiles.len(),
+                None => 0,
+            },
+            None => 0,
+        };
+        let name = self.profiles[next].clone();
+        self.request(&name)
+    }
+
+    /// Wait until a switch is requested, returns the requested profile
+    pub async fn wait_requested(&self) -> String {
+        loop {
+            if let Some(name) = self.requested.lock().take() {
+                return name;
+            }
+            self.notify.notified().await;
+        }
+    }
+}
@@ -1,6 +1,8 @@
 //! Shadowsocks Local Transparent Proxy
 
 pub use self::server::Redir;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub use self::sys::firewall::{RedirFirewallGuard, RedirFirewallRules, RedirTarget};
 
 mod redir_ext;
 mod server;
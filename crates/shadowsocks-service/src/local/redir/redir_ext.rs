@@ -19,8 +19,20 @@ use crate::config::RedirType;
 pub trait TcpListenerRedirExt {
     // Create a TcpListener for transparent proxy
     //
+    // `reuse_port` sets `SO_REUSEPORT` for binding multiple listeners to the same address
+    //
+    // Implementation is platform dependent
+    async fn bind_redir(
+        ty: RedirType,
+        addr: SocketAddr,
+        accept_opts: AcceptOpts,
+        reuse_port: bool,
+    ) -> io::Result<TcpListener>;
+
+    // Save SYN packets of accepted connections, for reading them by `TcpStreamRedirExt::syn_hop_limit`
+    //
     // Implementation is platform dependent
-    async fn bind_redir(ty: RedirType, addr: SocketAddr, accept_opts: AcceptOpts) -> io::Result<TcpListener>;
+    fn set_save_syn(&self) -> io::Result<()>;
 }
 
 /// Extension function for `TcpStream` for reading original destination address
@@ -29,6 +41,11 @@ pub trait TcpStreamRedirExt {
     //
     // Implementation is platform dependent
     fn destination_addr(&self, ty: RedirType) -> io::Result<SocketAddr>;
+
+    // Read TTL (or IPv6 hop limit) of the SYN packet, which was saved by `TcpListenerRedirExt::set_save_syn`
+    //
+    // Implementation is platform dependent
+    fn syn_hop_limit(&self) -> io::Result<u8>;
 }
 
 /// `UdpSocket` that support transparent proxy
@@ -1,15 +1,26 @@
 //! Shadowsocks Transparent Proxy Local Server
 
-use std::{io, sync::Arc, time::Duration};
+use std::{
+    io::{self, ErrorKind},
+    sync::Arc,
+    time::Duration,
+};
 
 use futures::{future, FutureExt};
+#[cfg(any(target_os = "linux", target_os = "android"))]
+use log::warn;
 use shadowsocks::{config::Mode, ServerAddr};
 
+#[cfg(any(target_os = "linux", target_os = "android"))]
+use crate::config::RedirFirewall;
 use crate::{
     config::RedirType,
-    local::{context::ServiceContext, loadbalancing::PingBalancer},
+    local::{context::ServiceContext, loadbalancing::PingBalancer, net::UdpNatConfig},
+    net::P2pPolicy,
 };
 
+#[cfg(any(target_os = "linux", target_os = "android"))]
+use super::sys::firewall::{RedirFirewallGuard, RedirFirewallRules, RedirTarget};
 use super::{tcprelay::run_tcp_redir, udprelay::UdpRedir};
 
 /// Transparent Proxy
@@ -18,8 +29,16 @@ pub struct Redir {
     mode: Mode,
     udp_expiry_duration: Option<Duration>,
     udp_capacity: Option<usize>,
+    udp_nat: UdpNatConfig,
     tcp_redir: RedirType,
     udp_redir: RedirType,
+    listeners: usize,
+    preserve_ttl: bool,
+    #[cfg(all(feature = "local-redir-ebpf", target_os = "linux"))]
+    ebpf: bool,
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    firewall: Option<RedirFirewall>,
+    p2p_policy: P2pPolicy,
 }
 
 impl Default for Redir {
@@ -42,8 +61,16 @@ impl Redir {
             mode: Mode::TcpOnly,
             udp_expiry_duration: None,
             udp_capacity: None,
+            udp_nat: UdpNatConfig::default(),
             tcp_redir: RedirType::tcp_default(),
             udp_redir: RedirType::udp_default(),
+            listeners: 1,
+            preserve_ttl: false,
+            #[cfg(all(feature = "local-redir-ebpf", target_os = "linux"))]
+            ebpf: false,
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            firewall: None,
+            p2p_policy: P2pPolicy::Allow,
         }
     }
 
@@ -57,6 +84,11 @@ impl Redir {
         self.udp_capacity = Some(c);
     }
 
+    /// Set NAT filtering and timeouts by target ports of UDP associations
+    pub fn set_udp_nat(&mut self, nat: UdpNatConfig) {
+        self.udp_nat = nat;
+    }
+
     /// Set server mode
     pub fn set_mode(&mut self, mode: Mode) {
         self.mode = mode;
@@ -72,7 +104,45 @@ impl Redir {
         self.udp_redir = ty;
     }
 
+    /// Set number of listeners binding to the same address, for both TCP and UDP relay
+    ///
+    /// Listeners are bound with `SO_REUSEPORT` if there are more than one, connections and packets will be
+    /// distributed between them by the kernel.
+    pub fn set_listeners(&mut self, n: usize) {
+        self.listeners = n;
+    }
+
+    /// Copy TTL (or IPv6 hop limit) of clients' TCP connections onto bypassed connections
+    ///
+    /// TTL is read from saved SYN packets, which is only supported on Linux
+    pub fn set_preserve_ttl(&mut self, preserve_ttl: bool) {
+        self.preserve_ttl = preserve_ttl;
+    }
+
+    /// Relay bypassed TCP connections in kernel with eBPF sockmap
+    ///
+    /// Requires Linux 5.13 or later and `CAP_NET_ADMIN` + `CAP_BPF` capabilities
+    #[cfg(all(feature = "local-redir-ebpf", target_os = "linux"))]
+    pub fn set_ebpf(&mut self, ebpf: bool) {
+        self.ebpf = ebpf;
+    }
+
+    /// Install firewall rules redirecting forwarded traffic to listeners by `install_firewall`, with `firewall`
+    ///
+    /// Destinations of this host, private ranges and servers are excluded. Requires `CAP_NET_ADMIN`
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub fn set_firewall(&mut self, firewall: RedirFirewall) {
+        self.firewall = Some(firewall);
+    }
+
+    /// Set policy of BitTorrent traffic sent through servers
+    pub fn set_p2p_policy(&mut self, p2p_policy: P2pPolicy) {
+        self.p2p_policy = p2p_policy;
+    }
+
     /// Start serving
+    ///
+    /// Firewall rules are not installed by serving, see `install_firewall`
     pub async fn run(self, tcp_addr: &ServerAddr, udp_addr: &ServerAddr, balancer: PingBalancer) -> io::Result<()> {
         let mut vfut = Vec::new();
 
@@ -88,17 +158,104 @@ impl Redir {
         res
     }
 
+    /// Install firewall rules set by `set_firewall` for listeners of `tcp_addr` and `udp_addr`
+    ///
+    /// Returns `None` if it isn't set. Rules are removed by the returned guard, which is kept by the owner of the
+    /// serving task, because serving tasks are aborted on workers of the runtime.
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub async fn install_firewall(
+        &self,
+        tcp_addr: &ServerAddr,
+        udp_addr: &ServerAddr,
+        balancer: &PingBalancer,
+    ) -> io::Result<Option<RedirFirewallGuard>> {
+        let firewall = match self.firewall {
+            Some(firewall) => firewall,
+            None => return Ok(None),
+        };
+
+        let resolve = |addr: &ServerAddr| {
+            let addr = addr.clone();
+            let context = self.context.clone();
+            async move {
+                match addr {
+                    ServerAddr::SocketAddr(sa) => Ok(sa),
+                    ServerAddr::DomainName(ref dname, port) => {
+                        let mut addrs = context.context_ref().dns_resolve(dname, port).await?;
+                        addrs
+                            .next()
+                            .ok_or_else(|| io::Error::new(ErrorKind::Other, format!("couldn't resolve {}", addr)))
+                    }
+                }
+            }
+        };
+
+        let ipv6_only = self.context.accept_opts().ipv6_only;
+        let tcp = if self.mode.enable_tcp() {
+            Some(RedirTarget {
+                ty: self.tcp_redir,
+                addr: resolve(tcp_addr).await?,
+                ipv6_only,
+            })
+        } else {
+            None
+        };
+        let udp = if self.mode.enable_udp() {
+            Some(RedirTarget {
+                ty: self.udp_redir,
+                addr: resolve(udp_addr).await?,
+                ipv6_only,
+            })
+        } else {
+            None
+        };
+
+        let port = tcp.or(udp).map(|t| t.addr.port()).unwrap_or_default();
+        let mut rules = RedirFirewallRules::new(firewall, port, tcp, udp);
+
+        // Servers added after starting are not excluded, traffic of this host isn't redirected anyway
+        for server in balancer.servers() {
+            match resolve(server.server_config().addr()).await {
+                Ok(sa) => rules.add_bypass(sa.ip()),
+                Err(err) => warn!(
+                    "redir firewall couldn't exclude server {}, {}",
+                    server.server_config().addr(),
+                    err
+                ),
+            }
+        }
+
+        match tokio::task::spawn_blocking(move || rules.install()).await {
+            Ok(r) => r.map(Some),
+            Err(err) => Err(io::Error::new(ErrorKind::Other, err)),
+        }
+    }
+
     async fn run_tcp_tunnel(&self, client_config: &ServerAddr, balancer: PingBalancer) -> io::Result<()> {
-        run_tcp_redir(self.context.clone(), client_config, balancer, self.tcp_redir).await
+        run_tcp_redir(
+            self.context.clone(),
+            client_config,
+            balancer,
+            self.tcp_redir,
+            self.listeners,
+            self.preserve_ttl,
+            #[cfg(all(feature = "local-redir-ebpf", target_os = "linux"))]
+            self.ebpf,
+            self.p2p_policy.clone(),
+        )
+        .await
     }
 
     async fn run_udp_tunnel(&self, client_config: &ServerAddr, balancer: PingBalancer) -> io::Result<()> {
-        let server = UdpRedir::new(
+        let mut server = UdpRedir::new(
             self.context.clone(),
             self.udp_redir,
             self.udp_expiry_duration,
             self.udp_capacity,
+            self.listeners,
         );
+        server.set_nat_config(self.udp_nat.clone());
+        server.set_p2p_policy(self.p2p_policy.clone());
         server.run(client_config, balancer).await
     }
 }
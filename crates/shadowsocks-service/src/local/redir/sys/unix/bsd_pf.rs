@@ -9,6 +9,7 @@ use std::{
 };
 
 use log::trace;
+use nix::errno::Errno;
 use once_cell::sync::Lazy;
 use socket2::{Protocol, SockAddr};
 
@@ -114,7 +115,7 @@ mod ffi {
         }
     }
 
-    // pub const PF_IN: libc::c_int = 1;
+    pub const PF_IN: libc::c_int = 1;
     pub const PF_OUT: libc::c_int = 2;
 
     ioctl_readwrite!(ioc_natlook, 'D', 23, pfioc_natlook);
@@ -218,10 +219,20 @@ impl PacketFilter {
             }
 
             pnl.proto = i32::from(proto) as u8;
-            pnl.direction = ffi::PF_OUT as u8;
 
+            // Connections of the local host are redirected with `rdr` rules on lo0 after `route-to`, states are
+            // created in the outbound direction. Connections from the other hosts (gateway) are redirected on the
+            // inbound interface.
+            pnl.direction = ffi::PF_OUT as u8;
             if let Err(err) = ffi::ioc_natlook(self.fd, &mut pnl as *mut _) {
-                return Err(Error::from_raw_os_error(err as i32));
+                if err != Errno::ENOENT {
+                    return Err(Error::from_raw_os_error(err as i32));
+                }
+
+                pnl.direction = ffi::PF_IN as u8;
+                if let Err(err) = ffi::ioc_natlook(self.fd, &mut pnl as *mut _) {
+                    return Err(Error::from_raw_os_error(err as i32));
+                }
             }
 
             let (_, dst_addr) = SockAddr::init(|dst_addr, addr_len| {
@@ -267,12 +278,16 @@ impl Drop for PacketFilter {
     }
 }
 
-pub static PF: Lazy<PacketFilter> = Lazy::new(|| match PacketFilter::open() {
-    Ok(pf) => pf,
-    Err(err) if err.kind() == ErrorKind::PermissionDenied => {
-        panic!("open /dev/pf permission denied, consider restart with root user");
-    }
-    Err(err) => {
-        panic!("open /dev/pf {}", err);
+static PF: Lazy<io::Result<PacketFilter>> = Lazy::new(PacketFilter::open);
+
+/// Shared `/dev/pf` handle, opened on the first call
+pub fn packet_filter() -> io::Result<&'static PacketFilter> {
+    match *PF {
+        Ok(ref pf) => Ok(pf),
+        Err(ref err) if err.kind() == ErrorKind::PermissionDenied => Err(Error::new(
+            ErrorKind::PermissionDenied,
+            "open /dev/pf permission denied, consider restart with root user",
+        )),
+        Err(ref err) => Err(Error::new(err.kind(), format!("open /dev/pf {}", err))),
     }
-});
+}
@@ -0,0 +1,524 @@
+//! Firewall rules of transparent proxy, installed by `iptables` or `nftables`
+//!
+//! Traffic forwarded through this host (`PREROUTING`) is redirected to the listeners, except destinations of this
+//! host, private ranges and servers. Traffic of this host itself (`OUTPUT`) is not redirected, so connections made by
+//! sslocal couldn't loop back.
+//!
+//! `TPROXY` requires a policy routing rule delivering marked packets to the local host, which is added by `ip`. The
+//! mark and the routing table are `0x53530000` plus the TCP port of the listener, for example, port 60080:
+//!
+//! ```plain
+//! ip rule add fwmark 0x5353eab0 lookup 1398008496
+//! ip route add local 0.0.0.0/0 dev lo table 1398008496
+//! ```
+//!
+//! Rules are named by the TCP port of the listener, so rules of other processes are not touched. Stale rules left by a
+//! killed process are replaced when installing.
+
+use std::{
+    fmt::{self, Write},
+    io::{self, Error, ErrorKind, Write as _},
+    net::{IpAddr, SocketAddr},
+    process::{Command, Stdio},
+};
+
+use ipnet::IpNet;
+use log::{debug, info, warn};
+use tokio::task;
+
+use crate::config::{RedirFirewall, RedirType};
+
+/// Mark of packets delivered to the local host by `TPROXY`, and the routing table delivering them, plus the port
+const TPROXY_MARK_BASE: u32 = 0x5353_0000;
+
+/// Destinations that are never redirected, besides addresses of this host
+const BYPASS_V4: &[&str] = &[
+    "0.0.0.0/8",
+    "10.0.0.0/8",
+    "100.64.0.0/10",
+    "127.0.0.0/8",
+    "169.254.0.0/16",
+    "172.16.0.0/12",
+    "192.168.0.0/16",
+    "224.0.0.0/4",
+    "240.0.0.0/4",
+];
+const BYPASS_V6: &[&str] = &["::/128", "::1/128", "fc00::/7", "fe80::/10", "ff00::/8"];
+
+/// A listener that traffic is redirected to
+#[derive(Debug, Clone, Copy)]
+pub struct RedirTarget {
+    /// Transparent proxy type of the listener, `Redirect` or `TProxy`
+    pub ty: RedirType,
+    /// Listening address, `TPROXY` delivers packets to its IP if it is not unspecified
+    pub addr: SocketAddr,
+    /// Unspecified IPv6 listening address doesn't accept IPv4
+    pub ipv6_only: bool,
+}
+
+impl RedirTarget {
+    fn has_family(&self, v6: bool) -> bool {
+        match self.addr.ip() {
+            IpAddr::V4(..) => !v6,
+            IpAddr::V6(ip) => v6 || (ip.is_unspecified() && !self.ipv6_only),
+        }
+    }
+
+    fn tproxy_ip(&self, v6: bool) -> Option<IpAddr> {
+        let ip = self.addr.ip();
+        if ip.is_unspecified() || ip.is_ipv6() != v6 {
+            None
+        } else {
+            Some(ip)
+        }
+    }
+}
+
+/// Rules of TCP and UDP listeners of a transparent proxy
+#[derive(Debug, Clone)]
+pub struct RedirFirewallRules {
+    firewall: RedirFirewall,
+    name: String,
+    mark: u32,
+    tcp: Option<RedirTarget>,
+    udp: Option<RedirTarget>,
+    bypass: Vec<IpNet>,
+}
+
+impl RedirFirewallRules {
+    /// Create rules redirecting TCP to `tcp` and UDP to `udp`, named by `port`
+    pub fn new(firewall: RedirFirewall, port: u16, tcp: Option<RedirTarget>, udp: Option<RedirTarget>) -> Self {
+        let bypass = BYPASS_V4
+            .iter()
+            .chain(BYPASS_V6)
+            .map(|net| net.parse::<IpNet>().expect("bypass network"))
+            .collect();
+
+        RedirFirewallRules {
+            firewall,
+            name: match firewall {
+                RedirFirewall::Iptables => format!("SS_REDIR_{}", port),
+                RedirFirewall::Nftables => format!("shadowsocks_redir_{}", port),
+            },
+            mark: TPROXY_MARK_BASE + u32::from(port),
+            tcp,
+            udp,
+            bypass,
+        }
+    }
+
+    /// Exclude destination `ip`, which is usually a server's address
+    pub fn add_bypass(&mut self, ip: IpAddr) {
+        let net = IpNet::from(ip);
+        if !self.bypass.iter().any(|n| n.contains(&ip)) {
+            self.bypass.push(net);
+        }
+    }
+
+    /// Mark of `TPROXY` packets, which is also the routing table delivering them to the local host
+    pub fn tproxy_mark(&self) -> u32 {
+        self.mark
+    }
+
+    /// Command lines installing the rules, the ruleset of `nftables` is read from the standard input
+    pub fn install_command_lines(&self) -> Vec<String> {
+        self.install_commands().iter().map(ToString::to_string).collect()
+    }
+
+    /// Install rules, which are removed by `RedirFirewallGuard::uninstall`, or when the returned guard is dropped
+    ///
+    /// Commands are run synchronously, this should be called in a blocking context.
+    pub fn install(self) -> io::Result<RedirFirewallGuard> {
+        // Stale rules of the same name
+        self.remove();
+
+        for cmd in self.install_commands() {
+            if let Err(err) = cmd.run() {
+                if !cmd.required {
+                    warn!("redir firewall {}, skipped", err);
+                    continue;
+                }
+                self.remove();
+                return Err(err);
+            }
+        }
+
+        info!("redir firewall {} rules {} installed", self.firewall, self.name);
+        Ok(RedirFirewallGuard { rules: Some(self) })
+    }
+
+    fn remove(&self) {
+        for cmd in self.remove_commands() {
+            if let Err(err) = cmd.run() {
+                debug!("redir firewall {}", err);
+            }
+        }
+    }
+
+    fn families(&self) -> impl Iterator<Item = bool> + '_ {
+        [false, true]
+            .into_iter()
+            .filter(move |v6| self.targets().any(|(_, target)| target.has_family(*v6)))
+    }
+
+    fn targets(&self) -> impl Iterator<Item = (&'static str, &RedirTarget)> {
+        self.tcp
+            .iter()
+            .map(|t| ("tcp", t))
+            .chain(self.udp.iter().map(|t| ("udp", t)))
+    }
+
+    fn has_type(&self, ty: RedirType) -> bool {
+        self.targets().any(|(_, target)| target.ty == ty)
+    }
+
+    fn bypass_nets(&self, v6: bool) -> impl Iterator<Item = &IpNet> {
+        self.bypass.iter().filter(move |net| matches!(net, IpNet::V6(..)) == v6)
+    }
+
+    fn install_commands(&self) -> Vec<FirewallCommand> {
+        let mut cmds = Vec::new();
+
+        match self.firewall {
+            RedirFirewall::Iptables => {
+                for v6 in self.families() {
+                    // IPv6 is optional, ip6tables may not be available
+                    let required = !v6;
+                    let program = if v6 { "ip6tables" } else { "iptables" };
+
+                    for (table, ty) in [("nat", RedirType::Redirect), ("mangle", RedirType::TProxy)] {
+                        // Chains without targets of the family are not created
+                        if !self
+                            .targets()
+                            .any(|(_, target)| target.ty == ty && target.has_family(v6))
+                        {
+                            continue;
+                        }
+
+                        cmds.push(FirewallCommand::new(
+                            program,
+                            &["-w", "-t", table, "-N", &self.name],
+                            required,
+                        ));
+                        let append = |args: &[&str]| {
+                            let mut full = vec!["-w", "-t", table, "-A", &self.name];
+                            full.extend_from_slice(args);
+                            FirewallCommand::new(program, &full, required)
+                        };
+
+                        cmds.push(append(&["-m", "addrtype", "--dst-type", "LOCAL", "-j", "RETURN"]));
+                        for net in self.bypass_nets(v6) {
+                            cmds.push(append(&["-d", &net.to_string(), "-j", "RETURN"]));
+                        }
+
+                        for (proto, target) in self.targets() {
+                            if target.ty != ty || !target.has_family(v6) {
+                                continue;
+                            }
+
+                            let port = target.addr.port().to_string();
+                            if ty == RedirType::Redirect {
+                                cmds.push(append(&["-p", proto, "-j", "REDIRECT", "--to-ports", &port]));
+                            } else {
+                                let mark = format!("{:#x}", self.mark);
+                                let mut args = vec!["-p", proto, "-j", "TPROXY", "--on-port", &port];
+                                let on_ip = target.tproxy_ip(v6).map(|ip| ip.to_string());
+                                if let Some(ref on_ip) = on_ip {
+                                    args.extend_from_slice(&["--on-ip", on_ip]);
+                                }
+                                args.extend_from_slice(&["--tproxy-mark", &mark]);
+                                cmds.push(append(&args));
+                            }
+                        }
+
+                        cmds.push(FirewallCommand::new(
+                            program,
+                            &["-w", "-t", table, "-A", "PREROUTING", "-j", &self.name],
+                            required,
+                        ));
+                    }
+                }
+            }
+            RedirFirewall::Nftables => {
+                cmds.push(FirewallCommand::with_input(
+                    "nft",
+                    &["-f", "-"],
+                    self.nftables_ruleset(),
+                ));
+            }
+        }
+
+        if self.has_type(RedirType::TProxy) {
+            for v6 in self.families() {
+                let family = if v6 { "-6" } else { "-4" };
+                let dst = if v6 { "::/0" } else { "0.0.0.0/0" };
+                let mark = format!("{:#x}", self.mark);
+                let table = self.mark.to_string();
+                // Rules left by the other instances listening on the same port are reused
+                cmds.push(
+                    FirewallCommand::new("ip", &[family, "rule", "add", "fwmark", &mark, "lookup", &table], !v6)
+                        .exists_ok(),
+                );
+                cmds.push(
+                    FirewallCommand::new(
+                        "ip",
+                        &[family, "route", "add", "local", dst, "dev", "lo", "table", &table],
+                        !v6,
+                    )
+                    .exists_ok(),
+                );
+            }
+        }
+
+        cmds
+    }
+
+    fn remove_commands(&self) -> Vec<FirewallCommand> {
+        let mut cmds = Vec::new();
+
+        match self.firewall {
+            RedirFirewall::Iptables => {
+                for v6 in self.families() {
+                    let program = if v6 { "ip6tables" } else { "iptables" };
+                    for (table, ty) in [("nat", RedirType::Redirect), ("mangle", RedirType::TProxy)] {
+                        if !self.has_type(ty) {
+                            continue;
+                        }
+                        for op in ["-D", "-F", "-X"] {
+                            let args: &[&str] = if op == "-D" {
+                                &["-w", "-t", table, "-D", "PREROUTING", "-j", &self.name]
+                            } else {
+                                &["-w", "-t", table, op, &self.name]
+                            };
+                            cmds.push(FirewallCommand::new(program, args, false));
+                        }
+                    }
+                }
+            }
+            RedirFirewall::Nftables => {
+                cmds.push(FirewallCommand::new(
+                    "nft",
+                    &["delete", "table", "inet", &self.name],
+                    false,
+                ));
+            }
+        }
+
+        if self.has_type(RedirType::TProxy) {
+            for v6 in self.families() {
+                let family = if v6 { "-6" } else { "-4" };
+                let dst = if v6 { "::/0" } else { "0.0.0.0/0" };
+                let mark = format!("{:#x}", self.mark);
+                let table = self.mark.to_string();
+                cmds.push(FirewallCommand::new(
+                    "ip",
+                    &[family, "rule", "del", "fwmark", &mark, "lookup", &table],
+                    false,
+                ));
+                cmds.push(FirewallCommand::new(
+                    "ip",
+                    &[family, "route", "del", "local", dst, "dev", "lo", "table", &table],
+                    false,
+                ));
+            }
+        }
+
+        cmds
+    }
+
+    /// Ruleset of `nftables`, a table of all the rules
+    pub fn nftables_ruleset(&self) -> String {
+        let mut ruleset = String::new();
+        let mut bypass_rules = String::from("\t\tfib daddr type local return\n");
+
+        for v6 in [false, true] {
+            let (set, ty, proto) = if v6 {
+                ("bypass_v6", "ipv6_addr", "ip6")
+            } else {
+                ("bypass_v4", "ipv4_addr", "ip")
+            };
+            let elements = self
+                .bypass_nets(v6)
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join(", ");
+            let _ = writeln!(
+                ruleset,
+                "\tset {} {{\n\t\ttype {}\n\t\tflags interval\n\t\telements = {{ {} }}\n\t}}",
+                set, ty, elements
+            );
+            let _ = writeln!(bypass_rules, "\t\t{} daddr @{} return", proto, set);
+        }
+
+        for (chain, ty, hook) in [
+            (
+                "prerouting_redirect",
+                RedirType::Redirect,
+                "type nat hook prerouting priority dstnat",
+            ),
+            (
+                "prerouting_tproxy",
+                RedirType::TProxy,
+                "type filter hook prerouting priority mangle",
+            ),
+        ] {
+            if !self.has_type(ty) {
+                continue;
+            }
+
+            let _ = write!(
+                ruleset,
+                "\tchain {} {{\n\t\t{}; policy accept;\n{}",
+                chain, hook, bypass_rules
+            );
+            for v6 in self.families() {
+                let (nfproto, family) = if v6 { ("ipv6", "ip6") } else { ("ipv4", "ip") };
+                for (proto, target) in self.targets() {
+                    if target.ty != ty || !target.has_family(v6) {
+                        continue;
+                    }
+
+                    let port = target.addr.port();
+                    if ty == RedirType::Redirect {
+                        let _ = writeln!(
+                            ruleset,
+                            "\t\tmeta nfproto {} meta l4proto {} redirect to :{}",
+                            nfproto, proto, port
+                        );
+                    } else {
+                        let to = match target.tproxy_ip(v6) {
+                            Some(ip) => SocketAddr::new(ip, port).to_string(),
+                            None => format!(":{}", port),
+                        };
+                        let _ = writeln!(
+                            ruleset,
+                            "\t\tmeta nfproto {} meta l4proto {} meta mark set {:#x} tproxy {} to {} accept",
+                            nfproto, proto, self.mark, family, to
+                        );
+                    }
+                }
+            }
+            ruleset.push_str("\t}\n");
+        }
+
+        format!(
+            "table inet {name} {{\n{ruleset}}}\n",
+            name = self.name,
+            ruleset = ruleset
+        )
+    }
+}
+
+/// Installed rules
+///
+/// Rules should be removed by `uninstall`, they are removed when it is dropped otherwise, blocking the current thread,
+/// so it shouldn't be dropped on workers of the runtime.
+#[derive(Debug)]
+pub struct RedirFirewallGuard {
+    rules: Option<RedirFirewallRules>,
+}
+
+impl RedirFirewallGuard {
+    /// Remove the rules, commands are run in a blocking thread
+    pub async fn uninstall(mut self) {
+        if let Some(rules) = self.rules.take() {
+            if let Err(err) = task::spawn_blocking(move || RedirFirewallGuard::remove(rules)).await {
+                warn!("redir firewall failed to remove rules, {}", err);
+            }
+        }
+    }
+
+    fn remove(rules: RedirFirewallRules) {
+        rules.remove();
+        info!("redir firewall {} rules {} removed", rules.firewall, rules.name);
+    }
+}
+
+impl Drop for RedirFirewallGuard {
+    fn drop(&mut self) {
+        // Dropped without uninstalling, like servers are dropped without waiting on exit
+        if let Some(rules) = self.rules.take() {
+            RedirFirewallGuard::remove(rules);
+        }
+    }
+}
+
+struct FirewallCommand {
+    program: &'static str,
+    args: Vec<String>,
+    input: Option<String>,
+    required: bool,
+    // Succeeds if the rule exists, `ip` fails with EEXIST
+    exists_ok: bool,
+}
+
+impl FirewallCommand {
+    fn new(program: &'static str, args: &[&str], required: bool) -> FirewallCommand {
+        FirewallCommand {
+            program,
+            args: args.iter().map(ToString::to_string).collect(),
+            input: None,
+            required,
+            exists_ok: false,
+        }
+    }
+
+    fn exists_ok(mut self) -> FirewallCommand {
+        self.exists_ok = true;
+        self
+    }
+
+    fn with_input(program: &'static str, args: &[&str], input: String) -> FirewallCommand {
+        let mut cmd = FirewallCommand::new(program, args, true);
+        cmd.input = Some(input);
+        cmd
+    }
+
+    fn run(&self) -> io::Result<()> {
+        debug!("redir firewall running {}", self);
+
+        let mut child = Command::new(self.program)
+            .args(&self.args)
+            .stdin(if self.input.is_some() {
+                Stdio::piped()
+            } else {
+                Stdio::null()
+            })
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|err| Error::new(err.kind(), format!("failed to run {}, {}", self.program, err)))?;
+
+        if let (Some(input), Some(mut stdin)) = (self.input.as_ref(), child.stdin.take()) {
+            stdin.write_all(input.as_bytes())?;
+        }
+
+        let output = child.wait_with_output()?;
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        if output.status.success() {
+            Ok(())
+        } else if self.exists_ok && stderr.contains("File exists") {
+            debug!("redir firewall {} exists", self);
+            Ok(())
+        } else {
+            let err = format!(
+                "{} {} failed with {}, {}",
+                self.program,
+                self.args.join(" "),
+                output.status,
+                stderr.trim()
+            );
+            Err(Error::new(ErrorKind::Other, err))
+        }
+    }
+}
+
+impl fmt::Display for FirewallCommand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.program)?;
+        for arg in &self.args {
+            write!(f, " {}", arg)?;
+        }
+        Ok(())
+    }
+}
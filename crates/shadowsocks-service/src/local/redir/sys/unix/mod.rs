@@ -9,3 +9,8 @@ cfg_if! {
         pub mod bsd_pf;
     }
 }
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub mod firewall;
+#[cfg(all(feature = "local-redir-ebpf", target_os = "linux"))]
+pub mod sockmap;
@@ -0,0 +1,425 @@
+//! In-kernel relay of bypassed connections with eBPF sockmap
+//!
+//! Sockets of both sides of a connection are inserted into a `BPF_MAP_TYPE_SOCKHASH` map, keyed by the socket
+//! cookie of their peers. A `BPF_SK_SKB_STREAM_VERDICT` program attached to the map redirects data received by a
+//! socket to the send queue of its peer, without copying it to the userspace.
+//!
+//! Data that couldn't be redirected (for example, received before both sockets were inserted) is passed to the
+//! socket's receive queue, and has to be relayed by the userspace as usual. The userspace relay is also responsible
+//! for detecting EOF and shutting down the peer, see `copy_spliced_bidirectional`.
+//!
+//! Requires Linux 5.13 or later (stream verdict programs without stream parsers), and `CAP_NET_ADMIN` + `CAP_BPF`
+//! (or `CAP_SYS_ADMIN`).
+
+use std::{
+    io::{self, Error, ErrorKind},
+    mem,
+    os::unix::io::{AsRawFd, RawFd},
+    time::Duration,
+};
+
+use futures::future;
+use log::trace;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt},
+    time,
+};
+
+const BPF_MAP_CREATE: libc::c_int = 0;
+const BPF_MAP_UPDATE_ELEM: libc::c_int = 2;
+const BPF_MAP_DELETE_ELEM: libc::c_int = 3;
+const BPF_PROG_LOAD: libc::c_int = 5;
+const BPF_PROG_ATTACH: libc::c_int = 8;
+
+const BPF_MAP_TYPE_SOCKHASH: u32 = 18;
+const BPF_PROG_TYPE_SK_SKB: u32 = 14;
+const BPF_SK_SKB_STREAM_VERDICT: u32 = 5;
+
+const BPF_ANY: u64 = 0;
+const BPF_PSEUDO_MAP_FD: u8 = 1;
+
+const BPF_FUNC_GET_SOCKET_COOKIE: i32 = 46;
+const BPF_FUNC_SK_REDIRECT_HASH: i32 = 72;
+
+const SK_PASS: i32 = 1;
+
+/// Maximum number of sockets in the map. Connections are relayed by the userspace if the map is full.
+const SOCKMAP_CAPACITY: u32 = 65536;
+
+const PROG_LICENSE: &[u8] = b"Dual MIT/GPL\0";
+
+/// Interval of checking whether redirected data was sent, before shutting down the peer
+const REDIRECT_FLUSH_INTERVAL: Duration = Duration::from_millis(1);
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct BpfInsn {
+    code: u8,
+    regs: u8,
+    off: i16,
+    imm: i32,
+}
+
+impl BpfInsn {
+    const fn new(code: u8, dst: u8, src: u8, off: i16, imm: i32) -> BpfInsn {
+        BpfInsn {
+            code,
+            regs: (src << 4) | (dst & 0x0f),
+            off,
+            imm,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct BpfMapCreateAttr {
+    map_type: u32,
+    key_size: u32,
+    value_size: u32,
+    max_entries: u32,
+    map_flags: u32,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct BpfMapElemAttr {
+    map_fd: u32,
+    _pad: u32,
+    key: u64,
+    value: u64,
+    flags: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct BpfProgLoadAttr {
+    prog_type: u32,
+    insn_cnt: u32,
+    insns: u64,
+    license: u64,
+    log_level: u32,
+    log_size: u32,
+    log_buf: u64,
+    kern_version: u32,
+    prog_flags: u32,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct BpfProgAttachAttr {
+    target_fd: u32,
+    attach_bpf_fd: u32,
+    attach_type: u32,
+    attach_flags: u32,
+}
+
+fn bpf<T>(cmd: libc::c_int, attr: &mut T) -> io::Result<libc::c_long> {
+    let ret = unsafe { libc::syscall(libc::SYS_bpf, cmd, attr as *mut T, mem::size_of::<T>() as libc::c_uint) };
+    if ret < 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(ret)
+}
+
+/// Verdict program, in pseudo C:
+///
+/// ```c
+/// int verdict(struct __sk_buff *skb) {
+///     __u64 cookie = bpf_get_socket_cookie(skb);
+///     bpf_sk_redirect_hash(skb, &sockmap, &cookie, 0);
+///     return SK_PASS;
+/// }
+/// ```
+///
+/// Data is passed to the receiving socket if the redirection failed.
+fn verdict_program(map_fd: RawFd) -> [BpfInsn; 12] {
+    [
+        // r6 = r1 (skb)
+        BpfInsn::new(0xbf, 6, 1, 0, 0),
+        // r0 = bpf_get_socket_cookie(skb)
+        BpfInsn::new(0x85, 0, 0, 0, BPF_FUNC_GET_SOCKET_COOKIE),
+        // *(u64 *)(r10 - 8) = r0
+        BpfInsn::new(0x7b, 10, 0, -8, 0),
+        // r1 = r6
+        BpfInsn::new(0xbf, 1, 6, 0, 0),
+        // r2 = map_fd (ld_imm64)
+        BpfInsn::new(0x18, 2, BPF_PSEUDO_MAP_FD, 0, map_fd),
+        BpfInsn::new(0x00, 0, 0, 0, 0),
+        // r3 = r10 - 8
+        BpfInsn::new(0xbf, 3, 10, 0, 0),
+        BpfInsn::new(0x07, 3, 0, 0, -8),
+        // r4 = 0, redirect to the peer's send queue
+        BpfInsn::new(0xb7, 4, 0, 0, 0),
+        // bpf_sk_redirect_hash(skb, map, &cookie, 0)
+        BpfInsn::new(0x85, 0, 0, 0, BPF_FUNC_SK_REDIRECT_HASH),
+        // return SK_PASS
+        BpfInsn::new(0xb7, 0, 0, 0, SK_PASS),
+        BpfInsn::new(0x95, 0, 0, 0, 0),
+    ]
+}
+
+fn socket_cookie<S: AsRawFd>(socket: &S) -> io::Result<u64> {
+    let mut cookie: u64 = 0;
+    let mut len = mem::size_of::<u64>() as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_COOKIE,
+            &mut cookie as *mut _ as *mut _,
+            &mut len,
+        )
+    };
+    if ret != 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(cookie)
+}
+
+struct Fd(RawFd);
+
+impl Drop for Fd {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.0);
+        }
+    }
+}
+
+/// Sockmap relaying data between pairs of sockets in kernel
+pub struct SockMap {
+    map: Fd,
+    _prog: Fd,
+}
+
+impl SockMap {
+    /// Create the sockmap and attach the verdict program to it
+    pub fn new() -> io::Result<SockMap> {
+        let mut attr = BpfMapCreateAttr {
+            map_type: BPF_MAP_TYPE_SOCKHASH,
+            key_size: mem::size_of::<u64>() as u32,
+            value_size: mem::size_of::<u32>() as u32,
+            max_entries: SOCKMAP_CAPACITY,
+            ..Default::default()
+        };
+        let map = Fd(bpf(BPF_MAP_CREATE, &mut attr)? as RawFd);
+
+        let insns = verdict_program(map.0);
+        let mut log_buf = vec![0u8; 4096];
+        let mut attr = BpfProgLoadAttr {
+            prog_type: BPF_PROG_TYPE_SK_SKB,
+            insn_cnt: insns.len() as u32,
+            insns: insns.as_ptr() as u64,
+            license: PROG_LICENSE.as_ptr() as u64,
+            log_level: 1,
+            log_size: log_buf.len() as u32,
+            log_buf: log_buf.as_mut_ptr() as u64,
+            ..Default::default()
+        };
+        let prog = match bpf(BPF_PROG_LOAD, &mut attr) {
+            Ok(fd) => Fd(fd as RawFd),
+            Err(err) => {
+                let log_len = log_buf.iter().position(|b| *b == 0).unwrap_or(log_buf.len());
+                let log = String::from_utf8_lossy(&log_buf[..log_len]);
+                let err = Error::new(
+                    err.kind(),
+                    format!(
+                        "failed to load sockmap verdict program, error: {}, log: {}",
+                        err,
+                        log.trim()
+                    ),
+                );
+                return Err(err);
+            }
+        };
+
+        let mut attr = BpfProgAttachAttr {
+            target_fd: map.0 as u32,
+            attach_bpf_fd: prog.0 as u32,
+            attach_type: BPF_SK_SKB_STREAM_VERDICT,
+            ..Default::default()
+        };
+        bpf(BPF_PROG_ATTACH, &mut attr)?;
+
+        Ok(SockMap { map, _prog: prog })
+    }
+
+    /// Relay data between `a` and `b` in kernel
+    ///
+    /// Both of them must be connected TCP sockets. They are removed from the map when the returned guard is dropped,
+    /// or when they are closed.
+    pub fn splice<A, B>(&self, a: &A, b: &B) -> io::Result<SockMapGuard<'_>>
+    where
+        A: AsRawFd,
+        B: AsRawFd,
+    {
+        let a_cookie = socket_cookie(a)?;
+        let b_cookie = socket_cookie(b)?;
+
+        let mut guard = SockMapGuard {
+            map: self,
+            cookies: Vec::with_capacity(2),
+        };
+
+        // Data received by `a` is redirected to `b`, and vice versa
+        self.insert(a_cookie, b.as_raw_fd())?;
+        guard.cookies.push(a_cookie);
+        self.insert(b_cookie, a.as_raw_fd())?;
+        guard.cookies.push(b_cookie);
+
+        trace!("sockmap spliced sockets {} <-> {}", a_cookie, b_cookie);
+
+        Ok(guard)
+    }
+
+    fn insert(&self, cookie: u64, fd: RawFd) -> io::Result<()> {
+        let value = fd as u32;
+        let mut attr = BpfMapElemAttr {
+            map_fd: self.map.0 as u32,
+            key: &cookie as *const _ as u64,
+            value: &value as *const _ as u64,
+            flags: BPF_ANY,
+            ..Default::default()
+        };
+        bpf(BPF_MAP_UPDATE_ELEM, &mut attr).map(|_| ())
+    }
+
+    fn remove(&self, cookie: u64) -> io::Result<()> {
+        let mut attr = BpfMapElemAttr {
+            map_fd: self.map.0 as u32,
+            key: &cookie as *const _ as u64,
+            ..Default::default()
+        };
+        match bpf(BPF_MAP_DELETE_ELEM, &mut attr) {
+            Ok(..) => Ok(()),
+            // Already removed because the socket was closed
+            Err(ref err) if err.kind() == ErrorKind::NotFound => Ok(()),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+/// Sockets spliced by `SockMap::splice`, removed from the map when dropped
+pub struct SockMapGuard<'a> {
+    map: &'a SockMap,
+    cookies: Vec<u64>,
+}
+
+impl Drop for SockMapGuard<'_> {
+    fn drop(&mut self) {
+        for cookie in self.cookies.iter() {
+            if let Err(err) = self.map.remove(*cookie) {
+                trace!("sockmap failed to remove socket {}, error: {}", cookie, err);
+            }
+        }
+    }
+}
+
+/// Prefix of `struct tcp_info`, until `tcpi_bytes_received` (Linux 4.1)
+#[repr(C)]
+struct TcpInfo {
+    state: u8,
+    _u8s: [u8; 7],
+    _u32s: [u32; 24],
+    _pacing_rate: u64,
+    _max_pacing_rate: u64,
+    bytes_acked: u64,
+    bytes_received: u64,
+}
+
+const TCP_ESTABLISHED: u8 = 1;
+const TCP_CLOSE_WAIT: u8 = 8;
+
+fn tcp_info(fd: RawFd) -> io::Result<TcpInfo> {
+    unsafe {
+        let mut info: TcpInfo = mem::zeroed();
+        let mut len = mem::size_of::<TcpInfo>() as libc::socklen_t;
+        let ret = libc::getsockopt(
+            fd,
+            libc::IPPROTO_TCP,
+            libc::TCP_INFO,
+            &mut info as *mut _ as *mut _,
+            &mut len,
+        );
+        if ret != 0 {
+            return Err(Error::last_os_error());
+        }
+        if (len as usize) < mem::size_of::<TcpInfo>() {
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                "tcp_info doesn't have tcpi_bytes_received",
+            ));
+        }
+        Ok(info)
+    }
+}
+
+/// Bytes that were written to the send queue of `fd`, by the userspace or by redirection
+fn tcp_bytes_queued(fd: RawFd) -> io::Result<u64> {
+    let info = tcp_info(fd)?;
+
+    let mut outq: libc::c_int = 0;
+    let ret = unsafe { libc::ioctl(fd, libc::TIOCOUTQ, &mut outq) };
+    if ret != 0 {
+        return Err(Error::last_os_error());
+    }
+
+    Ok(info.bytes_acked + outq as u64)
+}
+
+/// Wait until all data received by `src` was written to the send queue of `dst`, after `src`'s EOF
+///
+/// Redirected data is sent by a kernel worker asynchronously, `dst` mustn't be shut down before that.
+async fn wait_redirected(src: RawFd, dst: RawFd) -> io::Result<()> {
+    // FIN is also counted
+    let received = tcp_info(src)?.bytes_received.saturating_sub(1);
+
+    loop {
+        let info = tcp_info(dst)?;
+        if info.state != TCP_ESTABLISHED && info.state != TCP_CLOSE_WAIT {
+            // Peer is gone, nothing could be sent anymore
+            return Ok(());
+        }
+
+        if tcp_bytes_queued(dst)? >= received {
+            return Ok(());
+        }
+
+        time::sleep(REDIRECT_FLUSH_INTERVAL).await;
+    }
+}
+
+async fn copy_spliced<R, W>(src: RawFd, reader: &mut R, dst: RawFd, writer: &mut W) -> io::Result<u64>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    // Only data that wasn't redirected could be read here
+    let n = tokio::io::copy(reader, writer).await?;
+
+    wait_redirected(src, dst).await?;
+    writer.shutdown().await?;
+
+    Ok(n)
+}
+
+/// Relay data of sockets spliced by `SockMap::splice` that wasn't redirected, and shut down each side after the
+/// other side's EOF.
+///
+/// Returns bytes relayed by the userspace, `(a to b, b to a)`.
+pub async fn copy_spliced_bidirectional<A, B>(a: &mut A, b: &mut B) -> io::Result<(u64, u64)>
+where
+    A: AsyncRead + AsyncWrite + AsRawFd + Unpin,
+    B: AsyncRead + AsyncWrite + AsRawFd + Unpin,
+{
+    let (a_fd, b_fd) = (a.as_raw_fd(), b.as_raw_fd());
+    let (mut ar, mut aw) = tokio::io::split(a);
+    let (mut br, mut bw) = tokio::io::split(b);
+
+    future::try_join(
+        copy_spliced(a_fd, &mut ar, b_fd, &mut bw),
+        copy_spliced(b_fd, &mut br, a_fd, &mut aw),
+    )
+    .await
+}
@@ -7,6 +7,7 @@ use std::{
     time::Duration,
 };
 
+use futures::{future, FutureExt};
 use log::{debug, error, info, trace};
 use shadowsocks::{lookup_then, net::TcpListener as ShadowTcpListener, relay::socks5::Address, ServerAddr};
 use tokio::{
@@ -14,6 +15,10 @@ use tokio::{
     time,
 };
 
+#[cfg(all(feature = "local-redir-ebpf", target_os = "linux"))]
+use crate::local::redir::sys::sockmap::{copy_spliced_bidirectional, SockMap};
+#[cfg(feature = "acl")]
+use crate::{acl::RejectMode, local::utils::respond_rejected};
 use crate::{
     config::RedirType,
     local::{
@@ -23,6 +28,8 @@ use crate::{
         redir::redir_ext::{TcpListenerRedirExt, TcpStreamRedirExt},
         utils::{establish_tcp_tunnel, to_ipv4_mapped},
     },
+    log_fields,
+    net::{ConcurrencyKind, P2pPolicy},
 };
 
 mod sys;
@@ -36,13 +43,85 @@ async fn establish_client_tcp_redir<'a>(
     mut stream: TcpStream,
     peer_addr: SocketAddr,
     addr: &Address,
+    ttl: Option<u8>,
+    #[cfg(all(feature = "local-redir-ebpf", target_os = "linux"))] sockmap: Option<Arc<SockMap>>,
+    p2p_policy: P2pPolicy,
 ) -> io::Result<()> {
-    let server = balancer.best_tcp_server();
-    let svr_cfg = server.server_config();
+    let server = balancer.best_tcp_server_for(peer_addr, addr).await?;
 
-    let mut remote = AutoProxyClientStream::connect(context, &server, addr).await?;
+    let _tunnel_guard = context.concurrency_stat().acquire(ConcurrencyKind::TcpTunnel, 1);
+    let mut connection_guard = context.register_connection(ConcurrencyKind::TcpTunnel, peer_addr, Some(addr));
+    let mut remote = match AutoProxyClientStream::connect_with_ttl(context.clone(), &server, peer_addr, addr, ttl).await
+    {
+        Ok(remote) => remote,
+        Err(err) => {
+            #[cfg(feature = "acl")]
+            match AutoProxyClientStream::rejected_mode(&err) {
+                Some(RejectMode::Drop) => return Ok(()),
+                Some(RejectMode::Reset) => {
+                    // Closing with zero linger time sends RST
+                    return stream.set_linger(Some(Duration::ZERO));
+                }
+                Some(RejectMode::Response) => return respond_rejected(&mut stream, peer_addr, addr).await,
+                Some(RejectMode::Refuse) | None => {}
+            }
+            return Err(err);
+        }
+    };
+
+    // Bypassed connections are relayed in kernel, proxied connections have to be encrypted by us.
+    #[cfg(all(feature = "local-redir-ebpf", target_os = "linux"))]
+    if let (Some(sockmap), AutoProxyClientStream::Bypassed(ref mut r)) = (sockmap.as_deref(), &mut remote) {
+        match sockmap.splice(&stream, r) {
+            Ok(_guard) => {
+                debug!(
+                    "established tcp tunnel {} <-> {} bypassed, spliced by sockmap",
+                    peer_addr, addr
+                );
 
-    establish_tcp_tunnel(svr_cfg, &mut stream, &mut remote, peer_addr, addr).await
+                match copy_spliced_bidirectional(&mut stream, r).await {
+                    Ok((rn, wn)) => {
+                        trace!(
+                            "tcp tunnel {} <-> {} (spliced) closed, L2R {} bytes, R2L {} bytes",
+                            peer_addr,
+                            addr,
+                            rn,
+                            wn
+                        );
+                    }
+                    Err(err) => {
+                        trace!(
+                            "tcp tunnel {} <-> {} (spliced) closed with error: {}",
+                            peer_addr,
+                            addr,
+                            err
+                        );
+                    }
+                }
+
+                return Ok(());
+            }
+            Err(err) => {
+                debug!(
+                    "TCP redirect {} <-> {} couldn't be spliced by sockmap, error: {}",
+                    peer_addr, addr, err
+                );
+            }
+        }
+    }
+
+    establish_tcp_tunnel(
+        &context,
+        &balancer,
+        &p2p_policy,
+        &server,
+        &mut stream,
+        &mut remote,
+        peer_addr,
+        addr,
+        connection_guard.as_mut(),
+    )
+    .await
 }
 
 async fn handle_redir_client(
@@ -51,6 +130,9 @@ async fn handle_redir_client(
     s: TcpStream,
     peer_addr: SocketAddr,
     mut daddr: SocketAddr,
+    ttl: Option<u8>,
+    #[cfg(all(feature = "local-redir-ebpf", target_os = "linux"))] sockmap: Option<Arc<SockMap>>,
+    p2p_policy: P2pPolicy,
 ) -> io::Result<()> {
     // Get forward address from socket
     //
@@ -60,35 +142,101 @@ async fn handle_redir_client(
             daddr = SocketAddr::new(IpAddr::from(v4), a.port());
         }
     }
-    let target_addr = Address::from(daddr);
-    establish_client_tcp_redir(context, balancer, s, peer_addr, &target_addr).await
+    let target_addr = context.map_fake_ip(Address::from(daddr));
+    establish_client_tcp_redir(
+        context,
+        balancer,
+        s,
+        peer_addr,
+        &target_addr,
+        ttl,
+        #[cfg(all(feature = "local-redir-ebpf", target_os = "linux"))]
+        sockmap,
+        p2p_policy,
+    )
+    .await
 }
 
+/// Run TCP transparent proxy with `listeners` listeners binding to `client_config`
+///
+/// Listeners are bound with `SO_REUSEPORT` if there are more than one
 pub async fn run_tcp_redir(
     context: Arc<ServiceContext>,
     client_config: &ServerAddr,
     balancer: PingBalancer,
     redir_ty: RedirType,
+    listeners: usize,
+    preserve_ttl: bool,
+    #[cfg(all(feature = "local-redir-ebpf", target_os = "linux"))] ebpf: bool,
+    p2p_policy: P2pPolicy,
 ) -> io::Result<()> {
-    let listener = match *client_config {
-        ServerAddr::SocketAddr(ref saddr) => TcpListener::bind_redir(redir_ty, *saddr, context.accept_opts()).await?,
-        ServerAddr::DomainName(ref dname, port) => {
-            lookup_then!(context.context_ref(), dname, port, |addr| {
-                TcpListener::bind_redir(redir_ty, addr, context.accept_opts()).await
-            })?
-            .1
-        }
+    let reuse_port = listeners > 1;
+
+    // Shared by all listeners
+    #[cfg(all(feature = "local-redir-ebpf", target_os = "linux"))]
+    let sockmap = if ebpf {
+        let sockmap = SockMap::new()?;
+        info!("shadowsocks TCP redirect relays bypassed connections with eBPF sockmap");
+        Some(Arc::new(sockmap))
+    } else {
+        None
     };
 
-    let listener = ShadowTcpListener::from_listener(listener, context.accept_opts());
+    let mut vfut = Vec::with_capacity(listeners);
+    for _ in 0..listeners {
+        let listener = match *client_config {
+            ServerAddr::SocketAddr(ref saddr) => {
+                TcpListener::bind_redir(redir_ty, *saddr, context.accept_opts(), reuse_port).await?
+            }
+            ServerAddr::DomainName(ref dname, port) => {
+                lookup_then!(context.context_ref(), dname, port, |addr| {
+                    TcpListener::bind_redir(redir_ty, addr, context.accept_opts(), reuse_port).await
+                })?
+                .1
+            }
+        };
+
+        if preserve_ttl {
+            listener.set_save_syn()?;
+        }
 
-    let actual_local_addr = listener.local_addr().expect("determine port bound to");
+        let listener = ShadowTcpListener::from_listener(listener, context.accept_opts());
+
+        let actual_local_addr = listener.local_addr().expect("determine port bound to");
+
+        info!(
+            "shadowsocks TCP redirect ({}) listening on {}",
+            redir_ty, actual_local_addr
+        );
+
+        vfut.push(
+            serve_tcp_redir(
+                context.clone(),
+                listener,
+                balancer.clone(),
+                redir_ty,
+                preserve_ttl,
+                #[cfg(all(feature = "local-redir-ebpf", target_os = "linux"))]
+                sockmap.clone(),
+                p2p_policy.clone(),
+            )
+            .boxed(),
+        );
+    }
 
-    info!(
-        "shadowsocks TCP redirect ({}) listening on {}",
-        redir_ty, actual_local_addr
-    );
+    let (res, ..) = future::select_all(vfut).await;
+    res
+}
 
+async fn serve_tcp_redir(
+    context: Arc<ServiceContext>,
+    listener: ShadowTcpListener,
+    balancer: PingBalancer,
+    redir_ty: RedirType,
+    preserve_ttl: bool,
+    #[cfg(all(feature = "local-redir-ebpf", target_os = "linux"))] sockmap: Option<Arc<SockMap>>,
+    p2p_policy: P2pPolicy,
+) -> io::Result<()> {
     loop {
         let (socket, peer_addr) = match listener.accept().await {
             Ok(s) => s,
@@ -103,7 +251,10 @@ pub async fn run_tcp_redir(
 
         let context = context.clone();
         let balancer = balancer.clone();
-        tokio::spawn(async move {
+        #[cfg(all(feature = "local-redir-ebpf", target_os = "linux"))]
+        let sockmap = sockmap.clone();
+        let p2p_policy = p2p_policy.clone();
+        log_fields::spawn_connection(peer_addr, async move {
             let dst_addr = match socket.destination_addr(redir_ty) {
                 Ok(d) => d,
                 Err(err) => {
@@ -115,7 +266,35 @@ pub async fn run_tcp_redir(
                 }
             };
 
-            if let Err(err) = handle_redir_client(context, balancer, socket, peer_addr, dst_addr).await {
+            // Bypassed connections are sent with the TTL of client's SYN
+            let ttl = if preserve_ttl {
+                match socket.syn_hop_limit() {
+                    Ok(ttl) => Some(ttl),
+                    Err(err) => {
+                        debug!(
+                            "TCP redirect couldn't get TTL of SYN, peer: {}, error: {}",
+                            peer_addr, err
+                        );
+                        None
+                    }
+                }
+            } else {
+                None
+            };
+
+            if let Err(err) = handle_redir_client(
+                context,
+                balancer,
+                socket,
+                peer_addr,
+                dst_addr,
+                ttl,
+                #[cfg(all(feature = "local-redir-ebpf", target_os = "linux"))]
+                sockmap,
+                p2p_policy,
+            )
+            .await
+            {
                 debug!("TCP redirect client, error: {:?}", err);
             }
         });
@@ -19,7 +19,12 @@ use crate::{
 
 #[async_trait]
 impl TcpListenerRedirExt for TcpListener {
-    async fn bind_redir(ty: RedirType, addr: SocketAddr, accept_opts: AcceptOpts) -> io::Result<TcpListener> {
+    async fn bind_redir(
+        ty: RedirType,
+        addr: SocketAddr,
+        accept_opts: AcceptOpts,
+        reuse_port: bool,
+    ) -> io::Result<TcpListener> {
         match ty {
             #[cfg(any(
                 target_os = "openbsd",
@@ -29,7 +34,10 @@ impl TcpListenerRedirExt for TcpListener {
                 target_os = "macos",
                 target_os = "ios",
             ))]
-            RedirType::PacketFilter => {}
+            RedirType::PacketFilter => {
+                // Fail early if /dev/pf couldn't be opened, instead of failing on every connections
+                crate::local::redir::sys::bsd_pf::packet_filter()?;
+            }
 
             #[cfg(any(
                 target_os = "freebsd",
@@ -62,6 +70,9 @@ impl TcpListenerRedirExt for TcpListener {
         // https://docs.microsoft.com/en-us/windows/win32/winsock/using-so-reuseaddr-and-so-exclusiveaddruse
         #[cfg(unix)]
         socket.set_reuseaddr(true)?;
+        if reuse_port {
+            socket.set_reuseport(true)?;
+        }
 
         let set_dual_stack = is_dual_stack_addr(&addr);
         if set_dual_stack {
@@ -83,6 +94,13 @@ impl TcpListenerRedirExt for TcpListener {
 
         Ok(listener)
     }
+
+    fn set_save_syn(&self) -> io::Result<()> {
+        Err(Error::new(
+            ErrorKind::Unsupported,
+            "saving SYN packets requires TCP_SAVE_SYN, which is only supported on Linux",
+        ))
+    }
 }
 
 impl TcpStreamRedirExt for TcpStream {
@@ -97,12 +115,12 @@ impl TcpStreamRedirExt for TcpStream {
                 target_os = "ios",
             ))]
             RedirType::PacketFilter => {
-                use crate::local::redir::sys::bsd_pf::PF;
+                use crate::local::redir::sys::bsd_pf::packet_filter;
 
                 let peer_addr = self.peer_addr()?;
                 let bind_addr = self.local_addr()?;
 
-                PF.natlook(&bind_addr, &peer_addr, Protocol::TCP)
+                packet_filter()?.natlook(&bind_addr, &peer_addr, Protocol::TCP)
             }
             #[cfg(any(
                 target_os = "freebsd",
@@ -121,4 +139,11 @@ impl TcpStreamRedirExt for TcpStream {
             _ => unreachable!("not supported tcp transparent proxy type"),
         }
     }
+
+    fn syn_hop_limit(&self) -> io::Result<u8> {
+        Err(Error::new(
+            ErrorKind::Unsupported,
+            "reading SYN packets requires TCP_SAVED_SYN, which is only supported on Linux",
+        ))
+    }
 }
@@ -13,15 +13,23 @@ use tokio::net::{TcpListener, TcpSocket, TcpStream};
 
 use crate::{
     config::RedirType,
-    local::redir::{
-        redir_ext::{TcpListenerRedirExt, TcpStreamRedirExt},
-        sys::set_ipv6_only,
+    local::{
+        redir::{
+            redir_ext::{TcpListenerRedirExt, TcpStreamRedirExt},
+            sys::set_ipv6_only,
+        },
+        utils::to_ipv4_mapped,
     },
 };
 
 #[async_trait]
 impl TcpListenerRedirExt for TcpListener {
-    async fn bind_redir(ty: RedirType, addr: SocketAddr, accept_opts: AcceptOpts) -> io::Result<TcpListener> {
+    async fn bind_redir(
+        ty: RedirType,
+        addr: SocketAddr,
+        accept_opts: AcceptOpts,
+        reuse_port: bool,
+    ) -> io::Result<TcpListener> {
         match ty {
             RedirType::Redirect => {
                 // REDIRECT rule doesn't need to set IP_TRANSPARENT
@@ -40,12 +48,15 @@ impl TcpListenerRedirExt for TcpListener {
                 // https://docs.microsoft.com/en-us/windows/win32/winsock/using-so-reuseaddr-and-so-exclusiveaddruse
                 #[cfg(unix)]
                 socket.set_reuseaddr(true)?;
+                if reuse_port {
+                    socket.set_reuseport(true)?;
+                }
 
                 let set_dual_stack = is_dual_stack_addr(&addr);
                 if set_dual_stack {
-                    // Transparent socket shouldn't support dual-stack.
-
-                    if let Err(err) = set_ipv6_only(&socket, true) {
+                    // Original destinations of IPv4 connections accepted by dual-stack sockets
+                    // are recovered by get_original_destination_addr with SOL_IP.
+                    if let Err(err) = set_ipv6_only(&socket, accept_opts.ipv6_only) {
                         warn!("failed to set IPV6_V6ONLY, error: {}", err);
                     }
                 }
@@ -63,7 +74,7 @@ impl TcpListenerRedirExt for TcpListener {
             }
             RedirType::TProxy => {
                 // TPROXY rule requires IP_TRANSPARENT
-                create_redir_listener(addr, accept_opts).await
+                create_redir_listener(addr, accept_opts, reuse_port).await
             }
             _ => Err(Error::new(
                 ErrorKind::InvalidInput,
@@ -71,6 +82,24 @@ impl TcpListenerRedirExt for TcpListener {
             )),
         }
     }
+
+    fn set_save_syn(&self) -> io::Result<()> {
+        // Linux 4.2+ TCP_SAVE_SYN
+        unsafe {
+            let enable: libc::c_int = 1;
+            let ret = libc::setsockopt(
+                self.as_raw_fd(),
+                libc::IPPROTO_TCP,
+                libc::TCP_SAVE_SYN,
+                &enable as *const _ as *const _,
+                mem::size_of_val(&enable) as libc::socklen_t,
+            );
+            if ret != 0 {
+                return Err(Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
 }
 
 impl TcpStreamRedirExt for TcpStream {
@@ -84,41 +113,61 @@ impl TcpStreamRedirExt for TcpStream {
             _ => unreachable!("not supported tcp transparent proxy type"),
         }
     }
+
+    fn syn_hop_limit(&self) -> io::Result<u8> {
+        // TCP_SAVED_SYN returns the IP and TCP headers of the SYN packet, it could only be read once.
+        // The buffer must be large enough for all headers, including IP options and IPv6 extension headers.
+        let mut header = [0u8; 512];
+        let mut header_len = header.len() as libc::socklen_t;
+        unsafe {
+            let ret = libc::getsockopt(
+                self.as_raw_fd(),
+                libc::IPPROTO_TCP,
+                libc::TCP_SAVED_SYN,
+                header.as_mut_ptr() as *mut _,
+                &mut header_len,
+            );
+            if ret != 0 {
+                return Err(Error::last_os_error());
+            }
+        }
+
+        let header = &header[..header_len as usize];
+        match header.first().map(|b| b >> 4) {
+            Some(4) if header.len() >= 20 => Ok(header[8]),
+            Some(6) if header.len() >= 40 => Ok(header[7]),
+            _ => Err(Error::new(ErrorKind::InvalidData, "SYN packet not saved")),
+        }
+    }
 }
 
 fn get_original_destination_addr(s: &TcpStream) -> io::Result<SocketAddr> {
     let fd = s.as_raw_fd();
 
+    // IPv4 connections accepted by dual-stack sockets have IPv4-mapped IPv6 local addresses,
+    // their original destinations are tracked by IPv4's conntrack
+    let is_ipv4 = match s.local_addr()? {
+        SocketAddr::V4(..) => true,
+        SocketAddr::V6(ref a) => to_ipv4_mapped(a.ip()).is_some(),
+    };
+    let (level, optname) = if is_ipv4 {
+        (libc::SOL_IP, libc::SO_ORIGINAL_DST)
+    } else {
+        (libc::SOL_IPV6, libc::IP6T_SO_ORIGINAL_DST)
+    };
+
     unsafe {
         let (_, target_addr) = SockAddr::init(|target_addr, target_addr_len| {
-            match s.local_addr()? {
-                SocketAddr::V4(..) => {
-                    let ret = libc::getsockopt(
-                        fd,
-                        libc::SOL_IP,
-                        libc::SO_ORIGINAL_DST,
-                        target_addr as *mut _,
-                        target_addr_len, // libc::socklen_t
-                    );
-                    if ret != 0 {
-                        let err = Error::last_os_error();
-                        return Err(err);
-                    }
-                }
-                SocketAddr::V6(..) => {
-                    let ret = libc::getsockopt(
-                        fd,
-                        libc::SOL_IPV6,
-                        libc::IP6T_SO_ORIGINAL_DST,
-                        target_addr as *mut _,
-                        target_addr_len, // libc::socklen_t
-                    );
-
-                    if ret != 0 {
-                        let err = Error::last_os_error();
-                        return Err(err);
-                    }
-                }
+            let ret = libc::getsockopt(
+                fd,
+                level,
+                optname,
+                target_addr as *mut _,
+                target_addr_len, // libc::socklen_t
+            );
+            if ret != 0 {
+                let err = Error::last_os_error();
+                return Err(err);
             }
             Ok(())
         })?;
@@ -128,7 +177,7 @@ fn get_original_destination_addr(s: &TcpStream) -> io::Result<SocketAddr> {
     }
 }
 
-async fn create_redir_listener(addr: SocketAddr, accept_opts: AcceptOpts) -> io::Result<TcpListener> {
+async fn create_redir_listener(addr: SocketAddr, accept_opts: AcceptOpts, reuse_port: bool) -> io::Result<TcpListener> {
     let socket = match addr {
         SocketAddr::V4(..) => TcpSocket::new_v4()?,
         SocketAddr::V6(..) => TcpSocket::new_v6()?,
@@ -171,12 +220,15 @@ async fn create_redir_listener(addr: SocketAddr, accept_opts: AcceptOpts) -> io:
     // https://docs.microsoft.com/en-us/windows/win32/winsock/using-so-reuseaddr-and-so-exclusiveaddruse
     #[cfg(unix)]
     socket.set_reuseaddr(true)?;
+    if reuse_port {
+        socket.set_reuseport(true)?;
+    }
 
     let set_dual_stack = is_dual_stack_addr(&addr);
     if set_dual_stack {
-        // Transparent socket shouldn't support dual-stack.
-
-        if let Err(err) = set_ipv6_only(&socket, true) {
+        // IPV6_TRANSPARENT also applies to IPv4 connections accepted by dual-stack sockets,
+        // which could be redirected by nftables' tproxy in `inet` tables
+        if let Err(err) = set_ipv6_only(&socket, accept_opts.ipv6_only) {
             warn!("failed to set IPV6_V6ONLY, error: {}", err);
         }
     }
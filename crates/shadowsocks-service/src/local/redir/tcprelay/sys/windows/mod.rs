@@ -14,17 +14,30 @@ use crate::{
 
 #[async_trait]
 impl TcpListenerRedirExt for TcpListener {
-    async fn bind_redir(_ty: RedirType, _addr: SocketAddr, _accept_opts: AcceptOpts) -> io::Result<TcpListener> {
+    async fn bind_redir(
+        _ty: RedirType,
+        _addr: SocketAddr,
+        _accept_opts: AcceptOpts,
+        _reuse_port: bool,
+    ) -> io::Result<TcpListener> {
         let err = Error::new(
             ErrorKind::InvalidInput,
             "not supported tcp transparent proxy on Windows",
         );
         Err(err)
     }
+
+    fn set_save_syn(&self) -> io::Result<()> {
+        unreachable!("not supported tcp transparent on Windows")
+    }
 }
 
 impl TcpStreamRedirExt for TcpStream {
     fn destination_addr(&self, _ty: RedirType) -> io::Result<SocketAddr> {
         unreachable!("not supported tcp transparent on Windows")
     }
+
+    fn syn_hop_limit(&self) -> io::Result<u8> {
+        unreachable!("not supported tcp transparent on Windows")
+    }
 }
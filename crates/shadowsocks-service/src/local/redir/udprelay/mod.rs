@@ -8,6 +8,7 @@ use std::{
 };
 
 use async_trait::async_trait;
+use futures::{future, FutureExt};
 use log::{error, info, trace, warn};
 use lru_time_cache::LruCache;
 use shadowsocks::{
@@ -23,10 +24,11 @@ use crate::{
     local::{
         context::ServiceContext,
         loadbalancing::PingBalancer,
-        net::{UdpAssociationManager, UdpInboundWrite},
+        net::{UdpAssociationManager, UdpInboundWrite, UdpNatConfig},
         redir::redir_ext::{RedirSocketOpts, UdpSocketRedirExt},
         utils::to_ipv4_mapped,
     },
+    net::P2pPolicy,
 };
 
 use self::sys::UdpRedirSocket;
@@ -73,11 +75,13 @@ struct UdpRedirInboundWriter {
     redir_ty: RedirType,
     socket_opts: RedirSocketOpts,
     inbound_cache: Arc<UdpRedirInboundCache>,
+    #[cfg(target_os = "macos")]
+    listener: Arc<UdpRedirSocket>,
 }
 
 impl UdpRedirInboundWriter {
     #[allow(unused_variables, clippy::needless_update)]
-    fn new(redir_ty: RedirType, opts: &ConnectOpts) -> UdpRedirInboundWriter {
+    fn new(redir_ty: RedirType, opts: &ConnectOpts, listener: &Arc<UdpRedirSocket>) -> UdpRedirInboundWriter {
         UdpRedirInboundWriter {
             redir_ty,
             socket_opts: RedirSocketOpts {
@@ -87,6 +91,36 @@ impl UdpRedirInboundWriter {
                 ..Default::default()
             },
             inbound_cache: Arc::new(UdpRedirInboundCache::new()),
+            #[cfg(target_os = "macos")]
+            listener: listener.clone(),
+        }
+    }
+
+    /// Socket for sending responses from `addr`
+    async fn inbound_socket(&self, addr: SocketAddr) -> io::Result<Arc<UdpRedirSocket>> {
+        // pf translates sources of responses back to the original destinations with states created by `rdr` rules,
+        // responses have to be sent from the address that packets were redirected to.
+        #[cfg(target_os = "macos")]
+        if self.redir_ty == RedirType::PacketFilter {
+            return Ok(self.listener.clone());
+        }
+
+        let mut cache = self.inbound_cache.cache.lock().await;
+        if let Some(socket) = cache.get(&addr) {
+            Ok(socket.clone())
+        } else {
+            // Create a socket binds to destination addr
+            // This only works for systems that supports binding to non-local addresses
+            //
+            // This socket has to set SO_REUSEADDR and SO_REUSEPORT.
+            // Outbound addresses could be connected from different source addresses.
+            let inbound = UdpRedirSocket::bind_nonlocal(self.redir_ty, addr, &self.socket_opts)?;
+
+            // UDP socket could be shared between threads and is safe to be manipulated by multiple threads
+            let inbound = Arc::new(inbound);
+            cache.insert(addr, inbound.clone());
+
+            Ok(inbound)
         }
     }
 }
@@ -114,25 +148,7 @@ impl UdpInboundWrite for UdpRedirInboundWriter {
             }
         };
 
-        let inbound = {
-            let mut cache = self.inbound_cache.cache.lock().await;
-            if let Some(socket) = cache.get(&addr) {
-                socket.clone()
-            } else {
-                // Create a socket binds to destination addr
-                // This only works for systems that supports binding to non-local addresses
-                //
-                // This socket has to set SO_REUSEADDR and SO_REUSEPORT.
-                // Outbound addresses could be connected from different source addresses.
-                let inbound = UdpRedirSocket::bind_nonlocal(self.redir_ty, addr, &self.socket_opts)?;
-
-                // UDP socket could be shared between threads and is safe to be manipulated by multiple threads
-                let inbound = Arc::new(inbound);
-                cache.insert(addr, inbound.clone());
-
-                inbound
-            }
-        };
+        let inbound = self.inbound_socket(addr).await?;
 
         // Send back to client
         inbound.send_to(data, peer_addr).await.map(|n| {
@@ -162,6 +178,9 @@ pub struct UdpRedir {
     redir_ty: RedirType,
     time_to_live: Option<Duration>,
     capacity: Option<usize>,
+    nat: UdpNatConfig,
+    listeners: usize,
+    p2p_policy: P2pPolicy,
 }
 
 impl UdpRedir {
@@ -170,40 +189,75 @@ impl UdpRedir {
         redir_ty: RedirType,
         time_to_live: Option<Duration>,
         capacity: Option<usize>,
+        listeners: usize,
     ) -> UdpRedir {
         UdpRedir {
             context,
             redir_ty,
             time_to_live,
             capacity,
+            nat: UdpNatConfig::default(),
+            listeners,
+            p2p_policy: P2pPolicy::Allow,
         }
     }
 
+    /// Set NAT filtering and timeouts by target ports of associations
+    pub fn set_nat_config(&mut self, nat: UdpNatConfig) {
+        self.nat = nat;
+    }
+
+    /// Set policy of BitTorrent traffic sent through servers
+    pub fn set_p2p_policy(&mut self, p2p_policy: P2pPolicy) {
+        self.p2p_policy = p2p_policy;
+    }
+
     pub async fn run(&self, client_config: &ServerAddr, balancer: PingBalancer) -> io::Result<()> {
-        let listener = match *client_config {
-            ServerAddr::SocketAddr(ref saddr) => UdpRedirSocket::listen(self.redir_ty, *saddr)?,
-            ServerAddr::DomainName(ref dname, port) => {
-                lookup_then!(self.context.context_ref(), dname, port, |addr| {
-                    UdpRedirSocket::listen(self.redir_ty, addr)
-                })?
-                .1
-            }
-        };
+        let accept_opts = self.context.accept_opts();
+        let reuse_port = self.listeners > 1;
+
+        let mut vfut = Vec::with_capacity(self.listeners);
+        for _ in 0..self.listeners {
+            let listener = match *client_config {
+                ServerAddr::SocketAddr(ref saddr) => {
+                    UdpRedirSocket::listen(self.redir_ty, *saddr, &accept_opts, reuse_port)?
+                }
+                ServerAddr::DomainName(ref dname, port) => {
+                    lookup_then!(self.context.context_ref(), dname, port, |addr| {
+                        UdpRedirSocket::listen(self.redir_ty, addr, &accept_opts, reuse_port)
+                    })?
+                    .1
+                }
+            };
 
-        let local_addr = listener.local_addr().expect("determine port bound to");
-        info!(
-            "shadowsocks UDP redirect ({}) listening on {}",
-            self.redir_ty, local_addr
-        );
+            let local_addr = listener.local_addr().expect("determine port bound to");
+            info!(
+                "shadowsocks UDP redirect ({}) listening on {}",
+                self.redir_ty, local_addr
+            );
+
+            // Each listener has its own associations, packets of the same client will always be received by the
+            // same listener, because SO_REUSEPORT distributes by hashing the source and destination addresses
+            vfut.push(self.serve(listener, balancer.clone()).boxed());
+        }
+
+        let (res, ..) = future::select_all(vfut).await;
+        res
+    }
+
+    async fn serve(&self, listener: UdpRedirSocket, balancer: PingBalancer) -> io::Result<()> {
+        let listener = Arc::new(listener);
 
         #[allow(clippy::needless_update)]
         let (mut manager, cleanup_interval, mut keepalive_rx) = UdpAssociationManager::new(
             self.context.clone(),
-            UdpRedirInboundWriter::new(self.redir_ty, self.context.connect_opts_ref()),
+            UdpRedirInboundWriter::new(self.redir_ty, self.context.connect_opts_ref(), &listener),
             self.time_to_live,
             self.capacity,
+            self.nat.clone(),
             balancer,
         );
+        manager.set_p2p_policy(self.p2p_policy.clone());
 
         let mut pkt_buf = [0u8; MAXIMUM_UDP_PAYLOAD_SIZE];
         let mut cleanup_timer = time::interval(cleanup_interval);
@@ -220,8 +274,8 @@ impl UdpRedir {
                     manager.keep_alive(&peer_addr).await;
                 }
 
-                recv_result = listener.recv_dest_from(&mut pkt_buf) => {
-                    let (recv_len, src, mut dst) = match recv_result {
+                recv_result = listener.as_ref().recv_dest_from(&mut pkt_buf) => {
+                    let (recv_len, mut src, mut dst) = match recv_result {
                         Ok(o) => o,
                         Err(err) => {
                             error!("recv_dest_from failed with err: {}", err);
@@ -252,11 +306,17 @@ impl UdpRedir {
                     }
 
                     // Try to convert IPv4 mapped IPv6 address for dual-stack mode.
+                    // Responses are sent back by sockets binding to `dst`, so `src` must be in the same family.
                     if let SocketAddr::V6(ref a) = dst {
                         if let Some(v4) = to_ipv4_mapped(a.ip()) {
                             dst = SocketAddr::new(IpAddr::from(v4), a.port());
                         }
                     }
+                    if let SocketAddr::V6(ref a) = src {
+                        if let Some(v4) = to_ipv4_mapped(a.ip()) {
+                            src = SocketAddr::new(IpAddr::from(v4), a.port());
+                        }
+                    }
 
                     if let Err(err) = manager.send_to(src, Address::from(dst), pkt).await {
                         error!(
@@ -9,7 +9,7 @@ use std::{
 
 use async_trait::async_trait;
 use futures::{future::poll_fn, ready};
-use shadowsocks::net::is_dual_stack_addr;
+use shadowsocks::net::{is_dual_stack_addr, AcceptOpts};
 use socket2::{Domain, Protocol, SockAddr, Socket, Type};
 use tokio::io::unix::AsyncFd;
 
@@ -33,8 +33,13 @@ impl UdpRedirSocket {
     /// Create a new UDP socket binded to `addr`
     ///
     /// This will allow listening to `addr` that is not in local host
-    pub fn listen(ty: RedirType, addr: SocketAddr) -> io::Result<UdpRedirSocket> {
-        UdpRedirSocket::bind(ty, addr, false)
+    pub fn listen(
+        ty: RedirType,
+        addr: SocketAddr,
+        _accept_opts: &AcceptOpts,
+        reuse_port: bool,
+    ) -> io::Result<UdpRedirSocket> {
+        UdpRedirSocket::bind(ty, addr, reuse_port)
     }
 
     /// Create a new UDP socket binded to `addr`
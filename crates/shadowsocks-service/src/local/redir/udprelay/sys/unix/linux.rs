@@ -10,7 +10,7 @@ use std::{
 use cfg_if::cfg_if;
 use futures::{future::poll_fn, ready};
 use log::warn;
-use shadowsocks::net::is_dual_stack_addr;
+use shadowsocks::net::{is_dual_stack_addr, AcceptOpts};
 use socket2::{Domain, Protocol, SockAddr, Socket, Type};
 use tokio::io::unix::AsyncFd;
 
@@ -30,15 +30,22 @@ impl UdpRedirSocket {
     /// Create a new UDP socket binded to `addr`
     ///
     /// This will allow listening to `addr` that is not in local host
-    pub fn listen(ty: RedirType, addr: SocketAddr) -> io::Result<UdpRedirSocket> {
-        UdpRedirSocket::bind(ty, addr, false)
+    ///
+    /// `reuse_port` sets `SO_REUSEPORT` for binding multiple listeners to the same address
+    pub fn listen(
+        ty: RedirType,
+        addr: SocketAddr,
+        accept_opts: &AcceptOpts,
+        reuse_port: bool,
+    ) -> io::Result<UdpRedirSocket> {
+        UdpRedirSocket::bind(ty, addr, reuse_port, accept_opts.ipv6_only)
     }
 
     /// Create a new UDP socket binded to `addr`
     ///
     /// This will allow binding to `addr` that is not in local host
     pub fn bind_nonlocal(ty: RedirType, addr: SocketAddr, redir_opts: &RedirSocketOpts) -> io::Result<UdpRedirSocket> {
-        let socket = UdpRedirSocket::bind(ty, addr, true)?;
+        let socket = UdpRedirSocket::bind(ty, addr, true, true)?;
 
         if let Some(mark) = redir_opts.fwmark {
             let ret = unsafe {
@@ -58,7 +65,7 @@ impl UdpRedirSocket {
         Ok(socket)
     }
 
-    fn bind(ty: RedirType, addr: SocketAddr, reuse_port: bool) -> io::Result<UdpRedirSocket> {
+    fn bind(ty: RedirType, addr: SocketAddr, reuse_port: bool, ipv6_only: bool) -> io::Result<UdpRedirSocket> {
         if ty != RedirType::TProxy {
             return Err(Error::new(
                 ErrorKind::InvalidInput,
@@ -66,8 +73,10 @@ impl UdpRedirSocket {
             ));
         }
 
+        let dual_stack = is_dual_stack_addr(&addr) && !ipv6_only;
+
         let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
-        set_socket_before_bind(&addr, &socket)?;
+        set_socket_before_bind(&addr, &socket, dual_stack)?;
 
         socket.set_nonblocking(true)?;
         socket.set_reuse_address(true)?;
@@ -76,9 +85,7 @@ impl UdpRedirSocket {
         }
 
         if is_dual_stack_addr(&addr) {
-            // Transparent socket shouldn't support dual-stack.
-
-            if let Err(err) = set_ipv6_only(&socket, true) {
+            if let Err(err) = set_ipv6_only(&socket, !dual_stack) {
                 warn!("failed to set IPV6_V6ONLY, error: {}", err);
             }
         }
@@ -138,7 +145,7 @@ impl AsRawFd for UdpRedirSocket {
     }
 }
 
-fn set_socket_before_bind(addr: &SocketAddr, socket: &Socket) -> io::Result<()> {
+fn set_socket_before_bind(addr: &SocketAddr, socket: &Socket, dual_stack: bool) -> io::Result<()> {
     let fd = socket.as_raw_fd();
 
     let enable: libc::c_int = 1;
@@ -184,6 +191,20 @@ fn set_socket_before_bind(addr: &SocketAddr, socket: &Socket) -> io::Result<()>
         if ret != 0 {
             return Err(Error::last_os_error());
         }
+
+        // 3. Dual-stack sockets receive IPv4 packets' original destinations with IP_RECVORIGDSTADDR
+        if dual_stack {
+            let ret = libc::setsockopt(
+                fd,
+                libc::SOL_IP,
+                libc::IP_RECVORIGDSTADDR,
+                &enable as *const _ as *const _,
+                mem::size_of_val(&enable) as libc::socklen_t,
+            );
+            if ret != 0 {
+                return Err(Error::last_os_error());
+            }
+        }
     }
 
     Ok(())
@@ -1,52 +1,234 @@
+//! UDP transparent proxy with PacketFilter on macOS
+//!
+//! Packets are redirected to the listener with `rdr` rules, original destinations are looked up from pf's states
+//! with `DIOCNATLOOK`. Responses are sent by the listener, pf translates their sources back to the original
+//! destinations.
+
 use std::{
-    io,
-    net::SocketAddr,
+    io::{self, Error, ErrorKind},
+    mem,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+    os::unix::io::AsRawFd,
+    ptr,
     task::{Context, Poll},
 };
 
+use futures::{future::poll_fn, ready};
+use log::warn;
+use shadowsocks::net::{is_dual_stack_addr, AcceptOpts};
+use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+use tokio::io::unix::AsyncFd;
+
 use crate::{
     config::RedirType,
-    local::redir::redir_ext::{RedirSocketOpts, UdpSocketRedir},
+    local::redir::{
+        redir_ext::{RedirSocketOpts, UdpSocketRedir},
+        sys::{bsd_pf::packet_filter, set_ipv6_only},
+    },
 };
 
-pub struct UdpRedirSocket;
+pub struct UdpRedirSocket {
+    io: AsyncFd<UdpSocket>,
+    local_addr: SocketAddr,
+}
 
 impl UdpRedirSocket {
     /// Create a new UDP socket binded to `addr`
     ///
-    /// This will allow listening to `addr` that is not in local host
-    pub fn listen(_ty: RedirType, _addr: SocketAddr) -> io::Result<UdpRedirSocket> {
-        unimplemented!("UDP transparent proxy is not supported on macOS, iOS, ...")
+    /// Packets are redirected to `addr` by pf's `rdr` rules
+    pub fn listen(
+        ty: RedirType,
+        addr: SocketAddr,
+        _accept_opts: &AcceptOpts,
+        reuse_port: bool,
+    ) -> io::Result<UdpRedirSocket> {
+        if ty != RedirType::PacketFilter {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "not supported udp transparent proxy type",
+            ));
+        }
+
+        // Fail early if /dev/pf couldn't be opened
+        packet_filter()?;
+
+        let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+        socket.set_nonblocking(true)?;
+        socket.set_reuse_address(true)?;
+        if reuse_port {
+            socket.set_reuse_port(true)?;
+        }
+
+        if is_dual_stack_addr(&addr) {
+            // pf's states are looked up with addresses of the same family
+
+            if let Err(err) = set_ipv6_only(&socket, true) {
+                warn!("failed to set IPV6_V6ONLY, error: {}", err);
+            }
+        }
+
+        set_recv_destination(&addr, &socket)?;
+
+        socket.bind(&SockAddr::from(addr))?;
+
+        let socket: UdpSocket = socket.into();
+        let local_addr = socket.local_addr()?;
+        let io = AsyncFd::new(socket)?;
+        Ok(UdpRedirSocket { io, local_addr })
     }
 
     /// Create a new UDP socket binded to `addr`
     ///
-    /// This will allow binding to `addr` that is not in local host
+    /// Binding to non-local addresses is not supported on macOS, responses are sent by the listener
     pub fn bind_nonlocal(
         _ty: RedirType,
         _addr: SocketAddr,
         _redir_opts: &RedirSocketOpts,
     ) -> io::Result<UdpRedirSocket> {
-        unimplemented!("UDP transparent proxy is not supported on macOS, iOS, ...")
+        Err(Error::new(
+            ErrorKind::Unsupported,
+            "binding to non-local addresses is not supported on macOS",
+        ))
     }
 
     /// Send data to the socket to the given target address
-    pub async fn send_to(&self, _buf: &[u8], _target: SocketAddr) -> io::Result<usize> {
-        unimplemented!("UDP transparent proxy is not supported on macOS, iOS, ...")
+    pub async fn send_to(&self, buf: &[u8], target: SocketAddr) -> io::Result<usize> {
+        poll_fn(|cx| self.poll_send_to(cx, buf, target)).await
+    }
+
+    fn poll_send_to(&self, cx: &mut Context<'_>, buf: &[u8], target: SocketAddr) -> Poll<io::Result<usize>> {
+        loop {
+            let mut write_guard = ready!(self.io.poll_write_ready(cx))?;
+
+            match self.io.get_ref().send_to(buf, target) {
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
+                    write_guard.clear_ready();
+                }
+                x => return Poll::Ready(x),
+            }
+        }
     }
 
     /// Returns the local address that this socket is bound to.
     pub fn local_addr(&self) -> io::Result<SocketAddr> {
-        unimplemented!("UDP transparent proxy is not supported on macOS, iOS, ...")
+        Ok(self.local_addr)
     }
 }
 
 impl UdpSocketRedir for UdpRedirSocket {
     fn poll_recv_dest_from(
         &self,
-        _cx: &mut Context<'_>,
-        _buf: &mut [u8],
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
     ) -> Poll<io::Result<(usize, SocketAddr, SocketAddr)>> {
-        unimplemented!("UDP transparent proxy is not supported on macOS, iOS, ...")
+        let (n, src, local_ip) = loop {
+            let mut read_guard = ready!(self.io.poll_read_ready(cx))?;
+
+            match recv_dest_from(self.io.get_ref(), buf) {
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
+                    read_guard.clear_ready();
+                }
+                Err(err) => return Poll::Ready(Err(err)),
+                Ok(x) => break x,
+            }
+        };
+
+        // Address that the packet was redirected to, which is the key of pf's state
+        let local_addr = match local_ip {
+            Some(ip) => SocketAddr::new(ip, self.local_addr.port()),
+            None => self.local_addr,
+        };
+
+        let dst = packet_filter()?.natlook(&local_addr, &src, Protocol::UDP)?;
+        Poll::Ready(Ok((n, src, dst)))
+    }
+}
+
+fn set_recv_destination(addr: &SocketAddr, socket: &Socket) -> io::Result<()> {
+    let fd = socket.as_raw_fd();
+
+    let enable: libc::c_int = 1;
+
+    // Receive destination addresses of packets, listener may bind to unspecified addresses
+    let (level, opt) = match *addr {
+        SocketAddr::V4(..) => (libc::IPPROTO_IP, libc::IP_RECVDSTADDR),
+        SocketAddr::V6(..) => (libc::IPPROTO_IPV6, libc::IPV6_RECVPKTINFO),
+    };
+
+    unsafe {
+        let ret = libc::setsockopt(
+            fd,
+            level,
+            opt,
+            &enable as *const _ as *const _,
+            mem::size_of_val(&enable) as libc::socklen_t,
+        );
+        if ret != 0 {
+            return Err(Error::last_os_error());
+        }
+    }
+
+    Ok(())
+}
+
+fn get_destination_ip(msg: &libc::msghdr) -> Option<IpAddr> {
+    unsafe {
+        let mut cmsg: *mut libc::cmsghdr = libc::CMSG_FIRSTHDR(msg);
+        while !cmsg.is_null() {
+            let rcmsg = &*cmsg;
+            match (rcmsg.cmsg_level, rcmsg.cmsg_type) {
+                (libc::IPPROTO_IP, libc::IP_RECVDSTADDR) => {
+                    let addr = ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const libc::in_addr);
+                    return Some(IpAddr::V4(Ipv4Addr::from(u32::from_be(addr.s_addr))));
+                }
+                (libc::IPPROTO_IPV6, libc::IPV6_PKTINFO) => {
+                    let info = ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const libc::in6_pktinfo);
+                    return Some(IpAddr::V6(Ipv6Addr::from(info.ipi6_addr.s6_addr)));
+                }
+                _ => {}
+            }
+            cmsg = libc::CMSG_NXTHDR(msg, cmsg);
+        }
+    }
+
+    None
+}
+
+fn recv_dest_from(socket: &UdpSocket, buf: &mut [u8]) -> io::Result<(usize, SocketAddr, Option<IpAddr>)> {
+    unsafe {
+        let mut control_buf = [0u8; 64];
+        let mut src_addr: libc::sockaddr_storage = mem::zeroed();
+
+        let mut msg: libc::msghdr = mem::zeroed();
+        msg.msg_name = &mut src_addr as *mut _ as *mut _;
+        msg.msg_namelen = mem::size_of_val(&src_addr) as libc::socklen_t;
+
+        let mut iov = libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut _,
+            iov_len: buf.len() as libc::size_t,
+        };
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+
+        msg.msg_control = control_buf.as_mut_ptr() as *mut _;
+        msg.msg_controllen = control_buf.len() as libc::socklen_t;
+
+        let fd = socket.as_raw_fd();
+        let ret = libc::recvmsg(fd, &mut msg, 0);
+        if ret < 0 {
+            return Err(Error::last_os_error());
+        }
+
+        let (_, src_saddr) = SockAddr::init(|a, l| {
+            ptr::copy_nonoverlapping(msg.msg_name, a as *mut _, msg.msg_namelen as usize);
+            *l = msg.msg_namelen;
+            Ok(())
+        })?;
+
+        Ok((
+            ret as usize,
+            src_saddr.as_socket().expect("SocketAddr"),
+            get_destination_ip(&msg),
+        ))
     }
 }
@@ -1,5 +1,7 @@
 use std::{io, net::SocketAddr};
 
+use shadowsocks::net::AcceptOpts;
+
 use crate::{
     config::RedirType,
     local::redir::redir_ext::{RedirSocketOpts, UdpSocketRedir},
@@ -11,7 +13,12 @@ impl UdpRedirSocket {
     /// Create a new UDP socket binded to `addr`
     ///
     /// This will allow listening to `addr` that is not in local host
-    pub fn listen(_ty: RedirType, _addr: SocketAddr) -> io::Result<UdpRedirSocket> {
+    pub fn listen(
+        _ty: RedirType,
+        _addr: SocketAddr,
+        _accept_opts: &AcceptOpts,
+        _reuse_port: bool,
+    ) -> io::Result<UdpRedirSocket> {
         unimplemented!("UDP transparent proxy is not supported on this platform")
     }
 
@@ -4,6 +4,8 @@ use std::{
     task::{Context, Poll},
 };
 
+use shadowsocks::net::AcceptOpts;
+
 use crate::{
     config::RedirType,
     local::redir::redir_ext::{RedirSocketOpts, UdpSocketRedir},
@@ -15,7 +17,12 @@ impl UdpRedirSocket {
     /// Create a new UDP socket binded to `addr`
     ///
     /// This will allow listening to `addr` that is not in local host
-    pub fn listen(ty: RedirType, addr: SocketAddr) -> io::Result<UdpRedirSocket> {
+    pub fn listen(
+        ty: RedirType,
+        addr: SocketAddr,
+        _accept_opts: &AcceptOpts,
+        _reuse_port: bool,
+    ) -> io::Result<UdpRedirSocket> {
         UdpRedirSocket::bind(ty, addr, false)
     }
 
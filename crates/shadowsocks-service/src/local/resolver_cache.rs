@@ -0,0 +1,224 @@
+//! Negative cache of the resolver for outbound connections
+//!
+//! Names that failed to be resolved (NXDOMAIN, timeouts, ...) are answered with the same error without querying the
+//! upstreams until the entries expire, so clients retrying a broken domain won't hammer the upstreams. An entry is
+//! kept for `negative_min_ttl` after the first failure, and it is doubled for every consecutive failure up to
+//! `negative_max_ttl`. Entries are removed after their names are resolved, or flushed by the control socket.
+
+use std::{
+    collections::HashMap,
+    io::{self, ErrorKind},
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use log::trace;
+use shadowsocks::dns_resolver::{DnsResolve, DnsResolver};
+use spin::Mutex as SpinMutex;
+
+/// Default TTL of names that failed once
+pub const DEFAULT_NEGATIVE_MIN_TTL: Duration = Duration::from_secs(5);
+/// Default maximum TTL of names that failed consecutively
+pub const DEFAULT_NEGATIVE_MAX_TTL: Duration = Duration::from_secs(60);
+/// Default maximum count of cached names
+pub const DEFAULT_CACHE_SIZE: usize = 4096;
+
+/// Resolver cache configuration
+#[derive(Debug, Clone)]
+pub struct ResolverCacheConfig {
+    /// TTL of names that failed once
+    pub negative_min_ttl: Duration,
+    /// Maximum TTL of names that failed consecutively
+    pub negative_max_ttl: Duration,
+    /// Maximum count of cached names, names that expire the earliest are evicted
+    pub size: usize,
+}
+
+impl Default for ResolverCacheConfig {
+    fn default() -> ResolverCacheConfig {
+        ResolverCacheConfig {
+            negative_min_ttl: DEFAULT_NEGATIVE_MIN_TTL,
+            negative_max_ttl: DEFAULT_NEGATIVE_MAX_TTL,
+            size: DEFAULT_CACHE_SIZE,
+        }
+    }
+}
+
+struct NegativeEntry {
+    kind: ErrorKind,
+    error: String,
+    failures: u32,
+    failed_time: Instant,
+    ttl: Duration,
+}
+
+impl NegativeEntry {
+    fn expire_time(&self) -> Instant {
+        self.failed_time + self.ttl
+    }
+}
+
+/// A cached failure of resolving, listed by the control socket
+#[derive(Debug, Clone)]
+pub struct ResolverCacheEntry {
+    /// Name in lowercase
+    pub name: String,
+    /// Error of the last failure
+    pub error: String,
+    /// Consecutive failures
+    pub failures: u32,
+    /// Remaining time before the name is resolved again
+    pub expires_in: Duration,
+}
+
+/// Negative cache shared by resolvers of all locals
+pub struct ResolverCache {
+    entries: SpinMutex<HashMap<String, NegativeEntry>>,
+    config: ResolverCacheConfig,
+}
+
+impl ResolverCache {
+    /// Create an empty cache
+    pub fn new(config: ResolverCacheConfig) -> ResolverCache {
+        ResolverCache {
+            entries: SpinMutex::new(HashMap::new()),
+            config,
+        }
+    }
+
+    /// Lookup the cached failure of `name`
+    fn lookup(&self, name: &str) -> Option<io::Error> {
+        let entries = self.entries.lock();
+        let entry = entries.get(name)?;
+        if Instant::now() >= entry.expire_time() {
+            // Kept for doubling the TTL if it fails again
+            return None;
+        }
+
+        Some(io::Error::new(entry.kind, format!("{} (negative cached)", entry.error)))
+    }
+
+    fn record_success(&self, name: &str) {
+        self.entries.lock().remove(name);
+    }
+
+    fn record_failure(&self, name: &str, err: &io::Error) {
+        let now = Instant::now();
+        let mut entries = self.entries.lock();
+
+        // Failures are consecutive if the name fails again soon after its entry expired
+        let failures = match entries.get(name) {
+            Some(entry) if now < entry.expire_time() + self.config.negative_max_ttl => entry.failures.saturating_add(1),
+            _ => 1,
+        };
+        let ttl = self
+            .config
+            .negative_min_ttl
+            .checked_mul(1 << (failures - 1).min(16))
+            .unwrap_or(self.config.negative_max_ttl)
+            .min(self.config.negative_max_ttl);
+
+        if !entries.contains_key(name) && entries.len() >= self.config.size {
+            self.evict(&mut entries, now);
+        }
+
+        trace!("resolving {} failed {} times, cached for {:?}", name, failures, ttl);
+
+        entries.insert(
+            name.to_owned(),
+            NegativeEntry {
+                kind: err.kind(),
+                error: err.to_string(),
+                failures,
+                failed_time: now,
+                ttl,
+            },
+        );
+    }
+
+    fn evict(&self, entries: &mut HashMap<String, NegativeEntry>, now: Instant) {
+        let max_ttl = self.config.negative_max_ttl;
+        entries.retain(|_, entry| now < entry.expire_time() + max_ttl);
+
+        while entries.len() >= self.config.size {
+            let name = match entries.iter().min_by_key(|(_, entry)| entry.expire_time()) {
+                Some((name, ..)) => name.clone(),
+                None => break,
+            };
+            entries.remove(&name);
+        }
+    }
+
+    /// Unexpired failures, sorted by names
+    pub fn entries(&self) -> Vec<ResolverCacheEntry> {
+        let now = Instant::now();
+        let entries = self.entries.lock();
+
+        let mut result = entries
+            .iter()
+            .filter(|(_, entry)| now < entry.expire_time())
+            .map(|(name, entry)| ResolverCacheEntry {
+                name: name.clone(),
+                error: entry.error.clone(),
+                failures: entry.failures,
+                expires_in: entry.expire_time() - now,
+            })
+            .collect::<Vec<_>>();
+        result.sort_by(|a, b| a.name.cmp(&b.name));
+        result
+    }
+
+    /// Remove the failure of `name`, or all of them if `name` is `None`, returns the count of removed entries
+    pub fn flush(&self, name: Option<&str>) -> usize {
+        let mut entries = self.entries.lock();
+        match name {
+            Some(name) => entries.remove(&normalize_name(name)).map_or(0, |_| 1),
+            None => {
+                let n = entries.len();
+                entries.clear();
+                n
+            }
+        }
+    }
+}
+
+fn normalize_name(name: &str) -> String {
+    name.trim_end_matches('.').to_ascii_lowercase()
+}
+
+/// Resolver that answers cached failures without querying `resolver`
+pub struct CachedResolver {
+    cache: Arc<ResolverCache>,
+    resolver: DnsResolver,
+}
+
+impl CachedResolver {
+    /// Create with the shared cache and the underlying resolver
+    pub fn new(cache: Arc<ResolverCache>, resolver: DnsResolver) -> CachedResolver {
+        CachedResolver { cache, resolver }
+    }
+}
+
+#[async_trait]
+impl DnsResolve for CachedResolver {
+    async fn resolve(&self, addr: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+        let name = normalize_name(addr);
+        if let Some(err) = self.cache.lookup(&name) {
+            trace!("resolving {} failed, negative cached", name);
+            return Err(err);
+        }
+
+        match self.resolver.resolve(addr, port).await {
+            Ok(addrs) => {
+                self.cache.record_success(&name);
+                Ok(addrs.collect())
+            }
+            Err(err) => {
+                self.cache.record_failure(&name, &err);
+                Err(err)
+            }
+        }
+    }
+}
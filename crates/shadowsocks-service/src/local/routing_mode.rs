@@ -0,0 +1,116 @@
+//! Routing mode of local servers, switched at runtime
+//!
+//! GUI frontends usually offer a toggle of routing modes, which is switched by the control socket or the host app
+//! without restarting local servers:
+//!
+//! - Global, all targets are proxied
+//! - Rule, targets are proxied or bypassed by ACL, all targets are proxied without ACL
+//! - Direct, all targets are bypassed
+//!
+//! Targets rejected by ACL are rejected in all modes. Only new connections and UDP targets follow the switched mode,
+//! established connections are kept.
+
+use std::{
+    fmt::{self, Display},
+    str::FromStr,
+    sync::atomic::{AtomicU8, Ordering},
+};
+
+use log::info;
+
+/// How targets are routed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RoutingMode {
+    /// Proxy all targets
+    Global,
+    /// Proxy or bypass targets by ACL
+    #[default]
+    Rule,
+    /// Bypass all targets
+    Direct,
+}
+
+impl Display for RoutingMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            RoutingMode::Global => f.write_str("global"),
+            RoutingMode::Rule => f.write_str("rule"),
+            RoutingMode::Direct => f.write_str("direct"),
+        }
+    }
+}
+
+/// Error while parsing `RoutingMode` from string
+#[derive(Debug, Clone, Copy)]
+pub struct RoutingModeError;
+
+impl Display for RoutingModeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("invalid RoutingMode, expecting \"global\", \"rule\" or \"direct\"")
+    }
+}
+
+impl FromStr for RoutingMode {
+    type Err = RoutingModeError;
+
+    fn from_str(s: &str) -> Result<RoutingMode, RoutingModeError> {
+        match s {
+            "global" => Ok(RoutingMode::Global),
+            "rule" => Ok(RoutingMode::Rule),
+            "direct" => Ok(RoutingMode::Direct),
+            _ => Err(RoutingModeError),
+        }
+    }
+}
+
+impl RoutingMode {
+    fn as_u8(self) -> u8 {
+        match self {
+            RoutingMode::Global => 0,
+            RoutingMode::Rule => 1,
+            RoutingMode::Direct => 2,
+        }
+    }
+
+    fn from_u8(v: u8) -> RoutingMode {
+        match v {
+            0 => RoutingMode::Global,
+            2 => RoutingMode::Direct,
+            _ => RoutingMode::Rule,
+        }
+    }
+}
+
+/// Routing mode shared by all contexts of local servers
+#[derive(Debug)]
+pub struct RoutingModeSwitch {
+    mode: AtomicU8,
+}
+
+impl Default for RoutingModeSwitch {
+    fn default() -> RoutingModeSwitch {
+        RoutingModeSwitch::new(RoutingMode::default())
+    }
+}
+
+impl RoutingModeSwitch {
+    /// Create in `mode`
+    pub fn new(mode: RoutingMode) -> RoutingModeSwitch {
+        RoutingModeSwitch {
+            mode: AtomicU8::new(mode.as_u8()),
+        }
+    }
+
+    /// Current routing mode
+    pub fn mode(&self) -> RoutingMode {
+        RoutingMode::from_u8(self.mode.load(Ordering::Acquire))
+    }
+
+    /// Switch to `mode`, does nothing if it is the current one
+    pub fn set_mode(&self, mode: RoutingMode) {
+        let old_mode = RoutingMode::from_u8(self.mode.swap(mode.as_u8(), Ordering::AcqRel));
+        if old_mode != mode {
+            info!("routing mode switched from {} to {}", old_mode, mode);
+        }
+    }
+}
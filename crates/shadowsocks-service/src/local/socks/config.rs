@@ -2,9 +2,11 @@
 
 use std::{
     collections::HashMap,
+    fmt::{self, Display},
     fs::OpenOptions,
     io::{self, ErrorKind, Read},
     path::Path,
+    str::FromStr,
 };
 
 use log::trace;
@@ -139,3 +141,57 @@ impl Default for Socks5AuthPasswdConfig {
         Socks5AuthPasswdConfig::new()
     }
 }
+
+/// Handling of host names in SOCKS requests
+///
+/// Clients of `socks5h://` (and SOCKS4a) send host names, while clients of `socks5://` resolve them locally and send
+/// IP addresses, so the same target may be matched by different ACL rules depending on the client.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SocksHostnamePolicy {
+    /// Relay host names as they are, which are resolved by servers (or locally if bypassed)
+    #[default]
+    Forward,
+    /// Resolve host names locally, and relay the IP addresses, as if the client resolved them
+    Resolve,
+    /// Reject requests with host names, clients have to resolve them locally
+    Reject,
+}
+
+impl SocksHostnamePolicy {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            SocksHostnamePolicy::Forward => "forward",
+            SocksHostnamePolicy::Resolve => "resolve",
+            SocksHostnamePolicy::Reject => "reject",
+        }
+    }
+}
+
+impl Display for SocksHostnamePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Error while parsing `SocksHostnamePolicy` from string
+#[derive(Debug, Clone, Copy)]
+pub struct SocksHostnamePolicyError;
+
+impl Display for SocksHostnamePolicyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("invalid SocksHostnamePolicy, expecting \"forward\", \"resolve\" or \"reject\"")
+    }
+}
+
+impl FromStr for SocksHostnamePolicy {
+    type Err = SocksHostnamePolicyError;
+
+    fn from_str(s: &str) -> Result<SocksHostnamePolicy, SocksHostnamePolicyError> {
+        match s {
+            "forward" => Ok(SocksHostnamePolicy::Forward),
+            "resolve" => Ok(SocksHostnamePolicy::Resolve),
+            "reject" => Ok(SocksHostnamePolicy::Reject),
+            _ => Err(SocksHostnamePolicyError),
+        }
+    }
+}
@@ -1,19 +1,41 @@
 //! Shadowsocks SOCKS Local Server
 
+#[cfg(unix)]
+use std::path::Path;
 use std::{io, net::SocketAddr, sync::Arc, time::Duration};
 
 use futures::{future, FutureExt};
-use log::{error, info};
-use shadowsocks::{config::Mode, lookup_then, net::TcpListener as ShadowTcpListener, ServerAddr};
-use tokio::{net::TcpStream, time};
-
-use crate::local::{context::ServiceContext, loadbalancing::PingBalancer};
+use log::{debug, error, info};
+use shadowsocks::{
+    config::Mode,
+    lookup_then,
+    net::{TcpListener as ShadowTcpListener, UdpSocket as ShadowUdpSocket},
+    relay::socks5::Address,
+    ServerAddr,
+};
+#[cfg(feature = "local-http")]
+use tokio::sync::mpsc;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpStream,
+    time,
+};
+#[cfg(feature = "local-socks-tls")]
+use tokio_rustls::TlsAcceptor;
+
+#[cfg(feature = "local-http")]
+use crate::local::http::Http;
+use crate::{
+    local::{context::ServiceContext, loadbalancing::PingBalancer, net::UdpNatConfig},
+    log_fields,
+    net::P2pPolicy,
+};
 
 #[cfg(feature = "local-socks4")]
 use self::socks4::Socks4TcpHandler;
 use self::socks5::{Socks5TcpHandler, Socks5UdpServer};
 
-use super::config::Socks5AuthConfig;
+use super::config::{Socks5AuthConfig, SocksHostnamePolicy};
 
 #[cfg(feature = "local-socks4")]
 mod socks4;
@@ -25,8 +47,19 @@ pub struct Socks {
     mode: Mode,
     udp_expiry_duration: Option<Duration>,
     udp_capacity: Option<usize>,
+    udp_nat: UdpNatConfig,
     udp_bind_addr: Option<ServerAddr>,
     socks5_auth: Arc<Socks5AuthConfig>,
+    #[cfg(feature = "local-socks-tls")]
+    tls_acceptor: Option<TlsAcceptor>,
+    #[cfg(feature = "local-http")]
+    http: Option<Http>,
+    #[cfg(feature = "local-http")]
+    http_incoming: Option<mpsc::Sender<TcpStream>>,
+    p2p_policy: P2pPolicy,
+    hostname_policy: SocksHostnamePolicy,
+    tcp_listener: Option<ShadowTcpListener>,
+    udp_socket: Option<ShadowUdpSocket>,
 }
 
 impl Default for Socks {
@@ -49,8 +82,19 @@ impl Socks {
             mode: Mode::TcpOnly,
             udp_expiry_duration: None,
             udp_capacity: None,
+            udp_nat: UdpNatConfig::default(),
             udp_bind_addr: None,
             socks5_auth: Arc::new(Socks5AuthConfig::default()),
+            #[cfg(feature = "local-socks-tls")]
+            tls_acceptor: None,
+            #[cfg(feature = "local-http")]
+            http: None,
+            #[cfg(feature = "local-http")]
+            http_incoming: None,
+            p2p_policy: P2pPolicy::Allow,
+            hostname_policy: SocksHostnamePolicy::default(),
+            tcp_listener: None,
+            udp_socket: None,
         }
     }
 
@@ -69,6 +113,11 @@ impl Socks {
         self.udp_capacity = Some(c);
     }
 
+    /// Set NAT filtering and timeouts by target ports of UDP associations
+    pub fn set_udp_nat(&mut self, nat: UdpNatConfig) {
+        self.udp_nat = nat;
+    }
+
     /// UDP server's bind address
     ///
     /// * If `mode` is `tcp_only`, then it will still return this address for `UDP_ASSOCIATE` command
@@ -82,39 +131,110 @@ impl Socks {
         self.socks5_auth = Arc::new(p);
     }
 
+    /// Accept TLS connections with this acceptor (secure SOCKS)
+    ///
+    /// Only the TCP server is protected, UDP relay is still in plain text
+    #[cfg(feature = "local-socks-tls")]
+    pub fn set_tls_acceptor(&mut self, acceptor: TlsAcceptor) {
+        self.tls_acceptor = Some(acceptor);
+    }
+
+    /// Set policy of BitTorrent traffic sent through servers
+    pub fn set_p2p_policy(&mut self, p2p_policy: P2pPolicy) {
+        self.p2p_policy = p2p_policy;
+    }
+
+    /// Set handling of host names in requests, of both TCP and UDP
+    pub fn set_hostname_policy(&mut self, hostname_policy: SocksHostnamePolicy) {
+        self.hostname_policy = hostname_policy;
+    }
+
+    /// Serve HTTP proxy on the same TCP port (mixed port)
+    ///
+    /// Connections are dispatched by their first byte, which is the version of SOCKS protocols, or the first byte of
+    /// HTTP methods. Only TCP servers listening on `ServerAddr` are supported.
+    #[cfg(feature = "local-http")]
+    pub fn set_http(&mut self, http: Http) {
+        self.http = Some(http);
+    }
+
+    /// Serve TCP on a listener bound to the address of `run`, instead of binding it
+    pub fn set_tcp_listener(&mut self, listener: ShadowTcpListener) {
+        self.tcp_listener = Some(listener);
+    }
+
+    /// Serve UDP relay on a socket bound to the address of `run`, instead of binding it
+    ///
+    /// It should be bound to the UDP bind address instead, if it is set by `set_udp_bind_addr`
+    pub fn set_udp_socket(&mut self, socket: ShadowUdpSocket) {
+        self.udp_socket = Some(socket);
+    }
+
     /// Start serving
-    pub async fn run(self, client_config: &ServerAddr, balancer: PingBalancer) -> io::Result<()> {
+    pub async fn run(mut self, client_config: &ServerAddr, balancer: PingBalancer) -> io::Result<()> {
         let mut vfut = Vec::new();
+        let tcp_listener = self.tcp_listener.take();
+        let udp_socket = self.udp_socket.take();
+
+        #[cfg(feature = "local-http")]
+        if let Some(http) = self.http.take() {
+            let (tx, rx) = mpsc::channel(64);
+            self.http_incoming = Some(tx);
+            vfut.push(http.run_incoming(rx, balancer.clone()).boxed());
+        }
 
         if self.mode.enable_tcp() {
-            vfut.push(self.run_tcp_server(client_config, balancer.clone()).boxed());
+            vfut.push(
+                self.run_tcp_server(client_config, tcp_listener, balancer.clone())
+                    .boxed(),
+            );
         }
 
         if self.mode.enable_udp() {
             // NOTE: SOCKS 5 RFC requires TCP handshake for UDP ASSOCIATE command
             // But here we can start a standalone UDP SOCKS 5 relay server, for special use cases
 
-            vfut.push(self.run_udp_server(client_config, balancer).boxed());
+            vfut.push(self.run_udp_server(client_config, udp_socket, balancer).boxed());
         }
 
         let (res, ..) = future::select_all(vfut).await;
         res
     }
 
-    async fn run_tcp_server(&self, client_config: &ServerAddr, balancer: PingBalancer) -> io::Result<()> {
-        let listener = match *client_config {
-            ServerAddr::SocketAddr(ref saddr) => {
+    async fn run_tcp_server(
+        &self,
+        client_config: &ServerAddr,
+        listener: Option<ShadowTcpListener>,
+        balancer: PingBalancer,
+    ) -> io::Result<()> {
+        let listener = match (listener, client_config) {
+            (Some(listener), ..) => listener,
+            (None, ServerAddr::SocketAddr(saddr)) => {
                 ShadowTcpListener::bind_with_opts(saddr, self.context.accept_opts()).await?
             }
-            ServerAddr::DomainName(ref dname, port) => {
-                lookup_then!(self.context.context_ref(), dname, port, |addr| {
+            (None, ServerAddr::DomainName(dname, port)) => {
+                lookup_then!(self.context.context_ref(), dname, *port, |addr| {
                     ShadowTcpListener::bind_with_opts(&addr, self.context.accept_opts()).await
                 })?
                 .1
             }
         };
 
+        #[cfg(feature = "local-socks-tls")]
+        if self.tls_acceptor.is_some() {
+            info!("shadowsocks socks TCP (TLS) listening on {}", listener.local_addr()?);
+        } else {
+            info!("shadowsocks socks TCP listening on {}", listener.local_addr()?);
+        }
+        #[cfg(not(feature = "local-socks-tls"))]
         info!("shadowsocks socks TCP listening on {}", listener.local_addr()?);
+        #[cfg(feature = "local-http")]
+        if self.http_incoming.is_some() {
+            info!(
+                "shadowsocks HTTP (mixed with socks) listening on {}",
+                listener.local_addr()?
+            );
+        }
 
         // If UDP is enabled, SOCK5 UDP_ASSOCIATE command will let client to send requests to this address
         let udp_bind_addr = if self.mode.enable_udp() {
@@ -140,11 +260,74 @@ impl Socks {
             let udp_bind_addr = udp_bind_addr.clone();
             let mode = self.mode;
             let socks5_auth = self.socks5_auth.clone();
+            let p2p_policy = self.p2p_policy.clone();
+            let hostname_policy = self.hostname_policy;
+
+            #[cfg(feature = "local-socks-tls")]
+            if let Some(ref acceptor) = self.tls_acceptor {
+                let acceptor = acceptor.clone();
+
+                log_fields::spawn_connection(peer_addr, async move {
+                    if let Err(err) = Socks::handle_tls_client(
+                        context,
+                        udp_bind_addr,
+                        acceptor,
+                        stream,
+                        balancer,
+                        peer_addr,
+                        mode,
+                        socks5_auth,
+                        p2p_policy,
+                        hostname_policy,
+                    )
+                    .await
+                    {
+                        error!("socks5 tcp (tls) client handler error: {}", err);
+                    }
+                });
+
+                continue;
+            }
+
+            #[cfg(feature = "local-http")]
+            if let Some(ref http_incoming) = self.http_incoming {
+                let http_incoming = http_incoming.clone();
+
+                log_fields::spawn_connection(peer_addr, async move {
+                    if let Err(err) = Socks::handle_mixed_client(
+                        context,
+                        udp_bind_addr,
+                        http_incoming,
+                        stream,
+                        balancer,
+                        peer_addr,
+                        mode,
+                        socks5_auth,
+                        p2p_policy,
+                        hostname_policy,
+                    )
+                    .await
+                    {
+                        error!("mixed tcp client handler error: {}", err);
+                    }
+                });
+
+                continue;
+            }
 
-            tokio::spawn(async move {
-                if let Err(err) =
-                    Socks::handle_tcp_client(context, udp_bind_addr, stream, balancer, peer_addr, mode, socks5_auth)
-                        .await
+            log_fields::spawn_connection(peer_addr, async move {
+                if let Err(err) = Socks::handle_tcp_client(
+                    context,
+                    udp_bind_addr,
+                    stream,
+                    balancer,
+                    peer_addr,
+                    mode,
+                    socks5_auth,
+                    p2p_policy,
+                    hostname_policy,
+                )
+                .await
                 {
                     error!("socks5 tcp client handler error: {}", err);
                 }
@@ -152,7 +335,127 @@ impl Socks {
         }
     }
 
-    #[cfg(feature = "local-socks4")]
+    /// Start serving on a Unix Domain Socket
+    ///
+    /// UDP relay couldn't be served on Unix Domain Sockets, it will bind to the address set by `set_udp_bind_addr`
+    #[cfg(unix)]
+    pub async fn run_unix(self, path: &Path, socket_mode: Option<u32>, balancer: PingBalancer) -> io::Result<()> {
+        use std::io::ErrorKind;
+
+        let udp_bind_addr = if self.mode.enable_udp() {
+            match self.udp_bind_addr {
+                Some(ref a) => Some(a.clone()),
+                None => {
+                    let err = io::Error::new(
+                        ErrorKind::InvalidInput,
+                        "socks on unix domain socket requires an UDP bind address for UDP relay",
+                    );
+                    return Err(err);
+                }
+            }
+        } else {
+            None
+        };
+
+        let mut vfut = Vec::new();
+
+        if self.mode.enable_tcp() {
+            vfut.push(self.run_unix_tcp_server(path, socket_mode, balancer.clone()).boxed());
+        }
+
+        if let Some(ref udp_bind_addr) = udp_bind_addr {
+            vfut.push(self.run_udp_server(udp_bind_addr, None, balancer).boxed());
+        }
+
+        let (res, ..) = future::select_all(vfut).await;
+        res
+    }
+
+    #[cfg(unix)]
+    async fn run_unix_tcp_server(
+        &self,
+        path: &Path,
+        socket_mode: Option<u32>,
+        balancer: PingBalancer,
+    ) -> io::Result<()> {
+        use log::trace;
+
+        use crate::local::net::uds::{bind_unix_listener, unix_peer_identity};
+
+        let listener = bind_unix_listener(path, socket_mode)?;
+
+        info!("shadowsocks socks TCP listening on unix:{}", path.display());
+
+        let udp_bind_addr = self.udp_bind_addr.clone().map(Arc::new);
+
+        loop {
+            let stream = match listener.accept().await {
+                Ok((s, ..)) => s,
+                Err(err) => {
+                    error!("accept failed with error: {}", err);
+                    time::sleep(Duration::from_secs(1)).await;
+                    continue;
+                }
+            };
+
+            let (peer_identity, peer_addr) = unix_peer_identity(&stream);
+            trace!("socks client {} connected", peer_identity);
+
+            let balancer = balancer.clone();
+            let context = self.context.clone();
+            let udp_bind_addr = udp_bind_addr.clone();
+            let mode = self.mode;
+            let socks5_auth = self.socks5_auth.clone();
+            let p2p_policy = self.p2p_policy.clone();
+            let hostname_policy = self.hostname_policy;
+
+            #[cfg(feature = "local-socks-tls")]
+            if let Some(ref acceptor) = self.tls_acceptor {
+                let acceptor = acceptor.clone();
+
+                log_fields::spawn_connection(peer_addr, async move {
+                    if let Err(err) = Socks::handle_tls_client(
+                        context,
+                        udp_bind_addr,
+                        acceptor,
+                        stream,
+                        balancer,
+                        peer_addr,
+                        mode,
+                        socks5_auth,
+                        p2p_policy,
+                        hostname_policy,
+                    )
+                    .await
+                    {
+                        error!("socks5 tcp (tls) client {} handler error: {}", peer_identity, err);
+                    }
+                });
+
+                continue;
+            }
+
+            log_fields::spawn_connection(peer_addr, async move {
+                if let Err(err) = Socks::handle_buffered_client(
+                    context,
+                    udp_bind_addr,
+                    stream,
+                    balancer,
+                    peer_addr,
+                    mode,
+                    socks5_auth,
+                    p2p_policy,
+                    hostname_policy,
+                )
+                .await
+                {
+                    error!("socks5 tcp client {} handler error: {}", peer_identity, err);
+                }
+            });
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
     async fn handle_tcp_client(
         context: Arc<ServiceContext>,
         udp_bind_addr: Option<Arc<ServerAddr>>,
@@ -161,6 +464,46 @@ impl Socks {
         peer_addr: SocketAddr,
         mode: Mode,
         socks5_auth: Arc<Socks5AuthConfig>,
+        p2p_policy: P2pPolicy,
+        hostname_policy: SocksHostnamePolicy,
+    ) -> io::Result<()> {
+        use std::io::ErrorKind;
+
+        let mut version_buffer = [0u8; 1];
+        let n = stream.peek(&mut version_buffer).await?;
+        if n == 0 {
+            return Err(ErrorKind::UnexpectedEof.into());
+        }
+
+        Socks::handle_socks_client(
+            context,
+            udp_bind_addr,
+            version_buffer[0],
+            stream,
+            balancer,
+            peer_addr,
+            mode,
+            socks5_auth,
+            p2p_policy,
+            hostname_policy,
+        )
+        .await
+    }
+
+    /// Handle clients of mixed port, streams that are not started with SOCKS versions are passed to the HTTP server
+    #[cfg(feature = "local-http")]
+    #[allow(clippy::too_many_arguments)]
+    async fn handle_mixed_client(
+        context: Arc<ServiceContext>,
+        udp_bind_addr: Option<Arc<ServerAddr>>,
+        http_incoming: mpsc::Sender<TcpStream>,
+        stream: TcpStream,
+        balancer: PingBalancer,
+        peer_addr: SocketAddr,
+        mode: Mode,
+        socks5_auth: Arc<Socks5AuthConfig>,
+        p2p_policy: P2pPolicy,
+        hostname_policy: SocksHostnamePolicy,
     ) -> io::Result<()> {
         use std::io::ErrorKind;
 
@@ -171,13 +514,150 @@ impl Socks {
         }
 
         match version_buffer[0] {
+            #[cfg(feature = "local-socks4")]
+            0x04 => {}
+            0x05 => {}
+            _ => {
+                log::trace!("mixed client {} dispatched to HTTP", peer_addr);
+                if http_incoming.send(stream).await.is_err() {
+                    return Err(io::Error::new(ErrorKind::Other, "HTTP server exited"));
+                }
+                return Ok(());
+            }
+        }
+
+        Socks::handle_socks_client(
+            context,
+            udp_bind_addr,
+            version_buffer[0],
+            stream,
+            balancer,
+            peer_addr,
+            mode,
+            socks5_auth,
+            p2p_policy,
+            hostname_policy,
+        )
+        .await
+    }
+
+    #[cfg(feature = "local-socks-tls")]
+    #[allow(clippy::too_many_arguments)]
+    async fn handle_tls_client<S>(
+        context: Arc<ServiceContext>,
+        udp_bind_addr: Option<Arc<ServerAddr>>,
+        acceptor: TlsAcceptor,
+        stream: S,
+        balancer: PingBalancer,
+        peer_addr: SocketAddr,
+        mode: Mode,
+        socks5_auth: Arc<Socks5AuthConfig>,
+        p2p_policy: P2pPolicy,
+        hostname_policy: SocksHostnamePolicy,
+    ) -> io::Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + 'static,
+    {
+        let stream = match acceptor.accept(stream).await {
+            Ok(s) => s,
+            Err(err) => {
+                let err = io::Error::new(err.kind(), format!("tls handshake with {}: {}", peer_addr, err));
+                return Err(err);
+            }
+        };
+
+        // TLS streams couldn't be peeked
+        Socks::handle_buffered_client(
+            context,
+            udp_bind_addr,
+            stream,
+            balancer,
+            peer_addr,
+            mode,
+            socks5_auth,
+            p2p_policy,
+            hostname_policy,
+        )
+        .await
+    }
+
+    /// Handle clients from streams that couldn't be peeked, the first byte is buffered for detecting SOCKS version
+    #[cfg(any(feature = "local-socks-tls", unix))]
+    #[allow(clippy::too_many_arguments)]
+    async fn handle_buffered_client<S>(
+        context: Arc<ServiceContext>,
+        udp_bind_addr: Option<Arc<ServerAddr>>,
+        stream: S,
+        balancer: PingBalancer,
+        peer_addr: SocketAddr,
+        mode: Mode,
+        socks5_auth: Arc<Socks5AuthConfig>,
+        p2p_policy: P2pPolicy,
+        hostname_policy: SocksHostnamePolicy,
+    ) -> io::Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + 'static,
+    {
+        use std::io::ErrorKind;
+
+        use tokio::io::{AsyncBufReadExt, BufReader};
+
+        let mut stream = BufReader::new(stream);
+        let version = match stream.fill_buf().await?.first() {
+            Some(v) => *v,
+            None => return Err(ErrorKind::UnexpectedEof.into()),
+        };
+
+        Socks::handle_socks_client(
+            context,
+            udp_bind_addr,
+            version,
+            stream,
+            balancer,
+            peer_addr,
+            mode,
+            socks5_auth,
+            p2p_policy,
+            hostname_policy,
+        )
+        .await
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn handle_socks_client<S>(
+        context: Arc<ServiceContext>,
+        udp_bind_addr: Option<Arc<ServerAddr>>,
+        version: u8,
+        stream: S,
+        balancer: PingBalancer,
+        peer_addr: SocketAddr,
+        mode: Mode,
+        socks5_auth: Arc<Socks5AuthConfig>,
+        p2p_policy: P2pPolicy,
+        hostname_policy: SocksHostnamePolicy,
+    ) -> io::Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + 'static,
+    {
+        use std::io::ErrorKind;
+
+        match version {
+            #[cfg(feature = "local-socks4")]
             0x04 => {
-                let handler = Socks4TcpHandler::new(context, balancer, mode);
+                let handler = Socks4TcpHandler::new(context, balancer, mode, p2p_policy, hostname_policy);
                 handler.handle_socks4_client(stream, peer_addr).await
             }
 
             0x05 => {
-                let handler = Socks5TcpHandler::new(context, udp_bind_addr, balancer, mode, socks5_auth);
+                let handler = Socks5TcpHandler::new(
+                    context,
+                    udp_bind_addr,
+                    balancer,
+                    mode,
+                    socks5_auth,
+                    p2p_policy,
+                    hostname_policy,
+                );
                 handler.handle_socks5_client(stream, peer_addr).await
             }
 
@@ -189,24 +669,58 @@ impl Socks {
         }
     }
 
-    #[cfg(not(feature = "local-socks4"))]
-    async fn handle_tcp_client(
-        context: Arc<ServiceContext>,
-        udp_bind_addr: Option<Arc<ServerAddr>>,
-        stream: TcpStream,
+    async fn run_udp_server(
+        &self,
+        client_config: &ServerAddr,
+        socket: Option<ShadowUdpSocket>,
         balancer: PingBalancer,
-        peer_addr: SocketAddr,
-        mode: Mode,
-        socks5_auth: Arc<Socks5AuthConfig>,
     ) -> io::Result<()> {
-        let handler = Socks5TcpHandler::new(context, udp_bind_addr, balancer, mode, socks5_auth);
-        handler.handle_socks5_client(stream, peer_addr).await
+        let mut server = Socks5UdpServer::new(self.context.clone(), self.udp_expiry_duration, self.udp_capacity);
+        server.set_nat_config(self.udp_nat.clone());
+        server.set_p2p_policy(self.p2p_policy.clone());
+        server.set_hostname_policy(self.hostname_policy);
+
+        match self.udp_bind_addr {
+            Some(ref udp_bind_addr) => server.run(udp_bind_addr, socket, balancer).await,
+            None => server.run(client_config, socket, balancer).await,
+        }
     }
+}
 
-    async fn run_udp_server(&self, client_config: &ServerAddr, balancer: PingBalancer) -> io::Result<()> {
-        let server = Socks5UdpServer::new(self.context.clone(), self.udp_expiry_duration, self.udp_capacity);
-
-        let udp_bind_addr = self.udp_bind_addr.as_ref().unwrap_or(client_config);
-        server.run(udp_bind_addr, balancer).await
+/// Apply `policy` on the target address of a request from `peer_addr`
+///
+/// Host names are kept by `Forward`, replaced with the first resolved address by `Resolve`, and fail with
+/// `PermissionDenied` by `Reject`. IP addresses are always kept.
+async fn apply_hostname_policy(
+    context: &ServiceContext,
+    policy: SocksHostnamePolicy,
+    peer_addr: SocketAddr,
+    addr: Address,
+) -> io::Result<Address> {
+    let (dname, port) = match addr {
+        Address::DomainNameAddress(dname, port) => (dname, port),
+        addr => return Ok(addr),
+    };
+
+    match policy {
+        SocksHostnamePolicy::Forward => Ok(Address::DomainNameAddress(dname, port)),
+        SocksHostnamePolicy::Resolve => {
+            let (_, resolved) = lookup_then!(context.context_ref(), &dname, port, |addr| { Ok::<_, io::Error>(addr) })?;
+            debug!(
+                "socks client {} target {}:{} resolved to {}",
+                peer_addr, dname, port, resolved
+            );
+            Ok(Address::SocketAddress(resolved))
+        }
+        SocksHostnamePolicy::Reject => {
+            debug!(
+                "socks client {} target {}:{} rejected, host names are not allowed",
+                peer_addr, dname, port
+            );
+            Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("host name {} is not allowed, clients have to resolve it", dname),
+            ))
+        }
     }
 }
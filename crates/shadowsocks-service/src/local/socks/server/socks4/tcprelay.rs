@@ -8,16 +8,19 @@ use std::{
 
 use log::{debug, error, trace, warn};
 use shadowsocks::config::Mode;
-use tokio::{
-    io::{AsyncWriteExt, BufReader},
-    net::TcpStream,
-};
-
-use crate::local::{
-    context::ServiceContext,
-    loadbalancing::PingBalancer,
-    net::AutoProxyClientStream,
-    utils::establish_tcp_tunnel,
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+
+#[cfg(feature = "acl")]
+use crate::{acl::RejectMode, local::utils::respond_rejected};
+use crate::{
+    local::{
+        context::ServiceContext,
+        loadbalancing::PingBalancer,
+        net::AutoProxyClientStream,
+        socks::config::SocksHostnamePolicy,
+        utils::{debug_route, establish_tcp_tunnel},
+    },
+    net::{ConcurrencyKind, P2pPolicy},
 };
 
 use crate::local::socks::socks4::{
@@ -33,18 +36,31 @@ pub struct Socks4TcpHandler {
     context: Arc<ServiceContext>,
     balancer: PingBalancer,
     mode: Mode,
+    p2p_policy: P2pPolicy,
+    hostname_policy: SocksHostnamePolicy,
 }
 
 impl Socks4TcpHandler {
-    pub fn new(context: Arc<ServiceContext>, balancer: PingBalancer, mode: Mode) -> Socks4TcpHandler {
+    pub fn new(
+        context: Arc<ServiceContext>,
+        balancer: PingBalancer,
+        mode: Mode,
+        p2p_policy: P2pPolicy,
+        hostname_policy: SocksHostnamePolicy,
+    ) -> Socks4TcpHandler {
         Socks4TcpHandler {
             context,
             balancer,
             mode,
+            p2p_policy,
+            hostname_policy,
         }
     }
 
-    pub async fn handle_socks4_client(self, stream: TcpStream, peer_addr: SocketAddr) -> io::Result<()> {
+    pub async fn handle_socks4_client<S>(self, stream: S, peer_addr: SocketAddr) -> io::Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + 'static,
+    {
         // 1. Handshake
 
         // NOTE: Wraps it with BufReader for reading NULL terminated information in HandshakeRequest
@@ -80,12 +96,15 @@ impl Socks4TcpHandler {
         }
     }
 
-    async fn handle_socks4_connect(
+    async fn handle_socks4_connect<S>(
         self,
-        mut stream: BufReader<TcpStream>,
+        mut stream: BufReader<S>,
         peer_addr: SocketAddr,
         target_addr: Address,
-    ) -> io::Result<()> {
+    ) -> io::Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + 'static,
+    {
         if !self.mode.enable_tcp() {
             warn!("TCP CONNECT is disabled");
 
@@ -95,21 +114,69 @@ impl Socks4TcpHandler {
             return Ok(());
         }
 
-        let server = self.balancer.best_tcp_server();
-        let svr_cfg = server.server_config();
-        let target_addr = target_addr.into();
+        let target_addr = match super::super::apply_hostname_policy(
+            &self.context,
+            self.hostname_policy,
+            peer_addr,
+            target_addr.into(),
+        )
+        .await
+        {
+            Ok(addr) => addr,
+            Err(err) => {
+                warn!("socks4 client {} CONNECT refused, error: {}", peer_addr, err);
+
+                let handshake_rsp = HandshakeResponse::new(ResultCode::RequestRejectedOrFailed);
+                handshake_rsp.write_to(&mut stream).await?;
+
+                return Ok(());
+            }
+        };
+
+        let target_addr = self.context.map_fake_ip(target_addr);
+
+        let _tunnel_guard = self.context.concurrency_stat().acquire(ConcurrencyKind::TcpTunnel, 1);
+        let mut connection_guard =
+            self.context
+                .register_connection(ConcurrencyKind::TcpTunnel, peer_addr, Some(&target_addr));
+        let connect_result = match self.balancer.best_tcp_server_for(peer_addr, &target_addr).await {
+            Ok(server) => AutoProxyClientStream::connect(self.context.clone(), &server, peer_addr, &target_addr)
+                .await
+                .map(|remote| (server, remote)),
+            Err(err) => Err(err),
+        };
+        let (server, mut remote) = match connect_result {
+            Ok((server, remote)) => {
+                debug_route(&self.context, &server, peer_addr, &target_addr).await;
 
-        let mut remote = match AutoProxyClientStream::connect(self.context, &server, &target_addr).await {
-            Ok(remote) => {
                 // Tell the client that we are ready
                 let handshake_rsp = HandshakeResponse::new(ResultCode::RequestGranted);
                 handshake_rsp.write_to(&mut stream).await?;
 
                 trace!("sent header: {:?}", handshake_rsp);
 
-                remote
+                (server, remote)
             }
             Err(err) => {
+                #[cfg(feature = "acl")]
+                match AutoProxyClientStream::rejected_mode(&err) {
+                    Some(RejectMode::Drop) => {
+                        debug!("socks4 client {} CONNECT {} dropped by ACL", peer_addr, target_addr);
+                        return Ok(());
+                    }
+                    Some(RejectMode::Reset) => {
+                        debug!("socks4 client {} CONNECT {} reset by ACL", peer_addr, target_addr);
+                        let handshake_rsp = HandshakeResponse::new(ResultCode::RequestRejectedCannotConnect);
+                        return handshake_rsp.write_to(&mut stream).await;
+                    }
+                    Some(RejectMode::Response) => {
+                        let handshake_rsp = HandshakeResponse::new(ResultCode::RequestGranted);
+                        handshake_rsp.write_to(&mut stream).await?;
+                        return respond_rejected(&mut stream, peer_addr, &target_addr).await;
+                    }
+                    Some(RejectMode::Refuse) | None => {}
+                }
+
                 let result_code = match err.kind() {
                     ErrorKind::ConnectionRefused => ResultCode::RequestRejectedCannotConnect,
                     ErrorKind::ConnectionAborted => ResultCode::RequestRejectedCannotConnect,
@@ -132,6 +199,17 @@ impl Socks4TcpHandler {
         // UNWRAP.
         let mut stream = stream.into_inner();
 
-        establish_tcp_tunnel(svr_cfg, &mut stream, &mut remote, peer_addr, &target_addr).await
+        establish_tcp_tunnel(
+            &self.context,
+            &self.balancer,
+            &self.p2p_policy,
+            &server,
+            &mut stream,
+            &mut remote,
+            peer_addr,
+            &target_addr,
+            connection_guard.as_mut(),
+        )
+        .await
     }
 }
@@ -25,17 +25,19 @@ use shadowsocks::{
     },
     ServerAddr,
 };
-use tokio::net::TcpStream;
+use tokio::io::{AsyncRead, AsyncWrite};
 
+#[cfg(feature = "acl")]
+use crate::{acl::RejectMode, local::utils::respond_rejected};
 use crate::{
     local::{
         context::ServiceContext,
         loadbalancing::PingBalancer,
         net::AutoProxyClientStream,
-        socks::config::Socks5AuthConfig,
-        utils::establish_tcp_tunnel,
+        socks::config::{Socks5AuthConfig, SocksHostnamePolicy},
+        utils::{debug_route, establish_tcp_tunnel},
     },
-    net::utils::ignore_until_end,
+    net::{utils::ignore_until_end, ConcurrencyKind, P2pPolicy},
 };
 
 pub struct Socks5TcpHandler {
@@ -44,6 +46,8 @@ pub struct Socks5TcpHandler {
     balancer: PingBalancer,
     mode: Mode,
     auth: Arc<Socks5AuthConfig>,
+    p2p_policy: P2pPolicy,
+    hostname_policy: SocksHostnamePolicy,
 }
 
 impl Socks5TcpHandler {
@@ -53,6 +57,8 @@ impl Socks5TcpHandler {
         balancer: PingBalancer,
         mode: Mode,
         auth: Arc<Socks5AuthConfig>,
+        p2p_policy: P2pPolicy,
+        hostname_policy: SocksHostnamePolicy,
     ) -> Socks5TcpHandler {
         Socks5TcpHandler {
             context,
@@ -60,10 +66,15 @@ impl Socks5TcpHandler {
             balancer,
             mode,
             auth,
+            p2p_policy,
+            hostname_policy,
         }
     }
 
-    async fn check_auth(&self, stream: &mut TcpStream, handshake_req: &HandshakeRequest) -> io::Result<()> {
+    async fn check_auth<S>(&self, stream: &mut S, handshake_req: &HandshakeRequest) -> io::Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
         use std::io::Error;
 
         let allow_none = !self.auth.auth_required();
@@ -105,7 +116,10 @@ impl Socks5TcpHandler {
         ))
     }
 
-    async fn check_auth_password(&self, stream: &mut TcpStream) -> io::Result<()> {
+    async fn check_auth_password<S>(&self, stream: &mut S) -> io::Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
         use std::io::Error;
 
         const PASSWORD_AUTH_STATUS_FAILURE: u8 = 255;
@@ -181,7 +195,10 @@ impl Socks5TcpHandler {
         }
     }
 
-    pub async fn handle_socks5_client(self, mut stream: TcpStream, peer_addr: SocketAddr) -> io::Result<()> {
+    pub async fn handle_socks5_client<S>(self, mut stream: S, peer_addr: SocketAddr) -> io::Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + 'static,
+    {
         // 1. Handshake
 
         let handshake_req = match HandshakeRequest::read_from(&mut stream).await {
@@ -236,12 +253,10 @@ impl Socks5TcpHandler {
         }
     }
 
-    async fn handle_tcp_connect(
-        self,
-        mut stream: TcpStream,
-        peer_addr: SocketAddr,
-        target_addr: Address,
-    ) -> io::Result<()> {
+    async fn handle_tcp_connect<S>(self, mut stream: S, peer_addr: SocketAddr, target_addr: Address) -> io::Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + 'static,
+    {
         if !self.mode.enable_tcp() {
             warn!("TCP CONNECT is disabled");
 
@@ -251,11 +266,49 @@ impl Socks5TcpHandler {
             return Ok(());
         }
 
-        let server = self.balancer.best_tcp_server();
-        let svr_cfg = server.server_config();
+        let target_addr = match super::super::apply_hostname_policy(
+            &self.context,
+            self.hostname_policy,
+            peer_addr,
+            target_addr.clone(),
+        )
+        .await
+        {
+            Ok(addr) => addr,
+            Err(err) => {
+                warn!(
+                    "socks5 client {} CONNECT {} refused, error: {}",
+                    peer_addr, target_addr, err
+                );
+
+                let reply = if err.kind() == ErrorKind::PermissionDenied {
+                    socks5::Reply::AddressTypeNotSupported
+                } else {
+                    socks5::Reply::HostUnreachable
+                };
+                let rh = TcpResponseHeader::new(reply, target_addr);
+                rh.write_to(&mut stream).await?;
+
+                return Ok(());
+            }
+        };
+
+        let target_addr = self.context.map_fake_ip(target_addr);
+
+        let _tunnel_guard = self.context.concurrency_stat().acquire(ConcurrencyKind::TcpTunnel, 1);
+        let mut connection_guard =
+            self.context
+                .register_connection(ConcurrencyKind::TcpTunnel, peer_addr, Some(&target_addr));
+        let connect_result = match self.balancer.best_tcp_server_for(peer_addr, &target_addr).await {
+            Ok(server) => AutoProxyClientStream::connect(self.context.clone(), &server, peer_addr, &target_addr)
+                .await
+                .map(|remote| (server, remote)),
+            Err(err) => Err(err),
+        };
+        let (server, mut remote) = match connect_result {
+            Ok((server, remote)) => {
+                debug_route(&self.context, &server, peer_addr, &target_addr).await;
 
-        let mut remote = match AutoProxyClientStream::connect(self.context.clone(), &server, &target_addr).await {
-            Ok(remote) => {
                 // Tell the client that we are ready
                 let header =
                     TcpResponseHeader::new(socks5::Reply::Succeeded, Address::SocketAddress(remote.local_addr()?));
@@ -263,16 +316,37 @@ impl Socks5TcpHandler {
 
                 trace!("sent header: {:?}", header);
 
-                remote
+                (server, remote)
             }
             Err(err) => {
+                let dummy_address = SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0);
+
+                #[cfg(feature = "acl")]
+                match AutoProxyClientStream::rejected_mode(&err) {
+                    Some(RejectMode::Drop) => {
+                        debug!("socks5 client {} CONNECT {} dropped by ACL", peer_addr, target_addr);
+                        return Ok(());
+                    }
+                    Some(RejectMode::Reset) => {
+                        debug!("socks5 client {} CONNECT {} reset by ACL", peer_addr, target_addr);
+                        let header =
+                            TcpResponseHeader::new(Reply::ConnectionRefused, Address::SocketAddress(dummy_address));
+                        return header.write_to(&mut stream).await;
+                    }
+                    Some(RejectMode::Response) => {
+                        let header = TcpResponseHeader::new(Reply::Succeeded, Address::SocketAddress(dummy_address));
+                        header.write_to(&mut stream).await?;
+                        return respond_rejected(&mut stream, peer_addr, &target_addr).await;
+                    }
+                    Some(RejectMode::Refuse) | None => {}
+                }
+
                 let reply = match err.kind() {
                     ErrorKind::ConnectionRefused => Reply::ConnectionRefused,
                     ErrorKind::ConnectionAborted => Reply::HostUnreachable,
                     _ => Reply::NetworkUnreachable,
                 };
 
-                let dummy_address = SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0);
                 let header = TcpResponseHeader::new(reply, Address::SocketAddress(dummy_address));
                 header.write_to(&mut stream).await?;
 
@@ -280,10 +354,24 @@ impl Socks5TcpHandler {
             }
         };
 
-        establish_tcp_tunnel(svr_cfg, &mut stream, &mut remote, peer_addr, &target_addr).await
+        establish_tcp_tunnel(
+            &self.context,
+            &self.balancer,
+            &self.p2p_policy,
+            &server,
+            &mut stream,
+            &mut remote,
+            peer_addr,
+            &target_addr,
+            connection_guard.as_mut(),
+        )
+        .await
     }
 
-    async fn handle_udp_associate(self, mut stream: TcpStream, client_addr: Address) -> io::Result<()> {
+    async fn handle_udp_associate<S>(self, mut stream: S, client_addr: Address) -> io::Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
         match self.udp_bind_addr {
             None => {
                 warn!("socks5 udp is disabled");
@@ -10,10 +10,13 @@ use std::{
 use async_trait::async_trait;
 use byte_string::ByteStr;
 use bytes::{BufMut, BytesMut};
-use log::{error, info, trace};
+use log::{debug, error, info, trace};
 use shadowsocks::{
     lookup_then,
-    net::UdpSocket as ShadowUdpSocket,
+    net::{
+        udp::{self as shadow_udp, RecvBatch, SendBatch, UDP_BATCH_SIZE},
+        UdpSocket as ShadowUdpSocket,
+    },
     relay::{
         socks5::{Address, UdpAssociateHeader},
         udprelay::MAXIMUM_UDP_PAYLOAD_SIZE,
@@ -22,10 +25,14 @@ use shadowsocks::{
 };
 use tokio::{net::UdpSocket, time};
 
-use crate::local::{
-    context::ServiceContext,
-    loadbalancing::PingBalancer,
-    net::{UdpAssociationManager, UdpInboundWrite},
+use crate::{
+    local::{
+        context::ServiceContext,
+        loadbalancing::PingBalancer,
+        net::{UdpAssociationManager, UdpInboundWrite, UdpNatConfig},
+        socks::config::SocksHostnamePolicy,
+    },
+    net::P2pPolicy,
 };
 
 #[derive(Clone)]
@@ -46,12 +53,30 @@ impl UdpInboundWrite for Socks5UdpInboundWriter {
 
         self.inbound.send_to(&payload_buffer, peer_addr).await.map(|_| ())
     }
+
+    async fn send_batch_to(&self, peer_addr: SocketAddr, packets: &[(Address, &[u8])]) -> io::Result<()> {
+        let mut batch = SendBatch::new();
+        for (remote_addr, data) in packets {
+            // Reassemble packet
+            let payload_buffer = batch.push(Some(peer_addr));
+            let header = UdpAssociateHeader::new(0, remote_addr.clone());
+            payload_buffer.reserve(header.serialized_len() + data.len());
+
+            header.write_to_buf(payload_buffer);
+            payload_buffer.put_slice(data);
+        }
+
+        shadow_udp::send_batch(&self.inbound, &mut batch).await.map(|_| ())
+    }
 }
 
 pub struct Socks5UdpServer {
     context: Arc<ServiceContext>,
     time_to_live: Option<Duration>,
     capacity: Option<usize>,
+    nat: UdpNatConfig,
+    p2p_policy: P2pPolicy,
+    hostname_policy: SocksHostnamePolicy,
 }
 
 impl Socks5UdpServer {
@@ -64,16 +89,37 @@ impl Socks5UdpServer {
             context,
             time_to_live,
             capacity,
+            nat: UdpNatConfig::default(),
+            p2p_policy: P2pPolicy::Allow,
+            hostname_policy: SocksHostnamePolicy::default(),
         }
     }
 
-    pub async fn run(&self, client_config: &ServerAddr, balancer: PingBalancer) -> io::Result<()> {
-        let socket = match *client_config {
-            ServerAddr::SocketAddr(ref saddr) => {
+    pub fn set_nat_config(&mut self, nat: UdpNatConfig) {
+        self.nat = nat;
+    }
+
+    pub fn set_p2p_policy(&mut self, p2p_policy: P2pPolicy) {
+        self.p2p_policy = p2p_policy;
+    }
+
+    pub fn set_hostname_policy(&mut self, hostname_policy: SocksHostnamePolicy) {
+        self.hostname_policy = hostname_policy;
+    }
+
+    pub async fn run(
+        &self,
+        client_config: &ServerAddr,
+        socket: Option<ShadowUdpSocket>,
+        balancer: PingBalancer,
+    ) -> io::Result<()> {
+        let socket = match (socket, client_config) {
+            (Some(socket), ..) => socket,
+            (None, ServerAddr::SocketAddr(saddr)) => {
                 ShadowUdpSocket::listen_with_opts(saddr, self.context.accept_opts()).await?
             }
-            ServerAddr::DomainName(ref dname, port) => {
-                lookup_then!(self.context.context_ref(), dname, port, |addr| {
+            (None, ServerAddr::DomainName(dname, port)) => {
+                lookup_then!(self.context.context_ref(), dname, *port, |addr| {
                     ShadowUdpSocket::listen_with_opts(&addr, self.context.accept_opts()).await
                 })?
                 .1
@@ -91,10 +137,12 @@ impl Socks5UdpServer {
             },
             self.time_to_live,
             self.capacity,
+            self.nat.clone(),
             balancer,
         );
+        manager.set_p2p_policy(self.p2p_policy.clone());
 
-        let mut buffer = [0u8; MAXIMUM_UDP_PAYLOAD_SIZE];
+        let mut batch = RecvBatch::new(UDP_BATCH_SIZE, MAXIMUM_UDP_PAYLOAD_SIZE);
         let mut cleanup_timer = time::interval(cleanup_interval);
 
         loop {
@@ -109,53 +157,71 @@ impl Socks5UdpServer {
                     manager.keep_alive(&peer_addr).await;
                 }
 
-                recv_result = listener.recv_from(&mut buffer) => {
-                    let (n, peer_addr) = match recv_result {
-                        Ok(s) => s,
-                        Err(err) => {
-                            error!("udp server recv_from failed with error: {}", err);
-                            time::sleep(Duration::from_secs(1)).await;
-                            continue;
-                        }
-                    };
-
-                    let data = &buffer[..n];
-
-                    // PKT = UdpAssociateHeader + PAYLOAD
-                    let mut cur = Cursor::new(data);
-                    let header = match UdpAssociateHeader::read_from(&mut cur).await {
-                        Ok(h) => h,
-                        Err(..) => {
-                            error!("received invalid UDP associate packet: {:?}", ByteStr::new(data));
-                            continue;
-                        }
-                    };
-
-                    if header.frag != 0 {
-                        error!("received UDP associate with frag != 0, which is not supported by shadowsocks");
+                recv_result = shadow_udp::recv_batch(&listener, &mut batch) => {
+                    if let Err(err) = recv_result {
+                        error!("udp server recv_from failed with error: {}", err);
+                        time::sleep(Duration::from_secs(1)).await;
                         continue;
                     }
 
-                    let pos = cur.position() as usize;
-                    let payload = &data[pos..];
-
-                    trace!(
-                        "UDP ASSOCIATE {} -> {}, {} bytes",
-                        peer_addr,
-                        header.address,
-                        payload.len()
-                    );
-
-                    if let Err(err) = manager.send_to(peer_addr, header.address, payload).await {
-                        error!(
-                            "udp packet from {} relay {} bytes failed, error: {}",
-                            peer_addr,
-                            data.len(),
-                            err
-                        );
+                    for i in 0..batch.len() {
+                        let (data, peer_addr) = batch.get(i);
+                        self.recv_packet(&mut manager, peer_addr, data).await;
                     }
                 }
             }
         }
     }
+
+    async fn recv_packet(
+        &self,
+        manager: &mut UdpAssociationManager<Socks5UdpInboundWriter>,
+        peer_addr: SocketAddr,
+        data: &[u8],
+    ) {
+        // PKT = UdpAssociateHeader + PAYLOAD
+        let mut cur = Cursor::new(data);
+        let header = match UdpAssociateHeader::read_from(&mut cur).await {
+            Ok(h) => h,
+            Err(..) => {
+                error!("received invalid UDP associate packet: {:?}", ByteStr::new(data));
+                return;
+            }
+        };
+
+        if header.frag != 0 {
+            error!("received UDP associate with frag != 0, which is not supported by shadowsocks");
+            return;
+        }
+
+        let pos = cur.position() as usize;
+        let payload = &data[pos..];
+
+        trace!(
+            "UDP ASSOCIATE {} -> {}, {} bytes",
+            peer_addr,
+            header.address,
+            payload.len()
+        );
+
+        let target_addr =
+            match super::super::apply_hostname_policy(&self.context, self.hostname_policy, peer_addr, header.address)
+                .await
+            {
+                Ok(addr) => addr,
+                Err(err) => {
+                    debug!("udp packet from {} dropped, error: {}", peer_addr, err);
+                    return;
+                }
+            };
+
+        if let Err(err) = manager.send_to(peer_addr, target_addr, payload).await {
+            error!(
+                "udp packet from {} relay {} bytes failed, error: {}",
+                peer_addr,
+                data.len(),
+                err
+            );
+        }
+    }
 }
@@ -0,0 +1,127 @@
+//! System proxy settings of GNOME, by `gsettings`
+
+use std::{
+    io::{self, Error, ErrorKind},
+    process::Command,
+};
+
+use serde::{Deserialize, Serialize};
+
+use super::ProxyAddr;
+
+const PROXY_SCHEMA: &str = "org.gnome.system.proxy";
+
+/// Keys of proxy settings, values are kept as GVariant texts printed by `gsettings get`
+const PROXY_KEYS: &[(&str, &str)] = &[
+    ("org.gnome.system.proxy", "mode"),
+    ("org.gnome.system.proxy", "ignore-hosts"),
+    ("org.gnome.system.proxy.http", "host"),
+    ("org.gnome.system.proxy.http", "port"),
+    ("org.gnome.system.proxy.https", "host"),
+    ("org.gnome.system.proxy.https", "port"),
+    ("org.gnome.system.proxy.socks", "host"),
+    ("org.gnome.system.proxy.socks", "port"),
+];
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ProxyValue {
+    schema: String,
+    key: String,
+    value: String,
+}
+
+/// Values of `org.gnome.system.proxy` and its child schemas
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ProxySettings {
+    values: Vec<ProxyValue>,
+}
+
+impl ProxySettings {
+    /// Load the current settings
+    pub fn load() -> io::Result<ProxySettings> {
+        let mut values = Vec::with_capacity(PROXY_KEYS.len());
+        for (schema, key) in PROXY_KEYS {
+            let value = gsettings(&["get", schema, key])?;
+            values.push(ProxyValue {
+                schema: schema.to_string(),
+                key: key.to_string(),
+                value: value.trim().to_owned(),
+            });
+        }
+        Ok(ProxySettings { values })
+    }
+
+    /// Settings pointing to `http` (for both HTTP and HTTPS) and `socks`, except `bypass` hosts
+    pub fn with_proxy(&self, http: Option<&ProxyAddr>, socks: Option<&ProxyAddr>, bypass: &[&str]) -> ProxySettings {
+        let mut settings = self.clone();
+
+        let hosts = bypass.iter().map(|h| gvariant_string(h)).collect::<Vec<_>>().join(", ");
+        settings.set(PROXY_SCHEMA, "mode", gvariant_string("manual"));
+        settings.set(PROXY_SCHEMA, "ignore-hosts", format!("[{}]", hosts));
+
+        for (schema, addr) in [
+            ("org.gnome.system.proxy.http", http),
+            ("org.gnome.system.proxy.https", http),
+            ("org.gnome.system.proxy.socks", socks),
+        ] {
+            let (host, port) = match addr {
+                Some(addr) => (addr.host.as_str(), addr.port),
+                None => ("", 0),
+            };
+            settings.set(schema, "host", gvariant_string(host));
+            settings.set(schema, "port", port.to_string());
+        }
+
+        settings
+    }
+
+    /// Arguments of `gsettings` applying the settings, one command for each key
+    pub fn gsettings_args(&self) -> Vec<[&str; 4]> {
+        self.values
+            .iter()
+            .map(|v| ["set", v.schema.as_str(), v.key.as_str(), v.value.as_str()])
+            .collect()
+    }
+
+    /// Apply the settings
+    pub fn apply(&self) -> io::Result<()> {
+        for args in self.gsettings_args() {
+            gsettings(&args)?;
+        }
+        Ok(())
+    }
+
+    fn set(&mut self, schema: &str, key: &str, value: String) {
+        match self.values.iter_mut().find(|v| v.schema == schema && v.key == key) {
+            Some(v) => v.value = value,
+            None => self.values.push(ProxyValue {
+                schema: schema.to_owned(),
+                key: key.to_owned(),
+                value,
+            }),
+        }
+    }
+}
+
+fn gvariant_string(s: &str) -> String {
+    format!("'{}'", s.replace('\\', "\\\\").replace('\'', "\\'"))
+}
+
+fn gsettings(args: &[&str]) -> io::Result<String> {
+    let output = Command::new("gsettings")
+        .args(args)
+        .output()
+        .map_err(|err| Error::new(err.kind(), format!("failed to run gsettings, {}", err)))?;
+
+    if output.status.success() {
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    } else {
+        let err = format!(
+            "gsettings {} failed with {}, {}",
+            args.join(" "),
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+        Err(Error::new(ErrorKind::Other, err))
+    }
+}
@@ -0,0 +1,170 @@
+//! System proxy settings of macOS, by `networksetup`
+//!
+//! Proxies are configured per network service, all enabled services are changed.
+
+use std::{
+    io::{self, Error, ErrorKind},
+    process::Command,
+};
+
+use serde::{Deserialize, Serialize};
+
+use super::ProxyAddr;
+
+/// A proxy of a network service
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct ServiceProxy {
+    enabled: bool,
+    host: String,
+    port: u16,
+}
+
+impl ServiceProxy {
+    /// Parse output of `networksetup -getwebproxy`, lines of `Enabled: Yes`, `Server: HOST`, `Port: PORT`, ...
+    fn parse(output: &str) -> ServiceProxy {
+        let mut proxy = ServiceProxy::default();
+        for line in output.lines() {
+            let (name, value) = match line.split_once(':') {
+                Some((name, value)) => (name.trim(), value.trim()),
+                None => continue,
+            };
+            match name {
+                "Enabled" => proxy.enabled = value.eq_ignore_ascii_case("yes"),
+                "Server" => proxy.host = value.to_owned(),
+                "Port" => proxy.port = value.parse().unwrap_or(0),
+                _ => {}
+            }
+        }
+        proxy
+    }
+
+    fn from_addr(addr: Option<&ProxyAddr>) -> ServiceProxy {
+        match addr {
+            Some(addr) => ServiceProxy {
+                enabled: true,
+                host: addr.host.clone(),
+                port: addr.port,
+            },
+            None => ServiceProxy::default(),
+        }
+    }
+
+    fn apply(&self, service: &str, kind: &str) -> io::Result<()> {
+        // Server couldn't be set to empty, disabled proxies keep their servers
+        if !self.host.is_empty() {
+            networksetup(&[&format!("-set{}", kind), service, &self.host, &self.port.to_string()])?;
+        }
+        networksetup(&[
+            &format!("-set{}state", kind),
+            service,
+            if self.enabled { "on" } else { "off" },
+        ])?;
+        Ok(())
+    }
+}
+
+/// Proxies of a network service
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ServiceSettings {
+    service: String,
+    web: ServiceProxy,
+    secure_web: ServiceProxy,
+    socks: ServiceProxy,
+    bypass: Vec<String>,
+}
+
+/// Proxies of all enabled network services
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProxySettings {
+    services: Vec<ServiceSettings>,
+}
+
+impl ProxySettings {
+    /// Load the current settings
+    pub fn load() -> io::Result<ProxySettings> {
+        let output = networksetup(&["-listallnetworkservices"])?;
+
+        let mut services = Vec::new();
+        // The first line is a notice, disabled services are marked by `*`
+        for service in output.lines().skip(1) {
+            if service.is_empty() || service.starts_with('*') {
+                continue;
+            }
+
+            let bypass = networksetup(&["-getproxybypassdomains", service])?;
+            let bypass = bypass
+                .lines()
+                .map(str::trim)
+                // "There aren't any bypass domains set on SERVICE."
+                .filter(|l| !l.is_empty() && !l.contains(' '))
+                .map(ToOwned::to_owned)
+                .collect();
+
+            services.push(ServiceSettings {
+                service: service.to_owned(),
+                web: ServiceProxy::parse(&networksetup(&["-getwebproxy", service])?),
+                secure_web: ServiceProxy::parse(&networksetup(&["-getsecurewebproxy", service])?),
+                socks: ServiceProxy::parse(&networksetup(&["-getsocksfirewallproxy", service])?),
+                bypass,
+            });
+        }
+
+        Ok(ProxySettings { services })
+    }
+
+    /// Settings pointing to `http` (for both HTTP and HTTPS) and `socks`, except `bypass` hosts
+    pub fn with_proxy(&self, http: Option<&ProxyAddr>, socks: Option<&ProxyAddr>, bypass: &[&str]) -> ProxySettings {
+        let services = self
+            .services
+            .iter()
+            .map(|s| ServiceSettings {
+                service: s.service.clone(),
+                web: ServiceProxy::from_addr(http),
+                secure_web: ServiceProxy::from_addr(http),
+                socks: ServiceProxy::from_addr(socks),
+                bypass: bypass.iter().map(ToString::to_string).collect(),
+            })
+            .collect();
+        ProxySettings { services }
+    }
+
+    /// Apply the settings
+    pub fn apply(&self) -> io::Result<()> {
+        for s in &self.services {
+            s.web.apply(&s.service, "webproxy")?;
+            s.secure_web.apply(&s.service, "securewebproxy")?;
+            s.socks.apply(&s.service, "socksfirewallproxy")?;
+
+            let mut args = vec!["-setproxybypassdomains", s.service.as_str()];
+            if s.bypass.is_empty() {
+                args.push("Empty");
+            } else {
+                args.extend(s.bypass.iter().map(String::as_str));
+            }
+            networksetup(&args)?;
+        }
+        Ok(())
+    }
+}
+
+fn networksetup(args: &[&str]) -> io::Result<String> {
+    let output = Command::new("networksetup")
+        .args(args)
+        .output()
+        .map_err(|err| Error::new(err.kind(), format!("failed to run networksetup, {}", err)))?;
+
+    // networksetup may exit with 0 on errors, which are printed as "** Error: ..."
+    let stdout = String::from_utf8_lossy(&output.stdout).into_owned();
+    if output.status.success() && !stdout.starts_with("** Error") {
+        Ok(stdout)
+    } else {
+        let err = format!(
+            "networksetup {} failed with {}, {}{}",
+            args.join(" "),
+            output.status,
+            stdout.trim(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+        Err(Error::new(ErrorKind::Other, err))
+    }
+}
@@ -0,0 +1,246 @@
+//! Proxy settings of the operating system
+//!
+//! While running, the system proxy points to HTTP and SOCKS listeners of `sslocal`, and the previous settings are
+//! restored on exit. Settings are changed by
+//!
+//! - Windows: WinINET settings of the current user, in `HKCU\Software\Microsoft\Windows\CurrentVersion\Internet Settings`
+//! - macOS: `networksetup` of all enabled network services
+//! - Linux and BSDs: `gsettings` of GNOME, `org.gnome.system.proxy`
+//!
+//! The previous settings are saved in `StateStore` before being changed, and removed after being restored. Settings
+//! left by a crashed process are restored by the next start, if states are persisted by `state_dir`.
+
+use std::{
+    fmt::{self, Display},
+    io::{self, ErrorKind},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    sync::Arc,
+};
+
+use cfg_if::cfg_if;
+use log::{error, info, warn};
+use shadowsocks::ServerAddr;
+
+use crate::{
+    config::{LocalConfig, ProtocolType},
+    store::StateStore,
+};
+
+cfg_if! {
+    if #[cfg(windows)] {
+        mod windows;
+        pub use self::windows::ProxySettings;
+    } else if #[cfg(target_os = "macos")] {
+        mod macos;
+        pub use self::macos::ProxySettings;
+    } else {
+        mod gnome;
+        pub use self::gnome::ProxySettings;
+    }
+}
+
+const SYSTEM_PROXY_STATE_KEY: &str = "system-proxy";
+
+/// Hosts that are connected directly, instead of through the system proxy
+const BYPASS_HOSTS: &[&str] = &[
+    "localhost",
+    "127.0.0.0/8",
+    "::1",
+    "10.0.0.0/8",
+    "172.16.0.0/12",
+    "192.168.0.0/16",
+    "169.254.0.0/16",
+    "*.local",
+];
+
+/// Address of a listener, as written in proxy settings
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProxyAddr {
+    /// Host name or IP address, without brackets
+    pub host: String,
+    /// Port
+    pub port: u16,
+}
+
+impl ProxyAddr {
+    /// Listeners on unspecified addresses (`0.0.0.0` or `::`) are referred by loopback addresses
+    fn from_listen_addr(addr: &ServerAddr) -> ProxyAddr {
+        match *addr {
+            ServerAddr::SocketAddr(sa) => {
+                let ip = match sa.ip() {
+                    IpAddr::V4(ip) if ip.is_unspecified() => IpAddr::V4(Ipv4Addr::LOCALHOST),
+                    IpAddr::V6(ip) if ip.is_unspecified() => IpAddr::V6(Ipv6Addr::LOCALHOST),
+                    ip => ip,
+                };
+                ProxyAddr {
+                    host: ip.to_string(),
+                    port: sa.port(),
+                }
+            }
+            ServerAddr::DomainName(ref host, port) => ProxyAddr {
+                host: host.clone(),
+                port,
+            },
+        }
+    }
+}
+
+impl Display for ProxyAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.host.contains(':') {
+            write!(f, "[{}]:{}", self.host, self.port)
+        } else {
+            write!(f, "{}:{}", self.host, self.port)
+        }
+    }
+}
+
+/// Proxies that the system proxy points to
+#[derive(Debug, Clone, Default)]
+pub struct SystemProxyConfig {
+    /// HTTP proxy, also used for HTTPS
+    pub http: Option<ProxyAddr>,
+    /// SOCKS5 proxy
+    pub socks: Option<ProxyAddr>,
+}
+
+impl SystemProxyConfig {
+    /// Choose the first HTTP and SOCKS listeners of `locals`
+    ///
+    /// Listeners on Unix Domain Sockets and TLS are not supported by system proxies
+    pub fn from_locals(locals: &[LocalConfig]) -> SystemProxyConfig {
+        let mut config = SystemProxyConfig::default();
+
+        for local in locals {
+            let addr = match local.addr {
+                Some(ref a) => ProxyAddr::from_listen_addr(a),
+                None => continue,
+            };
+
+            #[cfg(unix)]
+            if local.unix_addr.is_some() {
+                continue;
+            }
+
+            #[cfg(any(feature = "local-socks-tls", feature = "local-http-tls", feature = "local-dns-tls"))]
+            if local.tls.is_some() {
+                continue;
+            }
+
+            if !local.mode.enable_tcp() {
+                continue;
+            }
+
+            let (http, socks) = match local.protocol {
+                #[cfg(feature = "local-http")]
+                ProtocolType::Http => (true, false),
+                #[cfg(feature = "local-http")]
+                ProtocolType::Mixed => (true, true),
+                ProtocolType::Socks => (false, true),
+                #[allow(unreachable_patterns)]
+                _ => (false, false),
+            };
+
+            if http && config.http.is_none() {
+                config.http = Some(addr.clone());
+            }
+            if socks && config.socks.is_none() {
+                config.socks = Some(addr);
+            }
+        }
+
+        config
+    }
+
+    /// Check if there are no proxies
+    pub fn is_empty(&self) -> bool {
+        self.http.is_none() && self.socks.is_none()
+    }
+}
+
+/// Changed system proxy settings, the previous settings are restored when it is dropped
+pub struct SystemProxyGuard {
+    previous: ProxySettings,
+    state_store: Arc<dyn StateStore>,
+}
+
+impl Drop for SystemProxyGuard {
+    fn drop(&mut self) {
+        match self.previous.apply() {
+            Ok(..) => {
+                info!("system proxy settings restored");
+                if let Err(err) = self.state_store.remove(SYSTEM_PROXY_STATE_KEY) {
+                    warn!("failed to remove saved system proxy settings, error: {}", err);
+                }
+            }
+            // Kept in the store, retried by the next start
+            Err(err) => error!("failed to restore system proxy settings, error: {}", err),
+        }
+    }
+}
+
+/// Point the system proxy to proxies of `config`, until the returned guard is dropped
+///
+/// If settings left by a crashed process are found in `state_store`, they are kept as the previous settings.
+pub fn enable_system_proxy(
+    config: &SystemProxyConfig,
+    state_store: Arc<dyn StateStore>,
+) -> io::Result<SystemProxyGuard> {
+    let previous = match load_saved(state_store.as_ref())? {
+        Some(previous) => {
+            info!("found system proxy settings left by the last run, they will be restored on exit");
+            previous
+        }
+        None => {
+            let previous = ProxySettings::load()?;
+            let value = serde_json::to_vec(&previous).map_err(|err| io::Error::new(ErrorKind::Other, err))?;
+            state_store.save(SYSTEM_PROXY_STATE_KEY, &value)?;
+            previous
+        }
+    };
+
+    let guard = SystemProxyGuard { previous, state_store };
+    guard
+        .previous
+        .with_proxy(config.http.as_ref(), config.socks.as_ref(), BYPASS_HOSTS)
+        .apply()?;
+
+    info!(
+        "system proxy settings changed, http: {}, socks: {}",
+        display_addr(config.http.as_ref()),
+        display_addr(config.socks.as_ref())
+    );
+
+    Ok(guard)
+}
+
+/// Restore settings left by a crashed process in `state_store`, if there are any
+pub fn recover_system_proxy(state_store: &dyn StateStore) -> io::Result<()> {
+    if let Some(previous) = load_saved(state_store)? {
+        previous.apply()?;
+        state_store.remove(SYSTEM_PROXY_STATE_KEY)?;
+        info!("system proxy settings left by the last run are restored");
+    }
+    Ok(())
+}
+
+fn load_saved(state_store: &dyn StateStore) -> io::Result<Option<ProxySettings>> {
+    match state_store.load(SYSTEM_PROXY_STATE_KEY)? {
+        Some(value) => match serde_json::from_slice(&value) {
+            Ok(settings) => Ok(Some(settings)),
+            Err(err) => {
+                warn!("ignored invalid saved system proxy settings, error: {}", err);
+                state_store.remove(SYSTEM_PROXY_STATE_KEY)?;
+                Ok(None)
+            }
+        },
+        None => Ok(None),
+    }
+}
+
+fn display_addr(addr: Option<&ProxyAddr>) -> String {
+    match addr {
+        Some(addr) => addr.to_string(),
+        None => "none".to_owned(),
+    }
+}
@@ -0,0 +1,258 @@
+//! System proxy settings of Windows, WinINET settings of the current user
+//!
+//! Settings are values of `HKCU\Software\Microsoft\Windows\CurrentVersion\Internet Settings`, applications are
+//! notified by `InternetSetOption` after they are changed.
+
+use std::{
+    ffi::OsStr,
+    io::{self, Error},
+    iter,
+    mem,
+    net::Ipv6Addr,
+    os::windows::ffi::OsStrExt,
+    ptr,
+};
+
+use ipnet::Ipv4Net;
+use serde::{Deserialize, Serialize};
+use winapi::{
+    shared::{
+        minwindef::DWORD,
+        winerror::{ERROR_FILE_NOT_FOUND, ERROR_SUCCESS},
+    },
+    um::{
+        wininet::{InternetSetOptionW, INTERNET_OPTION_REFRESH, INTERNET_OPTION_SETTINGS_CHANGED},
+        winnt::{REG_DWORD, REG_SZ},
+        winreg::{
+            RegDeleteKeyValueW,
+            RegGetValueW,
+            RegSetKeyValueW,
+            HKEY_CURRENT_USER,
+            RRF_RT_REG_DWORD,
+            RRF_RT_REG_SZ,
+        },
+    },
+};
+
+use super::ProxyAddr;
+
+const INTERNET_SETTINGS_KEY: &str = r"Software\Microsoft\Windows\CurrentVersion\Internet Settings";
+
+/// WinINET proxy settings, `None` if the value doesn't exist
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProxySettings {
+    proxy_enable: Option<u32>,
+    proxy_server: Option<String>,
+    proxy_override: Option<String>,
+    /// PAC URL takes precedence over `ProxyServer`
+    auto_config_url: Option<String>,
+}
+
+impl ProxySettings {
+    /// Load the current settings
+    pub fn load() -> io::Result<ProxySettings> {
+        Ok(ProxySettings {
+            proxy_enable: get_dword("ProxyEnable")?,
+            proxy_server: get_string("ProxyServer")?,
+            proxy_override: get_string("ProxyOverride")?,
+            auto_config_url: get_string("AutoConfigURL")?,
+        })
+    }
+
+    /// Settings pointing to `http` (for both HTTP and HTTPS) and `socks`, except `bypass` hosts
+    pub fn with_proxy(&self, http: Option<&ProxyAddr>, socks: Option<&ProxyAddr>, bypass: &[&str]) -> ProxySettings {
+        let mut servers = Vec::new();
+        if let Some(http) = http {
+            servers.push(format!("http={}", http));
+            servers.push(format!("https={}", http));
+        }
+        if let Some(socks) = socks {
+            servers.push(format!("socks={}", socks));
+        }
+
+        // Wildcards instead of CIDRs, `<local>` for host names without dots
+        let mut overrides = bypass.iter().flat_map(|h| wildcards(h)).collect::<Vec<_>>();
+        overrides.push("<local>".to_owned());
+
+        ProxySettings {
+            proxy_enable: Some(1),
+            proxy_server: Some(servers.join(";")),
+            proxy_override: Some(overrides.join(";")),
+            auto_config_url: None,
+        }
+    }
+
+    /// Apply the settings, and notify applications
+    pub fn apply(&self) -> io::Result<()> {
+        match self.proxy_enable {
+            Some(v) => set_dword("ProxyEnable", v)?,
+            None => delete_value("ProxyEnable")?,
+        }
+        for (name, value) in [
+            ("ProxyServer", &self.proxy_server),
+            ("ProxyOverride", &self.proxy_override),
+            ("AutoConfigURL", &self.auto_config_url),
+        ] {
+            match *value {
+                Some(ref v) => set_string(name, v)?,
+                None => delete_value(name)?,
+            }
+        }
+
+        unsafe {
+            InternetSetOptionW(ptr::null_mut(), INTERNET_OPTION_SETTINGS_CHANGED, ptr::null_mut(), 0);
+            InternetSetOptionW(ptr::null_mut(), INTERNET_OPTION_REFRESH, ptr::null_mut(), 0);
+        }
+
+        Ok(())
+    }
+}
+
+/// Convert an IPv4 CIDR to wildcards of whole octets, such as `172.16.0.0/12` to `172.16.*`, ..., `172.31.*`, and
+/// bracket IPv6 addresses
+fn wildcards(host: &str) -> Vec<String> {
+    if let Ok(ip) = host.parse::<Ipv6Addr>() {
+        return vec![format!("[{}]", ip)];
+    }
+
+    let net = match host.parse::<Ipv4Net>() {
+        Ok(net) => net,
+        Err(..) => return vec![host.to_owned()],
+    };
+
+    let octets = (net.prefix_len() + 7) / 8;
+    match net.subnets(octets * 8) {
+        Ok(subnets) => subnets
+            .map(|subnet| {
+                let mut wildcard = subnet.network().octets()[..octets as usize]
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect::<Vec<_>>();
+                if octets < 4 {
+                    wildcard.push("*".to_owned());
+                }
+                wildcard.join(".")
+            })
+            .collect(),
+        Err(..) => vec![host.to_owned()],
+    }
+}
+
+fn to_wide(s: &str) -> Vec<u16> {
+    OsStr::new(s).encode_wide().chain(iter::once(0)).collect()
+}
+
+fn check_status(status: i32, name: &str) -> io::Result<()> {
+    if status as DWORD == ERROR_SUCCESS {
+        Ok(())
+    } else {
+        let err = Error::from_raw_os_error(status);
+        Err(Error::new(err.kind(), format!("registry value {}, {}", name, err)))
+    }
+}
+
+fn get_dword(name: &str) -> io::Result<Option<u32>> {
+    let key = to_wide(INTERNET_SETTINGS_KEY);
+    let value = to_wide(name);
+    let mut data: DWORD = 0;
+    let mut size = mem::size_of::<DWORD>() as DWORD;
+
+    let status = unsafe {
+        RegGetValueW(
+            HKEY_CURRENT_USER,
+            key.as_ptr(),
+            value.as_ptr(),
+            RRF_RT_REG_DWORD,
+            ptr::null_mut(),
+            &mut data as *mut DWORD as *mut _,
+            &mut size,
+        )
+    };
+    if status as DWORD == ERROR_FILE_NOT_FOUND {
+        return Ok(None);
+    }
+    check_status(status, name)?;
+    Ok(Some(data))
+}
+
+fn get_string(name: &str) -> io::Result<Option<String>> {
+    let key = to_wide(INTERNET_SETTINGS_KEY);
+    let value = to_wide(name);
+    let mut size: DWORD = 0;
+
+    let status = unsafe {
+        RegGetValueW(
+            HKEY_CURRENT_USER,
+            key.as_ptr(),
+            value.as_ptr(),
+            RRF_RT_REG_SZ,
+            ptr::null_mut(),
+            ptr::null_mut(),
+            &mut size,
+        )
+    };
+    if status as DWORD == ERROR_FILE_NOT_FOUND {
+        return Ok(None);
+    }
+    check_status(status, name)?;
+
+    let mut buf = vec![0u16; (size as usize + 1) / 2];
+    let status = unsafe {
+        RegGetValueW(
+            HKEY_CURRENT_USER,
+            key.as_ptr(),
+            value.as_ptr(),
+            RRF_RT_REG_SZ,
+            ptr::null_mut(),
+            buf.as_mut_ptr() as *mut _,
+            &mut size,
+        )
+    };
+    check_status(status, name)?;
+
+    let len = buf.iter().position(|c| *c == 0).unwrap_or(buf.len());
+    Ok(Some(String::from_utf16_lossy(&buf[..len])))
+}
+
+fn set_dword(name: &str, data: u32) -> io::Result<()> {
+    let key = to_wide(INTERNET_SETTINGS_KEY);
+    let value = to_wide(name);
+    let status = unsafe {
+        RegSetKeyValueW(
+            HKEY_CURRENT_USER,
+            key.as_ptr(),
+            value.as_ptr(),
+            REG_DWORD,
+            &data as *const u32 as *const _,
+            mem::size_of::<u32>() as DWORD,
+        )
+    };
+    check_status(status, name)
+}
+
+fn set_string(name: &str, data: &str) -> io::Result<()> {
+    let key = to_wide(INTERNET_SETTINGS_KEY);
+    let value = to_wide(name);
+    let data = to_wide(data);
+    let status = unsafe {
+        RegSetKeyValueW(
+            HKEY_CURRENT_USER,
+            key.as_ptr(),
+            value.as_ptr(),
+            REG_SZ,
+            data.as_ptr() as *const _,
+            (data.len() * mem::size_of::<u16>()) as DWORD,
+        )
+    };
+    check_status(status, name)
+}
+
+fn delete_value(name: &str) -> io::Result<()> {
+    let key = to_wide(INTERNET_SETTINGS_KEY);
+    let value = to_wide(name);
+    let status = unsafe { RegDeleteKeyValueW(HKEY_CURRENT_USER, key.as_ptr(), value.as_ptr()) };
+    if status as DWORD == ERROR_FILE_NOT_FOUND {
+        return Ok(());
+    }
+    check_status(status, name)
+}
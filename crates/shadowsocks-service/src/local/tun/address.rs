@@ -0,0 +1,187 @@
+//! Address of tun interfaces
+//!
+//! Tun's network must not collide with networks of the host's interfaces, and must not contain addresses of the
+//! shadowsocks servers, otherwise packets will be routed to the wrong interface.
+
+use std::{
+    fmt::{self, Display},
+    io::{self, ErrorKind},
+    net::{IpAddr, Ipv4Addr},
+};
+
+use ipnet::{IpNet, Ipv4Net};
+use log::trace;
+
+/// Networks that free tun addresses are chosen from, in order
+///
+/// They are rarely used by LANs, each of them is split into /24 networks
+const AUTO_ADDRESS_POOLS: [(Ipv4Addr, u8); 3] = [
+    (Ipv4Addr::new(10, 255, 0, 0), 16),
+    (Ipv4Addr::new(172, 31, 0, 0), 16),
+    (Ipv4Addr::new(100, 127, 0, 0), 16),
+];
+
+/// Prefix length of automatically chosen tun addresses
+const AUTO_ADDRESS_PREFIX_LEN: u8 = 24;
+
+/// Network configured on an interface of the host
+#[derive(Debug, Clone)]
+pub struct HostNetwork {
+    /// Name of the interface
+    pub interface: String,
+    /// Address and network of the interface
+    pub network: IpNet,
+}
+
+impl Display for HostNetwork {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({})", self.network, self.interface)
+    }
+}
+
+/// Networks of all interfaces of the host
+#[cfg(all(unix, not(target_os = "android")))]
+pub fn host_networks() -> io::Result<Vec<HostNetwork>> {
+    use std::{ffi::CStr, ptr};
+
+    let mut networks = Vec::new();
+
+    unsafe {
+        let mut ifap: *mut libc::ifaddrs = ptr::null_mut();
+        if libc::getifaddrs(&mut ifap) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut ifa = ifap;
+        while !ifa.is_null() {
+            let rifa = &*ifa;
+            ifa = rifa.ifa_next;
+
+            let addr = match sockaddr_to_ip(rifa.ifa_addr) {
+                Some(a) => a,
+                None => continue,
+            };
+            let prefix_len = match sockaddr_to_ip(rifa.ifa_netmask) {
+                Some(IpAddr::V4(m)) => u32::from(m).count_ones() as u8,
+                Some(IpAddr::V6(m)) => u128::from(m).count_ones() as u8,
+                None if addr.is_ipv4() => 32,
+                None => 128,
+            };
+            let network = match IpNet::new(addr, prefix_len) {
+                Ok(n) => n,
+                Err(..) => continue,
+            };
+
+            let interface = CStr::from_ptr(rifa.ifa_name).to_string_lossy().into_owned();
+            networks.push(HostNetwork { interface, network });
+        }
+
+        libc::freeifaddrs(ifap);
+    }
+
+    Ok(networks)
+}
+
+/// Networks of all interfaces of the host
+///
+/// Not supported on this platform, networks of tun interfaces are assigned by the system
+#[cfg(not(all(unix, not(target_os = "android"))))]
+pub fn host_networks() -> io::Result<Vec<HostNetwork>> {
+    Ok(Vec::new())
+}
+
+#[cfg(all(unix, not(target_os = "android")))]
+unsafe fn sockaddr_to_ip(sa: *const libc::sockaddr) -> Option<IpAddr> {
+    use std::net::Ipv6Addr;
+
+    if sa.is_null() {
+        return None;
+    }
+
+    match (*sa).sa_family as libc::c_int {
+        libc::AF_INET => {
+            let sin = &*(sa as *const libc::sockaddr_in);
+            Some(IpAddr::V4(Ipv4Addr::from(u32::from_be(sin.sin_addr.s_addr))))
+        }
+        libc::AF_INET6 => {
+            let sin6 = &*(sa as *const libc::sockaddr_in6);
+            Some(IpAddr::V6(Ipv6Addr::from(sin6.sin6_addr.s6_addr)))
+        }
+        _ => None,
+    }
+}
+
+fn overlaps(a: &IpNet, b: &IpNet) -> bool {
+    a.contains(&b.network()) || b.contains(&a.network())
+}
+
+/// Check if `address` is safe to be configured on the tun interface `tun_name`
+///
+/// Networks of the interface `tun_name` itself are ignored, it may have been configured by the previous run.
+pub fn check_address(address: &IpNet, tun_name: Option<&str>, servers: &[IpAddr]) -> io::Result<()> {
+    if let Some(server) = servers.iter().find(|s| address.contains(*s)) {
+        let err = io::Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "tun address {} contains server address {}, packets to the server will be routed back to tun",
+                address, server
+            ),
+        );
+        return Err(err);
+    }
+
+    for network in host_networks()? {
+        if Some(network.interface.as_str()) == tun_name {
+            continue;
+        }
+
+        if overlaps(address, &network.network) {
+            let err = io::Error::new(
+                ErrorKind::AddrInUse,
+                format!(
+                    "tun address {} collides with network {} of the host, choose another one or set it to \"auto\"",
+                    address, network
+                ),
+            );
+            return Err(err);
+        }
+    }
+
+    Ok(())
+}
+
+/// Choose an address for tun interface `tun_name` that doesn't collide with any networks of the host and `servers`
+pub fn choose_address(tun_name: Option<&str>, servers: &[IpAddr]) -> io::Result<IpNet> {
+    let networks = host_networks()?;
+
+    for (pool_addr, pool_prefix_len) in AUTO_ADDRESS_POOLS {
+        let pool = Ipv4Net::new(pool_addr, pool_prefix_len).expect("tun address pool");
+        let subnets = pool.subnets(AUTO_ADDRESS_PREFIX_LEN).expect("tun address pool subnets");
+
+        for subnet in subnets {
+            let host = match subnet.hosts().next() {
+                Some(h) => h,
+                None => continue,
+            };
+            let address = IpNet::V4(Ipv4Net::new(host, AUTO_ADDRESS_PREFIX_LEN).expect("tun address"));
+
+            if servers.iter().any(|s| address.contains(s)) {
+                continue;
+            }
+            if let Some(network) = networks
+                .iter()
+                .find(|n| Some(n.interface.as_str()) != tun_name && overlaps(&address, &n.network))
+            {
+                trace!("tun address {} collides with network {}", address, network);
+                continue;
+            }
+
+            return Ok(address);
+        }
+    }
+
+    Err(io::Error::new(
+        ErrorKind::AddrNotAvailable,
+        "couldn't find a free tun address, all candidates collide with networks of the host",
+    ))
+}
@@ -0,0 +1,134 @@
+//! Tun configuration
+
+use std::{
+    fmt::{self, Display},
+    str::FromStr,
+    time::Duration,
+};
+
+/// Preset of TCP parameters of tun's network stack
+///
+/// smoltcp doesn't have congestion control, throughput of each connection is limited by its receive window (size of
+/// the receive buffer) divided by the round-trip time between clients and tun, which includes the manager thread's
+/// poll interval and ACK delay. Window scaling is enabled automatically for buffers larger than 64KiB.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TunTcpProfile {
+    /// Clients are on the same host or LAN, small buffers are enough
+    #[default]
+    Lan,
+    /// Clients are routed to tun over long-fat links, for example, `sslocal` running on a gateway.
+    /// Larger buffers for higher bandwidth-delay products, and ACKs are sent without delay.
+    Wan,
+}
+
+impl TunTcpProfile {
+    /// As string representation
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            TunTcpProfile::Lan => "lan",
+            TunTcpProfile::Wan => "wan",
+        }
+    }
+}
+
+impl Display for TunTcpProfile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for TunTcpProfile {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "lan" => Ok(TunTcpProfile::Lan),
+            "wan" => Ok(TunTcpProfile::Wan),
+            _ => Err(()),
+        }
+    }
+}
+
+/// TCP parameters of tun's network stack
+///
+/// Parameters that are not set are chosen by `profile`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TunTcpConfig {
+    /// Preset of the other parameters
+    pub profile: TunTcpProfile,
+    /// Send buffer size of each connection
+    ///
+    /// Falls back to `inbound_send_buffer_size` if not set
+    pub send_buffer_size: Option<u32>,
+    /// Receive buffer size of each connection, which is also the maximum receive window
+    ///
+    /// Falls back to `inbound_recv_buffer_size` if not set
+    pub recv_buffer_size: Option<u32>,
+    /// Delay of ACKs, `Some(Duration::ZERO)` disables delayed ACK
+    pub ack_delay: Option<Duration>,
+    /// Enable Nagle's algorithm
+    pub nagle: Option<bool>,
+    /// Connections will be aborted if sent data couldn't be acknowledged in this duration
+    pub timeout: Option<Duration>,
+}
+
+// NOTE: Buffer could contain 20 AEAD packets
+const LAN_TCP_BUFFER_SIZE: u32 = 0x3FFF * 20;
+const WAN_TCP_BUFFER_SIZE: u32 = 1024 * 1024;
+
+/// smoltcp's default
+const LAN_TCP_ACK_DELAY: Duration = Duration::from_millis(10);
+
+// FIXME: It should follow system's setting. 7200 is Linux's default.
+const DEFAULT_TCP_TIMEOUT: Duration = Duration::from_secs(7200);
+
+impl TunTcpConfig {
+    /// Create a configuration with `profile`
+    pub fn new(profile: TunTcpProfile) -> TunTcpConfig {
+        TunTcpConfig {
+            profile,
+            ..Default::default()
+        }
+    }
+
+    /// Default send buffer size of `profile`
+    pub fn default_send_buffer_size(&self) -> u32 {
+        match self.profile {
+            TunTcpProfile::Lan => LAN_TCP_BUFFER_SIZE,
+            TunTcpProfile::Wan => WAN_TCP_BUFFER_SIZE,
+        }
+    }
+
+    /// Default receive buffer size of `profile`
+    pub fn default_recv_buffer_size(&self) -> u32 {
+        match self.profile {
+            TunTcpProfile::Lan => LAN_TCP_BUFFER_SIZE,
+            TunTcpProfile::Wan => WAN_TCP_BUFFER_SIZE,
+        }
+    }
+
+    /// ACK delay, `None` if delayed ACK is disabled
+    pub fn effective_ack_delay(&self) -> Option<Duration> {
+        match self.ack_delay {
+            Some(d) if d.is_zero() => None,
+            Some(d) => Some(d),
+            None => match self.profile {
+                TunTcpProfile::Lan => Some(LAN_TCP_ACK_DELAY),
+                TunTcpProfile::Wan => None,
+            },
+        }
+    }
+
+    /// Whether Nagle's algorithm is enabled
+    pub fn effective_nagle(&self) -> bool {
+        self.nagle.unwrap_or(match self.profile {
+            TunTcpProfile::Lan => true,
+            TunTcpProfile::Wan => false,
+        })
+    }
+
+    /// Timeout of unacknowledged data
+    pub fn effective_timeout(&self) -> Duration {
+        self.timeout.unwrap_or(DEFAULT_TCP_TIMEOUT)
+    }
+}
@@ -34,6 +34,14 @@ impl<T: AsRef<[u8]> + Copy> IpPacket<T> {
         }
     }
 
+    /// IPv4's TTL or IPv6's hop limit
+    pub fn hop_limit(&self) -> u8 {
+        match *self {
+            IpPacket::Ipv4(ref packet) => packet.hop_limit(),
+            IpPacket::Ipv6(ref packet) => packet.hop_limit(),
+        }
+    }
+
     pub fn protocol(&self) -> IpProtocol {
         match *self {
             IpPacket::Ipv4(ref packet) => packet.protocol(),
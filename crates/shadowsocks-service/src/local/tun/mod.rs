@@ -4,7 +4,7 @@
 use std::os::unix::io::RawFd;
 use std::{
     io::{self, ErrorKind},
-    net::SocketAddr,
+    net::{IpAddr, SocketAddr},
     sync::Arc,
     time::Duration,
 };
@@ -12,20 +12,26 @@ use std::{
 use byte_string::ByteStr;
 use ipnet::IpNet;
 use log::{debug, error, info, trace, warn};
-use shadowsocks::config::Mode;
+use shadowsocks::{config::Mode, ServerAddr};
 use smoltcp::wire::{IpProtocol, TcpPacket, UdpPacket};
 use tokio::{io::AsyncReadExt, sync::mpsc, time};
 use tun::{AsyncDevice, Configuration as TunConfiguration, Device as TunDevice, Error as TunError, Layer};
 
-use crate::local::{context::ServiceContext, loadbalancing::PingBalancer};
+use crate::{
+    local::{context::ServiceContext, loadbalancing::PingBalancer, net::UdpNatConfig, pause::PauseController},
+    net::P2pPolicy,
+};
 
 use self::{
+    config::TunTcpConfig,
     ip_packet::IpPacket,
     sys::{write_packet_with_pi, IFF_PI_PREFIX_LEN},
     tcp::TcpTun,
     udp::UdpTun,
 };
 
+pub mod address;
+pub mod config;
 mod ip_packet;
 mod sys;
 mod tcp;
@@ -36,9 +42,16 @@ pub struct TunBuilder {
     context: Arc<ServiceContext>,
     balancer: PingBalancer,
     tun_config: TunConfiguration,
+    address: Option<IpNet>,
+    auto_address: bool,
+    name: Option<String>,
+    tcp_config: TunTcpConfig,
     udp_expiry_duration: Option<Duration>,
     udp_capacity: Option<usize>,
+    udp_nat: UdpNatConfig,
     mode: Mode,
+    p2p_policy: P2pPolicy,
+    preserve_ttl: bool,
 }
 
 impl TunBuilder {
@@ -47,19 +60,33 @@ impl TunBuilder {
             context,
             balancer,
             tun_config: TunConfiguration::default(),
+            address: None,
+            auto_address: false,
+            name: None,
+            tcp_config: TunTcpConfig::default(),
             udp_expiry_duration: None,
             udp_capacity: None,
+            udp_nat: UdpNatConfig::default(),
             mode: Mode::TcpOnly,
+            p2p_policy: P2pPolicy::Allow,
+            preserve_ttl: false,
         }
     }
 
     pub fn address(mut self, addr: IpNet) -> TunBuilder {
-        self.tun_config.address(addr.addr()).netmask(addr.netmask());
+        self.address = Some(addr);
+        self
+    }
+
+    /// Choose an address that doesn't collide with the host's networks and servers
+    pub fn auto_address(mut self) -> TunBuilder {
+        self.auto_address = true;
         self
     }
 
     pub fn name(mut self, name: &str) -> TunBuilder {
         self.tun_config.name(name);
+        self.name = Some(name.to_owned());
         self
     }
 
@@ -69,6 +96,12 @@ impl TunBuilder {
         self
     }
 
+    /// TCP parameters of the network stack
+    pub fn tcp_config(mut self, tcp_config: TunTcpConfig) -> TunBuilder {
+        self.tcp_config = tcp_config;
+        self
+    }
+
     pub fn udp_expiry_duration(mut self, udp_expiry_duration: Duration) -> TunBuilder {
         self.udp_expiry_duration = Some(udp_expiry_duration);
         self
@@ -79,12 +112,46 @@ impl TunBuilder {
         self
     }
 
+    /// NAT filtering and timeouts by target ports of UDP associations
+    pub fn udp_nat(mut self, udp_nat: UdpNatConfig) -> TunBuilder {
+        self.udp_nat = udp_nat;
+        self
+    }
+
     pub fn mode(mut self, mode: Mode) -> TunBuilder {
         self.mode = mode;
         self
     }
 
+    /// Policy of BitTorrent traffic sent through servers
+    pub fn p2p_policy(mut self, p2p_policy: P2pPolicy) -> TunBuilder {
+        self.p2p_policy = p2p_policy;
+        self
+    }
+
+    /// Copy TTL (or IPv6 hop limit) of clients' TCP connections onto bypassed connections
+    pub fn preserve_ttl(mut self, preserve_ttl: bool) -> TunBuilder {
+        self.preserve_ttl = preserve_ttl;
+        self
+    }
+
     pub async fn build(mut self) -> io::Result<Tun> {
+        if self.auto_address || self.address.is_some() {
+            let servers = self.server_ips().await;
+
+            let addr = if self.auto_address {
+                let addr = address::choose_address(self.name.as_deref(), &servers)?;
+                info!("shadowsocks tun chose address {} automatically", addr);
+                addr
+            } else {
+                let addr = self.address.unwrap();
+                address::check_address(&addr, self.name.as_deref(), &servers)?;
+                addr
+            };
+
+            self.tun_config.address(addr.addr()).netmask(addr.netmask());
+        }
+
         self.tun_config.layer(Layer::L3).up();
 
         #[cfg(any(target_os = "linux"))]
@@ -104,12 +171,19 @@ impl TunBuilder {
             self.balancer.clone(),
             self.udp_expiry_duration,
             self.udp_capacity,
+            self.udp_nat.clone(),
+            self.p2p_policy.clone(),
         );
 
+        let pause_controller = self.context.pause_controller().clone();
+
         let tcp = TcpTun::new(
             self.context,
             self.balancer,
             device.get_ref().mtu().unwrap_or(1500) as u32,
+            self.tcp_config,
+            self.p2p_policy,
+            self.preserve_ttl,
         );
 
         Ok(Tun {
@@ -119,8 +193,31 @@ impl TunBuilder {
             udp_cleanup_interval,
             udp_keepalive_rx,
             mode: self.mode,
+            pause_controller,
         })
     }
+
+    /// IP addresses of servers, which must not be routed to tun
+    async fn server_ips(&self) -> Vec<IpAddr> {
+        let mut ips = Vec::new();
+        for server in self.balancer.servers() {
+            match *server.server_config().addr() {
+                ServerAddr::SocketAddr(ref sa) => ips.push(sa.ip()),
+                ServerAddr::DomainName(ref dname, port) => {
+                    match self.context.context_ref().dns_resolve(dname, port).await {
+                        Ok(addrs) => ips.extend(addrs.map(|a| a.ip())),
+                        Err(err) => {
+                            warn!(
+                                "tun couldn't resolve server {} for checking address collision, error: {}",
+                                dname, err
+                            );
+                        }
+                    }
+                }
+            }
+        }
+        ips
+    }
 }
 
 pub struct Tun {
@@ -130,6 +227,7 @@ pub struct Tun {
     udp_cleanup_interval: Duration,
     udp_keepalive_rx: mpsc::Receiver<SocketAddr>,
     mode: Mode,
+    pause_controller: Arc<PauseController>,
 }
 
 impl Tun {
@@ -146,9 +244,17 @@ impl Tun {
 
         let mut packet_buffer = vec![0u8; 65536 + IFF_PI_PREFIX_LEN].into_boxed_slice();
         let mut udp_cleanup_timer = time::interval(self.udp_cleanup_interval);
+        let pause_controller = self.pause_controller.clone();
 
         loop {
             tokio::select! {
+                // Stop reading the device and polling the TCP stack until resumed
+                _ = pause_controller.wait_paused() => {
+                    self.tcp.pause();
+                    pause_controller.wait_resumed().await;
+                    self.tcp.resume();
+                }
+
                 // tun device
                 n = self.device.read(&mut packet_buffer) => {
                     let n = n?;
@@ -240,7 +346,11 @@ impl Tun {
                 trace!("[TUN] TCP packet {} -> {} {}", src_addr, dst_addr, tcp_packet);
 
                 // TCP first handshake packet.
-                if let Err(err) = self.tcp.handle_packet(src_addr, dst_addr, &tcp_packet).await {
+                if let Err(err) = self
+                    .tcp
+                    .handle_packet(src_addr, dst_addr, packet.hop_limit(), &tcp_packet)
+                    .await
+                {
                     error!(
                         "handle TCP packet failed, error: {}, {} <-> {}, packet: {:?}",
                         err, src_addr, dst_addr, tcp_packet
@@ -10,17 +10,17 @@ use std::{
     },
     task::{Context, Poll, Waker},
     thread::{self, JoinHandle, Thread},
-    time::Duration,
+    time::{Duration, Instant},
 };
 
-use log::{error, trace};
-use shadowsocks::{net::TcpSocketOpts, relay::socks5::Address};
+use log::{debug, error, trace};
+use shadowsocks::relay::socks5::Address;
 use smoltcp::{
     iface::{Interface, InterfaceBuilder, Routes, SocketHandle},
     phy::{DeviceCapabilities, Medium},
     socket::{TcpSocket, TcpSocketBuffer, TcpState},
     storage::RingBuffer,
-    time::{Duration as SmolDuration, Instant as SmolInstant},
+    time::Instant as SmolInstant,
     wire::{IpAddress, IpCidr, Ipv4Address, Ipv6Address, TcpPacket},
 };
 use spin::Mutex as SpinMutex;
@@ -29,18 +29,35 @@ use tokio::{
     sync::mpsc,
 };
 
-use crate::local::{
-    context::ServiceContext,
-    loadbalancing::PingBalancer,
-    net::AutoProxyClientStream,
-    utils::{establish_tcp_tunnel, to_ipv4_mapped},
+#[cfg(feature = "acl")]
+use crate::{acl::RejectMode, local::utils::respond_rejected};
+use crate::{
+    local::{
+        context::ServiceContext,
+        loadbalancing::PingBalancer,
+        net::AutoProxyClientStream,
+        utils::{establish_tcp_tunnel, to_ipv4_mapped},
+    },
+    log_fields,
+    net::{ConcurrencyGuard, ConcurrencyKind, ConcurrencyStat, P2pPolicy},
 };
 
-use super::virt_device::VirtTunDevice;
+use super::{config::TunTcpConfig, virt_device::VirtTunDevice};
 
-// NOTE: Default buffer could contain 20 AEAD packets
-const DEFAULT_TCP_SEND_BUFFER_SIZE: u32 = 0x3FFF * 20;
-const DEFAULT_TCP_RECV_BUFFER_SIZE: u32 = 0x3FFF * 20;
+/// Poll interval of the manager thread while there are TCP connections alive
+///
+/// Connections' buffers are flushed to smoltcp's sockets in this interval, so it have to be small.
+const ACTIVE_POLL_INTERVAL: Duration = Duration::from_millis(5);
+/// Maximum poll interval of the manager thread when there is no TCP connections
+///
+/// Manager thread will be woken up immediately by `ManagerNotify` when new packets arrive,
+/// so this only affects how often an idle interface is checked.
+const IDLE_MAX_POLL_INTERVAL: Duration = Duration::from_secs(10);
+/// smoltcp's timers are rounded up to multiples of this granularity,
+/// so that timers of different sockets are coalesced into one wakeup.
+const TIMER_COALESCING_GRANULARITY: Duration = Duration::from_millis(5);
+/// Interval of reporting manager thread's wakeup statistic
+const WAKEUP_REPORT_INTERVAL: Duration = Duration::from_secs(60);
 
 struct TcpSocketControl {
     send_buffer: RingBuffer<'static, u8>,
@@ -48,6 +65,11 @@ struct TcpSocketControl {
     recv_buffer: RingBuffer<'static, u8>,
     recv_waker: Option<Waker>,
     is_closed: bool,
+    // Aborted with RST instead of FIN
+    is_reset: bool,
+    // Released after both the connection and the socket in manager are dropped
+    _socket_guard: ConcurrencyGuard,
+    _buffer_guard: ConcurrencyGuard,
 }
 
 struct ManagerNotify {
@@ -94,10 +116,12 @@ impl TcpConnection {
         socket: TcpSocket<'static>,
         socket_creation_tx: &mpsc::UnboundedSender<TcpSocketCreation>,
         manager_notify: Arc<ManagerNotify>,
-        tcp_opts: &TcpSocketOpts,
+        send_buffer_size: u32,
+        recv_buffer_size: u32,
+        concurrency_stat: &Arc<ConcurrencyStat>,
     ) -> TcpConnection {
-        let send_buffer_size = tcp_opts.send_buffer_size.unwrap_or(DEFAULT_TCP_SEND_BUFFER_SIZE);
-        let recv_buffer_size = tcp_opts.recv_buffer_size.unwrap_or(DEFAULT_TCP_RECV_BUFFER_SIZE);
+        // Buffers of both this connection and the smoltcp socket
+        let buffer_size = 2 * (send_buffer_size as usize + recv_buffer_size as usize);
 
         let control = Arc::new(SpinMutex::new(TcpSocketControl {
             send_buffer: RingBuffer::new(vec![0u8; send_buffer_size as usize]),
@@ -105,6 +129,9 @@ impl TcpConnection {
             recv_buffer: RingBuffer::new(vec![0u8; recv_buffer_size as usize]),
             recv_waker: None,
             is_closed: false,
+            is_reset: false,
+            _socket_guard: concurrency_stat.acquire(ConcurrencyKind::TunSocket, 1),
+            _buffer_guard: concurrency_stat.acquire(ConcurrencyKind::TunBuffer, buffer_size),
         }));
 
         let _ = socket_creation_tx.send(TcpSocketCreation {
@@ -119,6 +146,18 @@ impl TcpConnection {
     }
 }
 
+impl TcpConnection {
+    /// Reset the connection with RST
+    fn reset(self) {
+        let mut control = self.control.lock();
+        control.is_reset = true;
+        control.is_closed = true;
+        drop(control);
+
+        self.manager_notify.notify();
+    }
+}
+
 impl AsyncRead for TcpConnection {
     fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
         let mut control = self.control.lock();
@@ -207,20 +246,109 @@ pub struct TcpTun {
     manager_notify: Arc<ManagerNotify>,
     manager_socket_creation_tx: mpsc::UnboundedSender<TcpSocketCreation>,
     manager_running: Arc<AtomicBool>,
+    manager_paused: Arc<AtomicBool>,
     balancer: PingBalancer,
     iface_rx: mpsc::UnboundedReceiver<Vec<u8>>,
     iface_tx: mpsc::UnboundedSender<Vec<u8>>,
+    tcp_config: TunTcpConfig,
+    p2p_policy: P2pPolicy,
+    preserve_ttl: bool,
 }
 
 impl Drop for TcpTun {
     fn drop(&mut self) {
         self.manager_running.store(false, Ordering::Relaxed);
+        // Manager thread may be parked for a long time if it is idle
+        self.manager_notify.notify();
         let _ = self.manager_handle.take().unwrap().join();
     }
 }
 
+/// Adaptive poll interval of the manager thread
+///
+/// The interval is doubled every time the manager finds itself idle, up to `IDLE_MAX_POLL_INTERVAL`,
+/// and resets to `ACTIVE_POLL_INTERVAL` as soon as there are connections again.
+struct PollInterval {
+    idle_interval: Duration,
+}
+
+impl PollInterval {
+    fn new() -> PollInterval {
+        PollInterval {
+            idle_interval: ACTIVE_POLL_INTERVAL,
+        }
+    }
+
+    /// Calculate the next parking duration
+    ///
+    /// `timer_delay` is the delay of the nearest timer reported by smoltcp's interface
+    fn next(&mut self, timer_delay: Option<Duration>, has_sockets: bool) -> Duration {
+        let fallback = if has_sockets {
+            self.idle_interval = ACTIVE_POLL_INTERVAL;
+            ACTIVE_POLL_INTERVAL
+        } else {
+            let interval = self.idle_interval;
+            self.idle_interval = (interval * 2).min(IDLE_MAX_POLL_INTERVAL);
+            interval
+        };
+
+        match timer_delay {
+            Some(delay) if delay < fallback => coalesce_timer(delay),
+            _ => fallback,
+        }
+    }
+}
+
+/// Round `delay` up to a multiple of `TIMER_COALESCING_GRANULARITY`
+fn coalesce_timer(delay: Duration) -> Duration {
+    let granularity = TIMER_COALESCING_GRANULARITY.as_micros();
+    match delay.as_micros() % granularity {
+        0 => delay,
+        rem => delay + Duration::from_micros((granularity - rem) as u64),
+    }
+}
+
+/// Counts manager thread's wakeups and reports them periodically
+struct WakeupStat {
+    wakeups: u64,
+    since: Instant,
+}
+
+impl WakeupStat {
+    fn new() -> WakeupStat {
+        WakeupStat {
+            wakeups: 0,
+            since: Instant::now(),
+        }
+    }
+
+    fn record(&mut self) {
+        self.wakeups += 1;
+
+        let elapsed = self.since.elapsed();
+        if elapsed >= WAKEUP_REPORT_INTERVAL {
+            debug!(
+                "VirtDevice::poll thread woke up {} times in {:?}, {:.2} wakeups/s",
+                self.wakeups,
+                elapsed,
+                self.wakeups as f64 / elapsed.as_secs_f64()
+            );
+
+            self.wakeups = 0;
+            self.since = Instant::now();
+        }
+    }
+}
+
 impl TcpTun {
-    pub fn new(context: Arc<ServiceContext>, balancer: PingBalancer, mtu: u32) -> TcpTun {
+    pub fn new(
+        context: Arc<ServiceContext>,
+        balancer: PingBalancer,
+        mtu: u32,
+        tcp_config: TunTcpConfig,
+        p2p_policy: P2pPolicy,
+        preserve_ttl: bool,
+    ) -> TcpTun {
         let mut capabilities = DeviceCapabilities::default();
         capabilities.medium = Medium::Ip;
         capabilities.max_transmission_unit = mtu as usize;
@@ -253,9 +381,11 @@ impl TcpTun {
         };
 
         let manager_running = Arc::new(AtomicBool::new(true));
+        let manager_paused = Arc::new(AtomicBool::new(false));
 
         let manager_handle = {
             let manager_running = manager_running.clone();
+            let manager_paused = manager_paused.clone();
 
             thread::spawn(move || {
                 let TcpSocketManager {
@@ -265,7 +395,18 @@ impl TcpTun {
                     ..
                 } = manager;
 
+                let mut poll_interval = PollInterval::new();
+                let mut wakeup_stat = WakeupStat::new();
+
                 while manager_running.load(Ordering::Relaxed) {
+                    // Sockets' timers are not fired while paused, it is woken up by resume
+                    if manager_paused.load(Ordering::Acquire) {
+                        thread::park();
+                        continue;
+                    }
+
+                    wakeup_stat.record();
+
                     while let Ok(TcpSocketCreation { control, socket }) = socket_creation_rx.try_recv() {
                         let handle = iface.add_socket(socket);
                         sockets.insert(handle, control);
@@ -311,7 +452,11 @@ impl TcpTun {
 
                         if control.is_closed {
                             // Close the socket.
-                            socket.close();
+                            if control.is_reset {
+                                socket.abort();
+                            } else {
+                                socket.close();
+                            }
                             // sockets_to_remove.push(socket_handle);
                             // close_socket_control(&mut *control);
                             continue;
@@ -377,9 +522,10 @@ impl TcpTun {
                         iface.remove_socket(socket_handle);
                     }
 
-                    let next_duration = iface.poll_delay(before_poll).unwrap_or(SmolDuration::from_millis(5));
-                    if next_duration != SmolDuration::ZERO {
-                        thread::park_timeout(Duration::from(next_duration));
+                    let timer_delay = iface.poll_delay(before_poll).map(Duration::from);
+                    let next_duration = poll_interval.next(timer_delay, !sockets.is_empty());
+                    if !next_duration.is_zero() {
+                        thread::park_timeout(next_duration);
                     }
                 }
 
@@ -395,34 +541,47 @@ impl TcpTun {
             manager_notify,
             manager_socket_creation_tx,
             manager_running,
+            manager_paused,
             balancer,
             iface_rx,
             iface_tx,
+            tcp_config,
+            p2p_policy,
+            preserve_ttl,
         }
     }
 
+    /// Handle a TCP packet, `hop_limit` is the TTL (or IPv6 hop limit) of the IP packet
     pub async fn handle_packet(
         &mut self,
         src_addr: SocketAddr,
         dst_addr: SocketAddr,
+        hop_limit: u8,
         tcp_packet: &TcpPacket<&[u8]>,
     ) -> io::Result<()> {
         // TCP first handshake packet, create a new Connection
         if tcp_packet.syn() && !tcp_packet.ack() {
             let accept_opts = self.context.accept_opts();
 
-            let send_buffer_size = accept_opts.tcp.send_buffer_size.unwrap_or(DEFAULT_TCP_SEND_BUFFER_SIZE);
-            let recv_buffer_size = accept_opts.tcp.recv_buffer_size.unwrap_or(DEFAULT_TCP_RECV_BUFFER_SIZE);
+            let send_buffer_size = self
+                .tcp_config
+                .send_buffer_size
+                .or(accept_opts.tcp.send_buffer_size)
+                .unwrap_or_else(|| self.tcp_config.default_send_buffer_size());
+            let recv_buffer_size = self
+                .tcp_config
+                .recv_buffer_size
+                .or(accept_opts.tcp.recv_buffer_size)
+                .unwrap_or_else(|| self.tcp_config.default_recv_buffer_size());
 
             let mut socket = TcpSocket::new(
                 TcpSocketBuffer::new(vec![0u8; recv_buffer_size as usize]),
                 TcpSocketBuffer::new(vec![0u8; send_buffer_size as usize]),
             );
             socket.set_keep_alive(accept_opts.tcp.keepalive.map(From::from));
-            // FIXME: It should follow system's setting. 7200 is Linux's default.
-            socket.set_timeout(Some(SmolDuration::from_secs(7200)));
-            // NO ACK delay
-            // socket.set_ack_delay(None);
+            socket.set_timeout(Some(From::from(self.tcp_config.effective_timeout())));
+            socket.set_ack_delay(self.tcp_config.effective_ack_delay().map(From::from));
+            socket.set_nagle_enabled(self.tcp_config.effective_nagle());
 
             if let Err(err) = socket.listen(dst_addr) {
                 return Err(io::Error::new(ErrorKind::Other, err));
@@ -434,14 +593,21 @@ impl TcpTun {
                 socket,
                 &self.manager_socket_creation_tx,
                 self.manager_notify.clone(),
-                &accept_opts.tcp,
+                send_buffer_size,
+                recv_buffer_size,
+                self.context.concurrency_stat(),
             );
 
             // establish a tunnel
             let context = self.context.clone();
             let balancer = self.balancer.clone();
-            tokio::spawn(async move {
-                if let Err(err) = handle_redir_client(context, balancer, connection, src_addr, dst_addr).await {
+            let p2p_policy = self.p2p_policy.clone();
+            // Bypassed connections are sent with the TTL of client's SYN
+            let ttl = if self.preserve_ttl { Some(hop_limit) } else { None };
+            log_fields::spawn_connection(src_addr, async move {
+                if let Err(err) =
+                    handle_redir_client(context, balancer, connection, src_addr, dst_addr, ttl, p2p_policy).await
+                {
                     error!("TCP tunnel failure, {} <-> {}, error: {}", src_addr, dst_addr, err);
                 }
             });
@@ -450,6 +616,17 @@ impl TcpTun {
         Ok(())
     }
 
+    /// Stop polling the TCP stack until `resume` is called
+    pub fn pause(&self) {
+        self.manager_paused.store(true, Ordering::Release);
+    }
+
+    /// Resume polling the TCP stack
+    pub fn resume(&self) {
+        self.manager_paused.store(false, Ordering::Release);
+        self.manager_notify.notify();
+    }
+
     pub async fn drive_interface_state(&mut self, frame: &[u8]) {
         if let Err(..) = self.iface_tx.send(frame.to_vec()) {
             panic!("interface send channel closed unexpectly");
@@ -476,13 +653,43 @@ async fn establish_client_tcp_redir<'a>(
     mut stream: TcpConnection,
     peer_addr: SocketAddr,
     addr: &Address,
+    ttl: Option<u8>,
+    p2p_policy: P2pPolicy,
 ) -> io::Result<()> {
-    let server = balancer.best_tcp_server();
-    let svr_cfg = server.server_config();
-
-    let mut remote = AutoProxyClientStream::connect(context, &server, addr).await?;
-
-    establish_tcp_tunnel(svr_cfg, &mut stream, &mut remote, peer_addr, addr).await
+    let server = balancer.best_tcp_server_for(peer_addr, addr).await?;
+
+    let _tunnel_guard = context.concurrency_stat().acquire(ConcurrencyKind::TcpTunnel, 1);
+    let mut connection_guard = context.register_connection(ConcurrencyKind::TcpTunnel, peer_addr, Some(addr));
+    let mut remote = match AutoProxyClientStream::connect_with_ttl(context.clone(), &server, peer_addr, addr, ttl).await
+    {
+        Ok(remote) => remote,
+        Err(err) => {
+            #[cfg(feature = "acl")]
+            match AutoProxyClientStream::rejected_mode(&err) {
+                Some(RejectMode::Drop) => return Ok(()),
+                Some(RejectMode::Reset) => {
+                    stream.reset();
+                    return Ok(());
+                }
+                Some(RejectMode::Response) => return respond_rejected(&mut stream, peer_addr, addr).await,
+                Some(RejectMode::Refuse) | None => {}
+            }
+            return Err(err);
+        }
+    };
+
+    establish_tcp_tunnel(
+        &context,
+        &balancer,
+        &p2p_policy,
+        &server,
+        &mut stream,
+        &mut remote,
+        peer_addr,
+        addr,
+        connection_guard.as_mut(),
+    )
+    .await
 }
 
 async fn handle_redir_client(
@@ -491,6 +698,8 @@ async fn handle_redir_client(
     s: TcpConnection,
     peer_addr: SocketAddr,
     mut daddr: SocketAddr,
+    ttl: Option<u8>,
+    p2p_policy: P2pPolicy,
 ) -> io::Result<()> {
     // Get forward address from socket
     //
@@ -500,6 +709,6 @@ async fn handle_redir_client(
             daddr = SocketAddr::new(IpAddr::from(v4), a.port());
         }
     }
-    let target_addr = Address::from(daddr);
-    establish_client_tcp_redir(context, balancer, s, peer_addr, &target_addr).await
+    let target_addr = context.map_fake_ip(Address::from(daddr));
+    establish_client_tcp_redir(context, balancer, s, peer_addr, &target_addr, ttl, p2p_policy).await
 }
@@ -12,11 +12,14 @@ use log::trace;
 use shadowsocks::relay::socks5::Address;
 use tokio::sync::mpsc;
 
-use crate::local::{
-    context::ServiceContext,
-    loadbalancing::PingBalancer,
-    net::{UdpAssociationManager, UdpInboundWrite},
-    utils::to_ipv4_mapped,
+use crate::{
+    local::{
+        context::ServiceContext,
+        loadbalancing::PingBalancer,
+        net::{UdpAssociationManager, UdpInboundWrite, UdpNatConfig},
+        utils::to_ipv4_mapped,
+    },
+    net::P2pPolicy,
 };
 
 pub struct UdpTun {
@@ -30,15 +33,19 @@ impl UdpTun {
         balancer: PingBalancer,
         time_to_live: Option<Duration>,
         capacity: Option<usize>,
+        nat: UdpNatConfig,
+        p2p_policy: P2pPolicy,
     ) -> (UdpTun, Duration, mpsc::Receiver<SocketAddr>) {
         let (tun_tx, tun_rx) = mpsc::channel(64);
-        let (manager, cleanup_interval, keepalive_rx) = UdpAssociationManager::new(
+        let (mut manager, cleanup_interval, keepalive_rx) = UdpAssociationManager::new(
             context,
             UdpTunInboundWriter::new(tun_tx),
             time_to_live,
             capacity,
+            nat,
             balancer,
         );
+        manager.set_p2p_policy(p2p_policy);
 
         (UdpTun { tun_rx, manager }, cleanup_interval, keepalive_rx)
     }
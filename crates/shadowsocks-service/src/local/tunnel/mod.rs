@@ -1,6 +1,6 @@
 //! Shadowsocks Local Tunnel Server
 
-pub use self::server::Tunnel;
+pub use self::server::{Tunnel, TunnelMapping};
 
 pub mod server;
 mod tcprelay;
@@ -2,20 +2,55 @@
 
 use std::{io, sync::Arc, time::Duration};
 
-use futures::{future, FutureExt};
-use shadowsocks::{config::Mode, relay::socks5::Address, ServerAddr};
-
-use crate::local::{context::ServiceContext, loadbalancing::PingBalancer};
+use futures::{future, future::BoxFuture, FutureExt};
+use shadowsocks::{
+    config::Mode,
+    net::{TcpListener as ShadowTcpListener, UdpSocket as ShadowUdpSocket},
+    relay::socks5::Address,
+    ServerAddr,
+};
+
+use crate::{
+    local::{context::ServiceContext, loadbalancing::PingBalancer, net::UdpNatConfig},
+    net::P2pPolicy,
+};
 
 use super::{tcprelay::run_tcp_tunnel, udprelay::UdpTunnel};
 
+/// An additional port mapping served by a `Tunnel`
+#[derive(Clone, Debug)]
+pub struct TunnelMapping {
+    /// Listen address of both TCP and UDP relay
+    pub local_addr: ServerAddr,
+    /// Destination address
+    pub forward_addr: Address,
+    /// Relays enabled for this mapping
+    pub mode: Mode,
+}
+
+impl TunnelMapping {
+    /// Create a new TCP only mapping from `local_addr` to `forward_addr`
+    pub fn new(local_addr: ServerAddr, forward_addr: Address) -> TunnelMapping {
+        TunnelMapping {
+            local_addr,
+            forward_addr,
+            mode: Mode::TcpOnly,
+        }
+    }
+}
+
 /// Tunnel Server
 pub struct Tunnel {
     context: Arc<ServiceContext>,
-    forward_addr: Address,
+    forward_addr: Option<Address>,
+    mappings: Vec<TunnelMapping>,
     mode: Mode,
     udp_expiry_duration: Option<Duration>,
     udp_capacity: Option<usize>,
+    udp_nat: UdpNatConfig,
+    p2p_policy: P2pPolicy,
+    tcp_listener: Option<ShadowTcpListener>,
+    udp_socket: Option<ShadowUdpSocket>,
 }
 
 impl Tunnel {
@@ -29,13 +64,41 @@ impl Tunnel {
     pub fn with_context(context: Arc<ServiceContext>, forward_addr: Address) -> Tunnel {
         Tunnel {
             context,
-            forward_addr,
+            forward_addr: Some(forward_addr),
+            mappings: Vec::new(),
             mode: Mode::TcpOnly,
             udp_expiry_duration: None,
             udp_capacity: None,
+            udp_nat: UdpNatConfig::default(),
+            p2p_policy: P2pPolicy::Allow,
+            tcp_listener: None,
+            udp_socket: None,
         }
     }
 
+    /// Create a new Tunnel server with context, serving only `mappings`
+    ///
+    /// Start it with `run_mappings`
+    pub fn with_mappings(context: Arc<ServiceContext>, mappings: Vec<TunnelMapping>) -> Tunnel {
+        Tunnel {
+            context,
+            forward_addr: None,
+            mappings,
+            mode: Mode::TcpOnly,
+            udp_expiry_duration: None,
+            udp_capacity: None,
+            udp_nat: UdpNatConfig::default(),
+            p2p_policy: P2pPolicy::Allow,
+            tcp_listener: None,
+            udp_socket: None,
+        }
+    }
+
+    /// Add a port mapping, which will be served along with the default forward address
+    pub fn add_mapping(&mut self, mapping: TunnelMapping) {
+        self.mappings.push(mapping);
+    }
+
     /// Set UDP association's expiry duration
     pub fn set_udp_expiry_duration(&mut self, d: Duration) {
         self.udp_expiry_duration = Some(d);
@@ -46,33 +109,134 @@ impl Tunnel {
         self.udp_capacity = Some(c);
     }
 
+    /// Set timeouts by target ports of UDP associations
+    ///
+    /// Associations of each mapping expire after the timeout of its forward port. NAT filtering doesn't apply,
+    /// because packets are always sent to the forward address.
+    pub fn set_udp_nat(&mut self, nat: UdpNatConfig) {
+        self.udp_nat = nat;
+    }
+
     /// Set server mode
     pub fn set_mode(&mut self, mode: Mode) {
         self.mode = mode;
     }
 
+    /// Set policy of BitTorrent traffic
+    pub fn set_p2p_policy(&mut self, p2p_policy: P2pPolicy) {
+        self.p2p_policy = p2p_policy;
+    }
+
+    /// Serve TCP on a listener bound to the TCP address of `run`, instead of binding it
+    pub fn set_tcp_listener(&mut self, listener: ShadowTcpListener) {
+        self.tcp_listener = Some(listener);
+    }
+
+    /// Serve UDP on a socket bound to the UDP address of `run`, instead of binding it
+    pub fn set_udp_socket(&mut self, socket: ShadowUdpSocket) {
+        self.udp_socket = Some(socket);
+    }
+
     /// Start serving
-    pub async fn run(self, tcp_addr: &ServerAddr, udp_addr: &ServerAddr, balancer: PingBalancer) -> io::Result<()> {
+    ///
+    /// Forwards `tcp_addr` and `udp_addr` to the default forward address, and all the additional mappings
+    pub async fn run(mut self, tcp_addr: &ServerAddr, udp_addr: &ServerAddr, balancer: PingBalancer) -> io::Result<()> {
+        let tcp_listener = self.tcp_listener.take();
+        let udp_socket = self.udp_socket.take();
+
+        let forward_addr = match self.forward_addr {
+            Some(ref a) => a,
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "tunnel without forward address, serve it with run_mappings",
+                ))
+            }
+        };
+
         let mut vfut = Vec::new();
 
         if self.mode.enable_tcp() {
-            vfut.push(self.run_tcp_tunnel(tcp_addr, balancer.clone()).boxed());
+            vfut.push(
+                self.run_tcp_tunnel(tcp_addr, tcp_listener, balancer.clone(), forward_addr)
+                    .boxed(),
+            );
         }
 
         if self.mode.enable_udp() {
-            vfut.push(self.run_udp_tunnel(udp_addr, balancer).boxed());
+            vfut.push(
+                self.run_udp_tunnel(udp_addr, udp_socket, balancer.clone(), forward_addr)
+                    .boxed(),
+            );
         }
 
+        self.push_mappings(&mut vfut, &balancer);
+
         let (res, ..) = future::select_all(vfut).await;
         res
     }
 
-    async fn run_tcp_tunnel(&self, client_config: &ServerAddr, balancer: PingBalancer) -> io::Result<()> {
-        run_tcp_tunnel(self.context.clone(), client_config, balancer, &self.forward_addr).await
+    /// Start serving the additional mappings only
+    pub async fn run_mappings(self, balancer: PingBalancer) -> io::Result<()> {
+        let mut vfut = Vec::new();
+        self.push_mappings(&mut vfut, &balancer);
+
+        if vfut.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::Other, "tunnel without any mappings"));
+        }
+
+        let (res, ..) = future::select_all(vfut).await;
+        res
+    }
+
+    fn push_mappings<'a>(&'a self, vfut: &mut Vec<BoxFuture<'a, io::Result<()>>>, balancer: &PingBalancer) {
+        for mapping in &self.mappings {
+            if mapping.mode.enable_tcp() {
+                vfut.push(
+                    self.run_tcp_tunnel(&mapping.local_addr, None, balancer.clone(), &mapping.forward_addr)
+                        .boxed(),
+                );
+            }
+
+            if mapping.mode.enable_udp() {
+                vfut.push(
+                    self.run_udp_tunnel(&mapping.local_addr, None, balancer.clone(), &mapping.forward_addr)
+                        .boxed(),
+                );
+            }
+        }
+    }
+
+    async fn run_tcp_tunnel(
+        &self,
+        client_config: &ServerAddr,
+        listener: Option<ShadowTcpListener>,
+        balancer: PingBalancer,
+        forward_addr: &Address,
+    ) -> io::Result<()> {
+        run_tcp_tunnel(
+            self.context.clone(),
+            client_config,
+            listener,
+            balancer,
+            forward_addr,
+            self.p2p_policy.clone(),
+        )
+        .await
     }
 
-    async fn run_udp_tunnel(&self, client_config: &ServerAddr, balancer: PingBalancer) -> io::Result<()> {
-        let mut server = UdpTunnel::new(self.context.clone(), self.udp_expiry_duration, self.udp_capacity);
-        server.run(client_config, balancer, &self.forward_addr).await
+    async fn run_udp_tunnel(
+        &self,
+        client_config: &ServerAddr,
+        socket: Option<ShadowUdpSocket>,
+        balancer: PingBalancer,
+        forward_addr: &Address,
+    ) -> io::Result<()> {
+        let default_expiry_duration = self.udp_expiry_duration.unwrap_or(crate::DEFAULT_UDP_EXPIRY_DURATION);
+        let expiry_duration = self.udp_nat.timeout_for(forward_addr.port(), default_expiry_duration);
+
+        let mut server = UdpTunnel::new(self.context.clone(), Some(expiry_duration), self.udp_capacity);
+        server.set_p2p_policy(self.p2p_policy.clone());
+        server.run(client_config, socket, balancer, forward_addr).await
     }
 }
@@ -6,23 +6,32 @@ use log::{error, info, trace};
 use shadowsocks::{lookup_then, net::TcpListener as ShadowTcpListener, relay::socks5::Address, ServerAddr};
 use tokio::{net::TcpStream, time};
 
-use crate::local::{
-    context::ServiceContext,
-    loadbalancing::PingBalancer,
-    net::AutoProxyClientStream,
-    utils::establish_tcp_tunnel,
+use crate::{
+    local::{
+        context::ServiceContext,
+        loadbalancing::PingBalancer,
+        net::AutoProxyClientStream,
+        utils::establish_tcp_tunnel,
+    },
+    log_fields,
+    net::{ConcurrencyKind, P2pPolicy},
 };
 
 pub async fn run_tcp_tunnel(
     context: Arc<ServiceContext>,
     client_config: &ServerAddr,
+    listener: Option<ShadowTcpListener>,
     balancer: PingBalancer,
     forward_addr: &Address,
+    p2p_policy: P2pPolicy,
 ) -> io::Result<()> {
-    let listener = match *client_config {
-        ServerAddr::SocketAddr(ref saddr) => ShadowTcpListener::bind_with_opts(saddr, context.accept_opts()).await?,
-        ServerAddr::DomainName(ref dname, port) => {
-            lookup_then!(context.context_ref(), dname, port, |addr| {
+    let listener = match (listener, client_config) {
+        (Some(listener), ..) => listener,
+        (None, ServerAddr::SocketAddr(saddr)) => {
+            ShadowTcpListener::bind_with_opts(saddr, context.accept_opts()).await?
+        }
+        (None, ServerAddr::DomainName(dname, port)) => {
+            lookup_then!(context.context_ref(), dname, *port, |addr| {
                 ShadowTcpListener::bind_with_opts(&addr, context.accept_opts()).await
             })?
             .1
@@ -44,13 +53,17 @@ pub async fn run_tcp_tunnel(
         let balancer = balancer.clone();
         let forward_addr = forward_addr.clone();
 
-        tokio::spawn(handle_tcp_client(
-            context.clone(),
-            stream,
-            balancer,
+        log_fields::spawn_connection(
             peer_addr,
-            forward_addr,
-        ));
+            handle_tcp_client(
+                context.clone(),
+                stream,
+                balancer,
+                peer_addr,
+                forward_addr,
+                p2p_policy.clone(),
+            ),
+        );
     }
 }
 
@@ -60,8 +73,9 @@ async fn handle_tcp_client(
     balancer: PingBalancer,
     peer_addr: SocketAddr,
     forward_addr: Address,
+    p2p_policy: P2pPolicy,
 ) -> io::Result<()> {
-    let server = balancer.best_tcp_server();
+    let server = balancer.best_tcp_server_for(peer_addr, &forward_addr).await?;
     let svr_cfg = server.server_config();
     trace!(
         "establishing tcp tunnel {} <-> {} through sever {} (outbound: {})",
@@ -71,7 +85,20 @@ async fn handle_tcp_client(
         svr_cfg.addr(),
     );
 
-    let mut remote = AutoProxyClientStream::connect_proxied(context, &server, &forward_addr).await?;
+    let _tunnel_guard = context.concurrency_stat().acquire(ConcurrencyKind::TcpTunnel, 1);
+    let mut connection_guard = context.register_connection(ConcurrencyKind::TcpTunnel, peer_addr, Some(&forward_addr));
+    let mut remote = AutoProxyClientStream::connect_proxied(context.clone(), &server, &forward_addr).await?;
 
-    establish_tcp_tunnel(svr_cfg, &mut stream, &mut remote, peer_addr, &forward_addr).await
+    establish_tcp_tunnel(
+        &context,
+        &balancer,
+        &p2p_policy,
+        &server,
+        &mut stream,
+        &mut remote,
+        peer_addr,
+        &forward_addr,
+        connection_guard.as_mut(),
+    )
+    .await
 }
@@ -8,23 +8,35 @@ use std::{
 };
 
 use bytes::Bytes;
-use futures::future;
+use futures::future::{self, Abortable, Aborted};
 use log::{debug, error, info, trace, warn};
 use lru_time_cache::LruCache;
 use shadowsocks::{
     lookup_then,
-    net::UdpSocket as ShadowUdpSocket,
-    relay::{
-        socks5::Address,
-        udprelay::{ProxySocket, MAXIMUM_UDP_PAYLOAD_SIZE},
+    net::{
+        udp::{self as shadow_udp, RecvBatch, SendBatch, UDP_BATCH_SIZE},
+        UdpSocket as ShadowUdpSocket,
     },
+    relay::{socks5::Address, udprelay::MAXIMUM_UDP_PAYLOAD_SIZE},
     ServerAddr,
 };
 use tokio::{net::UdpSocket, sync::mpsc, task::JoinHandle, time};
 
 use crate::{
-    local::{context::ServiceContext, loadbalancing::PingBalancer},
-    net::{MonProxySocket, UDP_ASSOCIATION_KEEP_ALIVE_CHANNEL_SIZE, UDP_ASSOCIATION_SEND_CHANNEL_SIZE},
+    local::{
+        connection_table::ConnectionTableGuard,
+        context::ServiceContext,
+        loadbalancing::{PingBalancer, ServerIdent},
+        net::{ProxiedRecvBatch, ProxiedUdpSocket},
+    },
+    net::{
+        p2p::is_bittorrent_packet,
+        ConcurrencyGuard,
+        ConcurrencyKind,
+        P2pPolicy,
+        UDP_ASSOCIATION_KEEP_ALIVE_CHANNEL_SIZE,
+        UDP_ASSOCIATION_SEND_CHANNEL_SIZE,
+    },
 };
 
 type AssociationMap = LruCache<SocketAddr, UdpAssociation>;
@@ -35,6 +47,7 @@ pub struct UdpTunnel {
     keepalive_tx: mpsc::Sender<SocketAddr>,
     keepalive_rx: mpsc::Receiver<SocketAddr>,
     time_to_live: Duration,
+    p2p_policy: P2pPolicy,
 }
 
 impl UdpTunnel {
@@ -53,21 +66,29 @@ impl UdpTunnel {
             keepalive_tx,
             keepalive_rx,
             time_to_live,
+            p2p_policy: P2pPolicy::Allow,
         }
     }
 
+    /// Set policy of BitTorrent traffic
+    pub fn set_p2p_policy(&mut self, p2p_policy: P2pPolicy) {
+        self.p2p_policy = p2p_policy;
+    }
+
     pub async fn run(
         &mut self,
         client_config: &ServerAddr,
+        socket: Option<ShadowUdpSocket>,
         balancer: PingBalancer,
         forward_addr: &Address,
     ) -> io::Result<()> {
-        let socket = match *client_config {
-            ServerAddr::SocketAddr(ref saddr) => {
+        let socket = match (socket, client_config) {
+            (Some(socket), ..) => socket,
+            (None, ServerAddr::SocketAddr(saddr)) => {
                 ShadowUdpSocket::listen_with_opts(saddr, self.context.accept_opts()).await?
             }
-            ServerAddr::DomainName(ref dname, port) => {
-                lookup_then!(self.context.context_ref(), dname, port, |addr| {
+            (None, ServerAddr::DomainName(dname, port)) => {
+                lookup_then!(self.context.context_ref(), dname, *port, |addr| {
                     ShadowUdpSocket::listen_with_opts(&addr, self.context.accept_opts()).await
                 })?
                 .1
@@ -79,7 +100,7 @@ impl UdpTunnel {
 
         let listener = Arc::new(socket);
 
-        let mut buffer = [0u8; MAXIMUM_UDP_PAYLOAD_SIZE];
+        let mut batch = RecvBatch::new(UDP_BATCH_SIZE, MAXIMUM_UDP_PAYLOAD_SIZE);
         let mut cleanup_timer = time::interval(self.time_to_live);
 
         loop {
@@ -94,39 +115,38 @@ impl UdpTunnel {
                     self.assoc_map.get(&peer_addr);
                 }
 
-                recv_result = listener.recv_from(&mut buffer) => {
-                    let (n, peer_addr) = match recv_result {
-                        Ok(s) => s,
-                        Err(err) => {
-                            error!("udp server recv_from failed with error: {}", err);
-                            time::sleep(Duration::from_secs(1)).await;
-                            continue;
-                        }
-                    };
-
-                    if n == 0 {
-                        // For windows, it will generate a ICMP Port Unreachable Message
-                        // https://docs.microsoft.com/en-us/windows/win32/api/winsock2/nf-winsock2-recvfrom
-                        // Which will result in recv_from return 0.
-                        //
-                        // It cannot be solved here, because `WSAGetLastError` is already set.
-                        //
-                        // See `relay::udprelay::utils::create_socket` for more detail.
+                recv_result = shadow_udp::recv_batch(&listener, &mut batch) => {
+                    if let Err(err) = recv_result {
+                        error!("udp server recv_from failed with error: {}", err);
+                        time::sleep(Duration::from_secs(1)).await;
                         continue;
                     }
 
-                    let data = &buffer[..n];
-                    if let Err(err) = self
-                        .send_packet(&listener, peer_addr, &balancer, forward_addr, data)
-                        .await
-                    {
-                        error!(
-                            "udp packet relay {} -> {} with {} bytes failed, error: {}",
-                            peer_addr,
-                            forward_addr,
-                            data.len(),
-                            err
-                        );
+                    for i in 0..batch.len() {
+                        let (data, peer_addr) = batch.get(i);
+                        if data.is_empty() {
+                            // For windows, it will generate a ICMP Port Unreachable Message
+                            // https://docs.microsoft.com/en-us/windows/win32/api/winsock2/nf-winsock2-recvfrom
+                            // Which will result in recv_from return 0.
+                            //
+                            // It cannot be solved here, because `WSAGetLastError` is already set.
+                            //
+                            // See `relay::udprelay::utils::create_socket` for more detail.
+                            continue;
+                        }
+
+                        if let Err(err) = self
+                            .send_packet(&listener, peer_addr, &balancer, forward_addr, data)
+                            .await
+                        {
+                            error!(
+                                "udp packet relay {} -> {} with {} bytes failed, error: {}",
+                                peer_addr,
+                                forward_addr,
+                                data.len(),
+                                err
+                            );
+                        }
                     }
                 }
             }
@@ -141,8 +161,18 @@ impl UdpTunnel {
         forward_addr: &Address,
         data: &[u8],
     ) -> io::Result<()> {
+        #[cfg(feature = "local-fault-injection")]
+        if let Some(fault_injector) = self.context.fault_injector() {
+            if fault_injector.drop_udp_packet() {
+                return Ok(());
+            }
+        }
+
         if let Some(assoc) = self.assoc_map.get(&peer_addr) {
-            return assoc.try_send(Bytes::copy_from_slice(data));
+            // Associations terminated by the control socket are recreated
+            if !assoc.is_closed() {
+                return assoc.try_send(Bytes::copy_from_slice(data));
+            }
         }
 
         let assoc = UdpAssociation::new(
@@ -152,6 +182,7 @@ impl UdpTunnel {
             forward_addr.clone(),
             self.keepalive_tx.clone(),
             balancer.clone(),
+            self.p2p_policy.clone(),
         );
 
         debug!("created udp association for {}", peer_addr);
@@ -166,6 +197,7 @@ impl UdpTunnel {
 struct UdpAssociation {
     assoc_handle: JoinHandle<()>,
     sender: mpsc::Sender<Bytes>,
+    _concurrency_guard: ConcurrencyGuard,
 }
 
 impl Drop for UdpAssociation {
@@ -182,10 +214,30 @@ impl UdpAssociation {
         forward_addr: Address,
         keepalive_tx: mpsc::Sender<SocketAddr>,
         balancer: PingBalancer,
+        p2p_policy: P2pPolicy,
     ) -> UdpAssociation {
-        let (assoc_handle, sender) =
-            UdpAssociationContext::create(context, inbound, peer_addr, forward_addr, keepalive_tx, balancer);
-        UdpAssociation { assoc_handle, sender }
+        let concurrency_guard = context.concurrency_stat().acquire(ConcurrencyKind::UdpAssociation, 1);
+        let connection_guard =
+            context.register_connection(ConcurrencyKind::UdpAssociation, peer_addr, Some(&forward_addr));
+        let (assoc_handle, sender) = UdpAssociationContext::create(
+            context,
+            inbound,
+            peer_addr,
+            forward_addr,
+            keepalive_tx,
+            balancer,
+            p2p_policy,
+            connection_guard,
+        );
+        UdpAssociation {
+            assoc_handle,
+            sender,
+            _concurrency_guard: concurrency_guard,
+        }
+    }
+
+    fn is_closed(&self) -> bool {
+        self.sender.is_closed()
     }
 
     fn try_send(&self, data: Bytes) -> io::Result<()> {
@@ -201,11 +253,18 @@ struct UdpAssociationContext {
     context: Arc<ServiceContext>,
     peer_addr: SocketAddr,
     forward_addr: Address,
-    proxied_socket: Option<MonProxySocket>,
+    proxied_socket: Option<ProxiedUdpSocket>,
+    proxied_server: Option<Arc<ServerIdent>>,
     keepalive_tx: mpsc::Sender<SocketAddr>,
     keepalive_flag: bool,
     balancer: PingBalancer,
     inbound: Arc<UdpSocket>,
+    // Packets sent back to client, in batches
+    respond_batch: SendBatch,
+    p2p_policy: P2pPolicy,
+    // BitTorrent traffic was detected, all the following packets are treated as BitTorrent traffic
+    p2p_detected: bool,
+    connection_guard: Option<ConnectionTableGuard>,
 }
 
 impl Drop for UdpAssociationContext {
@@ -215,6 +274,7 @@ impl Drop for UdpAssociationContext {
 }
 
 impl UdpAssociationContext {
+    #[allow(clippy::too_many_arguments)]
     fn create(
         context: Arc<ServiceContext>,
         inbound: Arc<UdpSocket>,
@@ -222,29 +282,48 @@ impl UdpAssociationContext {
         forward_addr: Address,
         keepalive_tx: mpsc::Sender<SocketAddr>,
         balancer: PingBalancer,
+        p2p_policy: P2pPolicy,
+        mut connection_guard: Option<ConnectionTableGuard>,
     ) -> (JoinHandle<()>, mpsc::Sender<Bytes>) {
         // Pending packets UDP_ASSOCIATION_SEND_CHANNEL_SIZE for each association should be good enough for a server.
         // If there are plenty of packets stuck in the channel, dropping excessive packets is a good way to protect the server from
         // being OOM.
         let (sender, receiver) = mpsc::channel(UDP_ASSOCIATION_SEND_CHANNEL_SIZE);
 
+        let abort_registration = connection_guard.as_mut().map(ConnectionTableGuard::abort_registration);
         let mut assoc = UdpAssociationContext {
             context,
             peer_addr,
             forward_addr,
             proxied_socket: None,
+            proxied_server: None,
             keepalive_tx,
             keepalive_flag: false,
             balancer,
             inbound,
+            respond_batch: SendBatch::new(),
+            p2p_policy,
+            p2p_detected: false,
+            connection_guard,
         };
-        let handle = tokio::spawn(async move { assoc.dispatch_packet(receiver).await });
+        let handle = tokio::spawn(async move {
+            let pause_controller = assoc.context.pause_controller().clone();
+            let dispatch = pause_controller.park(assoc.dispatch_packet(receiver));
+            match abort_registration {
+                Some(abort_registration) => {
+                    if let Err(Aborted) = Abortable::new(dispatch, abort_registration).await {
+                        debug!("udp association for {} terminated", peer_addr);
+                    }
+                }
+                None => dispatch.await,
+            }
+        });
 
         (handle, sender)
     }
 
     async fn dispatch_packet(&mut self, mut receiver: mpsc::Receiver<Bytes>) {
-        let mut proxied_buffer = Vec::new();
+        let mut proxied_batch = ProxiedRecvBatch::new();
         let mut keepalive_interval = time::interval(Duration::from_secs(1));
 
         loop {
@@ -261,18 +340,15 @@ impl UdpAssociationContext {
                     self.dispatch_received_packet(&data).await;
                 }
 
-                received_opt = receive_from_proxied_opt(&self.proxied_socket, &mut proxied_buffer) => {
-                    let (n, addr) = match received_opt {
-                        Ok(r) => r,
-                        Err(err) => {
-                            error!("udp relay {} <- ... failed, error: {}", self.peer_addr, err);
-                            // Socket failure. Reset for recreation.
-                            self.proxied_socket = None;
-                            continue;
-                        }
-                    };
+                received_opt = receive_from_proxied_opt(&mut self.proxied_socket, &mut proxied_batch) => {
+                    if let Err(err) = received_opt {
+                        error!("udp relay {} <- ... failed, error: {}", self.peer_addr, err);
+                        // Socket failure. Reset for recreation.
+                        self.proxied_socket = None;
+                        continue;
+                    }
 
-                    self.send_received_respond_packet(&addr, &proxied_buffer[..n]).await;
+                    self.send_received_respond_batch(&proxied_batch).await;
                 }
 
                 _ = keepalive_interval.tick() => {
@@ -289,17 +365,12 @@ impl UdpAssociationContext {
 
         #[inline]
         async fn receive_from_proxied_opt(
-            socket: &Option<MonProxySocket>,
-            buf: &mut Vec<u8>,
-        ) -> io::Result<(usize, Address)> {
+            socket: &mut Option<ProxiedUdpSocket>,
+            batch: &mut ProxiedRecvBatch,
+        ) -> io::Result<usize> {
             match *socket {
                 None => future::pending().await,
-                Some(ref s) => {
-                    if buf.is_empty() {
-                        buf.resize(MAXIMUM_UDP_PAYLOAD_SIZE, 0);
-                    }
-                    s.recv(buf).await
-                }
+                Some(ref mut s) => s.recv_batch(batch).await,
             }
         }
     }
@@ -312,6 +383,10 @@ impl UdpAssociationContext {
             data.len()
         );
 
+        if let Some(ref connection_guard) = self.connection_guard {
+            connection_guard.flow_stat().incr_rx(data.len() as u64);
+        }
+
         if let Err(err) = self.dispatch_received_proxied_packet(data).await {
             error!(
                 "udp relay {} -> {} with {} bytes, error: {}",
@@ -324,18 +399,79 @@ impl UdpAssociationContext {
     }
 
     async fn dispatch_received_proxied_packet(&mut self, data: &[u8]) -> io::Result<()> {
+        if !self.p2p_policy.is_allow() && !self.p2p_detected && is_bittorrent_packet(data) {
+            debug!(
+                "udp tunnel {} -> {} BitTorrent traffic detected, policy: {}",
+                self.peer_addr, self.forward_addr, self.p2p_policy
+            );
+            self.p2p_detected = true;
+
+            if let P2pPolicy::Route(ref tag) = self.p2p_policy {
+                if let Some(ref server) = self.proxied_server {
+                    if !server.server_config().has_tag(tag) {
+                        self.proxied_socket = None;
+                    }
+                }
+            }
+        }
+
+        let p2p_route = match self.p2p_policy {
+            P2pPolicy::Block if self.p2p_detected => {
+                trace!(
+                    "udp relay {} -> {} with {} bytes blocked, BitTorrent traffic",
+                    self.peer_addr,
+                    self.forward_addr,
+                    data.len()
+                );
+                return Ok(());
+            }
+            P2pPolicy::Route(ref tag) if self.p2p_detected => Some(tag.clone()),
+            _ => None,
+        };
+
+        if self.context.udp_migration() && self.proxied_socket.is_some() && p2p_route.is_none() {
+            if let Some(ref server) = self.proxied_server {
+                if let Some(new_server) = self
+                    .balancer
+                    .udp_failover_server_for(server, self.peer_addr, &self.forward_addr)
+                    .await
+                {
+                    info!(
+                        "udp tunnel {} migrating from dead server {} to {}",
+                        self.peer_addr,
+                        server.server_config().addr(),
+                        new_server.server_config().addr()
+                    );
+                    self.proxied_socket = None;
+                }
+            }
+        }
+
         let socket = match self.proxied_socket {
             Some(ref mut socket) => socket,
             None => {
                 // Create a new connection to proxy server
 
-                let server = self.balancer.best_udp_server();
+                let server = match p2p_route {
+                    Some(ref tag) => self
+                        .balancer
+                        .best_udp_server_tagged(self.peer_addr, &self.forward_addr, tag)?,
+                    None => {
+                        self.balancer
+                            .best_udp_server_for(self.peer_addr, &self.forward_addr)
+                            .await?
+                    }
+                };
                 let svr_cfg = server.server_config();
 
-                let socket =
-                    ProxySocket::connect_with_opts(self.context.context(), svr_cfg, self.context.connect_opts_ref())
-                        .await?;
-                let socket = MonProxySocket::from_socket(socket, self.context.flow_stat());
+                let socket = ProxiedUdpSocket::connect(&self.context, svr_cfg).await?;
+                if let Err(err) = socket.enable_gro() {
+                    warn!("udp tunnel failed to enable GRO, error: {}", err);
+                }
+                self.proxied_server = Some(server.clone());
+                if let Some(ref connection_guard) = self.connection_guard {
+                    connection_guard.set_server(svr_cfg.addr());
+                }
 
                 self.proxied_socket.insert(socket)
             }
@@ -360,23 +496,42 @@ impl UdpAssociationContext {
         Ok(())
     }
 
-    async fn send_received_respond_packet(&mut self, addr: &Address, data: &[u8]) {
-        trace!("udp relay {} <- {} received {} bytes", self.peer_addr, addr, data.len());
+    async fn send_received_respond_batch(&mut self, batch: &ProxiedRecvBatch) {
+        if batch.is_empty() {
+            return;
+        }
 
         // Keep association alive in map
         self.keepalive_flag = true;
 
+        let mut n = 0;
+        for i in 0..batch.len() {
+            let (data, addr) = batch.get(i);
+            trace!("udp relay {} <- {} received {} bytes", self.peer_addr, addr, data.len());
+
+            self.respond_batch.push(Some(self.peer_addr)).extend_from_slice(data);
+            n += data.len();
+        }
+
         // Send back to client
-        if let Err(err) = self.inbound.send_to(data, self.peer_addr).await {
+        if let Err(err) = shadow_udp::send_batch(&self.inbound, &mut self.respond_batch).await {
             warn!(
-                "udp failed to send back {} bytes to client {}, from target {}, error: {}",
-                data.len(),
+                "udp failed to send back {} packets to client {}, error: {}",
+                batch.len(),
                 self.peer_addr,
-                addr,
                 err
             );
         } else {
-            trace!("udp relay {} <- {} with {} bytes", self.peer_addr, addr, data.len());
+            if let Some(ref connection_guard) = self.connection_guard {
+                connection_guard.flow_stat().incr_tx(n as u64);
+            }
+            trace!(
+                "udp relay {} <- {} with {} packets, {} bytes",
+                self.peer_addr,
+                self.forward_addr,
+                batch.len(),
+                n
+            );
         }
     }
 }
@@ -1,45 +1,462 @@
 //! Shadowsocks Local Utilities
 
 use std::{
+    fmt::Display,
     io,
     net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
     time::Duration,
 };
 
-use log::{debug, trace};
+use futures::future::{Abortable, Aborted};
+use log::{debug, info, trace, warn};
 use shadowsocks::{
-    config::ServerConfig,
-    relay::{socks5::Address, tcprelay::utils::copy_encrypted_bidirectional},
+    context::ClockSkewError,
+    relay::{
+        socks5::Address,
+        tcprelay::utils::{copy_bidirectional_with_pools, copy_encrypted_bidirectional_with_pools},
+    },
 };
 use tokio::{
-    io::{copy_bidirectional, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
     time,
 };
 
-use crate::local::net::AutoProxyIo;
+use crate::{
+    local::{
+        connection_table::ConnectionTableGuard,
+        context::ServiceContext,
+        loadbalancing::{PingBalancer, ServerIdent},
+        net::{AutoProxyClientStream, AutoProxyIo},
+    },
+    log_fields::{self, LogField},
+    net::{p2p::is_bittorrent_stream, MonProxyStream, P2pPolicy},
+};
+
+/// Report the server and rule handling `peer_addr` connecting to `target_addr` through `server`, if route debugging
+/// is enabled
+///
+/// Returns the route, which is also sent to HTTP clients in the `X-SS-Route` header
+pub(crate) async fn debug_route(
+    context: &ServiceContext,
+    server: &ServerIdent,
+    peer_addr: SocketAddr,
+    target_addr: &Address,
+) -> Option<String> {
+    if !context.route_debug() {
+        return None;
+    }
 
+    let (bypassed, rule) = context.check_target_bypassed_by(Some(peer_addr), target_addr).await;
+    let server = if bypassed { None } else { Some(server) };
+    report_route(context, server, rule, peer_addr, target_addr)
+}
+
+/// Report the route decided by `rule` of `peer_addr` connecting to `target_addr`, if route debugging is enabled
+///
+/// `server` is `None` if the connection is bypassed
+pub(crate) fn report_route(
+    context: &ServiceContext,
+    server: Option<&ServerIdent>,
+    rule: &str,
+    peer_addr: SocketAddr,
+    target_addr: &Address,
+) -> Option<String> {
+    if !context.route_debug() {
+        return None;
+    }
+
+    let id = context.next_route_id();
+    let route = match server {
+        None => format!("id={}; direct; rule={}", id, rule),
+        Some(server) => format!("id={}; server={}; rule={}", id, server.server_config().addr(), rule),
+    };
+    info!("route {} -> {} {}", peer_addr, target_addr, route);
+
+    Some(route)
+}
+
+/// Answer the TCP client `peer_addr` whose connection to `target_addr` is rejected by ACL in `RejectMode::Response`,
+/// after handshakes of its protocol
+///
+/// Clients of port 80 receive an empty HTTP 403, the others are closed. Requests are drained before closing, so that
+/// clients are not reset for unread data
+#[cfg(feature = "acl")]
+pub(crate) async fn respond_rejected<S>(stream: &mut S, peer_addr: SocketAddr, target_addr: &Address) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    const HTTP_FORBIDDEN: &[u8] = b"HTTP/1.1 403 Forbidden\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+
+    trace!("responded {} -> {} rejected by ACL", peer_addr, target_addr);
+
+    if target_addr.port() == 80 {
+        stream.write_all(HTTP_FORBIDDEN).await?;
+    }
+    stream.shutdown().await?;
+
+    let _ = time::timeout(Duration::from_secs(5), tokio::io::copy(stream, &mut tokio::io::sink())).await;
+    Ok(())
+}
+
+/// Relay between `plain` of the client and `shadow` connected to `target_addr`
+///
+/// `connection` is the registration in the table of active connections, which is updated with the route and bytes
+/// relayed, and could terminate the tunnel.
+///
+/// Bypassed tunnels between plain TCP sockets are relayed with `splice(2)` if `tcp_splice` is enabled.
+#[allow(clippy::too_many_arguments)]
 pub(crate) async fn establish_tcp_tunnel<P, S>(
-    svr_cfg: &ServerConfig,
+    context: &Arc<ServiceContext>,
+    balancer: &PingBalancer,
+    p2p_policy: &P2pPolicy,
+    server: &ServerIdent,
     plain: &mut P,
     shadow: &mut S,
     peer_addr: SocketAddr,
     target_addr: &Address,
+    connection: Option<&mut ConnectionTableGuard>,
 ) -> io::Result<()>
 where
-    P: AsyncRead + AsyncWrite + Unpin,
+    P: AsyncRead + AsyncWrite + Unpin + 'static,
     S: AsyncRead + AsyncWrite + AutoProxyIo + Unpin,
 {
+    let connection = match connection {
+        Some(c) => c,
+        None => {
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            if let Some(result) = splice_tcp_tunnel(context, plain, shadow, peer_addr, target_addr, None).await {
+                return result;
+            }
+
+            return establish_tcp_tunnel_parked(
+                context,
+                balancer,
+                p2p_policy,
+                server,
+                plain,
+                shadow,
+                peer_addr,
+                target_addr,
+            )
+            .await;
+        }
+    };
+
     if shadow.is_proxied() {
+        connection.set_server(server.server_config().addr());
+    } else {
+        connection.set_bypassed();
+    }
+    if connection.is_flow_exported() {
+        let (_, rule) = context.check_target_bypassed_by(Some(peer_addr), target_addr).await;
+        connection.set_rule(rule);
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    if let Some(result) =
+        splice_tcp_tunnel(context, plain, shadow, peer_addr, target_addr, Some(&mut *connection)).await
+    {
+        return result;
+    }
+
+    let mut plain = MonProxyStream::from_stream(plain, connection.flow_stat().clone());
+    let tunnel = establish_tcp_tunnel_parked(
+        context,
+        balancer,
+        p2p_policy,
+        server,
+        &mut plain,
+        shadow,
+        peer_addr,
+        target_addr,
+    );
+    match Abortable::new(tunnel, connection.abort_registration()).await {
+        Ok(result) => result,
+        Err(Aborted) => {
+            debug!("tcp tunnel {} <-> {} terminated", peer_addr, target_addr);
+            Ok(())
+        }
+    }
+}
+
+/// Relay the bypassed tunnel with `splice(2)`, if `plain` and `shadow` are both plain TCP sockets
+///
+/// Returns `None` if it is not applicable, then the tunnel should be relayed by the userspace.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+async fn splice_tcp_tunnel<P, S>(
+    context: &ServiceContext,
+    plain: &mut P,
+    shadow: &mut S,
+    peer_addr: SocketAddr,
+    target_addr: &Address,
+    connection: Option<&mut ConnectionTableGuard>,
+) -> Option<io::Result<()>>
+where
+    P: 'static,
+    S: AutoProxyIo,
+{
+    use std::any::Any;
+
+    use tokio::net::TcpStream;
+
+    use crate::net::splice::Splicer;
+
+    if !context.tcp_splice() {
+        return None;
+    }
+
+    // Priorities and faults are applied by wrapping the client's stream, which couldn't be skipped
+    if context.priority_scheduler().is_some() {
+        return None;
+    }
+    #[cfg(feature = "local-fault-injection")]
+    if let Some(fault_injector) = context.fault_injector() {
+        if fault_injector.config().tunnel_kill_after_bytes.is_some() {
+            return None;
+        }
+    }
+
+    let plain = (plain as &mut dyn Any).downcast_mut::<TcpStream>()?;
+    let shadow = shadow.bypassed_socket()?;
+
+    let splicer = match Splicer::new() {
+        Ok(s) => s,
+        Err(err) => {
+            debug!(
+                "tcp tunnel {} <-> {} couldn't be spliced, relayed as usual, error: {}",
+                peer_addr, target_addr, err
+            );
+            return None;
+        }
+    };
+
+    let fields: &[LogField] = &[("peer", &peer_addr), ("target", target_addr)];
+    log_fields::with_fields(fields, || {
         debug!(
-            "established tcp tunnel {} <-> {} through sever {} (outbound: {})",
+            "established tcp tunnel {} <-> {} bypassed, spliced",
+            peer_addr, target_addr
+        )
+    });
+
+    let flow_stat = connection.as_ref().map(|c| c.flow_stat().clone());
+    let flow_stat = flow_stat.as_deref();
+    let relay = splicer.copy_bidirectional(
+        plain,
+        shadow,
+        |n| {
+            if let Some(flow_stat) = flow_stat {
+                flow_stat.incr_rx(n);
+            }
+        },
+        |n| {
+            if let Some(flow_stat) = flow_stat {
+                flow_stat.incr_tx(n);
+            }
+        },
+    );
+    let relay = context.pause_controller().park(relay);
+
+    let result = match connection {
+        Some(connection) => match Abortable::new(relay, connection.abort_registration()).await {
+            Ok(result) => result,
+            Err(Aborted) => {
+                debug!("tcp tunnel {} <-> {} terminated", peer_addr, target_addr);
+                return Some(Ok(()));
+            }
+        },
+        None => relay.await,
+    };
+
+    match result {
+        Ok((rn, wn)) => {
+            let fields = [
+                fields,
+                &[("l2r_bytes", &rn as &(dyn Display + Sync)), ("r2l_bytes", &wn)],
+            ]
+            .concat();
+            log_fields::with_fields(&fields, || {
+                trace!(
+                    "tcp tunnel {} <-> {} (spliced) closed, L2R {} bytes, R2L {} bytes",
+                    peer_addr,
+                    target_addr,
+                    rn,
+                    wn
+                )
+            });
+        }
+        Err(err) => {
+            log_fields::with_fields(fields, || {
+                trace!(
+                    "tcp tunnel {} <-> {} (spliced) closed with error: {}",
+                    peer_addr,
+                    target_addr,
+                    err
+                )
+            });
+        }
+    }
+
+    Some(Ok(()))
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn establish_tcp_tunnel_parked<P, S>(
+    context: &Arc<ServiceContext>,
+    balancer: &PingBalancer,
+    p2p_policy: &P2pPolicy,
+    server: &ServerIdent,
+    plain: &mut P,
+    shadow: &mut S,
+    peer_addr: SocketAddr,
+    target_addr: &Address,
+) -> io::Result<()>
+where
+    P: AsyncRead + AsyncWrite + Unpin,
+    S: AsyncRead + AsyncWrite + AutoProxyIo + Unpin,
+{
+    // Relays are not driven while local servers are paused
+    context
+        .pause_controller()
+        .park(establish_tcp_tunnel_prioritized(
+            context,
+            balancer,
+            p2p_policy,
+            server,
+            plain,
+            shadow,
+            peer_addr,
+            target_addr,
+        ))
+        .await
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn establish_tcp_tunnel_prioritized<P, S>(
+    context: &Arc<ServiceContext>,
+    balancer: &PingBalancer,
+    p2p_policy: &P2pPolicy,
+    server: &ServerIdent,
+    plain: &mut P,
+    shadow: &mut S,
+    peer_addr: SocketAddr,
+    target_addr: &Address,
+) -> io::Result<()>
+where
+    P: AsyncRead + AsyncWrite + Unpin,
+    S: AsyncRead + AsyncWrite + AutoProxyIo + Unpin,
+{
+    if let Some(priority_scheduler) = context.priority_scheduler() {
+        let mut plain = priority_scheduler.wrap_tunnel_stream(plain, target_addr);
+        return establish_tcp_tunnel_faulty(
+            context,
+            balancer,
+            p2p_policy,
+            server,
+            &mut plain,
+            shadow,
             peer_addr,
             target_addr,
-            svr_cfg.external_addr(),
-            svr_cfg.addr(),
-        );
+        )
+        .await;
+    }
+
+    establish_tcp_tunnel_faulty(
+        context,
+        balancer,
+        p2p_policy,
+        server,
+        plain,
+        shadow,
+        peer_addr,
+        target_addr,
+    )
+    .await
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn establish_tcp_tunnel_faulty<P, S>(
+    context: &Arc<ServiceContext>,
+    balancer: &PingBalancer,
+    p2p_policy: &P2pPolicy,
+    server: &ServerIdent,
+    plain: &mut P,
+    shadow: &mut S,
+    peer_addr: SocketAddr,
+    target_addr: &Address,
+) -> io::Result<()>
+where
+    P: AsyncRead + AsyncWrite + Unpin,
+    S: AsyncRead + AsyncWrite + AutoProxyIo + Unpin,
+{
+    #[cfg(feature = "local-fault-injection")]
+    if let Some(fault_injector) = context.fault_injector() {
+        if fault_injector.config().tunnel_kill_after_bytes.is_some() {
+            let mut plain = fault_injector.wrap_tunnel_stream(plain);
+            return establish_tcp_tunnel_relay(
+                context,
+                balancer,
+                p2p_policy,
+                server,
+                &mut plain,
+                shadow,
+                peer_addr,
+                target_addr,
+            )
+            .await;
+        }
+    }
+
+    establish_tcp_tunnel_relay(
+        context,
+        balancer,
+        p2p_policy,
+        server,
+        plain,
+        shadow,
+        peer_addr,
+        target_addr,
+    )
+    .await
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn establish_tcp_tunnel_relay<P, S>(
+    context: &Arc<ServiceContext>,
+    balancer: &PingBalancer,
+    p2p_policy: &P2pPolicy,
+    server: &ServerIdent,
+    plain: &mut P,
+    shadow: &mut S,
+    peer_addr: SocketAddr,
+    target_addr: &Address,
+) -> io::Result<()>
+where
+    P: AsyncRead + AsyncWrite + Unpin,
+    S: AsyncRead + AsyncWrite + AutoProxyIo + Unpin,
+{
+    let svr_cfg = server.server_config();
+    if shadow.is_proxied() {
+        let fields: &[LogField] = &[
+            ("peer", &peer_addr),
+            ("target", target_addr),
+            ("server", svr_cfg.addr()),
+        ];
+        log_fields::with_fields(fields, || {
+            debug!(
+                "established tcp tunnel {} <-> {} through sever {} (outbound: {})",
+                peer_addr,
+                target_addr,
+                svr_cfg.external_addr(),
+                svr_cfg.addr(),
+            )
+        });
     } else {
-        debug!("established tcp tunnel {} <-> {} bypassed", peer_addr, target_addr);
-        return establish_tcp_tunnel_bypassed(plain, shadow, peer_addr, target_addr).await;
+        let fields: &[LogField] = &[("peer", &peer_addr), ("target", target_addr)];
+        log_fields::with_fields(fields, || {
+            debug!("established tcp tunnel {} <-> {} bypassed", peer_addr, target_addr)
+        });
+        return establish_tcp_tunnel_bypassed(context, plain, shadow, peer_addr, target_addr).await;
     }
 
     // https://github.com/shadowsocks/shadowsocks-rust/issues/232
@@ -55,6 +472,43 @@ where
                 return Ok(());
             }
             Ok(Ok(n)) => {
+                if !p2p_policy.is_allow() && is_bittorrent_stream(&buffer[..n]) {
+                    match *p2p_policy {
+                        P2pPolicy::Block => {
+                            debug!(
+                                "tcp tunnel {} -> {} (proxied) blocked, BitTorrent traffic detected",
+                                peer_addr, target_addr
+                            );
+                            return Ok(());
+                        }
+                        P2pPolicy::Route(ref tag) if !svr_cfg.has_tag(tag) => {
+                            // Nothing have been sent to the current server, just connect to another one
+                            let server = balancer.best_tcp_server_tagged(peer_addr, target_addr, tag)?;
+                            let mut routed =
+                                AutoProxyClientStream::connect_proxied(context.clone(), &server, target_addr).await?;
+
+                            debug!(
+                                "tcp tunnel {} -> {} BitTorrent traffic detected, routed to sever {}",
+                                peer_addr,
+                                target_addr,
+                                server.server_config().addr(),
+                            );
+
+                            routed.write_all(&buffer[..n]).await?;
+                            return copy_proxied_bidirectional(
+                                context,
+                                &server,
+                                plain,
+                                &mut routed,
+                                peer_addr,
+                                target_addr,
+                            )
+                            .await;
+                        }
+                        _ => {}
+                    }
+                }
+
                 // Send the first packet.
                 shadow.write_all(&buffer[..n]).await?;
             }
@@ -72,23 +526,66 @@ where
         }
     }
 
-    match copy_encrypted_bidirectional(svr_cfg.method(), shadow, plain).await {
+    copy_proxied_bidirectional(context, server, plain, shadow, peer_addr, target_addr).await
+}
+
+async fn copy_proxied_bidirectional<P, S>(
+    context: &ServiceContext,
+    server: &ServerIdent,
+    plain: &mut P,
+    shadow: &mut S,
+    peer_addr: SocketAddr,
+    target_addr: &Address,
+) -> io::Result<()>
+where
+    P: AsyncRead + AsyncWrite + Unpin,
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let svr_cfg = server.server_config();
+    let fields: &[LogField] = &[
+        ("peer", &peer_addr),
+        ("target", target_addr),
+        ("server", svr_cfg.addr()),
+    ];
+    // Buffers configured by the server take precedence over the ones of the local
+    let sizes = svr_cfg.relay_buffer_sizes().or(context.relay_buffer_sizes());
+    match copy_encrypted_bidirectional_with_pools(shadow, plain, sizes.write_pool(), sizes.read_pool()).await {
         Ok((wn, rn)) => {
-            trace!(
-                "tcp tunnel {} <-> {} (proxied) closed, L2R {} bytes, R2L {} bytes",
-                peer_addr,
-                target_addr,
-                rn,
-                wn
-            );
+            let fields = [
+                fields,
+                &[("l2r_bytes", &rn as &(dyn Display + Sync)), ("r2l_bytes", &wn)],
+            ]
+            .concat();
+            log_fields::with_fields(&fields, || {
+                trace!(
+                    "tcp tunnel {} <-> {} (proxied) closed, L2R {} bytes, R2L {} bytes",
+                    peer_addr,
+                    target_addr,
+                    rn,
+                    wn
+                )
+            });
         }
         Err(err) => {
-            trace!(
-                "tcp tunnel {} <-> {} (proxied) closed with error: {}",
-                peer_addr,
-                target_addr,
-                err
-            );
+            server.relay_stat().record_relay_error(&err);
+            if ClockSkewError::is_clock_skew(&err) {
+                // Responses are always rejected until the clock is fixed
+                warn!(
+                    "tcp tunnel {} <-> {} (proxied) response of server {} rejected, {}",
+                    peer_addr,
+                    target_addr,
+                    svr_cfg.addr(),
+                    err
+                );
+            }
+            log_fields::with_fields(fields, || {
+                trace!(
+                    "tcp tunnel {} <-> {} (proxied) closed with error: {}",
+                    peer_addr,
+                    target_addr,
+                    err
+                )
+            });
         }
     }
 
@@ -96,6 +593,7 @@ where
 }
 
 async fn establish_tcp_tunnel_bypassed<P, S>(
+    context: &ServiceContext,
     plain: &mut P,
     shadow: &mut S,
     peer_addr: SocketAddr,
@@ -105,23 +603,34 @@ where
     P: AsyncRead + AsyncWrite + Unpin,
     S: AsyncRead + AsyncWrite + Unpin,
 {
-    match copy_bidirectional(plain, shadow).await {
+    let fields: &[LogField] = &[("peer", &peer_addr), ("target", target_addr)];
+    let sizes = context.relay_buffer_sizes();
+    match copy_bidirectional_with_pools(plain, shadow, sizes.read_pool(), sizes.write_pool()).await {
         Ok((rn, wn)) => {
-            trace!(
-                "tcp tunnel {} <-> {} (bypassed) closed, L2R {} bytes, R2L {} bytes",
-                peer_addr,
-                target_addr,
-                rn,
-                wn
-            );
+            let fields = [
+                fields,
+                &[("l2r_bytes", &rn as &(dyn Display + Sync)), ("r2l_bytes", &wn)],
+            ]
+            .concat();
+            log_fields::with_fields(&fields, || {
+                trace!(
+                    "tcp tunnel {} <-> {} (bypassed) closed, L2R {} bytes, R2L {} bytes",
+                    peer_addr,
+                    target_addr,
+                    rn,
+                    wn
+                )
+            });
         }
         Err(err) => {
-            trace!(
-                "tcp tunnel {} <-> {} (bypassed) closed with error: {}",
-                peer_addr,
-                target_addr,
-                err
-            );
+            log_fields::with_fields(fields, || {
+                trace!(
+                    "tcp tunnel {} <-> {} (bypassed) closed with error: {}",
+                    peer_addr,
+                    target_addr,
+                    err
+                )
+            });
         }
     }
 
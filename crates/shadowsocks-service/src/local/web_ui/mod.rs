@@ -0,0 +1,13 @@
+//! Web UI of status and basic control
+//!
+//! Serves a single page showing traffic, active connections and health of servers, which could also pin a server
+//! instead of the best one chosen by the load balancer
+//!
+//! - `GET /`, the page
+//! - `GET /api/status`, status in JSON
+//! - `POST /api/pin`, pin the server whose address is the request body
+//! - `DELETE /api/pin`, unpin the pinned server
+
+pub use self::server::WebUi;
+
+mod server;
@@ -0,0 +1,431 @@
+//! Web UI HTTP server
+
+use std::{
+    collections::BTreeMap,
+    convert::Infallible,
+    io::{self, ErrorKind},
+    ptr,
+    str,
+    sync::Arc,
+};
+
+use hyper::{
+    body,
+    header,
+    server::conn::AddrStream,
+    service::{make_service_fn, service_fn},
+    Body,
+    Method,
+    Request,
+    Response,
+    Server,
+    StatusCode,
+};
+use log::{error, info, trace};
+use serde::Serialize;
+use shadowsocks::{config::ServerAddr, lookup_then, net::TcpListener};
+
+use crate::{
+    local::{
+        context::ServiceContext,
+        loadbalancing::{server_stat::Score, PingBalancer, ServerIdent, ServerScore},
+    },
+    net::ConcurrencyKind,
+};
+
+static INDEX_HTML: &str = include_str!("index.html");
+
+/// Maximum length of request bodies, which are server addresses or profile names
+const MAX_BODY_SIZE: u64 = 1024;
+
+#[derive(Serialize)]
+struct StatusResponse {
+    /// Bytes sent to servers
+    tx: u64,
+    /// Bytes received from servers
+    rx: u64,
+    concurrency: BTreeMap<&'static str, ConcurrencyStatus>,
+    pinned: Option<String>,
+    servers: Vec<ServerStatus>,
+    /// The applied profile
+    profile: Option<String>,
+    /// Names of all profiles, which could be switched to
+    profiles: Vec<String>,
+    /// Path of ACL file, which could be reloaded
+    acl: Option<String>,
+}
+
+#[derive(Serialize)]
+struct ConcurrencyStatus {
+    current: usize,
+    peak: usize,
+}
+
+#[derive(Serialize)]
+struct ServerStatus {
+    addr: String,
+    remarks: Option<String>,
+    tags: Vec<String>,
+    /// Excluded from choosing by user
+    excluded: bool,
+    tcp: ScoreStatus,
+    udp: ScoreStatus,
+    /// `None` if the server doesn't use a plugin
+    plugin: Option<PluginState>,
+}
+
+#[derive(Serialize)]
+struct PluginState {
+    /// `starting`, `running` or `restarting`
+    health: &'static str,
+    /// Times the plugin has been restarted after it exited
+    restarts: usize,
+}
+
+#[derive(Serialize)]
+struct ScoreStatus {
+    /// Lower is better, `None` if it hasn't been checked
+    score: Option<u32>,
+    alive: bool,
+    /// Chosen by the load balancer (or pinned)
+    chosen: bool,
+    /// Latency in milliseconds of the latest check or request, `None` if it failed or hasn't been checked
+    last_latency: Option<u32>,
+    /// Seconds since the latest check or request, `None` if it hasn't been checked
+    last_checked: Option<u64>,
+}
+
+impl ScoreStatus {
+    fn new(score: &ServerScore, chosen: bool) -> ScoreStatus {
+        let score_value = score.score();
+        let last_score = score.last_score();
+        ScoreStatus {
+            score: if score_value == u32::MAX {
+                None
+            } else {
+                Some(score_value)
+            },
+            alive: score.is_alive(),
+            chosen,
+            last_latency: match last_score {
+                Some((_, Score::Latency(latency))) => Some(latency),
+                _ => None,
+            },
+            last_checked: last_score.map(|(elapsed, _)| elapsed.as_secs()),
+        }
+    }
+}
+
+/// Web UI server
+pub struct WebUi {
+    context: Arc<ServiceContext>,
+    balancer: PingBalancer,
+    listener: Option<TcpListener>,
+}
+
+impl WebUi {
+    /// Create with an existed context
+    pub fn with_context(context: Arc<ServiceContext>, balancer: PingBalancer) -> WebUi {
+        WebUi {
+            context,
+            balancer,
+            listener: None,
+        }
+    }
+
+    /// Serve on a listener bound to the address of `run`, instead of binding it
+    pub fn set_listener(&mut self, listener: TcpListener) {
+        self.listener = Some(listener);
+    }
+
+    /// Run server
+    pub async fn run(mut self, client_config: &ServerAddr) -> io::Result<()> {
+        let bind_result = match (self.listener.take(), client_config) {
+            (Some(listener), ..) => Ok(listener),
+            (None, ServerAddr::SocketAddr(sa)) => {
+                TcpListener::bind_with_opts(sa, self.context.accept_opts().clone()).await
+            }
+            (None, ServerAddr::DomainName(dname, port)) => {
+                lookup_then!(self.context.context_ref(), dname, *port, |addr| {
+                    TcpListener::bind_with_opts(&addr, self.context.accept_opts().clone()).await
+                })
+                .map(|(_, b)| b)
+            }
+        };
+
+        let listener = match bind_result {
+            Ok(l) => l.into_inner().into_std()?,
+            Err(err) => {
+                error!("web UI server bind error: {}", err);
+                return Err(err);
+            }
+        };
+
+        info!("shadowsocks web UI listening on http://{}", listener.local_addr()?);
+
+        let builder = match Server::from_tcp(listener) {
+            Ok(builder) => builder,
+            Err(err) => {
+                error!("hyper server from std::net::TcpListener error: {}", err);
+                let err = io::Error::new(ErrorKind::InvalidInput, err);
+                return Err(err);
+            }
+        };
+
+        let context = self.context.clone();
+        let balancer = self.balancer.clone();
+        let make_service = make_service_fn(|socket: &AddrStream| {
+            let peer_addr = socket.remote_addr();
+            let context = context.clone();
+            let balancer = balancer.clone();
+
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                    trace!("web UI request {} {} {}", peer_addr, req.method(), req.uri());
+
+                    let context = context.clone();
+                    let balancer = balancer.clone();
+                    async move { Ok::<_, Infallible>(handle_request(&context, &balancer, req).await) }
+                }))
+            }
+        });
+
+        if let Err(err) = builder.serve(make_service).await {
+            error!("hyper server exited with error: {}", err);
+            return Err(io::Error::new(ErrorKind::Other, err));
+        }
+
+        Ok(())
+    }
+}
+
+async fn handle_request(context: &ServiceContext, balancer: &PingBalancer, req: Request<Body>) -> Response<Body> {
+    match (req.method(), req.uri().path()) {
+        (&Method::GET, "/") | (&Method::HEAD, "/") => Response::builder()
+            .header(header::CONTENT_TYPE, "text/html; charset=utf-8")
+            .body(Body::from(INDEX_HTML))
+            .unwrap(),
+        (&Method::GET, "/api/status") => {
+            let status = json5::to_string(&make_status(context, balancer)).expect("serialize status");
+            Response::builder()
+                .header(header::CONTENT_TYPE, "application/json")
+                .header(header::CACHE_CONTROL, "no-cache")
+                .body(Body::from(status))
+                .unwrap()
+        }
+        (&Method::POST, "/api/pin") | (&Method::DELETE, "/api/pin") => {
+            // Pages of the other sites are not allowed to control
+            if !is_same_origin(&req) {
+                return error_response(StatusCode::FORBIDDEN, "cross-origin request");
+            }
+
+            if req.method() == Method::DELETE {
+                balancer.unpin_server();
+                return Response::builder()
+                    .status(StatusCode::NO_CONTENT)
+                    .body(Body::empty())
+                    .unwrap();
+            }
+
+            match read_server_addr(req).await {
+                Ok(addr) => match balancer.pin_server(&addr) {
+                    Ok(..) => Response::builder()
+                        .status(StatusCode::NO_CONTENT)
+                        .body(Body::empty())
+                        .unwrap(),
+                    Err(err) if err.kind() == ErrorKind::NotFound => {
+                        error_response(StatusCode::NOT_FOUND, &err.to_string())
+                    }
+                    Err(err) => error_response(StatusCode::CONFLICT, &err.to_string()),
+                },
+                Err(resp) => resp,
+            }
+        }
+        (&Method::POST, "/api/exclude") | (&Method::DELETE, "/api/exclude") => {
+            if !is_same_origin(&req) {
+                return error_response(StatusCode::FORBIDDEN, "cross-origin request");
+            }
+
+            let exclude = req.method() == Method::POST;
+            let result = match read_server_addr(req).await {
+                Ok(addr) if exclude => balancer.exclude_server(&addr),
+                Ok(addr) => balancer.include_server(&addr),
+                Err(resp) => return resp,
+            };
+            match result {
+                Ok(..) => Response::builder()
+                    .status(StatusCode::NO_CONTENT)
+                    .body(Body::empty())
+                    .unwrap(),
+                Err(err) if err.kind() == ErrorKind::NotFound => {
+                    error_response(StatusCode::NOT_FOUND, &err.to_string())
+                }
+                Err(err) => error_response(StatusCode::CONFLICT, &err.to_string()),
+            }
+        }
+        (&Method::POST, "/api/profile") => {
+            if !is_same_origin(&req) {
+                return error_response(StatusCode::FORBIDDEN, "cross-origin request");
+            }
+
+            let profile_switch = match context.profile_switch() {
+                Some(p) => p,
+                None => return error_response(StatusCode::NOT_FOUND, "no profiles configured"),
+            };
+            let name = match read_body(req).await {
+                Ok(body) => body,
+                Err(resp) => return resp,
+            };
+            match profile_switch.request(name.trim()) {
+                // Servers are recreated after responding
+                Ok(..) => Response::builder()
+                    .status(StatusCode::ACCEPTED)
+                    .body(Body::empty())
+                    .unwrap(),
+                Err(err) => error_response(StatusCode::NOT_FOUND, &err.to_string()),
+            }
+        }
+        #[cfg(feature = "acl")]
+        (&Method::POST, "/api/acl") => {
+            if !is_same_origin(&req) {
+                return error_response(StatusCode::FORBIDDEN, "cross-origin request");
+            }
+
+            match context.reload_acl() {
+                Ok(..) => Response::builder()
+                    .status(StatusCode::NO_CONTENT)
+                    .body(Body::empty())
+                    .unwrap(),
+                Err(err) if err.kind() == ErrorKind::NotFound => {
+                    error_response(StatusCode::NOT_FOUND, &err.to_string())
+                }
+                // Rules are kept if the file is malformed
+                Err(err) => error_response(StatusCode::INTERNAL_SERVER_ERROR, &err.to_string()),
+            }
+        }
+        (_, "/")
+        | (_, "/api/status")
+        | (_, "/api/pin")
+        | (_, "/api/exclude")
+        | (_, "/api/profile")
+        | (_, "/api/acl") => error_response(StatusCode::METHOD_NOT_ALLOWED, ""),
+        _ => error_response(StatusCode::NOT_FOUND, ""),
+    }
+}
+
+fn make_status(context: &ServiceContext, balancer: &PingBalancer) -> StatusResponse {
+    let concurrency_stat = context.concurrency_stat();
+    let concurrency = ConcurrencyKind::ALL
+        .iter()
+        .map(|kind| {
+            let status = ConcurrencyStatus {
+                current: concurrency_stat.current(*kind),
+                peak: concurrency_stat.peak(*kind),
+            };
+            (kind.as_str(), status)
+        })
+        .collect();
+
+    let best_tcp = balancer.best_tcp_server();
+    let best_udp = balancer.best_udp_server();
+    let is_chosen = |server: &ServerIdent, chosen: &Arc<ServerIdent>| ptr::eq(server, chosen.as_ref());
+
+    let servers = balancer
+        .servers()
+        .map(|server| {
+            let svr_cfg = server.server_config();
+            ServerStatus {
+                addr: svr_cfg.addr().to_string(),
+                remarks: svr_cfg.remarks().map(ToOwned::to_owned),
+                tags: svr_cfg.tags().to_vec(),
+                excluded: server.is_excluded(),
+                tcp: ScoreStatus::new(server.tcp_score(), is_chosen(server, &best_tcp)),
+                udp: ScoreStatus::new(server.udp_score(), is_chosen(server, &best_udp)),
+                plugin: server.plugin_status().map(|status| PluginState {
+                    health: status.health().as_str(),
+                    restarts: status.restarts(),
+                }),
+            }
+        })
+        .collect();
+
+    let flow_stat = context.flow_stat_ref();
+
+    StatusResponse {
+        tx: flow_stat.tx(),
+        rx: flow_stat.rx(),
+        concurrency,
+        pinned: balancer
+            .pinned_server()
+            .map(|server| server.server_config().addr().to_string()),
+        servers,
+        profile: context
+            .profile_switch()
+            .and_then(|p| p.current())
+            .map(ToOwned::to_owned),
+        profiles: context
+            .profile_switch()
+            .map(|p| p.profiles().to_vec())
+            .unwrap_or_default(),
+        #[cfg(feature = "acl")]
+        acl: context.acl().map(|acl| acl.file_path().display().to_string()),
+        #[cfg(not(feature = "acl"))]
+        acl: None,
+    }
+}
+
+/// Check if `Origin` of the request, which is sent by browsers for `POST` and `DELETE`, is this server
+fn is_same_origin(req: &Request<Body>) -> bool {
+    let origin = match req.headers().get(header::ORIGIN) {
+        Some(origin) => origin.as_bytes(),
+        None => return true,
+    };
+    let host = match req.headers().get(header::HOST) {
+        Some(host) => host.as_bytes(),
+        None => return false,
+    };
+
+    match origin.iter().position(|b| *b == b'/') {
+        Some(pos) => origin[pos..].strip_prefix(b"//") == Some(host),
+        None => false,
+    }
+}
+
+async fn read_server_addr(req: Request<Body>) -> Result<ServerAddr, Response<Body>> {
+    let body = read_body(req).await?;
+    match body.trim().parse::<ServerAddr>() {
+        Ok(addr) => Ok(addr),
+        Err(..) => Err(error_response(StatusCode::BAD_REQUEST, "invalid server address")),
+    }
+}
+
+async fn read_body(req: Request<Body>) -> Result<String, Response<Body>> {
+    let content_length = req
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok());
+    match content_length {
+        Some(n) if n <= MAX_BODY_SIZE => {}
+        Some(..) => return Err(error_response(StatusCode::PAYLOAD_TOO_LARGE, "")),
+        None => return Err(error_response(StatusCode::LENGTH_REQUIRED, "")),
+    }
+
+    let body = match body::to_bytes(req.into_body()).await {
+        Ok(b) => b,
+        Err(err) => return Err(error_response(StatusCode::BAD_REQUEST, &err.to_string())),
+    };
+
+    match str::from_utf8(&body) {
+        Ok(s) => Ok(s.to_owned()),
+        Err(..) => Err(error_response(StatusCode::BAD_REQUEST, "invalid UTF-8 body")),
+    }
+}
+
+fn error_response(status: StatusCode, message: &str) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "text/plain; charset=utf-8")
+        .body(Body::from(message.to_owned()))
+        .unwrap()
+}
@@ -0,0 +1,92 @@
+//! Structured fields of log records
+//!
+//! Fields are attached to records logged inside `with_fields`, in the MDC (mapped diagnostic context) of the current
+//! thread, so they are written as fields by structured encoders like log4rs' `JsonEncoder`. Records are encoded in
+//! the logging thread, so it works in async tasks as long as the closure doesn't yield.
+//!
+//! Nothing is attached until `enable` is called, formatting fields would be wasted for plain text logs.
+//!
+//! Connections are served in tasks instrumented by `tracing` spans of `connection_span`, carrying their IDs. Fields
+//! of spans are put in the MDC while they are entered, by the subscriber of `sslocal` and `ssserver`, so every record
+//! logged while serving a connection (DNS lookups, server selections, closed tunnels) could be joined by the ID.
+
+use std::{
+    fmt::Display,
+    future::Future,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use tokio::task::JoinHandle;
+use tracing::{info_span, Instrument, Span};
+
+#[cfg(not(any(target_arch = "mips", target_arch = "powerpc")))]
+type IdCounter = std::sync::atomic::AtomicU64;
+#[cfg(any(target_arch = "mips", target_arch = "powerpc"))]
+type IdCounter = std::sync::atomic::AtomicU32;
+
+/// A field of log records, the key and its value
+pub type LogField<'a> = (&'static str, &'a (dyn Display + Sync));
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static NEXT_CONNECTION_ID: IdCounter = IdCounter::new(1);
+
+/// Attach fields to records logged from now on
+pub fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+/// Check if fields are attached to records
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Allocate an ID for identifying records of a connection, unique in this process
+pub fn next_connection_id() -> u64 {
+    NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed) as _
+}
+
+/// Call `f` with `fields` attached to records logged in it
+///
+/// Fields of the same keys, like the ones of the entered span, are restored after `f` returns.
+pub fn with_fields<F, R>(fields: &[LogField<'_>], f: F) -> R
+where
+    F: FnOnce() -> R,
+{
+    if !is_enabled() {
+        return f();
+    }
+
+    let previous = fields
+        .iter()
+        .map(|(key, value)| (*key, log_mdc::insert(*key, value.to_string())))
+        .collect::<Vec<_>>();
+    let result = f();
+    for (key, value) in previous.into_iter().rev() {
+        match value {
+            Some(value) => {
+                log_mdc::insert(key, value);
+            }
+            None => {
+                log_mdc::remove(key);
+            }
+        }
+    }
+    result
+}
+
+/// Create the span of a connection accepted from `peer_addr`, with a new connection ID in field `conn`
+///
+/// IDs are allocated only if the span is enabled by the subscriber.
+pub fn connection_span<A: Display>(peer_addr: A) -> Span {
+    info_span!("connection", conn = next_connection_id(), peer = %peer_addr)
+}
+
+/// Spawn the task serving a connection accepted from `peer_addr`, in its `connection_span`
+pub fn spawn_connection<A, F>(peer_addr: A, future: F) -> JoinHandle<F::Output>
+where
+    A: Display,
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    tokio::spawn(future.instrument(connection_span(peer_addr)))
+}
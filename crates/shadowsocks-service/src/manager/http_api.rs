@@ -0,0 +1,227 @@
+//! HTTP API of manager server
+//!
+//! Same operations as the manager protocol, in JSON over HTTP, authenticated by a bearer token:
+//!
+//! - `GET /api/servers` - List servers, same as `list`
+//! - `POST /api/servers` - Add a server, same as `add`
+//! - `DELETE /api/servers/PORT` - Remove the server listening on `PORT`, same as `remove`
+//! - `GET /api/ping` - Transmitted bytes of servers, same as `ping`
+//! - `POST /api/stat` - Report transmitted bytes of standalone servers, same as `stat`
+//! - `GET /metrics` - Metrics of servers for Prometheus
+
+use std::{
+    convert::Infallible,
+    io::{self, ErrorKind},
+    sync::Arc,
+};
+
+use hyper::{
+    body,
+    header,
+    server::conn::AddrStream,
+    service::{make_service_fn, service_fn},
+    Body,
+    Method,
+    Request,
+    Response,
+    Server,
+    StatusCode,
+};
+use log::{error, info, trace};
+use serde::{de::DeserializeOwned, Serialize};
+use shadowsocks::manager::protocol::{AddRequest, RemoveRequest, StatRequest};
+
+use crate::config::ManagerHttpApiConfig;
+
+use super::{is_token_equal, server::Manager};
+
+/// Maximum length of request bodies
+const MAX_BODY_SIZE: u64 = 64 * 1024;
+
+/// HTTP API server of `Manager`
+pub struct ManagerHttpApi {
+    manager: Arc<Manager>,
+    config: ManagerHttpApiConfig,
+}
+
+impl ManagerHttpApi {
+    /// Create with the served manager
+    pub fn new(manager: Arc<Manager>, config: ManagerHttpApiConfig) -> ManagerHttpApi {
+        ManagerHttpApi { manager, config }
+    }
+
+    /// Run server
+    pub async fn run(self) -> io::Result<()> {
+        let listener = match self.manager.bind_tcp_listener(&self.config.addr).await {
+            Ok(l) => l.into_inner().into_std()?,
+            Err(err) => {
+                error!("manager HTTP API bind error: {}", err);
+                return Err(err);
+            }
+        };
+
+        info!(
+            "shadowsocks manager HTTP API listening on http://{}",
+            listener.local_addr()?
+        );
+
+        let builder = match Server::from_tcp(listener) {
+            Ok(builder) => builder,
+            Err(err) => {
+                error!("hyper server from std::net::TcpListener error: {}", err);
+                let err = io::Error::new(ErrorKind::InvalidInput, err);
+                return Err(err);
+            }
+        };
+
+        let manager = self.manager;
+        let token: Arc<str> = Arc::from(self.config.token);
+        let make_service = make_service_fn(|socket: &AddrStream| {
+            let peer_addr = socket.remote_addr();
+            let manager = manager.clone();
+            let token = token.clone();
+
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                    trace!("manager HTTP API request {} {} {}", peer_addr, req.method(), req.uri());
+
+                    let manager = manager.clone();
+                    let token = token.clone();
+                    async move { Ok::<_, Infallible>(handle_request(&manager, &token, req).await) }
+                }))
+            }
+        });
+
+        if let Err(err) = builder.serve(make_service).await {
+            error!("hyper server exited with error: {}", err);
+            return Err(io::Error::new(ErrorKind::Other, err));
+        }
+
+        Ok(())
+    }
+}
+
+async fn handle_request(manager: &Manager, token: &str, req: Request<Body>) -> Response<Body> {
+    if !is_authorized(&req, token) {
+        return Response::builder()
+            .status(StatusCode::UNAUTHORIZED)
+            .header(header::WWW_AUTHENTICATE, "Bearer")
+            .body(Body::empty())
+            .unwrap();
+    }
+
+    let path = req.uri().path().to_owned();
+    match (req.method(), path.as_str()) {
+        (&Method::GET, "/api/servers") => json_response(StatusCode::OK, &manager.handle_list().await),
+        (&Method::POST, "/api/servers") => {
+            let add_req = match read_json::<AddRequest>(req).await {
+                Ok(r) => r,
+                Err(resp) => return resp,
+            };
+            match manager.handle_add(&add_req).await {
+                Ok(rsp) if rsp.0 == "ok" => Response::builder()
+                    .status(StatusCode::CREATED)
+                    .body(Body::empty())
+                    .unwrap(),
+                // Invalid parameters are described in responses of the manager protocol
+                Ok(rsp) => error_response(StatusCode::BAD_REQUEST, &rsp.0),
+                Err(err) => {
+                    error!("add server_port: {} failed, error: {}", add_req.server_port, err);
+                    error_response(StatusCode::INTERNAL_SERVER_ERROR, &err.to_string())
+                }
+            }
+        }
+        (&Method::GET, "/api/ping") => json_response(StatusCode::OK, &manager.handle_ping().await),
+        (&Method::GET, "/metrics") => Response::builder()
+            .header(header::CONTENT_TYPE, "text/plain; version=0.0.4")
+            .header(header::CACHE_CONTROL, "no-cache")
+            .body(Body::from(manager.handle_metrics().await))
+            .unwrap(),
+        (&Method::POST, "/api/stat") => {
+            let stat_req = match read_json::<StatRequest>(req).await {
+                Ok(r) => r,
+                Err(resp) => return resp,
+            };
+            manager.handle_stat(&stat_req).await;
+            Response::builder()
+                .status(StatusCode::NO_CONTENT)
+                .body(Body::empty())
+                .unwrap()
+        }
+        (method, path) => match path.strip_prefix("/api/servers/") {
+            Some(port) => {
+                if method != Method::DELETE {
+                    return error_response(StatusCode::METHOD_NOT_ALLOWED, "");
+                }
+                let server_port = match port.parse::<u16>() {
+                    Ok(p) => p,
+                    Err(..) => return error_response(StatusCode::NOT_FOUND, ""),
+                };
+                manager.handle_remove(&RemoveRequest { server_port }).await;
+                Response::builder()
+                    .status(StatusCode::NO_CONTENT)
+                    .body(Body::empty())
+                    .unwrap()
+            }
+            None => match path {
+                "/api/servers" | "/api/ping" | "/api/stat" | "/metrics" => {
+                    error_response(StatusCode::METHOD_NOT_ALLOWED, "")
+                }
+                _ => error_response(StatusCode::NOT_FOUND, ""),
+            },
+        },
+    }
+}
+
+/// Check `Authorization: Bearer TOKEN` of the request
+fn is_authorized(req: &Request<Body>, token: &str) -> bool {
+    let value = match req.headers().get(header::AUTHORIZATION) {
+        Some(v) => v.as_bytes(),
+        None => return false,
+    };
+    match value.strip_prefix(b"Bearer ") {
+        Some(given) => is_token_equal(given, token.as_bytes()),
+        None => false,
+    }
+}
+
+async fn read_json<T: DeserializeOwned>(req: Request<Body>) -> Result<T, Response<Body>> {
+    let content_length = req
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok());
+    match content_length {
+        Some(n) if n <= MAX_BODY_SIZE => {}
+        Some(..) => return Err(error_response(StatusCode::PAYLOAD_TOO_LARGE, "")),
+        None => return Err(error_response(StatusCode::LENGTH_REQUIRED, "")),
+    }
+
+    let body = match body::to_bytes(req.into_body()).await {
+        Ok(b) => b,
+        Err(err) => return Err(error_response(StatusCode::BAD_REQUEST, &err.to_string())),
+    };
+
+    match serde_json::from_slice(&body) {
+        Ok(v) => Ok(v),
+        Err(err) => Err(error_response(StatusCode::BAD_REQUEST, &err.to_string())),
+    }
+}
+
+fn json_response<T: Serialize>(status: StatusCode, value: &T) -> Response<Body> {
+    let body = serde_json::to_vec(value).expect("serialize response");
+    Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "application/json")
+        .header(header::CACHE_CONTROL, "no-cache")
+        .body(Body::from(body))
+        .unwrap()
+}
+
+fn error_response(status: StatusCode, message: &str) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "text/plain; charset=utf-8")
+        .body(Body::from(message.to_owned()))
+        .unwrap()
+}
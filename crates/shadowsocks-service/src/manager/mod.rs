@@ -10,12 +10,37 @@ use shadowsocks::net::{AcceptOpts, ConnectOpts};
 use crate::{
     config::{Config, ConfigType},
     dns::build_dns_resolver,
-    server::SERVER_DEFAULT_KEEPALIVE_TIMEOUT,
+    net::RateLimiter,
+    server::{client_limit::ClientLimiter, firewall::OutboundFirewall, SERVER_DEFAULT_KEEPALIVE_TIMEOUT},
+    store::create_state_store,
 };
 
+#[cfg(feature = "manager-http-api")]
+pub use self::http_api::ManagerHttpApi;
 pub use self::server::Manager;
-
+#[cfg(feature = "manager-sip008")]
+pub use self::sip008::ManagerSip008Server;
+#[cfg(feature = "manager-web-ui")]
+pub use self::web_ui::ManagerWebUi;
+
+#[cfg(feature = "manager-http-api")]
+pub mod http_api;
+#[cfg(feature = "manager-http-api")]
+pub use crate::metrics;
 pub mod server;
+#[cfg(feature = "manager-sip008")]
+pub mod sip008;
+#[cfg(feature = "manager-web-ui")]
+pub mod web_ui;
+
+/// Compare tokens of requests in constant time of their lengths
+#[cfg(any(feature = "manager-http-api", feature = "manager-sip008", feature = "manager-web-ui"))]
+fn is_token_equal(given: &[u8], expected: &[u8]) -> bool {
+    if given.len() != expected.len() {
+        return false;
+    }
+    given.iter().zip(expected).fold(0u8, |acc, (a, b)| acc | (a ^ b)) == 0
+}
 
 /// Starts a manager server
 pub async fn run(config: Config) -> io::Result<()> {
@@ -31,7 +56,35 @@ pub async fn run(config: Config) -> io::Result<()> {
         }
     }
 
+    if let Err(err) = shadowsocks::relay::buffer_pool::init(config.buffer_pool) {
+        log::warn!("buffer pool initialization failed, error: {}", err);
+    }
+
+    if config.udp_offload {
+        if let Err(err) = shadowsocks::net::udp::init_offload() {
+            log::warn!(
+                "UDP offload initialization failed, error: {}, fallback to sending datagrams one by one",
+                err
+            );
+        }
+    }
+
+    let state_store = create_state_store(&config)?;
+
     let mut manager = Manager::new(config.manager.expect("missing manager config"));
+    manager.set_state_store(state_store);
+
+    if let Some(rate) = config.global_rate_limit {
+        manager.set_global_rate_limiter(Arc::new(RateLimiter::new(rate)));
+    }
+
+    if let Some(outbound_firewall) = config.outbound_firewall {
+        manager.set_outbound_firewall(Arc::new(OutboundFirewall::new(outbound_firewall)));
+    }
+
+    if let Some(client_limit) = config.client_limit {
+        manager.set_client_limiter(Arc::new(ClientLimiter::new(client_limit)));
+    }
 
     let mut connect_opts = ConnectOpts {
         #[cfg(any(target_os = "linux", target_os = "android"))]
@@ -39,6 +92,8 @@ pub async fn run(config: Config) -> io::Result<()> {
 
         #[cfg(target_os = "android")]
         vpn_protect_path: config.outbound_vpn_protect_path,
+        #[cfg(unix)]
+        socket_protector: config.outbound_socket_protector,
 
         bind_local_addr: config.outbound_bind_addr,
         bind_interface: config.outbound_bind_interface,
@@ -67,16 +122,26 @@ pub async fn run(config: Config) -> io::Result<()> {
     }
 
     manager.set_connect_opts(connect_opts);
+    manager.set_ip_strategy(config.ip_strategy);
     manager.set_accept_opts(accept_opts);
 
     if let Some(c) = config.udp_max_associations {
         manager.set_udp_capacity(c);
     }
 
+    if let Some(e) = config.udp_eviction {
+        manager.set_udp_eviction(e);
+    }
+
     if let Some(d) = config.udp_timeout {
         manager.set_udp_expiry_duration(d);
     }
 
+    if let Some(d) = config.drain_timeout {
+        manager.set_drain_timeout(d);
+    }
+
+    #[cfg(feature = "acl")]
     if let Some(acl) = config.acl {
         manager.set_acl(Arc::new(acl));
     }
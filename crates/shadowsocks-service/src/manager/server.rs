@@ -2,43 +2,101 @@
 
 #[cfg(unix)]
 use std::path::PathBuf;
-use std::{collections::HashMap, io, net::SocketAddr, sync::Arc, time::Duration};
+#[cfg(any(feature = "manager-http-api", feature = "manager-web-ui"))]
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::{
+    collections::HashMap,
+    io::{self, ErrorKind},
+    net::SocketAddr,
+    sync::Arc,
+    time::Duration,
+};
 
-use log::{error, info, trace};
+use futures::{stream::FuturesUnordered, FutureExt, StreamExt};
+use log::{error, info, trace, warn};
+#[cfg(feature = "aead-cipher-2022")]
+use shadowsocks::config::{ServerUser, ServerUserManager};
 use shadowsocks::{
-    config::{Mode, ServerConfig, ServerType},
+    config::{password_to_key, Mode, ServerConfig, ServerType},
     context::{Context, SharedContext},
-    crypto::v1::CipherKind,
+    crypto::CipherKind,
     dns_resolver::DnsResolver,
     manager::protocol::{
         self,
         AddRequest,
         AddResponse,
+        AddUserRequest,
+        AddUserResponse,
+        DrainRequest,
+        DrainResponse,
         ErrorResponse,
         ListResponse,
+        ListUsersRequest,
+        ListUsersResponse,
         ManagerRequest,
         PingResponse,
         RemoveRequest,
         RemoveResponse,
+        RemoveUserRequest,
+        RemoveUserResponse,
+        ResetTrafficRequest,
+        ResetTrafficResponse,
         StatRequest,
     },
-    net::{AcceptOpts, ConnectOpts},
+    net::{AcceptOpts, ConnectOpts, IpStrategy},
     plugin::PluginConfig,
     ManagerListener,
     ServerAddr,
 };
+#[cfg(any(feature = "manager-http-api", feature = "manager-sip008", feature = "manager-web-ui"))]
+use shadowsocks::{lookup_then, net::TcpListener};
 use tokio::{sync::Mutex, task::JoinHandle};
 
+#[cfg(feature = "acl")]
+use crate::acl::AccessControl;
+#[cfg(feature = "aead-cipher-2022")]
+use crate::net::UserFlowStat;
+#[cfg(any(feature = "manager-http-api", feature = "manager-web-ui"))]
+use crate::net::{ConcurrencyKind, ConcurrencyStat};
 use crate::{
-    acl::AccessControl,
     config::{ManagerConfig, ManagerServerHost, ManagerServerMode, SecurityConfig},
-    net::FlowStat,
-    server::Server,
+    net::{FlowStat, RateLimiter},
+    server::{
+        client_limit::ClientLimiter,
+        drain::DrainHandle,
+        firewall::OutboundFirewall,
+        udp_eviction::UdpEvictionConfig,
+        Server,
+    },
+    store::StateStore,
 };
 
+#[cfg(feature = "manager-http-api")]
+use super::http_api::ManagerHttpApi;
+#[cfg(feature = "manager-sip008")]
+use super::sip008::{ManagerSip008Server, Sip008Document, Sip008Server};
+#[cfg(feature = "manager-web-ui")]
+use super::web_ui::{ManagerWebUi, ServerStatus, UserStatus};
+#[cfg(feature = "manager-http-api")]
+use crate::metrics::{MetricType, MetricsEncoder};
+
+/// Key of servers added by commands in `StateStore`
+const MANAGER_SERVERS_KEY: &str = "manager-servers";
+
+/// Default deadline of draining servers removed by `drain`
+const MANAGER_DEFAULT_DRAIN_TIMEOUT: Duration = Duration::from_secs(300);
+
 enum ServerInstanceMode {
     Builtin {
         flow_stat: Arc<FlowStat>,
+        #[cfg(feature = "aead-cipher-2022")]
+        user_flow_stat: Arc<UserFlowStat>,
+        #[cfg(any(feature = "manager-http-api", feature = "manager-web-ui"))]
+        concurrency_stat: Arc<ConcurrencyStat>,
+        // Cleared when the server exits
+        #[cfg(any(feature = "manager-http-api", feature = "manager-web-ui"))]
+        running: Arc<AtomicBool>,
+        drain: DrainHandle,
         abortable: JoinHandle<io::Result<()>>,
     },
 
@@ -49,6 +107,8 @@ enum ServerInstanceMode {
 struct ServerInstance {
     mode: ServerInstanceMode,
     svr_cfg: ServerConfig,
+    // Added or changed by commands, saved in `StateStore`
+    persisted: bool,
 }
 
 impl Drop for ServerInstance {
@@ -68,6 +128,42 @@ impl ServerInstance {
             ServerInstanceMode::Standalone { flow_stat } => flow_stat,
         }
     }
+
+    // Password, used bytes and quota of `user` of this server, `None` if it couldn't be used by `user`
+    #[cfg(feature = "manager-sip008")]
+    fn sip008_credential(&self, user: Option<&str>) -> Option<(String, u64, Option<u64>)> {
+        match user {
+            None => {
+                // Clients of multi-user servers have to be identified by their users
+                #[cfg(feature = "aead-cipher-2022")]
+                if self.svr_cfg.user_manager().is_some() {
+                    return None;
+                }
+                Some((
+                    self.svr_cfg.password().to_owned(),
+                    self.flow_stat(),
+                    self.svr_cfg.quota(),
+                ))
+            }
+            #[cfg(feature = "aead-cipher-2022")]
+            Some(name) => {
+                let user_manager = self.svr_cfg.user_manager()?;
+                let user = user_manager.users().into_iter().find(|u| u.name() == name)?;
+                let used = match self.mode {
+                    ServerInstanceMode::Builtin { ref user_flow_stat, .. } => user_flow_stat
+                        .get(name)
+                        .map(|flow_stat| flow_stat.tx() + flow_stat.rx())
+                        .unwrap_or(0),
+                    #[cfg(unix)]
+                    ServerInstanceMode::Standalone { .. } => 0,
+                };
+                let password = format!("{}:{}", self.svr_cfg.password(), user.encoded_key());
+                Some((password, used, user.quota()))
+            }
+            #[cfg(not(feature = "aead-cipher-2022"))]
+            Some(..) => None,
+        }
+    }
 }
 
 /// Manager server
@@ -79,9 +175,16 @@ pub struct Manager {
     accept_opts: AcceptOpts,
     udp_expiry_duration: Option<Duration>,
     udp_capacity: Option<usize>,
+    udp_eviction: Option<UdpEvictionConfig>,
+    #[cfg(feature = "acl")]
     acl: Option<Arc<AccessControl>>,
-    ipv6_first: bool,
+    ip_strategy: IpStrategy,
     security: SecurityConfig,
+    state_store: Option<Arc<dyn StateStore>>,
+    global_rate_limiter: Option<Arc<RateLimiter>>,
+    outbound_firewall: Option<Arc<OutboundFirewall>>,
+    client_limiter: Option<Arc<ClientLimiter>>,
+    drain_timeout: Duration,
 }
 
 impl Manager {
@@ -100,9 +203,16 @@ impl Manager {
             accept_opts: AcceptOpts::default(),
             udp_expiry_duration: None,
             udp_capacity: None,
+            udp_eviction: None,
+            #[cfg(feature = "acl")]
             acl: None,
-            ipv6_first: false,
+            ip_strategy: IpStrategy::default(),
             security: SecurityConfig::default(),
+            state_store: None,
+            global_rate_limiter: None,
+            outbound_firewall: None,
+            client_limiter: None,
+            drain_timeout: MANAGER_DEFAULT_DRAIN_TIMEOUT,
         }
     }
 
@@ -126,6 +236,11 @@ impl Manager {
         self.udp_capacity = Some(c);
     }
 
+    /// Set eviction of UDP associations when one server has `udp_capacity` associations
+    pub fn set_udp_eviction(&mut self, e: UdpEvictionConfig) {
+        self.udp_eviction = Some(e);
+    }
+
     /// Get the manager's configuration
     pub fn config(&self) -> &ManagerConfig {
         &self.svr_cfg
@@ -138,13 +253,19 @@ impl Manager {
     }
 
     /// Set access control list
+    #[cfg(feature = "acl")]
     pub fn set_acl(&mut self, acl: Arc<AccessControl>) {
         self.acl = Some(acl);
     }
 
     /// Try to connect IPv6 addresses first if hostname could be resolved to both IPv4 and IPv6
     pub fn set_ipv6_first(&mut self, ipv6_first: bool) {
-        self.ipv6_first = ipv6_first;
+        self.ip_strategy = IpStrategy::from_ipv6_first(ipv6_first);
+    }
+
+    /// Set strategy of choosing among resolved IPv4 and IPv6 addresses
+    pub fn set_ip_strategy(&mut self, ip_strategy: IpStrategy) {
+        self.ip_strategy = ip_strategy;
     }
 
     /// Set security config
@@ -152,13 +273,85 @@ impl Manager {
         self.security = security;
     }
 
+    /// Set `StateStore` for persisting servers added by commands, and traffic counters of builtin servers
+    pub fn set_state_store(&mut self, state_store: Arc<dyn StateStore>) {
+        self.state_store = Some(state_store);
+    }
+
+    /// Set bandwidth limit shared by all builtin servers
+    pub fn set_global_rate_limiter(&mut self, global_rate_limiter: Arc<RateLimiter>) {
+        self.global_rate_limiter = Some(global_rate_limiter);
+    }
+
+    /// Set outbound firewall shared by all builtin servers
+    pub fn set_outbound_firewall(&mut self, outbound_firewall: Arc<OutboundFirewall>) {
+        self.outbound_firewall = Some(outbound_firewall);
+    }
+
+    /// Set per-client limiter shared by all builtin servers
+    pub fn set_client_limiter(&mut self, client_limiter: Arc<ClientLimiter>) {
+        self.client_limiter = Some(client_limiter);
+    }
+
+    /// Set how long established connections of servers removed by `drain` are kept
+    pub fn set_drain_timeout(&mut self, drain_timeout: Duration) {
+        self.drain_timeout = drain_timeout;
+    }
+
+    /// Bind a TCP listener of HTTP services on `addr`
+    #[cfg(any(feature = "manager-http-api", feature = "manager-sip008", feature = "manager-web-ui"))]
+    pub(super) async fn bind_tcp_listener(&self, addr: &ServerAddr) -> io::Result<TcpListener> {
+        match *addr {
+            ServerAddr::SocketAddr(sa) => TcpListener::bind_with_opts(&sa, self.accept_opts.clone()).await,
+            ServerAddr::DomainName(ref dname, port) => lookup_then!(&self.context, dname, port, |addr| {
+                TcpListener::bind_with_opts(&addr, self.accept_opts.clone()).await
+            })
+            .map(|(_, l)| l),
+        }
+    }
+
     /// Start serving
     pub async fn run(self) -> io::Result<()> {
+        // Servers added by commands before restart replace the configured ones on the same ports
+        self.restore_servers().await;
+
         let mut listener = ManagerListener::bind(&self.context, &self.svr_cfg.addr).await?;
+        if let Some(ref key) = self.svr_cfg.key {
+            listener.set_key(key.as_bytes());
+        }
 
         let local_addr = listener.local_addr()?;
         info!("shadowsocks manager server listening on {}", local_addr);
 
+        let manager = Arc::new(self);
+
+        let mut vfut = FuturesUnordered::new();
+
+        #[cfg(feature = "manager-http-api")]
+        if let Some(ref http_api) = manager.svr_cfg.http_api {
+            let http_api = ManagerHttpApi::new(manager.clone(), http_api.clone());
+            vfut.push(http_api.run().boxed());
+        }
+
+        #[cfg(feature = "manager-sip008")]
+        if let Some(ref sip008) = manager.svr_cfg.sip008 {
+            let sip008 = ManagerSip008Server::new(manager.clone(), sip008.clone());
+            vfut.push(sip008.run().boxed());
+        }
+
+        #[cfg(feature = "manager-web-ui")]
+        if let Some(ref web_ui) = manager.svr_cfg.web_ui {
+            let web_ui = ManagerWebUi::new(manager.clone(), web_ui.clone());
+            vfut.push(web_ui.run().boxed());
+        }
+
+        vfut.push(manager.serve(listener).boxed());
+
+        // Any of them exits
+        vfut.next().await.expect("manager services empty")
+    }
+
+    async fn serve(&self, mut listener: ManagerListener) -> io::Result<()> {
         loop {
             let (req, peer_addr) = match listener.recv_from().await {
                 Ok(r) => r,
@@ -185,6 +378,31 @@ impl Manager {
                     let rsp = self.handle_remove(req).await;
                     let _ = listener.send_to(&rsp, &peer_addr).await;
                 }
+                ManagerRequest::Drain(ref req) => {
+                    let rsp = self.handle_drain(req).await;
+                    let _ = listener.send_to(&rsp, &peer_addr).await;
+                }
+                ManagerRequest::AddUser(ref req) => {
+                    let rsp = self.handle_add_user(req).await;
+                    let _ = listener.send_to(&rsp, &peer_addr).await;
+                }
+                ManagerRequest::RemoveUser(ref req) => {
+                    let rsp = self.handle_remove_user(req).await;
+                    let _ = listener.send_to(&rsp, &peer_addr).await;
+                }
+                ManagerRequest::ListUsers(ref req) => match self.handle_list_users(req).await {
+                    Ok(rsp) => {
+                        let _ = listener.send_to(&rsp, &peer_addr).await;
+                    }
+                    Err(err) => {
+                        let rsp = ErrorResponse(err);
+                        let _ = listener.send_to(&rsp, &peer_addr).await;
+                    }
+                },
+                ManagerRequest::ResetTraffic(ref req) => {
+                    let rsp = self.handle_reset_traffic(req).await;
+                    let _ = listener.send_to(&rsp, &peer_addr).await;
+                }
                 ManagerRequest::List(..) => {
                     let rsp = self.handle_list().await;
                     let _ = listener.send_to(&rsp, &peer_addr).await;
@@ -225,16 +443,38 @@ impl Manager {
             server.set_udp_capacity(c);
         }
 
+        if let Some(ref e) = self.udp_eviction {
+            server.set_udp_eviction(e.clone());
+        }
+
+        #[cfg(feature = "acl")]
         if let Some(ref acl) = self.acl {
             server.set_acl(acl.clone());
         }
 
-        if self.ipv6_first {
-            server.set_ipv6_first(self.ipv6_first);
-        }
+        server.set_ip_strategy(self.ip_strategy);
 
         server.set_security_config(&self.security);
 
+        if let Some(ref state_store) = self.state_store {
+            server.set_state_store(state_store.clone());
+        }
+
+        if let Some(ref global_rate_limiter) = self.global_rate_limiter {
+            server.set_global_rate_limiter(global_rate_limiter.clone());
+        }
+
+        if let Some(ref outbound_firewall) = self.outbound_firewall {
+            server.set_outbound_firewall(outbound_firewall.clone());
+        }
+
+        if let Some(ref client_limiter) = self.client_limiter {
+            server.set_client_limiter(client_limiter.clone());
+        }
+
+        let drain = DrainHandle::new();
+        server.set_drain_handle(drain.clone());
+
         let server_port = server.config().addr().port();
 
         let mut servers = self.servers.lock().await;
@@ -248,14 +488,41 @@ impl Manager {
         }
 
         let flow_stat = server.flow_stat();
-
+        #[cfg(feature = "aead-cipher-2022")]
+        let user_flow_stat = server.user_flow_stat().clone();
+        #[cfg(any(feature = "manager-http-api", feature = "manager-web-ui"))]
+        let concurrency_stat = server.concurrency_stat().clone();
+
+        #[cfg(any(feature = "manager-http-api", feature = "manager-web-ui"))]
+        let running = Arc::new(AtomicBool::new(true));
+        #[cfg(any(feature = "manager-http-api", feature = "manager-web-ui"))]
+        let abortable = {
+            let running = running.clone();
+            tokio::spawn(async move {
+                let result = server.run().await;
+                running.store(false, Ordering::Relaxed);
+                result
+            })
+        };
+        #[cfg(not(any(feature = "manager-http-api", feature = "manager-web-ui")))]
         let abortable = tokio::spawn(async move { server.run().await });
 
         servers.insert(
             server_port,
             ServerInstance {
-                mode: ServerInstanceMode::Builtin { flow_stat, abortable },
+                mode: ServerInstanceMode::Builtin {
+                    flow_stat,
+                    #[cfg(feature = "aead-cipher-2022")]
+                    user_flow_stat,
+                    #[cfg(any(feature = "manager-http-api", feature = "manager-web-ui"))]
+                    concurrency_stat,
+                    #[cfg(any(feature = "manager-http-api", feature = "manager-web-ui"))]
+                    running,
+                    drain,
+                    abortable,
+                },
                 svr_cfg,
+                persisted: false,
             },
         );
     }
@@ -341,6 +608,14 @@ impl Manager {
         let mut config = Config::new(ConfigType::Server);
         config.server.push(svr_cfg.clone());
 
+        // Key is written in the configuration file instead of command line, which could be seen by the other users
+        if let Some(ref key) = self.svr_cfg.key {
+            let mut manager_config = ManagerConfig::new(self.svr_cfg.addr.clone());
+            manager_config.mode = svr_cfg.mode();
+            manager_config.key = Some(key.clone());
+            config.manager = Some(manager_config);
+        }
+
         trace!("created standalone server with config {:?}", config);
 
         let config_file_content = format!("{}", config);
@@ -391,11 +666,21 @@ impl Manager {
             ServerInstance {
                 mode: ServerInstanceMode::Standalone { flow_stat: 0 },
                 svr_cfg,
+                persisted: false,
             },
         );
     }
 
-    async fn handle_add(&self, req: &AddRequest) -> io::Result<AddResponse> {
+    pub(super) async fn handle_add(&self, req: &AddRequest) -> io::Result<AddResponse> {
+        let rsp = self.add_server_by_request(req).await?;
+        if rsp.0 == "ok" {
+            let mut servers = self.servers.lock().await;
+            self.persist_server(&mut servers, req.server_port);
+        }
+        Ok(rsp)
+    }
+
+    async fn add_server_by_request(&self, req: &AddRequest) -> io::Result<AddResponse> {
         let addr = match self.svr_cfg.server_host {
             ManagerServerHost::Domain(ref dname) => ServerAddr::DomainName(dname.clone(), req.server_port),
             ManagerServerHost::Ip(ip) => ServerAddr::SocketAddr(SocketAddr::new(ip, req.server_port)),
@@ -414,13 +699,33 @@ impl Manager {
             None => self.svr_cfg.method.unwrap_or(CipherKind::CHACHA20_POLY1305),
         };
 
+        if let Err(err) = password_to_key(method, &req.password) {
+            error!("invalid password, req: {:?}, error: {}", req, err);
+
+            return Ok(AddResponse(err.to_string()));
+        }
+
         let mut svr_cfg = ServerConfig::new(addr, req.password.clone(), method);
 
         if let Some(ref plugin) = req.plugin {
+            let plugin_mode = match req.plugin_mode {
+                None => Mode::TcpOnly,
+                Some(ref mode) => match mode.parse::<Mode>() {
+                    Ok(m) => m,
+                    Err(..) => {
+                        error!("unrecognized plugin_mode \"{}\", req: {:?}", mode, req);
+
+                        let err = format!("unrecognized plugin_mode \"{}\"", mode);
+                        return Ok(AddResponse(err));
+                    }
+                },
+            };
+
             let p = PluginConfig {
                 plugin: plugin.clone(),
                 plugin_opts: req.plugin_opts.clone(),
                 plugin_args: Vec::new(),
+                plugin_mode,
             };
             svr_cfg.set_plugin(p);
         } else if let Some(ref plugin) = self.svr_cfg.plugin {
@@ -442,14 +747,61 @@ impl Manager {
 
         svr_cfg.set_mode(mode.unwrap_or(self.svr_cfg.mode));
 
+        if let Some(quota) = req.quota {
+            if quota == 0 {
+                error!("invalid quota, req: {:?}", req);
+                return Ok(AddResponse("`quota` must be > 0".to_owned()));
+            }
+            svr_cfg.set_quota(quota);
+        }
+
+        if let Some(ref users) = req.users {
+            #[cfg(feature = "aead-cipher-2022")]
+            {
+                let user_manager = ServerUserManager::new();
+                for user in users {
+                    match ServerUser::with_encoded_key(user.name.clone(), method, &user.password) {
+                        Ok(mut u) => {
+                            if let Some(quota) = user.quota {
+                                u.set_quota(quota);
+                            }
+                            user_manager.add_user(u);
+                        }
+                        Err(err) => {
+                            error!(
+                                "invalid password of user \"{}\", req: {:?}, error: {}",
+                                user.name, req, err
+                            );
+
+                            let err = format!("invalid password of user \"{}\", {}", user.name, err);
+                            return Ok(AddResponse(err));
+                        }
+                    }
+                }
+                svr_cfg.set_user_manager(Arc::new(user_manager));
+            }
+
+            #[cfg(not(feature = "aead-cipher-2022"))]
+            {
+                error!("multi-user servers are not supported, req: {:?}", req);
+
+                let _ = users;
+                return Ok(AddResponse("multi-user servers are not supported".to_owned()));
+            }
+        }
+
         self.add_server(svr_cfg).await;
 
         Ok(AddResponse("ok".to_owned()))
     }
 
-    async fn handle_remove(&self, req: &RemoveRequest) -> RemoveResponse {
+    pub(super) async fn handle_remove(&self, req: &RemoveRequest) -> RemoveResponse {
         let mut servers = self.servers.lock().await;
-        servers.remove(&req.server_port);
+        if let Some(server) = servers.remove(&req.server_port) {
+            if server.persisted {
+                self.save_servers(&servers);
+            }
+        }
 
         #[cfg(unix)]
         if self.svr_cfg.server_mode == ManagerServerMode::Standalone {
@@ -459,7 +811,281 @@ impl Manager {
         RemoveResponse("ok".to_owned())
     }
 
-    async fn handle_list(&self) -> ListResponse {
+    async fn handle_drain(&self, req: &DrainRequest) -> DrainResponse {
+        let mut servers = self.servers.lock().await;
+        let server = match servers.remove(&req.server_port) {
+            Some(s) => s,
+            None => return DrainResponse(format!("server_port {} not found", req.server_port)),
+        };
+
+        let drain = match server.mode {
+            ServerInstanceMode::Builtin { ref drain, .. } => drain.clone(),
+            #[cfg(unix)]
+            ServerInstanceMode::Standalone { .. } => {
+                servers.insert(req.server_port, server);
+                return DrainResponse("standalone servers couldn't be drained".to_owned());
+            }
+        };
+
+        if server.persisted {
+            self.save_servers(&servers);
+        }
+
+        // Listeners are closed, the server is kept until established connections are closed or timed out
+        drain.drain();
+        info!(
+            "draining managed server listening on {} for at most {:?}",
+            server.svr_cfg.addr(),
+            self.drain_timeout
+        );
+        let drain_timeout = self.drain_timeout;
+        tokio::spawn(async move {
+            drain.wait_drained(drain_timeout).await;
+            drop(server);
+        });
+
+        DrainResponse("ok".to_owned())
+    }
+
+    #[cfg(feature = "aead-cipher-2022")]
+    async fn handle_add_user(&self, req: &AddUserRequest) -> AddUserResponse {
+        let mut servers = self.servers.lock().await;
+        let server = match servers.get(&req.server_port) {
+            Some(s) => s,
+            None => return AddUserResponse(format!("server_port {} not found", req.server_port)),
+        };
+
+        #[cfg(unix)]
+        if let ServerInstanceMode::Standalone { .. } = server.mode {
+            return AddUserResponse("users of standalone servers couldn't be changed".to_owned());
+        }
+
+        let user_manager = match server.svr_cfg.user_manager() {
+            Some(m) => m,
+            None => return AddUserResponse(format!("server_port {} is not a multi-user server", req.server_port)),
+        };
+
+        if req.quota == Some(0) {
+            return AddUserResponse("`quota` must be > 0".to_owned());
+        }
+
+        match ServerUser::with_encoded_key(req.name.clone(), server.svr_cfg.method(), &req.password) {
+            Ok(mut user) => {
+                if let Some(quota) = req.quota {
+                    user.set_quota(quota);
+                }
+                user_manager.add_user(user);
+
+                if let ServerInstanceMode::Builtin { ref user_flow_stat, .. } = server.mode {
+                    user_flow_stat.user(&req.name).set_quota(req.quota);
+                }
+                info!("added user \"{}\" to server_port {}", req.name, req.server_port);
+
+                self.persist_server(&mut servers, req.server_port);
+
+                AddUserResponse("ok".to_owned())
+            }
+            Err(err) => {
+                error!("invalid password of user \"{}\", error: {}", req.name, err);
+
+                AddUserResponse(err.to_string())
+            }
+        }
+    }
+
+    #[cfg(not(feature = "aead-cipher-2022"))]
+    async fn handle_add_user(&self, _: &AddUserRequest) -> AddUserResponse {
+        AddUserResponse("multi-user servers are not supported".to_owned())
+    }
+
+    #[cfg(feature = "aead-cipher-2022")]
+    async fn handle_remove_user(&self, req: &RemoveUserRequest) -> RemoveUserResponse {
+        let mut servers = self.servers.lock().await;
+        let server = match servers.get(&req.server_port) {
+            Some(s) => s,
+            None => return RemoveUserResponse(format!("server_port {} not found", req.server_port)),
+        };
+
+        let user_flow_stat = match server.mode {
+            ServerInstanceMode::Builtin { ref user_flow_stat, .. } => user_flow_stat,
+            #[cfg(unix)]
+            ServerInstanceMode::Standalone { .. } => {
+                return RemoveUserResponse("users of standalone servers couldn't be changed".to_owned());
+            }
+        };
+
+        let user_manager = match server.svr_cfg.user_manager() {
+            Some(m) => m,
+            None => {
+                return RemoveUserResponse(format!("server_port {} is not a multi-user server", req.server_port));
+            }
+        };
+
+        // Established connections of the user are kept until they are closed
+        if user_manager.remove_user(&req.name).is_some() {
+            user_flow_stat.remove(&req.name);
+            info!("removed user \"{}\" from server_port {}", req.name, req.server_port);
+
+            self.persist_server(&mut servers, req.server_port);
+        }
+
+        RemoveUserResponse("ok".to_owned())
+    }
+
+    #[cfg(not(feature = "aead-cipher-2022"))]
+    async fn handle_remove_user(&self, _: &RemoveUserRequest) -> RemoveUserResponse {
+        RemoveUserResponse("multi-user servers are not supported".to_owned())
+    }
+
+    /// Mark the server on `port` as changed by commands, and save servers to `StateStore`
+    fn persist_server(&self, servers: &mut HashMap<u16, ServerInstance>, port: u16) {
+        if let Some(server) = servers.get_mut(&port) {
+            server.persisted = true;
+        }
+        self.save_servers(servers);
+    }
+
+    fn save_servers(&self, servers: &HashMap<u16, ServerInstance>) {
+        let state_store = match self.state_store {
+            Some(ref s) => s,
+            None => return,
+        };
+
+        let mut requests = servers
+            .values()
+            .filter(|server| server.persisted)
+            .map(|server| server_request(&server.svr_cfg))
+            .collect::<Vec<AddRequest>>();
+        requests.sort_unstable_by_key(|req| req.server_port);
+
+        let value = json5::to_string(&requests).expect("serialize servers");
+        if let Err(err) = state_store.save(MANAGER_SERVERS_KEY, value.as_bytes()) {
+            warn!("failed to save managed servers, error: {}", err);
+        }
+    }
+
+    async fn restore_servers(&self) {
+        let state_store = match self.state_store {
+            Some(ref s) => s,
+            None => return,
+        };
+
+        let value = match state_store.load(MANAGER_SERVERS_KEY) {
+            Ok(Some(v)) => v,
+            Ok(None) => return,
+            Err(err) => {
+                warn!("failed to restore managed servers, error: {}", err);
+                return;
+            }
+        };
+
+        let requests = match json5::from_str::<Vec<AddRequest>>(&String::from_utf8_lossy(&value)) {
+            Ok(r) => r,
+            Err(err) => {
+                warn!("failed to restore managed servers, malformed state, error: {}", err);
+                return;
+            }
+        };
+
+        for req in requests {
+            match self.add_server_by_request(&req).await {
+                Ok(rsp) if rsp.0 == "ok" => {
+                    let mut servers = self.servers.lock().await;
+                    if let Some(server) = servers.get_mut(&req.server_port) {
+                        server.persisted = true;
+                    }
+                    info!("restored managed server_port {}", req.server_port);
+                }
+                Ok(rsp) => warn!("failed to restore server_port {}, {}", req.server_port, rsp.0),
+                Err(err) => warn!("failed to restore server_port {}, error: {}", req.server_port, err),
+            }
+        }
+    }
+
+    #[cfg(feature = "aead-cipher-2022")]
+    async fn handle_list_users(&self, req: &ListUsersRequest) -> io::Result<ListUsersResponse> {
+        let servers = self.servers.lock().await;
+        let server = match servers.get(&req.server_port) {
+            Some(s) => s,
+            None => {
+                let err = io::Error::new(
+                    ErrorKind::NotFound,
+                    format!("server_port {} not found", req.server_port),
+                );
+                return Err(err);
+            }
+        };
+
+        let user_manager = match server.svr_cfg.user_manager() {
+            Some(m) => m,
+            None => {
+                let err = io::Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("server_port {} is not a multi-user server", req.server_port),
+                );
+                return Err(err);
+            }
+        };
+
+        let users = user_manager
+            .users()
+            .iter()
+            .map(|u| protocol::ServerUserConfig {
+                name: u.name().to_owned(),
+                password: u.encoded_key(),
+                quota: u.quota(),
+            })
+            .collect();
+
+        Ok(ListUsersResponse { users })
+    }
+
+    #[cfg(not(feature = "aead-cipher-2022"))]
+    async fn handle_list_users(&self, _: &ListUsersRequest) -> io::Result<ListUsersResponse> {
+        Err(io::Error::new(ErrorKind::Other, "multi-user servers are not supported"))
+    }
+
+    async fn handle_reset_traffic(&self, req: &ResetTrafficRequest) -> ResetTrafficResponse {
+        let servers = self.servers.lock().await;
+        let server = match servers.get(&req.server_port) {
+            Some(s) => s,
+            None => return ResetTrafficResponse(format!("server_port {} not found", req.server_port)),
+        };
+
+        match server.mode {
+            #[cfg_attr(not(feature = "aead-cipher-2022"), allow(unused_variables))]
+            ServerInstanceMode::Builtin {
+                ref flow_stat,
+                #[cfg(feature = "aead-cipher-2022")]
+                ref user_flow_stat,
+                ..
+            } => match req.name {
+                None => flow_stat.reset(),
+                #[cfg(feature = "aead-cipher-2022")]
+                Some(ref name) => match user_flow_stat.get(name) {
+                    Some(flow_stat) => flow_stat.reset(),
+                    None => {
+                        return ResetTrafficResponse(format!(
+                            "user \"{}\" of server_port {} not found",
+                            name, req.server_port
+                        ));
+                    }
+                },
+                #[cfg(not(feature = "aead-cipher-2022"))]
+                Some(..) => return ResetTrafficResponse("multi-user servers are not supported".to_owned()),
+            },
+            #[cfg(unix)]
+            ServerInstanceMode::Standalone { .. } => {
+                return ResetTrafficResponse("traffic of standalone servers couldn't be reset".to_owned());
+            }
+        }
+
+        info!("reset traffic of server_port {}, user {:?}", req.server_port, req.name);
+
+        ResetTrafficResponse("ok".to_owned())
+    }
+
+    pub(super) async fn handle_list(&self) -> ListResponse {
         let instances = self.servers.lock().await;
 
         let mut servers = Vec::new();
@@ -474,7 +1100,22 @@ impl Manager {
                 no_delay: None,
                 plugin: None,
                 plugin_opts: None,
+                plugin_mode: None,
                 mode: None,
+                quota: svr_cfg.quota(),
+                #[cfg(feature = "aead-cipher-2022")]
+                users: svr_cfg.user_manager().map(|m| {
+                    m.users()
+                        .iter()
+                        .map(|u| protocol::ServerUserConfig {
+                            name: u.name().to_owned(),
+                            password: u.encoded_key(),
+                            quota: u.quota(),
+                        })
+                        .collect()
+                }),
+                #[cfg(not(feature = "aead-cipher-2022"))]
+                users: None,
             };
             servers.push(sc);
         }
@@ -482,7 +1123,7 @@ impl Manager {
         ListResponse { servers }
     }
 
-    async fn handle_ping(&self) -> PingResponse {
+    pub(super) async fn handle_ping(&self) -> PingResponse {
         let instances = self.servers.lock().await;
 
         let mut stat = HashMap::new();
@@ -493,12 +1134,209 @@ impl Manager {
         PingResponse { stat }
     }
 
+    #[cfg(feature = "manager-sip008")]
+    pub(super) async fn handle_sip008(&self, host: &str, user: Option<&str>) -> Sip008Document {
+        let instances = self.servers.lock().await;
+
+        let mut ports = instances.keys().copied().collect::<Vec<u16>>();
+        ports.sort_unstable();
+
+        let mut servers = Vec::new();
+        let mut bytes_used = 0;
+        // Known only if all servers have quotas
+        let mut bytes_remaining = Some(0);
+
+        for port in ports {
+            let server = &instances[&port];
+            let (password, used, quota) = match server.sip008_credential(user) {
+                Some(c) => c,
+                None => continue,
+            };
+
+            let svr_cfg = &server.svr_cfg;
+            servers.push(Sip008Server {
+                id: svr_cfg.id().map(ToOwned::to_owned),
+                remarks: svr_cfg.remarks().map(ToOwned::to_owned),
+                server: host.to_owned(),
+                server_port: port,
+                password,
+                method: svr_cfg.method().to_string(),
+                plugin: svr_cfg.plugin().map(|p| p.plugin.clone()),
+                plugin_opts: svr_cfg.plugin().and_then(|p| p.plugin_opts.clone()),
+            });
+
+            bytes_used += used;
+            bytes_remaining = match (bytes_remaining, quota) {
+                (Some(remaining), Some(quota)) => Some(remaining + quota.saturating_sub(used)),
+                _ => None,
+            };
+        }
+
+        Sip008Document {
+            version: 1,
+            servers,
+            bytes_used,
+            bytes_remaining,
+        }
+    }
+
+    #[cfg(feature = "manager-http-api")]
+    pub(super) async fn handle_metrics(&self) -> String {
+        let instances = self.servers.lock().await;
+
+        let mut ports = instances.keys().copied().collect::<Vec<u16>>();
+        ports.sort_unstable();
+
+        let mut up = Vec::new();
+        let mut bytes = Vec::new();
+        let mut connections = Vec::new();
+        #[cfg_attr(not(feature = "aead-cipher-2022"), allow(unused_mut))]
+        let mut user_bytes = Vec::new();
+
+        for port in ports {
+            let server = &instances[&port];
+            let port = port.to_string();
+
+            bytes.push((vec![("port", port.clone())], server.flow_stat()));
+
+            match server.mode {
+                ServerInstanceMode::Builtin {
+                    #[cfg(feature = "aead-cipher-2022")]
+                    ref user_flow_stat,
+                    ref concurrency_stat,
+                    ref running,
+                    ..
+                } => {
+                    up.push((vec![("port", port.clone())], running.load(Ordering::Relaxed) as u64));
+
+                    for (kind, name) in [
+                        (ConcurrencyKind::TcpTunnel, "tcp"),
+                        (ConcurrencyKind::UdpAssociation, "udp"),
+                    ] {
+                        let labels = vec![("port", port.clone()), ("kind", name.to_owned())];
+                        connections.push((labels, concurrency_stat.current(kind) as u64));
+                    }
+
+                    #[cfg(feature = "aead-cipher-2022")]
+                    for (name, flow_stat) in user_flow_stat.users() {
+                        let labels = vec![("port", port.clone()), ("user", name)];
+                        user_bytes.push((labels, flow_stat.tx() + flow_stat.rx()));
+                    }
+                }
+                // Standalone servers are known by their reports of `stat`
+                #[cfg(unix)]
+                ServerInstanceMode::Standalone { .. } => up.push((vec![("port", port)], 1)),
+            }
+        }
+
+        let mut encoder = MetricsEncoder::new();
+        encoder.family(
+            "shadowsocks_server_up",
+            "Whether the server is running",
+            MetricType::Gauge,
+            &up,
+        );
+        encoder.family(
+            "shadowsocks_server_bytes_total",
+            "Transmitted and received bytes of the server, since it was added or reset",
+            MetricType::Counter,
+            &bytes,
+        );
+        encoder.family(
+            "shadowsocks_server_connections",
+            "Active TCP connections and UDP associations of builtin servers",
+            MetricType::Gauge,
+            &connections,
+        );
+        encoder.family(
+            "shadowsocks_user_bytes_total",
+            "Transmitted and received bytes of users of multi-user servers",
+            MetricType::Counter,
+            &user_bytes,
+        );
+        encoder.finish()
+    }
+
+    #[cfg(feature = "manager-web-ui")]
+    pub(super) async fn handle_web_ui_status(&self) -> Vec<ServerStatus> {
+        let instances = self.servers.lock().await;
+
+        let mut ports = instances.keys().copied().collect::<Vec<u16>>();
+        ports.sort_unstable();
+
+        let mut servers = Vec::with_capacity(ports.len());
+        for port in ports {
+            let server = &instances[&port];
+            let svr_cfg = &server.svr_cfg;
+
+            let (running, connections) = match server.mode {
+                ServerInstanceMode::Builtin {
+                    ref concurrency_stat,
+                    ref running,
+                    ..
+                } => (
+                    running.load(Ordering::Relaxed),
+                    Some((
+                        concurrency_stat.current(ConcurrencyKind::TcpTunnel),
+                        concurrency_stat.current(ConcurrencyKind::UdpAssociation),
+                    )),
+                ),
+                // Connections of standalone servers are not reported
+                #[cfg(unix)]
+                ServerInstanceMode::Standalone { .. } => (true, None),
+            };
+
+            #[cfg(feature = "aead-cipher-2022")]
+            let users = svr_cfg.user_manager().map(|user_manager| {
+                user_manager
+                    .users()
+                    .iter()
+                    .map(|user| {
+                        let bytes = match server.mode {
+                            ServerInstanceMode::Builtin { ref user_flow_stat, .. } => user_flow_stat
+                                .get(user.name())
+                                .map(|flow_stat| flow_stat.tx() + flow_stat.rx())
+                                .unwrap_or(0),
+                            #[cfg(unix)]
+                            ServerInstanceMode::Standalone { .. } => 0,
+                        };
+                        UserStatus {
+                            name: user.name().to_owned(),
+                            password: user.encoded_key(),
+                            quota: user.quota(),
+                            bytes,
+                        }
+                    })
+                    .collect()
+            });
+            #[cfg(not(feature = "aead-cipher-2022"))]
+            let users: Option<Vec<UserStatus>> = None;
+
+            servers.push(ServerStatus {
+                port,
+                method: svr_cfg.method().to_string(),
+                password: svr_cfg.password().to_owned(),
+                mode: svr_cfg.mode().to_string(),
+                plugin: svr_cfg.plugin().map(|p| p.plugin.clone()),
+                plugin_opts: svr_cfg.plugin().and_then(|p| p.plugin_opts.clone()),
+                plugin_mode: svr_cfg.plugin().map(|p| p.plugin_mode.to_string()),
+                quota: svr_cfg.quota(),
+                bytes: server.flow_stat(),
+                running,
+                tcp: connections.map(|(tcp, _)| tcp),
+                udp: connections.map(|(_, udp)| udp),
+                users,
+            });
+        }
+
+        servers
+    }
+
     #[cfg(not(unix))]
-    async fn handle_stat(&self, _: &StatRequest) {}
+    pub(super) async fn handle_stat(&self, _: &StatRequest) {}
 
     #[cfg(unix)]
-    async fn handle_stat(&self, stat: &StatRequest) {
-        use log::warn;
+    pub(super) async fn handle_stat(&self, stat: &StatRequest) {
         use std::collections::hash_map::Entry;
 
         use crate::config::{Config, ConfigType};
@@ -565,6 +1403,7 @@ impl Manager {
                             vac.insert(ServerInstance {
                                 mode: ServerInstanceMode::Standalone { flow_stat: *flow },
                                 svr_cfg,
+                                persisted: false,
                             });
                         }
                     }
@@ -573,3 +1412,32 @@ impl Manager {
         }
     }
 }
+
+/// `add` request of `svr_cfg`, which starts the same server
+fn server_request(svr_cfg: &ServerConfig) -> AddRequest {
+    let plugin = svr_cfg.plugin();
+    AddRequest {
+        server_port: svr_cfg.addr().port(),
+        password: svr_cfg.password().to_owned(),
+        method: Some(svr_cfg.method().to_string()),
+        no_delay: None,
+        plugin: plugin.map(|p| p.plugin.clone()),
+        plugin_opts: plugin.and_then(|p| p.plugin_opts.clone()),
+        plugin_mode: plugin.map(|p| p.plugin_mode.to_string()),
+        mode: Some(svr_cfg.mode().to_string()),
+        quota: svr_cfg.quota(),
+        #[cfg(feature = "aead-cipher-2022")]
+        users: svr_cfg.user_manager().map(|m| {
+            m.users()
+                .iter()
+                .map(|u| protocol::ServerUserConfig {
+                    name: u.name().to_owned(),
+                    password: u.encoded_key(),
+                    quota: u.quota(),
+                })
+                .collect()
+        }),
+        #[cfg(not(feature = "aead-cipher-2022"))]
+        users: None,
+    }
+}
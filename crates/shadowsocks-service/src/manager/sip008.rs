@@ -0,0 +1,193 @@
+//! SIP008 online configuration server of manager
+//!
+//! Clients subscribe to `/sip008/TOKEN`, which lists the managed servers that could be used by the user of the
+//! subscription. [SIP008](https://shadowsocks.org/guide/sip008.html)
+
+use std::{convert::Infallible, io, net::SocketAddr, sync::Arc, time::Duration};
+
+use hyper::{header, server::conn::Http, service::service_fn, Body, Method, Request, Response, StatusCode};
+use log::{debug, error, info, trace};
+use serde::Serialize;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    time,
+};
+
+use crate::{
+    config::{ManagerServerHost, ManagerSip008Config, Sip008Subscription},
+    net::tls::create_tls_acceptor,
+};
+
+use super::{is_token_equal, server::Manager};
+
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// SIP008 document
+#[derive(Serialize, Debug)]
+pub struct Sip008Document {
+    pub version: u32,
+    pub servers: Vec<Sip008Server>,
+    /// Transmitted and received bytes of the listed servers
+    pub bytes_used: u64,
+    /// `None` if any of the listed servers doesn't have a quota
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bytes_remaining: Option<u64>,
+}
+
+/// Server of SIP008 documents
+#[derive(Serialize, Debug)]
+pub struct Sip008Server {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub remarks: Option<String>,
+    pub server: String,
+    pub server_port: u16,
+    pub password: String,
+    pub method: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub plugin: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub plugin_opts: Option<String>,
+}
+
+/// SIP008 online configuration server of `Manager`
+pub struct ManagerSip008Server {
+    manager: Arc<Manager>,
+    config: ManagerSip008Config,
+}
+
+impl ManagerSip008Server {
+    /// Create with the served manager
+    pub fn new(manager: Arc<Manager>, config: ManagerSip008Config) -> ManagerSip008Server {
+        ManagerSip008Server { manager, config }
+    }
+
+    /// Run server
+    pub async fn run(self) -> io::Result<()> {
+        let host = match self.config.server {
+            Some(ref host) => host.clone(),
+            None => match self.manager.config().server_host {
+                ManagerServerHost::Domain(ref dname) => dname.clone(),
+                ManagerServerHost::Ip(ip) if !ip.is_unspecified() => ip.to_string(),
+                ManagerServerHost::Ip(..) => {
+                    let err = io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "`server` of `manager_sip008` is required for servers listening on unspecified addresses",
+                    );
+                    return Err(err);
+                }
+            },
+        };
+
+        let acceptor = match self.config.tls {
+            Some(ref tls) => Some(create_tls_acceptor(tls, &[b"h2", b"http/1.1"])?),
+            None => None,
+        };
+
+        let listener = match self.manager.bind_tcp_listener(&self.config.addr).await {
+            Ok(l) => l,
+            Err(err) => {
+                error!("manager SIP008 server bind error: {}", err);
+                return Err(err);
+            }
+        };
+
+        info!(
+            "shadowsocks manager SIP008 server listening on {}://{}",
+            if acceptor.is_some() { "https" } else { "http" },
+            listener.local_addr()?
+        );
+
+        let host: Arc<str> = Arc::from(host);
+        let subscriptions: Arc<[Sip008Subscription]> = Arc::from(self.config.subscriptions);
+
+        loop {
+            let (stream, peer_addr) = match listener.accept().await {
+                Ok(s) => s,
+                Err(err) => {
+                    error!("manager SIP008 server accept failed with error: {}", err);
+                    time::sleep(Duration::from_secs(1)).await;
+                    continue;
+                }
+            };
+
+            let manager = self.manager.clone();
+            let host = host.clone();
+            let subscriptions = subscriptions.clone();
+            let acceptor = acceptor.clone();
+            tokio::spawn(async move {
+                let acceptor = match acceptor {
+                    Some(acceptor) => acceptor,
+                    None => return serve_connection(stream, peer_addr, manager, host, subscriptions).await,
+                };
+
+                match time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                    Ok(Ok(stream)) => serve_connection(stream, peer_addr, manager, host, subscriptions).await,
+                    Ok(Err(err)) => debug!("manager SIP008 client {} handshake failed, error: {}", peer_addr, err),
+                    Err(..) => debug!("manager SIP008 client {} handshake timed out", peer_addr),
+                }
+            });
+        }
+    }
+}
+
+async fn serve_connection<S>(
+    stream: S,
+    peer_addr: SocketAddr,
+    manager: Arc<Manager>,
+    host: Arc<str>,
+    subscriptions: Arc<[Sip008Subscription]>,
+) where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let service = service_fn(move |req: Request<Body>| {
+        trace!("manager SIP008 request {} {} {}", peer_addr, req.method(), req.uri());
+
+        let manager = manager.clone();
+        let host = host.clone();
+        let subscriptions = subscriptions.clone();
+        async move { Ok::<_, Infallible>(handle_request(&manager, &host, &subscriptions, req).await) }
+    });
+
+    if let Err(err) = Http::new().serve_connection(stream, service).await {
+        debug!("manager SIP008 client {} connection error: {}", peer_addr, err);
+    }
+}
+
+async fn handle_request(
+    manager: &Manager,
+    host: &str,
+    subscriptions: &[Sip008Subscription],
+    req: Request<Body>,
+) -> Response<Body> {
+    let token = match req.uri().path().strip_prefix("/sip008/") {
+        Some(token) => token,
+        None => return error_response(StatusCode::NOT_FOUND),
+    };
+
+    // Unknown tokens are not distinguished from unknown paths
+    let subscription = match subscriptions
+        .iter()
+        .find(|s| is_token_equal(token.as_bytes(), s.token.as_bytes()))
+    {
+        Some(s) => s,
+        None => return error_response(StatusCode::NOT_FOUND),
+    };
+
+    if req.method() != Method::GET && req.method() != Method::HEAD {
+        return error_response(StatusCode::METHOD_NOT_ALLOWED);
+    }
+
+    let document = manager.handle_sip008(host, subscription.user.as_deref()).await;
+    let body = serde_json::to_vec(&document).expect("serialize SIP008 document");
+    Response::builder()
+        .header(header::CONTENT_TYPE, "application/json")
+        .header(header::CACHE_CONTROL, "no-store")
+        .body(Body::from(body))
+        .unwrap()
+}
+
+fn error_response(status: StatusCode) -> Response<Body> {
+    Response::builder().status(status).body(Body::empty()).unwrap()
+}
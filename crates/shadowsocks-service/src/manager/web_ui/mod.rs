@@ -0,0 +1,13 @@
+//! Web dashboard of manager server
+//!
+//! Serves a single page showing ports, traffic and active connections of managed servers, which could also add, edit
+//! and remove servers. Requests are authenticated by HTTP Basic authentication with the configured password.
+//!
+//! - `GET /`, the page
+//! - `GET /api/status`, status of servers in JSON
+//! - `POST /api/servers`, add a server, or replace the server on the same port, same as `add`
+//! - `DELETE /api/servers/PORT`, remove the server listening on `PORT`, same as `remove`
+
+pub use self::server::{ManagerWebUi, ServerStatus, UserStatus};
+
+mod server;
@@ -0,0 +1,272 @@
+//! Web dashboard HTTP server
+
+use std::{
+    convert::Infallible,
+    io::{self, ErrorKind},
+    sync::Arc,
+};
+
+use hyper::{
+    body,
+    header,
+    server::conn::AddrStream,
+    service::{make_service_fn, service_fn},
+    Body,
+    Method,
+    Request,
+    Response,
+    Server,
+    StatusCode,
+};
+use log::{error, info, trace};
+use serde::{de::DeserializeOwned, Serialize};
+use shadowsocks::manager::protocol::{AddRequest, RemoveRequest};
+
+use crate::{
+    config::ManagerWebUiConfig,
+    manager::{is_token_equal, server::Manager},
+};
+
+static INDEX_HTML: &str = include_str!("index.html");
+
+/// Maximum length of request bodies, which are configurations of servers
+const MAX_BODY_SIZE: u64 = 64 * 1024;
+
+#[derive(Serialize)]
+struct StatusResponse {
+    servers: Vec<ServerStatus>,
+}
+
+/// Status of a managed server
+#[derive(Serialize, Debug)]
+pub struct ServerStatus {
+    pub port: u16,
+    pub method: String,
+    pub password: String,
+    pub mode: String,
+    pub plugin: Option<String>,
+    pub plugin_opts: Option<String>,
+    pub plugin_mode: Option<String>,
+    pub quota: Option<u64>,
+    /// Transmitted and received bytes, since the server was added or reset
+    pub bytes: u64,
+    /// `false` if the builtin server exited with error
+    pub running: bool,
+    /// Active TCP connections, `None` for standalone servers
+    pub tcp: Option<usize>,
+    /// Active UDP associations, `None` for standalone servers
+    pub udp: Option<usize>,
+    /// `None` for single-user servers
+    pub users: Option<Vec<UserStatus>>,
+}
+
+/// Status of a user of multi-user servers
+#[derive(Serialize, Debug)]
+pub struct UserStatus {
+    pub name: String,
+    pub password: String,
+    pub quota: Option<u64>,
+    /// Transmitted and received bytes of the user
+    pub bytes: u64,
+}
+
+/// Web dashboard of `Manager`
+pub struct ManagerWebUi {
+    manager: Arc<Manager>,
+    config: ManagerWebUiConfig,
+}
+
+impl ManagerWebUi {
+    /// Create with the served manager
+    pub fn new(manager: Arc<Manager>, config: ManagerWebUiConfig) -> ManagerWebUi {
+        ManagerWebUi { manager, config }
+    }
+
+    /// Run server
+    pub async fn run(self) -> io::Result<()> {
+        let listener = match self.manager.bind_tcp_listener(&self.config.addr).await {
+            Ok(l) => l.into_inner().into_std()?,
+            Err(err) => {
+                error!("manager web UI bind error: {}", err);
+                return Err(err);
+            }
+        };
+
+        info!(
+            "shadowsocks manager web UI listening on http://{}",
+            listener.local_addr()?
+        );
+
+        let builder = match Server::from_tcp(listener) {
+            Ok(builder) => builder,
+            Err(err) => {
+                error!("hyper server from std::net::TcpListener error: {}", err);
+                let err = io::Error::new(ErrorKind::InvalidInput, err);
+                return Err(err);
+            }
+        };
+
+        let manager = self.manager;
+        let password: Arc<str> = Arc::from(self.config.password);
+        let make_service = make_service_fn(|socket: &AddrStream| {
+            let peer_addr = socket.remote_addr();
+            let manager = manager.clone();
+            let password = password.clone();
+
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                    trace!("manager web UI request {} {} {}", peer_addr, req.method(), req.uri());
+
+                    let manager = manager.clone();
+                    let password = password.clone();
+                    async move { Ok::<_, Infallible>(handle_request(&manager, &password, req).await) }
+                }))
+            }
+        });
+
+        if let Err(err) = builder.serve(make_service).await {
+            error!("hyper server exited with error: {}", err);
+            return Err(io::Error::new(ErrorKind::Other, err));
+        }
+
+        Ok(())
+    }
+}
+
+async fn handle_request(manager: &Manager, password: &str, req: Request<Body>) -> Response<Body> {
+    if !is_authorized(&req, password) {
+        return Response::builder()
+            .status(StatusCode::UNAUTHORIZED)
+            .header(
+                header::WWW_AUTHENTICATE,
+                "Basic realm=\"shadowsocks manager\", charset=\"UTF-8\"",
+            )
+            .body(Body::empty())
+            .unwrap();
+    }
+
+    // Pages of the other sites are not allowed to control
+    if (req.method() == Method::POST || req.method() == Method::DELETE) && !is_same_origin(&req) {
+        return error_response(StatusCode::FORBIDDEN, "cross-origin request");
+    }
+
+    let path = req.uri().path().to_owned();
+    match (req.method(), path.as_str()) {
+        (&Method::GET, "/") | (&Method::HEAD, "/") => Response::builder()
+            .header(header::CONTENT_TYPE, "text/html; charset=utf-8")
+            .body(Body::from(INDEX_HTML))
+            .unwrap(),
+        (&Method::GET, "/api/status") => {
+            let status = StatusResponse {
+                servers: manager.handle_web_ui_status().await,
+            };
+            let body = serde_json::to_vec(&status).expect("serialize status");
+            Response::builder()
+                .header(header::CONTENT_TYPE, "application/json")
+                .header(header::CACHE_CONTROL, "no-store")
+                .body(Body::from(body))
+                .unwrap()
+        }
+        (&Method::POST, "/api/servers") => {
+            let add_req = match read_json::<AddRequest>(req).await {
+                Ok(r) => r,
+                Err(resp) => return resp,
+            };
+            match manager.handle_add(&add_req).await {
+                Ok(rsp) if rsp.0 == "ok" => Response::builder()
+                    .status(StatusCode::NO_CONTENT)
+                    .body(Body::empty())
+                    .unwrap(),
+                Ok(rsp) => error_response(StatusCode::BAD_REQUEST, &rsp.0),
+                Err(err) => {
+                    error!("add server_port: {} failed, error: {}", add_req.server_port, err);
+                    error_response(StatusCode::INTERNAL_SERVER_ERROR, &err.to_string())
+                }
+            }
+        }
+        (method, path) => match path.strip_prefix("/api/servers/") {
+            Some(port) => {
+                if method != Method::DELETE {
+                    return error_response(StatusCode::METHOD_NOT_ALLOWED, "");
+                }
+                let server_port = match port.parse::<u16>() {
+                    Ok(p) => p,
+                    Err(..) => return error_response(StatusCode::NOT_FOUND, ""),
+                };
+                manager.handle_remove(&RemoveRequest { server_port }).await;
+                Response::builder()
+                    .status(StatusCode::NO_CONTENT)
+                    .body(Body::empty())
+                    .unwrap()
+            }
+            None => match path {
+                "/" | "/api/status" | "/api/servers" => error_response(StatusCode::METHOD_NOT_ALLOWED, ""),
+                _ => error_response(StatusCode::NOT_FOUND, ""),
+            },
+        },
+    }
+}
+
+/// Check `Authorization: Basic BASE64(USER:PASSWORD)` of the request, with any user name
+fn is_authorized(req: &Request<Body>, password: &str) -> bool {
+    let value = match req.headers().get(header::AUTHORIZATION) {
+        Some(v) => v.as_bytes(),
+        None => return false,
+    };
+    let credential = match value.strip_prefix(b"Basic ").map(base64::decode) {
+        Some(Ok(c)) => c,
+        _ => return false,
+    };
+    match credential.iter().position(|b| *b == b':') {
+        Some(pos) => is_token_equal(&credential[pos + 1..], password.as_bytes()),
+        None => false,
+    }
+}
+
+/// Check if `Origin` of the request, which is sent by browsers for `POST` and `DELETE`, is this server
+fn is_same_origin(req: &Request<Body>) -> bool {
+    let origin = match req.headers().get(header::ORIGIN) {
+        Some(origin) => origin.as_bytes(),
+        None => return true,
+    };
+    let host = match req.headers().get(header::HOST) {
+        Some(host) => host.as_bytes(),
+        None => return false,
+    };
+
+    match origin.iter().position(|b| *b == b'/') {
+        Some(pos) => origin[pos..].strip_prefix(b"//") == Some(host),
+        None => false,
+    }
+}
+
+async fn read_json<T: DeserializeOwned>(req: Request<Body>) -> Result<T, Response<Body>> {
+    let content_length = req
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok());
+    match content_length {
+        Some(n) if n <= MAX_BODY_SIZE => {}
+        Some(..) => return Err(error_response(StatusCode::PAYLOAD_TOO_LARGE, "")),
+        None => return Err(error_response(StatusCode::LENGTH_REQUIRED, "")),
+    }
+
+    let body = match body::to_bytes(req.into_body()).await {
+        Ok(b) => b,
+        Err(err) => return Err(error_response(StatusCode::BAD_REQUEST, &err.to_string())),
+    };
+
+    match serde_json::from_slice(&body) {
+        Ok(v) => Ok(v),
+        Err(err) => Err(error_response(StatusCode::BAD_REQUEST, &err.to_string())),
+    }
+}
+
+fn error_response(status: StatusCode, message: &str) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "text/plain; charset=utf-8")
+        .body(Body::from(message.to_owned()))
+        .unwrap()
+}
@@ -0,0 +1,249 @@
+//! Prometheus metrics, in the text exposition format
+//!
+//! Served by manager's HTTP API, or `MetricsServer` of local and remote servers
+
+use std::fmt::Write;
+#[cfg(feature = "metrics")]
+use std::{
+    convert::Infallible,
+    io::{self, ErrorKind},
+    sync::Arc,
+};
+
+#[cfg(feature = "metrics")]
+use hyper::{
+    header,
+    server::conn::AddrStream,
+    service::{make_service_fn, service_fn},
+    Body,
+    Method,
+    Request,
+    Response,
+    Server,
+    StatusCode,
+};
+#[cfg(feature = "metrics")]
+use log::{error, info, trace};
+#[cfg(feature = "metrics")]
+use shadowsocks::{
+    config::ServerAddr,
+    context::SharedContext,
+    lookup_then,
+    net::{AcceptOpts, TcpListener},
+};
+
+/// Type of metric families
+#[derive(Debug, Clone, Copy)]
+pub enum MetricType {
+    Counter,
+    Gauge,
+    Histogram,
+}
+
+impl MetricType {
+    fn as_str(&self) -> &'static str {
+        match *self {
+            MetricType::Counter => "counter",
+            MetricType::Gauge => "gauge",
+            MetricType::Histogram => "histogram",
+        }
+    }
+}
+
+/// A sample of metric families, with its labels
+pub type MetricSample = (Vec<(&'static str, String)>, u64);
+
+/// A sample of histograms, with its labels
+#[derive(Debug, Clone)]
+pub struct HistogramSample {
+    pub labels: Vec<(&'static str, String)>,
+    /// Cumulative counts of observations, by their upper bounds
+    pub buckets: Vec<(u64, u64)>,
+    /// Sum of all observations
+    pub sum: u64,
+    /// Count of all observations, including the ones above the last bound
+    pub count: u64,
+}
+
+/// Encoder of metric families
+#[derive(Debug, Default)]
+pub struct MetricsEncoder {
+    output: String,
+}
+
+impl MetricsEncoder {
+    /// Create an empty encoder
+    pub fn new() -> MetricsEncoder {
+        MetricsEncoder::default()
+    }
+
+    /// Write a metric family with its samples
+    pub fn family(&mut self, name: &str, help: &str, metric_type: MetricType, samples: &[MetricSample]) {
+        self.header(name, help, metric_type);
+
+        for (labels, value) in samples {
+            self.sample(name, labels, None, *value);
+        }
+    }
+
+    /// Write a histogram family with its samples, in series of `_bucket`, `_sum` and `_count`
+    pub fn histogram(&mut self, name: &str, help: &str, samples: &[HistogramSample]) {
+        self.header(name, help, MetricType::Histogram);
+
+        let bucket_name = format!("{}_bucket", name);
+        let sum_name = format!("{}_sum", name);
+        let count_name = format!("{}_count", name);
+        for sample in samples {
+            for (le, count) in &sample.buckets {
+                self.sample(&bucket_name, &sample.labels, Some(&le.to_string()), *count);
+            }
+            self.sample(&bucket_name, &sample.labels, Some("+Inf"), sample.count);
+            self.sample(&sum_name, &sample.labels, None, sample.sum);
+            self.sample(&count_name, &sample.labels, None, sample.count);
+        }
+    }
+
+    fn header(&mut self, name: &str, help: &str, metric_type: MetricType) {
+        let _ = writeln!(self.output, "# HELP {} {}", name, help);
+        let _ = writeln!(self.output, "# TYPE {} {}", name, metric_type.as_str());
+    }
+
+    fn sample(&mut self, name: &str, labels: &[(&'static str, String)], le: Option<&str>, value: u64) {
+        self.output.push_str(name);
+        let le = le.map(|le| ("le", le));
+        let mut labels = labels.iter().map(|(l, v)| (*l, v.as_str())).chain(le).peekable();
+        if labels.peek().is_some() {
+            self.output.push('{');
+            for (i, (label, label_value)) in labels.enumerate() {
+                if i > 0 {
+                    self.output.push(',');
+                }
+                let _ = write!(self.output, "{}=\"", label);
+                escape_label_value(&mut self.output, label_value);
+                self.output.push('"');
+            }
+            self.output.push('}');
+        }
+        let _ = writeln!(self.output, " {}", value);
+    }
+
+    /// Get the encoded metrics
+    pub fn finish(self) -> String {
+        self.output
+    }
+}
+
+fn escape_label_value(output: &mut String, value: &str) {
+    for c in value.chars() {
+        match c {
+            '\\' => output.push_str("\\\\"),
+            '"' => output.push_str("\\\""),
+            '\n' => output.push_str("\\n"),
+            c => output.push(c),
+        }
+    }
+}
+
+/// Source of metrics served by `MetricsServer`
+#[cfg(feature = "metrics")]
+pub trait MetricsCollector: Send + Sync {
+    /// Write metric families into `encoder`
+    fn collect(&self, encoder: &mut MetricsEncoder);
+}
+
+/// HTTP server of `GET /metrics`, for Prometheus
+#[cfg(feature = "metrics")]
+pub struct MetricsServer {
+    context: SharedContext,
+    accept_opts: AcceptOpts,
+    collector: Arc<dyn MetricsCollector>,
+}
+
+#[cfg(feature = "metrics")]
+impl MetricsServer {
+    /// Create with the collector of metrics
+    pub fn new(context: SharedContext, accept_opts: AcceptOpts, collector: Arc<dyn MetricsCollector>) -> MetricsServer {
+        MetricsServer {
+            context,
+            accept_opts,
+            collector,
+        }
+    }
+
+    /// Run server
+    pub async fn run(self, bind_addr: &ServerAddr) -> io::Result<()> {
+        let bind_result = match *bind_addr {
+            ServerAddr::SocketAddr(sa) => TcpListener::bind_with_opts(&sa, self.accept_opts.clone()).await,
+            ServerAddr::DomainName(ref dname, port) => lookup_then!(&self.context, dname, port, |addr| {
+                TcpListener::bind_with_opts(&addr, self.accept_opts.clone()).await
+            })
+            .map(|(_, b)| b),
+        };
+
+        let listener = match bind_result {
+            Ok(l) => l.into_inner().into_std()?,
+            Err(err) => {
+                error!("metrics server bind error: {}", err);
+                return Err(err);
+            }
+        };
+
+        info!(
+            "shadowsocks metrics listening on http://{}/metrics",
+            listener.local_addr()?
+        );
+
+        let builder = match Server::from_tcp(listener) {
+            Ok(builder) => builder,
+            Err(err) => {
+                error!("hyper server from std::net::TcpListener error: {}", err);
+                let err = io::Error::new(ErrorKind::InvalidInput, err);
+                return Err(err);
+            }
+        };
+
+        let collector = self.collector;
+        let make_service = make_service_fn(|socket: &AddrStream| {
+            let peer_addr = socket.remote_addr();
+            let collector = collector.clone();
+
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                    trace!("metrics request {} {} {}", peer_addr, req.method(), req.uri());
+
+                    let response = handle_request(collector.as_ref(), &req);
+                    async move { Ok::<_, Infallible>(response) }
+                }))
+            }
+        });
+
+        if let Err(err) = builder.serve(make_service).await {
+            error!("hyper server exited with error: {}", err);
+            return Err(io::Error::new(ErrorKind::Other, err));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(feature = "metrics")]
+fn handle_request(collector: &dyn MetricsCollector, req: &Request<Body>) -> Response<Body> {
+    match (req.method(), req.uri().path()) {
+        (&Method::GET, "/metrics") => {
+            let mut encoder = MetricsEncoder::new();
+            collector.collect(&mut encoder);
+            Response::builder()
+                .header(header::CONTENT_TYPE, "text/plain; version=0.0.4")
+                .body(Body::from(encoder.finish()))
+                .unwrap()
+        }
+        (_, "/metrics") => Response::builder()
+            .status(StatusCode::METHOD_NOT_ALLOWED)
+            .body(Body::empty())
+            .unwrap(),
+        _ => Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::empty())
+            .unwrap(),
+    }
+}
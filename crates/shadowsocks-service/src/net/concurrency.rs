@@ -0,0 +1,123 @@
+//! Concurrency statistic
+//!
+//! Number of objects that are currently alive, and their high-water marks and times acquired since the statistic
+//! was created
+
+use std::{
+    fmt::{self, Debug},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+/// Kind of objects counted by `ConcurrencyStat`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ConcurrencyKind {
+    /// Relaying TCP connections
+    TcpTunnel,
+    /// UDP associations
+    UdpAssociation,
+    /// TCP sockets created in TUN's network stack
+    TunSocket,
+    /// Bytes of buffers allocated for TUN's TCP sockets
+    TunBuffer,
+}
+
+impl ConcurrencyKind {
+    /// All kinds
+    pub const ALL: [ConcurrencyKind; 4] = [
+        ConcurrencyKind::TcpTunnel,
+        ConcurrencyKind::UdpAssociation,
+        ConcurrencyKind::TunSocket,
+        ConcurrencyKind::TunBuffer,
+    ];
+
+    /// Name of this kind
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            ConcurrencyKind::TcpTunnel => "tcp_tunnels",
+            ConcurrencyKind::UdpAssociation => "udp_associations",
+            ConcurrencyKind::TunSocket => "tun_sockets",
+            ConcurrencyKind::TunBuffer => "tun_buffer_bytes",
+        }
+    }
+
+    fn index(&self) -> usize {
+        *self as usize
+    }
+}
+
+#[derive(Default)]
+struct Gauge {
+    current: AtomicUsize,
+    peak: AtomicUsize,
+    acquired: AtomicUsize,
+}
+
+/// Current values and high-water marks of concurrently alive objects
+#[derive(Default)]
+pub struct ConcurrencyStat {
+    gauges: [Gauge; 4],
+}
+
+impl Debug for ConcurrencyStat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut m = f.debug_map();
+        for kind in ConcurrencyKind::ALL {
+            m.entry(&kind.as_str(), &(self.current(kind), self.peak(kind)));
+        }
+        m.finish()
+    }
+}
+
+impl ConcurrencyStat {
+    /// Create an empty concurrency statistic
+    pub fn new() -> ConcurrencyStat {
+        ConcurrencyStat::default()
+    }
+
+    /// Current value of `kind`
+    pub fn current(&self, kind: ConcurrencyKind) -> usize {
+        self.gauges[kind.index()].current.load(Ordering::Relaxed)
+    }
+
+    /// Highest value of `kind` that has ever been reached
+    pub fn peak(&self, kind: ConcurrencyKind) -> usize {
+        self.gauges[kind.index()].peak.load(Ordering::Relaxed)
+    }
+
+    /// Times `kind` has been acquired, like accepted TCP connections
+    pub fn acquired(&self, kind: ConcurrencyKind) -> usize {
+        self.gauges[kind.index()].acquired.load(Ordering::Relaxed)
+    }
+
+    /// Increase `kind` by `n`, it will be decreased when the returned guard is dropped
+    pub fn acquire(self: &Arc<Self>, kind: ConcurrencyKind, n: usize) -> ConcurrencyGuard {
+        let gauge = &self.gauges[kind.index()];
+        let current = gauge.current.fetch_add(n, Ordering::AcqRel) + n;
+        gauge.peak.fetch_max(current, Ordering::AcqRel);
+        gauge.acquired.fetch_add(1, Ordering::Relaxed);
+
+        ConcurrencyGuard {
+            stat: self.clone(),
+            kind,
+            n,
+        }
+    }
+}
+
+/// Guard of an alive object counted in `ConcurrencyStat`
+pub struct ConcurrencyGuard {
+    stat: Arc<ConcurrencyStat>,
+    kind: ConcurrencyKind,
+    n: usize,
+}
+
+impl Drop for ConcurrencyGuard {
+    fn drop(&mut self) {
+        self.stat.gauges[self.kind.index()]
+            .current
+            .fetch_sub(self.n, Ordering::AcqRel);
+    }
+}
@@ -1,6 +1,11 @@
 //! Server flow statistic
 
-use std::sync::atomic::Ordering;
+use std::{
+    collections::HashMap,
+    sync::{atomic::Ordering, Arc},
+};
+
+use spin::Mutex as SpinMutex;
 
 #[cfg(not(any(target_arch = "mips", target_arch = "powerpc")))]
 type FlowCounter = std::sync::atomic::AtomicU64;
@@ -11,6 +16,10 @@ type FlowCounter = std::sync::atomic::AtomicU32;
 pub struct FlowStat {
     tx: FlowCounter,
     rx: FlowCounter,
+    // 0 for unlimited
+    quota: FlowCounter,
+    // Bytes used by the other servers of a replay cluster
+    peer_used: FlowCounter,
 }
 
 impl Default for FlowStat {
@@ -18,6 +27,8 @@ impl Default for FlowStat {
         FlowStat {
             tx: FlowCounter::new(0),
             rx: FlowCounter::new(0),
+            quota: FlowCounter::new(0),
+            peer_used: FlowCounter::new(0),
         }
     }
 }
@@ -47,4 +58,98 @@ impl FlowStat {
     pub fn incr_rx(&self, n: u64) {
         self.rx.fetch_add(n as _, Ordering::AcqRel);
     }
+
+    /// Reset transmitted and received bytes to 0
+    pub fn reset(&self) {
+        self.tx.store(0, Ordering::Release);
+        self.rx.store(0, Ordering::Release);
+    }
+
+    /// Quota of transmitted and received bytes, `None` for unlimited
+    pub fn quota(&self) -> Option<u64> {
+        let quota: u64 = self.quota.load(Ordering::Relaxed) as _;
+        match quota {
+            0 => None,
+            q => Some(q),
+        }
+    }
+
+    /// Set quota of transmitted and received bytes, `None` for unlimited
+    pub fn set_quota(&self, quota: Option<u64>) {
+        self.quota.store(quota.unwrap_or(0) as _, Ordering::Release);
+    }
+
+    /// Transmitted and received bytes of the other servers of a replay cluster
+    pub fn peer_used(&self) -> u64 {
+        self.peer_used.load(Ordering::Relaxed) as _
+    }
+
+    /// Set transmitted and received bytes of the other servers of a replay cluster, which are counted in the quota
+    pub fn set_peer_used(&self, n: u64) {
+        self.peer_used.store(n as _, Ordering::Release);
+    }
+
+    /// Check if transmitted and received bytes, including the ones of peers, have reached the quota
+    pub fn is_quota_exceeded(&self) -> bool {
+        match self.quota() {
+            Some(quota) => self.tx() + self.rx() + self.peer_used() >= quota,
+            None => false,
+        }
+    }
+}
+
+/// Flow statistic of each user in multi-user servers
+#[derive(Default)]
+pub struct UserFlowStat {
+    users: SpinMutex<HashMap<String, Arc<FlowStat>>>,
+}
+
+impl UserFlowStat {
+    /// Create an empty user flow statistic
+    pub fn new() -> UserFlowStat {
+        UserFlowStat::default()
+    }
+
+    /// Get flow statistic of user `name`, created if not exists
+    pub fn user(&self, name: &str) -> Arc<FlowStat> {
+        let mut users = self.users.lock();
+        match users.get(name) {
+            Some(flow_stat) => flow_stat.clone(),
+            None => {
+                let flow_stat = Arc::new(FlowStat::new());
+                users.insert(name.to_owned(), flow_stat.clone());
+                flow_stat
+            }
+        }
+    }
+
+    /// Get flow statistic of user `name`, `None` if it doesn't exist
+    pub fn get(&self, name: &str) -> Option<Arc<FlowStat>> {
+        self.users.lock().get(name).cloned()
+    }
+
+    /// Check if user `name` has reached its quota
+    pub fn is_quota_exceeded(&self, name: &str) -> bool {
+        match self.users.lock().get(name) {
+            Some(flow_stat) => flow_stat.is_quota_exceeded(),
+            None => false,
+        }
+    }
+
+    /// Flow statistic of all users, sorted by name
+    pub fn users(&self) -> Vec<(String, Arc<FlowStat>)> {
+        let mut users = self
+            .users
+            .lock()
+            .iter()
+            .map(|(name, flow_stat)| (name.clone(), flow_stat.clone()))
+            .collect::<Vec<_>>();
+        users.sort_by(|a, b| a.0.cmp(&b.0));
+        users
+    }
+
+    /// Remove flow statistic of user `name`
+    pub fn remove(&self, name: &str) -> Option<Arc<FlowStat>> {
+        self.users.lock().remove(name)
+    }
 }
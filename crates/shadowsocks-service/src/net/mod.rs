@@ -1,10 +1,33 @@
 //! Shadowsocks Service Network Utilities
 
-pub use self::{flow::FlowStat, mon_socket::MonProxySocket, mon_stream::MonProxyStream};
+pub use self::{
+    concurrency::{ConcurrencyGuard, ConcurrencyKind, ConcurrencyStat},
+    flow::{FlowStat, UserFlowStat},
+    mon_socket::MonProxySocket,
+    mon_stream::MonProxyStream,
+    p2p::P2pPolicy,
+    port_range::PortRange,
+    rate_limit::{RateLimiter, RateLimiters, ServerRateLimiter},
+};
 
+pub mod concurrency;
 pub mod flow;
 pub mod mon_socket;
 pub mod mon_stream;
+#[cfg(feature = "aead-cipher-2022")]
+pub mod ntp;
+pub mod p2p;
+pub mod port_range;
+pub mod rate_limit;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub mod splice;
+#[cfg(any(
+    feature = "local-socks-tls",
+    feature = "local-http-tls",
+    feature = "local-dns-tls",
+    feature = "manager-sip008"
+))]
+pub mod tls;
 pub mod utils;
 
 /// Packet size for all UDP associations' send queue
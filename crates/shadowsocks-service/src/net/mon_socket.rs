@@ -2,21 +2,60 @@
 
 use std::{io, net::SocketAddr, sync::Arc};
 
-use shadowsocks::{relay::socks5::Address, ProxySocket};
+use shadowsocks::{
+    net::udp::{RecvBatch, SendBatch},
+    relay::{socks5::Address, udprelay::UdpSocketControlData},
+    ProxySocket,
+};
 use tokio::net::ToSocketAddrs;
 
 use super::flow::FlowStat;
+#[cfg(feature = "aead-cipher-2022")]
+use super::flow::UserFlowStat;
 
 /// Monitored `ProxySocket`
 pub struct MonProxySocket {
     socket: ProxySocket,
     flow_stat: Arc<FlowStat>,
+    #[cfg(feature = "aead-cipher-2022")]
+    user_flow_stat: Option<Arc<UserFlowStat>>,
 }
 
 impl MonProxySocket {
     /// Create a new socket with flow monitor
     pub fn from_socket(socket: ProxySocket, flow_stat: Arc<FlowStat>) -> MonProxySocket {
-        MonProxySocket { socket, flow_stat }
+        MonProxySocket {
+            socket,
+            flow_stat,
+            #[cfg(feature = "aead-cipher-2022")]
+            user_flow_stat: None,
+        }
+    }
+
+    /// Also monitor packets of users in multi-user servers, identified by control data of packets
+    #[cfg(feature = "aead-cipher-2022")]
+    pub fn set_user_flow_stat(&mut self, user_flow_stat: Arc<UserFlowStat>) {
+        self.user_flow_stat = Some(user_flow_stat);
+    }
+
+    #[inline]
+    fn incr_user_tx(&self, control: &UdpSocketControlData, n: usize) {
+        #[cfg(feature = "aead-cipher-2022")]
+        if let (Some(ref user_flow_stat), Some(ref user)) = (&self.user_flow_stat, &control.user) {
+            user_flow_stat.user(user.name()).incr_tx(n as u64);
+        }
+        #[cfg(not(feature = "aead-cipher-2022"))]
+        let _ = (control, n);
+    }
+
+    #[inline]
+    fn incr_user_rx(&self, control: &UdpSocketControlData, n: usize) {
+        #[cfg(feature = "aead-cipher-2022")]
+        if let (Some(ref user_flow_stat), Some(ref user)) = (&self.user_flow_stat, &control.user) {
+            user_flow_stat.user(user.name()).incr_rx(n as u64);
+        }
+        #[cfg(not(feature = "aead-cipher-2022"))]
+        let _ = (control, n);
     }
 
     /// Send a UDP packet to addr through proxy
@@ -37,6 +76,22 @@ impl MonProxySocket {
         Ok(())
     }
 
+    /// Send a UDP packet to target from proxy, with control data of the packet
+    #[inline]
+    pub async fn send_to_with_ctrl<A: ToSocketAddrs>(
+        &self,
+        target: A,
+        addr: &Address,
+        control: &UdpSocketControlData,
+        payload: &[u8],
+    ) -> io::Result<()> {
+        let n = self.socket.send_to_with_ctrl(target, addr, control, payload).await?;
+        self.flow_stat.incr_tx(n as u64);
+        self.incr_user_tx(control, n);
+
+        Ok(())
+    }
+
     /// Receive packet from Shadowsocks' UDP server
     ///
     /// This function will use `recv_buf` to store intermediate data, so it has to be big enough to store the whole shadowsocks' packet
@@ -63,6 +118,78 @@ impl MonProxySocket {
         Ok((n, peer_addr, addr))
     }
 
+    /// Receive packet from Shadowsocks' UDP server, with control data of the packet
+    ///
+    /// This function will use `recv_buf` to store intermediate data, so it has to be big enough to store the whole shadowsocks' packet
+    ///
+    /// It is recommended to allocate a buffer to have at least 65536 bytes.
+    #[inline]
+    pub async fn recv_from_with_ctrl(
+        &self,
+        recv_buf: &mut [u8],
+    ) -> io::Result<(usize, SocketAddr, Address, Option<UdpSocketControlData>)> {
+        let (n, peer_addr, addr, recv_n, control) = self.socket.recv_from_with_ctrl(recv_buf).await?;
+        self.flow_stat.incr_rx(recv_n as u64);
+        if let Some(ref control) = control {
+            self.incr_user_rx(control, recv_n);
+        }
+
+        Ok((n, peer_addr, addr, control))
+    }
+
+    /// Receive a batch of packets, decrypted by `decrypt_packet`
+    #[inline]
+    pub async fn recv_batch(&self, batch: &mut RecvBatch) -> io::Result<usize> {
+        let n = self.socket.recv_batch(batch).await?;
+        self.flow_stat.incr_rx(batch.total_len() as u64);
+
+        Ok(n)
+    }
+
+    /// Decrypt a packet received by `recv_batch` in place, with control data of the packet
+    #[inline]
+    pub async fn decrypt_packet(
+        &self,
+        packet: &mut [u8],
+    ) -> io::Result<(usize, Address, Option<UdpSocketControlData>)> {
+        let recv_n = packet.len();
+        let (n, addr, control) = self.socket.decrypt_packet(packet).await?;
+        if let Some(ref control) = control {
+            self.incr_user_rx(control, recv_n);
+        }
+
+        Ok((n, addr, control))
+    }
+
+    /// Encrypt a UDP packet into `batch`, sent to `target` by `send_batch`
+    #[inline]
+    pub fn push_batch(&self, batch: &mut SendBatch, target: Option<SocketAddr>, addr: &Address, payload: &[u8]) {
+        self.socket.push_batch(batch, target, addr, payload);
+    }
+
+    /// Encrypt a UDP packet into `batch`, with control data of the packet
+    #[inline]
+    pub fn push_batch_with_ctrl(
+        &self,
+        batch: &mut SendBatch,
+        target: Option<SocketAddr>,
+        addr: &Address,
+        control: &UdpSocketControlData,
+        payload: &[u8],
+    ) {
+        self.socket.push_batch_with_ctrl(batch, target, addr, control, payload);
+        self.incr_user_tx(control, batch.packet_len(batch.len() - 1));
+    }
+
+    /// Send packets encrypted into `batch`
+    #[inline]
+    pub async fn send_batch(&self, batch: &mut SendBatch) -> io::Result<()> {
+        let n = self.socket.send_batch(batch).await?;
+        self.flow_stat.incr_tx(n as u64);
+
+        Ok(())
+    }
+
     #[inline]
     pub fn get_ref(&self) -> &ProxySocket {
         &self.socket
@@ -1,7 +1,7 @@
-//! TCP stream with flow statistic monitored
+//! TCP stream with flow statistic monitored, and bandwidth limited in servers
 
 use std::{
-    io::{self, IoSlice},
+    io::{self, ErrorKind, IoSlice},
     pin::Pin,
     sync::Arc,
     task::{Context, Poll},
@@ -10,7 +10,10 @@ use std::{
 use pin_project::pin_project;
 use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
 
-use super::flow::FlowStat;
+use super::{
+    flow::FlowStat,
+    rate_limit::{Direction, RateLimiters, Throttle},
+};
 
 /// Monitored `ProxyStream`
 #[pin_project]
@@ -18,12 +21,49 @@ pub struct MonProxyStream<S> {
     #[pin]
     stream: S,
     flow_stat: Arc<FlowStat>,
+    user_flow_stat: Option<Arc<FlowStat>>,
+    rate_limiters: RateLimiters,
+    upload: Throttle,
+    download: Throttle,
+    recorded: Option<Vec<u8>>,
 }
 
 impl<S> MonProxyStream<S> {
     #[inline]
     pub fn from_stream(stream: S, flow_stat: Arc<FlowStat>) -> MonProxyStream<S> {
-        MonProxyStream { stream, flow_stat }
+        MonProxyStream {
+            stream,
+            flow_stat,
+            user_flow_stat: None,
+            rate_limiters: RateLimiters::new(),
+            upload: Throttle::default(),
+            download: Throttle::default(),
+            recorded: None,
+        }
+    }
+
+    /// Also monitor the stream in flow statistic of its user, in multi-user servers
+    #[inline]
+    pub fn set_user_flow_stat(&mut self, user_flow_stat: Arc<FlowStat>) {
+        self.user_flow_stat = Some(user_flow_stat);
+    }
+
+    /// Limit bandwidth of the stream, reads are charged to upload and writes are charged to download
+    #[inline]
+    pub fn set_rate_limiters(&mut self, rate_limiters: RateLimiters) {
+        self.rate_limiters = rate_limiters;
+    }
+
+    /// Keep bytes read from the stream, until `take_recorded` is called
+    #[inline]
+    pub fn start_recording(&mut self) {
+        self.recorded = Some(Vec::new());
+    }
+
+    /// Stop recording, and take bytes read since `start_recording`
+    #[inline]
+    pub fn take_recorded(&mut self) -> Vec<u8> {
+        self.recorded.take().unwrap_or_default()
     }
 
     #[inline]
@@ -37,6 +77,22 @@ impl<S> MonProxyStream<S> {
     }
 }
 
+// Streams are closed after quota of the server or the user has been exceeded
+#[inline]
+fn check_quota(flow_stat: &FlowStat, user_flow_stat: &Option<Arc<FlowStat>>) -> io::Result<()> {
+    let exceeded = flow_stat.is_quota_exceeded()
+        || match *user_flow_stat {
+            Some(ref f) => f.is_quota_exceeded(),
+            None => false,
+        };
+
+    if exceeded {
+        Err(io::Error::new(ErrorKind::Other, "traffic quota exceeded"))
+    } else {
+        Ok(())
+    }
+}
+
 impl<S> AsyncRead for MonProxyStream<S>
 where
     S: AsyncRead + Unpin,
@@ -44,11 +100,21 @@ where
     #[inline]
     fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
         let this = self.project();
+        check_quota(this.flow_stat, this.user_flow_stat)?;
+        futures::ready!(this.upload.poll_ready(this.rate_limiters, Direction::Upload, cx));
+        let filled = buf.filled().len();
         match this.stream.poll_read(cx, buf) {
             Poll::Pending => Poll::Pending,
             Poll::Ready(Ok(())) => {
+                if let Some(recorded) = this.recorded {
+                    recorded.extend_from_slice(&buf.filled()[filled..]);
+                }
                 let n = buf.filled().len();
+                this.rate_limiters.consume(Direction::Upload, n);
                 this.flow_stat.incr_rx(n as u64);
+                if let Some(user_flow_stat) = this.user_flow_stat {
+                    user_flow_stat.incr_rx(n as u64);
+                }
                 Poll::Ready(Ok(()))
             }
             Poll::Ready(Err(err)) => Poll::Ready(Err(err)),
@@ -63,10 +129,16 @@ where
     #[inline]
     fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
         let this = self.project();
+        check_quota(this.flow_stat, this.user_flow_stat)?;
+        futures::ready!(this.download.poll_ready(this.rate_limiters, Direction::Download, cx));
         match this.stream.poll_write(cx, buf) {
             Poll::Pending => Poll::Pending,
             Poll::Ready(Ok(n)) => {
+                this.rate_limiters.consume(Direction::Download, n);
                 this.flow_stat.incr_tx(n as u64);
+                if let Some(user_flow_stat) = this.user_flow_stat {
+                    user_flow_stat.incr_tx(n as u64);
+                }
                 Poll::Ready(Ok(n))
             }
             Poll::Ready(Err(err)) => Poll::Ready(Err(err)),
@@ -0,0 +1,125 @@
+//! Checking the local clock against an NTP server
+//!
+//! AEAD 2022 peers reject headers with timestamps too far from their clocks. Hosts without RTC, like routers, may
+//! start with clocks hours behind, then every connection fails. Checking on startup tells which side is wrong.
+
+use std::{
+    io::{self, ErrorKind},
+    net::SocketAddr,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use log::{error, info, warn};
+use shadowsocks::{config::ServerAddr, context::SharedContext, lookup_then};
+use tokio::{net::UdpSocket, time};
+
+/// Seconds between 1900-01-01, the epoch of NTP, and the UNIX epoch
+const NTP_UNIX_EPOCH_DIFF: u64 = 2_208_988_800;
+
+const NTP_PACKET_LEN: usize = 48;
+
+/// Timeout of querying an NTP server
+pub const NTP_QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+
+fn unix_millis(t: SystemTime) -> i64 {
+    match t.duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_millis() as i64,
+        Err(err) => -(err.duration().as_millis() as i64),
+    }
+}
+
+fn ntp_to_unix_millis(buf: &[u8]) -> i64 {
+    let secs = u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]) as i64;
+    let frac = u32::from_be_bytes([buf[4], buf[5], buf[6], buf[7]]) as i64;
+    (secs - NTP_UNIX_EPOCH_DIFF as i64) * 1000 + ((frac * 1000) >> 32)
+}
+
+fn unix_millis_to_ntp(ms: i64, buf: &mut [u8]) {
+    let secs = (ms.div_euclid(1000) + NTP_UNIX_EPOCH_DIFF as i64) as u32;
+    let frac = ((ms.rem_euclid(1000) << 32) / 1000) as u32;
+    buf[..4].copy_from_slice(&secs.to_be_bytes());
+    buf[4..8].copy_from_slice(&frac.to_be_bytes());
+}
+
+async fn query_clock_offset_addr(addr: SocketAddr) -> io::Result<i64> {
+    let bind_addr: SocketAddr = match addr {
+        SocketAddr::V4(..) => "0.0.0.0:0".parse().unwrap(),
+        SocketAddr::V6(..) => "[::]:0".parse().unwrap(),
+    };
+    let socket = UdpSocket::bind(bind_addr).await?;
+    socket.connect(addr).await?;
+
+    // SNTP client request (RFC 4330), LI 0, VN 4, Mode 3, with the transmit timestamp echoed in the response
+    let mut req = [0u8; NTP_PACKET_LEN];
+    req[0] = 0x23;
+    let t1 = unix_millis(SystemTime::now());
+    unix_millis_to_ntp(t1, &mut req[40..48]);
+    socket.send(&req).await?;
+
+    let mut resp = [0u8; NTP_PACKET_LEN];
+    loop {
+        let n = socket.recv(&mut resp).await?;
+        let t4 = unix_millis(SystemTime::now());
+
+        // Mode 4 (server), stratum 0 is a Kiss-o'-Death packet
+        if n < NTP_PACKET_LEN || resp[0] & 0x07 != 4 || resp[24..32] != req[40..48] {
+            continue;
+        }
+        if resp[1] == 0 {
+            return Err(io::Error::new(ErrorKind::Other, "NTP server refused the query"));
+        }
+
+        let t2 = ntp_to_unix_millis(&resp[32..40]);
+        let t3 = ntp_to_unix_millis(&resp[40..48]);
+        return Ok(((t2 - t1) + (t3 - t4)) / 2);
+    }
+}
+
+/// Query the offset of `server`'s clock to the local clock by SNTP, in milliseconds
+///
+/// It is positive if the local clock is behind. Queries are sent directly, not through any proxies.
+pub async fn query_clock_offset(context: &SharedContext, server: &ServerAddr, timeout: Duration) -> io::Result<i64> {
+    let query = async {
+        match *server {
+            ServerAddr::SocketAddr(addr) => query_clock_offset_addr(addr).await,
+            ServerAddr::DomainName(ref dname, port) => {
+                lookup_then!(context, dname, port, |addr| { query_clock_offset_addr(addr).await }).map(|(_, r)| r)
+            }
+        }
+    };
+
+    match time::timeout(timeout, query).await {
+        Ok(r) => r,
+        Err(..) => Err(ErrorKind::TimedOut.into()),
+    }
+}
+
+/// Check the local clock against `server`, errors are logged if they differ by more than `tolerance`
+pub async fn check_clock(context: SharedContext, server: ServerAddr, tolerance: Duration) {
+    let offset = match query_clock_offset(&context, &server, NTP_QUERY_TIMEOUT).await {
+        Ok(offset) => offset,
+        Err(err) => {
+            warn!("clock check with NTP server {} failed, error: {}", server, err);
+            return;
+        }
+    };
+
+    let direction = if offset > 0 { "behind" } else { "ahead of" };
+    if offset.unsigned_abs() > tolerance.as_millis() as u64 {
+        error!(
+            "local clock is {:.1}s {} NTP server {}, more than the tolerance {}s of AEAD 2022 timestamps, \
+             connections will be rejected until the clock is fixed",
+            offset.unsigned_abs() as f64 / 1000.0,
+            direction,
+            server,
+            tolerance.as_secs()
+        );
+    } else {
+        info!(
+            "local clock is {:.1}s {} NTP server {}",
+            offset.unsigned_abs() as f64 / 1000.0,
+            direction,
+            server
+        );
+    }
+}
@@ -0,0 +1,120 @@
+//! Peer-to-peer (BitTorrent) traffic detection
+//!
+//! Only plain BitTorrent protocols could be detected. Connections with Message Stream Encryption (MSE)
+//! are indistinguishable from random data.
+
+use std::{
+    fmt::{self, Display},
+    str::FromStr,
+};
+
+/// What to do with detected BitTorrent traffic
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum P2pPolicy {
+    /// Relay as usual
+    #[default]
+    Allow,
+    /// Close the TCP connection, or drop the UDP packet
+    Block,
+    /// Relay through servers tagged with this tag (only for local)
+    Route(String),
+}
+
+impl P2pPolicy {
+    /// Check if packets have to be inspected
+    pub fn is_allow(&self) -> bool {
+        matches!(*self, P2pPolicy::Allow)
+    }
+}
+
+impl Display for P2pPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            P2pPolicy::Allow => f.write_str("allow"),
+            P2pPolicy::Block => f.write_str("block"),
+            P2pPolicy::Route(ref tag) => write!(f, "route:{}", tag),
+        }
+    }
+}
+
+/// Error while parsing `P2pPolicy` from string
+#[derive(Debug, Clone, Copy)]
+pub struct P2pPolicyError;
+
+impl Display for P2pPolicyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("invalid P2pPolicy, expecting \"allow\", \"block\" or \"route:TAG\"")
+    }
+}
+
+impl FromStr for P2pPolicy {
+    type Err = P2pPolicyError;
+
+    fn from_str(s: &str) -> Result<P2pPolicy, P2pPolicyError> {
+        match s {
+            "allow" => Ok(P2pPolicy::Allow),
+            "block" => Ok(P2pPolicy::Block),
+            _ => match s.strip_prefix("route:") {
+                Some(tag) if !tag.is_empty() => Ok(P2pPolicy::Route(tag.to_owned())),
+                _ => Err(P2pPolicyError),
+            },
+        }
+    }
+}
+
+const BITTORRENT_HANDSHAKE: &[u8] = b"\x13BitTorrent protocol";
+
+/// Check if `buf`, the first chunk of data sent by client of a TCP connection, is BitTorrent traffic
+///
+/// - Peer wire protocol handshake
+/// - Announce and scrape requests of HTTP trackers
+pub fn is_bittorrent_stream(buf: &[u8]) -> bool {
+    if buf.starts_with(BITTORRENT_HANDSHAKE) {
+        return true;
+    }
+
+    if buf.starts_with(b"GET /") {
+        // Only the request line
+        let line = match buf.iter().position(|b| *b == b'\n') {
+            Some(pos) => &buf[..pos],
+            None => buf,
+        };
+        return contains(line, b"info_hash=") && (contains(line, b"/announce") || contains(line, b"/scrape"));
+    }
+
+    false
+}
+
+/// Check if `buf`, payload of a UDP packet sent by client, is BitTorrent traffic
+///
+/// - DHT (KRPC) queries and responses
+/// - uTP connection requests (`ST_SYN`)
+/// - UDP tracker connect requests
+pub fn is_bittorrent_packet(buf: &[u8]) -> bool {
+    // KRPC messages are bencoded dictionaries, "d1:ad2:id20:...e1:q4:ping1:t2:aa1:y1:qe"
+    if buf.starts_with(b"d1:") && buf.ends_with(b"e") {
+        return contains(buf, b"1:y1:q") || contains(buf, b"1:y1:r") || contains(buf, b"1:y1:e");
+    }
+
+    // uTP header, 20 bytes
+    //
+    // type (4 bits), version (4 bits), extension, connection_id (2 bytes),
+    // timestamp_microseconds (4 bytes), timestamp_difference_microseconds (4 bytes),
+    // wnd_size (4 bytes), seq_nr (2 bytes), ack_nr (2 bytes)
+    //
+    // ST_SYN (type 4, version 1) haven't received anything from peer, so timestamp_difference is always 0
+    if buf.len() >= 20 && buf[0] == 0x41 && buf[1] <= 2 && buf[8..12] == [0, 0, 0, 0] {
+        return true;
+    }
+
+    // UDP tracker connect request, protocol_id (0x41727101980), action (0, connect), transaction_id
+    if buf.len() == 16 && buf[..12] == [0, 0, 0x04, 0x17, 0x27, 0x10, 0x19, 0x80, 0, 0, 0, 0] {
+        return true;
+    }
+
+    false
+}
+
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    haystack.windows(needle.len()).any(|w| w == needle)
+}
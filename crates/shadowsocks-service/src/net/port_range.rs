@@ -0,0 +1,47 @@
+//! Ranges of ports
+
+use std::{
+    fmt::{self, Display},
+    ops::RangeInclusive,
+    str::FromStr,
+};
+
+/// Range of ports, like `443` or `5000-5100`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PortRange(pub RangeInclusive<u16>);
+
+impl Display for PortRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.0.start() == self.0.end() {
+            write!(f, "{}", self.0.start())
+        } else {
+            write!(f, "{}-{}", self.0.start(), self.0.end())
+        }
+    }
+}
+
+/// Error while parsing `PortRange` from string
+#[derive(Debug, Clone, Copy)]
+pub struct PortRangeError;
+
+impl Display for PortRangeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("invalid port range, expecting \"PORT\" or \"START-END\"")
+    }
+}
+
+impl FromStr for PortRange {
+    type Err = PortRangeError;
+
+    fn from_str(s: &str) -> Result<PortRange, PortRangeError> {
+        let (start, end) = match s.split_once('-') {
+            Some((start, end)) => (start.trim(), end.trim()),
+            None => (s.trim(), s.trim()),
+        };
+
+        match (start.parse::<u16>(), end.parse::<u16>()) {
+            (Ok(start), Ok(end)) if start <= end => Ok(PortRange(start..=end)),
+            _ => Err(PortRangeError),
+        }
+    }
+}
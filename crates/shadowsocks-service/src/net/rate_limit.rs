@@ -0,0 +1,252 @@
+//! Bandwidth limits of servers
+//!
+//! Limits are enforced with token buckets, shared by streams and UDP associations of the same scope: all servers
+//! (global), one server, one client IP address or one user. Each direction has its own bucket.
+//!
+//! Bytes are charged after they were transferred, a stream that overdrew a bucket waits until the debt is repaid.
+
+use std::{
+    collections::HashMap,
+    future::Future,
+    net::{IpAddr, SocketAddr},
+    pin::Pin,
+    sync::{Arc, Weak},
+    task::{self, Poll},
+    time::Duration,
+};
+
+use shadowsocks::config::RateLimitConfig;
+use spin::Mutex as SpinMutex;
+use tokio::time::{self, Instant, Sleep};
+
+/// Longest wait before checking tokens again
+const MAX_WAIT: Duration = Duration::from_millis(100);
+
+/// Direction of traffic, from the view of clients
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// Client -> server
+    Upload,
+    /// Server -> client
+    Download,
+}
+
+struct BucketInner {
+    last_refill: Instant,
+    /// Negative if overdrawn
+    tokens: i64,
+}
+
+/// Token bucket of one direction
+struct Bucket {
+    rate: u64,
+    burst: i64,
+    inner: SpinMutex<BucketInner>,
+}
+
+impl Bucket {
+    fn new(rate: u64) -> Bucket {
+        // Allows at most 100ms of bursts
+        let burst = (rate / 10).max(16 * 1024) as i64;
+
+        Bucket {
+            rate,
+            burst,
+            inner: SpinMutex::new(BucketInner {
+                last_refill: Instant::now(),
+                tokens: burst,
+            }),
+        }
+    }
+
+    /// `None` if could transfer now, otherwise time to wait
+    fn check(&self) -> Option<Duration> {
+        let mut inner = self.inner.lock();
+
+        let now = Instant::now();
+        let refill = (self.rate as u128 * (now - inner.last_refill).as_nanos() / 1_000_000_000) as i64;
+        if refill > 0 {
+            inner.last_refill = now;
+            inner.tokens = inner.tokens.saturating_add(refill).min(self.burst);
+        }
+
+        if inner.tokens > 0 {
+            return None;
+        }
+
+        let wait_nanos = ((-inner.tokens) as u128 + 1) * 1_000_000_000 / self.rate as u128;
+        let wait = Duration::from_nanos(wait_nanos.min(u64::MAX as u128) as u64);
+        Some(wait.clamp(Duration::from_millis(1), MAX_WAIT))
+    }
+
+    fn consume(&self, n: usize) {
+        let mut inner = self.inner.lock();
+        inner.tokens = inner.tokens.saturating_sub(n as i64);
+    }
+}
+
+/// Bandwidth limit of both directions, in bytes per second
+pub struct RateLimiter {
+    upload: Bucket,
+    download: Bucket,
+}
+
+impl RateLimiter {
+    /// Create a limiter of `rate` bytes per second of each direction, `rate` must be > 0
+    pub fn new(rate: u64) -> RateLimiter {
+        assert!(rate > 0, "rate limit must be > 0");
+
+        RateLimiter {
+            upload: Bucket::new(rate),
+            download: Bucket::new(rate),
+        }
+    }
+
+    fn bucket(&self, dir: Direction) -> &Bucket {
+        match dir {
+            Direction::Upload => &self.upload,
+            Direction::Download => &self.download,
+        }
+    }
+}
+
+/// Limiters applied to a stream or an UDP association
+#[derive(Clone, Default)]
+pub struct RateLimiters {
+    limiters: Vec<Arc<RateLimiter>>,
+}
+
+impl RateLimiters {
+    /// Create an empty set, which doesn't limit anything
+    pub fn new() -> RateLimiters {
+        RateLimiters::default()
+    }
+
+    /// Check if there is no limiter
+    pub fn is_empty(&self) -> bool {
+        self.limiters.is_empty()
+    }
+
+    /// `None` if could transfer in `dir` now, otherwise time to wait
+    pub fn check(&self, dir: Direction) -> Option<Duration> {
+        self.limiters.iter().filter_map(|l| l.bucket(dir).check()).max()
+    }
+
+    /// Charge `n` bytes transferred in `dir`
+    pub fn consume(&self, dir: Direction, n: usize) {
+        for limiter in &self.limiters {
+            limiter.bucket(dir).consume(n);
+        }
+    }
+
+    /// Wait until could transfer in `dir`, then charge `n` bytes
+    pub async fn acquire(&self, dir: Direction, n: usize) {
+        while let Some(wait) = self.check(dir) {
+            time::sleep(wait).await;
+        }
+        self.consume(dir, n);
+    }
+}
+
+/// Waiting state of one direction of a stream
+#[derive(Default)]
+pub struct Throttle {
+    sleep: Option<Pin<Box<Sleep>>>,
+}
+
+impl Throttle {
+    /// Wait until `limiters` allow transferring in `dir`
+    pub fn poll_ready(&mut self, limiters: &RateLimiters, dir: Direction, cx: &mut task::Context<'_>) -> Poll<()> {
+        if limiters.is_empty() {
+            return Poll::Ready(());
+        }
+
+        loop {
+            if let Some(ref mut sleep) = self.sleep {
+                futures::ready!(sleep.as_mut().poll(cx));
+            }
+
+            match limiters.check(dir) {
+                None => {
+                    self.sleep = None;
+                    return Poll::Ready(());
+                }
+                Some(wait) => match self.sleep {
+                    Some(ref mut sleep) => sleep.as_mut().reset(Instant::now() + wait),
+                    None => self.sleep = Some(Box::pin(time::sleep(wait))),
+                },
+            }
+        }
+    }
+}
+
+/// Limiters of a server, created for each client IP address and user on demand
+pub struct ServerRateLimiter {
+    config: RateLimitConfig,
+    global: Option<Arc<RateLimiter>>,
+    server: Option<Arc<RateLimiter>>,
+    // Dropped after all streams and associations of the client are closed
+    clients: SpinMutex<HashMap<IpAddr, Weak<RateLimiter>>>,
+    users: SpinMutex<HashMap<String, Arc<RateLimiter>>>,
+}
+
+impl Default for ServerRateLimiter {
+    fn default() -> Self {
+        ServerRateLimiter::new(RateLimitConfig::default())
+    }
+}
+
+impl ServerRateLimiter {
+    /// Create limiters of a server with `config`
+    pub fn new(config: RateLimitConfig) -> ServerRateLimiter {
+        ServerRateLimiter {
+            server: config.server.map(|rate| Arc::new(RateLimiter::new(rate))),
+            config,
+            global: None,
+            clients: SpinMutex::new(HashMap::new()),
+            users: SpinMutex::new(HashMap::new()),
+        }
+    }
+
+    /// Also limit by `global`, which is shared with the other servers
+    pub fn set_global(&mut self, global: Arc<RateLimiter>) {
+        self.global = Some(global);
+    }
+
+    /// Limiters applied to traffic of `peer_addr`, and of `user` in multi-user servers
+    pub fn limiters(&self, peer_addr: &SocketAddr, user: Option<&str>) -> RateLimiters {
+        let mut limiters = RateLimiters::new();
+        limiters.limiters.extend(self.global.iter().cloned());
+        limiters.limiters.extend(self.server.iter().cloned());
+
+        if let Some(rate) = self.config.client {
+            limiters.limiters.push(self.client_limiter(peer_addr.ip(), rate));
+        }
+
+        if let (Some(rate), Some(name)) = (self.config.user, user) {
+            let limiter = self
+                .users
+                .lock()
+                .entry(name.to_owned())
+                .or_insert_with(|| Arc::new(RateLimiter::new(rate)))
+                .clone();
+            limiters.limiters.push(limiter);
+        }
+
+        limiters
+    }
+
+    fn client_limiter(&self, ip: IpAddr, rate: u64) -> Arc<RateLimiter> {
+        let mut clients = self.clients.lock();
+        if let Some(limiter) = clients.get(&ip).and_then(Weak::upgrade) {
+            return limiter;
+        }
+
+        // Clients that have gone are cleaned up before adding a new one
+        clients.retain(|_, l| l.strong_count() > 0);
+
+        let limiter = Arc::new(RateLimiter::new(rate));
+        clients.insert(ip, Arc::downgrade(&limiter));
+        limiter
+    }
+}
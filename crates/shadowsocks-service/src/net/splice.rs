@@ -0,0 +1,143 @@
+//! Zero-copy relay between TCP sockets with `splice(2)`
+//!
+//! Data received by one socket is moved into a pipe, and then from the pipe to the send queue of the other socket,
+//! without being copied to the userspace. Each direction owns a pipe, so a relay takes 4 more file descriptors.
+//!
+//! Only plain sockets could be spliced. Data of streams wrapped by encryption, TLS or monitors has to be relayed by the
+//! userspace as usual.
+
+use std::{
+    io::{self, ErrorKind},
+    net::Shutdown,
+    os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+    ptr,
+};
+
+use futures::future;
+use socket2::SockRef;
+use tokio::{io::Interest, net::TcpStream};
+
+/// Maximum bytes moved by one `splice` call, the default capacity of pipes
+const SPLICE_SIZE: usize = 64 * 1024;
+
+/// Pipe between two sockets
+struct Pipe {
+    read_fd: OwnedFd,
+    write_fd: OwnedFd,
+}
+
+impl Pipe {
+    fn new() -> io::Result<Pipe> {
+        let mut fds: [libc::c_int; 2] = [-1; 2];
+        let ret = unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | libc::O_CLOEXEC) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        unsafe {
+            Ok(Pipe {
+                read_fd: OwnedFd::from_raw_fd(fds[0]),
+                write_fd: OwnedFd::from_raw_fd(fds[1]),
+            })
+        }
+    }
+}
+
+fn splice(fd_in: RawFd, fd_out: RawFd, len: usize) -> io::Result<usize> {
+    let ret = unsafe {
+        libc::splice(
+            fd_in,
+            ptr::null_mut(),
+            fd_out,
+            ptr::null_mut(),
+            len,
+            libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(ret as usize)
+}
+
+/// Pipes of both directions of a relay
+///
+/// Created before relaying, so that it could fallback to the userspace if file descriptors are exhausted.
+pub struct Splicer {
+    a_to_b: Pipe,
+    b_to_a: Pipe,
+}
+
+impl Splicer {
+    /// Create pipes of a relay
+    pub fn new() -> io::Result<Splicer> {
+        Ok(Splicer {
+            a_to_b: Pipe::new()?,
+            b_to_a: Pipe::new()?,
+        })
+    }
+
+    /// Relay data between `a` and `b` until both directions are shut down, like `copy_bidirectional`
+    ///
+    /// `a_to_b` and `b_to_a` are called with bytes moved in each direction. Returns a tuple of bytes copied `a` to
+    /// `b` and bytes copied `b` to `a`.
+    pub async fn copy_bidirectional<F, G>(
+        &self,
+        a: &TcpStream,
+        b: &TcpStream,
+        a_to_b: F,
+        b_to_a: G,
+    ) -> io::Result<(u64, u64)>
+    where
+        F: Fn(u64),
+        G: Fn(u64),
+    {
+        future::try_join(
+            copy_spliced(&self.a_to_b, a, b, a_to_b),
+            copy_spliced(&self.b_to_a, b, a, b_to_a),
+        )
+        .await
+    }
+}
+
+async fn copy_spliced<F>(pipe: &Pipe, reader: &TcpStream, writer: &TcpStream, copied: F) -> io::Result<u64>
+where
+    F: Fn(u64),
+{
+    let mut amt = 0u64;
+
+    loop {
+        // The pipe is always drained before receiving more, so `EAGAIN` means nothing to be received
+        let n = loop {
+            reader.readable().await?;
+            match reader.try_io(Interest::READABLE, || {
+                splice(reader.as_raw_fd(), pipe.write_fd.as_raw_fd(), SPLICE_SIZE)
+            }) {
+                Ok(n) => break n,
+                Err(ref err) if err.kind() == ErrorKind::WouldBlock => continue,
+                Err(err) => return Err(err),
+            }
+        };
+
+        if n == 0 {
+            SockRef::from(writer).shutdown(Shutdown::Write)?;
+            return Ok(amt);
+        }
+
+        let mut pending = n;
+        while pending > 0 {
+            writer.writable().await?;
+            match writer.try_io(Interest::WRITABLE, || {
+                splice(pipe.read_fd.as_raw_fd(), writer.as_raw_fd(), pending)
+            }) {
+                Ok(n) => {
+                    pending -= n;
+                    amt += n as u64;
+                    copied(n as u64);
+                }
+                Err(ref err) if err.kind() == ErrorKind::WouldBlock => continue,
+                Err(err) => return Err(err),
+            }
+        }
+    }
+}
@@ -0,0 +1,91 @@
+//! TLS acceptor for local servers and manager's SIP008 server
+//!
+//! Certificates and keys are loaded by [rustls](https://crates.io/crates/rustls)
+
+use std::{
+    fs::File,
+    io::{self, BufReader, ErrorKind},
+    path::Path,
+    sync::Arc,
+};
+
+use log::trace;
+use tokio_rustls::{
+    rustls::{server::AllowAnyAuthenticatedClient, Certificate, PrivateKey, RootCertStore, ServerConfig},
+    TlsAcceptor,
+};
+
+use crate::config::LocalTlsConfig;
+
+fn load_certificates(path: &Path) -> io::Result<Vec<Certificate>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let certs = rustls_pemfile::certs(&mut reader)?;
+
+    if certs.is_empty() {
+        let err = io::Error::new(
+            ErrorKind::InvalidData,
+            format!("no certificate found in {}", path.display()),
+        );
+        return Err(err);
+    }
+
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+fn load_private_key(path: &Path) -> io::Result<PrivateKey> {
+    use rustls_pemfile::Item;
+
+    let mut reader = BufReader::new(File::open(path)?);
+
+    // Takes the first private key in the file
+    while let Some(item) = rustls_pemfile::read_one(&mut reader)? {
+        match item {
+            Item::RSAKey(key) | Item::PKCS8Key(key) | Item::ECKey(key) => return Ok(PrivateKey(key)),
+            _ => continue,
+        }
+    }
+
+    Err(io::Error::new(
+        ErrorKind::InvalidData,
+        format!("no private key found in {}", path.display()),
+    ))
+}
+
+/// Create a `TlsAcceptor` from configuration
+///
+/// `alpn_protocols` are the application protocols supported by the server, in preference order
+pub fn create_tls_acceptor(config: &LocalTlsConfig, alpn_protocols: &[&[u8]]) -> io::Result<TlsAcceptor> {
+    let certs = load_certificates(&config.certificate_path)?;
+    let key = load_private_key(&config.private_key_path)?;
+
+    let builder = ServerConfig::builder().with_safe_defaults();
+
+    let builder = match config.client_ca_path {
+        None => builder.with_no_client_auth(),
+        Some(ref client_ca_path) => {
+            let mut roots = RootCertStore::empty();
+            for cert in load_certificates(client_ca_path)? {
+                if let Err(err) = roots.add(&cert) {
+                    let err = io::Error::new(ErrorKind::InvalidData, format!("invalid client CA, {}", err));
+                    return Err(err);
+                }
+            }
+
+            trace!(
+                "loaded {} client CA certificates from {}",
+                roots.len(),
+                client_ca_path.display()
+            );
+
+            builder.with_client_cert_verifier(AllowAnyAuthenticatedClient::new(roots))
+        }
+    };
+
+    let mut server_config = match builder.with_single_cert(certs, key) {
+        Ok(c) => c,
+        Err(err) => return Err(io::Error::new(ErrorKind::InvalidInput, err)),
+    };
+    server_config.alpn_protocols = alpn_protocols.iter().map(|p| p.to_vec()).collect();
+
+    Ok(TlsAcceptor::from(Arc::new(server_config)))
+}
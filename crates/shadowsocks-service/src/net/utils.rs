@@ -1,6 +1,9 @@
 //! Network Utilities
 
-use std::io;
+use std::{
+    io::{self, ErrorKind},
+    net::{SocketAddr, TcpListener, UdpSocket},
+};
 
 use tokio::io::{AsyncRead, AsyncReadExt};
 
@@ -20,3 +23,40 @@ where
 
     Ok(())
 }
+
+/// Times of choosing another port if the chosen one is in use by UDP
+const UNSPECIFIED_PORT_ATTEMPTS: usize = 8;
+
+/// Choose an available port for `addr` if its port is 0, otherwise returns `addr` unchanged
+///
+/// Listeners are bound after servers are spawned, so the port is chosen by the system for a probing socket, which is
+/// closed before returning. The port is available for both TCP and UDP if both `tcp` and `udp` are required.
+pub fn choose_unspecified_port(addr: SocketAddr, tcp: bool, udp: bool) -> io::Result<SocketAddr> {
+    if addr.port() != 0 {
+        return Ok(addr);
+    }
+
+    let mut last_err = None;
+    for _ in 0..UNSPECIFIED_PORT_ATTEMPTS {
+        let chosen_addr = if tcp || !udp {
+            TcpListener::bind(addr)?.local_addr()?
+        } else {
+            UdpSocket::bind(addr)?.local_addr()?
+        };
+
+        if tcp && udp {
+            match UdpSocket::bind(chosen_addr) {
+                Ok(..) => {}
+                Err(err) if err.kind() == ErrorKind::AddrInUse => {
+                    last_err = Some(err);
+                    continue;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+
+        return Ok(chosen_addr);
+    }
+
+    Err(last_err.unwrap_or_else(|| io::Error::new(ErrorKind::AddrInUse, "address in use")))
+}
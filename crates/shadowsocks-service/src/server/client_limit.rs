@@ -0,0 +1,209 @@
+//! Per-client limits of servers
+//!
+//! Concurrent TCP connections and UDP associations are counted by source IP addresses of clients, across all
+//! servers sharing the limiter. When a client reaches its limit, new ones are either refused, or accepted by closing
+//! the least recently used one of the same kind.
+
+use std::{
+    collections::HashMap,
+    fmt::{self, Display},
+    net::IpAddr,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use spin::Mutex as SpinMutex;
+use tokio::sync::Notify;
+
+use crate::net::ConcurrencyKind;
+
+/// What to do when a client has reached its limit
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ClientLimitPolicy {
+    /// Refuse new connections and associations
+    #[default]
+    Deny,
+    /// Close the least recently used connection or association to make room for the new one
+    Lru,
+}
+
+impl Display for ClientLimitPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            ClientLimitPolicy::Deny => f.write_str("deny"),
+            ClientLimitPolicy::Lru => f.write_str("lru"),
+        }
+    }
+}
+
+/// Error while parsing `ClientLimitPolicy` from string
+#[derive(Debug, Clone, Copy)]
+pub struct ClientLimitPolicyError;
+
+impl Display for ClientLimitPolicyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("invalid ClientLimitPolicy, expecting \"deny\" or \"lru\"")
+    }
+}
+
+impl FromStr for ClientLimitPolicy {
+    type Err = ClientLimitPolicyError;
+
+    fn from_str(s: &str) -> Result<ClientLimitPolicy, ClientLimitPolicyError> {
+        match s {
+            "deny" => Ok(ClientLimitPolicy::Deny),
+            "lru" => Ok(ClientLimitPolicy::Lru),
+            _ => Err(ClientLimitPolicyError),
+        }
+    }
+}
+
+/// Configuration of per-client limits, `None` for unlimited
+#[derive(Debug, Clone, Default)]
+pub struct ClientLimitConfig {
+    /// Concurrent TCP connections of a client, including UDP-over-TCP and multiplexed connections
+    pub max_tcp_connections: Option<usize>,
+    /// Concurrent UDP associations of a client
+    pub max_udp_associations: Option<usize>,
+    /// What to do when a limit has been reached
+    pub policy: ClientLimitPolicy,
+}
+
+impl ClientLimitConfig {
+    fn max(&self, kind: ConcurrencyKind) -> Option<usize> {
+        match kind {
+            ConcurrencyKind::TcpTunnel => self.max_tcp_connections,
+            ConcurrencyKind::UdpAssociation => self.max_udp_associations,
+            _ => None,
+        }
+    }
+}
+
+struct ClientSlot {
+    id: u64,
+    // Value of `ClientLimiter::clock` when it was used the last time
+    last_used: AtomicU64,
+    evicted: Notify,
+}
+
+/// Slots in use by clients, of each kind
+type ClientSlots = HashMap<(IpAddr, ConcurrencyKind), Vec<Arc<ClientSlot>>>;
+
+/// Per-client limiter, shared by all servers
+pub struct ClientLimiter {
+    config: ClientLimitConfig,
+    clock: AtomicU64,
+    clients: SpinMutex<ClientSlots>,
+}
+
+impl fmt::Debug for ClientLimiter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ClientLimiter").field("config", &self.config).finish()
+    }
+}
+
+impl ClientLimiter {
+    /// Create a limiter with `config`
+    pub fn new(config: ClientLimitConfig) -> ClientLimiter {
+        ClientLimiter {
+            config,
+            clock: AtomicU64::new(0),
+            clients: SpinMutex::new(HashMap::new()),
+        }
+    }
+
+    /// Configuration of this limiter
+    pub fn config(&self) -> &ClientLimitConfig {
+        &self.config
+    }
+
+    /// Acquire a slot of `kind` for client `ip`, `None` if it is refused
+    ///
+    /// The slot is released when the returned guard is dropped. With `ClientLimitPolicy::Lru`, the least recently
+    /// used slot of the client is evicted if the limit has been reached.
+    pub fn acquire(self: &Arc<Self>, kind: ConcurrencyKind, ip: IpAddr) -> Option<ClientLimitGuard> {
+        let max = match self.config.max(kind) {
+            Some(m) => m,
+            None => return Some(ClientLimitGuard::unlimited()),
+        };
+        if max == 0 {
+            return None;
+        }
+
+        let now = self.clock.fetch_add(1, Ordering::Relaxed);
+        let slot = Arc::new(ClientSlot {
+            id: now,
+            last_used: AtomicU64::new(now),
+            evicted: Notify::new(),
+        });
+
+        let mut clients = self.clients.lock();
+        let slots = clients.entry((ip, kind)).or_default();
+        if slots.len() >= max {
+            match self.config.policy {
+                ClientLimitPolicy::Deny => return None,
+                ClientLimitPolicy::Lru => {
+                    let (lru, _) = slots
+                        .iter()
+                        .enumerate()
+                        .min_by_key(|(_, s)| s.last_used.load(Ordering::Relaxed))
+                        .expect("client slots empty");
+                    // Removed from the table immediately, so it is counted as released
+                    let evicted = slots.swap_remove(lru);
+                    evicted.evicted.notify_one();
+                }
+            }
+        }
+        slots.push(slot.clone());
+        drop(clients);
+
+        Some(ClientLimitGuard {
+            slot: Some((self.clone(), ip, kind, slot)),
+        })
+    }
+}
+
+/// Guard of a slot acquired from `ClientLimiter`
+pub struct ClientLimitGuard {
+    slot: Option<(Arc<ClientLimiter>, IpAddr, ConcurrencyKind, Arc<ClientSlot>)>,
+}
+
+impl ClientLimitGuard {
+    /// Guard of clients without limits
+    pub fn unlimited() -> ClientLimitGuard {
+        ClientLimitGuard { slot: None }
+    }
+
+    /// Mark the slot as used recently
+    pub fn touch(&self) {
+        if let Some((ref limiter, _, _, ref slot)) = self.slot {
+            let now = limiter.clock.fetch_add(1, Ordering::Relaxed);
+            slot.last_used.store(now, Ordering::Relaxed);
+        }
+    }
+
+    /// Wait until the slot has been evicted, never returns if eviction is impossible
+    pub async fn evicted(&self) {
+        match self.slot {
+            Some((_, _, _, ref slot)) => slot.evicted.notified().await,
+            None => futures::future::pending().await,
+        }
+    }
+}
+
+impl Drop for ClientLimitGuard {
+    fn drop(&mut self) {
+        if let Some((ref limiter, ip, kind, ref slot)) = self.slot {
+            let mut clients = limiter.clients.lock();
+            if let Some(slots) = clients.get_mut(&(ip, kind)) {
+                slots.retain(|s| s.id != slot.id);
+                if slots.is_empty() {
+                    clients.remove(&(ip, kind));
+                }
+            }
+        }
+    }
+}
@@ -1,16 +1,31 @@
 //! Shadowsocks Local Server Context
 
-use std::{net::SocketAddr, sync::Arc};
+use std::{borrow::Cow, net::SocketAddr, sync::Arc};
 
+use futures::future;
 use shadowsocks::{
     config::ServerType,
-    context::{Context, SharedContext},
+    context::{Context, NonceObserver, SharedContext},
     dns_resolver::DnsResolver,
-    net::ConnectOpts,
+    net::{ConnectOpts, IpStrategy},
     relay::Address,
 };
 
-use crate::{acl::AccessControl, config::SecurityConfig, net::FlowStat};
+#[cfg(feature = "acl")]
+use crate::acl::AccessControl;
+use crate::{
+    config::SecurityConfig,
+    net::{ConcurrencyKind, ConcurrencyStat, FlowStat, P2pPolicy, ServerRateLimiter, UserFlowStat},
+    store::{MemoryStateStore, StateStore},
+};
+
+use super::{
+    client_limit::{ClientLimitGuard, ClientLimiter},
+    drain::DrainHandle,
+    firewall::{FirewallProtocol, OutboundFirewall},
+    monitor::SecurityMonitor,
+    udp_eviction::UdpEvictionStat,
+};
 
 /// Server Service Context
 pub struct ServiceContext {
@@ -18,10 +33,41 @@ pub struct ServiceContext {
     connect_opts: ConnectOpts,
 
     // Access Control
+    #[cfg(feature = "acl")]
     acl: Option<Arc<AccessControl>>,
 
     // Flow statistic report
     flow_stat: Arc<FlowStat>,
+
+    // Flow statistic of users in multi-user servers
+    user_flow_stat: Arc<UserFlowStat>,
+
+    // Concurrently alive objects and their high-water marks
+    concurrency_stat: Arc<ConcurrencyStat>,
+
+    // Persistent states
+    state_store: Arc<dyn StateStore>,
+
+    // Replayed nonces and malformed handshakes
+    security_monitor: Arc<SecurityMonitor>,
+
+    // Expired, evicted and refused UDP associations
+    udp_eviction_stat: Arc<UdpEvictionStat>,
+
+    // BitTorrent traffic
+    p2p_policy: P2pPolicy,
+
+    // Bandwidth limits
+    rate_limiter: ServerRateLimiter,
+
+    // Outbound firewall rules
+    outbound_firewall: Option<Arc<OutboundFirewall>>,
+
+    // Per-client limits of connections and associations
+    client_limiter: Option<Arc<ClientLimiter>>,
+
+    // Stopping accepting new connections before exiting
+    drain: Option<DrainHandle>,
 }
 
 impl Default for ServiceContext {
@@ -29,8 +75,19 @@ impl Default for ServiceContext {
         ServiceContext {
             context: Context::new_shared(ServerType::Server),
             connect_opts: ConnectOpts::default(),
+            #[cfg(feature = "acl")]
             acl: None,
             flow_stat: Arc::new(FlowStat::new()),
+            user_flow_stat: Arc::new(UserFlowStat::new()),
+            concurrency_stat: Arc::new(ConcurrencyStat::new()),
+            state_store: Arc::new(MemoryStateStore::new()),
+            security_monitor: Arc::new(SecurityMonitor::new()),
+            udp_eviction_stat: Arc::new(UdpEvictionStat::new()),
+            p2p_policy: P2pPolicy::Allow,
+            rate_limiter: ServerRateLimiter::default(),
+            outbound_firewall: None,
+            client_limiter: None,
+            drain: None,
         }
     }
 }
@@ -61,12 +118,41 @@ impl ServiceContext {
         &self.connect_opts
     }
 
+    /// Get `ConnectOpts` for connecting to target `addr`, with its strategy of choosing among resolved addresses
+    pub fn target_connect_opts(&self, addr: &Address) -> Cow<'_, ConnectOpts> {
+        match self.acl_ip_strategy(addr) {
+            Some(ip_strategy) => {
+                let mut connect_opts = self.connect_opts.clone();
+                connect_opts.ip_strategy = Some(ip_strategy);
+                Cow::Owned(connect_opts)
+            }
+            None => Cow::Borrowed(&self.connect_opts),
+        }
+    }
+
+    /// Strategy of choosing among resolved addresses of target `addr`
+    pub fn target_ip_strategy(&self, addr: &Address) -> IpStrategy {
+        self.acl_ip_strategy(addr).unwrap_or_else(|| self.context.ip_strategy())
+    }
+
+    // Strategy of host names configured by ACL's `[ip_strategy:STRATEGY]` sections
+    #[cfg_attr(not(feature = "acl"), allow(unused_variables))]
+    fn acl_ip_strategy(&self, addr: &Address) -> Option<IpStrategy> {
+        #[cfg(feature = "acl")]
+        if let (Some(ref acl), Address::DomainNameAddress(ref host, ..)) = (&self.acl, addr) {
+            return acl.ip_strategy(host);
+        }
+        None
+    }
+
     /// Set Access Control List
+    #[cfg(feature = "acl")]
     pub fn set_acl(&mut self, acl: Arc<AccessControl>) {
         self.acl = Some(acl);
     }
 
     /// Get Access Control List reference
+    #[cfg(feature = "acl")]
     pub fn acl(&self) -> Option<&AccessControl> {
         self.acl.as_deref()
     }
@@ -81,6 +167,61 @@ impl ServiceContext {
         self.flow_stat.as_ref()
     }
 
+    /// Get flow statistic of users in multi-user servers
+    pub fn user_flow_stat(&self) -> &Arc<UserFlowStat> {
+        &self.user_flow_stat
+    }
+
+    /// Get concurrency statistic, including high-water marks since this context was created
+    pub fn concurrency_stat(&self) -> &Arc<ConcurrencyStat> {
+        &self.concurrency_stat
+    }
+
+    /// Set storage backend of persistent states
+    pub fn set_state_store(&mut self, state_store: Arc<dyn StateStore>) {
+        self.state_store = state_store;
+    }
+
+    /// Get storage backend of persistent states
+    pub fn state_store(&self) -> &Arc<dyn StateStore> {
+        &self.state_store
+    }
+
+    /// Set monitor of security events
+    pub fn set_security_monitor(&mut self, security_monitor: Arc<SecurityMonitor>) {
+        self.security_monitor = security_monitor;
+    }
+
+    /// Get monitor of security events
+    pub fn security_monitor(&self) -> &Arc<SecurityMonitor> {
+        &self.security_monitor
+    }
+
+    /// Get counters of expired, evicted and refused UDP associations
+    pub fn udp_eviction_stat(&self) -> &Arc<UdpEvictionStat> {
+        &self.udp_eviction_stat
+    }
+
+    /// Set policy of BitTorrent traffic
+    pub fn set_p2p_policy(&mut self, p2p_policy: P2pPolicy) {
+        self.p2p_policy = p2p_policy;
+    }
+
+    /// Get policy of BitTorrent traffic
+    pub fn p2p_policy(&self) -> &P2pPolicy {
+        &self.p2p_policy
+    }
+
+    /// Set bandwidth limits
+    pub fn set_rate_limiter(&mut self, rate_limiter: ServerRateLimiter) {
+        self.rate_limiter = rate_limiter;
+    }
+
+    /// Get bandwidth limits
+    pub fn rate_limiter(&self) -> &ServerRateLimiter {
+        &self.rate_limiter
+    }
+
     /// Set customized DNS resolver
     pub fn set_dns_resolver(&mut self, resolver: Arc<DnsResolver>) {
         let context = Arc::get_mut(&mut self.context).expect("cannot set DNS resolver on a shared context");
@@ -93,18 +234,83 @@ impl ServiceContext {
     }
 
     /// Check if target should be bypassed
+    #[cfg_attr(not(feature = "acl"), allow(unused_variables))]
     pub async fn check_outbound_blocked(&self, addr: &Address) -> bool {
-        match self.acl {
+        #[cfg(feature = "acl")]
+        if let Some(ref acl) = self.acl {
+            return acl.check_outbound_blocked(&self.context, addr).await;
+        }
+        false
+    }
+
+    /// Set outbound firewall, which is shared by all servers
+    pub fn set_outbound_firewall(&mut self, outbound_firewall: Arc<OutboundFirewall>) {
+        self.outbound_firewall = Some(outbound_firewall);
+    }
+
+    /// Check if `protocol` traffic to target `addr` is denied by the outbound firewall, for `user` in multi-user
+    /// servers
+    ///
+    /// This function may perform a DNS resolution
+    pub async fn check_outbound_denied(&self, protocol: FirewallProtocol, addr: &Address, user: Option<&str>) -> bool {
+        match self.outbound_firewall {
+            Some(ref firewall) => firewall.check_denied(&self.context, protocol, addr, user).await,
             None => false,
-            Some(ref acl) => acl.check_outbound_blocked(&self.context, addr).await,
+        }
+    }
+
+    /// Set per-client limiter, which is shared by all servers
+    pub fn set_client_limiter(&mut self, client_limiter: Arc<ClientLimiter>) {
+        self.client_limiter = Some(client_limiter);
+    }
+
+    /// Set drain handle, which is shared by all servers of an instance
+    pub fn set_drain_handle(&mut self, drain: DrainHandle) {
+        self.drain = Some(drain);
+    }
+
+    /// Check if the server is draining, which shouldn't accept new connections or associations
+    pub fn is_draining(&self) -> bool {
+        match self.drain {
+            Some(ref drain) => drain.is_draining(),
+            None => false,
+        }
+    }
+
+    /// Wait until the server starts draining, never resolves without a drain handle
+    pub async fn wait_draining(&self) {
+        match self.drain {
+            Some(ref drain) => drain.wait().await,
+            None => future::pending().await,
+        }
+    }
+
+    /// Acquire a slot of `kind` for the client `peer_addr`, `None` if it has reached its limit
+    pub fn acquire_client_slot(&self, kind: ConcurrencyKind, peer_addr: &SocketAddr) -> Option<ClientLimitGuard> {
+        match self.client_limiter {
+            Some(ref limiter) => limiter.acquire(kind, peer_addr.ip()),
+            None => Some(ClientLimitGuard::unlimited()),
         }
     }
 
     /// Check if client should be blocked
+    #[cfg_attr(not(feature = "acl"), allow(unused_variables))]
     pub fn check_client_blocked(&self, addr: &SocketAddr) -> bool {
-        match self.acl {
+        #[cfg(feature = "acl")]
+        if let Some(ref acl) = self.acl {
+            return acl.check_client_blocked(addr);
+        }
+        false
+    }
+
+    /// Check if quota of the server, or quota of `user` in multi-user servers, has been exceeded
+    pub fn check_quota_exceeded(&self, user: Option<&str>) -> bool {
+        if self.flow_stat.is_quota_exceeded() {
+            return true;
+        }
+        match user {
+            Some(name) => self.user_flow_stat.is_quota_exceeded(name),
             None => false,
-            Some(ref acl) => acl.check_client_blocked(addr),
         }
     }
 
@@ -114,9 +320,25 @@ impl ServiceContext {
         context.set_ipv6_first(ipv6_first);
     }
 
+    /// Set strategy of choosing among resolved IPv4 and IPv6 addresses
+    pub fn set_ip_strategy(&mut self, ip_strategy: IpStrategy) {
+        let context = Arc::get_mut(&mut self.context).expect("cannot set ip_strategy on a shared context");
+        context.set_ip_strategy(ip_strategy);
+    }
+
     /// Set security config
     pub fn set_security_config(&mut self, security: &SecurityConfig) {
         let context = Arc::get_mut(&mut self.context).expect("cannot set security on a shared context");
         context.set_replay_attack_policy(security.replay_attack.policy);
+        #[cfg(feature = "aead-cipher-2022")]
+        if let Some(tolerance) = security.clock_skew.tolerance {
+            context.set_timestamp_tolerance(tolerance);
+        }
+    }
+
+    /// Set observer of the accepted nonces (IV/salt)
+    pub fn set_nonce_observer(&mut self, observer: Arc<dyn NonceObserver>) {
+        let context = Arc::get_mut(&mut self.context).expect("cannot set nonce observer on a shared context");
+        context.set_nonce_observer(observer);
     }
 }
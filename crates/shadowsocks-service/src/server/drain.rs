@@ -0,0 +1,123 @@
+//! Draining servers before exiting
+//!
+//! Draining servers stop accepting TCP connections and creating UDP associations, while established connections and
+//! associations are kept relaying until they are closed by peers or timed out. Upgrades and restarts wait for them
+//! up to a deadline instead of cutting all clients off at once.
+
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use log::info;
+use tokio::{
+    sync::watch,
+    time::{self, Instant},
+};
+
+use crate::net::{ConcurrencyKind, ConcurrencyStat};
+
+/// Interval of checking remaining connections while draining
+const DRAIN_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+/// Interval of logging remaining connections while draining
+const DRAIN_REPORT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Handle for draining servers, shared by all servers of an instance
+#[derive(Debug, Clone)]
+pub struct DrainHandle {
+    tx: Arc<watch::Sender<bool>>,
+    rx: watch::Receiver<bool>,
+    stats: Arc<Mutex<Vec<Arc<ConcurrencyStat>>>>,
+}
+
+impl DrainHandle {
+    /// Create a handle that isn't draining
+    pub fn new() -> DrainHandle {
+        let (tx, rx) = watch::channel(false);
+        DrainHandle {
+            tx: Arc::new(tx),
+            rx,
+            stats: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    /// Count connections of a server, whose concurrency statistic is `stat`
+    pub(crate) fn add_server_stat(&self, stat: Arc<ConcurrencyStat>) {
+        self.stats.lock().unwrap().push(stat);
+    }
+
+    /// Start draining servers of this handle, which could be called from any thread
+    pub fn drain(&self) {
+        let _ = self.tx.send(true);
+    }
+
+    /// Check if draining has been started
+    pub fn is_draining(&self) -> bool {
+        *self.rx.borrow()
+    }
+
+    /// Wait until draining is started, resolves immediately if it has been started
+    pub async fn wait(&self) {
+        let mut rx = self.rx.clone();
+        while !*rx.borrow() {
+            // The sender is owned by `self`, it couldn't be closed
+            if rx.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+
+    /// Relaying TCP connections and UDP associations of all servers
+    pub fn active_sessions(&self) -> (usize, usize) {
+        let stats = self.stats.lock().unwrap();
+        stats.iter().fold((0, 0), |(tcp, udp), stat| {
+            (
+                tcp + stat.current(ConcurrencyKind::TcpTunnel),
+                udp + stat.current(ConcurrencyKind::UdpAssociation),
+            )
+        })
+    }
+
+    /// Wait until all connections and associations are closed, or `timeout` elapsed, logging the remaining ones
+    ///
+    /// Draining has to be started by `drain`. Returns `true` if all of them are closed.
+    pub async fn wait_drained(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let mut next_report = Instant::now();
+
+        loop {
+            let (tcp, udp) = self.active_sessions();
+            if tcp == 0 && udp == 0 {
+                info!("all connections are drained");
+                return true;
+            }
+
+            let now = Instant::now();
+            if now >= deadline {
+                info!(
+                    "drain timed out, closing {} tcp connections and {} udp associations",
+                    tcp, udp
+                );
+                return false;
+            }
+
+            if now >= next_report {
+                info!(
+                    "draining {} tcp connections and {} udp associations, {}s before timed out",
+                    tcp,
+                    udp,
+                    (deadline - now).as_secs()
+                );
+                next_report = now + DRAIN_REPORT_INTERVAL;
+            }
+
+            time::sleep_until(deadline.min(now + DRAIN_CHECK_INTERVAL)).await;
+        }
+    }
+}
+
+impl Default for DrainHandle {
+    fn default() -> DrainHandle {
+        DrainHandle::new()
+    }
+}
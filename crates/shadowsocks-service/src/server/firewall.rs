@@ -0,0 +1,219 @@
+//! Outbound firewall of servers
+//!
+//! Targets of TCP connections and UDP packets are checked by rules in order, the first matched rule decides whether
+//! it is allowed. Targets that don't match any rule are handled by the default action.
+//!
+//! Host names are resolved if any rule matches IP addresses, and they are denied if any of their addresses is denied.
+
+use std::{
+    fmt::{self, Display},
+    net::IpAddr,
+    str::FromStr,
+};
+
+use ipnet::IpNet;
+use shadowsocks::{context::Context, relay::socks5::Address};
+
+use crate::net::PortRange;
+
+/// What to do with targets matched by a rule
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FirewallAction {
+    /// Relay as usual
+    #[default]
+    Allow,
+    /// Close the TCP connection, or drop the UDP packet
+    Deny,
+}
+
+impl Display for FirewallAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            FirewallAction::Allow => f.write_str("allow"),
+            FirewallAction::Deny => f.write_str("deny"),
+        }
+    }
+}
+
+/// Error while parsing `FirewallAction` from string
+#[derive(Debug, Clone, Copy)]
+pub struct FirewallActionError;
+
+impl Display for FirewallActionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("invalid FirewallAction, expecting \"allow\" or \"deny\"")
+    }
+}
+
+impl FromStr for FirewallAction {
+    type Err = FirewallActionError;
+
+    fn from_str(s: &str) -> Result<FirewallAction, FirewallActionError> {
+        match s {
+            "allow" => Ok(FirewallAction::Allow),
+            "deny" => Ok(FirewallAction::Deny),
+            _ => Err(FirewallActionError),
+        }
+    }
+}
+
+/// Protocol of outbound traffic
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FirewallProtocol {
+    /// TCP connections, including streams in multiplexed connections
+    Tcp,
+    /// UDP packets, including packets relayed in TCP streams
+    Udp,
+}
+
+impl Display for FirewallProtocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            FirewallProtocol::Tcp => f.write_str("tcp"),
+            FirewallProtocol::Udp => f.write_str("udp"),
+        }
+    }
+}
+
+/// Error while parsing `FirewallProtocol` from string
+#[derive(Debug, Clone, Copy)]
+pub struct FirewallProtocolError;
+
+impl Display for FirewallProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("invalid FirewallProtocol, expecting \"tcp\" or \"udp\"")
+    }
+}
+
+impl FromStr for FirewallProtocol {
+    type Err = FirewallProtocolError;
+
+    fn from_str(s: &str) -> Result<FirewallProtocol, FirewallProtocolError> {
+        match s {
+            "tcp" => Ok(FirewallProtocol::Tcp),
+            "udp" => Ok(FirewallProtocol::Udp),
+            _ => Err(FirewallProtocolError),
+        }
+    }
+}
+
+/// A rule of the outbound firewall, empty conditions match everything
+#[derive(Debug, Clone, Default)]
+pub struct FirewallRule {
+    /// What to do with matched targets
+    pub action: FirewallAction,
+    /// Matches both protocols if not set
+    pub protocol: Option<FirewallProtocol>,
+    /// Target IP addresses
+    pub cidrs: Vec<IpNet>,
+    /// Target ports
+    pub ports: Vec<PortRange>,
+    /// Users of multi-user servers, traffic without users never matches rules with users
+    pub users: Vec<String>,
+}
+
+impl FirewallRule {
+    /// Check if traffic to `ip` (`None` for unresolved host names) and `port` is matched
+    fn is_matched(&self, protocol: FirewallProtocol, ip: Option<IpAddr>, port: u16, user: Option<&str>) -> bool {
+        if let Some(p) = self.protocol {
+            if p != protocol {
+                return false;
+            }
+        }
+
+        if !self.ports.is_empty() && !self.ports.iter().any(|r| r.0.contains(&port)) {
+            return false;
+        }
+
+        if !self.users.is_empty() {
+            match user {
+                Some(user) if self.users.iter().any(|u| u == user) => {}
+                _ => return false,
+            }
+        }
+
+        if !self.cidrs.is_empty() {
+            match ip {
+                Some(ip) if self.cidrs.iter().any(|n| n.contains(&ip)) => {}
+                _ => return false,
+            }
+        }
+
+        true
+    }
+}
+
+/// Configuration of the outbound firewall
+#[derive(Debug, Clone, Default)]
+pub struct FirewallConfig {
+    /// Rules are matched in order
+    pub rules: Vec<FirewallRule>,
+    /// Action of targets that don't match any rule
+    pub default_action: FirewallAction,
+}
+
+/// Outbound firewall, shared by all servers
+#[derive(Debug)]
+pub struct OutboundFirewall {
+    config: FirewallConfig,
+    // Resolve host names only if addresses matter
+    has_cidrs: bool,
+}
+
+impl OutboundFirewall {
+    /// Create a firewall with `config`
+    pub fn new(config: FirewallConfig) -> OutboundFirewall {
+        let has_cidrs = config.rules.iter().any(|r| !r.cidrs.is_empty());
+        OutboundFirewall { config, has_cidrs }
+    }
+
+    /// Configuration of this firewall
+    pub fn config(&self) -> &FirewallConfig {
+        &self.config
+    }
+
+    /// Check if traffic to `addr` is denied, for `user` in multi-user servers
+    ///
+    /// This function may perform a DNS resolution
+    pub async fn check_denied(
+        &self,
+        context: &Context,
+        protocol: FirewallProtocol,
+        addr: &Address,
+        user: Option<&str>,
+    ) -> bool {
+        match *addr {
+            Address::SocketAddress(ref saddr) => self.decide(protocol, Some(saddr.ip()), saddr.port(), user),
+            Address::DomainNameAddress(ref host, port) => {
+                if self.has_cidrs {
+                    if let Ok(vaddr) = context.dns_resolve(host, port).await {
+                        let mut resolved = false;
+                        for saddr in vaddr {
+                            if self.decide(protocol, Some(saddr.ip()), port, user) {
+                                return true;
+                            }
+                            resolved = true;
+                        }
+                        if resolved {
+                            return false;
+                        }
+                    }
+                }
+
+                self.decide(protocol, None, port, user)
+            }
+        }
+    }
+
+    /// `true` if denied
+    fn decide(&self, protocol: FirewallProtocol, ip: Option<IpAddr>, port: u16, user: Option<&str>) -> bool {
+        let action = self
+            .config
+            .rules
+            .iter()
+            .find(|r| r.is_matched(protocol, ip, port, user))
+            .map(|r| r.action)
+            .unwrap_or(self.config.default_action);
+        action == FirewallAction::Deny
+    }
+}
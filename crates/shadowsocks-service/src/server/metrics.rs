@@ -0,0 +1,109 @@
+//! Prometheus metrics of servers, served by `MetricsServer`
+
+use std::sync::Arc;
+
+use crate::{
+    metrics::{MetricSample, MetricType, MetricsCollector, MetricsEncoder},
+    net::{ConcurrencyKind, ConcurrencyStat, FlowStat},
+};
+
+use super::{monitor::SecurityMonitor, udp_eviction::UdpEvictionStat, Server};
+
+struct ServerMetricsSource {
+    addr: String,
+    flow_stat: Arc<FlowStat>,
+    concurrency_stat: Arc<ConcurrencyStat>,
+    security_monitor: Arc<SecurityMonitor>,
+    udp_eviction_stat: Arc<UdpEvictionStat>,
+}
+
+/// Collects traffic, connections and security events of servers
+#[derive(Default)]
+pub struct ServerMetrics {
+    servers: Vec<ServerMetricsSource>,
+}
+
+impl ServerMetrics {
+    /// Create without any servers
+    pub fn new() -> ServerMetrics {
+        ServerMetrics::default()
+    }
+
+    /// Collect metrics of `server`, labeled by its address
+    pub fn add_server(&mut self, server: &Server) {
+        self.servers.push(ServerMetricsSource {
+            addr: server.config().addr().to_string(),
+            flow_stat: server.flow_stat(),
+            concurrency_stat: server.concurrency_stat().clone(),
+            security_monitor: server.security_monitor().clone(),
+            udp_eviction_stat: server.udp_eviction_stat().clone(),
+        });
+    }
+}
+
+impl MetricsCollector for ServerMetrics {
+    fn collect(&self, encoder: &mut MetricsEncoder) {
+        let mut bytes: Vec<MetricSample> = Vec::new();
+        let mut connections: Vec<MetricSample> = Vec::new();
+        let mut accepted: Vec<MetricSample> = Vec::new();
+        let mut security_events: Vec<MetricSample> = Vec::new();
+        let mut udp_evictions: Vec<MetricSample> = Vec::new();
+
+        for server in &self.servers {
+            let labels =
+                |name: &'static str, value: &str| vec![("server", server.addr.clone()), (name, value.to_owned())];
+
+            bytes.push((labels("direction", "tx"), server.flow_stat.tx()));
+            bytes.push((labels("direction", "rx"), server.flow_stat.rx()));
+
+            for (kind, name) in [
+                (ConcurrencyKind::TcpTunnel, "tcp"),
+                (ConcurrencyKind::UdpAssociation, "udp"),
+            ] {
+                connections.push((labels("kind", name), server.concurrency_stat.current(kind) as u64));
+                accepted.push((labels("kind", name), server.concurrency_stat.acquired(kind) as u64));
+            }
+
+            let counters = server.security_monitor.counters();
+            security_events.push((labels("kind", "replayed_nonce"), counters.replayed_nonces));
+            security_events.push((labels("kind", "malformed_handshake"), counters.malformed_handshakes));
+            security_events.push((labels("kind", "clock_skew"), counters.clock_skews));
+
+            let udp_eviction_stat = &server.udp_eviction_stat;
+            udp_evictions.push((labels("reason", "expired"), udp_eviction_stat.expired()));
+            udp_evictions.push((labels("reason", "evicted"), udp_eviction_stat.evicted()));
+            udp_evictions.push((labels("reason", "refused"), udp_eviction_stat.refused()));
+        }
+
+        encoder.family(
+            "shadowsocks_server_bytes_total",
+            "Bytes sent to (tx) and received from (rx) clients",
+            MetricType::Counter,
+            &bytes,
+        );
+        encoder.family(
+            "shadowsocks_server_connections",
+            "Active TCP connections and UDP associations",
+            MetricType::Gauge,
+            &connections,
+        );
+        encoder.family(
+            "shadowsocks_server_accepted_total",
+            "Accepted TCP connections and UDP associations",
+            MetricType::Counter,
+            &accepted,
+        );
+        encoder.family(
+            "shadowsocks_server_security_events_total",
+            "Replayed nonces, malformed handshakes and timestamps too far from the local clock from clients",
+            MetricType::Counter,
+            &security_events,
+        );
+        encoder.family(
+            "shadowsocks_server_udp_evictions_total",
+            "UDP associations expired, evicted for new ones, or refused because the table was full",
+            MetricType::Counter,
+            &udp_evictions,
+        );
+    }
+}
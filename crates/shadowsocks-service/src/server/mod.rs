@@ -11,20 +11,45 @@ use std::{
 
 use futures::{future, ready};
 use log::trace;
-use shadowsocks::net::{AcceptOpts, ConnectOpts};
+use shadowsocks::{
+    net::{AcceptOpts, ConnectOpts},
+    ServerAddr,
+};
 use tokio::task::JoinHandle;
 
 use crate::{
     config::{Config, ConfigType},
     dns::build_dns_resolver,
+    net::{utils::choose_unspecified_port, RateLimiter},
+    shutdown::ShutdownHandle,
+    store::create_state_store,
+};
+
+use self::{
+    client_limit::ClientLimiter,
+    drain::DrainHandle,
+    firewall::OutboundFirewall,
+    replay_cluster::ReplayCluster,
 };
 
 pub use self::server::Server;
 
+pub mod client_limit;
 pub mod context;
+pub mod drain;
+pub mod firewall;
+#[cfg(feature = "metrics")]
+pub mod metrics;
+pub mod monitor;
+mod mux;
+pub mod replay_cluster;
+mod replay_filter;
 #[allow(clippy::module_inception)]
 pub mod server;
 mod tcprelay;
+mod traffic;
+pub mod udp_eviction;
+mod udp_over_tcp;
 mod udprelay;
 
 /// Default TCP Keep Alive timeout
@@ -32,8 +57,90 @@ mod udprelay;
 /// This is borrowed from Go's `net` library's default setting
 pub(crate) const SERVER_DEFAULT_KEEPALIVE_TIMEOUT: Duration = Duration::from_secs(15);
 
-/// Starts a shadowsocks server
+/// Shadowsocks server instance, of all servers in a configuration
+pub struct Instance {
+    vfut: Vec<ServerHandle>,
+    server_addrs: Vec<ServerAddr>,
+    shutdown: ShutdownHandle,
+    drain: DrainHandle,
+}
+
+impl Instance {
+    /// Wait until any of the servers were exited, or the instance is shut down by its `ShutdownHandle`
+    ///
+    /// Returns `Ok(())` if it is shut down, after listeners and background tasks are closed.
+    pub async fn wait_until_exit(self) -> io::Result<()> {
+        tokio::select! {
+            (res, ..) = future::select_all(self.vfut) => res,
+            _ = self.shutdown.wait() => {
+                trace!("servers are shut down");
+                Ok(())
+            }
+        }
+    }
+
+    /// Get the handle for shutting down this instance
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// Get the handle for draining servers of this instance
+    ///
+    /// Draining servers stop accepting new connections and associations, while `wait_until_exit` keeps pending
+    /// for the established ones.
+    pub fn drain_handle(&self) -> DrainHandle {
+        self.drain.clone()
+    }
+
+    /// Get listen addresses of servers, in the order of the configuration
+    ///
+    /// Ports that are 0 in the configuration are replaced by the ones chosen by the system.
+    pub fn server_addrs(&self) -> &[ServerAddr] {
+        &self.server_addrs
+    }
+}
+
+/// Builder of server instances
+///
+/// Servers are spawned on the runtime that `build` is awaited on, which could be the caller's own runtime.
+pub struct ServerBuilder {
+    config: Config,
+    shutdown: ShutdownHandle,
+}
+
+impl ServerBuilder {
+    /// Create a builder of instances of `config`
+    pub fn new(config: Config) -> ServerBuilder {
+        ServerBuilder {
+            config,
+            shutdown: ShutdownHandle::new(),
+        }
+    }
+
+    /// Set the handle for shutting down the instance, a new one is created by default
+    ///
+    /// Instances sharing the same handle are shut down together.
+    pub fn shutdown_handle(&mut self, shutdown: ShutdownHandle) {
+        self.shutdown = shutdown;
+    }
+
+    /// Create the instance and start its servers
+    pub async fn build(self) -> io::Result<Instance> {
+        create_with_shutdown(self.config, self.shutdown).await
+    }
+}
+
+/// Creates a shadowsocks server instance
+pub async fn create(config: Config) -> io::Result<Instance> {
+    ServerBuilder::new(config).build().await
+}
+
+/// Create then run a shadowsocks server
 pub async fn run(config: Config) -> io::Result<()> {
+    create(config).await?.wait_until_exit().await
+}
+
+async fn create_with_shutdown(config: Config, shutdown: ShutdownHandle) -> io::Result<Instance> {
     assert_eq!(config.config_type, ConfigType::Server);
     assert!(!config.server.is_empty());
 
@@ -56,6 +163,29 @@ pub async fn run(config: Config) -> io::Result<()> {
         }
     }
 
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    if config.io_uring {
+        use shadowsocks::net::uring;
+        if let Err(err) = uring::init(uring::DEFAULT_ENTRIES) {
+            log::warn!("io_uring initialization failed, error: {}, fallback to epoll", err);
+        }
+    }
+
+    if let Err(err) = shadowsocks::relay::buffer_pool::init(config.buffer_pool) {
+        log::warn!("buffer pool initialization failed, error: {}", err);
+    }
+
+    if config.udp_offload {
+        if let Err(err) = shadowsocks::net::udp::init_offload() {
+            log::warn!(
+                "UDP offload initialization failed, error: {}, fallback to sending datagrams one by one",
+                err
+            );
+        }
+    }
+
+    let state_store = create_state_store(&config)?;
+
     let mut servers = Vec::new();
 
     let mut connect_opts = ConnectOpts {
@@ -64,6 +194,8 @@ pub async fn run(config: Config) -> io::Result<()> {
 
         #[cfg(target_os = "android")]
         vpn_protect_path: config.outbound_vpn_protect_path,
+        #[cfg(unix)]
+        socket_protector: config.outbound_socket_protector,
 
         bind_local_addr: config.outbound_bind_addr,
         bind_interface: config.outbound_bind_interface,
@@ -91,9 +223,38 @@ pub async fn run(config: Config) -> io::Result<()> {
         .await
         .map(Arc::new);
 
+    #[cfg(feature = "acl")]
     let acl = config.acl.map(Arc::new);
 
-    for svr_cfg in config.server {
+    // Nonces accepted and traffic counters of all servers are shared with the peers
+    let replay_cluster = match config.security.replay_attack.cluster {
+        Some(ref cluster) => Some(Arc::new(
+            ReplayCluster::bind(cluster, resolver.clone(), state_store.as_ref()).await?,
+        )),
+        None => None,
+    };
+
+    // Bandwidth limit shared by all servers
+    let global_rate_limiter = config.global_rate_limit.map(|rate| Arc::new(RateLimiter::new(rate)));
+
+    let outbound_firewall = config.outbound_firewall.map(|c| Arc::new(OutboundFirewall::new(c)));
+
+    // Clients are limited across all servers
+    let client_limiter = config.client_limit.map(|c| Arc::new(ClientLimiter::new(c)));
+
+    // Draining stops accepting on all servers
+    let drain = DrainHandle::new();
+
+    let mut server_addrs = Vec::with_capacity(config.server.len());
+
+    for mut svr_cfg in config.server {
+        // Port 0 is chosen before spawning, so that the listen address is known by the caller
+        if let ServerAddr::SocketAddr(sa) = *svr_cfg.addr() {
+            let mode = svr_cfg.mode();
+            svr_cfg.set_addr(choose_unspecified_port(sa, mode.enable_tcp(), mode.enable_udp())?);
+        }
+        server_addrs.push(svr_cfg.addr().clone());
+
         let mut server = Server::new(svr_cfg);
 
         if let Some(ref r) = resolver {
@@ -102,43 +263,110 @@ pub async fn run(config: Config) -> io::Result<()> {
 
         server.set_connect_opts(connect_opts.clone());
         server.set_accept_opts(accept_opts.clone());
+        server.set_state_store(state_store.clone());
 
         if let Some(c) = config.udp_max_associations {
             server.set_udp_capacity(c);
         }
+        if let Some(ref e) = config.udp_eviction {
+            server.set_udp_eviction(e.clone());
+        }
         if let Some(d) = config.udp_timeout {
             server.set_udp_expiry_duration(d);
         }
         if let Some(ref m) = config.manager {
             server.set_manager_addr(m.addr.clone());
+            if let Some(ref key) = m.key {
+                server.set_manager_key(key.clone());
+            }
         }
 
+        #[cfg(feature = "acl")]
         if let Some(ref acl) = acl {
             server.set_acl(acl.clone());
         }
 
-        if config.ipv6_first {
-            server.set_ipv6_first(config.ipv6_first);
-        }
+        server.set_ip_strategy(config.ip_strategy);
 
         server.set_security_config(&config.security);
+        server.set_p2p_policy(config.p2p_policy.clone());
+
+        if let Some(ref replay_cluster) = replay_cluster {
+            server.set_replay_cluster(replay_cluster.clone());
+        }
+
+        if let Some(ref global_rate_limiter) = global_rate_limiter {
+            server.set_global_rate_limiter(global_rate_limiter.clone());
+        }
+
+        if let Some(ref outbound_firewall) = outbound_firewall {
+            server.set_outbound_firewall(outbound_firewall.clone());
+        }
+
+        if let Some(ref client_limiter) = client_limiter {
+            server.set_client_limiter(client_limiter.clone());
+        }
+
+        server.set_drain_handle(drain.clone());
 
         servers.push(server);
     }
 
-    if servers.len() == 1 {
-        let server = servers.pop().unwrap();
-        return server.run().await;
+    // Logged only, the check doesn't stop servers
+    #[cfg(feature = "aead-cipher-2022")]
+    if let Some(ntp_server) = config.security.clock_skew.ntp_server {
+        use shadowsocks::{
+            config::ServerType,
+            context::{Context, DEFAULT_TIMESTAMP_TOLERANCE},
+        };
+
+        let mut context = Context::new(ServerType::Server);
+        if let Some(ref r) = resolver {
+            context.set_dns_resolver(r.clone());
+        }
+        let tolerance = config
+            .security
+            .clock_skew
+            .tolerance
+            .unwrap_or(DEFAULT_TIMESTAMP_TOLERANCE);
+        tokio::spawn(crate::net::ntp::check_clock(Arc::new(context), ntp_server, tolerance));
     }
 
-    let mut vfut = Vec::with_capacity(servers.len());
+    let mut vfut = Vec::with_capacity(servers.len() + 2);
+
+    #[cfg(feature = "metrics")]
+    if let Some(metrics_addr) = config.metrics_addr {
+        use shadowsocks::{config::ServerType, context::Context};
+
+        use self::metrics::ServerMetrics;
+        use crate::metrics::MetricsServer;
+
+        let mut metrics = ServerMetrics::new();
+        for server in &servers {
+            metrics.add_server(server);
+        }
+
+        let context = Context::new_shared(ServerType::Server);
+        let server = MetricsServer::new(context, accept_opts.clone(), Arc::new(metrics));
+        vfut.push(ServerHandle(tokio::spawn(
+            async move { server.run(&metrics_addr).await },
+        )));
+    }
+
+    if let Some(replay_cluster) = replay_cluster {
+        vfut.push(ServerHandle(tokio::spawn(replay_cluster.run())));
+    }
 
     for server in servers {
         vfut.push(ServerHandle(tokio::spawn(async move { server.run().await })));
     }
 
-    let (res, ..) = future::select_all(vfut).await;
-    res
+    Ok(Instance {
+        vfut,
+        server_addrs,
+        shutdown,
+        drain,
+    })
 }
 
 struct ServerHandle(JoinHandle<io::Result<()>>);
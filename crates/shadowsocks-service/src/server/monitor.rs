@@ -0,0 +1,191 @@
+//! Server security events monitor
+//!
+//! Counts replayed nonces (IV/salt), malformed handshakes and skewed clocks detected by servers, globally and per
+//! source IP, and reports every event to an optional hook.
+
+use std::{
+    fmt::{self, Debug, Display},
+    net::{IpAddr, SocketAddr},
+    sync::{atomic::Ordering, Arc},
+    time::Duration,
+};
+
+use log::trace;
+use lru_time_cache::LruCache;
+use spin::Mutex as SpinMutex;
+
+#[cfg(not(any(target_arch = "mips", target_arch = "powerpc")))]
+type EventCounter = std::sync::atomic::AtomicU64;
+#[cfg(any(target_arch = "mips", target_arch = "powerpc"))]
+type EventCounter = std::sync::atomic::AtomicU32;
+
+/// Maximum source IPs that will be tracked
+const PEER_COUNTERS_CAPACITY: usize = 4096;
+/// Counters of a source IP will be reset if no events happened in this duration
+const PEER_COUNTERS_EXPIRY_DURATION: Duration = Duration::from_secs(60 * 60);
+
+/// Kind of security events
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SecurityEventKind {
+    /// Client sent a repeated nonce (IV/salt), which may be a replay attack
+    ReplayedNonce,
+    /// Client sent an undecryptable or malformed handshake, which may be a probe or wrong method / key
+    MalformedHandshake,
+    /// Client sent an AEAD 2022 timestamp too far from the local clock, the clock of either side is wrong
+    ClockSkew,
+}
+
+impl Display for SecurityEventKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            SecurityEventKind::ReplayedNonce => f.write_str("replayed-nonce"),
+            SecurityEventKind::MalformedHandshake => f.write_str("malformed-handshake"),
+            SecurityEventKind::ClockSkew => f.write_str("clock-skew"),
+        }
+    }
+}
+
+/// A security event detected by server
+#[derive(Debug, Clone)]
+pub struct SecurityEvent {
+    /// Kind of this event
+    pub kind: SecurityEventKind,
+    /// Address of the client
+    pub peer_addr: SocketAddr,
+    /// Address that the server is listening on
+    pub server_addr: SocketAddr,
+    /// Number of events of this `kind` from the client's IP, including this one
+    pub peer_count: u64,
+}
+
+/// Hook for receiving security events
+///
+/// Called synchronously in servers' tasks, so it shouldn't block
+pub type SecurityEventHook = Arc<dyn Fn(&SecurityEvent) + Send + Sync>;
+
+/// Counters of security events
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SecurityCounters {
+    /// Number of repeated nonces
+    pub replayed_nonces: u64,
+    /// Number of malformed handshakes
+    pub malformed_handshakes: u64,
+    /// Number of timestamps too far from the local clock
+    pub clock_skews: u64,
+}
+
+impl SecurityCounters {
+    fn incr(&mut self, kind: SecurityEventKind) -> u64 {
+        let counter = match kind {
+            SecurityEventKind::ReplayedNonce => &mut self.replayed_nonces,
+            SecurityEventKind::MalformedHandshake => &mut self.malformed_handshakes,
+            SecurityEventKind::ClockSkew => &mut self.clock_skews,
+        };
+        *counter += 1;
+        *counter
+    }
+}
+
+/// Security events monitor, could be shared between servers
+pub struct SecurityMonitor {
+    replayed_nonces: EventCounter,
+    malformed_handshakes: EventCounter,
+    clock_skews: EventCounter,
+    peer_counters: SpinMutex<LruCache<IpAddr, SecurityCounters>>,
+    hook: Option<SecurityEventHook>,
+}
+
+impl Debug for SecurityMonitor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SecurityMonitor")
+            .field("replayed_nonces", &self.replayed_nonces)
+            .field("malformed_handshakes", &self.malformed_handshakes)
+            .field("clock_skews", &self.clock_skews)
+            .field("hook", &self.hook.is_some())
+            .finish()
+    }
+}
+
+impl Default for SecurityMonitor {
+    fn default() -> Self {
+        SecurityMonitor::new()
+    }
+}
+
+impl SecurityMonitor {
+    /// Create a monitor without hook
+    pub fn new() -> SecurityMonitor {
+        SecurityMonitor {
+            replayed_nonces: EventCounter::new(0),
+            malformed_handshakes: EventCounter::new(0),
+            clock_skews: EventCounter::new(0),
+            peer_counters: SpinMutex::new(LruCache::with_expiry_duration_and_capacity(
+                PEER_COUNTERS_EXPIRY_DURATION,
+                PEER_COUNTERS_CAPACITY,
+            )),
+            hook: None,
+        }
+    }
+
+    /// Create a monitor that reports every event to `hook`
+    pub fn with_hook(hook: SecurityEventHook) -> SecurityMonitor {
+        let mut monitor = SecurityMonitor::new();
+        monitor.hook = Some(hook);
+        monitor
+    }
+
+    /// Report an event
+    pub fn report(&self, kind: SecurityEventKind, peer_addr: SocketAddr, server_addr: SocketAddr) {
+        match kind {
+            SecurityEventKind::ReplayedNonce => self.replayed_nonces.fetch_add(1, Ordering::Relaxed),
+            SecurityEventKind::MalformedHandshake => self.malformed_handshakes.fetch_add(1, Ordering::Relaxed),
+            SecurityEventKind::ClockSkew => self.clock_skews.fetch_add(1, Ordering::Relaxed),
+        };
+
+        let peer_count = {
+            let mut peer_counters = self.peer_counters.lock();
+            match peer_counters.get_mut(&peer_addr.ip()) {
+                Some(c) => c.incr(kind),
+                None => {
+                    let mut c = SecurityCounters::default();
+                    let n = c.incr(kind);
+                    peer_counters.insert(peer_addr.ip(), c);
+                    n
+                }
+            }
+        };
+
+        trace!(
+            "security event {} from {} on {}, count {}",
+            kind,
+            peer_addr,
+            server_addr,
+            peer_count
+        );
+
+        if let Some(ref hook) = self.hook {
+            let event = SecurityEvent {
+                kind,
+                peer_addr,
+                server_addr,
+                peer_count,
+            };
+            hook(&event);
+        }
+    }
+
+    /// Total counters of all clients
+    pub fn counters(&self) -> SecurityCounters {
+        SecurityCounters {
+            replayed_nonces: self.replayed_nonces.load(Ordering::Relaxed) as _,
+            malformed_handshakes: self.malformed_handshakes.load(Ordering::Relaxed) as _,
+            clock_skews: self.clock_skews.load(Ordering::Relaxed) as _,
+        }
+    }
+
+    /// Counters of a source IP
+    pub fn peer_counters(&self, ip: &IpAddr) -> SecurityCounters {
+        let peer_counters = self.peer_counters.lock();
+        peer_counters.peek(ip).copied().unwrap_or_default()
+    }
+}
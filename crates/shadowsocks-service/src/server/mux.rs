@@ -0,0 +1,184 @@
+//! Shadowsocks multiplexed streams server
+
+use std::{fmt::Display, io, net::SocketAddr, sync::Arc, time::Duration};
+
+use bytes::BytesMut;
+use log::{debug, error, trace};
+use shadowsocks::{
+    net::TcpStream as OutboundTcpStream,
+    relay::{
+        mux::{serve_connection, BoxedMuxIo, ConnectionAddr, MuxStream},
+        tcprelay::proxy_stream::ProxyServerStream,
+    },
+};
+use tokio::{
+    io::{copy_bidirectional, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    time,
+};
+
+use crate::{
+    log_fields::{self, LogField},
+    net::{p2p::is_bittorrent_stream, ConcurrencyKind},
+};
+
+use super::{context::ServiceContext, firewall::FirewallProtocol};
+
+/// Serves streams multiplexed in a client's TCP stream
+pub struct MuxRelay;
+
+impl MuxRelay {
+    /// Serve sessions of Mux.Cool on `stream` of `user_name` (in multi-user servers) until client closes it
+    pub async fn serve<S>(
+        context: Arc<ServiceContext>,
+        peer_addr: SocketAddr,
+        server_addr: SocketAddr,
+        user_name: Option<String>,
+        stream: ProxyServerStream<S>,
+        timeout: Option<Duration>,
+    ) -> io::Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let info = ConnectionAddr {
+            local_addr: server_addr,
+            peer_addr,
+        };
+
+        let user_name = user_name.map(Arc::<str>::from);
+        serve_connection(Box::new(stream) as BoxedMuxIo, info, BytesMut::new(), |session| {
+            // Sessions are connections of their own
+            log_fields::spawn_connection(
+                peer_addr,
+                serve_session(context.clone(), session, user_name.clone(), timeout),
+            );
+        })
+        .await;
+
+        debug!("mux connection from {} is closed", peer_addr);
+        Ok(())
+    }
+}
+
+/// Tunnels a session to its target
+async fn serve_session(
+    context: Arc<ServiceContext>,
+    mut session: MuxStream<ConnectionAddr>,
+    user_name: Option<Arc<str>>,
+    timeout: Option<Duration>,
+) {
+    let peer_addr = session.info().peer_addr;
+    let target_addr = match session.target() {
+        Some(a) => a.clone(),
+        None => {
+            debug!("mux session from {} without a valid target address", peer_addr);
+            return;
+        }
+    };
+
+    let _tunnel_guard = context.concurrency_stat().acquire(ConcurrencyKind::TcpTunnel, 1);
+
+    if context.check_outbound_blocked(&target_addr).await {
+        error!(
+            "tcp client {} mux outbound {} blocked by ACL rules",
+            peer_addr, target_addr
+        );
+        return;
+    }
+
+    if context
+        .check_outbound_denied(FirewallProtocol::Tcp, &target_addr, user_name.as_deref())
+        .await
+    {
+        error!(
+            "tcp client {} mux outbound {} denied by firewall rules",
+            peer_addr, target_addr
+        );
+        return;
+    }
+
+    // BitTorrent traffic could only be detected with the first packet,
+    // which have to be received before connecting to target.
+    let mut first_packet = Vec::new();
+    if !context.p2p_policy().is_allow() {
+        let mut buffer = [0u8; 8192];
+        match time::timeout(Duration::from_millis(500), session.read(&mut buffer)).await {
+            Ok(Ok(0)) | Ok(Err(..)) => return,
+            Ok(Ok(n)) => {
+                if is_bittorrent_stream(&buffer[..n]) {
+                    debug!(
+                        "tcp client {} mux outbound {} blocked, BitTorrent traffic",
+                        peer_addr, target_addr
+                    );
+                    return;
+                }
+                first_packet.extend_from_slice(&buffer[..n]);
+            }
+            Err(..) => {}
+        }
+    }
+
+    let connect_opts = context.target_connect_opts(&target_addr);
+    let connect_fut = OutboundTcpStream::connect_remote_with_opts(context.context_ref(), &target_addr, &connect_opts);
+    let connect_result = match timeout {
+        Some(d) => match time::timeout(d, connect_fut).await {
+            Ok(r) => r,
+            Err(..) => Err(io::ErrorKind::TimedOut.into()),
+        },
+        None => connect_fut.await,
+    };
+    let mut remote_stream = match connect_result {
+        Ok(s) => s,
+        Err(err) => {
+            error!(
+                "tcp tunnel {} -> {} (mux) connect failed, error: {}",
+                peer_addr, target_addr, err
+            );
+            return;
+        }
+    };
+
+    if !first_packet.is_empty() {
+        if let Err(err) = remote_stream.write_all(&first_packet).await {
+            debug!(
+                "tcp tunnel {} -> {} (mux) write failed, error: {}",
+                peer_addr, target_addr, err
+            );
+            return;
+        }
+    }
+
+    let server_addr = session.info().local_addr;
+    let fields: &[LogField] = &[("peer", &peer_addr), ("target", &target_addr), ("server", &server_addr)];
+    log_fields::with_fields(fields, || {
+        debug!("established tcp tunnel {} <-> {} (mux)", peer_addr, target_addr)
+    });
+
+    match copy_bidirectional(&mut session, &mut remote_stream).await {
+        Ok((rn, wn)) => {
+            let fields = [
+                fields,
+                &[("l2r_bytes", &rn as &(dyn Display + Sync)), ("r2l_bytes", &wn)],
+            ]
+            .concat();
+            log_fields::with_fields(&fields, || {
+                trace!(
+                    "tcp tunnel {} <-> {} (mux) closed, L2R {} bytes, R2L {} bytes",
+                    peer_addr,
+                    target_addr,
+                    rn,
+                    wn
+                )
+            });
+        }
+        Err(err) => {
+            log_fields::with_fields(fields, || {
+                trace!(
+                    "tcp tunnel {} <-> {} (mux) closed with error: {}",
+                    peer_addr,
+                    target_addr,
+                    err
+                )
+            });
+        }
+    }
+}
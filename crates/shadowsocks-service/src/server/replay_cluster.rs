@@ -0,0 +1,635 @@
+//! Sharing accepted nonces (IV/salt) and traffic counters between servers
+//!
+//! Operators running servers with the same keys behind DNS round-robin would like a connection captured by one of
+//! them not to be replayed to the others, and quotas of servers and users to be used up by the traffic of all of
+//! them. Each server of a cluster sends nonces accepted by itself to its peers in batches, and records nonces
+//! received from the peers into its own replay filter. Traffic counters of each server port and user are sent to the
+//! peers periodically, which are added to their own counters while checking quotas.
+//!
+//! Packets are encrypted with `chacha20-ietf-poly1305` and a key derived from the password of the cluster
+//!
+//! ```plain
+//! +--------+---------+----------+-----------+------+----------+----------+
+//! |  SALT  | SESSION | SEQUENCE | TIMESTAMP | TYPE |   BODY   | DATA_TAG |
+//! +--------+---------+----------+-----------+------+----------+----------+
+//! |   32   |    8    |    8     |     8     |  1   | Variable |    16    |
+//! +--------+---------+----------+-----------+------+----------+----------+
+//! ```
+//!
+//! `SESSION` is chosen randomly when the server starts, and `SEQUENCE` is increased by each packet of the session.
+//! Packets with `TIMESTAMP` (UNIX timestamp in seconds) not in `MAX_CLOCK_SKEW`, or `SEQUENCE` received before, are
+//! rejected, so packets captured couldn't be replayed.
+//!
+//! `BODY` of nonces (`TYPE` 0)
+//!
+//! ```plain
+//! +-----------+----------+-----------+----------+-----+
+//! | NONCE_LEN |  NONCE   | NONCE_LEN |  NONCE   | ... |
+//! +-----------+----------+-----------+----------+-----+
+//! |     1     | Variable |     1     | Variable |     |
+//! +-----------+----------+-----------+----------+-----+
+//! ```
+//!
+//! `BODY` of traffic counters (`TYPE` 1), `NAME` is empty for the server itself. Bytes used by `NODE` are sent, so
+//! lost packets are corrected by the next ones, and `NODE` is saved in the `StateStore` with the counters.
+//!
+//! ```plain
+//! +------+------+----------+----------+------+------+----------+----------+-----+
+//! | NODE | PORT | NAME_LEN |   NAME   | USED | PORT | NAME_LEN |   NAME   | ... |
+//! +------+------+----------+----------+------+------+----------+----------+-----+
+//! |  8   |  2   |    1     | Variable |  8   |  2   |    1     | Variable |     |
+//! +------+------+----------+----------+------+------+----------+----------+-----+
+//! ```
+
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    io,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+        Weak,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use log::{debug, error, info, trace, warn};
+use shadowsocks::{
+    config::{ServerAddr, ServerType},
+    context::{Context, NonceObserver, SharedContext},
+    crypto::{
+        v1::{openssl_bytes_to_key, random_iv_or_salt, Cipher},
+        CipherKind,
+    },
+    dns_resolver::DnsResolver,
+};
+use spin::Mutex as SpinMutex;
+use tokio::{net::UdpSocket, sync::Notify, time};
+
+use crate::{config::ReplayClusterConfig, store::StateStore};
+
+use super::context::ServiceContext;
+
+const CLUSTER_METHOD: CipherKind = CipherKind::CHACHA20_POLY1305;
+
+/// `SESSION`, `SEQUENCE`, `TIMESTAMP` and `TYPE`
+const HEADER_LEN: usize = 8 + 8 + 8 + 1;
+
+const MESSAGE_NONCES: u8 = 0;
+const MESSAGE_TRAFFIC: u8 = 1;
+
+/// Plaintext size of a packet, which shouldn't be fragmented
+const MAX_BATCH_SIZE: usize = 1200;
+
+/// Nonces are sent at least this often
+const FLUSH_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Nonces are dropped if they couldn't be sent in time
+const MAX_PENDING_SIZE: usize = 1024 * 1024;
+
+/// Peers' domain names are resolved again after this duration
+const PEER_RESOLVE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Changed traffic counters are sent this often
+const TRAFFIC_SYNC_INTERVAL: Duration = Duration::from_secs(1);
+
+/// All traffic counters are sent this often, for peers started later
+const TRAFFIC_REFRESH_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Maximum difference between `TIMESTAMP` of packets and the local clock
+const MAX_CLOCK_SKEW: Duration = Duration::from_secs(60);
+
+/// Sequences of sessions are forgotten if they haven't sent anything in this duration, after which their packets
+/// would be rejected by `TIMESTAMP`
+const SESSION_EXPIRY: Duration = Duration::from_secs(3 * 60);
+
+/// Packets received out of order in this many sequences are still accepted
+const SEQUENCE_WINDOW: u64 = 64;
+
+/// Key of the identifier of this server in `StateStore`
+const NODE_KEY: &str = "replay-cluster-node";
+
+/// Sequences received from a session of a peer
+struct SessionWindow {
+    highest: u64,
+    // Bit N for `highest - N`
+    received: u64,
+    last_seen: Instant,
+}
+
+impl SessionWindow {
+    fn new(sequence: u64, now: Instant) -> SessionWindow {
+        SessionWindow {
+            highest: sequence,
+            received: 1,
+            last_seen: now,
+        }
+    }
+
+    /// Record `sequence`, `false` if it has been received, or is too old to tell
+    fn accept(&mut self, sequence: u64, now: Instant) -> bool {
+        if sequence > self.highest {
+            let shift = sequence - self.highest;
+            self.received = if shift >= SEQUENCE_WINDOW {
+                0
+            } else {
+                self.received << shift
+            };
+            self.received |= 1;
+            self.highest = sequence;
+        } else {
+            let offset = self.highest - sequence;
+            if offset >= SEQUENCE_WINDOW || self.received & (1 << offset) != 0 {
+                return false;
+            }
+            self.received |= 1 << offset;
+        }
+        self.last_seen = now;
+        true
+    }
+}
+
+/// Bytes used by a peer, with the packet it was received from
+struct PeerTraffic {
+    session: u64,
+    sequence: u64,
+    used: u64,
+}
+
+/// A server of this instance, identified by its `server_port`
+struct ClusterServer {
+    port: u16,
+    context: Weak<ServiceContext>,
+}
+
+/// A server of the cluster, sharing nonces and traffic counters with its peers
+pub struct ReplayCluster {
+    context: SharedContext,
+    socket: UdpSocket,
+    peers: Vec<ServerAddr>,
+    key: Box<[u8]>,
+    node: u64,
+    session: u64,
+    sequence: AtomicU64,
+    pending: SpinMutex<Vec<u8>>,
+    notify: Notify,
+    servers: SpinMutex<Vec<ClusterServer>>,
+    sessions: SpinMutex<HashMap<u64, SessionWindow>>,
+    // Bytes used by each peer, of (`server_port`, user name)
+    traffic: SpinMutex<HashMap<(u16, String), HashMap<u64, PeerTraffic>>>,
+}
+
+impl ReplayCluster {
+    /// Bind `listen_addr` of `config` for receiving nonces from peers
+    ///
+    /// Identifier of this server is saved in `state_store`, so that traffic counters restored from it are not
+    /// counted twice by the peers.
+    pub async fn bind(
+        config: &ReplayClusterConfig,
+        resolver: Option<Arc<DnsResolver>>,
+        state_store: &dyn StateStore,
+    ) -> io::Result<ReplayCluster> {
+        let socket = UdpSocket::bind(config.listen_addr).await?;
+
+        let mut context = Context::new(ServerType::Server);
+        if let Some(resolver) = resolver {
+            context.set_dns_resolver(resolver);
+        }
+
+        let mut key = vec![0u8; CLUSTER_METHOD.key_len()].into_boxed_slice();
+        openssl_bytes_to_key(config.password.as_bytes(), &mut key);
+
+        let node = match state_store.load(NODE_KEY)? {
+            Some(value) if value.len() == 8 => u64::from_be_bytes(value.try_into().unwrap()),
+            _ => {
+                let node = random_u64();
+                state_store.save(NODE_KEY, &node.to_be_bytes())?;
+                node
+            }
+        };
+
+        Ok(ReplayCluster {
+            context: Arc::new(context),
+            socket,
+            peers: config.peers.clone(),
+            key,
+            node,
+            session: random_u64(),
+            sequence: AtomicU64::new(0),
+            pending: SpinMutex::new(Vec::new()),
+            notify: Notify::new(),
+            servers: SpinMutex::new(Vec::new()),
+            sessions: SpinMutex::new(HashMap::new()),
+            traffic: SpinMutex::new(HashMap::new()),
+        })
+    }
+
+    /// Record nonces received from peers into `context`, and share traffic counters of `context` as the server
+    /// listening on `port`
+    pub fn add_server(&self, port: u16, context: &Arc<ServiceContext>) {
+        self.servers.lock().push(ClusterServer {
+            port,
+            context: Arc::downgrade(context),
+        });
+    }
+
+    /// Send and receive nonces and traffic counters
+    pub async fn run(self: Arc<Self>) -> io::Result<()> {
+        info!(
+            "shadowsocks replay cluster listening on {}, node: {:016x}, peers: {}",
+            self.socket.local_addr()?,
+            self.node,
+            self.peers.len()
+        );
+
+        tokio::select! {
+            r = self.run_sender() => r,
+            r = self.run_receiver() => r,
+        }
+    }
+
+    async fn run_sender(&self) -> io::Result<()> {
+        let mut peer_addrs = Vec::new();
+        let mut resolve_time: Option<Instant> = None;
+
+        let mut sync_interval = time::interval(TRAFFIC_SYNC_INTERVAL);
+        let mut sent_traffic = HashMap::new();
+        let mut refresh_time = Instant::now();
+
+        let mut packet =
+            Vec::with_capacity(CLUSTER_METHOD.salt_len() + HEADER_LEN + MAX_BATCH_SIZE + CLUSTER_METHOD.tag_len());
+
+        loop {
+            let sync = tokio::select! {
+                _ = self.notify.notified() => false,
+                _ = time::sleep(FLUSH_INTERVAL) => false,
+                _ = sync_interval.tick() => true,
+            };
+
+            let pending = std::mem::take(&mut *self.pending.lock());
+
+            let traffic = if sync {
+                let refresh = refresh_time.elapsed() >= TRAFFIC_REFRESH_INTERVAL;
+                if refresh {
+                    sent_traffic.clear();
+                    refresh_time = Instant::now();
+                }
+                self.traffic_batches(&mut sent_traffic)
+            } else {
+                Vec::new()
+            };
+
+            if pending.is_empty() && traffic.is_empty() {
+                continue;
+            }
+
+            match resolve_time {
+                Some(t) if t.elapsed() < PEER_RESOLVE_INTERVAL => {}
+                _ => {
+                    peer_addrs = self.resolve_peers().await;
+                    resolve_time = Some(Instant::now());
+                }
+            }
+
+            for batch in split_batches(&pending) {
+                self.send_packet(MESSAGE_NONCES, batch, &mut packet, &peer_addrs).await;
+            }
+            for batch in &traffic {
+                self.send_packet(MESSAGE_TRAFFIC, batch, &mut packet, &peer_addrs).await;
+            }
+
+            trace!(
+                "sent {} bytes of nonces and {} packets of traffic counters to {} peers",
+                pending.len(),
+                traffic.len(),
+                peer_addrs.len()
+            );
+        }
+    }
+
+    async fn send_packet(&self, message: u8, body: &[u8], packet: &mut Vec<u8>, peer_addrs: &[SocketAddr]) {
+        self.encrypt_packet(message, body, packet);
+
+        for peer_addr in peer_addrs {
+            if let Err(err) = self.socket.send_to(packet, peer_addr).await {
+                debug!("failed to send packet to peer {}, error: {}", peer_addr, err);
+            }
+        }
+    }
+
+    /// Encode traffic counters changed since they were recorded in `sent` into batches
+    fn traffic_batches(&self, sent: &mut HashMap<(u16, String), u64>) -> Vec<Vec<u8>> {
+        let servers = {
+            let mut servers = self.servers.lock();
+            servers.retain(|s| s.context.strong_count() > 0);
+            servers
+                .iter()
+                .filter_map(|s| s.context.upgrade().map(|c| (s.port, c)))
+                .collect::<Vec<_>>()
+        };
+
+        let mut counters = Vec::new();
+        for (port, context) in servers {
+            let flow_stat = context.flow_stat_ref();
+            counters.push(((port, String::new()), flow_stat.tx() + flow_stat.rx()));
+            for (name, flow_stat) in context.user_flow_stat().users() {
+                if name.len() <= u8::MAX as usize {
+                    counters.push(((port, name), flow_stat.tx() + flow_stat.rx()));
+                }
+            }
+        }
+
+        let mut batches = Vec::new();
+        let mut batch = Vec::new();
+        for (key, used) in counters {
+            if sent.get(&key) == Some(&used) {
+                continue;
+            }
+
+            let (port, ref name) = key;
+            if batch.len() + 2 + 1 + name.len() + 8 > MAX_BATCH_SIZE {
+                batches.push(std::mem::take(&mut batch));
+            }
+            if batch.is_empty() {
+                batch.extend_from_slice(&self.node.to_be_bytes());
+            }
+            batch.extend_from_slice(&port.to_be_bytes());
+            batch.push(name.len() as u8);
+            batch.extend_from_slice(name.as_bytes());
+            batch.extend_from_slice(&used.to_be_bytes());
+
+            sent.insert(key, used);
+        }
+        if !batch.is_empty() {
+            batches.push(batch);
+        }
+        batches
+    }
+
+    async fn run_receiver(&self) -> io::Result<()> {
+        let mut buffer = vec![0u8; 65536];
+
+        loop {
+            let (n, peer_addr) = match self.socket.recv_from(&mut buffer).await {
+                Ok(r) => r,
+                Err(err) => {
+                    error!("replay cluster recv_from failed, error: {}", err);
+                    time::sleep(Duration::from_secs(1)).await;
+                    continue;
+                }
+            };
+
+            let data = match self.decrypt_packet(&mut buffer[..n]) {
+                Some(d) if d.len() >= HEADER_LEN => d,
+                _ => {
+                    warn!("replay cluster received an invalid packet from {}", peer_addr);
+                    continue;
+                }
+            };
+
+            let (header, body) = data.split_at(HEADER_LEN);
+            let session = u64::from_be_bytes(header[0..8].try_into().unwrap());
+            let sequence = u64::from_be_bytes(header[8..16].try_into().unwrap());
+            let timestamp = u64::from_be_bytes(header[16..24].try_into().unwrap());
+            let message = header[24];
+
+            // Sent by this server, which is one of the peers
+            if session == self.session {
+                continue;
+            }
+
+            if !self.accept_packet(session, sequence, timestamp) {
+                warn!(
+                    "replay cluster rejected a replayed or expired packet from {}, session: {:016x}, sequence: {}",
+                    peer_addr, session, sequence
+                );
+                continue;
+            }
+
+            match message {
+                MESSAGE_NONCES => self.record_nonces(body, peer_addr),
+                MESSAGE_TRAFFIC => self.record_traffic(session, sequence, body, peer_addr),
+                _ => warn!(
+                    "replay cluster received an unknown message {} from {}",
+                    message, peer_addr
+                ),
+            }
+        }
+    }
+
+    /// Check `TIMESTAMP` and `SEQUENCE` of a packet of `session`
+    fn accept_packet(&self, session: u64, sequence: u64, timestamp: u64) -> bool {
+        let now = unix_timestamp();
+        if now.abs_diff(timestamp) > MAX_CLOCK_SKEW.as_secs() {
+            return false;
+        }
+
+        let now = Instant::now();
+        let mut sessions = self.sessions.lock();
+        sessions.retain(|_, w| now.duration_since(w.last_seen) < SESSION_EXPIRY);
+        match sessions.entry(session) {
+            Entry::Occupied(mut e) => e.get_mut().accept(sequence, now),
+            Entry::Vacant(e) => {
+                e.insert(SessionWindow::new(sequence, now));
+                true
+            }
+        }
+    }
+
+    fn record_nonces(&self, body: &[u8], peer_addr: SocketAddr) {
+        let contexts = {
+            let mut servers = self.servers.lock();
+            servers.retain(|s| s.context.strong_count() > 0);
+            servers.iter().filter_map(|s| s.context.upgrade()).collect::<Vec<_>>()
+        };
+
+        let mut count = 0;
+        let mut remaining = body;
+        while let Some((&len, rest)) = remaining.split_first() {
+            let len = len as usize;
+            if len == 0 || rest.len() < len {
+                break;
+            }
+            let (nonce, rest) = rest.split_at(len);
+            for context in &contexts {
+                context.context_ref().record_nonce(nonce);
+            }
+            count += 1;
+            remaining = rest;
+        }
+
+        trace!("recorded {} nonces from peer {}", count, peer_addr);
+    }
+
+    fn record_traffic(&self, session: u64, sequence: u64, body: &[u8], peer_addr: SocketAddr) {
+        if body.len() < 8 {
+            warn!("replay cluster received malformed traffic counters from {}", peer_addr);
+            return;
+        }
+        let (node, mut remaining) = body.split_at(8);
+        let node = u64::from_be_bytes(node.try_into().unwrap());
+        if node == self.node {
+            warn!(
+                "replay cluster received traffic counters of node {:016x} from {}, which is this server",
+                node, peer_addr
+            );
+            return;
+        }
+
+        let mut changed = Vec::new();
+        {
+            let mut traffic = self.traffic.lock();
+            while !remaining.is_empty() {
+                if remaining.len() < 3 || remaining.len() < 3 + remaining[2] as usize + 8 {
+                    warn!("replay cluster received malformed traffic counters from {}", peer_addr);
+                    break;
+                }
+                let port = u16::from_be_bytes([remaining[0], remaining[1]]);
+                let name_len = remaining[2] as usize;
+                let name = String::from_utf8_lossy(&remaining[3..3 + name_len]).into_owned();
+                let used = u64::from_be_bytes(remaining[3 + name_len..3 + name_len + 8].try_into().unwrap());
+                remaining = &remaining[3 + name_len + 8..];
+
+                let peers = traffic.entry((port, name.clone())).or_default();
+                let peer = PeerTraffic {
+                    session,
+                    sequence,
+                    used,
+                };
+                match peers.entry(node) {
+                    // Counters sent before the received ones in the same session
+                    Entry::Occupied(e) if e.get().session == session && e.get().sequence > sequence => continue,
+                    Entry::Occupied(mut e) => *e.get_mut() = peer,
+                    Entry::Vacant(e) => {
+                        e.insert(peer);
+                    }
+                }
+
+                let used = peers.values().map(|p| p.used).sum::<u64>();
+                changed.push((port, name, used));
+            }
+        }
+
+        let servers = self.servers.lock();
+        for (port, name, used) in changed {
+            for server in servers.iter().filter(|s| s.port == port) {
+                if let Some(context) = server.context.upgrade() {
+                    if name.is_empty() {
+                        context.flow_stat_ref().set_peer_used(used);
+                    } else {
+                        context.user_flow_stat().user(&name).set_peer_used(used);
+                    }
+                }
+            }
+        }
+
+        trace!(
+            "recorded traffic counters of node {:016x} from peer {}",
+            node,
+            peer_addr
+        );
+    }
+
+    async fn resolve_peers(&self) -> Vec<SocketAddr> {
+        let mut peer_addrs = Vec::with_capacity(self.peers.len());
+        for peer in &self.peers {
+            match *peer {
+                ServerAddr::SocketAddr(sa) => peer_addrs.push(sa),
+                ServerAddr::DomainName(ref dname, port) => match self.context.dns_resolve(dname, port).await {
+                    Ok(addrs) => peer_addrs.extend(addrs),
+                    Err(err) => warn!("failed to resolve peer {}, error: {}", peer, err),
+                },
+            }
+        }
+        peer_addrs
+    }
+
+    fn encrypt_packet(&self, message: u8, body: &[u8], packet: &mut Vec<u8>) {
+        let salt_len = CLUSTER_METHOD.salt_len();
+
+        packet.clear();
+        packet.resize(salt_len, 0);
+        random_iv_or_salt(&mut packet[..salt_len]);
+
+        let mut cipher = Cipher::new(CLUSTER_METHOD, &self.key, &packet[..salt_len]);
+        packet.extend_from_slice(&self.session.to_be_bytes());
+        packet.extend_from_slice(&self.sequence.fetch_add(1, Ordering::Relaxed).to_be_bytes());
+        packet.extend_from_slice(&unix_timestamp().to_be_bytes());
+        packet.push(message);
+        packet.extend_from_slice(body);
+        packet.resize(packet.len() + CLUSTER_METHOD.tag_len(), 0);
+        cipher.encrypt_packet(&mut packet[salt_len..]);
+    }
+
+    fn decrypt_packet<'a>(&self, packet: &'a mut [u8]) -> Option<&'a [u8]> {
+        let salt_len = CLUSTER_METHOD.salt_len();
+        let tag_len = CLUSTER_METHOD.tag_len();
+        if packet.len() < salt_len + tag_len {
+            return None;
+        }
+
+        let (salt, data) = packet.split_at_mut(salt_len);
+        let mut cipher = Cipher::new(CLUSTER_METHOD, &self.key, salt);
+        if !cipher.decrypt_packet(data) {
+            return None;
+        }
+
+        let data_len = data.len() - tag_len;
+        Some(&data[..data_len])
+    }
+}
+
+impl NonceObserver for ReplayCluster {
+    fn nonce_accepted(&self, nonce: &[u8]) {
+        if nonce.len() > u8::MAX as usize {
+            return;
+        }
+
+        let mut pending = self.pending.lock();
+        if pending.len() + 1 + nonce.len() > MAX_PENDING_SIZE {
+            return;
+        }
+        pending.push(nonce.len() as u8);
+        pending.extend_from_slice(nonce);
+
+        if pending.len() >= MAX_BATCH_SIZE {
+            self.notify.notify_one();
+        }
+    }
+}
+
+fn random_u64() -> u64 {
+    let mut buf = [0u8; 8];
+    random_iv_or_salt(&mut buf);
+    u64::from_be_bytes(buf)
+}
+
+fn unix_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Split `pending` into batches of whole nonces, each of them fits in a packet
+fn split_batches(pending: &[u8]) -> impl Iterator<Item = &[u8]> {
+    let mut remaining = pending;
+    std::iter::from_fn(move || {
+        if remaining.is_empty() {
+            return None;
+        }
+
+        let mut end = 0;
+        while end < remaining.len() {
+            let next = end + 1 + remaining[end] as usize;
+            if next > MAX_BATCH_SIZE && end > 0 {
+                break;
+            }
+            end = next;
+        }
+
+        let (batch, rest) = remaining.split_at(end);
+        remaining = rest;
+        Some(batch)
+    })
+}
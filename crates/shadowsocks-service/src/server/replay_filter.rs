@@ -0,0 +1,76 @@
+//! Persistent replay protection of servers
+//!
+//! Nonces (IV/salt) recorded by the replay protector of a server are saved in a `StateStore` periodically, and
+//! restored when the server is started again, so connections captured before restarts couldn't be replayed.
+
+use std::{io, sync::Arc, time::Duration};
+
+use log::{trace, warn};
+use shadowsocks::context::SharedContext;
+use tokio::{task, time};
+
+use crate::store::StateStore;
+
+/// Nonces of a server, saved in `StateStore`
+pub struct ReplayFilterRecorder {
+    key: String,
+    state_store: Arc<dyn StateStore>,
+    context: SharedContext,
+    interval: Duration,
+}
+
+impl ReplayFilterRecorder {
+    /// Create a recorder of nonces of the server listening on `port`, saved every `interval`
+    pub fn new(
+        port: u16,
+        state_store: Arc<dyn StateStore>,
+        context: SharedContext,
+        interval: Duration,
+    ) -> ReplayFilterRecorder {
+        ReplayFilterRecorder {
+            key: format!("replay-filter-{}", port),
+            state_store,
+            context,
+            interval,
+        }
+    }
+
+    /// Restore nonces saved before, replacing the recorded ones
+    pub fn load(&self) -> io::Result<()> {
+        let value = match self.state_store.load(&self.key)? {
+            Some(v) => v,
+            None => return Ok(()),
+        };
+
+        self.context.import_replay_filter(&value)?;
+        trace!("restored replay filter {}, {} bytes", self.key, value.len());
+
+        Ok(())
+    }
+
+    /// Save nonces periodically if they have been changed, and never returns
+    pub async fn run(self) -> io::Result<()> {
+        let mut saved = self.context.export_replay_filter();
+
+        loop {
+            time::sleep(self.interval).await;
+
+            let value = self.context.export_replay_filter();
+            if value == saved {
+                continue;
+            }
+
+            // Filters are a few megabytes, which shouldn't be written in the runtime's threads
+            let state_store = self.state_store.clone();
+            let key = self.key.clone();
+            let (result, value) = task::spawn_blocking(move || (state_store.save(&key, &value), value))
+                .await
+                .expect("save replay filter");
+
+            match result {
+                Ok(..) => saved = value,
+                Err(err) => warn!("failed to save replay filter {}, error: {}", self.key, err),
+            }
+        }
+    }
+}
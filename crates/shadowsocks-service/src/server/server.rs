@@ -7,20 +7,38 @@ use std::{
     time::Duration,
 };
 
-use futures::{stream::FuturesUnordered, FutureExt, StreamExt};
-use log::{error, trace};
+use futures::{future, stream::FuturesUnordered, FutureExt, StreamExt};
+use log::{error, info, trace, warn};
 use shadowsocks::{
-    config::{ManagerAddr, ServerConfig},
+    config::{ManagerAddr, ServerAddr, ServerConfig},
     dns_resolver::DnsResolver,
-    net::{AcceptOpts, ConnectOpts},
-    plugin::{Plugin, PluginMode},
+    net::{AcceptOpts, ConnectOpts, IpStrategy},
+    plugin::{PluginMode, PluginSupervisor},
     ManagerClient,
 };
 use tokio::time;
 
-use crate::{acl::AccessControl, config::SecurityConfig, net::FlowStat};
+#[cfg(feature = "acl")]
+use crate::acl::AccessControl;
+use crate::{
+    config::{ReplayPersistConfig, SecurityConfig},
+    net::{ConcurrencyStat, FlowStat, P2pPolicy, RateLimiter, ServerRateLimiter, UserFlowStat},
+    store::{FileStateStore, StateStore},
+};
 
-use super::{context::ServiceContext, tcprelay::TcpServer, udprelay::UdpServer};
+use super::{
+    client_limit::ClientLimiter,
+    context::ServiceContext,
+    drain::DrainHandle,
+    firewall::OutboundFirewall,
+    monitor::SecurityMonitor,
+    replay_cluster::ReplayCluster,
+    replay_filter::ReplayFilterRecorder,
+    tcprelay::TcpServer,
+    traffic::TrafficRecorder,
+    udp_eviction::{UdpEvictionConfig, UdpEvictionStat},
+    udprelay::UdpServer,
+};
 
 /// Shadowsocks Server
 pub struct Server {
@@ -28,14 +46,22 @@ pub struct Server {
     svr_cfg: ServerConfig,
     udp_expiry_duration: Option<Duration>,
     udp_capacity: Option<usize>,
+    udp_eviction: UdpEvictionConfig,
     manager_addr: Option<ManagerAddr>,
+    manager_key: Option<String>,
     accept_opts: AcceptOpts,
+    replay_cluster: Option<Arc<ReplayCluster>>,
+    replay_persist: Option<ReplayPersistConfig>,
 }
 
 impl Server {
     /// Create a new server from configuration
     pub fn new(svr_cfg: ServerConfig) -> Server {
-        Server::with_context(Arc::new(ServiceContext::new()), svr_cfg)
+        let mut context = ServiceContext::new();
+        if let Some(rate_limit) = svr_cfg.rate_limit() {
+            context.set_rate_limiter(ServerRateLimiter::new(rate_limit.clone()));
+        }
+        Server::with_context(Arc::new(context), svr_cfg)
     }
 
     /// Create a new server with context
@@ -45,8 +71,12 @@ impl Server {
             svr_cfg,
             udp_expiry_duration: None,
             udp_capacity: None,
+            udp_eviction: UdpEvictionConfig::default(),
             manager_addr: None,
+            manager_key: None,
             accept_opts: AcceptOpts::default(),
+            replay_cluster: None,
+            replay_persist: None,
         }
     }
 
@@ -60,8 +90,28 @@ impl Server {
         self.context.flow_stat_ref()
     }
 
+    /// Get flow statistic of users, if this is a multi-user server
+    pub fn user_flow_stat(&self) -> &Arc<UserFlowStat> {
+        self.context.user_flow_stat()
+    }
+
+    /// Get concurrency statistic, including high-water marks since this server was created
+    pub fn concurrency_stat(&self) -> &Arc<ConcurrencyStat> {
+        self.context.concurrency_stat()
+    }
+
     /// Set `ConnectOpts`
-    pub fn set_connect_opts(&mut self, opts: ConnectOpts) {
+    ///
+    /// Outbound binds and TCP tuning of the server's configuration take precedence over the ones of `opts`.
+    pub fn set_connect_opts(&mut self, mut opts: ConnectOpts) {
+        self.svr_cfg.tcp_tuning().apply(&mut opts.tcp);
+        if let Some(iface) = self.svr_cfg.outbound_bind_interface() {
+            opts.bind_interface = Some(iface.to_owned());
+        }
+        if let Some(addr) = self.svr_cfg.outbound_bind_addr() {
+            opts.bind_local_addr = Some(addr);
+        }
+
         let context = Arc::get_mut(&mut self.context).expect("cannot set ConnectOpts on a shared context");
         context.set_connect_opts(opts)
     }
@@ -76,11 +126,26 @@ impl Server {
         self.udp_capacity = Some(c);
     }
 
+    /// Set which UDP association is evicted when there are `udp_capacity` associations
+    pub fn set_udp_eviction(&mut self, eviction: UdpEvictionConfig) {
+        self.udp_eviction = eviction;
+    }
+
+    /// Get counters of expired, evicted and refused UDP associations
+    pub fn udp_eviction_stat(&self) -> &Arc<UdpEvictionStat> {
+        self.context.udp_eviction_stat()
+    }
+
     /// Set manager's address to report `stat`
     pub fn set_manager_addr(&mut self, manager_addr: ManagerAddr) {
         self.manager_addr = Some(manager_addr);
     }
 
+    /// Set manager's pre-shared key for authenticating `stat`
+    pub fn set_manager_key(&mut self, manager_key: String) {
+        self.manager_key = Some(manager_key);
+    }
+
     /// Get server's configuration
     pub fn config(&self) -> &ServerConfig {
         &self.svr_cfg
@@ -93,13 +158,71 @@ impl Server {
     }
 
     /// Set access control list
+    #[cfg(feature = "acl")]
     pub fn set_acl(&mut self, acl: Arc<AccessControl>) {
         let context = Arc::get_mut(&mut self.context).expect("cannot set ACL on a shared context");
         context.set_acl(acl);
     }
 
+    /// Set storage backend of persistent states
+    pub fn set_state_store(&mut self, state_store: Arc<dyn StateStore>) {
+        let context = Arc::get_mut(&mut self.context).expect("cannot set state store on a shared context");
+        context.set_state_store(state_store);
+    }
+
+    /// Set monitor of security events, which could be shared between servers
+    pub fn set_security_monitor(&mut self, security_monitor: Arc<SecurityMonitor>) {
+        let context = Arc::get_mut(&mut self.context).expect("cannot set security monitor on a shared context");
+        context.set_security_monitor(security_monitor);
+    }
+
+    /// Get monitor of security events
+    pub fn security_monitor(&self) -> &Arc<SecurityMonitor> {
+        self.context.security_monitor()
+    }
+
+    /// Set policy of BitTorrent traffic
+    ///
+    /// `P2pPolicy::Route` is only meaningful for local servers
+    pub fn set_p2p_policy(&mut self, p2p_policy: P2pPolicy) {
+        let context = Arc::get_mut(&mut self.context).expect("cannot set P2P policy on a shared context");
+        context.set_p2p_policy(p2p_policy);
+    }
+
+    /// Also limit bandwidth of this server by `global`, which could be shared between servers
+    pub fn set_global_rate_limiter(&mut self, global: Arc<RateLimiter>) {
+        let context = Arc::get_mut(&mut self.context).expect("cannot set rate limiter on a shared context");
+        let mut rate_limiter = ServerRateLimiter::new(self.svr_cfg.rate_limit().cloned().unwrap_or_default());
+        rate_limiter.set_global(global);
+        context.set_rate_limiter(rate_limiter);
+    }
+
+    /// Set outbound firewall, which could be shared between servers
+    pub fn set_outbound_firewall(&mut self, outbound_firewall: Arc<OutboundFirewall>) {
+        let context = Arc::get_mut(&mut self.context).expect("cannot set outbound firewall on a shared context");
+        context.set_outbound_firewall(outbound_firewall);
+    }
+
+    /// Set per-client limiter, which could be shared between servers
+    pub fn set_client_limiter(&mut self, client_limiter: Arc<ClientLimiter>) {
+        let context = Arc::get_mut(&mut self.context).expect("cannot set client limiter on a shared context");
+        context.set_client_limiter(client_limiter);
+    }
+
+    /// Set drain handle, which could be shared between servers
+    ///
+    /// Draining servers stop accepting new connections and associations, established ones are kept.
+    pub fn set_drain_handle(&mut self, drain: DrainHandle) {
+        drain.add_server_stat(self.context.concurrency_stat().clone());
+        let context = Arc::get_mut(&mut self.context).expect("cannot set drain handle on a shared context");
+        context.set_drain_handle(drain);
+    }
+
     /// Set `AcceptOpts` for accepting new connections
-    pub fn set_accept_opts(&mut self, opts: AcceptOpts) {
+    ///
+    /// TCP tuning of the server's configuration takes precedence over the one of `opts`.
+    pub fn set_accept_opts(&mut self, mut opts: AcceptOpts) {
+        self.svr_cfg.tcp_tuning().apply(&mut opts.tcp);
         self.accept_opts = opts;
     }
 
@@ -109,44 +232,117 @@ impl Server {
         context.set_ipv6_first(ipv6_first);
     }
 
+    /// Set strategy of choosing among resolved IPv4 and IPv6 addresses
+    pub fn set_ip_strategy(&mut self, ip_strategy: IpStrategy) {
+        let context = Arc::get_mut(&mut self.context).expect("cannot set ip_strategy on a shared context");
+        context.set_ip_strategy(ip_strategy);
+    }
+
     /// Set security config
     pub fn set_security_config(&mut self, security: &SecurityConfig) {
         let context = Arc::get_mut(&mut self.context).expect("cannot set security on a shared context");
-        context.set_security_config(security)
+        context.set_security_config(security);
+        self.replay_persist = security.replay_attack.persist.clone();
+    }
+
+    /// Share accepted nonces (IV/salt) and traffic counters with the other servers of `replay_cluster`
+    pub fn set_replay_cluster(&mut self, replay_cluster: Arc<ReplayCluster>) {
+        let context = Arc::get_mut(&mut self.context).expect("cannot set replay cluster on a shared context");
+        context.set_nonce_observer(replay_cluster.clone());
+        self.replay_cluster = Some(replay_cluster);
     }
 
     /// Start serving
     pub async fn run(mut self) -> io::Result<()> {
+        if let Some(ref replay_cluster) = self.replay_cluster {
+            replay_cluster.add_server(self.svr_cfg.addr().port(), &self.context);
+        }
+
         let vfut = FuturesUnordered::new();
 
-        if self.svr_cfg.mode().enable_tcp() {
-            if let Some(plugin_cfg) = self.svr_cfg.plugin() {
-                let plugin = Plugin::start(plugin_cfg, self.svr_cfg.addr(), PluginMode::Server)?;
+        // Traffic counters are restored before serving any clients, so quotas are checked with the saved ones
+        let recorder = TrafficRecorder::new(
+            self.svr_cfg.addr().port(),
+            self.context.state_store().clone(),
+            self.context.flow_stat(),
+            self.context.user_flow_stat().clone(),
+        );
+        if let Err(err) = recorder.load() {
+            warn!(
+                "failed to restore traffic counters of {}, error: {}",
+                self.svr_cfg.addr(),
+                err
+            );
+        }
+        vfut.push(recorder.run().boxed());
+
+        // Nonces are restored before serving any clients, so connections captured before restart are rejected
+        if let Some(ref persist) = self.replay_persist {
+            let state_store = Arc::new(FileStateStore::new(&persist.path)?);
+            let recorder = ReplayFilterRecorder::new(
+                self.svr_cfg.addr().port(),
+                state_store,
+                self.context.context(),
+                persist.interval,
+            );
+            if let Err(err) = recorder.load() {
+                warn!(
+                    "failed to restore replay filter of {}, error: {}",
+                    self.svr_cfg.addr(),
+                    err
+                );
+            }
+            vfut.push(recorder.run().boxed());
+        }
+
+        self.context.flow_stat_ref().set_quota(self.svr_cfg.quota());
+        #[cfg(feature = "aead-cipher-2022")]
+        if let Some(user_manager) = self.svr_cfg.user_manager() {
+            for user in user_manager.users() {
+                if let Some(quota) = user.quota() {
+                    self.context.user_flow_stat().user(user.name()).set_quota(Some(quota));
+                }
+            }
+        }
+
+        if let Some(plugin_cfg) = self.svr_cfg.external_plugin() {
+            // Plugins are started if they relay TCP or UDP (SIP003u) of the server
+            let (mode, plugin_mode) = (self.svr_cfg.mode(), plugin_cfg.plugin_mode);
+            if (mode.enable_tcp() && plugin_mode.enable_tcp()) || (mode.enable_udp() && plugin_mode.enable_udp()) {
+                let plugin = PluginSupervisor::start(plugin_cfg, self.svr_cfg.addr(), PluginMode::Server)?;
                 self.svr_cfg.set_plugin_addr(plugin.local_addr().into());
+                // Exited plugins are restarted, server keeps running
                 vfut.push(
                     async move {
-                        match plugin.join().await {
-                            Ok(status) => {
-                                error!("plugin exited with status: {}", status);
-                                Ok(())
-                            }
-                            Err(err) => {
-                                error!("plugin exited with error: {}", err);
-                                Err(err)
-                            }
-                        }
+                        plugin.run().await;
+                        Ok(())
                     }
                     .boxed(),
                 );
             }
+        }
 
-            let tcp_fut = self.run_tcp_server().boxed();
-            vfut.push(tcp_fut);
+        // Servers with port hopping or additional addresses listen on every port of them, sharing the same context.
+        // Every address is listened on by `listeners` sockets, load balanced by SO_REUSEPORT.
+        let listen_configs = self.listen_configs();
+        let listeners = self.svr_cfg.listeners().max(1);
+
+        if self.svr_cfg.mode().enable_tcp() {
+            for svr_cfg in listen_configs.iter() {
+                for _ in 0..listeners {
+                    let tcp_fut = self.run_tcp_server(svr_cfg.clone()).boxed();
+                    vfut.push(tcp_fut);
+                }
+            }
         }
 
         if self.svr_cfg.mode().enable_udp() {
-            let udp_fut = self.run_udp_server().boxed();
-            vfut.push(udp_fut);
+            for svr_cfg in listen_configs.iter() {
+                for _ in 0..listeners {
+                    let udp_fut = self.run_udp_server(svr_cfg.clone()).boxed();
+                    vfut.push(udp_fut);
+                }
+            }
         }
 
         if self.manager_addr.is_some() {
@@ -163,19 +359,55 @@ impl Server {
         Err(err)
     }
 
-    async fn run_tcp_server(&self) -> io::Result<()> {
-        let server = TcpServer::new(self.context.clone(), self.accept_opts.clone());
-        server.run(&self.svr_cfg).await
+    fn listen_configs(&self) -> Vec<ServerConfig> {
+        let with_addr = |addr: ServerAddr| {
+            let mut svr_cfg = self.svr_cfg.clone();
+            svr_cfg.set_addr(addr);
+            svr_cfg
+        };
+
+        let mut listen_configs = match self.svr_cfg.port_hopping() {
+            Some(port_hopping) => port_hopping
+                .ports()
+                .map(|port| with_addr(self.svr_cfg.addr().with_port(port)))
+                .collect(),
+            None => vec![self.svr_cfg.clone()],
+        };
+
+        for listen_addr in self.svr_cfg.listen_addrs() {
+            listen_configs.extend(listen_addr.socket_addrs().map(|addr| with_addr(ServerAddr::from(addr))));
+        }
+
+        listen_configs
+    }
+
+    fn listen_accept_opts(&self) -> AcceptOpts {
+        let mut accept_opts = self.accept_opts.clone();
+        accept_opts.reuse_port = self.svr_cfg.listeners() > 1;
+        accept_opts
+    }
+
+    async fn run_tcp_server(&self, svr_cfg: ServerConfig) -> io::Result<()> {
+        let server = TcpServer::new(self.context.clone(), self.listen_accept_opts());
+        tokio::select! {
+            res = server.run(&svr_cfg) => res,
+            _ = self.context.wait_draining() => {
+                // Listener is closed, spawned connections are kept relaying
+                info!("tcp server {} stopped accepting connections, draining", svr_cfg.addr());
+                future::pending().await
+            }
+        }
     }
 
-    async fn run_udp_server(&self) -> io::Result<()> {
+    async fn run_udp_server(&self, svr_cfg: ServerConfig) -> io::Result<()> {
         let server = UdpServer::new(
             self.context.clone(),
             self.udp_expiry_duration,
             self.udp_capacity,
-            self.accept_opts.clone(),
+            self.udp_eviction.clone(),
+            self.listen_accept_opts(),
         );
-        server.run(&self.svr_cfg).await
+        server.run(&svr_cfg).await
     }
 
     async fn run_manager_report(&self) -> io::Result<()> {
@@ -195,6 +427,10 @@ impl Server {
                 Ok(mut client) => {
                     use shadowsocks::manager::protocol::StatRequest;
 
+                    if let Some(ref key) = self.manager_key {
+                        client.set_key(key.as_bytes());
+                    }
+
                     let mut stat = HashMap::new();
                     let flow = self.flow_stat_ref();
                     stat.insert(self.svr_cfg.addr().port(), flow.tx() + flow.rx());
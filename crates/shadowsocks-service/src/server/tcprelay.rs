@@ -1,6 +1,7 @@
 //! Shadowsocks TCP server
 
 use std::{
+    fmt::Display,
     future::Future,
     io::{self, ErrorKind},
     net::SocketAddr,
@@ -9,25 +10,49 @@ use std::{
 };
 
 use log::{debug, error, info, trace, warn};
+#[cfg(feature = "transport-grpc")]
+use shadowsocks::net::grpc::{GrpcConfig, GrpcListener};
+#[cfg(feature = "transport-obfs")]
+use shadowsocks::net::obfs::{ObfsConfig, ObfsListener};
+#[cfg(feature = "transport-quic")]
+use shadowsocks::net::quic::{QuicConfig, QuicListener};
+#[cfg(feature = "transport-shadow-tls")]
+use shadowsocks::net::shadow_tls::{ShadowTlsConfig, ShadowTlsListener};
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+use shadowsocks::net::uring::{self, UringTcpStream};
+#[cfg(feature = "transport-websocket")]
+use shadowsocks::net::websocket::{WebSocketConfig, WebSocketListener};
 use shadowsocks::{
-    crypto::v1::CipherKind,
+    context::{ClockSkewError, ReplayedNonceError},
     net::{AcceptOpts, TcpStream as OutboundTcpStream},
     relay::{
+        buffer_pool::RelayBufferSizes,
+        mux::is_mux_address,
         socks5::{Address, Error as Socks5Error},
-        tcprelay::{utils::copy_encrypted_bidirectional, ProxyServerStream},
+        tcprelay::{utils::copy_encrypted_bidirectional_with_pools, ProxyServerStream},
+        udp_over_tcp::is_udp_over_tcp_address,
     },
     ProxyListener,
     ServerConfig,
 };
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
+    io::{copy_bidirectional, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
     net::TcpStream as TokioTcpStream,
     time,
 };
 
-use crate::net::{utils::ignore_until_end, MonProxyStream};
+use crate::{
+    log_fields::{self, LogField},
+    net::{p2p::is_bittorrent_stream, utils::ignore_until_end, ConcurrencyKind, MonProxyStream},
+};
 
-use super::context::ServiceContext;
+use super::{
+    context::ServiceContext,
+    firewall::FirewallProtocol,
+    monitor::SecurityEventKind,
+    mux::MuxRelay,
+    udp_over_tcp::UdpOverTcpRelay,
+};
 
 pub struct TcpServer {
     context: Arc<ServiceContext>,
@@ -39,27 +64,76 @@ impl TcpServer {
         TcpServer { context, accept_opts }
     }
 
-    pub async fn run(self, svr_cfg: &ServerConfig) -> io::Result<()> {
-        let listener = ProxyListener::bind_with_opts(self.context.context(), svr_cfg, self.accept_opts).await?;
+    pub async fn run(mut self, svr_cfg: &ServerConfig) -> io::Result<()> {
+        if svr_cfg.mptcp() {
+            self.accept_opts.tcp.mptcp = true;
+        }
+
+        #[cfg(feature = "transport-websocket")]
+        if let Some(ws) = svr_cfg.websocket() {
+            return self.run_websocket(svr_cfg, ws).await;
+        }
+        #[cfg(feature = "transport-obfs")]
+        if let Some(obfs) = svr_cfg.obfs() {
+            return self.run_obfs(svr_cfg, obfs).await;
+        }
+        #[cfg(feature = "transport-shadow-tls")]
+        if let Some(st) = svr_cfg.shadow_tls() {
+            return self.run_shadow_tls(svr_cfg, st).await;
+        }
+        #[cfg(feature = "transport-quic")]
+        if let Some(quic) = svr_cfg.quic() {
+            return self.run_quic(svr_cfg, quic).await;
+        }
+        #[cfg(feature = "transport-grpc")]
+        if let Some(grpc) = svr_cfg.grpc() {
+            return self.run_grpc(svr_cfg, grpc).await;
+        }
+
+        let listener = ProxyListener::bind_with_opts(self.context.context(), svr_cfg, self.accept_opts.clone()).await?;
+        let server_addr = listener.local_addr()?;
 
         info!(
             "shadowsocks tcp server listening on {}, inbound address {}",
-            listener.local_addr().expect("listener.local_addr"),
+            server_addr,
             svr_cfg.addr()
         );
 
+        #[cfg(all(feature = "io-uring", target_os = "linux"))]
+        if uring::is_enabled() {
+            return self
+                .run_accept(&listener, svr_cfg, server_addr, UringTcpStream::new)
+                .await;
+        }
+
+        self.run_accept(&listener, svr_cfg, server_addr, |s| s).await
+    }
+
+    async fn run_accept<F, S>(
+        &self,
+        listener: &ProxyListener,
+        svr_cfg: &ServerConfig,
+        server_addr: SocketAddr,
+        map_fn: F,
+    ) -> io::Result<()>
+    where
+        F: Fn(TokioTcpStream) -> S,
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
         loop {
             let flow_stat = self.context.flow_stat();
 
-            let (local_stream, peer_addr) =
-                match listener.accept_map(|s| MonProxyStream::from_stream(s, flow_stat)).await {
-                    Ok(s) => s,
-                    Err(err) => {
-                        error!("tcp server accept failed with error: {}", err);
-                        time::sleep(Duration::from_secs(1)).await;
-                        continue;
-                    }
-                };
+            let (local_stream, peer_addr) = match listener
+                .accept_map(|s| MonProxyStream::from_stream(map_fn(s), flow_stat))
+                .await
+            {
+                Ok(s) => s,
+                Err(err) => {
+                    error!("tcp server accept failed with error: {}", err);
+                    time::sleep(Duration::from_secs(1)).await;
+                    continue;
+                }
+            };
 
             if self.context.check_client_blocked(&peer_addr) {
                 warn!("access denied from {} by ACL rules", peer_addr);
@@ -68,19 +142,230 @@ impl TcpServer {
 
             let client = TcpServerClient {
                 context: self.context.clone(),
-                method: svr_cfg.method(),
                 peer_addr,
+                server_addr,
                 stream: local_stream,
                 timeout: svr_cfg.timeout(),
+                udp_over_tcp: svr_cfg.udp_over_tcp(),
+                mux: svr_cfg.mux().is_some(),
+                fallback: svr_cfg.fallback().cloned(),
+                relay_buffer_sizes: svr_cfg.relay_buffer_sizes(),
             };
 
-            tokio::spawn(async move {
-                if let Err(err) = client.serve().await {
-                    debug!("tcp server stream aborted with error: {}", err);
+            self.spawn_client(client);
+        }
+    }
+
+    #[cfg(feature = "transport-websocket")]
+    async fn run_websocket(self, svr_cfg: &ServerConfig, ws: &WebSocketConfig) -> io::Result<()> {
+        let mut listener =
+            WebSocketListener::bind_with_opts(self.context.context_ref(), svr_cfg.addr(), ws, self.accept_opts.clone())
+                .await?;
+        let server_addr = listener.local_addr()?;
+
+        info!(
+            "shadowsocks tcp server listening on {} with websocket path {}{}, inbound address {}",
+            server_addr,
+            ws.path(),
+            if ws.tls() { " over tls" } else { "" },
+            svr_cfg.addr()
+        );
+
+        loop {
+            let (stream, peer_addr) = listener.accept().await?;
+            self.spawn_transport_client(svr_cfg, server_addr, peer_addr, stream);
+        }
+    }
+
+    #[cfg(feature = "transport-obfs")]
+    async fn run_obfs(self, svr_cfg: &ServerConfig, obfs: &ObfsConfig) -> io::Result<()> {
+        let listener = ObfsListener::bind_with_opts(
+            self.context.context_ref(),
+            svr_cfg.addr(),
+            obfs,
+            self.accept_opts.clone(),
+        )
+        .await?;
+        let server_addr = listener.local_addr()?;
+
+        info!(
+            "shadowsocks tcp server listening on {} with obfs {}, inbound address {}",
+            server_addr,
+            obfs.mode(),
+            svr_cfg.addr()
+        );
+
+        loop {
+            let (stream, peer_addr) = match listener.accept().await {
+                Ok(s) => s,
+                Err(err) => {
+                    error!("tcp server accept failed with error: {}", err);
+                    time::sleep(Duration::from_secs(1)).await;
+                    continue;
                 }
-            });
+            };
+            self.spawn_transport_client(svr_cfg, server_addr, peer_addr, stream);
         }
     }
+
+    #[cfg(feature = "transport-shadow-tls")]
+    async fn run_shadow_tls(self, svr_cfg: &ServerConfig, st: &ShadowTlsConfig) -> io::Result<()> {
+        let mut listener = ShadowTlsListener::bind_with_opts(
+            self.context.context(),
+            svr_cfg.addr(),
+            st,
+            self.accept_opts.clone(),
+            self.context.connect_opts_ref().clone(),
+        )
+        .await?;
+        let server_addr = listener.local_addr()?;
+
+        info!(
+            "shadowsocks tcp server listening on {} with shadow-tls sni {}, inbound address {}",
+            server_addr,
+            st.sni(),
+            svr_cfg.addr()
+        );
+
+        loop {
+            let (stream, peer_addr) = listener.accept().await?;
+            self.spawn_transport_client(svr_cfg, server_addr, peer_addr, stream);
+        }
+    }
+
+    #[cfg(feature = "transport-quic")]
+    async fn run_quic(self, svr_cfg: &ServerConfig, quic: &QuicConfig) -> io::Result<()> {
+        let mut listener = QuicListener::bind_with_opts(
+            self.context.context_ref(),
+            svr_cfg.addr(),
+            quic,
+            self.accept_opts.clone(),
+        )
+        .await?;
+        let server_addr = listener.local_addr()?;
+
+        info!(
+            "shadowsocks tcp server listening on {} with quic (udp), inbound address {}",
+            server_addr,
+            svr_cfg.addr()
+        );
+
+        loop {
+            let (stream, peer_addr) = listener.accept().await?;
+            self.spawn_transport_client(svr_cfg, server_addr, peer_addr, stream);
+        }
+    }
+
+    #[cfg(feature = "transport-grpc")]
+    async fn run_grpc(self, svr_cfg: &ServerConfig, grpc: &GrpcConfig) -> io::Result<()> {
+        let mut listener = GrpcListener::bind_with_opts(
+            self.context.context_ref(),
+            svr_cfg.addr(),
+            grpc,
+            self.accept_opts.clone(),
+        )
+        .await?;
+        let server_addr = listener.local_addr()?;
+
+        info!(
+            "shadowsocks tcp server listening on {} with grpc service {}{}, inbound address {}",
+            server_addr,
+            grpc.service_name(),
+            if grpc.tls() { " over tls" } else { "" },
+            svr_cfg.addr()
+        );
+
+        loop {
+            let (stream, peer_addr) = listener.accept().await?;
+            self.spawn_transport_client(svr_cfg, server_addr, peer_addr, stream);
+        }
+    }
+
+    /// Serves a stream accepted by listeners of transports
+    #[cfg(any(
+        feature = "transport-websocket",
+        feature = "transport-obfs",
+        feature = "transport-shadow-tls",
+        feature = "transport-quic",
+        feature = "transport-grpc"
+    ))]
+    fn spawn_transport_client<S>(
+        &self,
+        svr_cfg: &ServerConfig,
+        server_addr: SocketAddr,
+        peer_addr: SocketAddr,
+        stream: S,
+    ) where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        if self.context.check_client_blocked(&peer_addr) {
+            warn!("access denied from {} by ACL rules", peer_addr);
+            return;
+        }
+
+        let stream = MonProxyStream::from_stream(stream, self.context.flow_stat());
+        #[cfg(feature = "aead-cipher-2022")]
+        #[cfg_attr(not(feature = "stream-padding"), allow(unused_mut))]
+        let mut stream = ProxyServerStream::from_stream_with_user_manager(
+            self.context.context(),
+            stream,
+            svr_cfg.method(),
+            svr_cfg.key(),
+            svr_cfg.user_manager().cloned(),
+        );
+        #[cfg(not(feature = "aead-cipher-2022"))]
+        #[cfg_attr(not(feature = "stream-padding"), allow(unused_mut))]
+        let mut stream =
+            ProxyServerStream::from_stream(self.context.context(), stream, svr_cfg.method(), svr_cfg.key());
+        #[cfg(feature = "stream-padding")]
+        if let Some(padding) = svr_cfg.padding() {
+            stream.set_padding(padding.clone());
+        }
+
+        let client = TcpServerClient {
+            context: self.context.clone(),
+            peer_addr,
+            server_addr,
+            stream,
+            timeout: svr_cfg.timeout(),
+            udp_over_tcp: svr_cfg.udp_over_tcp(),
+            mux: svr_cfg.mux().is_some(),
+            fallback: svr_cfg.fallback().cloned(),
+            relay_buffer_sizes: svr_cfg.relay_buffer_sizes(),
+        };
+
+        self.spawn_client(client);
+    }
+
+    fn spawn_client<S>(&self, client: TcpServerClient<S>)
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let client_guard = match self
+            .context
+            .acquire_client_slot(ConcurrencyKind::TcpTunnel, &client.peer_addr)
+        {
+            Some(g) => g,
+            None => {
+                warn!("tcp client {} refused, too many connections", client.peer_addr);
+                return;
+            }
+        };
+
+        log_fields::spawn_connection(client.peer_addr, async move {
+            let peer_addr = client.peer_addr;
+            tokio::select! {
+                result = client.serve() => {
+                    if let Err(err) = result {
+                        debug!("tcp server stream aborted with error: {}", err);
+                    }
+                }
+                _ = client_guard.evicted() => {
+                    debug!("tcp client {} closed, evicted by a newer connection", peer_addr);
+                }
+            }
+        });
+    }
 }
 
 #[inline]
@@ -97,16 +382,36 @@ where
     }
 }
 
-struct TcpServerClient {
+struct TcpServerClient<S> {
     context: Arc<ServiceContext>,
-    method: CipherKind,
     peer_addr: SocketAddr,
-    stream: ProxyServerStream<MonProxyStream<TokioTcpStream>>,
+    server_addr: SocketAddr,
+    stream: ProxyServerStream<MonProxyStream<S>>,
     timeout: Option<Duration>,
+    udp_over_tcp: bool,
+    mux: bool,
+    fallback: Option<Address>,
+    relay_buffer_sizes: RelayBufferSizes,
 }
 
-impl TcpServerClient {
+impl<S> TcpServerClient<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
     async fn serve(mut self) -> io::Result<()> {
+        if self.context.check_quota_exceeded(None) {
+            debug!("tcp client {} closed, traffic quota exceeded", self.peer_addr);
+            return Ok(());
+        }
+
+        let rate_limiters = self.context.rate_limiter().limiters(&self.peer_addr, None);
+        self.stream.get_mut().set_rate_limiters(rate_limiters);
+
+        // Bytes of the handshake are kept for relaying to the fallback if it is invalid
+        if self.fallback.is_some() {
+            self.stream.get_mut().start_recording();
+        }
+
         let target_addr = match Address::read_from(&mut self.stream).await {
             Ok(a) => a,
             Err(Socks5Error::IoError(ref err)) if err.kind() == ErrorKind::UnexpectedEof => {
@@ -114,6 +419,10 @@ impl TcpServerClient {
                     "handshake failed, received EOF before a complete target Address, peer: {}",
                     self.peer_addr
                 );
+
+                if let Some(fallback) = self.fallback.take() {
+                    return self.serve_fallback(fallback).await;
+                }
                 return Ok(());
             }
             Err(err) => {
@@ -125,6 +434,21 @@ impl TcpServerClient {
                     self.peer_addr, err
                 );
 
+                let kind = match err {
+                    Socks5Error::IoError(ref err) if ReplayedNonceError::is_replayed_nonce(err) => {
+                        SecurityEventKind::ReplayedNonce
+                    }
+                    Socks5Error::IoError(ref err) if ClockSkewError::is_clock_skew(err) => SecurityEventKind::ClockSkew,
+                    _ => SecurityEventKind::MalformedHandshake,
+                };
+                self.context
+                    .security_monitor()
+                    .report(kind, self.peer_addr, self.server_addr);
+
+                if let Some(fallback) = self.fallback.take() {
+                    return self.serve_fallback(fallback).await;
+                }
+
                 // Unwrap and get the plain stream.
                 // Otherwise it will keep reporting decryption error before reaching EOF.
                 //
@@ -143,12 +467,82 @@ impl TcpServerClient {
             }
         };
 
+        self.stream.get_mut().take_recorded();
+
+        if self.stream.nonce_replayed() {
+            self.context
+                .security_monitor()
+                .report(SecurityEventKind::ReplayedNonce, self.peer_addr, self.server_addr);
+        }
+
+        // Name of the user, in multi-user servers
+        #[cfg(feature = "aead-cipher-2022")]
+        let user_name = self.stream.user().map(|u| u.name().to_owned());
+        #[cfg(not(feature = "aead-cipher-2022"))]
+        let user_name: Option<String> = None;
+
+        if let Some(ref user_name) = user_name {
+            trace!("tcp client {} is user {}", self.peer_addr, user_name);
+            if self.context.check_quota_exceeded(Some(user_name)) {
+                debug!(
+                    "tcp client {} closed, traffic quota of user {} exceeded",
+                    self.peer_addr, user_name
+                );
+                return Ok(());
+            }
+
+            let user_flow_stat = self.context.user_flow_stat().user(user_name);
+            self.stream.get_mut().set_user_flow_stat(user_flow_stat);
+
+            let rate_limiters = self.context.rate_limiter().limiters(&self.peer_addr, Some(user_name));
+            self.stream.get_mut().set_rate_limiters(rate_limiters);
+        }
+
+        if is_udp_over_tcp_address(&target_addr) {
+            if !self.udp_over_tcp {
+                warn!(
+                    "tcp client {} requested udp-over-tcp, which is not enabled for this server",
+                    self.peer_addr
+                );
+                return Ok(());
+            }
+
+            trace!(
+                "accepted tcp client connection {}, serving udp-over-tcp",
+                self.peer_addr
+            );
+            return UdpOverTcpRelay::serve(self.context, self.peer_addr, user_name, self.stream).await;
+        }
+
+        if is_mux_address(&target_addr) {
+            if !self.mux {
+                warn!(
+                    "tcp client {} requested mux, which is not enabled for this server",
+                    self.peer_addr
+                );
+                return Ok(());
+            }
+
+            trace!("accepted tcp client connection {}, serving mux", self.peer_addr);
+            return MuxRelay::serve(
+                self.context,
+                self.peer_addr,
+                self.server_addr,
+                user_name,
+                self.stream,
+                self.timeout,
+            )
+            .await;
+        }
+
         trace!(
             "accepted tcp client connection {}, establishing tunnel to {}",
             self.peer_addr,
             target_addr
         );
 
+        let _tunnel_guard = self.context.concurrency_stat().acquire(ConcurrencyKind::TcpTunnel, 1);
+
         if self.context.check_outbound_blocked(&target_addr).await {
             error!(
                 "tcp client {} outbound {} blocked by ACL rules",
@@ -157,13 +551,44 @@ impl TcpServerClient {
             return Ok(());
         }
 
+        if self
+            .context
+            .check_outbound_denied(FirewallProtocol::Tcp, &target_addr, user_name.as_deref())
+            .await
+        {
+            error!(
+                "tcp client {} outbound {} denied by firewall rules",
+                self.peer_addr, target_addr
+            );
+            return Ok(());
+        }
+
+        // BitTorrent traffic could only be detected with the first packet,
+        // which have to be received before connecting to target.
+        let mut first_packet = None;
+        if !self.context.p2p_policy().is_allow() {
+            let mut buffer = [0u8; 8192];
+            match time::timeout(Duration::from_millis(500), self.stream.read(&mut buffer)).await {
+                Ok(Ok(0)) => return Ok(()),
+                Ok(Ok(n)) => {
+                    if is_bittorrent_stream(&buffer[..n]) {
+                        debug!(
+                            "tcp client {} outbound {} blocked, BitTorrent traffic",
+                            self.peer_addr, target_addr
+                        );
+                        return Ok(());
+                    }
+                    first_packet = Some(buffer[..n].to_vec());
+                }
+                Ok(Err(err)) => return Err(err),
+                Err(..) => first_packet = Some(Vec::new()),
+            }
+        }
+
+        let connect_opts = self.context.target_connect_opts(&target_addr);
         let mut remote_stream = match timeout_fut(
             self.timeout,
-            OutboundTcpStream::connect_remote_with_opts(
-                self.context.context_ref(),
-                &target_addr,
-                self.context.connect_opts_ref(),
-            ),
+            OutboundTcpStream::connect_remote_with_opts(self.context.context_ref(), &target_addr, &connect_opts),
         )
         .await
         {
@@ -182,7 +607,14 @@ impl TcpServerClient {
         // Protocols like FTP, clients will wait for servers to send Welcome Message without sending anything.
         //
         // Wait at most 500ms, and then sends handshake packet to remote servers.
-        if self.context.connect_opts_ref().tcp.fastopen {
+        if let Some(first_packet) = first_packet {
+            if first_packet.is_empty() {
+                // Already waited. Send handshake to server if it is TFO.
+                timeout_fut(self.timeout, remote_stream.write(&[])).await?;
+            } else {
+                timeout_fut(self.timeout, remote_stream.write_all(&first_packet)).await?;
+            }
+        } else if self.context.connect_opts_ref().tcp.fastopen {
             let mut buffer = [0u8; 8192];
             match time::timeout(Duration::from_millis(500), self.stream.read(&mut buffer)).await {
                 Ok(Ok(0)) => {
@@ -207,28 +639,107 @@ impl TcpServerClient {
             }
         }
 
+        let fields: &[LogField] = &[
+            ("peer", &self.peer_addr),
+            ("target", &target_addr),
+            ("server", &self.server_addr),
+        ];
+        log_fields::with_fields(fields, || {
+            debug!(
+                "established tcp tunnel {} <-> {} with {:?}",
+                self.peer_addr,
+                target_addr,
+                self.context.connect_opts_ref()
+            )
+        });
+
+        match copy_encrypted_bidirectional_with_pools(
+            &mut self.stream,
+            &mut remote_stream,
+            self.relay_buffer_sizes.read_pool(),
+            self.relay_buffer_sizes.write_pool(),
+        )
+        .await
+        {
+            Ok((rn, wn)) => {
+                let fields = [
+                    fields,
+                    &[("l2r_bytes", &rn as &(dyn Display + Sync)), ("r2l_bytes", &wn)],
+                ]
+                .concat();
+                log_fields::with_fields(&fields, || {
+                    trace!(
+                        "tcp tunnel {} <-> {} closed, L2R {} bytes, R2L {} bytes",
+                        self.peer_addr,
+                        target_addr,
+                        rn,
+                        wn
+                    )
+                });
+            }
+            Err(err) => {
+                log_fields::with_fields(fields, || {
+                    trace!(
+                        "tcp tunnel {} <-> {} closed with error: {}",
+                        self.peer_addr,
+                        target_addr,
+                        err
+                    )
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Relays the connection with an invalid handshake to `fallback`, including bytes received in the handshake,
+    /// so it looks like an ordinary connection of the fallback service
+    async fn serve_fallback(self, fallback: Address) -> io::Result<()> {
+        // Raw bytes, which are not decrypted
+        let mut stream = self.stream.into_inner();
+        let received = stream.take_recorded();
+
+        let connect_opts = self.context.target_connect_opts(&fallback);
+        let mut remote_stream = match timeout_fut(
+            self.timeout,
+            OutboundTcpStream::connect_remote_with_opts(self.context.context_ref(), &fallback, &connect_opts),
+        )
+        .await
+        {
+            Ok(s) => s,
+            Err(err) => {
+                error!(
+                    "tcp fallback {} -> {} connect failed, error: {}",
+                    self.peer_addr, fallback, err
+                );
+                return Err(err);
+            }
+        };
+
         debug!(
-            "established tcp tunnel {} <-> {} with {:?}",
+            "relaying tcp client {} to fallback {} with {} bytes received",
             self.peer_addr,
-            target_addr,
-            self.context.connect_opts_ref()
+            fallback,
+            received.len()
         );
 
-        match copy_encrypted_bidirectional(self.method, &mut self.stream, &mut remote_stream).await {
+        timeout_fut(self.timeout, remote_stream.write_all(&received)).await?;
+
+        match copy_bidirectional(&mut stream, &mut remote_stream).await {
             Ok((rn, wn)) => {
                 trace!(
-                    "tcp tunnel {} <-> {} closed, L2R {} bytes, R2L {} bytes",
+                    "tcp fallback {} <-> {} closed, L2R {} bytes, R2L {} bytes",
                     self.peer_addr,
-                    target_addr,
+                    fallback,
                     rn,
                     wn
                 );
             }
             Err(err) => {
                 trace!(
-                    "tcp tunnel {} <-> {} closed with error: {}",
+                    "tcp fallback {} <-> {} closed with error: {}",
                     self.peer_addr,
-                    target_addr,
+                    fallback,
                     err
                 );
             }
@@ -0,0 +1,111 @@
+//! Persistent traffic counters of servers
+//!
+//! Transmitted and received bytes of a server and each of its users are saved in the server's `StateStore`
+//! periodically, and restored when the server is started again, so that quotas wouldn't be reset by restarts.
+
+use std::{
+    fmt::Write,
+    io::{self, ErrorKind},
+    sync::Arc,
+    time::Duration,
+};
+
+use log::{trace, warn};
+use tokio::time;
+
+use crate::{
+    net::{FlowStat, UserFlowStat},
+    store::StateStore,
+};
+
+/// Interval of saving traffic counters to `StateStore`
+const TRAFFIC_SAVE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Traffic counters of a server, saved in `StateStore`
+pub struct TrafficRecorder {
+    key: String,
+    state_store: Arc<dyn StateStore>,
+    flow_stat: Arc<FlowStat>,
+    user_flow_stat: Arc<UserFlowStat>,
+}
+
+impl TrafficRecorder {
+    /// Create a recorder of counters of the server listening on `port`
+    pub fn new(
+        port: u16,
+        state_store: Arc<dyn StateStore>,
+        flow_stat: Arc<FlowStat>,
+        user_flow_stat: Arc<UserFlowStat>,
+    ) -> TrafficRecorder {
+        TrafficRecorder {
+            key: format!("traffic-{}", port),
+            state_store,
+            flow_stat,
+            user_flow_stat,
+        }
+    }
+
+    /// Restore counters saved before, adding to the current counters
+    ///
+    /// Counters are saved as lines of `TX RX` of the server, followed by `TX RX NAME` of each user
+    pub fn load(&self) -> io::Result<()> {
+        let value = match self.state_store.load(&self.key)? {
+            Some(v) => v,
+            None => return Ok(()),
+        };
+
+        let value = String::from_utf8_lossy(&value);
+        for (i, line) in value.lines().enumerate() {
+            let mut parts = line.splitn(3, ' ');
+            let tx = parts.next().and_then(|s| s.parse::<u64>().ok());
+            let rx = parts.next().and_then(|s| s.parse::<u64>().ok());
+            let name = parts.next();
+
+            let flow_stat = match (tx, rx, name) {
+                (Some(..), Some(..), None) if i == 0 => self.flow_stat.clone(),
+                (Some(..), Some(..), Some(name)) if i > 0 => self.user_flow_stat.user(name),
+                _ => {
+                    let err = io::Error::new(
+                        ErrorKind::InvalidData,
+                        format!("malformed traffic counters \"{}\" in line {}", line, i + 1),
+                    );
+                    return Err(err);
+                }
+            };
+
+            flow_stat.incr_tx(tx.unwrap_or(0));
+            flow_stat.incr_rx(rx.unwrap_or(0));
+        }
+
+        trace!("restored traffic counters {}", self.key);
+
+        Ok(())
+    }
+
+    fn serialize(&self) -> String {
+        let mut value = format!("{} {}\n", self.flow_stat.tx(), self.flow_stat.rx());
+        for (name, flow_stat) in self.user_flow_stat.users() {
+            let _ = writeln!(value, "{} {} {}", flow_stat.tx(), flow_stat.rx(), name);
+        }
+        value
+    }
+
+    /// Save counters periodically if they have been changed, and never returns
+    pub async fn run(self) -> io::Result<()> {
+        let mut saved = self.serialize();
+
+        loop {
+            time::sleep(TRAFFIC_SAVE_INTERVAL).await;
+
+            let value = self.serialize();
+            if value == saved {
+                continue;
+            }
+
+            match self.state_store.save(&self.key, value.as_bytes()) {
+                Ok(..) => saved = value,
+                Err(err) => warn!("failed to save traffic counters {}, error: {}", self.key, err),
+            }
+        }
+    }
+}
@@ -0,0 +1,131 @@
+//! Eviction of UDP associations of servers
+//!
+//! When the association table of a server is full (`udp_max_associations`), room for a new association is made by
+//! evicting an existing one:
+//!
+//! - LRU, the least recently active association is always evicted, new clients are never refused
+//! - Oldest idle, the least recently active association is only evicted if it has been idle for `min_idle`,
+//!   otherwise the new association is refused, so active sessions are never broken by new ones
+//!
+//! Associations of each client are also limited by `client_limit`.
+
+use std::{
+    fmt::{self, Display},
+    str::FromStr,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+/// Default idle time of associations before they could be evicted by `UdpEvictionPolicy::OldestIdle`
+pub const DEFAULT_MIN_IDLE: Duration = Duration::from_secs(30);
+
+/// Which association is evicted when the table is full
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum UdpEvictionPolicy {
+    /// Evict the least recently active association
+    #[default]
+    Lru,
+    /// Evict the least recently active association if it has been idle long enough, or refuse the new one
+    OldestIdle,
+}
+
+impl Display for UdpEvictionPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            UdpEvictionPolicy::Lru => f.write_str("lru"),
+            UdpEvictionPolicy::OldestIdle => f.write_str("oldest_idle"),
+        }
+    }
+}
+
+/// Error while parsing `UdpEvictionPolicy` from string
+#[derive(Debug, Clone, Copy)]
+pub struct UdpEvictionPolicyError;
+
+impl Display for UdpEvictionPolicyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("invalid UdpEvictionPolicy, expecting \"lru\" or \"oldest_idle\"")
+    }
+}
+
+impl FromStr for UdpEvictionPolicy {
+    type Err = UdpEvictionPolicyError;
+
+    fn from_str(s: &str) -> Result<UdpEvictionPolicy, UdpEvictionPolicyError> {
+        match s {
+            "lru" => Ok(UdpEvictionPolicy::Lru),
+            "oldest_idle" => Ok(UdpEvictionPolicy::OldestIdle),
+            _ => Err(UdpEvictionPolicyError),
+        }
+    }
+}
+
+/// Eviction of associations when the table is full
+#[derive(Debug, Clone)]
+pub struct UdpEvictionConfig {
+    /// Which association is evicted
+    pub policy: UdpEvictionPolicy,
+    /// Idle time of associations before they could be evicted, only for `UdpEvictionPolicy::OldestIdle`
+    pub min_idle: Duration,
+}
+
+impl Default for UdpEvictionConfig {
+    fn default() -> UdpEvictionConfig {
+        UdpEvictionConfig {
+            policy: UdpEvictionPolicy::default(),
+            min_idle: DEFAULT_MIN_IDLE,
+        }
+    }
+}
+
+impl UdpEvictionConfig {
+    /// Check if it is the default configuration
+    pub fn is_default(&self) -> bool {
+        self.policy == UdpEvictionPolicy::default() && self.min_idle == DEFAULT_MIN_IDLE
+    }
+}
+
+/// Counters of associations removed from the table, or refused by the table
+#[derive(Debug, Default)]
+pub struct UdpEvictionStat {
+    expired: AtomicU64,
+    evicted: AtomicU64,
+    refused: AtomicU64,
+}
+
+impl UdpEvictionStat {
+    /// Create with all counters zero
+    pub fn new() -> UdpEvictionStat {
+        UdpEvictionStat::default()
+    }
+
+    /// Associations removed after being idle for `udp_timeout`
+    pub fn expired(&self) -> u64 {
+        self.expired.load(Ordering::Relaxed)
+    }
+
+    /// Associations evicted for new ones, because the table was full
+    pub fn evicted(&self) -> u64 {
+        self.evicted.load(Ordering::Relaxed)
+    }
+
+    /// New associations refused, because the table was full of active associations
+    pub fn refused(&self) -> u64 {
+        self.refused.load(Ordering::Relaxed)
+    }
+
+    /// Count `n` expired associations
+    pub fn incr_expired(&self, n: u64) {
+        self.expired.fetch_add(n, Ordering::Relaxed);
+    }
+
+    /// Count an evicted association
+    pub fn incr_evicted(&self) {
+        self.evicted.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a refused association
+    pub fn incr_refused(&self) {
+        self.refused.fetch_add(1, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,256 @@
+//! Shadowsocks UDP-over-TCP server
+
+use std::{
+    io::{self, ErrorKind},
+    net::SocketAddr,
+    sync::Arc,
+};
+
+use bytes::Bytes;
+use futures::future;
+use log::{debug, error, trace, warn};
+use shadowsocks::{
+    lookup_then,
+    net::UdpSocket as OutboundUdpSocket,
+    relay::{
+        socks5::Address,
+        tcprelay::proxy_stream::{ProxyServerStream, ProxyServerStreamWriteHalf},
+        udp_over_tcp::{read_packet, write_packet},
+        udprelay::MAXIMUM_UDP_PAYLOAD_SIZE,
+    },
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    sync::mpsc,
+};
+
+use crate::net::UDP_ASSOCIATION_SEND_CHANNEL_SIZE;
+
+use super::{context::ServiceContext, firewall::FirewallProtocol};
+
+/// Relays UDP packets carried in a client's TCP stream
+pub struct UdpOverTcpRelay<S> {
+    context: Arc<ServiceContext>,
+    peer_addr: SocketAddr,
+    user_name: Option<String>,
+    outbound_ipv4_socket: Option<OutboundUdpSocket>,
+    outbound_ipv6_socket: Option<OutboundUdpSocket>,
+    writer: ProxyServerStreamWriteHalf<S>,
+}
+
+impl<S> Drop for UdpOverTcpRelay<S> {
+    fn drop(&mut self) {
+        debug!("udp-over-tcp relay for {} is closed", self.peer_addr);
+    }
+}
+
+impl<S> UdpOverTcpRelay<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    /// Serve UDP-over-TCP on `stream` of `user_name` (in multi-user servers) until client closes it
+    pub async fn serve(
+        context: Arc<ServiceContext>,
+        peer_addr: SocketAddr,
+        user_name: Option<String>,
+        stream: ProxyServerStream<S>,
+    ) -> io::Result<()> {
+        let (mut reader, writer) = stream.into_split();
+
+        // Packets are read in a separated task, because reading a frame from stream couldn't be cancelled safely
+        let (sender, mut receiver) = mpsc::channel(UDP_ASSOCIATION_SEND_CHANNEL_SIZE);
+        let reader_handle = tokio::spawn(async move {
+            let mut buffer = vec![0u8; MAXIMUM_UDP_PAYLOAD_SIZE];
+            loop {
+                let packet = read_packet(&mut reader, &mut buffer)
+                    .await
+                    .map(|(n, addr)| (addr, Bytes::copy_from_slice(&buffer[..n])));
+                let failed = packet.is_err();
+
+                if sender.send(packet).await.is_err() || failed {
+                    break;
+                }
+            }
+        });
+
+        let mut relay = UdpOverTcpRelay {
+            context,
+            peer_addr,
+            user_name,
+            outbound_ipv4_socket: None,
+            outbound_ipv6_socket: None,
+            writer,
+        };
+
+        let result = relay.dispatch_packet(&mut receiver).await;
+        reader_handle.abort();
+        result
+    }
+
+    async fn dispatch_packet(&mut self, receiver: &mut mpsc::Receiver<io::Result<(Address, Bytes)>>) -> io::Result<()> {
+        let mut outbound_ipv4_buffer = Vec::new();
+        let mut outbound_ipv6_buffer = Vec::new();
+
+        loop {
+            tokio::select! {
+                packet_received_opt = receiver.recv() => {
+                    let (target_addr, data) = match packet_received_opt {
+                        Some(Ok(d)) => d,
+                        Some(Err(ref err)) if err.kind() == ErrorKind::UnexpectedEof => {
+                            trace!("udp-over-tcp relay {} -> ... stream closed", self.peer_addr);
+                            return Ok(());
+                        }
+                        Some(Err(err)) => return Err(err),
+                        None => return Ok(()),
+                    };
+
+                    self.dispatch_received_packet(&target_addr, &data).await;
+                }
+
+                received_opt = receive_from_outbound_opt(&self.outbound_ipv4_socket, &mut outbound_ipv4_buffer) => {
+                    let (n, addr) = match received_opt {
+                        Ok(r) => r,
+                        Err(err) => {
+                            error!("udp-over-tcp relay {} <- ... failed, error: {}", self.peer_addr, err);
+                            // Socket failure. Reset for recreation.
+                            self.outbound_ipv4_socket = None;
+                            continue;
+                        }
+                    };
+
+                    let addr = Address::from(addr);
+                    self.send_received_respond_packet(&addr, &outbound_ipv4_buffer[..n]).await?;
+                }
+
+                received_opt = receive_from_outbound_opt(&self.outbound_ipv6_socket, &mut outbound_ipv6_buffer) => {
+                    let (n, addr) = match received_opt {
+                        Ok(r) => r,
+                        Err(err) => {
+                            error!("udp-over-tcp relay {} <- ... failed, error: {}", self.peer_addr, err);
+                            // Socket failure. Reset for recreation.
+                            self.outbound_ipv6_socket = None;
+                            continue;
+                        }
+                    };
+
+                    let addr = Address::from(addr);
+                    self.send_received_respond_packet(&addr, &outbound_ipv6_buffer[..n]).await?;
+                }
+            }
+        }
+
+        #[inline]
+        async fn receive_from_outbound_opt(
+            socket: &Option<OutboundUdpSocket>,
+            buf: &mut Vec<u8>,
+        ) -> io::Result<(usize, SocketAddr)> {
+            match *socket {
+                None => future::pending().await,
+                Some(ref s) => {
+                    if buf.is_empty() {
+                        buf.resize(MAXIMUM_UDP_PAYLOAD_SIZE, 0);
+                    }
+                    s.recv_from(buf).await
+                }
+            }
+        }
+    }
+
+    async fn dispatch_received_packet(&mut self, target_addr: &Address, data: &[u8]) {
+        trace!(
+            "udp-over-tcp relay {} -> {} with {} bytes",
+            self.peer_addr,
+            target_addr,
+            data.len()
+        );
+
+        if self.context.check_outbound_blocked(target_addr).await {
+            error!(
+                "udp-over-tcp client {} outbound {} blocked by ACL rules",
+                self.peer_addr, target_addr
+            );
+            return;
+        }
+
+        if self
+            .context
+            .check_outbound_denied(FirewallProtocol::Udp, target_addr, self.user_name.as_deref())
+            .await
+        {
+            error!(
+                "udp-over-tcp client {} outbound {} denied by firewall rules",
+                self.peer_addr, target_addr
+            );
+            return;
+        }
+
+        if let Err(err) = self.dispatch_received_outbound_packet(target_addr, data).await {
+            error!(
+                "udp-over-tcp relay {} -> {} with {} bytes, error: {}",
+                self.peer_addr,
+                target_addr,
+                data.len(),
+                err
+            );
+        }
+    }
+
+    async fn dispatch_received_outbound_packet(&mut self, target_addr: &Address, data: &[u8]) -> io::Result<()> {
+        match *target_addr {
+            Address::SocketAddress(sa) => self.send_received_outbound_packet(sa, data).await,
+            Address::DomainNameAddress(ref dname, port) => {
+                let ip_strategy = self.context.target_ip_strategy(target_addr);
+                lookup_then!(self.context.context_ref(), dname, port, ip_strategy, |sa| {
+                    self.send_received_outbound_packet(sa, data).await
+                })
+                .map(|_| ())
+            }
+        }
+    }
+
+    async fn send_received_outbound_packet(&mut self, target_addr: SocketAddr, data: &[u8]) -> io::Result<()> {
+        let socket = match target_addr {
+            SocketAddr::V4(..) => match self.outbound_ipv4_socket {
+                Some(ref mut socket) => socket,
+                None => {
+                    let socket =
+                        OutboundUdpSocket::connect_any_with_opts(&target_addr, self.context.connect_opts_ref()).await?;
+                    self.outbound_ipv4_socket.insert(socket)
+                }
+            },
+            SocketAddr::V6(..) => match self.outbound_ipv6_socket {
+                Some(ref mut socket) => socket,
+                None => {
+                    let socket =
+                        OutboundUdpSocket::connect_any_with_opts(&target_addr, self.context.connect_opts_ref()).await?;
+                    self.outbound_ipv6_socket.insert(socket)
+                }
+            },
+        };
+
+        let n = socket.send_to(data, target_addr).await?;
+        if n != data.len() {
+            warn!(
+                "{} -> {} sent {} bytes != expected {} bytes",
+                self.peer_addr,
+                target_addr,
+                n,
+                data.len()
+            );
+        }
+
+        Ok(())
+    }
+
+    async fn send_received_respond_packet(&mut self, addr: &Address, data: &[u8]) -> io::Result<()> {
+        trace!(
+            "udp-over-tcp relay {} <- {} received {} bytes",
+            self.peer_addr,
+            addr,
+            data.len()
+        );
+
+        // Send back to client. The stream is broken if it fails.
+        write_packet(&mut self.writer, addr, data).await
+    }
+}
@@ -4,27 +4,50 @@ use std::{
     io::{self, ErrorKind},
     net::SocketAddr,
     sync::Arc,
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 use bytes::Bytes;
 use futures::future;
 use log::{debug, error, info, trace, warn};
 use lru_time_cache::LruCache;
+#[cfg(feature = "aead-cipher-2022")]
+use shadowsocks::{config::ServerUser, relay::udprelay::PacketWindowFilter};
 use shadowsocks::{
+    context::ClockSkewError,
     lookup_then,
-    net::{AcceptOpts, UdpSocket as OutboundUdpSocket},
+    net::{
+        udp::{self as outbound_udp, RecvBatch, SendBatch, UDP_BATCH_SIZE},
+        AcceptOpts,
+        UdpSocket as OutboundUdpSocket,
+    },
     relay::{
+        buffer_pool::udp_buffer_pool,
         socks5::Address,
-        udprelay::{ProxySocket, MAXIMUM_UDP_PAYLOAD_SIZE},
+        udprelay::{ProxySocket, UdpSocketControlData, MAXIMUM_UDP_PAYLOAD_SIZE},
     },
     ServerConfig,
 };
 use tokio::{sync::mpsc, task::JoinHandle, time};
 
-use crate::net::{MonProxySocket, UDP_ASSOCIATION_KEEP_ALIVE_CHANNEL_SIZE, UDP_ASSOCIATION_SEND_CHANNEL_SIZE};
+use crate::net::{
+    p2p::is_bittorrent_packet,
+    rate_limit::Direction,
+    ConcurrencyGuard,
+    ConcurrencyKind,
+    MonProxySocket,
+    RateLimiters,
+    UDP_ASSOCIATION_KEEP_ALIVE_CHANNEL_SIZE,
+    UDP_ASSOCIATION_SEND_CHANNEL_SIZE,
+};
 
-use super::context::ServiceContext;
+use super::{
+    client_limit::ClientLimitGuard,
+    context::ServiceContext,
+    firewall::FirewallProtocol,
+    monitor::SecurityEventKind,
+    udp_eviction::{UdpEvictionConfig, UdpEvictionPolicy},
+};
 
 type AssociationMap = LruCache<SocketAddr, UdpAssociation>;
 
@@ -34,6 +57,10 @@ pub struct UdpServer {
     keepalive_tx: mpsc::Sender<SocketAddr>,
     keepalive_rx: mpsc::Receiver<SocketAddr>,
     time_to_live: Duration,
+    capacity: Option<usize>,
+    eviction: UdpEvictionConfig,
+    // Associations in `assoc_map`, including expired ones that haven't been removed
+    assoc_count: usize,
     accept_opts: AcceptOpts,
 }
 
@@ -42,13 +69,12 @@ impl UdpServer {
         context: Arc<ServiceContext>,
         time_to_live: Option<Duration>,
         capacity: Option<usize>,
+        eviction: UdpEvictionConfig,
         accept_opts: AcceptOpts,
     ) -> UdpServer {
         let time_to_live = time_to_live.unwrap_or(crate::DEFAULT_UDP_EXPIRY_DURATION);
-        let assoc_map = match capacity {
-            Some(capacity) => LruCache::with_expiry_duration_and_capacity(time_to_live, capacity),
-            None => LruCache::with_expiry_duration(time_to_live),
-        };
+        // Capacity is kept by `make_room` with the eviction policy
+        let assoc_map = LruCache::with_expiry_duration(time_to_live);
 
         let (keepalive_tx, keepalive_rx) = mpsc::channel(UDP_ASSOCIATION_KEEP_ALIVE_CHANNEL_SIZE);
 
@@ -58,6 +84,9 @@ impl UdpServer {
             keepalive_tx,
             keepalive_rx,
             time_to_live,
+            capacity,
+            eviction,
+            assoc_count: 0,
             accept_opts,
         }
     }
@@ -70,10 +99,19 @@ impl UdpServer {
             socket.local_addr().expect("listener.local_addr"),
         );
 
-        let socket = MonProxySocket::from_socket(socket, self.context.flow_stat());
+        #[allow(unused_mut)]
+        let mut socket = MonProxySocket::from_socket(socket, self.context.flow_stat());
+        #[cfg(feature = "aead-cipher-2022")]
+        if svr_cfg.user_manager().is_some() {
+            socket.set_user_flow_stat(self.context.user_flow_stat().clone());
+        }
+        // Received only by `recv_batch`, which splits datagrams coalesced by GRO
+        if let Err(err) = socket.get_ref().enable_gro() {
+            warn!("udp server failed to enable GRO, error: {}", err);
+        }
         let listener = Arc::new(socket);
 
-        let mut buffer = [0u8; MAXIMUM_UDP_PAYLOAD_SIZE];
+        let mut batch = RecvBatch::new(UDP_BATCH_SIZE, MAXIMUM_UDP_PAYLOAD_SIZE);
         let mut cleanup_timer = time::interval(self.time_to_live);
 
         loop {
@@ -81,57 +119,103 @@ impl UdpServer {
                 _ = cleanup_timer.tick() => {
                     // cleanup expired associations. iter() will remove expired elements
                     let _ = self.assoc_map.iter();
+
+                    // Associations expired since the last cleanup, some of them were removed by accessing the map
+                    let assoc_count = self.assoc_map.len();
+                    let expired = self.assoc_count.saturating_sub(assoc_count);
+                    if expired > 0 {
+                        self.context.udp_eviction_stat().incr_expired(expired as u64);
+                    }
+                    self.assoc_count = assoc_count;
                 }
 
                 peer_addr_opt = self.keepalive_rx.recv() => {
                     let peer_addr = peer_addr_opt.expect("keep-alive channel closed unexpectly");
-                    self.assoc_map.get(&peer_addr);
-                }
-
-                recv_result = listener.recv_from(&mut buffer) => {
-                    let (n, peer_addr, target_addr) = match recv_result {
-                        Ok(s) => s,
-                        Err(err) => {
-                            error!("udp server recv_from failed with error: {}", err);
-                            continue;
-                        }
-                    };
-
-                    if n == 0 {
-                        // For windows, it will generate a ICMP Port Unreachable Message
-                        // https://docs.microsoft.com/en-us/windows/win32/api/winsock2/nf-winsock2-recvfrom
-                        // Which will result in recv_from return 0.
-                        //
-                        // It cannot be solved here, because `WSAGetLastError` is already set.
-                        //
-                        // See `relay::udprelay::utils::create_socket` for more detail.
-                        continue;
+                    if let Some(assoc) = self.assoc_map.get_mut(&peer_addr) {
+                        assoc.last_active = Instant::now();
                     }
+                }
 
-                    if self.context.check_client_blocked(&peer_addr) {
-                        warn!(
-                            "udp client {} outbound {} access denied by ACL rules",
-                            peer_addr, target_addr
-                        );
+                recv_result = listener.recv_batch(&mut batch) => {
+                    if let Err(err) = recv_result {
+                        error!("udp server recv_from failed with error: {}", err);
                         continue;
                     }
 
-                    if self.context.check_outbound_blocked(&target_addr).await {
-                        warn!("udp client {} outbound {} blocked by ACL rules", peer_addr, target_addr);
-                        continue;
+                    for i in 0..batch.len() {
+                        let (packet, peer_addr) = batch.get_mut(i);
+                        self.recv_packet(&listener, peer_addr, packet).await;
                     }
+                }
+            }
+        }
+    }
 
-                    let data = &buffer[..n];
-                    if let Err(err) = self.send_packet(&listener, peer_addr, target_addr, data).await {
-                        error!(
-                            "udp packet relay {} with {} bytes failed, error: {}",
-                            peer_addr,
-                            data.len(),
-                            err
-                        );
+    async fn recv_packet(&mut self, listener: &Arc<MonProxySocket>, peer_addr: SocketAddr, packet: &mut [u8]) {
+        let (n, target_addr, control) = match listener.decrypt_packet(packet).await {
+            Ok(s) => s,
+            Err(err) => {
+                error!("udp server recv_from failed with error: {}", err);
+                if ClockSkewError::is_clock_skew(&err) {
+                    if let Ok(server_addr) = listener.get_ref().local_addr() {
+                        self.context
+                            .security_monitor()
+                            .report(SecurityEventKind::ClockSkew, peer_addr, server_addr);
                     }
                 }
+                return;
             }
+        };
+
+        if n == 0 {
+            // For windows, it will generate a ICMP Port Unreachable Message
+            // https://docs.microsoft.com/en-us/windows/win32/api/winsock2/nf-winsock2-recvfrom
+            // Which will result in recv_from return 0.
+            //
+            // It cannot be solved here, because `WSAGetLastError` is already set.
+            //
+            // See `relay::udprelay::utils::create_socket` for more detail.
+            return;
+        }
+
+        if self.context.check_client_blocked(&peer_addr) {
+            warn!(
+                "udp client {} outbound {} access denied by ACL rules",
+                peer_addr, target_addr
+            );
+            return;
+        }
+
+        if self.context.check_outbound_blocked(&target_addr).await {
+            warn!("udp client {} outbound {} blocked by ACL rules", peer_addr, target_addr);
+            return;
+        }
+
+        if self.context.check_quota_exceeded(packet_user_name(control.as_ref())) {
+            debug!(
+                "udp client {} outbound {} dropped, traffic quota exceeded",
+                peer_addr, target_addr
+            );
+            return;
+        }
+
+        let data = &packet[..n];
+
+        if !self.context.p2p_policy().is_allow() && is_bittorrent_packet(data) {
+            debug!(
+                "udp client {} outbound {} blocked, BitTorrent traffic",
+                peer_addr, target_addr
+            );
+            return;
+        }
+
+        if let Err(err) = self.send_packet(listener, peer_addr, target_addr, control, data).await {
+            error!(
+                "udp packet relay {} with {} bytes failed, error: {}",
+                peer_addr,
+                data.len(),
+                err
+            );
         }
     }
 
@@ -140,31 +224,113 @@ impl UdpServer {
         listener: &Arc<MonProxySocket>,
         peer_addr: SocketAddr,
         target_addr: Address,
+        control: Option<UdpSocketControlData>,
         data: &[u8],
     ) -> io::Result<()> {
-        if let Some(assoc) = self.assoc_map.get(&peer_addr) {
-            return assoc.try_send((target_addr, Bytes::copy_from_slice(data)));
+        match self.assoc_map.get_mut(&peer_addr) {
+            Some(assoc) if !assoc.sender.is_closed() => {
+                assoc.last_active = Instant::now();
+                return assoc.try_send((target_addr, control, Bytes::copy_from_slice(data)));
+            }
+            Some(..) => {
+                // Evicted by the client limiter, create a new one
+                self.assoc_map.remove(&peer_addr);
+                self.assoc_count = self.assoc_count.saturating_sub(1);
+            }
+            None => {}
         }
 
-        let assoc = UdpAssociation::new(
+        if self.context.is_draining() {
+            debug!("udp client {} dropped, server is draining", peer_addr);
+            return Ok(());
+        }
+
+        let client_guard = match self
+            .context
+            .acquire_client_slot(ConcurrencyKind::UdpAssociation, &peer_addr)
+        {
+            Some(g) => g,
+            None => {
+                debug!("udp client {} dropped, too many associations", peer_addr);
+                return Ok(());
+            }
+        };
+
+        if !self.make_room() {
+            debug!("udp client {} dropped, association table is full", peer_addr);
+            return Ok(());
+        }
+
+        let mut assoc = UdpAssociation::new(
             self.context.clone(),
             listener.clone(),
             peer_addr,
             self.keepalive_tx.clone(),
+            client_guard,
         );
 
         debug!("created udp association for {}", peer_addr);
 
-        assoc.try_send((target_addr, Bytes::copy_from_slice(data)))?;
+        assoc.try_send((target_addr, control, Bytes::copy_from_slice(data)))?;
         self.assoc_map.insert(peer_addr, assoc);
+        self.assoc_count += 1;
 
         Ok(())
     }
+
+    /// Evict an association if the table is full, returns `false` if the new association has to be refused
+    fn make_room(&mut self) -> bool {
+        let capacity = match self.capacity {
+            Some(c) => c,
+            None => return true,
+        };
+        if self.assoc_map.len() < capacity {
+            return true;
+        }
+
+        // The least recently active association is the last one
+        let (peer_addr, idle) = match self.assoc_map.peek_iter().last() {
+            Some((peer_addr, assoc)) => (*peer_addr, assoc.last_active.elapsed()),
+            None => return false,
+        };
+
+        if self.eviction.policy == UdpEvictionPolicy::OldestIdle && idle < self.eviction.min_idle {
+            self.context.udp_eviction_stat().incr_refused();
+            return false;
+        }
+
+        debug!("udp association for {} evicted, idle for {:?}", peer_addr, idle);
+        self.assoc_map.remove(&peer_addr);
+        self.assoc_count = self.assoc_count.saturating_sub(1);
+        self.context.udp_eviction_stat().incr_evicted();
+        true
+    }
+}
+
+type UdpAssociationPacket = (Address, Option<UdpSocketControlData>, Bytes);
+
+// Name of the user who sent the packet, in multi-user servers
+#[inline]
+fn packet_user_name(control: Option<&UdpSocketControlData>) -> Option<&str> {
+    #[cfg(feature = "aead-cipher-2022")]
+    if let Some(user) = control.and_then(|c| c.user.as_ref()) {
+        return Some(user.name());
+    }
+    #[cfg(not(feature = "aead-cipher-2022"))]
+    let _ = control;
+
+    None
 }
 
 struct UdpAssociation {
     assoc_handle: JoinHandle<()>,
-    sender: mpsc::Sender<(Address, Bytes)>,
+    sender: mpsc::Sender<UdpAssociationPacket>,
+    _concurrency_guard: ConcurrencyGuard,
+    // Last packet sent by the client, or received from targets
+    last_active: Instant,
+    // Client session ID and its received packet IDs (AEAD 2022)
+    #[cfg(feature = "aead-cipher-2022")]
+    client_session: Option<(u64, PacketWindowFilter)>,
 }
 
 impl Drop for UdpAssociation {
@@ -179,12 +345,53 @@ impl UdpAssociation {
         inbound: Arc<MonProxySocket>,
         peer_addr: SocketAddr,
         keepalive_tx: mpsc::Sender<SocketAddr>,
+        client_guard: ClientLimitGuard,
     ) -> UdpAssociation {
-        let (assoc_handle, sender) = UdpAssociationContext::create(context, inbound, peer_addr, keepalive_tx);
-        UdpAssociation { assoc_handle, sender }
+        let concurrency_guard = context.concurrency_stat().acquire(ConcurrencyKind::UdpAssociation, 1);
+        let (assoc_handle, sender) =
+            UdpAssociationContext::create(context, inbound, peer_addr, keepalive_tx, client_guard);
+        UdpAssociation {
+            assoc_handle,
+            sender,
+            _concurrency_guard: concurrency_guard,
+            last_active: Instant::now(),
+            #[cfg(feature = "aead-cipher-2022")]
+            client_session: None,
+        }
+    }
+
+    #[cfg(feature = "aead-cipher-2022")]
+    fn check_packet_id(&mut self, control: &UdpSocketControlData) -> io::Result<()> {
+        match self.client_session {
+            Some((session_id, ref mut filter)) if session_id == control.client_session_id => {
+                if !filter.validate_packet_id(control.packet_id) {
+                    let err = io::Error::new(
+                        ErrorKind::InvalidData,
+                        format!(
+                            "udp packet {} of client session {:#x} replayed",
+                            control.packet_id, session_id
+                        ),
+                    );
+                    return Err(err);
+                }
+            }
+            _ => {
+                // New session of client, may be restarted with the same address
+                let mut filter = PacketWindowFilter::new();
+                filter.validate_packet_id(control.packet_id);
+                self.client_session = Some((control.client_session_id, filter));
+            }
+        }
+
+        Ok(())
     }
 
-    fn try_send(&self, data: (Address, Bytes)) -> io::Result<()> {
+    fn try_send(&mut self, data: UdpAssociationPacket) -> io::Result<()> {
+        #[cfg(feature = "aead-cipher-2022")]
+        if let Some(ref control) = data.1 {
+            self.check_packet_id(control)?;
+        }
+
         if let Err(..) = self.sender.try_send(data) {
             let err = io::Error::new(ErrorKind::Other, "udp relay channel full");
             return Err(err);
@@ -201,6 +408,16 @@ struct UdpAssociationContext {
     keepalive_tx: mpsc::Sender<SocketAddr>,
     keepalive_flag: bool,
     inbound: Arc<MonProxySocket>,
+    // Packets sent back to client, in batches
+    respond_batch: SendBatch,
+    rate_limiters: RateLimiters,
+    client_guard: ClientLimitGuard,
+    // Client session ID, server session ID and the next server packet ID (AEAD 2022)
+    #[cfg(feature = "aead-cipher-2022")]
+    session: Option<(u64, u64, u64)>,
+    // User of multi-user servers (AEAD 2022)
+    #[cfg(feature = "aead-cipher-2022")]
+    user: Option<Arc<ServerUser>>,
 }
 
 impl Drop for UdpAssociationContext {
@@ -215,12 +432,15 @@ impl UdpAssociationContext {
         inbound: Arc<MonProxySocket>,
         peer_addr: SocketAddr,
         keepalive_tx: mpsc::Sender<SocketAddr>,
-    ) -> (JoinHandle<()>, mpsc::Sender<(Address, Bytes)>) {
+        client_guard: ClientLimitGuard,
+    ) -> (JoinHandle<()>, mpsc::Sender<UdpAssociationPacket>) {
         // Pending packets UDP_ASSOCIATION_SEND_CHANNEL_SIZE for each association should be good enough for a server.
         // If there are plenty of packets stuck in the channel, dropping excessive packets is a good way to protect the server from
         // being OOM.
         let (sender, receiver) = mpsc::channel(UDP_ASSOCIATION_SEND_CHANNEL_SIZE);
 
+        let rate_limiters = context.rate_limiter().limiters(&peer_addr, None);
+
         let mut assoc = UdpAssociationContext {
             context,
             peer_addr,
@@ -229,21 +449,28 @@ impl UdpAssociationContext {
             keepalive_tx,
             keepalive_flag: false,
             inbound,
+            respond_batch: SendBatch::new(),
+            rate_limiters,
+            client_guard,
+            #[cfg(feature = "aead-cipher-2022")]
+            session: None,
+            #[cfg(feature = "aead-cipher-2022")]
+            user: None,
         };
         let handle = tokio::spawn(async move { assoc.dispatch_packet(receiver).await });
 
         (handle, sender)
     }
 
-    async fn dispatch_packet(&mut self, mut receiver: mpsc::Receiver<(Address, Bytes)>) {
-        let mut outbound_ipv4_buffer = Vec::new();
-        let mut outbound_ipv6_buffer = Vec::new();
+    async fn dispatch_packet(&mut self, mut receiver: mpsc::Receiver<UdpAssociationPacket>) {
+        let mut outbound_ipv4_batch = None;
+        let mut outbound_ipv6_batch = None;
         let mut keepalive_interval = time::interval(Duration::from_secs(1));
 
         loop {
             tokio::select! {
                 packet_received_opt = receiver.recv() => {
-                    let (target_addr, data) = match packet_received_opt {
+                    let (target_addr, control, data) = match packet_received_opt {
                         Some(d) => d,
                         None => {
                             trace!("udp association for {} -> ... channel closed", self.peer_addr);
@@ -251,37 +478,48 @@ impl UdpAssociationContext {
                         }
                     };
 
+                    self.client_guard.touch();
+
+                    #[cfg(feature = "aead-cipher-2022")]
+                    if let Some(control) = control {
+                        self.update_client_session(control.client_session_id);
+                        self.update_user(control.user);
+                    }
+                    #[cfg(not(feature = "aead-cipher-2022"))]
+                    let _ = control;
+
                     self.dispatch_received_packet(&target_addr, &data).await;
                 }
 
-                received_opt = receive_from_outbound_opt(&self.outbound_ipv4_socket, &mut outbound_ipv4_buffer) => {
-                    let (n, addr) = match received_opt {
-                        Ok(r) => r,
-                        Err(err) => {
-                            error!("udp relay {} <- ... failed, error: {}", self.peer_addr, err);
-                            // Socket failure. Reset for recreation.
-                            self.outbound_ipv4_socket = None;
-                            continue;
-                        }
-                    };
+                received_opt = receive_from_outbound_opt(&self.outbound_ipv4_socket, &mut outbound_ipv4_batch) => {
+                    if let Err(err) = received_opt {
+                        error!("udp relay {} <- ... failed, error: {}", self.peer_addr, err);
+                        // Socket failure. Reset for recreation.
+                        self.outbound_ipv4_socket = None;
+                        continue;
+                    }
 
-                    let addr = Address::from(addr);
-                    self.send_received_respond_packet(&addr, &outbound_ipv4_buffer[..n]).await;
+                    if let Some(ref batch) = outbound_ipv4_batch {
+                        self.send_received_respond_batch(batch).await;
+                    }
                 }
 
-                received_opt = receive_from_outbound_opt(&self.outbound_ipv6_socket, &mut outbound_ipv6_buffer) => {
-                    let (n, addr) = match received_opt {
-                        Ok(r) => r,
-                        Err(err) => {
-                            error!("udp relay {} <- ... failed, error: {}", self.peer_addr, err);
-                            // Socket failure. Reset for recreation.
-                            self.outbound_ipv6_socket = None;
-                            continue;
-                        }
-                    };
+                received_opt = receive_from_outbound_opt(&self.outbound_ipv6_socket, &mut outbound_ipv6_batch) => {
+                    if let Err(err) = received_opt {
+                        error!("udp relay {} <- ... failed, error: {}", self.peer_addr, err);
+                        // Socket failure. Reset for recreation.
+                        self.outbound_ipv6_socket = None;
+                        continue;
+                    }
+
+                    if let Some(ref batch) = outbound_ipv6_batch {
+                        self.send_received_respond_batch(batch).await;
+                    }
+                }
 
-                    let addr = Address::from(addr);
-                    self.send_received_respond_packet(&addr, &outbound_ipv6_buffer[..n]).await;
+                _ = self.client_guard.evicted() => {
+                    debug!("udp association for {} is evicted by a newer association", self.peer_addr);
+                    break;
                 }
 
                 _ = keepalive_interval.tick() => {
@@ -299,20 +537,67 @@ impl UdpAssociationContext {
         #[inline]
         async fn receive_from_outbound_opt(
             socket: &Option<OutboundUdpSocket>,
-            buf: &mut Vec<u8>,
-        ) -> io::Result<(usize, SocketAddr)> {
+            batch: &mut Option<RecvBatch>,
+        ) -> io::Result<usize> {
             match *socket {
                 None => future::pending().await,
                 Some(ref s) => {
-                    if buf.is_empty() {
-                        buf.resize(MAXIMUM_UDP_PAYLOAD_SIZE, 0);
-                    }
-                    s.recv_from(buf).await
+                    let batch = batch.get_or_insert_with(|| RecvBatch::with_pool(UDP_BATCH_SIZE, udp_buffer_pool()));
+                    outbound_udp::recv_batch(s, batch).await
                 }
             }
         }
     }
 
+    #[cfg(feature = "aead-cipher-2022")]
+    fn update_client_session(&mut self, client_session_id: u64) {
+        match self.session {
+            Some((id, ..)) if id == client_session_id => {}
+            _ => {
+                trace!(
+                    "udp association for {} client session {:#x}",
+                    self.peer_addr,
+                    client_session_id
+                );
+                self.session = Some((client_session_id, rand::random(), 0));
+            }
+        }
+    }
+
+    #[cfg(feature = "aead-cipher-2022")]
+    fn update_user(&mut self, user: Option<Arc<ServerUser>>) {
+        let changed = match (&self.user, &user) {
+            (Some(a), Some(b)) => a.name() != b.name(),
+            (None, None) => false,
+            _ => true,
+        };
+        if changed {
+            // Bandwidth of the association is shared with the other ones of the user
+            self.rate_limiters = self
+                .context
+                .rate_limiter()
+                .limiters(&self.peer_addr, user.as_ref().map(|u| u.name()));
+        }
+        self.user = user;
+    }
+
+    // Control data of the next packet sent back to client
+    fn next_control(&mut self) -> Option<UdpSocketControlData> {
+        #[cfg(feature = "aead-cipher-2022")]
+        if let Some((client_session_id, server_session_id, ref mut packet_id)) = self.session {
+            let control = UdpSocketControlData {
+                client_session_id,
+                server_session_id,
+                packet_id: *packet_id,
+                user: self.user.clone(),
+            };
+            *packet_id += 1;
+            return Some(control);
+        }
+
+        None
+    }
+
     async fn dispatch_received_packet(&mut self, target_addr: &Address, data: &[u8]) {
         trace!(
             "udp relay {} -> {} with {} bytes",
@@ -329,6 +614,24 @@ impl UdpAssociationContext {
             return;
         }
 
+        #[cfg(feature = "aead-cipher-2022")]
+        let user_name = self.user.as_ref().map(|u| u.name());
+        #[cfg(not(feature = "aead-cipher-2022"))]
+        let user_name = None;
+        if self
+            .context
+            .check_outbound_denied(FirewallProtocol::Udp, target_addr, user_name)
+            .await
+        {
+            error!(
+                "udp client {} outbound {} denied by firewall rules",
+                self.peer_addr, target_addr
+            );
+            return;
+        }
+
+        self.rate_limiters.acquire(Direction::Upload, data.len()).await;
+
         if let Err(err) = self.dispatch_received_outbound_packet(target_addr, data).await {
             error!(
                 "udp relay {} -> {} with {} bytes, error: {}",
@@ -344,7 +647,8 @@ impl UdpAssociationContext {
         match *target_addr {
             Address::SocketAddress(sa) => self.send_received_outbound_packet(sa, data).await,
             Address::DomainNameAddress(ref dname, port) => {
-                lookup_then!(self.context.context_ref(), dname, port, |sa| {
+                let ip_strategy = self.context.target_ip_strategy(target_addr);
+                lookup_then!(self.context.context_ref(), dname, port, ip_strategy, |sa| {
                     self.send_received_outbound_packet(sa, data).await
                 })
                 .map(|_| ())
@@ -386,23 +690,52 @@ impl UdpAssociationContext {
         Ok(())
     }
 
-    async fn send_received_respond_packet(&mut self, addr: &Address, data: &[u8]) {
-        trace!("udp relay {} <- {} received {} bytes", self.peer_addr, addr, data.len());
+    async fn send_received_respond_batch(&mut self, batch: &RecvBatch) {
+        for i in 0..batch.len() {
+            let (data, addr) = batch.get(i);
+            let addr = Address::from(addr);
+
+            trace!("udp relay {} <- {} received {} bytes", self.peer_addr, addr, data.len());
+
+            // Keep association alive in map
+            self.keepalive_flag = true;
+
+            let control = self.next_control();
+            if self.context.check_quota_exceeded(packet_user_name(control.as_ref())) {
+                debug!(
+                    "udp relay {} <- {} dropped {} bytes, traffic quota exceeded",
+                    self.peer_addr,
+                    addr,
+                    data.len()
+                );
+                continue;
+            }
 
-        // Keep association alive in map
-        self.keepalive_flag = true;
+            self.rate_limiters.acquire(Direction::Download, data.len()).await;
+
+            let target = Some(self.peer_addr);
+            match control {
+                Some(ref control) => {
+                    self.inbound
+                        .push_batch_with_ctrl(&mut self.respond_batch, target, &addr, control, data)
+                }
+                None => self.inbound.push_batch(&mut self.respond_batch, target, &addr, data),
+            }
+        }
+
+        if self.respond_batch.is_empty() {
+            return;
+        }
 
         // Send back to client
-        if let Err(err) = self.inbound.send_to(self.peer_addr, addr, data).await {
+        let n = self.respond_batch.len();
+        if let Err(err) = self.inbound.send_batch(&mut self.respond_batch).await {
             warn!(
-                "udp failed to send back {} bytes to client {}, from target {}, error: {}",
-                data.len(),
-                self.peer_addr,
-                addr,
-                err
+                "udp failed to send back {} packets to client {}, error: {}",
+                n, self.peer_addr, err
             );
         } else {
-            trace!("udp relay {} <- {} with {} bytes", self.peer_addr, addr, data.len());
+            trace!("udp relay {} <- ... with {} packets", self.peer_addr, n);
         }
     }
 }
@@ -0,0 +1,52 @@
+//! Stopping local and server instances embedded in other programs
+//!
+//! Instances are stopped by their `ShutdownHandle`, which could be cloned and sent to other tasks or threads. Waiting
+//! for the instance (`wait_until_exit`) resolves with `Ok(())` after its servers are stopped, instead of only with
+//! errors. Listeners and background tasks of the instance are closed, established connections are kept until they are
+//! closed by peers or timed out, like reloading `sslocal`.
+
+use std::sync::Arc;
+
+use tokio::sync::watch;
+
+/// Handle for stopping an instance
+#[derive(Debug, Clone)]
+pub struct ShutdownHandle {
+    tx: Arc<watch::Sender<bool>>,
+    rx: watch::Receiver<bool>,
+}
+
+impl ShutdownHandle {
+    /// Create a handle that hasn't been shut down
+    pub fn new() -> ShutdownHandle {
+        let (tx, rx) = watch::channel(false);
+        ShutdownHandle { tx: Arc::new(tx), rx }
+    }
+
+    /// Request instances of this handle to stop, which could be called from any thread
+    pub fn shutdown(&self) {
+        let _ = self.tx.send(true);
+    }
+
+    /// Check if shutdown has been requested
+    pub fn is_shutdown(&self) -> bool {
+        *self.rx.borrow()
+    }
+
+    /// Wait until shutdown is requested, resolves immediately if it has been requested
+    pub async fn wait(&self) {
+        let mut rx = self.rx.clone();
+        while !*rx.borrow() {
+            // The sender is owned by `self`, it couldn't be closed
+            if rx.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+impl Default for ShutdownHandle {
+    fn default() -> ShutdownHandle {
+        ShutdownHandle::new()
+    }
+}
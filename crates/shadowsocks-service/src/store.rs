@@ -0,0 +1,147 @@
+//! Storage backends for persistent states
+//!
+//! States that should survive restarts (fake-DNS mappings, learned routes, quotas, balancer states, ...) are saved
+//! as opaque values under string keys of a [`StateStore`]. Embedders could supply their own implementation, for
+//! example, to store states in the platform's database.
+
+use std::{
+    collections::HashMap,
+    fmt::Debug,
+    fs::{self, File},
+    io::{self, ErrorKind, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use crate::config::Config;
+
+/// Key-value storage for persistent states
+///
+/// Keys are short names consist of ASCII alphanumerics, `-`, `_` and `.`, values are opaque bytes.
+pub trait StateStore: Debug + Send + Sync {
+    /// Load value of `key`, `None` if it doesn't exist
+    fn load(&self, key: &str) -> io::Result<Option<Vec<u8>>>;
+
+    /// Save `value` to `key`, replacing the existed one
+    fn save(&self, key: &str, value: &[u8]) -> io::Result<()>;
+
+    /// Remove `key`, succeed if it doesn't exist
+    fn remove(&self, key: &str) -> io::Result<()>;
+}
+
+fn check_key(key: &str) -> io::Result<()> {
+    let valid = !key.is_empty()
+        && !key.starts_with('.')
+        && key
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_' || b == b'.');
+
+    if valid {
+        Ok(())
+    } else {
+        let err = io::Error::new(ErrorKind::InvalidInput, format!("invalid state key \"{}\"", key));
+        Err(err)
+    }
+}
+
+/// States are kept in memory, and will be lost after restart
+#[derive(Debug, Default)]
+pub struct MemoryStateStore {
+    states: Mutex<HashMap<String, Vec<u8>>>,
+}
+
+impl MemoryStateStore {
+    /// Create an empty store
+    pub fn new() -> MemoryStateStore {
+        MemoryStateStore::default()
+    }
+}
+
+impl StateStore for MemoryStateStore {
+    fn load(&self, key: &str) -> io::Result<Option<Vec<u8>>> {
+        check_key(key)?;
+        Ok(self.states.lock().unwrap().get(key).cloned())
+    }
+
+    fn save(&self, key: &str, value: &[u8]) -> io::Result<()> {
+        check_key(key)?;
+        self.states.lock().unwrap().insert(key.to_owned(), value.to_vec());
+        Ok(())
+    }
+
+    fn remove(&self, key: &str) -> io::Result<()> {
+        check_key(key)?;
+        self.states.lock().unwrap().remove(key);
+        Ok(())
+    }
+}
+
+/// States are saved as files in a directory, one file per key
+#[derive(Debug)]
+pub struct FileStateStore {
+    dir: PathBuf,
+}
+
+impl FileStateStore {
+    /// Create a store in `dir`, the directory will be created if it doesn't exist
+    pub fn new<P: Into<PathBuf>>(dir: P) -> io::Result<FileStateStore> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        Ok(FileStateStore { dir })
+    }
+
+    /// Directory of this store
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+}
+
+impl StateStore for FileStateStore {
+    fn load(&self, key: &str) -> io::Result<Option<Vec<u8>>> {
+        check_key(key)?;
+
+        match fs::read(self.dir.join(key)) {
+            Ok(v) => Ok(Some(v)),
+            Err(ref err) if err.kind() == ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn save(&self, key: &str, value: &[u8]) -> io::Result<()> {
+        check_key(key)?;
+
+        // Write to a temporary file and then rename it, so readers will never see a partial written state
+        let tmp_path = self.dir.join(format!(".{}.tmp", key));
+        {
+            let mut file = File::create(&tmp_path)?;
+            file.write_all(value)?;
+            file.sync_all()?;
+        }
+        fs::rename(&tmp_path, self.dir.join(key))
+    }
+
+    fn remove(&self, key: &str) -> io::Result<()> {
+        check_key(key)?;
+
+        match fs::remove_file(self.dir.join(key)) {
+            Ok(..) => Ok(()),
+            Err(ref err) if err.kind() == ErrorKind::NotFound => Ok(()),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+/// Create the `StateStore` configured in `config`
+///
+/// `Config::state_store` supplied by embedders is preferred, then a `FileStateStore` in `Config::state_dir`.
+/// Otherwise states are kept in memory.
+pub fn create_state_store(config: &Config) -> io::Result<Arc<dyn StateStore>> {
+    if let Some(ref store) = config.state_store {
+        return Ok(store.clone());
+    }
+
+    match config.state_dir {
+        Some(ref dir) => Ok(Arc::new(FileStateStore::new(dir)?)),
+        None => Ok(Arc::new(MemoryStateStore::new())),
+    }
+}
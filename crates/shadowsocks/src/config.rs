@@ -2,22 +2,44 @@
 
 #[cfg(unix)]
 use std::path::PathBuf;
+#[cfg(feature = "aead-cipher-2022")]
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+};
 use std::{
     error,
     fmt::{self, Display},
-    net::SocketAddr,
+    net::{IpAddr, SocketAddr},
+    ops::RangeInclusive,
     str::FromStr,
     time::Duration,
 };
 
 use base64::{decode_config, encode_config, URL_SAFE_NO_PAD};
+#[cfg(feature = "aead-cipher-2022")]
+use bytes::Bytes;
 use log::error;
+use percent_encoding::percent_decode_str;
 use url::{self, Url};
 
+#[cfg(feature = "transport-grpc")]
+use crate::net::grpc::GrpcConfig;
+#[cfg(feature = "transport-obfs")]
+use crate::net::obfs::ObfsConfig;
+#[cfg(feature = "transport-quic")]
+use crate::net::quic::QuicConfig;
+#[cfg(feature = "transport-shadow-tls")]
+use crate::net::shadow_tls::ShadowTlsConfig;
+#[cfg(feature = "transport-websocket")]
+use crate::net::websocket::WebSocketConfig;
+#[cfg(feature = "stream-padding")]
+use crate::relay::tcprelay::padding::PaddingConfig;
 use crate::{
-    crypto::v1::{openssl_bytes_to_key, CipherKind},
+    crypto::{available_ciphers, v1::openssl_bytes_to_key, CipherCategory, CipherKind},
+    net::{PortHopping, TcpTuning, UpstreamProxy},
     plugin::PluginConfig,
-    relay::socks5::Address,
+    relay::{buffer_pool::RelayBufferSizes, mux::MuxConfig, socks5::Address},
 };
 
 /// Shadowsocks server type
@@ -144,6 +166,17 @@ impl ServerWeight {
     }
 }
 
+/// Bandwidth limits of a server, in bytes per second of each direction
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RateLimitConfig {
+    /// Shared by all clients of the server
+    pub server: Option<u64>,
+    /// Shared by connections and UDP associations from the same client IP address
+    pub client: Option<u64>,
+    /// Shared by connections and UDP associations of the same user, in multi-user servers
+    pub user: Option<u64>,
+}
+
 /// Configuration for a server
 #[derive(Clone, Debug)]
 pub struct ServerConfig {
@@ -155,6 +188,12 @@ pub struct ServerConfig {
     method: CipherKind,
     /// Encryption key
     enc_key: Box<[u8]>,
+    /// Identity keys (iPSKs) of multi-user servers, sent in Extensible Identity Headers before `enc_key`
+    #[cfg(feature = "aead-cipher-2022")]
+    identity_keys: Arc<Vec<Bytes>>,
+    /// Users of a multi-user server, identified by Extensible Identity Headers
+    #[cfg(feature = "aead-cipher-2022")]
+    user_manager: Option<Arc<ServerUserManager>>,
     /// Handshake timeout (connect)
     timeout: Option<Duration>,
 
@@ -162,6 +201,21 @@ pub struct ServerConfig {
     plugin: Option<PluginConfig>,
     /// Plugin address
     plugin_addr: Option<ServerAddr>,
+    /// WebSocket transport, replacing v2ray-plugin
+    #[cfg(feature = "transport-websocket")]
+    websocket: Option<WebSocketConfig>,
+    /// obfs transport, replacing simple-obfs
+    #[cfg(feature = "transport-obfs")]
+    obfs: Option<ObfsConfig>,
+    /// ShadowTLS transport
+    #[cfg(feature = "transport-shadow-tls")]
+    shadow_tls: Option<ShadowTlsConfig>,
+    /// QUIC transport
+    #[cfg(feature = "transport-quic")]
+    quic: Option<QuicConfig>,
+    /// gRPC transport
+    #[cfg(feature = "transport-grpc")]
+    grpc: Option<GrpcConfig>,
 
     /// Remark (Profile Name), normally used as an identifier of this erver
     remarks: Option<String>,
@@ -173,10 +227,256 @@ pub struct ServerConfig {
 
     /// Weight
     weight: ServerWeight,
+
+    /// Relay UDP packets in TCP streams
+    udp_over_tcp: bool,
+
+    /// Country where the server is located, normally an ISO 3166-1 alpha-2 code
+    country: Option<String>,
+    /// City where the server is located
+    city: Option<String>,
+    /// Tags for selecting servers, like features of the server
+    tags: Vec<String>,
+
+    /// Pacing rate of TCP streams to the server, in bytes per second
+    tcp_pacing_rate: Option<u64>,
+
+    /// Multipath TCP between clients and the server
+    mptcp: bool,
+
+    /// Tuning of TCP sockets between clients and the server
+    tcp_tuning: TcpTuning,
+    /// Sizes of buffers of TCP relays of the server
+    relay_buffer_sizes: RelayBufferSizes,
+
+    /// Multiplexing TCP streams in shadowsocks streams
+    mux: Option<MuxConfig>,
+
+    /// Ports of the server listening on a range of ports
+    port_hopping: Option<PortHopping>,
+
+    /// Proxy which TCP connections to the server are made through
+    upstream_proxy: Option<UpstreamProxy>,
+
+    /// Additional addresses of the server to listen on
+    listen_addrs: Vec<ServerListenAddr>,
+    /// Sockets listening on every address of the server
+    listeners: usize,
+
+    /// Padding of TCP streams against traffic analysis
+    #[cfg(feature = "stream-padding")]
+    padding: Option<PaddingConfig>,
+
+    /// Quota of transmitted and received bytes of the server
+    quota: Option<u64>,
+
+    /// Bandwidth limits of clients of the server
+    rate_limit: Option<RateLimitConfig>,
+
+    /// Address which TCP connections with invalid handshakes are relayed to
+    fallback: Option<Address>,
+
+    /// Interface which outbound sockets are bound to, overriding the global one
+    outbound_bind_interface: Option<String>,
+    /// Address which outbound sockets are bound to, overriding the global one
+    outbound_bind_addr: Option<IpAddr>,
+}
+
+/// Error of a password which couldn't be used as the key of an AEAD 2022 method
+#[derive(Debug, Clone, thiserror::Error)]
+#[error("password of {method} must be a base64 encoded {key_len} bytes key")]
+pub struct InvalidKeyError {
+    method: CipherKind,
+    key_len: usize,
+}
+
+/// Make the key of `method` from `password`
+///
+/// Keys of AEAD 2022 methods are not derived from passwords, `password` is the base64 encoded key itself.
+/// Clients of multi-user servers prepend identity keys (iPSKs) of the servers to it, separated by `:`.
+pub fn password_to_key(method: CipherKind, password: &str) -> Result<Box<[u8]>, InvalidKeyError> {
+    #[cfg(feature = "aead-cipher-2022")]
+    if method.is_aead_2022() {
+        return password_to_aead_2022_keys(method, password).map(|(key, _)| key);
+    }
+
+    let mut enc_key = vec![0u8; method.key_len()].into_boxed_slice();
+    openssl_bytes_to_key(password.as_bytes(), &mut enc_key);
+    Ok(enc_key)
+}
+
+/// Check if `method` supports Extensible Identity Headers, which are encrypted by AES
+#[cfg(feature = "aead-cipher-2022")]
+pub(crate) fn method_supports_eih(method: CipherKind) -> bool {
+    matches!(
+        method,
+        CipherKind::AEAD2022_BLAKE3_AES_128_GCM | CipherKind::AEAD2022_BLAKE3_AES_256_GCM
+    )
+}
+
+/// Decode `password` of an AEAD 2022 method, "iPSK:...:uPSK", into the key and identity keys
+#[cfg(feature = "aead-cipher-2022")]
+fn password_to_aead_2022_keys(method: CipherKind, password: &str) -> Result<(Box<[u8]>, Vec<Bytes>), InvalidKeyError> {
+    let err = InvalidKeyError {
+        method,
+        key_len: method.key_len(),
+    };
+
+    let mut keys = Vec::new();
+    for encoded in password.split(':') {
+        match base64::decode(encoded) {
+            Ok(key) if key.len() == method.key_len() => keys.push(Bytes::from(key)),
+            _ => return Err(err),
+        }
+    }
+
+    let key = keys.pop().expect("split returns at least one item");
+    if !keys.is_empty() && !method_supports_eih(method) {
+        return Err(err);
+    }
+
+    Ok((key.to_vec().into_boxed_slice(), keys))
+}
+
+/// Identity keys prepended to the key in `password`, empty for single-user servers
+#[cfg(feature = "aead-cipher-2022")]
+fn password_to_identity_keys(method: CipherKind, password: &str) -> Vec<Bytes> {
+    if !method.is_aead_2022() {
+        return Vec::new();
+    }
+    password_to_aead_2022_keys(method, password)
+        .map(|(_, identity_keys)| identity_keys)
+        .unwrap_or_default()
+}
+
+/// A user of a multi-user server with AEAD 2022 methods
+///
+/// Clients of the user send the user's key (uPSK), which is identified by the first 16 bytes of its BLAKE3 hash in
+/// Extensible Identity Headers ([SIP023](https://github.com/Shadowsocks-NET/shadowsocks-specs/blob/main/2022-2-shadowsocks-2022-extensible-identity-headers.md)).
+#[cfg(feature = "aead-cipher-2022")]
+#[derive(Debug, Clone)]
+pub struct ServerUser {
+    name: String,
+    key: Bytes,
+    identity_hash: Bytes,
+    quota: Option<u64>,
+}
+
+#[cfg(feature = "aead-cipher-2022")]
+impl ServerUser {
+    /// Create a user with its key
+    pub fn new<N, K>(name: N, key: K) -> ServerUser
+    where
+        N: Into<String>,
+        K: Into<Bytes>,
+    {
+        let key = key.into();
+        let hash = blake3::hash(&key);
+        let identity_hash = Bytes::copy_from_slice(&hash.as_bytes()[..16]);
+
+        ServerUser {
+            name: name.into(),
+            key,
+            identity_hash,
+            quota: None,
+        }
+    }
+
+    /// Create a user with its base64 encoded key of `method`
+    pub fn with_encoded_key<N>(name: N, method: CipherKind, password: &str) -> Result<ServerUser, InvalidKeyError>
+    where
+        N: Into<String>,
+    {
+        match base64::decode(password) {
+            Ok(key) if key.len() == method.key_len() && method_supports_eih(method) => Ok(ServerUser::new(name, key)),
+            _ => Err(InvalidKeyError {
+                method,
+                key_len: method.key_len(),
+            }),
+        }
+    }
+
+    /// Name of the user
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Key (uPSK) of the user
+    pub fn key(&self) -> &[u8] {
+        &self.key
+    }
+
+    /// Base64 encoded key of the user
+    pub fn encoded_key(&self) -> String {
+        base64::encode(&self.key)
+    }
+
+    /// Identity of the user in Extensible Identity Headers, the first 16 bytes of key's BLAKE3 hash
+    pub fn identity_hash(&self) -> &[u8] {
+        &self.identity_hash
+    }
+
+    /// Quota of transmitted and received bytes of the user
+    pub fn quota(&self) -> Option<u64> {
+        self.quota
+    }
+
+    /// Set quota of transmitted and received bytes of the user
+    pub fn set_quota(&mut self, quota: u64) {
+        self.quota = Some(quota);
+    }
+}
+
+/// Users of a multi-user server, which could be added and removed while the server is running
+#[cfg(feature = "aead-cipher-2022")]
+#[derive(Debug, Default)]
+pub struct ServerUserManager {
+    users: RwLock<HashMap<Bytes, Arc<ServerUser>>>,
+}
+
+#[cfg(feature = "aead-cipher-2022")]
+impl ServerUserManager {
+    /// Create an empty user manager
+    pub fn new() -> ServerUserManager {
+        ServerUserManager::default()
+    }
+
+    /// Add a user, replacing the user with the same name or key
+    pub fn add_user(&self, user: ServerUser) {
+        let mut users = self.users.write().unwrap();
+        users.retain(|_, u| u.name != user.name);
+        users.insert(user.identity_hash.clone(), Arc::new(user));
+    }
+
+    /// Remove the user with `name`, returns the removed user
+    pub fn remove_user(&self, name: &str) -> Option<Arc<ServerUser>> {
+        let mut users = self.users.write().unwrap();
+        let hash = users.iter().find(|(_, u)| u.name == name).map(|(h, _)| h.clone())?;
+        users.remove(&hash)
+    }
+
+    /// Find the user identified by `identity_hash`
+    pub fn get_user_by_hash(&self, identity_hash: &[u8]) -> Option<Arc<ServerUser>> {
+        self.users.read().unwrap().get(identity_hash).cloned()
+    }
+
+    /// All users, sorted by names
+    pub fn users(&self) -> Vec<Arc<ServerUser>> {
+        let mut users = self.users.read().unwrap().values().cloned().collect::<Vec<_>>();
+        users.sort_by(|a, b| a.name.cmp(&b.name));
+        users
+    }
+
+    /// Number of users
+    pub fn user_count(&self) -> usize {
+        self.users.read().unwrap().len()
+    }
 }
 
 impl ServerConfig {
     /// Create a new `ServerConfig`
+    ///
+    /// Panics if `password` is not a valid key of an AEAD 2022 `method`, which could be checked by [`password_to_key`].
     pub fn new<A, P>(addr: A, password: P, method: CipherKind) -> ServerConfig
     where
         A: Into<ServerAddr>,
@@ -184,40 +484,86 @@ impl ServerConfig {
     {
         let password = password.into();
 
-        let mut enc_key = vec![0u8; method.key_len()].into_boxed_slice();
-        openssl_bytes_to_key(password.as_bytes(), &mut enc_key);
+        let enc_key = password_to_key(method, &password).unwrap_or_else(|err| panic!("{}", err));
 
         ServerConfig {
             addr: addr.into(),
+            #[cfg(feature = "aead-cipher-2022")]
+            identity_keys: Arc::new(password_to_identity_keys(method, &password)),
+            #[cfg(feature = "aead-cipher-2022")]
+            user_manager: None,
             password,
             method,
             enc_key,
             timeout: None,
             plugin: None,
             plugin_addr: None,
+            #[cfg(feature = "transport-websocket")]
+            websocket: None,
+            #[cfg(feature = "transport-obfs")]
+            obfs: None,
+            #[cfg(feature = "transport-shadow-tls")]
+            shadow_tls: None,
+            #[cfg(feature = "transport-quic")]
+            quic: None,
+            #[cfg(feature = "transport-grpc")]
+            grpc: None,
             remarks: None,
             id: None,
             mode: Mode::TcpAndUdp, // Server serves TCP & UDP by default
             weight: ServerWeight::new(),
+            udp_over_tcp: false,
+            country: None,
+            city: None,
+            tags: Vec::new(),
+            tcp_pacing_rate: None,
+            mptcp: false,
+            tcp_tuning: TcpTuning::default(),
+            relay_buffer_sizes: RelayBufferSizes::default(),
+            mux: None,
+            port_hopping: None,
+            upstream_proxy: None,
+            listen_addrs: Vec::new(),
+            listeners: 1,
+            #[cfg(feature = "stream-padding")]
+            padding: None,
+            quota: None,
+            rate_limit: None,
+            fallback: None,
+            outbound_bind_interface: None,
+            outbound_bind_addr: None,
         }
     }
 
     /// Set encryption method
+    ///
+    /// Panics if `password` is not a valid key of an AEAD 2022 `method`, the same as `new`
     pub fn set_method<P>(&mut self, method: CipherKind, password: P)
     where
         P: Into<String>,
     {
         self.method = method;
         self.password = password.into();
-
-        let mut enc_key = vec![0u8; method.key_len()].into_boxed_slice();
-        openssl_bytes_to_key(self.password.as_bytes(), &mut enc_key);
-
-        self.enc_key = enc_key;
+        self.enc_key = password_to_key(method, &self.password).unwrap_or_else(|err| panic!("{}", err));
+        #[cfg(feature = "aead-cipher-2022")]
+        {
+            self.identity_keys = Arc::new(password_to_identity_keys(method, &self.password));
+        }
     }
 
     /// Set plugin
+    ///
+    /// v2ray-plugin and simple-obfs are replaced by the builtin WebSocket and obfs transports if their options are
+    /// supported, and they only relay TCP
     pub fn set_plugin(&mut self, p: PluginConfig) {
+        #[cfg(feature = "transport-websocket")]
+        {
+            self.websocket = WebSocketConfig::from_plugin(&p);
+        }
+        #[cfg(feature = "transport-obfs")]
+        {
+            self.obfs = ObfsConfig::from_plugin(&p);
+        }
         self.plugin = Some(p);
     }
 
@@ -244,6 +590,27 @@ impl ServerConfig {
         self.password.as_str()
     }
 
+    /// Get identity keys (iPSKs) of multi-user servers, which are sent before the key
+    #[cfg(feature = "aead-cipher-2022")]
+    pub fn identity_keys(&self) -> &[Bytes] {
+        &self.identity_keys
+    }
+
+    /// Set users of a multi-user server
+    ///
+    /// Clients have to identify themselves with their users' keys in Extensible Identity Headers, which are
+    /// only supported by `2022-blake3-aes-*-gcm` methods. Key of this server is the identity key (iPSK).
+    #[cfg(feature = "aead-cipher-2022")]
+    pub fn set_user_manager(&mut self, user_manager: Arc<ServerUserManager>) {
+        self.user_manager = Some(user_manager);
+    }
+
+    /// Get users of a multi-user server
+    #[cfg(feature = "aead-cipher-2022")]
+    pub fn user_manager(&self) -> Option<&Arc<ServerUserManager>> {
+        self.user_manager.as_ref()
+    }
+
     /// Get method
     pub fn method(&self) -> CipherKind {
         self.method
@@ -254,6 +621,67 @@ impl ServerConfig {
         self.plugin.as_ref()
     }
 
+    /// Get plugin which has to be started as an external program
+    pub fn external_plugin(&self) -> Option<&PluginConfig> {
+        #[cfg(feature = "transport-websocket")]
+        if self.websocket.is_some() {
+            return None;
+        }
+        #[cfg(feature = "transport-obfs")]
+        if self.obfs.is_some() {
+            return None;
+        }
+        self.plugin.as_ref()
+    }
+
+    /// Get WebSocket transport
+    #[cfg(feature = "transport-websocket")]
+    pub fn websocket(&self) -> Option<&WebSocketConfig> {
+        self.websocket.as_ref()
+    }
+
+    /// Get obfs transport
+    #[cfg(feature = "transport-obfs")]
+    pub fn obfs(&self) -> Option<&ObfsConfig> {
+        self.obfs.as_ref()
+    }
+
+    /// Set ShadowTLS transport
+    #[cfg(feature = "transport-shadow-tls")]
+    pub fn set_shadow_tls(&mut self, config: ShadowTlsConfig) {
+        self.shadow_tls = Some(config);
+    }
+
+    /// Get ShadowTLS transport
+    #[cfg(feature = "transport-shadow-tls")]
+    pub fn shadow_tls(&self) -> Option<&ShadowTlsConfig> {
+        self.shadow_tls.as_ref()
+    }
+
+    /// Set QUIC transport
+    #[cfg(feature = "transport-quic")]
+    pub fn set_quic(&mut self, config: QuicConfig) {
+        self.quic = Some(config);
+    }
+
+    /// Get QUIC transport
+    #[cfg(feature = "transport-quic")]
+    pub fn quic(&self) -> Option<&QuicConfig> {
+        self.quic.as_ref()
+    }
+
+    /// Set gRPC transport
+    #[cfg(feature = "transport-grpc")]
+    pub fn set_grpc(&mut self, config: GrpcConfig) {
+        self.grpc = Some(config);
+    }
+
+    /// Get gRPC transport
+    #[cfg(feature = "transport-grpc")]
+    pub fn grpc(&self) -> Option<&GrpcConfig> {
+        self.grpc.as_ref()
+    }
+
     /// Set plugin address
     pub fn set_plugin_addr(&mut self, a: ServerAddr) {
         self.plugin_addr = Some(a);
@@ -264,9 +692,20 @@ impl ServerConfig {
         self.plugin_addr.as_ref()
     }
 
-    /// Get server's external address
+    /// Get server's external address of TCP, which is plugin's address if plugin relays TCP
     pub fn external_addr(&self) -> &ServerAddr {
-        self.plugin_addr.as_ref().unwrap_or(&self.addr)
+        match self.plugin_addr {
+            Some(ref addr) if self.plugin.as_ref().map(|p| p.plugin_mode.enable_tcp()) != Some(false) => addr,
+            _ => &self.addr,
+        }
+    }
+
+    /// Get server's external address of UDP, which is plugin's address if plugin relays UDP (SIP003u)
+    pub fn udp_external_addr(&self) -> &ServerAddr {
+        match self.plugin_addr {
+            Some(ref addr) if self.plugin.as_ref().map(|p| p.plugin_mode.enable_udp()) == Some(true) => addr,
+            _ => &self.addr,
+        }
     }
 
     /// Set timeout
@@ -315,6 +754,18 @@ impl ServerConfig {
         self.mode = mode;
     }
 
+    /// Check if UDP packets are relayed in TCP streams (UDP-over-TCP)
+    pub fn udp_over_tcp(&self) -> bool {
+        self.udp_over_tcp
+    }
+
+    /// Relay UDP packets in TCP streams (UDP-over-TCP)
+    ///
+    /// Client sends UDP packets to this server in TCP streams. For servers, it allows clients to request UDP-over-TCP.
+    pub fn set_udp_over_tcp(&mut self, udp_over_tcp: bool) {
+        self.udp_over_tcp = udp_over_tcp;
+    }
+
     /// Get server's balancer weight
     pub fn weight(&self) -> &ServerWeight {
         &self.weight
@@ -325,6 +776,243 @@ impl ServerConfig {
         self.weight = weight;
     }
 
+    /// Get server's country
+    pub fn country(&self) -> Option<&str> {
+        self.country.as_deref()
+    }
+
+    /// Set server's country
+    pub fn set_country<S>(&mut self, country: S)
+    where
+        S: Into<String>,
+    {
+        self.country = Some(country.into());
+    }
+
+    /// Get server's city
+    pub fn city(&self) -> Option<&str> {
+        self.city.as_deref()
+    }
+
+    /// Set server's city
+    pub fn set_city<S>(&mut self, city: S)
+    where
+        S: Into<String>,
+    {
+        self.city = Some(city.into());
+    }
+
+    /// Get server's tags
+    pub fn tags(&self) -> &[String] {
+        &self.tags
+    }
+
+    /// Set server's tags
+    pub fn set_tags(&mut self, tags: Vec<String>) {
+        self.tags = tags;
+    }
+
+    /// Get pacing rate of TCP streams to the server, in bytes per second
+    pub fn tcp_pacing_rate(&self) -> Option<u64> {
+        self.tcp_pacing_rate
+    }
+
+    /// Set pacing rate of TCP streams to the server, in bytes per second
+    ///
+    /// Data are sent evenly at this rate instead of in bursts, so that bulk uploads wouldn't fill up buffers of
+    /// a constrained uplink.
+    pub fn set_tcp_pacing_rate(&mut self, rate: u64) {
+        self.tcp_pacing_rate = Some(rate);
+    }
+
+    /// Check if Multipath TCP is used between clients and the server
+    pub fn mptcp(&self) -> bool {
+        self.mptcp
+    }
+
+    /// Use Multipath TCP between clients and the server
+    ///
+    /// Clients connect to the server, and the server listens, with MPTCP sockets, so that connections could use
+    /// multiple paths, like Wi-Fi and cellular, and survive failures of some of them. TCP sockets are used instead
+    /// on platforms or kernels without MPTCP.
+    pub fn set_mptcp(&mut self, mptcp: bool) {
+        self.mptcp = mptcp;
+    }
+
+    /// Get tuning of TCP sockets between clients and the server
+    pub fn tcp_tuning(&self) -> &TcpTuning {
+        &self.tcp_tuning
+    }
+
+    /// Set tuning of TCP sockets between clients and the server
+    ///
+    /// Clients apply it on connections to the server, and the server applies it on both the accepted connections and
+    /// connections to targets, overriding the options configured globally.
+    pub fn set_tcp_tuning(&mut self, tuning: TcpTuning) {
+        self.tcp_tuning = tuning;
+    }
+
+    /// Get sizes of buffers of TCP relays of the server
+    pub fn relay_buffer_sizes(&self) -> RelayBufferSizes {
+        self.relay_buffer_sizes
+    }
+
+    /// Set sizes of buffers of TCP relays of the server, instead of the size of the shared pool
+    pub fn set_relay_buffer_sizes(&mut self, sizes: RelayBufferSizes) {
+        self.relay_buffer_sizes = sizes;
+    }
+
+    /// Get quota of transmitted and received bytes of the server
+    pub fn quota(&self) -> Option<u64> {
+        self.quota
+    }
+
+    /// Set quota of transmitted and received bytes of the server
+    ///
+    /// Servers stop serving clients after the quota is exceeded, until their traffic counters are reset.
+    pub fn set_quota(&mut self, quota: u64) {
+        self.quota = Some(quota);
+    }
+
+    /// Get bandwidth limits of clients of the server
+    pub fn rate_limit(&self) -> Option<&RateLimitConfig> {
+        self.rate_limit.as_ref()
+    }
+
+    /// Set bandwidth limits of clients of the server
+    ///
+    /// Relaying of TCP streams and UDP packets is delayed while a limit is exceeded, so that one heavy client
+    /// couldn't starve the others.
+    pub fn set_rate_limit(&mut self, rate_limit: RateLimitConfig) {
+        self.rate_limit = Some(rate_limit);
+    }
+
+    /// Get address which TCP connections with invalid handshakes are relayed to
+    pub fn fallback(&self) -> Option<&Address> {
+        self.fallback.as_ref()
+    }
+
+    /// Relay TCP connections with invalid handshakes to `addr`, with the bytes received from clients
+    ///
+    /// Active probers would see an ordinary service, like a web server, instead of connections which are held
+    /// open until they are closed by clients.
+    pub fn set_fallback(&mut self, addr: Address) {
+        self.fallback = Some(addr);
+    }
+
+    /// Get interface which outbound sockets are bound to
+    pub fn outbound_bind_interface(&self) -> Option<&str> {
+        self.outbound_bind_interface.as_deref()
+    }
+
+    /// Bind outbound sockets to interface `iface`, overriding the global one
+    ///
+    /// For servers, outbound sockets are the ones connecting to targets. For locals, they are the ones connecting
+    /// to this server. Different servers could be steered out different uplinks of multi-WAN hosts.
+    pub fn set_outbound_bind_interface(&mut self, iface: String) {
+        self.outbound_bind_interface = Some(iface);
+    }
+
+    /// Get address which outbound sockets are bound to
+    pub fn outbound_bind_addr(&self) -> Option<IpAddr> {
+        self.outbound_bind_addr
+    }
+
+    /// Bind outbound sockets to `addr`, overriding the global one
+    ///
+    /// See `set_outbound_bind_interface` for which sockets are outbound ones.
+    pub fn set_outbound_bind_addr(&mut self, addr: IpAddr) {
+        self.outbound_bind_addr = Some(addr);
+    }
+
+    /// Get multiplexing configuration
+    pub fn mux(&self) -> Option<&MuxConfig> {
+        self.mux.as_ref()
+    }
+
+    /// Multiplex TCP streams in shadowsocks streams
+    ///
+    /// Client opens streams to this server in shared connections. For servers, it allows clients to multiplex.
+    pub fn set_mux(&mut self, config: MuxConfig) {
+        self.mux = Some(config);
+    }
+
+    /// Get port hopping configuration
+    pub fn port_hopping(&self) -> Option<&PortHopping> {
+        self.port_hopping.as_ref()
+    }
+
+    /// Listen on a range of ports, and hop between them on clients
+    ///
+    /// Port of `addr` is the one that traffic and states of the server are recorded by, and clients start from.
+    pub fn set_port_hopping(&mut self, config: PortHopping) {
+        self.port_hopping = Some(config);
+    }
+
+    /// Get server's address with the current port of `port_hopping`, which clients connect to
+    pub fn hopping_addr(&self) -> Option<ServerAddr> {
+        self.port_hopping.as_ref().map(|h| self.addr.with_port(h.port()))
+    }
+
+    /// Get upstream proxy of TCP connections to the server
+    pub fn upstream_proxy(&self) -> Option<&UpstreamProxy> {
+        self.upstream_proxy.as_ref()
+    }
+
+    /// Connect to the server through an upstream proxy, only for clients
+    ///
+    /// Only plain TCP connections are made through it, UDP packets are sent directly unless `udp_over_tcp` is enabled.
+    pub fn set_upstream_proxy(&mut self, proxy: UpstreamProxy) {
+        self.upstream_proxy = Some(proxy);
+    }
+
+    /// Get additional addresses of the server to listen on
+    pub fn listen_addrs(&self) -> &[ServerListenAddr] {
+        &self.listen_addrs
+    }
+
+    /// Listen on additional addresses with the same key and cipher, only for servers
+    ///
+    /// `addr` is still listened on, and is the one that traffic and states of the server are recorded by.
+    pub fn set_listen_addrs(&mut self, addrs: Vec<ServerListenAddr>) {
+        self.listen_addrs = addrs;
+    }
+
+    /// Get number of sockets listening on every address of the server
+    pub fn listeners(&self) -> usize {
+        self.listeners
+    }
+
+    /// Listen on every address with `listeners` sockets, only for servers
+    ///
+    /// More than one socket requires SO_REUSEPORT load balancing, which is only supported on Linux.
+    pub fn set_listeners(&mut self, listeners: usize) {
+        self.listeners = listeners;
+    }
+
+    /// Get padding configuration
+    #[cfg(feature = "stream-padding")]
+    pub fn padding(&self) -> Option<&PaddingConfig> {
+        self.padding.as_ref()
+    }
+
+    /// Carry data of TCP streams in padded frames
+    ///
+    /// Clients and the server must be configured with the same, otherwise streams couldn't be decoded.
+    #[cfg(feature = "stream-padding")]
+    pub fn set_padding(&mut self, config: PaddingConfig) {
+        self.padding = Some(config);
+    }
+
+    /// Check if server is tagged by `tag`, which matches the country, city or any of the tags, case-insensitively
+    pub fn has_tag(&self, tag: &str) -> bool {
+        self.country
+            .iter()
+            .chain(self.city.iter())
+            .chain(self.tags.iter())
+            .any(|t| t.eq_ignore_ascii_case(tag))
+    }
+
     /// Get URL for QRCode
     /// ```plain
     /// ss:// + base64(method:password@host:port)
@@ -355,7 +1043,8 @@ impl ServerConfig {
         url
     }
 
-    /// Parse from [SIP002](https://github.com/shadowsocks/shadowsocks-org/issues/27) URL
+    /// Parse from [SIP002](https://github.com/shadowsocks/shadowsocks-org/issues/27) URL, with the tag (`#TAG`) as
+    /// remarks
     pub fn from_url(encoded: &str) -> Result<ServerConfig, UrlParseError> {
         let parsed = Url::parse(encoded).map_err(UrlParseError::from)?;
 
@@ -399,7 +1088,22 @@ impl ServerConfig {
             }
         };
 
-        let mut svrconfig = ServerConfig::new(addr, pwd.to_owned(), method.parse().unwrap());
+        let method = match method.parse::<CipherKind>() {
+            Ok(m) => m,
+            Err(..) => return Err(UrlParseError::InvalidUserInfo),
+        };
+        if password_to_key(method, pwd).is_err() {
+            return Err(UrlParseError::InvalidUserInfo);
+        }
+
+        let mut svrconfig = ServerConfig::new(addr, pwd.to_owned(), method);
+
+        if let Some(tag) = parsed.fragment() {
+            let tag = percent_decode_str(tag).decode_utf8_lossy();
+            if !tag.is_empty() {
+                svrconfig.set_remarks(tag.into_owned());
+            }
+        }
 
         if let Some(q) = parsed.query() {
             let query = match serde_urlencoded::from_bytes::<Vec<(String, String)>>(q.as_bytes()) {
@@ -423,6 +1127,7 @@ impl ServerConfig {
                             plugin: p.to_owned(),
                             plugin_opts: vsp.next().map(ToOwned::to_owned),
                             plugin_args: Vec::new(), // SIP002 doesn't have arguments for plugins
+                            plugin_mode: Mode::TcpOnly,
                         };
                         svrconfig.set_plugin(plugin);
                     }
@@ -435,7 +1140,41 @@ impl ServerConfig {
 
     /// Check if it is a basic format server
     pub fn is_basic(&self) -> bool {
-        self.remarks.is_none() && self.id.is_none()
+        #[cfg(feature = "transport-shadow-tls")]
+        if self.shadow_tls.is_some() {
+            return false;
+        }
+        #[cfg(feature = "transport-quic")]
+        if self.quic.is_some() {
+            return false;
+        }
+        #[cfg(feature = "transport-grpc")]
+        if self.grpc.is_some() {
+            return false;
+        }
+        #[cfg(feature = "stream-padding")]
+        if self.padding.is_some() {
+            return false;
+        }
+        self.remarks.is_none()
+            && self.id.is_none()
+            && self.country.is_none()
+            && self.city.is_none()
+            && self.tags.is_empty()
+            && self.tcp_pacing_rate.is_none()
+            && !self.mptcp
+            && self.tcp_tuning.is_empty()
+            && self.relay_buffer_sizes.is_default()
+            && self.mux.is_none()
+            && self.port_hopping.is_none()
+            && self.upstream_proxy.is_none()
+            && self.listen_addrs.is_empty()
+            && self.listeners == 1
+            && self.quota.is_none()
+            && self.rate_limit.is_none()
+            && self.fallback.is_none()
+            && self.outbound_bind_interface.is_none()
+            && self.outbound_bind_addr.is_none()
     }
 }
 
@@ -493,6 +1232,105 @@ impl FromStr for ServerConfig {
     }
 }
 
+/// Properties of an encryption method compiled into this build
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct CipherInfo {
+    name: &'static str,
+    kind: CipherKind,
+}
+
+impl CipherInfo {
+    /// Name of the method, which is accepted by `method` in configuration
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// The cipher
+    pub fn kind(&self) -> CipherKind {
+        self.kind
+    }
+
+    /// Category of the cipher
+    pub fn category(&self) -> CipherCategory {
+        self.kind.category()
+    }
+
+    /// Length of key derived from password
+    pub fn key_len(&self) -> usize {
+        self.kind.key_len()
+    }
+
+    /// Length of IV (stream ciphers) or salt (AEAD ciphers), 0 for `none`
+    pub fn nonce_len(&self) -> usize {
+        match self.kind.category() {
+            #[cfg(feature = "stream-cipher")]
+            CipherCategory::Stream => self.kind.iv_len(),
+            CipherCategory::Aead => self.kind.salt_len(),
+            #[cfg(feature = "aead-cipher-2022")]
+            CipherCategory::Aead2022 => self.kind.salt_len(),
+            CipherCategory::None => 0,
+        }
+    }
+
+    /// Length of AEAD tag, 0 for the others
+    pub fn tag_len(&self) -> usize {
+        match self.kind.category() {
+            CipherCategory::Aead => self.kind.tag_len(),
+            #[cfg(feature = "aead-cipher-2022")]
+            CipherCategory::Aead2022 => self.kind.tag_len(),
+            _ => 0,
+        }
+    }
+
+    /// Methods that are standardized by shadowsocks community and considered secure
+    ///
+    /// https://shadowsocks.org/guide/aead.html
+    pub fn is_recommended(&self) -> bool {
+        #[cfg(feature = "aead-cipher-2022")]
+        if matches!(
+            self.kind,
+            CipherKind::AEAD2022_BLAKE3_AES_128_GCM
+                | CipherKind::AEAD2022_BLAKE3_AES_256_GCM
+                | CipherKind::AEAD2022_BLAKE3_CHACHA20_POLY1305
+        ) {
+            return true;
+        }
+        matches!(
+            self.kind,
+            CipherKind::AES_128_GCM | CipherKind::AES_256_GCM | CipherKind::CHACHA20_POLY1305
+        )
+    }
+}
+
+/// All encryption methods compiled into this build, in the same order as `available_ciphers`
+///
+/// Aliases (like `plain` for `none`) are omitted, each `CipherKind` appears only once.
+pub fn supported_ciphers() -> Vec<CipherInfo> {
+    let mut ciphers = Vec::with_capacity(available_ciphers().len());
+    for name in available_ciphers() {
+        let kind = match name.parse::<CipherKind>() {
+            Ok(k) => k,
+            Err(..) => continue,
+        };
+        if ciphers.iter().any(|c: &CipherInfo| c.kind == kind) {
+            continue;
+        }
+        // Prefer the canonical name of kind
+        let name = available_ciphers()
+            .iter()
+            .find(|n| **n == kind.to_string())
+            .unwrap_or(name);
+        ciphers.push(CipherInfo { name, kind });
+    }
+    ciphers
+}
+
+/// Find a supported encryption method by name, aliases are also accepted
+pub fn find_cipher(name: &str) -> Option<CipherInfo> {
+    let kind = name.parse::<CipherKind>().ok()?;
+    supported_ciphers().into_iter().find(|c| c.kind == kind)
+}
+
 /// Server address
 #[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub enum ServerAddr {
@@ -518,6 +1356,14 @@ impl ServerAddr {
             ServerAddr::DomainName(_, p) => p,
         }
     }
+
+    /// Get the same host with another `port`
+    pub fn with_port(&self, port: u16) -> ServerAddr {
+        match *self {
+            ServerAddr::SocketAddr(ref s) => ServerAddr::SocketAddr(SocketAddr::new(s.ip(), port)),
+            ServerAddr::DomainName(ref dm, _) => ServerAddr::DomainName(dm.clone(), port),
+        }
+    }
 }
 
 /// Parse `ServerAddr` error
@@ -612,6 +1458,67 @@ impl From<&ServerAddr> for Address {
     }
 }
 
+/// Additional address of a server to listen on, with a range of ports
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServerListenAddr {
+    /// IP address to listen on
+    pub ip: IpAddr,
+    /// Ports to listen on, all sharing the key and cipher of the server
+    pub ports: RangeInclusive<u16>,
+}
+
+impl ServerListenAddr {
+    /// Create from an IP address and a range of ports
+    pub fn new(ip: IpAddr, ports: RangeInclusive<u16>) -> ServerListenAddr {
+        ServerListenAddr { ip, ports }
+    }
+
+    /// All socket addresses to listen on
+    pub fn socket_addrs(&self) -> impl Iterator<Item = SocketAddr> + '_ {
+        self.ports.clone().map(move |port| SocketAddr::new(self.ip, port))
+    }
+}
+
+impl Display for ServerListenAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.ip {
+            IpAddr::V4(ref ip) => write!(f, "{}:", ip)?,
+            IpAddr::V6(ref ip) => write!(f, "[{}]:", ip)?,
+        }
+        if self.ports.start() == self.ports.end() {
+            write!(f, "{}", self.ports.start())
+        } else {
+            write!(f, "{}-{}", self.ports.start(), self.ports.end())
+        }
+    }
+}
+
+/// Error while parsing `ServerListenAddr` from string
+#[derive(Debug, Clone, Copy)]
+pub struct ServerListenAddrError;
+
+impl Display for ServerListenAddrError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("invalid ServerListenAddr, expecting \"IP:PORT\" or \"IP:START-END\"")
+    }
+}
+
+impl FromStr for ServerListenAddr {
+    type Err = ServerListenAddrError;
+
+    fn from_str(s: &str) -> Result<ServerListenAddr, ServerListenAddrError> {
+        let (ip, ports) = s.rsplit_once(':').ok_or(ServerListenAddrError)?;
+        let ip = ip.strip_prefix('[').and_then(|ip| ip.strip_suffix(']')).unwrap_or(ip);
+        let ip = ip.parse::<IpAddr>().map_err(|_| ServerListenAddrError)?;
+
+        let (start, end) = ports.split_once('-').unwrap_or((ports, ports));
+        match (start.parse::<u16>(), end.parse::<u16>()) {
+            (Ok(start), Ok(end)) if start > 0 && start <= end => Ok(ServerListenAddr::new(ip, start..=end)),
+            _ => Err(ServerListenAddrError),
+        }
+    }
+}
+
 /// Address for Manager server
 #[derive(Debug, Clone)]
 pub enum ManagerAddr {
@@ -751,3 +1658,47 @@ impl FromStr for ReplayAttackPolicy {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn supported_ciphers_unique() {
+        let ciphers = supported_ciphers();
+        for (i, c) in ciphers.iter().enumerate() {
+            assert_eq!(c.name(), c.kind().to_string());
+            assert!(ciphers[i + 1..].iter().all(|o| o.kind() != c.kind()));
+        }
+        assert_eq!(find_cipher("plain").map(|c| c.name()), Some("none"));
+        assert!(find_cipher("aes-256-gcm").unwrap().is_recommended());
+    }
+
+    #[cfg(feature = "aead-cipher-2022")]
+    #[test]
+    fn aead_2022_identity_keys() {
+        let method = CipherKind::AEAD2022_BLAKE3_AES_128_GCM;
+        let password = "Hpui/+6XlOL9GbN+b2vQqQ==:n9dlyeKHhcPDA0Gq2ayocA==";
+
+        let svr_cfg = ServerConfig::new("127.0.0.1:8388".parse::<SocketAddr>().unwrap(), password, method);
+        let user = ServerUser::with_encoded_key("u", method, "n9dlyeKHhcPDA0Gq2ayocA==").unwrap();
+        assert_eq!(svr_cfg.identity_keys().len(), 1);
+        assert_eq!(svr_cfg.key(), user.key());
+
+        // Identity headers are only for AES ciphers
+        assert!(password_to_key(CipherKind::AEAD2022_BLAKE3_CHACHA20_POLY1305, password).is_err());
+        assert!(ServerUser::with_encoded_key(
+            "u",
+            CipherKind::AEAD2022_BLAKE3_CHACHA20_POLY1305,
+            "n9dlyeKHhcPDA0Gq2ayocA=="
+        )
+        .is_err());
+
+        let user_manager = ServerUserManager::new();
+        let identity_hash = user.identity_hash().to_vec();
+        user_manager.add_user(user);
+        assert_eq!(user_manager.get_user_by_hash(&identity_hash).unwrap().name(), "u");
+        assert!(user_manager.remove_user("u").is_some());
+        assert!(user_manager.get_user_by_hash(&identity_hash).is_none());
+    }
+}
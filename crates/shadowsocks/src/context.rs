@@ -1,17 +1,26 @@
 //! Shadowsocks service context
 
 use std::{io, net::SocketAddr, sync::Arc};
+#[cfg(feature = "aead-cipher-2022")]
+use std::{sync::Mutex, time::Duration};
 
 use byte_string::ByteStr;
 use log::warn;
+#[cfg(feature = "aead-cipher-2022")]
+use lru_time_cache::LruCache;
 
+#[cfg(feature = "aead-cipher-2022")]
+use crate::relay::tcprelay::aead_2022::get_now_timestamp;
 use crate::{
     config::{ReplayAttackPolicy, ServerType},
     crypto::v1::random_iv_or_salt,
     dns_resolver::DnsResolver,
+    net::IpStrategy,
     security::replay::ReplayProtector,
 };
 
+pub use crate::security::replay::NonceObserver;
+
 /// Service context
 pub struct Context {
     // Protector against replay attack
@@ -19,25 +28,82 @@ pub struct Context {
     replay_protector: ReplayProtector,
     // Policy against replay attack
     replay_policy: ReplayAttackPolicy,
+    // Observer of the accepted nonces, for sharing them with the other servers
+    nonce_observer: Option<Arc<dyn NonceObserver>>,
+    // Salts of AEAD 2022 streams seen recently, repeated ones are always rejected regardless of `replay_policy`
+    #[cfg(feature = "aead-cipher-2022")]
+    salt_cache: Mutex<LruCache<Vec<u8>, ()>>,
+    // Maximum difference between timestamps of AEAD 2022 peers and the local clock
+    #[cfg(feature = "aead-cipher-2022")]
+    timestamp_tolerance: Duration,
 
     // trust-dns resolver, which supports REAL asynchronous resolving, and also customizable
     dns_resolver: Arc<DnsResolver>,
 
-    // Connect IPv6 address first
-    ipv6_first: bool,
+    // Choosing among resolved IPv4 and IPv6 addresses
+    ip_strategy: IpStrategy,
 }
 
 /// `Context` for sharing between services
 pub type SharedContext = Arc<Context>;
 
+/// Default maximum difference between timestamps of AEAD 2022 peers and the local clock
+#[cfg(feature = "aead-cipher-2022")]
+pub const DEFAULT_TIMESTAMP_TOLERANCE: Duration = Duration::from_secs(30);
+
+/// Salts of AEAD 2022 streams are remembered for twice of the timestamp tolerance, streams with older timestamps are
+/// rejected before checking salts
+#[cfg(feature = "aead-cipher-2022")]
+fn salt_cache(timestamp_tolerance: Duration) -> Mutex<LruCache<Vec<u8>, ()>> {
+    Mutex::new(LruCache::with_expiry_duration(timestamp_tolerance * 2))
+}
+
+/// Error of rejecting a repeated nonce (IV/salt) with `ReplayAttackPolicy::Reject`
+#[derive(Debug, thiserror::Error)]
+#[error("detected repeated nonce (iv/salt)")]
+pub struct ReplayedNonceError;
+
+impl ReplayedNonceError {
+    /// Check if `err` is caused by a rejected repeated nonce
+    pub fn is_replayed_nonce(err: &io::Error) -> bool {
+        matches!(err.get_ref(), Some(e) if e.is::<ReplayedNonceError>())
+    }
+}
+
+/// Error of rejecting an AEAD 2022 header, whose timestamp is too far from the local clock
+///
+/// Usually the clock of the peer or this host is wrong, like routers without RTC before syncing time.
+#[derive(Debug, thiserror::Error)]
+#[error("timestamp {timestamp} of peer is too far from local clock {now} (tolerance {tolerance}s), check clocks")]
+pub struct ClockSkewError {
+    /// UNIX timestamp sent by the peer
+    pub timestamp: u64,
+    /// UNIX timestamp of the local clock
+    pub now: u64,
+    /// Tolerance in seconds
+    pub tolerance: u64,
+}
+
+impl ClockSkewError {
+    /// Check if `err` is caused by a timestamp out of the tolerance
+    pub fn is_clock_skew(err: &io::Error) -> bool {
+        matches!(err.get_ref(), Some(e) if e.is::<ClockSkewError>())
+    }
+}
+
 impl Context {
     /// Create a new `Context` for `Client` or `Server`
     pub fn new(config_type: ServerType) -> Context {
         Context {
             replay_protector: ReplayProtector::new(config_type),
             replay_policy: ReplayAttackPolicy::Ignore,
+            nonce_observer: None,
+            #[cfg(feature = "aead-cipher-2022")]
+            salt_cache: salt_cache(DEFAULT_TIMESTAMP_TOLERANCE),
+            #[cfg(feature = "aead-cipher-2022")]
+            timestamp_tolerance: DEFAULT_TIMESTAMP_TOLERANCE,
             dns_resolver: Arc::new(DnsResolver::system_resolver()),
-            ipv6_first: false,
+            ip_strategy: IpStrategy::default(),
         }
     }
 
@@ -96,29 +162,102 @@ impl Context {
 
     /// Check nonce replay
     pub fn check_nonce_replay(&self, nonce: &[u8]) -> io::Result<()> {
+        self.check_nonce_replayed(nonce).map(|_| ())
+    }
+
+    /// Check nonce replay, returns `true` if a repeated nonce was detected but not rejected by policy
+    pub(crate) fn check_nonce_replayed(&self, nonce: &[u8]) -> io::Result<bool> {
         if nonce.is_empty() {
-            return Ok(());
+            return Ok(false);
         }
 
         match self.replay_policy {
-            ReplayAttackPolicy::Ignore => Ok(()),
+            ReplayAttackPolicy::Ignore => Ok(false),
             ReplayAttackPolicy::Detect => {
                 if self.replay_protector.check_nonce_and_set(nonce) {
                     warn!("detected repeated nonce (iv/salt) {:?}", ByteStr::new(nonce));
+                    Ok(true)
+                } else {
+                    self.notify_nonce_accepted(nonce);
+                    Ok(false)
                 }
-                Ok(())
             }
             ReplayAttackPolicy::Reject => {
                 if self.replay_protector.check_nonce_and_set(nonce) {
-                    let err = io::Error::new(io::ErrorKind::Other, "detected repeated nonce (iv/salt)");
-                    Err(err)
+                    Err(io::Error::new(io::ErrorKind::Other, ReplayedNonceError))
                 } else {
-                    Ok(())
+                    self.notify_nonce_accepted(nonce);
+                    Ok(false)
                 }
             }
         }
     }
 
+    /// Check salt replay of AEAD 2022 streams
+    ///
+    /// The protocol requires servers to reject repeated salts, so it is not affected by `ReplayAttackPolicy`.
+    #[cfg(feature = "aead-cipher-2022")]
+    pub(crate) fn check_salt_replay_2022(&self, salt: &[u8]) -> io::Result<()> {
+        let mut salt_cache = self.salt_cache.lock().unwrap();
+        if salt_cache.contains_key(salt) {
+            return Err(io::Error::new(io::ErrorKind::Other, ReplayedNonceError));
+        }
+        salt_cache.insert(salt.to_vec(), ());
+        drop(salt_cache);
+
+        self.notify_nonce_accepted(salt);
+        Ok(())
+    }
+
+    /// Check `timestamp` of an AEAD 2022 header received from peer is within the tolerance of the local clock
+    #[cfg(feature = "aead-cipher-2022")]
+    pub(crate) fn check_timestamp_2022(&self, timestamp: u64) -> io::Result<()> {
+        let now = get_now_timestamp();
+        let tolerance = self.timestamp_tolerance.as_secs();
+        if now.abs_diff(timestamp) > tolerance {
+            let err = ClockSkewError {
+                timestamp,
+                now,
+                tolerance,
+            };
+            return Err(io::Error::new(io::ErrorKind::InvalidData, err));
+        }
+        Ok(())
+    }
+
+    #[inline]
+    fn notify_nonce_accepted(&self, nonce: &[u8]) {
+        if let Some(ref observer) = self.nonce_observer {
+            observer.nonce_accepted(nonce);
+        }
+    }
+
+    /// Record `nonce` as used, which was accepted by another server
+    ///
+    /// Clients sending it later will be treated as replay attacks, as if it was accepted by this server
+    pub fn record_nonce(&self, nonce: &[u8]) {
+        #[cfg(feature = "aead-cipher-2022")]
+        if !nonce.is_empty() {
+            self.salt_cache.lock().unwrap().insert(nonce.to_vec(), ());
+        }
+
+        if nonce.is_empty() || self.replay_policy == ReplayAttackPolicy::Ignore {
+            return;
+        }
+        let _ = self.replay_protector.check_nonce_and_set(nonce);
+    }
+
+    /// Serialize nonces recorded by the replay protector, for restoring them by `import_replay_filter`
+    pub fn export_replay_filter(&self) -> Vec<u8> {
+        self.replay_protector.export()
+    }
+
+    /// Restore nonces serialized by `export_replay_filter`, so they will still be treated as replay attacks after
+    /// restart
+    pub fn import_replay_filter(&self, buf: &[u8]) -> io::Result<()> {
+        self.replay_protector.import(buf)
+    }
+
     /// Set a DNS resolver
     ///
     /// The resolver should be wrapped in an `Arc`, because it could be shared with the other servers
@@ -139,16 +278,71 @@ impl Context {
 
     /// Try to connect IPv6 addresses first if hostname could be resolved to both IPv4 and IPv6
     pub fn set_ipv6_first(&mut self, ipv6_first: bool) {
-        self.ipv6_first = ipv6_first;
+        self.ip_strategy = IpStrategy::from_ipv6_first(ipv6_first);
     }
 
     /// Try to connect IPv6 addresses first if hostname could be resolved to both IPv4 and IPv6
     pub fn ipv6_first(&self) -> bool {
-        self.ipv6_first
+        self.ip_strategy.is_ipv6_first()
+    }
+
+    /// Set strategy of choosing among IPv4 and IPv6 addresses that hostnames are resolved to
+    pub fn set_ip_strategy(&mut self, ip_strategy: IpStrategy) {
+        self.ip_strategy = ip_strategy;
+    }
+
+    /// Strategy of choosing among IPv4 and IPv6 addresses that hostnames are resolved to
+    pub fn ip_strategy(&self) -> IpStrategy {
+        self.ip_strategy
     }
 
     /// Set policy against replay attack
     pub fn set_replay_attack_policy(&mut self, replay_policy: ReplayAttackPolicy) {
         self.replay_policy = replay_policy;
     }
+
+    /// Set maximum difference between timestamps of AEAD 2022 peers and the local clock, `DEFAULT_TIMESTAMP_TOLERANCE`
+    /// by default
+    ///
+    /// Salts are remembered for twice of it, so it should be set before serving.
+    #[cfg(feature = "aead-cipher-2022")]
+    pub fn set_timestamp_tolerance(&mut self, tolerance: Duration) {
+        self.timestamp_tolerance = tolerance;
+        self.salt_cache = salt_cache(tolerance);
+    }
+
+    /// Maximum difference between timestamps of AEAD 2022 peers and the local clock
+    #[cfg(feature = "aead-cipher-2022")]
+    pub fn timestamp_tolerance(&self) -> Duration {
+        self.timestamp_tolerance
+    }
+
+    /// Set observer of the nonces accepted by `check_nonce_replay`
+    pub fn set_nonce_observer(&mut self, observer: Arc<dyn NonceObserver>) {
+        self.nonce_observer = Some(observer);
+    }
+}
+
+#[cfg(all(test, feature = "aead-cipher-2022"))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn check_timestamp_2022_tolerance() {
+        let mut context = Context::new(ServerType::Server);
+        let now = get_now_timestamp();
+
+        assert!(context.check_timestamp_2022(now - 20).is_ok());
+        let err = context.check_timestamp_2022(now + 60).unwrap_err();
+        assert!(ClockSkewError::is_clock_skew(&err));
+        assert!(!ReplayedNonceError::is_replayed_nonce(&err));
+
+        // Routers without RTC may be minutes behind before syncing time
+        context.set_timestamp_tolerance(Duration::from_secs(300));
+        assert!(context.check_timestamp_2022(now + 60).is_ok());
+        assert!(context.check_timestamp_2022(now - 240).is_ok());
+        assert!(ClockSkewError::is_clock_skew(
+            &context.check_timestamp_2022(now - 600).unwrap_err()
+        ));
+    }
 }
@@ -8,17 +8,26 @@ mod resolver;
 mod trust_dns_resolver;
 
 /// Helper macro for resolving host and then process each addresses
+///
+/// Addresses are chosen by `Context::ip_strategy`, or the strategy passed before the closure
 #[macro_export]
 macro_rules! lookup_then {
-    ($context:expr, $addr:expr, $port:expr, |$resolved_addr:ident| $body:block) => {{
+    ($context:expr, $addr:expr, $port:expr, |$resolved_addr:ident| $body:block) => {
+        lookup_then!($context, $addr, $port, $context.ip_strategy(), |$resolved_addr| $body)
+    };
+
+    ($context:expr, $addr:expr, $port:expr, $ip_strategy:expr, |$resolved_addr:ident| $body:block) => {{
         use std::net::SocketAddr;
 
-        let ipv6_first = $context.ipv6_first();
+        let ip_strategy = $ip_strategy;
 
         let mut v4_addrs = Vec::new();
         let mut v6_addrs = Vec::new();
 
         for addr in $context.dns_resolve($addr, $port).await? {
+            if !ip_strategy.is_allowed(&addr) {
+                continue;
+            }
             match addr {
                 SocketAddr::V4(..) => v4_addrs.push(addr),
                 SocketAddr::V6(..) => v6_addrs.push(addr),
@@ -28,14 +37,17 @@ macro_rules! lookup_then {
         let has_v4 = !v4_addrs.is_empty();
         let has_v6 = !v6_addrs.is_empty();
 
-        assert!(has_v4 || has_v6, "resolved empty address");
-
-        if !has_v4 && has_v6 {
+        if !has_v4 && !has_v6 {
+            Err(std::io::Error::new(
+                std::io::ErrorKind::AddrNotAvailable,
+                format!("{}:{} resolved no addresses allowed by {}", $addr, $port, ip_strategy),
+            ))
+        } else if !has_v4 && has_v6 {
             lookup_then!(RESOLVE @ v6_addrs, $resolved_addr, $body)
         } else if has_v4 && !has_v6 {
             lookup_then!(RESOLVE @ v4_addrs, $resolved_addr, $body)
         } else {
-            if ipv6_first {
+            if ip_strategy.is_ipv6_first() {
                 match lookup_then!(RESOLVE @ v6_addrs, $resolved_addr, $body) {
                     Ok(r) => Ok(r),
                     Err(_v6_err) => lookup_then!(RESOLVE @ v4_addrs, $resolved_addr, $body),
@@ -68,160 +80,104 @@ macro_rules! lookup_then {
     }};
 }
 
+/// Helper macro for resolving host and then connecting to the resolved addresses with Happy Eyeballs
+///
+/// Addresses are chosen by `Context::ip_strategy`, or the strategy passed before the closure
 #[macro_export]
 macro_rules! lookup_then_connect {
-    ($context:expr, $addr:expr, $port:expr, |$resolved_addr:ident| $body:block) => {{
-        use futures::future::{self, Either};
+    ($context:expr, $addr:expr, $port:expr, |$resolved_addr:ident| $body:block) => {
+        lookup_then_connect!($context, $addr, $port, $context.ip_strategy(), |$resolved_addr| $body)
+    };
+
+    ($context:expr, $addr:expr, $port:expr, $ip_strategy:expr, |$resolved_addr:ident| $body:block) => {{
+        use futures::stream::{FuturesUnordered, StreamExt};
         use log::trace;
         use std::{net::SocketAddr, time::Duration};
         use tokio::time;
 
-        let ipv6_first = $context.ipv6_first();
+        let ip_strategy = $ip_strategy;
 
         let mut v4_addrs = Vec::new();
         let mut v6_addrs = Vec::new();
 
         for addr in $context.dns_resolve($addr, $port).await? {
+            if !ip_strategy.is_allowed(&addr) {
+                continue;
+            }
             match addr {
                 SocketAddr::V4(..) => v4_addrs.push(addr),
                 SocketAddr::V6(..) => v6_addrs.push(addr),
             }
         }
 
-        let has_v4 = !v4_addrs.is_empty();
-        let has_v6 = !v6_addrs.is_empty();
-
-        assert!(has_v4 || has_v6, "resolved empty address");
-
-        // Happy Eyeballs, RFC6555, RFC8305
+        // Happy Eyeballs, RFC8305
         //
-        // RFC6555 gives an example that Chrome and Firefox uses 300ms
-        const FIXED_DELAY: Duration = Duration::from_millis(300);
-
-        // Connects every addresses synchronously.
-        // TODO: Try another address after FIXED_DELAY if one of the IPs is unreachable.
+        // Addresses are interleaved by family, starting with the preferred one. A new connection attempt is started
+        // every "Connection Attempt Delay", or immediately after the previous one failed. The first established
+        // connection wins, and the others are cancelled.
         //
-        // This would require `future::select_ok`, which will require futures to be `Unpin`
-        // (boxed future, excessive memory allocation).
-
-        let connect_v4 = async {
-            // use futures::FutureExt;
-            //
-            // let mut vfut = Vec::new();
-            //
-            // let mut delay = Duration::from_millis(0);
-            //
-            // for $resolved_addr in v4_addrs {
-            //     vfut.push(
-            //         async move {
-            //             if delay != Duration::from_millis(0) {
-            //                 time::sleep(delay).await;
-            //             }
-            //
-            //             trace!("trying connect {}:{} {}", $addr, $port, $resolved_addr);
-            //
-            //             match $body {
-            //                 Ok(r) => Ok(($resolved_addr, r)),
-            //                 Err(err) => Err(err),
-            //             }
-            //         }
-            //         .boxed(),
-            //     );
-            //
-            //     delay += FIXED_DELAY;
-            // }
-            //
-            // match future::select_ok(vfut).await {
-            //     Ok((r, _)) => Ok(r),
-            //     Err(err) => Err(err),
-            // }
-
-            let mut result = None;
-
-            for $resolved_addr in v4_addrs {
-                trace!("trying connect {}:{} {}", $addr, $port, $resolved_addr);
-
-                match $body {
-                    Ok(r) => {
-                        trace!("connected {}:{} {}", $addr, $port, $resolved_addr);
-                        result = Some(Ok(($resolved_addr, r)));
-                        break;
-                    }
-                    Err(err) => {
-                        result = Some(Err(err));
-                    }
+        // RFC8305 recommends 250ms for "Connection Attempt Delay"
+        const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+        let (preferred_addrs, other_addrs) = if ip_strategy.is_ipv6_first() {
+            (v6_addrs, v4_addrs)
+        } else {
+            (v4_addrs, v6_addrs)
+        };
+
+        let mut addrs = Vec::with_capacity(preferred_addrs.len() + other_addrs.len());
+        let mut preferred_iter = preferred_addrs.into_iter();
+        let mut other_iter = other_addrs.into_iter();
+        loop {
+            match (preferred_iter.next(), other_iter.next()) {
+                (None, None) => break,
+                (preferred, other) => {
+                    addrs.extend(preferred);
+                    addrs.extend(other);
                 }
             }
+        }
 
-            result.expect("impossible")
+        let connect = |$resolved_addr: SocketAddr| async move {
+            let result = $body;
+            ($resolved_addr, result)
         };
 
-        let connect_v6 = async {
-            let mut result = None;
-
-            for $resolved_addr in v6_addrs {
-                trace!("trying connect {}:{} {}", $addr, $port, $resolved_addr);
+        let mut pending_addrs = addrs.into_iter();
+        let mut attempts = FuturesUnordered::new();
+        let mut last_err = None;
 
-                match $body {
-                    Ok(r) => {
-                        trace!("connected {}:{} {}", $addr, $port, $resolved_addr);
-                        result = Some(Ok(($resolved_addr, r)));
-                        break;
-                    }
-                    Err(err) => {
-                        result = Some(Err(err));
-                    }
-                }
+        loop {
+            if let Some(addr) = pending_addrs.next() {
+                trace!("trying connect {}:{} {}", $addr, $port, addr);
+                attempts.push(connect(addr));
             }
 
-            result.expect("impossible")
-        };
+            if attempts.is_empty() {
+                break Err(last_err.unwrap_or_else(|| {
+                    std::io::Error::new(
+                        std::io::ErrorKind::AddrNotAvailable,
+                        format!("{}:{} resolved no addresses allowed by {}", $addr, $port, ip_strategy),
+                    )
+                }));
+            }
 
-        if has_v4 && !has_v6 {
-            connect_v4.await
-        } else if !has_v4 && has_v6 {
-            connect_v6.await
-        } else {
-            if ipv6_first {
-                let v4_fut = async move {
-                    time::sleep(FIXED_DELAY).await;
-                    connect_v4.await
-                };
-                let v6_fut = connect_v6;
-
-                tokio::pin!(v4_fut);
-                tokio::pin!(v6_fut);
-
-                match future::select(v4_fut, v6_fut).await {
-                    Either::Left((v4_res, v6_fut)) => match v4_res {
-                        Ok(res) => Ok(res),
-                        Err(_v4_err) => v6_fut.await,
-                    },
-                    Either::Right((v6_res, v4_fut)) => match v6_res {
-                        Ok(res) => Ok(res),
-                        Err(_v6_err) => v4_fut.await,
-                    },
-                }
-            } else {
-                let v6_fut = async move {
-                    time::sleep(FIXED_DELAY).await;
-                    connect_v6.await
-                };
-                let v4_fut = connect_v4;
-
-                tokio::pin!(v4_fut);
-                tokio::pin!(v6_fut);
-
-                match future::select(v4_fut, v6_fut).await {
-                    Either::Left((v4_res, v6_fut)) => match v4_res {
-                        Ok(res) => Ok(res),
-                        Err(_v4_err) => v6_fut.await,
-                    },
-                    Either::Right((v6_res, v4_fut)) => match v6_res {
-                        Ok(res) => Ok(res),
-                        Err(_v6_err) => v4_fut.await,
-                    },
+            let has_pending = pending_addrs.len() > 0;
+
+            tokio::select! {
+                Some((addr, result)) = attempts.next() => {
+                    match result {
+                        Ok(r) => {
+                            trace!("connected {}:{} {}", $addr, $port, addr);
+                            break Ok((addr, r));
+                        }
+                        Err(err) => {
+                            trace!("failed to connect {}:{} {}, error: {}", $addr, $port, addr, err);
+                            last_err = Some(err);
+                        }
+                    }
                 }
+                _ = time::sleep(CONNECTION_ATTEMPT_DELAY), if has_pending => {}
             }
         }
     }};
@@ -5,18 +5,29 @@ use log::warn;
 use crate::{config::ManagerAddr, context::Context, net::ConnectOpts, relay::udprelay::MAXIMUM_UDP_PAYLOAD_SIZE};
 
 use super::{
+    auth::ManagerAuthenticator,
     datagram::ManagerDatagram,
     error::Error,
     protocol::{
         AddRequest,
         AddResponse,
+        AddUserRequest,
+        AddUserResponse,
+        DrainRequest,
+        DrainResponse,
         ListRequest,
         ListResponse,
+        ListUsersRequest,
+        ListUsersResponse,
         ManagerProtocol,
         PingRequest,
         PingResponse,
         RemoveRequest,
         RemoveResponse,
+        RemoveUserRequest,
+        RemoveUserResponse,
+        ResetTrafficRequest,
+        ResetTrafficResponse,
         StatRequest,
     },
 };
@@ -24,6 +35,7 @@ use super::{
 /// Client for communicating with Manager
 pub struct ManagerClient {
     socket: ManagerDatagram,
+    authenticator: Option<ManagerAuthenticator>,
 }
 
 macro_rules! impl_command {
@@ -44,6 +56,16 @@ impl ManagerClient {
 
     impl_command!(remove, RemoveRequest, RemoveResponse);
 
+    impl_command!(drain, DrainRequest, DrainResponse);
+
+    impl_command!(add_user, AddUserRequest, AddUserResponse);
+
+    impl_command!(remove_user, RemoveUserRequest, RemoveUserResponse);
+
+    impl_command!(list_users, ListUsersRequest, ListUsersResponse);
+
+    impl_command!(reset_traffic, ResetTrafficRequest, ResetTrafficResponse);
+
     /// Create a `ManagerDatagram` for sending data to manager
     pub async fn connect(
         context: &Context,
@@ -52,13 +74,29 @@ impl ManagerClient {
     ) -> Result<ManagerClient, Error> {
         ManagerDatagram::connect(context, bind_addr, connect_opts)
             .await
-            .map(|socket| ManagerClient { socket })
+            .map(|socket| ManagerClient {
+                socket,
+                authenticator: None,
+            })
             .map_err(Into::into)
     }
 
+    /// Authenticate requests with the pre-shared `key` of manager
+    pub fn set_key(&mut self, key: &[u8]) {
+        self.authenticator = Some(ManagerAuthenticator::new(key));
+    }
+
+    fn encode<S: ManagerProtocol>(&self, req: &S) -> Result<Vec<u8>, Error> {
+        let buf = req.to_bytes()?;
+        match self.authenticator {
+            Some(ref authenticator) => Ok(authenticator.sign(&buf)),
+            None => Ok(buf),
+        }
+    }
+
     /// Send `stat` report
     pub async fn stat(&mut self, req: &StatRequest) -> Result<(), Error> {
-        let buf = req.to_bytes()?;
+        let buf = self.encode(req)?;
         let n = self.socket.send(&buf).await?;
         if n != buf.len() {
             warn!("manager send {} bytes != buffer {} bytes", n, buf.len());
@@ -71,7 +109,7 @@ impl ManagerClient {
         S: ManagerProtocol,
         R: ManagerProtocol,
     {
-        let buf = req.to_bytes()?;
+        let buf = self.encode(req)?;
         let n = self.socket.send(&buf).await?;
         if n != buf.len() {
             warn!("manager send {} bytes != buffer {} bytes", n, buf.len());
@@ -7,6 +7,7 @@ use log::warn;
 use crate::{config::ManagerAddr, context::Context, relay::udprelay::MAXIMUM_UDP_PAYLOAD_SIZE};
 
 use super::{
+    auth::ManagerAuthenticator,
     datagram::{ManagerDatagram, ManagerSocketAddr},
     error::Error,
     protocol::{ManagerProtocol, ManagerRequest},
@@ -15,6 +16,7 @@ use super::{
 /// Manager server Listener
 pub struct ManagerListener {
     socket: ManagerDatagram,
+    authenticator: Option<ManagerAuthenticator>,
 }
 
 impl ManagerListener {
@@ -22,13 +24,25 @@ impl ManagerListener {
     pub async fn bind(context: &Context, bind_addr: &ManagerAddr) -> io::Result<ManagerListener> {
         ManagerDatagram::bind(context, bind_addr)
             .await
-            .map(|socket| ManagerListener { socket })
+            .map(|socket| ManagerListener {
+                socket,
+                authenticator: None,
+            })
+    }
+
+    /// Only accept requests authenticated with the pre-shared `key`
+    pub fn set_key(&mut self, key: &[u8]) {
+        self.authenticator = Some(ManagerAuthenticator::new(key));
     }
 
     pub async fn recv_from(&mut self) -> Result<(ManagerRequest, ManagerSocketAddr), Error> {
         let mut buf = [0u8; MAXIMUM_UDP_PAYLOAD_SIZE];
         let (n, peer_addr) = self.socket.recv_from(&mut buf).await?;
-        Ok((ManagerRequest::from_bytes(&buf[..n])?, peer_addr))
+        let req = match self.authenticator {
+            Some(ref mut authenticator) => authenticator.verify(&buf[..n])?,
+            None => &buf[..n],
+        };
+        Ok((ManagerRequest::from_bytes(req)?, peer_addr))
     }
 
     pub async fn send_to<P: ManagerProtocol>(&mut self, data: &P, target: &ManagerSocketAddr) -> Result<(), Error> {
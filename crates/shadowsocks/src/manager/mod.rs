@@ -2,8 +2,9 @@
 //!
 //! Service for managing multiple relay servers. [Manage Multiple Users](https://github.com/shadowsocks/shadowsocks/wiki/Manage-Multiple-Users)
 
-pub use self::{client::ManagerClient, listener::ManagerListener};
+pub use self::{auth::ManagerAuthenticator, client::ManagerClient, listener::ManagerListener};
 
+pub mod auth;
 pub mod client;
 pub mod datagram;
 pub mod error;
@@ -30,7 +30,24 @@ pub struct ServerConfig {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub plugin_opts: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
+    pub plugin_mode: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub mode: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub users: Option<Vec<ServerUserConfig>>,
+    /// Quota of transmitted and received bytes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub quota: Option<u64>,
+}
+
+/// User of multi-user servers (AEAD 2022)
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ServerUserConfig {
+    pub name: String,
+    pub password: String,
+    /// Quota of transmitted and received bytes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub quota: Option<u64>,
 }
 
 /// `add` request
@@ -128,6 +145,264 @@ impl ManagerProtocol for RemoveResponse {
     }
 }
 
+/// `drain` request, stops accepting new connections of the server, and removes it after established ones are
+/// closed or timed out
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DrainRequest {
+    pub server_port: u16,
+}
+
+impl ManagerProtocol for DrainRequest {
+    fn from_bytes(buf: &[u8]) -> Result<Self, Error> {
+        let mut nsplit = buf.splitn(2, |b| *b == b':');
+
+        let cmd = nsplit.next().expect("first element shouldn't be None");
+        let cmd = str::from_utf8(cmd)?.trim();
+        if cmd != "drain" {
+            return Err(Error::UnrecognizedCommand(cmd.to_owned()));
+        }
+
+        match nsplit.next() {
+            None => Err(Error::MissingParameter),
+            Some(param) => {
+                let req = serde_json::from_slice(param)?;
+                Ok(req)
+            }
+        }
+    }
+
+    fn to_bytes(&self) -> Result<Vec<u8>, Error> {
+        let mut buf = b"drain: ".to_vec();
+        serde_json::to_writer(&mut buf, self)?;
+        buf.push(b'\n');
+        Ok(buf)
+    }
+}
+
+/// `drain` response
+#[derive(Debug, Clone)]
+pub struct DrainResponse(pub String);
+
+impl ManagerProtocol for DrainResponse {
+    fn from_bytes(buf: &[u8]) -> Result<Self, Error> {
+        Ok(DrainResponse(str::from_utf8(buf)?.trim().to_owned()))
+    }
+
+    fn to_bytes(&self) -> Result<Vec<u8>, Error> {
+        let mut v = self.0.as_bytes().to_owned();
+        v.push(b'\n');
+        Ok(v)
+    }
+}
+
+/// `add_user` request, or `addu` for short
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AddUserRequest {
+    pub server_port: u16,
+    pub name: String,
+    pub password: String,
+    /// Quota of transmitted and received bytes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub quota: Option<u64>,
+}
+
+impl ManagerProtocol for AddUserRequest {
+    fn from_bytes(buf: &[u8]) -> Result<Self, Error> {
+        let mut nsplit = buf.splitn(2, |b| *b == b':');
+
+        let cmd = nsplit.next().expect("first element shouldn't be None");
+        let cmd = str::from_utf8(cmd)?.trim();
+        if cmd != "add_user" && cmd != "addu" {
+            return Err(Error::UnrecognizedCommand(cmd.to_owned()));
+        }
+
+        match nsplit.next() {
+            None => Err(Error::MissingParameter),
+            Some(param) => {
+                let req = serde_json::from_slice(param)?;
+                Ok(req)
+            }
+        }
+    }
+
+    fn to_bytes(&self) -> Result<Vec<u8>, Error> {
+        let mut buf = b"add_user: ".to_vec();
+        serde_json::to_writer(&mut buf, self)?;
+        buf.push(b'\n');
+        Ok(buf)
+    }
+}
+
+/// `add_user` response
+#[derive(Debug, Clone)]
+pub struct AddUserResponse(pub String);
+
+impl ManagerProtocol for AddUserResponse {
+    fn from_bytes(buf: &[u8]) -> Result<Self, Error> {
+        Ok(AddUserResponse(str::from_utf8(buf)?.trim().to_owned()))
+    }
+
+    fn to_bytes(&self) -> Result<Vec<u8>, Error> {
+        let mut v = self.0.as_bytes().to_owned();
+        v.push(b'\n');
+        Ok(v)
+    }
+}
+
+/// `remove_user` request, or `removeu` for short
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RemoveUserRequest {
+    pub server_port: u16,
+    pub name: String,
+}
+
+impl ManagerProtocol for RemoveUserRequest {
+    fn from_bytes(buf: &[u8]) -> Result<Self, Error> {
+        let mut nsplit = buf.splitn(2, |b| *b == b':');
+
+        let cmd = nsplit.next().expect("first element shouldn't be None");
+        let cmd = str::from_utf8(cmd)?.trim();
+        if cmd != "remove_user" && cmd != "removeu" {
+            return Err(Error::UnrecognizedCommand(cmd.to_owned()));
+        }
+
+        match nsplit.next() {
+            None => Err(Error::MissingParameter),
+            Some(param) => {
+                let req = serde_json::from_slice(param)?;
+                Ok(req)
+            }
+        }
+    }
+
+    fn to_bytes(&self) -> Result<Vec<u8>, Error> {
+        let mut buf = b"remove_user: ".to_vec();
+        serde_json::to_writer(&mut buf, self)?;
+        buf.push(b'\n');
+        Ok(buf)
+    }
+}
+
+/// `remove_user` response
+#[derive(Debug, Clone)]
+pub struct RemoveUserResponse(pub String);
+
+impl ManagerProtocol for RemoveUserResponse {
+    fn from_bytes(buf: &[u8]) -> Result<Self, Error> {
+        Ok(RemoveUserResponse(str::from_utf8(buf)?.trim().to_owned()))
+    }
+
+    fn to_bytes(&self) -> Result<Vec<u8>, Error> {
+        let mut v = self.0.as_bytes().to_owned();
+        v.push(b'\n');
+        Ok(v)
+    }
+}
+
+/// `list_users` request, or `listu` for short, lists users of a multi-user server
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ListUsersRequest {
+    pub server_port: u16,
+}
+
+impl ManagerProtocol for ListUsersRequest {
+    fn from_bytes(buf: &[u8]) -> Result<Self, Error> {
+        let mut nsplit = buf.splitn(2, |b| *b == b':');
+
+        let cmd = nsplit.next().expect("first element shouldn't be None");
+        let cmd = str::from_utf8(cmd)?.trim();
+        if cmd != "list_users" && cmd != "listu" {
+            return Err(Error::UnrecognizedCommand(cmd.to_owned()));
+        }
+
+        match nsplit.next() {
+            None => Err(Error::MissingParameter),
+            Some(param) => {
+                let req = serde_json::from_slice(param)?;
+                Ok(req)
+            }
+        }
+    }
+
+    fn to_bytes(&self) -> Result<Vec<u8>, Error> {
+        let mut buf = b"list_users: ".to_vec();
+        serde_json::to_writer(&mut buf, self)?;
+        buf.push(b'\n');
+        Ok(buf)
+    }
+}
+
+/// `list_users` response
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(transparent)]
+pub struct ListUsersResponse {
+    pub users: Vec<ServerUserConfig>,
+}
+
+impl ManagerProtocol for ListUsersResponse {
+    fn from_bytes(buf: &[u8]) -> Result<Self, Error> {
+        let req = serde_json::from_slice(buf)?;
+        Ok(req)
+    }
+
+    fn to_bytes(&self) -> Result<Vec<u8>, Error> {
+        let mut buf = serde_json::to_vec(self)?;
+        buf.push(b'\n');
+        Ok(buf)
+    }
+}
+
+/// `reset_traffic` request, resets counters of the server or one of its users
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ResetTrafficRequest {
+    pub server_port: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+}
+
+impl ManagerProtocol for ResetTrafficRequest {
+    fn from_bytes(buf: &[u8]) -> Result<Self, Error> {
+        let mut nsplit = buf.splitn(2, |b| *b == b':');
+
+        let cmd = nsplit.next().expect("first element shouldn't be None");
+        let cmd = str::from_utf8(cmd)?.trim();
+        if cmd != "reset_traffic" {
+            return Err(Error::UnrecognizedCommand(cmd.to_owned()));
+        }
+
+        match nsplit.next() {
+            None => Err(Error::MissingParameter),
+            Some(param) => {
+                let req = serde_json::from_slice(param)?;
+                Ok(req)
+            }
+        }
+    }
+
+    fn to_bytes(&self) -> Result<Vec<u8>, Error> {
+        let mut buf = b"reset_traffic: ".to_vec();
+        serde_json::to_writer(&mut buf, self)?;
+        buf.push(b'\n');
+        Ok(buf)
+    }
+}
+
+/// `reset_traffic` response
+#[derive(Debug, Clone)]
+pub struct ResetTrafficResponse(pub String);
+
+impl ManagerProtocol for ResetTrafficResponse {
+    fn from_bytes(buf: &[u8]) -> Result<Self, Error> {
+        Ok(ResetTrafficResponse(str::from_utf8(buf)?.trim().to_owned()))
+    }
+
+    fn to_bytes(&self) -> Result<Vec<u8>, Error> {
+        let mut v = self.0.as_bytes().to_owned();
+        v.push(b'\n');
+        Ok(v)
+    }
+}
+
 /// `list` request
 #[derive(Debug, Clone)]
 pub struct ListRequest;
@@ -275,6 +550,11 @@ impl<E: ToString> ManagerProtocol for ErrorResponse<E> {
 pub enum ManagerRequest {
     Add(AddRequest),
     Remove(RemoveRequest),
+    Drain(DrainRequest),
+    AddUser(AddUserRequest),
+    RemoveUser(RemoveUserRequest),
+    ListUsers(ListUsersRequest),
+    ResetTraffic(ResetTrafficRequest),
     List(ListRequest),
     Ping(PingRequest),
     Stat(StatRequest),
@@ -286,6 +566,11 @@ impl ManagerRequest {
         match *self {
             ManagerRequest::Add(..) => "add",
             ManagerRequest::Remove(..) => "remove",
+            ManagerRequest::Drain(..) => "drain",
+            ManagerRequest::AddUser(..) => "add_user",
+            ManagerRequest::RemoveUser(..) => "remove_user",
+            ManagerRequest::ListUsers(..) => "list_users",
+            ManagerRequest::ResetTraffic(..) => "reset_traffic",
             ManagerRequest::List(..) => "list",
             ManagerRequest::Ping(..) => "ping",
             ManagerRequest::Stat(..) => "stat",
@@ -298,6 +583,11 @@ impl ManagerProtocol for ManagerRequest {
         match *self {
             ManagerRequest::Add(ref req) => req.to_bytes(),
             ManagerRequest::Remove(ref req) => req.to_bytes(),
+            ManagerRequest::Drain(ref req) => req.to_bytes(),
+            ManagerRequest::AddUser(ref req) => req.to_bytes(),
+            ManagerRequest::RemoveUser(ref req) => req.to_bytes(),
+            ManagerRequest::ListUsers(ref req) => req.to_bytes(),
+            ManagerRequest::ResetTraffic(ref req) => req.to_bytes(),
             ManagerRequest::List(ref req) => req.to_bytes(),
             ManagerRequest::Ping(ref req) => req.to_bytes(),
             ManagerRequest::Stat(ref req) => req.to_bytes(),
@@ -323,6 +613,41 @@ impl ManagerProtocol for ManagerRequest {
                     Ok(ManagerRequest::Remove(req))
                 }
             },
+            "drain" => match nsplit.next() {
+                None => Err(Error::MissingParameter),
+                Some(param) => {
+                    let req = serde_json::from_slice(param)?;
+                    Ok(ManagerRequest::Drain(req))
+                }
+            },
+            "add_user" | "addu" => match nsplit.next() {
+                None => Err(Error::MissingParameter),
+                Some(param) => {
+                    let req = serde_json::from_slice(param)?;
+                    Ok(ManagerRequest::AddUser(req))
+                }
+            },
+            "remove_user" | "removeu" => match nsplit.next() {
+                None => Err(Error::MissingParameter),
+                Some(param) => {
+                    let req = serde_json::from_slice(param)?;
+                    Ok(ManagerRequest::RemoveUser(req))
+                }
+            },
+            "list_users" | "listu" => match nsplit.next() {
+                None => Err(Error::MissingParameter),
+                Some(param) => {
+                    let req = serde_json::from_slice(param)?;
+                    Ok(ManagerRequest::ListUsers(req))
+                }
+            },
+            "reset_traffic" => match nsplit.next() {
+                None => Err(Error::MissingParameter),
+                Some(param) => {
+                    let req = serde_json::from_slice(param)?;
+                    Ok(ManagerRequest::ResetTraffic(req))
+                }
+            },
             "list" => {
                 if nsplit.next().is_some() {
                     return Err(Error::RedundantParameter);
@@ -360,6 +685,8 @@ pub enum Error {
     RedundantParameter,
     #[error("unrecognized command \"{0}\"")]
     UnrecognizedCommand(String),
+    #[error("authentication failed, {0}")]
+    AuthenticationFailed(&'static str),
 }
 
 impl From<Error> for io::Error {
@@ -0,0 +1,78 @@
+//! Streams carrying data in frames of another protocol
+//!
+//! Frames are encoded into a write buffer, which is sent before the next frame could be encoded, so that
+//! frames are never interleaved by partial writes of the underlying stream.
+
+use std::{
+    io::{self, ErrorKind},
+    pin::Pin,
+    task::{self, Poll},
+};
+
+use bytes::{Buf, BytesMut};
+use futures::ready;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// Bytes read from the underlying stream at once
+const READ_CHUNK_SIZE: usize = 16 * 1024;
+
+/// Reads more data from `stream` into `read_buf`, returns `false` on EOF
+pub fn poll_fill<S>(stream: &mut S, read_buf: &mut BytesMut, cx: &mut task::Context<'_>) -> Poll<io::Result<bool>>
+where
+    S: AsyncRead + Unpin,
+{
+    let filled = read_buf.len();
+    read_buf.resize(filled + READ_CHUNK_SIZE, 0);
+
+    let mut buf = ReadBuf::new(&mut read_buf[filled..]);
+    let result = Pin::new(stream).poll_read(cx, &mut buf);
+    let n = buf.filled().len();
+    read_buf.truncate(filled + n);
+
+    ready!(result)?;
+    Ok(n > 0).into()
+}
+
+/// Writes all of `write_buf` to `stream`
+pub fn poll_write_buffered<S>(
+    stream: &mut S,
+    write_buf: &mut BytesMut,
+    cx: &mut task::Context<'_>,
+) -> Poll<io::Result<()>>
+where
+    S: AsyncWrite + Unpin,
+{
+    while !write_buf.is_empty() {
+        let n = ready!(Pin::new(&mut *stream).poll_write(cx, write_buf))?;
+        if n == 0 {
+            return Err(ErrorKind::WriteZero.into()).into();
+        }
+        write_buf.advance(n);
+    }
+    Ok(()).into()
+}
+
+/// Writer encoding data into frames
+pub trait FramedWrite {
+    /// Sends all the frames encoded
+    fn poll_write_encoded(&mut self, cx: &mut task::Context<'_>) -> Poll<io::Result<()>>;
+
+    /// Encodes a prefix of `data` into frames, returns its length
+    fn encode(&mut self, data: &[u8]) -> usize;
+
+    /// `AsyncWrite::poll_write` of framed streams
+    fn poll_write_framed(&mut self, cx: &mut task::Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        ready!(self.poll_write_encoded(cx))?;
+
+        if buf.is_empty() {
+            return Ok(0).into();
+        }
+
+        let n = self.encode(buf);
+
+        // Data have been buffered, errors will be reported by the next call
+        let _ = self.poll_write_encoded(cx);
+
+        Ok(n).into()
+    }
+}
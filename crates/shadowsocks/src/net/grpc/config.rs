@@ -0,0 +1,232 @@
+//! Configuration of the gRPC transport
+
+use std::{
+    fmt::{self, Debug},
+    fs::File,
+    io::{self, BufReader, ErrorKind},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use once_cell::sync::OnceCell;
+use tokio::sync::Mutex;
+use tokio_rustls::{
+    rustls::{Certificate, ClientConfig, OwnedTrustAnchor, PrivateKey, RootCertStore, ServerConfig},
+    TlsAcceptor,
+    TlsConnector,
+};
+
+use super::PooledConnection;
+
+/// ALPN of connections, gRPC requires HTTP/2
+const ALPN_PROTOCOL: &[u8] = b"h2";
+/// Service name of V2Ray's and Xray's `gun` protocol
+const DEFAULT_SERVICE_NAME: &str = "GunService";
+/// Streams sharing one connection by default
+const DEFAULT_MAX_STREAMS: usize = 100;
+
+/// gRPC transport configuration
+///
+/// - `service_name`: name of the service, streams are calls of `/{service_name}/Tun`
+/// - `server_name`: TLS server name and `:authority` of requests, the server's domain name by default,
+///   required for servers with IP addresses
+/// - `tls`: HTTP/2 over TLS, or cleartext HTTP/2 (h2c) for servers behind reverse proxies terminating TLS
+/// - `certificate` / `private_key`: certificate chain and private key of the server, in PEM.
+///   Clients trust `certificate` besides the well-known CAs.
+/// - `max_streams`: streams sharing one connection of a client, `1` disables multiplexing
+#[derive(Clone)]
+pub struct GrpcConfig {
+    service_name: String,
+    server_name: Option<String>,
+    tls: bool,
+    certificate: Option<PathBuf>,
+    private_key: Option<PathBuf>,
+    max_streams: usize,
+    tls_connector: Arc<OnceCell<TlsConnector>>,
+    pub(super) pool: Arc<Mutex<Vec<Arc<PooledConnection>>>>,
+}
+
+impl Debug for GrpcConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("GrpcConfig")
+            .field("service_name", &self.service_name)
+            .field("server_name", &self.server_name)
+            .field("tls", &self.tls)
+            .field("certificate", &self.certificate)
+            .field("private_key", &self.private_key)
+            .field("max_streams", &self.max_streams)
+            .finish()
+    }
+}
+
+impl Default for GrpcConfig {
+    fn default() -> GrpcConfig {
+        GrpcConfig::new()
+    }
+}
+
+fn load_certificates(path: &Path) -> io::Result<Vec<Certificate>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let certs = rustls_pemfile::certs(&mut reader)?;
+    if certs.is_empty() {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            format!("no certificate found in {}", path.display()),
+        ));
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+fn load_private_key(path: &Path) -> io::Result<PrivateKey> {
+    use rustls_pemfile::Item;
+
+    let mut reader = BufReader::new(File::open(path)?);
+
+    // Takes the first private key in the file
+    while let Some(item) = rustls_pemfile::read_one(&mut reader)? {
+        match item {
+            Item::RSAKey(key) | Item::PKCS8Key(key) | Item::ECKey(key) => return Ok(PrivateKey(key)),
+            _ => continue,
+        }
+    }
+
+    Err(io::Error::new(
+        ErrorKind::InvalidData,
+        format!("no private key found in {}", path.display()),
+    ))
+}
+
+impl GrpcConfig {
+    /// Creates a configuration with default values
+    pub fn new() -> GrpcConfig {
+        GrpcConfig {
+            service_name: DEFAULT_SERVICE_NAME.to_owned(),
+            server_name: None,
+            tls: true,
+            certificate: None,
+            private_key: None,
+            max_streams: DEFAULT_MAX_STREAMS,
+            tls_connector: Arc::new(OnceCell::new()),
+            pool: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    /// Set service name
+    pub fn set_service_name<S: Into<String>>(&mut self, service_name: S) {
+        self.service_name = service_name.into();
+    }
+
+    /// Set TLS server name
+    pub fn set_server_name<S: Into<String>>(&mut self, server_name: S) {
+        self.server_name = Some(server_name.into());
+    }
+
+    /// Set whether connections are over TLS
+    pub fn set_tls(&mut self, tls: bool) {
+        self.tls = tls;
+    }
+
+    /// Set path of the certificate chain
+    pub fn set_certificate<P: Into<PathBuf>>(&mut self, path: P) {
+        self.certificate = Some(path.into());
+    }
+
+    /// Set path of the private key
+    pub fn set_private_key<P: Into<PathBuf>>(&mut self, path: P) {
+        self.private_key = Some(path.into());
+    }
+
+    /// Set maximum streams sharing one connection
+    ///
+    /// Panics if `max_streams` is `0`
+    pub fn set_max_streams(&mut self, max_streams: usize) {
+        assert!(max_streams > 0, "max_streams must be > 0");
+        self.max_streams = max_streams;
+    }
+
+    /// Service name
+    pub fn service_name(&self) -> &str {
+        &self.service_name
+    }
+
+    /// TLS server name
+    pub fn server_name(&self) -> Option<&str> {
+        self.server_name.as_deref()
+    }
+
+    /// Whether connections are over TLS
+    pub fn tls(&self) -> bool {
+        self.tls
+    }
+
+    /// Path of the certificate chain
+    pub fn certificate(&self) -> Option<&Path> {
+        self.certificate.as_deref()
+    }
+
+    /// Path of the private key
+    pub fn private_key(&self) -> Option<&Path> {
+        self.private_key.as_deref()
+    }
+
+    /// Maximum streams sharing one connection
+    pub fn max_streams(&self) -> usize {
+        self.max_streams
+    }
+
+    /// Path of requests, `/{service_name}/Tun`
+    pub(crate) fn path(&self) -> String {
+        format!("/{}/Tun", self.service_name)
+    }
+
+    /// TLS connector for clients, trusting `certificate` besides the well-known CAs
+    pub(crate) fn tls_connector(&self) -> io::Result<&TlsConnector> {
+        self.tls_connector.get_or_try_init(|| {
+            let mut store = RootCertStore::empty();
+            store.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|root| {
+                OwnedTrustAnchor::from_subject_spki_name_constraints(root.subject, root.spki, root.name_constraints)
+            }));
+
+            if let Some(ref path) = self.certificate {
+                for cert in load_certificates(path)? {
+                    if let Err(err) = store.add(&cert) {
+                        return Err(io::Error::new(
+                            ErrorKind::InvalidData,
+                            format!("invalid certificate, {}", err),
+                        ));
+                    }
+                }
+            }
+
+            let mut config = ClientConfig::builder()
+                .with_safe_defaults()
+                .with_root_certificates(store)
+                .with_no_client_auth();
+            config.alpn_protocols = vec![ALPN_PROTOCOL.to_vec()];
+
+            Ok(TlsConnector::from(Arc::new(config)))
+        })
+    }
+
+    /// TLS acceptor for servers, with `certificate` and `private_key`
+    pub(crate) fn tls_acceptor(&self) -> io::Result<TlsAcceptor> {
+        let (certificate, private_key) = match (&self.certificate, &self.private_key) {
+            (Some(c), Some(k)) => (c, k),
+            _ => {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidInput,
+                    "grpc server with tls requires certificate and private_key",
+                ));
+            }
+        };
+
+        let mut config = ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(load_certificates(certificate)?, load_private_key(private_key)?)
+            .map_err(|err| io::Error::new(ErrorKind::InvalidInput, err))?;
+        config.alpn_protocols = vec![ALPN_PROTOCOL.to_vec()];
+
+        Ok(TlsAcceptor::from(Arc::new(config)))
+    }
+}
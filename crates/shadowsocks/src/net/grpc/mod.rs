@@ -0,0 +1,380 @@
+//! gRPC transport, carrying shadowsocks' streams in streaming calls of HTTP/2 connections
+//!
+//! ```plain
+//! +------------+                                              +------------+
+//! |  SS Client +-- gRPC (HTTP/2) [TLS] [CDN] (Public Internet) --+  SS Server |
+//! +------------+                                              +------------+
+//! ```
+//!
+//! Every shadowsocks stream is a bidirectional streaming call of `/{service_name}/Tun`, with data in `Hunk`
+//! messages, the same as the `gun` protocol of V2Ray and Xray. CDNs forwarding gRPC proxy it like any other
+//! gRPC service.
+//!
+//! Clients share a connection among up to `max_streams` streams, and open another one if all of them are full.
+
+use std::{
+    io::{self, ErrorKind},
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+        Weak,
+    },
+    time::Duration,
+};
+
+use bytes::Bytes;
+use h2::client::SendRequest;
+use http::{header, Method, Request, Response, StatusCode};
+use log::{debug, error, trace};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpStream as TokioTcpStream,
+    sync::{mpsc, Mutex},
+    task::JoinHandle,
+    time,
+};
+use tokio_rustls::{rustls::ServerName, TlsAcceptor};
+
+use crate::{config::ServerAddr, context::Context};
+
+use self::stream::SocketInfo;
+pub use self::{config::GrpcConfig, stream::GrpcStream};
+use super::{sys::TcpStream as SysTcpStream, AcceptOpts, ConnectOpts, TcpListener};
+
+mod config;
+mod stream;
+
+/// Time limit of TLS and HTTP/2 handshakes of inbound connections
+const ACCEPT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(30);
+/// Streams waiting in `GrpcListener` before being accepted
+const ACCEPT_BACKLOG: usize = 1024;
+/// Pooled connections without streams are closed after being idle for 2 ticks
+const POOL_IDLE_TICK: Duration = Duration::from_secs(30);
+/// Streams opened concurrently by a client in one connection
+const MAX_CONCURRENT_STREAMS: u32 = 1024;
+/// `User-Agent` of requests, the same as grpc-go
+const USER_AGENT: &str = "grpc-go/1.48.0";
+
+/// Object safe `AsyncRead + AsyncWrite`
+trait AsyncIo: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T> AsyncIo for T where T: AsyncRead + AsyncWrite + Send + Unpin {}
+
+/// Type erased plain or TLS stream carrying HTTP/2
+type BoxedIo = Box<dyn AsyncIo>;
+
+fn h2_error(err: h2::Error) -> io::Error {
+    if err.is_io() {
+        err.into_io().expect("h2 io error")
+    } else {
+        io::Error::new(ErrorKind::Other, err)
+    }
+}
+
+/// A client's connection to a server, shared by streams
+pub(crate) struct PooledConnection {
+    send_request: SendRequest<Bytes>,
+    uri: String,
+    info: SocketInfo,
+    closed: Arc<AtomicBool>,
+}
+
+impl PooledConnection {
+    fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Acquire)
+    }
+}
+
+/// Makes an HTTP/2 connection to `addr`
+async fn connect_grpc(
+    context: &Context,
+    config: &GrpcConfig,
+    addr: &ServerAddr,
+    opts: &ConnectOpts,
+) -> io::Result<PooledConnection> {
+    let (stream, host) = match *addr {
+        ServerAddr::SocketAddr(ref sa) => {
+            let host = match config.server_name() {
+                Some(name) => name.to_owned(),
+                None if sa.is_ipv6() => format!("[{}]", sa.ip()),
+                None => sa.ip().to_string(),
+            };
+            (SysTcpStream::connect(*sa, opts).await?, host)
+        }
+        ServerAddr::DomainName(ref domain, port) => {
+            let stream = lookup_then_connect!(context, domain, port, |addr| {
+                SysTcpStream::connect(addr, opts).await
+            })?
+            .1;
+            (stream, config.server_name().unwrap_or(domain).to_owned())
+        }
+    };
+
+    let info = SocketInfo::new(&stream, stream.local_addr()?, stream.peer_addr()?);
+
+    let io: BoxedIo = if config.tls() {
+        let server_name = match ServerName::try_from(host.as_str()) {
+            Ok(n) => n,
+            Err(..) => {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("invalid dnsname \"{}\"", host),
+                ));
+            }
+        };
+        Box::new(config.tls_connector()?.connect(server_name, stream).await?)
+    } else {
+        Box::new(stream)
+    };
+
+    let (send_request, connection) = h2::client::handshake(io).await.map_err(h2_error)?;
+
+    let closed = Arc::new(AtomicBool::new(false));
+    tokio::spawn({
+        let closed = closed.clone();
+        let addr = addr.clone();
+        async move {
+            if let Err(err) = connection.await {
+                debug!("grpc connection to {} closed with error: {}", addr, err);
+            }
+            closed.store(true, Ordering::Release);
+        }
+    });
+
+    let (scheme, default_port) = if config.tls() { ("https", 443) } else { ("http", 80) };
+    let uri = match addr.port() {
+        p if p == default_port => format!("{}://{}{}", scheme, host, config.path()),
+        p => format!("{}://{}:{}{}", scheme, host, p, config.path()),
+    };
+
+    debug!("grpc connection {} -> {} established", info.local_addr, addr);
+
+    Ok(PooledConnection {
+        send_request,
+        uri,
+        info,
+        closed,
+    })
+}
+
+/// Opens a streaming call in `connection`
+async fn open_stream(connection: &Arc<PooledConnection>) -> io::Result<GrpcStream> {
+    let mut send_request = connection.send_request.clone().ready().await.map_err(h2_error)?;
+
+    let request = Request::builder()
+        .method(Method::POST)
+        .uri(connection.uri.as_str())
+        .header(header::CONTENT_TYPE, "application/grpc")
+        .header(header::TE, "trailers")
+        .header(header::USER_AGENT, USER_AGENT)
+        .body(())
+        .map_err(|err| io::Error::new(ErrorKind::InvalidInput, err))?;
+
+    let (response, send) = send_request.send_request(request, false).map_err(h2_error)?;
+    Ok(GrpcStream::client(send, response, connection.clone()))
+}
+
+/// Closes the pooled connection after it has no streams for a while
+async fn monitor_idle(pooled: Weak<PooledConnection>, pool: Arc<Mutex<Vec<Arc<PooledConnection>>>>) {
+    let mut idle_ticks = 0;
+    loop {
+        time::sleep(POOL_IDLE_TICK).await;
+
+        let conn = match pooled.upgrade() {
+            Some(c) => c,
+            None => return,
+        };
+
+        // Referenced by the pool and us
+        if Arc::strong_count(&conn) > 2 && !conn.is_closed() {
+            idle_ticks = 0;
+            continue;
+        }
+
+        idle_ticks += 1;
+        if idle_ticks >= 2 || conn.is_closed() {
+            let mut pool = pool.lock().await;
+            pool.retain(|c| !Arc::ptr_eq(c, &conn));
+            trace!("grpc connection {} closed after idle", conn.info.local_addr);
+            return;
+        }
+    }
+}
+
+impl GrpcConfig {
+    /// Opens a stream to the server at `addr`, in a connection shared by up to `max_streams` streams
+    pub async fn connect(&self, context: &Context, addr: &ServerAddr, opts: &ConnectOpts) -> io::Result<GrpcStream> {
+        let mut pool = self.pool.lock().await;
+        pool.retain(|c| !c.is_closed());
+
+        // Referenced by the pool and streams
+        if let Some(pooled) = pool
+            .iter()
+            .find(|c| Arc::strong_count(c) <= self.max_streams())
+            .cloned()
+        {
+            match open_stream(&pooled).await {
+                Ok(s) => return Ok(s),
+                Err(err) => {
+                    debug!("grpc connection to {} lost, error: {}", addr, err);
+                    pool.retain(|c| !Arc::ptr_eq(c, &pooled));
+                }
+            }
+        }
+
+        let pooled = Arc::new(connect_grpc(context, self, addr, opts).await?);
+        tokio::spawn(monitor_idle(Arc::downgrade(&pooled), self.pool.clone()));
+        pool.push(pooled.clone());
+
+        open_stream(&pooled).await
+    }
+}
+
+type AcceptResult = (GrpcStream, SocketAddr);
+
+/// Accepts calls of an inbound connection, passes their streams to `tx`
+async fn accept_grpc(
+    stream: TokioTcpStream,
+    peer_addr: SocketAddr,
+    path: &str,
+    tls_acceptor: Option<&TlsAcceptor>,
+    tx: &mpsc::Sender<AcceptResult>,
+) -> io::Result<()> {
+    let info = SocketInfo::new(&stream, stream.local_addr()?, peer_addr);
+
+    let handshake = async {
+        let io: BoxedIo = match tls_acceptor {
+            Some(acceptor) => Box::new(acceptor.accept(stream).await?),
+            None => Box::new(stream),
+        };
+        h2::server::Builder::new()
+            .max_concurrent_streams(MAX_CONCURRENT_STREAMS)
+            .handshake::<_, Bytes>(io)
+            .await
+            .map_err(h2_error)
+    };
+
+    let mut connection = match time::timeout(ACCEPT_HANDSHAKE_TIMEOUT, handshake).await {
+        Ok(r) => r?,
+        Err(..) => return Err(io::Error::new(ErrorKind::TimedOut, "grpc handshake timeout")),
+    };
+
+    trace!("grpc client {} connected", peer_addr);
+
+    while let Some(result) = connection.accept().await {
+        let (request, mut respond) = result.map_err(h2_error)?;
+
+        if request.method() != Method::POST || request.uri().path() != path {
+            trace!(
+                "grpc client {} requested {} {}, not found",
+                peer_addr,
+                request.method(),
+                request.uri().path()
+            );
+            let response = Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(())
+                .expect("grpc response");
+            let _ = respond.send_response(response, true);
+            continue;
+        }
+
+        let response = Response::builder()
+            .header(header::CONTENT_TYPE, "application/grpc")
+            .body(())
+            .expect("grpc response");
+        let send = respond.send_response(response, false).map_err(h2_error)?;
+
+        let stream = GrpcStream::server(send, request.into_body(), info);
+        if tx.send((stream, peer_addr)).await.is_err() {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+/// A listener accepting shadowsocks' streams carried by the gRPC transport
+pub struct GrpcListener {
+    local_addr: SocketAddr,
+    rx: mpsc::Receiver<AcceptResult>,
+    accept_task: JoinHandle<()>,
+}
+
+impl Drop for GrpcListener {
+    fn drop(&mut self) {
+        self.accept_task.abort();
+    }
+}
+
+impl GrpcListener {
+    /// Creates a listener binding to `addr`
+    pub async fn bind_with_opts(
+        context: &Context,
+        addr: &ServerAddr,
+        config: &GrpcConfig,
+        accept_opts: AcceptOpts,
+    ) -> io::Result<GrpcListener> {
+        let tls_acceptor = if config.tls() {
+            Some(config.tls_acceptor()?)
+        } else {
+            None
+        };
+
+        let listener = match *addr {
+            ServerAddr::SocketAddr(ref sa) => TcpListener::bind_with_opts(sa, accept_opts).await?,
+            ServerAddr::DomainName(ref domain, port) => {
+                lookup_then!(context, domain, port, |addr| {
+                    TcpListener::bind_with_opts(&addr, accept_opts.clone()).await
+                })?
+                .1
+            }
+        };
+        let local_addr = listener.local_addr()?;
+
+        let (tx, rx) = mpsc::channel(ACCEPT_BACKLOG);
+        let path: Arc<str> = Arc::from(config.path());
+
+        let accept_task = tokio::spawn(async move {
+            loop {
+                let (stream, peer_addr) = match listener.accept().await {
+                    Ok(s) => s,
+                    Err(err) => {
+                        error!("grpc server accept failed with error: {}", err);
+                        time::sleep(Duration::from_secs(1)).await;
+                        continue;
+                    }
+                };
+
+                let path = path.clone();
+                let tls_acceptor = tls_acceptor.clone();
+                let tx = tx.clone();
+                tokio::spawn(async move {
+                    if let Err(err) = accept_grpc(stream, peer_addr, &path, tls_acceptor.as_ref(), &tx).await {
+                        debug!("grpc client {} aborted with error: {}", peer_addr, err);
+                    }
+                });
+            }
+        });
+
+        Ok(GrpcListener {
+            local_addr,
+            rx,
+            accept_task,
+        })
+    }
+
+    /// Accepts a stream, which may be one of many streams in a connection of the peer
+    pub async fn accept(&mut self) -> io::Result<(GrpcStream, SocketAddr)> {
+        match self.rx.recv().await {
+            Some(r) => Ok(r),
+            None => Err(io::Error::new(ErrorKind::Other, "grpc listener closed")),
+        }
+    }
+
+    /// Get local binded address
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.local_addr)
+    }
+}
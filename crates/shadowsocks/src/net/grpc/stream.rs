@@ -0,0 +1,372 @@
+//! Streams carried by the gRPC transport
+
+#[cfg(unix)]
+use std::os::unix::io::{AsRawFd, RawFd};
+#[cfg(windows)]
+use std::os::windows::io::{AsRawSocket, RawSocket};
+use std::{
+    future::Future,
+    io::{self, ErrorKind},
+    net::SocketAddr,
+    pin::Pin,
+    sync::Arc,
+    task::{self, Poll},
+};
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use futures::ready;
+use h2::{client::ResponseFuture, RecvStream, SendStream};
+use http::{HeaderMap, HeaderValue, StatusCode};
+use socket2::SockRef;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::net::framed::FramedWrite;
+
+use super::{h2_error, PooledConnection};
+
+/// Maximum data in a `Hunk` message written at once
+const MAX_HUNK_SIZE: usize = 16 * 1024;
+/// Maximum length of received messages
+const MAX_MESSAGE_SIZE: usize = 4 * 1024 * 1024;
+/// Length of the message prefix, the compressed flag and length
+const MESSAGE_HEADER_LEN: usize = 5;
+
+/// The socket carrying a gRPC connection
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct SocketInfo {
+    /// Local address of the socket
+    pub local_addr: SocketAddr,
+    /// Peer address of the socket
+    pub peer_addr: SocketAddr,
+    #[cfg(unix)]
+    fd: RawFd,
+    #[cfg(windows)]
+    socket: RawSocket,
+}
+
+impl SocketInfo {
+    #[cfg(unix)]
+    pub(crate) fn new<S: AsRawFd>(s: &S, local_addr: SocketAddr, peer_addr: SocketAddr) -> SocketInfo {
+        SocketInfo {
+            local_addr,
+            peer_addr,
+            fd: s.as_raw_fd(),
+        }
+    }
+
+    #[cfg(windows)]
+    pub(crate) fn new<S: AsRawSocket>(s: &S, local_addr: SocketAddr, peer_addr: SocketAddr) -> SocketInfo {
+        SocketInfo {
+            local_addr,
+            peer_addr,
+            socket: s.as_raw_socket(),
+        }
+    }
+}
+
+#[cfg(unix)]
+impl AsRawFd for SocketInfo {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
+    }
+}
+
+#[cfg(windows)]
+impl AsRawSocket for SocketInfo {
+    fn as_raw_socket(&self) -> RawSocket {
+        self.socket
+    }
+}
+
+/// Encodes `data` into a length-prefixed `Hunk { bytes data = 1; }` message
+fn encode_hunk(data: &[u8]) -> Bytes {
+    let mut varint = [0u8; 10];
+    let mut varint_len = 0;
+    let mut n = data.len() as u64;
+    loop {
+        let b = (n & 0x7f) as u8;
+        n >>= 7;
+        if n == 0 {
+            varint[varint_len] = b;
+            varint_len += 1;
+            break;
+        }
+        varint[varint_len] = b | 0x80;
+        varint_len += 1;
+    }
+
+    let hunk_len = 1 + varint_len + data.len();
+    let mut buf = BytesMut::with_capacity(MESSAGE_HEADER_LEN + hunk_len);
+    buf.put_u8(0);
+    buf.put_u32(hunk_len as u32);
+    // field 1, length-delimited
+    buf.put_u8(0x0a);
+    buf.put_slice(&varint[..varint_len]);
+    buf.put_slice(data);
+    buf.freeze()
+}
+
+fn decode_varint(buf: &mut Bytes) -> io::Result<u64> {
+    let mut n = 0u64;
+    for shift in (0..64).step_by(7) {
+        if !buf.has_remaining() {
+            break;
+        }
+        let b = buf.get_u8();
+        n |= ((b & 0x7f) as u64) << shift;
+        if b & 0x80 == 0 {
+            return Ok(n);
+        }
+    }
+    Err(io::Error::new(ErrorKind::InvalidData, "invalid grpc message varint"))
+}
+
+/// Decodes a length-prefixed message at the front of `buf`, returns `None` if it is incomplete
+///
+/// Data of all `data` fields are joined, which also accepts `MultiHunk { repeated bytes data = 1; }` of Xray.
+fn decode_hunk(buf: &mut BytesMut) -> io::Result<Option<Bytes>> {
+    if buf.len() < MESSAGE_HEADER_LEN {
+        return Ok(None);
+    }
+    if buf[0] != 0 {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            "compressed grpc message unsupported",
+        ));
+    }
+    let len = u32::from_be_bytes([buf[1], buf[2], buf[3], buf[4]]) as usize;
+    if len > MAX_MESSAGE_SIZE {
+        return Err(io::Error::new(ErrorKind::InvalidData, "grpc message too long"));
+    }
+    if buf.len() < MESSAGE_HEADER_LEN + len {
+        return Ok(None);
+    }
+
+    buf.advance(MESSAGE_HEADER_LEN);
+    let mut message = buf.split_to(len).freeze();
+
+    let mut data = Bytes::new();
+    while message.has_remaining() {
+        let key = decode_varint(&mut message)?;
+        match key & 0x07 {
+            0 => {
+                decode_varint(&mut message)?;
+            }
+            1 if message.remaining() >= 8 => message.advance(8),
+            2 => {
+                let field_len = decode_varint(&mut message)? as usize;
+                if field_len > message.remaining() {
+                    return Err(io::Error::new(ErrorKind::InvalidData, "invalid grpc message field"));
+                }
+                let field = message.split_to(field_len);
+                if key >> 3 == 1 {
+                    data = if data.is_empty() {
+                        field
+                    } else {
+                        let mut joined = BytesMut::with_capacity(data.len() + field.len());
+                        joined.put_slice(&data);
+                        joined.put_slice(&field);
+                        joined.freeze()
+                    };
+                }
+            }
+            5 if message.remaining() >= 4 => message.advance(4),
+            _ => return Err(io::Error::new(ErrorKind::InvalidData, "invalid grpc message field")),
+        }
+    }
+
+    Ok(Some(data))
+}
+
+enum RecvState {
+    /// Clients wait for response headers before reading the body
+    Response(ResponseFuture),
+    Body(RecvStream),
+}
+
+/// A bidirectional streaming call carrying a shadowsocks stream
+///
+/// Streams of a connection share one TCP socket, socket options applied to any of them take effect on all.
+pub struct GrpcStream {
+    send: SendStream<Bytes>,
+    recv: RecvState,
+    read_buf: BytesMut,
+    read_pending: Bytes,
+    write_pending: Bytes,
+    is_server: bool,
+    end_sent: bool,
+    info: SocketInfo,
+    // Pooled connections are idle if they are not referenced by streams
+    _connection: Option<Arc<PooledConnection>>,
+}
+
+impl GrpcStream {
+    pub(crate) fn client(
+        send: SendStream<Bytes>,
+        response: ResponseFuture,
+        connection: Arc<PooledConnection>,
+    ) -> GrpcStream {
+        GrpcStream {
+            send,
+            recv: RecvState::Response(response),
+            read_buf: BytesMut::new(),
+            read_pending: Bytes::new(),
+            write_pending: Bytes::new(),
+            is_server: false,
+            end_sent: false,
+            info: connection.info,
+            _connection: Some(connection),
+        }
+    }
+
+    pub(crate) fn server(send: SendStream<Bytes>, recv: RecvStream, info: SocketInfo) -> GrpcStream {
+        GrpcStream {
+            send,
+            recv: RecvState::Body(recv),
+            read_buf: BytesMut::new(),
+            read_pending: Bytes::new(),
+            write_pending: Bytes::new(),
+            is_server: true,
+            end_sent: false,
+            info,
+            _connection: None,
+        }
+    }
+
+    /// Returns the local address of the connection's socket
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.info.local_addr)
+    }
+
+    /// Returns the peer address of the connection's socket
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.info.peer_addr)
+    }
+
+    /// Gets the value of the `TCP_NODELAY` option on the connection's socket
+    pub fn nodelay(&self) -> io::Result<bool> {
+        SockRef::from(&self.info).nodelay()
+    }
+
+    /// Sets the value of the `TCP_NODELAY` option on the connection's socket
+    pub fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+        SockRef::from(&self.info).set_nodelay(nodelay)
+    }
+}
+
+impl FramedWrite for GrpcStream {
+    /// Sends `write_pending` within the flow control window
+    fn poll_write_encoded(&mut self, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        while !self.write_pending.is_empty() {
+            self.send.reserve_capacity(self.write_pending.len());
+            let capacity = match ready!(self.send.poll_capacity(cx)) {
+                Some(Ok(c)) => c,
+                Some(Err(err)) => return Err(h2_error(err)).into(),
+                None => return Err(ErrorKind::BrokenPipe.into()).into(),
+            };
+            if capacity == 0 {
+                continue;
+            }
+
+            let data = self.write_pending.split_to(capacity.min(self.write_pending.len()));
+            self.send.send_data(data, false).map_err(h2_error)?;
+        }
+        Ok(()).into()
+    }
+
+    fn encode(&mut self, data: &[u8]) -> usize {
+        let n = data.len().min(MAX_HUNK_SIZE);
+        self.write_pending = encode_hunk(&data[..n]);
+        n
+    }
+}
+
+#[cfg(unix)]
+impl AsRawFd for GrpcStream {
+    fn as_raw_fd(&self) -> RawFd {
+        self.info.as_raw_fd()
+    }
+}
+
+#[cfg(windows)]
+impl AsRawSocket for GrpcStream {
+    fn as_raw_socket(&self) -> RawSocket {
+        self.info.as_raw_socket()
+    }
+}
+
+impl AsyncRead for GrpcStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut task::Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        loop {
+            if !this.read_pending.is_empty() {
+                let n = this.read_pending.len().min(buf.remaining());
+                buf.put_slice(&this.read_pending[..n]);
+                this.read_pending.advance(n);
+                return Ok(()).into();
+            }
+
+            if let Some(data) = decode_hunk(&mut this.read_buf)? {
+                this.read_pending = data;
+                continue;
+            }
+
+            let body = match this.recv {
+                RecvState::Response(ref mut response) => {
+                    let response = ready!(Pin::new(response).poll(cx)).map_err(h2_error)?;
+                    if response.status() != StatusCode::OK {
+                        return Err(io::Error::new(
+                            ErrorKind::Other,
+                            format!("grpc server responded with {}", response.status()),
+                        ))
+                        .into();
+                    }
+                    this.recv = RecvState::Body(response.into_body());
+                    continue;
+                }
+                RecvState::Body(ref mut body) => body,
+            };
+
+            match ready!(body.poll_data(cx)) {
+                Some(Ok(data)) => {
+                    let _ = body.flow_control().release_capacity(data.len());
+                    this.read_buf.extend_from_slice(&data);
+                }
+                Some(Err(err)) => return Err(h2_error(err)).into(),
+                None if this.read_buf.is_empty() => return Ok(()).into(),
+                None => return Err(ErrorKind::UnexpectedEof.into()).into(),
+            }
+        }
+    }
+}
+
+impl AsyncWrite for GrpcStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut task::Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        self.get_mut().poll_write_framed(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        self.get_mut().poll_write_encoded(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        ready!(this.poll_write_encoded(cx))?;
+
+        if !this.end_sent {
+            // Servers end calls with trailers carrying the status
+            if this.is_server {
+                let mut trailers = HeaderMap::new();
+                trailers.insert("grpc-status", HeaderValue::from_static("0"));
+                this.send.send_trailers(trailers).map_err(h2_error)?;
+            } else {
+                this.send.send_data(Bytes::new(), true).map_err(h2_error)?;
+            }
+            this.end_sent = true;
+        }
+
+        Ok(()).into()
+    }
+}
@@ -0,0 +1,183 @@
+//! Handing off listening sockets between processes
+//!
+//! Listening sockets bound by `TcpListener` and `UdpSocket` are recorded after `enable` is called, so they could be
+//! sent to another process, which is usually a newer binary taking over the service. Sockets received from the other
+//! process are reused by binds to the same addresses instead of creating new ones. Records are removed when their
+//! listeners are dropped.
+
+use std::{
+    net::SocketAddr,
+    os::unix::io::{AsRawFd, FromRawFd, RawFd},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+use log::{debug, trace};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use socket2::Socket;
+
+/// Type of listening sockets
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ListenerKind {
+    Tcp,
+    Udp,
+}
+
+/// A listening socket, and the address it was bound to
+#[derive(Debug)]
+pub struct HandoffListener {
+    pub kind: ListenerKind,
+    /// Address requested by `bind`, which may be different from the socket's local address (port 0)
+    pub addr: SocketAddr,
+    pub socket: Socket,
+}
+
+impl HandoffListener {
+    /// Raw file descriptor of the socket, for sending to the other process
+    pub fn as_raw_fd(&self) -> RawFd {
+        self.socket.as_raw_fd()
+    }
+}
+
+/// A recorded listening socket, removed when the listener registered it is dropped
+struct BoundListener {
+    id: u64,
+    listener: HandoffListener,
+}
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static NEXT_BOUND_ID: AtomicU64 = AtomicU64::new(0);
+static BOUND: Lazy<Mutex<Vec<BoundListener>>> = Lazy::new(|| Mutex::new(Vec::new()));
+static INHERITED: Lazy<Mutex<Vec<HandoffListener>>> = Lazy::new(|| Mutex::new(Vec::new()));
+
+/// Record listening sockets bound from now on, which are kept open until handed off
+pub fn enable() {
+    ENABLED.store(true, Ordering::Release);
+}
+
+/// Reuse `listeners` received from the other process for binds to the same addresses
+pub fn inherit(listeners: Vec<HandoffListener>) {
+    INHERITED.lock().unwrap().extend(listeners);
+}
+
+/// Count of inherited sockets that haven't been reused yet
+pub fn inherited_count() -> usize {
+    INHERITED.lock().unwrap().len()
+}
+
+/// Close inherited sockets that haven't been reused
+pub fn clear_inherited() {
+    let listeners = std::mem::take(&mut *INHERITED.lock().unwrap());
+    for listener in listeners {
+        debug!(
+            "inherited {:?} listener {} is not used, closing",
+            listener.kind, listener.addr
+        );
+    }
+}
+
+/// Duplicate listening sockets bound in this process, for sending to the other process
+pub fn bound_listeners() -> Vec<HandoffListener> {
+    let bound = BOUND.lock().unwrap();
+    let mut listeners = Vec::with_capacity(bound.len());
+    for BoundListener { listener, .. } in bound.iter() {
+        match listener.socket.try_clone() {
+            Ok(socket) => listeners.push(HandoffListener {
+                kind: listener.kind,
+                addr: listener.addr,
+                socket,
+            }),
+            Err(err) => debug!("failed to duplicate listener {}, error: {}", listener.addr, err),
+        }
+    }
+    listeners
+}
+
+/// Forget listening sockets bound in this process, after they are handed off
+pub fn clear_bound() {
+    BOUND.lock().unwrap().clear();
+}
+
+/// Reuse listening sockets bound in this process for binds to the same addresses, for recreating servers in this
+/// process without closing their listeners. Sockets that are not reused are closed by `clear_inherited`.
+pub fn recycle_bound() {
+    let bound = std::mem::take(&mut *BOUND.lock().unwrap());
+    inherit(bound.into_iter().map(|b| b.listener).collect());
+}
+
+/// Take the inherited socket bound to `addr`
+pub(crate) fn take_inherited(kind: ListenerKind, addr: &SocketAddr) -> Option<Socket> {
+    let mut inherited = INHERITED.lock().unwrap();
+    let pos = inherited.iter().position(|l| l.kind == kind && l.addr == *addr)?;
+    let listener = inherited.swap_remove(pos);
+    trace!("reusing inherited {:?} listener {}", kind, addr);
+    Some(listener.socket)
+}
+
+/// Record of a listening socket in this process, which is removed when it is dropped
+///
+/// Listeners own it, so sockets closed by the process are not kept open by the record.
+#[derive(Debug)]
+pub(crate) struct BoundRegistration {
+    id: u64,
+}
+
+impl BoundRegistration {
+    /// Keep the socket recorded after the listener is converted into another type, which doesn't own this record
+    pub(crate) fn keep(self) {
+        std::mem::forget(self);
+    }
+}
+
+impl Drop for BoundRegistration {
+    fn drop(&mut self) {
+        let mut bound = BOUND.lock().unwrap();
+        if let Some(pos) = bound.iter().position(|b| b.id == self.id) {
+            let b = bound.swap_remove(pos);
+            trace!(
+                "{:?} listener {} is closed, not recorded",
+                b.listener.kind,
+                b.listener.addr
+            );
+        }
+    }
+}
+
+/// Record a listening socket bound to `addr`, until the returned registration is dropped
+pub(crate) fn register_bound<S: AsRawFd>(
+    kind: ListenerKind,
+    addr: &SocketAddr,
+    socket: &S,
+) -> Option<BoundRegistration> {
+    if !ENABLED.load(Ordering::Acquire) {
+        return None;
+    }
+
+    // Keeps a duplicated descriptor, the original one is owned by the listener
+    let socket = unsafe { Socket::from_raw_fd(socket.as_raw_fd()) };
+    let duplicated = socket.try_clone();
+    std::mem::forget(socket);
+
+    match duplicated {
+        Ok(socket) => {
+            let id = NEXT_BOUND_ID.fetch_add(1, Ordering::Relaxed);
+            BOUND.lock().unwrap().push(BoundListener {
+                id,
+                listener: HandoffListener {
+                    kind,
+                    addr: *addr,
+                    socket,
+                },
+            });
+            Some(BoundRegistration { id })
+        }
+        Err(err) => {
+            debug!("failed to duplicate listener {}, error: {}", addr, err);
+            None
+        }
+    }
+}
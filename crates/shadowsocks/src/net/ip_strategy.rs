@@ -0,0 +1,81 @@
+//! Strategies of choosing among resolved addresses
+
+use std::{
+    fmt::{self, Display},
+    net::SocketAddr,
+    str::FromStr,
+};
+
+/// How to pick among IPv4 and IPv6 addresses that a host name is resolved to
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum IpStrategy {
+    /// Try IPv4 addresses first
+    #[default]
+    PreferIpv4,
+    /// Try IPv6 addresses first
+    PreferIpv6,
+    /// Only connect to IPv4 addresses
+    Ipv4Only,
+    /// Only connect to IPv6 addresses
+    Ipv6Only,
+}
+
+impl IpStrategy {
+    /// `PreferIpv6` if `ipv6_first`, otherwise `PreferIpv4`
+    pub fn from_ipv6_first(ipv6_first: bool) -> IpStrategy {
+        if ipv6_first {
+            IpStrategy::PreferIpv6
+        } else {
+            IpStrategy::PreferIpv4
+        }
+    }
+
+    /// Check if IPv6 addresses are tried first
+    pub fn is_ipv6_first(&self) -> bool {
+        matches!(*self, IpStrategy::PreferIpv6 | IpStrategy::Ipv6Only)
+    }
+
+    /// Check if `addr` could be used
+    pub fn is_allowed(&self, addr: &SocketAddr) -> bool {
+        match *self {
+            IpStrategy::PreferIpv4 | IpStrategy::PreferIpv6 => true,
+            IpStrategy::Ipv4Only => addr.is_ipv4(),
+            IpStrategy::Ipv6Only => addr.is_ipv6(),
+        }
+    }
+}
+
+impl Display for IpStrategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            IpStrategy::PreferIpv4 => f.write_str("prefer_ipv4"),
+            IpStrategy::PreferIpv6 => f.write_str("ipv6_first"),
+            IpStrategy::Ipv4Only => f.write_str("ipv4_only"),
+            IpStrategy::Ipv6Only => f.write_str("ipv6_only"),
+        }
+    }
+}
+
+/// Error while parsing `IpStrategy` from string
+#[derive(Debug, Clone, Copy)]
+pub struct IpStrategyError;
+
+impl Display for IpStrategyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("invalid IpStrategy, expecting \"prefer_ipv4\", \"ipv6_first\", \"ipv4_only\" or \"ipv6_only\"")
+    }
+}
+
+impl FromStr for IpStrategy {
+    type Err = IpStrategyError;
+
+    fn from_str(s: &str) -> Result<IpStrategy, IpStrategyError> {
+        match s {
+            "prefer_ipv4" | "ipv4_first" => Ok(IpStrategy::PreferIpv4),
+            "ipv6_first" | "prefer_ipv6" => Ok(IpStrategy::PreferIpv6),
+            "ipv4_only" => Ok(IpStrategy::Ipv4Only),
+            "ipv6_only" => Ok(IpStrategy::Ipv6Only),
+            _ => Err(IpStrategyError),
+        }
+    }
+}
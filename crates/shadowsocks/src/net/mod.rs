@@ -2,19 +2,49 @@
 
 use std::net::SocketAddr;
 
+#[cfg(unix)]
+pub use self::option::SocketProtector;
 #[cfg(unix)]
 pub use self::sys::uds::{UnixListener, UnixStream};
 pub use self::{
-    option::{AcceptOpts, ConnectOpts, TcpSocketOpts},
+    ip_strategy::{IpStrategy, IpStrategyError},
+    option::{AcceptOpts, ConnectOpts, TcpSocketOpts, TcpTuning},
+    port_hopping::PortHopping,
     sys::{set_tcp_fastopen, socket_bind_dual_stack},
     tcp::{TcpListener, TcpStream},
     udp::UdpSocket,
+    upstream_proxy::{UpstreamProxy, UpstreamProxyError, UpstreamProxyProtocol},
 };
 
+#[cfg(any(
+    feature = "transport-websocket",
+    feature = "transport-shadow-tls",
+    feature = "transport-obfs",
+    feature = "transport-grpc"
+))]
+mod framed;
+#[cfg(feature = "transport-grpc")]
+pub mod grpc;
+#[cfg(unix)]
+pub mod handoff;
+mod ip_strategy;
+#[cfg(feature = "transport-obfs")]
+pub mod obfs;
 mod option;
+mod pacer;
+mod port_hopping;
+#[cfg(feature = "transport-quic")]
+pub mod quic;
+#[cfg(feature = "transport-shadow-tls")]
+pub mod shadow_tls;
 mod sys;
 pub mod tcp;
 pub mod udp;
+mod upstream_proxy;
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+pub mod uring;
+#[cfg(feature = "transport-websocket")]
+pub mod websocket;
 
 /// Address family `AF_INET`, `AF_INET6`
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
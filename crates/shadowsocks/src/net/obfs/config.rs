@@ -0,0 +1,119 @@
+//! Configuration of the obfs transport, read from simple-obfs' options
+
+use std::fmt::{self, Display};
+
+use log::warn;
+
+use crate::plugin::{split_plugin_opts, PluginConfig};
+
+/// Name of simple-obfs' client program, whose options are handled by the obfs transport
+pub const OBFS_LOCAL: &str = "obfs-local";
+/// Name of simple-obfs' server program, whose options are handled by the obfs transport
+pub const OBFS_SERVER: &str = "obfs-server";
+
+/// Host used by simple-obfs if `obfs-host` is not set
+const DEFAULT_HOST: &str = "cloudfront.net";
+
+/// Obfuscating mode
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ObfsMode {
+    /// Looks like a WebSocket upgrade of HTTP/1.1
+    Http,
+    /// Looks like a TLS 1.2 session resumed with a session ticket
+    Tls,
+}
+
+impl Display for ObfsMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            ObfsMode::Http => f.write_str("http"),
+            ObfsMode::Tls => f.write_str("tls"),
+        }
+    }
+}
+
+/// obfs transport configuration, compatible with [simple-obfs](https://github.com/shadowsocks/simple-obfs)
+///
+/// Options are the same as simple-obfs' `plugin_opts`, like `obfs=tls;obfs-host=www.bing.com`
+///
+/// - `obfs`: `http` or `tls`
+/// - `obfs-host`: `Host` header of requests or SNI of ClientHello, `cloudfront.net` by default
+/// - `obfs-uri`: request path of `http` mode, `/` by default
+#[derive(Debug, Clone)]
+pub struct ObfsConfig {
+    mode: ObfsMode,
+    host: String,
+    uri: String,
+}
+
+impl ObfsConfig {
+    /// Parses simple-obfs' options
+    pub fn from_plugin_opts(opts: Option<&str>) -> Result<ObfsConfig, String> {
+        let mut mode = None;
+        let mut config = ObfsConfig {
+            mode: ObfsMode::Http,
+            host: DEFAULT_HOST.to_owned(),
+            uri: "/".to_owned(),
+        };
+
+        for (key, value) in split_plugin_opts(opts.unwrap_or("")) {
+            match (key.as_str(), value) {
+                ("obfs", Some(m)) => match m.as_str() {
+                    "http" => mode = Some(ObfsMode::Http),
+                    "tls" => mode = Some(ObfsMode::Tls),
+                    _ => return Err(format!("unsupported obfs \"{}\"", m)),
+                },
+                ("obfs-host", Some(host)) if !host.is_empty() => config.host = host,
+                ("obfs-uri", Some(uri)) => {
+                    config.uri = if uri.starts_with('/') { uri } else { format!("/{}", uri) };
+                }
+                ("fast-open", None) => {}
+                (key, Some(value)) => return Err(format!("unsupported option \"{}={}\"", key, value)),
+                (key, None) => return Err(format!("unsupported option \"{}\"", key)),
+            }
+        }
+
+        match mode {
+            Some(mode) => {
+                config.mode = mode;
+                Ok(config)
+            }
+            None => Err("missing option \"obfs\"".to_owned()),
+        }
+    }
+
+    /// Creates the configuration if `plugin` is simple-obfs and its options are supported
+    ///
+    /// Plugins given by path, with arguments, or relaying UDP (SIP003u) are always started as external programs.
+    pub fn from_plugin(plugin: &PluginConfig) -> Option<ObfsConfig> {
+        if (plugin.plugin != OBFS_LOCAL && plugin.plugin != OBFS_SERVER)
+            || !plugin.plugin_args.is_empty()
+            || plugin.plugin_mode.enable_udp()
+        {
+            return None;
+        }
+
+        match ObfsConfig::from_plugin_opts(plugin.plugin_opts.as_deref()) {
+            Ok(c) => Some(c),
+            Err(err) => {
+                warn!("{} is started as a plugin program, {}", plugin.plugin, err);
+                None
+            }
+        }
+    }
+
+    /// Obfuscating mode
+    pub fn mode(&self) -> ObfsMode {
+        self.mode
+    }
+
+    /// `Host` header of requests or SNI of ClientHello
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+
+    /// Request path of `http` mode
+    pub fn uri(&self) -> &str {
+        &self.uri
+    }
+}
@@ -0,0 +1,92 @@
+//! obfs transport, compatible with [simple-obfs](https://github.com/shadowsocks/simple-obfs)
+//!
+//! ```plain
+//! +------------+                                           +------------+
+//! |  SS Client +-- HTTP Upgrade / TLS (Public Internet) ---+  SS Server |
+//! +------------+                                           +------------+
+//! ```
+//!
+//! Servers configured with `obfs-local` or `obfs-server` speak its protocols in process, without starting the
+//! plugin program. Neither `http` nor `tls` mode encrypts anything, they only make shadowsocks' streams look like
+//! a WebSocket upgrade or a resumed TLS session.
+
+use std::{io, net::SocketAddr};
+
+use tokio::net::TcpStream as TokioTcpStream;
+
+use crate::{config::ServerAddr, context::Context};
+
+pub use self::{
+    config::{ObfsConfig, ObfsMode, OBFS_LOCAL, OBFS_SERVER},
+    stream::ObfsStream,
+};
+use super::{sys::TcpStream as SysTcpStream, AcceptOpts, ConnectOpts, TcpListener};
+
+mod config;
+mod stream;
+
+impl ObfsConfig {
+    /// Connects to the server at `addr`
+    ///
+    /// Nothing is sent until the first write, which is carried by the obfuscated request.
+    pub async fn connect(
+        &self,
+        context: &Context,
+        addr: &ServerAddr,
+        opts: &ConnectOpts,
+    ) -> io::Result<ObfsStream<SysTcpStream>> {
+        let stream = match *addr {
+            ServerAddr::SocketAddr(ref addr) => SysTcpStream::connect(*addr, opts).await?,
+            ServerAddr::DomainName(ref domain, port) => {
+                lookup_then_connect!(context, domain, port, |addr| {
+                    SysTcpStream::connect(addr, opts).await
+                })?
+                .1
+            }
+        };
+
+        Ok(ObfsStream::client(stream, self, addr.port()))
+    }
+}
+
+/// A listener accepting shadowsocks' streams carried by the obfs transport
+pub struct ObfsListener {
+    listener: TcpListener,
+    config: ObfsConfig,
+}
+
+impl ObfsListener {
+    /// Creates a listener binding to `addr`
+    pub async fn bind_with_opts(
+        context: &Context,
+        addr: &ServerAddr,
+        config: &ObfsConfig,
+        accept_opts: AcceptOpts,
+    ) -> io::Result<ObfsListener> {
+        let listener = match *addr {
+            ServerAddr::SocketAddr(ref sa) => TcpListener::bind_with_opts(sa, accept_opts).await?,
+            ServerAddr::DomainName(ref domain, port) => {
+                lookup_then!(context, domain, port, |addr| {
+                    TcpListener::bind_with_opts(&addr, accept_opts.clone()).await
+                })?
+                .1
+            }
+        };
+
+        Ok(ObfsListener {
+            listener,
+            config: config.clone(),
+        })
+    }
+
+    /// Accepts a stream, whose obfuscated request is read with the first data
+    pub async fn accept(&self) -> io::Result<(ObfsStream<TokioTcpStream>, SocketAddr)> {
+        let (stream, peer_addr) = self.listener.accept().await?;
+        Ok((ObfsStream::server(stream, &self.config), peer_addr))
+    }
+
+    /// Get local binded address
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+}
@@ -0,0 +1,506 @@
+//! Streams obfuscated with simple-obfs' protocols
+
+use std::{
+    io::{self, ErrorKind},
+    ops::Deref,
+    pin::Pin,
+    task::{self, Poll},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use futures::ready;
+use rand::Rng;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::net::framed::{self, FramedWrite};
+
+use super::config::{ObfsConfig, ObfsMode};
+
+/// Payload of a TLS record (or the HTTP request) written at once
+const MAX_PAYLOAD_SIZE: usize = 16 * 1024;
+/// Maximum length of HTTP headers
+const MAX_HEADER_SIZE: usize = 8 * 1024;
+
+const TLS_RECORD_HEADER_LEN: usize = 5;
+const CONTENT_TYPE_CHANGE_CIPHER_SPEC: u8 = 0x14;
+const CONTENT_TYPE_HANDSHAKE: u8 = 0x16;
+const CONTENT_TYPE_APPLICATION_DATA: u8 = 0x17;
+const HANDSHAKE_TYPE_CLIENT_HELLO: u8 = 0x01;
+const EXTENSION_SERVER_NAME: u16 = 0x0000;
+const EXTENSION_SESSION_TICKET: u16 = 0x0023;
+
+/// Cipher suites of ClientHello sent by simple-obfs
+static TLS_CIPHER_SUITES: [u8; 56] = [
+    0xc0, 0x2c, 0xc0, 0x30, 0x00, 0x9f, 0xcc, 0xa9, 0xcc, 0xa8, 0xcc, 0xaa, 0xc0, 0x2b, 0xc0, 0x2f, 0x00, 0x9e, 0xc0,
+    0x24, 0xc0, 0x28, 0x00, 0x6b, 0xc0, 0x23, 0xc0, 0x27, 0x00, 0x67, 0xc0, 0x0a, 0xc0, 0x14, 0x00, 0x39, 0xc0, 0x09,
+    0xc0, 0x13, 0x00, 0x33, 0x00, 0x9d, 0x00, 0x9c, 0x00, 0x3d, 0x00, 0x3c, 0x00, 0x35, 0x00, 0x2f, 0x00, 0xff,
+];
+
+/// Extensions of ClientHello after server_name: ec_point_formats, supported_groups, signature_algorithms,
+/// encrypt_then_mac and extended_master_secret
+static TLS_CLIENT_HELLO_EXTENSIONS: [u8; 66] = [
+    0x00, 0x0b, 0x00, 0x04, 0x03, 0x01, 0x00, 0x02, // ec_point_formats
+    0x00, 0x0a, 0x00, 0x0a, 0x00, 0x08, 0x00, 0x1d, 0x00, 0x17, 0x00, 0x19, 0x00, 0x18, // supported_groups
+    0x00, 0x0d, 0x00, 0x20, 0x00, 0x1e, 0x06, 0x01, 0x06, 0x02, 0x06, 0x03, 0x05, 0x01, 0x05, 0x02, 0x05, 0x03, 0x04,
+    0x01, 0x04, 0x02, 0x04, 0x03, 0x03, 0x01, 0x03, 0x02, 0x03, 0x03, 0x02, 0x01, 0x02, 0x02, 0x02,
+    0x03, // signature_algorithms
+    0x00, 0x16, 0x00, 0x00, // encrypt_then_mac
+    0x00, 0x17, 0x00, 0x00, // extended_master_secret
+];
+
+/// Extensions of ServerHello: renegotiation_info, extended_master_secret and ec_point_formats
+static TLS_SERVER_HELLO_EXTENSIONS: [u8; 15] = [
+    0xff, 0x01, 0x00, 0x01, 0x00, 0x00, 0x17, 0x00, 0x00, 0x00, 0x0b, 0x00, 0x02, 0x01, 0x00,
+];
+
+static TLS_CHANGE_CIPHER_SPEC: [u8; 6] = [CONTENT_TYPE_CHANGE_CIPHER_SPEC, 0x03, 0x03, 0x00, 0x01, 0x01];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Role {
+    Client,
+    Server,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum ReadState {
+    /// HTTP headers, or ClientHello of servers in `tls` mode
+    Header,
+    /// TLS records, handshake records after ChangeCipherSpec carry data for clients
+    Records { after_change_cipher_spec: bool },
+    /// Data without framing
+    Raw,
+}
+
+/// A stream obfuscated with simple-obfs' `http` or `tls` protocol
+///
+/// Nothing is exchanged before the first data, clients send the first data in the HTTP request or the session ticket
+/// of ClientHello, then servers respond with the first data in the HTTP response or the handshake after ServerHello.
+pub struct ObfsStream<S> {
+    stream: S,
+    mode: ObfsMode,
+    role: Role,
+    host: String,
+    uri: String,
+    read_buf: BytesMut,
+    read_state: ReadState,
+    read_remaining: usize,
+    // Data taken out of ClientHello
+    read_pending: Bytes,
+    write_buf: BytesMut,
+    write_count: usize,
+    session_id: [u8; 32],
+}
+
+impl<S> ObfsStream<S> {
+    fn new(stream: S, config: &ObfsConfig, role: Role, host: String) -> ObfsStream<S> {
+        let read_state = match (config.mode(), role) {
+            (ObfsMode::Tls, Role::Client) => ReadState::Records {
+                after_change_cipher_spec: false,
+            },
+            _ => ReadState::Header,
+        };
+
+        ObfsStream {
+            stream,
+            mode: config.mode(),
+            role,
+            host,
+            uri: config.uri().to_owned(),
+            read_buf: BytesMut::new(),
+            read_state,
+            read_remaining: 0,
+            read_pending: Bytes::new(),
+            write_buf: BytesMut::new(),
+            write_count: 0,
+            session_id: [0u8; 32],
+        }
+    }
+
+    /// Creates a client stream on a connection to a server at `port`
+    pub fn client(stream: S, config: &ObfsConfig, port: u16) -> ObfsStream<S> {
+        // simple-obfs appends the port to `Host` if it isn't the default one of HTTP
+        let host = match (config.mode(), port) {
+            (ObfsMode::Http, p) if p != 80 => format!("{}:{}", config.host(), p),
+            _ => config.host().to_owned(),
+        };
+        ObfsStream::new(stream, config, Role::Client, host)
+    }
+
+    /// Creates a server stream on an accepted connection
+    pub fn server(stream: S, config: &ObfsConfig) -> ObfsStream<S> {
+        ObfsStream::new(stream, config, Role::Server, config.host().to_owned())
+    }
+
+    /// Consumes headers or a record header at the front of `read_buf`, returns `false` if it needs more data
+    fn consume_header(&mut self) -> io::Result<bool> {
+        match self.read_state {
+            ReadState::Raw => Ok(false),
+            ReadState::Header => match self.mode {
+                ObfsMode::Http => self.consume_http_header(),
+                ObfsMode::Tls => self.consume_client_hello(),
+            },
+            ReadState::Records {
+                after_change_cipher_spec,
+            } => {
+                if self.read_remaining > 0 || self.read_buf.len() < TLS_RECORD_HEADER_LEN {
+                    return Ok(false);
+                }
+
+                let content_type = self.read_buf[0];
+                let len = u16::from_be_bytes([self.read_buf[3], self.read_buf[4]]) as usize;
+                match content_type {
+                    CONTENT_TYPE_APPLICATION_DATA => {}
+                    CONTENT_TYPE_HANDSHAKE if self.role == Role::Client && after_change_cipher_spec => {}
+                    CONTENT_TYPE_CHANGE_CIPHER_SPEC | CONTENT_TYPE_HANDSHAKE => {
+                        // Skips the whole record
+                        if self.read_buf.len() < TLS_RECORD_HEADER_LEN + len {
+                            return Ok(false);
+                        }
+                        self.read_buf.advance(TLS_RECORD_HEADER_LEN + len);
+                        if content_type == CONTENT_TYPE_CHANGE_CIPHER_SPEC {
+                            self.read_state = ReadState::Records {
+                                after_change_cipher_spec: true,
+                            };
+                        }
+                        return Ok(true);
+                    }
+                    _ => {
+                        return Err(io::Error::new(
+                            ErrorKind::InvalidData,
+                            format!("unexpected tls record type {:#x}", content_type),
+                        ));
+                    }
+                }
+
+                self.read_buf.advance(TLS_RECORD_HEADER_LEN);
+                self.read_remaining = len;
+                Ok(true)
+            }
+        }
+    }
+
+    fn consume_http_header(&mut self) -> io::Result<bool> {
+        let end = match self.read_buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            Some(p) => p + 4,
+            None if self.read_buf.len() > MAX_HEADER_SIZE => {
+                return Err(io::Error::new(ErrorKind::InvalidData, "http header too long"));
+            }
+            None => return Ok(false),
+        };
+
+        let valid = match self.role {
+            Role::Client => self.read_buf.starts_with(b"HTTP/1."),
+            Role::Server => self.read_buf.starts_with(b"GET ") || self.read_buf.starts_with(b"POST "),
+        };
+        if !valid {
+            return Err(io::Error::new(ErrorKind::InvalidData, "invalid obfs http header"));
+        }
+
+        self.read_buf.advance(end);
+        self.read_state = ReadState::Raw;
+        Ok(true)
+    }
+
+    /// Takes the session ticket of ClientHello as data
+    fn consume_client_hello(&mut self) -> io::Result<bool> {
+        if self.read_buf.len() < TLS_RECORD_HEADER_LEN {
+            return Ok(false);
+        }
+        let len = u16::from_be_bytes([self.read_buf[3], self.read_buf[4]]) as usize;
+        if self.read_buf.len() < TLS_RECORD_HEADER_LEN + len {
+            return Ok(false);
+        }
+
+        let record = self.read_buf.split_to(TLS_RECORD_HEADER_LEN + len).freeze();
+        let invalid = || io::Error::new(ErrorKind::InvalidData, "invalid obfs tls client hello");
+
+        if record[0] != CONTENT_TYPE_HANDSHAKE || len < 4 || record[5] != HANDSHAKE_TYPE_CLIENT_HELLO {
+            return Err(invalid());
+        }
+
+        // handshake header, version, random
+        let mut hello = record.slice(TLS_RECORD_HEADER_LEN + 4..);
+        if hello.remaining() < 2 + 32 + 1 {
+            return Err(invalid());
+        }
+        hello.advance(2 + 32);
+
+        let session_id_len = hello.get_u8() as usize;
+        if session_id_len != 32 || hello.remaining() < session_id_len + 2 {
+            return Err(invalid());
+        }
+        hello.copy_to_slice(&mut self.session_id);
+
+        let cipher_suites_len = hello.get_u16() as usize;
+        if hello.remaining() < cipher_suites_len + 1 {
+            return Err(invalid());
+        }
+        hello.advance(cipher_suites_len);
+
+        let comp_methods_len = hello.get_u8() as usize;
+        if hello.remaining() < comp_methods_len + 2 {
+            return Err(invalid());
+        }
+        hello.advance(comp_methods_len);
+
+        let mut extensions_len = hello.get_u16() as usize;
+        if hello.remaining() < extensions_len {
+            return Err(invalid());
+        }
+
+        while extensions_len >= 4 {
+            let ext_type = hello.get_u16();
+            let ext_len = hello.get_u16() as usize;
+            if ext_len > extensions_len - 4 {
+                return Err(invalid());
+            }
+            if ext_type == EXTENSION_SESSION_TICKET {
+                self.read_pending = hello.slice(..ext_len);
+                self.read_state = ReadState::Records {
+                    after_change_cipher_spec: false,
+                };
+                return Ok(true);
+            }
+            hello.advance(ext_len);
+            extensions_len -= 4 + ext_len;
+        }
+
+        Err(io::Error::new(
+            ErrorKind::InvalidData,
+            "obfs tls client hello without session ticket",
+        ))
+    }
+
+    fn encode_payload(&mut self, payload: &[u8]) {
+        match (self.mode, self.role, self.write_count) {
+            (ObfsMode::Http, Role::Client, 0) => self.encode_http_request(payload),
+            (ObfsMode::Http, Role::Server, 0) => self.encode_http_response(payload),
+            (ObfsMode::Http, ..) => self.write_buf.put_slice(payload),
+            (ObfsMode::Tls, Role::Client, 0) => self.encode_client_hello(payload),
+            (ObfsMode::Tls, Role::Client, 1) => {
+                // ChangeCipherSpec and Finished, before the first application data
+                self.write_buf.put_slice(&TLS_CHANGE_CIPHER_SPEC);
+                self.write_buf
+                    .put_slice(&[CONTENT_TYPE_HANDSHAKE, 0x03, 0x03, 0x00, 0x20]);
+                let mut finished = [0u8; 32];
+                rand::thread_rng().fill(&mut finished);
+                self.write_buf.put_slice(&finished);
+                self.encode_record(CONTENT_TYPE_APPLICATION_DATA, payload);
+            }
+            (ObfsMode::Tls, Role::Server, 0) => {
+                self.encode_server_hello();
+                self.write_buf.put_slice(&TLS_CHANGE_CIPHER_SPEC);
+                self.encode_record(CONTENT_TYPE_HANDSHAKE, payload);
+            }
+            (ObfsMode::Tls, ..) => self.encode_record(CONTENT_TYPE_APPLICATION_DATA, payload),
+        }
+
+        self.write_count = self.write_count.saturating_add(1);
+    }
+
+    fn encode_http_request(&mut self, payload: &[u8]) {
+        let mut rng = rand::thread_rng();
+        let key: [u8; 16] = rng.gen();
+        let header = format!(
+            "GET {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: curl/7.{}.{}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: {}\r\nContent-Length: {}\r\n\r\n",
+            self.uri,
+            self.host,
+            rng.gen_range(0..=51),
+            rng.gen_range(0..=2),
+            base64::encode(key),
+            payload.len()
+        );
+        self.write_buf.put_slice(header.as_bytes());
+        self.write_buf.put_slice(payload);
+    }
+
+    fn encode_http_response(&mut self, payload: &[u8]) {
+        let mut rng = rand::thread_rng();
+        let accept: [u8; 16] = rng.gen();
+        let header = format!(
+            "HTTP/1.1 101 Switching Protocols\r\nServer: nginx/1.{}.{}\r\nDate: {}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+            rng.gen_range(0..=11),
+            rng.gen_range(0..=12),
+            httpdate::fmt_http_date(SystemTime::now()),
+            base64::encode(accept)
+        );
+        self.write_buf.put_slice(header.as_bytes());
+        self.write_buf.put_slice(payload);
+    }
+
+    fn encode_record(&mut self, content_type: u8, payload: &[u8]) {
+        self.write_buf.reserve(TLS_RECORD_HEADER_LEN + payload.len());
+        self.write_buf.put_slice(&[content_type, 0x03, 0x03]);
+        self.write_buf.put_u16(payload.len() as u16);
+        self.write_buf.put_slice(payload);
+    }
+
+    fn put_random(&mut self) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        self.write_buf.put_u32(now as u32);
+
+        let mut random = [0u8; 28];
+        rand::thread_rng().fill(&mut random);
+        self.write_buf.put_slice(&random);
+    }
+
+    /// ClientHello carrying `payload` in the session ticket extension
+    fn encode_client_hello(&mut self, payload: &[u8]) {
+        let start = self.write_buf.len();
+
+        self.write_buf
+            .put_slice(&[CONTENT_TYPE_HANDSHAKE, 0x03, 0x01, 0x00, 0x00]);
+        self.write_buf
+            .put_slice(&[HANDSHAKE_TYPE_CLIENT_HELLO, 0x00, 0x00, 0x00]);
+        self.write_buf.put_slice(&[0x03, 0x03]);
+        self.put_random();
+
+        rand::thread_rng().fill(&mut self.session_id);
+        self.write_buf.put_u8(32);
+        let session_id = self.session_id;
+        self.write_buf.put_slice(&session_id);
+
+        self.write_buf.put_u16(TLS_CIPHER_SUITES.len() as u16);
+        self.write_buf.put_slice(&TLS_CIPHER_SUITES);
+        self.write_buf.put_slice(&[0x01, 0x00]);
+
+        let extensions_start = self.write_buf.len();
+        self.write_buf.put_u16(0);
+
+        self.write_buf.put_u16(EXTENSION_SESSION_TICKET);
+        self.write_buf.put_u16(payload.len() as u16);
+        self.write_buf.put_slice(payload);
+
+        let host_len = self.host.len();
+        self.write_buf.put_u16(EXTENSION_SERVER_NAME);
+        self.write_buf.put_u16((host_len + 5) as u16);
+        self.write_buf.put_u16((host_len + 3) as u16);
+        self.write_buf.put_u8(0);
+        self.write_buf.put_u16(host_len as u16);
+        let host = self.host.clone();
+        self.write_buf.put_slice(host.as_bytes());
+
+        self.write_buf.put_slice(&TLS_CLIENT_HELLO_EXTENSIONS);
+
+        let total = self.write_buf.len() - start;
+        let extensions_len = (self.write_buf.len() - extensions_start - 2) as u16;
+        self.write_buf[start + 3..start + 5].copy_from_slice(&((total - TLS_RECORD_HEADER_LEN) as u16).to_be_bytes());
+        self.write_buf[start + 7..start + 9]
+            .copy_from_slice(&((total - TLS_RECORD_HEADER_LEN - 4) as u16).to_be_bytes());
+        self.write_buf[extensions_start..extensions_start + 2].copy_from_slice(&extensions_len.to_be_bytes());
+    }
+
+    /// ServerHello resuming the session of the client
+    fn encode_server_hello(&mut self) {
+        self.write_buf
+            .put_slice(&[CONTENT_TYPE_HANDSHAKE, 0x03, 0x01, 0x00, 91]);
+        self.write_buf.put_slice(&[0x02, 0x00, 0x00, 87]);
+        self.write_buf.put_slice(&[0x03, 0x03]);
+        self.put_random();
+
+        self.write_buf.put_u8(32);
+        let session_id = self.session_id;
+        self.write_buf.put_slice(&session_id);
+
+        // TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256, no compression
+        self.write_buf.put_slice(&[0xcc, 0xa8, 0x00]);
+        self.write_buf.put_u16(TLS_SERVER_HELLO_EXTENSIONS.len() as u16);
+        self.write_buf.put_slice(&TLS_SERVER_HELLO_EXTENSIONS);
+    }
+}
+
+impl<S> FramedWrite for ObfsStream<S>
+where
+    S: AsyncWrite + Unpin,
+{
+    fn poll_write_encoded(&mut self, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        framed::poll_write_buffered(&mut self.stream, &mut self.write_buf, cx)
+    }
+
+    fn encode(&mut self, data: &[u8]) -> usize {
+        let n = data.len().min(MAX_PAYLOAD_SIZE);
+        self.encode_payload(&data[..n]);
+        n
+    }
+}
+
+impl<S> Deref for ObfsStream<S> {
+    type Target = S;
+
+    fn deref(&self) -> &S {
+        &self.stream
+    }
+}
+
+impl<S> AsyncRead for ObfsStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_read(self: Pin<&mut Self>, cx: &mut task::Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        loop {
+            if !this.read_pending.is_empty() {
+                let n = this.read_pending.len().min(buf.remaining());
+                buf.put_slice(&this.read_pending[..n]);
+                this.read_pending.advance(n);
+                return Ok(()).into();
+            }
+
+            if !this.read_buf.is_empty() {
+                let available = match this.read_state {
+                    ReadState::Raw => this.read_buf.len(),
+                    ReadState::Records { .. } => this.read_remaining.min(this.read_buf.len()),
+                    ReadState::Header => 0,
+                };
+                if available > 0 {
+                    let n = available.min(buf.remaining());
+                    buf.put_slice(&this.read_buf[..n]);
+                    this.read_buf.advance(n);
+                    if let ReadState::Records { .. } = this.read_state {
+                        this.read_remaining -= n;
+                    }
+                    return Ok(()).into();
+                }
+            }
+
+            if this.consume_header()? {
+                continue;
+            }
+
+            if !ready!(framed::poll_fill(&mut this.stream, &mut this.read_buf, cx))? {
+                return match this.read_state {
+                    ReadState::Raw => Ok(()).into(),
+                    ReadState::Records { .. } if this.read_remaining == 0 && this.read_buf.is_empty() => Ok(()).into(),
+                    ReadState::Header if this.read_buf.is_empty() => Ok(()).into(),
+                    _ => Err(ErrorKind::UnexpectedEof.into()).into(),
+                };
+            }
+        }
+    }
+}
+
+impl<S> AsyncWrite for ObfsStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_write(self: Pin<&mut Self>, cx: &mut task::Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        self.get_mut().poll_write_framed(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_encoded(cx))?;
+        Pin::new(&mut this.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_encoded(cx))?;
+        Pin::new(&mut this.stream).poll_shutdown(cx)
+    }
+}
@@ -1,7 +1,11 @@
 //! Options for connecting to remote server
 
+#[cfg(unix)]
+use std::{fmt, io, os::unix::io::RawFd, sync::Arc};
 use std::{net::IpAddr, time::Duration};
 
+use super::IpStrategy;
+
 /// Options for connecting to TCP remote server
 #[derive(Debug, Clone, Default)]
 pub struct TcpSocketOpts {
@@ -20,6 +24,136 @@ pub struct TcpSocketOpts {
     /// `SO_KEEPALIVE` and sets `TCP_KEEPIDLE`, `TCP_KEEPINTVL` and `TCP_KEEPCNT` respectively,
     /// enables keep-alive messages on connection-oriented sockets
     pub keepalive: Option<Duration>,
+
+    /// `TCP_KEEPINTVL`, interval between keep-alive probes, the same as `keepalive` if not set
+    pub keepalive_interval: Option<Duration>,
+
+    /// `TCP_KEEPCNT`, number of unacknowledged keep-alive probes before dropping the connection, system default if
+    /// not set
+    ///
+    /// Ignored on Windows
+    pub keepalive_retries: Option<u32>,
+
+    /// `TCP_USER_TIMEOUT`, maximum time that transmitted data may remain unacknowledged before the connection is
+    /// dropped
+    ///
+    /// Only supported on Linux and Android
+    pub user_timeout: Option<Duration>,
+
+    /// `TCP_CONGESTION`, congestion control algorithm, like `bbr` or `cubic`
+    ///
+    /// Only supported on Linux and Android
+    pub congestion: Option<String>,
+
+    /// `TCP_NOTSENT_LOWAT`, maximum bytes of unsent data in the send buffer, reducing latency of streams buffered by
+    /// the kernel
+    ///
+    /// Only supported on Linux and Android
+    pub notsent_lowat: Option<u32>,
+
+    /// `SO_MAX_PACING_RATE`, in bytes per second, limits sending rate of outbound streams
+    ///
+    /// Streams are paced in user space on platforms other than Linux and Android
+    pub pacing_rate: Option<u64>,
+
+    /// `IPPROTO_MPTCP`, creates Multipath TCP sockets (since Linux 5.6)
+    ///
+    /// Falls back to TCP sockets on other platforms, or kernels without MPTCP
+    pub mptcp: bool,
+}
+
+/// Tuning of TCP sockets of a server or a local instance
+///
+/// Options that are set override the ones of `TcpSocketOpts` configured globally, the others are kept.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TcpTuning {
+    /// `SO_SNDBUF`
+    pub send_buffer_size: Option<u32>,
+    /// `SO_RCVBUF`
+    pub recv_buffer_size: Option<u32>,
+    /// `TCP_NODELAY`
+    pub nodelay: Option<bool>,
+    /// `TCP_KEEPIDLE`, enables keep-alive
+    pub keepalive: Option<Duration>,
+    /// `TCP_KEEPINTVL`
+    pub keepalive_interval: Option<Duration>,
+    /// `TCP_KEEPCNT`
+    pub keepalive_retries: Option<u32>,
+    /// `TCP_USER_TIMEOUT`
+    pub user_timeout: Option<Duration>,
+    /// `TCP_CONGESTION`
+    pub congestion: Option<String>,
+    /// `TCP_NOTSENT_LOWAT`
+    pub notsent_lowat: Option<u32>,
+}
+
+impl TcpTuning {
+    /// Check if nothing is tuned
+    pub fn is_empty(&self) -> bool {
+        *self == TcpTuning::default()
+    }
+
+    /// Override options of `opts` with the ones that are set
+    pub fn apply(&self, opts: &mut TcpSocketOpts) {
+        if let Some(size) = self.send_buffer_size {
+            opts.send_buffer_size = Some(size);
+        }
+        if let Some(size) = self.recv_buffer_size {
+            opts.recv_buffer_size = Some(size);
+        }
+        if let Some(nodelay) = self.nodelay {
+            opts.nodelay = nodelay;
+        }
+        if let Some(keepalive) = self.keepalive {
+            opts.keepalive = Some(keepalive);
+        }
+        if let Some(interval) = self.keepalive_interval {
+            opts.keepalive_interval = Some(interval);
+        }
+        if let Some(retries) = self.keepalive_retries {
+            opts.keepalive_retries = Some(retries);
+        }
+        if let Some(timeout) = self.user_timeout {
+            opts.user_timeout = Some(timeout);
+        }
+        if let Some(ref congestion) = self.congestion {
+            opts.congestion = Some(congestion.clone());
+        }
+        if let Some(bytes) = self.notsent_lowat {
+            opts.notsent_lowat = Some(bytes);
+        }
+    }
+}
+
+/// Callback protecting outbound sockets from being routed back to the VPN of the process itself
+///
+/// It is called with the file descriptor of every outbound socket before it connects or sends anything, like
+/// Android's `VpnService.protect()`. Connections fail with the error it returns.
+#[cfg(unix)]
+#[derive(Clone)]
+pub struct SocketProtector(Arc<dyn Fn(RawFd) -> io::Result<()> + Send + Sync>);
+
+#[cfg(unix)]
+impl SocketProtector {
+    /// Create a protector calling `protect`
+    pub fn new<F>(protect: F) -> SocketProtector
+    where
+        F: Fn(RawFd) -> io::Result<()> + Send + Sync + 'static,
+    {
+        SocketProtector(Arc::new(protect))
+    }
+
+    /// Protect the socket `fd`
+    pub fn protect(&self, fd: RawFd) -> io::Result<()> {
+        (self.0)(fd)
+    }
+}
+
+#[cfg(unix)]
+impl fmt::Debug for SocketProtector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SocketProtector")
+    }
 }
 
 /// Options for connecting to remote server
@@ -40,6 +174,10 @@ pub struct ConnectOpts {
     #[cfg(target_os = "android")]
     pub vpn_protect_path: Option<std::path::PathBuf>,
 
+    /// Callback called with every outbound socket before connecting, for frontends embedding the library
+    #[cfg(unix)]
+    pub socket_protector: Option<SocketProtector>,
+
     /// Outbound socket binds to this IP address, mostly for choosing network interfaces
     ///
     /// It only affects sockets that trying to connect to addresses with the same family
@@ -48,6 +186,12 @@ pub struct ConnectOpts {
     /// Outbound socket binds to interface
     pub bind_interface: Option<String>,
 
+    /// IP TTL (IPv4) or hop limit (IPv6) of outbound TCP sockets, system default if `None`
+    pub ip_ttl: Option<u32>,
+
+    /// Strategy of choosing among resolved addresses of host names, overriding `Context::ip_strategy` if set
+    pub ip_strategy: Option<IpStrategy>,
+
     /// TCP options
     pub tcp: TcpSocketOpts,
 }
@@ -60,4 +204,8 @@ pub struct AcceptOpts {
 
     /// Enable IPV6_V6ONLY option for socket
     pub ipv6_only: bool,
+
+    /// Enable SO_REUSEPORT option for binding multiple sockets to the same address, which are load balanced by the
+    /// kernel on Linux
+    pub reuse_port: bool,
 }
@@ -0,0 +1,75 @@
+//! Pacing outbound TCP streams in user space
+//!
+//! On platforms without `SO_MAX_PACING_RATE`, writes are spread evenly in time instead of being sent as bursts,
+//! which would fill up buffers of the bottleneck link and increase latency of all the other connections.
+
+use std::{
+    future::Future,
+    pin::Pin,
+    task::{self, Poll},
+    time::Duration,
+};
+
+use tokio::time::{self, Instant, Sleep};
+
+use super::ConnectOpts;
+
+/// Bytes could be written at once, at least one full-sized segment
+const MIN_QUANTUM: usize = 1500;
+
+/// Paces writes at a fixed rate
+pub struct Pacer {
+    rate: u64,
+    quantum: usize,
+    next_send: Instant,
+    sleep: Option<Pin<Box<Sleep>>>,
+}
+
+impl Pacer {
+    /// Create a pacer if `opts` requires pacing that couldn't be done by the kernel
+    pub fn from_opts(opts: &ConnectOpts) -> Option<Pacer> {
+        if cfg!(any(target_os = "linux", target_os = "android")) {
+            // Paced by kernel with `SO_MAX_PACING_RATE`
+            return None;
+        }
+
+        match opts.tcp.pacing_rate {
+            Some(rate) if rate > 0 => Some(Pacer {
+                rate,
+                // Allows at most 10ms of bursts
+                quantum: ((rate / 100) as usize).max(MIN_QUANTUM),
+                next_send: Instant::now(),
+                sleep: None,
+            }),
+            _ => None,
+        }
+    }
+
+    /// Wait until the next write is allowed
+    pub fn poll_ready(&mut self, cx: &mut task::Context<'_>) -> Poll<()> {
+        if let Some(ref mut sleep) = self.sleep {
+            futures::ready!(sleep.as_mut().poll(cx));
+            self.sleep = None;
+        }
+        Poll::Ready(())
+    }
+
+    /// Maximum bytes of the next write
+    pub fn quantum(&self) -> usize {
+        self.quantum
+    }
+
+    /// Account `n` bytes that have been written
+    pub fn consume(&mut self, n: usize) {
+        let now = Instant::now();
+        if self.next_send < now {
+            // Idle time couldn't be saved for bursts
+            self.next_send = now;
+        }
+        self.next_send += Duration::from_nanos((n as u128 * 1_000_000_000 / self.rate as u128) as u64);
+
+        if self.next_send > now + Duration::from_millis(1) {
+            self.sleep = Some(Box::pin(time::sleep_until(self.next_send)));
+        }
+    }
+}
@@ -0,0 +1,115 @@
+//! Port hopping of servers listening on a range of ports
+//!
+//! Servers listen on every port of the range with the same method and key. Clients connect to one of them, and hop
+//! to another one after an interval or a failed connection, so throttling or blocking of one port doesn't last.
+
+use std::{
+    fmt::{self, Debug},
+    ops::RangeInclusive,
+    sync::{
+        atomic::{AtomicU16, Ordering},
+        Arc,
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use log::debug;
+
+use crate::crypto::v1::random_iv_or_salt;
+
+struct PortHoppingState {
+    port: AtomicU16,
+    hopped_at: Mutex<Instant>,
+}
+
+/// Port hopping configuration of a server
+///
+/// - `ports`: ports that the server listens on
+/// - `interval`: clients hop to another port after this long, only on failures if not set
+///
+/// Clones share the current port of clients.
+#[derive(Clone)]
+pub struct PortHopping {
+    ports: RangeInclusive<u16>,
+    interval: Option<Duration>,
+    state: Arc<PortHoppingState>,
+}
+
+impl Debug for PortHopping {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PortHopping")
+            .field("ports", &self.ports)
+            .field("interval", &self.interval)
+            .finish()
+    }
+}
+
+impl PortHopping {
+    /// Creates a configuration of `ports`, clients start from `port`
+    pub fn new(ports: RangeInclusive<u16>, port: u16) -> PortHopping {
+        PortHopping {
+            ports,
+            interval: None,
+            state: Arc::new(PortHoppingState {
+                port: AtomicU16::new(port),
+                hopped_at: Mutex::new(Instant::now()),
+            }),
+        }
+    }
+
+    /// Ports that the server listens on
+    pub fn ports(&self) -> RangeInclusive<u16> {
+        self.ports.clone()
+    }
+
+    /// Interval of hopping
+    pub fn interval(&self) -> Option<Duration> {
+        self.interval
+    }
+
+    /// Hop to another port after `interval`
+    pub fn set_interval(&mut self, interval: Duration) {
+        self.interval = Some(interval);
+    }
+
+    /// Port that clients connect to, hopped if it has been used for `interval`
+    pub fn port(&self) -> u16 {
+        if let Some(interval) = self.interval {
+            let mut hopped_at = self.state.hopped_at.lock().unwrap();
+            if hopped_at.elapsed() >= interval {
+                *hopped_at = Instant::now();
+                drop(hopped_at);
+                self.hop_port();
+            }
+        }
+        self.state.port.load(Ordering::Relaxed)
+    }
+
+    /// Hop to another random port, after failing to connect to the current one
+    pub fn hop(&self) {
+        *self.state.hopped_at.lock().unwrap() = Instant::now();
+        self.hop_port();
+    }
+
+    fn hop_port(&self) {
+        let (start, end) = (*self.ports.start() as u32, *self.ports.end() as u32);
+        let count = end - start + 1;
+        if count <= 1 {
+            return;
+        }
+
+        // Any other ports of the range
+        let current = self.state.port.load(Ordering::Relaxed) as u32;
+        let mut random = [0u8; 4];
+        random_iv_or_salt(&mut random);
+        let offset = u32::from_ne_bytes(random) % (count - 1);
+        let port = match current.checked_sub(start) {
+            Some(index) if index < count => start + (index + 1 + offset) % count,
+            _ => start + offset,
+        };
+
+        debug!("port hopped from {} to {}", current, port);
+        self.state.port.store(port as u16, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,215 @@
+//! Configuration of the QUIC transport
+
+use std::{
+    fmt::{self, Debug},
+    fs::File,
+    io::{self, BufReader, ErrorKind},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use once_cell::sync::OnceCell;
+use quinn::{ClientConfig as QuicClientConfig, IdleTimeout, ServerConfig as QuicServerConfig, TransportConfig, VarInt};
+use tokio::sync::Mutex;
+use tokio_rustls::rustls::{
+    self,
+    Certificate,
+    ClientConfig,
+    OwnedTrustAnchor,
+    PrivateKey,
+    RootCertStore,
+    ServerConfig,
+};
+
+use super::PooledConnection;
+
+/// ALPN of connections, the same as HTTP/3
+const ALPN_PROTOCOL: &[u8] = b"h3";
+/// Connections are closed after being silent for this long
+const MAX_IDLE_TIMEOUT_MS: u32 = 60_000;
+/// Clients ping servers to keep connections, and their NAT mappings, alive
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(15);
+/// Streams opened concurrently by a client in one connection
+const MAX_CONCURRENT_BIDI_STREAMS: u32 = 1024;
+
+/// QUIC transport configuration
+///
+/// - `server_name`: TLS server name, the server's domain name by default, required for servers with IP addresses
+/// - `certificate` / `private_key`: certificate chain and private key of the server, in PEM.
+///   Clients trust `certificate` besides the well-known CAs.
+#[derive(Clone)]
+pub struct QuicConfig {
+    server_name: Option<String>,
+    certificate: Option<PathBuf>,
+    private_key: Option<PathBuf>,
+    client_config: Arc<OnceCell<QuicClientConfig>>,
+    pub(super) pool: Arc<Mutex<Option<Arc<PooledConnection>>>>,
+}
+
+impl Debug for QuicConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("QuicConfig")
+            .field("server_name", &self.server_name)
+            .field("certificate", &self.certificate)
+            .field("private_key", &self.private_key)
+            .finish()
+    }
+}
+
+impl Default for QuicConfig {
+    fn default() -> QuicConfig {
+        QuicConfig::new()
+    }
+}
+
+fn load_certificates(path: &Path) -> io::Result<Vec<Certificate>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let certs = rustls_pemfile::certs(&mut reader)?;
+    if certs.is_empty() {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            format!("no certificate found in {}", path.display()),
+        ));
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+fn load_private_key(path: &Path) -> io::Result<PrivateKey> {
+    use rustls_pemfile::Item;
+
+    let mut reader = BufReader::new(File::open(path)?);
+
+    // Takes the first private key in the file
+    while let Some(item) = rustls_pemfile::read_one(&mut reader)? {
+        match item {
+            Item::RSAKey(key) | Item::PKCS8Key(key) | Item::ECKey(key) => return Ok(PrivateKey(key)),
+            _ => continue,
+        }
+    }
+
+    Err(io::Error::new(
+        ErrorKind::InvalidData,
+        format!("no private key found in {}", path.display()),
+    ))
+}
+
+impl QuicConfig {
+    /// Creates a configuration with default values
+    pub fn new() -> QuicConfig {
+        QuicConfig {
+            server_name: None,
+            certificate: None,
+            private_key: None,
+            client_config: Arc::new(OnceCell::new()),
+            pool: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Set TLS server name
+    pub fn set_server_name<S: Into<String>>(&mut self, server_name: S) {
+        self.server_name = Some(server_name.into());
+    }
+
+    /// Set path of the certificate chain
+    pub fn set_certificate<P: Into<PathBuf>>(&mut self, path: P) {
+        self.certificate = Some(path.into());
+    }
+
+    /// Set path of the private key
+    pub fn set_private_key<P: Into<PathBuf>>(&mut self, path: P) {
+        self.private_key = Some(path.into());
+    }
+
+    /// TLS server name
+    pub fn server_name(&self) -> Option<&str> {
+        self.server_name.as_deref()
+    }
+
+    /// Path of the certificate chain
+    pub fn certificate(&self) -> Option<&Path> {
+        self.certificate.as_deref()
+    }
+
+    /// Path of the private key
+    pub fn private_key(&self) -> Option<&Path> {
+        self.private_key.as_deref()
+    }
+
+    /// Client configuration, with 0-RTT enabled
+    pub(crate) fn client_config(&self) -> io::Result<QuicClientConfig> {
+        self.client_config
+            .get_or_try_init(|| {
+                let mut store = RootCertStore::empty();
+                store.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|root| {
+                    OwnedTrustAnchor::from_subject_spki_name_constraints(root.subject, root.spki, root.name_constraints)
+                }));
+
+                if let Some(ref path) = self.certificate {
+                    for cert in load_certificates(path)? {
+                        if let Err(err) = store.add(&cert) {
+                            return Err(io::Error::new(
+                                ErrorKind::InvalidData,
+                                format!("invalid certificate, {}", err),
+                            ));
+                        }
+                    }
+                }
+
+                let mut crypto = ClientConfig::builder()
+                    .with_safe_default_cipher_suites()
+                    .with_safe_default_kx_groups()
+                    .with_protocol_versions(&[&rustls::version::TLS13])
+                    .map_err(|err| io::Error::new(ErrorKind::Other, err))?
+                    .with_root_certificates(store)
+                    .with_no_client_auth();
+                crypto.enable_early_data = true;
+                crypto.alpn_protocols = vec![ALPN_PROTOCOL.to_vec()];
+
+                let mut transport = TransportConfig::default();
+                transport
+                    .max_idle_timeout(Some(IdleTimeout::from(VarInt::from_u32(MAX_IDLE_TIMEOUT_MS))))
+                    .keep_alive_interval(Some(KEEP_ALIVE_INTERVAL));
+
+                let mut config = QuicClientConfig::new(Arc::new(crypto));
+                config.transport = Arc::new(transport);
+                Ok(config)
+            })
+            .cloned()
+    }
+
+    /// Server configuration with `certificate` and `private_key`, accepting 0-RTT
+    pub(crate) fn server_config(&self) -> io::Result<QuicServerConfig> {
+        let (certificate, private_key) = match (&self.certificate, &self.private_key) {
+            (Some(c), Some(k)) => (c, k),
+            _ => {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidInput,
+                    "quic server requires certificate and private_key",
+                ));
+            }
+        };
+
+        let mut crypto = ServerConfig::builder()
+            .with_safe_default_cipher_suites()
+            .with_safe_default_kx_groups()
+            .with_protocol_versions(&[&rustls::version::TLS13])
+            .map_err(|err| io::Error::new(ErrorKind::Other, err))?
+            .with_no_client_auth()
+            .with_single_cert(load_certificates(certificate)?, load_private_key(private_key)?)
+            .map_err(|err| io::Error::new(ErrorKind::InvalidInput, err))?;
+        // QUIC requires either 0 or u32::MAX
+        crypto.max_early_data_size = u32::MAX;
+        crypto.alpn_protocols = vec![ALPN_PROTOCOL.to_vec()];
+
+        let mut transport = TransportConfig::default();
+        transport
+            .max_idle_timeout(Some(IdleTimeout::from(VarInt::from_u32(MAX_IDLE_TIMEOUT_MS))))
+            .max_concurrent_bidi_streams(VarInt::from_u32(MAX_CONCURRENT_BIDI_STREAMS))
+            .max_concurrent_uni_streams(VarInt::from_u32(0));
+
+        let mut config = QuicServerConfig::with_crypto(Arc::new(crypto));
+        config.transport = Arc::new(transport);
+        Ok(config)
+    }
+}
@@ -0,0 +1,416 @@
+//! QUIC transport, carrying shadowsocks' streams in streams of QUIC connections
+//!
+//! ```plain
+//! +------------+                                    +------------+
+//! |  SS Client +-- QUIC [0-RTT] (Public Internet) --+  SS Server |
+//! +------------+                                    +------------+
+//! ```
+//!
+//! Clients keep one connection to each server, every shadowsocks stream is a bidirectional stream in it.
+//! Connections are resumed with 0-RTT, and survive NAT rebinding and changes of clients' addresses.
+//!
+//! Servers listen on UDP of the server's port, so UDP relay couldn't be enabled with the QUIC transport.
+
+#[cfg(unix)]
+use std::os::unix::io::{AsRawFd, RawFd};
+#[cfg(windows)]
+use std::os::windows::io::{AsRawSocket, RawSocket};
+use std::{
+    io::{self, ErrorKind},
+    net::SocketAddr,
+    pin::Pin,
+    sync::{Arc, Weak},
+    task::{self, Poll},
+    time::Duration,
+};
+
+use futures::StreamExt;
+use log::{debug, trace};
+use quinn::{Connecting, Connection, Endpoint, EndpointConfig, RecvStream, SendStream, VarInt};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    sync::mpsc,
+    task::JoinHandle,
+    time,
+};
+
+use crate::{config::ServerAddr, context::Context};
+
+pub use self::config::QuicConfig;
+use super::{AcceptOpts, ConnectOpts, UdpSocket};
+
+mod config;
+
+/// Pooled connections without streams are closed after being idle for 2 ticks
+const POOL_IDLE_TICK: Duration = Duration::from_secs(30);
+/// Streams waiting in `QuicListener` before being accepted
+const ACCEPT_BACKLOG: usize = 1024;
+
+/// Raw handle of the UDP socket of an endpoint
+#[derive(Debug, Clone, Copy)]
+struct SocketHandle {
+    #[cfg(unix)]
+    fd: RawFd,
+    #[cfg(windows)]
+    socket: RawSocket,
+}
+
+impl SocketHandle {
+    fn new(socket: &tokio::net::UdpSocket) -> SocketHandle {
+        SocketHandle {
+            #[cfg(unix)]
+            fd: socket.as_raw_fd(),
+            #[cfg(windows)]
+            socket: socket.as_raw_socket(),
+        }
+    }
+}
+
+/// A client's connection to a server, shared by streams
+pub(crate) struct PooledConnection {
+    connection: Connection,
+    // Endpoint have to be kept, otherwise the connection will be closed
+    _endpoint: Endpoint,
+    local_addr: SocketAddr,
+    handle: SocketHandle,
+}
+
+impl Drop for PooledConnection {
+    fn drop(&mut self) {
+        self.connection.close(VarInt::from_u32(0), b"");
+    }
+}
+
+/// Makes a QUIC connection to `addr`, with 0-RTT if a session ticket of the server is available
+async fn connect_quic(
+    context: &Context,
+    config: &QuicConfig,
+    addr: &ServerAddr,
+    opts: &ConnectOpts,
+) -> io::Result<PooledConnection> {
+    let (server_addr, server_name) = match *addr {
+        ServerAddr::SocketAddr(sa) => match config.server_name() {
+            Some(name) => (sa, name),
+            None => {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidInput,
+                    "quic server_name is required for servers with IP addresses",
+                ));
+            }
+        },
+        ServerAddr::DomainName(ref domain, port) => {
+            let (sa, ..) = lookup_then!(context, domain, port, |addr| { Ok::<_, io::Error>(addr) })?;
+            (sa, config.server_name().unwrap_or(domain))
+        }
+    };
+
+    let socket: tokio::net::UdpSocket = UdpSocket::connect_any_with_opts(&server_addr, opts).await?.into();
+    let handle = SocketHandle::new(&socket);
+    let local_addr = socket.local_addr()?;
+
+    let (endpoint, _) = Endpoint::new(EndpointConfig::default(), None, socket.into_std()?)?;
+    let connecting = match endpoint.connect_with(config.client_config()?, server_addr, server_name) {
+        Ok(c) => c,
+        Err(err) => return Err(io::Error::new(ErrorKind::InvalidInput, err)),
+    };
+
+    let connection = match connecting.into_0rtt() {
+        Ok((new_connection, _)) => {
+            trace!("quic connecting to {} with 0-RTT", addr);
+            new_connection.connection
+        }
+        Err(connecting) => connecting.await?.connection,
+    };
+
+    debug!(
+        "quic connection {} -> {} ({}) established",
+        local_addr,
+        addr,
+        connection.remote_address()
+    );
+
+    Ok(PooledConnection {
+        connection,
+        _endpoint: endpoint,
+        local_addr,
+        handle,
+    })
+}
+
+/// Closes the pooled connection after it has no streams for a while
+async fn monitor_idle(pooled: Weak<PooledConnection>, pool: Arc<tokio::sync::Mutex<Option<Arc<PooledConnection>>>>) {
+    let mut idle_ticks = 0;
+    loop {
+        time::sleep(POOL_IDLE_TICK).await;
+
+        let conn = match pooled.upgrade() {
+            Some(c) => c,
+            None => return,
+        };
+
+        // Referenced by the pool and us
+        if Arc::strong_count(&conn) > 2 {
+            idle_ticks = 0;
+            continue;
+        }
+
+        idle_ticks += 1;
+        if idle_ticks >= 2 {
+            let mut pool = pool.lock().await;
+            if matches!(*pool, Some(ref c) if Arc::ptr_eq(c, &conn)) {
+                trace!(
+                    "quic connection to {} closed after idle",
+                    conn.connection.remote_address()
+                );
+                *pool = None;
+            }
+            return;
+        }
+    }
+}
+
+impl QuicConfig {
+    /// Opens a stream to the server at `addr`, in the connection shared by streams to the server
+    pub async fn connect(&self, context: &Context, addr: &ServerAddr, opts: &ConnectOpts) -> io::Result<QuicStream> {
+        let mut pool = self.pool.lock().await;
+
+        if let Some(pooled) = pool.clone() {
+            match pooled.connection.open_bi().await {
+                Ok((send, recv)) => return Ok(QuicStream::new(send, recv, pooled)),
+                Err(err) => {
+                    debug!("quic connection to {} lost, error: {}", addr, err);
+                    *pool = None;
+                }
+            }
+        }
+
+        let pooled = Arc::new(connect_quic(context, self, addr, opts).await?);
+        tokio::spawn(monitor_idle(Arc::downgrade(&pooled), self.pool.clone()));
+        *pool = Some(pooled.clone());
+
+        let (send, recv) = pooled.connection.open_bi().await?;
+        Ok(QuicStream::new(send, recv, pooled))
+    }
+}
+
+/// The connection carrying a stream
+trait StreamConnection: Send + Sync {
+    fn connection(&self) -> &Connection;
+    fn local_addr(&self) -> SocketAddr;
+    fn handle(&self) -> SocketHandle;
+}
+
+impl StreamConnection for PooledConnection {
+    fn connection(&self) -> &Connection {
+        &self.connection
+    }
+
+    fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    fn handle(&self) -> SocketHandle {
+        self.handle
+    }
+}
+
+/// A connection accepted by `QuicListener`
+struct InboundConnection {
+    connection: Connection,
+    local_addr: SocketAddr,
+    handle: SocketHandle,
+}
+
+impl StreamConnection for InboundConnection {
+    fn connection(&self) -> &Connection {
+        &self.connection
+    }
+
+    fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    fn handle(&self) -> SocketHandle {
+        self.handle
+    }
+}
+
+/// A bidirectional stream in a QUIC connection
+///
+/// Streams of a connection share one UDP socket, socket options applied to any of them take effect on all.
+pub struct QuicStream {
+    send: SendStream,
+    recv: RecvStream,
+    // Pooled connections are idle if they are not referenced by streams
+    connection: Arc<dyn StreamConnection>,
+}
+
+impl QuicStream {
+    fn new(send: SendStream, recv: RecvStream, connection: Arc<dyn StreamConnection>) -> QuicStream {
+        QuicStream { send, recv, connection }
+    }
+
+    /// Returns the local address of the connection's socket
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.connection.local_addr())
+    }
+
+    /// Returns the current address of the peer, which may change with migration
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.connection.connection().remote_address())
+    }
+
+    /// QUIC sends data without Nagle's algorithm
+    pub fn nodelay(&self) -> io::Result<bool> {
+        Ok(true)
+    }
+
+    /// QUIC sends data without Nagle's algorithm, this does nothing
+    pub fn set_nodelay(&self, _nodelay: bool) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+impl AsRawFd for QuicStream {
+    fn as_raw_fd(&self) -> RawFd {
+        self.connection.handle().fd
+    }
+}
+
+#[cfg(windows)]
+impl AsRawSocket for QuicStream {
+    fn as_raw_socket(&self) -> RawSocket {
+        self.connection.handle().socket
+    }
+}
+
+impl AsyncRead for QuicStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut task::Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().recv).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for QuicStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut task::Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().send).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().send).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().send).poll_shutdown(cx)
+    }
+}
+
+type AcceptResult = (QuicStream, SocketAddr);
+
+/// Accepts streams of an inbound connection, passes them to `tx`
+async fn accept_quic(
+    connecting: Connecting,
+    local_addr: SocketAddr,
+    handle: SocketHandle,
+    tx: &mpsc::Sender<AcceptResult>,
+) -> io::Result<()> {
+    // Servers could always send 0.5-RTT data, streams opened in clients' 0-RTT are accepted immediately
+    let mut new_connection = match connecting.into_0rtt() {
+        Ok((c, _)) => c,
+        Err(connecting) => connecting.await?,
+    };
+
+    let connection = Arc::new(InboundConnection {
+        connection: new_connection.connection.clone(),
+        local_addr,
+        handle,
+    });
+    trace!("quic client {} connected", new_connection.connection.remote_address());
+
+    while let Some(stream) = new_connection.bi_streams.next().await {
+        let (send, recv) = stream?;
+        let peer_addr = connection.connection.remote_address();
+        let stream = QuicStream::new(send, recv, connection.clone());
+        if tx.send((stream, peer_addr)).await.is_err() {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+/// A listener accepting shadowsocks' streams carried by the QUIC transport
+pub struct QuicListener {
+    local_addr: SocketAddr,
+    endpoint: Endpoint,
+    rx: mpsc::Receiver<AcceptResult>,
+    accept_task: JoinHandle<()>,
+}
+
+impl Drop for QuicListener {
+    fn drop(&mut self) {
+        self.accept_task.abort();
+        self.endpoint.close(VarInt::from_u32(0), b"");
+    }
+}
+
+impl QuicListener {
+    /// Creates a listener binding to UDP of `addr`
+    pub async fn bind_with_opts(
+        context: &Context,
+        addr: &ServerAddr,
+        config: &QuicConfig,
+        accept_opts: AcceptOpts,
+    ) -> io::Result<QuicListener> {
+        let server_config = config.server_config()?;
+
+        let socket = match *addr {
+            ServerAddr::SocketAddr(ref sa) => UdpSocket::listen_with_opts(sa, accept_opts).await?,
+            ServerAddr::DomainName(ref domain, port) => {
+                lookup_then!(context, domain, port, |addr| {
+                    UdpSocket::listen_with_opts(&addr, accept_opts.clone()).await
+                })?
+                .1
+            }
+        };
+        let socket: tokio::net::UdpSocket = socket.into();
+        let handle = SocketHandle::new(&socket);
+        let local_addr = socket.local_addr()?;
+
+        let (endpoint, mut incoming) =
+            Endpoint::new(EndpointConfig::default(), Some(server_config), socket.into_std()?)?;
+
+        let (tx, rx) = mpsc::channel(ACCEPT_BACKLOG);
+        let accept_task = tokio::spawn(async move {
+            while let Some(connecting) = incoming.next().await {
+                let tx = tx.clone();
+                tokio::spawn(async move {
+                    let peer_addr = connecting.remote_address();
+                    if let Err(err) = accept_quic(connecting, local_addr, handle, &tx).await {
+                        debug!("quic client {} aborted with error: {}", peer_addr, err);
+                    }
+                });
+            }
+        });
+
+        Ok(QuicListener {
+            local_addr,
+            endpoint,
+            rx,
+            accept_task,
+        })
+    }
+
+    /// Accepts a stream, which may be one of many streams in a connection of the peer
+    pub async fn accept(&mut self) -> io::Result<(QuicStream, SocketAddr)> {
+        match self.rx.recv().await {
+            Some(r) => Ok(r),
+            None => Err(io::Error::new(ErrorKind::Other, "quic listener closed")),
+        }
+    }
+
+    /// Get local binded address
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.local_addr)
+    }
+}
@@ -0,0 +1,117 @@
+//! Configuration of the ShadowTLS transport
+
+use std::{
+    fmt::{self, Debug},
+    sync::Arc,
+    time::SystemTime,
+};
+
+use once_cell::sync::OnceCell;
+use tokio_rustls::{
+    rustls::{
+        client::{ServerCertVerified, ServerCertVerifier},
+        Certificate,
+        ClientConfig,
+        Error as TlsError,
+        ServerName,
+    },
+    TlsConnector,
+};
+
+use crate::relay::socks5::Address;
+
+/// ShadowTLS transport configuration
+///
+/// - `password`: key of the authentication tags, shared by clients and servers
+/// - `sni`: the decoy domain, sent as SNI by clients
+/// - `handshake_server`: address of the site handshakes are relayed to by servers, `{sni}:443` by default
+#[derive(Clone)]
+pub struct ShadowTlsConfig {
+    password: String,
+    sni: String,
+    handshake_server: Option<Address>,
+    tls_connector: Arc<OnceCell<TlsConnector>>,
+}
+
+impl Debug for ShadowTlsConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ShadowTlsConfig")
+            .field("sni", &self.sni)
+            .field("handshake_server", &self.handshake_server)
+            .finish()
+    }
+}
+
+/// The decoy's certificate is not verified, shadowsocks' streams are authenticated by tags instead
+struct NoCertificateVerification;
+
+impl ServerCertVerifier for NoCertificateVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, TlsError> {
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+impl ShadowTlsConfig {
+    /// Creates a configuration with `password` and the decoy domain `sni`
+    pub fn new<P, N>(password: P, sni: N) -> ShadowTlsConfig
+    where
+        P: Into<String>,
+        N: Into<String>,
+    {
+        ShadowTlsConfig {
+            password: password.into(),
+            sni: sni.into(),
+            handshake_server: None,
+            tls_connector: Arc::new(OnceCell::new()),
+        }
+    }
+
+    /// Set address of the handshake server
+    pub fn set_handshake_server(&mut self, addr: Address) {
+        self.handshake_server = Some(addr);
+    }
+
+    /// Key of the authentication tags
+    pub fn password(&self) -> &str {
+        &self.password
+    }
+
+    /// The decoy domain
+    pub fn sni(&self) -> &str {
+        &self.sni
+    }
+
+    /// Address of the handshake server, if it is set explicitly
+    pub fn handshake_server(&self) -> Option<&Address> {
+        self.handshake_server.as_ref()
+    }
+
+    /// Address where handshakes are relayed to
+    pub(crate) fn handshake_addr(&self) -> Address {
+        match self.handshake_server {
+            Some(ref addr) => addr.clone(),
+            None => Address::DomainNameAddress(self.sni.clone(), 443),
+        }
+    }
+
+    /// TLS connector for clients, offering ALPN like browsers
+    pub(crate) fn tls_connector(&self) -> &TlsConnector {
+        self.tls_connector.get_or_init(|| {
+            let mut config = ClientConfig::builder()
+                .with_safe_defaults()
+                .with_custom_certificate_verifier(Arc::new(NoCertificateVerification))
+                .with_no_client_auth();
+            config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+            TlsConnector::from(Arc::new(config))
+        })
+    }
+}
@@ -0,0 +1,325 @@
+//! ShadowTLS transport, carrying shadowsocks' streams after a TLS handshake with a decoy site
+//!
+//! ```plain
+//!                                                        +----------------+
+//!                                       +-- handshake ---+ Decoy (sni:443) |
+//! +------------+                        |                +----------------+
+//! |  SS Client +-- TLS (Public Internet) --+  SS Server |
+//! +------------+                           +------------+
+//! ```
+//!
+//! Clients make a real TLS handshake with the decoy domain as SNI, which is relayed by the server to the decoy site.
+//! Then both sides send shadowsocks' streams in TLS application data records, the first record of each side
+//! begins with a tag derived from the password and ServerHello.random of the handshake.
+//!
+//! Servers keep relaying connections to the decoy site until a record with the client's tag is received,
+//! so clients without the password, like active probes, are talking to the decoy site all the time.
+
+use std::{
+    io::{self, ErrorKind},
+    net::SocketAddr,
+    pin::Pin,
+    task::{self, Poll},
+    time::Duration,
+};
+
+use bytes::BytesMut;
+use futures::ready;
+use log::{debug, error, trace};
+use tokio::{
+    io::{copy, copy_bidirectional, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf},
+    net::TcpStream as TokioTcpStream,
+    sync::mpsc,
+    task::JoinHandle,
+    time,
+};
+use tokio_rustls::rustls::ServerName;
+
+use crate::{
+    config::ServerAddr,
+    context::{Context, SharedContext},
+};
+
+use self::record::{
+    record_header,
+    AuthTags,
+    ServerHelloScanner,
+    CONTENT_TYPE_APPLICATION_DATA,
+    RECORD_HEADER_LEN,
+    TAG_LEN,
+};
+pub use self::{config::ShadowTlsConfig, record::ShadowTlsStream};
+use super::{sys::TcpStream as SysTcpStream, AcceptOpts, ConnectOpts, TcpListener, TcpStream};
+
+mod config;
+mod record;
+
+/// Streams waiting in `ShadowTlsListener` before being accepted
+const ACCEPT_BACKLOG: usize = 1024;
+
+/// Records ServerHello.random read by the TLS client
+struct ServerHelloRecorder<S> {
+    stream: S,
+    scanner: ServerHelloScanner,
+    server_random: Option<[u8; 32]>,
+}
+
+impl<S> AsyncRead for ServerHelloRecorder<S>
+where
+    S: AsyncRead + Unpin,
+{
+    fn poll_read(self: Pin<&mut Self>, cx: &mut task::Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        let filled = buf.filled().len();
+        ready!(Pin::new(&mut this.stream).poll_read(cx, buf))?;
+        if this.server_random.is_none() {
+            this.server_random = this.scanner.feed(&buf.filled()[filled..]);
+        }
+
+        Ok(()).into()
+    }
+}
+
+impl<S> AsyncWrite for ServerHelloRecorder<S>
+where
+    S: AsyncWrite + Unpin,
+{
+    fn poll_write(self: Pin<&mut Self>, cx: &mut task::Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_shutdown(cx)
+    }
+}
+
+impl ShadowTlsConfig {
+    /// Connects to the server at `addr` and makes a TLS handshake with the decoy domain
+    pub async fn connect(
+        &self,
+        context: &Context,
+        addr: &ServerAddr,
+        opts: &ConnectOpts,
+    ) -> io::Result<ShadowTlsStream<SysTcpStream>> {
+        let stream = match *addr {
+            ServerAddr::SocketAddr(ref addr) => SysTcpStream::connect(*addr, opts).await?,
+            ServerAddr::DomainName(ref domain, port) => {
+                lookup_then_connect!(context, domain, port, |addr| {
+                    SysTcpStream::connect(addr, opts).await
+                })?
+                .1
+            }
+        };
+
+        let server_name = match ServerName::try_from(self.sni()) {
+            Ok(n) => n,
+            Err(..) => {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("invalid dnsname \"{}\"", self.sni()),
+                ));
+            }
+        };
+
+        let recorder = ServerHelloRecorder {
+            stream,
+            scanner: ServerHelloScanner::default(),
+            server_random: None,
+        };
+        let tls_stream = self.tls_connector().connect(server_name, recorder).await?;
+
+        // The TLS session is abandoned, records after the handshake are ours
+        let (recorder, _) = tls_stream.into_inner();
+        let server_random = match recorder.server_random {
+            Some(r) => r,
+            None => {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    "shadow-tls handshake finished without ServerHello",
+                ));
+            }
+        };
+
+        trace!("shadow-tls handshake with {} as {} finished", addr, self.sni());
+
+        let tags = AuthTags::new(self.password(), &server_random);
+        Ok(ShadowTlsStream::new(
+            recorder.stream,
+            tags.server,
+            tags.client,
+            BytesMut::new(),
+        ))
+    }
+}
+
+type AcceptResult = (ShadowTlsStream<TokioTcpStream>, SocketAddr);
+
+/// Relays an inbound connection to the handshake server until the client is authenticated
+///
+/// Returns `None` if the connection ends without authentication.
+async fn accept_shadow_tls(
+    context: &Context,
+    mut stream: TokioTcpStream,
+    config: &ShadowTlsConfig,
+    connect_opts: &ConnectOpts,
+) -> io::Result<Option<ShadowTlsStream<TokioTcpStream>>> {
+    let mut handshake = TcpStream::connect_remote_with_opts(context, &config.handshake_addr(), connect_opts).await?;
+
+    let mut scanner = ServerHelloScanner::default();
+    let mut tags = None;
+
+    let mut client_buf = BytesMut::new();
+    let mut server_buf = vec![0u8; 16 * 1024];
+
+    loop {
+        tokio::select! {
+            n = stream.read_buf(&mut client_buf) => {
+                if n? == 0 {
+                    handshake.write_all(&client_buf).await?;
+                    handshake.shutdown().await?;
+                    copy(&mut handshake, &mut stream).await?;
+                    return Ok(None);
+                }
+
+                // Forward complete records, until the one with the client's tag
+                loop {
+                    let (content_type, len) = match record_header(&client_buf) {
+                        Ok(Some(h)) => h,
+                        Ok(None) => break,
+                        Err(..) => {
+                            // Not TLS, let the handshake server answer it
+                            handshake.write_all(&client_buf).await?;
+                            copy_bidirectional(&mut stream, &mut handshake).await?;
+                            return Ok(None);
+                        }
+                    };
+                    if client_buf.len() < RECORD_HEADER_LEN + len {
+                        break;
+                    }
+
+                    if let Some(AuthTags { client, server }) = tags {
+                        if content_type == CONTENT_TYPE_APPLICATION_DATA
+                            && len >= TAG_LEN
+                            && client_buf[RECORD_HEADER_LEN..RECORD_HEADER_LEN + TAG_LEN] == client
+                        {
+                            return Ok(Some(ShadowTlsStream::new(stream, client, server, client_buf)));
+                        }
+                    }
+
+                    let record = client_buf.split_to(RECORD_HEADER_LEN + len);
+                    handshake.write_all(&record).await?;
+                }
+            }
+            n = handshake.read(&mut server_buf) => {
+                let n = n?;
+                if n == 0 {
+                    stream.shutdown().await?;
+                    return Ok(None);
+                }
+
+                stream.write_all(&server_buf[..n]).await?;
+
+                if tags.is_none() {
+                    if let Some(server_random) = scanner.feed(&server_buf[..n]) {
+                        tags = Some(AuthTags::new(config.password(), &server_random));
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// A listener accepting shadowsocks' streams carried by the ShadowTLS transport
+///
+/// Inbound connections are relayed to the handshake server in the background, only authenticated ones are accepted.
+pub struct ShadowTlsListener {
+    local_addr: SocketAddr,
+    rx: mpsc::Receiver<AcceptResult>,
+    accept_task: JoinHandle<()>,
+}
+
+impl Drop for ShadowTlsListener {
+    fn drop(&mut self) {
+        self.accept_task.abort();
+    }
+}
+
+impl ShadowTlsListener {
+    /// Creates a listener binding to `addr`, connecting handshake servers with `connect_opts`
+    pub async fn bind_with_opts(
+        context: SharedContext,
+        addr: &ServerAddr,
+        config: &ShadowTlsConfig,
+        accept_opts: AcceptOpts,
+        connect_opts: ConnectOpts,
+    ) -> io::Result<ShadowTlsListener> {
+        let listener = match *addr {
+            ServerAddr::SocketAddr(ref sa) => TcpListener::bind_with_opts(sa, accept_opts).await?,
+            ServerAddr::DomainName(ref domain, port) => {
+                lookup_then!(&context, domain, port, |addr| {
+                    TcpListener::bind_with_opts(&addr, accept_opts.clone()).await
+                })?
+                .1
+            }
+        };
+        let local_addr = listener.local_addr()?;
+
+        let (tx, rx) = mpsc::channel(ACCEPT_BACKLOG);
+        let config = config.clone();
+
+        let accept_task = tokio::spawn(async move {
+            loop {
+                let (stream, peer_addr) = match listener.accept().await {
+                    Ok(s) => s,
+                    Err(err) => {
+                        error!("shadow-tls server accept failed with error: {}", err);
+                        time::sleep(Duration::from_secs(1)).await;
+                        continue;
+                    }
+                };
+
+                let context = context.clone();
+                let config = config.clone();
+                let connect_opts = connect_opts.clone();
+                let tx = tx.clone();
+                tokio::spawn(async move {
+                    match accept_shadow_tls(&context, stream, &config, &connect_opts).await {
+                        Ok(Some(stream)) => {
+                            let _ = tx.send((stream, peer_addr)).await;
+                        }
+                        Ok(None) => {
+                            debug!("shadow-tls client {} relayed to {}", peer_addr, config.handshake_addr());
+                        }
+                        Err(err) => {
+                            debug!("shadow-tls client {} aborted with error: {}", peer_addr, err);
+                        }
+                    }
+                });
+            }
+        });
+
+        Ok(ShadowTlsListener {
+            local_addr,
+            rx,
+            accept_task,
+        })
+    }
+
+    /// Accepts an authenticated stream
+    pub async fn accept(&mut self) -> io::Result<(ShadowTlsStream<TokioTcpStream>, SocketAddr)> {
+        match self.rx.recv().await {
+            Some(r) => Ok(r),
+            None => Err(io::Error::new(ErrorKind::Other, "shadow-tls listener closed")),
+        }
+    }
+
+    /// Get local binded address
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.local_addr)
+    }
+}
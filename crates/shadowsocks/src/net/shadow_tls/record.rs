@@ -0,0 +1,297 @@
+//! TLS records carrying shadowsocks' stream after the handshake
+//!
+//! Every write is sent as one application data record. The first record sent by each side begins with
+//! its authentication tag, records before the peer's tag are handshake traffic and ignored.
+
+use std::{
+    io::{self, ErrorKind},
+    ops::Deref,
+    pin::Pin,
+    task::{self, Poll},
+};
+
+use bytes::{Buf, BufMut, BytesMut};
+use futures::ready;
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::net::framed::{self, FramedWrite};
+
+pub const CONTENT_TYPE_CHANGE_CIPHER_SPEC: u8 = 0x14;
+pub const CONTENT_TYPE_HANDSHAKE: u8 = 0x16;
+pub const CONTENT_TYPE_APPLICATION_DATA: u8 = 0x17;
+
+const HANDSHAKE_TYPE_SERVER_HELLO: u8 = 0x02;
+
+/// ContentType, ProtocolVersion and length
+pub const RECORD_HEADER_LEN: usize = 5;
+/// Maximum payload of records sent by us, 2^14 as TLSPlaintext
+const MAX_RECORD_PAYLOAD: usize = 16 * 1024;
+/// Maximum payload of records accepted from peers, 2^14 + 2048 as TLSCiphertext
+const MAX_RECORD_LEN: usize = 16 * 1024 + 2048;
+
+/// Length of authentication tags, truncated HMAC-SHA1
+pub const TAG_LEN: usize = 8;
+
+/// ServerHello.random of HelloRetryRequest, RFC 8446 4.1.3
+const HELLO_RETRY_REQUEST_RANDOM: [u8; 32] = [
+    0xCF, 0x21, 0xAD, 0x74, 0xE5, 0x9A, 0x61, 0x11, 0xBE, 0x1D, 0x8C, 0x02, 0x1E, 0x65, 0xB8, 0x91, 0xC2, 0xA2, 0x11,
+    0x16, 0x7A, 0xBB, 0x8C, 0x5E, 0x07, 0x9E, 0x09, 0xE2, 0xC8, 0xA8, 0x33, 0x9C,
+];
+
+/// Parses the header of the record at the front of `buf`, returns its content type and payload length
+///
+/// Returns `Ok(None)` if the header is incomplete, and an error if `buf` doesn't look like TLS.
+pub fn record_header(buf: &[u8]) -> io::Result<Option<(u8, usize)>> {
+    if buf.len() < RECORD_HEADER_LEN {
+        return Ok(None);
+    }
+
+    let content_type = buf[0];
+    let len = u16::from_be_bytes([buf[3], buf[4]]) as usize;
+
+    if !(CONTENT_TYPE_CHANGE_CIPHER_SPEC..=CONTENT_TYPE_APPLICATION_DATA).contains(&content_type)
+        || buf[1] != 0x03
+        || len > MAX_RECORD_LEN
+    {
+        return Err(io::Error::new(ErrorKind::InvalidData, "not a TLS record"));
+    }
+
+    Ok(Some((content_type, len)))
+}
+
+/// Authentication tags of both sides, derived from the password and ServerHello.random of the handshake
+#[derive(Debug, Clone, Copy)]
+pub struct AuthTags {
+    pub client: [u8; TAG_LEN],
+    pub server: [u8; TAG_LEN],
+}
+
+impl AuthTags {
+    pub fn new(password: &str, server_random: &[u8; 32]) -> AuthTags {
+        let tag = |side: &[u8]| {
+            let mut mac = Hmac::<Sha1>::new_from_slice(password.as_bytes()).expect("HMAC accepts keys of any length");
+            mac.update(server_random);
+            mac.update(side);
+
+            let mut tag = [0u8; TAG_LEN];
+            tag.copy_from_slice(&mac.finalize().into_bytes()[..TAG_LEN]);
+            tag
+        };
+
+        AuthTags {
+            client: tag(b"C"),
+            server: tag(b"S"),
+        }
+    }
+}
+
+/// Finds ServerHello.random in the records sent by the handshake server
+#[derive(Default)]
+pub struct ServerHelloScanner {
+    buf: BytesMut,
+    done: bool,
+}
+
+impl ServerHelloScanner {
+    /// Feeds bytes sent by the handshake server, returns ServerHello.random once it is found
+    ///
+    /// HelloRetryRequest is skipped, the random of the following ServerHello is returned.
+    pub fn feed(&mut self, data: &[u8]) -> Option<[u8; 32]> {
+        if self.done {
+            return None;
+        }
+        self.buf.extend_from_slice(data);
+
+        loop {
+            let (content_type, len) = match record_header(&self.buf) {
+                Ok(Some(h)) => h,
+                Ok(None) => return None,
+                Err(..) => {
+                    self.done = true;
+                    return None;
+                }
+            };
+            if self.buf.len() < RECORD_HEADER_LEN + len {
+                return None;
+            }
+
+            let record = self.buf.split_to(RECORD_HEADER_LEN + len);
+            let payload = &record[RECORD_HEADER_LEN..];
+
+            // Handshake header (4), legacy_version (2), random (32)
+            if content_type == CONTENT_TYPE_HANDSHAKE
+                && payload.len() >= 38
+                && payload[0] == HANDSHAKE_TYPE_SERVER_HELLO
+            {
+                let mut random = [0u8; 32];
+                random.copy_from_slice(&payload[6..38]);
+                if random != HELLO_RETRY_REQUEST_RANDOM {
+                    self.done = true;
+                    self.buf = BytesMut::new();
+                    return Some(random);
+                }
+            }
+        }
+    }
+}
+
+/// A stream carried in TLS application data records after an authenticated handshake
+pub struct ShadowTlsStream<S> {
+    stream: S,
+    read_buf: BytesMut,
+    read_remaining: usize,
+    read_tag: Option<[u8; TAG_LEN]>,
+    write_buf: BytesMut,
+    write_tag: Option<[u8; TAG_LEN]>,
+}
+
+impl<S> ShadowTlsStream<S> {
+    /// Creates a stream on a connection whose handshake has been finished
+    ///
+    /// Records are read from `buffered` first, which are bytes already read from `stream`.
+    pub fn new(stream: S, read_tag: [u8; TAG_LEN], write_tag: [u8; TAG_LEN], buffered: BytesMut) -> ShadowTlsStream<S> {
+        ShadowTlsStream {
+            stream,
+            read_buf: buffered,
+            read_remaining: 0,
+            read_tag: Some(read_tag),
+            write_buf: BytesMut::new(),
+            write_tag: Some(write_tag),
+        }
+    }
+
+    /// Consumes the record at the front of `read_buf` if it is complete, returns `false` if it needs more data
+    fn consume_record(&mut self) -> io::Result<bool> {
+        let (content_type, len) = match record_header(&self.read_buf)? {
+            Some(h) => h,
+            None => return Ok(false),
+        };
+
+        match self.read_tag {
+            // Authenticated, records are all ours
+            None => {
+                if content_type != CONTENT_TYPE_APPLICATION_DATA {
+                    return Err(io::Error::new(
+                        ErrorKind::InvalidData,
+                        format!("unexpected TLS record with content type {}", content_type),
+                    ));
+                }
+                self.read_buf.advance(RECORD_HEADER_LEN);
+                self.read_remaining = len;
+            }
+            // Records from the handshake server are skipped until the peer's tag
+            Some(tag) => {
+                if self.read_buf.len() < RECORD_HEADER_LEN + len {
+                    return Ok(false);
+                }
+
+                let payload = &self.read_buf[RECORD_HEADER_LEN..RECORD_HEADER_LEN + len];
+                if content_type == CONTENT_TYPE_APPLICATION_DATA && len >= TAG_LEN && payload[..TAG_LEN] == tag {
+                    self.read_tag = None;
+                    self.read_buf.advance(RECORD_HEADER_LEN + TAG_LEN);
+                    self.read_remaining = len - TAG_LEN;
+                } else {
+                    self.read_buf.advance(RECORD_HEADER_LEN + len);
+                }
+            }
+        }
+
+        Ok(true)
+    }
+
+    fn encode_record(&mut self, data: &[u8]) {
+        let tag = self.write_tag.take();
+        let len = data.len() + tag.map(|t| t.len()).unwrap_or(0);
+
+        self.write_buf.reserve(RECORD_HEADER_LEN + len);
+        self.write_buf.put_u8(CONTENT_TYPE_APPLICATION_DATA);
+        // TLS 1.2, which is also the legacy_record_version of TLS 1.3
+        self.write_buf.put_u16(0x0303);
+        self.write_buf.put_u16(len as u16);
+        if let Some(tag) = tag {
+            self.write_buf.put_slice(&tag);
+        }
+        self.write_buf.put_slice(data);
+    }
+}
+
+impl<S> FramedWrite for ShadowTlsStream<S>
+where
+    S: AsyncWrite + Unpin,
+{
+    fn poll_write_encoded(&mut self, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        framed::poll_write_buffered(&mut self.stream, &mut self.write_buf, cx)
+    }
+
+    fn encode(&mut self, data: &[u8]) -> usize {
+        let max_payload = MAX_RECORD_PAYLOAD - self.write_tag.map(|t| t.len()).unwrap_or(0);
+        let n = data.len().min(max_payload);
+        self.encode_record(&data[..n]);
+        n
+    }
+}
+
+impl<S> Deref for ShadowTlsStream<S> {
+    type Target = S;
+
+    fn deref(&self) -> &S {
+        &self.stream
+    }
+}
+
+impl<S> AsyncRead for ShadowTlsStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_read(self: Pin<&mut Self>, cx: &mut task::Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        loop {
+            if this.read_remaining > 0 && !this.read_buf.is_empty() {
+                let n = this.read_remaining.min(this.read_buf.len()).min(buf.remaining());
+                buf.put_slice(&this.read_buf[..n]);
+                this.read_buf.advance(n);
+                this.read_remaining -= n;
+                return Ok(()).into();
+            }
+
+            if this.read_remaining == 0 && this.consume_record()? {
+                continue;
+            }
+
+            if !ready!(framed::poll_fill(&mut this.stream, &mut this.read_buf, cx))? {
+                if this.read_remaining > 0 || !this.read_buf.is_empty() {
+                    return Err(io::Error::new(
+                        ErrorKind::UnexpectedEof,
+                        "TLS record terminated unexpectedly",
+                    ))
+                    .into();
+                }
+                return Ok(()).into();
+            }
+        }
+    }
+}
+
+impl<S> AsyncWrite for ShadowTlsStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_write(self: Pin<&mut Self>, cx: &mut task::Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        self.get_mut().poll_write_framed(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_encoded(cx))?;
+        Pin::new(&mut this.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_encoded(cx))?;
+        Pin::new(&mut this.stream).poll_shutdown(cx)
+    }
+}
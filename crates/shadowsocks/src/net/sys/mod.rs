@@ -5,10 +5,10 @@ use std::{
 
 use cfg_if::cfg_if;
 use log::{debug, warn};
-use socket2::{SockAddr, Socket};
+use socket2::{SockAddr, SockRef, Socket, TcpKeepalive};
 use tokio::net::TcpSocket;
 
-use super::ConnectOpts;
+use super::{ConnectOpts, TcpSocketOpts};
 
 cfg_if! {
     if #[cfg(unix)] {
@@ -20,7 +20,85 @@ cfg_if! {
     }
 }
 
+/// Create a `TcpSocket` for connecting to or listening on `addr`, a Multipath TCP socket if `mptcp` is set and supported
+pub fn create_tcp_socket(addr: &SocketAddr, mptcp: bool) -> io::Result<TcpSocket> {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    if mptcp {
+        match create_mptcp_socket(addr) {
+            Ok(socket) => return Ok(socket),
+            Err(err) => debug!("MPTCP socket for {} unavailable, fallback to TCP, error: {}", addr, err),
+        }
+    }
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    let _ = mptcp;
+
+    match *addr {
+        SocketAddr::V4(..) => TcpSocket::new_v4(),
+        SocketAddr::V6(..) => TcpSocket::new_v6(),
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn create_mptcp_socket(addr: &SocketAddr) -> io::Result<TcpSocket> {
+    use socket2::{Domain, Protocol, Type};
+    use std::os::unix::io::{FromRawFd, IntoRawFd};
+
+    // Not defined in libc for all targets
+    const IPPROTO_MPTCP: libc::c_int = 262;
+
+    let protocol = Protocol::from(IPPROTO_MPTCP);
+    let socket = Socket::new(Domain::for_address(*addr), Type::STREAM, Some(protocol))?;
+    socket.set_nonblocking(true)?;
+    Ok(unsafe { TcpSocket::from_raw_fd(socket.into_raw_fd()) })
+}
+
+/// Keep-alive parameters of `opts`, `None` if keep-alive is not enabled
+pub fn tcp_keepalive(opts: &TcpSocketOpts) -> Option<TcpKeepalive> {
+    let time = opts.keepalive?;
+
+    #[allow(unused_mut)]
+    let mut keepalive = TcpKeepalive::new().with_time(time);
+
+    #[cfg(any(
+        target_os = "android",
+        target_os = "dragonfly",
+        target_os = "freebsd",
+        target_os = "fuchsia",
+        target_os = "illumos",
+        target_os = "linux",
+        target_os = "netbsd",
+        target_vendor = "apple",
+        windows,
+    ))]
+    {
+        keepalive = keepalive.with_interval(opts.keepalive_interval.unwrap_or(time));
+    }
+
+    #[cfg(any(
+        target_os = "android",
+        target_os = "dragonfly",
+        target_os = "freebsd",
+        target_os = "fuchsia",
+        target_os = "illumos",
+        target_os = "linux",
+        target_os = "netbsd",
+        target_vendor = "apple",
+    ))]
+    if let Some(retries) = opts.keepalive_retries {
+        keepalive = keepalive.with_retries(retries);
+    }
+
+    Some(keepalive)
+}
+
 fn set_common_sockopt_for_connect(addr: SocketAddr, socket: &TcpSocket, opts: &ConnectOpts) -> io::Result<()> {
+    // Protected before it sends anything
+    #[cfg(unix)]
+    if let Some(ref protector) = opts.socket_protector {
+        use std::os::unix::io::AsRawFd;
+        protector.protect(socket.as_raw_fd())?;
+    }
+
     // Binds to IP address
     if let Some(ip) = opts.bind_local_addr {
         match (ip, addr.ip()) {
@@ -44,6 +122,15 @@ fn set_common_sockopt_for_connect(addr: SocketAddr, socket: &TcpSocket, opts: &C
         socket.set_recv_buffer_size(buf_size)?;
     }
 
+    // Set `IP_TTL` / `IPV6_UNICAST_HOPS`
+    if let Some(ttl) = opts.ip_ttl {
+        let sock = SockRef::from(socket);
+        match addr {
+            SocketAddr::V4(..) => sock.set_ttl(ttl)?,
+            SocketAddr::V6(..) => sock.set_unicast_hops_v6(ttl)?,
+        }
+    }
+
     Ok(())
 }
 
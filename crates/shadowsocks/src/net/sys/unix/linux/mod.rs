@@ -4,20 +4,23 @@ use std::{
     net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
     os::unix::io::{AsRawFd, RawFd},
     pin::Pin,
+    ptr,
     task::{self, Poll},
 };
 
 use cfg_if::cfg_if;
 use log::error;
 use pin_project::pin_project;
+use socket2::SockAddr;
 use tokio::{
     io::{AsyncRead, AsyncWrite, ReadBuf},
-    net::{TcpSocket, TcpStream as TokioTcpStream, UdpSocket},
+    net::{TcpStream as TokioTcpStream, UdpSocket},
 };
 use tokio_tfo::TfoStream;
 
 use crate::net::{
-    sys::{set_common_sockopt_after_connect, set_common_sockopt_for_connect},
+    sys::{create_tcp_socket, set_common_sockopt_after_connect, set_common_sockopt_for_connect},
+    udp::UDP_BATCH_SIZE,
     AddrFamily,
     ConnectOpts,
 };
@@ -31,10 +34,7 @@ pub enum TcpStream {
 
 impl TcpStream {
     pub async fn connect(addr: SocketAddr, opts: &ConnectOpts) -> io::Result<TcpStream> {
-        let socket = match addr {
-            SocketAddr::V4(..) => TcpSocket::new_v4()?,
-            SocketAddr::V6(..) => TcpSocket::new_v6()?,
-        };
+        let socket = create_tcp_socket(&addr, opts.tcp.mptcp)?;
 
         // Any traffic to localhost should not be protected
         // This is a workaround for VPNService
@@ -78,6 +78,35 @@ impl TcpStream {
             set_bindtodevice(&socket, iface)?;
         }
 
+        // Set SO_MAX_PACING_RATE for pacing by TCP stack or fq qdisc (since 3.13)
+        if let Some(rate) = opts.tcp.pacing_rate {
+            // Kernels before 5.0 accept only 32-bit rates
+            let rate = rate.min(u32::MAX as u64) as u32;
+            let ret = unsafe {
+                libc::setsockopt(
+                    socket.as_raw_fd(),
+                    libc::SOL_SOCKET,
+                    libc::SO_MAX_PACING_RATE,
+                    &rate as *const _ as *const _,
+                    mem::size_of_val(&rate) as libc::socklen_t,
+                )
+            };
+            if ret != 0 {
+                let err = io::Error::last_os_error();
+                error!("set SO_MAX_PACING_RATE error: {}", err);
+                return Err(err);
+            }
+        }
+
+        // Congestion control is chosen before SYN, so the whole connection is controlled by it
+        if let Some(ref algorithm) = opts.tcp.congestion {
+            set_tcp_congestion(&socket, algorithm)?;
+        }
+
+        if let Some(bytes) = opts.tcp.notsent_lowat {
+            set_tcp_notsent_lowat(&socket, bytes)?;
+        }
+
         set_common_sockopt_for_connect(addr, &socket, opts)?;
 
         if !opts.tcp.fastopen {
@@ -197,6 +226,47 @@ pub fn set_tcp_fastopen<S: AsRawFd>(socket: &S) -> io::Result<()> {
     Ok(())
 }
 
+/// Set `TCP_CONGESTION`, the congestion control algorithm of `socket`, like `bbr`
+///
+/// Algorithms not listed in `net.ipv4.tcp_allowed_congestion_control` require `CAP_NET_ADMIN`
+pub fn set_tcp_congestion<S: AsRawFd>(socket: &S, algorithm: &str) -> io::Result<()> {
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_TCP,
+            libc::TCP_CONGESTION,
+            algorithm.as_ptr() as *const libc::c_void,
+            algorithm.len() as libc::socklen_t,
+        )
+    };
+    if ret != 0 {
+        let err = io::Error::last_os_error();
+        error!("set TCP_CONGESTION {} error: {}", algorithm, err);
+        return Err(err);
+    }
+    Ok(())
+}
+
+/// Set `TCP_NOTSENT_LOWAT`, limiting unsent bytes in the send buffer of `socket` (since Linux 3.12)
+pub fn set_tcp_notsent_lowat<S: AsRawFd>(socket: &S, bytes: u32) -> io::Result<()> {
+    let bytes = bytes as libc::c_int;
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_TCP,
+            libc::TCP_NOTSENT_LOWAT,
+            &bytes as *const _ as *const libc::c_void,
+            mem::size_of_val(&bytes) as libc::socklen_t,
+        )
+    };
+    if ret != 0 {
+        let err = io::Error::last_os_error();
+        error!("set TCP_NOTSENT_LOWAT error: {}", err);
+        return Err(err);
+    }
+    Ok(())
+}
+
 /// Create a `UdpSocket` for connecting to `addr`
 pub async fn create_outbound_udp_socket(af: AddrFamily, config: &ConnectOpts) -> io::Result<UdpSocket> {
     let bind_addr = match (af, config.bind_local_addr) {
@@ -253,6 +323,192 @@ pub async fn create_outbound_udp_socket(af: AddrFamily, config: &ConnectOpts) ->
     Ok(socket)
 }
 
+/// `UDP_SEGMENT`, size of datagrams segmented from a message by GSO (since Linux 4.18)
+const UDP_SEGMENT: libc::c_int = 103;
+/// `UDP_GRO`, coalesces datagrams received into one message (since Linux 5.0)
+const UDP_GRO: libc::c_int = 104;
+/// Maximum size of a message segmented by GSO, which is still a UDP datagram before segmenting
+const UDP_GSO_MAX_SIZE: usize = 65507;
+
+/// Space of control messages, for one `UDP_SEGMENT` or `UDP_GRO`
+type CmsgBuffer = [u64; 4];
+
+/// Check if UDP GSO and GRO are supported by the kernel
+pub fn check_udp_offload() -> io::Result<()> {
+    let socket = socket2::Socket::new(socket2::Domain::IPV4, socket2::Type::DGRAM, None)?;
+    set_udp_option(&socket, UDP_SEGMENT, 0)?;
+    set_udp_option(&socket, UDP_GRO, 1)?;
+    Ok(())
+}
+
+/// Coalesce datagrams received by `socket` with GRO, or stop doing that
+///
+/// Datagrams from the same source in the same size are received as one message, which has to be split by the
+/// segment size returned by `recv_mmsg`.
+pub fn set_udp_gro<S: AsRawFd>(socket: &S, enabled: bool) -> io::Result<()> {
+    set_udp_option(socket, UDP_GRO, enabled as libc::c_int)
+}
+
+fn set_udp_option<S: AsRawFd>(socket: &S, name: libc::c_int, value: libc::c_int) -> io::Result<()> {
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_UDP,
+            name,
+            &value as *const _ as *const libc::c_void,
+            mem::size_of_val(&value) as libc::socklen_t,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Receive messages into `bufs` with one `recvmmsg` call, pushing their lengths, source addresses and segment sizes
+/// into `messages`
+///
+/// Segment size is the size of datagrams coalesced into the message by GRO, or `0` if it isn't coalesced. `socket`
+/// has to be non-blocking, `WouldBlock` is returned if no datagram is received.
+pub fn recv_mmsg<S: AsRawFd>(
+    socket: &S,
+    bufs: &mut [Box<[u8]>],
+    messages: &mut Vec<(usize, SocketAddr, usize)>,
+) -> io::Result<usize> {
+    let count = bufs.len().min(UDP_BATCH_SIZE);
+
+    let mut names: [libc::sockaddr_storage; UDP_BATCH_SIZE] = unsafe { mem::zeroed() };
+    let mut iovs: [libc::iovec; UDP_BATCH_SIZE] = unsafe { mem::zeroed() };
+    let mut controls: [CmsgBuffer; UDP_BATCH_SIZE] = [[0; 4]; UDP_BATCH_SIZE];
+    let mut msgs: [libc::mmsghdr; UDP_BATCH_SIZE] = unsafe { mem::zeroed() };
+    for (i, buf) in bufs.iter_mut().take(count).enumerate() {
+        iovs[i].iov_base = buf.as_mut_ptr() as *mut libc::c_void;
+        iovs[i].iov_len = buf.len();
+        msgs[i].msg_hdr.msg_name = &mut names[i] as *mut _ as *mut libc::c_void;
+        msgs[i].msg_hdr.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+        msgs[i].msg_hdr.msg_iov = &mut iovs[i];
+        msgs[i].msg_hdr.msg_iovlen = 1;
+        msgs[i].msg_hdr.msg_control = controls[i].as_mut_ptr() as *mut libc::c_void;
+        msgs[i].msg_hdr.msg_controllen = mem::size_of::<CmsgBuffer>() as _;
+    }
+
+    let ret = unsafe { libc::recvmmsg(socket.as_raw_fd(), msgs.as_mut_ptr(), count as _, 0, ptr::null_mut()) };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let n = ret as usize;
+    for (msg, name) in msgs.iter().zip(names.iter()).take(n) {
+        let addr = unsafe { SockAddr::new(*name, msg.msg_hdr.msg_namelen) };
+        // Sources other than IPv4 or IPv6 are not expected, but indexes of `messages` have to match `bufs`
+        let addr = addr
+            .as_socket()
+            .unwrap_or_else(|| SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0));
+
+        let mut len = msg.msg_len as usize;
+        let segment_size = gro_segment_size(&msg.msg_hdr);
+        if segment_size > 0 && msg.msg_hdr.msg_flags & libc::MSG_TRUNC != 0 {
+            // The last datagram is truncated, the others are intact
+            len -= len % segment_size;
+        }
+        messages.push((len, addr, segment_size));
+    }
+
+    Ok(n)
+}
+
+fn gro_segment_size(hdr: &libc::msghdr) -> usize {
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(hdr);
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == libc::SOL_UDP && (*cmsg).cmsg_type == UDP_GRO {
+                let segment_size = ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const libc::c_int);
+                return segment_size.max(0) as usize;
+            }
+            cmsg = libc::CMSG_NXTHDR(hdr, cmsg);
+        }
+    }
+    0
+}
+
+/// Send datagrams in `bufs` to `targets` (`None` for the connected address) with one `sendmmsg` call
+///
+/// With `gso`, consecutive datagrams to the same target in the same size (the last of them could be smaller) are sent
+/// as one message, which is segmented by the kernel or the NIC with GSO. Returns the number of datagrams sent, which
+/// may be less than `bufs.len()`. `socket` has to be non-blocking.
+pub fn send_mmsg<S: AsRawFd>(
+    socket: &S,
+    bufs: &[&[u8]],
+    targets: &[Option<SocketAddr>],
+    gso: bool,
+) -> io::Result<usize> {
+    let count = bufs.len().min(targets.len()).min(UDP_BATCH_SIZE);
+
+    let names = targets[..count]
+        .iter()
+        .map(|target| target.map(SockAddr::from))
+        .collect::<Vec<_>>();
+    let mut iovs: [libc::iovec; UDP_BATCH_SIZE] = unsafe { mem::zeroed() };
+    let mut controls: [CmsgBuffer; UDP_BATCH_SIZE] = [[0; 4]; UDP_BATCH_SIZE];
+    let mut msgs: [libc::mmsghdr; UDP_BATCH_SIZE] = unsafe { mem::zeroed() };
+    // Datagrams in each message
+    let mut segments = [0usize; UDP_BATCH_SIZE];
+
+    let mut msg_count = 0;
+    let mut i = 0;
+    while i < count {
+        let segment_size = bufs[i].len();
+        let mut end = i + 1;
+        if gso && segment_size > 0 {
+            let mut size = segment_size;
+            while end < count
+                && targets[end] == targets[i]
+                && bufs[end - 1].len() == segment_size
+                && bufs[end].len() <= segment_size
+                && size + bufs[end].len() <= UDP_GSO_MAX_SIZE
+            {
+                size += bufs[end].len();
+                end += 1;
+            }
+        }
+
+        for (iov, buf) in iovs[i..end].iter_mut().zip(&bufs[i..end]) {
+            iov.iov_base = buf.as_ptr() as *mut libc::c_void;
+            iov.iov_len = buf.len();
+        }
+
+        let hdr = &mut msgs[msg_count].msg_hdr;
+        if let Some(ref name) = names[i] {
+            hdr.msg_name = name.as_ptr() as *mut libc::c_void;
+            hdr.msg_namelen = name.len();
+        }
+        hdr.msg_iov = &mut iovs[i];
+        hdr.msg_iovlen = (end - i) as _;
+        if end - i > 1 {
+            hdr.msg_control = controls[msg_count].as_mut_ptr() as *mut libc::c_void;
+            unsafe {
+                hdr.msg_controllen = libc::CMSG_SPACE(mem::size_of::<u16>() as _) as _;
+                let cmsg = libc::CMSG_FIRSTHDR(hdr);
+                (*cmsg).cmsg_level = libc::SOL_UDP;
+                (*cmsg).cmsg_type = UDP_SEGMENT;
+                (*cmsg).cmsg_len = libc::CMSG_LEN(mem::size_of::<u16>() as _) as _;
+                ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut u16, segment_size as u16);
+            }
+        }
+
+        segments[msg_count] = end - i;
+        msg_count += 1;
+        i = end;
+    }
+
+    let ret = unsafe { libc::sendmmsg(socket.as_raw_fd(), msgs.as_mut_ptr(), msg_count as _, 0) };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(segments[..ret as usize].iter().sum())
+}
+
 fn set_bindtodevice<S: AsRawFd>(socket: &S, iface: &str) -> io::Result<()> {
     let iface_bytes = iface.as_bytes();
 
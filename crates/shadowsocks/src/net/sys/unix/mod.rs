@@ -5,10 +5,14 @@ use std::{
 };
 
 use cfg_if::cfg_if;
-use socket2::{Domain, Protocol, Socket, TcpKeepalive, Type};
+use socket2::{Domain, Protocol, Socket, Type};
 use tokio::net::UdpSocket;
 
-use crate::net::{is_dual_stack_addr, sys::socket_bind_dual_stack, ConnectOpts};
+use crate::net::{
+    is_dual_stack_addr,
+    sys::{socket_bind_dual_stack, tcp_keepalive},
+    ConnectOpts,
+};
 
 cfg_if! {
     if #[cfg(any(target_os = "linux", target_os = "android"))] {
@@ -32,15 +36,22 @@ cfg_if! {
 
 pub mod uds;
 
-/// Create a `UdpSocket` binded to `addr`
-pub async fn create_inbound_udp_socket(addr: &SocketAddr, ipv6_only: bool) -> io::Result<UdpSocket> {
+/// Create a `UdpSocket` binded to `addr`, with `SO_REUSEPORT` if `reuse_port`
+pub async fn create_inbound_udp_socket(addr: &SocketAddr, ipv6_only: bool, reuse_port: bool) -> io::Result<UdpSocket> {
     let set_dual_stack = is_dual_stack_addr(addr);
 
-    if !set_dual_stack {
+    if !set_dual_stack && !reuse_port {
         UdpSocket::bind(addr).await
     } else {
         let socket = Socket::new(Domain::for_address(*addr), Type::DGRAM, Some(Protocol::UDP))?;
-        socket_bind_dual_stack(&socket, addr, ipv6_only)?;
+        if reuse_port {
+            socket.set_reuse_port(true)?;
+        }
+        if set_dual_stack {
+            socket_bind_dual_stack(&socket, addr, ipv6_only)?;
+        } else {
+            socket.bind(&(*addr).into())?;
+        }
 
         // UdpSocket::from_std requires socket to be non-blocked
         socket.set_nonblocking(true)?;
@@ -67,24 +78,15 @@ pub fn set_common_sockopt_after_connect<S: AsRawFd>(stream: &S, opts: &ConnectOp
         try_sockopt!(socket.set_nodelay(true));
     }
 
-    if let Some(keepalive_duration) = opts.tcp.keepalive {
-        #[allow(unused_mut)]
-        let mut keepalive = TcpKeepalive::new().with_time(keepalive_duration);
-
-        #[cfg(any(
-            target_os = "freebsd",
-            target_os = "fuchsia",
-            target_os = "linux",
-            target_os = "netbsd",
-            target_vendor = "apple",
-        ))]
-        {
-            keepalive = keepalive.with_interval(keepalive_duration);
-        }
-
+    if let Some(keepalive) = tcp_keepalive(&opts.tcp) {
         try_sockopt!(socket.set_tcp_keepalive(&keepalive));
     }
 
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    if let Some(timeout) = opts.tcp.user_timeout {
+        try_sockopt!(socket.set_tcp_user_timeout(Some(timeout)));
+    }
+
     let _ = socket.into_raw_fd();
 
     Ok(())
@@ -11,7 +11,7 @@ use std::{
 
 use log::error;
 use pin_project::pin_project;
-use socket2::{Domain, Protocol, Socket, TcpKeepalive, Type};
+use socket2::{Domain, Protocol, Socket, Type};
 use tokio::{
     io::{AsyncRead, AsyncWrite, ReadBuf},
     net::{TcpSocket, TcpStream as TokioTcpStream, UdpSocket},
@@ -34,7 +34,7 @@ use winapi::{
 
 use crate::net::{
     is_dual_stack_addr,
-    sys::{set_common_sockopt_for_connect, socket_bind_dual_stack},
+    sys::{set_common_sockopt_for_connect, socket_bind_dual_stack, tcp_keepalive},
     AddrFamily,
     ConnectOpts,
 };
@@ -272,8 +272,8 @@ fn disable_connection_reset(socket: &UdpSocket) -> io::Result<()> {
 
 /// Create a `UdpSocket` binded to `addr`
 ///
-/// It also disables `WSAECONNRESET` for UDP socket
-pub async fn create_inbound_udp_socket(addr: &SocketAddr, ipv6_only: bool) -> io::Result<UdpSocket> {
+/// It also disables `WSAECONNRESET` for UDP socket. `SO_REUSEPORT` is not supported, `reuse_port` is ignored
+pub async fn create_inbound_udp_socket(addr: &SocketAddr, ipv6_only: bool, _reuse_port: bool) -> io::Result<UdpSocket> {
     let set_dual_stack = is_dual_stack_addr(addr);
 
     let socket = if !set_dual_stack {
@@ -330,10 +330,7 @@ pub fn set_common_sockopt_after_connect<S: AsRawSocket>(stream: &S, opts: &Conne
         try_sockopt!(socket.set_nodelay(true));
     }
 
-    if let Some(keepalive_duration) = opts.tcp.keepalive {
-        let keepalive = TcpKeepalive::new()
-            .with_time(keepalive_duration)
-            .with_interval(keepalive_duration);
+    if let Some(keepalive) = tcp_keepalive(&opts.tcp) {
         try_sockopt!(socket.set_tcp_keepalive(&keepalive));
     }
 
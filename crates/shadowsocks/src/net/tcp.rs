@@ -14,30 +14,146 @@ use std::{
 
 use futures::{future, ready};
 use pin_project::pin_project;
-use socket2::{Socket, TcpKeepalive};
+use socket2::Socket;
 use tokio::{
     io::{AsyncRead, AsyncWrite, ReadBuf},
-    net::{TcpListener as TokioTcpListener, TcpSocket, TcpStream as TokioTcpStream},
+    net::{TcpListener as TokioTcpListener, TcpStream as TokioTcpStream},
 };
 
 use crate::{context::Context, relay::socks5::Address, ServerAddr};
 
+#[cfg(feature = "transport-grpc")]
+use super::grpc::{GrpcConfig, GrpcStream};
+#[cfg(unix)]
+use super::handoff::{self, ListenerKind};
+#[cfg(feature = "transport-obfs")]
+use super::obfs::{ObfsConfig, ObfsStream};
+#[cfg(feature = "transport-quic")]
+use super::quic::{QuicConfig, QuicStream};
+#[cfg(feature = "transport-shadow-tls")]
+use super::shadow_tls::{ShadowTlsConfig, ShadowTlsStream};
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+use super::uring::{self, UringTcpStream};
+#[cfg(feature = "transport-websocket")]
+use super::websocket::{WebSocketConfig, WebSocketStream};
 use super::{
     is_dual_stack_addr,
-    sys::{set_tcp_fastopen, socket_bind_dual_stack, TcpStream as SysTcpStream},
+    pacer::Pacer,
+    sys::{create_tcp_socket, set_tcp_fastopen, socket_bind_dual_stack, tcp_keepalive, TcpStream as SysTcpStream},
     AcceptOpts,
     ConnectOpts,
+    UpstreamProxy,
 };
 
+#[pin_project(project = OutboundStreamProj)]
+enum OutboundStream {
+    Tcp(#[pin] SysTcpStream),
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    Uring(#[pin] UringTcpStream),
+    #[cfg(feature = "transport-websocket")]
+    WebSocket(#[pin] WebSocketStream),
+    #[cfg(feature = "transport-obfs")]
+    Obfs(#[pin] ObfsStream<SysTcpStream>),
+    #[cfg(feature = "transport-shadow-tls")]
+    ShadowTls(#[pin] ShadowTlsStream<SysTcpStream>),
+    #[cfg(feature = "transport-quic")]
+    Quic(#[pin] QuicStream),
+    #[cfg(feature = "transport-grpc")]
+    Grpc(#[pin] GrpcStream),
+}
+
+macro_rules! outbound_stream_call {
+    ($self:expr, $name:ident ( $($param:expr),* )) => {
+        match $self {
+            OutboundStream::Tcp(s) => s.$name($($param),*),
+            #[cfg(all(feature = "io-uring", target_os = "linux"))]
+            OutboundStream::Uring(s) => s.$name($($param),*),
+            #[cfg(feature = "transport-websocket")]
+            OutboundStream::WebSocket(s) => s.$name($($param),*),
+            #[cfg(feature = "transport-obfs")]
+            OutboundStream::Obfs(s) => s.$name($($param),*),
+            #[cfg(feature = "transport-shadow-tls")]
+            OutboundStream::ShadowTls(s) => s.$name($($param),*),
+            #[cfg(feature = "transport-quic")]
+            OutboundStream::Quic(s) => s.$name($($param),*),
+            #[cfg(feature = "transport-grpc")]
+            OutboundStream::Grpc(s) => s.$name($($param),*),
+        }
+    };
+}
+
+macro_rules! outbound_stream_poll {
+    ($self:expr, $name:ident ( $($param:expr),* )) => {
+        match $self.project() {
+            OutboundStreamProj::Tcp(s) => s.$name($($param),*),
+            #[cfg(all(feature = "io-uring", target_os = "linux"))]
+            OutboundStreamProj::Uring(s) => s.$name($($param),*),
+            #[cfg(feature = "transport-websocket")]
+            OutboundStreamProj::WebSocket(s) => s.$name($($param),*),
+            #[cfg(feature = "transport-obfs")]
+            OutboundStreamProj::Obfs(s) => s.$name($($param),*),
+            #[cfg(feature = "transport-shadow-tls")]
+            OutboundStreamProj::ShadowTls(s) => s.$name($($param),*),
+            #[cfg(feature = "transport-quic")]
+            OutboundStreamProj::Quic(s) => s.$name($($param),*),
+            #[cfg(feature = "transport-grpc")]
+            OutboundStreamProj::Grpc(s) => s.$name($($param),*),
+        }
+    };
+}
+
+impl AsyncRead for OutboundStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut task::Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        outbound_stream_poll!(self, poll_read(cx, buf))
+    }
+}
+
+impl AsyncWrite for OutboundStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut task::Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        outbound_stream_poll!(self, poll_write(cx, buf))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        outbound_stream_poll!(self, poll_flush(cx))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        outbound_stream_poll!(self, poll_shutdown(cx))
+    }
+}
+
 /// TcpStream for outbound connections
+///
+/// Streams to servers with the WebSocket transport are carried by WebSocket connections, socket APIs apply to the connection's socket.
 #[pin_project]
-pub struct TcpStream(#[pin] SysTcpStream);
+pub struct TcpStream {
+    #[pin]
+    stream: OutboundStream,
+    pacer: Option<Pacer>,
+}
 
 impl TcpStream {
+    fn new(stream: SysTcpStream, opts: &ConnectOpts) -> TcpStream {
+        // Streams of TFO are left to epoll, they are connected by the first write
+        #[cfg(all(feature = "io-uring", target_os = "linux"))]
+        let stream = match stream {
+            SysTcpStream::Standard(s) if uring::is_enabled() => OutboundStream::Uring(UringTcpStream::new(s)),
+            s => OutboundStream::Tcp(s),
+        };
+        #[cfg(not(all(feature = "io-uring", target_os = "linux")))]
+        let stream = OutboundStream::Tcp(stream);
+
+        TcpStream {
+            stream,
+            pacer: Pacer::from_opts(opts),
+        }
+    }
+
     /// Connects to address
     pub async fn connect_with_opts(addr: &SocketAddr, opts: &ConnectOpts) -> io::Result<TcpStream> {
         // tcp_stream_connect(addr, opts).await.map(TcpStream)
-        SysTcpStream::connect(*addr, opts).await.map(TcpStream)
+        let stream = SysTcpStream::connect(*addr, opts).await?;
+        Ok(TcpStream::new(stream, opts))
     }
 
     /// Connects shadowsocks server
@@ -49,14 +165,107 @@ impl TcpStream {
         let stream = match *addr {
             ServerAddr::SocketAddr(ref addr) => SysTcpStream::connect(*addr, opts).await?,
             ServerAddr::DomainName(ref domain, port) => {
-                lookup_then_connect!(context, domain, port, |addr| {
+                let ip_strategy = opts.ip_strategy.unwrap_or_else(|| context.ip_strategy());
+                lookup_then_connect!(context, domain, port, ip_strategy, |addr| {
                     SysTcpStream::connect(addr, opts).await
                 })?
                 .1
             }
         };
 
-        Ok(TcpStream(stream))
+        Ok(TcpStream::new(stream, opts))
+    }
+
+    /// Connects shadowsocks server through the upstream proxy `proxy`, which resolves domain names of `addr`
+    pub async fn connect_server_via_proxy_with_opts(
+        context: &Context,
+        proxy: &UpstreamProxy,
+        addr: &ServerAddr,
+        opts: &ConnectOpts,
+    ) -> io::Result<TcpStream> {
+        let mut stream = TcpStream::connect_server_with_opts(context, proxy.addr(), opts).await?;
+        proxy.handshake(&mut stream, addr).await?;
+        Ok(stream)
+    }
+
+    /// Connects shadowsocks server with the WebSocket transport
+    #[cfg(feature = "transport-websocket")]
+    pub async fn connect_server_websocket_with_opts(
+        context: &Context,
+        addr: &ServerAddr,
+        config: &WebSocketConfig,
+        opts: &ConnectOpts,
+    ) -> io::Result<TcpStream> {
+        let stream = config.connect(context, addr, opts).await?;
+
+        Ok(TcpStream {
+            stream: OutboundStream::WebSocket(stream),
+            pacer: Pacer::from_opts(opts),
+        })
+    }
+
+    /// Connects shadowsocks server with the obfs transport
+    #[cfg(feature = "transport-obfs")]
+    pub async fn connect_server_obfs_with_opts(
+        context: &Context,
+        addr: &ServerAddr,
+        config: &ObfsConfig,
+        opts: &ConnectOpts,
+    ) -> io::Result<TcpStream> {
+        let stream = config.connect(context, addr, opts).await?;
+
+        Ok(TcpStream {
+            stream: OutboundStream::Obfs(stream),
+            pacer: Pacer::from_opts(opts),
+        })
+    }
+
+    /// Connects shadowsocks server with the ShadowTLS transport
+    #[cfg(feature = "transport-shadow-tls")]
+    pub async fn connect_server_shadow_tls_with_opts(
+        context: &Context,
+        addr: &ServerAddr,
+        config: &ShadowTlsConfig,
+        opts: &ConnectOpts,
+    ) -> io::Result<TcpStream> {
+        let stream = config.connect(context, addr, opts).await?;
+
+        Ok(TcpStream {
+            stream: OutboundStream::ShadowTls(stream),
+            pacer: Pacer::from_opts(opts),
+        })
+    }
+
+    /// Connects shadowsocks server with the QUIC transport
+    #[cfg(feature = "transport-quic")]
+    pub async fn connect_server_quic_with_opts(
+        context: &Context,
+        addr: &ServerAddr,
+        config: &QuicConfig,
+        opts: &ConnectOpts,
+    ) -> io::Result<TcpStream> {
+        let stream = config.connect(context, addr, opts).await?;
+
+        Ok(TcpStream {
+            stream: OutboundStream::Quic(stream),
+            pacer: Pacer::from_opts(opts),
+        })
+    }
+
+    /// Connects shadowsocks server with the gRPC transport
+    #[cfg(feature = "transport-grpc")]
+    pub async fn connect_server_grpc_with_opts(
+        context: &Context,
+        addr: &ServerAddr,
+        config: &GrpcConfig,
+        opts: &ConnectOpts,
+    ) -> io::Result<TcpStream> {
+        let stream = config.connect(context, addr, opts).await?;
+
+        Ok(TcpStream {
+            stream: OutboundStream::Grpc(stream),
+            pacer: Pacer::from_opts(opts),
+        })
     }
 
     /// Connects proxy remote target
@@ -68,54 +277,82 @@ impl TcpStream {
         let stream = match *addr {
             Address::SocketAddress(ref addr) => SysTcpStream::connect(*addr, opts).await?,
             Address::DomainNameAddress(ref domain, port) => {
-                lookup_then_connect!(context, domain, port, |addr| {
+                let ip_strategy = opts.ip_strategy.unwrap_or_else(|| context.ip_strategy());
+                lookup_then_connect!(context, domain, port, ip_strategy, |addr| {
                     SysTcpStream::connect(addr, opts).await
                 })?
                 .1
             }
         };
 
-        Ok(TcpStream(stream))
+        Ok(TcpStream::new(stream, opts))
     }
 
     /// Returns the local address that this stream is bound to.
     pub fn local_addr(&self) -> io::Result<SocketAddr> {
-        self.0.local_addr()
+        outbound_stream_call!(&self.stream, local_addr())
     }
 
     /// Returns the remote address that this stream is connected to.
     pub fn peer_addr(&self) -> io::Result<SocketAddr> {
-        self.0.peer_addr()
+        outbound_stream_call!(&self.stream, peer_addr())
     }
 
     /// Gets the value of the `TCP_NODELAY` option on this socket.
     pub fn nodelay(&self) -> io::Result<bool> {
-        self.0.nodelay()
+        outbound_stream_call!(&self.stream, nodelay())
     }
 
     /// Sets the value of the `TCP_NODELAY` option on this socket.
     pub fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
-        self.0.set_nodelay(nodelay)
+        outbound_stream_call!(&self.stream, set_nodelay(nodelay))
+    }
+
+    /// The socket of this stream, if data is sent and received on it as is
+    ///
+    /// Streams carried by transports, paced, connected by TCP Fast Open, or driven by io_uring return `None`, because
+    /// data sent or received on the socket directly would skip them.
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub fn as_plain_socket(&self) -> Option<&TokioTcpStream> {
+        if self.pacer.is_some() {
+            return None;
+        }
+
+        match self.stream {
+            OutboundStream::Tcp(SysTcpStream::Standard(ref s)) => Some(s),
+            _ => None,
+        }
     }
 }
 
 impl AsyncRead for TcpStream {
     fn poll_read(self: Pin<&mut Self>, cx: &mut task::Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
-        self.project().0.poll_read(cx, buf)
+        self.project().stream.poll_read(cx, buf)
     }
 }
 
 impl AsyncWrite for TcpStream {
     fn poll_write(self: Pin<&mut Self>, cx: &mut task::Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
-        self.project().0.poll_write(cx, buf)
+        let this = self.project();
+        match *this.pacer {
+            None => this.stream.poll_write(cx, buf),
+            Some(ref mut pacer) => {
+                ready!(pacer.poll_ready(cx));
+
+                let buf = &buf[..buf.len().min(pacer.quantum())];
+                let n = ready!(this.stream.poll_write(cx, buf))?;
+                pacer.consume(n);
+                Ok(n).into()
+            }
+        }
     }
 
     fn poll_flush(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
-        self.project().0.poll_flush(cx)
+        self.project().stream.poll_flush(cx)
     }
 
     fn poll_shutdown(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
-        self.project().0.poll_shutdown(cx)
+        self.project().stream.poll_shutdown(cx)
     }
 }
 
@@ -123,15 +360,26 @@ impl AsyncWrite for TcpStream {
 pub struct TcpListener {
     inner: TokioTcpListener,
     accept_opts: AcceptOpts,
+    #[cfg(unix)]
+    registration: Option<handoff::BoundRegistration>,
 }
 
 impl TcpListener {
     /// Creates a new TcpListener, which will be bound to the specified address.
     pub async fn bind_with_opts(addr: &SocketAddr, accept_opts: AcceptOpts) -> io::Result<TcpListener> {
-        let socket = match *addr {
-            SocketAddr::V4(..) => TcpSocket::new_v4()?,
-            SocketAddr::V6(..) => TcpSocket::new_v6()?,
-        };
+        #[cfg(unix)]
+        if let Some(socket) = handoff::take_inherited(ListenerKind::Tcp, addr) {
+            socket.set_nonblocking(true)?;
+            let inner = TokioTcpListener::from_std(socket.into())?;
+            let registration = handoff::register_bound(ListenerKind::Tcp, addr, &inner);
+            return Ok(TcpListener {
+                inner,
+                accept_opts,
+                registration,
+            });
+        }
+
+        let socket = create_tcp_socket(addr, accept_opts.tcp.mptcp)?;
 
         // On platforms with Berkeley-derived sockets, this allows to quickly
         // rebind a socket, without needing to wait for the OS to clean up the
@@ -143,6 +391,11 @@ impl TcpListener {
         #[cfg(not(windows))]
         socket.set_reuseaddr(true)?;
 
+        #[cfg(unix)]
+        if accept_opts.reuse_port {
+            socket.set_reuseport(true)?;
+        }
+
         let set_dual_stack = is_dual_stack_addr(addr);
 
         if set_dual_stack {
@@ -160,7 +413,12 @@ impl TcpListener {
             set_tcp_fastopen(&inner)?;
         }
 
-        Ok(TcpListener { inner, accept_opts })
+        Ok(TcpListener {
+            #[cfg(unix)]
+            registration: handoff::register_bound(ListenerKind::Tcp, addr, &inner),
+            inner,
+            accept_opts,
+        })
     }
 
     /// Create a `TcpListener` from tokio's `TcpListener`
@@ -168,6 +426,8 @@ impl TcpListener {
         TcpListener {
             inner: listener,
             accept_opts,
+            #[cfg(unix)]
+            registration: None,
         }
     }
 
@@ -184,7 +444,13 @@ impl TcpListener {
     }
 
     /// Unwraps and take the internal `TcpListener`
+    ///
+    /// The socket is kept recorded for handing off, because the returned listener is still serving.
     pub fn into_inner(self) -> TokioTcpListener {
+        #[cfg(unix)]
+        if let Some(registration) = self.registration {
+            registration.keep();
+        }
         self.inner
     }
 }
@@ -205,12 +471,24 @@ impl DerefMut for TcpListener {
 
 impl From<TcpListener> for TokioTcpListener {
     fn from(listener: TcpListener) -> TokioTcpListener {
-        listener.inner
+        listener.into_inner()
     }
 }
 
 #[cfg(unix)]
 fn setsockopt_with_opt(f: &tokio::net::TcpStream, opts: &AcceptOpts) -> io::Result<()> {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    {
+        use super::sys::{set_tcp_congestion, set_tcp_notsent_lowat};
+
+        if let Some(ref algorithm) = opts.tcp.congestion {
+            set_tcp_congestion(f, algorithm)?;
+        }
+        if let Some(bytes) = opts.tcp.notsent_lowat {
+            set_tcp_notsent_lowat(f, bytes)?;
+        }
+    }
+
     let socket = unsafe { Socket::from_raw_fd(f.as_raw_fd()) };
 
     macro_rules! try_sockopt {
@@ -235,24 +513,15 @@ fn setsockopt_with_opt(f: &tokio::net::TcpStream, opts: &AcceptOpts) -> io::Resu
 
     try_sockopt!(socket.set_nodelay(opts.tcp.nodelay));
 
-    if let Some(keepalive_duration) = opts.tcp.keepalive {
-        #[allow(unused_mut)]
-        let mut keepalive = TcpKeepalive::new().with_time(keepalive_duration);
-
-        #[cfg(any(
-            target_os = "freebsd",
-            target_os = "fuchsia",
-            target_os = "linux",
-            target_os = "netbsd",
-            target_vendor = "apple",
-        ))]
-        {
-            keepalive = keepalive.with_interval(keepalive_duration);
-        }
-
+    if let Some(keepalive) = tcp_keepalive(&opts.tcp) {
         try_sockopt!(socket.set_tcp_keepalive(&keepalive));
     }
 
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    if let Some(timeout) = opts.tcp.user_timeout {
+        try_sockopt!(socket.set_tcp_user_timeout(Some(timeout)));
+    }
+
     let _ = socket.into_raw_fd();
     Ok(())
 }
@@ -283,10 +552,7 @@ fn setsockopt_with_opt(f: &tokio::net::TcpStream, opts: &AcceptOpts) -> io::Resu
 
     try_sockopt!(socket.set_nodelay(opts.tcp.nodelay));
 
-    if let Some(keepalive_duration) = opts.tcp.keepalive {
-        let keepalive = TcpKeepalive::new()
-            .with_time(keepalive_duration)
-            .with_interval(keepalive_duration);
+    if let Some(keepalive) = tcp_keepalive(&opts.tcp) {
         try_sockopt!(socket.set_tcp_keepalive(&keepalive));
     }
 
@@ -303,13 +569,13 @@ fn setsockopt_with_opt(f: &tokio::net::TcpStream, opts: &AcceptOpts) -> io::Resu
 #[cfg(unix)]
 impl AsRawFd for TcpStream {
     fn as_raw_fd(&self) -> RawFd {
-        self.0.as_raw_fd()
+        outbound_stream_call!(&self.stream, as_raw_fd())
     }
 }
 
 #[cfg(windows)]
 impl AsRawSocket for TcpStream {
     fn as_raw_socket(&self) -> RawSocket {
-        self.0.as_raw_socket()
+        outbound_stream_call!(&self.stream, as_raw_socket())
     }
 }
@@ -1,15 +1,34 @@
 //! UDP socket wrappers
+//!
+//! Datagrams could be received and sent in batches by `recv_batch` and `send_batch`, with one `recvmmsg` or `sendmmsg`
+//! call on Linux and Android, saving syscalls of relays with many small packets, like QUIC. On other platforms, they
+//! fallback to receiving datagrams queued in the socket without waiting, and sending datagrams one by one.
+//!
+//! If io_uring is initialized (feature = "io-uring"), readiness of sockets is polled by io_uring instead of epoll, and
+//! datagrams of a batch are sent by `sendmsg` operations submitted at once.
+//!
+//! If UDP offload is initialized by `init_offload` (Linux and Android), consecutive datagrams of a batch to the same
+//! target in the same size are sent as one message segmented by GSO, and sockets enabled by `enable_gro` receive
+//! datagrams coalesced by GRO, which are split by `recv_batch`.
 
 use std::{
     io,
     net::SocketAddr,
     ops::{Deref, DerefMut},
+    sync::atomic::{AtomicBool, Ordering},
 };
 
+use bytes::BytesMut;
 use pin_project::pin_project;
 
-use crate::{context::Context, relay::socks5::Address, ServerAddr};
+use crate::{
+    context::Context,
+    relay::{buffer_pool::BufferPool, socks5::Address},
+    ServerAddr,
+};
 
+#[cfg(unix)]
+use super::handoff::{self, ListenerKind};
 use super::{
     sys::{create_inbound_udp_socket, create_outbound_udp_socket},
     AcceptOpts,
@@ -19,7 +38,12 @@ use super::{
 
 /// Wrappers for outbound `UdpSocket`
 #[pin_project]
-pub struct UdpSocket(#[pin] tokio::net::UdpSocket);
+pub struct UdpSocket {
+    #[pin]
+    inner: tokio::net::UdpSocket,
+    #[cfg(unix)]
+    registration: Option<handoff::BoundRegistration>,
+}
 
 impl UdpSocket {
     /// Connects to shadowsocks server
@@ -30,20 +54,20 @@ impl UdpSocket {
     ) -> io::Result<UdpSocket> {
         let socket = match *addr {
             ServerAddr::SocketAddr(ref remote_addr) => {
-                let socket = create_outbound_udp_socket(From::from(remote_addr), opts).await?;
+                let socket = create_protected_udp_socket(From::from(remote_addr), opts).await?;
                 socket.connect(remote_addr).await?;
                 socket
             }
             ServerAddr::DomainName(ref dname, port) => {
                 lookup_then!(context, dname, port, |remote_addr| {
-                    let s = create_outbound_udp_socket(From::from(&remote_addr), opts).await?;
+                    let s = create_protected_udp_socket(From::from(&remote_addr), opts).await?;
                     s.connect(remote_addr).await.map(|_| s)
                 })?
                 .1
             }
         };
 
-        Ok(UdpSocket(socket))
+        Ok(UdpSocket::from(socket))
     }
 
     /// Connects to proxy target
@@ -54,27 +78,27 @@ impl UdpSocket {
     ) -> io::Result<UdpSocket> {
         let socket = match *addr {
             Address::SocketAddress(ref remote_addr) => {
-                let socket = create_outbound_udp_socket(From::from(remote_addr), opts).await?;
+                let socket = create_protected_udp_socket(From::from(remote_addr), opts).await?;
                 socket.connect(remote_addr).await?;
                 socket
             }
             Address::DomainNameAddress(ref dname, port) => {
                 lookup_then!(context, dname, port, |remote_addr| {
-                    let s = create_outbound_udp_socket(From::from(&remote_addr), opts).await?;
+                    let s = create_protected_udp_socket(From::from(&remote_addr), opts).await?;
                     s.connect(remote_addr).await.map(|_| s)
                 })?
                 .1
             }
         };
 
-        Ok(UdpSocket(socket))
+        Ok(UdpSocket::from(socket))
     }
 
     /// Connects to shadowsocks server
     pub async fn connect_with_opts(addr: &SocketAddr, opts: &ConnectOpts) -> io::Result<UdpSocket> {
-        let socket = create_outbound_udp_socket(From::from(addr), opts).await?;
+        let socket = create_protected_udp_socket(From::from(addr), opts).await?;
         socket.connect(addr).await?;
-        Ok(UdpSocket(socket))
+        Ok(UdpSocket::from(socket))
     }
 
     /// Binds to a specific address (inbound)
@@ -85,38 +109,440 @@ impl UdpSocket {
 
     /// Binds to a specific address (inbound)
     pub async fn listen_with_opts(addr: &SocketAddr, opts: AcceptOpts) -> io::Result<UdpSocket> {
-        let socket = create_inbound_udp_socket(addr, opts.ipv6_only).await?;
-        Ok(UdpSocket(socket))
+        #[cfg(unix)]
+        if let Some(socket) = handoff::take_inherited(ListenerKind::Udp, addr) {
+            socket.set_nonblocking(true)?;
+            let socket = tokio::net::UdpSocket::from_std(socket.into())?;
+            return Ok(UdpSocket {
+                registration: handoff::register_bound(ListenerKind::Udp, addr, &socket),
+                inner: socket,
+            });
+        }
+
+        let socket = create_inbound_udp_socket(addr, opts.ipv6_only, opts.reuse_port).await?;
+
+        Ok(UdpSocket {
+            #[cfg(unix)]
+            registration: handoff::register_bound(ListenerKind::Udp, addr, &socket),
+            inner: socket,
+        })
     }
 
     /// Binds to a specific address with opts
     pub async fn connect_any_with_opts<AF: Into<AddrFamily>>(af: AF, opts: &ConnectOpts) -> io::Result<UdpSocket> {
-        create_outbound_udp_socket(af.into(), opts).await.map(UdpSocket)
+        create_protected_udp_socket(af.into(), opts).await.map(UdpSocket::from)
+    }
+}
+
+/// Create an outbound socket, protected by `ConnectOpts::socket_protector` before it sends anything
+async fn create_protected_udp_socket(af: AddrFamily, opts: &ConnectOpts) -> io::Result<tokio::net::UdpSocket> {
+    let socket = create_outbound_udp_socket(af, opts).await?;
+
+    #[cfg(unix)]
+    if let Some(ref protector) = opts.socket_protector {
+        use std::os::unix::io::AsRawFd;
+        protector.protect(socket.as_raw_fd())?;
     }
+
+    Ok(socket)
 }
 
 impl Deref for UdpSocket {
     type Target = tokio::net::UdpSocket;
 
     fn deref(&self) -> &Self::Target {
-        &self.0
+        &self.inner
     }
 }
 
 impl DerefMut for UdpSocket {
     fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.0
+        &mut self.inner
     }
 }
 
 impl From<tokio::net::UdpSocket> for UdpSocket {
     fn from(s: tokio::net::UdpSocket) -> Self {
-        UdpSocket(s)
+        UdpSocket {
+            inner: s,
+            #[cfg(unix)]
+            registration: None,
+        }
     }
 }
 
 impl From<UdpSocket> for tokio::net::UdpSocket {
     fn from(s: UdpSocket) -> tokio::net::UdpSocket {
-        s.0
+        // Kept recorded for handing off, the socket is still serving
+        #[cfg(unix)]
+        if let Some(registration) = s.registration {
+            registration.keep();
+        }
+        s.inner
+    }
+}
+
+/// Maximum number of datagrams received by `recv_batch` or sent by `send_batch` in one call
+pub const UDP_BATCH_SIZE: usize = 16;
+
+static OFFLOAD_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Enable UDP GSO for `send_batch` and GRO for `enable_gro`, fails if they are not supported by the kernel
+pub fn init_offload() -> io::Result<()> {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    {
+        super::sys::check_udp_offload()?;
+        OFFLOAD_ENABLED.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "UDP offload is only supported on Linux and Android",
+    ))
+}
+
+/// Check if UDP offload has been enabled by `init_offload`
+pub fn is_offload_enabled() -> bool {
+    OFFLOAD_ENABLED.load(Ordering::Relaxed)
+}
+
+/// Receive datagrams coalesced by GRO with `socket`, if UDP offload has been enabled by `init_offload`
+///
+/// The socket has to be received only by `recv_batch`, coalesced datagrams would be taken as one by other methods.
+pub fn enable_gro(socket: &tokio::net::UdpSocket) -> io::Result<()> {
+    if !is_offload_enabled() {
+        return Ok(());
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    super::sys::set_udp_gro(socket, true)?;
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    let _ = socket;
+
+    Ok(())
+}
+
+/// Datagrams received in a batch by `recv_batch`
+pub struct RecvBatch {
+    bufs: Vec<Box<[u8]>>,
+    // Lengths, source addresses and segment sizes (`0` if not coalesced by GRO) of messages received into `bufs`
+    messages: Vec<(usize, SocketAddr, usize)>,
+    // Indexes of `bufs`, ranges and source addresses of datagrams split from `messages`
+    packets: Vec<(usize, usize, usize, SocketAddr)>,
+    batch_size: usize,
+    // Buffers are taken from it only while receiving, if it is created by `with_pool`
+    pool: Option<&'static BufferPool>,
+}
+
+impl RecvBatch {
+    /// Create with `batch_size` (at most `UDP_BATCH_SIZE`) buffers of `buf_size` bytes
+    ///
+    /// Datagrams larger than `buf_size` are truncated, so it should be large enough for the whole datagram, like
+    /// `MAXIMUM_UDP_PAYLOAD_SIZE`.
+    pub fn new(batch_size: usize, buf_size: usize) -> RecvBatch {
+        let batch_size = batch_size.clamp(1, UDP_BATCH_SIZE);
+        RecvBatch {
+            bufs: (0..batch_size)
+                .map(|_| vec![0u8; buf_size].into_boxed_slice())
+                .collect(),
+            messages: Vec::with_capacity(batch_size),
+            packets: Vec::with_capacity(batch_size),
+            batch_size,
+            pool: None,
+        }
+    }
+
+    /// Create with `batch_size` (at most `UDP_BATCH_SIZE`) buffers taken from `pool`
+    ///
+    /// Buffers are put back to `pool` while `recv_batch` is waiting for datagrams, so batches of idle sockets don't
+    /// hold any.
+    pub fn with_pool(batch_size: usize, pool: &'static BufferPool) -> RecvBatch {
+        let batch_size = batch_size.clamp(1, UDP_BATCH_SIZE);
+        RecvBatch {
+            bufs: Vec::with_capacity(batch_size),
+            messages: Vec::with_capacity(batch_size),
+            packets: Vec::with_capacity(batch_size),
+            batch_size,
+            pool: Some(pool),
+        }
+    }
+
+    /// Number of datagrams received
+    pub fn len(&self) -> usize {
+        self.packets.len()
+    }
+
+    /// Check if no datagram is received
+    pub fn is_empty(&self) -> bool {
+        self.packets.is_empty()
+    }
+
+    /// The `i`th datagram received, and its source address
+    pub fn get(&self, i: usize) -> (&[u8], SocketAddr) {
+        let (index, start, end, addr) = self.packets[i];
+        (&self.bufs[index][start..end], addr)
+    }
+
+    /// The `i`th datagram received, which could be decrypted in place, and its source address
+    pub fn get_mut(&mut self, i: usize) -> (&mut [u8], SocketAddr) {
+        let (index, start, end, addr) = self.packets[i];
+        (&mut self.bufs[index][start..end], addr)
+    }
+
+    /// Total length of datagrams received
+    pub fn total_len(&self) -> usize {
+        self.packets.iter().map(|(_, start, end, _)| end - start).sum()
+    }
+
+    fn clear(&mut self) {
+        self.messages.clear();
+        self.packets.clear();
+    }
+
+    /// Split messages coalesced by GRO into datagrams
+    fn split_messages(&mut self) -> usize {
+        for (index, &(n, addr, segment_size)) in self.messages.iter().enumerate() {
+            if segment_size == 0 || segment_size >= n {
+                self.packets.push((index, 0, n, addr));
+                continue;
+            }
+
+            let mut start = 0;
+            while start < n {
+                let end = (start + segment_size).min(n);
+                self.packets.push((index, start, end, addr));
+                start = end;
+            }
+        }
+        self.packets.len()
+    }
+
+    fn acquire(&mut self) {
+        if let Some(pool) = self.pool {
+            while self.bufs.len() < self.batch_size {
+                self.bufs.push(pool.acquire());
+            }
+        }
     }
+
+    fn release(&mut self) {
+        if let Some(pool) = self.pool {
+            for buf in self.bufs.drain(..) {
+                pool.release(buf);
+            }
+        }
+    }
+}
+
+impl Drop for RecvBatch {
+    fn drop(&mut self) {
+        self.release();
+    }
+}
+
+/// Datagrams sent in a batch by `send_batch`
+#[derive(Default)]
+pub struct SendBatch {
+    bufs: Vec<BytesMut>,
+    targets: Vec<Option<SocketAddr>>,
+    len: usize,
+}
+
+impl SendBatch {
+    /// Create an empty batch
+    pub fn new() -> SendBatch {
+        SendBatch::default()
+    }
+
+    /// Push a datagram to `target` (`None` for the connected address of the socket), returns the buffer for writing it
+    ///
+    /// Buffers are reused after the batch is sent.
+    pub fn push(&mut self, target: Option<SocketAddr>) -> &mut BytesMut {
+        if self.len == self.bufs.len() {
+            self.bufs.push(BytesMut::new());
+            self.targets.push(None);
+        }
+
+        let i = self.len;
+        self.len += 1;
+        self.targets[i] = target;
+
+        let buf = &mut self.bufs[i];
+        buf.clear();
+        buf
+    }
+
+    /// Remove the last datagram pushed, if it fails to be written
+    pub fn pop(&mut self) {
+        self.len = self.len.saturating_sub(1);
+    }
+
+    /// Number of datagrams to be sent
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Check if there is no datagram to be sent
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Check if there are `UDP_BATCH_SIZE` datagrams, which should be sent before pushing more
+    pub fn is_full(&self) -> bool {
+        self.len >= UDP_BATCH_SIZE
+    }
+
+    /// Length of the `i`th datagram
+    pub fn packet_len(&self, i: usize) -> usize {
+        self.bufs[i].len()
+    }
+
+    /// Remove all datagrams, if they are not going to be sent
+    pub fn clear(&mut self) {
+        self.len = 0;
+    }
+}
+
+/// Receive datagrams into `batch`, waiting until at least one of them is received
+///
+/// Returns the number of datagrams received.
+pub async fn recv_batch(socket: &tokio::net::UdpSocket, batch: &mut RecvBatch) -> io::Result<usize> {
+    batch.clear();
+
+    loop {
+        batch.acquire();
+        match try_recv_batch(socket, batch) {
+            Ok(..) => return Ok(batch.split_messages()),
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {}
+            Err(err) => return Err(err),
+        }
+
+        // Buffers are not held while waiting
+        batch.release();
+
+        #[cfg(all(feature = "io-uring", target_os = "linux"))]
+        if super::uring::is_enabled() {
+            super::uring::readable(socket).await?;
+            continue;
+        }
+
+        socket.readable().await?;
+    }
+}
+
+/// Receive messages queued in the socket into `batch` without waiting
+fn try_recv_batch(socket: &tokio::net::UdpSocket, batch: &mut RecvBatch) -> io::Result<usize> {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    {
+        use tokio::io::Interest;
+
+        let RecvBatch {
+            ref mut bufs,
+            ref mut messages,
+            ..
+        } = *batch;
+
+        // Readiness is polled by io_uring, which is not tracked by tokio
+        #[cfg(all(feature = "io-uring", target_os = "linux"))]
+        if super::uring::is_enabled() {
+            return super::sys::recv_mmsg(socket, bufs, messages);
+        }
+
+        socket.try_io(Interest::READABLE, || super::sys::recv_mmsg(socket, bufs, messages))
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    {
+        while batch.messages.len() < batch.bufs.len() {
+            let i = batch.messages.len();
+            match socket.try_recv_from(&mut batch.bufs[i]) {
+                Ok((n, addr)) => batch.messages.push((n, addr, 0)),
+                // Errors are returned by the next call
+                Err(err) if batch.messages.is_empty() => return Err(err),
+                Err(..) => break,
+            }
+        }
+
+        Ok(batch.messages.len())
+    }
+}
+
+/// Send all datagrams in `batch`, and clear it
+///
+/// Datagrams failed to be sent are skipped, and the first error is returned after the others are sent. Returns the
+/// total length of datagrams sent.
+pub async fn send_batch(socket: &tokio::net::UdpSocket, batch: &mut SendBatch) -> io::Result<usize> {
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    if super::uring::is_enabled() {
+        let bufs = batch.bufs[..batch.len].iter().map(|b| &b[..]).collect::<Vec<_>>();
+        let result = super::uring::send_batch(socket, &bufs, &batch.targets[..batch.len]).await;
+        batch.clear();
+        return result;
+    }
+
+    let mut sent = 0;
+    let mut sent_len = 0;
+    let mut first_err = None;
+
+    while sent < batch.len {
+        let bufs = &batch.bufs[sent..batch.len];
+        let targets = &batch.targets[sent..batch.len];
+
+        match send_batch_once(socket, bufs, targets).await {
+            Ok(n) => {
+                sent_len += bufs[..n].iter().map(|b| b.len()).sum::<usize>();
+                sent += n;
+            }
+            Err(err) => {
+                // Skip the datagram that failed
+                first_err.get_or_insert(err);
+                sent += 1;
+            }
+        }
+    }
+
+    batch.clear();
+
+    match first_err {
+        None => Ok(sent_len),
+        Some(err) => Err(err),
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+async fn send_batch_once(
+    socket: &tokio::net::UdpSocket,
+    bufs: &[BytesMut],
+    targets: &[Option<SocketAddr>],
+) -> io::Result<usize> {
+    use tokio::io::Interest;
+
+    let bufs = bufs.iter().take(UDP_BATCH_SIZE).map(|b| &b[..]).collect::<Vec<_>>();
+    let mut gso = is_offload_enabled();
+    loop {
+        socket.writable().await?;
+        match socket.try_io(Interest::WRITABLE, || {
+            super::sys::send_mmsg(socket, &bufs, targets, gso)
+        }) {
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => continue,
+            // Segments larger than MTU of the route are rejected, send them without GSO
+            Err(..) if gso => gso = false,
+            result => return result,
+        }
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+async fn send_batch_once(
+    socket: &tokio::net::UdpSocket,
+    bufs: &[BytesMut],
+    targets: &[Option<SocketAddr>],
+) -> io::Result<usize> {
+    match targets[0] {
+        Some(target) => socket.send_to(&bufs[0], target).await?,
+        None => socket.send(&bufs[0]).await?,
+    };
+    Ok(1)
 }
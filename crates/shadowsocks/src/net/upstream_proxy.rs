@@ -0,0 +1,282 @@
+//! Connecting to servers through an upstream proxy
+//!
+//! Clients behind proxies (like corporate networks) couldn't connect to servers directly. Connections to servers
+//! are tunneled through a SOCKS5 (RFC 1928, with RFC 1929 authentication) or HTTP `CONNECT` proxy, which resolves
+//! domain names of servers by itself.
+//!
+//! Only TCP connections are tunneled. Servers are connected through the proxy before any data of shadowsocks is
+//! sent, so the proxy could be another shadowsocks client's local server for building multi-hop chains.
+
+use std::{
+    fmt::{self, Display},
+    io::{self, ErrorKind},
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+};
+
+use log::trace;
+use percent_encoding::{percent_decode_str, utf8_percent_encode, NON_ALPHANUMERIC};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use url::{Host, Url};
+
+use crate::{
+    config::ServerAddr,
+    relay::socks5::{
+        self,
+        Command,
+        HandshakeRequest,
+        HandshakeResponse,
+        PasswdAuthRequest,
+        PasswdAuthResponse,
+        Reply,
+        TcpRequestHeader,
+        TcpResponseHeader,
+        SOCKS5_AUTH_METHOD_NONE,
+        SOCKS5_AUTH_METHOD_PASSWORD,
+    },
+};
+
+/// Maximum length of HTTP response headers of `CONNECT`
+const MAX_HTTP_RESPONSE_SIZE: usize = 8192;
+
+/// Protocol of the upstream proxy
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UpstreamProxyProtocol {
+    /// SOCKS5, with `CONNECT` command
+    Socks5,
+    /// HTTP `CONNECT` method
+    Http,
+}
+
+impl UpstreamProxyProtocol {
+    fn scheme(self) -> &'static str {
+        match self {
+            UpstreamProxyProtocol::Socks5 => "socks5",
+            UpstreamProxyProtocol::Http => "http",
+        }
+    }
+}
+
+/// Error while parsing `UpstreamProxy` from URL
+#[derive(Debug, Clone, Copy)]
+pub struct UpstreamProxyError;
+
+impl Display for UpstreamProxyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("invalid upstream proxy, expecting \"socks5://[USER:PASS@]HOST:PORT\" or \"http://...\" URL")
+    }
+}
+
+/// Upstream proxy of connections to a server
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UpstreamProxy {
+    protocol: UpstreamProxyProtocol,
+    addr: ServerAddr,
+    auth: Option<(String, String)>,
+}
+
+impl UpstreamProxy {
+    /// Creates an upstream proxy listening on `addr`
+    pub fn new<A>(protocol: UpstreamProxyProtocol, addr: A) -> UpstreamProxy
+    where
+        A: Into<ServerAddr>,
+    {
+        UpstreamProxy {
+            protocol,
+            addr: addr.into(),
+            auth: None,
+        }
+    }
+
+    /// Protocol of the proxy
+    pub fn protocol(&self) -> UpstreamProxyProtocol {
+        self.protocol
+    }
+
+    /// Address of the proxy
+    pub fn addr(&self) -> &ServerAddr {
+        &self.addr
+    }
+
+    /// Username and password of the proxy
+    pub fn auth(&self) -> Option<(&str, &str)> {
+        self.auth.as_ref().map(|(u, p)| (u.as_str(), p.as_str()))
+    }
+
+    /// Authenticate with `username` and `password`, which are limited to 255 bytes for SOCKS5
+    pub fn set_auth<U, P>(&mut self, username: U, password: P)
+    where
+        U: Into<String>,
+        P: Into<String>,
+    {
+        self.auth = Some((username.into(), password.into()));
+    }
+
+    /// Tunnel to the server `addr` through the proxy connected by `stream`
+    pub async fn handshake<S>(&self, stream: &mut S, addr: &ServerAddr) -> io::Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        trace!("connecting {} through upstream proxy {}", addr, self.addr);
+
+        match self.protocol {
+            UpstreamProxyProtocol::Socks5 => self.handshake_socks5(stream, addr).await,
+            UpstreamProxyProtocol::Http => self.handshake_http(stream, addr).await,
+        }
+    }
+
+    async fn handshake_socks5<S>(&self, stream: &mut S, addr: &ServerAddr) -> io::Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let method = match self.auth {
+            Some(..) => SOCKS5_AUTH_METHOD_PASSWORD,
+            None => SOCKS5_AUTH_METHOD_NONE,
+        };
+        HandshakeRequest::new(vec![method]).write_to(stream).await?;
+        let response = HandshakeResponse::read_from(stream).await?;
+        if response.chosen_method != method {
+            let err = io::Error::new(
+                ErrorKind::Other,
+                format!(
+                    "upstream proxy {} chose authentication method {:#x}, expecting {:#x}",
+                    self.addr, response.chosen_method, method
+                ),
+            );
+            return Err(err);
+        }
+
+        if let Some((ref username, ref password)) = self.auth {
+            if username.is_empty() || username.len() > 255 || password.is_empty() || password.len() > 255 {
+                let err = io::Error::new(
+                    ErrorKind::InvalidInput,
+                    format!(
+                        "upstream proxy {} username and password must be 1 to 255 bytes",
+                        self.addr
+                    ),
+                );
+                return Err(err);
+            }
+            PasswdAuthRequest::new(username.as_str(), password.as_str())
+                .write_to(stream)
+                .await?;
+            let response = PasswdAuthResponse::read_from(stream).await?;
+            if response.status != 0 {
+                let err = io::Error::new(
+                    ErrorKind::PermissionDenied,
+                    format!("upstream proxy {} authentication failed", self.addr),
+                );
+                return Err(err);
+            }
+        }
+
+        TcpRequestHeader::new(Command::TcpConnect, addr.into())
+            .write_to(stream)
+            .await?;
+        let response = TcpResponseHeader::read_from(stream).await?;
+        match response.reply {
+            Reply::Succeeded => Ok(()),
+            reply => Err(socks5::Error::Reply(reply).into()),
+        }
+    }
+
+    async fn handshake_http<S>(&self, stream: &mut S, addr: &ServerAddr) -> io::Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let mut request = format!("CONNECT {addr} HTTP/1.1\r\nHost: {addr}\r\n");
+        if let Some((ref username, ref password)) = self.auth {
+            let credentials = base64::encode(format!("{}:{}", username, password));
+            request.push_str(&format!("Proxy-Authorization: Basic {}\r\n", credentials));
+        }
+        request.push_str("\r\n");
+        stream.write_all(request.as_bytes()).await?;
+
+        // Read byte by byte, data of the tunnel shouldn't be consumed
+        let mut response = Vec::new();
+        while !response.ends_with(b"\r\n\r\n") {
+            if response.len() >= MAX_HTTP_RESPONSE_SIZE {
+                let err = io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("upstream proxy {} response too large", self.addr),
+                );
+                return Err(err);
+            }
+            response.push(stream.read_u8().await?);
+        }
+
+        // Status line: HTTP/1.1 200 Connection established
+        let status_line = response.split(|b| *b == b'\r').next().unwrap_or_default();
+        let status_line = String::from_utf8_lossy(status_line);
+        let mut parts = status_line.split_whitespace();
+        match (parts.next(), parts.next()) {
+            (Some(version), Some(status)) if version.starts_with("HTTP/") && status.starts_with('2') => Ok(()),
+            _ => {
+                let err = io::Error::new(
+                    ErrorKind::Other,
+                    format!("upstream proxy {} refused CONNECT: {}", self.addr, status_line),
+                );
+                Err(err)
+            }
+        }
+    }
+}
+
+impl Display for UpstreamProxy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}://", self.protocol.scheme())?;
+        if let Some((ref username, ref password)) = self.auth {
+            write!(
+                f,
+                "{}:{}@",
+                utf8_percent_encode(username, NON_ALPHANUMERIC),
+                utf8_percent_encode(password, NON_ALPHANUMERIC)
+            )?;
+        }
+        write!(f, "{}", self.addr)
+    }
+}
+
+impl FromStr for UpstreamProxy {
+    type Err = UpstreamProxyError;
+
+    fn from_str(s: &str) -> Result<UpstreamProxy, UpstreamProxyError> {
+        let url = Url::parse(s).map_err(|_| UpstreamProxyError)?;
+        let protocol = match url.scheme() {
+            "socks5" | "socks5h" => UpstreamProxyProtocol::Socks5,
+            "http" => UpstreamProxyProtocol::Http,
+            _ => return Err(UpstreamProxyError),
+        };
+        if !matches!(url.path(), "" | "/") || url.query().is_some() || url.fragment().is_some() {
+            return Err(UpstreamProxyError);
+        }
+
+        let port = url.port_or_known_default().ok_or(UpstreamProxyError)?;
+        let addr = match url.host() {
+            Some(Host::Domain(domain)) => ServerAddr::DomainName(domain.to_owned(), port),
+            Some(Host::Ipv4(ip)) => ServerAddr::SocketAddr(SocketAddr::new(IpAddr::V4(ip), port)),
+            Some(Host::Ipv6(ip)) => ServerAddr::SocketAddr(SocketAddr::new(IpAddr::V6(ip), port)),
+            None => return Err(UpstreamProxyError),
+        };
+
+        let mut proxy = UpstreamProxy::new(protocol, addr);
+        if !url.username().is_empty() {
+            let decode = |s: &str| {
+                percent_decode_str(s)
+                    .decode_utf8()
+                    .map(|s| s.into_owned())
+                    .map_err(|_| UpstreamProxyError)
+            };
+            let username = decode(url.username())?;
+            let password = decode(url.password().unwrap_or_default())?;
+            if protocol == UpstreamProxyProtocol::Socks5
+                && (username.len() > 255 || password.is_empty() || password.len() > 255)
+            {
+                return Err(UpstreamProxyError);
+            }
+            proxy.set_auth(username, password);
+        }
+
+        Ok(proxy)
+    }
+}
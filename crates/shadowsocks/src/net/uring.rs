@@ -0,0 +1,684 @@
+//! io_uring backend of TCP streams and UDP sockets (Linux only)
+//!
+//! Sockets are still created by tokio, but their reads and writes are submitted to an io_uring shared by the whole
+//! process, instead of waiting for readiness from epoll and calling `recv` and `send`. Operations submitted by all
+//! connections while the runtime is busy are flushed by one `io_uring_enter`, and their completions are reaped from the
+//! shared completion queue without syscalls.
+//!
+//! It is disabled until `init` is called, then `UringTcpStream` and `recv_batch`, `send_batch` of `net::udp` are
+//! served by the ring.
+
+use std::{
+    io::{self, ErrorKind},
+    mem,
+    net::{Shutdown, SocketAddr},
+    os::unix::io::{AsRawFd, RawFd},
+    pin::Pin,
+    ptr,
+    sync::{Mutex, MutexGuard},
+    task::{Context, Poll, Waker},
+};
+
+use futures::{future, ready};
+use io_uring::{opcode, squeue, types, IoUring, Probe};
+use log::{debug, error};
+use once_cell::sync::OnceCell;
+use slab::Slab;
+use socket2::{SockAddr, SockRef};
+use tokio::{
+    io::{unix::AsyncFd, AsyncRead, AsyncWrite, ReadBuf},
+    net::TcpStream as TokioTcpStream,
+    sync::Notify,
+};
+
+/// Default number of entries of the submission queue
+pub const DEFAULT_ENTRIES: u32 = 4096;
+
+/// `user_data` of operations that nobody waits for, like cancellations
+const IGNORED_USER_DATA: u64 = u64::MAX;
+
+/// Size of buffers receiving from TCP streams, if the caller's buffer is smaller
+const READ_BUFFER_SIZE: usize = 16 * 1024;
+/// Maximum size of one operation of TCP streams
+const MAX_BUFFER_SIZE: usize = 64 * 1024;
+
+static DRIVER: OnceCell<Driver> = OnceCell::new();
+
+/// Initialize the io_uring shared by all sockets, with `entries` entries of the submission queue
+///
+/// Completions are reaped by a task spawned in the current tokio runtime, which has to live as long as the sockets.
+/// Calling it again after it is initialized does nothing.
+pub fn init(entries: u32) -> io::Result<()> {
+    if DRIVER.get().is_some() {
+        return Ok(());
+    }
+
+    let uring = IoUring::builder()
+        .setup_cqsize(entries.saturating_mul(4))
+        .build(entries)?;
+
+    let mut probe = Probe::new();
+    uring.submitter().register_probe(&mut probe)?;
+    for (code, name) in [
+        (opcode::Recv::CODE, "recv"),
+        (opcode::Send::CODE, "send"),
+        (opcode::PollAdd::CODE, "poll"),
+        (opcode::SendMsg::CODE, "sendmsg"),
+        (opcode::AsyncCancel::CODE, "cancel"),
+    ] {
+        if !probe.is_supported(code) {
+            return Err(io::Error::new(
+                ErrorKind::Unsupported,
+                format!("io_uring of this kernel doesn't support {}", name),
+            ));
+        }
+    }
+
+    // Kernel signals the eventfd when completions are posted, waking the reaper by epoll
+    let eventfd = EventFd::new()?;
+    uring.submitter().register_eventfd(eventfd.0)?;
+    let eventfd = AsyncFd::new(eventfd)?;
+
+    let driver = Driver {
+        ring: Mutex::new(Ring {
+            uring,
+            ops: Slab::new(),
+            generation: 0,
+        }),
+        notify: Notify::new(),
+    };
+    if DRIVER.set(driver).is_err() {
+        // Initialized by another thread concurrently
+        return Ok(());
+    }
+
+    let driver = DRIVER.get().expect("io_uring driver");
+    tokio::spawn(driver.run(eventfd));
+
+    debug!("io_uring initialized with {} entries", entries);
+
+    Ok(())
+}
+
+/// Check if sockets are served by io_uring
+pub fn is_enabled() -> bool {
+    DRIVER.get().is_some()
+}
+
+fn driver() -> io::Result<&'static Driver> {
+    DRIVER
+        .get()
+        .ok_or_else(|| io::Error::new(ErrorKind::Other, "io_uring is not initialized"))
+}
+
+struct EventFd(RawFd);
+
+impl EventFd {
+    fn new() -> io::Result<EventFd> {
+        let fd = unsafe { libc::eventfd(0, libc::EFD_NONBLOCK | libc::EFD_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(EventFd(fd))
+    }
+
+    /// Reset the counter, until there is nothing to read
+    fn drain(&self) -> io::Result<()> {
+        let mut counter = 0u64;
+        loop {
+            let ret = unsafe { libc::read(self.0, &mut counter as *mut u64 as *mut libc::c_void, 8) };
+            if ret < 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+    }
+}
+
+impl AsRawFd for EventFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0
+    }
+}
+
+impl Drop for EventFd {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.0);
+        }
+    }
+}
+
+enum Lifecycle {
+    /// Submitted, the waker is woken when it is completed
+    Submitted(Option<Waker>),
+    /// Completed with the result of the syscall
+    Completed(i32),
+    /// Its `Op` was dropped, memory is freed when it is completed
+    Ignored,
+}
+
+/// Memory referenced by the kernel until the operation is completed
+enum OpData {
+    Empty,
+    Buffer(Vec<u8>),
+    Message(Box<Message>),
+}
+
+impl OpData {
+    fn into_buffer(self) -> Vec<u8> {
+        match self {
+            OpData::Empty => Vec::new(),
+            OpData::Buffer(buf) => buf,
+            OpData::Message(msg) => msg.buf,
+        }
+    }
+}
+
+/// `msghdr` of `sendmsg`, pointing to its own fields
+struct Message {
+    hdr: libc::msghdr,
+    iov: libc::iovec,
+    name: libc::sockaddr_storage,
+    buf: Vec<u8>,
+}
+
+// Pointers of `hdr` and `iov` only point to the message itself, which is boxed
+unsafe impl Send for Message {}
+
+impl Message {
+    /// Message sending `buf` to `target` (`None` for the connected address)
+    fn send(buf: Vec<u8>, target: Option<SocketAddr>) -> Box<Message> {
+        let mut msg = Box::new(Message {
+            hdr: unsafe { mem::zeroed() },
+            iov: unsafe { mem::zeroed() },
+            name: unsafe { mem::zeroed() },
+            buf,
+        });
+        msg.iov.iov_base = msg.buf.as_mut_ptr() as *mut libc::c_void;
+        msg.iov.iov_len = msg.buf.len();
+        msg.hdr.msg_iov = &mut msg.iov;
+        msg.hdr.msg_iovlen = 1;
+        if let Some(target) = target {
+            let addr = SockAddr::from(target);
+            unsafe {
+                ptr::copy_nonoverlapping(
+                    addr.as_ptr() as *const u8,
+                    &mut msg.name as *mut _ as *mut u8,
+                    addr.len() as usize,
+                );
+            }
+            msg.hdr.msg_name = &mut msg.name as *mut _ as *mut libc::c_void;
+            msg.hdr.msg_namelen = addr.len();
+        }
+        msg
+    }
+}
+
+struct Operation {
+    /// Key in the slab, and a generation telling it from the others reusing the key
+    user_data: u64,
+    lifecycle: Lifecycle,
+    data: OpData,
+}
+
+struct Ring {
+    uring: IoUring,
+    ops: Slab<Operation>,
+    generation: u32,
+}
+
+impl Ring {
+    /// Push to the submission queue, which is submitted now if it is full
+    fn push(&mut self, sqe: &squeue::Entry, wakers: &mut Vec<Waker>) -> io::Result<()> {
+        loop {
+            if unsafe { self.uring.submission().push(sqe) }.is_ok() {
+                return Ok(());
+            }
+            self.submit(wakers)?;
+        }
+    }
+
+    fn submit(&mut self, wakers: &mut Vec<Waker>) -> io::Result<()> {
+        match self.uring.submit() {
+            Ok(..) => Ok(()),
+            Err(ref err) if err.raw_os_error() == Some(libc::EBUSY) => {
+                // Completion queue is overflowed, reap them and try again
+                self.reap(wakers);
+                self.uring.submit().map(|_| ())
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    fn reap(&mut self, wakers: &mut Vec<Waker>) {
+        let Ring {
+            ref mut uring,
+            ref mut ops,
+            ..
+        } = *self;
+        for cqe in uring.completion() {
+            if cqe.user_data() == IGNORED_USER_DATA {
+                continue;
+            }
+            let key = (cqe.user_data() & 0xffff_ffff) as usize;
+            let op = match ops.get_mut(key) {
+                Some(op) if op.user_data == cqe.user_data() => op,
+                _ => continue,
+            };
+            match mem::replace(&mut op.lifecycle, Lifecycle::Completed(cqe.result())) {
+                Lifecycle::Submitted(waker) => wakers.extend(waker),
+                Lifecycle::Ignored => {
+                    ops.remove(key);
+                }
+                Lifecycle::Completed(..) => {}
+            }
+        }
+    }
+}
+
+struct Driver {
+    ring: Mutex<Ring>,
+    /// Notified when there are operations to be submitted
+    notify: Notify,
+}
+
+impl Driver {
+    fn lock(&self) -> MutexGuard<'_, Ring> {
+        self.ring.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    /// Submit an operation built with the memory of `data`, which is kept until it is completed
+    ///
+    /// It is submitted to the kernel with the others by the reaper later.
+    fn submit<F>(&'static self, mut data: OpData, build: F) -> io::Result<Op>
+    where
+        F: FnOnce(&mut OpData) -> squeue::Entry,
+    {
+        let mut wakers = Vec::new();
+        let mut ring = self.lock();
+
+        ring.generation = ring.generation.wrapping_add(1);
+        let generation = ring.generation;
+        let entry = ring.ops.vacant_entry();
+        let key = entry.key();
+        let user_data = (u64::from(generation) << 32) | key as u64;
+        // Pointers to the heap are still valid after `data` is moved into the slab
+        let sqe = build(&mut data).user_data(user_data);
+        entry.insert(Operation {
+            user_data,
+            lifecycle: Lifecycle::Submitted(None),
+            data,
+        });
+
+        if let Err(err) = ring.push(&sqe, &mut wakers) {
+            ring.ops.remove(key);
+            return Err(err);
+        }
+        drop(ring);
+
+        wakers.into_iter().for_each(Waker::wake);
+        self.notify.notify_one();
+
+        Ok(Op {
+            driver: self,
+            key,
+            user_data,
+            completed: false,
+        })
+    }
+
+    /// Submit pending operations, and wake the completed ones
+    fn flush(&self, wakers: &mut Vec<Waker>) {
+        let mut ring = self.lock();
+        if !ring.uring.submission().is_empty() {
+            if let Err(err) = ring.submit(wakers) {
+                error!("io_uring submit failed with error: {}", err);
+                // Try again later
+                self.notify.notify_one();
+            }
+        }
+        ring.reap(wakers);
+    }
+
+    async fn run(&'static self, eventfd: AsyncFd<EventFd>) {
+        let mut wakers = Vec::new();
+        loop {
+            tokio::select! {
+                _ = self.notify.notified() => {}
+                r = eventfd.readable() => match r {
+                    Ok(mut guard) => {
+                        let _ = guard.try_io(|fd| fd.get_ref().drain());
+                    }
+                    Err(err) => {
+                        error!("io_uring eventfd failed with error: {}, completions are not reaped", err);
+                        return;
+                    }
+                },
+            }
+
+            self.flush(&mut wakers);
+            wakers.drain(..).for_each(Waker::wake);
+        }
+    }
+}
+
+/// A submitted operation, cancelled if it is dropped before it is completed
+struct Op {
+    driver: &'static Driver,
+    key: usize,
+    user_data: u64,
+    completed: bool,
+}
+
+impl Op {
+    /// Poll for the result of the syscall (`-errno` if it failed), and the memory of the operation
+    fn poll(&mut self, cx: &mut Context<'_>) -> Poll<(i32, OpData)> {
+        let mut wakers = Vec::new();
+        let mut ring = self.driver.lock();
+        if let Lifecycle::Submitted(..) = ring.ops[self.key].lifecycle {
+            // Completions may be posted but not reaped yet, which doesn't need a syscall
+            ring.reap(&mut wakers);
+        }
+
+        let op = &mut ring.ops[self.key];
+        let result = match op.lifecycle {
+            Lifecycle::Completed(res) => {
+                let op = ring.ops.remove(self.key);
+                self.completed = true;
+                Poll::Ready((res, op.data))
+            }
+            Lifecycle::Submitted(ref mut waker) => {
+                match *waker {
+                    Some(ref w) if w.will_wake(cx.waker()) => {}
+                    _ => *waker = Some(cx.waker().clone()),
+                }
+                Poll::Pending
+            }
+            Lifecycle::Ignored => unreachable!("polling a dropped io_uring operation"),
+        };
+        drop(ring);
+
+        wakers.into_iter().for_each(Waker::wake);
+        result
+    }
+
+    async fn result(mut self) -> (i32, OpData) {
+        future::poll_fn(|cx| self.poll(cx)).await
+    }
+}
+
+impl Drop for Op {
+    fn drop(&mut self) {
+        if self.completed {
+            return;
+        }
+
+        let mut wakers = Vec::new();
+        let mut ring = self.driver.lock();
+        if let Lifecycle::Completed(..) = ring.ops[self.key].lifecycle {
+            ring.ops.remove(self.key);
+            return;
+        }
+
+        ring.ops[self.key].lifecycle = Lifecycle::Ignored;
+        let sqe = opcode::AsyncCancel::new(self.user_data)
+            .build()
+            .user_data(IGNORED_USER_DATA);
+        if let Err(err) = ring.push(&sqe, &mut wakers) {
+            error!("io_uring cancel failed with error: {}", err);
+        }
+        drop(ring);
+
+        wakers.into_iter().for_each(Waker::wake);
+        self.driver.notify.notify_one();
+    }
+}
+
+fn result_to_io(res: i32) -> io::Result<usize> {
+    if res < 0 {
+        Err(io::Error::from_raw_os_error(-res))
+    } else {
+        Ok(res as usize)
+    }
+}
+
+/// TCP stream, receiving and sending with io_uring
+///
+/// Writes are buffered, data is copied and submitted before `poll_write` returns. Errors of sending are returned by
+/// the next `poll_write`, `poll_flush` or `poll_shutdown`.
+pub struct UringTcpStream {
+    read_op: Option<Op>,
+    /// Data received but not read, `read_buf[read_pos..]`
+    read_buf: Vec<u8>,
+    read_pos: usize,
+    write_op: Option<Op>,
+    /// Position in the buffer of `write_op` that has been sent
+    write_pos: usize,
+    write_buf: Vec<u8>,
+    // Dropped after operations are cancelled
+    stream: TokioTcpStream,
+}
+
+impl UringTcpStream {
+    /// Serve `stream` with io_uring, which has to be initialized by `init`
+    pub fn new(stream: TokioTcpStream) -> UringTcpStream {
+        UringTcpStream {
+            read_op: None,
+            read_buf: Vec::new(),
+            read_pos: 0,
+            write_op: None,
+            write_pos: 0,
+            write_buf: Vec::new(),
+            stream,
+        }
+    }
+
+    /// Returns the local address that this stream is bound to.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.stream.local_addr()
+    }
+
+    /// Returns the remote address that this stream is connected to.
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.stream.peer_addr()
+    }
+
+    /// Gets the value of the `TCP_NODELAY` option on this socket.
+    pub fn nodelay(&self) -> io::Result<bool> {
+        self.stream.nodelay()
+    }
+
+    /// Sets the value of the `TCP_NODELAY` option on this socket.
+    pub fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+        self.stream.set_nodelay(nodelay)
+    }
+
+    /// Gets a reference to the underlying stream
+    pub fn get_ref(&self) -> &TokioTcpStream {
+        &self.stream
+    }
+
+    fn submit_recv(&mut self, size: usize) -> io::Result<()> {
+        let mut buf = mem::take(&mut self.read_buf);
+        buf.clear();
+        buf.reserve(size.clamp(READ_BUFFER_SIZE, MAX_BUFFER_SIZE));
+
+        let fd = types::Fd(self.stream.as_raw_fd());
+        let op = driver()?.submit(OpData::Buffer(buf), |data| match *data {
+            OpData::Buffer(ref mut buf) => opcode::Recv::new(fd, buf.as_mut_ptr(), buf.capacity() as u32).build(),
+            _ => unreachable!(),
+        })?;
+        self.read_op = Some(op);
+        Ok(())
+    }
+
+    fn submit_send(&mut self, buf: Vec<u8>) -> io::Result<()> {
+        let fd = types::Fd(self.stream.as_raw_fd());
+        let pos = self.write_pos;
+        let op = driver()?.submit(OpData::Buffer(buf), |data| match *data {
+            OpData::Buffer(ref buf) => opcode::Send::new(fd, buf[pos..].as_ptr(), (buf.len() - pos) as u32)
+                .flags(libc::MSG_NOSIGNAL)
+                .build(),
+            _ => unreachable!(),
+        })?;
+        self.write_op = Some(op);
+        Ok(())
+    }
+
+    /// Wait until data buffered by `poll_write` is sent
+    fn poll_write_op(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while let Some(ref mut op) = self.write_op {
+            let (res, data) = ready!(op.poll(cx));
+            self.write_op = None;
+
+            let buf = data.into_buffer();
+            match result_to_io(res) {
+                Ok(n) => {
+                    self.write_pos += n;
+                    if n > 0 && self.write_pos < buf.len() {
+                        self.submit_send(buf)?;
+                    } else if self.write_pos < buf.len() {
+                        self.write_buf = buf;
+                        return Err(io::Error::new(ErrorKind::WriteZero, "write zero byte into socket")).into();
+                    } else {
+                        self.write_buf = buf;
+                    }
+                }
+                Err(err) => {
+                    self.write_buf = buf;
+                    return Err(err).into();
+                }
+            }
+        }
+        Ok(()).into()
+    }
+}
+
+impl AsyncRead for UringTcpStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if buf.remaining() == 0 {
+            return Ok(()).into();
+        }
+
+        loop {
+            if this.read_pos < this.read_buf.len() {
+                let n = buf.remaining().min(this.read_buf.len() - this.read_pos);
+                buf.put_slice(&this.read_buf[this.read_pos..this.read_pos + n]);
+                this.read_pos += n;
+                return Ok(()).into();
+            }
+
+            match this.read_op {
+                None => this.submit_recv(buf.remaining())?,
+                Some(ref mut op) => {
+                    let (res, data) = ready!(op.poll(cx));
+                    this.read_op = None;
+
+                    let mut read_buf = data.into_buffer();
+                    let result = result_to_io(res);
+                    if let Ok(n) = result {
+                        // Filled by the kernel
+                        unsafe { read_buf.set_len(n) };
+                    }
+                    this.read_buf = read_buf;
+                    this.read_pos = 0;
+
+                    match result {
+                        // EOF
+                        Ok(0) => return Ok(()).into(),
+                        Ok(..) => {}
+                        Err(err) => return Err(err).into(),
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl AsyncWrite for UringTcpStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_op(cx))?;
+
+        if buf.is_empty() {
+            return Ok(0).into();
+        }
+
+        let n = buf.len().min(MAX_BUFFER_SIZE);
+        let mut write_buf = mem::take(&mut this.write_buf);
+        write_buf.clear();
+        write_buf.extend_from_slice(&buf[..n]);
+        this.write_pos = 0;
+        this.submit_send(write_buf)?;
+
+        Ok(n).into()
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.get_mut().poll_write_op(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_op(cx))?;
+        SockRef::from(&this.stream).shutdown(Shutdown::Write).into()
+    }
+}
+
+impl AsRawFd for UringTcpStream {
+    fn as_raw_fd(&self) -> RawFd {
+        self.stream.as_raw_fd()
+    }
+}
+
+/// Wait until `socket` is readable, polled by io_uring
+///
+/// Unlike receiving by io_uring, nothing is lost if it is cancelled.
+pub(crate) async fn readable<S: AsRawFd>(socket: &S) -> io::Result<()> {
+    let fd = types::Fd(socket.as_raw_fd());
+    let op = driver()?.submit(OpData::Empty, |_| opcode::PollAdd::new(fd, libc::POLLIN as u32).build())?;
+    result_to_io(op.result().await.0).map(|_| ())
+}
+
+/// Send datagrams in `bufs` to `targets` (`None` for the connected address) with io_uring, all submitted at once
+///
+/// Datagrams failed to be sent are skipped, and the first error is returned after the others are sent. Returns the
+/// total length of datagrams sent.
+pub(crate) async fn send_batch<S: AsRawFd>(
+    socket: &S,
+    bufs: &[&[u8]],
+    targets: &[Option<SocketAddr>],
+) -> io::Result<usize> {
+    let fd = types::Fd(socket.as_raw_fd());
+    let driver = driver()?;
+
+    let mut ops = Vec::with_capacity(bufs.len());
+    for (buf, target) in bufs.iter().zip(targets) {
+        let msg = Message::send(buf.to_vec(), *target);
+        ops.push(driver.submit(OpData::Message(msg), |data| match *data {
+            OpData::Message(ref msg) => opcode::SendMsg::new(fd, &msg.hdr).build(),
+            _ => unreachable!(),
+        }));
+    }
+
+    let mut sent_len = 0;
+    let mut first_err = None;
+    for op in ops {
+        let result = match op {
+            Ok(op) => result_to_io(op.result().await.0),
+            Err(err) => Err(err),
+        };
+        match result {
+            Ok(n) => sent_len += n,
+            Err(err) => {
+                first_err.get_or_insert(err);
+            }
+        }
+    }
+
+    match first_err {
+        None => Ok(sent_len),
+        Some(err) => Err(err),
+    }
+}
@@ -0,0 +1,262 @@
+//! Configuration of the WebSocket transport, read from v2ray-plugin's options
+
+use std::{
+    env,
+    fmt::{self, Debug},
+    fs::File,
+    io::{self, BufReader, Cursor, ErrorKind},
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
+
+use log::warn;
+use once_cell::sync::OnceCell;
+use tokio_rustls::{
+    rustls::{Certificate, ClientConfig, OwnedTrustAnchor, PrivateKey, RootCertStore, ServerConfig},
+    TlsAcceptor,
+    TlsConnector,
+};
+
+use crate::{
+    plugin::{split_plugin_opts, PluginConfig},
+    relay::mux::MuxPool,
+};
+
+use super::stream::SocketInfo;
+
+/// Name of the plugin whose options are handled by the WebSocket transport
+pub const V2RAY_PLUGIN: &str = "v2ray-plugin";
+
+/// Host used by v2ray-plugin if `host` is not set
+const DEFAULT_HOST: &str = "cloudfront.com";
+/// Client connections without Mux.Cool sessions for this long are closed, the same as v2ray
+const MUX_IDLE_TIMEOUT: Duration = Duration::from_secs(16);
+
+/// WebSocket transport configuration, compatible with [v2ray-plugin](https://github.com/shadowsocks/v2ray-plugin)
+///
+/// Options are the same as v2ray-plugin's `plugin_opts`, like `tls;host=example.com;path=/ws;mux=4`
+///
+/// - `path`: request path of the WebSocket, `/` by default
+/// - `host`: `Host` header and TLS server name, `cloudfront.com` by default
+/// - `tls`: carries the WebSocket in TLS
+/// - `cert` / `key`: certificate and private key of the server, `~/.acme.sh/{host}/fullchain.cer` and `~/.acme.sh/{host}/{host}.key` by default.
+///   Clients trust `cert` besides the well-known CAs.
+/// - `certRaw`: certificate in base64, instead of `cert`
+/// - `mux`: sessions opened concurrently in one connection with Mux.Cool by clients, `1` by default, `0` disables it
+#[derive(Clone)]
+pub struct WebSocketConfig {
+    path: String,
+    host: String,
+    tls: bool,
+    mux: usize,
+    cert: Option<PathBuf>,
+    key: Option<PathBuf>,
+    cert_raw: Option<String>,
+    mux_pool: Option<Arc<MuxPool<SocketInfo>>>,
+    tls_connector: Arc<OnceCell<TlsConnector>>,
+}
+
+impl Debug for WebSocketConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WebSocketConfig")
+            .field("path", &self.path)
+            .field("host", &self.host)
+            .field("tls", &self.tls)
+            .field("mux", &self.mux)
+            .field("cert", &self.cert)
+            .field("key", &self.key)
+            .finish()
+    }
+}
+
+impl WebSocketConfig {
+    /// Parses v2ray-plugin's options
+    pub fn from_plugin_opts(opts: Option<&str>) -> Result<WebSocketConfig, String> {
+        let mut config = WebSocketConfig {
+            path: "/".to_owned(),
+            host: DEFAULT_HOST.to_owned(),
+            tls: false,
+            mux: 1,
+            cert: None,
+            key: None,
+            cert_raw: None,
+            mux_pool: None,
+            tls_connector: Arc::new(OnceCell::new()),
+        };
+
+        for (key, value) in split_plugin_opts(opts.unwrap_or("")) {
+            match (key.as_str(), value) {
+                ("tls", None) => config.tls = true,
+                ("server", None) | ("fastOpen", None) | ("loglevel", Some(..)) => {}
+                ("mode", Some(mode)) if mode == "websocket" => {}
+                ("path", Some(path)) => {
+                    config.path = if path.starts_with('/') {
+                        path
+                    } else {
+                        format!("/{}", path)
+                    };
+                }
+                ("host", Some(host)) if !host.is_empty() => config.host = host,
+                ("cert", Some(cert)) => config.cert = Some(PathBuf::from(cert)),
+                ("key", Some(key)) => config.key = Some(PathBuf::from(key)),
+                ("certRaw", Some(cert)) => config.cert_raw = Some(cert),
+                ("mux", Some(mux)) => match mux.parse::<usize>() {
+                    Ok(mux) => config.mux = mux,
+                    Err(..) => return Err(format!("invalid mux \"{}\"", mux)),
+                },
+                (key, Some(value)) => return Err(format!("unsupported option \"{}={}\"", key, value)),
+                (key, None) => return Err(format!("unsupported option \"{}\"", key)),
+            }
+        }
+
+        if config.mux > 0 {
+            config.mux_pool = Some(Arc::new(MuxPool::new(config.mux, MUX_IDLE_TIMEOUT)));
+        }
+
+        Ok(config)
+    }
+
+    /// Creates the configuration if `plugin` is v2ray-plugin and its options are supported
+    ///
+    /// Plugins given by path, with arguments, or relaying UDP (SIP003u) are always started as external programs.
+    pub fn from_plugin(plugin: &PluginConfig) -> Option<WebSocketConfig> {
+        if plugin.plugin != V2RAY_PLUGIN || !plugin.plugin_args.is_empty() || plugin.plugin_mode.enable_udp() {
+            return None;
+        }
+
+        match WebSocketConfig::from_plugin_opts(plugin.plugin_opts.as_deref()) {
+            Ok(c) => Some(c),
+            Err(err) => {
+                warn!("{} is started as a plugin program, {}", V2RAY_PLUGIN, err);
+                None
+            }
+        }
+    }
+
+    /// Request path of the WebSocket
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// `Host` header and TLS server name
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+
+    /// Carries the WebSocket in TLS
+    pub fn tls(&self) -> bool {
+        self.tls
+    }
+
+    /// Sessions opened concurrently in one connection with Mux.Cool, `0` if disabled
+    pub fn mux(&self) -> usize {
+        self.mux
+    }
+
+    pub(crate) fn mux_pool(&self) -> Option<&MuxPool<SocketInfo>> {
+        self.mux_pool.as_deref()
+    }
+
+    fn load_certificates(&self) -> io::Result<Vec<Certificate>> {
+        let certs = match (&self.cert, &self.cert_raw) {
+            (Some(path), _) => {
+                let mut reader = BufReader::new(File::open(path)?);
+                rustls_pemfile::certs(&mut reader)?
+            }
+            (None, Some(raw)) => {
+                let pem = format!("-----BEGIN CERTIFICATE-----\n{}\n-----END CERTIFICATE-----\n", raw);
+                rustls_pemfile::certs(&mut Cursor::new(pem))?
+            }
+            (None, None) => {
+                let path = self.acme_path("fullchain.cer")?;
+                let mut reader = BufReader::new(File::open(path)?);
+                rustls_pemfile::certs(&mut reader)?
+            }
+        };
+
+        if certs.is_empty() {
+            return Err(io::Error::new(ErrorKind::InvalidData, "no certificate found"));
+        }
+
+        Ok(certs.into_iter().map(Certificate).collect())
+    }
+
+    fn load_private_key(&self) -> io::Result<PrivateKey> {
+        use rustls_pemfile::Item;
+
+        let path = match self.key {
+            Some(ref path) => path.clone(),
+            None => self.acme_path(&format!("{}.key", self.host))?,
+        };
+        let mut reader = BufReader::new(File::open(&path)?);
+
+        // Takes the first private key in the file
+        while let Some(item) = rustls_pemfile::read_one(&mut reader)? {
+            match item {
+                Item::RSAKey(key) | Item::PKCS8Key(key) | Item::ECKey(key) => return Ok(PrivateKey(key)),
+                _ => continue,
+            }
+        }
+
+        Err(io::Error::new(
+            ErrorKind::InvalidData,
+            format!("no private key found in {}", path.display()),
+        ))
+    }
+
+    /// Certificates issued by acme.sh, where v2ray-plugin looks for them by default
+    fn acme_path(&self, file: &str) -> io::Result<PathBuf> {
+        match env::var_os("HOME") {
+            Some(home) => Ok(PathBuf::from(home).join(".acme.sh").join(&self.host).join(file)),
+            None => Err(io::Error::new(
+                ErrorKind::NotFound,
+                "certificate path is not set, and $HOME for the default is unknown",
+            )),
+        }
+    }
+
+    /// TLS connector for clients, trusting `cert` besides the well-known CAs
+    pub(crate) fn tls_connector(&self) -> io::Result<&TlsConnector> {
+        self.tls_connector.get_or_try_init(|| {
+            let mut store = RootCertStore::empty();
+            store.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|root| {
+                OwnedTrustAnchor::from_subject_spki_name_constraints(root.subject, root.spki, root.name_constraints)
+            }));
+
+            if self.cert.is_some() || self.cert_raw.is_some() {
+                for cert in self.load_certificates()? {
+                    if let Err(err) = store.add(&cert) {
+                        return Err(io::Error::new(ErrorKind::InvalidData, format!("invalid cert, {}", err)));
+                    }
+                }
+            }
+
+            let mut config = ClientConfig::builder()
+                .with_safe_defaults()
+                .with_root_certificates(store)
+                .with_no_client_auth();
+            config.alpn_protocols = vec![b"http/1.1".to_vec()];
+
+            Ok(TlsConnector::from(Arc::new(config)))
+        })
+    }
+
+    /// TLS acceptor for servers, with `cert` and `key`
+    pub(crate) fn tls_acceptor(&self) -> io::Result<TlsAcceptor> {
+        let certs = self.load_certificates()?;
+        let key = self.load_private_key()?;
+
+        let mut config = match ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(certs, key)
+        {
+            Ok(c) => c,
+            Err(err) => return Err(io::Error::new(ErrorKind::InvalidInput, err)),
+        };
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+
+        Ok(TlsAcceptor::from(Arc::new(config)))
+    }
+}
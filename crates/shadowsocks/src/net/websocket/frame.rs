@@ -0,0 +1,303 @@
+//! WebSocket framing (RFC 6455) for binary streams
+//!
+//! Every write is sent as one binary frame. Text and continuation frames are read as data,
+//! pings are answered with pongs and a close frame ends the stream.
+
+use std::{
+    io::{self, ErrorKind},
+    pin::Pin,
+    task::{self, Poll},
+};
+
+use bytes::{Buf, BufMut, BytesMut};
+use futures::ready;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::net::framed::{self, FramedWrite};
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xA;
+
+/// Maximum payload of frames sent by us
+const MAX_FRAME_PAYLOAD: usize = 16 * 1024;
+/// Bytes read from the underlying stream at once
+const READ_CHUNK_SIZE: usize = 16 * 1024;
+/// Maximum payload of control frames, RFC 6455 5.5
+const MAX_CONTROL_PAYLOAD: usize = 125;
+
+/// Which end of the WebSocket connection we are
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    /// Client masks every frame it sends
+    Client,
+    /// Server never masks frames
+    Server,
+}
+
+struct FrameHeader {
+    opcode: u8,
+    mask: Option<[u8; 4]>,
+    remaining: u64,
+    offset: usize,
+}
+
+/// A binary stream carried in WebSocket frames
+pub struct WebSocketFramed<S> {
+    stream: S,
+    role: Role,
+    read_buf: BytesMut,
+    read_frame: Option<FrameHeader>,
+    read_eof: bool,
+    write_buf: BytesMut,
+    close_sent: bool,
+}
+
+impl<S> WebSocketFramed<S> {
+    /// Creates a framed stream on an upgraded connection
+    ///
+    /// `buffered` are bytes already read from `stream` after the handshake
+    pub fn new(stream: S, role: Role, buffered: &[u8]) -> WebSocketFramed<S> {
+        let mut read_buf = BytesMut::with_capacity(READ_CHUNK_SIZE.max(buffered.len()));
+        read_buf.put_slice(buffered);
+
+        WebSocketFramed {
+            stream,
+            role,
+            read_buf,
+            read_frame: None,
+            read_eof: false,
+            write_buf: BytesMut::new(),
+            close_sent: false,
+        }
+    }
+
+    fn encode_frame(&mut self, opcode: u8, payload: &[u8]) {
+        self.write_buf.reserve(14 + payload.len());
+        self.write_buf.put_u8(0x80 | opcode);
+
+        let mask_bit = if self.role == Role::Client { 0x80 } else { 0x00 };
+        if payload.len() < 126 {
+            self.write_buf.put_u8(mask_bit | payload.len() as u8);
+        } else if payload.len() <= u16::MAX as usize {
+            self.write_buf.put_u8(mask_bit | 126);
+            self.write_buf.put_u16(payload.len() as u16);
+        } else {
+            self.write_buf.put_u8(mask_bit | 127);
+            self.write_buf.put_u64(payload.len() as u64);
+        }
+
+        match self.role {
+            Role::Server => self.write_buf.put_slice(payload),
+            Role::Client => {
+                let mask = rand::random::<[u8; 4]>();
+                self.write_buf.put_slice(&mask);
+                self.write_buf
+                    .extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+            }
+        }
+    }
+
+    /// Tries to parse a frame header from `read_buf`
+    fn parse_header(&mut self) -> io::Result<Option<FrameHeader>> {
+        let buf = &self.read_buf[..];
+        if buf.len() < 2 {
+            return Ok(None);
+        }
+
+        let opcode = buf[0] & 0x0F;
+        let masked = buf[1] & 0x80 != 0;
+        let (length, mut header_len) = match buf[1] & 0x7F {
+            126 if buf.len() >= 4 => (u16::from_be_bytes([buf[2], buf[3]]) as u64, 4),
+            127 if buf.len() >= 10 => {
+                let mut n = [0u8; 8];
+                n.copy_from_slice(&buf[2..10]);
+                (u64::from_be_bytes(n), 10)
+            }
+            126 | 127 => return Ok(None),
+            n => (n as u64, 2),
+        };
+
+        let mask = if masked {
+            if buf.len() < header_len + 4 {
+                return Ok(None);
+            }
+            let mut mask = [0u8; 4];
+            mask.copy_from_slice(&buf[header_len..header_len + 4]);
+            header_len += 4;
+            Some(mask)
+        } else {
+            None
+        };
+
+        // Clients must mask their frames, servers must not
+        if masked != (self.role == Role::Server) {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "websocket frame with unexpected masking",
+            ));
+        }
+
+        if opcode >= OPCODE_CLOSE && length > MAX_CONTROL_PAYLOAD as u64 {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "websocket control frame too large",
+            ));
+        }
+
+        self.read_buf.advance(header_len);
+
+        Ok(Some(FrameHeader {
+            opcode,
+            mask,
+            remaining: length,
+            offset: 0,
+        }))
+    }
+
+    fn unmask(frame: &mut FrameHeader, data: &mut [u8]) {
+        if let Some(mask) = frame.mask {
+            for b in data.iter_mut() {
+                *b ^= mask[frame.offset % 4];
+                frame.offset += 1;
+            }
+        }
+    }
+}
+
+impl<S> FramedWrite for WebSocketFramed<S>
+where
+    S: AsyncWrite + Unpin,
+{
+    fn poll_write_encoded(&mut self, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        framed::poll_write_buffered(&mut self.stream, &mut self.write_buf, cx)
+    }
+
+    fn encode(&mut self, data: &[u8]) -> usize {
+        let n = data.len().min(MAX_FRAME_PAYLOAD);
+        self.encode_frame(OPCODE_BINARY, &data[..n]);
+        n
+    }
+}
+
+impl<S> WebSocketFramed<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    /// Handles a complete control frame whose payload is at the front of `read_buf`
+    fn handle_control(&mut self, cx: &mut task::Context<'_>, mut frame: FrameHeader) -> io::Result<()> {
+        let mut payload = self.read_buf.split_to(frame.remaining as usize);
+        Self::unmask(&mut frame, &mut payload);
+
+        match frame.opcode {
+            OPCODE_PING => {
+                self.encode_frame(OPCODE_PONG, &payload);
+                // Best effort, the rest is sent with the next write or flush
+                let _ = self.poll_write_encoded(cx)?;
+            }
+            OPCODE_CLOSE => {
+                self.read_eof = true;
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+}
+
+impl<S> AsyncRead for WebSocketFramed<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_read(self: Pin<&mut Self>, cx: &mut task::Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        loop {
+            if this.read_eof {
+                return Ok(()).into();
+            }
+
+            match this.read_frame.take() {
+                None => {
+                    if let Some(frame) = this.parse_header()? {
+                        this.read_frame = Some(frame);
+                        continue;
+                    }
+                }
+                Some(frame) if frame.opcode >= OPCODE_CLOSE => {
+                    if this.read_buf.len() as u64 >= frame.remaining {
+                        this.handle_control(cx, frame)?;
+                        continue;
+                    }
+                    this.read_frame = Some(frame);
+                }
+                Some(mut frame) => {
+                    if !matches!(frame.opcode, OPCODE_CONTINUATION | OPCODE_TEXT | OPCODE_BINARY) {
+                        return Err(io::Error::new(
+                            ErrorKind::InvalidData,
+                            format!("websocket frame with unknown opcode {:#x}", frame.opcode),
+                        ))
+                        .into();
+                    }
+
+                    if frame.remaining == 0 {
+                        continue;
+                    }
+
+                    if !this.read_buf.is_empty() {
+                        let n = (frame.remaining as usize).min(this.read_buf.len()).min(buf.remaining());
+                        let mut data = this.read_buf.split_to(n);
+                        Self::unmask(&mut frame, &mut data);
+                        buf.put_slice(&data);
+
+                        frame.remaining -= n as u64;
+                        if frame.remaining > 0 {
+                            this.read_frame = Some(frame);
+                        }
+                        return Ok(()).into();
+                    }
+                    this.read_frame = Some(frame);
+                }
+            }
+
+            if !ready!(framed::poll_fill(&mut this.stream, &mut this.read_buf, cx))? {
+                if this.read_frame.is_some() || !this.read_buf.is_empty() {
+                    return Err(ErrorKind::UnexpectedEof.into()).into();
+                }
+                this.read_eof = true;
+            }
+        }
+    }
+}
+
+impl<S> AsyncWrite for WebSocketFramed<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_write(self: Pin<&mut Self>, cx: &mut task::Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        self.get_mut().poll_write_framed(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_encoded(cx))?;
+        Pin::new(&mut this.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        if !this.close_sent {
+            ready!(this.poll_write_encoded(cx))?;
+            // Status code 1000, normal closure
+            this.encode_frame(OPCODE_CLOSE, &1000u16.to_be_bytes());
+            this.close_sent = true;
+        }
+
+        ready!(this.poll_write_encoded(cx))?;
+        Pin::new(&mut this.stream).poll_shutdown(cx)
+    }
+}
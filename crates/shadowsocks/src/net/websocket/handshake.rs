@@ -0,0 +1,187 @@
+//! WebSocket opening handshake (RFC 6455 section 4)
+
+use std::io::{self, ErrorKind};
+
+use bytes::BytesMut;
+use log::trace;
+use sha1::{Digest, Sha1};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use super::frame::{Role, WebSocketFramed};
+
+const WEBSOCKET_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+const MAX_HEADER_SIZE: usize = 8192;
+const MAX_HEADERS: usize = 64;
+
+fn accept_key(key: &str) -> String {
+    let mut hasher = Sha1::new();
+    hasher.update(key.as_bytes());
+    hasher.update(WEBSOCKET_GUID.as_bytes());
+    base64::encode(hasher.finalize())
+}
+
+/// Reads a HTTP header until `\r\n\r\n`, returns the header length and all bytes read
+async fn read_header<S, F>(stream: &mut S, mut parse: F) -> io::Result<(usize, BytesMut)>
+where
+    S: AsyncRead + Unpin,
+    F: FnMut(&[u8]) -> io::Result<Option<usize>>,
+{
+    let mut buf = BytesMut::with_capacity(1024);
+
+    loop {
+        if stream.read_buf(&mut buf).await? == 0 {
+            return Err(io::Error::new(
+                ErrorKind::UnexpectedEof,
+                "websocket handshake terminated unexpectedly",
+            ));
+        }
+
+        if let Some(n) = parse(&buf)? {
+            return Ok((n, buf));
+        }
+
+        if buf.len() > MAX_HEADER_SIZE {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "websocket handshake header too large",
+            ));
+        }
+    }
+}
+
+fn header_value<'a>(headers: &[httparse::Header<'a>], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|h| h.name.eq_ignore_ascii_case(name))
+        .and_then(|h| std::str::from_utf8(h.value).ok())
+        .map(str::trim)
+}
+
+fn header_contains(headers: &[httparse::Header<'_>], name: &str, token: &str) -> bool {
+    header_value(headers, name)
+        .map(|v| v.split(',').any(|t| t.trim().eq_ignore_ascii_case(token)))
+        .unwrap_or(false)
+}
+
+fn parse_error(err: httparse::Error) -> io::Error {
+    io::Error::new(
+        ErrorKind::InvalidData,
+        format!("websocket handshake malformed, {}", err),
+    )
+}
+
+/// Sends the opening handshake as a client and waits for the server's response
+pub async fn client_handshake<S>(mut stream: S, host: &str, path: &str) -> io::Result<WebSocketFramed<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let key = base64::encode(rand::random::<[u8; 16]>());
+    let request = format!(
+        "GET {} HTTP/1.1\r\n\
+         Host: {}\r\n\
+         User-Agent: Go-http-client/1.1\r\n\
+         Upgrade: websocket\r\n\
+         Connection: Upgrade\r\n\
+         Sec-WebSocket-Key: {}\r\n\
+         Sec-WebSocket-Version: 13\r\n\r\n",
+        path, host, key
+    );
+    stream.write_all(request.as_bytes()).await?;
+
+    let expected_accept = accept_key(&key);
+    let (n, buf) = read_header(&mut stream, |buf| {
+        let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+        let mut response = httparse::Response::new(&mut headers);
+        let n = match response.parse(buf).map_err(parse_error)? {
+            httparse::Status::Complete(n) => n,
+            httparse::Status::Partial => return Ok(None),
+        };
+
+        if response.code != Some(101) {
+            return Err(io::Error::new(
+                ErrorKind::ConnectionRefused,
+                format!(
+                    "websocket handshake rejected with status {} {}",
+                    response.code.unwrap_or(0),
+                    response.reason.unwrap_or("")
+                ),
+            ));
+        }
+
+        if header_value(response.headers, "Sec-WebSocket-Accept") != Some(expected_accept.as_str()) {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "websocket handshake with invalid Sec-WebSocket-Accept",
+            ));
+        }
+
+        Ok(Some(n))
+    })
+    .await?;
+
+    trace!("websocket connected to {}{}", host, path);
+
+    Ok(WebSocketFramed::new(stream, Role::Client, &buf[n..]))
+}
+
+/// Waits for a client's opening handshake and accepts it if the request matches `path`
+pub async fn server_handshake<S>(mut stream: S, path: &str) -> io::Result<WebSocketFramed<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut response = Err("400 Bad Request");
+    let (n, buf) = read_header(&mut stream, |buf| {
+        let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+        let mut request = httparse::Request::new(&mut headers);
+        let n = match request.parse(buf).map_err(parse_error)? {
+            httparse::Status::Complete(n) => n,
+            httparse::Status::Partial => return Ok(None),
+        };
+
+        let request_path = request.path.unwrap_or("");
+        let request_path = request_path.split('?').next().unwrap_or(request_path);
+
+        response = if request.method != Some("GET") {
+            Err("405 Method Not Allowed")
+        } else if request_path != path {
+            Err("404 Not Found")
+        } else if !header_contains(request.headers, "Upgrade", "websocket")
+            || !header_contains(request.headers, "Connection", "upgrade")
+        {
+            Err("426 Upgrade Required")
+        } else {
+            match header_value(request.headers, "Sec-WebSocket-Key") {
+                Some(key) if !key.is_empty() => Ok(accept_key(key)),
+                _ => Err("400 Bad Request"),
+            }
+        };
+
+        Ok(Some(n))
+    })
+    .await?;
+
+    match response {
+        Ok(accept) => {
+            let response = format!(
+                "HTTP/1.1 101 Switching Protocols\r\n\
+                 Upgrade: websocket\r\n\
+                 Connection: Upgrade\r\n\
+                 Sec-WebSocket-Accept: {}\r\n\r\n",
+                accept
+            );
+            stream.write_all(response.as_bytes()).await?;
+
+            Ok(WebSocketFramed::new(stream, Role::Server, &buf[n..]))
+        }
+        Err(status) => {
+            let response = format!("HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n", status);
+            let _ = stream.write_all(response.as_bytes()).await;
+            let _ = stream.shutdown().await;
+
+            Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("websocket handshake refused with {}", status),
+            ))
+        }
+    }
+}
@@ -0,0 +1,259 @@
+//! WebSocket transport, compatible with [v2ray-plugin](https://github.com/shadowsocks/v2ray-plugin)
+//!
+//! ```plain
+//! +------------+                                                 +------------+
+//! |  SS Client +-- WebSocket [TLS] [Mux.Cool] (Public Internet) --+  SS Server |
+//! +------------+                                                 +------------+
+//! ```
+//!
+//! Servers configured with v2ray-plugin speak its protocol in process, without starting the plugin program.
+
+use std::{
+    io::{self, ErrorKind},
+    net::SocketAddr,
+    time::Duration,
+};
+
+use bytes::BytesMut;
+use log::{debug, error, trace};
+use tokio::{io::AsyncReadExt, net::TcpStream as TokioTcpStream, sync::mpsc, task::JoinHandle, time};
+use tokio_rustls::rustls::ServerName;
+
+use crate::{
+    config::ServerAddr,
+    context::Context,
+    relay::{
+        mux::{self, is_mux_stream, BoxedMuxIo},
+        socks5::Address,
+    },
+};
+
+pub use self::{
+    config::{WebSocketConfig, V2RAY_PLUGIN},
+    stream::WebSocketStream,
+};
+use self::{
+    frame::WebSocketFramed,
+    handshake::{client_handshake, server_handshake},
+    stream::{BoxedIo, SocketInfo},
+};
+use super::{sys::TcpStream as SysTcpStream, AcceptOpts, ConnectOpts, TcpListener};
+
+mod config;
+mod frame;
+mod handshake;
+mod stream;
+
+/// Time limit of TLS and WebSocket handshakes of inbound connections
+const ACCEPT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(30);
+/// Streams waiting in `WebSocketListener` before being accepted
+const ACCEPT_BACKLOG: usize = 1024;
+
+/// Makes a WebSocket connection to `addr`
+async fn connect_websocket(
+    context: &Context,
+    config: &WebSocketConfig,
+    addr: &ServerAddr,
+    opts: &ConnectOpts,
+) -> io::Result<(WebSocketFramed<BoxedIo>, SocketInfo)> {
+    let stream = match *addr {
+        ServerAddr::SocketAddr(ref addr) => SysTcpStream::connect(*addr, opts).await?,
+        ServerAddr::DomainName(ref domain, port) => {
+            lookup_then_connect!(context, domain, port, |addr| {
+                SysTcpStream::connect(addr, opts).await
+            })?
+            .1
+        }
+    };
+
+    let info = SocketInfo::new(&stream, stream.local_addr()?, stream.peer_addr()?);
+
+    let io: BoxedIo = if config.tls() {
+        let server_name = match ServerName::try_from(config.host()) {
+            Ok(n) => n,
+            Err(..) => {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("invalid dnsname \"{}\"", config.host()),
+                ));
+            }
+        };
+        Box::new(config.tls_connector()?.connect(server_name, stream).await?)
+    } else {
+        Box::new(stream)
+    };
+
+    let framed = client_handshake(io, config.host(), config.path()).await?;
+    Ok((framed, info))
+}
+
+impl WebSocketConfig {
+    /// Opens a stream to the server at `addr`
+    ///
+    /// With `mux` enabled, the stream is a session in a connection shared with others.
+    pub async fn connect(
+        &self,
+        context: &Context,
+        addr: &ServerAddr,
+        opts: &ConnectOpts,
+    ) -> io::Result<WebSocketStream> {
+        match self.mux_pool() {
+            Some(pool) => {
+                let stream = pool
+                    .open(&Address::from(addr), || async {
+                        let (framed, info) = connect_websocket(context, self, addr, opts).await?;
+                        Ok((Box::new(framed) as BoxedMuxIo, info))
+                    })
+                    .await?;
+                Ok(WebSocketStream::mux(stream))
+            }
+            None => {
+                let (framed, info) = connect_websocket(context, self, addr, opts).await?;
+                Ok(WebSocketStream::direct(framed, info, Default::default()))
+            }
+        }
+    }
+}
+
+type AcceptResult = (WebSocketStream, SocketAddr);
+
+/// Accepts an inbound WebSocket connection, passes streams in it to `tx`
+async fn accept_websocket(
+    stream: TokioTcpStream,
+    peer_addr: SocketAddr,
+    config: &WebSocketConfig,
+    tls_acceptor: Option<&tokio_rustls::TlsAcceptor>,
+    tx: &mpsc::Sender<AcceptResult>,
+) -> io::Result<()> {
+    let info = SocketInfo::new(&stream, stream.local_addr()?, peer_addr);
+
+    let handshake = async {
+        let io: BoxedIo = match tls_acceptor {
+            Some(acceptor) => Box::new(acceptor.accept(stream).await?),
+            None => Box::new(stream),
+        };
+        let mut framed = server_handshake(io, config.path()).await?;
+
+        // Clients with mux open sessions with Mux.Cool frames, others start with shadowsocks' salt
+        let mut buf = BytesMut::new();
+        let is_mux = loop {
+            if framed.read_buf(&mut buf).await? == 0 {
+                break false;
+            }
+            if let Some(is_mux) = is_mux_stream(&buf) {
+                break is_mux;
+            }
+        };
+
+        Ok::<_, io::Error>((framed, buf, is_mux))
+    };
+
+    let (framed, buf, is_mux) = match time::timeout(ACCEPT_HANDSHAKE_TIMEOUT, handshake).await {
+        Ok(r) => r?,
+        Err(..) => return Err(io::Error::new(ErrorKind::TimedOut, "websocket handshake timeout")),
+    };
+
+    if !is_mux {
+        let stream = WebSocketStream::direct(framed, info, buf.freeze());
+        let _ = tx.send((stream, peer_addr)).await;
+        return Ok(());
+    }
+
+    trace!("websocket client {} opened mux connection", peer_addr);
+
+    mux::serve_connection(Box::new(framed), info, buf, |stream| {
+        if tx.try_send((WebSocketStream::mux(stream), peer_addr)).is_err() {
+            debug!(
+                "websocket mux session from {} dropped, too many pending streams",
+                peer_addr
+            );
+        }
+    })
+    .await;
+
+    Ok(())
+}
+
+/// A listener accepting shadowsocks' streams carried by the WebSocket transport
+pub struct WebSocketListener {
+    local_addr: SocketAddr,
+    rx: mpsc::Receiver<AcceptResult>,
+    accept_task: JoinHandle<()>,
+}
+
+impl Drop for WebSocketListener {
+    fn drop(&mut self) {
+        self.accept_task.abort();
+    }
+}
+
+impl WebSocketListener {
+    /// Creates a listener binding to `addr`
+    pub async fn bind_with_opts(
+        context: &Context,
+        addr: &ServerAddr,
+        config: &WebSocketConfig,
+        accept_opts: AcceptOpts,
+    ) -> io::Result<WebSocketListener> {
+        let tls_acceptor = if config.tls() {
+            Some(config.tls_acceptor()?)
+        } else {
+            None
+        };
+
+        let listener = match *addr {
+            ServerAddr::SocketAddr(ref sa) => TcpListener::bind_with_opts(sa, accept_opts).await?,
+            ServerAddr::DomainName(ref domain, port) => {
+                lookup_then!(context, domain, port, |addr| {
+                    TcpListener::bind_with_opts(&addr, accept_opts.clone()).await
+                })?
+                .1
+            }
+        };
+        let local_addr = listener.local_addr()?;
+
+        let (tx, rx) = mpsc::channel(ACCEPT_BACKLOG);
+        let config = config.clone();
+
+        let accept_task = tokio::spawn(async move {
+            loop {
+                let (stream, peer_addr) = match listener.accept().await {
+                    Ok(s) => s,
+                    Err(err) => {
+                        error!("websocket server accept failed with error: {}", err);
+                        time::sleep(Duration::from_secs(1)).await;
+                        continue;
+                    }
+                };
+
+                let config = config.clone();
+                let tls_acceptor = tls_acceptor.clone();
+                let tx = tx.clone();
+                tokio::spawn(async move {
+                    if let Err(err) = accept_websocket(stream, peer_addr, &config, tls_acceptor.as_ref(), &tx).await {
+                        debug!("websocket client {} aborted with error: {}", peer_addr, err);
+                    }
+                });
+            }
+        });
+
+        Ok(WebSocketListener {
+            local_addr,
+            rx,
+            accept_task,
+        })
+    }
+
+    /// Accepts a stream, which may be a Mux.Cool session in a connection of the peer
+    pub async fn accept(&mut self) -> io::Result<(WebSocketStream, SocketAddr)> {
+        match self.rx.recv().await {
+            Some(r) => Ok(r),
+            None => Err(io::Error::new(ErrorKind::Other, "websocket listener closed")),
+        }
+    }
+
+    /// Get local binded address
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.local_addr)
+    }
+}
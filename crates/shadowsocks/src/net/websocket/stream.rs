@@ -0,0 +1,203 @@
+//! Streams carried by the WebSocket transport
+
+#[cfg(unix)]
+use std::os::unix::io::{AsRawFd, RawFd};
+#[cfg(windows)]
+use std::os::windows::io::{AsRawSocket, RawSocket};
+use std::{
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    task::{self, Poll},
+};
+
+use bytes::{Buf, Bytes};
+use socket2::SockRef;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::relay::mux::{MuxInfo, MuxStream};
+
+use super::frame::WebSocketFramed;
+
+/// Object safe `AsyncRead + AsyncWrite`
+pub trait AsyncIo: AsyncRead + AsyncWrite + Send + Sync + Unpin {}
+
+impl<T> AsyncIo for T where T: AsyncRead + AsyncWrite + Send + Sync + Unpin {}
+
+/// Type erased plain or TLS stream carrying WebSocket
+pub type BoxedIo = Box<dyn AsyncIo>;
+
+/// The socket carrying a WebSocket connection
+#[derive(Debug, Clone, Copy)]
+pub struct SocketInfo {
+    /// Local address of the socket
+    pub local_addr: SocketAddr,
+    /// Peer address of the socket
+    pub peer_addr: SocketAddr,
+    #[cfg(unix)]
+    fd: RawFd,
+    #[cfg(windows)]
+    socket: RawSocket,
+}
+
+impl SocketInfo {
+    #[cfg(unix)]
+    pub(crate) fn new<S: AsRawFd>(s: &S, local_addr: SocketAddr, peer_addr: SocketAddr) -> SocketInfo {
+        SocketInfo {
+            local_addr,
+            peer_addr,
+            fd: s.as_raw_fd(),
+        }
+    }
+
+    #[cfg(windows)]
+    pub(crate) fn new<S: AsRawSocket>(s: &S, local_addr: SocketAddr, peer_addr: SocketAddr) -> SocketInfo {
+        SocketInfo {
+            local_addr,
+            peer_addr,
+            socket: s.as_raw_socket(),
+        }
+    }
+}
+
+impl MuxInfo for SocketInfo {
+    fn peer_addr(&self) -> SocketAddr {
+        self.peer_addr
+    }
+}
+
+#[cfg(unix)]
+impl AsRawFd for SocketInfo {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
+    }
+}
+
+#[cfg(windows)]
+impl AsRawSocket for SocketInfo {
+    fn as_raw_socket(&self) -> RawSocket {
+        self.socket
+    }
+}
+
+enum WebSocketStreamInner {
+    Direct {
+        framed: Box<WebSocketFramed<BoxedIo>>,
+        info: SocketInfo,
+    },
+    Mux(MuxStream<SocketInfo>),
+}
+
+/// A stream carried by a WebSocket connection, or by a Mux.Cool session in it
+///
+/// Sessions of Mux.Cool share one socket, socket options applied to any of them take effect on all.
+pub struct WebSocketStream {
+    inner: WebSocketStreamInner,
+    read_pending: Bytes,
+}
+
+impl WebSocketStream {
+    pub(crate) fn direct(framed: WebSocketFramed<BoxedIo>, info: SocketInfo, read_pending: Bytes) -> WebSocketStream {
+        WebSocketStream {
+            inner: WebSocketStreamInner::Direct {
+                framed: Box::new(framed),
+                info,
+            },
+            read_pending,
+        }
+    }
+
+    pub(crate) fn mux(stream: MuxStream<SocketInfo>) -> WebSocketStream {
+        WebSocketStream {
+            inner: WebSocketStreamInner::Mux(stream),
+            read_pending: Bytes::new(),
+        }
+    }
+
+    fn socket_info(&self) -> &SocketInfo {
+        match self.inner {
+            WebSocketStreamInner::Direct { ref info, .. } => info,
+            WebSocketStreamInner::Mux(ref s) => s.info(),
+        }
+    }
+
+    /// Returns `true` if this stream is a Mux.Cool session
+    pub fn is_mux(&self) -> bool {
+        matches!(self.inner, WebSocketStreamInner::Mux(..))
+    }
+
+    /// Returns the local address of the underlying socket
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.socket_info().local_addr)
+    }
+
+    /// Returns the peer address of the underlying socket
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.socket_info().peer_addr)
+    }
+
+    /// Gets the value of the `TCP_NODELAY` option on the underlying socket
+    pub fn nodelay(&self) -> io::Result<bool> {
+        SockRef::from(self.socket_info()).nodelay()
+    }
+
+    /// Sets the value of the `TCP_NODELAY` option on the underlying socket
+    pub fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+        SockRef::from(self.socket_info()).set_nodelay(nodelay)
+    }
+}
+
+#[cfg(unix)]
+impl AsRawFd for WebSocketStream {
+    fn as_raw_fd(&self) -> RawFd {
+        self.socket_info().as_raw_fd()
+    }
+}
+
+#[cfg(windows)]
+impl AsRawSocket for WebSocketStream {
+    fn as_raw_socket(&self) -> RawSocket {
+        self.socket_info().as_raw_socket()
+    }
+}
+
+impl AsyncRead for WebSocketStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut task::Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        if !this.read_pending.is_empty() {
+            let n = this.read_pending.len().min(buf.remaining());
+            buf.put_slice(&this.read_pending[..n]);
+            this.read_pending.advance(n);
+            return Ok(()).into();
+        }
+
+        match this.inner {
+            WebSocketStreamInner::Direct { ref mut framed, .. } => Pin::new(framed.as_mut()).poll_read(cx, buf),
+            WebSocketStreamInner::Mux(ref mut s) => Pin::new(s).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for WebSocketStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut task::Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match self.get_mut().inner {
+            WebSocketStreamInner::Direct { ref mut framed, .. } => Pin::new(framed.as_mut()).poll_write(cx, buf),
+            WebSocketStreamInner::Mux(ref mut s) => Pin::new(s).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut().inner {
+            WebSocketStreamInner::Direct { ref mut framed, .. } => Pin::new(framed.as_mut()).poll_flush(cx),
+            WebSocketStreamInner::Mux(ref mut s) => Pin::new(s).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut().inner {
+            WebSocketStreamInner::Direct { ref mut framed, .. } => Pin::new(framed.as_mut()).poll_shutdown(cx),
+            WebSocketStreamInner::Mux(ref mut s) => Pin::new(s).poll_shutdown(cx),
+        }
+    }
+}
@@ -14,7 +14,7 @@
 
 use std::{
     io,
-    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, UdpSocket},
     process::ExitStatus,
     time::{Duration, Instant},
 };
@@ -22,10 +22,13 @@ use std::{
 use log::{debug, error};
 use tokio::{net::TcpStream, process::Child, time};
 
-use crate::config::ServerAddr;
+use crate::config::{Mode, ServerAddr};
+
+pub use self::supervisor::{PluginHealth, PluginStatus, PluginSupervisor};
 
 mod obfs_proxy;
 mod ss_plugin;
+mod supervisor;
 
 /// Config for plugin
 #[derive(Debug, Clone)]
@@ -33,6 +36,54 @@ pub struct PluginConfig {
     pub plugin: String,
     pub plugin_opts: Option<String>,
     pub plugin_args: Vec<String>,
+    /// Protocols relayed by plugin, `Mode::TcpOnly` for most plugins
+    ///
+    /// Plugins supporting UDP (SIP003u) relay UDP on the same ports as TCP, `udp_only` plugins don't relay TCP.
+    pub plugin_mode: Mode,
+}
+
+/// Splits SIP003 plugin options into key-value pairs, `\` escapes `;`, `=` and `\`
+#[cfg(any(feature = "transport-websocket", feature = "transport-obfs"))]
+pub(crate) fn split_plugin_opts(opts: &str) -> Vec<(String, Option<String>)> {
+    let mut pairs = Vec::new();
+    let mut key = String::new();
+    let mut value: Option<String> = None;
+
+    let mut escaped = false;
+    for c in opts.chars() {
+        if !escaped {
+            match c {
+                '\\' => {
+                    escaped = true;
+                    continue;
+                }
+                '=' if value.is_none() => {
+                    value = Some(String::new());
+                    continue;
+                }
+                ';' => {
+                    if !key.is_empty() {
+                        pairs.push((key.trim().to_owned(), value.take()));
+                    }
+                    key.clear();
+                    value = None;
+                    continue;
+                }
+                _ => {}
+            }
+        }
+
+        escaped = false;
+        match value {
+            Some(ref mut v) => v.push(c),
+            None => key.push(c),
+        }
+    }
+    if !key.is_empty() {
+        pairs.push((key.trim().to_owned(), value));
+    }
+
+    pairs
 }
 
 /// Mode of Plugin
@@ -60,6 +111,9 @@ pub enum PluginMode {
 pub struct Plugin {
     process: Child,
     local_addr: SocketAddr,
+    remote_addr: ServerAddr,
+    mode: PluginMode,
+    plugin_mode: Mode,
 }
 
 impl Plugin {
@@ -76,8 +130,17 @@ impl Plugin {
             ServerAddr::DomainName(..) => Ipv4Addr::LOCALHOST.into(),
         };
 
-        let local_addr = get_local_port(loop_ip)?;
+        let local_addr = get_local_port(loop_ip, c.plugin_mode.enable_udp())?;
+        Plugin::start_with_local_addr(c, remote_addr, local_addr, mode)
+    }
 
+    /// Start a plugin subprocess with `local_addr`, which is kept by restarted plugins
+    fn start_with_local_addr(
+        c: &PluginConfig,
+        remote_addr: &ServerAddr,
+        local_addr: SocketAddr,
+        mode: PluginMode,
+    ) -> io::Result<Plugin> {
         match start_plugin(c, remote_addr, &local_addr, mode) {
             Err(err) => {
                 error!(
@@ -108,40 +171,62 @@ impl Plugin {
                     }
                 }
 
-                Ok(Plugin { process, local_addr })
+                Ok(Plugin {
+                    process,
+                    local_addr,
+                    remote_addr: remote_addr.clone(),
+                    mode,
+                    plugin_mode: c.plugin_mode,
+                })
             }
         }
     }
 
     /// Join until plugin exits
     pub async fn join(mut self) -> io::Result<ExitStatus> {
+        self.wait().await
+    }
+
+    /// Wait until plugin exits
+    pub async fn wait(&mut self) -> io::Result<ExitStatus> {
         self.process.wait().await
     }
 
-    /// Check if plugin have been started
+    /// Check if plugin have been started, that it accepts connections
+    ///
+    /// `udp_only` plugins couldn't be checked, they are considered started.
     pub async fn wait_started(&self, timeout: Duration) -> bool {
-        let start_time = Instant::now();
-
-        loop {
-            let now_time = Instant::now();
-            let elapsed_time = now_time - start_time;
-            if elapsed_time >= timeout {
-                return false;
-            }
+        if !self.plugin_mode.enable_tcp() {
+            return true;
+        }
+        wait_listening(&self.listen_addr(), timeout).await
+    }
 
-            let remain_time = timeout - elapsed_time;
-            match time::timeout(remain_time, TcpStream::connect(self.local_addr)).await {
-                Ok(Ok(..)) => {
-                    return true;
-                }
-                Ok(Err(..)) => {}
-                Err(..) => {
-                    return false;
+    /// Get the address that plugin accepts connections on
+    ///
+    /// `local_addr` for `PluginMode::Client`, `remote_addr` for `PluginMode::Server`, whose unspecified IP is
+    /// replaced by loopback
+    fn listen_addr(&self) -> ServerAddr {
+        match self.mode {
+            PluginMode::Client => self.local_addr.into(),
+            PluginMode::Server => match self.remote_addr {
+                ServerAddr::SocketAddr(sa) if sa.ip().is_unspecified() => {
+                    let loop_ip: IpAddr = match sa.ip() {
+                        IpAddr::V4(..) => Ipv4Addr::LOCALHOST.into(),
+                        IpAddr::V6(..) => Ipv6Addr::LOCALHOST.into(),
+                    };
+                    SocketAddr::new(loop_ip, sa.port()).into()
                 }
-            }
+                ref addr => addr.clone(),
+            },
         }
     }
 
+    /// Get process ID of plugin, `None` if it has exited
+    pub fn id(&self) -> Option<u32> {
+        self.process.id()
+    }
+
     /// Get listen address of plugin
     pub fn local_addr(&self) -> SocketAddr {
         self.local_addr
@@ -229,9 +314,62 @@ fn start_plugin(plugin: &PluginConfig, remote: &ServerAddr, local: &SocketAddr,
     cmd.spawn()
 }
 
-fn get_local_port(loop_ip: IpAddr) -> io::Result<SocketAddr> {
-    let listener = TcpListener::bind(SocketAddr::new(loop_ip, 0))?;
-    listener.local_addr()
+/// Check if `addr` accepts connections in `timeout`
+async fn wait_listening(addr: &ServerAddr, timeout: Duration) -> bool {
+    let start_time = Instant::now();
+
+    loop {
+        let now_time = Instant::now();
+        let elapsed_time = now_time - start_time;
+        if elapsed_time >= timeout {
+            return false;
+        }
+
+        let remain_time = timeout - elapsed_time;
+        let connect_fut = async {
+            match *addr {
+                ServerAddr::SocketAddr(sa) => TcpStream::connect(sa).await,
+                ServerAddr::DomainName(ref domain, port) => TcpStream::connect((domain.as_str(), port)).await,
+            }
+        };
+        match time::timeout(remain_time, connect_fut).await {
+            Ok(Ok(..)) => {
+                return true;
+            }
+            Ok(Err(..)) => {
+                // Don't spin while plugin is starting
+                time::sleep(Duration::from_millis(50)).await;
+            }
+            Err(..) => {
+                return false;
+            }
+        }
+    }
+}
+
+/// Find a free port on `loop_ip`, which is also free for UDP if `with_udp`
+fn get_local_port(loop_ip: IpAddr, with_udp: bool) -> io::Result<SocketAddr> {
+    const MAX_UDP_ATTEMPTS: usize = 10;
+
+    let mut attempts = 0;
+    loop {
+        let listener = TcpListener::bind(SocketAddr::new(loop_ip, 0))?;
+        let addr = listener.local_addr()?;
+        if !with_udp {
+            return Ok(addr);
+        }
+
+        // Ports are allocated separately for TCP and UDP
+        match UdpSocket::bind(addr) {
+            Ok(..) => return Ok(addr),
+            Err(err) => {
+                attempts += 1;
+                if attempts >= MAX_UDP_ATTEMPTS {
+                    return Err(err);
+                }
+            }
+        }
+    }
 }
 
 #[cfg(test)]
@@ -241,7 +379,7 @@ mod test {
     #[test]
     fn generate_random_port() {
         let loop_ip = Ipv4Addr::LOCALHOST.into();
-        let addr = get_local_port(loop_ip).unwrap();
+        let addr = get_local_port(loop_ip, false).unwrap();
         println!("{:?}", addr);
     }
 }
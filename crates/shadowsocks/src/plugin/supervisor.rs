@@ -0,0 +1,240 @@
+//! Supervisor restarting exited plugins
+
+use std::{
+    fmt::{self, Display},
+    io,
+    net::SocketAddr,
+    process::ExitStatus,
+    sync::{
+        atomic::{AtomicU8, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use log::{error, info, warn};
+use tokio::time;
+
+use crate::config::ServerAddr;
+
+use super::{wait_listening, Plugin, PluginConfig, PluginMode};
+
+/// Delay before restarting an exited plugin, doubled by each restart
+const INITIAL_RESTART_DELAY: Duration = Duration::from_secs(1);
+/// Maximum delay before restarting an exited plugin
+const MAX_RESTART_DELAY: Duration = Duration::from_secs(60);
+/// Plugins running for this long are stable, the next restart delay is reset
+const STABLE_DURATION: Duration = Duration::from_secs(60);
+/// Started plugins are expected to accept connections in this duration, otherwise it is warned and checked again
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Health of a supervised plugin
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PluginHealth {
+    /// Started, but doesn't accept connections yet
+    Starting,
+    /// Accepting connections
+    Running,
+    /// Exited, waiting to be restarted
+    Restarting,
+}
+
+impl PluginHealth {
+    /// Name of the health, in lower case
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            PluginHealth::Starting => "starting",
+            PluginHealth::Running => "running",
+            PluginHealth::Restarting => "restarting",
+        }
+    }
+
+    fn from_u8(v: u8) -> PluginHealth {
+        match v {
+            0 => PluginHealth::Starting,
+            1 => PluginHealth::Running,
+            _ => PluginHealth::Restarting,
+        }
+    }
+}
+
+impl Display for PluginHealth {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Status of a supervised plugin, shared with `PluginSupervisor`
+#[derive(Debug)]
+pub struct PluginStatus {
+    health: AtomicU8,
+    restarts: AtomicUsize,
+}
+
+impl PluginStatus {
+    fn new() -> PluginStatus {
+        PluginStatus {
+            health: AtomicU8::new(PluginHealth::Starting as u8),
+            restarts: AtomicUsize::new(0),
+        }
+    }
+
+    /// Current health of plugin
+    pub fn health(&self) -> PluginHealth {
+        PluginHealth::from_u8(self.health.load(Ordering::Acquire))
+    }
+
+    /// Check if plugin is accepting connections
+    pub fn is_running(&self) -> bool {
+        self.health() == PluginHealth::Running
+    }
+
+    /// Times plugin has been restarted
+    pub fn restarts(&self) -> usize {
+        self.restarts.load(Ordering::Relaxed)
+    }
+
+    fn set_health(&self, health: PluginHealth) {
+        self.health.store(health as u8, Ordering::Release);
+    }
+}
+
+/// A plugin restarted whenever it exits
+///
+/// Restarted plugins keep listening on (or sending data to) the same `local_addr`, so servers and clients using it
+/// don't have to be reconfigured. Restarts are delayed exponentially from 1 second to 1 minute while plugin keeps
+/// exiting, and plugins are `PluginHealth::Running` only after they accept connections again.
+pub struct PluginSupervisor {
+    config: PluginConfig,
+    remote_addr: ServerAddr,
+    mode: PluginMode,
+    plugin: Plugin,
+    status: Arc<PluginStatus>,
+}
+
+impl PluginSupervisor {
+    /// Start a plugin subprocess, see `Plugin::start`
+    pub fn start(c: &PluginConfig, remote_addr: &ServerAddr, mode: PluginMode) -> io::Result<PluginSupervisor> {
+        let plugin = Plugin::start(c, remote_addr, mode)?;
+        Ok(PluginSupervisor {
+            config: c.clone(),
+            remote_addr: remote_addr.clone(),
+            mode,
+            plugin,
+            status: Arc::new(PluginStatus::new()),
+        })
+    }
+
+    /// Get listen address of plugin
+    pub fn local_addr(&self) -> SocketAddr {
+        self.plugin.local_addr()
+    }
+
+    /// Get status of plugin, which is updated while `run` is running
+    pub fn status(&self) -> Arc<PluginStatus> {
+        self.status.clone()
+    }
+
+    /// Check if plugin have been started, that it accepts connections
+    pub async fn wait_started(&self, timeout: Duration) -> bool {
+        let started = self.plugin.wait_started(timeout).await;
+        if started {
+            self.status.set_health(PluginHealth::Running);
+        }
+        started
+    }
+
+    /// Supervise plugin, restarting it whenever it exits. Never returns, plugin is killed when it is dropped.
+    pub async fn run(self) {
+        let PluginSupervisor {
+            config,
+            remote_addr,
+            mode,
+            mut plugin,
+            status,
+        } = self;
+
+        let local_addr = plugin.local_addr();
+        let mut restart_delay = INITIAL_RESTART_DELAY;
+
+        loop {
+            let started_time = Instant::now();
+            let result = wait_exited(&mut plugin, &status, &config, &remote_addr).await;
+
+            status.set_health(PluginHealth::Restarting);
+            if started_time.elapsed() >= STABLE_DURATION {
+                restart_delay = INITIAL_RESTART_DELAY;
+            }
+
+            match result {
+                Ok(exit_status) => error!(
+                    "plugin \"{}\" for server {} exited with status: {}, restarting in {:?}",
+                    config.plugin, remote_addr, exit_status, restart_delay
+                ),
+                Err(err) => error!(
+                    "plugin \"{}\" for server {} exited with error: {}, restarting in {:?}",
+                    config.plugin, remote_addr, err, restart_delay
+                ),
+            }
+
+            loop {
+                time::sleep(restart_delay).await;
+                restart_delay = (restart_delay * 2).min(MAX_RESTART_DELAY);
+
+                // Errors are logged by `start_with_local_addr`
+                if let Ok(p) = Plugin::start_with_local_addr(&config, &remote_addr, local_addr, mode) {
+                    plugin = p;
+                    break;
+                }
+            }
+
+            let restarts = status.restarts.fetch_add(1, Ordering::Relaxed) + 1;
+            status.set_health(PluginHealth::Starting);
+
+            info!(
+                "restarted plugin \"{}\" for server {} ({}), restarted {} times",
+                config.plugin,
+                remote_addr,
+                plugin.id().unwrap_or(0),
+                restarts
+            );
+        }
+    }
+}
+
+/// Wait until `plugin` exits, it is marked `PluginHealth::Running` once it accepts connections
+async fn wait_exited(
+    plugin: &mut Plugin,
+    status: &PluginStatus,
+    config: &PluginConfig,
+    remote_addr: &ServerAddr,
+) -> io::Result<ExitStatus> {
+    if !status.is_running() && !config.plugin_mode.enable_tcp() {
+        // `udp_only` plugins couldn't be checked
+        status.set_health(PluginHealth::Running);
+    } else if !status.is_running() {
+        let listen_addr = plugin.listen_addr();
+
+        let check_fut = async {
+            while !wait_listening(&listen_addr, STARTUP_TIMEOUT).await {
+                warn!(
+                    "plugin \"{}\" for server {} isn't accepting connections on {} in {:?}",
+                    config.plugin, remote_addr, listen_addr, STARTUP_TIMEOUT
+                );
+            }
+        };
+
+        tokio::select! {
+            result = plugin.wait() => return result,
+            _ = check_fut => {
+                status.set_health(PluginHealth::Running);
+                info!(
+                    "plugin \"{}\" for server {} is accepting connections on {}",
+                    config.plugin, remote_addr, listen_addr
+                );
+            }
+        }
+    }
+
+    plugin.wait().await
+}
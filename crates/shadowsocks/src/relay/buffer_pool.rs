@@ -0,0 +1,196 @@
+//! Pools of buffers shared by relays
+//!
+//! Copying loops of TCP tunnels and receiving batches of UDP associations take buffers from the pools only when there
+//! is data to be relayed, and put them back when they are waiting, so the memory is proportional to the number of busy
+//! connections rather than all connections. Buffers are reused up to the cap of a pool, and the others are freed.
+//!
+//! Pools are configured by `init` before relays are started, or they are created with the default configuration.
+//! Servers and local instances could relay with buffers of other sizes, taken from pools created by
+//! `sized_tcp_buffer_pool` on demand.
+
+use std::{
+    fmt,
+    io::{self, ErrorKind},
+    sync::Mutex,
+};
+
+use once_cell::sync::OnceCell;
+
+use super::udprelay::MAXIMUM_UDP_PAYLOAD_SIZE;
+
+/// Default size of buffers of TCP relays
+pub const DEFAULT_BUFFER_SIZE: usize = 16 * 1024;
+
+/// Default maximum number of buffers kept by a pool
+pub const DEFAULT_MAX_BUFFERS: usize = 1024;
+
+/// Configuration of buffer pools of relays
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BufferPoolConfig {
+    /// Size of buffers for copying TCP streams
+    ///
+    /// Buffers for receiving UDP datagrams are always `MAXIMUM_UDP_PAYLOAD_SIZE`.
+    pub buffer_size: usize,
+    /// Maximum number of idle buffers kept by each pool
+    pub max_buffers: usize,
+}
+
+impl Default for BufferPoolConfig {
+    fn default() -> BufferPoolConfig {
+        BufferPoolConfig {
+            buffer_size: DEFAULT_BUFFER_SIZE,
+            max_buffers: DEFAULT_MAX_BUFFERS,
+        }
+    }
+}
+
+/// Pool of buffers in the same size
+pub struct BufferPool {
+    buffer_size: usize,
+    max_buffers: usize,
+    buffers: Mutex<Vec<Box<[u8]>>>,
+}
+
+impl BufferPool {
+    /// Create an empty pool of buffers of `buffer_size` bytes, keeping at most `max_buffers` of them
+    pub fn new(buffer_size: usize, max_buffers: usize) -> BufferPool {
+        BufferPool {
+            buffer_size,
+            max_buffers,
+            buffers: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Size of buffers
+    pub fn buffer_size(&self) -> usize {
+        self.buffer_size
+    }
+
+    /// Take a buffer from the pool, or allocate one if the pool is empty
+    pub fn acquire(&self) -> Box<[u8]> {
+        match self.buffers.lock().unwrap().pop() {
+            Some(buf) => buf,
+            None => vec![0u8; self.buffer_size].into_boxed_slice(),
+        }
+    }
+
+    /// Put a buffer taken by `acquire` back to the pool, or free it if the pool is full
+    pub fn release(&self, buf: Box<[u8]>) {
+        if buf.len() != self.buffer_size {
+            return;
+        }
+
+        let mut buffers = self.buffers.lock().unwrap();
+        if buffers.len() < self.max_buffers {
+            buffers.push(buf);
+        }
+    }
+
+    /// Number of idle buffers in the pool
+    pub fn idle_buffers(&self) -> usize {
+        self.buffers.lock().unwrap().len()
+    }
+}
+
+impl fmt::Debug for BufferPool {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BufferPool")
+            .field("buffer_size", &self.buffer_size)
+            .field("max_buffers", &self.max_buffers)
+            .field("idle_buffers", &self.idle_buffers())
+            .finish()
+    }
+}
+
+/// Sizes of buffers of TCP relays of a server or a local instance
+///
+/// Buffers of `tcp_buffer_pool` are used for the directions which are not set.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RelayBufferSizes {
+    /// Size of buffers for data read from clients, relayed to remotes
+    pub read: Option<usize>,
+    /// Size of buffers for data relayed from remotes, written to clients
+    pub write: Option<usize>,
+}
+
+impl RelayBufferSizes {
+    /// Check if both directions use buffers of `tcp_buffer_pool`
+    pub fn is_default(&self) -> bool {
+        self.read.is_none() && self.write.is_none()
+    }
+
+    /// Sizes of `self`, with the directions which are not set taken from `sizes`
+    pub fn or(self, sizes: RelayBufferSizes) -> RelayBufferSizes {
+        RelayBufferSizes {
+            read: self.read.or(sizes.read),
+            write: self.write.or(sizes.write),
+        }
+    }
+
+    /// Pool of buffers for data read from clients
+    pub fn read_pool(&self) -> &'static BufferPool {
+        self.read.map_or_else(tcp_buffer_pool, sized_tcp_buffer_pool)
+    }
+
+    /// Pool of buffers for data written to clients
+    pub fn write_pool(&self) -> &'static BufferPool {
+        self.write.map_or_else(tcp_buffer_pool, sized_tcp_buffer_pool)
+    }
+}
+
+static TCP_BUFFER_POOL: OnceCell<BufferPool> = OnceCell::new();
+static UDP_BUFFER_POOL: OnceCell<BufferPool> = OnceCell::new();
+static SIZED_TCP_BUFFER_POOLS: Mutex<Vec<&'static BufferPool>> = Mutex::new(Vec::new());
+
+/// Configure the shared pools
+///
+/// Fails if they have been created with another configuration, by an earlier call or by being used before.
+pub fn init(config: BufferPoolConfig) -> io::Result<()> {
+    if config.buffer_size == 0 {
+        return Err(io::Error::new(ErrorKind::InvalidInput, "buffer size must not be 0"));
+    }
+
+    let tcp_pool = TCP_BUFFER_POOL.get_or_init(|| BufferPool::new(config.buffer_size, config.max_buffers));
+    let udp_pool = UDP_BUFFER_POOL.get_or_init(|| BufferPool::new(MAXIMUM_UDP_PAYLOAD_SIZE, config.max_buffers));
+    if tcp_pool.buffer_size != config.buffer_size
+        || tcp_pool.max_buffers != config.max_buffers
+        || udp_pool.max_buffers != config.max_buffers
+    {
+        return Err(io::Error::new(
+            ErrorKind::Other,
+            "buffer pools have already been created with another configuration",
+        ));
+    }
+
+    Ok(())
+}
+
+/// Pool of buffers for copying TCP streams
+pub fn tcp_buffer_pool() -> &'static BufferPool {
+    TCP_BUFFER_POOL.get_or_init(|| BufferPool::new(DEFAULT_BUFFER_SIZE, DEFAULT_MAX_BUFFERS))
+}
+
+/// Pool of buffers for receiving UDP datagrams, in `MAXIMUM_UDP_PAYLOAD_SIZE`
+pub fn udp_buffer_pool() -> &'static BufferPool {
+    UDP_BUFFER_POOL.get_or_init(|| BufferPool::new(MAXIMUM_UDP_PAYLOAD_SIZE, DEFAULT_MAX_BUFFERS))
+}
+
+/// Pool of buffers for copying TCP streams, in `buffer_size` bytes
+///
+/// It is `tcp_buffer_pool` if the size is the same. Pools of other sizes are created on the first use, keeping as
+/// many buffers as `tcp_buffer_pool`, and are never freed. Sizes are configured, so there wouldn't be many of them.
+pub fn sized_tcp_buffer_pool(buffer_size: usize) -> &'static BufferPool {
+    let tcp_pool = tcp_buffer_pool();
+    if buffer_size == tcp_pool.buffer_size {
+        return tcp_pool;
+    }
+
+    let mut pools = SIZED_TCP_BUFFER_POOLS.lock().unwrap();
+    if let Some(pool) = pools.iter().find(|p| p.buffer_size == buffer_size) {
+        return pool;
+    }
+
+    let pool: &'static BufferPool = Box::leak(Box::new(BufferPool::new(buffer_size, tcp_pool.max_buffers)));
+    pools.push(pool);
+    pool
+}
@@ -2,6 +2,9 @@
 
 pub use self::socks5::Address;
 
+pub mod buffer_pool;
+pub mod mux;
 pub mod socks5;
 pub mod tcprelay;
+pub mod udp_over_tcp;
 pub mod udprelay;
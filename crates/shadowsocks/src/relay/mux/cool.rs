@@ -0,0 +1,652 @@
+//! Mux.Cool, multiplexing sessions in one connection as v2ray's `mux` option does
+//!
+//! ```plain
+//! +-------------------+-----------------+-------------------------------+
+//! | Metadata Len (u16)| Metadata        | Data Len (u16) + Data         |
+//! +-------------------+-----------------+-------------------------------+
+//!
+//! Metadata: Session ID (u16), Status (u8), Option (u8), [Network (u8), Port (u16), Address] for New
+//! ```
+
+use std::{
+    collections::HashMap,
+    future::Future,
+    io::{self, ErrorKind},
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{self, Poll},
+    time::Duration,
+};
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use futures::{ready, FutureExt};
+use log::{debug, trace};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf},
+    sync::{mpsc, Mutex as AsyncMutex, OwnedSemaphorePermit, Semaphore},
+    time,
+};
+
+use crate::relay::socks5::Address;
+
+const STATUS_NEW: u8 = 0x01;
+const STATUS_KEEP: u8 = 0x02;
+const STATUS_END: u8 = 0x03;
+const STATUS_KEEP_ALIVE: u8 = 0x04;
+
+const OPTION_DATA: u8 = 0x01;
+const OPTION_ERROR: u8 = 0x02;
+
+const NETWORK_TCP: u8 = 0x01;
+const NETWORK_UDP: u8 = 0x02;
+
+const ADDRESS_IPV4: u8 = 0x01;
+const ADDRESS_DOMAIN: u8 = 0x02;
+const ADDRESS_IPV6: u8 = 0x03;
+
+/// Maximum data length in one frame
+const MAX_FRAME_DATA: usize = 8 * 1024;
+/// Sessions opened in one connection before a new connection is made, the same as v2ray
+pub const MAX_CONNECTION_SESSIONS: usize = 128;
+/// Frames buffered for a session before the connection stops reading
+const SESSION_READ_BUFFER: usize = 16;
+/// Frames buffered for writing before sessions have to wait
+const CONNECTION_WRITE_BUFFER: usize = 64;
+
+/// Object safe `AsyncRead + AsyncWrite` carrying a Mux.Cool connection
+pub trait MuxIo: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T> MuxIo for T where T: AsyncRead + AsyncWrite + Send + Unpin {}
+
+/// Type erased stream carrying a Mux.Cool connection
+pub type BoxedMuxIo = Box<dyn MuxIo>;
+
+/// Information of the connection carrying sessions, shared by them
+pub trait MuxInfo: Send + Sync + 'static {
+    /// Address of the other side of the connection, for logging
+    fn peer_addr(&self) -> SocketAddr;
+}
+
+/// Addresses of the connection carrying sessions
+#[derive(Debug, Clone, Copy)]
+pub struct ConnectionAddr {
+    /// Local address of the connection
+    pub local_addr: SocketAddr,
+    /// Peer address of the connection
+    pub peer_addr: SocketAddr,
+}
+
+impl MuxInfo for ConnectionAddr {
+    fn peer_addr(&self) -> SocketAddr {
+        self.peer_addr
+    }
+}
+
+/// Checks if `buf`, the first bytes of a connection, look like a Mux.Cool New frame
+///
+/// Shadowsocks streams start with a random salt, so a mismatch in any of the fixed fields rules out Mux.Cool.
+/// Returns `None` if more bytes are needed to decide.
+pub fn is_mux_stream(buf: &[u8]) -> Option<bool> {
+    if buf.len() < 7 {
+        return None;
+    }
+
+    let metadata_len = u16::from_be_bytes([buf[0], buf[1]]) as usize;
+    Some(
+        (12..=264).contains(&metadata_len)
+            && buf[4] == STATUS_NEW
+            && buf[5] & !(OPTION_DATA | OPTION_ERROR) == 0
+            && (buf[6] == NETWORK_TCP || buf[6] == NETWORK_UDP),
+    )
+}
+
+fn encode_target(buf: &mut BytesMut, target: &Address) {
+    buf.put_u8(NETWORK_TCP);
+    match *target {
+        Address::SocketAddress(SocketAddr::V4(ref addr)) => {
+            buf.put_u16(addr.port());
+            buf.put_u8(ADDRESS_IPV4);
+            buf.put_slice(&addr.ip().octets());
+        }
+        Address::SocketAddress(SocketAddr::V6(ref addr)) => {
+            buf.put_u16(addr.port());
+            buf.put_u8(ADDRESS_IPV6);
+            buf.put_slice(&addr.ip().octets());
+        }
+        Address::DomainNameAddress(ref domain, port) => {
+            buf.put_u16(port);
+            buf.put_u8(ADDRESS_DOMAIN);
+            buf.put_u8(domain.len() as u8);
+            buf.put_slice(domain.as_bytes());
+        }
+    }
+}
+
+/// Parses Port and Address after Network in the metadata of a New frame
+fn decode_target(buf: &[u8]) -> Option<Address> {
+    if buf.len() < 3 {
+        return None;
+    }
+    let port = u16::from_be_bytes([buf[0], buf[1]]);
+    let addr = &buf[3..];
+
+    match buf[2] {
+        ADDRESS_IPV4 if addr.len() >= 4 => {
+            let ip = Ipv4Addr::new(addr[0], addr[1], addr[2], addr[3]);
+            Some(Address::SocketAddress(SocketAddr::new(ip.into(), port)))
+        }
+        ADDRESS_IPV6 if addr.len() >= 16 => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&addr[..16]);
+            Some(Address::SocketAddress(SocketAddr::new(
+                Ipv6Addr::from(octets).into(),
+                port,
+            )))
+        }
+        ADDRESS_DOMAIN if !addr.is_empty() && addr.len() > addr[0] as usize => {
+            let domain = String::from_utf8(addr[1..1 + addr[0] as usize].to_vec()).ok()?;
+            Some(Address::DomainNameAddress(domain, port))
+        }
+        _ => None,
+    }
+}
+
+fn encode_frame(id: u16, status: u8, option: u8, target: Option<&Address>, data: &[u8]) -> Bytes {
+    let mut metadata = BytesMut::with_capacity(4 + 1 + 2 + 1 + 256);
+    metadata.put_u16(id);
+    metadata.put_u8(status);
+    metadata.put_u8(option);
+    if let Some(target) = target {
+        encode_target(&mut metadata, target);
+    }
+
+    let mut buf = BytesMut::with_capacity(2 + metadata.len() + 2 + data.len());
+    buf.put_u16(metadata.len() as u16);
+    buf.put_slice(&metadata);
+    if option & OPTION_DATA != 0 {
+        buf.put_u16(data.len() as u16);
+        buf.put_slice(data);
+    }
+    buf.freeze()
+}
+
+struct Frame {
+    id: u16,
+    status: u8,
+    option: u8,
+    network: u8,
+    target: Option<Address>,
+    data: Bytes,
+}
+
+/// Parses one frame from the front of `buf`, returns `None` if it is incomplete
+fn decode_frame(buf: &mut BytesMut) -> io::Result<Option<Frame>> {
+    if buf.len() < 2 {
+        return Ok(None);
+    }
+    let metadata_len = u16::from_be_bytes([buf[0], buf[1]]) as usize;
+    if metadata_len < 4 {
+        return Err(io::Error::new(ErrorKind::InvalidData, "mux frame with short metadata"));
+    }
+    if buf.len() < 2 + metadata_len {
+        return Ok(None);
+    }
+
+    let metadata = &buf[2..2 + metadata_len];
+    let id = u16::from_be_bytes([metadata[0], metadata[1]]);
+    let status = metadata[2];
+    let option = metadata[3];
+    let (network, target) = if status == STATUS_NEW && metadata_len > 4 {
+        (metadata[4], decode_target(&metadata[5..]))
+    } else {
+        (NETWORK_TCP, None)
+    };
+
+    let mut frame_len = 2 + metadata_len;
+    let data_len = if option & OPTION_DATA != 0 {
+        if buf.len() < frame_len + 2 {
+            return Ok(None);
+        }
+        let n = u16::from_be_bytes([buf[frame_len], buf[frame_len + 1]]) as usize;
+        frame_len += 2;
+        n
+    } else {
+        0
+    };
+    if buf.len() < frame_len + data_len {
+        return Ok(None);
+    }
+
+    buf.advance(frame_len);
+    let data = buf.split_to(data_len).freeze();
+
+    Ok(Some(Frame {
+        id,
+        status,
+        option,
+        network,
+        target,
+        data,
+    }))
+}
+
+type WriteItem = (Bytes, Option<OwnedSemaphorePermit>);
+
+struct MuxSessions {
+    readers: HashMap<u16, mpsc::Sender<Bytes>>,
+    next_id: u16,
+    opened: usize,
+    active: usize,
+    closed: bool,
+}
+
+struct MuxShared<I> {
+    sessions: Mutex<MuxSessions>,
+    frame_tx: mpsc::UnboundedSender<WriteItem>,
+    write_budget: Arc<Semaphore>,
+    info: I,
+}
+
+impl<I: MuxInfo> MuxShared<I> {
+    fn new(info: I) -> (Arc<MuxShared<I>>, mpsc::UnboundedReceiver<WriteItem>) {
+        let (frame_tx, frame_rx) = mpsc::unbounded_channel();
+        let shared = MuxShared {
+            sessions: Mutex::new(MuxSessions {
+                readers: HashMap::new(),
+                next_id: 1,
+                opened: 0,
+                active: 0,
+                closed: false,
+            }),
+            frame_tx,
+            write_budget: Arc::new(Semaphore::new(CONNECTION_WRITE_BUFFER)),
+            info,
+        };
+        (Arc::new(shared), frame_rx)
+    }
+
+    /// Registers a session, `id` is allocated if it is `None`
+    ///
+    /// Fails if the connection is closed, or has `limit` active sessions or too many opened
+    fn add_session(
+        self: &Arc<Self>,
+        id: Option<u16>,
+        limit: Option<usize>,
+        target: Option<Address>,
+    ) -> Option<MuxStream<I>> {
+        let mut sessions = self.sessions.lock().unwrap();
+        if sessions.closed {
+            return None;
+        }
+        if let Some(limit) = limit {
+            if sessions.active >= limit || sessions.opened >= MAX_CONNECTION_SESSIONS {
+                return None;
+            }
+        }
+        let id = match id {
+            Some(id) => id,
+            None => {
+                let id = sessions.next_id;
+                sessions.next_id = sessions.next_id.wrapping_add(1).max(1);
+                id
+            }
+        };
+
+        let (tx, rx) = mpsc::channel(SESSION_READ_BUFFER);
+        sessions.readers.insert(id, tx);
+        sessions.opened += 1;
+        sessions.active += 1;
+        drop(sessions);
+
+        Some(MuxStream {
+            id,
+            shared: self.clone(),
+            target,
+            rx,
+            read_pending: Bytes::new(),
+            write_permit: None,
+            end_sent: false,
+        })
+    }
+
+    fn is_idle(&self) -> bool {
+        self.sessions.lock().unwrap().active == 0
+    }
+
+    fn close(&self) {
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.closed = true;
+        sessions.readers.clear();
+    }
+}
+
+/// Runs a Mux.Cool connection until it is closed
+///
+/// Sessions opened by the peer are passed to `on_new`
+async fn run_connection<I, F>(
+    shared: Arc<MuxShared<I>>,
+    io: BoxedMuxIo,
+    buffered: BytesMut,
+    mut frame_rx: mpsc::UnboundedReceiver<WriteItem>,
+    mut on_new: F,
+    idle_timeout: Option<Duration>,
+) where
+    I: MuxInfo,
+    F: FnMut(MuxStream<I>),
+{
+    let peer_addr = shared.info.peer_addr();
+    let (mut reader, mut writer) = tokio::io::split(io);
+
+    let read_fut = async {
+        let mut buf = buffered;
+        loop {
+            while let Some(frame) = decode_frame(&mut buf)? {
+                match frame.status {
+                    STATUS_NEW => {
+                        if frame.network != NETWORK_TCP {
+                            debug!("mux session {} with unsupported network {}", frame.id, frame.network);
+                            let _ = shared
+                                .frame_tx
+                                .send((encode_frame(frame.id, STATUS_END, OPTION_ERROR, None, &[]), None));
+                            continue;
+                        }
+
+                        if let Some(stream) = shared.add_session(Some(frame.id), None, frame.target) {
+                            trace!("mux session {} opened by peer {}", frame.id, peer_addr);
+                            on_new(stream);
+                        }
+                    }
+                    STATUS_KEEP | STATUS_KEEP_ALIVE => {}
+                    STATUS_END => {
+                        if frame.option & OPTION_ERROR != 0 {
+                            debug!("mux session {} ended by peer with error", frame.id);
+                        }
+                        // Dropping the sender makes the session's reader reach EOF
+                        shared.sessions.lock().unwrap().readers.remove(&frame.id);
+                        continue;
+                    }
+                    status => {
+                        return Err(io::Error::new(
+                            ErrorKind::InvalidData,
+                            format!("mux frame with unknown status {:#x}", status),
+                        ));
+                    }
+                }
+
+                if !frame.data.is_empty() {
+                    let tx = shared.sessions.lock().unwrap().readers.get(&frame.id).cloned();
+                    if let Some(tx) = tx {
+                        // Session may have been dropped, data are discarded
+                        let _ = tx.send(frame.data).await;
+                    }
+                }
+            }
+
+            if reader.read_buf(&mut buf).await? == 0 {
+                return Ok::<_, io::Error>(());
+            }
+        }
+    };
+
+    let write_fut = async {
+        while let Some(mut item) = frame_rx.recv().await {
+            // Frames already queued are written together before flushing
+            loop {
+                let (frame, permit) = item;
+                writer.write_all(&frame).await?;
+                drop(permit);
+
+                match frame_rx.recv().now_or_never() {
+                    Some(Some(next)) => item = next,
+                    _ => break,
+                }
+            }
+            writer.flush().await?;
+        }
+        Ok::<_, io::Error>(())
+    };
+
+    let idle_fut = async {
+        match idle_timeout {
+            None => futures::future::pending().await,
+            Some(timeout) => {
+                let mut idle_ticks = 0;
+                loop {
+                    time::sleep(timeout / 2).await;
+                    if shared.is_idle() {
+                        idle_ticks += 1;
+                        if idle_ticks >= 2 {
+                            break;
+                        }
+                    } else {
+                        idle_ticks = 0;
+                    }
+                }
+            }
+        }
+    };
+
+    tokio::select! {
+        r = read_fut => {
+            if let Err(err) = r {
+                debug!("mux connection {} read failed, error: {}", peer_addr, err);
+            }
+        }
+        r = write_fut => {
+            if let Err(err) = r {
+                debug!("mux connection {} write failed, error: {}", peer_addr, err);
+            }
+        }
+        _ = idle_fut => {
+            trace!("mux connection {} closed for being idle", peer_addr);
+        }
+    }
+
+    shared.close();
+    let _ = writer.shutdown().await;
+}
+
+/// A session in a Mux.Cool connection
+pub struct MuxStream<I> {
+    id: u16,
+    shared: Arc<MuxShared<I>>,
+    target: Option<Address>,
+    rx: mpsc::Receiver<Bytes>,
+    read_pending: Bytes,
+    write_permit: Option<Pin<Box<dyn Future<Output = Option<OwnedSemaphorePermit>> + Send>>>,
+    end_sent: bool,
+}
+
+impl<I> MuxStream<I> {
+    /// Information of the connection carrying this session
+    pub fn info(&self) -> &I {
+        &self.shared.info
+    }
+
+    /// Target address in the New frame, for sessions opened by the peer
+    pub fn target(&self) -> Option<&Address> {
+        self.target.as_ref()
+    }
+
+    fn send_frame(&self, frame: Bytes, permit: Option<OwnedSemaphorePermit>) -> io::Result<()> {
+        self.shared
+            .frame_tx
+            .send((frame, permit))
+            .map_err(|_| io::Error::new(ErrorKind::BrokenPipe, "mux connection closed"))
+    }
+
+    fn poll_write_permit(&mut self, cx: &mut task::Context<'_>) -> Poll<io::Result<OwnedSemaphorePermit>> {
+        let fut = self.write_permit.get_or_insert_with(|| {
+            let budget = self.shared.write_budget.clone();
+            Box::pin(async move { budget.acquire_owned().await.ok() })
+        });
+
+        let permit = ready!(fut.as_mut().poll(cx));
+        self.write_permit = None;
+
+        match permit {
+            Some(p) => Ok(p).into(),
+            None => Err(io::Error::new(ErrorKind::BrokenPipe, "mux connection closed")).into(),
+        }
+    }
+}
+
+impl<I> Drop for MuxStream<I> {
+    fn drop(&mut self) {
+        if !self.end_sent {
+            let _ = self.send_frame(encode_frame(self.id, STATUS_END, 0, None, &[]), None);
+        }
+
+        let mut sessions = self.shared.sessions.lock().unwrap();
+        sessions.readers.remove(&self.id);
+        sessions.active -= 1;
+    }
+}
+
+impl<I> AsyncRead for MuxStream<I> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut task::Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        if this.read_pending.is_empty() {
+            match ready!(this.rx.poll_recv(cx)) {
+                Some(data) => this.read_pending = data,
+                None => return Ok(()).into(),
+            }
+        }
+
+        let n = this.read_pending.len().min(buf.remaining());
+        buf.put_slice(&this.read_pending[..n]);
+        this.read_pending.advance(n);
+
+        Ok(()).into()
+    }
+}
+
+impl<I> AsyncWrite for MuxStream<I> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut task::Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+
+        if this.end_sent {
+            return Err(io::Error::new(ErrorKind::BrokenPipe, "mux session has been shut down")).into();
+        }
+        if buf.is_empty() {
+            return Ok(0).into();
+        }
+
+        let permit = ready!(this.poll_write_permit(cx))?;
+
+        let n = buf.len().min(MAX_FRAME_DATA);
+        this.send_frame(
+            encode_frame(this.id, STATUS_KEEP, OPTION_DATA, None, &buf[..n]),
+            Some(permit),
+        )?;
+
+        Ok(n).into()
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        // Frames are written and flushed by the connection
+        Ok(()).into()
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        if !this.end_sent {
+            this.end_sent = true;
+            this.send_frame(encode_frame(this.id, STATUS_END, 0, None, &[]), None)?;
+        }
+
+        Ok(()).into()
+    }
+}
+
+/// Client side connections of Mux.Cool, opening at most `concurrency` sessions in each of them
+///
+/// Connections without sessions for `idle_timeout` are closed.
+pub struct MuxPool<I> {
+    concurrency: usize,
+    idle_timeout: Duration,
+    connections: Mutex<Vec<Arc<MuxShared<I>>>>,
+    dial_lock: AsyncMutex<()>,
+}
+
+impl<I: MuxInfo> MuxPool<I> {
+    /// Creates an empty pool
+    pub fn new(concurrency: usize, idle_timeout: Duration) -> MuxPool<I> {
+        MuxPool {
+            concurrency,
+            idle_timeout,
+            connections: Mutex::new(Vec::new()),
+            dial_lock: AsyncMutex::new(()),
+        }
+    }
+
+    /// Opens a session in a connection with free slots
+    fn open_available(&self) -> Option<MuxStream<I>> {
+        let mut connections = self.connections.lock().unwrap();
+        connections.retain(|c| !c.sessions.lock().unwrap().closed);
+        connections
+            .iter()
+            .find_map(|c| c.add_session(None, Some(self.concurrency), None))
+    }
+
+    /// Opens a session to `target`, on an existing connection or a new one made by `connect`
+    pub async fn open<F, Fut>(&self, target: &Address, connect: F) -> io::Result<MuxStream<I>>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = io::Result<(BoxedMuxIo, I)>>,
+    {
+        let stream = match self.open_available() {
+            Some(stream) => stream,
+            None => {
+                // Concurrent opens wait for one dial and share it, instead of making a connection each
+                let _dial_guard = self.dial_lock.lock().await;
+
+                match self.open_available() {
+                    Some(stream) => stream,
+                    None => {
+                        let (io, info) = connect().await?;
+                        let (shared, frame_rx) = MuxShared::new(info);
+
+                        trace!("mux connection {} established", shared.info.peer_addr());
+
+                        tokio::spawn(run_connection(
+                            shared.clone(),
+                            io,
+                            BytesMut::new(),
+                            frame_rx,
+                            |stream| {
+                                // Servers are not supposed to open sessions
+                                drop(stream);
+                            },
+                            Some(self.idle_timeout),
+                        ));
+
+                        self.connections.lock().unwrap().push(shared.clone());
+
+                        match shared.add_session(None, None, None) {
+                            Some(s) => s,
+                            None => return Err(io::Error::new(ErrorKind::BrokenPipe, "mux connection closed")),
+                        }
+                    }
+                }
+            }
+        };
+
+        stream.send_frame(encode_frame(stream.id, STATUS_NEW, 0, Some(target), &[]), None)?;
+
+        Ok(stream)
+    }
+}
+
+/// Serves a Mux.Cool connection accepted by a server, sessions opened by the client are passed to `on_new`
+pub async fn serve_connection<I, F>(io: BoxedMuxIo, info: I, buffered: BytesMut, on_new: F)
+where
+    I: MuxInfo,
+    F: FnMut(MuxStream<I>),
+{
+    let (shared, frame_rx) = MuxShared::new(info);
+    run_connection(shared, io, buffered, frame_rx, on_new, None).await
+}
@@ -0,0 +1,103 @@
+//! Multiplexing TCP streams in shadowsocks streams
+//!
+//! Client connects to the special target address [`MUX_DOMAIN`]:[`MUX_PORT`], the same as v2ray's, and then the
+//! stream carries Mux.Cool frames. Every session in it is a TCP stream to the target address of its New frame.
+//!
+//! ```plain
+//! +------------+                                            +------------+
+//! |  SS Client +-- SS Stream [Mux.Cool] (Public Internet) --+  SS Server |
+//! +------------+                                            +------------+
+//! ```
+//!
+//! Sessions share the handshake and the salt of the stream, so opening them costs no round trips.
+
+use std::{
+    fmt::{self, Debug},
+    sync::Arc,
+    time::Duration,
+};
+
+pub use self::cool::{
+    is_mux_stream,
+    serve_connection,
+    BoxedMuxIo,
+    ConnectionAddr,
+    MuxInfo,
+    MuxIo,
+    MuxPool,
+    MuxStream,
+    MAX_CONNECTION_SESSIONS,
+};
+
+use super::socks5::Address;
+
+mod cool;
+
+/// Target domain name for requesting a Mux.Cool stream
+pub const MUX_DOMAIN: &str = "v1.mux.cool";
+/// Target port for requesting a Mux.Cool stream
+pub const MUX_PORT: u16 = 9527;
+
+/// Default streams opened concurrently in one connection, the same as v2ray's `concurrency`
+pub const DEFAULT_MAX_STREAMS: usize = 8;
+/// Default time before connections without streams are closed
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Target address for requesting a Mux.Cool stream
+pub fn mux_address() -> Address {
+    Address::DomainNameAddress(MUX_DOMAIN.to_owned(), MUX_PORT)
+}
+
+/// Check if `addr` is requesting a Mux.Cool stream
+pub fn is_mux_address(addr: &Address) -> bool {
+    matches!(*addr, Address::DomainNameAddress(ref dname, MUX_PORT) if dname == MUX_DOMAIN)
+}
+
+/// Multiplexing configuration of a server
+///
+/// - `max_streams`: streams opened concurrently in one connection by clients
+/// - `idle_timeout`: connections without streams for this long are closed by clients
+///
+/// Servers with it accept multiplexed streams from clients.
+#[derive(Clone)]
+pub struct MuxConfig {
+    max_streams: usize,
+    idle_timeout: Duration,
+    pool: Arc<MuxPool<ConnectionAddr>>,
+}
+
+impl Debug for MuxConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MuxConfig")
+            .field("max_streams", &self.max_streams)
+            .field("idle_timeout", &self.idle_timeout)
+            .finish()
+    }
+}
+
+impl MuxConfig {
+    /// Creates a configuration, `max_streams` is limited to [`MAX_CONNECTION_SESSIONS`]
+    pub fn new(max_streams: usize, idle_timeout: Duration) -> MuxConfig {
+        let max_streams = max_streams.clamp(1, MAX_CONNECTION_SESSIONS);
+        MuxConfig {
+            max_streams,
+            idle_timeout,
+            pool: Arc::new(MuxPool::new(max_streams, idle_timeout)),
+        }
+    }
+
+    /// Streams opened concurrently in one connection
+    pub fn max_streams(&self) -> usize {
+        self.max_streams
+    }
+
+    /// Connections without streams for this long are closed
+    pub fn idle_timeout(&self) -> Duration {
+        self.idle_timeout
+    }
+
+    /// Connections to the server, shared by all clones of this configuration
+    pub fn pool(&self) -> &MuxPool<ConnectionAddr> {
+        &self.pool
+    }
+}
@@ -7,7 +7,6 @@ use std::{
     fmt::{self, Debug, Display, Formatter},
     io::{self, ErrorKind},
     net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6, ToSocketAddrs},
-    slice,
     str::FromStr,
     vec,
 };
@@ -228,29 +227,17 @@ impl Address {
                 let _ = stream.read_exact(&mut buf).await?;
 
                 let v4addr = Ipv4Addr::new(buf[0], buf[1], buf[2], buf[3]);
-                let port = unsafe {
-                    let raw_port = &buf[4..];
-                    u16::from_be(*(raw_port.as_ptr() as *const _))
-                };
+                let port = u16::from_be_bytes([buf[4], buf[5]]);
                 Ok(Address::SocketAddress(SocketAddr::V4(SocketAddrV4::new(v4addr, port))))
             }
             consts::SOCKS5_ADDR_TYPE_IPV6 => {
                 let mut buf = [0u8; 18];
                 let _ = stream.read_exact(&mut buf).await?;
 
-                let buf: &[u16] = unsafe { slice::from_raw_parts(buf.as_ptr() as *const _, 9) };
-
-                let v6addr = Ipv6Addr::new(
-                    u16::from_be(buf[0]),
-                    u16::from_be(buf[1]),
-                    u16::from_be(buf[2]),
-                    u16::from_be(buf[3]),
-                    u16::from_be(buf[4]),
-                    u16::from_be(buf[5]),
-                    u16::from_be(buf[6]),
-                    u16::from_be(buf[7]),
-                );
-                let port = u16::from_be(buf[8]);
+                let mut raw_v6addr = [0u8; 16];
+                raw_v6addr.copy_from_slice(&buf[..16]);
+                let v6addr = Ipv6Addr::from(raw_v6addr);
+                let port = u16::from_be_bytes([buf[16], buf[17]]);
 
                 Ok(Address::SocketAddress(SocketAddr::V6(SocketAddrV6::new(
                     v6addr, port, 0, 0,
@@ -268,7 +255,7 @@ impl Address {
                 let _ = stream.read_exact(&mut raw_addr).await?;
 
                 let raw_port = &raw_addr[length..];
-                let port = unsafe { u16::from_be(*(raw_port.as_ptr() as *const _)) };
+                let port = u16::from_be_bytes([raw_port[0], raw_port[1]]);
 
                 raw_addr.truncate(length);
 
@@ -918,7 +905,7 @@ impl PasswdAuthResponse {
         R: AsyncRead + Unpin,
     {
         let mut buf = [0u8; 2];
-        let _ = r.read_exact(&mut buf);
+        let _ = r.read_exact(&mut buf).await?;
 
         if buf[0] != 0x01 {
             return Err(Error::UnsupportedPasswdAuthVersion(buf[0]));
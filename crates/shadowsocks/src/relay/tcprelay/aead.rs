@@ -48,7 +48,7 @@ use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
 
 use crate::{
     context::Context,
-    crypto::v1::{Cipher, CipherKind},
+    crypto::{v1::Cipher, CipherKind},
 };
 
 /// AEAD packet payload must be smaller than 0x3FFF
@@ -68,6 +68,7 @@ pub struct DecryptedReader {
     buffer: BytesMut,
     method: CipherKind,
     salt: Option<Bytes>,
+    nonce_replayed: bool,
 }
 
 impl DecryptedReader {
@@ -81,6 +82,7 @@ impl DecryptedReader {
                 buffer: BytesMut::with_capacity(method.salt_len()),
                 method,
                 salt: None,
+                nonce_replayed: false,
             }
         } else {
             DecryptedReader {
@@ -89,10 +91,16 @@ impl DecryptedReader {
                 buffer: BytesMut::with_capacity(2 + method.tag_len()),
                 method,
                 salt: None,
+                nonce_replayed: false,
             }
         }
     }
 
+    /// Check if salt of this stream is a repeated one (`ReplayAttackPolicy::Detect`)
+    pub fn nonce_replayed(&self) -> bool {
+        self.nonce_replayed
+    }
+
     /// Attempt to read decrypted data from stream
     pub fn poll_read_decrypted<S>(
         &mut self,
@@ -221,7 +229,7 @@ impl DecryptedReader {
         // Check repeated salt after first successful decryption #442
         if self.salt.is_some() {
             let salt = self.salt.take().unwrap();
-            context.check_nonce_replay(&salt)?;
+            self.nonce_replayed = context.check_nonce_replayed(&salt)?;
         }
 
         // Remote TAG
@@ -0,0 +1,698 @@
+//! AEAD 2022 packet I/O facilities
+//!
+//! AEAD 2022 protocol is defined in <https://github.com/shadowsocks/shadowsocks-org/issues/196>.
+//!
+//! ```plain
+//! TCP request fixed-length header (before encryption)
+//! +------+-----------+--------------------------+
+//! | TYPE | TIMESTAMP | Variable-Length Header Len |
+//! +------+-----------+--------------------------+
+//! |  1   |     8     |             2            |
+//! +------+-----------+--------------------------+
+//!
+//! TCP request variable-length header (before encryption)
+//! +------+---------------------+------------------+-------------+---------+-----------------+
+//! | ATYP | Destination Address | Destination Port | Padding Len | Padding | Initial Payload |
+//! +------+---------------------+------------------+-------------+---------+-----------------+
+//! |  1   |       Variable      |         2        |      2      | Variable|     Variable    |
+//! +------+---------------------+------------------+-------------+---------+-----------------+
+//!
+//! TCP response fixed-length header (before encryption)
+//! +------+-----------+--------------+-------------------+
+//! | TYPE | TIMESTAMP | Request Salt | First Chunk Len   |
+//! +------+-----------+--------------+-------------------+
+//! |  1   |     8     |     Fixed    |         2         |
+//! +------+-----------+--------------+-------------------+
+//!
+//! TCP stream (after encryption, *ciphertext*)
+//! +--------+------------------------+-----------+------------------------+-----------+---------------+
+//! |  SALT  | *Fixed-Length Header*  |    TAG    | *Variable-Length Header* |   TAG   | Chunks ...    |
+//! +--------+------------------------+-----------+------------------------+-----------+---------------+
+//! ```
+//!
+//! Chunks are the same as AEAD protocol's, except that data in a chunk could be at most 0xFFFF bytes.
+//! Keys are derived from the pre-shared key and salt with BLAKE3, and nonces are counters starting from 0.
+//!
+//! Requests to multi-user servers have 16 bytes Extensible Identity Headers
+//! ([SIP023](https://github.com/Shadowsocks-NET/shadowsocks-specs/blob/main/2022-2-shadowsocks-2022-extensible-identity-headers.md))
+//! between SALT and the fixed-length header, one for each identity key (iPSK). An identity header is the hash of
+//! the next key encrypted by AES with a subkey derived from the identity key and salt. Servers find the user by
+//! the hash, then the rest of the request and the response are encrypted with the user's key (uPSK).
+
+use std::{
+    io::{self, ErrorKind},
+    marker::Unpin,
+    pin::Pin,
+    slice,
+    sync::Arc,
+    task::{self, Poll},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use aes::{Aes128, Aes256, Block, BlockDecrypt, BlockEncrypt, NewBlockCipher};
+use byte_string::ByteStr;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use futures::ready;
+use log::trace;
+use rand::Rng;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::{
+    config::{ServerUser, ServerUserManager},
+    context::Context,
+    crypto::{v2::tcp::TcpCipher, CipherKind},
+};
+
+use super::crypto_io::StreamType;
+
+/// AEAD 2022 chunk payload must be smaller than 0xFFFF
+pub const MAX_PACKET_SIZE: usize = 0xFFFF;
+
+const HEADER_TYPE_CLIENT_STREAM: u8 = 0;
+const HEADER_TYPE_SERVER_STREAM: u8 = 1;
+
+// Requests without initial payloads are padded with 1 ~ 900 bytes
+const MIN_PADDING_SIZE: usize = 1;
+const MAX_PADDING_SIZE: usize = 900;
+
+/// Length of an Extensible Identity Header
+pub(crate) const IDENTITY_HEADER_LEN: usize = 16;
+const IDENTITY_SUBKEY_CONTEXT: &str = "shadowsocks 2022 identity subkey";
+
+/// Encrypt a 16 bytes `block` in place by AES of `method`
+pub(crate) fn aes_encrypt_block(method: CipherKind, key: &[u8], block: &mut [u8]) {
+    let block = Block::from_mut_slice(block);
+    match method {
+        CipherKind::AEAD2022_BLAKE3_AES_128_GCM => Aes128::new_from_slice(key).expect("aes key").encrypt_block(block),
+        CipherKind::AEAD2022_BLAKE3_AES_256_GCM => Aes256::new_from_slice(key).expect("aes key").encrypt_block(block),
+        _ => unreachable!("{} isn't an AES method", method),
+    }
+}
+
+/// Decrypt a 16 bytes `block` in place by AES of `method`
+pub(crate) fn aes_decrypt_block(method: CipherKind, key: &[u8], block: &mut [u8]) {
+    let block = Block::from_mut_slice(block);
+    match method {
+        CipherKind::AEAD2022_BLAKE3_AES_128_GCM => Aes128::new_from_slice(key).expect("aes key").decrypt_block(block),
+        CipherKind::AEAD2022_BLAKE3_AES_256_GCM => Aes256::new_from_slice(key).expect("aes key").decrypt_block(block),
+        _ => unreachable!("{} isn't an AES method", method),
+    }
+}
+
+/// Hash of `key` in identity headers
+pub(crate) fn identity_hash(key: &[u8]) -> [u8; IDENTITY_HEADER_LEN] {
+    let mut hash = [0u8; IDENTITY_HEADER_LEN];
+    hash.copy_from_slice(&blake3::hash(key).as_bytes()[..IDENTITY_HEADER_LEN]);
+    hash
+}
+
+// Key of identity headers of a stream, derived from the identity key and salt
+fn identity_subkey(method: CipherKind, identity_key: &[u8], salt: &[u8]) -> Vec<u8> {
+    let mut key_material = Vec::with_capacity(identity_key.len() + salt.len());
+    key_material.extend_from_slice(identity_key);
+    key_material.extend_from_slice(salt);
+
+    let subkey = blake3::derive_key(IDENTITY_SUBKEY_CONTEXT, &key_material);
+    subkey[..method.key_len()].to_vec()
+}
+
+/// Current UNIX timestamp in seconds
+pub(crate) fn get_now_timestamp() -> u64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_secs(),
+        Err(..) => 0,
+    }
+}
+
+/// Length of the socks5 address at the beginning of `buf`
+pub(crate) fn address_len(buf: &[u8]) -> io::Result<usize> {
+    let len = match buf.first() {
+        Some(0x01) => 1 + 4 + 2,
+        Some(0x04) => 1 + 16 + 2,
+        Some(0x03) if buf.len() > 1 => 1 + 1 + buf[1] as usize + 2,
+        _ => return Err(io::Error::new(ErrorKind::InvalidData, "invalid address in header")),
+    };
+    if buf.len() < len {
+        return Err(io::Error::new(ErrorKind::InvalidData, "incomplete address in header"));
+    }
+    Ok(len)
+}
+
+enum DecryptReadState {
+    ReadSalt,
+    ReadFixedHeader,
+    ReadVariableHeader { length: usize },
+    ReadLength,
+    ReadData { length: usize },
+    BufferedData { pos: usize },
+}
+
+/// Reader wrapper that will decrypt data automatically
+pub struct DecryptedReader {
+    stream_ty: StreamType,
+    state: DecryptReadState,
+    cipher: Option<TcpCipher>,
+    buffer: BytesMut,
+    method: CipherKind,
+    key: Bytes,
+    // Client: salt sent in request, which must be echoed by server
+    // Server: salt received in request, which will be echoed in response
+    request_salt: Option<Bytes>,
+    // Server: users of multi-user servers and the user identified by the request
+    user_manager: Option<Arc<ServerUserManager>>,
+    user: Option<Arc<ServerUser>>,
+}
+
+impl DecryptedReader {
+    /// Create a reader, `request_salt` should be the salt sent by a `Client` stream
+    pub fn new(stream_ty: StreamType, method: CipherKind, key: &[u8], request_salt: Option<&[u8]>) -> DecryptedReader {
+        DecryptedReader {
+            stream_ty,
+            state: DecryptReadState::ReadSalt,
+            cipher: None,
+            buffer: BytesMut::with_capacity(method.salt_len()),
+            method,
+            key: Bytes::copy_from_slice(key),
+            request_salt: request_salt.map(Bytes::copy_from_slice),
+            user_manager: None,
+            user: None,
+        }
+    }
+
+    /// Create a reader of a `Server` stream, which identifies users of `user_manager` by identity headers
+    ///
+    /// `key` is the identity key (iPSK) of the server
+    pub fn with_user_manager(method: CipherKind, key: &[u8], user_manager: Arc<ServerUserManager>) -> DecryptedReader {
+        let mut reader = DecryptedReader::new(StreamType::Server, method, key, None);
+        reader.user_manager = Some(user_manager);
+        reader
+    }
+
+    /// User identified by the request received by a `Server` stream, only valid after the header was read
+    pub fn user(&self) -> Option<&Arc<ServerUser>> {
+        self.user.as_ref()
+    }
+
+    /// Salt of the request received by a `Server` stream, only valid after the header was read
+    pub fn request_salt(&self) -> Option<&[u8]> {
+        match self.stream_ty {
+            StreamType::Server => self.request_salt.as_deref(),
+            StreamType::Client => None,
+        }
+    }
+
+    /// Attempt to read decrypted data from stream
+    pub fn poll_read_decrypted<S>(
+        &mut self,
+        cx: &mut task::Context<'_>,
+        context: &Context,
+        stream: &mut S,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>>
+    where
+        S: AsyncRead + Unpin + ?Sized,
+    {
+        loop {
+            match self.state {
+                DecryptReadState::ReadSalt => {
+                    if !ready!(self.poll_read_salt(cx, stream))? {
+                        return Ok(()).into();
+                    }
+
+                    self.buffer.clear();
+                    self.state = DecryptReadState::ReadFixedHeader;
+                }
+                DecryptReadState::ReadFixedHeader => {
+                    let length = ready!(self.poll_read_fixed_header(cx, context, stream))?;
+
+                    self.buffer.clear();
+                    self.state = DecryptReadState::ReadVariableHeader { length };
+                }
+                DecryptReadState::ReadVariableHeader { length } => {
+                    ready!(self.poll_read_data(cx, stream, length))?;
+
+                    if let StreamType::Server = self.stream_ty {
+                        self.strip_padding()?;
+                    }
+                    self.state = DecryptReadState::BufferedData { pos: 0 };
+                }
+                DecryptReadState::ReadLength => match ready!(self.poll_read_length(cx, stream))? {
+                    None => {
+                        return Ok(()).into();
+                    }
+                    Some(length) => {
+                        self.buffer.clear();
+                        self.state = DecryptReadState::ReadData { length };
+                    }
+                },
+                DecryptReadState::ReadData { length } => {
+                    ready!(self.poll_read_data(cx, stream, length))?;
+
+                    self.state = DecryptReadState::BufferedData { pos: 0 };
+                }
+                DecryptReadState::BufferedData { ref mut pos } => {
+                    if *pos < self.buffer.len() {
+                        let buffered = &self.buffer[*pos..];
+
+                        let consumed = usize::min(buffered.len(), buf.remaining());
+                        buf.put_slice(&buffered[..consumed]);
+
+                        *pos += consumed;
+
+                        return Ok(()).into();
+                    }
+
+                    self.buffer.clear();
+                    self.state = DecryptReadState::ReadLength;
+                }
+            }
+        }
+    }
+
+    // Returns `false` if the stream was closed before sending anything
+    fn poll_read_salt<S>(&mut self, cx: &mut task::Context<'_>, stream: &mut S) -> Poll<io::Result<bool>>
+    where
+        S: AsyncRead + Unpin + ?Sized,
+    {
+        let salt_len = self.method.salt_len();
+        let header_len = match self.user_manager {
+            Some(..) => salt_len + IDENTITY_HEADER_LEN,
+            None => salt_len,
+        };
+
+        let n = ready!(self.poll_read_exact(cx, stream, header_len))?;
+        if n == 0 {
+            return Ok(false).into();
+        }
+
+        let (salt, identity_header) = self.buffer[..header_len].split_at_mut(salt_len);
+        trace!("got AEAD 2022 salt {:?}", ByteStr::new(salt));
+
+        if let Some(ref user_manager) = self.user_manager {
+            let subkey = identity_subkey(self.method, &self.key, salt);
+            aes_decrypt_block(self.method, &subkey, identity_header);
+
+            let user = match user_manager.get_user_by_hash(identity_header) {
+                Some(user) => user,
+                None => {
+                    let err = io::Error::new(
+                        ErrorKind::InvalidData,
+                        format!("unknown user identity {:?}", ByteStr::new(identity_header)),
+                    );
+                    return Err(err).into();
+                }
+            };
+            trace!("got AEAD 2022 user {}", user.name());
+
+            self.cipher = Some(TcpCipher::new(self.method, user.key(), salt));
+            self.user = Some(user);
+        } else {
+            self.cipher = Some(TcpCipher::new(self.method, &self.key, salt));
+        }
+
+        if let StreamType::Server = self.stream_ty {
+            self.request_salt = Some(Bytes::copy_from_slice(salt));
+        }
+
+        Ok(true).into()
+    }
+
+    fn poll_read_fixed_header<S>(
+        &mut self,
+        cx: &mut task::Context<'_>,
+        context: &Context,
+        stream: &mut S,
+    ) -> Poll<io::Result<usize>>
+    where
+        S: AsyncRead + Unpin + ?Sized,
+    {
+        let header_len = match self.stream_ty {
+            StreamType::Server => 1 + 8 + 2,
+            StreamType::Client => 1 + 8 + self.method.salt_len() + 2,
+        };
+        let tag_len = self.method.tag_len();
+
+        let n = ready!(self.poll_read_exact(cx, stream, header_len + tag_len))?;
+        if n == 0 {
+            return Err(ErrorKind::UnexpectedEof.into()).into();
+        }
+
+        let cipher = self.cipher.as_mut().expect("cipher is None");
+        if !cipher.decrypt_packet(&mut self.buffer[..header_len + tag_len]) {
+            return Err(io::Error::new(ErrorKind::Other, "invalid tag-in")).into();
+        }
+
+        let mut header = &self.buffer[..header_len];
+
+        let (expected_type, header_name) = match self.stream_ty {
+            StreamType::Server => (HEADER_TYPE_CLIENT_STREAM, "request"),
+            StreamType::Client => (HEADER_TYPE_SERVER_STREAM, "response"),
+        };
+        let header_type = header.get_u8();
+        if header_type != expected_type {
+            let err = io::Error::new(
+                ErrorKind::InvalidData,
+                format!("invalid {} header type {}", header_name, header_type),
+            );
+            return Err(err).into();
+        }
+
+        context.check_timestamp_2022(header.get_u64())?;
+
+        match self.stream_ty {
+            StreamType::Server => {
+                // Salt is checked after the header was authenticated, so it couldn't be flooded by attackers
+                let salt = self.request_salt.as_ref().expect("request salt is None");
+                context.check_salt_replay_2022(salt)?;
+            }
+            StreamType::Client => {
+                let salt_len = self.method.salt_len();
+                let request_salt = &header[..salt_len];
+                if self.request_salt.as_deref() != Some(request_salt) {
+                    return Err(io::Error::new(
+                        ErrorKind::InvalidData,
+                        "response with a mismatched request salt",
+                    ))
+                    .into();
+                }
+                header.advance(salt_len);
+            }
+        }
+
+        Ok(header.get_u16() as usize).into()
+    }
+
+    fn poll_read_length<S>(&mut self, cx: &mut task::Context<'_>, stream: &mut S) -> Poll<io::Result<Option<usize>>>
+    where
+        S: AsyncRead + Unpin + ?Sized,
+    {
+        let length_len = 2 + self.method.tag_len();
+
+        let n = ready!(self.poll_read_exact(cx, stream, length_len))?;
+        if n == 0 {
+            return Ok(None).into();
+        }
+
+        let cipher = self.cipher.as_mut().expect("cipher is None");
+
+        let m = &mut self.buffer[..length_len];
+        if !cipher.decrypt_packet(m) {
+            return Err(io::Error::new(ErrorKind::Other, "invalid tag-in")).into();
+        }
+
+        Ok(Some(u16::from_be_bytes([m[0], m[1]]) as usize)).into()
+    }
+
+    fn poll_read_data<S>(&mut self, cx: &mut task::Context<'_>, stream: &mut S, size: usize) -> Poll<io::Result<()>>
+    where
+        S: AsyncRead + Unpin + ?Sized,
+    {
+        let data_len = size + self.method.tag_len();
+
+        let n = ready!(self.poll_read_exact(cx, stream, data_len))?;
+        if n == 0 {
+            return Err(ErrorKind::UnexpectedEof.into()).into();
+        }
+
+        let cipher = self.cipher.as_mut().expect("cipher is None");
+
+        let m = &mut self.buffer[..data_len];
+        if !cipher.decrypt_packet(m) {
+            return Err(io::Error::new(ErrorKind::Other, "invalid tag-in")).into();
+        }
+
+        // Remote TAG
+        self.buffer.truncate(size);
+
+        Ok(()).into()
+    }
+
+    // Variable-length header: ADDRESS + PADDING_LEN + PADDING + PAYLOAD => ADDRESS + PAYLOAD
+    fn strip_padding(&mut self) -> io::Result<()> {
+        let addr_len = address_len(&self.buffer)?;
+        if self.buffer.len() < addr_len + 2 {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "header too short for padding length",
+            ));
+        }
+
+        let padding_len = u16::from_be_bytes([self.buffer[addr_len], self.buffer[addr_len + 1]]) as usize;
+        let payload_start = addr_len + 2 + padding_len;
+        if self.buffer.len() < payload_start {
+            return Err(io::Error::new(ErrorKind::InvalidData, "header too short for padding"));
+        }
+
+        self.buffer.copy_within(payload_start.., addr_len);
+        let length = self.buffer.len() - 2 - padding_len;
+        self.buffer.truncate(length);
+
+        Ok(())
+    }
+
+    fn poll_read_exact<S>(&mut self, cx: &mut task::Context<'_>, stream: &mut S, size: usize) -> Poll<io::Result<usize>>
+    where
+        S: AsyncRead + Unpin + ?Sized,
+    {
+        assert!(size != 0);
+
+        self.buffer.reserve(size.saturating_sub(self.buffer.len()));
+        while self.buffer.len() < size {
+            let remaining = size - self.buffer.len();
+            let buffer = &mut self.buffer.chunk_mut()[..remaining];
+
+            let mut read_buf =
+                ReadBuf::uninit(unsafe { slice::from_raw_parts_mut(buffer.as_mut_ptr() as *mut _, remaining) });
+            ready!(Pin::new(&mut *stream).poll_read(cx, &mut read_buf))?;
+
+            let n = read_buf.filled().len();
+            if n == 0 {
+                if !self.buffer.is_empty() {
+                    return Err(ErrorKind::UnexpectedEof.into()).into();
+                } else {
+                    return Ok(0).into();
+                }
+            }
+
+            unsafe {
+                self.buffer.advance_mut(n);
+            }
+        }
+
+        Ok(size).into()
+    }
+}
+
+enum EncryptWriteState {
+    AssemblePacket,
+    Writing { pos: usize, length: usize },
+}
+
+/// Writer wrapper that will encrypt data automatically
+pub struct EncryptedWriter {
+    stream_ty: StreamType,
+    method: CipherKind,
+    cipher: TcpCipher,
+    buffer: BytesMut,
+    state: EncryptWriteState,
+    salt_len: usize,
+    header_sent: bool,
+    // Server: salt of the request, which is required by the response header
+    request_salt: Option<Bytes>,
+}
+
+impl EncryptedWriter {
+    /// Creates a new EncryptedWriter
+    pub fn new(stream_ty: StreamType, method: CipherKind, key: &[u8], nonce: &[u8]) -> EncryptedWriter {
+        EncryptedWriter::with_identity(stream_ty, method, key, nonce, &[])
+    }
+
+    /// Creates a new EncryptedWriter of a `Client` stream to a multi-user server, sending identity headers of
+    /// `identity_keys` (iPSKs) before the header encrypted by the user's `key` (uPSK)
+    pub fn with_identity(
+        stream_ty: StreamType,
+        method: CipherKind,
+        key: &[u8],
+        nonce: &[u8],
+        identity_keys: &[Bytes],
+    ) -> EncryptedWriter {
+        // nonce and identity headers should be sent with the first packet
+        let mut buffer = BytesMut::with_capacity(nonce.len() + identity_keys.len() * IDENTITY_HEADER_LEN);
+        buffer.put(nonce);
+
+        for (i, identity_key) in identity_keys.iter().enumerate() {
+            let next_key = match identity_keys.get(i + 1) {
+                Some(k) => k.as_ref(),
+                None => key,
+            };
+
+            let mut identity_header = identity_hash(next_key);
+            let subkey = identity_subkey(method, identity_key, nonce);
+            aes_encrypt_block(method, &subkey, &mut identity_header);
+            buffer.put_slice(&identity_header);
+        }
+
+        EncryptedWriter {
+            stream_ty,
+            method,
+            cipher: TcpCipher::new(method, key, nonce),
+            buffer,
+            state: EncryptWriteState::AssemblePacket,
+            salt_len: method.salt_len(),
+            header_sent: false,
+            request_salt: None,
+        }
+    }
+
+    /// Check if it is a `Server` stream waiting for the request's salt
+    pub fn need_request_salt(&self) -> bool {
+        matches!(self.stream_ty, StreamType::Server) && self.request_salt.is_none()
+    }
+
+    /// Set salt of the request, which is sent back in the response header
+    pub fn set_request_salt(&mut self, salt: &[u8]) {
+        self.request_salt = Some(Bytes::copy_from_slice(salt));
+    }
+
+    /// Encrypt the response of a `Server` stream with `key` of the user identified by the request
+    ///
+    /// It must be set before writing anything
+    pub fn set_user_key(&mut self, key: &[u8]) {
+        assert!(!self.header_sent, "user key is set after sending the header");
+        // Buffer keeps the salt before sending the header
+        self.cipher = TcpCipher::new(self.method, key, &self.buffer[..self.salt_len]);
+    }
+
+    /// Attempt to write encrypted data into the writer
+    pub fn poll_write_encrypted<S>(
+        &mut self,
+        cx: &mut task::Context<'_>,
+        stream: &mut S,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>>
+    where
+        S: AsyncWrite + Unpin + ?Sized,
+    {
+        loop {
+            match self.state {
+                EncryptWriteState::AssemblePacket => {
+                    let length = if self.header_sent {
+                        let length = usize::min(buf.len(), MAX_PACKET_SIZE);
+                        self.put_chunk(&buf[..length]);
+                        length
+                    } else {
+                        let length = match self.stream_ty {
+                            StreamType::Client => self.put_request_header(buf)?,
+                            StreamType::Server => self.put_response_header(buf)?,
+                        };
+                        self.header_sent = true;
+                        length
+                    };
+
+                    self.state = EncryptWriteState::Writing { pos: 0, length };
+                }
+                EncryptWriteState::Writing { ref mut pos, length } => {
+                    while *pos < self.buffer.len() {
+                        let n = ready!(Pin::new(&mut *stream).poll_write(cx, &self.buffer[*pos..]))?;
+                        if n == 0 {
+                            return Err(ErrorKind::UnexpectedEof.into()).into();
+                        }
+                        *pos += n;
+                    }
+
+                    // Reset state
+                    self.state = EncryptWriteState::AssemblePacket;
+                    self.buffer.clear();
+
+                    return Ok(length).into();
+                }
+            }
+        }
+    }
+
+    // First write of a client is ADDRESS + PAYLOAD, which are all consumed
+    fn put_request_header(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let addr_len =
+            address_len(buf).map_err(|_| io::Error::new(ErrorKind::InvalidInput, "request without address"))?;
+        let (addr, payload) = buf.split_at(addr_len);
+
+        let padding_len = if payload.is_empty() {
+            rand::thread_rng().gen_range(MIN_PADDING_SIZE..=MAX_PADDING_SIZE)
+        } else {
+            0
+        };
+        let initial_len = usize::min(payload.len(), MAX_PACKET_SIZE - addr_len - 2 - padding_len);
+        let (initial_payload, remaining) = payload.split_at(initial_len);
+
+        let variable_len = addr_len + 2 + padding_len + initial_len;
+
+        let mut header = BytesMut::with_capacity(1 + 8 + 2);
+        header.put_u8(HEADER_TYPE_CLIENT_STREAM);
+        header.put_u64(get_now_timestamp());
+        header.put_u16(variable_len as u16);
+        self.put_encrypted(&header);
+
+        let mut variable = BytesMut::with_capacity(variable_len);
+        variable.put_slice(addr);
+        variable.put_u16(padding_len as u16);
+        variable.put_bytes(0, padding_len);
+        variable.put_slice(initial_payload);
+        self.put_encrypted(&variable);
+
+        for chunk in remaining.chunks(MAX_PACKET_SIZE) {
+            self.put_chunk(chunk);
+        }
+
+        trace!(
+            "AEAD 2022 request header with {} bytes padding, {} bytes payload",
+            padding_len,
+            payload.len()
+        );
+
+        Ok(buf.len())
+    }
+
+    fn put_response_header(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let request_salt = match self.request_salt {
+            Some(ref s) => s.clone(),
+            None => {
+                return Err(io::Error::new(
+                    ErrorKind::Other,
+                    "response before receiving the request salt",
+                ))
+            }
+        };
+        debug_assert_eq!(request_salt.len(), self.salt_len);
+
+        let length = usize::min(buf.len(), MAX_PACKET_SIZE);
+
+        let mut header = BytesMut::with_capacity(1 + 8 + self.salt_len + 2);
+        header.put_u8(HEADER_TYPE_SERVER_STREAM);
+        header.put_u64(get_now_timestamp());
+        header.put_slice(&request_salt);
+        header.put_u16(length as u16);
+        self.put_encrypted(&header);
+
+        self.put_encrypted(&buf[..length]);
+
+        Ok(length)
+    }
+
+    fn put_chunk(&mut self, data: &[u8]) {
+        self.put_encrypted(&(data.len() as u16).to_be_bytes());
+        self.put_encrypted(data);
+    }
+
+    fn put_encrypted(&mut self, data: &[u8]) {
+        let tag_len = self.cipher.tag_len();
+        let size = data.len() + tag_len;
+        self.buffer.reserve(size);
+
+        let mbuf = &mut self.buffer.chunk_mut()[..size];
+        let mbuf = unsafe { slice::from_raw_parts_mut(mbuf.as_mut_ptr(), mbuf.len()) };
+
+        self.buffer.put_slice(data);
+        self.cipher.encrypt_packet(mbuf);
+        unsafe { self.buffer.advance_mut(tag_len) };
+    }
+}
@@ -1,5 +1,7 @@
 //! IO facilities for TCP relay
 
+#[cfg(feature = "aead-cipher-2022")]
+use std::sync::Arc;
 use std::{
     io,
     marker::Unpin,
@@ -8,18 +10,35 @@ use std::{
 };
 
 use byte_string::ByteStr;
+#[cfg(feature = "aead-cipher-2022")]
+use bytes::Bytes;
 use log::trace;
 use tokio::io::{AsyncRead, AsyncWrite, ReadBuf, ReadHalf, WriteHalf};
 
+#[cfg(feature = "aead-cipher-2022")]
+use crate::config::{ServerUser, ServerUserManager};
 use crate::{
     context::Context,
-    crypto::v1::{CipherCategory, CipherKind},
+    crypto::{CipherCategory, CipherKind},
 };
 
 use super::aead::{DecryptedReader as AeadDecryptedReader, EncryptedWriter as AeadEncryptedWriter};
+#[cfg(feature = "aead-cipher-2022")]
+use super::aead_2022::{DecryptedReader as Aead2022DecryptedReader, EncryptedWriter as Aead2022EncryptedWriter};
+#[cfg(feature = "stream-padding")]
+use super::padding::{PaddingConfig, PaddingReader, PaddingWriter};
 #[cfg(feature = "stream-cipher")]
 use super::stream::{DecryptedReader as StreamDecryptedReader, EncryptedWriter as StreamEncryptedWriter};
 
+/// Role of a stream in the shadowsocks' tunnel
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum StreamType {
+    /// Stream connected to a server
+    Client,
+    /// Stream accepted from a client
+    Server,
+}
+
 /// Reader for reading encrypted data stream from shadowsocks' tunnel
 #[allow(clippy::large_enum_variant)]
 pub enum DecryptedReader {
@@ -27,19 +46,56 @@ pub enum DecryptedReader {
     Aead(AeadDecryptedReader),
     #[cfg(feature = "stream-cipher")]
     Stream(StreamDecryptedReader),
+    #[cfg(feature = "aead-cipher-2022")]
+    Aead2022(Aead2022DecryptedReader),
 }
 
 impl DecryptedReader {
     /// Create a new reader for reading encrypted data
-    pub fn new(method: CipherKind, key: &[u8]) -> DecryptedReader {
+    ///
+    /// `nonce` is the one sent by the writer of the same stream, which is required by `Client` streams of AEAD 2022
+    /// methods for authenticating responses
+    #[allow(unused_variables)]
+    pub fn new(stream_ty: StreamType, method: CipherKind, key: &[u8], nonce: &[u8]) -> DecryptedReader {
         match method.category() {
             #[cfg(feature = "stream-cipher")]
             CipherCategory::Stream => DecryptedReader::Stream(StreamDecryptedReader::new(method, key)),
             CipherCategory::Aead => DecryptedReader::Aead(AeadDecryptedReader::new(method, key)),
+            #[cfg(feature = "aead-cipher-2022")]
+            CipherCategory::Aead2022 => {
+                let request_salt = match stream_ty {
+                    StreamType::Client => Some(nonce),
+                    StreamType::Server => None,
+                };
+                DecryptedReader::Aead2022(Aead2022DecryptedReader::new(stream_ty, method, key, request_salt))
+            }
             CipherCategory::None => DecryptedReader::None,
         }
     }
 
+    /// Check if nonce (IV/salt) of this stream is a repeated one (`ReplayAttackPolicy::Detect`)
+    ///
+    /// Repeated salts of AEAD 2022 methods are always rejected
+    pub fn nonce_replayed(&self) -> bool {
+        match *self {
+            #[cfg(feature = "stream-cipher")]
+            DecryptedReader::Stream(ref reader) => reader.nonce_replayed(),
+            DecryptedReader::Aead(ref reader) => reader.nonce_replayed(),
+            #[cfg(feature = "aead-cipher-2022")]
+            DecryptedReader::Aead2022(..) => false,
+            DecryptedReader::None => false,
+        }
+    }
+
+    /// User of multi-user servers identified by the request, only valid after the header was read
+    #[cfg(feature = "aead-cipher-2022")]
+    pub fn user(&self) -> Option<&Arc<ServerUser>> {
+        match *self {
+            DecryptedReader::Aead2022(ref reader) => reader.user(),
+            _ => None,
+        }
+    }
+
     /// Attempt to read decrypted data from `stream`
     #[inline]
     pub fn poll_read_decrypted<S>(
@@ -56,6 +112,8 @@ impl DecryptedReader {
             #[cfg(feature = "stream-cipher")]
             DecryptedReader::Stream(ref mut reader) => reader.poll_read_decrypted(cx, context, stream, buf),
             DecryptedReader::Aead(ref mut reader) => reader.poll_read_decrypted(cx, context, stream, buf),
+            #[cfg(feature = "aead-cipher-2022")]
+            DecryptedReader::Aead2022(ref mut reader) => reader.poll_read_decrypted(cx, context, stream, buf),
             DecryptedReader::None => Pin::new(stream).poll_read(cx, buf),
         }
     }
@@ -67,19 +125,44 @@ pub enum EncryptedWriter {
     Aead(AeadEncryptedWriter),
     #[cfg(feature = "stream-cipher")]
     Stream(StreamEncryptedWriter),
+    #[cfg(feature = "aead-cipher-2022")]
+    Aead2022(Aead2022EncryptedWriter),
 }
 
 impl EncryptedWriter {
     /// Create a new writer for writing encrypted data
-    pub fn new(method: CipherKind, key: &[u8], nonce: &[u8]) -> EncryptedWriter {
+    #[allow(unused_variables)]
+    pub fn new(stream_ty: StreamType, method: CipherKind, key: &[u8], nonce: &[u8]) -> EncryptedWriter {
         match method.category() {
             #[cfg(feature = "stream-cipher")]
             CipherCategory::Stream => EncryptedWriter::Stream(StreamEncryptedWriter::new(method, key, nonce)),
             CipherCategory::Aead => EncryptedWriter::Aead(AeadEncryptedWriter::new(method, key, nonce)),
+            #[cfg(feature = "aead-cipher-2022")]
+            CipherCategory::Aead2022 => {
+                EncryptedWriter::Aead2022(Aead2022EncryptedWriter::new(stream_ty, method, key, nonce))
+            }
             CipherCategory::None => EncryptedWriter::None,
         }
     }
 
+    /// Pass salt of the request received by `dec` to the response header of AEAD 2022 `Server` streams,
+    /// and key of the user identified by the request to multi-user servers
+    #[inline]
+    #[allow(unused_variables)]
+    fn sync_request_salt(&mut self, dec: &DecryptedReader) {
+        #[cfg(feature = "aead-cipher-2022")]
+        if let (EncryptedWriter::Aead2022(ref mut writer), DecryptedReader::Aead2022(ref reader)) = (self, dec) {
+            if writer.need_request_salt() {
+                if let Some(salt) = reader.request_salt() {
+                    writer.set_request_salt(salt);
+                    if let Some(user) = reader.user() {
+                        writer.set_user_key(user.key());
+                    }
+                }
+            }
+        }
+    }
+
     /// Attempt to write encrypted data to `stream`
     #[inline]
     pub fn poll_write_encrypted<S>(
@@ -95,6 +178,8 @@ impl EncryptedWriter {
             #[cfg(feature = "stream-cipher")]
             EncryptedWriter::Stream(ref mut writer) => writer.poll_write_encrypted(cx, stream, buf),
             EncryptedWriter::Aead(ref mut writer) => writer.poll_write_encrypted(cx, stream, buf),
+            #[cfg(feature = "aead-cipher-2022")]
+            EncryptedWriter::Aead2022(ref mut writer) => writer.poll_write_encrypted(cx, stream, buf),
             EncryptedWriter::None => Pin::new(stream).poll_write(cx, buf),
         }
     }
@@ -106,11 +191,19 @@ pub struct CryptoStream<S> {
     dec: DecryptedReader,
     enc: EncryptedWriter,
     method: CipherKind,
+    #[cfg(feature = "stream-padding")]
+    padding: Option<(PaddingReader, PaddingWriter)>,
 }
 
 impl<S> CryptoStream<S> {
     /// Create a new CryptoStream with the underlying stream connection
-    pub fn from_stream(context: &Context, stream: S, method: CipherKind, key: &[u8]) -> CryptoStream<S> {
+    pub fn from_stream(
+        context: &Context,
+        stream: S,
+        stream_ty: StreamType,
+        method: CipherKind,
+        key: &[u8],
+    ) -> CryptoStream<S> {
         let category = method.category();
 
         if category == CipherCategory::None {
@@ -122,6 +215,8 @@ impl<S> CryptoStream<S> {
             #[cfg(feature = "stream-cipher")]
             CipherCategory::Stream => method.iv_len(),
             CipherCategory::Aead => method.salt_len(),
+            #[cfg(feature = "aead-cipher-2022")]
+            CipherCategory::Aead2022 => method.salt_len(),
             CipherCategory::None => 0,
         };
 
@@ -139,14 +234,69 @@ impl<S> CryptoStream<S> {
                 trace!("generated AEAD cipher salt {:?}", ByteStr::new(&local_salt));
                 local_salt
             }
+            #[cfg(feature = "aead-cipher-2022")]
+            CipherCategory::Aead2022 => {
+                let mut local_salt = vec![0u8; prev_len];
+                context.generate_nonce(&mut local_salt, true);
+                trace!("generated AEAD 2022 cipher salt {:?}", ByteStr::new(&local_salt));
+                local_salt
+            }
             CipherCategory::None => Vec::new(),
         };
 
         CryptoStream {
             stream,
-            dec: DecryptedReader::new(method, key),
-            enc: EncryptedWriter::new(method, key, &iv),
+            dec: DecryptedReader::new(stream_ty, method, key, &iv),
+            enc: EncryptedWriter::new(stream_ty, method, key, &iv),
+            method,
+            #[cfg(feature = "stream-padding")]
+            padding: None,
+        }
+    }
+
+    /// Create a new CryptoStream of a multi-user server, or a client of it, with AEAD 2022 methods
+    ///
+    /// `Client` streams send identity headers of `identity_keys` (iPSKs) with the user's `key` (uPSK), and
+    /// `Server` streams identify users of `user_manager` by them with the server's `key` (iPSK).
+    #[cfg(feature = "aead-cipher-2022")]
+    pub fn from_stream_with_identity(
+        context: &Context,
+        stream: S,
+        stream_ty: StreamType,
+        method: CipherKind,
+        key: &[u8],
+        identity_keys: &[Bytes],
+        user_manager: Option<Arc<ServerUserManager>>,
+    ) -> CryptoStream<S> {
+        let user_manager = match (stream_ty, user_manager) {
+            (StreamType::Server, Some(user_manager)) => Some(user_manager),
+            _ => None,
+        };
+        let identity_keys = match stream_ty {
+            StreamType::Client => identity_keys,
+            StreamType::Server => &[],
+        };
+        if method.category() != CipherCategory::Aead2022 || (identity_keys.is_empty() && user_manager.is_none()) {
+            return CryptoStream::from_stream(context, stream, stream_ty, method, key);
+        }
+
+        let mut local_salt = vec![0u8; method.salt_len()];
+        context.generate_nonce(&mut local_salt, true);
+        trace!("generated AEAD 2022 cipher salt {:?}", ByteStr::new(&local_salt));
+
+        let dec = match user_manager {
+            Some(user_manager) => Aead2022DecryptedReader::with_user_manager(method, key, user_manager),
+            None => Aead2022DecryptedReader::new(stream_ty, method, key, Some(&local_salt)),
+        };
+        let enc = Aead2022EncryptedWriter::with_identity(stream_ty, method, key, &local_salt, identity_keys);
+
+        CryptoStream {
+            stream,
+            dec: DecryptedReader::Aead2022(dec),
+            enc: EncryptedWriter::Aead2022(enc),
             method,
+            #[cfg(feature = "stream-padding")]
+            padding: None,
         }
     }
 
@@ -156,9 +306,17 @@ impl<S> CryptoStream<S> {
             dec: DecryptedReader::None,
             enc: EncryptedWriter::None,
             method,
+            #[cfg(feature = "stream-padding")]
+            padding: None,
         }
     }
 
+    /// Carry data in padded frames, it must be set before the first read and write
+    #[cfg(feature = "stream-padding")]
+    pub fn set_padding(&mut self, config: PaddingConfig) {
+        self.padding = Some((PaddingReader::new(), PaddingWriter::new(config)));
+    }
+
     /// Return a reference to the underlying stream
     pub fn get_ref(&self) -> &S {
         &self.stream
@@ -180,6 +338,17 @@ impl<S> CryptoStream<S> {
     pub fn method(&self) -> CipherKind {
         self.method
     }
+
+    /// Check if nonce (IV/salt) received from peer is a repeated one (`ReplayAttackPolicy::Detect`)
+    pub fn nonce_replayed(&self) -> bool {
+        self.dec.nonce_replayed()
+    }
+
+    /// User of multi-user servers identified by the request, only valid after the header was read
+    #[cfg(feature = "aead-cipher-2022")]
+    pub fn user(&self) -> Option<&Arc<ServerUser>> {
+        self.dec.user()
+    }
 }
 
 impl<S> CryptoStream<S>
@@ -194,6 +363,11 @@ where
         context: &Context,
         buf: &mut ReadBuf<'_>,
     ) -> Poll<io::Result<()>> {
+        #[cfg(feature = "stream-padding")]
+        if let Some((ref mut reader, _)) = self.padding {
+            let (dec, stream) = (&mut self.dec, &mut self.stream);
+            return reader.poll_read(cx, buf, |cx, buf| dec.poll_read_decrypted(cx, context, stream, buf));
+        }
         self.dec.poll_read_decrypted(cx, context, &mut self.stream, buf)
     }
 }
@@ -205,6 +379,12 @@ where
     /// Attempt to write encrypted data to `stream`
     #[inline]
     pub fn poll_write_encrypted(&mut self, cx: &mut task::Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        self.enc.sync_request_salt(&self.dec);
+        #[cfg(feature = "stream-padding")]
+        if let Some((_, ref mut writer)) = self.padding {
+            let (enc, stream) = (&mut self.enc, &mut self.stream);
+            return writer.poll_write(cx, buf, |cx, buf| enc.poll_write_encrypted(cx, stream, buf));
+        }
         self.enc.poll_write_encrypted(cx, &mut self.stream, buf)
     }
 
@@ -225,19 +405,32 @@ impl<S> CryptoStream<S>
 where
     S: AsyncRead + AsyncWrite + Unpin,
 {
-    pub fn into_split(self) -> (CryptoStreamReadHalf<S>, CryptoStreamWriteHalf<S>) {
+    pub fn into_split(mut self) -> (CryptoStreamReadHalf<S>, CryptoStreamWriteHalf<S>) {
+        // Salt of the request couldn't be passed to the write half after splitting
+        self.enc.sync_request_salt(&self.dec);
+
         let (reader, writer) = tokio::io::split(self.stream);
 
+        #[cfg(feature = "stream-padding")]
+        let (pad_reader, pad_writer) = match self.padding {
+            Some((reader, writer)) => (Some(reader), Some(writer)),
+            None => (None, None),
+        };
+
         (
             CryptoStreamReadHalf {
                 reader,
                 dec: self.dec,
                 method: self.method,
+                #[cfg(feature = "stream-padding")]
+                padding: pad_reader,
             },
             CryptoStreamWriteHalf {
                 writer,
                 enc: self.enc,
                 method: self.method,
+                #[cfg(feature = "stream-padding")]
+                padding: pad_writer,
             },
         )
     }
@@ -247,6 +440,8 @@ pub struct CryptoStreamReadHalf<S> {
     reader: ReadHalf<S>,
     dec: DecryptedReader,
     method: CipherKind,
+    #[cfg(feature = "stream-padding")]
+    padding: Option<PaddingReader>,
 }
 
 impl<S> CryptoStreamReadHalf<S> {
@@ -268,6 +463,11 @@ where
         context: &Context,
         buf: &mut ReadBuf<'_>,
     ) -> Poll<io::Result<()>> {
+        #[cfg(feature = "stream-padding")]
+        if let Some(ref mut padding) = self.padding {
+            let (dec, reader) = (&mut self.dec, &mut self.reader);
+            return padding.poll_read(cx, buf, |cx, buf| dec.poll_read_decrypted(cx, context, reader, buf));
+        }
         self.dec.poll_read_decrypted(cx, context, &mut self.reader, buf)
     }
 }
@@ -276,6 +476,8 @@ pub struct CryptoStreamWriteHalf<S> {
     writer: WriteHalf<S>,
     enc: EncryptedWriter,
     method: CipherKind,
+    #[cfg(feature = "stream-padding")]
+    padding: Option<PaddingWriter>,
 }
 
 impl<S> CryptoStreamWriteHalf<S> {
@@ -292,6 +494,11 @@ where
     /// Attempt to write encrypted data to `stream`
     #[inline]
     pub fn poll_write_encrypted(&mut self, cx: &mut task::Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        #[cfg(feature = "stream-padding")]
+        if let Some(ref mut padding) = self.padding {
+            let (enc, writer) = (&mut self.enc, &mut self.writer);
+            return padding.poll_write(cx, buf, |cx, buf| enc.poll_write_encrypted(cx, writer, buf));
+        }
         self.enc.poll_write_encrypted(cx, &mut self.writer, buf)
     }
 
@@ -6,7 +6,11 @@ pub use self::{
 };
 
 mod aead;
+#[cfg(feature = "aead-cipher-2022")]
+pub(crate) mod aead_2022;
 pub mod crypto_io;
+#[cfg(feature = "stream-padding")]
+pub mod padding;
 pub mod proxy_listener;
 pub mod proxy_stream;
 #[cfg(feature = "stream-cipher")]
@@ -0,0 +1,306 @@
+//! Padding of TCP streams against traffic analysis
+//!
+//! Data of padded streams is carried in frames, which are encrypted like any other data of the stream:
+//!
+//! ```plain
+//! +----------+-------------+------+---------+
+//! | DATA LEN | PADDING LEN | DATA | PADDING |
+//! +----------+-------------+------+---------+
+//! |  u16be   |    u16be    | ...  |   ...   |
+//! +----------+-------------+------+---------+
+//! ```
+//!
+//! Every frame is followed by random length padding, which blurs lengths of packets. Large writes could be split into
+//! bursts of random sizes, with random delays (jitter) between them, which blurs their timing.
+//!
+//! Padding is not negotiated on the wire, clients and servers must be configured with it the same way as methods.
+
+use std::{
+    cmp,
+    future::Future,
+    io::{self, ErrorKind},
+    pin::Pin,
+    task::{self, Poll},
+    time::Duration,
+};
+
+use bytes::{BufMut, BytesMut};
+use futures::ready;
+use rand::Rng;
+use tokio::{io::ReadBuf, time::Sleep};
+
+/// Default maximum length of padding of each frame
+pub const DEFAULT_MAX_PADDING: usize = 256;
+
+/// Maximum length of data of each frame, the same as AEAD chunks'
+pub const MAX_FRAME_DATA_SIZE: usize = 0x3FFF;
+
+const FRAME_HEADER_SIZE: usize = 4;
+
+/// Padding configuration of a server
+///
+/// - `max_padding`: each frame is padded with [0, `max_padding`] random bytes
+/// - `max_burst`: writes larger than it are split into bursts of [`max_burst` / 2, `max_burst`] bytes, not split if 0
+/// - `max_jitter`: bursts of a write are delayed for [0, `max_jitter`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PaddingConfig {
+    max_padding: usize,
+    max_burst: usize,
+    max_jitter: Duration,
+}
+
+impl Default for PaddingConfig {
+    fn default() -> PaddingConfig {
+        PaddingConfig::new(DEFAULT_MAX_PADDING)
+    }
+}
+
+impl PaddingConfig {
+    /// Creates a configuration padding each frame with [0, `max_padding`] bytes, `max_padding` is limited to `u16::MAX`
+    pub fn new(max_padding: usize) -> PaddingConfig {
+        PaddingConfig {
+            max_padding: cmp::min(max_padding, u16::MAX as usize),
+            max_burst: 0,
+            max_jitter: Duration::ZERO,
+        }
+    }
+
+    /// Maximum length of padding of each frame
+    pub fn max_padding(&self) -> usize {
+        self.max_padding
+    }
+
+    /// Maximum size of bursts, writes are not split if 0
+    pub fn max_burst(&self) -> usize {
+        self.max_burst
+    }
+
+    /// Split writes into bursts of at most `max_burst` bytes, limited to [`MAX_FRAME_DATA_SIZE`]
+    pub fn set_max_burst(&mut self, max_burst: usize) {
+        self.max_burst = cmp::min(max_burst, MAX_FRAME_DATA_SIZE);
+    }
+
+    /// Maximum delay between bursts of a write
+    pub fn max_jitter(&self) -> Duration {
+        self.max_jitter
+    }
+
+    /// Delay bursts of a write for at most `max_jitter`, only if writes are split by `set_max_burst`
+    pub fn set_max_jitter(&mut self, max_jitter: Duration) {
+        self.max_jitter = max_jitter;
+    }
+
+    fn padding_len(&self) -> usize {
+        if self.max_padding == 0 {
+            0
+        } else {
+            rand::thread_rng().gen_range(0..=self.max_padding)
+        }
+    }
+
+    fn burst_len(&self, remaining: usize) -> usize {
+        if self.max_burst == 0 {
+            return remaining;
+        }
+        let burst = rand::thread_rng().gen_range(self.max_burst - self.max_burst / 2..=self.max_burst);
+        cmp::min(burst, remaining)
+    }
+
+    fn jitter(&self) -> Option<Duration> {
+        if self.max_jitter.is_zero() {
+            None
+        } else {
+            Some(rand::thread_rng().gen_range(Duration::ZERO..=self.max_jitter))
+        }
+    }
+}
+
+enum PaddingWriteState {
+    Assemble,
+    Writing { pos: usize },
+    Delaying(Pin<Box<Sleep>>),
+}
+
+/// Writer of padded frames
+pub struct PaddingWriter {
+    config: PaddingConfig,
+    state: PaddingWriteState,
+    frame: BytesMut,
+    frame_data_len: usize,
+    written: usize,
+}
+
+impl PaddingWriter {
+    /// Creates a writer with `config`
+    pub fn new(config: PaddingConfig) -> PaddingWriter {
+        PaddingWriter {
+            config,
+            state: PaddingWriteState::Assemble,
+            frame: BytesMut::new(),
+            frame_data_len: 0,
+            written: 0,
+        }
+    }
+
+    /// Attempt to write `buf` in padded frames with `write_fn`
+    ///
+    /// Like `write_all`, the whole `buf` is written before returning, in frames of at most [`MAX_FRAME_DATA_SIZE`]
+    /// bytes, so it must be the same one until it returns `Poll::Ready`. `write_fn` must also write whole buffers.
+    pub fn poll_write<F>(&mut self, cx: &mut task::Context<'_>, buf: &[u8], mut write_fn: F) -> Poll<io::Result<usize>>
+    where
+        F: FnMut(&mut task::Context<'_>, &[u8]) -> Poll<io::Result<usize>>,
+    {
+        if buf.is_empty() {
+            return Ok(0).into();
+        }
+
+        loop {
+            match self.state {
+                PaddingWriteState::Assemble => {
+                    let remaining = &buf[self.written..];
+                    let data_len = cmp::min(self.config.burst_len(remaining.len()), MAX_FRAME_DATA_SIZE);
+                    let padding_len = self.config.padding_len();
+
+                    self.frame.clear();
+                    self.frame.reserve(FRAME_HEADER_SIZE + data_len + padding_len);
+                    self.frame.put_u16(data_len as u16);
+                    self.frame.put_u16(padding_len as u16);
+                    self.frame.put_slice(&remaining[..data_len]);
+                    // Padding is encrypted with the frame, zeros are as good as random bytes
+                    self.frame.put_bytes(0, padding_len);
+
+                    self.frame_data_len = data_len;
+                    self.state = PaddingWriteState::Writing { pos: 0 };
+                }
+                PaddingWriteState::Writing { ref mut pos } => {
+                    while *pos < self.frame.len() {
+                        let n = ready!(write_fn(cx, &self.frame[*pos..]))?;
+                        if n == 0 {
+                            return Err(ErrorKind::WriteZero.into()).into();
+                        }
+                        *pos += n;
+                    }
+
+                    self.written += self.frame_data_len;
+                    if self.written >= buf.len() {
+                        let n = self.written;
+                        self.written = 0;
+                        self.state = PaddingWriteState::Assemble;
+                        return Ok(n).into();
+                    }
+
+                    // More bursts of this write
+                    self.state = match self.config.jitter() {
+                        Some(d) => PaddingWriteState::Delaying(Box::pin(tokio::time::sleep(d))),
+                        None => PaddingWriteState::Assemble,
+                    };
+                }
+                PaddingWriteState::Delaying(ref mut sleep) => {
+                    ready!(sleep.as_mut().poll(cx));
+                    self.state = PaddingWriteState::Assemble;
+                }
+            }
+        }
+    }
+}
+
+enum PaddingReadState {
+    Header,
+    Data(usize),
+    Padding(usize),
+}
+
+/// Reader of padded frames
+pub struct PaddingReader {
+    state: PaddingReadState,
+    header: [u8; FRAME_HEADER_SIZE],
+    header_len: usize,
+    padding_len: usize,
+}
+
+impl Default for PaddingReader {
+    fn default() -> PaddingReader {
+        PaddingReader::new()
+    }
+}
+
+impl PaddingReader {
+    /// Creates a reader
+    pub fn new() -> PaddingReader {
+        PaddingReader {
+            state: PaddingReadState::Header,
+            header: [0u8; FRAME_HEADER_SIZE],
+            header_len: 0,
+            padding_len: 0,
+        }
+    }
+
+    /// Attempt to read data of padded frames with `read_fn`, padding is skipped
+    pub fn poll_read<F>(
+        &mut self,
+        cx: &mut task::Context<'_>,
+        buf: &mut ReadBuf<'_>,
+        mut read_fn: F,
+    ) -> Poll<io::Result<()>>
+    where
+        F: FnMut(&mut task::Context<'_>, &mut ReadBuf<'_>) -> Poll<io::Result<()>>,
+    {
+        loop {
+            match self.state {
+                PaddingReadState::Header => {
+                    while self.header_len < FRAME_HEADER_SIZE {
+                        let mut header_buf = ReadBuf::new(&mut self.header[self.header_len..]);
+                        ready!(read_fn(cx, &mut header_buf))?;
+                        let n = header_buf.filled().len();
+                        if n == 0 {
+                            if self.header_len == 0 {
+                                // EOF between frames
+                                return Ok(()).into();
+                            }
+                            return Err(ErrorKind::UnexpectedEof.into()).into();
+                        }
+                        self.header_len += n;
+                    }
+
+                    self.header_len = 0;
+                    let data_len = u16::from_be_bytes([self.header[0], self.header[1]]) as usize;
+                    self.padding_len = u16::from_be_bytes([self.header[2], self.header[3]]) as usize;
+                    self.state = PaddingReadState::Data(data_len);
+                }
+                PaddingReadState::Data(0) => {
+                    self.state = PaddingReadState::Padding(self.padding_len);
+                }
+                PaddingReadState::Data(ref mut remaining) => {
+                    if buf.remaining() == 0 {
+                        return Ok(()).into();
+                    }
+
+                    let dst = buf.initialize_unfilled_to(cmp::min(*remaining, buf.remaining()));
+                    let mut data_buf = ReadBuf::new(dst);
+                    ready!(read_fn(cx, &mut data_buf))?;
+                    let n = data_buf.filled().len();
+                    if n == 0 {
+                        return Err(ErrorKind::UnexpectedEof.into()).into();
+                    }
+                    buf.advance(n);
+                    *remaining -= n;
+                    return Ok(()).into();
+                }
+                PaddingReadState::Padding(0) => {
+                    self.state = PaddingReadState::Header;
+                }
+                PaddingReadState::Padding(ref mut remaining) => {
+                    let mut discard = [0u8; 512];
+                    let len = cmp::min(*remaining, discard.len());
+                    let mut discard_buf = ReadBuf::new(&mut discard[..len]);
+                    ready!(read_fn(cx, &mut discard_buf))?;
+                    let n = discard_buf.filled().len();
+                    if n == 0 {
+                        return Err(ErrorKind::UnexpectedEof.into()).into();
+                    }
+                    *remaining -= n;
+                }
+            }
+        }
+    }
+}
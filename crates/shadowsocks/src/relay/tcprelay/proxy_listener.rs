@@ -1,5 +1,7 @@
 //! A TCP listener for accepting shadowsocks' client connection
 
+#[cfg(feature = "aead-cipher-2022")]
+use std::sync::Arc;
 use std::{io, net::SocketAddr};
 
 use once_cell::sync::Lazy;
@@ -8,10 +10,14 @@ use tokio::{
     net::TcpStream,
 };
 
+#[cfg(feature = "aead-cipher-2022")]
+use crate::config::ServerUserManager;
+#[cfg(feature = "stream-padding")]
+use crate::relay::tcprelay::padding::PaddingConfig;
 use crate::{
     config::{ServerAddr, ServerConfig},
     context::SharedContext,
-    crypto::v1::CipherKind,
+    crypto::CipherKind,
     net::{AcceptOpts, TcpListener},
     relay::tcprelay::proxy_stream::server::ProxyServerStream,
 };
@@ -21,6 +27,10 @@ pub struct ProxyListener {
     listener: TcpListener,
     method: CipherKind,
     key: Box<[u8]>,
+    #[cfg(feature = "aead-cipher-2022")]
+    user_manager: Option<Arc<ServerUserManager>>,
+    #[cfg(feature = "stream-padding")]
+    padding: Option<PaddingConfig>,
     context: SharedContext,
 }
 
@@ -56,6 +66,10 @@ impl ProxyListener {
             listener,
             method: svr_cfg.method(),
             key: svr_cfg.key().to_vec().into_boxed_slice(),
+            #[cfg(feature = "aead-cipher-2022")]
+            user_manager: svr_cfg.user_manager().cloned(),
+            #[cfg(feature = "stream-padding")]
+            padding: svr_cfg.padding().cloned(),
             context,
         }
     }
@@ -76,7 +90,23 @@ impl ProxyListener {
         let stream = map_fn(stream);
 
         // Create a ProxyServerStream and read the target address from it
-        let stream = ProxyServerStream::from_stream(self.context.clone(), stream, self.method, &self.key);
+        #[cfg(feature = "aead-cipher-2022")]
+        #[cfg_attr(not(feature = "stream-padding"), allow(unused_mut))]
+        let mut stream = ProxyServerStream::from_stream_with_user_manager(
+            self.context.clone(),
+            stream,
+            self.method,
+            &self.key,
+            self.user_manager.clone(),
+        );
+        #[cfg(not(feature = "aead-cipher-2022"))]
+        #[cfg_attr(not(feature = "stream-padding"), allow(unused_mut))]
+        let mut stream = ProxyServerStream::from_stream(self.context.clone(), stream, self.method, &self.key);
+
+        #[cfg(feature = "stream-padding")]
+        if let Some(ref padding) = self.padding {
+            stream.set_padding(padding.clone());
+        }
 
         Ok((stream, peer_addr))
     }
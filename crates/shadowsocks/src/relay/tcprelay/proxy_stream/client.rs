@@ -18,11 +18,11 @@ use tokio::{
 
 use crate::{
     config::ServerConfig,
-    context::SharedContext,
+    context::{Context, SharedContext},
     net::{ConnectOpts, TcpStream as OutboundTcpStream},
     relay::{
         socks5::Address,
-        tcprelay::crypto_io::{CryptoStream, CryptoStreamReadHalf, CryptoStreamWriteHalf},
+        tcprelay::crypto_io::{CryptoStream, CryptoStreamReadHalf, CryptoStreamWriteHalf, StreamType},
     },
 };
 
@@ -100,34 +100,7 @@ where
         A: Into<Address>,
         F: FnOnce(OutboundTcpStream) -> S,
     {
-        let stream = match svr_cfg.timeout() {
-            Some(d) => {
-                match time::timeout(
-                    d,
-                    OutboundTcpStream::connect_server_with_opts(&context, svr_cfg.external_addr(), opts),
-                )
-                .await
-                {
-                    Ok(Ok(s)) => s,
-                    Ok(Err(e)) => return Err(e),
-                    Err(..) => {
-                        return Err(io::Error::new(
-                            ErrorKind::TimedOut,
-                            format!("connect {} timeout", svr_cfg.addr()),
-                        ))
-                    }
-                }
-            }
-            None => OutboundTcpStream::connect_server_with_opts(&context, svr_cfg.external_addr(), opts).await?,
-        };
-
-        trace!(
-            "connected tcp remote {} (outbound: {}) with {:?}",
-            svr_cfg.addr(),
-            svr_cfg.external_addr(),
-            opts
-        );
-
+        let stream = connect_server_with_opts(&context, svr_cfg, opts).await?;
         Ok(ProxyClientStream::from_stream(context, map_fn(stream), svr_cfg, addr))
     }
 
@@ -139,7 +112,26 @@ where
         A: Into<Address>,
     {
         let addr = addr.into();
-        let stream = CryptoStream::from_stream(&context, stream, svr_cfg.method(), svr_cfg.key());
+        #[cfg(feature = "aead-cipher-2022")]
+        #[cfg_attr(not(feature = "stream-padding"), allow(unused_mut))]
+        let mut stream = CryptoStream::from_stream_with_identity(
+            &context,
+            stream,
+            StreamType::Client,
+            svr_cfg.method(),
+            svr_cfg.key(),
+            svr_cfg.identity_keys(),
+            None,
+        );
+        #[cfg(not(feature = "aead-cipher-2022"))]
+        #[cfg_attr(not(feature = "stream-padding"), allow(unused_mut))]
+        let mut stream =
+            CryptoStream::from_stream(&context, stream, StreamType::Client, svr_cfg.method(), svr_cfg.key());
+
+        #[cfg(feature = "stream-padding")]
+        if let Some(padding) = svr_cfg.padding() {
+            stream.set_padding(padding.clone());
+        }
 
         ProxyClientStream {
             stream,
@@ -350,3 +342,90 @@ where
         self.project().writer.poll_shutdown(cx)
     }
 }
+
+/// Connect to shadowsocks' server configured by `svr_cfg` through its transports, without sending any requests
+///
+/// The connection could be wrapped by `ProxyClientStream::from_stream` later, the request is sent with the first write.
+pub async fn connect_server_with_opts(
+    context: &Context,
+    svr_cfg: &ServerConfig,
+    opts: &ConnectOpts,
+) -> io::Result<OutboundTcpStream> {
+    let server_opts;
+    let opts = if svr_cfg.tcp_pacing_rate().is_some() || svr_cfg.mptcp() || !svr_cfg.tcp_tuning().is_empty() {
+        let mut o = opts.clone();
+        if let Some(rate) = svr_cfg.tcp_pacing_rate() {
+            o.tcp.pacing_rate = Some(rate);
+        }
+        o.tcp.mptcp |= svr_cfg.mptcp();
+        svr_cfg.tcp_tuning().apply(&mut o.tcp);
+        server_opts = o;
+        &server_opts
+    } else {
+        opts
+    };
+
+    // Servers listening on a range of ports are connected on the current port
+    let hopping_addr = svr_cfg.hopping_addr();
+    #[allow(unused_variables)]
+    let server_addr = hopping_addr.as_ref().unwrap_or_else(|| svr_cfg.addr());
+    let external_addr = hopping_addr.as_ref().unwrap_or_else(|| svr_cfg.external_addr());
+
+    let connect_fut = async {
+        #[cfg(feature = "transport-websocket")]
+        if let Some(ws) = svr_cfg.websocket() {
+            return OutboundTcpStream::connect_server_websocket_with_opts(context, server_addr, ws, opts).await;
+        }
+        #[cfg(feature = "transport-obfs")]
+        if let Some(obfs) = svr_cfg.obfs() {
+            return OutboundTcpStream::connect_server_obfs_with_opts(context, server_addr, obfs, opts).await;
+        }
+        #[cfg(feature = "transport-shadow-tls")]
+        if let Some(st) = svr_cfg.shadow_tls() {
+            return OutboundTcpStream::connect_server_shadow_tls_with_opts(context, server_addr, st, opts).await;
+        }
+        #[cfg(feature = "transport-quic")]
+        if let Some(quic) = svr_cfg.quic() {
+            return OutboundTcpStream::connect_server_quic_with_opts(context, server_addr, quic, opts).await;
+        }
+        #[cfg(feature = "transport-grpc")]
+        if let Some(grpc) = svr_cfg.grpc() {
+            return OutboundTcpStream::connect_server_grpc_with_opts(context, server_addr, grpc, opts).await;
+        }
+        if let Some(proxy) = svr_cfg.upstream_proxy() {
+            return OutboundTcpStream::connect_server_via_proxy_with_opts(context, proxy, external_addr, opts).await;
+        }
+        OutboundTcpStream::connect_server_with_opts(context, external_addr, opts).await
+    };
+
+    let result = match svr_cfg.timeout() {
+        Some(d) => match time::timeout(d, connect_fut).await {
+            Ok(r) => r,
+            Err(..) => Err(io::Error::new(
+                ErrorKind::TimedOut,
+                format!("connect {} timeout", external_addr),
+            )),
+        },
+        None => connect_fut.await,
+    };
+
+    let stream = match result {
+        Ok(s) => s,
+        Err(err) => {
+            // The port may be blocked, try another one next time
+            if let Some(port_hopping) = svr_cfg.port_hopping() {
+                port_hopping.hop();
+            }
+            return Err(err);
+        }
+    };
+
+    trace!(
+        "connected tcp remote {} (outbound: {}) with {:?}",
+        svr_cfg.addr(),
+        external_addr,
+        opts
+    );
+
+    Ok(stream)
+}
@@ -1,7 +1,7 @@
 //! Stream interface for communicating with shadowsocks proxy servers
 
 pub use self::{
-    client::{ProxyClientStream, ProxyClientStreamReadHalf, ProxyClientStreamWriteHalf},
+    client::{connect_server_with_opts, ProxyClientStream, ProxyClientStreamReadHalf, ProxyClientStreamWriteHalf},
     server::{ProxyServerStream, ProxyServerStreamReadHalf, ProxyServerStreamWriteHalf},
 };
 
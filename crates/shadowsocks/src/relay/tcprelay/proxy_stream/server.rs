@@ -1,5 +1,7 @@
 //! A TCP stream for communicating with shadowsocks' proxy client
 
+#[cfg(feature = "aead-cipher-2022")]
+use std::sync::Arc;
 use std::{
     io,
     pin::Pin,
@@ -9,10 +11,14 @@ use std::{
 use pin_project::pin_project;
 use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
 
+#[cfg(feature = "aead-cipher-2022")]
+use crate::config::{ServerUser, ServerUserManager};
+#[cfg(feature = "stream-padding")]
+use crate::relay::tcprelay::padding::PaddingConfig;
 use crate::{
     context::SharedContext,
-    crypto::v1::CipherKind,
-    relay::tcprelay::crypto_io::{CryptoStream, CryptoStreamReadHalf, CryptoStreamWriteHalf},
+    crypto::CipherKind,
+    relay::tcprelay::crypto_io::{CryptoStream, CryptoStreamReadHalf, CryptoStreamWriteHalf, StreamType},
 };
 
 /// A stream for communicating with shadowsocks' proxy client
@@ -24,18 +30,50 @@ pub struct ProxyServerStream<S> {
 }
 
 impl<S> ProxyServerStream<S> {
-    pub(crate) fn from_stream(
+    /// Create a `ProxyServerStream` with a `stream` accepted from a shadowsocks' client
+    ///
+    /// NOTE: `method` and `key` must be the same as the client's, otherwise strange errors would occurs
+    pub fn from_stream(context: SharedContext, stream: S, method: CipherKind, key: &[u8]) -> ProxyServerStream<S> {
+        ProxyServerStream {
+            stream: CryptoStream::from_stream(&context, stream, StreamType::Server, method, key),
+            context,
+        }
+    }
+
+    /// Create a `ProxyServerStream` with a `stream` accepted from a client of a multi-user server
+    ///
+    /// Clients are identified as users of `user_manager` with AEAD 2022 methods, `key` is the identity key (iPSK) of
+    /// the server. It is the same as `from_stream` if `user_manager` is `None`.
+    #[cfg(feature = "aead-cipher-2022")]
+    pub fn from_stream_with_user_manager(
         context: SharedContext,
         stream: S,
         method: CipherKind,
         key: &[u8],
+        user_manager: Option<Arc<ServerUserManager>>,
     ) -> ProxyServerStream<S> {
         ProxyServerStream {
-            stream: CryptoStream::from_stream(&context, stream, method, key),
+            stream: CryptoStream::from_stream_with_identity(
+                &context,
+                stream,
+                StreamType::Server,
+                method,
+                key,
+                &[],
+                user_manager,
+            ),
             context,
         }
     }
 
+    /// Carry data in padded frames, like clients configured with the same `config`
+    ///
+    /// It must be set before reading the target address.
+    #[cfg(feature = "stream-padding")]
+    pub fn set_padding(&mut self, config: PaddingConfig) {
+        self.stream.set_padding(config);
+    }
+
     /// Get reference of the internal stream
     pub fn get_ref(&self) -> &S {
         self.stream.get_ref()
@@ -50,6 +88,22 @@ impl<S> ProxyServerStream<S> {
     pub fn into_inner(self) -> S {
         self.stream.into_inner()
     }
+
+    /// Check if nonce (IV/salt) received from client is a repeated one
+    ///
+    /// Only valid after the first successful read, and replays could only be detected but not rejected
+    /// with `ReplayAttackPolicy::Detect`
+    pub fn nonce_replayed(&self) -> bool {
+        self.stream.nonce_replayed()
+    }
+
+    /// User of multi-user servers identified by the client
+    ///
+    /// Only valid after the first successful read
+    #[cfg(feature = "aead-cipher-2022")]
+    pub fn user(&self) -> Option<&Arc<ServerUser>> {
+        self.stream.user()
+    }
 }
 
 impl<S> ProxyServerStream<S>
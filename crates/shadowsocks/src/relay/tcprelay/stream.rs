@@ -15,7 +15,7 @@ use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
 
 use crate::{
     context::Context,
-    crypto::v1::{Cipher, CipherKind},
+    crypto::{v1::Cipher, CipherKind},
 };
 
 enum DecryptReadState {
@@ -29,6 +29,7 @@ pub struct DecryptedReader {
     cipher: Option<Cipher>,
     buffer: BytesMut,
     method: CipherKind,
+    nonce_replayed: bool,
 }
 
 impl DecryptedReader {
@@ -41,6 +42,7 @@ impl DecryptedReader {
                 cipher: None,
                 buffer: BytesMut::with_capacity(method.iv_len()),
                 method,
+                nonce_replayed: false,
             }
         } else {
             DecryptedReader {
@@ -48,10 +50,16 @@ impl DecryptedReader {
                 cipher: Some(Cipher::new(method, key, &[])),
                 buffer: BytesMut::new(),
                 method,
+                nonce_replayed: false,
             }
         }
     }
 
+    /// Check if IV of this stream is a repeated one (`ReplayAttackPolicy::Detect`)
+    pub fn nonce_replayed(&self) -> bool {
+        self.nonce_replayed
+    }
+
     /// Attempt to read decrypted data from reader
     pub fn poll_read_decrypted<S>(
         &mut self,
@@ -112,7 +120,7 @@ impl DecryptedReader {
         }
 
         let iv = &self.buffer[..iv_len];
-        context.check_nonce_replay(iv)?;
+        self.nonce_replayed = context.check_nonce_replayed(iv)?;
 
         trace!("got stream iv {:?}", ByteStr::new(iv));
 
@@ -14,7 +14,10 @@ use futures::ready;
 use pin_project::pin_project;
 use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
 
-use crate::crypto::v1::{CipherCategory, CipherKind};
+use crate::{
+    crypto::{CipherCategory, CipherKind},
+    relay::buffer_pool::{tcp_buffer_pool, BufferPool},
+};
 
 #[derive(Debug)]
 struct CopyBuffer {
@@ -22,17 +25,26 @@ struct CopyBuffer {
     pos: usize,
     cap: usize,
     amt: u64,
-    buf: Box<[u8]>,
+    // Taken from `pool` for reading, and put back when all data read has been written
+    buf: Option<Box<[u8]>>,
+    pool: &'static BufferPool,
 }
 
 impl CopyBuffer {
-    fn new(buffer_size: usize) -> Self {
+    fn new(pool: &'static BufferPool) -> Self {
         Self {
             read_done: false,
             pos: 0,
             cap: 0,
             amt: 0,
-            buf: vec![0; buffer_size].into_boxed_slice(),
+            buf: None,
+            pool,
+        }
+    }
+
+    fn release(&mut self) {
+        if let Some(buf) = self.buf.take() {
+            self.pool.release(buf);
         }
     }
 
@@ -51,11 +63,23 @@ impl CopyBuffer {
             // continue.
             if self.pos == self.cap && !self.read_done {
                 let me = &mut *self;
-                let mut buf = ReadBuf::new(&mut me.buf);
-                ready!(reader.as_mut().poll_read(cx, &mut buf))?;
+                let pool = me.pool;
+                let mut buf = ReadBuf::new(me.buf.get_or_insert_with(|| pool.acquire()));
+                let result = reader.as_mut().poll_read(cx, &mut buf);
                 let n = buf.filled().len();
+                match result {
+                    Poll::Ready(Ok(())) => {}
+                    Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                    Poll::Pending => {
+                        // Idle connections don't hold buffers
+                        self.release();
+                        return Poll::Pending;
+                    }
+                }
+
                 if n == 0 {
                     self.read_done = true;
+                    self.release();
                 } else {
                     self.pos = 0;
                     self.cap = n;
@@ -65,7 +89,8 @@ impl CopyBuffer {
             // If our buffer has some data, let's write it out!
             while self.pos < self.cap {
                 let me = &mut *self;
-                let i = ready!(writer.as_mut().poll_write(cx, &me.buf[me.pos..me.cap]))?;
+                let buf = me.buf.as_ref().expect("buffer of data read");
+                let i = ready!(writer.as_mut().poll_write(cx, &buf[me.pos..me.cap]))?;
                 if i == 0 {
                     return Poll::Ready(Err(io::Error::new(
                         io::ErrorKind::WriteZero,
@@ -87,6 +112,12 @@ impl CopyBuffer {
     }
 }
 
+impl Drop for CopyBuffer {
+    fn drop(&mut self) {
+        self.release();
+    }
+}
+
 /// A future that asynchronously copies the entire contents of a reader into a
 /// writer.
 #[derive(Debug)]
@@ -113,7 +144,9 @@ where
 }
 
 /// Copy data from encrypted reader to plain writer
-pub async fn copy_from_encrypted<ER, PW>(method: CipherKind, reader: &mut ER, writer: &mut PW) -> io::Result<u64>
+///
+/// Buffers are taken from `tcp_buffer_pool`, `method` is no longer used for sizing them.
+pub async fn copy_from_encrypted<ER, PW>(_method: CipherKind, reader: &mut ER, writer: &mut PW) -> io::Result<u64>
 where
     ER: AsyncRead + Unpin + ?Sized,
     PW: AsyncWrite + Unpin + ?Sized,
@@ -121,13 +154,15 @@ where
     Copy {
         reader,
         writer,
-        buf: CopyBuffer::new(encrypted_read_buffer_size(method)),
+        buf: CopyBuffer::new(tcp_buffer_pool()),
     }
     .await
 }
 
 /// Copy data from plain reader to encrypted writer
-pub async fn copy_to_encrypted<PR, EW>(method: CipherKind, reader: &mut PR, writer: &mut EW) -> io::Result<u64>
+///
+/// Buffers are taken from `tcp_buffer_pool`, `method` is no longer used for sizing them.
+pub async fn copy_to_encrypted<PR, EW>(_method: CipherKind, reader: &mut PR, writer: &mut EW) -> io::Result<u64>
 where
     PR: AsyncRead + Unpin + ?Sized,
     EW: AsyncWrite + Unpin + ?Sized,
@@ -135,7 +170,7 @@ where
     Copy {
         reader,
         writer,
-        buf: CopyBuffer::new(plain_read_buffer_size(method)),
+        buf: CopyBuffer::new(tcp_buffer_pool()),
     }
     .await
 }
@@ -143,6 +178,8 @@ where
 fn encrypted_read_buffer_size(method: CipherKind) -> usize {
     match method.category() {
         CipherCategory::Aead => super::aead::MAX_PACKET_SIZE + method.tag_len(),
+        #[cfg(feature = "aead-cipher-2022")]
+        CipherCategory::Aead2022 => super::aead_2022::MAX_PACKET_SIZE + method.tag_len(),
         #[cfg(feature = "stream-cipher")]
         CipherCategory::Stream => 1 << 14,
         CipherCategory::None => 1 << 14,
@@ -152,6 +189,8 @@ fn encrypted_read_buffer_size(method: CipherKind) -> usize {
 fn plain_read_buffer_size(method: CipherKind) -> usize {
     match method.category() {
         CipherCategory::Aead => super::aead::MAX_PACKET_SIZE,
+        #[cfg(feature = "aead-cipher-2022")]
+        CipherCategory::Aead2022 => super::aead_2022::MAX_PACKET_SIZE,
         #[cfg(feature = "stream-cipher")]
         CipherCategory::Stream => 1 << 14,
         CipherCategory::None => 1 << 14,
@@ -267,8 +306,13 @@ where
 /// # Return value
 ///
 /// Returns a tuple of bytes copied `encrypted` to `plain` and bytes copied `plain` to `encrypted`.
+///
+/// # Buffers
+///
+/// Buffers are taken from `tcp_buffer_pool` only while there is data to be copied, so idle connections don't hold
+/// any. `method` is no longer used for sizing them.
 pub async fn copy_encrypted_bidirectional<E, P>(
-    method: CipherKind,
+    _method: CipherKind,
     encrypted: &mut E,
     plain: &mut P,
 ) -> Result<(u64, u64), std::io::Error>
@@ -279,8 +323,70 @@ where
     CopyBidirectional {
         a: encrypted,
         b: plain,
-        a_to_b: TransferState::Running(CopyBuffer::new(encrypted_read_buffer_size(method))),
-        b_to_a: TransferState::Running(CopyBuffer::new(plain_read_buffer_size(method))),
+        a_to_b: TransferState::Running(CopyBuffer::new(tcp_buffer_pool())),
+        b_to_a: TransferState::Running(CopyBuffer::new(tcp_buffer_pool())),
+    }
+    .await
+}
+
+/// Copies data in both directions between `encrypted` stream and `plain` stream, like `copy_encrypted_bidirectional`
+///
+/// Buffers of each direction are taken from `encrypted_to_plain` and `plain_to_encrypted` respectively.
+pub async fn copy_encrypted_bidirectional_with_pools<E, P>(
+    encrypted: &mut E,
+    plain: &mut P,
+    encrypted_to_plain: &'static BufferPool,
+    plain_to_encrypted: &'static BufferPool,
+) -> io::Result<(u64, u64)>
+where
+    E: AsyncRead + AsyncWrite + Unpin + ?Sized,
+    P: AsyncRead + AsyncWrite + Unpin + ?Sized,
+{
+    CopyBidirectional {
+        a: encrypted,
+        b: plain,
+        a_to_b: TransferState::Running(CopyBuffer::new(encrypted_to_plain)),
+        b_to_a: TransferState::Running(CopyBuffer::new(plain_to_encrypted)),
+    }
+    .await
+}
+
+/// Copies data in both directions between plain streams `a` and `b`, like `tokio::io::copy_bidirectional`
+///
+/// Buffers are taken from `tcp_buffer_pool`, like `copy_encrypted_bidirectional`. Returns a tuple of bytes copied
+/// `a` to `b` and bytes copied `b` to `a`.
+pub async fn copy_bidirectional<A, B>(a: &mut A, b: &mut B) -> io::Result<(u64, u64)>
+where
+    A: AsyncRead + AsyncWrite + Unpin + ?Sized,
+    B: AsyncRead + AsyncWrite + Unpin + ?Sized,
+{
+    CopyBidirectional {
+        a,
+        b,
+        a_to_b: TransferState::Running(CopyBuffer::new(tcp_buffer_pool())),
+        b_to_a: TransferState::Running(CopyBuffer::new(tcp_buffer_pool())),
+    }
+    .await
+}
+
+/// Copies data in both directions between plain streams `a` and `b`, like `copy_bidirectional`
+///
+/// Buffers of each direction are taken from `a_to_b` and `b_to_a` respectively.
+pub async fn copy_bidirectional_with_pools<A, B>(
+    a: &mut A,
+    b: &mut B,
+    a_to_b: &'static BufferPool,
+    b_to_a: &'static BufferPool,
+) -> io::Result<(u64, u64)>
+where
+    A: AsyncRead + AsyncWrite + Unpin + ?Sized,
+    B: AsyncRead + AsyncWrite + Unpin + ?Sized,
+{
+    CopyBidirectional {
+        a,
+        b,
+        a_to_b: TransferState::Running(CopyBuffer::new(a_to_b)),
+        b_to_a: TransferState::Running(CopyBuffer::new(b_to_a)),
     }
     .await
 }
@@ -0,0 +1,80 @@
+//! UDP-over-TCP
+//!
+//! UDP packets are carried in a shadowsocks TCP stream, for networks that UDP is blocked or unreliable.
+//!
+//! Client connects to the special target address [`UDP_OVER_TCP_DOMAIN`], and then packets in both directions are
+//! framed as
+//!
+//! ```plain
+//! +------+----------+----------+
+//! | ADDR | LEN      | DATA     |
+//! +------+----------+----------+
+//! | Var  | u16 (BE) | Variable |
+//! +------+----------+----------+
+//! ```
+//!
+//! `ADDR` is in SOCKS5 address format. It is the target address in packets sending to the server, and the source
+//! address in packets sending back to the client.
+
+use std::io::{self, ErrorKind};
+
+use bytes::{BufMut, BytesMut};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use super::socks5::Address;
+
+/// Target domain name for requesting an UDP-over-TCP stream
+pub const UDP_OVER_TCP_DOMAIN: &str = "udp-over-tcp.shadowsocks.arpa";
+
+/// Target address for requesting an UDP-over-TCP stream
+pub fn udp_over_tcp_address() -> Address {
+    Address::DomainNameAddress(UDP_OVER_TCP_DOMAIN.to_owned(), 0)
+}
+
+/// Check if `addr` is requesting an UDP-over-TCP stream
+pub fn is_udp_over_tcp_address(addr: &Address) -> bool {
+    matches!(*addr, Address::DomainNameAddress(ref dname, 0) if dname == UDP_OVER_TCP_DOMAIN)
+}
+
+/// Read a packet from `reader`
+///
+/// Returns the length of data read into `buf` and the address of the packet
+pub async fn read_packet<R>(reader: &mut R, buf: &mut [u8]) -> io::Result<(usize, Address)>
+where
+    R: AsyncRead + Unpin,
+{
+    let addr = Address::read_from(reader).await?;
+    let len = reader.read_u16().await? as usize;
+
+    if len > buf.len() {
+        let err = io::Error::new(
+            ErrorKind::InvalidData,
+            format!("udp-over-tcp packet length {} exceeds buffer size {}", len, buf.len()),
+        );
+        return Err(err);
+    }
+
+    reader.read_exact(&mut buf[..len]).await?;
+    Ok((len, addr))
+}
+
+/// Write a packet with `data` and `addr` to `writer`
+pub async fn write_packet<W>(writer: &mut W, addr: &Address, data: &[u8]) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    if data.len() > u16::MAX as usize {
+        let err = io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("udp-over-tcp packet length {} is too large", data.len()),
+        );
+        return Err(err);
+    }
+
+    let mut buffer = BytesMut::with_capacity(addr.serialized_len() + 2 + data.len());
+    addr.write_to_buf(&mut buffer);
+    buffer.put_u16(data.len() as u16);
+    buffer.put_slice(data);
+
+    writer.write_all(&buffer).await
+}
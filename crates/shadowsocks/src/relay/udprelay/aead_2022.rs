@@ -0,0 +1,480 @@
+//! AEAD 2022 UDP protocol
+//!
+//! AEAD 2022 protocol is defined in <https://github.com/shadowsocks/shadowsocks-org/issues/196>.
+//!
+//! Packets of `2022-blake3-aes-*-gcm`
+//! ```plain
+//! +---------------------------+----------------------------------------+-------+
+//! |     *Separate Header*     |                 *Body*                 |  TAG  |
+//! +------------+--------------+----------------------------------------+-------+
+//! | Session ID |  Packet ID   |       TYPE + TIMESTAMP + ... + DATA    | Fixed |
+//! +------------+--------------+----------------------------------------+-------+
+//! |     8      |      8       |                Variable                |       |
+//! +------------+--------------+----------------------------------------+-------+
+//! ```
+//!
+//! Separate header is encrypted by AES with the pre-shared key in one block, and body is encrypted with a subkey
+//! derived from the pre-shared key and session ID, with the last 12 bytes of separate header as nonce.
+//!
+//! Packets sent to multi-user servers have 16 bytes Extensible Identity Headers between separate header and body,
+//! one for each identity key (iPSK). Separate header is encrypted with the first identity key, and an identity
+//! header is the hash of the next key XOR separate header, encrypted by AES with the identity key. Body and
+//! packets sent back by servers are encrypted with the user's key (uPSK).
+//!
+//! Packets of `2022-blake3-chacha20-poly1305`
+//! ```plain
+//! +-------+------------+-----------+----------------------------------+-------+
+//! | NONCE | Session ID | Packet ID |  TYPE + TIMESTAMP + ... + DATA   |  TAG  |
+//! +-------+------------+-----------+----------------------------------+-------+
+//! |  24   |     8      |     8     |             Variable             | Fixed |
+//! +-------+------------+-----------+----------------------------------+-------+
+//! ```
+//!
+//! Packets are encrypted by XChaCha20-Poly1305 with the pre-shared key.
+//!
+//! Body of requests and responses
+//! ```plain
+//! Request
+//! +------+-----------+-------------+---------+---------+----------+
+//! | TYPE | TIMESTAMP | Padding Len | Padding | ADDRESS |   DATA   |
+//! +------+-----------+-------------+---------+---------+----------+
+//! |  1   |     8     |      2      | Variable| Variable| Variable |
+//! +------+-----------+-------------+---------+---------+----------+
+//!
+//! Response
+//! +------+-----------+-------------------+-------------+---------+---------+----------+
+//! | TYPE | TIMESTAMP | Client Session ID | Padding Len | Padding | ADDRESS |   DATA   |
+//! +------+-----------+-------------------+-------------+---------+---------+----------+
+//! |  1   |     8     |         8         |      2      | Variable| Variable| Variable |
+//! +------+-----------+-------------------+-------------+---------+---------+----------+
+//! ```
+
+use std::{
+    io::{self, Cursor, ErrorKind},
+    sync::Arc,
+};
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use log::trace;
+
+use crate::{
+    config::{ServerUser, ServerUserManager},
+    context::Context,
+    crypto::{v2::udp::UdpCipher, CipherKind},
+    relay::{
+        socks5::Address,
+        tcprelay::aead_2022::{
+            aes_decrypt_block,
+            aes_encrypt_block,
+            get_now_timestamp,
+            identity_hash,
+            IDENTITY_HEADER_LEN,
+        },
+    },
+};
+
+use super::crypto_io::UdpSocketControlData;
+
+const CLIENT_PACKET_TYPE: u8 = 0;
+const SERVER_PACKET_TYPE: u8 = 1;
+
+const BLOCK_BITS: u64 = 64;
+const BLOCK_BIT_LOG: u64 = 6;
+const RING_BLOCKS: u64 = 16;
+const WINDOW_SIZE: u64 = (RING_BLOCKS - 1) * BLOCK_BITS;
+
+/// Sliding window of packet IDs received in a session, against replayed packets
+///
+/// Implements the bitmap of [RFC 6479](https://datatracker.ietf.org/doc/html/rfc6479), packets older than
+/// 960 packets ago are rejected.
+#[derive(Debug, Clone, Default)]
+pub struct PacketWindowFilter {
+    last_packet_id: u64,
+    ring: [u64; RING_BLOCKS as usize],
+}
+
+impl PacketWindowFilter {
+    /// Create an empty filter
+    pub fn new() -> PacketWindowFilter {
+        PacketWindowFilter::default()
+    }
+
+    /// Check if `packet_id` hasn't been received and isn't too old, then remember it
+    pub fn validate_packet_id(&mut self, packet_id: u64) -> bool {
+        let block = packet_id >> BLOCK_BIT_LOG;
+
+        if packet_id > self.last_packet_id {
+            let current = self.last_packet_id >> BLOCK_BIT_LOG;
+            let diff = u64::min(block - current, RING_BLOCKS);
+            for i in 1..=diff {
+                self.ring[((current + i) & (RING_BLOCKS - 1)) as usize] = 0;
+            }
+            self.last_packet_id = packet_id;
+        } else if self.last_packet_id - packet_id > WINDOW_SIZE {
+            return false;
+        }
+
+        let index = (block & (RING_BLOCKS - 1)) as usize;
+        let bit = 1u64 << (packet_id & (BLOCK_BITS - 1));
+        let old = self.ring[index];
+        self.ring[index] = old | bit;
+        old & bit == 0
+    }
+}
+
+#[inline]
+fn is_aes_method(method: CipherKind) -> bool {
+    matches!(
+        method,
+        CipherKind::AEAD2022_BLAKE3_AES_128_GCM | CipherKind::AEAD2022_BLAKE3_AES_256_GCM
+    )
+}
+
+#[allow(clippy::too_many_arguments)]
+fn encrypt_payload_aead_2022(
+    context: &Context,
+    method: CipherKind,
+    key: &[u8],
+    identity_keys: &[Bytes],
+    session_id: u64,
+    packet_id: u64,
+    put_body_header: impl FnOnce(&mut BytesMut),
+    addr: &Address,
+    payload: &[u8],
+    dst: &mut BytesMut,
+) {
+    let tag_len = method.tag_len();
+    let nonce_len = if is_aes_method(method) { 0 } else { method.nonce_len() };
+    let identity_len = identity_keys.len() * IDENTITY_HEADER_LEN;
+
+    dst.reserve(nonce_len + 16 + identity_len + 1 + 8 + 8 + 2 + addr.serialized_len() + payload.len() + tag_len);
+
+    // NONCE (XChaCha20-Poly1305 only)
+    dst.resize(nonce_len, 0);
+    context.generate_nonce(&mut dst[..nonce_len], false);
+
+    dst.put_u64(session_id);
+    dst.put_u64(packet_id);
+    // Identity headers (AES only), filled after encrypting body
+    dst.put_bytes(0, identity_len);
+    put_body_header(dst);
+    // No padding
+    dst.put_u16(0);
+    addr.write_to_buf(dst);
+    dst.put_slice(payload);
+    unsafe {
+        dst.advance_mut(tag_len);
+    }
+
+    let cipher = UdpCipher::new(method, key, session_id);
+    if is_aes_method(method) {
+        let (header, body) = dst.split_at_mut(16);
+        let (identity_headers, body) = body.split_at_mut(identity_len);
+        cipher.encrypt_packet(&header[4..16], body);
+
+        for (i, (identity_key, identity_header)) in identity_keys
+            .iter()
+            .zip(identity_headers.chunks_mut(IDENTITY_HEADER_LEN))
+            .enumerate()
+        {
+            let next_key = match identity_keys.get(i + 1) {
+                Some(k) => k.as_ref(),
+                None => key,
+            };
+            let hash = identity_hash(next_key);
+            for (b, (h, s)) in identity_header.iter_mut().zip(hash.iter().zip(header.iter())) {
+                *b = h ^ s;
+            }
+            aes_encrypt_block(method, identity_key, identity_header);
+        }
+
+        let header_key = match identity_keys.first() {
+            Some(k) => k.as_ref(),
+            None => key,
+        };
+        aes_encrypt_block(method, header_key, header);
+    } else {
+        let (nonce, data) = dst.split_at_mut(nonce_len);
+        cipher.encrypt_packet(nonce, data);
+    }
+}
+
+/// Encrypt a packet sent by client, with identity headers of `identity_keys` (iPSKs) for multi-user servers
+#[allow(clippy::too_many_arguments)]
+pub fn encrypt_client_payload_aead_2022(
+    context: &Context,
+    method: CipherKind,
+    key: &[u8],
+    identity_keys: &[Bytes],
+    addr: &Address,
+    control: &UdpSocketControlData,
+    payload: &[u8],
+    dst: &mut BytesMut,
+) {
+    encrypt_payload_aead_2022(
+        context,
+        method,
+        key,
+        identity_keys,
+        control.client_session_id,
+        control.packet_id,
+        |buf| {
+            buf.put_u8(CLIENT_PACKET_TYPE);
+            buf.put_u64(get_now_timestamp());
+        },
+        addr,
+        payload,
+        dst,
+    )
+}
+
+/// Encrypt a packet sent by server, with key of the user in `control` for multi-user servers
+pub fn encrypt_server_payload_aead_2022(
+    context: &Context,
+    method: CipherKind,
+    key: &[u8],
+    addr: &Address,
+    control: &UdpSocketControlData,
+    payload: &[u8],
+    dst: &mut BytesMut,
+) {
+    let key = match control.user {
+        Some(ref user) => user.key(),
+        None => key,
+    };
+    encrypt_payload_aead_2022(
+        context,
+        method,
+        key,
+        &[],
+        control.server_session_id,
+        control.packet_id,
+        |buf| {
+            buf.put_u8(SERVER_PACKET_TYPE);
+            buf.put_u64(get_now_timestamp());
+            buf.put_u64(control.client_session_id);
+        },
+        addr,
+        payload,
+        dst,
+    )
+}
+
+// Decrypt `payload` in place, returns (session ID, packet ID, user, body)
+//
+// Users of multi-user servers in `user_manager` are identified by identity headers
+#[allow(clippy::type_complexity)]
+fn decrypt_payload_aead_2022<'a>(
+    method: CipherKind,
+    key: &[u8],
+    user_manager: Option<&ServerUserManager>,
+    payload: &'a mut [u8],
+) -> io::Result<(u64, u64, Option<Arc<ServerUser>>, &'a mut [u8])> {
+    let tag_len = method.tag_len();
+
+    let (session_id, packet_id, user, body) = if is_aes_method(method) {
+        let identity_len = if user_manager.is_some() { IDENTITY_HEADER_LEN } else { 0 };
+        if payload.len() < 16 + identity_len + tag_len {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "udp packet too short for header",
+            ));
+        }
+
+        let (header, body) = payload.split_at_mut(16);
+        aes_decrypt_block(method, key, header);
+
+        let mut h = &header[..];
+        let session_id = h.get_u64();
+        let packet_id = h.get_u64();
+
+        let (identity_header, body) = body.split_at_mut(identity_len);
+        let user = match user_manager {
+            Some(user_manager) => {
+                aes_decrypt_block(method, key, identity_header);
+                for (b, s) in identity_header.iter_mut().zip(header.iter()) {
+                    *b ^= s;
+                }
+
+                match user_manager.get_user_by_hash(identity_header) {
+                    Some(user) => Some(user),
+                    None => {
+                        return Err(io::Error::new(
+                            ErrorKind::InvalidData,
+                            "udp packet with unknown user identity",
+                        ));
+                    }
+                }
+            }
+            None => None,
+        };
+
+        let body_key = match user {
+            Some(ref user) => user.key(),
+            None => key,
+        };
+        let cipher = UdpCipher::new(method, body_key, session_id);
+        if !cipher.decrypt_packet(&header[4..16], body) {
+            return Err(io::Error::new(ErrorKind::Other, "invalid tag-in"));
+        }
+
+        (session_id, packet_id, user, body)
+    } else {
+        let nonce_len = method.nonce_len();
+        if payload.len() < nonce_len + 16 + tag_len {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "udp packet too short for header",
+            ));
+        }
+
+        let (nonce, data) = payload.split_at_mut(nonce_len);
+        let cipher = UdpCipher::new(method, key, 0);
+        if !cipher.decrypt_packet(nonce, data) {
+            return Err(io::Error::new(ErrorKind::Other, "invalid tag-in"));
+        }
+
+        let (header, body) = data.split_at_mut(16);
+        let mut h = &header[..];
+        (h.get_u64(), h.get_u64(), None, body)
+    };
+
+    let body_len = body.len() - tag_len;
+    Ok((session_id, packet_id, user, &mut body[..body_len]))
+}
+
+// Parse the rest of body after TYPE + TIMESTAMP (+ Client Session ID), returns (data start, data length, address)
+async fn parse_body(body: &[u8]) -> io::Result<(usize, usize, Address)> {
+    if body.len() < 2 {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            "udp packet too short for padding length",
+        ));
+    }
+    let padding_len = u16::from_be_bytes([body[0], body[1]]) as usize;
+    if body.len() < 2 + padding_len {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            "udp packet too short for padding",
+        ));
+    }
+
+    let addr_start = 2 + padding_len;
+    let mut cur = Cursor::new(&body[addr_start..]);
+    let addr = match Address::read_from(&mut cur).await {
+        Ok(a) => a,
+        Err(..) => {
+            let err = io::Error::new(ErrorKind::InvalidData, "parse udp packet Address failed");
+            return Err(err);
+        }
+    };
+
+    let data_start = addr_start + cur.position() as usize;
+    Ok((data_start, body.len() - data_start, addr))
+}
+
+/// Decrypt a packet sent by client, identifying users of multi-user servers in `user_manager`
+pub async fn decrypt_client_payload_aead_2022(
+    context: &Context,
+    method: CipherKind,
+    key: &[u8],
+    user_manager: Option<&ServerUserManager>,
+    payload: &mut [u8],
+) -> io::Result<(usize, Address, UdpSocketControlData)> {
+    let (client_session_id, packet_id, user, body) = decrypt_payload_aead_2022(method, key, user_manager, payload)?;
+
+    if body.len() < 1 + 8 {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            "udp packet too short for body header",
+        ));
+    }
+    let mut h = &body[..];
+    let packet_type = h.get_u8();
+    if packet_type != CLIENT_PACKET_TYPE {
+        let err = io::Error::new(
+            ErrorKind::InvalidData,
+            format!("invalid client packet type {}", packet_type),
+        );
+        return Err(err);
+    }
+    context.check_timestamp_2022(h.get_u64())?;
+
+    let header_len = 1 + 8;
+    let (data_start, data_len, addr) = parse_body(&body[header_len..]).await?;
+    let data_start = header_len + data_start;
+    body.copy_within(data_start..data_start + data_len, 0);
+    // Body is a suffix of payload
+    let body_offset = match user {
+        Some(..) => payload_offset(method) + IDENTITY_HEADER_LEN,
+        None => payload_offset(method),
+    };
+    payload.copy_within(body_offset..body_offset + data_len, 0);
+
+    trace!(
+        "UDP packet got AEAD 2022 client session {:#x} packet {}",
+        client_session_id,
+        packet_id
+    );
+
+    let control = UdpSocketControlData {
+        client_session_id,
+        server_session_id: 0,
+        packet_id,
+        user,
+    };
+    Ok((data_len, addr, control))
+}
+
+/// Decrypt a packet sent by server
+pub async fn decrypt_server_payload_aead_2022(
+    context: &Context,
+    method: CipherKind,
+    key: &[u8],
+    payload: &mut [u8],
+) -> io::Result<(usize, Address, UdpSocketControlData)> {
+    let (server_session_id, packet_id, _, body) = decrypt_payload_aead_2022(method, key, None, payload)?;
+
+    if body.len() < 1 + 8 + 8 {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            "udp packet too short for body header",
+        ));
+    }
+    let mut h = &body[..];
+    let packet_type = h.get_u8();
+    if packet_type != SERVER_PACKET_TYPE {
+        let err = io::Error::new(
+            ErrorKind::InvalidData,
+            format!("invalid server packet type {}", packet_type),
+        );
+        return Err(err);
+    }
+    context.check_timestamp_2022(h.get_u64())?;
+    let client_session_id = h.get_u64();
+
+    let header_len = 1 + 8 + 8;
+    let (data_start, data_len, addr) = parse_body(&body[header_len..]).await?;
+    let data_start = header_len + data_start;
+    body.copy_within(data_start..data_start + data_len, 0);
+    let body_offset = payload_offset(method);
+    payload.copy_within(body_offset..body_offset + data_len, 0);
+
+    let control = UdpSocketControlData {
+        client_session_id,
+        server_session_id,
+        packet_id,
+        user: None,
+    };
+    Ok((data_len, addr, control))
+}
+
+// Offset of body in packets
+#[inline]
+fn payload_offset(method: CipherKind) -> usize {
+    if is_aes_method(method) {
+        16
+    } else {
+        method.nonce_len() + 16
+    }
+}
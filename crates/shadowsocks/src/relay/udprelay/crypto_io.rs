@@ -19,20 +19,124 @@
 //! | Fixed  | Variable  |   Fixed   |
 //! +--------+-----------+-----------+
 //! ```
+//!
+//! Payload with AEAD 2022 cipher is defined in the `aead_2022` module
 use std::io::{self, Cursor, ErrorKind};
+#[cfg(feature = "aead-cipher-2022")]
+use std::sync::Arc;
 
 use byte_string::ByteStr;
 use bytes::{BufMut, BytesMut};
 use log::trace;
 
+#[cfg(feature = "aead-cipher-2022")]
+use crate::config::ServerUser;
 use crate::{
     context::Context,
-    crypto::v1::{Cipher, CipherCategory, CipherKind},
+    crypto::{v1::Cipher, CipherCategory, CipherKind},
     relay::socks5::Address,
 };
 
+#[cfg(feature = "aead-cipher-2022")]
+use super::aead_2022::{
+    decrypt_client_payload_aead_2022,
+    decrypt_server_payload_aead_2022,
+    encrypt_client_payload_aead_2022,
+    encrypt_server_payload_aead_2022,
+};
+
+/// Control data of a UDP packet
+///
+/// Only AEAD 2022 ciphers have session and packet IDs in packets, they are left `0` for the other ciphers.
+#[derive(Debug, Clone, Default)]
+pub struct UdpSocketControlData {
+    /// Session ID of the client
+    pub client_session_id: u64,
+    /// Session ID of the server, `0` in packets sent by clients
+    pub server_session_id: u64,
+    /// Packet ID in the session of the sender
+    pub packet_id: u64,
+    /// User of multi-user servers, who sent the packet or will receive it
+    #[cfg(feature = "aead-cipher-2022")]
+    pub user: Option<Arc<ServerUser>>,
+}
+
+/// Encrypt `Client -> Server` payload into ShadowSocks UDP encrypted packet
+#[allow(unused_variables)]
+pub fn encrypt_client_payload(
+    context: &Context,
+    method: CipherKind,
+    key: &[u8],
+    addr: &Address,
+    control: &UdpSocketControlData,
+    payload: &[u8],
+    dst: &mut BytesMut,
+) {
+    match method.category() {
+        #[cfg(feature = "aead-cipher-2022")]
+        CipherCategory::Aead2022 => {
+            encrypt_client_payload_aead_2022(context, method, key, &[], addr, control, payload, dst)
+        }
+        _ => encrypt_payload(context, method, key, addr, payload, dst),
+    }
+}
+
+/// Encrypt `Server -> Client` payload into ShadowSocks UDP encrypted packet
+#[allow(unused_variables)]
+pub fn encrypt_server_payload(
+    context: &Context,
+    method: CipherKind,
+    key: &[u8],
+    addr: &Address,
+    control: &UdpSocketControlData,
+    payload: &[u8],
+    dst: &mut BytesMut,
+) {
+    match method.category() {
+        #[cfg(feature = "aead-cipher-2022")]
+        CipherCategory::Aead2022 => encrypt_server_payload_aead_2022(context, method, key, addr, control, payload, dst),
+        _ => encrypt_payload(context, method, key, addr, payload, dst),
+    }
+}
+
+/// Decrypt `Client -> Server` payload from ShadowSocks UDP encrypted packet
+pub async fn decrypt_client_payload(
+    context: &Context,
+    method: CipherKind,
+    key: &[u8],
+    payload: &mut [u8],
+) -> io::Result<(usize, Address, Option<UdpSocketControlData>)> {
+    match method.category() {
+        #[cfg(feature = "aead-cipher-2022")]
+        CipherCategory::Aead2022 => decrypt_client_payload_aead_2022(context, method, key, None, payload)
+            .await
+            .map(|(n, a, c)| (n, a, Some(c))),
+        _ => decrypt_payload(context, method, key, payload)
+            .await
+            .map(|(n, a)| (n, a, None)),
+    }
+}
+
+/// Decrypt `Server -> Client` payload from ShadowSocks UDP encrypted packet
+pub async fn decrypt_server_payload(
+    context: &Context,
+    method: CipherKind,
+    key: &[u8],
+    payload: &mut [u8],
+) -> io::Result<(usize, Address, Option<UdpSocketControlData>)> {
+    match method.category() {
+        #[cfg(feature = "aead-cipher-2022")]
+        CipherCategory::Aead2022 => decrypt_server_payload_aead_2022(context, method, key, payload)
+            .await
+            .map(|(n, a, c)| (n, a, Some(c))),
+        _ => decrypt_payload(context, method, key, payload)
+            .await
+            .map(|(n, a)| (n, a, None)),
+    }
+}
+
 /// Encrypt payload into ShadowSocks UDP encrypted packet
-pub fn encrypt_payload(
+fn encrypt_payload(
     context: &Context,
     method: CipherKind,
     key: &[u8],
@@ -49,6 +153,8 @@ pub fn encrypt_payload(
         #[cfg(feature = "stream-cipher")]
         CipherCategory::Stream => encrypt_payload_stream(context, method, key, addr, payload, dst),
         CipherCategory::Aead => encrypt_payload_aead(context, method, key, addr, payload, dst),
+        #[cfg(feature = "aead-cipher-2022")]
+        CipherCategory::Aead2022 => unreachable!("AEAD 2022 packets are encrypted with control data"),
     }
 }
 
@@ -121,7 +227,7 @@ fn encrypt_payload_aead(
 }
 
 /// Decrypt payload from ShadowSocks UDP encrypted packet
-pub async fn decrypt_payload(
+async fn decrypt_payload(
     context: &Context,
     method: CipherKind,
     key: &[u8],
@@ -146,6 +252,8 @@ pub async fn decrypt_payload(
         #[cfg(feature = "stream-cipher")]
         CipherCategory::Stream => decrypt_payload_stream(context, method, key, payload).await,
         CipherCategory::Aead => decrypt_payload_aead(context, method, key, payload).await,
+        #[cfg(feature = "aead-cipher-2022")]
+        CipherCategory::Aead2022 => unreachable!("AEAD 2022 packets are decrypted with control data"),
     }
 }
 
@@ -49,8 +49,15 @@
 
 use std::time::Duration;
 
-pub use self::proxy_socket::ProxySocket;
+#[cfg(feature = "aead-cipher-2022")]
+pub use self::aead_2022::PacketWindowFilter;
+pub use self::{
+    crypto_io::UdpSocketControlData,
+    proxy_socket::{ProxySocket, UdpSocketType},
+};
 
+#[cfg(feature = "aead-cipher-2022")]
+mod aead_2022;
 mod crypto_io;
 pub mod proxy_socket;
 
@@ -1,7 +1,15 @@
 //! UDP socket for communicating with shadowsocks' proxy server
 
+#[cfg(feature = "aead-cipher-2022")]
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+    Mutex,
+};
 use std::{io, net::SocketAddr, time::Duration};
 
+#[cfg(feature = "aead-cipher-2022")]
+use bytes::Bytes;
 use bytes::BytesMut;
 use log::{trace, warn};
 use once_cell::sync::Lazy;
@@ -13,23 +21,120 @@ use tokio::{
 use crate::{
     config::{ServerAddr, ServerConfig},
     context::SharedContext,
-    crypto::v1::CipherKind,
-    net::{AcceptOpts, ConnectOpts, UdpSocket as ShadowUdpSocket},
+    crypto::CipherKind,
+    net::{
+        udp::{self as shadow_udp, RecvBatch, SendBatch},
+        AcceptOpts,
+        ConnectOpts,
+        UdpSocket as ShadowUdpSocket,
+    },
     relay::socks5::Address,
 };
 
-use super::crypto_io::{decrypt_payload, encrypt_payload};
+#[cfg(feature = "aead-cipher-2022")]
+use super::aead_2022::{decrypt_client_payload_aead_2022, encrypt_client_payload_aead_2022, PacketWindowFilter};
+use super::crypto_io::{
+    decrypt_client_payload,
+    decrypt_server_payload,
+    encrypt_client_payload,
+    encrypt_server_payload,
+    UdpSocketControlData,
+};
+#[cfg(feature = "aead-cipher-2022")]
+use crate::{config::ServerUserManager, crypto::CipherCategory};
 
 static DEFAULT_CONNECT_OPTS: Lazy<ConnectOpts> = Lazy::new(Default::default);
 
+/// UDP socket type, defining whether the socket is used in Client or Server
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UdpSocketType {
+    /// Socket used for communicating with client
+    Server,
+    /// Socket used for communicating with server
+    Client,
+}
+
+/// Session of a client socket with AEAD 2022 ciphers
+#[cfg(feature = "aead-cipher-2022")]
+struct ClientSession {
+    session_id: u64,
+    packet_id: AtomicU64,
+    // Server session ID and its received packet IDs
+    server_session: Mutex<Option<(u64, PacketWindowFilter)>>,
+}
+
+#[cfg(feature = "aead-cipher-2022")]
+impl ClientSession {
+    fn new() -> ClientSession {
+        ClientSession {
+            session_id: rand::random(),
+            packet_id: AtomicU64::new(0),
+            server_session: Mutex::new(None),
+        }
+    }
+
+    fn next_control(&self) -> UdpSocketControlData {
+        UdpSocketControlData {
+            client_session_id: self.session_id,
+            server_session_id: 0,
+            packet_id: self.packet_id.fetch_add(1, Ordering::Relaxed),
+            user: None,
+        }
+    }
+
+    fn check_control(&self, control: &UdpSocketControlData) -> io::Result<()> {
+        if control.client_session_id != self.session_id {
+            let err = io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "udp packet of client session {:#x}, expecting {:#x}",
+                    control.client_session_id, self.session_id
+                ),
+            );
+            return Err(err);
+        }
+
+        let mut server_session = self.server_session.lock().unwrap();
+        match *server_session {
+            Some((session_id, ref mut filter)) if session_id == control.server_session_id => {
+                if !filter.validate_packet_id(control.packet_id) {
+                    let err = io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!(
+                            "udp packet {} of server session {:#x} replayed",
+                            control.packet_id, session_id
+                        ),
+                    );
+                    return Err(err);
+                }
+            }
+            _ => {
+                // New session of server, may be restarted
+                let mut filter = PacketWindowFilter::new();
+                filter.validate_packet_id(control.packet_id);
+                *server_session = Some((control.server_session_id, filter));
+            }
+        }
+
+        Ok(())
+    }
+}
+
 /// UDP client for communicating with ShadowSocks' server
 pub struct ProxySocket {
+    socket_type: UdpSocketType,
     socket: UdpSocket,
     method: CipherKind,
     key: Box<[u8]>,
     send_timeout: Option<Duration>,
     recv_timeout: Option<Duration>,
     context: SharedContext,
+    #[cfg(feature = "aead-cipher-2022")]
+    client_session: Option<ClientSession>,
+    #[cfg(feature = "aead-cipher-2022")]
+    identity_keys: Vec<Bytes>,
+    #[cfg(feature = "aead-cipher-2022")]
+    user_manager: Option<Arc<ServerUserManager>>,
 }
 
 impl ProxySocket {
@@ -44,29 +149,57 @@ impl ProxySocket {
         svr_cfg: &ServerConfig,
         opts: &ConnectOpts,
     ) -> io::Result<ProxySocket> {
-        // Note: Plugins doesn't support UDP relay
-
-        let socket = ShadowUdpSocket::connect_server_with_opts(&context, svr_cfg.addr(), opts).await?;
+        // Plugins supporting UDP (SIP003u) relay to svr_cfg.addr(), servers listening on a range of ports are
+        // connected on the current port
+        let hopping_addr = svr_cfg.hopping_addr();
+        let addr = hopping_addr.as_ref().unwrap_or_else(|| svr_cfg.udp_external_addr());
+        let socket = ShadowUdpSocket::connect_server_with_opts(&context, addr, opts).await?;
 
-        trace!("connected udp remote {} with {:?}", svr_cfg.addr(), opts);
+        trace!("connected udp remote {} with {:?}", addr, opts);
 
-        Ok(ProxySocket::from_socket(context, svr_cfg, socket.into()))
+        Ok(ProxySocket::from_socket(
+            UdpSocketType::Client,
+            context,
+            svr_cfg,
+            socket.into(),
+        ))
     }
 
     /// Create a `ProxySocket` from a `UdpSocket`
-    pub fn from_socket(context: SharedContext, svr_cfg: &ServerConfig, socket: UdpSocket) -> ProxySocket {
+    pub fn from_socket(
+        socket_type: UdpSocketType,
+        context: SharedContext,
+        svr_cfg: &ServerConfig,
+        socket: UdpSocket,
+    ) -> ProxySocket {
         let key = svr_cfg.key().to_vec().into_boxed_slice();
         let method = svr_cfg.method();
 
         // NOTE: svr_cfg.timeout() is not for this socket, but for associations.
 
         ProxySocket {
+            socket_type,
             socket,
             method,
             key,
             send_timeout: None,
             recv_timeout: None,
             context,
+            #[cfg(feature = "aead-cipher-2022")]
+            client_session: match (socket_type, method.category()) {
+                (UdpSocketType::Client, CipherCategory::Aead2022) => Some(ClientSession::new()),
+                _ => None,
+            },
+            #[cfg(feature = "aead-cipher-2022")]
+            identity_keys: match socket_type {
+                UdpSocketType::Client => svr_cfg.identity_keys().to_vec(),
+                UdpSocketType::Server => Vec::new(),
+            },
+            #[cfg(feature = "aead-cipher-2022")]
+            user_manager: match socket_type {
+                UdpSocketType::Client => None,
+                UdpSocketType::Server => svr_cfg.user_manager().cloned(),
+            },
         }
     }
 
@@ -81,8 +214,8 @@ impl ProxySocket {
         svr_cfg: &ServerConfig,
         opts: AcceptOpts,
     ) -> io::Result<ProxySocket> {
-        // Plugins doesn't support UDP
-        let socket = match svr_cfg.addr() {
+        // Plugins supporting UDP (SIP003u) relay from svr_cfg.addr()
+        let socket = match svr_cfg.udp_external_addr() {
             ServerAddr::SocketAddr(sa) => ShadowUdpSocket::listen_with_opts(sa, opts).await?,
             ServerAddr::DomainName(domain, port) => {
                 lookup_then!(&context, domain, *port, |addr| {
@@ -91,13 +224,95 @@ impl ProxySocket {
                 .1
             }
         };
-        Ok(ProxySocket::from_socket(context, svr_cfg, socket.into()))
+        Ok(ProxySocket::from_socket(
+            UdpSocketType::Server,
+            context,
+            svr_cfg,
+            socket.into(),
+        ))
+    }
+
+    fn encrypt_send_buffer(
+        &self,
+        addr: &Address,
+        control: &UdpSocketControlData,
+        payload: &[u8],
+        send_buf: &mut BytesMut,
+    ) {
+        match self.socket_type {
+            #[cfg(feature = "aead-cipher-2022")]
+            UdpSocketType::Client if !self.identity_keys.is_empty() => encrypt_client_payload_aead_2022(
+                &self.context,
+                self.method,
+                &self.key,
+                &self.identity_keys,
+                addr,
+                control,
+                payload,
+                send_buf,
+            ),
+            UdpSocketType::Client => {
+                encrypt_client_payload(&self.context, self.method, &self.key, addr, control, payload, send_buf)
+            }
+            UdpSocketType::Server => {
+                encrypt_server_payload(&self.context, self.method, &self.key, addr, control, payload, send_buf)
+            }
+        }
+    }
+
+    async fn decrypt_recv_buffer(
+        &self,
+        recv_buf: &mut [u8],
+    ) -> io::Result<(usize, Address, Option<UdpSocketControlData>)> {
+        match self.socket_type {
+            UdpSocketType::Client => {
+                let result = decrypt_server_payload(&self.context, self.method, &self.key, recv_buf).await?;
+                #[cfg(feature = "aead-cipher-2022")]
+                if let (Some(ref session), Some(ref control)) = (&self.client_session, &result.2) {
+                    session.check_control(control)?;
+                }
+                Ok(result)
+            }
+            #[cfg(feature = "aead-cipher-2022")]
+            UdpSocketType::Server if self.user_manager.is_some() => {
+                let (n, addr, control) = decrypt_client_payload_aead_2022(
+                    &self.context,
+                    self.method,
+                    &self.key,
+                    self.user_manager.as_deref(),
+                    recv_buf,
+                )
+                .await?;
+                Ok((n, addr, Some(control)))
+            }
+            UdpSocketType::Server => decrypt_client_payload(&self.context, self.method, &self.key, recv_buf).await,
+        }
+    }
+
+    // Control data of the next packet sent by this socket
+    fn next_control(&self) -> UdpSocketControlData {
+        #[cfg(feature = "aead-cipher-2022")]
+        if let Some(ref session) = self.client_session {
+            return session.next_control();
+        }
+
+        UdpSocketControlData::default()
     }
 
     /// Send a UDP packet to addr through proxy
     pub async fn send(&self, addr: &Address, payload: &[u8]) -> io::Result<usize> {
+        self.send_with_ctrl(addr, &self.next_control(), payload).await
+    }
+
+    /// Send a UDP packet to addr through proxy, with control data of the packet
+    pub async fn send_with_ctrl(
+        &self,
+        addr: &Address,
+        control: &UdpSocketControlData,
+        payload: &[u8],
+    ) -> io::Result<usize> {
         let mut send_buf = BytesMut::new();
-        encrypt_payload(&self.context, self.method, &self.key, addr, payload, &mut send_buf);
+        self.encrypt_send_buffer(addr, control, payload, &mut send_buf);
 
         trace!(
             "UDP server client send to {}, payload length {} bytes, packet length {} bytes",
@@ -128,8 +343,22 @@ impl ProxySocket {
 
     /// Send a UDP packet to target from proxy
     pub async fn send_to<A: ToSocketAddrs>(&self, target: A, addr: &Address, payload: &[u8]) -> io::Result<usize> {
+        self.send_to_with_ctrl(target, addr, &self.next_control(), payload)
+            .await
+    }
+
+    /// Send a UDP packet to target from proxy, with control data of the packet
+    ///
+    /// Servers with AEAD 2022 ciphers have to send packets with session IDs of the client, and packet IDs of its own session.
+    pub async fn send_to_with_ctrl<A: ToSocketAddrs>(
+        &self,
+        target: A,
+        addr: &Address,
+        control: &UdpSocketControlData,
+        payload: &[u8],
+    ) -> io::Result<usize> {
         let mut send_buf = BytesMut::new();
-        encrypt_payload(&self.context, self.method, &self.key, addr, payload, &mut send_buf);
+        self.encrypt_send_buffer(addr, control, payload, &mut send_buf);
 
         trace!(
             "UDP server client send to, addr {}, payload length {} bytes, packet length {} bytes",
@@ -164,6 +393,20 @@ impl ProxySocket {
     ///
     /// It is recommended to allocate a buffer to have at least 65536 bytes.
     pub async fn recv(&self, recv_buf: &mut [u8]) -> io::Result<(usize, Address, usize)> {
+        self.recv_with_ctrl(recv_buf)
+            .await
+            .map(|(n, addr, recv_n, _)| (n, addr, recv_n))
+    }
+
+    /// Receive packet from Shadowsocks' UDP server, with control data of the packet
+    ///
+    /// This function will use `recv_buf` to store intermediate data, so it has to be big enough to store the whole shadowsocks' packet
+    ///
+    /// It is recommended to allocate a buffer to have at least 65536 bytes.
+    pub async fn recv_with_ctrl(
+        &self,
+        recv_buf: &mut [u8],
+    ) -> io::Result<(usize, Address, usize, Option<UdpSocketControlData>)> {
         // Waiting for response from server SERVER -> CLIENT
         let recv_n = match self.recv_timeout {
             None => self.socket.recv(recv_buf).await?,
@@ -174,7 +417,7 @@ impl ProxySocket {
             },
         };
 
-        let (n, addr) = decrypt_payload(&self.context, self.method, &self.key, &mut recv_buf[..recv_n]).await?;
+        let (n, addr, control) = self.decrypt_recv_buffer(&mut recv_buf[..recv_n]).await?;
 
         trace!(
             "UDP server client receive from {}, packet length {} bytes, payload length {} bytes",
@@ -183,7 +426,7 @@ impl ProxySocket {
             n
         );
 
-        Ok((n, addr, recv_n))
+        Ok((n, addr, recv_n, control))
     }
 
     /// Receive packet from Shadowsocks' UDP server
@@ -192,6 +435,20 @@ impl ProxySocket {
     ///
     /// It is recommended to allocate a buffer to have at least 65536 bytes.
     pub async fn recv_from(&self, recv_buf: &mut [u8]) -> io::Result<(usize, SocketAddr, Address, usize)> {
+        self.recv_from_with_ctrl(recv_buf)
+            .await
+            .map(|(n, peer_addr, addr, recv_n, _)| (n, peer_addr, addr, recv_n))
+    }
+
+    /// Receive packet from Shadowsocks' UDP server, with control data of the packet
+    ///
+    /// This function will use `recv_buf` to store intermediate data, so it has to be big enough to store the whole shadowsocks' packet
+    ///
+    /// It is recommended to allocate a buffer to have at least 65536 bytes.
+    pub async fn recv_from_with_ctrl(
+        &self,
+        recv_buf: &mut [u8],
+    ) -> io::Result<(usize, SocketAddr, Address, usize, Option<UdpSocketControlData>)> {
         // Waiting for response from server SERVER -> CLIENT
         let (recv_n, target_addr) = match self.recv_timeout {
             None => self.socket.recv_from(recv_buf).await?,
@@ -201,7 +458,7 @@ impl ProxySocket {
                 Err(..) => return Err(io::ErrorKind::TimedOut.into()),
             },
         };
-        let (n, addr) = decrypt_payload(&self.context, self.method, &self.key, &mut recv_buf[..recv_n]).await?;
+        let (n, addr, control) = self.decrypt_recv_buffer(&mut recv_buf[..recv_n]).await?;
 
         trace!(
             "UDP server client receive from {}, addr {}, packet length {} bytes, payload length {} bytes",
@@ -211,7 +468,85 @@ impl ProxySocket {
             n,
         );
 
-        Ok((n, target_addr, addr, recv_n))
+        Ok((n, target_addr, addr, recv_n, control))
+    }
+
+    /// Receive a batch of packets from Shadowsocks' UDP server or clients
+    ///
+    /// Packets are encrypted, and have to be decrypted by `decrypt_packet`. Buffers of `batch` have to be big enough
+    /// to store the whole shadowsocks' packets.
+    pub async fn recv_batch(&self, batch: &mut RecvBatch) -> io::Result<usize> {
+        let n = match self.recv_timeout {
+            None => shadow_udp::recv_batch(&self.socket, batch).await?,
+            Some(d) => match time::timeout(d, shadow_udp::recv_batch(&self.socket, batch)).await {
+                Ok(Ok(n)) => n,
+                Ok(Err(err)) => return Err(err),
+                Err(..) => return Err(io::ErrorKind::TimedOut.into()),
+            },
+        };
+
+        trace!(
+            "UDP server client receive batch of {} packets, {} bytes",
+            n,
+            batch.total_len()
+        );
+
+        Ok(n)
+    }
+
+    /// Receive packets coalesced by GRO in `recv_batch`, if UDP offload is enabled by `net::udp::init_offload`
+    ///
+    /// Packets have to be received only by `recv_batch` afterwards.
+    pub fn enable_gro(&self) -> io::Result<()> {
+        shadow_udp::enable_gro(&self.socket)
+    }
+
+    /// Decrypt a packet received by `recv_batch` in place
+    ///
+    /// Returns the length of payload at the beginning of `packet`, the address of the payload and control data.
+    pub async fn decrypt_packet(
+        &self,
+        packet: &mut [u8],
+    ) -> io::Result<(usize, Address, Option<UdpSocketControlData>)> {
+        self.decrypt_recv_buffer(packet).await
+    }
+
+    /// Encrypt a UDP packet of addr into `batch`, sent to `target` (`None` for the connected server) by `send_batch`
+    pub fn push_batch(&self, batch: &mut SendBatch, target: Option<SocketAddr>, addr: &Address, payload: &[u8]) {
+        self.push_batch_with_ctrl(batch, target, addr, &self.next_control(), payload)
+    }
+
+    /// Encrypt a UDP packet of addr into `batch`, with control data of the packet
+    pub fn push_batch_with_ctrl(
+        &self,
+        batch: &mut SendBatch,
+        target: Option<SocketAddr>,
+        addr: &Address,
+        control: &UdpSocketControlData,
+        payload: &[u8],
+    ) {
+        let send_buf = batch.push(target);
+        self.encrypt_send_buffer(addr, control, payload, send_buf);
+    }
+
+    /// Send packets encrypted into `batch`, returns the total length of packets sent
+    pub async fn send_batch(&self, batch: &mut SendBatch) -> io::Result<usize> {
+        let n = batch.len();
+        let send_len = match self.send_timeout {
+            None => shadow_udp::send_batch(&self.socket, batch).await?,
+            Some(d) => match time::timeout(d, shadow_udp::send_batch(&self.socket, batch)).await {
+                Ok(Ok(l)) => l,
+                Ok(Err(err)) => return Err(err),
+                Err(..) => {
+                    batch.clear();
+                    return Err(io::ErrorKind::TimedOut.into());
+                }
+            },
+        };
+
+        trace!("UDP server client send batch of {} packets, {} bytes", n, send_len);
+
+        Ok(send_len)
     }
 
     /// Get local addr of socket
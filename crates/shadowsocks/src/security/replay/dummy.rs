@@ -1,3 +1,5 @@
+use std::io;
+
 use crate::config::ServerType;
 
 /// A dummy protector against replay attack
@@ -17,4 +19,16 @@ impl ReplayProtector {
     pub fn check_nonce_and_set(&self, _nonce: &[u8]) -> bool {
         false
     }
+
+    /// Serialize the recorded nonces, which is always empty
+    #[inline(always)]
+    pub fn export(&self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    /// Restore nonces serialized by `export`, nothing would be restored
+    #[inline(always)]
+    pub fn import(&self, _buf: &[u8]) -> io::Result<()> {
+        Ok(())
+    }
 }
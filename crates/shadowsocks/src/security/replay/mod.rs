@@ -9,3 +9,12 @@ cfg_if! {
         pub use self::dummy::*;
     }
 }
+
+/// Observer of nonces (IV/salt) accepted by servers
+///
+/// Servers sharing the same keys could tell each other the nonces they have seen, so a connection captured by one
+/// of them couldn't be replayed to the others.
+pub trait NonceObserver: Send + Sync {
+    /// Called with a nonce that has just been checked and recorded, it shouldn't block
+    fn nonce_accepted(&self, nonce: &[u8]);
+}
@@ -1,4 +1,7 @@
+use std::io::{self, ErrorKind};
+
 use bloomfilter::Bloom;
+use bytes::{Buf, BufMut};
 use log::debug;
 use spin::Mutex as SpinMutex;
 
@@ -91,6 +94,72 @@ impl PingPongBloom {
 
         false
     }
+
+    // Serialize both filters and their counters
+    pub fn export(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.put_u64(self.item_count as u64);
+        buf.put_u8(self.current as u8);
+
+        for (bloom, count) in self.blooms.iter().zip(self.bloom_count.iter()) {
+            buf.put_u64(*count as u64);
+            buf.put_u64(bloom.number_of_bits());
+            buf.put_u32(bloom.number_of_hash_functions());
+            for (k0, k1) in bloom.sip_keys() {
+                buf.put_u64(k0);
+                buf.put_u64(k1);
+            }
+            let bitmap = bloom.bitmap();
+            buf.put_u64(bitmap.len() as u64);
+            buf.put_slice(&bitmap);
+        }
+
+        buf
+    }
+
+    // Restore filters exported by `export`, they must have the same capacity
+    pub fn import(&mut self, mut buf: &[u8]) -> io::Result<()> {
+        fn malformed() -> io::Error {
+            io::Error::new(ErrorKind::InvalidData, "malformed replay filter")
+        }
+
+        if buf.remaining() < 9 {
+            return Err(malformed());
+        }
+        if buf.get_u64() != self.item_count as u64 {
+            let err = io::Error::new(ErrorKind::InvalidData, "replay filter capacity mismatched");
+            return Err(err);
+        }
+        let current = buf.get_u8() as usize;
+        if current >= self.blooms.len() {
+            return Err(malformed());
+        }
+
+        let mut blooms = Vec::with_capacity(self.blooms.len());
+        let mut bloom_count = [0usize; 2];
+        for count in bloom_count.iter_mut() {
+            if buf.remaining() < 8 + 8 + 4 + 32 + 8 {
+                return Err(malformed());
+            }
+            *count = buf.get_u64() as usize;
+            let bits = buf.get_u64();
+            let k_num = buf.get_u32();
+            let sip_keys = [(buf.get_u64(), buf.get_u64()), (buf.get_u64(), buf.get_u64())];
+            let bitmap_len = buf.get_u64() as usize;
+            if bits == 0 || k_num == 0 || buf.remaining() < bitmap_len || bits > bitmap_len as u64 * 8 {
+                return Err(malformed());
+            }
+            blooms.push(Bloom::from_existing(&buf[..bitmap_len], bits, k_num, sip_keys));
+            buf.advance(bitmap_len);
+        }
+
+        let mut blooms = blooms.into_iter();
+        self.blooms = [blooms.next().unwrap(), blooms.next().unwrap()];
+        self.bloom_count = bloom_count;
+        self.current = current;
+
+        Ok(())
+    }
 }
 
 /// A Bloom Filter based protector against replay attach
@@ -120,4 +189,14 @@ impl ReplayProtector {
         let mut ppbloom = self.nonce_ppbloom.lock();
         ppbloom.check_and_set(nonce)
     }
+
+    /// Serialize the recorded nonces, for restoring them by `import` after restart
+    pub fn export(&self) -> Vec<u8> {
+        self.nonce_ppbloom.lock().export()
+    }
+
+    /// Restore nonces serialized by `export`, replacing the recorded ones
+    pub fn import(&self, buf: &[u8]) -> io::Result<()> {
+        self.nonce_ppbloom.lock().import(buf)
+    }
 }
@@ -0,0 +1,39 @@
+#![cfg(unix)]
+
+use std::net::{SocketAddr, UdpSocket as StdUdpSocket};
+
+use shadowsocks::net::{handoff, AcceptOpts, TcpListener, UdpSocket};
+
+#[tokio::test]
+async fn handoff_records_until_dropped() {
+    let _ = env_logger::try_init();
+
+    handoff::enable();
+
+    // A free UDP port, which couldn't be bound again if a duplicated descriptor is kept open
+    let addr: SocketAddr = {
+        let socket = StdUdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.local_addr().unwrap()
+    };
+
+    let socket = UdpSocket::listen_with_opts(&addr, AcceptOpts::default()).await.unwrap();
+    let listener = TcpListener::bind_with_opts(&"127.0.0.1:0".parse().unwrap(), AcceptOpts::default())
+        .await
+        .unwrap();
+    assert_eq!(handoff::bound_listeners().len(), 2);
+
+    drop(socket);
+    drop(listener);
+    assert!(handoff::bound_listeners().is_empty());
+
+    // Bound again, the record of the dropped socket doesn't occupy the address
+    let socket = UdpSocket::listen_with_opts(&addr, AcceptOpts::default()).await.unwrap();
+    assert_eq!(handoff::bound_listeners().len(), 1);
+
+    // Converted sockets are still serving, they are kept recorded
+    let socket: tokio::net::UdpSocket = socket.into();
+    assert_eq!(handoff::bound_listeners().len(), 1);
+    drop(socket);
+
+    handoff::clear_bound();
+}
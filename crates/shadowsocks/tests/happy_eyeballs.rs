@@ -0,0 +1,88 @@
+use std::{
+    io,
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use futures::future;
+use shadowsocks::{
+    config::ServerType,
+    context::{Context, SharedContext},
+    dns_resolver::{DnsResolve, DnsResolver},
+    lookup_then_connect,
+};
+use tokio::net::{TcpListener, TcpStream};
+
+/// Resolves all names to `addrs`
+struct StaticResolver {
+    addrs: Vec<SocketAddr>,
+}
+
+#[async_trait]
+impl DnsResolve for StaticResolver {
+    async fn resolve(&self, _addr: &str, _port: u16) -> io::Result<Vec<SocketAddr>> {
+        Ok(self.addrs.clone())
+    }
+}
+
+fn context_resolving(addrs: Vec<SocketAddr>) -> SharedContext {
+    let mut context = Context::new(ServerType::Local);
+    context.set_dns_resolver(Arc::new(DnsResolver::custom_resolver(StaticResolver { addrs })));
+    Arc::new(context)
+}
+
+/// Connect to `example.com` resolved by `context`, attempts to `blackhole` never finish
+async fn connect(
+    context: &SharedContext,
+    port: u16,
+    blackhole: Option<SocketAddr>,
+) -> io::Result<(SocketAddr, TcpStream)> {
+    lookup_then_connect!(context, "example.com", port, |addr| {
+        if Some(addr) == blackhole {
+            future::pending::<()>().await;
+        }
+        TcpStream::connect(addr).await
+    })
+}
+
+#[tokio::test]
+async fn happy_eyeballs_skips_unroutable() {
+    let _ = env_logger::try_init();
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let listen_addr = listener.local_addr().unwrap();
+
+    // TEST-NET-1 is never routed. Connecting to it fails immediately without a default route, so it is kept pending
+    // as if SYNs were dropped
+    let unroutable = SocketAddr::new("192.0.2.1".parse().unwrap(), listen_addr.port());
+    let context = context_resolving(vec![unroutable, listen_addr]);
+
+    let start = Instant::now();
+    let (addr, _stream) = connect(&context, listen_addr.port(), Some(unroutable)).await.unwrap();
+    let elapsed = start.elapsed();
+
+    assert_eq!(addr, listen_addr);
+    // The next address is tried after one "Connection Attempt Delay" (250ms), without waiting for the first attempt
+    assert!(elapsed >= Duration::from_millis(200), "connected in {:?}", elapsed);
+    assert!(elapsed < Duration::from_secs(1), "connected in {:?}", elapsed);
+}
+
+#[tokio::test]
+async fn happy_eyeballs_all_failed() {
+    let _ = env_logger::try_init();
+
+    // Nothing is listening
+    let addr = {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        listener.local_addr().unwrap()
+    };
+    let context = context_resolving(vec![addr, addr]);
+    let err = connect(&context, addr.port(), None).await.unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+
+    // Nothing is resolved
+    let context = context_resolving(Vec::new());
+    assert!(connect(&context, addr.port(), None).await.is_err());
+}
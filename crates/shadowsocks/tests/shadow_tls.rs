@@ -0,0 +1,150 @@
+#![cfg(feature = "transport-shadow-tls")]
+
+use std::{net::SocketAddr, sync::Arc};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+use tokio_rustls::{
+    rustls::{Certificate, ClientConfig, PrivateKey, RootCertStore, ServerConfig, ServerName},
+    TlsAcceptor,
+    TlsConnector,
+};
+
+use shadowsocks::{
+    config::{ServerAddr, ServerType},
+    context::Context,
+    net::{
+        shadow_tls::{ShadowTlsConfig, ShadowTlsListener},
+        AcceptOpts,
+        ConnectOpts,
+    },
+    relay::socks5::Address,
+};
+
+/// Self-signed certificate of `localhost`
+const CERTIFICATE: &str = "\
+MIIBtjCCAVygAwIBAgIUcEICMLpH1uiTHEu+AF0gt/g4+DUwCgYIKoZIzj0EAwIw
+FDESMBAGA1UEAwwJbG9jYWxob3N0MCAXDTI2MTAxNjAyNTQzM1oYDzIxMjYwOTIy
+MDI1NDMzWjAUMRIwEAYDVQQDDAlsb2NhbGhvc3QwWTATBgcqhkjOPQIBBggqhkjO
+PQMBBwNCAAQJqlkv4MNJqqwNBXCjCAEdIc3RaQRJDFk+nCN40UiP3r/giB8oft1l
+9hfIrmrWwUIHnNBjgc850WOeUYfq+Os8o4GJMIGGMB0GA1UdDgQWBBStKuZXHBdg
+XgvIbO+Sx0j5LTTA6zAfBgNVHSMEGDAWgBStKuZXHBdgXgvIbO+Sx0j5LTTA6zAU
+BgNVHREEDTALgglsb2NhbGhvc3QwDAYDVR0TAQH/BAIwADALBgNVHQ8EBAMCB4Aw
+EwYDVR0lBAwwCgYIKwYBBQUHAwEwCgYIKoZIzj0EAwIDSAAwRQIgZOz9GyhOzfoa
+pjJ1QE5nSDnG/d7pa5Vw0oazPEl6CXkCIQCVa3bZtkoEo65oITr1yCyVYSKfvtjh
+QW7kJ1uxZHfFXA==";
+
+/// PKCS#8 private key of `CERTIFICATE`
+const PRIVATE_KEY: &str = "\
+MIGHAgEAMBMGByqGSM49AgEGCCqGSM49AwEHBG0wawIBAQQgDSzPWM17NqDqOuk8
+Z0RmugqQfpt2VzprMwQ1Y8Ew3HihRANCAAQJqlkv4MNJqqwNBXCjCAEdIc3RaQRJ
+DFk+nCN40UiP3r/giB8oft1l9hfIrmrWwUIHnNBjgc850WOeUYfq+Os8";
+
+fn decode_base64(lines: &str) -> Vec<u8> {
+    base64::decode(lines.replace('\n', "")).unwrap()
+}
+
+/// The decoy site, greeting TLS clients with `decoy`
+async fn run_decoy_server() -> SocketAddr {
+    let config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(
+            vec![Certificate(decode_base64(CERTIFICATE))],
+            PrivateKey(decode_base64(PRIVATE_KEY)),
+        )
+        .unwrap();
+    let acceptor = TlsAcceptor::from(Arc::new(config));
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            let acceptor = acceptor.clone();
+            tokio::spawn(async move {
+                if let Ok(mut stream) = acceptor.accept(stream).await {
+                    let _ = stream.write_all(b"decoy").await;
+                    let _ = stream.flush().await;
+                }
+            });
+        }
+    });
+    addr
+}
+
+async fn bind_server(config: &ShadowTlsConfig) -> ShadowTlsListener {
+    ShadowTlsListener::bind_with_opts(
+        Context::new_shared(ServerType::Server),
+        &ServerAddr::from("127.0.0.1:0".parse::<SocketAddr>().unwrap()),
+        config,
+        AcceptOpts::default(),
+        ConnectOpts::default(),
+    )
+    .await
+    .unwrap()
+}
+
+#[tokio::test]
+async fn shadow_tls_relay() {
+    let _ = env_logger::try_init();
+
+    let decoy_addr = run_decoy_server().await;
+    let mut config = ShadowTlsConfig::new("password", "localhost");
+    config.set_handshake_server(Address::from(decoy_addr));
+
+    let mut listener = bind_server(&config).await;
+    let server_addr = ServerAddr::from(listener.local_addr().unwrap());
+
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut buffer = [0u8; 5];
+        stream.read_exact(&mut buffer).await.unwrap();
+        stream.write_all(&buffer).await.unwrap();
+        stream.flush().await.unwrap();
+    });
+
+    let context = Context::new(ServerType::Local);
+    let mut stream = config
+        .connect(&context, &server_addr, &ConnectOpts::default())
+        .await
+        .unwrap();
+
+    let mut buffer = [0u8; 5];
+    stream.write_all(b"hello").await.unwrap();
+    stream.flush().await.unwrap();
+    stream.read_exact(&mut buffer).await.unwrap();
+    assert_eq!(&buffer, b"hello");
+}
+
+#[tokio::test]
+async fn shadow_tls_probe_sees_decoy() {
+    let _ = env_logger::try_init();
+
+    let decoy_addr = run_decoy_server().await;
+    let mut config = ShadowTlsConfig::new("password", "localhost");
+    config.set_handshake_server(Address::from(decoy_addr));
+
+    let listener = bind_server(&config).await;
+
+    // Clients without the password are TLS clients of the decoy site, verifying its certificate
+    let mut roots = RootCertStore::empty();
+    roots.add(&Certificate(decode_base64(CERTIFICATE))).unwrap();
+    let client_config = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    let connector = TlsConnector::from(Arc::new(client_config));
+
+    let stream = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+    let mut stream = connector
+        .connect(ServerName::try_from("localhost").unwrap(), stream)
+        .await
+        .unwrap();
+
+    let mut buffer = [0u8; 5];
+    stream.read_exact(&mut buffer).await.unwrap();
+    assert_eq!(&buffer, b"decoy");
+}
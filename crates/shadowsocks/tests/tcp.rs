@@ -16,7 +16,7 @@ use tokio::{
 use shadowsocks::{
     config::{ServerConfig, ServerType},
     context::Context,
-    crypto::v1::CipherKind,
+    crypto::CipherKind,
     relay::{
         socks5::Address,
         tcprelay::{
@@ -163,6 +163,23 @@ async fn tcp_tunnel_aead() {
         .unwrap();
 }
 
+#[cfg(feature = "aead-cipher-2022")]
+#[tokio::test]
+async fn tcp_tunnel_aead_2022() {
+    let _ = env_logger::try_init();
+
+    let server_addr = "127.0.0.1:34001".parse::<SocketAddr>().unwrap();
+    let local_addr = "127.0.0.1:34101".parse::<SocketAddr>().unwrap();
+    tcp_tunnel_example(
+        server_addr,
+        local_addr,
+        "3SYJ/f8nmVuzKvKglykRQDSgg10e/ADilkdRWrrY9HU=",
+        CipherKind::AEAD2022_BLAKE3_AES_256_GCM,
+    )
+    .await
+    .unwrap();
+}
+
 #[cfg(feature = "stream-cipher")]
 #[tokio::test]
 async fn tcp_tunnel_stream() {
@@ -185,3 +202,46 @@ async fn tcp_tunnel_none() {
         .await
         .unwrap();
 }
+
+#[cfg(unix)]
+#[tokio::test]
+async fn tcp_connect_protected() {
+    use std::{
+        io::ErrorKind,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    use shadowsocks::net::{ConnectOpts, SocketProtector, TcpStream as ShadowTcpStream};
+
+    let _ = env_logger::try_init();
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let protected = Arc::new(AtomicUsize::new(0));
+    let opts = ConnectOpts {
+        socket_protector: Some(SocketProtector::new({
+            let protected = protected.clone();
+            move |_| {
+                protected.fetch_add(1, Ordering::Relaxed);
+                Ok(())
+            }
+        })),
+        ..Default::default()
+    };
+    ShadowTcpStream::connect_with_opts(&addr, &opts).await.unwrap();
+    assert_eq!(protected.load(Ordering::Relaxed), 1);
+
+    // Connections fail if sockets couldn't be protected
+    let opts = ConnectOpts {
+        socket_protector: Some(SocketProtector::new(|_| {
+            Err(io::Error::new(ErrorKind::PermissionDenied, "protect() failed"))
+        })),
+        ..Default::default()
+    };
+    let err = ShadowTcpStream::connect_with_opts(&addr, &opts)
+        .await
+        .err()
+        .expect("connected without protection");
+    assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+}
@@ -0,0 +1,75 @@
+#![cfg(feature = "stream-padding")]
+
+use std::{net::SocketAddr, time::Duration};
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use shadowsocks::{
+    config::{ServerConfig, ServerType},
+    context::Context,
+    crypto::CipherKind,
+    relay::{socks5::Address, tcprelay::padding::PaddingConfig},
+    ProxyClientStream,
+    ProxyListener,
+};
+
+async fn padding_echo(method: CipherKind, padding: PaddingConfig) {
+    let mut svr_cfg = ServerConfig::new("127.0.0.1:0".parse::<SocketAddr>().unwrap(), "p$p", method);
+    svr_cfg.set_padding(padding.clone());
+
+    let listener = ProxyListener::bind(Context::new_shared(ServerType::Server), &svr_cfg)
+        .await
+        .unwrap();
+    let mut svr_cfg = ServerConfig::new(listener.local_addr().unwrap(), "p$p", method);
+    svr_cfg.set_padding(padding);
+
+    tokio::spawn(async move {
+        let (mut stream, ..) = listener.accept().await.unwrap();
+
+        let target_addr = Address::read_from(&mut stream).await.unwrap();
+        assert_eq!(
+            target_addr,
+            Address::DomainNameAddress("www.example.com".to_owned(), 80)
+        );
+
+        let mut buffer = vec![0u8; 4096];
+        loop {
+            let n = stream.read(&mut buffer).await.unwrap();
+            if n == 0 {
+                break;
+            }
+            stream.write_all(&buffer[..n]).await.unwrap();
+        }
+    });
+
+    let context = Context::new_shared(ServerType::Local);
+    let target_addr = Address::DomainNameAddress("www.example.com".to_owned(), 80);
+    let mut stream = ProxyClientStream::connect(context, &svr_cfg, target_addr)
+        .await
+        .unwrap();
+
+    let payload = (0..40000).map(|i| i as u8).collect::<Vec<u8>>();
+    stream.write_all(&payload).await.unwrap();
+
+    let mut echoed = vec![0u8; payload.len()];
+    stream.read_exact(&mut echoed).await.unwrap();
+    assert_eq!(echoed, payload);
+}
+
+#[tokio::test]
+async fn tcp_padding_echo() {
+    let _ = env_logger::try_init();
+
+    padding_echo(CipherKind::AES_256_GCM, PaddingConfig::default()).await;
+    padding_echo(CipherKind::NONE, PaddingConfig::new(1024)).await;
+}
+
+#[tokio::test]
+async fn tcp_padding_bursts_echo() {
+    let _ = env_logger::try_init();
+
+    let mut padding = PaddingConfig::new(64);
+    padding.set_max_burst(1000);
+    padding.set_max_jitter(Duration::from_millis(2));
+    padding_echo(CipherKind::CHACHA20_POLY1305, padding).await;
+}
@@ -15,7 +15,7 @@ use log::debug;
 use shadowsocks::{
     config::ServerType,
     context::Context,
-    crypto::v1::CipherKind,
+    crypto::CipherKind,
     net::{AcceptOpts, ConnectOpts},
     relay::{
         socks5::Address,
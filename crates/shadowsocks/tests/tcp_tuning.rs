@@ -0,0 +1,92 @@
+#![cfg(any(target_os = "linux", target_os = "android"))]
+
+use std::{
+    io,
+    mem,
+    os::unix::io::{AsRawFd, RawFd},
+};
+
+use shadowsocks::net::{AcceptOpts, ConnectOpts, TcpListener, TcpStream, TcpTuning};
+
+fn tcp_congestion(fd: RawFd) -> io::Result<String> {
+    let mut buf = [0u8; 16];
+    let mut len = buf.len() as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::IPPROTO_TCP,
+            libc::TCP_CONGESTION,
+            buf.as_mut_ptr() as *mut _,
+            &mut len,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let name = &buf[..len as usize];
+    let end = name.iter().position(|b| *b == 0).unwrap_or(name.len());
+    Ok(String::from_utf8_lossy(&name[..end]).into_owned())
+}
+
+fn tcp_notsent_lowat(fd: RawFd) -> io::Result<u32> {
+    let mut bytes: libc::c_int = 0;
+    let mut len = mem::size_of_val(&bytes) as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::IPPROTO_TCP,
+            libc::TCP_NOTSENT_LOWAT,
+            &mut bytes as *mut _ as *mut _,
+            &mut len,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(bytes as u32)
+}
+
+#[tokio::test]
+async fn tcp_tuning_sockopts() {
+    let _ = env_logger::try_init();
+
+    // `reno` is built in, and allowed for unprivileged processes
+    let accept_tuning = TcpTuning {
+        congestion: Some("reno".to_owned()),
+        notsent_lowat: Some(16384),
+        ..Default::default()
+    };
+    let mut accept_opts = AcceptOpts::default();
+    accept_tuning.apply(&mut accept_opts.tcp);
+
+    let connect_tuning = TcpTuning {
+        congestion: Some("reno".to_owned()),
+        notsent_lowat: Some(32768),
+        ..Default::default()
+    };
+    let mut connect_opts = ConnectOpts::default();
+    connect_tuning.apply(&mut connect_opts.tcp);
+
+    let listener = TcpListener::bind_with_opts(&"127.0.0.1:0".parse().unwrap(), accept_opts)
+        .await
+        .unwrap();
+    let listen_addr = listener.local_addr().unwrap();
+
+    let (client, accepted) = tokio::join!(
+        TcpStream::connect_with_opts(&listen_addr, &connect_opts),
+        listener.accept()
+    );
+    let client = client.unwrap();
+    let (accepted, _) = accepted.unwrap();
+
+    assert_eq!(tcp_congestion(client.as_raw_fd()).unwrap(), "reno");
+    assert_eq!(tcp_notsent_lowat(client.as_raw_fd()).unwrap(), 32768);
+
+    assert_eq!(tcp_congestion(accepted.as_raw_fd()).unwrap(), "reno");
+    assert_eq!(tcp_notsent_lowat(accepted.as_raw_fd()).unwrap(), 16384);
+
+    // Unknown algorithms are errors, instead of silently falling back to the default one
+    let mut connect_opts = ConnectOpts::default();
+    connect_opts.tcp.congestion = Some("no-such-algorithm".to_owned());
+    assert!(TcpStream::connect_with_opts(&listen_addr, &connect_opts).await.is_err());
+}
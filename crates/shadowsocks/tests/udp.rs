@@ -7,13 +7,17 @@ use tokio::{net::UdpSocket, sync::Barrier};
 use shadowsocks::{
     config::{ServerConfig, ServerType},
     context::{Context, SharedContext},
-    crypto::v1::CipherKind,
-    relay::{socks5::Address, udprelay::ProxySocket},
+    crypto::CipherKind,
+    relay::{
+        socks5::Address,
+        udprelay::{ProxySocket, UdpSocketControlData},
+    },
 };
 
 async fn handle_udp_server_client(
     peer_addr: SocketAddr,
     remote_addr: Address,
+    control: Option<UdpSocketControlData>,
     payload: &[u8],
     socket: &ProxySocket,
 ) -> io::Result<()> {
@@ -29,7 +33,19 @@ async fn handle_udp_server_client(
     let mut buf = [0u8; 65536];
     let n = remote_socket.recv(&mut buf).await?;
 
-    socket.send_to(peer_addr, &remote_addr, &buf[..n]).await?;
+    match control {
+        Some(control) => {
+            let control = UdpSocketControlData {
+                server_session_id: 0x5353,
+                packet_id: 0,
+                ..control
+            };
+            socket
+                .send_to_with_ctrl(peer_addr, &remote_addr, &control, &buf[..n])
+                .await?
+        }
+        None => socket.send_to(peer_addr, &remote_addr, &buf[..n]).await?,
+    };
 
     Ok(())
 }
@@ -62,6 +78,15 @@ async fn udp_tunnel_echo(
     let svr_cfg_server = ServerConfig::new(server_addr, password, method);
     let svr_cfg_local = svr_cfg_server.clone();
 
+    udp_tunnel_echo_with_configs(svr_cfg_server, svr_cfg_local, local_addr, target_addr).await
+}
+
+async fn udp_tunnel_echo_with_configs(
+    svr_cfg_server: ServerConfig,
+    svr_cfg_local: ServerConfig,
+    local_addr: SocketAddr,
+    target_addr: SocketAddr,
+) -> io::Result<()> {
     let ctx_server = Context::new_shared(ServerType::Server);
     let ctx_local = Context::new_shared(ServerType::Local);
 
@@ -91,8 +116,8 @@ async fn udp_tunnel_echo(
 
         let mut recv_buf = vec![0u8; 65536];
         loop {
-            let (n, peer_addr, remote_addr, ..) = socket.recv_from(&mut recv_buf).await.unwrap();
-            let _ = handle_udp_server_client(peer_addr, remote_addr, &recv_buf[..n], &socket).await;
+            let (n, peer_addr, remote_addr, _, control) = socket.recv_from_with_ctrl(&mut recv_buf).await.unwrap();
+            let _ = handle_udp_server_client(peer_addr, remote_addr, control, &recv_buf[..n], &socket).await;
         }
     });
 
@@ -148,6 +173,74 @@ async fn udp_tunnel_aead() {
         .unwrap();
 }
 
+#[cfg(feature = "aead-cipher-2022")]
+#[tokio::test]
+async fn udp_tunnel_aead_2022() {
+    let _ = env_logger::try_init();
+
+    let server_addr = "127.0.0.1:24001".parse::<SocketAddr>().unwrap();
+    let local_addr = "127.0.0.1:24101".parse::<SocketAddr>().unwrap();
+    let target_addr = "127.0.0.1:24201".parse::<SocketAddr>().unwrap();
+
+    udp_tunnel_echo(
+        server_addr,
+        local_addr,
+        target_addr,
+        "3SYJ/f8nmVuzKvKglykRQDSgg10e/ADilkdRWrrY9HU=",
+        CipherKind::AEAD2022_BLAKE3_AES_256_GCM,
+    )
+    .await
+    .unwrap();
+}
+
+#[cfg(feature = "aead-cipher-2022")]
+#[tokio::test]
+async fn udp_tunnel_aead_2022_chacha20() {
+    let _ = env_logger::try_init();
+
+    let server_addr = "127.0.0.1:25001".parse::<SocketAddr>().unwrap();
+    let local_addr = "127.0.0.1:25101".parse::<SocketAddr>().unwrap();
+    let target_addr = "127.0.0.1:25201".parse::<SocketAddr>().unwrap();
+
+    udp_tunnel_echo(
+        server_addr,
+        local_addr,
+        target_addr,
+        "3SYJ/f8nmVuzKvKglykRQDSgg10e/ADilkdRWrrY9HU=",
+        CipherKind::AEAD2022_BLAKE3_CHACHA20_POLY1305,
+    )
+    .await
+    .unwrap();
+}
+
+#[cfg(feature = "aead-cipher-2022")]
+#[tokio::test]
+async fn udp_tunnel_aead_2022_multi_user() {
+    use shadowsocks::config::{ServerUser, ServerUserManager};
+
+    let _ = env_logger::try_init();
+
+    let server_addr = "127.0.0.1:26001".parse::<SocketAddr>().unwrap();
+    let local_addr = "127.0.0.1:26101".parse::<SocketAddr>().unwrap();
+    let target_addr = "127.0.0.1:26201".parse::<SocketAddr>().unwrap();
+
+    let method = CipherKind::AEAD2022_BLAKE3_AES_128_GCM;
+    let identity_key = "Hpui/+6XlOL9GbN+b2vQqQ==";
+    let user_key = "n9dlyeKHhcPDA0Gq2ayocA==";
+
+    let user_manager = ServerUserManager::new();
+    user_manager.add_user(ServerUser::with_encoded_key("other", method, "zsSYXzkLi/fXZL7HeSMTjQ==").unwrap());
+    user_manager.add_user(ServerUser::with_encoded_key("user", method, user_key).unwrap());
+
+    let mut svr_cfg_server = ServerConfig::new(server_addr, identity_key, method);
+    svr_cfg_server.set_user_manager(Arc::new(user_manager));
+    let svr_cfg_local = ServerConfig::new(server_addr, format!("{}:{}", identity_key, user_key), method);
+
+    udp_tunnel_echo_with_configs(svr_cfg_server, svr_cfg_local, local_addr, target_addr)
+        .await
+        .unwrap();
+}
+
 #[cfg(feature = "stream-cipher")]
 #[tokio::test]
 async fn udp_tunnel_stream() {
@@ -0,0 +1,65 @@
+use std::net::SocketAddr;
+
+use shadowsocks::{
+    config::{ServerConfig, ServerType},
+    context::Context,
+    crypto::CipherKind,
+    relay::{
+        socks5::Address,
+        udp_over_tcp::{is_udp_over_tcp_address, read_packet, udp_over_tcp_address, write_packet},
+    },
+    ProxyClientStream,
+    ProxyListener,
+};
+
+#[tokio::test]
+async fn udp_over_tcp_echo() {
+    let _ = env_logger::try_init();
+
+    let svr_cfg = ServerConfig::new(
+        "127.0.0.1:0".parse::<SocketAddr>().unwrap(),
+        "p$p",
+        CipherKind::AES_256_GCM,
+    );
+
+    let listener = ProxyListener::bind(Context::new_shared(ServerType::Server), &svr_cfg)
+        .await
+        .unwrap();
+    let svr_cfg = ServerConfig::new(listener.local_addr().unwrap(), "p$p", CipherKind::AES_256_GCM);
+
+    tokio::spawn(async move {
+        let (mut stream, ..) = listener.accept().await.unwrap();
+
+        let target_addr = Address::read_from(&mut stream).await.unwrap();
+        assert!(is_udp_over_tcp_address(&target_addr));
+
+        // Echo packets back with their target addresses
+        let mut buffer = vec![0u8; 65536];
+        loop {
+            let (n, addr) = match read_packet(&mut stream, &mut buffer).await {
+                Ok(p) => p,
+                Err(..) => break,
+            };
+            write_packet(&mut stream, &addr, &buffer[..n]).await.unwrap();
+        }
+    });
+
+    let context = Context::new_shared(ServerType::Local);
+    let mut stream = ProxyClientStream::connect(context, &svr_cfg, udp_over_tcp_address())
+        .await
+        .unwrap();
+
+    let target_addr = Address::DomainNameAddress("dns.google".to_owned(), 53);
+    let packets: [&[u8]; 3] = [b"HELLO WORLD", b"", &[0xAB; 2048]];
+
+    for payload in packets.iter() {
+        write_packet(&mut stream, &target_addr, payload).await.unwrap();
+    }
+
+    let mut buffer = vec![0u8; 65536];
+    for payload in packets.iter() {
+        let (n, addr) = read_packet(&mut stream, &mut buffer).await.unwrap();
+        assert_eq!(addr, target_addr);
+        assert_eq!(&buffer[..n], *payload);
+    }
+}
@@ -1,5 +1,7 @@
 //! Common configuration utilities
 
+#[cfg(feature = "logging")]
+use std::fmt;
 use std::{
     env,
     fs::OpenOptions,
@@ -12,6 +14,7 @@ use cfg_if::cfg_if;
 use clap::ArgMatches;
 use directories::ProjectDirs;
 use serde::Deserialize;
+use shadowsocks_service::config::{interpolate_config_value, ConfigFormat};
 
 /// Default configuration file path
 pub fn get_default_config_path() -> Option<PathBuf> {
@@ -77,6 +80,9 @@ pub enum ConfigError {
     /// JSON parsing error
     #[error("{0}")]
     JsonError(#[from] json5::Error),
+    /// YAML or TOML parsing error
+    #[error("{0}")]
+    FormatError(String),
     /// Invalid value
     #[error("Invalid value: {0}")]
     InvalidValue(String),
@@ -94,7 +100,7 @@ pub struct Config {
 }
 
 impl Config {
-    /// Load `Config` from file
+    /// Load `Config` from file, in the format detected by its extension, with environment variables substituted in string values
     pub fn load_from_file<P: AsRef<Path>>(filename: &P) -> Result<Config, ConfigError> {
         let filename = filename.as_ref();
 
@@ -102,7 +108,16 @@ impl Config {
         let mut content = String::new();
         reader.read_to_string(&mut content)?;
 
-        Config::load_from_str(&content)
+        let mut value = match ConfigFormat::from_path(filename) {
+            ConfigFormat::Json => json5::from_str(&content)?,
+            format => format
+                .deserialize(&content)
+                .map_err(|err| ConfigError::FormatError(err.to_string()))?,
+        };
+        interpolate_config_value(&mut value);
+
+        let ssconfig = serde_json::from_value(value).map_err(|err| ConfigError::FormatError(err.to_string()))?;
+        Config::load_from_ssconfig(ssconfig)
     }
 
     /// Load `Config` from string
@@ -126,6 +141,12 @@ impl Config {
                 if let Some(without_time) = format.without_time {
                     nformat.without_time = without_time;
                 }
+                if let Some(kind) = format.kind {
+                    match kind.parse::<LogFormatKind>() {
+                        Ok(k) => nformat.kind = k,
+                        Err(..) => return Err(ConfigError::InvalidValue(kind)),
+                    }
+                }
                 nlog.format = nformat;
             }
 
@@ -170,6 +191,12 @@ impl Config {
                 self.log.format.without_time = true;
             }
 
+            match matches.value_of_t::<LogFormatKind>("LOG_FORMAT") {
+                Ok(kind) => self.log.format.kind = kind,
+                Err(ref err) if err.kind() == clap::ErrorKind::ArgumentNotFound => {}
+                Err(err) => err.exit(),
+            }
+
             if let Some(log_config) = matches.value_of("LOG_CONFIG") {
                 self.log.config_path = Some(log_config.into());
             }
@@ -208,6 +235,47 @@ pub struct LogConfig {
 #[derive(Debug, Clone, Default)]
 pub struct LogFormatConfig {
     pub without_time: bool,
+    /// Plain text, or structured records
+    pub kind: LogFormatKind,
+}
+
+/// Format of records of the default logger
+#[cfg(feature = "logging")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LogFormatKind {
+    /// Plain text, for reading in terminals
+    #[default]
+    Text,
+    /// JSON objects, one per line, with fields of connections, for log collectors
+    Json,
+}
+
+/// Parse `LogFormatKind` from string error
+#[cfg(feature = "logging")]
+#[derive(Debug)]
+pub struct LogFormatKindError;
+
+#[cfg(feature = "logging")]
+impl fmt::Display for LogFormatKindError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("invalid log format, expecting \"text\" or \"json\"")
+    }
+}
+
+#[cfg(feature = "logging")]
+impl std::error::Error for LogFormatKindError {}
+
+#[cfg(feature = "logging")]
+impl FromStr for LogFormatKind {
+    type Err = LogFormatKindError;
+
+    fn from_str(s: &str) -> Result<LogFormatKind, Self::Err> {
+        match s {
+            "text" => Ok(LogFormatKind::Text),
+            "json" => Ok(LogFormatKind::Json),
+            _ => Err(LogFormatKindError),
+        }
+    }
 }
 
 /// Runtime mode (Tokio)
@@ -278,6 +346,7 @@ struct SSLogConfig {
 #[derive(Deserialize)]
 struct SSLogFormat {
     without_time: Option<bool>,
+    kind: Option<String>,
 }
 
 #[derive(Deserialize)]
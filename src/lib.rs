@@ -11,6 +11,8 @@ pub mod password;
 pub mod service;
 pub mod sys;
 pub mod validator;
+#[cfg(windows)]
+pub mod winsvc;
 
 /// Exit code when server exits unexpectly
 pub const EXIT_CODE_SERVER_EXIT_UNEXPECTEDLY: i32 = exitcode::SOFTWARE;
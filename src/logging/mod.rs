@@ -6,10 +6,20 @@ use log::LevelFilter;
 use log4rs::{
     append::console::{ConsoleAppender, Target},
     config::{Appender, Config, Logger, Root},
-    encode::pattern::PatternEncoder,
+    encode::{json::JsonEncoder, pattern::PatternEncoder, Encode},
 };
+use shadowsocks_service::log_fields;
 
-use crate::config::LogConfig;
+use crate::config::{LogConfig, LogFormatKind};
+
+use self::span::SpanFieldsSubscriber;
+
+mod span;
+
+/// Put fields of spans of connections in the MDC, for logging with the records
+fn init_span_fields() {
+    let _ = tracing::subscriber::set_global_default(SpanFieldsSubscriber::new());
+}
 
 /// Initialize logger ([log4rs](https://crates.io/crates/log4rs)) from yaml configuration file
 pub fn init_with_file<P>(path: P)
@@ -17,6 +27,7 @@ where
     P: AsRef<Path>,
 {
     log4rs::init_file(path, Default::default()).expect("init logging with file");
+    init_span_fields();
 }
 
 /// Initialize logger with default configuration
@@ -24,22 +35,34 @@ pub fn init_with_config(bin_name: &str, config: &LogConfig) {
     let debug_level = config.level;
     let without_time = config.format.without_time;
 
-    let mut pattern = String::new();
-    if !without_time {
-        pattern += "{d} ";
-    }
-    pattern += "{h({l}):<5} ";
-    if debug_level >= 1 {
-        pattern += "[{P}:{I}] [{M}] ";
-    }
-    pattern += "{m}{n}";
+    let encoder: Box<dyn Encode> = match config.format.kind {
+        LogFormatKind::Text => {
+            let mut pattern = String::new();
+            if !without_time {
+                pattern += "{d} ";
+            }
+            pattern += "{h({l}):<5} ";
+            if debug_level >= 1 {
+                // ID of the connection, if it is logged while serving one
+                pattern += "[{P}:{I}] [{M}] [conn {X(conn)(-)}] ";
+            }
+            pattern += "{m}{n}";
+
+            Box::new(PatternEncoder::new(&pattern))
+        }
+        LogFormatKind::Json => {
+            // Fields of connections are in "mdc" of records
+            log_fields::enable();
+            Box::new(JsonEncoder::new())
+        }
+    };
 
     let logging_builder = Config::builder().appender(
         Appender::builder().build(
             "console",
             Box::new(
                 ConsoleAppender::builder()
-                    .encoder(Box::new(PatternEncoder::new(&pattern)))
+                    .encoder(encoder)
                     .target(Target::Stderr)
                     .build(),
             ),
@@ -66,6 +89,7 @@ pub fn init_with_config(bin_name: &str, config: &LogConfig) {
     .expect("logging");
 
     log4rs::init_config(config).expect("logging");
+    init_span_fields();
 }
 
 /// Init a default logger
@@ -0,0 +1,171 @@
+//! Fields of `tracing` spans in log records
+//!
+//! Connections are served in tasks instrumented by spans of `log_fields::connection_span`. Fields of spans are put in
+//! the MDC of the thread while they are entered, so records logged by `log` in the tasks have them, in `{X(conn)}`
+//! of patterns and `"mdc"` of JSON records.
+
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    fmt,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+};
+
+use tracing::{
+    field::{Field, Visit},
+    span::{Attributes, Id, Record},
+    Event,
+    Metadata,
+    Subscriber,
+};
+use tracing_core::span::Current;
+
+struct SpanFields {
+    metadata: &'static Metadata<'static>,
+    fields: Vec<(&'static str, String)>,
+    refs: usize,
+}
+
+/// An entered span, and values of the MDC replaced by entering it
+type EnteredSpan = (Id, Vec<(&'static str, Option<String>)>);
+
+thread_local! {
+    // Entered spans of the thread, replaced values are restored when they are exited
+    static ENTERED: RefCell<Vec<EnteredSpan>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Subscriber putting fields of spans in the MDC, spans of other crates and events are ignored
+pub struct SpanFieldsSubscriber {
+    next_id: AtomicUsize,
+    spans: Mutex<HashMap<u64, SpanFields>>,
+}
+
+impl SpanFieldsSubscriber {
+    /// Create without any spans
+    pub fn new() -> SpanFieldsSubscriber {
+        SpanFieldsSubscriber {
+            next_id: AtomicUsize::new(1),
+            spans: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl Subscriber for SpanFieldsSubscriber {
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        metadata.is_span() && metadata.target().starts_with("shadowsocks")
+    }
+
+    fn new_span(&self, attrs: &Attributes<'_>) -> Id {
+        let mut fields = Vec::new();
+        attrs.record(&mut FieldVisitor(&mut fields));
+
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed) as u64;
+        let span = SpanFields {
+            metadata: attrs.metadata(),
+            fields,
+            refs: 1,
+        };
+        self.spans.lock().unwrap().insert(id, span);
+        Id::from_u64(id)
+    }
+
+    fn record(&self, span: &Id, values: &Record<'_>) {
+        let mut recorded = Vec::new();
+        values.record(&mut FieldVisitor(&mut recorded));
+
+        let mut spans = self.spans.lock().unwrap();
+        if let Some(span) = spans.get_mut(&span.into_u64()) {
+            for (key, value) in recorded {
+                match span.fields.iter_mut().find(|(k, _)| *k == key) {
+                    Some(field) => field.1 = value,
+                    None => span.fields.push((key, value)),
+                }
+            }
+        }
+    }
+
+    fn record_follows_from(&self, _span: &Id, _follows: &Id) {}
+
+    fn event(&self, _event: &Event<'_>) {}
+
+    fn enter(&self, span: &Id) {
+        let fields = match self.spans.lock().unwrap().get(&span.into_u64()) {
+            Some(span) => span.fields.clone(),
+            None => Vec::new(),
+        };
+
+        let replaced = fields
+            .into_iter()
+            .map(|(key, value)| (key, log_mdc::insert(key, value)))
+            .collect();
+        ENTERED.with(|e| e.borrow_mut().push((span.clone(), replaced)));
+    }
+
+    fn exit(&self, _span: &Id) {
+        let replaced = match ENTERED.with(|e| e.borrow_mut().pop()) {
+            Some((_, r)) => r,
+            None => return,
+        };
+
+        for (key, value) in replaced.into_iter().rev() {
+            match value {
+                Some(value) => {
+                    log_mdc::insert(key, value);
+                }
+                None => {
+                    log_mdc::remove(key);
+                }
+            }
+        }
+    }
+
+    fn current_span(&self) -> Current {
+        let id = match ENTERED.with(|e| e.borrow().last().map(|(id, _)| id.clone())) {
+            Some(id) => id,
+            None => return Current::none(),
+        };
+
+        match self.spans.lock().unwrap().get(&id.into_u64()) {
+            Some(span) => Current::new(id, span.metadata),
+            None => Current::none(),
+        }
+    }
+
+    fn clone_span(&self, span: &Id) -> Id {
+        if let Some(span) = self.spans.lock().unwrap().get_mut(&span.into_u64()) {
+            span.refs += 1;
+        }
+        span.clone()
+    }
+
+    fn try_close(&self, span: Id) -> bool {
+        let mut spans = self.spans.lock().unwrap();
+        let id = span.into_u64();
+        match spans.get_mut(&id) {
+            Some(span) if span.refs > 1 => {
+                span.refs -= 1;
+                false
+            }
+            Some(..) => {
+                spans.remove(&id);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+struct FieldVisitor<'a>(&'a mut Vec<(&'static str, String)>);
+
+impl Visit for FieldVisitor<'_> {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.push((field.name(), value.to_owned()));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.0.push((field.name(), format!("{:?}", value)));
+    }
+}
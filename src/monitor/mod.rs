@@ -13,3 +13,5 @@ mod imp;
 mod imp;
 
 pub use self::imp::create_signal_monitor;
+#[cfg(windows)]
+pub use self::imp::request_stop;
@@ -1,13 +1,26 @@
+use futures::future::{self, Either};
 use log::info;
+use once_cell::sync::Lazy;
 use std::io;
-use tokio::signal::ctrl_c;
+use tokio::{signal::ctrl_c, sync::Notify};
+
+static STOP_REQUESTED: Lazy<Notify> = Lazy::new(Notify::new);
+
+/// Request the signal monitor to exit, like received Ctrl-C
+///
+/// Called by the service control handler when the service is being stopped.
+pub fn request_stop() {
+    STOP_REQUESTED.notify_one();
+}
 
 /// Create a monitor future for signals
 ///
-/// It will exit when received Ctrl-C.
+/// It will exit when received Ctrl-C, or stopping is requested by `request_stop`.
 pub async fn create_signal_monitor() -> io::Result<()> {
-    let _ = ctrl_c().await;
-    info!("received CTRL-C, exiting");
+    match future::select(Box::pin(ctrl_c()), Box::pin(STOP_REQUESTED.notified())).await {
+        Either::Left(..) => info!("received CTRL-C, exiting"),
+        Either::Right(..) => info!("received service stop request, exiting"),
+    }
 
     Ok(())
 }
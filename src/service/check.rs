@@ -0,0 +1,146 @@
+//! Diagnostics of `--check-config`
+//!
+//! Configurations are already parsed and checked by `Config::check_integrity` before, these are problems found only
+//! while starting services, like listening on the same address twice or missing plugins.
+
+use std::{
+    collections::HashMap,
+    env,
+    path::{Path, PathBuf},
+    process,
+};
+
+use shadowsocks_service::config::Config;
+#[cfg(feature = "local")]
+use shadowsocks_service::config::{LocalConfig, ProtocolType};
+
+/// Print problems of `config` and exit, with `EXIT_CODE_LOAD_CONFIG_FAILURE` if there are any
+pub fn check_config(config: &Config) -> ! {
+    let problems = diagnose(config);
+    if problems.is_empty() {
+        println!("configuration is OK");
+        process::exit(0);
+    }
+
+    for problem in &problems {
+        eprintln!("{}", problem);
+    }
+    eprintln!("configuration has {} problem(s)", problems.len());
+    process::exit(crate::EXIT_CODE_LOAD_CONFIG_FAILURE);
+}
+
+fn diagnose(config: &Config) -> Vec<String> {
+    let mut problems = Vec::new();
+
+    // Listening addresses, (is UDP, address) => description of the listener
+    let mut listeners = HashMap::new();
+    let mut check_listener = |udp: bool, addr: String, desc: String| {
+        let transport = if udp { "UDP" } else { "TCP" };
+        match listeners.get(&(udp, addr.clone())) {
+            Some(prev) => problems.push(format!(
+                "{} and {} both listen on {} {}, change the address of either one",
+                prev, desc, transport, addr
+            )),
+            None => {
+                listeners.insert((udp, addr), desc);
+            }
+        }
+    };
+
+    #[cfg(feature = "local")]
+    for (idx, local) in config.local.iter().enumerate() {
+        let desc = format!("`locals` #{} ({})", idx + 1, local.protocol.as_str());
+        if let Some(ref addr) = local.addr {
+            if local.mode.enable_tcp() {
+                check_listener(false, addr.to_string(), desc.clone());
+            }
+            if local_udp_enabled(local) {
+                let udp_addr = local.udp_addr.as_ref().unwrap_or(addr);
+                check_listener(true, udp_addr.to_string(), desc);
+            }
+        }
+    }
+
+    if config.config_type.is_server() || config.config_type.is_manager() {
+        for (idx, svr) in config.server.iter().enumerate() {
+            let desc = format!("`servers` #{} ({})", idx + 1, svr.addr());
+            if svr.mode().enable_tcp() {
+                check_listener(false, svr.addr().to_string(), desc.clone());
+            }
+            if svr.mode().enable_udp() {
+                check_listener(true, svr.addr().to_string(), desc);
+            }
+        }
+    }
+
+    for (idx, svr) in config.server.iter().enumerate() {
+        if let Some(plugin) = svr.external_plugin() {
+            if find_plugin(&plugin.plugin).is_none() {
+                problems.push(format!(
+                    "plugin \"{}\" of `servers` #{} ({}) is not an executable file or found in PATH, install it or \
+                     set \"plugin\" to its path",
+                    plugin.plugin,
+                    idx + 1,
+                    svr.addr()
+                ));
+            }
+        }
+    }
+
+    problems
+}
+
+/// Check if `local` relays UDP on its UDP address
+#[cfg(feature = "local")]
+#[allow(unreachable_patterns)]
+fn local_udp_enabled(local: &LocalConfig) -> bool {
+    match local.protocol {
+        ProtocolType::Socks => local.mode.enable_udp(),
+        #[cfg(feature = "local-tunnel")]
+        ProtocolType::Tunnel => local.mode.enable_udp(),
+        #[cfg(feature = "local-redir")]
+        ProtocolType::Redir => local.mode.enable_udp(),
+        #[cfg(feature = "local-dns")]
+        ProtocolType::Dns => local.mode.enable_udp(),
+        _ => false,
+    }
+}
+
+/// Find the executable file of plugin `name`, which is a path, or a file name searched in `PATH`
+fn find_plugin(name: &str) -> Option<PathBuf> {
+    let path = Path::new(name);
+    if path.components().count() > 1 {
+        return is_executable(path).then(|| path.to_owned());
+    }
+
+    let paths = env::var_os("PATH")?;
+    env::split_paths(&paths).find_map(|dir| {
+        let path = dir.join(name);
+        if is_executable(&path) {
+            return Some(path);
+        }
+        #[cfg(windows)]
+        {
+            let path = path.with_extension("exe");
+            if is_executable(&path) {
+                return Some(path);
+            }
+        }
+        None
+    })
+}
+
+#[cfg(unix)]
+fn is_executable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+
+    match path.metadata() {
+        Ok(meta) => meta.is_file() && meta.permissions().mode() & 0o111 != 0,
+        Err(..) => false,
+    }
+}
+
+#[cfg(not(unix))]
+fn is_executable(path: &Path) -> bool {
+    path.is_file()
+}
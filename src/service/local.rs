@@ -1,24 +1,35 @@
 //! Local server launchers
 
-use std::{net::IpAddr, path::PathBuf, process, time::Duration};
+use std::{fmt, net::IpAddr, path::PathBuf, process, sync::Arc, time::Duration};
 
 use clap::{Arg, ArgGroup, ArgMatches, Command, ErrorKind as ClapErrorKind};
-use futures::future::{self, Either};
-use log::{info, trace};
-use tokio::{self, runtime::Builder};
+use futures::future::{self, Either, FutureExt};
+use log::{error, info, trace};
+use tokio::{self, runtime::Builder, task::JoinHandle, time};
 
 #[cfg(feature = "local-redir")]
-use shadowsocks_service::config::RedirType;
-#[cfg(any(feature = "local-dns", feature = "local-tunnel"))]
+use shadowsocks_service::config::{RedirFirewall, RedirType};
+#[cfg(all(unix, feature = "acl"))]
+use shadowsocks_service::local::context::ServiceContext;
+#[cfg(any(feature = "local-dns", feature = "local-tunnel", feature = "acl"))]
 use shadowsocks_service::shadowsocks::relay::socks5::Address;
+#[cfg(feature = "acl")]
 use shadowsocks_service::{
     acl::AccessControl,
+    shadowsocks::{config::ServerType, context::Context},
+};
+use shadowsocks_service::{
     config::{read_variable_field_value, Config, ConfigType, LocalConfig, ProtocolType},
     create_local,
-    local::loadbalancing::PingBalancer,
+    local::{
+        flow_export::FlowExportConfig,
+        loadbalancing::PingBalancer,
+        profile::ProfileSwitch,
+        routing_mode::RoutingMode,
+    },
     shadowsocks::{
-        config::{Mode, ServerAddr, ServerConfig},
-        crypto::v1::{available_ciphers, CipherKind},
+        config::{password_to_key, ManagerAddr, Mode, ServerAddr, ServerConfig},
+        crypto::{available_ciphers, CipherKind},
         plugin::PluginConfig,
     },
 };
@@ -31,6 +42,8 @@ use crate::{
     validator,
 };
 
+use super::{check::check_config, ServiceHandoff};
+
 /// Defines command line options
 pub fn define_command_line_options(mut app: Command<'_>) -> Command<'_> {
     app = app.arg(
@@ -40,6 +53,18 @@ pub fn define_command_line_options(mut app: Command<'_>) -> Command<'_> {
             .takes_value(true)
             .help("Shadowsocks configuration file (https://shadowsocks.org/en/config/quick-guide.html)"),
     )
+    .arg(
+        Arg::new("CHECK_CONFIG")
+            .long("check-config")
+            .help("Check the configuration and exit, with a non-zero code if there are any problems"),
+    )
+    .arg(
+        Arg::new("PROFILE")
+            .long("profile")
+            .takes_value(true)
+            .requires("CONFIG")
+            .help("Profile in `profiles` of the configuration file applied on start, could be switched by SIGUSR2 or the web UI"),
+    )
     .arg(
         Arg::new("LOCAL_ADDR")
             .short('b')
@@ -122,39 +147,85 @@ pub fn define_command_line_options(mut app: Command<'_>) -> Command<'_> {
             .requires("PLUGIN")
             .help("Set SIP003 plugin options"),
     )
+    .arg(
+        Arg::new("PLUGIN_MODE")
+            .long("plugin-mode")
+            .takes_value(true)
+            .requires("PLUGIN")
+            .possible_values(["tcp_only", "tcp_and_udp", "udp_only"])
+            .help("Protocols relayed by the SIP003 plugin, tcp_and_udp or udp_only for plugins supporting UDP (SIP003u)"),
+    )
     .arg(
         Arg::new("URL")
             .long("server-url")
             .takes_value(true)
+            .multiple_occurrences(true)
             .validator(validator::validate_server_url)
-            .help("Server address in SIP002 (https://shadowsocks.org/en/wiki/SIP002-URI-Scheme.html) URL"),
+            .help("Server address in SIP002 (https://shadowsocks.org/en/wiki/SIP002-URI-Scheme.html) URL, could be set multiple times"),
     )
     .group(ArgGroup::new("SERVER_CONFIG")
         .arg("SERVER_ADDR").arg("URL").multiple(true))
-    .arg(
-        Arg::new("ACL")
-            .long("acl")
-            .takes_value(true)
-            .help("Path to ACL (Access Control List)"),
-    )
     .arg(Arg::new("DNS").long("dns").takes_value(true).help("DNS nameservers, formatted like [(tcp|udp)://]host[:port][,host[:port]]..., or unix:///path/to/dns, or predefined keys like \"google\", \"cloudflare\""))
+    .arg(Arg::new("SERVER_DNS").long("server-dns").takes_value(true).help("DNS nameservers for resolving hostnames of servers, the same format as --dns, like tls://1.1.1.1#cloudflare-dns.com"))
     .arg(Arg::new("TCP_NO_DELAY").long("tcp-no-delay").alias("no-delay").help("Set TCP_NODELAY option for sockets"))
     .arg(Arg::new("TCP_FAST_OPEN").long("tcp-fast-open").alias("fast-open").help("Enable TCP Fast Open (TFO)"))
     .arg(Arg::new("TCP_KEEP_ALIVE").long("tcp-keep-alive").takes_value(true).validator(validator::validate_u64).help("Set TCP keep alive timeout seconds"))
     .arg(Arg::new("UDP_TIMEOUT").long("udp-timeout").takes_value(true).validator(validator::validate_u64).help("Timeout seconds for UDP relay"))
     .arg(Arg::new("UDP_MAX_ASSOCIATIONS").long("udp-max-associations").takes_value(true).validator(validator::validate_u64).help("Maximum associations to be kept simultaneously for UDP relay"))
+    .arg(Arg::new("UDP_MIGRATION").long("udp-migration").help("Migrate UDP associations to another server when their server is marked dead by the balancer"))
+    .arg(Arg::new("ROUTING_MODE").long("routing-mode").takes_value(true).possible_values(["global", "rule", "direct"]).help("Initial routing mode, global for proxying all targets, rule for following ACL, direct for bypassing all targets, which could be switched by the control socket"))
+    .arg(Arg::new("SYSTEM_PROXY").long("system-proxy").help("Point proxy settings of the operating system to socks, http or mixed locals while running, and restore them on exit"))
+    .arg(Arg::new("DIRECT_FALLBACK").long("direct-fallback").help("Connect targets directly, except those rejected by ACL, while all servers are marked dead by the balancer"))
     .arg(Arg::new("INBOUND_SEND_BUFFER_SIZE").long("inbound-send-buffer-size").takes_value(true).validator(validator::validate_u32).help("Set inbound sockets' SO_SNDBUF option"))
     .arg(Arg::new("INBOUND_RECV_BUFFER_SIZE").long("inbound-recv-buffer-size").takes_value(true).validator(validator::validate_u32).help("Set inbound sockets' SO_RCVBUF option"))
     .arg(Arg::new("OUTBOUND_SEND_BUFFER_SIZE").long("outbound-send-buffer-size").takes_value(true).validator(validator::validate_u32).help("Set outbound sockets' SO_SNDBUF option"))
     .arg(Arg::new("OUTBOUND_RECV_BUFFER_SIZE").long("outbound-recv-buffer-size").takes_value(true).validator(validator::validate_u32).help("Set outbound sockets' SO_RCVBUF option"))
     .arg(Arg::new("OUTBOUND_BIND_ADDR").long("outbound-bind-addr").takes_value(true).alias("bind-addr").validator(validator::validate_ip_addr).help("Bind address, outbound socket will bind this address"))
     .arg(Arg::new("OUTBOUND_BIND_INTERFACE").long("outbound-bind-interface").takes_value(true).help("Set SO_BINDTODEVICE / IP_BOUND_IF / IP_UNICAST_IF option for outbound socket"))
+    .arg(Arg::new("CONTROL_ADDR").long("control-addr").takes_value(true).help("Control socket for listing and terminating active connections, a TCP address (requiring `control_key` in the configuration) or the path of a Unix socket"))
+    .arg(Arg::new("FLOW_COLLECTOR").long("flow-collector").takes_value(true).help("Export records of closed connections to IPFIX (NetFlow v10) collector FLOW_COLLECTOR over UDP"))
     .arg(
         Arg::new("IPV6_FIRST")
             .short('6')
             .help("Resolve hostname to IPv6 address first"),
     );
 
+    #[cfg(feature = "acl")]
+    {
+        app = app
+            .arg(
+                Arg::new("ACL")
+                    .long("acl")
+                    .takes_value(true)
+                    .help("Path to ACL (Access Control List)"),
+            )
+            .arg(
+                Arg::new("ACL_TRACE")
+                    .long("acl-trace")
+                    .help("Log ACL rules (and their lines) deciding every TCP connection"),
+            )
+            .arg(
+                Arg::new("ACL_CHECK")
+                    .long("acl-check")
+                    .takes_value(true)
+                    .multiple_occurrences(true)
+                    .value_name("ADDR")
+                    .help(
+                        "Print ACL decisions of target addresses (HOST[:PORT]) and exit, without sending any traffic",
+                    ),
+            );
+    }
+
+    #[cfg(feature = "metrics")]
+    {
+        app = app.arg(
+            Arg::new("METRICS_ADDR")
+                .long("metrics-addr")
+                .takes_value(true)
+                .help("Serve Prometheus metrics in http://METRICS_ADDR/metrics"),
+        );
+    }
+
     #[cfg(feature = "logging")]
     {
         app = app
@@ -169,6 +240,13 @@ pub fn define_command_line_options(mut app: Command<'_>) -> Command<'_> {
                     .long("log-without-time")
                     .help("Log without datetime prefix"),
             )
+            .arg(
+                Arg::new("LOG_FORMAT")
+                    .long("log-format")
+                    .takes_value(true)
+                    .possible_values(["text", "json"])
+                    .help("Format of logs, \"json\" writes a JSON object per record with fields of connections"),
+            )
             .arg(
                 Arg::new("LOG_CONFIG")
                     .long("log-config")
@@ -247,6 +325,17 @@ pub fn define_command_line_options(mut app: Command<'_>) -> Command<'_> {
                     .help("UDP redir (transparent proxy) type"),
             );
         }
+
+        if cfg!(any(target_os = "linux", target_os = "android")) {
+            app = app.arg(
+                Arg::new("REDIR_FIREWALL")
+                    .long("redir-firewall")
+                    .takes_value(true)
+                    .requires("LOCAL_ADDR")
+                    .possible_values(&["iptables", "nftables"])
+                    .help("Install redir (transparent proxy) rules of forwarded traffic, and remove them on exit"),
+            );
+        }
     }
 
     #[cfg(target_os = "android")]
@@ -316,8 +405,8 @@ pub fn define_command_line_options(mut app: Command<'_>) -> Command<'_> {
                 Arg::new("TUN_INTERFACE_ADDRESS")
                     .long("tun-interface-address")
                     .takes_value(true)
-                    .validator(validator::validate_ipnet)
-                    .help("Tun interface address (network)"),
+                    .validator(validator::validate_tun_interface_address)
+                    .help("Tun interface address (network), or \"auto\" for choosing a free one"),
             );
 
         #[cfg(unix)]
@@ -343,6 +432,11 @@ pub fn define_command_line_options(mut app: Command<'_>) -> Command<'_> {
             );
     }
 
+    #[cfg(windows)]
+    {
+        app = crate::winsvc::define_command_line_options(app, "sslocal");
+    }
+
     #[cfg(feature = "multi-threaded")]
     {
         app = app
@@ -365,6 +459,15 @@ pub fn define_command_line_options(mut app: Command<'_>) -> Command<'_> {
 
 /// Program entrance `main`
 pub fn main(matches: &ArgMatches) {
+    #[cfg(windows)]
+    if crate::winsvc::dispatch(matches, "Shadowsocks local", run) {
+        return;
+    }
+
+    run(matches)
+}
+
+fn run(matches: &ArgMatches) {
     let (config, runtime) = {
         let config_path_opt = matches.value_of("CONFIG").map(PathBuf::from).or_else(|| {
             if !matches.is_present("SERVER_CONFIG") {
@@ -405,7 +508,8 @@ pub fn main(matches: &ArgMatches) {
         trace!("{:?}", service_config);
 
         let mut config = match config_path_opt {
-            Some(cpath) => match Config::load_from_file(&cpath, ConfigType::Local) {
+            Some(cpath) => match Config::load_profile_from_file(&cpath, ConfigType::Local, matches.value_of("PROFILE"))
+            {
                 Ok(cfg) => cfg,
                 Err(err) => {
                     eprintln!("loading config {:?}, {}", cpath, err);
@@ -436,6 +540,11 @@ pub fn main(matches: &ArgMatches) {
                 Err(err) => err.exit(),
             };
 
+            if let Err(err) = password_to_key(method, &password) {
+                eprintln!("{}", err);
+                process::exit(crate::EXIT_CODE_LOAD_CONFIG_FAILURE);
+            }
+
             let mut sc = ServerConfig::new(svr_addr, password, method);
             if let Some(timeout) = timeout {
                 sc.set_timeout(timeout);
@@ -446,6 +555,10 @@ pub fn main(matches: &ArgMatches) {
                     plugin: p.to_owned(),
                     plugin_opts: matches.value_of("PLUGIN_OPT").map(ToOwned::to_owned),
                     plugin_args: Vec::new(),
+                    plugin_mode: matches
+                        .value_of("PLUGIN_MODE")
+                        .map(|m| m.parse::<Mode>().expect("plugin-mode"))
+                        .unwrap_or(Mode::TcpOnly),
                 };
 
                 sc.set_plugin(plugin);
@@ -454,8 +567,8 @@ pub fn main(matches: &ArgMatches) {
             config.server.push(sc);
         }
 
-        match matches.value_of_t::<ServerConfig>("URL") {
-            Ok(svr_addr) => config.server.push(svr_addr),
+        match matches.values_of_t::<ServerConfig>("URL") {
+            Ok(svr_addrs) => config.server.extend(svr_addrs),
             Err(ref err) if err.kind() == ClapErrorKind::ArgumentNotFound => {}
             Err(err) => err.exit(),
         }
@@ -487,6 +600,12 @@ pub fn main(matches: &ArgMatches) {
                 Some("dns") => ProtocolType::Dns,
                 #[cfg(feature = "local-tun")]
                 Some("tun") => ProtocolType::Tun,
+                #[cfg(feature = "local-pac")]
+                Some("pac") => ProtocolType::Pac,
+                #[cfg(feature = "local-web-ui")]
+                Some("web-ui") => ProtocolType::WebUi,
+                #[cfg(feature = "local-http")]
+                Some("mixed") => ProtocolType::Mixed,
                 Some(p) => panic!("not supported `protocol` \"{}\"", p),
                 None => ProtocolType::Socks,
             };
@@ -531,6 +650,12 @@ pub fn main(matches: &ArgMatches) {
                     Err(ref err) if err.kind() == ClapErrorKind::ArgumentNotFound => {}
                     Err(err) => err.exit(),
                 }
+
+                match matches.value_of_t::<RedirFirewall>("REDIR_FIREWALL") {
+                    Ok(firewall) => local_config.redir_firewall = Some(firewall),
+                    Err(ref err) if err.kind() == ClapErrorKind::ArgumentNotFound => {}
+                    Err(err) => err.exit(),
+                }
             }
 
             #[cfg(feature = "local-dns")]
@@ -597,10 +722,13 @@ pub fn main(matches: &ArgMatches) {
             {
                 use ipnet::IpNet;
 
-                match matches.value_of_t::<IpNet>("TUN_INTERFACE_ADDRESS") {
-                    Ok(tun_address) => local_config.tun_interface_address = Some(tun_address),
-                    Err(ref err) if err.kind() == ClapErrorKind::ArgumentNotFound => {}
-                    Err(err) => err.exit(),
+                match matches.value_of("TUN_INTERFACE_ADDRESS") {
+                    Some("auto") => local_config.tun_interface_address_auto = true,
+                    Some(..) => match matches.value_of_t::<IpNet>("TUN_INTERFACE_ADDRESS") {
+                        Ok(tun_address) => local_config.tun_interface_address = Some(tun_address),
+                        Err(err) => err.exit(),
+                    },
+                    None => {}
                 }
                 match matches.value_of_t::<String>("TUN_INTERFACE_NAME") {
                     Ok(tun_name) => local_config.tun_interface_name = Some(tun_name),
@@ -670,6 +798,7 @@ pub fn main(matches: &ArgMatches) {
             Err(err) => err.exit(),
         }
 
+        #[cfg(feature = "acl")]
         if let Some(acl_file) = matches.value_of("ACL") {
             let acl = match AccessControl::load_from_file(acl_file) {
                 Ok(acl) => acl,
@@ -681,10 +810,41 @@ pub fn main(matches: &ArgMatches) {
             config.acl = Some(acl);
         }
 
+        #[cfg(feature = "acl")]
+        if matches.is_present("ACL_TRACE") {
+            config.acl_trace = true;
+        }
+
+        #[cfg(feature = "metrics")]
+        match matches.value_of_t::<ServerAddr>("METRICS_ADDR") {
+            Ok(addr) => config.metrics_addr = Some(addr),
+            Err(ref err) if err.kind() == ClapErrorKind::ArgumentNotFound => {}
+            Err(err) => err.exit(),
+        }
+
+        match matches.value_of_t::<ManagerAddr>("CONTROL_ADDR") {
+            Ok(addr) => config.control_addr = Some(addr),
+            Err(ref err) if err.kind() == ClapErrorKind::ArgumentNotFound => {}
+            Err(err) => err.exit(),
+        }
+
+        match matches.value_of_t::<ServerAddr>("FLOW_COLLECTOR") {
+            Ok(addr) => match config.flow_export {
+                Some(ref mut flow_export) => flow_export.collector = addr,
+                None => config.flow_export = Some(FlowExportConfig::new(addr)),
+            },
+            Err(ref err) if err.kind() == ClapErrorKind::ArgumentNotFound => {}
+            Err(err) => err.exit(),
+        }
+
         if let Some(dns) = matches.value_of("DNS") {
             config.set_dns_formatted(dns).expect("dns");
         }
 
+        if let Some(server_dns) = matches.value_of("SERVER_DNS") {
+            config.set_server_dns_formatted(server_dns).expect("server-dns");
+        }
+
         if matches.is_present("IPV6_FIRST") {
             config.ipv6_first = true;
         }
@@ -701,6 +861,22 @@ pub fn main(matches: &ArgMatches) {
             Err(err) => err.exit(),
         }
 
+        if matches.is_present("UDP_MIGRATION") {
+            config.udp_migration = true;
+        }
+
+        if matches.is_present("DIRECT_FALLBACK") {
+            config.direct_fallback = true;
+        }
+
+        if matches.is_present("SYSTEM_PROXY") {
+            config.system_proxy = true;
+        }
+
+        if let Some(routing_mode) = matches.value_of("ROUTING_MODE") {
+            config.routing_mode = routing_mode.parse::<RoutingMode>().expect("routing-mode");
+        }
+
         match matches.value_of_t::<u32>("INBOUND_SEND_BUFFER_SIZE") {
             Ok(bs) => config.inbound_send_buffer_size = Some(bs),
             Err(ref err) if err.kind() == ClapErrorKind::ArgumentNotFound => {}
@@ -730,27 +906,41 @@ pub fn main(matches: &ArgMatches) {
 
         // DONE READING options
 
+        #[cfg(feature = "acl")]
+        if let Some(addrs) = matches.values_of("ACL_CHECK") {
+            check_acl(&config, addrs);
+            return;
+        }
+
         if config.local.is_empty() {
             eprintln!(
                 "missing `local_address`, consider specifying it by --local-addr command line option, \
                     or \"local_address\" and \"local_port\" in configuration file"
             );
-            return;
+            process::exit(crate::EXIT_CODE_LOAD_CONFIG_FAILURE);
         }
 
-        if config.server.is_empty() {
+        #[cfg(feature = "local-online-config")]
+        let has_servers = !config.server.is_empty() || config.online_config.is_some();
+        #[cfg(not(feature = "local-online-config"))]
+        let has_servers = !config.server.is_empty();
+        if !has_servers {
             eprintln!(
                 "missing proxy servers, consider specifying it by \
                     --server-addr, --encrypt-method, --password command line option, \
                         or --server-url command line option, \
                         or configuration file, check more details in https://shadowsocks.org/en/config/quick-guide.html"
             );
-            return;
+            process::exit(crate::EXIT_CODE_LOAD_CONFIG_FAILURE);
         }
 
         if let Err(err) = config.check_integrity() {
             eprintln!("config integrity check failed, {}", err);
-            return;
+            process::exit(crate::EXIT_CODE_LOAD_CONFIG_FAILURE);
+        }
+
+        if matches.is_present("CHECK_CONFIG") {
+            check_config(&config);
         }
 
         #[cfg(unix)]
@@ -779,48 +969,285 @@ pub fn main(matches: &ArgMatches) {
         (config, runtime)
     };
 
-    runtime.block_on(async move {
-        let config_path = config.config_path.clone();
+    // Options of the command line are kept while switching profiles and reloading
+    #[cfg(feature = "acl")]
+    let cli_acl = if matches.is_present("ACL") {
+        config.acl.clone()
+    } else {
+        None
+    };
+    let cli_keep_alive = matches.is_present("TCP_KEEP_ALIVE");
+    let cli_udp_timeout = matches.is_present("UDP_TIMEOUT");
 
-        let instance = create_local(config).await.expect("create local");
+    runtime.block_on(async move {
+        let handoff = ServiceHandoff::start(&config).await;
 
-        if let Some(config_path) = config_path {
-            launch_reload_server_task(config_path, instance.server_balancer().clone());
+        // Listening sockets are recorded for reusing them while recreating servers, which is only possible by
+        // reloading or switching profiles of the configuration file. `handoff` records them by itself.
+        #[cfg(unix)]
+        if config.config_path.is_some() {
+            shadowsocks_service::shadowsocks::net::handoff::enable();
         }
 
+        #[cfg(unix)]
+        let mut sighup = {
+            use tokio::signal::unix::{signal, SignalKind};
+            signal(SignalKind::hangup()).expect("signal")
+        };
+
         let abort_signal = monitor::create_signal_monitor();
-        let server = instance.wait_until_exit();
+        let handed_off = handoff.wait_handed_off();
 
         tokio::pin!(abort_signal);
-        tokio::pin!(server);
+        tokio::pin!(handed_off);
+
+        let mut config = config;
+        let mut instance = create_local(config.clone()).await.expect("create local");
+
+        let context = 'serve: loop {
+            let context = instance.context().clone();
+            let profile_switch = instance.profile_switch().cloned();
+
+            let mut signal_tasks = Vec::new();
+            if let Some(ref config_path) = config.config_path {
+                signal_tasks.push(launch_reload_server_task(
+                    config_path.clone(),
+                    config.profile.clone(),
+                    instance.server_balancer().clone(),
+                ));
+            }
+            if let Some(ref profile_switch) = profile_switch {
+                signal_tasks.push(launch_switch_profile_task(profile_switch.clone()));
+            }
+            #[cfg(all(unix, feature = "acl"))]
+            {
+                let acl_contexts = instance.acl_contexts();
+                if !acl_contexts.is_empty() {
+                    signal_tasks.push(launch_reload_acl_task(acl_contexts));
+                }
+            }
 
-        match future::select(server, abort_signal).await {
-            // Server future resolved without an error. This should never happen.
-            Either::Left((Ok(..), ..)) => {
-                eprintln!("server exited unexpectedly");
-                process::exit(crate::EXIT_CODE_SERVER_EXIT_UNEXPECTEDLY);
+            // Boxed for dropping it after handing off or switching profiles
+            let mut server = Box::pin(instance.wait_until_exit());
+
+            let (request, new_config) = loop {
+                let profile_requested = async {
+                    match profile_switch {
+                        Some(ref profile_switch) => profile_switch.wait_requested().await,
+                        None => future::pending().await,
+                    }
+                };
+                let reload_requested = async {
+                    #[cfg(unix)]
+                    sighup.recv().await;
+                    #[cfg(not(unix))]
+                    future::pending::<()>().await;
+                };
+                let recreate_requested = future::select(
+                    Box::pin(profile_requested.map(RecreateRequest::SwitchProfile)),
+                    Box::pin(reload_requested.map(|_| RecreateRequest::Reload)),
+                )
+                .map(|either| either.factor_first().0);
+                tokio::pin!(recreate_requested);
+
+                let exit = future::select(abort_signal.as_mut(), handed_off.as_mut());
+                match future::select(server.as_mut(), future::select(exit, recreate_requested)).await {
+                    // Server future resolved without an error. This should never happen.
+                    Either::Left((Ok(..), ..)) => {
+                        eprintln!("server exited unexpectedly");
+                        process::exit(crate::EXIT_CODE_SERVER_EXIT_UNEXPECTEDLY);
+                    }
+                    // Server future resolved with error, which are listener errors in most cases
+                    Either::Left((Err(err), ..)) => {
+                        eprintln!("server aborted with {}", err);
+                        process::exit(crate::EXIT_CODE_SERVER_ABORTED);
+                    }
+                    // The abort signal future resolved. Means we should just exit.
+                    Either::Right((Either::Left((Either::Left(..), ..)), ..)) => break 'serve context,
+                    // Listeners have been handed off to a new process. Stop accepting, but keep serving established connections
+                    Either::Right((Either::Left((Either::Right((drain_timeout, ..)), ..)), ..)) => {
+                        drop(server);
+                        info!("serving established connections for {:?} before exiting", drain_timeout);
+                        let _ = time::timeout(drain_timeout, abort_signal.as_mut()).await;
+                        break 'serve context;
+                    }
+                    // Switching to another profile or reloading, the configuration is loaded before stopping the
+                    // current servers
+                    Either::Right((Either::Right((request, ..)), ..)) => match request.load(&config) {
+                        Ok(new_config) => break (request, new_config),
+                        Err(err) => error!("{} failed, {}", request, err),
+                    },
+                }
+            };
+
+            let mut new_config = new_config;
+            #[cfg(feature = "acl")]
+            if cli_acl.is_some() {
+                new_config.acl = cli_acl.clone();
+            }
+            if cli_keep_alive {
+                new_config.keep_alive = config.keep_alive;
             }
-            // Server future resolved with error, which are listener errors in most cases
-            Either::Left((Err(err), ..)) => {
-                eprintln!("server aborted with {}", err);
-                process::exit(crate::EXIT_CODE_SERVER_ABORTED);
+            if cli_udp_timeout {
+                new_config.udp_timeout = config.udp_timeout;
             }
-            // The abort signal future resolved. Means we should just exit.
-            Either::Right(_) => (),
+
+            // Established connections are kept, they are not owned by listeners. Listeners are kept open for the new
+            // servers, only listeners of changed addresses are rebound
+            for task in signal_tasks {
+                task.abort();
+            }
+            #[cfg(unix)]
+            shadowsocks_service::shadowsocks::net::handoff::recycle_bound();
+            drop(server);
+
+            instance = match create_local(new_config.clone()).await {
+                Ok(instance) => {
+                    info!("{} succeeded", request);
+                    config = new_config;
+                    instance
+                }
+                Err(err) => {
+                    error!(
+                        "{} failed, {}, recreating servers of the current configuration",
+                        request, err
+                    );
+                    create_local(config.clone()).await.expect("create local")
+                }
+            };
+
+            #[cfg(unix)]
+            shadowsocks_service::shadowsocks::net::handoff::clear_inherited();
+        };
+
+        if let Err(err) = context.save_connection_table() {
+            error!("failed to save active connections, error: {}", err);
+        }
+    });
+}
+
+/// Print ACL decisions of `addrs`, resolving domain names by the system resolver if IP rules are checked
+#[cfg(feature = "acl")]
+fn check_acl<'a>(config: &Config, addrs: impl Iterator<Item = &'a str>) {
+    let acl = match config.acl {
+        Some(ref acl) => acl,
+        None => {
+            eprintln!(
+                "missing ACL, consider specifying it by --acl command line option, or \"acl\" in configuration file"
+            );
+            process::exit(crate::EXIT_CODE_LOAD_ACL_FAILURE);
+        }
+    };
+
+    let mut context = Context::new(ServerType::Local);
+    context.set_ipv6_first(config.ipv6_first);
+
+    let runtime = Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .expect("create tokio Runtime");
+
+    runtime.block_on(async move {
+        for addr in addrs {
+            // Bare IP addresses are not domain names
+            let target = match addr.parse::<IpAddr>() {
+                Ok(ip) => Address::SocketAddress((ip, 0).into()),
+                Err(..) => match addr.parse::<Address>() {
+                    Ok(target) => target,
+                    Err(..) => {
+                        println!("{} invalid address, expecting HOST[:PORT]", addr);
+                        continue;
+                    }
+                },
+            };
+
+            let decision = acl.explain_target(&context, &target).await;
+            println!("{} {}", addr, decision);
         }
     });
 }
 
+/// Requests of recreating local servers with a new configuration
+enum RecreateRequest {
+    /// Switch to the profile, by `SIGUSR2` or the web UI
+    SwitchProfile(String),
+    /// Reload the configuration file with the current profile, by `SIGHUP`
+    Reload,
+}
+
+impl RecreateRequest {
+    /// Load the new configuration from the configuration file of `config`
+    fn load(&self, config: &Config) -> Result<Config, String> {
+        match *self {
+            RecreateRequest::SwitchProfile(ref profile) => load_profile(config, Some(profile), false),
+            RecreateRequest::Reload => load_profile(config, config.profile.as_deref(), true),
+        }
+    }
+}
+
+impl fmt::Display for RecreateRequest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            RecreateRequest::SwitchProfile(ref profile) => write!(f, "switch to profile {}", profile),
+            RecreateRequest::Reload => f.write_str("reload configuration"),
+        }
+    }
+}
+
+/// Load the configuration file of `config` with profile `name` applied, options of the command line are kept
+///
+/// Profiles replace locals, servers, balancer and ACL. Reloading also replaces timeouts and the online configuration.
+fn load_profile(config: &Config, name: Option<&str>, reload: bool) -> Result<Config, String> {
+    let config_path = match config.config_path {
+        Some(ref p) => p,
+        None => return Err("missing configuration file".to_owned()),
+    };
+    let profile_config = Config::load_profile_from_file(config_path, ConfigType::Local, name)
+        .map_err(|err| format!("loading config {:?}, {}", config_path, err))?;
+
+    let mut new_config = config.clone();
+    new_config.local = profile_config.local;
+    new_config.server = profile_config.server;
+    new_config.balancer = profile_config.balancer;
+    #[cfg(feature = "acl")]
+    {
+        new_config.acl = profile_config.acl;
+    }
+    new_config.profiles = profile_config.profiles;
+    new_config.profile = profile_config.profile;
+    if reload {
+        new_config.keep_alive = profile_config.keep_alive;
+        new_config.udp_timeout = profile_config.udp_timeout;
+        new_config.udp_max_associations = profile_config.udp_max_associations;
+        #[cfg(feature = "local-online-config")]
+        {
+            new_config.online_config = profile_config.online_config;
+        }
+    }
+
+    #[cfg(feature = "local-online-config")]
+    let has_servers = !new_config.server.is_empty() || new_config.online_config.is_some();
+    #[cfg(not(feature = "local-online-config"))]
+    let has_servers = !new_config.server.is_empty();
+    if new_config.local.is_empty() || !has_servers {
+        return Err("missing locals or servers".to_owned());
+    }
+    new_config
+        .check_integrity()
+        .map_err(|err| format!("config integrity check failed, {}", err))?;
+
+    Ok(new_config)
+}
+
 #[cfg(unix)]
-fn launch_reload_server_task(config_path: PathBuf, balancer: PingBalancer) {
-    use log::error;
+fn launch_reload_server_task(config_path: PathBuf, profile: Option<String>, balancer: PingBalancer) -> JoinHandle<()> {
     use tokio::signal::unix::{signal, SignalKind};
 
     tokio::spawn(async move {
         let mut sigusr1 = signal(SignalKind::user_defined1()).expect("signal");
 
         while sigusr1.recv().await.is_some() {
-            let config = match Config::load_from_file(&config_path, ConfigType::Local) {
+            let config = match Config::load_profile_from_file(&config_path, ConfigType::Local, profile.as_deref()) {
                 Ok(c) => c,
                 Err(err) => {
                     error!("auto-reload {} failed with error: {}", config_path.display(), err);
@@ -835,8 +1262,49 @@ fn launch_reload_server_task(config_path: PathBuf, balancer: PingBalancer) {
                 error!("auto-reload {} but found error: {}", config_path.display(), err);
             }
         }
-    });
+    })
+}
+
+#[cfg(not(unix))]
+fn launch_reload_server_task(_: PathBuf, _: Option<String>, _: PingBalancer) -> JoinHandle<()> {
+    tokio::spawn(async {})
+}
+
+/// Reload ACLs (and their GeoIP databases) of `contexts` on `SIGUSR1`, with the servers
+#[cfg(all(unix, feature = "acl"))]
+fn launch_reload_acl_task(contexts: Vec<Arc<ServiceContext>>) -> JoinHandle<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    tokio::spawn(async move {
+        let mut sigusr1 = signal(SignalKind::user_defined1()).expect("signal");
+
+        while sigusr1.recv().await.is_some() {
+            for context in &contexts {
+                if let Err(err) = context.reload_acl() {
+                    error!("reload ACL failed with error: {}", err);
+                }
+            }
+        }
+    })
+}
+
+/// Switch to the next profile on `SIGUSR2`
+#[cfg(unix)]
+fn launch_switch_profile_task(profile_switch: Arc<ProfileSwitch>) -> JoinHandle<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    tokio::spawn(async move {
+        let mut sigusr2 = signal(SignalKind::user_defined2()).expect("signal");
+
+        while sigusr2.recv().await.is_some() {
+            if let Err(err) = profile_switch.request_next() {
+                error!("switch profile failed, {}", err);
+            }
+        }
+    })
 }
 
 #[cfg(not(unix))]
-fn launch_reload_server_task(_: PathBuf, _: PingBalancer) {}
+fn launch_switch_profile_task(_: Arc<ProfileSwitch>) -> JoinHandle<()> {
+    tokio::spawn(async {})
+}
@@ -7,15 +7,16 @@ use futures::future::{self, Either};
 use log::{info, trace};
 use tokio::{self, runtime::Builder};
 
+#[cfg(feature = "acl")]
+use shadowsocks_service::acl::AccessControl;
 #[cfg(unix)]
 use shadowsocks_service::config::ManagerServerMode;
 use shadowsocks_service::{
-    acl::AccessControl,
     config::{Config, ConfigType, ManagerConfig, ManagerServerHost},
     run_manager,
     shadowsocks::{
         config::{ManagerAddr, Mode},
-        crypto::v1::{available_ciphers, CipherKind},
+        crypto::{available_ciphers, CipherKind},
         plugin::PluginConfig,
     },
 };
@@ -28,6 +29,8 @@ use crate::{
     validator,
 };
 
+use super::check::check_config;
+
 /// Defines command line options
 pub fn define_command_line_options(mut app: Command<'_>) -> Command<'_> {
     app = app
@@ -38,6 +41,11 @@ pub fn define_command_line_options(mut app: Command<'_>) -> Command<'_> {
                 .takes_value(true)
                 .help("Shadowsocks configuration file (https://shadowsocks.org/en/config/quick-guide.html), the only required fields are \"manager_address\" and \"manager_port\". Servers defined will be created when process is started."),
         )
+        .arg(
+            Arg::new("CHECK_CONFIG")
+                .long("check-config")
+                .help("Check the configuration and exit, with a non-zero code if there are any problems"),
+        )
         .arg(
             Arg::new("UDP_ONLY")
                 .short('u')
@@ -91,12 +99,21 @@ pub fn define_command_line_options(mut app: Command<'_>) -> Command<'_> {
                 .takes_value(true)
                 .requires("PLUGIN")
                 .help("Default SIP003 plugin options"),
-        ).arg(Arg::new("ACL").long("acl").takes_value(true).help("Path to ACL (Access Control List)"))
+        )
+        .arg(
+            Arg::new("PLUGIN_MODE")
+                .long("plugin-mode")
+                .takes_value(true)
+                .requires("PLUGIN")
+                .possible_values(["tcp_only", "tcp_and_udp", "udp_only"])
+                .help("Protocols relayed by the default SIP003 plugin, tcp_and_udp or udp_only for plugins supporting UDP (SIP003u)"),
+        )
         .arg(Arg::new("DNS").long("dns").takes_value(true).help("DNS nameservers, formatted like [(tcp|udp)://]host[:port][,host[:port]]..., or unix:///path/to/dns, or predefined keys like \"google\", \"cloudflare\""))
         .arg(Arg::new("TCP_NO_DELAY").long("tcp-no-delay").alias("no-delay").help("Set TCP_NODELAY option for sockets"))
         .arg(Arg::new("TCP_FAST_OPEN").long("tcp-fast-open").alias("fast-open").help("Enable TCP Fast Open (TFO)"))
         .arg(Arg::new("TCP_KEEP_ALIVE").long("tcp-keep-alive").takes_value(true).validator(validator::validate_u64).help("Set TCP keep alive timeout seconds"))
         .arg(Arg::new("UDP_TIMEOUT").long("udp-timeout").takes_value(true).validator(validator::validate_u64).help("Timeout seconds for UDP relay"))
+        .arg(Arg::new("DRAIN_TIMEOUT").long("drain-timeout").takes_value(true).validator(validator::validate_u64).help("Keep relaying established connections of servers removed by `drain` command for at most these seconds"))
         .arg(Arg::new("UDP_MAX_ASSOCIATIONS").long("udp-max-associations").takes_value(true).validator(validator::validate_u64).help("Maximum associations to be kept simultaneously for UDP relay"))
         .arg(Arg::new("INBOUND_SEND_BUFFER_SIZE").long("inbound-send-buffer-size").takes_value(true).validator(validator::validate_u32).help("Set inbound sockets' SO_SNDBUF option"))
         .arg(Arg::new("INBOUND_RECV_BUFFER_SIZE").long("inbound-recv-buffer-size").takes_value(true).validator(validator::validate_u32).help("Set inbound sockets' SO_RCVBUF option"))
@@ -108,6 +125,16 @@ pub fn define_command_line_options(mut app: Command<'_>) -> Command<'_> {
                 .help("Resolve hostname to IPv6 address first"),
         );
 
+    #[cfg(feature = "acl")]
+    {
+        app = app.arg(
+            Arg::new("ACL")
+                .long("acl")
+                .takes_value(true)
+                .help("Path to ACL (Access Control List)"),
+        );
+    }
+
     #[cfg(feature = "logging")]
     {
         app = app
@@ -122,6 +149,13 @@ pub fn define_command_line_options(mut app: Command<'_>) -> Command<'_> {
                     .long("log-without-time")
                     .help("Log without datetime prefix"),
             )
+            .arg(
+                Arg::new("LOG_FORMAT")
+                    .long("log-format")
+                    .takes_value(true)
+                    .possible_values(["text", "json"])
+                    .help("Format of logs, \"json\" writes a JSON object per record with fields of connections"),
+            )
             .arg(
                 Arg::new("LOG_CONFIG")
                     .long("log-config")
@@ -330,6 +364,10 @@ pub fn main(matches: &ArgMatches) {
                     plugin: p.to_owned(),
                     plugin_opts: matches.value_of("PLUGIN_OPT").map(ToOwned::to_owned),
                     plugin_args: Vec::new(),
+                    plugin_mode: matches
+                        .value_of("PLUGIN_MODE")
+                        .map(|m| m.parse::<Mode>().expect("plugin-mode"))
+                        .unwrap_or(Mode::TcpOnly),
                 });
             }
 
@@ -370,6 +408,7 @@ pub fn main(matches: &ArgMatches) {
             Err(err) => err.exit(),
         }
 
+        #[cfg(feature = "acl")]
         if let Some(acl_file) = matches.value_of("ACL") {
             let acl = match AccessControl::load_from_file(acl_file) {
                 Ok(acl) => acl,
@@ -395,6 +434,12 @@ pub fn main(matches: &ArgMatches) {
             Err(err) => err.exit(),
         }
 
+        match matches.value_of_t::<u64>("DRAIN_TIMEOUT") {
+            Ok(drain_timeout) => config.drain_timeout = Some(Duration::from_secs(drain_timeout)),
+            Err(ref err) if err.kind() == ClapErrorKind::ArgumentNotFound => {}
+            Err(err) => err.exit(),
+        }
+
         match matches.value_of_t::<usize>("UDP_MAX_ASSOCIATIONS") {
             Ok(udp_max_assoc) => config.udp_max_associations = Some(udp_max_assoc),
             Err(ref err) if err.kind() == ClapErrorKind::ArgumentNotFound => {}
@@ -435,12 +480,16 @@ pub fn main(matches: &ArgMatches) {
                 "missing `manager_address`, consider specifying it by --manager-address command line option, \
                     or \"manager_address\" and \"manager_port\" keys in configuration file"
             );
-            return;
+            process::exit(crate::EXIT_CODE_LOAD_CONFIG_FAILURE);
         }
 
         if let Err(err) = config.check_integrity() {
             eprintln!("config integrity check failed, {}", err);
-            return;
+            process::exit(crate::EXIT_CODE_LOAD_CONFIG_FAILURE);
+        }
+
+        if matches.is_present("CHECK_CONFIG") {
+            check_config(&config);
         }
 
         #[cfg(unix)]
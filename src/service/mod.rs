@@ -1,8 +1,70 @@
 //! Service launchers
 
+use std::time::Duration;
+
+use shadowsocks_service::config::Config;
+#[cfg(unix)]
+use shadowsocks_service::handoff::Handoff;
+
+pub mod check;
 #[cfg(feature = "local")]
 pub mod local;
 #[cfg(feature = "manager")]
 pub mod manager;
 #[cfg(feature = "server")]
 pub mod server;
+
+/// Listening sockets handoff of a service, for binary upgrades
+#[cfg_attr(not(unix), allow(dead_code))]
+struct ServiceHandoff {
+    #[cfg(unix)]
+    handoff: Option<Handoff>,
+}
+
+#[cfg_attr(not(unix), allow(dead_code))]
+impl ServiceHandoff {
+    /// Take over listening sockets passed by the service manager (socket activation), and from the old process if
+    /// `handoff` is configured
+    ///
+    /// Exits the process if it fails, because listening on the same addresses would fail too.
+    async fn start(config: &Config) -> ServiceHandoff {
+        #[cfg(unix)]
+        if let Err(err) = shadowsocks_service::activation::inherit_listeners() {
+            eprintln!("take over activated sockets failed, {}", err);
+            std::process::exit(crate::EXIT_CODE_SERVER_ABORTED);
+        }
+
+        #[cfg(unix)]
+        if let Some(ref handoff_config) = config.handoff {
+            match Handoff::start(handoff_config.clone()).await {
+                Ok(handoff) => return ServiceHandoff { handoff: Some(handoff) },
+                Err(err) => {
+                    eprintln!(
+                        "take over listeners from {} failed, {}",
+                        handoff_config.path.display(),
+                        err
+                    );
+                    std::process::exit(crate::EXIT_CODE_SERVER_ABORTED);
+                }
+            }
+        }
+
+        let _ = config;
+        ServiceHandoff {
+            #[cfg(unix)]
+            handoff: None,
+        }
+    }
+
+    /// Resolves with the draining duration after listening sockets are handed off to a new process
+    async fn wait_handed_off(self) -> Duration {
+        #[cfg(unix)]
+        if let Some(handoff) = self.handoff {
+            let drain_timeout = handoff.drain_timeout();
+            handoff.run().await;
+            return drain_timeout;
+        }
+
+        futures::future::pending().await
+    }
+}
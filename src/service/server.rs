@@ -5,15 +5,16 @@ use std::{net::IpAddr, path::PathBuf, process, time::Duration};
 use clap::{Arg, ArgGroup, ArgMatches, Command, ErrorKind as ClapErrorKind};
 use futures::future::{self, Either};
 use log::{info, trace};
-use tokio::{self, runtime::Builder};
+use tokio::{self, runtime::Builder, time};
 
+#[cfg(feature = "acl")]
+use shadowsocks_service::acl::AccessControl;
 use shadowsocks_service::{
-    acl::AccessControl,
     config::{read_variable_field_value, Config, ConfigType, ManagerConfig},
-    run_server,
+    create_server,
     shadowsocks::{
-        config::{ManagerAddr, Mode, ServerAddr, ServerConfig},
-        crypto::v1::{available_ciphers, CipherKind},
+        config::{password_to_key, ManagerAddr, Mode, ServerAddr, ServerConfig},
+        crypto::{available_ciphers, CipherKind},
         plugin::PluginConfig,
     },
 };
@@ -26,6 +27,8 @@ use crate::{
     validator,
 };
 
+use super::{check::check_config, ServiceHandoff};
+
 /// Defines command line options
 pub fn define_command_line_options(mut app: Command<'_>) -> Command<'_> {
     app = app
@@ -36,6 +39,11 @@ pub fn define_command_line_options(mut app: Command<'_>) -> Command<'_> {
                 .takes_value(true)
                 .help("Shadowsocks configuration file (https://shadowsocks.org/en/config/quick-guide.html)"),
         )
+        .arg(
+            Arg::new("CHECK_CONFIG")
+                .long("check-config")
+                .help("Check the configuration and exit, with a non-zero code if there are any problems"),
+        )
         .arg(
             Arg::new("OUTBOUND_BIND_ADDR")
                 .short('b')
@@ -115,13 +123,21 @@ pub fn define_command_line_options(mut app: Command<'_>) -> Command<'_> {
                 .requires("PLUGIN")
                 .help("Set SIP003 plugin options"),
         )
+        .arg(
+            Arg::new("PLUGIN_MODE")
+                .long("plugin-mode")
+                .takes_value(true)
+                .requires("PLUGIN")
+                .possible_values(["tcp_only", "tcp_and_udp", "udp_only"])
+                .help("Protocols relayed by the SIP003 plugin, tcp_and_udp or udp_only for plugins supporting UDP (SIP003u)"),
+        )
         .arg(Arg::new("MANAGER_ADDR").long("manager-addr").takes_value(true).alias("manager-address").help("ShadowSocks Manager (ssmgr) address, could be \"IP:Port\", \"Domain:Port\" or \"/path/to/unix.sock\""))
-        .arg(Arg::new("ACL").long("acl").takes_value(true).help("Path to ACL (Access Control List)"))
         .arg(Arg::new("DNS").long("dns").takes_value(true).help("DNS nameservers, formatted like [(tcp|udp)://]host[:port][,host[:port]]..., or unix:///path/to/dns, or predefined keys like \"google\", \"cloudflare\""))
         .arg(Arg::new("TCP_NO_DELAY").long("tcp-no-delay").alias("no-delay").help("Set TCP_NODELAY option for sockets"))
         .arg(Arg::new("TCP_FAST_OPEN").long("tcp-fast-open").alias("fast-open").help("Enable TCP Fast Open (TFO)"))
         .arg(Arg::new("TCP_KEEP_ALIVE").long("tcp-keep-alive").takes_value(true).validator(validator::validate_u64).help("Set TCP keep alive timeout seconds"))
         .arg(Arg::new("UDP_TIMEOUT").long("udp-timeout").takes_value(true).validator(validator::validate_u64).help("Timeout seconds for UDP relay"))
+        .arg(Arg::new("DRAIN_TIMEOUT").long("drain-timeout").takes_value(true).validator(validator::validate_u64).help("Stop accepting on SIGTERM, and keep relaying established connections for at most these seconds"))
         .arg(Arg::new("UDP_MAX_ASSOCIATIONS").long("udp-max-associations").takes_value(true).validator(validator::validate_u64).help("Maximum associations to be kept simultaneously for UDP relay"))
         .arg(Arg::new("INBOUND_SEND_BUFFER_SIZE").long("inbound-send-buffer-size").takes_value(true).validator(validator::validate_u32).help("Set inbound sockets' SO_SNDBUF option"))
         .arg(Arg::new("INBOUND_RECV_BUFFER_SIZE").long("inbound-recv-buffer-size").takes_value(true).validator(validator::validate_u32).help("Set inbound sockets' SO_RCVBUF option"))
@@ -133,6 +149,26 @@ pub fn define_command_line_options(mut app: Command<'_>) -> Command<'_> {
                 .help("Resolve hostname to IPv6 address first"),
         );
 
+    #[cfg(feature = "acl")]
+    {
+        app = app.arg(
+            Arg::new("ACL")
+                .long("acl")
+                .takes_value(true)
+                .help("Path to ACL (Access Control List)"),
+        );
+    }
+
+    #[cfg(feature = "metrics")]
+    {
+        app = app.arg(
+            Arg::new("METRICS_ADDR")
+                .long("metrics-addr")
+                .takes_value(true)
+                .help("Serve Prometheus metrics in http://METRICS_ADDR/metrics"),
+        );
+    }
+
     #[cfg(feature = "logging")]
     {
         app = app
@@ -147,6 +183,13 @@ pub fn define_command_line_options(mut app: Command<'_>) -> Command<'_> {
                     .long("log-without-time")
                     .help("Log without datetime prefix"),
             )
+            .arg(
+                Arg::new("LOG_FORMAT")
+                    .long("log-format")
+                    .takes_value(true)
+                    .possible_values(["text", "json"])
+                    .help("Format of logs, \"json\" writes a JSON object per record with fields of connections"),
+            )
             .arg(
                 Arg::new("LOG_CONFIG")
                     .long("log-config")
@@ -200,6 +243,11 @@ pub fn define_command_line_options(mut app: Command<'_>) -> Command<'_> {
         );
     }
 
+    #[cfg(windows)]
+    {
+        app = crate::winsvc::define_command_line_options(app, "ssserver");
+    }
+
     #[cfg(feature = "multi-threaded")]
     {
         app = app
@@ -222,6 +270,15 @@ pub fn define_command_line_options(mut app: Command<'_>) -> Command<'_> {
 
 /// Program entrance `main`
 pub fn main(matches: &ArgMatches) {
+    #[cfg(windows)]
+    if crate::winsvc::dispatch(matches, "Shadowsocks server", run) {
+        return;
+    }
+
+    run(matches)
+}
+
+fn run(matches: &ArgMatches) {
     let (config, runtime) = {
         let config_path_opt = matches.value_of("CONFIG").map(PathBuf::from).or_else(|| {
             if !matches.is_present("SERVER_CONFIG") {
@@ -292,6 +349,11 @@ pub fn main(matches: &ArgMatches) {
                 Err(err) => err.exit(),
             };
 
+            if let Err(err) = password_to_key(method, &password) {
+                eprintln!("{}", err);
+                process::exit(crate::EXIT_CODE_LOAD_CONFIG_FAILURE);
+            }
+
             let mut sc = ServerConfig::new(svr_addr, password, method);
             if let Some(timeout) = timeout {
                 sc.set_timeout(timeout);
@@ -302,6 +364,10 @@ pub fn main(matches: &ArgMatches) {
                     plugin: p.to_owned(),
                     plugin_opts: matches.value_of("PLUGIN_OPT").map(ToOwned::to_owned),
                     plugin_args: Vec::new(),
+                    plugin_mode: matches
+                        .value_of("PLUGIN_MODE")
+                        .map(|m| m.parse::<Mode>().expect("plugin-mode"))
+                        .unwrap_or(Mode::TcpOnly),
                 };
 
                 sc.set_plugin(plugin);
@@ -376,6 +442,7 @@ pub fn main(matches: &ArgMatches) {
             Err(err) => err.exit(),
         }
 
+        #[cfg(feature = "acl")]
         if let Some(acl_file) = matches.value_of("ACL") {
             let acl = match AccessControl::load_from_file(acl_file) {
                 Ok(acl) => acl,
@@ -387,6 +454,13 @@ pub fn main(matches: &ArgMatches) {
             config.acl = Some(acl);
         }
 
+        #[cfg(feature = "metrics")]
+        match matches.value_of_t::<ServerAddr>("METRICS_ADDR") {
+            Ok(addr) => config.metrics_addr = Some(addr),
+            Err(ref err) if err.kind() == ClapErrorKind::ArgumentNotFound => {}
+            Err(err) => err.exit(),
+        }
+
         if let Some(dns) = matches.value_of("DNS") {
             config.set_dns_formatted(dns).expect("dns");
         }
@@ -401,6 +475,12 @@ pub fn main(matches: &ArgMatches) {
             Err(err) => err.exit(),
         }
 
+        match matches.value_of_t::<u64>("DRAIN_TIMEOUT") {
+            Ok(drain_timeout) => config.drain_timeout = Some(Duration::from_secs(drain_timeout)),
+            Err(ref err) if err.kind() == ClapErrorKind::ArgumentNotFound => {}
+            Err(err) => err.exit(),
+        }
+
         match matches.value_of_t::<usize>("UDP_MAX_ASSOCIATIONS") {
             Ok(udp_max_assoc) => config.udp_max_associations = Some(udp_max_assoc),
             Err(ref err) if err.kind() == ClapErrorKind::ArgumentNotFound => {}
@@ -442,12 +522,16 @@ pub fn main(matches: &ArgMatches) {
                     --server-addr, --encrypt-method, --password command line option, \
                         or configuration file, check more details in https://shadowsocks.org/en/config/quick-guide.html"
             );
-            return;
+            process::exit(crate::EXIT_CODE_LOAD_CONFIG_FAILURE);
         }
 
         if let Err(err) = config.check_integrity() {
             eprintln!("config integrity check failed, {}", err);
-            return;
+            process::exit(crate::EXIT_CODE_LOAD_CONFIG_FAILURE);
+        }
+
+        if matches.is_present("CHECK_CONFIG") {
+            check_config(&config);
         }
 
         #[cfg(unix)]
@@ -477,13 +561,27 @@ pub fn main(matches: &ArgMatches) {
     };
 
     runtime.block_on(async move {
+        let handoff = ServiceHandoff::start(&config).await;
+        let drain_timeout = config.drain_timeout;
+
+        let instance = match create_server(config).await {
+            Ok(instance) => instance,
+            Err(err) => {
+                eprintln!("server aborted with {}", err);
+                process::exit(crate::EXIT_CODE_SERVER_ABORTED);
+            }
+        };
+        let drain = instance.drain_handle();
+
         let abort_signal = monitor::create_signal_monitor();
-        let server = run_server(config);
+        // Boxed for dropping it after handing off
+        let server = Box::pin(instance.wait_until_exit());
+        let handed_off = handoff.wait_handed_off();
 
         tokio::pin!(abort_signal);
-        tokio::pin!(server);
+        tokio::pin!(handed_off);
 
-        match future::select(server, abort_signal).await {
+        match future::select(server, future::select(abort_signal, handed_off)).await {
             // Server future resolved without an error. This should never happen.
             Either::Left((Ok(..), ..)) => {
                 eprintln!("server exited unexpectedly");
@@ -494,8 +592,30 @@ pub fn main(matches: &ArgMatches) {
                 eprintln!("server aborted with {}", err);
                 process::exit(crate::EXIT_CODE_SERVER_ABORTED);
             }
-            // The abort signal future resolved. Means we should just exit.
-            Either::Right(_) => (),
+            // The abort signal future resolved. Stop accepting and drain established connections if configured,
+            // until all of them are closed, timed out, or received another signal
+            Either::Right((Either::Left(..), server)) => {
+                if let Some(drain_timeout) = drain_timeout {
+                    drain.drain();
+                    info!(
+                        "draining established connections for at most {:?} before exiting",
+                        drain_timeout
+                    );
+
+                    let abort_signal = monitor::create_signal_monitor();
+                    tokio::select! {
+                        _ = server => (),
+                        _ = abort_signal => (),
+                        _ = drain.wait_drained(drain_timeout) => (),
+                    }
+                }
+            }
+            // Listeners have been handed off to a new process. Stop accepting, but keep serving established connections
+            Either::Right((Either::Right((drain_timeout, abort_signal)), server)) => {
+                drop(server);
+                info!("serving established connections for {:?} before exiting", drain_timeout);
+                let _ = time::timeout(drain_timeout, abort_signal).await;
+            }
         }
     });
 }
@@ -64,3 +64,11 @@ pub fn validate_ipnet(v: &str) -> Result<(), String> {
         }
     }
 }
+
+#[cfg(feature = "local-tun")]
+pub fn validate_tun_interface_address(v: &str) -> Result<(), String> {
+    if v == "auto" {
+        return Ok(());
+    }
+    validate_ipnet(v).map_err(|err| format!("{}, or \"auto\"", err))
+}
@@ -0,0 +1,243 @@
+//! Windows service, managed by the Service Control Manager (SCM)
+//!
+//! `--service install` registers the current executable as a service, started automatically with the same command
+//! line arguments, and `--service uninstall` removes it. The SCM starts the service with `--service run`, which
+//! connects the process to the SCM and runs the server until the service is stopped.
+
+use std::{
+    env,
+    ffi::{OsStr, OsString},
+    io::{self, ErrorKind},
+    process,
+    sync::Mutex,
+    thread,
+    time::{Duration, Instant},
+};
+
+use clap::{Arg, ArgMatches, Command};
+use log::{error, info};
+use windows_service::{
+    define_windows_service,
+    service::{
+        ServiceAccess,
+        ServiceControl,
+        ServiceControlAccept,
+        ServiceErrorControl,
+        ServiceExitCode,
+        ServiceInfo,
+        ServiceStartType,
+        ServiceState,
+        ServiceStatus,
+        ServiceType,
+    },
+    service_control_handler::{self, ServiceControlHandlerResult},
+    service_dispatcher,
+    service_manager::{ServiceManager, ServiceManagerAccess},
+};
+
+use crate::monitor;
+
+/// Maximum time waiting for the service to be stopped while uninstalling it
+const STOP_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The server to be run by the service, with the command line arguments
+struct ServiceEntry {
+    name: String,
+    run: fn(&ArgMatches),
+    matches: ArgMatches,
+}
+
+static SERVICE_ENTRY: Mutex<Option<ServiceEntry>> = Mutex::new(None);
+
+/// Define command line options of Windows services
+pub fn define_command_line_options<'a>(app: Command<'a>, default_name: &'a str) -> Command<'a> {
+    app.arg(
+        Arg::new("SERVICE")
+            .long("service")
+            .takes_value(true)
+            .possible_values(["install", "uninstall", "run"])
+            .help("Install or uninstall as a Windows service, or run as the service started by the SCM"),
+    )
+    .arg(
+        Arg::new("SERVICE_NAME")
+            .long("service-name")
+            .takes_value(true)
+            .default_value(default_name)
+            .help("Name of the Windows service"),
+    )
+}
+
+/// Handle the `--service` command, returns `false` if it isn't specified and the server should be run as usual
+///
+/// `run` is the server's entrance, which returns when the abort signal is received.
+pub fn dispatch(matches: &ArgMatches, description: &str, run: fn(&ArgMatches)) -> bool {
+    let command = match matches.value_of("SERVICE") {
+        Some(command) => command,
+        None => return false,
+    };
+    let name = matches.value_of("SERVICE_NAME").expect("service name");
+
+    let result = match command {
+        "install" => install(name, description),
+        "uninstall" => uninstall(name),
+        "run" => {
+            *SERVICE_ENTRY.lock().unwrap() = Some(ServiceEntry {
+                name: name.to_owned(),
+                run,
+                matches: matches.clone(),
+            });
+            service_dispatcher::start(name, ffi_service_main).map_err(to_io_error)
+        }
+        _ => unreachable!("unknown service command {}", command),
+    };
+
+    if let Err(err) = result {
+        eprintln!("service {} {} failed, {}", name, command, err);
+        process::exit(crate::EXIT_CODE_SERVER_ABORTED);
+    }
+
+    true
+}
+
+fn to_io_error(err: windows_service::Error) -> io::Error {
+    match err {
+        windows_service::Error::Winapi(err) => err,
+        err => io::Error::new(ErrorKind::Other, err),
+    }
+}
+
+/// Register the current executable as a service, started with the current command line arguments
+fn install(name: &str, description: &str) -> io::Result<()> {
+    let manager = ServiceManager::local_computer(
+        None::<&str>,
+        ServiceManagerAccess::CONNECT | ServiceManagerAccess::CREATE_SERVICE,
+    )
+    .map_err(to_io_error)?;
+
+    let info = ServiceInfo {
+        name: OsString::from(name),
+        display_name: OsString::from(name),
+        service_type: ServiceType::OWN_PROCESS,
+        start_type: ServiceStartType::AutoStart,
+        error_control: ServiceErrorControl::Normal,
+        executable_path: env::current_exe()?,
+        launch_arguments: service_arguments(env::args_os().skip(1)),
+        dependencies: Vec::new(),
+        account_name: None,
+        account_password: None,
+    };
+
+    let service = manager
+        .create_service(&info, ServiceAccess::CHANGE_CONFIG)
+        .map_err(to_io_error)?;
+    service.set_description(description).map_err(to_io_error)?;
+
+    println!("service {} installed", name);
+    Ok(())
+}
+
+/// Command line arguments of the service, with `--service install` replaced by `--service run`
+fn service_arguments<I>(mut args: I) -> Vec<OsString>
+where
+    I: Iterator<Item = OsString>,
+{
+    let mut service_args = Vec::new();
+    while let Some(arg) = args.next() {
+        if arg == OsStr::new("--service") {
+            args.next();
+            continue;
+        }
+        if matches!(arg.to_str(), Some(arg) if arg.starts_with("--service=")) {
+            continue;
+        }
+        service_args.push(arg);
+    }
+
+    service_args.push(OsString::from("--service"));
+    service_args.push(OsString::from("run"));
+    service_args
+}
+
+/// Stop the service if it is running, and delete it
+fn uninstall(name: &str) -> io::Result<()> {
+    let manager = ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT).map_err(to_io_error)?;
+    let service = manager
+        .open_service(
+            name,
+            ServiceAccess::QUERY_STATUS | ServiceAccess::STOP | ServiceAccess::DELETE,
+        )
+        .map_err(to_io_error)?;
+
+    // Deleted services are removed after they are stopped
+    service.delete().map_err(to_io_error)?;
+
+    if service.query_status().map_err(to_io_error)?.current_state != ServiceState::Stopped {
+        service.stop().map_err(to_io_error)?;
+
+        let start = Instant::now();
+        while service.query_status().map_err(to_io_error)?.current_state != ServiceState::Stopped {
+            if start.elapsed() > STOP_TIMEOUT {
+                return Err(io::Error::new(
+                    ErrorKind::TimedOut,
+                    "service is not stopped, it will be removed after it stops",
+                ));
+            }
+            thread::sleep(Duration::from_millis(500));
+        }
+    }
+
+    println!("service {} uninstalled", name);
+    Ok(())
+}
+
+define_windows_service!(ffi_service_main, service_main);
+
+fn service_main(_arguments: Vec<OsString>) {
+    let entry = SERVICE_ENTRY
+        .lock()
+        .unwrap()
+        .take()
+        .expect("service is started without an entry");
+
+    if let Err(err) = run_service(entry) {
+        error!("service failed, {}", err);
+    }
+}
+
+fn run_service(entry: ServiceEntry) -> io::Result<()> {
+    let status_handle = service_control_handler::register(&entry.name, |control| match control {
+        ServiceControl::Stop | ServiceControl::Shutdown => {
+            monitor::request_stop();
+            ServiceControlHandlerResult::NoError
+        }
+        ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+        _ => ServiceControlHandlerResult::NotImplemented,
+    })
+    .map_err(to_io_error)?;
+
+    let set_state = |current_state, controls_accepted| {
+        status_handle
+            .set_service_status(ServiceStatus {
+                service_type: ServiceType::OWN_PROCESS,
+                current_state,
+                controls_accepted,
+                exit_code: ServiceExitCode::NO_ERROR,
+                checkpoint: 0,
+                wait_hint: Duration::default(),
+                process_id: None,
+            })
+            .map_err(to_io_error)
+    };
+
+    set_state(
+        ServiceState::Running,
+        ServiceControlAccept::STOP | ServiceControlAccept::SHUTDOWN,
+    )?;
+    info!("service {} started", entry.name);
+
+    // Returns after the stop request is received by the signal monitor, and established connections are closed
+    (entry.run)(&entry.matches);
+
+    info!("service {} stopped", entry.name);
+    set_state(ServiceState::Stopped, ServiceControlAccept::empty())
+}
@@ -0,0 +1,122 @@
+#![cfg(all(feature = "local", feature = "acl"))]
+
+use std::{env, fs, io};
+
+use shadowsocks_service::{
+    acl::{AccessControl, Mode, RejectMode},
+    shadowsocks::{config::ServerType, context::Context, relay::socks5::Address},
+};
+
+/// Load ACL `content` from a file `name`, in a temporary directory of `test`
+fn load_acl(test: &str, name: &str, content: &str) -> io::Result<AccessControl> {
+    let dir = env::temp_dir().join(format!("ss-test-acl-{}-{}", test, std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join(name);
+    fs::write(&path, content).unwrap();
+    let acl = AccessControl::load_from_file(&path);
+    fs::remove_dir_all(&dir).unwrap();
+    acl
+}
+
+#[tokio::test]
+async fn acl_clash_rules() {
+    let _ = env_logger::try_init();
+
+    let acl = load_acl(
+        "clash",
+        "config.yaml",
+        r#"
+port: 7890
+proxies: []
+rules:
+  - DOMAIN-SUFFIX,google.com,PROXY
+  - DOMAIN,direct.example.com,DIRECT
+  - IP-CIDR,10.0.0.0/8,DIRECT,no-resolve
+  - DOMAIN-SUFFIX,ads.example.com,REJECT
+  - DOMAIN-SUFFIX,tracker.example.com,REJECT-DROP
+  - DOMAIN-SUFFIX,netflix.com,us
+  - 'SRC-IP-CIDR,192.168.1.1/32,DIRECT'
+  - MATCH,PROXY
+"#,
+    )
+    .unwrap();
+
+    // `MATCH,PROXY` proxies unmatched targets, like `[proxy_all]`
+    assert_eq!(acl.mode(), Mode::BlackList);
+    assert!(acl.is_default_in_proxy_list());
+
+    assert_eq!(acl.check_host_in_proxy_list("www.google.com"), Some(true));
+    assert_eq!(acl.check_host_in_proxy_list("direct.example.com"), Some(false));
+    assert_eq!(acl.check_host_in_proxy_list("www.example.com"), None);
+    assert!(!acl.check_ip_in_proxy_list(&"10.1.2.3".parse().unwrap()));
+    assert!(acl.check_ip_in_proxy_list(&"192.168.1.1".parse().unwrap()));
+
+    assert_eq!(acl.check_host_rejected("img.ads.example.com"), Some(RejectMode::Refuse));
+    assert_eq!(acl.check_host_rejected("tracker.example.com"), Some(RejectMode::Drop));
+    assert_eq!(acl.check_host_rejected("www.google.com"), None);
+
+    // Proxy groups are routes
+    let context = Context::new(ServerType::Local);
+    let addr = Address::DomainNameAddress("www.netflix.com".to_owned(), 443);
+    assert_eq!(acl.route_tag(&context, &addr).await, Some("us"));
+
+    // Lists of rules, detected by their first rules, and payloads without policies
+    let acl = load_acl(
+        "clash-list",
+        "rules.txt",
+        "# Rule provider\nDOMAIN-SUFFIX,google.com\nIP-CIDR,8.8.8.0/24\n",
+    )
+    .unwrap();
+
+    // Without `MATCH`, unmatched targets are connected directly, and rules without policies are proxied
+    assert_eq!(acl.mode(), Mode::WhiteList);
+    assert_eq!(acl.check_host_in_proxy_list("www.google.com"), Some(true));
+    assert!(acl.check_ip_in_proxy_list(&"8.8.8.8".parse().unwrap()));
+    assert!(!acl.check_ip_in_proxy_list(&"1.1.1.1".parse().unwrap()));
+
+    assert!(load_acl("clash-reject-all", "config.yaml", "rules:\n  - MATCH,REJECT\n").is_err());
+}
+
+#[test]
+fn acl_domain_keyword_regex() {
+    let acl = load_acl(
+        "keyword",
+        "rules.acl",
+        r#"[bypass_all]
+
+[proxy_list]
+DOMAIN-KEYWORD,Google
+DOMAIN-REGEX,^ad[0-9]+\.
+youtube
+netflix\.com
+
+[outbound_block_list]
+DOMAIN-KEYWORD,telemetry
+"#,
+    )
+    .unwrap();
+
+    // Keywords are matched anywhere in hosts, ignoring cases
+    assert_eq!(acl.check_host_in_proxy_list("www.google.com"), Some(true));
+    assert_eq!(acl.check_host_in_proxy_list("GOOGLEUSERCONTENT.COM"), Some(true));
+    assert_eq!(acl.check_host_in_proxy_list("ad42.example.com"), Some(true));
+    assert_eq!(acl.check_host_in_proxy_list("bad42.example.com"), None);
+
+    // Regular expressions of plain words are matched as keywords
+    assert_eq!(acl.check_host_in_proxy_list("m.youtube.com"), Some(true));
+    assert_eq!(acl.check_host_in_proxy_list("www.netflix.com.hk"), Some(true));
+    assert_eq!(acl.check_host_in_proxy_list("netflixcom.example"), None);
+
+    assert_eq!(
+        acl.check_host_rejected("telemetry.example.com"),
+        Some(RejectMode::Refuse)
+    );
+
+    // Keywords are exported as regular expressions
+    let regex = acl.export_proxy_list().regex;
+    for rule in ["^ad[0-9]+\\.", "google", "youtube", "netflix\\.com"] {
+        assert!(regex.iter().any(|r| r == rule), "{} not in {:?}", rule, regex);
+    }
+
+    assert!(load_acl("keyword-empty", "rules.acl", "[proxy_list]\nDOMAIN-KEYWORD,\n").is_err());
+}
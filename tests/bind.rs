@@ -0,0 +1,158 @@
+#![cfg(all(feature = "local-tunnel", feature = "server"))]
+
+use std::net::{SocketAddr, TcpListener as StdTcpListener, UdpSocket as StdUdpSocket};
+
+use tokio::{
+    self,
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    time::{self, Duration},
+};
+
+use shadowsocks_service::{
+    config::{Config, ConfigType},
+    create_local,
+    run_server,
+    shadowsocks::ServerAddr,
+};
+
+use common::spawn_echo_server;
+
+mod common;
+
+#[tokio::test]
+async fn bind_fallback_port() {
+    let _ = env_logger::try_init();
+
+    let echo_addr = spawn_echo_server().await;
+
+    // The configured port is in use
+    let occupied = StdTcpListener::bind("127.0.0.1:9240").unwrap();
+
+    let local_config = Config::load_from_str(
+        &format!(
+            r#"{{
+                "locals": [
+                    {{
+                        "local_port": 9240,
+                        "local_address": "127.0.0.1",
+                        "protocol": "tunnel",
+                        "forward_address": "{}",
+                        "forward_port": {},
+                        "bind_fallback_ports": [9240, 9241]
+                    }}
+                ],
+                "server": "127.0.0.1",
+                "server_port": 9250,
+                "password": "password",
+                "method": "aes-256-gcm"
+            }}"#,
+            echo_addr.ip(),
+            echo_addr.port()
+        ),
+        ConfigType::Local,
+    )
+    .unwrap();
+
+    let server_config = Config::load_from_str(
+        r#"{
+            "server": "127.0.0.1",
+            "server_port": 9250,
+            "password": "password",
+            "method": "aes-256-gcm"
+        }"#,
+        ConfigType::Server,
+    )
+    .unwrap();
+
+    tokio::spawn(run_server(server_config));
+    time::sleep(Duration::from_secs(1)).await;
+
+    let local = create_local(local_config).await.unwrap();
+    let fallback_addr = ServerAddr::from("127.0.0.1:9241".parse::<SocketAddr>().unwrap());
+    assert_eq!(local.listen_addrs()[0].addr, fallback_addr);
+    assert_eq!(local.listener_fallbacks().len(), 1);
+    assert_eq!(local.listener_fallbacks()[0].addr, fallback_addr);
+    tokio::spawn(local.wait_until_exit());
+
+    // Listening as soon as the local is created, without a window of binding it again
+    let mut stream = TcpStream::connect("127.0.0.1:9241").await.unwrap();
+    let mut buffer = [0u8; 5];
+    stream.write_all(b"hello").await.unwrap();
+    stream.read_exact(&mut buffer).await.unwrap();
+    assert_eq!(&buffer, b"hello");
+
+    // The occupied port is not taken over
+    occupied.set_nonblocking(true).unwrap();
+    let _probe = TcpStream::connect("127.0.0.1:9240").await.unwrap();
+    time::sleep(Duration::from_millis(100)).await;
+    assert!(occupied.accept().is_ok());
+}
+
+#[tokio::test]
+async fn bind_unspecified_port() {
+    let _ = env_logger::try_init();
+
+    let echo_addr = spawn_echo_server().await;
+
+    let mut local_config = Config::load_from_str(
+        &format!(
+            r#"{{
+                "locals": [
+                    {{
+                        "local_port": 9296,
+                        "local_address": "127.0.0.1",
+                        "protocol": "tunnel",
+                        "mode": "tcp_and_udp",
+                        "forward_address": "{}",
+                        "forward_port": {}
+                    }}
+                ],
+                "server": "127.0.0.1",
+                "server_port": 9295,
+                "password": "password",
+                "method": "aes-256-gcm"
+            }}"#,
+            echo_addr.ip(),
+            echo_addr.port()
+        ),
+        ConfigType::Local,
+    )
+    .unwrap();
+    // Port 0 is only allowed for embedding, not in configuration files
+    local_config.local[0].addr = Some(ServerAddr::from("127.0.0.1:0".parse::<SocketAddr>().unwrap()));
+
+    let server_config = Config::load_from_str(
+        r#"{
+            "server": "127.0.0.1",
+            "server_port": 9295,
+            "password": "password",
+            "method": "aes-256-gcm"
+        }"#,
+        ConfigType::Server,
+    )
+    .unwrap();
+
+    tokio::spawn(run_server(server_config));
+    time::sleep(Duration::from_secs(1)).await;
+
+    let local = create_local(local_config).await.unwrap();
+    let local_addr = match local.listen_addrs()[0].addr {
+        ServerAddr::SocketAddr(sa) => sa,
+        ref addr => panic!("unexpected listen address {}", addr),
+    };
+    assert_ne!(local_addr.port(), 0);
+
+    // Both TCP and UDP of the chosen port are bound by the local, not probed and released
+    assert_eq!(
+        StdUdpSocket::bind(local_addr).unwrap_err().kind(),
+        std::io::ErrorKind::AddrInUse
+    );
+    tokio::spawn(local.wait_until_exit());
+
+    let mut stream = TcpStream::connect(local_addr).await.unwrap();
+    let mut buffer = [0u8; 5];
+    stream.write_all(b"hello").await.unwrap();
+    stream.read_exact(&mut buffer).await.unwrap();
+    assert_eq!(&buffer, b"hello");
+}
@@ -0,0 +1,155 @@
+#![cfg(all(feature = "aead-cipher-2022", feature = "local", feature = "server"))]
+
+use std::{
+    io::{self, ErrorKind},
+    net::SocketAddr,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use tokio::net::UdpSocket;
+
+use shadowsocks_service::{
+    config::{Config, ConfigType},
+    local::loadbalancing::relay_stat::{RelayErrorClass, RelayStat},
+    net::ntp::query_clock_offset,
+    server::monitor::{SecurityEventKind, SecurityMonitor},
+    shadowsocks::{
+        config::{ServerAddr, ServerType},
+        context::{ClockSkewError, Context},
+    },
+};
+
+/// An SNTP server answering with its clock `offset_secs` ahead of the local clock, or Kiss-o'-Death if it is `None`
+async fn run_ntp_server(offset_secs: Option<u64>) -> SocketAddr {
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let addr = socket.local_addr().unwrap();
+
+    tokio::spawn(async move {
+        let mut req = [0u8; 48];
+        loop {
+            let (_, peer_addr) = socket.recv_from(&mut req).await.unwrap();
+
+            let mut resp = [0u8; 48];
+            resp[0] = 0x24;
+            if let Some(offset_secs) = offset_secs {
+                let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+                let secs = (now.as_secs() + offset_secs + 2_208_988_800) as u32;
+                let frac = ((u64::from(now.subsec_millis()) << 32) / 1000) as u32;
+                resp[1] = 2;
+                resp[24..32].copy_from_slice(&req[40..48]);
+                // Receive and transmit timestamps
+                for ts in resp[32..48].chunks_mut(8) {
+                    ts[..4].copy_from_slice(&secs.to_be_bytes());
+                    ts[4..].copy_from_slice(&frac.to_be_bytes());
+                }
+            } else {
+                resp[24..32].copy_from_slice(&req[40..48]);
+            }
+            let _ = socket.send_to(&resp, peer_addr).await;
+        }
+    });
+
+    addr
+}
+
+#[tokio::test]
+async fn clock_skew_ntp_offset() {
+    let _ = env_logger::try_init();
+
+    let context = Context::new_shared(ServerType::Local);
+
+    let addr = run_ntp_server(Some(120)).await;
+    let offset = query_clock_offset(&context, &ServerAddr::SocketAddr(addr), Duration::from_secs(5))
+        .await
+        .unwrap();
+    assert!((119_000..=121_000).contains(&offset), "offset {}", offset);
+
+    let addr = run_ntp_server(None).await;
+    assert!(
+        query_clock_offset(&context, &ServerAddr::SocketAddr(addr), Duration::from_secs(5))
+            .await
+            .is_err()
+    );
+
+    // Nothing listening
+    let addr = {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        socket.local_addr().unwrap()
+    };
+    assert!(
+        query_clock_offset(&context, &ServerAddr::SocketAddr(addr), Duration::from_millis(200))
+            .await
+            .is_err()
+    );
+}
+
+#[test]
+fn clock_skew_counted_on_both_sides() {
+    let err = io::Error::new(
+        ErrorKind::InvalidData,
+        ClockSkewError {
+            timestamp: 1_000,
+            now: 4_600,
+            tolerance: 30,
+        },
+    );
+    assert!(ClockSkewError::is_clock_skew(&err));
+
+    // Local, closed relays through the server
+    assert_eq!(RelayErrorClass::of(&err), RelayErrorClass::ClockSkew);
+    let relay_stat = RelayStat::new();
+    relay_stat.record_relay_error(&err);
+    relay_stat.record_relay_error(&io::Error::new(ErrorKind::InvalidData, "invalid address"));
+    let errors = relay_stat.relay_errors();
+    assert!(errors.contains(&(RelayErrorClass::ClockSkew, 1)));
+    assert!(errors.contains(&(RelayErrorClass::Protocol, 1)));
+
+    // Server, rejected clients
+    let monitor = SecurityMonitor::new();
+    let peer_addr = "127.0.0.1:40000".parse().unwrap();
+    monitor.report(
+        SecurityEventKind::ClockSkew,
+        peer_addr,
+        "127.0.0.1:8388".parse().unwrap(),
+    );
+    assert_eq!(monitor.counters().clock_skews, 1);
+    assert_eq!(monitor.peer_counters(&peer_addr.ip()).clock_skews, 1);
+    assert_eq!(monitor.counters().malformed_handshakes, 0);
+}
+
+#[test]
+fn clock_skew_config() {
+    let load = |clock_skew: &str| {
+        let config = format!(
+            r#"{{ "server": "127.0.0.1", "server_port": 8388, "password": "password", "method": "aes-256-gcm",
+                  "security": {{ "clock_skew": {} }} }}"#,
+            clock_skew
+        );
+        Config::load_from_str(&config, ConfigType::Server)
+    };
+
+    let config = load(r#"{ "tolerance": 120, "ntp_server": "pool.ntp.org" }"#).unwrap();
+    assert!(config.check_integrity().is_ok());
+    let clock_skew = &config.security.clock_skew;
+    assert_eq!(clock_skew.tolerance, Some(Duration::from_secs(120)));
+    assert_eq!(
+        clock_skew.ntp_server,
+        Some(ServerAddr::DomainName("pool.ntp.org".to_owned(), 123))
+    );
+
+    let config = load(r#"{ "ntp_server": "192.0.2.1" }"#).unwrap();
+    assert_eq!(
+        config.security.clock_skew.ntp_server,
+        Some(ServerAddr::SocketAddr("192.0.2.1:123".parse().unwrap()))
+    );
+    assert_eq!(config.security.clock_skew.tolerance, None);
+
+    let config = load(r#"{ "ntp_server": "[2001:db8::1]:1123" }"#).unwrap();
+    assert_eq!(
+        config.security.clock_skew.ntp_server,
+        Some(ServerAddr::SocketAddr("[2001:db8::1]:1123".parse().unwrap()))
+    );
+
+    assert!(load(r#"{ "tolerance": 0 }"#).is_err());
+    assert!(load(r#"{ "ntp_server": "ntp:example" }"#).is_err());
+}
@@ -0,0 +1,44 @@
+//! Fixtures shared by integration tests
+
+#![allow(dead_code)]
+
+use std::net::SocketAddr;
+
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpListener,
+    time::{self, Duration},
+};
+
+/// Echo everything received by connections of `listener` back
+pub async fn run_echo_server(listener: TcpListener) {
+    loop {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        tokio::spawn(async move {
+            let (mut reader, mut writer) = stream.split();
+            let _ = tokio::io::copy(&mut reader, &mut writer).await;
+        });
+    }
+}
+
+/// Start an echo server on an ephemeral port of loopback
+pub async fn spawn_echo_server() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(run_echo_server(listener));
+    addr
+}
+
+/// Check if `message` sent to `stream` is echoed back in 1 second
+pub async fn echo<S>(stream: &mut S, message: &[u8]) -> bool
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let relay = async {
+        let mut buffer = vec![0u8; message.len()];
+        stream.write_all(message).await?;
+        stream.read_exact(&mut buffer).await?;
+        Ok::<_, std::io::Error>(buffer)
+    };
+    matches!(time::timeout(Duration::from_secs(1), relay).await, Ok(Ok(buffer)) if buffer == message)
+}
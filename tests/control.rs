@@ -0,0 +1,86 @@
+#![cfg(feature = "local-tunnel")]
+
+use tokio::{
+    self,
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::TcpStream,
+    time::{self, Duration},
+};
+
+use shadowsocks_service::{
+    config::{Config, ConfigType},
+    run_local,
+    shadowsocks::manager::ManagerAuthenticator,
+};
+
+fn local_config(control_key: Option<&str>) -> Config {
+    let control_key = match control_key {
+        Some(key) => format!(r#""control_key": "{}","#, key),
+        None => String::new(),
+    };
+    Config::load_from_str(
+        &format!(
+            r#"{{
+                "locals": [
+                    {{
+                        "local_port": 9311,
+                        "local_address": "127.0.0.1",
+                        "protocol": "tunnel",
+                        "forward_address": "127.0.0.1",
+                        "forward_port": 9313
+                    }}
+                ],
+                "server": "127.0.0.1",
+                "server_port": 9313,
+                "password": "password",
+                "method": "aes-256-gcm",
+                "control_address": "127.0.0.1:9312",
+                {}
+                "mode": "tcp_only"
+            }}"#,
+            control_key
+        ),
+        ConfigType::Local,
+    )
+    .unwrap()
+}
+
+async fn send_command(stream: &mut BufReader<TcpStream>, command: &[u8]) -> String {
+    stream.get_mut().write_all(command).await.unwrap();
+    let mut response = String::new();
+    stream.read_line(&mut response).await.unwrap();
+    response
+}
+
+#[test]
+fn control_tcp_requires_key() {
+    assert!(local_config(None).check_integrity().is_err());
+    assert!(local_config(Some("control-key")).check_integrity().is_ok());
+}
+
+#[tokio::test]
+async fn control_authenticated_commands() {
+    let _ = env_logger::try_init();
+
+    tokio::spawn(run_local(local_config(Some("control-key"))));
+    time::sleep(Duration::from_secs(1)).await;
+
+    let mut stream = BufReader::new(TcpStream::connect("127.0.0.1:9312").await.unwrap());
+
+    let response = send_command(&mut stream, b"{\"command\":\"mode\"}\n").await;
+    assert!(response.starts_with(r#"{"error":"authentication failed"#));
+
+    let authenticator = ManagerAuthenticator::new(b"wrong-key");
+    let response = send_command(&mut stream, &authenticator.sign(b"{\"command\":\"mode\"}\n")).await;
+    assert!(response.starts_with(r#"{"error":"authentication failed"#));
+
+    let authenticator = ManagerAuthenticator::new(b"control-key");
+    let command = authenticator.sign(b"{\"command\":\"mode\",\"mode\":\"direct\"}\n");
+    let response = send_command(&mut stream, &command).await;
+    assert_eq!(response, "{\"mode\":\"direct\"}\n");
+
+    // Replayed on another connection
+    let mut stream = BufReader::new(TcpStream::connect("127.0.0.1:9312").await.unwrap());
+    let response = send_command(&mut stream, &command).await;
+    assert!(response.starts_with(r#"{"error":"authentication failed"#));
+}
@@ -0,0 +1,94 @@
+#![cfg(feature = "local")]
+
+use std::net::SocketAddr;
+
+use tokio::{
+    self,
+    time::{self, Duration, Instant},
+};
+
+use shadowsocks_service::{
+    config::{Config, ConfigType},
+    local::socks::client::socks5::Socks5TcpClient,
+    run_local,
+};
+
+use common::{echo, spawn_echo_server};
+
+mod common;
+
+async fn echo_through(proxy: &str, target: SocketAddr) -> bool {
+    match time::timeout(Duration::from_secs(1), Socks5TcpClient::connect(target, proxy)).await {
+        Ok(Ok(mut stream)) => echo(&mut stream, b"hello").await,
+        _ => false,
+    }
+}
+
+#[tokio::test]
+async fn direct_fallback_when_servers_down() {
+    let _ = env_logger::try_init();
+
+    let echo_addr = spawn_echo_server().await;
+
+    // Nothing is listening on the server port
+    let local_config = Config::load_from_str(
+        r#"{
+            "locals": [
+                {
+                    "local_port": 9263,
+                    "local_address": "127.0.0.1"
+                }
+            ],
+            "server": "127.0.0.1",
+            "server_port": 9264,
+            "password": "password",
+            "method": "aes-256-gcm",
+            "direct_fallback": true
+        }"#,
+        ConfigType::Local,
+    )
+    .unwrap();
+
+    tokio::spawn(run_local(local_config));
+
+    // Servers are marked down by the first check of the balancer
+    let deadline = Instant::now() + Duration::from_secs(10);
+    let mut relayed = false;
+    while Instant::now() < deadline {
+        if echo_through("127.0.0.1:9263", echo_addr).await {
+            relayed = true;
+            break;
+        }
+        time::sleep(Duration::from_millis(200)).await;
+    }
+    assert!(relayed, "target isn't connected directly while the server is down");
+}
+
+#[tokio::test]
+async fn direct_fallback_disabled() {
+    let _ = env_logger::try_init();
+
+    let echo_addr = spawn_echo_server().await;
+
+    let local_config = Config::load_from_str(
+        r#"{
+            "locals": [
+                {
+                    "local_port": 9265,
+                    "local_address": "127.0.0.1"
+                }
+            ],
+            "server": "127.0.0.1",
+            "server_port": 9264,
+            "password": "password",
+            "method": "aes-256-gcm"
+        }"#,
+        ConfigType::Local,
+    )
+    .unwrap();
+
+    tokio::spawn(run_local(local_config));
+    time::sleep(Duration::from_secs(1)).await;
+
+    assert!(!echo_through("127.0.0.1:9265", echo_addr).await);
+}
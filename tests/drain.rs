@@ -0,0 +1,98 @@
+#![cfg(all(feature = "local-tunnel", feature = "server"))]
+
+use tokio::{
+    self,
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    time::{self, Duration},
+};
+
+use shadowsocks_service::{
+    config::{Config, ConfigType},
+    create_server,
+    run_local,
+};
+
+use common::spawn_echo_server;
+
+mod common;
+
+#[tokio::test]
+async fn drain_keeps_established_connections() {
+    let _ = env_logger::try_init();
+
+    let echo_addr = spawn_echo_server().await;
+
+    let local_config = Config::load_from_str(
+        &format!(
+            r#"{{
+                "locals": [
+                    {{
+                        "local_port": 9261,
+                        "local_address": "127.0.0.1",
+                        "protocol": "tunnel",
+                        "forward_address": "{}",
+                        "forward_port": {}
+                    }}
+                ],
+                "server": "127.0.0.1",
+                "server_port": 9262,
+                "password": "password",
+                "method": "aes-256-gcm"
+            }}"#,
+            echo_addr.ip(),
+            echo_addr.port()
+        ),
+        ConfigType::Local,
+    )
+    .unwrap();
+
+    let server_config = Config::load_from_str(
+        r#"{
+            "server": "127.0.0.1",
+            "server_port": 9262,
+            "password": "password",
+            "method": "aes-256-gcm"
+        }"#,
+        ConfigType::Server,
+    )
+    .unwrap();
+
+    let server = create_server(server_config).await.unwrap();
+    let drain = server.drain_handle();
+    tokio::spawn(server.wait_until_exit());
+    tokio::spawn(run_local(local_config));
+    time::sleep(Duration::from_secs(1)).await;
+
+    let mut stream = TcpStream::connect("127.0.0.1:9261").await.unwrap();
+    let mut buffer = [0u8; 5];
+
+    stream.write_all(b"hello").await.unwrap();
+    stream.read_exact(&mut buffer).await.unwrap();
+    assert_eq!(&buffer, b"hello");
+    assert_eq!(drain.active_sessions(), (1, 0));
+
+    drain.drain();
+    assert!(drain.is_draining());
+
+    // Listener is closed
+    let mut refused = false;
+    for _ in 0..20 {
+        if TcpStream::connect("127.0.0.1:9262").await.is_err() {
+            refused = true;
+            break;
+        }
+        time::sleep(Duration::from_millis(50)).await;
+    }
+    assert!(refused, "server still accepts connections while draining");
+
+    // Established connections are kept relaying, and waited for
+    stream.write_all(b"world").await.unwrap();
+    stream.read_exact(&mut buffer).await.unwrap();
+    assert_eq!(&buffer, b"world");
+    assert!(!drain.wait_drained(Duration::from_millis(500)).await);
+
+    drop(stream);
+    assert!(drain.wait_drained(Duration::from_secs(5)).await);
+    assert_eq!(drain.active_sessions(), (0, 0));
+}
@@ -0,0 +1,171 @@
+#![cfg(all(feature = "local-tunnel", feature = "server"))]
+
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+
+use tokio::{
+    self,
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    time::{self, Duration},
+};
+
+use shadowsocks_service::{
+    config::{Config, ConfigType},
+    run_local,
+    run_server,
+};
+
+use common::spawn_echo_server;
+
+mod common;
+
+/// Forwards connections of `listener` to `target`, counting them in `connections`
+async fn run_counting_forwarder(listener: TcpListener, target: &'static str, connections: Arc<AtomicUsize>) {
+    loop {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        connections.fetch_add(1, Ordering::SeqCst);
+        tokio::spawn(async move {
+            let mut remote = TcpStream::connect(target).await.unwrap();
+            let _ = tokio::io::copy_bidirectional(&mut stream, &mut remote).await;
+        });
+    }
+}
+
+#[tokio::test]
+async fn mux_shares_connections() {
+    let _ = env_logger::try_init();
+
+    let echo_addr = spawn_echo_server().await;
+
+    // sslocal connects the server through a forwarder on 9271, which counts connections
+    let local_config = Config::load_from_str(
+        &format!(
+            r#"{{
+                "locals": [
+                    {{
+                        "local_port": 9270,
+                        "local_address": "127.0.0.1",
+                        "protocol": "tunnel",
+                        "forward_address": "{}",
+                        "forward_port": {}
+                    }}
+                ],
+                "server": "127.0.0.1",
+                "server_port": 9271,
+                "password": "password",
+                "method": "aes-256-gcm",
+                "mux": {{
+                    "max_streams": 2
+                }}
+            }}"#,
+            echo_addr.ip(),
+            echo_addr.port()
+        ),
+        ConfigType::Local,
+    )
+    .unwrap();
+
+    let server_config = Config::load_from_str(
+        r#"{
+            "server": "127.0.0.1",
+            "server_port": 9272,
+            "password": "password",
+            "method": "aes-256-gcm",
+            "mux": {}
+        }"#,
+        ConfigType::Server,
+    )
+    .unwrap();
+
+    let connections = Arc::new(AtomicUsize::new(0));
+    let forwarder = TcpListener::bind("127.0.0.1:9271").await.unwrap();
+    tokio::spawn(run_counting_forwarder(forwarder, "127.0.0.1:9272", connections.clone()));
+
+    tokio::spawn(run_server(server_config));
+    tokio::spawn(run_local(local_config));
+    time::sleep(Duration::from_secs(1)).await;
+
+    // Streams are kept open, so they are opened concurrently
+    let mut streams = Vec::new();
+    for i in 0..4u8 {
+        let mut stream = TcpStream::connect("127.0.0.1:9270").await.unwrap();
+        let message = [b'a' + i; 32];
+        let mut buffer = [0u8; 32];
+        stream.write_all(&message).await.unwrap();
+        stream.read_exact(&mut buffer).await.unwrap();
+        assert_eq!(buffer, message);
+        streams.push(stream);
+    }
+
+    // 2 streams in each connection
+    assert_eq!(connections.load(Ordering::SeqCst), 2);
+
+    // Streams are still relayed independently
+    for (i, stream) in streams.iter_mut().enumerate() {
+        let message = [b'A' + i as u8; 32];
+        let mut buffer = [0u8; 32];
+        stream.write_all(&message).await.unwrap();
+        stream.read_exact(&mut buffer).await.unwrap();
+        assert_eq!(buffer, message);
+    }
+}
+
+#[tokio::test]
+async fn mux_refused_by_server() {
+    let _ = env_logger::try_init();
+
+    let echo_addr = spawn_echo_server().await;
+
+    let local_config = Config::load_from_str(
+        &format!(
+            r#"{{
+                "locals": [
+                    {{
+                        "local_port": 9273,
+                        "local_address": "127.0.0.1",
+                        "protocol": "tunnel",
+                        "forward_address": "{}",
+                        "forward_port": {}
+                    }}
+                ],
+                "server": "127.0.0.1",
+                "server_port": 9274,
+                "password": "password",
+                "method": "aes-256-gcm",
+                "mux": {{}}
+            }}"#,
+            echo_addr.ip(),
+            echo_addr.port()
+        ),
+        ConfigType::Local,
+    )
+    .unwrap();
+
+    // Servers without `mux` don't accept multiplexed connections
+    let server_config = Config::load_from_str(
+        r#"{
+            "server": "127.0.0.1",
+            "server_port": 9274,
+            "password": "password",
+            "method": "aes-256-gcm"
+        }"#,
+        ConfigType::Server,
+    )
+    .unwrap();
+
+    tokio::spawn(run_server(server_config));
+    tokio::spawn(run_local(local_config));
+    time::sleep(Duration::from_secs(1)).await;
+
+    let mut stream = TcpStream::connect("127.0.0.1:9273").await.unwrap();
+    let mut buffer = [0u8; 5];
+    stream.write_all(b"hello").await.unwrap();
+    let relayed = time::timeout(Duration::from_secs(2), stream.read_exact(&mut buffer)).await;
+    assert!(
+        !matches!(relayed, Ok(Ok(..))),
+        "multiplexed stream is relayed by the server without mux"
+    );
+}
@@ -0,0 +1,92 @@
+#![cfg(all(feature = "local-tunnel", feature = "server"))]
+
+use tokio::{
+    self,
+    net::TcpStream,
+    time::{self, Duration},
+};
+
+use shadowsocks_service::{
+    config::{Config, ConfigType},
+    run_local,
+    run_server,
+};
+
+use common::{echo, spawn_echo_server};
+
+mod common;
+
+async fn echo_through(proxy: &str) -> bool {
+    match TcpStream::connect(proxy).await {
+        Ok(mut stream) => echo(&mut stream, b"hello").await,
+        Err(..) => false,
+    }
+}
+
+#[tokio::test]
+async fn outbound_firewall() {
+    let _ = env_logger::try_init();
+
+    let allowed_port = spawn_echo_server().await.port();
+
+    let denied_port = spawn_echo_server().await.port();
+
+    // Tunnels on 9287 and 9288 to each of the echo servers
+    let local_config = Config::load_from_str(
+        &format!(
+            r#"{{
+                "locals": [
+                    {{
+                        "local_port": 9287,
+                        "local_address": "127.0.0.1",
+                        "protocol": "tunnel",
+                        "forward_address": "127.0.0.1",
+                        "forward_port": {}
+                    }},
+                    {{
+                        "local_port": 9288,
+                        "local_address": "127.0.0.1",
+                        "protocol": "tunnel",
+                        "forward_address": "127.0.0.1",
+                        "forward_port": {}
+                    }}
+                ],
+                "server": "127.0.0.1",
+                "server_port": 9289,
+                "password": "password",
+                "method": "aes-256-gcm"
+            }}"#,
+            allowed_port, denied_port
+        ),
+        ConfigType::Local,
+    )
+    .unwrap();
+
+    // Targets without any matched rule are denied
+    let server_config = Config::load_from_str(
+        &format!(
+            r#"{{
+                "server": "127.0.0.1",
+                "server_port": 9289,
+                "password": "password",
+                "method": "aes-256-gcm",
+                "outbound_firewall": {{
+                    "rules": [
+                        {{ "action": "allow", "protocol": "tcp", "cidrs": ["127.0.0.0/8"], "ports": ["{}"] }}
+                    ],
+                    "default_action": "deny"
+                }}
+            }}"#,
+            allowed_port
+        ),
+        ConfigType::Server,
+    )
+    .unwrap();
+
+    tokio::spawn(run_server(server_config));
+    tokio::spawn(run_local(local_config));
+    time::sleep(Duration::from_secs(1)).await;
+
+    assert!(echo_through("127.0.0.1:9287").await);
+    assert!(!echo_through("127.0.0.1:9288").await);
+}
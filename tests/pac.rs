@@ -0,0 +1,60 @@
+#![cfg(feature = "local-pac")]
+
+use tokio::{
+    self,
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    time::{self, Duration},
+};
+
+use shadowsocks_service::{
+    config::{Config, ConfigType},
+    run_local,
+};
+
+#[tokio::test]
+async fn pac_proxy_of_connected_address() {
+    let _ = env_logger::try_init();
+
+    let local_config = Config::load_from_str(
+        r#"{
+            "locals": [
+                {
+                    "local_port": 9292,
+                    "local_address": "0.0.0.0",
+                    "protocol": "socks"
+                },
+                {
+                    "local_port": 9293,
+                    "local_address": "127.0.0.1",
+                    "protocol": "pac"
+                }
+            ],
+            "server": "127.0.0.1",
+            "server_port": 9294,
+            "password": "password",
+            "method": "aes-256-gcm"
+        }"#,
+        ConfigType::Local,
+    )
+    .unwrap();
+
+    tokio::spawn(run_local(local_config));
+    time::sleep(Duration::from_secs(1)).await;
+
+    let mut stream = TcpStream::connect("127.0.0.1:9293").await.unwrap();
+    stream
+        .write_all(b"GET /proxy.pac HTTP/1.1\r\nHost: 127.0.0.1\r\nConnection: close\r\n\r\n")
+        .await
+        .unwrap();
+
+    let mut response = String::new();
+    time::timeout(Duration::from_secs(5), stream.read_to_string(&mut response))
+        .await
+        .unwrap()
+        .unwrap();
+
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+    // The SOCKS5 listener on 0.0.0.0 is referred by the address we connected to
+    assert!(response.contains("SOCKS5 127.0.0.1:9292"), "{}", response);
+}
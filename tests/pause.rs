@@ -0,0 +1,95 @@
+#![cfg(all(feature = "local-tunnel", feature = "server"))]
+
+use tokio::{
+    self,
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    time::{self, Duration},
+};
+
+use shadowsocks_service::{
+    config::{Config, ConfigType},
+    create_local,
+    run_server,
+};
+
+use common::spawn_echo_server;
+
+mod common;
+
+#[tokio::test]
+async fn pause_and_resume() {
+    let _ = env_logger::try_init();
+
+    let echo_addr = spawn_echo_server().await;
+
+    let local_config = Config::load_from_str(
+        &format!(
+            r#"{{
+                "locals": [
+                    {{
+                        "local_port": 9290,
+                        "local_address": "127.0.0.1",
+                        "protocol": "tunnel",
+                        "forward_address": "{}",
+                        "forward_port": {}
+                    }}
+                ],
+                "server": "127.0.0.1",
+                "server_port": 9291,
+                "password": "password",
+                "method": "aes-256-gcm"
+            }}"#,
+            echo_addr.ip(),
+            echo_addr.port()
+        ),
+        ConfigType::Local,
+    )
+    .unwrap();
+
+    let server_config = Config::load_from_str(
+        r#"{
+            "server": "127.0.0.1",
+            "server_port": 9291,
+            "password": "password",
+            "method": "aes-256-gcm"
+        }"#,
+        ConfigType::Server,
+    )
+    .unwrap();
+
+    tokio::spawn(run_server(server_config));
+    time::sleep(Duration::from_secs(1)).await;
+
+    let local = create_local(local_config).await.unwrap();
+    let pause_controller = local.pause_controller();
+    tokio::spawn(local.wait_until_exit());
+    time::sleep(Duration::from_secs(1)).await;
+
+    let mut stream = TcpStream::connect("127.0.0.1:9290").await.unwrap();
+    let mut buffer = [0u8; 5];
+
+    stream.write_all(b"hello").await.unwrap();
+    stream.read_exact(&mut buffer).await.unwrap();
+    assert_eq!(&buffer, b"hello");
+
+    // Nothing is relayed while paused
+    pause_controller.pause();
+    stream.write_all(b"world").await.unwrap();
+    assert!(
+        time::timeout(Duration::from_millis(500), stream.read_exact(&mut buffer))
+            .await
+            .is_err()
+    );
+
+    // New connections are not served either
+    let mut paused_stream = TcpStream::connect("127.0.0.1:9290").await.unwrap();
+    paused_stream.write_all(b"again").await.unwrap();
+
+    pause_controller.resume();
+    stream.read_exact(&mut buffer).await.unwrap();
+    assert_eq!(&buffer, b"world");
+
+    paused_stream.read_exact(&mut buffer).await.unwrap();
+    assert_eq!(&buffer, b"again");
+}
@@ -0,0 +1,74 @@
+#![cfg(all(feature = "local-tunnel", feature = "server"))]
+
+use tokio::{
+    self,
+    net::TcpStream,
+    time::{self, Duration},
+};
+
+use shadowsocks_service::{
+    config::{Config, ConfigType},
+    run_local,
+    run_server,
+};
+
+use common::{echo, spawn_echo_server};
+
+mod common;
+
+#[tokio::test]
+async fn quota_exceeded() {
+    let _ = env_logger::try_init();
+
+    let echo_addr = spawn_echo_server().await;
+
+    let local_config = Config::load_from_str(
+        &format!(
+            r#"{{
+                "locals": [
+                    {{
+                        "local_port": 9283,
+                        "local_address": "127.0.0.1",
+                        "protocol": "tunnel",
+                        "forward_address": "{}",
+                        "forward_port": {}
+                    }}
+                ],
+                "server": "127.0.0.1",
+                "server_port": 9284,
+                "password": "password",
+                "method": "aes-256-gcm"
+            }}"#,
+            echo_addr.ip(),
+            echo_addr.port()
+        ),
+        ConfigType::Local,
+    )
+    .unwrap();
+
+    let server_config = Config::load_from_str(
+        r#"{
+            "server": "127.0.0.1",
+            "server_port": 9284,
+            "password": "password",
+            "method": "aes-256-gcm",
+            "quota": 4096
+        }"#,
+        ConfigType::Server,
+    )
+    .unwrap();
+
+    tokio::spawn(run_server(server_config));
+    tokio::spawn(run_local(local_config));
+    time::sleep(Duration::from_secs(1)).await;
+
+    let mut stream = TcpStream::connect("127.0.0.1:9283").await.unwrap();
+    assert!(echo(&mut stream, b"hello").await);
+
+    // Uses up the quota, the established connection may be closed before all of them are echoed
+    let _ = echo(&mut stream, &[0u8; 8192]).await;
+
+    // New connections are closed without being relayed
+    let mut stream = TcpStream::connect("127.0.0.1:9283").await.unwrap();
+    assert!(!echo(&mut stream, b"hello").await);
+}
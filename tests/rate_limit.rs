@@ -0,0 +1,89 @@
+#![cfg(all(feature = "local-tunnel", feature = "server"))]
+
+use tokio::{
+    self,
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    time::{self, Duration, Instant},
+};
+
+use shadowsocks_service::{
+    config::{Config, ConfigType},
+    run_local,
+    run_server,
+};
+
+use common::spawn_echo_server;
+
+mod common;
+
+#[tokio::test]
+async fn rate_limit_client() {
+    let _ = env_logger::try_init();
+
+    let echo_addr = spawn_echo_server().await;
+
+    let local_config = Config::load_from_str(
+        &format!(
+            r#"{{
+                "locals": [
+                    {{
+                        "local_port": 9285,
+                        "local_address": "127.0.0.1",
+                        "protocol": "tunnel",
+                        "forward_address": "{}",
+                        "forward_port": {}
+                    }}
+                ],
+                "server": "127.0.0.1",
+                "server_port": 9286,
+                "password": "password",
+                "method": "aes-256-gcm"
+            }}"#,
+            echo_addr.ip(),
+            echo_addr.port()
+        ),
+        ConfigType::Local,
+    )
+    .unwrap();
+
+    // 64KiB/s of each direction
+    let server_config = Config::load_from_str(
+        r#"{
+            "server": "127.0.0.1",
+            "server_port": 9286,
+            "password": "password",
+            "method": "aes-256-gcm",
+            "rate_limit": {
+                "client": 65536
+            }
+        }"#,
+        ConfigType::Server,
+    )
+    .unwrap();
+
+    tokio::spawn(run_server(server_config));
+    tokio::spawn(run_local(local_config));
+    time::sleep(Duration::from_secs(1)).await;
+
+    let stream = TcpStream::connect("127.0.0.1:9285").await.unwrap();
+    let (mut reader, mut writer) = stream.into_split();
+
+    let start = Instant::now();
+    let message = vec![0xaau8; 128 * 1024];
+    let expected = message.clone();
+    let write = tokio::spawn(async move { writer.write_all(&message).await });
+
+    let mut buffer = vec![0u8; expected.len()];
+    time::timeout(Duration::from_secs(10), reader.read_exact(&mut buffer))
+        .await
+        .unwrap()
+        .unwrap();
+    let elapsed = start.elapsed();
+    write.await.unwrap().unwrap();
+
+    assert!(buffer == expected);
+    // 128KiB in 2s, except the burst of 100ms
+    assert!(elapsed >= Duration::from_millis(1500), "relayed in {:?}", elapsed);
+    assert!(elapsed < Duration::from_secs(6), "relayed in {:?}", elapsed);
+}
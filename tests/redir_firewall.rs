@@ -0,0 +1,130 @@
+#![cfg(all(feature = "local-redir", target_os = "linux"))]
+
+use std::net::SocketAddr;
+
+use shadowsocks_service::{
+    config::{Config, ConfigType, RedirFirewall, RedirType},
+    local::redir::{RedirFirewallRules, RedirTarget},
+};
+
+fn target(ty: RedirType, addr: &str) -> Option<RedirTarget> {
+    Some(RedirTarget {
+        ty,
+        addr: addr.parse::<SocketAddr>().unwrap(),
+        ipv6_only: false,
+    })
+}
+
+#[test]
+fn redir_firewall_iptables() {
+    let mut rules = RedirFirewallRules::new(
+        RedirFirewall::Iptables,
+        60080,
+        target(RedirType::Redirect, "[::]:60080"),
+        target(RedirType::TProxy, "127.0.0.1:60081"),
+    );
+    rules.add_bypass("203.0.113.1".parse().unwrap());
+    // Already in a private range
+    rules.add_bypass("10.0.0.1".parse().unwrap());
+
+    assert_eq!(rules.tproxy_mark(), 0x5353eab0);
+
+    let lines = rules.install_command_lines();
+    let has = |line: &str| lines.iter().any(|l| l == line);
+
+    assert!(has("iptables -w -t nat -N SS_REDIR_60080"));
+    assert!(has("iptables -w -t nat -A SS_REDIR_60080 -d 203.0.113.1/32 -j RETURN"));
+    assert!(has(
+        "iptables -w -t nat -A SS_REDIR_60080 -p tcp -j REDIRECT --to-ports 60080"
+    ));
+    assert!(has("iptables -w -t nat -A PREROUTING -j SS_REDIR_60080"));
+    assert!(has(
+        "iptables -w -t mangle -A SS_REDIR_60080 -p udp -j TPROXY --on-port 60081 --on-ip 127.0.0.1 \
+         --tproxy-mark 0x5353eab0"
+    ));
+    assert!(has("iptables -w -t mangle -A PREROUTING -j SS_REDIR_60080"));
+    assert!(has("ip -4 rule add fwmark 0x5353eab0 lookup 1398008496"));
+    assert!(has("ip -4 route add local 0.0.0.0/0 dev lo table 1398008496"));
+    assert_eq!(lines.iter().filter(|l| l.contains("10.0.0.1")).count(), 0);
+
+    // Only forwarded traffic is redirected
+    assert!(lines.iter().all(|l| !l.contains("OUTPUT")));
+
+    // The UDP listener is IPv4 only, TCP is redirected for both families
+    assert!(has(
+        "ip6tables -w -t nat -A SS_REDIR_60080 -p tcp -j REDIRECT --to-ports 60080"
+    ));
+    assert!(lines.iter().all(|l| !l.starts_with("ip6tables -w -t mangle")));
+}
+
+#[test]
+fn redir_firewall_nftables() {
+    let rules = RedirFirewallRules::new(
+        RedirFirewall::Nftables,
+        60080,
+        target(RedirType::Redirect, "0.0.0.0:60080"),
+        target(RedirType::TProxy, "0.0.0.0:60080"),
+    );
+
+    let ruleset = rules.nftables_ruleset();
+    assert!(ruleset.starts_with("table inet shadowsocks_redir_60080 {\n"));
+    assert!(ruleset.contains("type nat hook prerouting priority dstnat"));
+    assert!(ruleset.contains("meta nfproto ipv4 meta l4proto tcp redirect to :60080"));
+    assert!(ruleset.contains("meta nfproto ipv4 meta l4proto udp meta mark set 0x5353eab0 tproxy ip to :60080 accept"));
+    assert!(!ruleset.contains("nfproto ipv6"));
+    assert!(!ruleset.contains("hook output"));
+
+    // The ruleset is loaded at once, policy routing of TPROXY is added by `ip`
+    let lines = rules.install_command_lines();
+    assert_eq!(
+        lines,
+        [
+            "nft -f -",
+            "ip -4 rule add fwmark 0x5353eab0 lookup 1398008496",
+            "ip -4 route add local 0.0.0.0/0 dev lo table 1398008496",
+        ]
+    );
+
+    // Rules of listeners on other ports don't share marks
+    let other = RedirFirewallRules::new(
+        RedirFirewall::Nftables,
+        60090,
+        None,
+        target(RedirType::TProxy, "0.0.0.0:60090"),
+    );
+    assert_ne!(other.tproxy_mark(), rules.tproxy_mark());
+}
+
+#[test]
+fn redir_firewall_config() {
+    let load = |locals: &str| {
+        let config = format!(
+            r#"{{ "server": "127.0.0.1", "server_port": 8388, "password": "password", "method": "aes-256-gcm",
+                  "locals": [{}] }}"#,
+            locals
+        );
+        Config::load_from_str(&config, ConfigType::Local)
+    };
+
+    let config =
+        load(r#"{ "protocol": "redir", "local_address": "::", "local_port": 60080, "redir_firewall": "nftables" }"#)
+            .unwrap();
+    assert!(config.check_integrity().is_ok());
+    assert_eq!(config.local[0].redir_firewall, Some(RedirFirewall::Nftables));
+
+    assert!(
+        load(r#"{ "protocol": "redir", "local_address": "::", "local_port": 60080, "redir_firewall": "pf" }"#).is_err()
+    );
+
+    // Only for redir
+    let config = load(r#"{ "local_address": "::", "local_port": 1080, "redir_firewall": "iptables" }"#).unwrap();
+    assert!(config.check_integrity().is_err());
+
+    // Only for one local
+    let config = load(
+        r#"{ "protocol": "redir", "local_address": "::", "local_port": 60080, "redir_firewall": "iptables" },
+           { "protocol": "redir", "local_address": "::", "local_port": 60081, "redir_firewall": "iptables" }"#,
+    )
+    .unwrap();
+    assert!(config.check_integrity().is_err());
+}
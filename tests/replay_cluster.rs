@@ -0,0 +1,165 @@
+#![cfg(all(feature = "local-tunnel", feature = "server"))]
+
+use std::net::SocketAddr;
+
+use tokio::{
+    self,
+    net::{TcpStream, UdpSocket},
+    time::{self, Duration},
+};
+
+use shadowsocks_service::{
+    config::{Config, ConfigType},
+    run_local,
+    run_server,
+};
+
+use common::{echo, spawn_echo_server};
+
+mod common;
+
+/// Start a tunnel on `local_port` to `echo_addr` through a server on `server_ip` and `server_port`
+fn start_tunnel(local_port: u16, server_ip: &str, server_port: u16, echo_addr: SocketAddr) {
+    let local_config = Config::load_from_str(
+        &format!(
+            r#"{{
+                "locals": [
+                    {{
+                        "local_port": {},
+                        "local_address": "127.0.0.1",
+                        "protocol": "tunnel",
+                        "forward_address": "{}",
+                        "forward_port": {}
+                    }}
+                ],
+                "server": "{}",
+                "server_port": {},
+                "password": "password",
+                "method": "aes-256-gcm"
+            }}"#,
+            local_port,
+            echo_addr.ip(),
+            echo_addr.port(),
+            server_ip,
+            server_port
+        ),
+        ConfigType::Local,
+    )
+    .unwrap();
+
+    tokio::spawn(run_local(local_config));
+}
+
+/// Start a server on `server_ip` and `server_port` with a quota of 4096 bytes, in a cluster listening on
+/// `cluster_port`
+///
+/// Servers of a cluster share traffic counters of the same `server_port`, so they listen on different IPs.
+fn start_server(server_ip: &str, server_port: u16, cluster_port: u16, peer_ports: &[u16]) {
+    let peers = peer_ports
+        .iter()
+        .map(|port| format!(r#""127.0.0.1:{}""#, port))
+        .collect::<Vec<_>>()
+        .join(", ");
+    let server_config = Config::load_from_str(
+        &format!(
+            r#"{{
+                "server": "{}",
+                "server_port": {},
+                "password": "password",
+                "method": "aes-256-gcm",
+                "quota": 4096,
+                "security": {{
+                    "replay_attack": {{
+                        "cluster": {{
+                            "listen_address": "127.0.0.1:{}",
+                            "peers": [{}],
+                            "password": "cluster-password"
+                        }}
+                    }}
+                }}
+            }}"#,
+            server_ip, server_port, cluster_port, peers
+        ),
+        ConfigType::Server,
+    )
+    .unwrap();
+
+    tokio::spawn(run_server(server_config));
+}
+
+async fn echo_through(port: u16, message: &[u8]) -> bool {
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+    echo(&mut stream, message).await
+}
+
+#[tokio::test]
+async fn cluster_shared_quota() {
+    let _ = env_logger::try_init();
+
+    let echo_addr = spawn_echo_server().await;
+
+    start_server("127.0.0.1", 9302, 9303, &[9304]);
+    start_server("127.0.0.2", 9302, 9304, &[9303]);
+    start_tunnel(9300, "127.0.0.1", 9302, echo_addr);
+    start_tunnel(9301, "127.0.0.2", 9302, echo_addr);
+    time::sleep(Duration::from_secs(1)).await;
+
+    assert!(echo_through(9301, b"hello").await);
+
+    // Uses up the quota on the first server
+    let _ = echo_through(9300, &[0u8; 8192]).await;
+    assert!(!echo_through(9300, b"hello").await);
+
+    // The second server has received the traffic counters of the first one
+    time::sleep(Duration::from_secs(2)).await;
+    assert!(!echo_through(9301, b"hello").await);
+}
+
+/// Receive packets sent to `socket` until nothing is received in 500ms
+async fn receive_packets(socket: &UdpSocket) -> Vec<Vec<u8>> {
+    let mut packets = Vec::new();
+    let mut buffer = [0u8; 65536];
+    while let Ok(Ok((n, ..))) = time::timeout(Duration::from_millis(500), socket.recv_from(&mut buffer)).await {
+        packets.push(buffer[..n].to_vec());
+    }
+    packets
+}
+
+#[tokio::test]
+async fn cluster_rejects_replayed_packets() {
+    let _ = env_logger::try_init();
+
+    let echo_addr = spawn_echo_server().await;
+
+    // Packets of the first server are captured, and sent to the second server by the test
+    let capture = UdpSocket::bind("127.0.0.1:9309").await.unwrap();
+    start_server("127.0.0.1", 9307, 9308, &[9309]);
+    start_server("127.0.0.2", 9307, 9310, &[]);
+    start_tunnel(9305, "127.0.0.1", 9307, echo_addr);
+    start_tunnel(9306, "127.0.0.2", 9307, echo_addr);
+    time::sleep(Duration::from_secs(2)).await;
+
+    // Counters before the quota is used up
+    let unused_packets = receive_packets(&capture).await;
+    assert!(!unused_packets.is_empty());
+
+    let _ = echo_through(9305, &[0u8; 8192]).await;
+    time::sleep(Duration::from_secs(1)).await;
+    let used_packets = receive_packets(&capture).await;
+    assert!(!used_packets.is_empty());
+
+    assert!(echo_through(9306, b"hello").await);
+
+    for packet in &used_packets {
+        capture.send_to(packet, "127.0.0.1:9310").await.unwrap();
+    }
+    time::sleep(Duration::from_millis(500)).await;
+    assert!(!echo_through(9306, b"hello").await);
+
+    // Counters sent before the received ones couldn't lift the quota
+    for packet in &unused_packets {
+        capture.send_to(packet, "127.0.0.1:9310").await.unwrap();
+    }
+    time::sleep(Duration::from_millis(500)).await;
+    assert!(!echo_through(9306, b"hello").await);
+}
@@ -0,0 +1,68 @@
+#![cfg(all(
+    feature = "local-http",
+    any(
+        target_os = "linux",
+        target_os = "freebsd",
+        target_os = "openbsd",
+        target_os = "netbsd"
+    )
+))]
+
+use shadowsocks_service::{
+    config::{LocalConfig, ProtocolType},
+    local::system_proxy::{ProxySettings, SystemProxyConfig},
+    shadowsocks::config::{Mode, ServerAddr},
+};
+
+fn local(addr: &str, protocol: ProtocolType) -> LocalConfig {
+    LocalConfig::new_with_addr(
+        ServerAddr::from(addr.parse::<std::net::SocketAddr>().unwrap()),
+        protocol,
+    )
+}
+
+#[test]
+fn system_proxy_gsettings() {
+    let mut udp_only = local("127.0.0.1:1085", ProtocolType::Socks);
+    udp_only.mode = Mode::UdpOnly;
+
+    // The first listener of each protocol is chosen, unspecified addresses are referred by loopback addresses
+    let config = SystemProxyConfig::from_locals(&[
+        udp_only,
+        local("[::]:1080", ProtocolType::Socks),
+        local("0.0.0.0:1087", ProtocolType::Http),
+        local("127.0.0.1:1088", ProtocolType::Http),
+    ]);
+    assert_eq!(config.http.as_ref().unwrap().to_string(), "127.0.0.1:1087");
+    assert_eq!(config.socks.as_ref().unwrap().to_string(), "[::1]:1080");
+
+    let settings =
+        ProxySettings::default().with_proxy(config.http.as_ref(), config.socks.as_ref(), &["localhost", "*.local"]);
+    assert_eq!(
+        settings.gsettings_args(),
+        [
+            ["set", "org.gnome.system.proxy", "mode", "'manual'"],
+            [
+                "set",
+                "org.gnome.system.proxy",
+                "ignore-hosts",
+                "['localhost', '*.local']"
+            ],
+            ["set", "org.gnome.system.proxy.http", "host", "'127.0.0.1'"],
+            ["set", "org.gnome.system.proxy.http", "port", "1087"],
+            ["set", "org.gnome.system.proxy.https", "host", "'127.0.0.1'"],
+            ["set", "org.gnome.system.proxy.https", "port", "1087"],
+            ["set", "org.gnome.system.proxy.socks", "host", "'::1'"],
+            ["set", "org.gnome.system.proxy.socks", "port", "1080"],
+        ]
+    );
+
+    // Proxies that aren't configured are cleared, instead of kept from the previous settings
+    let settings = settings.with_proxy(None, config.socks.as_ref(), &["it's"]);
+    let args = settings.gsettings_args();
+    assert!(args.contains(&["set", "org.gnome.system.proxy", "ignore-hosts", r"['it\'s']"]));
+    assert!(args.contains(&["set", "org.gnome.system.proxy.http", "host", "''"]));
+    assert!(args.contains(&["set", "org.gnome.system.proxy.http", "port", "0"]));
+    assert!(args.contains(&["set", "org.gnome.system.proxy.socks", "host", "'::1'"]));
+    assert_eq!(args.len(), 8);
+}